@@ -5,7 +5,7 @@ use argon2::password_hash::PasswordHash;
 use async_trait::async_trait;
 
 use http::header::{ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ALLOW};
-use hyper::{body::Incoming as IncomingBody, Request, Response, StatusCode};
+use hyper::{body::Incoming as IncomingBody, Method, Request, Response, StatusCode};
 use tokio::sync::watch;
 
 use opentelemetry::trace::SpanRef;
@@ -22,18 +22,27 @@ use garage_util::socket_address::UnixOrTCPSocketAddress;
 
 use crate::generic_server::*;
 
+use crate::admin::bandwidth::*;
 use crate::admin::bucket::*;
 use crate::admin::cluster::*;
 use crate::admin::error::*;
 use crate::admin::key::*;
+use crate::admin::recent_errors::*;
 use crate::admin::router_v0;
 use crate::admin::router_v1::{Authorization, Endpoint};
+use crate::admin::worker::*;
 use crate::helpers::*;
+use crate::s3::bandwidth::BandwidthLimiter;
+use crate::s3::import::ImportJobs;
+use crate::s3::recent_errors::RecentErrors;
 
 pub type ResBody = BoxBody<Error>;
 
 pub struct AdminApiServer {
 	garage: Arc<Garage>,
+	s3_recent_errors: Arc<RecentErrors>,
+	s3_bandwidth: Arc<BandwidthLimiter>,
+	s3_import_jobs: Arc<ImportJobs>,
 	#[cfg(feature = "metrics")]
 	exporter: PrometheusExporter,
 	metrics_token: Option<String>,
@@ -43,6 +52,9 @@ pub struct AdminApiServer {
 impl AdminApiServer {
 	pub fn new(
 		garage: Arc<Garage>,
+		s3_recent_errors: Arc<RecentErrors>,
+		s3_bandwidth: Arc<BandwidthLimiter>,
+		s3_import_jobs: Arc<ImportJobs>,
 		#[cfg(feature = "metrics")] exporter: PrometheusExporter,
 	) -> Self {
 		let cfg = &garage.config.admin;
@@ -50,6 +62,9 @@ impl AdminApiServer {
 		let admin_token = cfg.admin_token.as_deref().map(hash_bearer_token);
 		Self {
 			garage,
+			s3_recent_errors,
+			s3_bandwidth,
+			s3_import_jobs,
 			#[cfg(feature = "metrics")]
 			exporter,
 			metrics_token,
@@ -63,8 +78,13 @@ impl AdminApiServer {
 		must_exit: watch::Receiver<bool>,
 	) -> Result<(), GarageError> {
 		let region = self.garage.config.s3_api.s3_region.clone();
-		ApiServer::new(region, self)
-			.run_server(bind_addr, Some(0o220), must_exit)
+		let slow_request_threshold_msec = self.garage.config.admin.slow_request_threshold_msec;
+		let header_limits = HeaderLimits::new(
+			self.garage.config.admin.max_header_size,
+			self.garage.config.admin.max_header_count,
+		);
+		ApiServer::new(region, self, slow_request_threshold_msec)
+			.run_server(bind_addr, Some(0o220), header_limits, None, must_exit)
 			.await
 	}
 
@@ -230,6 +250,13 @@ impl ApiHandler for AdminApiServer {
 	type Error = Error;
 
 	fn parse_endpoint(&self, req: &Request<IncomingBody>) -> Result<Endpoint, Error> {
+		if self.garage.config.admin.read_only
+			&& !matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS)
+		{
+			return Err(Error::forbidden(
+				"The admin API of this node is read-only, only GET requests are allowed",
+			));
+		}
 		if req.uri().path().starts_with("/v0/") {
 			let endpoint_v0 = router_v0::Endpoint::from_request(req)?;
 			Endpoint::from_v0(endpoint_v0)
@@ -271,14 +298,40 @@ impl ApiHandler for AdminApiServer {
 			Endpoint::Metrics => self.handle_metrics(),
 			Endpoint::GetClusterStatus => handle_get_cluster_status(&self.garage).await,
 			Endpoint::GetClusterHealth => handle_get_cluster_health(&self.garage).await,
+			Endpoint::GetNodeVersions => handle_get_node_versions(&self.garage).await,
 			Endpoint::ConnectClusterNodes => handle_connect_cluster_nodes(&self.garage, req).await,
+			Endpoint::ReconnectClusterNode { id } => {
+				handle_reconnect_cluster_node(&self.garage, id).await
+			}
+			Endpoint::GetRecentS3Errors {
+				error_code,
+				access_key_id,
+			} => handle_get_recent_s3_errors(&self.s3_recent_errors, error_code, access_key_id),
+			Endpoint::GetS3Bandwidth => handle_get_s3_bandwidth(&self.s3_bandwidth),
+			Endpoint::GetUsageHistory { period_secs } => {
+				handle_get_usage_history(&self.garage, period_secs)
+			}
+			Endpoint::UpdateS3Bandwidth => {
+				handle_update_s3_bandwidth(&self.s3_bandwidth, req).await
+			}
+			Endpoint::GetWorkerVariable { variable } => {
+				handle_get_worker_variable(&self.garage, variable)
+			}
+			Endpoint::SetWorkerVariable => handle_set_worker_variable(&self.garage, req).await,
 			// Layout
 			Endpoint::GetClusterLayout => handle_get_cluster_layout(&self.garage).await,
 			Endpoint::UpdateClusterLayout => handle_update_cluster_layout(&self.garage, req).await,
 			Endpoint::ApplyClusterLayout => handle_apply_cluster_layout(&self.garage, req).await,
+			Endpoint::PreviewClusterLayoutChanges => {
+				handle_preview_cluster_layout_changes(&self.garage).await
+			}
 			Endpoint::RevertClusterLayout => handle_revert_cluster_layout(&self.garage).await,
+			Endpoint::GetClusterPlacement { include_partitions } => {
+				let include_partitions = include_partitions.map(|x| x == "true").unwrap_or(false);
+				handle_get_cluster_placement(&self.garage, include_partitions).await
+			}
 			// Keys
-			Endpoint::ListKeys => handle_list_keys(&self.garage).await,
+			Endpoint::ListKeys { sort } => handle_list_keys(&self.garage, sort).await,
 			Endpoint::GetKeyInfo {
 				id,
 				search,
@@ -290,15 +343,65 @@ impl ApiHandler for AdminApiServer {
 			Endpoint::CreateKey => handle_create_key(&self.garage, req).await,
 			Endpoint::ImportKey => handle_import_key(&self.garage, req).await,
 			Endpoint::UpdateKey { id } => handle_update_key(&self.garage, id, req).await,
+			Endpoint::RotateKeySecret { id } => {
+				handle_rotate_key_secret(&self.garage, id, req).await
+			}
 			Endpoint::DeleteKey { id } => handle_delete_key(&self.garage, id).await,
 			// Buckets
 			Endpoint::ListBuckets => handle_list_buckets(&self.garage).await,
+			Endpoint::FindOrphanBuckets => handle_find_orphan_buckets(&self.garage).await,
 			Endpoint::GetBucketInfo { id, global_alias } => {
 				handle_get_bucket_info(&self.garage, id, global_alias).await
 			}
 			Endpoint::CreateBucket => handle_create_bucket(&self.garage, req).await,
 			Endpoint::DeleteBucket { id } => handle_delete_bucket(&self.garage, id).await,
 			Endpoint::UpdateBucket { id } => handle_update_bucket(&self.garage, id, req).await,
+			Endpoint::DeletePrefix => handle_delete_prefix(&self.garage, req).await,
+			Endpoint::GetDeletePrefixJob { job_id } => {
+				handle_get_delete_prefix_job(&self.garage, job_id).await
+			}
+			Endpoint::CancelDeletePrefixJob { job_id } => {
+				handle_cancel_delete_prefix_job(&self.garage, job_id).await
+			}
+			Endpoint::CopyBucketContents => handle_copy_bucket_contents(&self.garage, req).await,
+			Endpoint::GetCopyBucketContentsJob { job_id } => {
+				handle_get_copy_bucket_contents_job(&self.garage, job_id).await
+			}
+			Endpoint::CancelCopyBucketContentsJob { job_id } => {
+				handle_cancel_copy_bucket_contents_job(&self.garage, job_id).await
+			}
+			Endpoint::ImportFromS3 => handle_import_from_s3(&self.s3_import_jobs, req).await,
+			Endpoint::GetImportFromS3Job { job_id } => {
+				handle_get_import_from_s3_job(&self.s3_import_jobs, job_id)
+			}
+			Endpoint::CancelImportFromS3Job { job_id } => {
+				handle_cancel_import_from_s3_job(&self.s3_import_jobs, job_id)
+			}
+			Endpoint::RestorePreviousVersion => {
+				handle_restore_previous_version(&self.garage, req).await
+			}
+			Endpoint::ListIncompleteUploads { id } => {
+				handle_list_incomplete_uploads(&self.garage, id).await
+			}
+			Endpoint::VerifyBucketCounters => {
+				handle_verify_bucket_counters(&self.garage, req).await
+			}
+			Endpoint::DumpBucketObjects {
+				id,
+				format,
+				all_versions,
+				start_after,
+			} => {
+				handle_dump_bucket_objects(
+					&self.garage,
+					&req,
+					id,
+					format,
+					all_versions,
+					start_after,
+				)
+				.await
+			}
 			// Bucket-key permissions
 			Endpoint::BucketAllowKey => {
 				handle_bucket_change_key_perm(&self.garage, req, true).await
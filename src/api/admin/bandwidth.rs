@@ -0,0 +1,65 @@
+use hyper::{body::Incoming as IncomingBody, Request, Response};
+use serde::{Deserialize, Serialize};
+
+use crate::admin::api_server::ResBody;
+use crate::admin::error::*;
+use crate::helpers::{json_ok_response, parse_json_body};
+use crate::s3::bandwidth::*;
+
+pub fn handle_get_s3_bandwidth(limiter: &BandwidthLimiter) -> Result<Response<ResBody>, Error> {
+	Ok(json_ok_response(&bandwidth_info(limiter))?)
+}
+
+pub async fn handle_update_s3_bandwidth(
+	limiter: &BandwidthLimiter,
+	req: Request<IncomingBody>,
+) -> Result<Response<ResBody>, Error> {
+	let req = parse_json_body::<UpdateS3BandwidthRequest, _, Error>(req).await?;
+
+	if req.global_limit == Some(0) || req.connection_limit == Some(0) {
+		return Err(Error::bad_request(
+			"Bandwidth limits must be positive, use null to remove a limit",
+		));
+	}
+
+	limiter.set_limits(BandwidthLimits {
+		global: req.global_limit,
+		per_connection: req.connection_limit,
+	});
+
+	Ok(json_ok_response(&bandwidth_info(limiter))?)
+}
+
+fn bandwidth_info(limiter: &BandwidthLimiter) -> S3BandwidthResponse {
+	let limits = limiter.limits();
+	S3BandwidthResponse {
+		global_limit: limits.global,
+		connection_limit: limits.per_connection,
+		throughput: S3Throughput {
+			incoming: limiter.throughput(Direction::In),
+			outgoing: limiter.throughput(Direction::Out),
+		},
+	}
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UpdateS3BandwidthRequest {
+	global_limit: Option<u64>,
+	connection_limit: Option<u64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct S3BandwidthResponse {
+	global_limit: Option<u64>,
+	connection_limit: Option<u64>,
+	throughput: S3Throughput,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct S3Throughput {
+	incoming: u64,
+	outgoing: u64,
+}
@@ -1,11 +1,19 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::pin::Pin;
 use std::sync::Arc;
 
+use async_compression::tokio::bufread::ZstdEncoder;
+use bytes::Bytes;
+use futures::stream::{Stream, StreamExt};
+use http::header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE};
 use hyper::{body::Incoming as IncomingBody, Request, Response, StatusCode};
 use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::io::{ReaderStream, StreamReader};
 
-use garage_util::crdt::*;
 use garage_util::data::*;
+use garage_util::error::Error as GarageError;
 use garage_util::time::*;
 
 use garage_table::*;
@@ -14,14 +22,19 @@ use garage_model::bucket_alias_table::*;
 use garage_model::bucket_table::*;
 use garage_model::garage::Garage;
 use garage_model::permission::*;
+use garage_model::s3::copy_bucket::*;
+use garage_model::s3::counter_check::*;
+use garage_model::s3::delete_prefix::*;
 use garage_model::s3::mpu_table;
 use garage_model::s3::object_table::*;
+use garage_model::s3::previous_version::restore_previous_version;
 
 use crate::admin::api_server::ResBody;
 use crate::admin::error::*;
 use crate::admin::key::ApiBucketKeyPerm;
 use crate::common_error::CommonError;
 use crate::helpers::*;
+use crate::s3::import::*;
 
 pub async fn handle_list_buckets(garage: &Arc<Garage>) -> Result<Response<ResBody>, Error> {
 	let buckets = garage
@@ -80,11 +93,72 @@ struct BucketLocalAlias {
 	alias: String,
 }
 
+pub async fn handle_find_orphan_buckets(garage: &Arc<Garage>) -> Result<Response<ResBody>, Error> {
+	let buckets = garage.bucket_helper().list_orphan_buckets().await?;
+
+	let mut res = Vec::with_capacity(buckets.len());
+	for bucket in buckets {
+		let counters = garage
+			.object_counter_table
+			.table
+			.get(&bucket.id, &EmptyKey)
+			.await?
+			.map(|x| x.filtered_values(&garage.system.cluster_layout()))
+			.unwrap_or_default();
+
+		let state = bucket.state.as_option().unwrap();
+		res.push(OrphanBucketResultItem {
+			id: hex::encode(bucket.id),
+			global_aliases: state
+				.aliases
+				.items()
+				.iter()
+				.filter(|(_, _, a)| *a)
+				.map(|(n, _, _)| n.to_string())
+				.collect::<Vec<_>>(),
+			local_aliases: state
+				.local_aliases
+				.items()
+				.iter()
+				.filter(|(_, _, a)| *a)
+				.map(|((k, n), _, _)| BucketLocalAlias {
+					access_key_id: k.to_string(),
+					alias: n.to_string(),
+				})
+				.collect::<Vec<_>>(),
+			objects: *counters.get(OBJECTS).unwrap_or(&0),
+			bytes: *counters.get(BYTES).unwrap_or(&0),
+		});
+	}
+
+	Ok(json_ok_response(&res)?)
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OrphanBucketResultItem {
+	id: String,
+	/// Aliases recorded in the bucket, which don't resolve to it
+	global_aliases: Vec<String>,
+	local_aliases: Vec<BucketLocalAlias>,
+	objects: i64,
+	bytes: i64,
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct ApiBucketQuotas {
 	max_size: Option<u64>,
 	max_objects: Option<u64>,
+	max_multipart_uploads: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ApiBucketUsageAlerts {
+	quota_percent: Option<u8>,
+	max_size: Option<u64>,
+	max_objects: Option<u64>,
 }
 
 pub async fn handle_get_bucket_info(
@@ -175,6 +249,7 @@ async fn bucket_info_results(
 	let state = bucket.state.as_option().unwrap();
 
 	let quotas = state.quotas.get();
+	let usage_alerts = state.usage_alerts.get();
 	let res =
 		GetBucketInfoResult {
 			id: hex::encode(bucket.id),
@@ -199,11 +274,13 @@ async fn bucket_info_results(
 					GetBucketInfoKey {
 						access_key_id: key.key_id,
 						name: p.name.get().to_string(),
+						suspended: *p.suspended.get(),
 						permissions: p
 							.authorized_buckets
 							.get(&bucket.id)
 							.map(|p| ApiBucketKeyPerm {
 								read: p.allow_read,
+								list: p.can_list(),
 								write: p.allow_write,
 								owner: p.allow_owner,
 							})
@@ -227,7 +304,22 @@ async fn bucket_info_results(
 			quotas: ApiBucketQuotas {
 				max_size: quotas.max_size,
 				max_objects: quotas.max_objects,
+				max_multipart_uploads: quotas.max_multipart_uploads,
 			},
+			read_only: *state.read_only.get(),
+			previous_version_retention_secs: *state.previous_version_retention_secs.get(),
+			forbidden_as_not_found: *state.forbidden_as_not_found.get(),
+			replication_factor: *state.replication_factor.get(),
+			case_insensitive_keys: *state.case_insensitive_keys.get(),
+			usage_alerts: ApiBucketUsageAlerts {
+				quota_percent: usage_alerts.quota_percent,
+				max_size: usage_alerts.max_size,
+				max_objects: usage_alerts.max_objects,
+			},
+			resync_high_priority: *state.resync_high_priority.get(),
+			block_size: *state.block_size.get(),
+			allowed_methods: state.allowed_methods.get().clone(),
+			allow_degraded_reads: *state.allow_degraded_reads.get(),
 		};
 
 	Ok(json_ok_response(&res)?)
@@ -249,6 +341,16 @@ struct GetBucketInfoResult {
 	unfinished_multipart_upload_parts: i64,
 	unfinished_multipart_upload_bytes: i64,
 	quotas: ApiBucketQuotas,
+	read_only: bool,
+	previous_version_retention_secs: Option<u64>,
+	forbidden_as_not_found: bool,
+	replication_factor: Option<usize>,
+	case_insensitive_keys: bool,
+	usage_alerts: ApiBucketUsageAlerts,
+	resync_high_priority: bool,
+	block_size: Option<usize>,
+	allowed_methods: Option<Vec<String>>,
+	allow_degraded_reads: bool,
 }
 
 #[derive(Serialize)]
@@ -263,6 +365,7 @@ struct GetBucketInfoWebsiteResult {
 struct GetBucketInfoKey {
 	access_key_id: String,
 	name: String,
+	suspended: bool,
 	permissions: ApiBucketKeyPerm,
 	bucket_local_aliases: Vec<String>,
 }
@@ -271,12 +374,18 @@ pub async fn handle_create_bucket(
 	garage: &Arc<Garage>,
 	req: Request<IncomingBody>,
 ) -> Result<Response<ResBody>, Error> {
-	let req = parse_json_body::<CreateBucketRequest, _, Error>(req).await?;
+	let mut req = parse_json_body::<CreateBucketRequest, _, Error>(req).await?;
+	if let Some(ga) = &mut req.global_alias {
+		*ga = canonical_bucket_name(ga).into_owned();
+	}
+	if let Some(la) = &mut req.local_alias {
+		la.alias = canonical_bucket_name(&la.alias).into_owned();
+	}
 
 	let helper = garage.locked_helper().await;
 
 	if let Some(ga) = &req.global_alias {
-		if !is_valid_bucket_name(ga) {
+		if !is_valid_bucket_name(ga, garage.config.allow_punycode) {
 			return Err(Error::bad_request(format!(
 				"{}: {}",
 				ga, INVALID_BUCKET_NAME_MESSAGE
@@ -291,7 +400,7 @@ pub async fn handle_create_bucket(
 	}
 
 	if let Some(la) = &req.local_alias {
-		if !is_valid_bucket_name(&la.alias) {
+		if !is_valid_bucket_name(&la.alias, garage.config.allow_punycode) {
 			return Err(Error::bad_request(format!(
 				"{}: {}",
 				la.alias, INVALID_BUCKET_NAME_MESSAGE
@@ -317,7 +426,7 @@ pub async fn handle_create_bucket(
 			.set_local_bucket_alias(bucket.id, &la.access_key_id, &la.alias)
 			.await?;
 
-		if la.allow.read || la.allow.write || la.allow.owner {
+		if la.allow.read || la.allow.write || la.allow.owner || la.allow.list {
 			helper
 				.set_bucket_key_permissions(
 					bucket.id,
@@ -327,6 +436,7 @@ pub async fn handle_create_bucket(
 						allow_read: la.allow.read,
 						allow_write: la.allow.write,
 						allow_owner: la.allow.owner,
+						allow_list: if la.allow.list { Some(true) } else { None },
 					},
 				)
 				.await?;
@@ -360,8 +470,7 @@ pub async fn handle_delete_bucket(
 
 	let bucket_id = parse_bucket_id(&id)?;
 
-	let mut bucket = helper.bucket().get_existing_bucket(bucket_id).await?;
-	let state = bucket.state.as_option().unwrap();
+	helper.bucket().get_existing_bucket(bucket_id).await?;
 
 	// Check bucket is empty
 	if !helper.bucket().is_bucket_empty(bucket_id).await? {
@@ -369,32 +478,7 @@ pub async fn handle_delete_bucket(
 	}
 
 	// --- done checking, now commit ---
-	// 1. delete authorization from keys that had access
-	for (key_id, perm) in bucket.authorized_keys() {
-		if perm.is_any() {
-			helper
-				.set_bucket_key_permissions(bucket.id, key_id, BucketKeyPerm::NO_PERMISSIONS)
-				.await?;
-		}
-	}
-	// 2. delete all local aliases
-	for ((key_id, alias), _, active) in state.local_aliases.items().iter() {
-		if *active {
-			helper
-				.unset_local_bucket_alias(bucket.id, key_id, alias)
-				.await?;
-		}
-	}
-	// 3. delete all global aliases
-	for (alias, _, active) in state.aliases.items().iter() {
-		if *active {
-			helper.purge_global_bucket_alias(bucket.id, alias).await?;
-		}
-	}
-
-	// 4. delete bucket
-	bucket.state = Deletable::delete();
-	garage.bucket_table.insert(&bucket).await?;
+	helper.delete_bucket(bucket_id).await?;
 
 	Ok(Response::builder()
 		.status(StatusCode::NO_CONTENT)
@@ -438,9 +522,83 @@ pub async fn handle_update_bucket(
 		state.quotas.update(BucketQuotas {
 			max_size: q.max_size,
 			max_objects: q.max_objects,
+			max_multipart_uploads: q.max_multipart_uploads,
+		});
+	}
+
+	if let Some(ro) = req.read_only {
+		state.read_only.update(ro);
+	}
+
+	if let Some(secs) = req.previous_version_retention_secs {
+		state
+			.previous_version_retention_secs
+			.update(Some(secs).filter(|s| *s > 0));
+	}
+
+	if let Some(nf) = req.forbidden_as_not_found {
+		state.forbidden_as_not_found.update(nf);
+	}
+
+	if let Some(rf) = req.replication_factor {
+		let cluster_rf = garage.replication_factor.replication_factor();
+		if rf > cluster_rf {
+			return Err(Error::bad_request(format!(
+				"replicationFactor cannot be more than the cluster's replication factor ({})",
+				cluster_rf
+			)));
+		}
+		state
+			.replication_factor
+			.update(Some(rf).filter(|n| *n > 0 && *n < cluster_rf));
+	}
+
+	if let Some(ci) = req.case_insensitive_keys {
+		if ci != *state.case_insensitive_keys.get()
+			&& !garage.bucket_helper().is_bucket_empty(bucket_id).await?
+		{
+			return Err(Error::bad_request(
+				"caseInsensitiveKeys can only be changed on empty buckets",
+			));
+		}
+		state.case_insensitive_keys.update(ci);
+	}
+
+	if let Some(ua) = req.usage_alerts {
+		if ua.quota_percent.map(|p| p == 0 || p > 100).unwrap_or(false) {
+			return Err(Error::bad_request(
+				"usageAlerts.quotaPercent must be between 1 and 100",
+			));
+		}
+		state.usage_alerts.update(BucketUsageAlerts {
+			quota_percent: ua.quota_percent,
+			max_size: ua.max_size,
+			max_objects: ua.max_objects,
 		});
 	}
 
+	if let Some(hp) = req.resync_high_priority {
+		state.resync_high_priority.update(hp);
+	}
+
+	if let Some(bs) = req.block_size {
+		if bs > 0 {
+			check_bucket_block_size(bs)
+				.map_err(|e| Error::bad_request(format!("Invalid blockSize: {}", e)))?;
+		}
+		state.block_size.update(Some(bs).filter(|n| *n > 0));
+	}
+
+	if let Some(methods) = req.allowed_methods {
+		let methods = parse_bucket_allowed_methods(&methods)
+			.map_err(|e| Error::bad_request(format!("Invalid allowedMethods: {}", e)))?;
+		state.allowed_methods.update(methods);
+	}
+
+	if let Some(dr) = req.allow_degraded_reads {
+		state.allow_degraded_reads.update(dr);
+	}
+
 	garage.bucket_table.insert(&bucket).await?;
 
 	bucket_info_results(garage, bucket_id).await
@@ -451,6 +609,16 @@ pub async fn handle_update_bucket(
 struct UpdateBucketRequest {
 	website_access: Option<UpdateBucketWebsiteAccess>,
 	quotas: Option<ApiBucketQuotas>,
+	read_only: Option<bool>,
+	previous_version_retention_secs: Option<u64>,
+	forbidden_as_not_found: Option<bool>,
+	replication_factor: Option<usize>,
+	case_insensitive_keys: Option<bool>,
+	usage_alerts: Option<ApiBucketUsageAlerts>,
+	resync_high_priority: Option<bool>,
+	block_size: Option<usize>,
+	allowed_methods: Option<Vec<String>>,
+	allow_degraded_reads: Option<bool>,
 }
 
 #[derive(Deserialize)]
@@ -494,6 +662,9 @@ pub async fn handle_bucket_change_key_perm(
 	if req.permissions.owner {
 		perm.allow_owner = new_perm_flag;
 	}
+	if req.permissions.list {
+		perm.allow_list = Some(new_perm_flag);
+	}
 
 	helper
 		.set_bucket_key_permissions(bucket.id, &key.key_id, perm)
@@ -574,9 +745,823 @@ pub async fn handle_local_unalias_bucket(
 	bucket_info_results(garage, bucket_id).await
 }
 
+// ---- DELETE PREFIX ----
+
+pub async fn handle_delete_prefix(
+	garage: &Arc<Garage>,
+	req: Request<IncomingBody>,
+) -> Result<Response<ResBody>, Error> {
+	let req = parse_json_body::<DeletePrefixRequest, _, Error>(req).await?;
+	let bucket_id = parse_bucket_id(&req.bucket_id)?;
+
+	let status = garage
+		.delete_prefix_jobs
+		.start(
+			garage.clone(),
+			bucket_id,
+			req.prefix,
+			req.dry_run,
+			req.allow_empty_prefix,
+		)
+		.await?;
+
+	Ok(json_ok_response(&DeletePrefixJobResponse::from(status))?)
+}
+
+pub async fn handle_get_delete_prefix_job(
+	garage: &Arc<Garage>,
+	job_id: String,
+) -> Result<Response<ResBody>, Error> {
+	let status = garage
+		.delete_prefix_jobs
+		.get(&parse_job_id(&job_id)?)
+		.ok_or_else(|| Error::NoSuchDeletePrefixJob(job_id))?;
+
+	Ok(json_ok_response(&DeletePrefixJobResponse::from(status))?)
+}
+
+pub async fn handle_cancel_delete_prefix_job(
+	garage: &Arc<Garage>,
+	job_id: String,
+) -> Result<Response<ResBody>, Error> {
+	let status = garage
+		.delete_prefix_jobs
+		.cancel(&parse_job_id(&job_id)?)
+		.ok_or_else(|| Error::NoSuchDeletePrefixJob(job_id))?;
+
+	Ok(json_ok_response(&DeletePrefixJobResponse::from(status))?)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DeletePrefixRequest {
+	bucket_id: String,
+	prefix: String,
+	#[serde(default)]
+	dry_run: bool,
+	#[serde(default)]
+	allow_empty_prefix: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DeletePrefixJobResponse {
+	job_id: String,
+	bucket_id: String,
+	prefix: String,
+	dry_run: bool,
+	state: String,
+	error: Option<String>,
+	objects_processed: u64,
+	bytes_freed: u64,
+	started_at: String,
+	finished_at: Option<String>,
+}
+
+impl From<DeletePrefixJobStatus> for DeletePrefixJobResponse {
+	fn from(s: DeletePrefixJobStatus) -> Self {
+		Self {
+			job_id: hex::encode(s.job_id),
+			bucket_id: hex::encode(s.bucket_id),
+			prefix: s.prefix,
+			dry_run: s.dry_run,
+			state: s.state.to_string(),
+			error: s.error,
+			objects_processed: s.objects_processed,
+			bytes_freed: s.bytes_freed,
+			started_at: msec_to_rfc3339(s.started_at),
+			finished_at: s.finished_at.map(msec_to_rfc3339),
+		}
+	}
+}
+
+// ---- COPY BUCKET CONTENTS ----
+
+pub async fn handle_copy_bucket_contents(
+	garage: &Arc<Garage>,
+	req: Request<IncomingBody>,
+) -> Result<Response<ResBody>, Error> {
+	let req = parse_json_body::<CopyBucketContentsRequest, _, Error>(req).await?;
+	let source_bucket_id = parse_bucket_id(&req.source_bucket_id)?;
+	let dest_bucket_id = parse_bucket_id(&req.dest_bucket_id)?;
+
+	let status = garage
+		.copy_bucket_jobs
+		.start(
+			garage.clone(),
+			source_bucket_id,
+			dest_bucket_id,
+			req.prefix,
+			req.overwrite,
+			req.max_objects_per_sec,
+		)
+		.await?;
+
+	Ok(json_ok_response(&CopyBucketContentsJobResponse::from(
+		status,
+	))?)
+}
+
+pub async fn handle_get_copy_bucket_contents_job(
+	garage: &Arc<Garage>,
+	job_id: String,
+) -> Result<Response<ResBody>, Error> {
+	let status = garage
+		.copy_bucket_jobs
+		.get(&parse_job_id(&job_id)?)
+		.ok_or_else(|| Error::NoSuchCopyBucketJob(job_id))?;
+
+	Ok(json_ok_response(&CopyBucketContentsJobResponse::from(
+		status,
+	))?)
+}
+
+pub async fn handle_cancel_copy_bucket_contents_job(
+	garage: &Arc<Garage>,
+	job_id: String,
+) -> Result<Response<ResBody>, Error> {
+	let status = garage
+		.copy_bucket_jobs
+		.cancel(&parse_job_id(&job_id)?)
+		.ok_or_else(|| Error::NoSuchCopyBucketJob(job_id))?;
+
+	Ok(json_ok_response(&CopyBucketContentsJobResponse::from(
+		status,
+	))?)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CopyBucketContentsRequest {
+	source_bucket_id: String,
+	dest_bucket_id: String,
+	#[serde(default)]
+	prefix: String,
+	#[serde(default)]
+	overwrite: bool,
+	max_objects_per_sec: Option<u64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CopyBucketContentsJobResponse {
+	job_id: String,
+	source_bucket_id: String,
+	dest_bucket_id: String,
+	prefix: String,
+	overwrite: bool,
+	max_objects_per_sec: Option<u64>,
+	state: String,
+	error: Option<String>,
+	last_key: Option<String>,
+	objects_copied: u64,
+	objects_skipped: u64,
+	bytes_copied: u64,
+	objects_total: u64,
+	estimated_seconds_remaining: Option<u64>,
+	started_at: String,
+	finished_at: Option<String>,
+}
+
+impl From<CopyBucketJobStatus> for CopyBucketContentsJobResponse {
+	fn from(s: CopyBucketJobStatus) -> Self {
+		Self {
+			job_id: hex::encode(s.job_id),
+			source_bucket_id: hex::encode(s.source_bucket_id),
+			dest_bucket_id: hex::encode(s.dest_bucket_id),
+			estimated_seconds_remaining: s.eta_secs(now_msec()),
+			prefix: s.prefix,
+			overwrite: s.overwrite,
+			max_objects_per_sec: s.max_objects_per_sec,
+			state: s.state.to_string(),
+			error: s.error,
+			last_key: s.last_key,
+			objects_copied: s.objects_copied,
+			objects_skipped: s.objects_skipped,
+			bytes_copied: s.bytes_copied,
+			objects_total: s.objects_total,
+			started_at: msec_to_rfc3339(s.started_at),
+			finished_at: s.finished_at.map(msec_to_rfc3339),
+		}
+	}
+}
+
+// ---- IMPORT FROM S3 ----
+
+pub async fn handle_import_from_s3(
+	import_jobs: &Arc<ImportJobs>,
+	req: Request<IncomingBody>,
+) -> Result<Response<ResBody>, Error> {
+	let req = parse_json_body::<ImportFromS3Request, _, Error>(req).await?;
+	let conflict_policy = match &req.conflict_policy {
+		None => ImportConflictPolicy::SkipIdentical,
+		Some(p) => ImportConflictPolicy::parse(p).ok_or_bad_request(
+			"Invalid conflict policy, expected \"skipIdentical\" or \"overwrite\"",
+		)?,
+	};
+
+	let credentials = match (req.credentials, req.credentials_name) {
+		(Some(c), None) => ImportCredentialsSource::Literal(ImportCredentials {
+			access_key_id: c.access_key_id,
+			secret_access_key: c.secret_access_key,
+		}),
+		(None, Some(name)) => ImportCredentialsSource::Named(name),
+		_ => {
+			return Err(Error::bad_request(
+				"Exactly one of credentials and credentialsName must be given",
+			))
+		}
+	};
+
+	let status = import_jobs
+		.start(ImportParams {
+			dest_bucket_id: parse_bucket_id(&req.dest_bucket_id)?,
+			source_endpoint: req.source_endpoint,
+			source_region: req.source_region,
+			source_bucket: req.source_bucket,
+			credentials,
+			prefix: req.prefix,
+			conflict_policy,
+			preserve_multipart: req.preserve_multipart,
+			concurrency: req.concurrency.unwrap_or(IMPORT_DEFAULT_CONCURRENCY),
+		})
+		.await?;
+
+	Ok(json_ok_response(&ImportFromS3JobResponse::from(status))?)
+}
+
+pub fn handle_get_import_from_s3_job(
+	import_jobs: &ImportJobs,
+	job_id: String,
+) -> Result<Response<ResBody>, Error> {
+	let status = import_jobs
+		.get(&parse_job_id(&job_id)?)
+		.ok_or_else(|| Error::NoSuchImportJob(job_id))?;
+
+	Ok(json_ok_response(&ImportFromS3JobResponse::from(status))?)
+}
+
+pub fn handle_cancel_import_from_s3_job(
+	import_jobs: &ImportJobs,
+	job_id: String,
+) -> Result<Response<ResBody>, Error> {
+	let status = import_jobs
+		.cancel(&parse_job_id(&job_id)?)
+		.ok_or_else(|| Error::NoSuchImportJob(job_id))?;
+
+	Ok(json_ok_response(&ImportFromS3JobResponse::from(status))?)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ImportFromS3Request {
+	dest_bucket_id: String,
+	source_endpoint: String,
+	#[serde(default = "default_import_region")]
+	source_region: String,
+	source_bucket: String,
+	credentials: Option<ImportFromS3Credentials>,
+	credentials_name: Option<String>,
+	#[serde(default)]
+	prefix: String,
+	conflict_policy: Option<String>,
+	#[serde(default)]
+	preserve_multipart: bool,
+	concurrency: Option<usize>,
+}
+
+fn default_import_region() -> String {
+	"us-east-1".into()
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ImportFromS3Credentials {
+	access_key_id: String,
+	secret_access_key: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ImportFromS3JobResponse {
+	job_id: String,
+	dest_bucket_id: String,
+	source_endpoint: String,
+	source_region: String,
+	source_bucket: String,
+	credentials_name: Option<String>,
+	prefix: String,
+	conflict_policy: String,
+	preserve_multipart: bool,
+	concurrency: usize,
+	state: String,
+	error: Option<String>,
+	cursor: Option<String>,
+	objects_imported: u64,
+	objects_skipped: u64,
+	objects_failed: u64,
+	bytes_imported: u64,
+	bytes_skipped: u64,
+	objects_total: Option<u64>,
+	bytes_total: Option<u64>,
+	estimated_seconds_remaining: Option<u64>,
+	failures: Vec<ImportFromS3FailureResponse>,
+	started_at: String,
+	finished_at: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ImportFromS3FailureResponse {
+	key: String,
+	error: String,
+}
+
+impl From<ImportJobStatus> for ImportFromS3JobResponse {
+	fn from(s: ImportJobStatus) -> Self {
+		// Only the name of credentials from the configuration is returned
+		Self {
+			job_id: hex::encode(s.job_id),
+			dest_bucket_id: hex::encode(s.dest_bucket_id),
+			estimated_seconds_remaining: s.eta_secs(now_msec()),
+			source_endpoint: s.source_endpoint,
+			source_region: s.source_region,
+			source_bucket: s.source_bucket,
+			credentials_name: s.credentials_name,
+			prefix: s.prefix,
+			conflict_policy: s.conflict_policy.to_string(),
+			preserve_multipart: s.preserve_multipart,
+			concurrency: s.concurrency,
+			state: s.state.to_string(),
+			error: s.error,
+			cursor: s.cursor,
+			objects_imported: s.objects_imported,
+			objects_skipped: s.objects_skipped,
+			objects_failed: s.objects_failed,
+			bytes_imported: s.bytes_imported,
+			bytes_skipped: s.bytes_skipped,
+			objects_total: s.objects_total,
+			bytes_total: s.bytes_total,
+			failures: s
+				.failures
+				.into_iter()
+				.map(|f| ImportFromS3FailureResponse {
+					key: f.key,
+					error: f.error,
+				})
+				.collect(),
+			started_at: msec_to_rfc3339(s.started_at),
+			finished_at: s.finished_at.map(msec_to_rfc3339),
+		}
+	}
+}
+
+// ---- VERIFY COUNTERS ----
+
+pub async fn handle_verify_bucket_counters(
+	garage: &Arc<Garage>,
+	req: Request<IncomingBody>,
+) -> Result<Response<ResBody>, Error> {
+	let req = parse_json_body::<VerifyBucketCountersRequest, _, Error>(req).await?;
+	let bucket_id = parse_bucket_id(&req.bucket_id)?;
+	garage
+		.bucket_helper()
+		.get_existing_bucket(bucket_id)
+		.await?;
+
+	let report = garage
+		.object_counter_checker
+		.check_bucket(bucket_id, req.fix)
+		.await?;
+
+	Ok(json_ok_response(&VerifyBucketCountersResponse::from(
+		report,
+	))?)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct VerifyBucketCountersRequest {
+	bucket_id: String,
+	#[serde(default)]
+	fix: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct VerifyBucketCountersResponse {
+	bucket_id: String,
+	counters: BTreeMap<String, i64>,
+	drift: bool,
+	nodes: Vec<VerifyBucketCountersNode>,
+	errors: Vec<VerifyBucketCountersError>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct VerifyBucketCountersNode {
+	node_id: String,
+	counters: BTreeMap<String, i64>,
+	actual: BTreeMap<String, i64>,
+	drift: BTreeMap<String, i64>,
+	fixed: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct VerifyBucketCountersError {
+	node_id: String,
+	error: String,
+}
+
+impl From<CounterCheckReport> for VerifyBucketCountersResponse {
+	fn from(r: CounterCheckReport) -> Self {
+		Self {
+			bucket_id: hex::encode(r.bucket_id),
+			drift: r.has_drift(),
+			counters: r.counters,
+			nodes: r
+				.nodes
+				.into_iter()
+				.map(|n| VerifyBucketCountersNode {
+					node_id: hex::encode(n.node),
+					drift: n.drift(),
+					counters: n.counters,
+					actual: n.actual,
+					fixed: n.fixed,
+				})
+				.collect(),
+			errors: r
+				.errors
+				.into_iter()
+				.map(|(node, error)| VerifyBucketCountersError {
+					node_id: hex::encode(node),
+					error,
+				})
+				.collect(),
+		}
+	}
+}
+
+// ---- INCOMPLETE UPLOADS ----
+
+pub async fn handle_list_incomplete_uploads(
+	garage: &Arc<Garage>,
+	id: String,
+) -> Result<Response<ResBody>, Error> {
+	let bucket_id = parse_bucket_id(&id)?;
+	garage
+		.bucket_helper()
+		.get_existing_bucket(bucket_id)
+		.await?;
+
+	let mut res = vec![];
+	let mut start = None;
+	loop {
+		let objects = garage
+			.object_table
+			.get_range(
+				&bucket_id,
+				start,
+				Some(ObjectFilter::IsUploading {
+					check_multipart: Some(true),
+				}),
+				1000,
+				EnumerationOrder::Forward,
+			)
+			.await?;
+
+		for object in objects.iter() {
+			for version in object.versions().iter() {
+				if !version.is_uploading(Some(true)) {
+					continue;
+				}
+				let mpu = garage.mpu_table.get(&version.uuid, &EmptyKey).await?;
+				res.push(IncompleteUploadResultItem {
+					key: object.key.clone(),
+					upload_id: hex::encode(version.uuid),
+					initiated: msec_to_rfc3339(version.timestamp),
+					last_activity: msec_to_rfc3339(
+						mpu.as_ref()
+							.map(|m| m.last_activity)
+							.unwrap_or(version.timestamp),
+					),
+					parts: mpu.map(|m| m.parts.items().len()).unwrap_or(0),
+				});
+			}
+		}
+
+		if objects.len() < 1000 {
+			break;
+		} else {
+			start = Some(objects.last().unwrap().key.clone());
+		}
+	}
+
+	Ok(json_ok_response(&res)?)
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct IncompleteUploadResultItem {
+	key: String,
+	upload_id: String,
+	initiated: String,
+	last_activity: String,
+	parts: usize,
+}
+
+// ---- OBJECT LISTING DUMP ----
+
+/// Number of objects read from the object table at once when dumping
+/// the object listing of a bucket
+const DUMP_PAGE_SIZE: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DumpFormat {
+	Csv,
+	Jsonl,
+}
+
+pub async fn handle_dump_bucket_objects(
+	garage: &Arc<Garage>,
+	req: &Request<IncomingBody>,
+	id: String,
+	format: Option<String>,
+	all_versions: Option<String>,
+	start_after: Option<String>,
+) -> Result<Response<ResBody>, Error> {
+	let bucket_id = parse_bucket_id(&id)?;
+	garage
+		.bucket_helper()
+		.get_existing_bucket(bucket_id)
+		.await?;
+
+	let (format, content_type) = match format.as_deref() {
+		None | Some("csv") => (DumpFormat::Csv, "text/csv"),
+		Some("jsonl") => (DumpFormat::Jsonl, "application/x-ndjson"),
+		Some(f) => return Err(Error::bad_request(format!("Invalid dump format: {}", f))),
+	};
+	let all_versions = all_versions.map(|x| x == "true").unwrap_or(false);
+	let compress = req
+		.headers()
+		.get(ACCEPT_ENCODING)
+		.and_then(|v| v.to_str().ok())
+		.map(accepts_zstd)
+		.unwrap_or(false);
+
+	// The listing is produced by a background task that reads the object
+	// table page by page, the bounded channel makes it wait for the client
+	// to read the previous pages
+	let (tx, rx) = mpsc::channel(2);
+	tokio::spawn(dump_bucket_objects(
+		garage.clone(),
+		bucket_id,
+		format,
+		all_versions,
+		start_after,
+		tx,
+	));
+
+	let stream = ReceiverStream::new(rx);
+	let mut resp = Response::builder()
+		.status(StatusCode::OK)
+		.header(CONTENT_TYPE, content_type);
+	let body_stream: Pin<Box<dyn Stream<Item = std::io::Result<Bytes>> + Send + Sync>> = if compress
+	{
+		resp = resp.header(CONTENT_ENCODING, "zstd");
+		Box::pin(ReaderStream::new(ZstdEncoder::new(StreamReader::new(
+			stream,
+		))))
+	} else {
+		Box::pin(stream)
+	};
+	let body_stream = body_stream.map(|x| {
+		x.map(hyper::body::Frame::data)
+			.map_err(|e| Error::from(GarageError::from(e)))
+	});
+
+	Ok(resp.body(ResBody::new(http_body_util::StreamBody::new(body_stream)))?)
+}
+
+/// Whether the value of an Accept-Encoding header allows a zstd-compressed response
+fn accepts_zstd(accept_encoding: &str) -> bool {
+	accept_encoding.split(',').any(|item| {
+		let mut params = item.split(';');
+		let coding_ok = params
+			.next()
+			.map(|c| c.trim().eq_ignore_ascii_case("zstd"))
+			.unwrap_or(false);
+		let q_ok = params
+			.filter_map(|p| p.trim().strip_prefix("q="))
+			.all(|q| q.trim().parse::<f32>().map(|q| q > 0.0).unwrap_or(false));
+		coding_ok && q_ok
+	})
+}
+
+async fn dump_bucket_objects(
+	garage: Arc<Garage>,
+	bucket_id: Uuid,
+	format: DumpFormat,
+	all_versions: bool,
+	start_after: Option<String>,
+	tx: mpsc::Sender<std::io::Result<Bytes>>,
+) {
+	let mut buf = String::new();
+	if format == DumpFormat::Csv {
+		buf.push_str(if all_versions {
+			"key,versionId,state,size,etag,lastModified\n"
+		} else {
+			"key,size,etag,lastModified\n"
+		});
+	}
+
+	// Each page is read with a new range query starting at the last key of
+	// the previous one, so that no iterator on the database is kept open
+	// while the client reads the listing
+	let mut start = start_after;
+	loop {
+		let filter = if all_versions {
+			None
+		} else {
+			Some(ObjectFilter::IsData)
+		};
+		let objects = match garage
+			.object_table
+			.get_range(
+				&bucket_id,
+				start.clone(),
+				filter,
+				DUMP_PAGE_SIZE,
+				EnumerationOrder::Forward,
+			)
+			.await
+		{
+			Ok(objects) => objects,
+			Err(e) => {
+				// Interrupting the body makes the client see a truncated
+				// transfer, it can resume from the last key it received
+				warn!("Dump of objects of bucket {:?} failed: {}", bucket_id, e);
+				let _ = tx
+					.send(Err(std::io::Error::new(
+						std::io::ErrorKind::Other,
+						e.to_string(),
+					)))
+					.await;
+				return;
+			}
+		};
+
+		for object in objects.iter() {
+			// The range starts at the given key included
+			if Some(&object.key) == start.as_ref() {
+				continue;
+			}
+			for version in object.versions().iter() {
+				let meta = match &version.state {
+					ObjectVersionState::Complete(ObjectVersionData::Inline(meta, _))
+					| ObjectVersionState::Complete(ObjectVersionData::FirstBlock(meta, _)) => Some(meta),
+					_ => None,
+				};
+				// Objects whose TTL has elapsed are hidden until they are deleted
+				let is_current = meta.map(|m| !m.is_expired()).unwrap_or(false);
+				if !all_versions && !is_current {
+					continue;
+				}
+				let row = DumpRow {
+					key: &object.key,
+					version_id: all_versions.then(|| hex::encode(version.uuid)),
+					state: all_versions.then(|| dump_version_state(&version.state)),
+					size: meta.map(|m| m.size),
+					etag: meta.map(|m| m.etag.as_str()),
+					last_modified: msec_to_rfc3339(version.timestamp),
+				};
+				match format {
+					DumpFormat::Csv => row.write_csv(&mut buf),
+					DumpFormat::Jsonl => {
+						buf.push_str(&serde_json::to_string(&row).unwrap());
+						buf.push('\n');
+					}
+				}
+			}
+		}
+
+		// An error means that the client went away
+		if !buf.is_empty()
+			&& tx
+				.send(Ok(Bytes::from(std::mem::take(&mut buf))))
+				.await
+				.is_err()
+		{
+			return;
+		}
+
+		if objects.len() < DUMP_PAGE_SIZE {
+			break;
+		}
+		start = Some(objects.last().unwrap().key.clone());
+	}
+}
+
+fn dump_version_state(state: &ObjectVersionState) -> &'static str {
+	match state {
+		ObjectVersionState::Uploading { .. } => "uploading",
+		ObjectVersionState::Complete(ObjectVersionData::DeleteMarker) => "deleteMarker",
+		ObjectVersionState::Complete(_) => "complete",
+		ObjectVersionState::Aborted => "aborted",
+	}
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DumpRow<'a> {
+	key: &'a str,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	version_id: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	state: Option<&'static str>,
+	size: Option<u64>,
+	etag: Option<&'a str>,
+	last_modified: String,
+}
+
+impl DumpRow<'_> {
+	fn write_csv(&self, buf: &mut String) {
+		push_csv_field(buf, self.key);
+		if let (Some(version_id), Some(state)) = (&self.version_id, self.state) {
+			buf.push(',');
+			buf.push_str(version_id);
+			buf.push(',');
+			buf.push_str(state);
+		}
+		buf.push(',');
+		if let Some(size) = self.size {
+			buf.push_str(&size.to_string());
+		}
+		buf.push(',');
+		if let Some(etag) = self.etag {
+			push_csv_field(buf, etag);
+		}
+		buf.push(',');
+		buf.push_str(&self.last_modified);
+		buf.push('\n');
+	}
+}
+
+/// Write a CSV field, quoted if it contains special characters
+fn push_csv_field(buf: &mut String, field: &str) {
+	if field.contains([',', '"', '\n', '\r']) {
+		buf.push('"');
+		buf.push_str(&field.replace('"', "\"\""));
+		buf.push('"');
+	} else {
+		buf.push_str(field);
+	}
+}
+
+// ---- PREVIOUS VERSIONS ----
+
+pub async fn handle_restore_previous_version(
+	garage: &Arc<Garage>,
+	req: Request<IncomingBody>,
+) -> Result<Response<ResBody>, Error> {
+	let req = parse_json_body::<RestorePreviousVersionRequest, _, Error>(req).await?;
+	let bucket_id = parse_bucket_id(&req.bucket_id)?;
+
+	let restored = restore_previous_version(garage, bucket_id, &req.key).await?;
+
+	Ok(json_ok_response(&RestorePreviousVersionResponse {
+		bucket_id: hex::encode(bucket_id),
+		key: req.key,
+		version_id: hex::encode(restored.uuid),
+		last_modified: msec_to_rfc3339(restored.timestamp),
+	})?)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RestorePreviousVersionRequest {
+	bucket_id: String,
+	key: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RestorePreviousVersionResponse {
+	bucket_id: String,
+	key: String,
+	version_id: String,
+	last_modified: String,
+}
+
 // ---- HELPER ----
 
 fn parse_bucket_id(id: &str) -> Result<Uuid, Error> {
 	let id_hex = hex::decode(id).ok_or_bad_request("Invalid bucket id")?;
 	Ok(Uuid::try_from(&id_hex).ok_or_bad_request("Invalid bucket id")?)
 }
+
+fn parse_job_id(id: &str) -> Result<Uuid, Error> {
+	let id_hex = hex::decode(id).ok_or_bad_request("Invalid job id")?;
+	Ok(Uuid::try_from(&id_hex).ok_or_bad_request("Invalid job id")?)
+}
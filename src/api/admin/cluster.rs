@@ -7,8 +7,12 @@ use serde::{Deserialize, Serialize};
 
 use garage_util::crdt::*;
 use garage_util::data::*;
+use garage_util::time::*;
 
 use garage_rpc::layout;
+use garage_rpc::peer_state::PeerState;
+use garage_rpc::replication_mode::ConsistencyMode;
+use garage_rpc::system::GARAGE_VERSION_TAG;
 
 use garage_model::garage::Garage;
 
@@ -31,6 +35,13 @@ pub async fn handle_get_cluster_status(garage: &Arc<Garage>) -> Result<Response<
 					hostname: i.status.hostname,
 					is_up: i.is_up,
 					last_seen_secs_ago: i.last_seen_secs_ago,
+					state: i.state,
+					state_since_secs_ago: i.state_since_secs_ago,
+					last_rpc_secs_ago: i.last_rpc_secs_ago,
+					minority_partition: i.status.minority_partition,
+					read_only_api: i.status.read_only_api,
+					data_storage_degraded: i.status.data_storage_degraded,
+					clock_behind_msec: i.status.clock_behind_msec,
 					data_partition: i
 						.status
 						.data_disk_avail
@@ -57,6 +68,7 @@ pub async fn handle_get_cluster_status(garage: &Arc<Garage>) -> Result<Response<
 				zone: r.zone.to_string(),
 				capacity: r.capacity,
 				tags: r.tags.clone(),
+				non_voting: r.non_voting,
 			};
 			match nodes.get_mut(id) {
 				None => {
@@ -135,6 +147,56 @@ pub async fn handle_get_cluster_health(garage: &Arc<Garage>) -> Result<Response<
 	Ok(json_ok_response(&health)?)
 }
 
+/// Names of the features that select a database engine
+const DB_ENGINE_FEATURES: &[&str] = &["lmdb", "sqlite"];
+
+pub async fn handle_get_node_versions(garage: &Arc<Garage>) -> Result<Response<ResBody>, Error> {
+	let mut nodes = garage
+		.system
+		.get_known_nodes()
+		.into_iter()
+		.map(|i| NodeVersionResp {
+			id: hex::encode(i.id),
+			hostname: i.status.hostname,
+			is_up: i.is_up,
+			db_engines: i.status.garage_features.as_ref().map(|features| {
+				features
+					.iter()
+					.filter(|f| DB_ENGINE_FEATURES.contains(&f.as_str()))
+					.cloned()
+					.collect()
+			}),
+			garage_version: i.status.garage_version,
+			garage_features: i.status.garage_features,
+			replication_factor: Some(i.status.replication_factor).filter(|rf| *rf > 0),
+			consistency_mode: i.status.consistency_mode,
+		})
+		.collect::<Vec<_>>();
+	nodes.sort_by(|x, y| x.id.cmp(&y.id));
+
+	// Features are only known for the nodes that report them,
+	// i.e. the nodes that run a version of Garage recent enough
+	let mut common_features: Option<Vec<String>> = None;
+	for features in nodes.iter().filter_map(|n| n.garage_features.as_ref()) {
+		common_features = Some(match common_features {
+			None => features.clone(),
+			Some(common) => common
+				.into_iter()
+				.filter(|f| features.contains(f))
+				.collect(),
+		});
+	}
+
+	let res = GetNodeVersionsResponse {
+		node: hex::encode(garage.system.id),
+		rpc_protocol_version: format!("{:016x}", GARAGE_VERSION_TAG),
+		common_features: common_features.unwrap_or_default(),
+		nodes,
+	};
+
+	Ok(json_ok_response(&res)?)
+}
+
 pub async fn handle_connect_cluster_nodes(
 	garage: &Arc<Garage>,
 	req: Request<IncomingBody>,
@@ -159,12 +221,149 @@ pub async fn handle_connect_cluster_nodes(
 	Ok(json_ok_response(&res)?)
 }
 
+pub async fn handle_reconnect_cluster_node(
+	garage: &Arc<Garage>,
+	id: String,
+) -> Result<Response<ResBody>, Error> {
+	let node = hex::decode(&id).ok_or_bad_request("Invalid node identifier")?;
+	let node = Uuid::try_from(&node).ok_or_bad_request("Invalid node identifier")?;
+
+	if node == garage.system.id {
+		return Err(Error::bad_request("Cannot reconnect to the local node"));
+	}
+	if !garage.system.get_known_nodes().iter().any(|n| n.id == node) {
+		return Err(Error::bad_request(format!("Unknown node: {}", id)));
+	}
+
+	let info = garage.system.reconnect(node).await?;
+	let res = NodeResp {
+		id: hex::encode(info.id),
+		addr: info.addr,
+		hostname: info.status.hostname,
+		is_up: info.is_up,
+		last_seen_secs_ago: info.last_seen_secs_ago,
+		..Default::default()
+	};
+
+	Ok(json_ok_response(&res)?)
+}
+
+pub fn handle_get_usage_history(
+	garage: &Arc<Garage>,
+	period_secs: Option<String>,
+) -> Result<Response<ResBody>, Error> {
+	let history = garage.usage_history.as_ref().ok_or_bad_request(
+		"Usage history is not enabled on this node (`usage_history_interval` is not set)",
+	)?;
+	let period_secs = match period_secs {
+		Some(p) => p.parse::<u64>().ok_or_bad_request("Invalid periodSecs")?,
+		None => 24 * 3600,
+	};
+
+	let since = now_msec().saturating_sub(period_secs.saturating_mul(1000));
+	let samples = history
+		.get_since(since)?
+		.into_iter()
+		.map(|s| UsageSampleResp {
+			timestamp: msec_to_rfc3339(s.timestamp),
+			bytes: s.bytes,
+			objects: s.objects,
+			resync_queue_length: s.resync_queue_len,
+			nodes: s
+				.nodes
+				.into_iter()
+				.map(|n| NodeUsageResp {
+					id: hex::encode(n.id),
+					data_partition: FreeSpaceResp {
+						available: n.data_avail,
+						total: n.data_total,
+					},
+				})
+				.collect(),
+		})
+		.collect::<Vec<_>>();
+
+	let res = GetUsageHistoryResponse {
+		node: hex::encode(garage.system.id),
+		interval_secs: history.interval.as_secs(),
+		samples,
+	};
+
+	Ok(json_ok_response(&res)?)
+}
+
 pub async fn handle_get_cluster_layout(garage: &Arc<Garage>) -> Result<Response<ResBody>, Error> {
 	let res = format_cluster_layout(garage.system.cluster_layout().inner());
 
 	Ok(json_ok_response(&res)?)
 }
 
+pub async fn handle_get_cluster_placement(
+	garage: &Arc<Garage>,
+	include_partitions: bool,
+) -> Result<Response<ResBody>, Error> {
+	let known_nodes = garage
+		.system
+		.get_known_nodes()
+		.into_iter()
+		.map(|i| (i.id, i))
+		.collect::<HashMap<_, _>>();
+
+	let layout = garage.system.cluster_layout();
+	let current = layout.current();
+
+	let mut nodes = current
+		.all_nodes()
+		.iter()
+		.filter_map(|id| current.node_role(id).map(|role| (id, role)))
+		.map(|(id, role)| {
+			let info = known_nodes.get(id);
+			let data_disk_avail = info.and_then(|i| i.status.data_disk_avail);
+			PlacementNodeResp {
+				id: hex::encode(id),
+				hostname: info.and_then(|i| i.status.hostname.clone()),
+				is_up: info.map(|i| i.is_up).unwrap_or(false),
+				zone: role.zone.clone(),
+				capacity: role.capacity,
+				tags: role.tags.clone(),
+				partitions: current.get_node_usage(id).unwrap_or(0),
+				used_bytes: data_disk_avail.map(|(avail, total)| total.saturating_sub(avail)),
+				data_partition: data_disk_avail.map(|(avail, total)| FreeSpaceResp {
+					available: avail,
+					total,
+				}),
+			}
+		})
+		.collect::<Vec<_>>();
+	nodes.sort_by(|x, y| x.zone.cmp(&y.zone).then_with(|| x.id.cmp(&y.id)));
+
+	// The partition map is empty until a first layout has been applied
+	let ring_ready = current.ring_assignment_data.len()
+		== current.replication_factor * current.partitions().count();
+	let partitions = (include_partitions && ring_ready).then(|| {
+		current
+			.partitions()
+			.map(|(partition, first_hash)| PartitionResp {
+				partition,
+				nodes: current
+					.nodes_of(&first_hash, current.replication_factor)
+					.map(hex::encode)
+					.collect(),
+			})
+			.collect::<Vec<_>>()
+	});
+
+	let res = GetClusterPlacementResponse {
+		layout_version: current.version,
+		replication_factor: current.replication_factor,
+		partition_size: current.partition_size,
+		nodes,
+		partitions,
+	};
+
+	Ok(json_ok_response(&res)?)
+}
+
 fn format_cluster_layout(layout: &layout::LayoutHistory) -> GetClusterLayoutResponse {
 	let roles = layout
 		.current()
@@ -177,6 +376,7 @@ fn format_cluster_layout(layout: &layout::LayoutHistory) -> GetClusterLayoutResp
 			zone: v.zone.clone(),
 			capacity: v.capacity,
 			tags: v.tags.clone(),
+			non_voting: v.non_voting,
 		})
 		.collect::<Vec<_>>();
 
@@ -198,15 +398,23 @@ fn format_cluster_layout(layout: &layout::LayoutHistory) -> GetClusterLayoutResp
 					zone: r.zone.clone(),
 					capacity: r.capacity,
 					tags: r.tags.clone(),
+					non_voting: r.non_voting,
 				},
 			},
 		})
 		.collect::<Vec<_>>();
 
+	let canary = layout.current().canary.as_ref().map(|c| LayoutCanaryResp {
+		percent: c.percent,
+		changed_partitions: c.changed_partitions,
+		moved_partitions: c.partitions.len(),
+	});
+
 	GetClusterLayoutResponse {
 		version: layout.current().version,
 		roles,
 		staged_role_changes,
+		canary,
 	}
 }
 
@@ -237,6 +445,28 @@ struct GetClusterStatusResponse {
 	nodes: Vec<NodeResp>,
 }
 
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GetNodeVersionsResponse {
+	node: String,
+	rpc_protocol_version: String,
+	common_features: Vec<String>,
+	nodes: Vec<NodeVersionResp>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NodeVersionResp {
+	id: String,
+	hostname: Option<String>,
+	is_up: bool,
+	garage_version: Option<String>,
+	garage_features: Option<Vec<String>>,
+	db_engines: Option<Vec<String>>,
+	replication_factor: Option<usize>,
+	consistency_mode: Option<ConsistencyMode>,
+}
+
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 struct ApplyClusterLayoutResponse {
@@ -244,6 +474,54 @@ struct ApplyClusterLayoutResponse {
 	layout: GetClusterLayoutResponse,
 }
 
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PreviewClusterLayoutChangesResponse {
+	new_version: u64,
+	partition_size_before: u64,
+	partition_size_after: u64,
+	partitions_moved: usize,
+	nodes: Vec<NodePlanResp>,
+	warnings: Vec<String>,
+	score: AssignmentScoreResp,
+	message: Vec<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AssignmentScoreResp {
+	usable_capacity: u64,
+	total_capacity: u64,
+	moved_copies: usize,
+	zones: Vec<ZoneScoreResp>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ZoneScoreResp {
+	zone: String,
+	partitions: usize,
+	unique_partitions: usize,
+	capacity: u64,
+	usage: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NodePlanResp {
+	id: String,
+	zone_before: Option<String>,
+	zone_after: Option<String>,
+	capacity_before: Option<u64>,
+	capacity_after: Option<u64>,
+	partitions_before: usize,
+	partitions_after: usize,
+	partitions_gained: usize,
+	partitions_lost: usize,
+	usage_before: u64,
+	usage_after: u64,
+}
+
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 struct ConnectClusterNodesResponse {
@@ -257,6 +535,16 @@ struct GetClusterLayoutResponse {
 	version: u64,
 	roles: Vec<NodeRoleResp>,
 	staged_role_changes: Vec<NodeRoleChange>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	canary: Option<LayoutCanaryResp>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LayoutCanaryResp {
+	percent: u8,
+	changed_partitions: usize,
+	moved_partitions: usize,
 }
 
 #[derive(Serialize)]
@@ -266,6 +554,40 @@ struct NodeRoleResp {
 	zone: String,
 	capacity: Option<u64>,
 	tags: Vec<String>,
+	non_voting: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GetClusterPlacementResponse {
+	layout_version: u64,
+	replication_factor: usize,
+	partition_size: u64,
+	nodes: Vec<PlacementNodeResp>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	partitions: Option<Vec<PartitionResp>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PlacementNodeResp {
+	id: String,
+	hostname: Option<String>,
+	is_up: bool,
+	zone: String,
+	capacity: Option<u64>,
+	tags: Vec<String>,
+	partitions: usize,
+	used_bytes: Option<u64>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	data_partition: Option<FreeSpaceResp>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PartitionResp {
+	partition: u16,
+	nodes: Vec<String>,
 }
 
 #[derive(Serialize, Default)]
@@ -284,13 +606,46 @@ struct NodeResp {
 	hostname: Option<String>,
 	is_up: bool,
 	last_seen_secs_ago: Option<u64>,
+	state: PeerState,
+	state_since_secs_ago: Option<u64>,
+	last_rpc_secs_ago: Option<u64>,
 	draining: bool,
+	minority_partition: bool,
+	read_only_api: bool,
+	data_storage_degraded: bool,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	clock_behind_msec: Option<u64>,
 	#[serde(skip_serializing_if = "Option::is_none")]
 	data_partition: Option<FreeSpaceResp>,
 	#[serde(skip_serializing_if = "Option::is_none")]
 	metadata_partition: Option<FreeSpaceResp>,
 }
 
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GetUsageHistoryResponse {
+	node: String,
+	interval_secs: u64,
+	samples: Vec<UsageSampleResp>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UsageSampleResp {
+	timestamp: String,
+	bytes: i64,
+	objects: i64,
+	resync_queue_length: u64,
+	nodes: Vec<NodeUsageResp>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NodeUsageResp {
+	id: String,
+	data_partition: FreeSpaceResp,
+}
+
 // ---- update functions ----
 
 pub async fn handle_update_cluster_layout(
@@ -314,11 +669,18 @@ pub async fn handle_update_cluster_layout(
 				zone,
 				capacity,
 				tags,
-			} => Some(layout::NodeRole {
-				zone,
-				capacity,
-				tags,
-			}),
+				non_voting,
+			} => {
+				if non_voting && capacity.is_some() {
+					return Err(Error::bad_request("Only gateway nodes can be non-voting"));
+				}
+				Some(layout::NodeRole {
+					zone,
+					capacity,
+					tags,
+					non_voting,
+				})
+			}
 			_ => return Err(Error::bad_request("Invalid layout change")),
 		};
 
@@ -346,7 +708,10 @@ pub async fn handle_apply_cluster_layout(
 	let param = parse_json_body::<ApplyLayoutRequest, _, Error>(req).await?;
 
 	let layout = garage.system.cluster_layout().inner().clone();
-	let (layout, msg) = layout.apply_staged_changes(Some(param.version))?;
+	let (layout, msg) = match param.canary_percent {
+		Some(percent) => layout.apply_staged_changes_canary(Some(param.version), percent)?,
+		None => layout.apply_staged_changes(Some(param.version))?,
+	};
 
 	garage
 		.system
@@ -361,6 +726,57 @@ pub async fn handle_apply_cluster_layout(
 	Ok(json_ok_response(&res)?)
 }
 
+pub async fn handle_preview_cluster_layout_changes(
+	garage: &Arc<Garage>,
+) -> Result<Response<ResBody>, Error> {
+	let layout = garage.system.cluster_layout().inner().clone();
+	let plan = layout.plan_staged_changes()?;
+
+	let res = PreviewClusterLayoutChangesResponse {
+		new_version: plan.new_version,
+		partition_size_before: plan.partition_size_before,
+		partition_size_after: plan.partition_size_after,
+		partitions_moved: plan.partitions_moved,
+		nodes: plan
+			.nodes
+			.into_iter()
+			.map(|n| NodePlanResp {
+				id: hex::encode(n.id),
+				zone_before: n.zone_before,
+				zone_after: n.zone_after,
+				capacity_before: n.capacity_before,
+				capacity_after: n.capacity_after,
+				partitions_before: n.partitions_before,
+				partitions_after: n.partitions_after,
+				partitions_gained: n.partitions_gained,
+				partitions_lost: n.partitions_lost,
+				usage_before: n.usage_before,
+				usage_after: n.usage_after,
+			})
+			.collect(),
+		warnings: plan.warnings,
+		score: AssignmentScoreResp {
+			usable_capacity: plan.score.usable_capacity,
+			total_capacity: plan.score.total_capacity,
+			moved_copies: plan.score.moved_copies,
+			zones: plan
+				.score
+				.zones
+				.into_iter()
+				.map(|z| ZoneScoreResp {
+					zone: z.zone,
+					partitions: z.partitions,
+					unique_partitions: z.unique_partitions,
+					capacity: z.capacity,
+					usage: z.usage,
+				})
+				.collect(),
+		},
+		message: plan.message,
+	};
+	Ok(json_ok_response(&res)?)
+}
+
 pub async fn handle_revert_cluster_layout(
 	garage: &Arc<Garage>,
 ) -> Result<Response<ResBody>, Error> {
@@ -384,6 +800,8 @@ type UpdateClusterLayoutRequest = Vec<NodeRoleChange>;
 #[serde(rename_all = "camelCase")]
 struct ApplyLayoutRequest {
 	version: u64,
+	#[serde(default)]
+	canary_percent: Option<u8>,
 }
 
 // ----
@@ -406,5 +824,7 @@ enum NodeRoleChangeEnum {
 		zone: String,
 		capacity: Option<u64>,
 		tags: Vec<String>,
+		#[serde(default)]
+		non_voting: bool,
 	},
 }
@@ -27,6 +27,18 @@ pub enum Error {
 		_0
 	)]
 	KeyAlreadyExists(String),
+
+	/// The delete-prefix job does not exist on this node
+	#[error(display = "Delete-prefix job not found: {}", _0)]
+	NoSuchDeletePrefixJob(String),
+
+	/// The bucket copy job does not exist on this node
+	#[error(display = "Bucket copy job not found: {}", _0)]
+	NoSuchCopyBucketJob(String),
+
+	/// The import job does not exist on this node
+	#[error(display = "Import job not found: {}", _0)]
+	NoSuchImportJob(String),
 }
 
 impl<T> From<T> for Error
@@ -46,6 +58,9 @@ impl Error {
 			Error::Common(c) => c.aws_code(),
 			Error::NoSuchAccessKey(_) => "NoSuchAccessKey",
 			Error::KeyAlreadyExists(_) => "KeyAlreadyExists",
+			Error::NoSuchDeletePrefixJob(_) => "NoSuchDeletePrefixJob",
+			Error::NoSuchCopyBucketJob(_) => "NoSuchCopyBucketJob",
+			Error::NoSuchImportJob(_) => "NoSuchImportJob",
 		}
 	}
 }
@@ -55,7 +70,10 @@ impl ApiError for Error {
 	fn http_status_code(&self) -> StatusCode {
 		match self {
 			Error::Common(c) => c.http_status_code(),
-			Error::NoSuchAccessKey(_) => StatusCode::NOT_FOUND,
+			Error::NoSuchAccessKey(_)
+			| Error::NoSuchDeletePrefixJob(_)
+			| Error::NoSuchCopyBucketJob(_)
+			| Error::NoSuchImportJob(_) => StatusCode::NOT_FOUND,
 			Error::KeyAlreadyExists(_) => StatusCode::CONFLICT,
 		}
 	}
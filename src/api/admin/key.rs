@@ -5,6 +5,7 @@ use hyper::{body::Incoming as IncomingBody, Request, Response, StatusCode};
 use serde::{Deserialize, Serialize};
 
 use garage_table::*;
+use garage_util::time::*;
 
 use garage_model::garage::Garage;
 use garage_model::key_table::*;
@@ -13,8 +14,11 @@ use crate::admin::api_server::ResBody;
 use crate::admin::error::*;
 use crate::helpers::*;
 
-pub async fn handle_list_keys(garage: &Arc<Garage>) -> Result<Response<ResBody>, Error> {
-	let res = garage
+pub async fn handle_list_keys(
+	garage: &Arc<Garage>,
+	sort: Option<String>,
+) -> Result<Response<ResBody>, Error> {
+	let mut keys = garage
 		.key_table
 		.get_range(
 			&EmptyKey,
@@ -23,11 +27,33 @@ pub async fn handle_list_keys(garage: &Arc<Garage>) -> Result<Response<ResBody>,
 			10000,
 			EnumerationOrder::Forward,
 		)
-		.await?
+		.await?;
+
+	match sort.as_deref() {
+		None => (),
+		// Most recently used keys first
+		Some("lastUsed") => {
+			keys.sort_by_key(|k| std::cmp::Reverse(key_usage(garage, k).0));
+		}
+		Some(s) => {
+			return Err(Error::bad_request(format!(
+				"Invalid sort order: {} (the only option is lastUsed)",
+				s
+			)))
+		}
+	}
+
+	let res = keys
 		.iter()
-		.map(|k| ListKeyResultItem {
-			id: k.key_id.to_string(),
-			name: k.params().unwrap().name.get().clone(),
+		.map(|k| {
+			let (last_used, requests) = key_usage(garage, k);
+			ListKeyResultItem {
+				id: k.key_id.to_string(),
+				name: k.params().unwrap().name.get().clone(),
+				suspended: k.is_suspended(),
+				last_used: last_used.map(msec_to_rfc3339),
+				requests,
+			}
 		})
 		.collect::<Vec<_>>();
 
@@ -39,6 +65,21 @@ pub async fn handle_list_keys(garage: &Arc<Garage>) -> Result<Response<ResBody>,
 struct ListKeyResultItem {
 	id: String,
 	name: String,
+	suspended: bool,
+	last_used: Option<String>,
+	requests: u64,
+}
+
+/// Time at which a key was last used and number of requests signed with it,
+/// including the usage recorded by this node that was not written yet
+fn key_usage(garage: &Garage, key: &Key) -> (Option<u64>, u64) {
+	let params = key.params().unwrap();
+	let pending = garage.key_usage.pending(&key.key_id);
+	let last_used = match params.last_used_at {
+		Some(t) => Some(std::cmp::max(t, pending.last_used)),
+		None => Some(pending.last_used).filter(|t| *t > 0),
+	};
+	(last_used, params.request_count() + pending.requests)
 }
 
 pub async fn handle_get_key_info(
@@ -133,6 +174,12 @@ pub async fn handle_update_key(
 			key_state.allow_create_bucket.update(false);
 		}
 	}
+	if let Some(system) = req.system {
+		key_state.system.update(system);
+	}
+	if let Some(suspended) = req.suspended {
+		key_state.suspended.update(suspended);
+	}
 
 	garage.key_table.insert(&key).await?;
 
@@ -145,6 +192,29 @@ struct UpdateKeyRequest {
 	name: Option<String>,
 	allow: Option<KeyPerm>,
 	deny: Option<KeyPerm>,
+	system: Option<bool>,
+	suspended: Option<bool>,
+}
+
+pub async fn handle_rotate_key_secret(
+	garage: &Arc<Garage>,
+	id: String,
+	req: Request<IncomingBody>,
+) -> Result<Response<ResBody>, Error> {
+	let req = parse_json_body::<RotateKeySecretRequest, _, Error>(req).await?;
+
+	let mut key = garage.key_helper().get_existing_key(&id).await?;
+	key.rotate_secret(req.secret_access_key.as_deref())
+		.ok_or_bad_request("Invalid secret key")?;
+	garage.key_table.insert(&key).await?;
+
+	key_info_results(garage, key, true).await
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RotateKeySecretRequest {
+	secret_access_key: Option<String>,
 }
 
 pub async fn handle_delete_key(
@@ -192,17 +262,22 @@ async fn key_info_results(
 		}
 	}
 
+	let (last_used, requests) = key_usage(garage, &key);
 	let res = GetKeyInfoResult {
 		name: key_state.name.get().clone(),
 		access_key_id: key.key_id.clone(),
 		secret_access_key: if show_secret {
-			Some(key_state.secret_key.clone())
+			Some(key_state.secret().to_string())
 		} else {
 			None
 		},
 		permissions: KeyPerm {
 			create_bucket: *key_state.allow_create_bucket.get(),
 		},
+		system: *key_state.system.get(),
+		suspended: *key_state.suspended.get(),
+		last_used: last_used.map(msec_to_rfc3339),
+		requests,
 		buckets: relevant_buckets
 			.into_values()
 			.map(|bucket| {
@@ -228,6 +303,7 @@ async fn key_info_results(
 						.get(&bucket.id)
 						.map(|p| ApiBucketKeyPerm {
 							read: p.allow_read,
+							list: p.can_list(),
 							write: p.allow_write,
 							owner: p.allow_owner,
 						})
@@ -248,6 +324,10 @@ struct GetKeyInfoResult {
 	#[serde(skip_serializing_if = "is_default")]
 	secret_access_key: Option<String>,
 	permissions: KeyPerm,
+	system: bool,
+	suspended: bool,
+	last_used: Option<String>,
+	requests: u64,
 	buckets: Vec<KeyInfoBucketResult>,
 }
 
@@ -276,4 +356,6 @@ pub(crate) struct ApiBucketKeyPerm {
 	pub(crate) write: bool,
 	#[serde(default)]
 	pub(crate) owner: bool,
+	#[serde(default)]
+	pub(crate) list: bool,
 }
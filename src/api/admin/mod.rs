@@ -3,6 +3,9 @@ mod error;
 mod router_v0;
 mod router_v1;
 
+mod bandwidth;
 mod bucket;
 mod cluster;
 mod key;
+mod recent_errors;
+mod worker;
@@ -0,0 +1,55 @@
+use hyper::Response;
+use serde::Serialize;
+
+use garage_util::time::*;
+
+use crate::admin::api_server::ResBody;
+use crate::admin::error::*;
+use crate::helpers::json_ok_response;
+use crate::s3::recent_errors::*;
+
+pub fn handle_get_recent_s3_errors(
+	recent_errors: &RecentErrors,
+	error_code: Option<String>,
+	access_key_id: Option<String>,
+) -> Result<Response<ResBody>, Error> {
+	let filter = RecentErrorsFilter {
+		error_code,
+		access_key_id,
+	};
+
+	let res = recent_errors
+		.list(&filter)
+		.into_iter()
+		.map(|e| RecentS3ErrorResp {
+			timestamp: msec_to_rfc3339(e.timestamp),
+			operation: e.operation,
+			bucket: e.bucket,
+			key_hash: e.key_hash.map(hex::encode),
+			key: e.key,
+			status_code: e.status_code,
+			error_code: e.error_code,
+			access_key_id: e.access_key_id,
+			source_addr: e.source_addr,
+			forwarded_for: e.forwarded_for,
+		})
+		.collect::<Vec<_>>();
+
+	Ok(json_ok_response(&res)?)
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RecentS3ErrorResp {
+	timestamp: String,
+	operation: &'static str,
+	bucket: Option<String>,
+	key_hash: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	key: Option<String>,
+	status_code: u16,
+	error_code: &'static str,
+	access_key_id: Option<String>,
+	source_addr: String,
+	forwarded_for: Option<String>,
+}
@@ -23,14 +23,37 @@ pub enum Endpoint {
 	Metrics,
 	GetClusterStatus,
 	GetClusterHealth,
+	GetNodeVersions,
 	ConnectClusterNodes,
+	ReconnectClusterNode {
+		id: String,
+	},
+	GetRecentS3Errors {
+		error_code: Option<String>,
+		access_key_id: Option<String>,
+	},
+	GetS3Bandwidth,
+	UpdateS3Bandwidth,
+	GetUsageHistory {
+		period_secs: Option<String>,
+	},
+	GetWorkerVariable {
+		variable: Option<String>,
+	},
+	SetWorkerVariable,
 	// Layout
 	GetClusterLayout,
 	UpdateClusterLayout,
 	ApplyClusterLayout,
+	PreviewClusterLayoutChanges,
 	RevertClusterLayout,
+	GetClusterPlacement {
+		include_partitions: Option<String>,
+	},
 	// Keys
-	ListKeys,
+	ListKeys {
+		sort: Option<String>,
+	},
 	CreateKey,
 	ImportKey,
 	GetKeyInfo {
@@ -44,8 +67,12 @@ pub enum Endpoint {
 	UpdateKey {
 		id: String,
 	},
+	RotateKeySecret {
+		id: String,
+	},
 	// Buckets
 	ListBuckets,
+	FindOrphanBuckets,
 	CreateBucket,
 	GetBucketInfo {
 		id: Option<String>,
@@ -57,6 +84,38 @@ pub enum Endpoint {
 	UpdateBucket {
 		id: String,
 	},
+	DeletePrefix,
+	GetDeletePrefixJob {
+		job_id: String,
+	},
+	CancelDeletePrefixJob {
+		job_id: String,
+	},
+	CopyBucketContents,
+	GetCopyBucketContentsJob {
+		job_id: String,
+	},
+	CancelCopyBucketContentsJob {
+		job_id: String,
+	},
+	ImportFromS3,
+	GetImportFromS3Job {
+		job_id: String,
+	},
+	CancelImportFromS3Job {
+		job_id: String,
+	},
+	RestorePreviousVersion,
+	ListIncompleteUploads {
+		id: String,
+	},
+	VerifyBucketCounters,
+	DumpBucketObjects {
+		id: String,
+		format: Option<String>,
+		all_versions: Option<String>,
+		start_after: Option<String>,
+	},
 	// Bucket-Key Permissions
 	BucketAllowKey,
 	BucketDenyKey,
@@ -99,27 +158,57 @@ impl Endpoint {
 			GET "/metrics" => Metrics,
 			GET "/v1/status" => GetClusterStatus,
 			GET "/v1/health" => GetClusterHealth,
+			GET "/v1/status/versions" => GetNodeVersions,
 			POST "/v1/connect" => ConnectClusterNodes,
+			POST "/v1/connect/reconnect" => ReconnectClusterNode (query::id),
+			GET "/v1/s3/recent-errors" => GetRecentS3Errors (query_opt::error_code, query_opt::access_key_id),
+			GET "/v1/s3/bandwidth" => GetS3Bandwidth,
+			POST "/v1/s3/bandwidth" => UpdateS3Bandwidth,
+			GET "/v1/usage-history" => GetUsageHistory (query_opt::period_secs),
+			GET "/v1/worker/variable" => GetWorkerVariable (query_opt::variable),
+			POST "/v1/worker/variable" => SetWorkerVariable,
 			// Layout endpoints
 			GET "/v1/layout" => GetClusterLayout,
 			POST "/v1/layout" => UpdateClusterLayout,
 			POST "/v1/layout/apply" => ApplyClusterLayout,
+			GET "/v1/layout/preview" => PreviewClusterLayoutChanges,
 			POST "/v1/layout/revert" => RevertClusterLayout,
+			GET "/v1/layout/placement" => GetClusterPlacement (query_opt::include_partitions),
 			// API key endpoints
 			GET "/v1/key" if id => GetKeyInfo (query_opt::id, query_opt::search, query_opt::show_secret_key),
 			GET "/v1/key" if search => GetKeyInfo (query_opt::id, query_opt::search, query_opt::show_secret_key),
 			POST "/v1/key" if id => UpdateKey (query::id),
 			POST "/v1/key" => CreateKey,
 			POST "/v1/key/import" => ImportKey,
+			POST "/v1/key/rotate-secret" => RotateKeySecret (query::id),
 			DELETE "/v1/key" if id => DeleteKey (query::id),
-			GET "/v1/key" => ListKeys,
+			GET "/v1/key" => ListKeys (query_opt::sort),
 			// Bucket endpoints
 			GET "/v1/bucket" if id => GetBucketInfo (query_opt::id, query_opt::global_alias),
 			GET "/v1/bucket" if global_alias => GetBucketInfo (query_opt::id, query_opt::global_alias),
 			GET "/v1/bucket" => ListBuckets,
+			GET "/v1/bucket/orphans" => FindOrphanBuckets,
 			POST "/v1/bucket" => CreateBucket,
 			DELETE "/v1/bucket" if id => DeleteBucket (query::id),
 			PUT "/v1/bucket" if id => UpdateBucket (query::id),
+			// Server-side deletion of objects by prefix
+			POST "/v1/bucket/delete-prefix" => DeletePrefix,
+			GET "/v1/bucket/delete-prefix" => GetDeletePrefixJob (query::job_id),
+			DELETE "/v1/bucket/delete-prefix" => CancelDeletePrefixJob (query::job_id),
+			POST "/v1/bucket/copy-contents" => CopyBucketContents,
+			GET "/v1/bucket/copy-contents" => GetCopyBucketContentsJob (query::job_id),
+			DELETE "/v1/bucket/copy-contents" => CancelCopyBucketContentsJob (query::job_id),
+			POST "/v1/bucket/import-s3" => ImportFromS3,
+			GET "/v1/bucket/import-s3" => GetImportFromS3Job (query::job_id),
+			DELETE "/v1/bucket/import-s3" => CancelImportFromS3Job (query::job_id),
+			// Restoration of overwritten objects
+			POST "/v1/bucket/restore-previous-version" => RestorePreviousVersion,
+			// Incomplete multipart uploads
+			GET "/v1/bucket/incomplete-uploads" => ListIncompleteUploads (query::id),
+			// Verification of object counters
+			POST "/v1/bucket/verify-counters" => VerifyBucketCounters,
+			// Full listing of the objects of a bucket
+			GET "/v1/bucket/objects/dump" => DumpBucketObjects (query::id, query_opt::format, query_opt::all_versions, query_opt::start_after),
 			// Bucket-key permissions
 			POST "/v1/bucket/allow" => BucketAllowKey,
 			POST "/v1/bucket/deny" => BucketDenyKey,
@@ -153,7 +242,7 @@ impl Endpoint {
 			// - ApplyCusterLayout: response format changed
 
 			// Key endpoints
-			router_v0::Endpoint::ListKeys => Ok(Self::ListKeys),
+			router_v0::Endpoint::ListKeys => Ok(Self::ListKeys { sort: None }),
 			router_v0::Endpoint::CreateKey => Ok(Self::CreateKey),
 			router_v0::Endpoint::GetKeyInfo { id, search } => Ok(Self::GetKeyInfo {
 				id,
@@ -230,6 +319,14 @@ generateQueryParameters! {
 		"globalAlias" => global_alias,
 		"alias" => alias,
 		"accessKeyId" => access_key_id,
-		"showSecretKey" => show_secret_key
+		"showSecretKey" => show_secret_key,
+		"jobId" => job_id,
+		"errorCode" => error_code,
+		"periodSecs" => period_secs,
+		"allVersions" => all_versions,
+		"startAfter" => start_after,
+		"includePartitions" => include_partitions,
+		"sort" => sort,
+		"variable" => variable
 	]
 }
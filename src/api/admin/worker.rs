@@ -0,0 +1,67 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use hyper::{body::Incoming as IncomingBody, Request, Response};
+use serde::{Deserialize, Serialize};
+
+use garage_model::garage::Garage;
+
+use crate::admin::api_server::ResBody;
+use crate::admin::error::*;
+use crate::helpers::{json_ok_response, parse_json_body};
+
+pub fn handle_get_worker_variable(
+	garage: &Arc<Garage>,
+	variable: Option<String>,
+) -> Result<Response<ResBody>, Error> {
+	let vars = match variable {
+		Some(v) => {
+			let value = garage
+				.bg_vars
+				.get(&v)
+				.ok_or_bad_request(format!("Invalid variable {}", v))?;
+			vec![(v, value)]
+		}
+		None => garage.bg_vars.get_all(),
+	};
+	Ok(json_ok_response(&worker_vars_response(garage, vars))?)
+}
+
+pub async fn handle_set_worker_variable(
+	garage: &Arc<Garage>,
+	req: Request<IncomingBody>,
+) -> Result<Response<ResBody>, Error> {
+	let req = parse_json_body::<SetWorkerVariableRequest, _, Error>(req).await?;
+
+	garage
+		.bg_vars
+		.set(&req.variable, &req.value)
+		.ok_or_bad_request(format!("Cannot set variable {}", req.variable))?;
+	let value = garage.bg_vars.get(&req.variable)?;
+
+	Ok(json_ok_response(&worker_vars_response(
+		garage,
+		vec![(req.variable, value)],
+	))?)
+}
+
+fn worker_vars_response(garage: &Garage, vars: Vec<(String, String)>) -> WorkerVariablesResponse {
+	WorkerVariablesResponse {
+		node: hex::encode(garage.system.id),
+		variables: vars.into_iter().collect(),
+	}
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SetWorkerVariableRequest {
+	variable: String,
+	value: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WorkerVariablesResponse {
+	node: String,
+	variables: BTreeMap<String, String>,
+}
@@ -34,6 +34,10 @@ pub enum CommonError {
 	#[error(display = "Invalid header value: {}", _0)]
 	InvalidHeader(#[error(source)] hyper::header::ToStrError),
 
+	/// The request cannot be handled by this node at the moment
+	#[error(display = "Service unavailable: {}", _0)]
+	ServiceUnavailable(String),
+
 	// ---- SPECIFIC ERROR CONDITIONS ----
 	// These have to be error codes referenced in the S3 spec here:
 	// https://docs.aws.amazon.com/AmazonS3/latest/API/ErrorResponses.html#ErrorCodeList
@@ -58,12 +62,19 @@ pub enum CommonError {
 impl CommonError {
 	pub fn http_status_code(&self) -> StatusCode {
 		match self {
+			CommonError::InternalError(GarageError::Db(e)) if e.is_overloaded() => {
+				StatusCode::SERVICE_UNAVAILABLE
+			}
 			CommonError::InternalError(
-				GarageError::Timeout | GarageError::RemoteError(_) | GarageError::Quorum(..),
+				GarageError::Timeout
+				| GarageError::RemoteError(_)
+				| GarageError::Quorum(..)
+				| GarageError::NoQuorum(..),
 			) => StatusCode::SERVICE_UNAVAILABLE,
 			CommonError::InternalError(_) | CommonError::Hyper(_) | CommonError::Http(_) => {
 				StatusCode::INTERNAL_SERVER_ERROR
 			}
+			CommonError::ServiceUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
 			CommonError::BadRequest(_) => StatusCode::BAD_REQUEST,
 			CommonError::Forbidden(_) => StatusCode::FORBIDDEN,
 			CommonError::NoSuchBucket(_) => StatusCode::NOT_FOUND,
@@ -77,12 +88,19 @@ impl CommonError {
 	pub fn aws_code(&self) -> &'static str {
 		match self {
 			CommonError::Forbidden(_) => "AccessDenied",
+			CommonError::InternalError(GarageError::Db(e)) if e.is_overloaded() => {
+				"ServiceUnavailable"
+			}
 			CommonError::InternalError(
-				GarageError::Timeout | GarageError::RemoteError(_) | GarageError::Quorum(..),
+				GarageError::Timeout
+				| GarageError::RemoteError(_)
+				| GarageError::Quorum(..)
+				| GarageError::NoQuorum(..),
 			) => "ServiceUnavailable",
 			CommonError::InternalError(_) | CommonError::Hyper(_) | CommonError::Http(_) => {
 				"InternalError"
 			}
+			CommonError::ServiceUnavailable(_) => "ServiceUnavailable",
 			CommonError::BadRequest(_) => "InvalidRequest",
 			CommonError::NoSuchBucket(_) => "NoSuchBucket",
 			CommonError::BucketAlreadyExists => "BucketAlreadyExists",
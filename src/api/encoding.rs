@@ -1,22 +1,28 @@
 //! Module containing various helpers for encoding
 
+use std::fmt::Write;
+
 /// Encode &str for use in a URI
 pub fn uri_encode(string: &str, encode_slash: bool) -> String {
 	let mut result = String::with_capacity(string.len() * 2);
+	uri_encode_into(&mut result, string, encode_slash).unwrap();
+	result
+}
+
+/// Encode &str for use in a URI, writing the result into `w`
+pub fn uri_encode_into<W: Write>(w: &mut W, string: &str, encode_slash: bool) -> std::fmt::Result {
 	for c in string.chars() {
 		match c {
-			'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' | '~' | '.' => result.push(c),
-			'/' if encode_slash => result.push_str("%2F"),
-			'/' if !encode_slash => result.push('/'),
+			'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' | '~' | '.' => w.write_char(c)?,
+			'/' if encode_slash => w.write_str("%2F")?,
+			'/' if !encode_slash => w.write_char('/')?,
 			_ => {
-				result.push_str(
-					&format!("{}", c)
-						.bytes()
-						.map(|b| format!("%{:02X}", b))
-						.collect::<String>(),
-				);
+				let mut buf = [0u8; 4];
+				for b in c.encode_utf8(&mut buf).bytes() {
+					write!(w, "%{:02X}", b)?;
+				}
 			}
 		}
 	}
-	result
+	Ok(())
 }
@@ -1,7 +1,9 @@
 use std::convert::Infallible;
 use std::fs::{self, Permissions};
 use std::os::unix::fs::PermissionsExt;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
 
@@ -9,34 +11,101 @@ use futures::future::Future;
 use futures::stream::{futures_unordered::FuturesUnordered, StreamExt};
 
 use http_body_util::BodyExt;
-use hyper::header::HeaderValue;
+use hyper::header::{HeaderValue, CONNECTION};
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::{body::Incoming as IncomingBody, Request, Response};
-use hyper::{HeaderMap, StatusCode};
+use hyper::{HeaderMap, StatusCode, Version};
 use hyper_util::rt::TokioIo;
 
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
 use tokio::sync::watch;
+use tracing::Instrument;
 
 use opentelemetry::{
 	global,
-	metrics::{Counter, ValueRecorder},
+	metrics::{Counter, UpDownCounter, ValueRecorder},
 	trace::{FutureExt, SpanRef, TraceContextExt, Tracer},
 	Context, KeyValue,
 };
 
+use garage_util::data::gen_uuid;
 use garage_util::error::Error as GarageError;
 use garage_util::forwarded_headers;
-use garage_util::metrics::{gen_trace_id, RecordDuration};
+use garage_util::metrics::{collect_request_phases, gen_trace_id, RecordDuration};
 use garage_util::socket_address::UnixOrTCPSocketAddress;
 
-use crate::helpers::{BoxBody, ErrorBody};
+use crate::common_error::{CommonError, CommonErrorDerivative};
+use crate::helpers::{empty_body, BoxBody, ErrorBody};
+use crate::tls_policy::TlsPolicy;
+
+/// Address of the remote end of the connection on which a request was
+/// received, inserted in the extensions of the request before it is handled
+#[derive(Clone, Debug)]
+pub(crate) struct ClientAddr(pub String);
+
+/// Identifier of a request, inserted in the extensions of the request
+/// before it is handled, that is the `request_id` field of the logs
+/// written while handling it
+#[derive(Clone, Debug)]
+pub(crate) struct RequestId(pub String);
+
+/// Default maximum total size of the headers of a request
+pub const DEFAULT_MAX_HEADER_SIZE: usize = 64 * 1024;
+/// Default maximum number of headers of a request (requests with more than
+/// 100 headers are always rejected by the HTTP parser)
+pub const DEFAULT_MAX_HEADER_COUNT: usize = 100;
+
+/// Limits on the headers of the requests received by a listener, requests
+/// exceeding them are rejected before being handled
+#[derive(Clone, Copy, Debug)]
+pub struct HeaderLimits {
+	pub max_size: usize,
+	pub max_count: usize,
+}
+
+impl HeaderLimits {
+	pub fn new(max_size: Option<usize>, max_count: Option<usize>) -> Self {
+		Self {
+			max_size: max_size.unwrap_or(DEFAULT_MAX_HEADER_SIZE),
+			max_count: max_count.unwrap_or(DEFAULT_MAX_HEADER_COUNT),
+		}
+	}
+
+	/// Check the headers of a request against the limits, returns a
+	/// description of the exceeded limit if any
+	fn check(&self, headers: &HeaderMap) -> Result<(), String> {
+		if headers.len() > self.max_count {
+			return Err(format!(
+				"{} headers, maximum is {}",
+				headers.len(),
+				self.max_count
+			));
+		}
+		// Count 4 bytes for the ": " and "\r\n" around each header value
+		let size = headers
+			.iter()
+			.map(|(name, value)| name.as_str().len() + value.len() + 4)
+			.sum::<usize>();
+		if size > self.max_size {
+			return Err(format!(
+				"{} bytes of headers, maximum is {}",
+				size, self.max_size
+			));
+		}
+		Ok(())
+	}
+}
 
 pub(crate) trait ApiEndpoint: Send + Sync + 'static {
 	fn name(&self) -> &'static str;
 	fn add_span_attributes(&self, span: SpanRef<'_>);
+
+	/// Bucket targeted by the request, if any, for logging
+	fn bucket_name(&self) -> Option<&str> {
+		None
+	}
 }
 
 pub trait ApiError: std::error::Error + Send + Sync + 'static {
@@ -51,7 +120,7 @@ pub(crate) trait ApiHandler: Send + Sync + 'static {
 	const API_NAME_DISPLAY: &'static str;
 
 	type Endpoint: ApiEndpoint;
-	type Error: ApiError;
+	type Error: ApiError + CommonErrorDerivative;
 
 	fn parse_endpoint(&self, r: &Request<IncomingBody>) -> Result<Self::Endpoint, Self::Error>;
 	async fn handle(
@@ -64,19 +133,38 @@ pub(crate) trait ApiHandler: Send + Sync + 'static {
 pub(crate) struct ApiServer<A: ApiHandler> {
 	region: String,
 	api_handler: A,
+	tls_policy: Option<TlsPolicy>,
+	/// Requests that take longer than this are logged
+	slow_request_threshold: Option<Duration>,
 
 	// Metrics
 	request_counter: Counter<u64>,
 	error_counter: Counter<u64>,
 	request_duration: ValueRecorder<f64>,
+	requests_in_flight: UpDownCounter<i64>,
 }
 
 impl<A: ApiHandler> ApiServer<A> {
-	pub fn new(region: String, api_handler: A) -> Arc<Self> {
+	pub fn new(region: String, api_handler: A, slow_request_threshold_msec: u64) -> Arc<Self> {
+		Self::new_with_tls_policy(region, api_handler, None, slow_request_threshold_msec)
+	}
+
+	/// Create an API server. Requests that take more than `slow_request_threshold_msec`
+	/// milliseconds are logged, unless it is 0.
+	pub fn new_with_tls_policy(
+		region: String,
+		api_handler: A,
+		tls_policy: Option<TlsPolicy>,
+		slow_request_threshold_msec: u64,
+	) -> Arc<Self> {
 		let meter = global::meter("garage/api");
 		Arc::new(Self {
 			region,
 			api_handler,
+			tls_policy,
+			slow_request_threshold: Some(slow_request_threshold_msec)
+				.filter(|t| *t > 0)
+				.map(Duration::from_millis),
 			request_counter: meter
 				.u64_counter(format!("api.{}.request_counter", A::API_NAME))
 				.with_description(format!(
@@ -98,6 +186,13 @@ impl<A: ApiHandler> ApiServer<A> {
 					A::API_NAME_DISPLAY
 				))
 				.init(),
+			requests_in_flight: meter
+				.i64_up_down_counter(format!("api.{}.requests_in_flight", A::API_NAME))
+				.with_description(format!(
+					"Number of API calls to the various {} API endpoints currently being handled",
+					A::API_NAME_DISPLAY
+				))
+				.init(),
 		})
 	}
 
@@ -105,6 +200,8 @@ impl<A: ApiHandler> ApiServer<A> {
 		self: Arc<Self>,
 		bind_addr: UnixOrTCPSocketAddress,
 		unix_bind_addr_mode: Option<u32>,
+		header_limits: HeaderLimits,
+		max_requests_per_connection: Option<u64>,
 		must_exit: watch::Receiver<bool>,
 	) -> Result<(), GarageError> {
 		let server_name = format!("{} API", A::API_NAME_DISPLAY);
@@ -115,7 +212,15 @@ impl<A: ApiHandler> ApiServer<A> {
 				let listener = TcpListener::bind(addr).await?;
 
 				let handler = move |request, socketaddr| self.clone().handler(request, socketaddr);
-				server_loop(server_name, listener, handler, must_exit).await
+				server_loop(
+					server_name,
+					listener,
+					handler,
+					header_limits,
+					max_requests_per_connection,
+					must_exit,
+				)
+				.await
 			}
 			UnixOrTCPSocketAddress::UnixSocket(ref path) => {
 				if path.exists() {
@@ -131,7 +236,15 @@ impl<A: ApiHandler> ApiServer<A> {
 				)?;
 
 				let handler = move |request, socketaddr| self.clone().handler(request, socketaddr);
-				server_loop(server_name, listener, handler, must_exit).await
+				server_loop(
+					server_name,
+					listener,
+					handler,
+					header_limits,
+					max_requests_per_connection,
+					must_exit,
+				)
+				.await
 			}
 		}
 	}
@@ -141,6 +254,20 @@ impl<A: ApiHandler> ApiServer<A> {
 		req: Request<IncomingBody>,
 		addr: String,
 	) -> Result<Response<BoxBody<A::Error>>, http::Error> {
+		let request_id = hex::encode_upper(&gen_uuid().as_slice()[..8]);
+		let span = info_span!("request", request_id = %request_id);
+		self.handler_stage1(req, addr, RequestId(request_id))
+			.instrument(span)
+			.await
+	}
+
+	async fn handler_stage1(
+		&self,
+		mut req: Request<IncomingBody>,
+		addr: String,
+		request_id: RequestId,
+	) -> Result<Response<BoxBody<A::Error>>, http::Error> {
+		req.extensions_mut().insert(request_id);
 		let uri = req.uri().clone();
 
 		if let Ok(forwarded_for_ip_addr) =
@@ -168,13 +295,22 @@ impl<A: ApiHandler> ApiServer<A> {
 			])
 			.start(&tracer);
 
+		let hsts_header = self
+			.tls_policy
+			.as_ref()
+			.and_then(|p| p.hsts_header(&addr, req.headers()));
+
 		let res = self
-			.handler_stage2(req)
+			.handler_stage2(req, &addr)
 			.with_context(Context::current_with_span(span))
 			.await;
 
 		match res {
-			Ok(x) => {
+			Ok(mut x) => {
+				if let Some(hsts) = hsts_header {
+					x.headers_mut()
+						.insert(hyper::header::STRICT_TRANSPORT_SECURITY, hsts);
+				}
 				debug!("{} {:?}", x.status(), x.headers());
 				Ok(x)
 			}
@@ -183,7 +319,10 @@ impl<A: ApiHandler> ApiServer<A> {
 				let mut http_error_builder = Response::builder().status(e.http_status_code());
 
 				if let Some(header_map) = http_error_builder.headers_mut() {
-					e.add_http_headers(header_map)
+					e.add_http_headers(header_map);
+					if let Some(hsts) = hsts_header {
+						header_map.insert(hyper::header::STRICT_TRANSPORT_SECURITY, hsts);
+					}
 				}
 
 				let http_error = http_error_builder.body(body)?;
@@ -201,8 +340,29 @@ impl<A: ApiHandler> ApiServer<A> {
 
 	async fn handler_stage2(
 		&self,
-		req: Request<IncomingBody>,
+		mut req: Request<IncomingBody>,
+		addr: &str,
 	) -> Result<Response<BoxBody<A::Error>>, A::Error> {
+		if let Some(policy) = &self.tls_policy {
+			if policy.must_reject(addr, req.headers()) {
+				return Err(A::Error::from(CommonError::Forbidden(
+					"Requests to this endpoint must be made over HTTPS".into(),
+				)));
+			}
+			if policy.must_reject_unsigned_payload(addr, req.headers()) {
+				return Err(A::Error::from(CommonError::Forbidden(
+					"Requests with an unsigned payload (UNSIGNED-PAYLOAD) must be made over HTTPS"
+						.into(),
+				)));
+			}
+			if let Some(reason) = policy.reject_signed_host(addr, req.headers(), req.uri().query())
+			{
+				return Err(A::Error::from(CommonError::Forbidden(reason)));
+			}
+		}
+
+		req.extensions_mut().insert(ClientAddr(addr.to_string()));
+
 		let endpoint = self.api_handler.parse_endpoint(&req)?;
 		debug!("Endpoint: {}", endpoint.name());
 
@@ -218,14 +378,45 @@ impl<A: ApiHandler> ApiServer<A> {
 
 		let metrics_tags = &[KeyValue::new("api_endpoint", endpoint.name())];
 
-		let res = self
-			.api_handler
-			.handle(req, endpoint)
-			.record_duration(&self.request_duration, &metrics_tags[..])
-			.await;
+		let endpoint_name = endpoint.name();
+		let bucket_name = endpoint.bucket_name().map(str::to_string);
+		let request_bytes = content_length(req.headers());
+		let request_start = Instant::now();
+
+		let in_flight = InFlightGuard::new(&self.requests_in_flight, metrics_tags);
+		let (res, phases) = collect_request_phases(
+			self.api_handler
+				.handle(req, endpoint)
+				.record_duration(&self.request_duration, &metrics_tags[..]),
+		)
+		.await;
+		drop(in_flight);
 
 		self.request_counter.add(1, &metrics_tags[..]);
 
+		let elapsed = Instant::now().saturating_duration_since(request_start);
+		if self.slow_request_threshold.is_some_and(|t| elapsed >= t) {
+			let slowest_phase = phases
+				.iter()
+				.max_by_key(|(_, d)| **d)
+				.map(|(p, d)| format!("{} ({:.3}s)", p, d.as_secs_f64()))
+				.unwrap_or_else(|| "none".into());
+			let response_bytes = match &res {
+				Ok(r) => content_length(r.headers()),
+				Err(_) => None,
+			};
+			warn!(
+				"Slow {} API request: {} on bucket {} took {:.3}s (request bytes: {}, response bytes: {}, slowest phase: {})",
+				A::API_NAME_DISPLAY,
+				endpoint_name,
+				bucket_name.as_deref().unwrap_or("-"),
+				elapsed.as_secs_f64(),
+				fmt_bytes(request_bytes),
+				fmt_bytes(response_bytes),
+				slowest_phase,
+			);
+		}
+
 		let status_code = match &res {
 			Ok(r) => r.status(),
 			Err(e) => e.http_status_code(),
@@ -246,6 +437,54 @@ impl<A: ApiHandler> ApiServer<A> {
 
 // ==== helper functions ====
 
+/// Counts a request as in flight as long as it is not dropped,
+/// including when the request future is cancelled
+struct InFlightGuard<'a> {
+	counter: &'a UpDownCounter<i64>,
+	tags: &'a [KeyValue],
+}
+
+impl<'a> InFlightGuard<'a> {
+	fn new(counter: &'a UpDownCounter<i64>, tags: &'a [KeyValue]) -> Self {
+		counter.add(1, tags);
+		Self { counter, tags }
+	}
+}
+
+impl<'a> Drop for InFlightGuard<'a> {
+	fn drop(&mut self) {
+		self.counter.add(-1, self.tags);
+	}
+}
+
+/// Whether the client asks for the connection to be closed after the
+/// response to a request: HTTP/1.0 connections are only kept alive if
+/// the client asks for it, HTTP/1.1 connections unless it asks not to
+fn wants_close<B>(req: &Request<B>) -> bool {
+	let has_token = |token: &str| {
+		req.headers()
+			.get_all(CONNECTION)
+			.iter()
+			.filter_map(|v| v.to_str().ok())
+			.flat_map(|v| v.split(','))
+			.any(|t| t.trim().eq_ignore_ascii_case(token))
+	};
+	has_token("close") || (req.version() <= Version::HTTP_10 && !has_token("keep-alive"))
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+	headers
+		.get(hyper::header::CONTENT_LENGTH)
+		.and_then(|v| v.to_str().ok())
+		.and_then(|v| v.parse().ok())
+}
+
+fn fmt_bytes(bytes: Option<u64>) -> String {
+	bytes
+		.map(|b| b.to_string())
+		.unwrap_or_else(|| "unknown".into())
+}
+
 #[async_trait]
 pub trait Accept: Send + Sync + 'static {
 	type Stream: AsyncRead + AsyncWrite + Send + Sync + 'static;
@@ -279,6 +518,8 @@ pub async fn server_loop<A, H, F, E>(
 	server_name: String,
 	listener: A,
 	handler: H,
+	header_limits: HeaderLimits,
+	max_requests_per_connection: Option<u64>,
 	mut must_exit: watch::Receiver<bool>,
 ) -> Result<(), GarageError>
 where
@@ -343,7 +584,40 @@ where
 		let io = TokioIo::new(stream);
 
 		let handler = handler.clone();
-		let serve = move |req: Request<IncomingBody>| handler(req, client_addr.clone());
+		let request_count = AtomicU64::new(0);
+		let serve = move |req: Request<IncomingBody>| {
+			// The connection is closed after the response if the client asks
+			// for it, or once it has been used for the maximum number of requests
+			let count = request_count.fetch_add(1, Ordering::Relaxed) + 1;
+			let close =
+				wants_close(&req) || max_requests_per_connection.is_some_and(|m| count >= m);
+
+			let handler_fut = match header_limits.check(req.headers()) {
+				Ok(()) => Some(handler(req, client_addr.clone())),
+				Err(e) => {
+					info!(
+						"{} Rejecting request with too large headers: {}",
+						client_addr, e
+					);
+					None
+				}
+			};
+			async move {
+				let mut resp = match handler_fut {
+					Some(fut) => fut.await?,
+					None => Response::builder()
+						.status(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+						.body(empty_body())?,
+				};
+				if close {
+					// hyper closes the connection once a response
+					// with this header has been written
+					resp.headers_mut()
+						.insert(CONNECTION, HeaderValue::from_static("close"));
+				}
+				Ok::<_, http::Error>(resp)
+			}
+		};
 
 		let fut = tokio::task::spawn(async move {
 			let io = Box::pin(io);
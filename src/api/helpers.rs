@@ -6,11 +6,11 @@ use futures::{Stream, StreamExt, TryStreamExt};
 use http_body_util::{BodyExt, Full as FullBody};
 use hyper::{
 	body::{Body, Bytes},
-	Request, Response,
+	Method, Request, Response,
 };
-use idna::domain_to_unicode;
 use serde::{Deserialize, Serialize};
 
+use garage_model::bucket_alias_table::canonical_bucket_name;
 use garage_model::bucket_table::BucketParams;
 use garage_model::garage::Garage;
 use garage_model::key_table::Key;
@@ -26,6 +26,8 @@ pub enum Authorization {
 	None,
 	/// Having Read permission on bucket
 	Read,
+	/// Having List permission on bucket
+	List,
 	/// Having Write permission on bucket
 	Write,
 	/// Having Owner permission on bucket
@@ -39,6 +41,38 @@ pub struct ReqCtx {
 	pub bucket_name: String,
 	pub bucket_params: BucketParams,
 	pub api_key: Key,
+	/// Key of the object targeted by the request with its original casing,
+	/// if the bucket has case-insensitive keys (the key passed to request
+	/// handlers is then lowercased)
+	pub original_key: Option<String>,
+}
+
+/// Refuse requests while this node is partitioned from the majority of the
+/// nodes of the cluster layout, if `minority_partition_protection` is enabled.
+/// Reads are requests that only require a read or list permission and
+/// GET or HEAD requests, all other requests are writes.
+pub fn check_minority_partition(
+	garage: &Garage,
+	authorization: &Authorization,
+	method: &Method,
+) -> Result<(), Error> {
+	let guard = &garage.system.minority_partition;
+	let is_read = matches!(
+		authorization,
+		Authorization::None | Authorization::Read | Authorization::List
+	) || *method == Method::GET
+		|| *method == Method::HEAD;
+	let refused = if is_read {
+		guard.refuses_reads()
+	} else {
+		guard.refuses_writes()
+	};
+	if refused {
+		return Err(Error::ServiceUnavailable(
+			"this node is partitioned from the majority of the cluster".into(),
+		));
+	}
+	Ok(())
 }
 
 /// Host to bucket
@@ -47,28 +81,60 @@ pub struct ReqCtx {
 /// considering that ".garage-site.tld" is the "root domain". For domains not matching
 /// the provided root domain, no bucket is returned
 /// This behavior has been chosen to follow AWS S3 semantic.
+///
+/// The root domain is matched case-insensitively, and IP addresses never
+/// designate a bucket: requests made to an IP address are path-style.
+/// An international root domain is matched in its punycode form, which is
+/// the form of hosts returned by `authority_to_host`.
 pub fn host_to_bucket<'a>(host: &'a str, root: &str) -> Option<&'a str> {
-	let root = root.trim_start_matches('.');
+	if is_ip_literal(host) {
+		return None;
+	}
+	let root = canonical_bucket_name(root);
+	let root = root.trim_start_matches('.').trim_end_matches('.');
 	let label_root = root.chars().filter(|c| c == &'.').count() + 1;
 	let root = root.rsplit('.');
 	let mut host = host.rsplitn(label_root + 1, '.');
 	for root_part in root {
 		let host_part = host.next()?;
-		if root_part != host_part {
+		if !root_part.eq_ignore_ascii_case(host_part) {
 			return None;
 		}
 	}
-	host.next()
+	host.next().filter(|bucket| !bucket.is_empty())
+}
+
+/// Check if a host, as returned by `authority_to_host`, is an IPv4 address
+/// or an IPv6 address between brackets
+fn is_ip_literal(host: &str) -> bool {
+	match host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+		Some(ipv6) => ipv6.parse::<std::net::Ipv6Addr>().is_ok(),
+		None => host.parse::<std::net::Ipv4Addr>().is_ok(),
+	}
+}
+
+/// Get the authority given by the Host header of a request. International
+/// domain names are accepted in UTF-8 as well as in punycode.
+pub fn host_header<B>(req: &Request<B>) -> Result<&str, Error> {
+	let host = req
+		.headers()
+		.get(hyper::header::HOST)
+		.ok_or_bad_request("Host header required")?;
+	std::str::from_utf8(host.as_bytes()).ok_or_bad_request("Invalid Host header")
 }
 
 /// Extract host from the authority section given by the HTTP host header
 ///
+/// The host is normalized: it is lowercased, the trailing dot of
+/// fully qualified domain names is removed, and international domain
+/// names are converted to punycode, the canonical form of bucket names.
+///
 /// The HTTP host contains both a host and a port.
 /// Extracting the port is more complex than just finding the colon (:) symbol due to IPv6
 /// We do not use the collect pattern as there is no way in std rust to collect over a stack allocated value
 /// check here: <https://docs.rs/collect_slice/1.2.0/collect_slice/>
 pub fn authority_to_host(authority: &str) -> Result<String, Error> {
-	let mut iter = authority.chars().enumerate();
+	let mut iter = authority.char_indices();
 	let (_, first_char) = iter
 		.next()
 		.ok_or_else(|| Error::bad_request("Authority is empty".to_string()))?;
@@ -97,7 +163,10 @@ pub fn authority_to_host(authority: &str) -> Result<String, Error> {
 			authority
 		))),
 	};
-	authority.map(|h| domain_to_unicode(h).0)
+	authority.map(|h| {
+		let h = h.strip_suffix('.').unwrap_or(h).to_lowercase();
+		canonical_bucket_name(&h).into_owned()
+	})
 }
 
 /// Extract the bucket name and the key name from an HTTP path and possibly a bucket provided in
@@ -302,6 +371,37 @@ mod tests {
 		Ok(())
 	}
 
+	#[test]
+	fn authority_to_host_normalization() -> Result<(), Error> {
+		assert_eq!(
+			authority_to_host("Bucket.S3.Garage.TLD")?,
+			"bucket.s3.garage.tld"
+		);
+		assert_eq!(
+			authority_to_host("bucket.s3.garage.tld.")?,
+			"bucket.s3.garage.tld"
+		);
+		assert_eq!(
+			authority_to_host("bucket.s3.garage.tld.:3900")?,
+			"bucket.s3.garage.tld"
+		);
+		assert_eq!(
+			authority_to_host("BUCKET.s3.garage.tld.:3900")?,
+			"bucket.s3.garage.tld"
+		);
+		assert_eq!(authority_to_host("[::FFFF:1]:3900")?, "[::ffff:1]");
+		assert_eq!(
+			authority_to_host("Bücher.s3.garage.tld:3900")?,
+			"xn--bcher-kva.s3.garage.tld"
+		);
+		assert_eq!(
+			authority_to_host("xn--bcher-kva.s3.garage.tld")?,
+			"xn--bcher-kva.s3.garage.tld"
+		);
+		assert_eq!(authority_to_host("127.0.0.1.")?, "127.0.0.1");
+		Ok(())
+	}
+
 	#[test]
 	fn host_to_bucket_test() {
 		assert_eq!(
@@ -324,6 +424,72 @@ mod tests {
 
 		assert_eq!(host_to_bucket("not-garage.tld", "garage.tld"), None);
 		assert_eq!(host_to_bucket("not-garage.tld", ".garage.tld"), None);
+
+		// The root domain is compared case-insensitively
+		assert_eq!(
+			host_to_bucket("john.doe.garage.tld", ".Garage.TLD").unwrap(),
+			"john.doe"
+		);
+		assert_eq!(
+			host_to_bucket("john.doe.garage.tld", "garage.tld.").unwrap(),
+			"john.doe"
+		);
+		assert_eq!(host_to_bucket(".garage.tld", "garage.tld"), None);
+	}
+
+	#[test]
+	fn host_to_bucket_with_authority() -> Result<(), Error> {
+		let bucket = |authority: &str, root: &str| -> Result<Option<String>, Error> {
+			let host = authority_to_host(authority)?;
+			Ok(host_to_bucket(&host, root).map(str::to_string))
+		};
+
+		for root in ["s3.garage.tld", ".s3.garage.tld", "S3.Garage.tld"] {
+			for authority in [
+				"bucket.s3.garage.tld",
+				"bucket.s3.garage.tld:3900",
+				"bucket.s3.garage.tld.",
+				"bucket.s3.garage.tld.:3900",
+				"bucket.s3.garage.tld:3900.",
+				"Bucket.S3.Garage.TLD",
+				"BUCKET.S3.GARAGE.TLD.:3900",
+			] {
+				assert_eq!(bucket(authority, root)?.as_deref(), Some("bucket"));
+			}
+
+			for authority in [
+				"s3.garage.tld",
+				"s3.garage.tld:3900",
+				"S3.GARAGE.TLD.",
+				"127.0.0.1",
+				"127.0.0.1:3900",
+				"[::1]",
+				"[::1]:3900",
+				"[2001:db8::1]:3900",
+			] {
+				assert_eq!(bucket(authority, root)?, None);
+			}
+		}
+
+		// IP addresses are never treated as a vhost-style bucket,
+		// even when the root domain would match them
+		assert_eq!(bucket("10.0.0.1", "0.1")?, None);
+		assert_eq!(bucket("10.0.0.1:3900", "0.0.1")?, None);
+		assert_eq!(bucket("[::1]:3900", "1]")?, None);
+
+		// A bucket named like an IPv4 address can be accessed path-style
+		let host = authority_to_host("192.168.1.1:3900")?;
+		let vhost_bucket = host_to_bucket(&host, "s3.garage.tld");
+		assert_eq!(vhost_bucket, None);
+		let (bucket, key) = parse_bucket_key("/10.0.0.1/some/key", vhost_bucket)?;
+		assert_eq!(bucket, "10.0.0.1");
+		assert_eq!(key, Some("some/key"));
+
+		// ... or vhost-style, under the root domain
+		let host = authority_to_host("10.0.0.1.s3.garage.tld:3900")?;
+		assert_eq!(host_to_bucket(&host, "s3.garage.tld"), Some("10.0.0.1"));
+
+		Ok(())
 	}
 
 	#[test]
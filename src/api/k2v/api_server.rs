@@ -5,9 +5,10 @@ use async_trait::async_trait;
 use hyper::{body::Incoming as IncomingBody, Method, Request, Response};
 use tokio::sync::watch;
 
-use opentelemetry::{trace::SpanRef, KeyValue};
+use opentelemetry::{global, metrics::Counter, trace::SpanRef, KeyValue};
 
 use garage_util::error::Error as GarageError;
+use garage_util::metrics::timed_phase;
 use garage_util::socket_address::UnixOrTCPSocketAddress;
 
 use garage_model::garage::Garage;
@@ -21,6 +22,7 @@ use crate::helpers::*;
 use crate::k2v::batch::*;
 use crate::k2v::index::*;
 use crate::k2v::item::*;
+use crate::k2v::limits::K2VLimits;
 use crate::k2v::router::Endpoint;
 use crate::s3::cors::*;
 
@@ -29,6 +31,8 @@ pub type ResBody = BoxBody<Error>;
 
 pub struct K2VApiServer {
 	garage: Arc<Garage>,
+	limits: K2VLimits,
+	limit_rejection_counter: Counter<u64>,
 }
 
 pub(crate) struct K2VApiEndpoint {
@@ -43,9 +47,30 @@ impl K2VApiServer {
 		s3_region: String,
 		must_exit: watch::Receiver<bool>,
 	) -> Result<(), GarageError> {
-		ApiServer::new(s3_region, K2VApiServer { garage })
-			.run_server(bind_addr, None, must_exit)
-			.await
+		let slow_request_threshold_msec = garage.config.admin.slow_request_threshold_msec;
+		let k2v_config = garage.config.k2v_api.as_ref();
+		let header_limits = HeaderLimits::new(
+			k2v_config.and_then(|c| c.max_header_size),
+			k2v_config.and_then(|c| c.max_header_count),
+		);
+		let limits = K2VLimits::from_config(k2v_config);
+		let limit_rejection_counter = global::meter("garage/api")
+			.u64_counter("api.k2v.limit_rejection_counter")
+			.with_description(
+				"Number of K2V insertions rejected because they exceed a limit on the size of items",
+			)
+			.init();
+		ApiServer::new(
+			s3_region,
+			K2VApiServer {
+				garage,
+				limits,
+				limit_rejection_counter,
+			},
+			slow_request_threshold_msec,
+		)
+		.run_server(bind_addr, None, header_limits, None, must_exit)
+		.await
 	}
 }
 
@@ -60,6 +85,22 @@ impl ApiHandler for K2VApiServer {
 	fn parse_endpoint(&self, req: &Request<IncomingBody>) -> Result<K2VApiEndpoint, Error> {
 		let (endpoint, bucket_name) = Endpoint::from_request(req)?;
 
+		let read_only = self
+			.garage
+			.config
+			.k2v_api
+			.as_ref()
+			.map(|c| c.read_only)
+			.unwrap_or(false);
+		if read_only && endpoint.is_mutation() {
+			return Err(Error::forbidden(format!(
+				"{} is not allowed: the K2V API of this node is read-only",
+				endpoint.name()
+			)));
+		}
+
+		check_minority_partition(&self.garage, &endpoint.authorization_type(), req.method())?;
+
 		Ok(K2VApiEndpoint {
 			bucket_name,
 			endpoint,
@@ -85,7 +126,8 @@ impl ApiHandler for K2VApiServer {
 			return Ok(options_res.map(|_empty_body: EmptyBody| empty_body()));
 		}
 
-		let (req, api_key, _content_sha256) = verify_request(&garage, req, "k2v").await?;
+		let (req, api_key, _content_sha256) =
+			timed_phase("signature", verify_request(&garage, req, "k2v")).await?;
 
 		let bucket_id = garage
 			.bucket_helper()
@@ -108,6 +150,12 @@ impl ApiHandler for K2VApiServer {
 			return Err(Error::forbidden("Operation is not allowed for this key."));
 		}
 
+		if endpoint.authorization_type() != Authorization::Read && *bucket_params.read_only.get() {
+			return Err(Error::forbidden(
+				"Bucket is read-only: items cannot be modified.",
+			));
+		}
+
 		// Look up what CORS rule might apply to response.
 		// Requests for methods different than GET, HEAD or POST
 		// are always preflighted, i.e. the browser should make
@@ -116,7 +164,7 @@ impl ApiHandler for K2VApiServer {
 			Method::GET | Method::HEAD | Method::POST => {
 				find_matching_cors_rule(&bucket_params, &req)
 					.ok_or_internal_error("Error looking up CORS rule")?
-					.cloned()
+					.map(|(rule, origin)| (rule.clone(), origin.to_string()))
 			}
 			_ => None,
 		};
@@ -127,6 +175,7 @@ impl ApiHandler for K2VApiServer {
 			bucket_name,
 			bucket_params,
 			api_key,
+			original_key: None,
 		};
 
 		let resp = match endpoint {
@@ -137,7 +186,7 @@ impl ApiHandler for K2VApiServer {
 			Endpoint::InsertItem {
 				partition_key,
 				sort_key,
-			} => handle_insert_item(ctx, req, &partition_key, &sort_key).await,
+			} => handle_insert_item(ctx, req, &partition_key, &sort_key, &self.limits).await,
 			Endpoint::ReadItem {
 				partition_key,
 				sort_key,
@@ -157,7 +206,7 @@ impl ApiHandler for K2VApiServer {
 				limit,
 				reverse,
 			} => handle_read_index(ctx, prefix, start, end, limit, reverse).await,
-			Endpoint::InsertBatch {} => handle_insert_batch(ctx, req).await,
+			Endpoint::InsertBatch {} => handle_insert_batch(ctx, req, &self.limits).await,
 			Endpoint::ReadBatch {} => handle_read_batch(ctx, req).await,
 			Endpoint::DeleteBatch {} => handle_delete_batch(ctx, req).await,
 			Endpoint::PollRange { partition_key } => {
@@ -166,11 +215,18 @@ impl ApiHandler for K2VApiServer {
 			Endpoint::Options => unreachable!(),
 		};
 
+		if let Err(e) = &resp {
+			for limit in e.exceeded_limits() {
+				self.limit_rejection_counter
+					.add(1, &[KeyValue::new("limit", limit)]);
+			}
+		}
+
 		// If request was a success and we have a CORS rule that applies to it,
 		// add the corresponding CORS headers to the response
 		let mut resp_ok = resp?;
-		if let Some(rule) = matching_cors_rule {
-			add_cors_headers(&mut resp_ok, &rule)
+		if let Some((rule, origin)) = matching_cors_rule {
+			add_cors_headers(&mut resp_ok, &rule, &origin)
 				.ok_or_internal_error("Invalid bucket CORS configuration")?;
 		}
 
@@ -186,4 +242,8 @@ impl ApiEndpoint for K2VApiEndpoint {
 	fn add_span_attributes(&self, span: SpanRef<'_>) {
 		span.set_attribute(KeyValue::new("bucket", self.bucket_name.clone()));
 	}
+
+	fn bucket_name(&self) -> Option<&str> {
+		Some(&self.bucket_name)
+	}
 }
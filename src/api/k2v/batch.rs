@@ -10,11 +10,13 @@ use garage_model::k2v::item_table::*;
 use crate::helpers::*;
 use crate::k2v::api_server::{ReqBody, ResBody};
 use crate::k2v::error::*;
+use crate::k2v::limits::*;
 use crate::k2v::range::read_range;
 
 pub async fn handle_insert_batch(
 	ctx: ReqCtx,
 	req: Request<ReqBody>,
+	limits: &K2VLimits,
 ) -> Result<Response<ResBody>, Error> {
 	let ReqCtx {
 		garage, bucket_id, ..
@@ -35,6 +37,44 @@ pub async fn handle_insert_batch(
 		items2.push((it.pk, it.sk, ct, v));
 	}
 
+	// All entries are checked before any of them is inserted
+	let current_items =
+		futures::future::try_join_all(items2.iter().map(|(pk, sk, _, v)| async move {
+			if !limits.need_item(v) {
+				return Ok(None);
+			}
+			let partition = K2VItemPartition {
+				bucket_id: *bucket_id,
+				partition_key: pk.clone(),
+			};
+			garage.k2v.item_table.get(&partition, sk).await
+		}))
+		.await?;
+	let violations = items2
+		.iter()
+		.zip(current_items.iter())
+		.enumerate()
+		.filter_map(|(index, ((pk, sk, ct, v), current))| {
+			let violation = limits.check_insert(current.as_ref(), ct, v).err()?;
+			Some(BatchEntryViolation {
+				index,
+				pk: pk.clone(),
+				sk: sk.clone(),
+				limit: violation.limit.name(),
+				message: violation.to_string(),
+			})
+		})
+		.collect::<Vec<_>>();
+	if !violations.is_empty() {
+		let msg = format!(
+			"{} entries of the batch exceed limits, no entry was inserted (first: entry {}, {})",
+			violations.len(),
+			violations[0].index,
+			violations[0].message
+		);
+		return Err(Error::BatchLimitExceeded(msg, violations));
+	}
+
 	garage.k2v.rpc.insert_batch(*bucket_id, items2).await?;
 
 	Ok(Response::builder()
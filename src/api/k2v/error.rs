@@ -6,6 +6,7 @@ use crate::common_error::CommonError;
 pub use crate::common_error::{CommonErrorDerivative, OkOrBadRequest, OkOrInternalError};
 use crate::generic_server::ApiError;
 use crate::helpers::*;
+use crate::k2v::limits::{BatchEntryViolation, LimitViolation};
 use crate::signature::error::Error as SignatureError;
 
 /// Errors of this crate
@@ -35,6 +36,15 @@ pub enum Error {
 	/// The request contained an invalid UTF-8 sequence in its path or in other parameters
 	#[error(display = "Invalid UTF-8: {}", _0)]
 	InvalidUtf8Str(#[error(source)] std::str::Utf8Error),
+
+	/// An inserted value exceeds one of the limits on the size of items
+	#[error(display = "Limit exceeded: {}", _0)]
+	LimitExceeded(LimitViolation),
+
+	/// Some entries of an InsertBatch request exceed the limits on the
+	/// size of items (no entry of the batch is inserted)
+	#[error(display = "Limit exceeded: {}", _0)]
+	BatchLimitExceeded(String, Vec<BatchEntryViolation>),
 }
 
 impl<T> From<T> for Error
@@ -72,6 +82,16 @@ impl Error {
 			Error::AuthorizationHeaderMalformed(_) => "AuthorizationHeaderMalformed",
 			Error::InvalidBase64(_) => "InvalidBase64",
 			Error::InvalidUtf8Str(_) => "InvalidUtf8String",
+			Error::LimitExceeded(_) | Error::BatchLimitExceeded(..) => "LimitExceeded",
+		}
+	}
+
+	/// Names of the limits that caused the request to be rejected
+	pub(crate) fn exceeded_limits(&self) -> Vec<&'static str> {
+		match self {
+			Error::LimitExceeded(v) => vec![v.limit.name()],
+			Error::BatchLimitExceeded(_, entries) => entries.iter().map(|e| e.limit).collect(),
+			_ => vec![],
 		}
 	}
 }
@@ -85,7 +105,9 @@ impl ApiError for Error {
 			Error::NotAcceptable(_) => StatusCode::NOT_ACCEPTABLE,
 			Error::AuthorizationHeaderMalformed(_)
 			| Error::InvalidBase64(_)
-			| Error::InvalidUtf8Str(_) => StatusCode::BAD_REQUEST,
+			| Error::InvalidUtf8Str(_)
+			| Error::LimitExceeded(_)
+			| Error::BatchLimitExceeded(..) => StatusCode::BAD_REQUEST,
 		}
 	}
 
@@ -101,7 +123,17 @@ impl ApiError for Error {
 			path: path.to_string(),
 			region: garage_region.to_string(),
 		};
-		let error_str = serde_json::to_string_pretty(&error).unwrap_or_else(|_| {
+		let error_str = match self {
+			// Detail which entries of the batch exceed the limits
+			Error::BatchLimitExceeded(_, entries) => {
+				serde_json::to_value(&error).and_then(|mut v| {
+					v["entries"] = serde_json::to_value(entries)?;
+					serde_json::to_string_pretty(&v)
+				})
+			}
+			_ => serde_json::to_string_pretty(&error),
+		};
+		let error_str = error_str.unwrap_or_else(|_| {
 			r#"
 {
 	"code": "InternalError",
@@ -9,6 +9,7 @@ use garage_model::k2v::item_table::*;
 use crate::helpers::*;
 use crate::k2v::api_server::{ReqBody, ResBody};
 use crate::k2v::error::*;
+use crate::k2v::limits::K2VLimits;
 
 pub const X_GARAGE_CAUSALITY_TOKEN: &str = "X-Garage-Causality-Token";
 
@@ -127,6 +128,7 @@ pub async fn handle_insert_item(
 	req: Request<ReqBody>,
 	partition_key: &str,
 	sort_key: &str,
+	limits: &K2VLimits,
 ) -> Result<Response<ResBody>, Error> {
 	let ReqCtx {
 		garage, bucket_id, ..
@@ -145,6 +147,25 @@ pub async fn handle_insert_item(
 
 	let value = DvvsValue::Value(body.to_vec());
 
+	let current = if limits.need_item(&value) {
+		garage
+			.k2v
+			.item_table
+			.get(
+				&K2VItemPartition {
+					bucket_id: *bucket_id,
+					partition_key: partition_key.to_string(),
+				},
+				&sort_key.to_string(),
+			)
+			.await?
+	} else {
+		None
+	};
+	limits
+		.check_insert(current.as_ref(), &causal_context, &value)
+		.map_err(Error::LimitExceeded)?;
+
 	garage
 		.k2v
 		.rpc
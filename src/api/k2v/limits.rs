@@ -0,0 +1,247 @@
+//! Limits on the size of K2V items, checked when values are inserted.
+//!
+//! Deletions are never refused, so that items that exceed the limits
+//! (e.g. because they were written before the limits were set) can
+//! still be cleaned up.
+use std::fmt;
+
+use serde::Serialize;
+
+use garage_util::config::K2VApiConfig;
+use garage_util::data::Uuid;
+
+use garage_model::k2v::causality::*;
+use garage_model::k2v::item_table::*;
+
+/// One of the limits on the size of K2V items
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum K2VLimit {
+	ValueSize,
+	ConcurrentValues,
+	ItemTotalSize,
+}
+
+impl K2VLimit {
+	/// Name of the limit, as in the configuration file
+	pub fn name(&self) -> &'static str {
+		match self {
+			Self::ValueSize => "max_value_size",
+			Self::ConcurrentValues => "max_concurrent_values_per_item",
+			Self::ItemTotalSize => "max_item_total_size",
+		}
+	}
+}
+
+/// An insertion that would exceed one of the limits
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LimitViolation {
+	pub limit: K2VLimit,
+	/// Value that the insertion would reach
+	pub actual: usize,
+	/// Maximum allowed value
+	pub max: usize,
+}
+
+impl fmt::Display for LimitViolation {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self.limit {
+			K2VLimit::ValueSize => write!(f, "value size is {} bytes", self.actual)?,
+			K2VLimit::ConcurrentValues => {
+				write!(f, "item would have {} concurrent values", self.actual)?
+			}
+			K2VLimit::ItemTotalSize => {
+				write!(f, "values of item would total {} bytes", self.actual)?
+			}
+		}
+		write!(f, ", more than {} ({})", self.limit.name(), self.max)
+	}
+}
+
+/// An entry of an InsertBatch request that exceeds one of the limits
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchEntryViolation {
+	/// Position of the entry in the batch
+	pub index: usize,
+	pub pk: String,
+	pub sk: String,
+	pub limit: &'static str,
+	pub message: String,
+}
+
+/// Limits on the size of K2V items, from the `[k2v_api]` section
+/// of the configuration (no limit if not set)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct K2VLimits {
+	pub max_value_size: Option<usize>,
+	pub max_concurrent_values_per_item: Option<usize>,
+	pub max_item_total_size: Option<usize>,
+}
+
+impl K2VLimits {
+	pub fn from_config(config: Option<&K2VApiConfig>) -> Self {
+		match config {
+			None => Self::default(),
+			Some(c) => Self {
+				max_value_size: c.max_value_size,
+				max_concurrent_values_per_item: c.max_concurrent_values_per_item,
+				max_item_total_size: c.max_item_total_size,
+			},
+		}
+	}
+
+	/// Whether the current state of an item must be read
+	/// to check the insertion of a value
+	pub fn need_item(&self, value: &DvvsValue) -> bool {
+		matches!(value, DvvsValue::Value(_))
+			&& (self.max_concurrent_values_per_item.is_some() || self.max_item_total_size.is_some())
+	}
+
+	/// Check that inserting `value` with causality context `context` in
+	/// an item whose current state is `current` does not exceed the limits
+	pub fn check_insert(
+		&self,
+		current: Option<&K2VItem>,
+		context: &Option<CausalContext>,
+		value: &DvvsValue,
+	) -> Result<(), LimitViolation> {
+		let v = match value {
+			DvvsValue::Value(v) => v,
+			DvvsValue::Deleted => return Ok(()),
+		};
+
+		check(K2VLimit::ValueSize, v.len(), self.max_value_size)?;
+
+		if self.need_item(value) {
+			// Compute the values of the item after the insertion,
+			// as done by the storage nodes
+			let mut item = match current {
+				Some(i) => i.clone(),
+				None => K2VItem::new(Uuid::from([0u8; 32]), String::new(), String::new()),
+			};
+			item.update(Uuid::from([0u8; 32]), context, value.clone(), 0);
+			let values = item.values();
+
+			check(
+				K2VLimit::ConcurrentValues,
+				values.len(),
+				self.max_concurrent_values_per_item,
+			)?;
+
+			let total_size = values
+				.iter()
+				.map(|v| match v {
+					DvvsValue::Value(v) => v.len(),
+					DvvsValue::Deleted => 0,
+				})
+				.sum();
+			check(
+				K2VLimit::ItemTotalSize,
+				total_size,
+				self.max_item_total_size,
+			)?;
+		}
+
+		Ok(())
+	}
+}
+
+fn check(limit: K2VLimit, actual: usize, max: Option<usize>) -> Result<(), LimitViolation> {
+	match max {
+		Some(max) if actual > max => Err(LimitViolation { limit, actual, max }),
+		_ => Ok(()),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn node(i: u8) -> Uuid {
+		Uuid::from([i; 32])
+	}
+
+	fn value(len: usize) -> DvvsValue {
+		DvvsValue::Value(vec![0x42; len])
+	}
+
+	#[test]
+	fn test_value_size_limit() {
+		let limits = K2VLimits {
+			max_value_size: Some(1024),
+			..Default::default()
+		};
+		assert!(limits.check_insert(None, &None, &value(1024)).is_ok());
+		assert_eq!(
+			limits.check_insert(None, &None, &value(1025)),
+			Err(LimitViolation {
+				limit: K2VLimit::ValueSize,
+				actual: 1025,
+				max: 1024,
+			})
+		);
+		assert!(limits
+			.check_insert(None, &None, &DvvsValue::Deleted)
+			.is_ok());
+		assert!(!limits.need_item(&value(10)));
+	}
+
+	#[test]
+	fn test_concurrent_values_limit() {
+		let limits = K2VLimits {
+			max_concurrent_values_per_item: Some(3),
+			..Default::default()
+		};
+
+		// Two concurrent values written by two nodes
+		let mut item = K2VItem::new(node(0), "pk".into(), "sk".into());
+		item.update(node(1), &None, value(1), 0);
+		item.update(node(2), &None, value(2), 0);
+		assert_eq!(item.values().len(), 2);
+
+		// A third concurrent value is accepted, a fourth one is not
+		assert!(limits.check_insert(Some(&item), &None, &value(3)).is_ok());
+		item.update(node(1), &None, value(3), 0);
+		assert_eq!(
+			limits.check_insert(Some(&item), &None, &value(4)),
+			Err(LimitViolation {
+				limit: K2VLimit::ConcurrentValues,
+				actual: 4,
+				max: 3,
+			})
+		);
+
+		// Writing with the causality token of the item replaces all values
+		let ct = Some(item.causal_context());
+		assert!(limits.check_insert(Some(&item), &ct, &value(4)).is_ok());
+
+		// The item can always be deleted
+		assert!(limits
+			.check_insert(Some(&item), &None, &DvvsValue::Deleted)
+			.is_ok());
+	}
+
+	#[test]
+	fn test_item_total_size_limit() {
+		let limits = K2VLimits {
+			max_item_total_size: Some(100),
+			..Default::default()
+		};
+
+		let mut item = K2VItem::new(node(0), "pk".into(), "sk".into());
+		item.update(node(1), &None, value(60), 0);
+
+		assert!(limits.check_insert(Some(&item), &None, &value(40)).is_ok());
+		assert_eq!(
+			limits
+				.check_insert(Some(&item), &None, &value(41))
+				.unwrap_err()
+				.limit,
+			K2VLimit::ItemTotalSize
+		);
+
+		let ct = Some(item.causal_context());
+		assert!(limits.check_insert(Some(&item), &ct, &value(100)).is_ok());
+		assert!(limits.check_insert(None, &None, &value(101)).is_err());
+	}
+}
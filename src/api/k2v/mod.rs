@@ -5,5 +5,6 @@ mod router;
 mod batch;
 mod index;
 mod item;
+mod limits;
 
 mod range;
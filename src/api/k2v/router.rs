@@ -233,6 +233,12 @@ impl Endpoint {
 			Authorization::Write
 		}
 	}
+
+	/// Whether this endpoint modifies items, and must be refused
+	/// if the API is read-only
+	pub fn is_mutation(&self) -> bool {
+		self.authorization_type() == Authorization::Write && !matches!(self, Endpoint::Options)
+	}
 }
 
 // parameter name => struct field
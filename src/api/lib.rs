@@ -8,8 +8,10 @@ mod encoding;
 pub mod generic_server;
 pub mod helpers;
 mod router_macros;
-/// This mode is public only to help testing. Don't expect stability here
+/// This mode is public only to help testing. Don't expect stability here,
+/// except for `signature::sign` which can be used by Rust clients to sign requests
 pub mod signature;
+mod tls_policy;
 
 pub mod admin;
 #[cfg(feature = "k2v")]
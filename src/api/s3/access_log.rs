@@ -0,0 +1,524 @@
+//! Server access logging: records of the requests made to buckets that have
+//! a logging configuration are buffered in memory by the node that handles
+//! them, and regularly delivered as objects in the target bucket.
+//!
+//! Delivery is best-effort: records are dropped when the buffers of the node
+//! are full, when the target bucket no longer exists, or when writing the log
+//! object fails. Log objects are written directly in the target bucket, without
+//! going through the S3 API, so that deliveries are never logged themselves.
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{TimeZone, Utc};
+use futures::stream;
+use hyper::header::{AUTHORIZATION, HOST, REFERER, USER_AGENT};
+use hyper::Request;
+use tokio::sync::{watch, Notify};
+
+use garage_model::bucket_table::LoggingConfig;
+use garage_model::garage::Garage;
+use garage_model::key_table::Key;
+use garage_model::s3::object_table::ObjectVersionMetaInner;
+use garage_table::EmptyKey;
+use garage_util::background::*;
+use garage_util::config::S3ApiConfig;
+use garage_util::data::*;
+use garage_util::error::Error as GarageError;
+
+use crate::encoding::uri_encode;
+use crate::helpers::ReqCtx;
+use crate::s3::encryption::EncryptionParams;
+use crate::s3::error::Error;
+use crate::s3::put::{save_stream, ChecksumMode};
+use crate::s3::recent_errors::RequestInfo;
+
+/// Interval after which buffered records are delivered, by default
+pub const DEFAULT_ACCESS_LOG_FLUSH_INTERVAL_SECS: u64 = 300;
+/// Size of the buffered records of a bucket above which they are delivered
+/// without waiting, by default
+pub const DEFAULT_ACCESS_LOG_FLUSH_SIZE: usize = 5 << 20;
+/// Maximum size of all buffered records, by default
+pub const DEFAULT_ACCESS_LOG_BUFFER_SIZE: usize = 64 << 20;
+
+pub struct AccessLogger {
+	flush_interval: Duration,
+	flush_size: usize,
+	buffer_size: usize,
+	state: Mutex<BufferState>,
+	notify: Notify,
+}
+
+#[derive(Default)]
+struct BufferState {
+	/// Buffered records, by source bucket
+	buffers: HashMap<Uuid, LogBuffer>,
+	total_size: usize,
+	dropped: u64,
+}
+
+struct LogBuffer {
+	target: LoggingConfig,
+	since: Instant,
+	records: String,
+}
+
+impl AccessLogger {
+	pub fn new(config: &S3ApiConfig) -> Self {
+		Self {
+			flush_interval: Duration::from_secs(
+				config
+					.access_log_flush_interval_secs
+					.unwrap_or(DEFAULT_ACCESS_LOG_FLUSH_INTERVAL_SECS),
+			),
+			flush_size: config
+				.access_log_flush_size
+				.unwrap_or(DEFAULT_ACCESS_LOG_FLUSH_SIZE),
+			buffer_size: config
+				.access_log_buffer_size
+				.unwrap_or(DEFAULT_ACCESS_LOG_BUFFER_SIZE),
+			state: Mutex::new(BufferState::default()),
+			notify: Notify::new(),
+		}
+	}
+
+	/// Number of records that have been dropped because the buffers were full
+	pub fn dropped(&self) -> u64 {
+		self.state.lock().unwrap().dropped
+	}
+
+	pub(crate) fn record(&self, record: AccessLogRecord) {
+		let line = record.to_string();
+
+		let mut state = self.state.lock().unwrap();
+		if state.total_size + line.len() > self.buffer_size {
+			state.dropped += 1;
+			return;
+		}
+		state.total_size += line.len();
+
+		let buffer = state
+			.buffers
+			.entry(record.bucket_id)
+			.or_insert_with(|| LogBuffer {
+				target: record.target.clone(),
+				since: Instant::now(),
+				records: String::new(),
+			});
+		// Records are delivered to the latest target of the bucket
+		buffer.target = record.target;
+		buffer.records.push_str(&line);
+		if buffer.records.len() >= self.flush_size {
+			self.notify.notify_one();
+		}
+	}
+
+	/// Take the buffers that are old enough or large enough to be delivered
+	fn take_ready(&self) -> Vec<LogBuffer> {
+		let mut state = self.state.lock().unwrap();
+		let ready = state
+			.buffers
+			.iter()
+			.filter(|(_, b)| {
+				b.since.elapsed() >= self.flush_interval || b.records.len() >= self.flush_size
+			})
+			.map(|(id, _)| *id)
+			.collect::<Vec<_>>();
+		let ready = ready
+			.into_iter()
+			.filter_map(|id| state.buffers.remove(&id))
+			.collect::<Vec<_>>();
+		state.total_size -= ready.iter().map(|b| b.records.len()).sum::<usize>();
+		ready
+	}
+}
+
+/// Record of a request made to a bucket that has access logging enabled
+pub(crate) struct AccessLogRecord {
+	pub bucket_id: Uuid,
+	pub bucket_name: String,
+	pub target: LoggingConfig,
+	pub time: u64,
+	pub remote_ip: String,
+	pub requester: Option<String>,
+	pub request_id: String,
+	pub operation: String,
+	pub key: Option<String>,
+	pub request_uri: String,
+	pub status: u16,
+	pub error_code: Option<&'static str>,
+	pub bytes_sent: Option<u64>,
+	pub total_time_msec: u128,
+	pub referer: Option<String>,
+	pub user_agent: Option<String>,
+	pub auth_type: Option<&'static str>,
+	pub host: Option<String>,
+}
+
+/// Information about a request to a bucket that has access logging enabled,
+/// gathered once the bucket is known, and completed into an `AccessLogRecord`
+/// once the response is known
+pub(crate) struct PendingAccessLog {
+	bucket_id: Uuid,
+	bucket_name: String,
+	target: LoggingConfig,
+	method: String,
+	request_uri: String,
+	referer: Option<String>,
+	user_agent: Option<String>,
+	auth_type: Option<&'static str>,
+	host: Option<String>,
+}
+
+impl PendingAccessLog {
+	pub(crate) fn new<B>(
+		req: &Request<B>,
+		bucket_id: Uuid,
+		bucket_name: &str,
+		target: LoggingConfig,
+	) -> Self {
+		let header = |name| {
+			req.headers()
+				.get(name)
+				.and_then(|v| v.to_str().ok())
+				.map(str::to_string)
+		};
+		let auth_type = if req.headers().contains_key(AUTHORIZATION) {
+			Some("AuthHeader")
+		} else if req
+			.uri()
+			.query()
+			.unwrap_or_default()
+			.contains("X-Amz-Signature=")
+		{
+			Some("QueryString")
+		} else {
+			None
+		};
+		Self {
+			bucket_id,
+			bucket_name: bucket_name.to_string(),
+			target,
+			method: req.method().to_string(),
+			request_uri: format!("{} {} {:?}", req.method(), req.uri(), req.version()),
+			referer: header(REFERER),
+			user_agent: header(USER_AGENT),
+			auth_type,
+			host: header(HOST),
+		}
+	}
+
+	pub(crate) fn finish(
+		self,
+		info: &RequestInfo,
+		start: Instant,
+		status: u16,
+		error_code: Option<&'static str>,
+		bytes_sent: Option<u64>,
+	) -> AccessLogRecord {
+		AccessLogRecord {
+			bucket_id: self.bucket_id,
+			bucket_name: self.bucket_name,
+			target: self.target,
+			time: garage_util::time::now_msec(),
+			remote_ip: info.source_addr.clone(),
+			requester: info.access_key_id.clone(),
+			request_id: info.request_id.clone(),
+			operation: format!("REST.{}.{}", self.method, info.operation),
+			key: info.key.clone(),
+			request_uri: self.request_uri,
+			status,
+			error_code,
+			bytes_sent,
+			total_time_msec: start.elapsed().as_millis(),
+			referer: self.referer,
+			user_agent: self.user_agent,
+			auth_type: self.auth_type,
+			host: self.host,
+		}
+	}
+}
+
+impl std::fmt::Display for AccessLogRecord {
+	/// Format the record as a line of the AWS server access log format.
+	/// Fields that Garage does not know (bucket owner, object size, version ID,
+	/// host ID, TLS parameters, access point) are written as `-`.
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let time = Utc
+			.timestamp_millis_opt(self.time as i64)
+			.unwrap()
+			.format("[%d/%b/%Y:%H:%M:%S +0000]");
+		let remote_ip = match self.remote_ip.rsplit_once(':') {
+			Some((ip, port)) if port.bytes().all(|c| c.is_ascii_digit()) => {
+				ip.trim_start_matches('[').trim_end_matches(']')
+			}
+			_ => self.remote_ip.as_str(),
+		};
+		writeln!(
+			f,
+			"- {} {} {} {} {} {} {} \"{}\" {} {} {} - {} - \"{}\" \"{}\" - - {} - {} {} - - -",
+			self.bucket_name,
+			time,
+			or_dash(Some(remote_ip)),
+			or_dash(self.requester.as_deref()),
+			self.request_id,
+			self.operation,
+			self.key
+				.as_deref()
+				.map(|k| uri_encode(k, false))
+				.as_deref()
+				.unwrap_or("-"),
+			quoted(&self.request_uri),
+			self.status,
+			or_dash(self.error_code),
+			self.bytes_sent
+				.filter(|n| *n > 0)
+				.map(|n| n.to_string())
+				.as_deref()
+				.unwrap_or("-"),
+			self.total_time_msec,
+			quoted(self.referer.as_deref().unwrap_or("-")),
+			quoted(self.user_agent.as_deref().unwrap_or("-")),
+			self.requester.as_ref().map(|_| "SigV4").unwrap_or("-"),
+			or_dash(self.auth_type),
+			or_dash(self.host.as_deref()),
+		)
+	}
+}
+
+fn or_dash(v: Option<&str>) -> &str {
+	match v {
+		Some(v) if !v.is_empty() => v,
+		_ => "-",
+	}
+}
+
+/// Make a value safe to write between double quotes in a log line
+fn quoted(v: &str) -> String {
+	v.chars()
+		.map(|c| match c {
+			'"' => '\'',
+			c if c.is_control() => ' ',
+			c => c,
+		})
+		.collect()
+}
+
+// ---- delivery worker ----
+
+pub struct AccessLogWorker {
+	garage: Arc<Garage>,
+	logger: Arc<AccessLogger>,
+	delivered: u64,
+	failed: u64,
+}
+
+impl AccessLogWorker {
+	pub fn new(garage: Arc<Garage>, logger: Arc<AccessLogger>) -> Self {
+		Self {
+			garage,
+			logger,
+			delivered: 0,
+			failed: 0,
+		}
+	}
+
+	async fn deliver(&self, buffer: LogBuffer) -> Result<(), GarageError> {
+		let LoggingConfig {
+			target_bucket,
+			target_bucket_name,
+			target_prefix,
+		} = buffer.target;
+
+		// Logs are not delivered to a bucket that has been deleted
+		let bucket = self
+			.garage
+			.bucket_table
+			.get(&EmptyKey, &target_bucket)
+			.await?
+			.filter(|b| !b.is_deleted());
+		let bucket_params = match bucket {
+			Some(b) => b.state.into_option().unwrap(),
+			None => {
+				debug!(
+					"Target bucket {} of access logs no longer exists, dropping logs",
+					target_bucket_name
+				);
+				return Ok(());
+			}
+		};
+
+		let key = format!(
+			"{}{}-{}",
+			target_prefix,
+			Utc::now().format("%Y-%m-%d-%H-%M-%S"),
+			hex::encode_upper(&gen_uuid().as_slice()[..8])
+		);
+		let original_key = match *bucket_params.case_insensitive_keys.get() {
+			true => Some(key.clone()),
+			false => None,
+		};
+		let key = bucket_params.index_key(&key);
+
+		let ctx = ReqCtx {
+			garage: self.garage.clone(),
+			bucket_id: target_bucket,
+			bucket_name: target_bucket_name,
+			bucket_params,
+			// Not stored and not used for authorization: log objects are
+			// written by Garage itself
+			api_key: Key::new("access log delivery"),
+			original_key,
+		};
+		let meta = ObjectVersionMetaInner {
+			headers: vec![("content-type".into(), "text/plain".into())],
+			checksum: None,
+			full_sha256: None,
+		};
+		let size = buffer.records.len() as u64;
+		let body = stream::iter(vec![Ok::<_, Error>(Bytes::from(buffer.records))]);
+		save_stream(
+			&ctx,
+			meta,
+			EncryptionParams::Plaintext,
+			body,
+			Some(size),
+			&key,
+			ChecksumMode::Calculate(None),
+			None,
+			false,
+		)
+		.await
+		.map_err(|e| GarageError::Message(format!("Could not write access log {}: {}", key, e)))?;
+		Ok(())
+	}
+}
+
+#[async_trait]
+impl Worker for AccessLogWorker {
+	fn name(&self) -> String {
+		"S3 access log delivery".into()
+	}
+
+	fn status(&self) -> WorkerStatus {
+		WorkerStatus {
+			freeform: vec![
+				format!("Log objects delivered: {}", self.delivered),
+				format!("Failed deliveries: {}", self.failed),
+				format!("Dropped records: {}", self.logger.dropped()),
+			],
+			..Default::default()
+		}
+	}
+
+	async fn work(
+		&mut self,
+		_must_exit: &mut watch::Receiver<bool>,
+	) -> Result<WorkerState, GarageError> {
+		let ready = self.logger.take_ready();
+		if ready.is_empty() {
+			return Ok(WorkerState::Idle);
+		}
+		for buffer in ready {
+			match self.deliver(buffer).await {
+				Ok(()) => self.delivered += 1,
+				Err(e) => {
+					// Delivery is best-effort: the records are dropped
+					warn!("{}", e);
+					self.failed += 1;
+				}
+			}
+		}
+		Ok(WorkerState::Busy)
+	}
+
+	async fn wait_for_work(&mut self) -> WorkerState {
+		let tick = std::cmp::min(self.logger.flush_interval, Duration::from_secs(10));
+		tokio::select! {
+			_ = self.logger.notify.notified() => (),
+			_ = tokio::time::sleep(tick) => (),
+		}
+		WorkerState::Busy
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn logger(flush_size: usize, buffer_size: usize) -> AccessLogger {
+		AccessLogger {
+			flush_interval: Duration::from_secs(DEFAULT_ACCESS_LOG_FLUSH_INTERVAL_SECS),
+			flush_size,
+			buffer_size,
+			state: Mutex::new(BufferState::default()),
+			notify: Notify::new(),
+		}
+	}
+
+	fn record(bucket_id: Uuid, key: &str) -> AccessLogRecord {
+		AccessLogRecord {
+			bucket_id,
+			bucket_name: "mybucket".into(),
+			target: LoggingConfig {
+				target_bucket: [1u8; 32].into(),
+				target_bucket_name: "logs".into(),
+				target_prefix: "mybucket/".into(),
+			},
+			time: 1549411238000,
+			remote_ip: "[2001:db8::1]:49152".into(),
+			requester: Some("GK31c2f218a2e44f485b94239e".into()),
+			request_id: "3E57427F3EXAMPLE".into(),
+			operation: "REST.GET.GetObject".into(),
+			key: Some(key.into()),
+			request_uri: "GET /mybucket/my%20key HTTP/1.1".into(),
+			status: 200,
+			error_code: None,
+			bytes_sent: Some(113),
+			total_time_msec: 7,
+			referer: None,
+			user_agent: Some("aws-sdk \"test\"".into()),
+			auth_type: Some("AuthHeader"),
+			host: Some("localhost:3900".into()),
+		}
+	}
+
+	#[test]
+	fn test_record_format() {
+		assert_eq!(
+			record([0u8; 32].into(), "my key").to_string(),
+			"- mybucket [06/Feb/2019:00:00:38 +0000] 2001:db8::1 GK31c2f218a2e44f485b94239e \
+			3E57427F3EXAMPLE REST.GET.GetObject my%20key \"GET /mybucket/my%20key HTTP/1.1\" \
+			200 - 113 - 7 - \"-\" \"aws-sdk 'test'\" - - SigV4 - AuthHeader localhost:3900 - - -\n"
+		);
+	}
+
+	#[test]
+	fn test_buffers() {
+		let line_len = record([0u8; 32].into(), "a").to_string().len();
+		let logger = logger(3 * line_len, 5 * line_len);
+		let (b1, b2) = ([1u8; 32].into(), [2u8; 32].into());
+
+		logger.record(record(b1, "a"));
+		logger.record(record(b1, "b"));
+		logger.record(record(b2, "c"));
+		assert!(logger.take_ready().is_empty());
+
+		// The buffer of b1 reaches the flush size, and is the only one delivered
+		logger.record(record(b1, "d"));
+		let ready = logger.take_ready();
+		assert_eq!(ready.len(), 1);
+		assert_eq!(ready[0].records.lines().count(), 3);
+
+		// Records are dropped when the buffers are full
+		for key in ["e", "f", "g", "h", "i"] {
+			logger.record(record(b2, key));
+		}
+		assert_eq!(logger.dropped(), 1);
+		assert_eq!(logger.take_ready()[0].records.lines().count(), 5);
+		assert_eq!(logger.state.lock().unwrap().total_size, 0);
+	}
+}
@@ -1,16 +1,22 @@
 use std::sync::Arc;
+use std::time::Instant;
 
 use async_trait::async_trait;
 
 use hyper::header;
-use hyper::{body::Incoming as IncomingBody, Request, Response};
+use hyper::{body::Incoming as IncomingBody, Method, Request, Response};
 use tokio::sync::watch;
 
-use opentelemetry::{trace::SpanRef, KeyValue};
+use opentelemetry::{global, metrics::Counter, trace::SpanRef, KeyValue};
 
+use garage_util::config::{AddressingStyle, DirectoryMarkers};
 use garage_util::error::Error as GarageError;
+use garage_util::forwarded_headers;
+use garage_util::metrics::{timed_phase, with_request_timing, RequestTiming};
 use garage_util::socket_address::UnixOrTCPSocketAddress;
 
+use garage_model::bucket_alias_table::{canonical_bucket_name, is_valid_bucket_name};
+use garage_model::bucket_table::BucketParams;
 use garage_model::garage::Garage;
 use garage_model::key_table::Key;
 
@@ -18,8 +24,11 @@ use crate::generic_server::*;
 use crate::s3::error::*;
 
 use crate::signature::verify_request;
+use crate::tls_policy::TlsPolicy;
 
 use crate::helpers::*;
+use crate::s3::access_log::*;
+use crate::s3::bandwidth::*;
 use crate::s3::bucket::*;
 use crate::s3::copy::*;
 use crate::s3::cors::*;
@@ -27,17 +36,38 @@ use crate::s3::delete::*;
 use crate::s3::get::*;
 use crate::s3::lifecycle::*;
 use crate::s3::list::*;
+use crate::s3::logging::*;
 use crate::s3::multipart::*;
 use crate::s3::post_object::handle_post_object;
 use crate::s3::put::*;
+use crate::s3::recent_errors::*;
+use crate::s3::recent_puts::{RecentPuts, RECENT_PUT_TTL};
+use crate::s3::request_payment::*;
 use crate::s3::router::Endpoint;
+use crate::s3::shadow::ShadowMirror;
 use crate::s3::website::*;
 
 pub use crate::signature::streaming::ReqBody;
 pub type ResBody = BoxBody<Error>;
 
+/// Request header asking for a breakdown of the time spent handling the request
+pub const X_GARAGE_DEBUG_TIMING: &str = "x-garage-debug-timing";
+/// Response header containing that breakdown
+pub const X_GARAGE_TIMING: &str = "x-garage-timing";
+
+// Bodies of responses with debug timing are produced entirely before the
+// response is sent, so that the timing includes fetching their data,
+// but only up to this size
+const DEBUG_TIMING_MAX_BUFFERED_BODY: u64 = 64 * 1024 * 1024;
+
 pub struct S3ApiServer {
 	garage: Arc<Garage>,
+	recent_errors: Arc<RecentErrors>,
+	bandwidth: Arc<BandwidthLimiter>,
+	access_logger: Arc<AccessLogger>,
+	recent_puts: RecentPuts,
+	error_code_counter: Counter<u64>,
+	shadow: Option<ShadowMirror>,
 }
 
 pub(crate) struct S3ApiEndpoint {
@@ -50,11 +80,55 @@ impl S3ApiServer {
 		garage: Arc<Garage>,
 		addr: UnixOrTCPSocketAddress,
 		s3_region: String,
+		recent_errors: Arc<RecentErrors>,
+		bandwidth: Arc<BandwidthLimiter>,
+		access_logger: Arc<AccessLogger>,
 		must_exit: watch::Receiver<bool>,
 	) -> Result<(), GarageError> {
-		ApiServer::new(s3_region, S3ApiServer { garage })
-			.run_server(addr, None, must_exit)
-			.await
+		let tls_policy = TlsPolicy::from_s3_config(&garage.config.s3_api)?;
+		if garage.config.s3_api.addressing_style == AddressingStyle::Vhost
+			&& garage.config.s3_api.root_domain.is_none()
+		{
+			return Err(GarageError::Message(
+				"s3_api.addressing_style = \"vhost\" requires s3_api.root_domain to be set".into(),
+			));
+		}
+		let error_code_counter = global::meter("garage/api")
+			.u64_counter("api.s3.error_code_counter")
+			.with_description("Number of S3 API calls that resulted in errors, by error code")
+			.init();
+		let shadow = ShadowMirror::from_config(garage.clone())?;
+		let api_server = S3ApiServer {
+			garage,
+			recent_errors,
+			bandwidth,
+			access_logger,
+			recent_puts: RecentPuts::new(RECENT_PUT_TTL),
+			error_code_counter,
+			shadow,
+		};
+		let slow_request_threshold_msec =
+			api_server.garage.config.admin.slow_request_threshold_msec;
+		let header_limits = HeaderLimits::new(
+			api_server.garage.config.s3_api.max_header_size,
+			api_server.garage.config.s3_api.max_header_count,
+		);
+		let max_requests_per_connection =
+			api_server.garage.config.s3_api.max_requests_per_connection;
+		ApiServer::new_with_tls_policy(
+			s3_region,
+			api_server,
+			tls_policy,
+			slow_request_threshold_msec,
+		)
+		.run_server(
+			addr,
+			None,
+			header_limits,
+			max_requests_per_connection,
+			must_exit,
+		)
+		.await
 	}
 
 	async fn handle_request_without_bucket(
@@ -79,24 +153,54 @@ impl ApiHandler for S3ApiServer {
 	type Error = Error;
 
 	fn parse_endpoint(&self, req: &Request<IncomingBody>) -> Result<S3ApiEndpoint, Error> {
-		let authority = req
-			.headers()
-			.get(header::HOST)
-			.ok_or_bad_request("Host header required")?
-			.to_str()?;
-
+		let authority = host_header(req)?;
 		let host = authority_to_host(authority)?;
 
-		let bucket_name = self
-			.garage
-			.config
-			.s3_api
+		let s3_config = &self.garage.config.s3_api;
+		let vhost_bucket = s3_config
 			.root_domain
 			.as_ref()
 			.and_then(|root_domain| host_to_bucket(&host, root_domain));
 
 		let (endpoint, bucket_name) =
-			Endpoint::from_request(req, bucket_name.map(ToOwned::to_owned))?;
+			Endpoint::from_request(req, vhost_bucket.map(ToOwned::to_owned))?;
+
+		// Bucket names are looked up in their canonical form, so that
+		// international bucket names can be given in unicode (percent-encoded
+		// in path-style requests) as well as in punycode
+		let bucket_name = bucket_name.map(|b| {
+			let b = percent_encoding::percent_decode_str(&b).decode_utf8_lossy();
+			canonical_bucket_name(&b).into_owned()
+		});
+
+		check_addressing_style(
+			s3_config.addressing_style,
+			authority,
+			s3_config.root_domain.as_deref(),
+			vhost_bucket.is_some(),
+			bucket_name.as_deref(),
+		)?;
+		if let (Some(_), Some(root_domain), Some(bucket)) = (
+			vhost_bucket,
+			s3_config.root_domain.as_deref(),
+			bucket_name.as_deref(),
+		) {
+			check_vhost_bucket(
+				authority,
+				root_domain,
+				bucket,
+				self.garage.config.allow_punycode,
+			)?;
+		}
+
+		if s3_config.read_only && endpoint.is_mutation() {
+			return Err(Error::forbidden(format!(
+				"{} is not allowed: the S3 API of this node is read-only",
+				endpoint.name()
+			)));
+		}
+
+		check_minority_partition(&self.garage, &endpoint.authorization_type(), req.method())?;
 
 		Ok(S3ApiEndpoint {
 			bucket_name,
@@ -108,15 +212,105 @@ impl ApiHandler for S3ApiServer {
 		&self,
 		req: Request<IncomingBody>,
 		endpoint: S3ApiEndpoint,
+	) -> Result<Response<ResBody>, Error> {
+		let start = Instant::now();
+		let request_info = RequestInfo {
+			operation: endpoint.endpoint.name(),
+			bucket: endpoint.bucket_name.clone(),
+			key: endpoint.endpoint.get_key().map(str::to_string),
+			access_key_id: request_access_key_id(&req),
+			source_addr: req
+				.extensions()
+				.get::<ClientAddr>()
+				.map(|a| a.0.clone())
+				.unwrap_or_default(),
+			forwarded_for: forwarded_headers::handle_forwarded_for_headers(req.headers()).ok(),
+			request_id: req
+				.extensions()
+				.get::<RequestId>()
+				.map(|r| r.0.clone())
+				.unwrap_or_default(),
+		};
+
+		// Timing is collected only if the client asks for it, and it is
+		// returned only if the key is allowed to see it
+		let timing = req
+			.headers()
+			.get(X_GARAGE_DEBUG_TIMING)
+			.is_some_and(|v| v == "1")
+			.then(RequestTiming::new);
+		let mut debug_timing_allowed = false;
+
+		let mut access_log = None;
+		let res = with_request_timing(
+			timing.clone(),
+			self.handle_request(req, endpoint, &mut access_log, &mut debug_timing_allowed),
+		)
+		.await;
+		let res = match (timing, res) {
+			(Some(timing), Ok(resp)) if debug_timing_allowed => {
+				add_debug_timing(resp, &timing).await
+			}
+			(_, res) => res,
+		};
+		let res =
+			res.map(|res| res.map(|body| ResBody::new(self.bandwidth.pace(body, Direction::Out))));
+
+		if let Some(access_log) = access_log {
+			let (status, error_code, bytes_sent) = match &res {
+				Ok(resp) => (
+					resp.status().as_u16(),
+					None,
+					resp.headers()
+						.get(header::CONTENT_LENGTH)
+						.and_then(|v| v.to_str().ok())
+						.and_then(|v| v.parse().ok()),
+				),
+				Err(e) => (e.http_status_code().as_u16(), Some(e.aws_code()), None),
+			};
+			self.access_logger.record(access_log.finish(
+				&request_info,
+				start,
+				status,
+				error_code,
+				bytes_sent,
+			));
+		}
+
+		if let Err(e) = &res {
+			let error_code = e.aws_code();
+			self.error_code_counter.add(
+				1,
+				&[
+					KeyValue::new("api_endpoint", request_info.operation),
+					KeyValue::new("error_code", error_code),
+				],
+			);
+			self.recent_errors
+				.record(request_info, e.http_status_code().as_u16(), error_code);
+		}
+
+		res
+	}
+}
+
+impl S3ApiServer {
+	async fn handle_request(
+		&self,
+		req: Request<IncomingBody>,
+		endpoint: S3ApiEndpoint,
+		access_log: &mut Option<PendingAccessLog>,
+		debug_timing_allowed: &mut bool,
 	) -> Result<Response<ResBody>, Error> {
 		let S3ApiEndpoint {
 			bucket_name,
-			endpoint,
+			mut endpoint,
 		} = endpoint;
 		let garage = self.garage.clone();
 
 		// Some endpoints are processed early, before we even check for an API key
 		if let Endpoint::PostObject = endpoint {
+			let req = req.map(|body| self.bandwidth.pace(body, Direction::In));
 			return handle_post_object(garage, req, bucket_name.unwrap()).await;
 		}
 		if let Endpoint::Options = endpoint {
@@ -124,7 +318,10 @@ impl ApiHandler for S3ApiServer {
 			return Ok(options_res.map(|_empty_body: EmptyBody| empty_body()));
 		}
 
-		let (req, api_key, content_sha256) = verify_request(&garage, req, "s3").await?;
+		let (req, api_key, content_sha256) =
+			timed_phase("signature", verify_request(&garage, req, "s3")).await?;
+		let req = req.map(|body| ReqBody::new(self.bandwidth.pace(body, Direction::In)));
+		*debug_timing_allowed = garage.config.admin.allow_debug_timing;
 
 		let bucket_name = match bucket_name {
 			None => {
@@ -147,28 +344,109 @@ impl ApiHandler for S3ApiServer {
 			.await;
 		}
 
-		let bucket_id = garage
-			.bucket_helper()
-			.resolve_bucket(&bucket_name, &api_key)
-			.await?;
-		let bucket = garage
-			.bucket_helper()
-			.get_existing_bucket(bucket_id)
-			.await?;
+		let (bucket_id, bucket) = timed_phase("bucket resolution", async {
+			let bucket_id = garage
+				.bucket_helper()
+				.resolve_bucket(&bucket_name, &api_key)
+				.await?;
+			let bucket = garage
+				.bucket_helper()
+				.get_existing_bucket(bucket_id)
+				.await?;
+			Ok::<_, Error>((bucket_id, bucket))
+		})
+		.await?;
 		let bucket_params = bucket.state.into_option().unwrap();
+		*debug_timing_allowed |= api_key.allow_owner(&bucket_id);
+
+		// Requests to buckets that have access logging enabled are recorded
+		// once their response is known, whether they succeed or not
+		if let Some(target) = bucket_params.logging_config.get() {
+			*access_log = Some(PendingAccessLog::new(
+				&req,
+				bucket_id,
+				&bucket_name,
+				target.clone(),
+			));
+		}
+
+		// In buckets with case-insensitive keys, objects are stored under their
+		// lowercased key, and the original key is kept to be stored in the object
+		let original_key = match endpoint.get_key_mut() {
+			Some(key) if *bucket_params.case_insensitive_keys.get() => {
+				let index_key = bucket_params.index_key(key);
+				Some(std::mem::replace(key, index_key))
+			}
+			_ => None,
+		};
+
+		// The methods allowed on the bucket apply whatever the permissions
+		// of the key, so they are checked first
+		check_bucket_allowed_method(&bucket_params, req.method())?;
 
 		let allowed = match endpoint.authorization_type() {
 			Authorization::Read => api_key.allow_read(&bucket_id),
+			Authorization::List => api_key.allow_list(&bucket_id),
 			Authorization::Write => api_key.allow_write(&bucket_id),
 			Authorization::Owner => api_key.allow_owner(&bucket_id),
 			_ => unreachable!(),
 		};
 
 		if !allowed {
+			// The existence of the object is not checked, so that this
+			// does not tell whether an object exists either
+			if *bucket_params.forbidden_as_not_found.get()
+				&& matches!(
+					endpoint,
+					Endpoint::GetObject { .. } | Endpoint::HeadObject { .. }
+				) {
+				return Err(Error::NoSuchKey);
+			}
 			return Err(Error::forbidden("Operation is not allowed for this key."));
 		}
 
-		let matching_cors_rule = find_matching_cors_rule(&bucket_params, &req)?.cloned();
+		if endpoint.is_mutation() && *bucket_params.read_only.get() {
+			return Err(Error::forbidden(
+				"Bucket is read-only: objects and bucket configuration cannot be modified.",
+			));
+		}
+
+		let matching_cors_rule = find_matching_cors_rule(&bucket_params, &req)?
+			.map(|(rule, origin)| (rule.clone(), origin.to_string()));
+
+		// On Requester Pays buckets, responses to data operations indicate
+		// that the request has been charged to the requester
+		let request_charged = *bucket_params.requester_pays.get()
+			&& matches!(
+				endpoint,
+				Endpoint::GetObject { .. }
+					| Endpoint::HeadObject { .. }
+					| Endpoint::PutObject { .. }
+					| Endpoint::CopyObject { .. }
+					| Endpoint::DeleteObject { .. }
+					| Endpoint::ListObjects { .. }
+					| Endpoint::ListObjectsV2 { .. }
+			);
+
+		let skip_directory_marker =
+			garage.config.s3_api.directory_markers == DirectoryMarkers::Directory;
+		let max_list_response_size = garage
+			.config
+			.s3_api
+			.max_list_response_size
+			.unwrap_or(DEFAULT_MAX_LIST_RESPONSE_SIZE);
+		let max_list_parts = garage
+			.config
+			.s3_api
+			.max_list_parts
+			.unwrap_or(DEFAULT_MAX_LIST_PARTS)
+			.clamp(1, MAX_PARTS);
+
+		// Successful write requests that are sampled are mirrored to the
+		// shadow cluster, if there is one
+		let shadow_request = self.shadow.as_ref().and_then(|shadow| {
+			shadow.sample(&endpoint, bucket_id, &bucket_name, original_key.as_deref())
+		});
 
 		let ctx = ReqCtx {
 			garage,
@@ -176,6 +454,7 @@ impl ApiHandler for S3ApiServer {
 			bucket_name,
 			bucket_params,
 			api_key,
+			original_key,
 		};
 
 		let resp = match endpoint {
@@ -214,7 +493,9 @@ impl ApiHandler for S3ApiServer {
 				part_number,
 				upload_id,
 			} => handle_upload_part_copy(ctx, &req, &key, part_number, &upload_id).await,
-			Endpoint::PutObject { key } => handle_put(ctx, req, &key, content_sha256).await,
+			Endpoint::PutObject { key } => {
+				handle_put(ctx, req, &key, content_sha256, &self.recent_puts).await
+			}
 			Endpoint::AbortMultipartUpload { key, upload_id } => {
 				handle_abort_multipart_upload(ctx, &key, &upload_id).await
 			}
@@ -226,10 +507,7 @@ impl ApiHandler for S3ApiServer {
 				handle_complete_multipart_upload(ctx, req, &key, &upload_id, content_sha256).await
 			}
 			Endpoint::CreateBucket {} => unreachable!(),
-			Endpoint::HeadBucket {} => {
-				let response = Response::builder().body(empty_body()).unwrap();
-				Ok(response)
-			}
+			Endpoint::HeadBucket {} => handle_head_bucket(ctx),
 			Endpoint::DeleteBucket {} => handle_delete_bucket(ctx).await,
 			Endpoint::GetBucketLocation {} => handle_get_bucket_location(ctx),
 			Endpoint::GetBucketVersioning {} => handle_get_bucket_versioning(),
@@ -240,19 +518,25 @@ impl ApiHandler for S3ApiServer {
 				max_keys,
 				prefix,
 			} => {
+				let params = &ctx.bucket_params;
 				let query = ListObjectsQuery {
 					common: ListQueryCommon {
 						bucket_name: ctx.bucket_name.clone(),
 						bucket_id,
-						delimiter,
+						delimiter: delimiter.map(|d| params.index_key(&d)),
 						page_size: max_keys.unwrap_or(1000).clamp(1, 1000),
-						prefix: prefix.unwrap_or_default(),
+						prefix: params.index_key(&prefix.unwrap_or_default()),
 						urlencode_resp: encoding_type.map(|e| e == "url").unwrap_or(false),
+						skip_directory_marker,
+						max_response_size: Some(max_list_response_size),
+						modified_since: parse_modified_since(req.headers())?,
 					},
 					is_v2: false,
-					marker,
+					marker: marker.map(|m| params.index_key(&m)),
 					continuation_token: None,
 					start_after: None,
+					allow_degraded_read: parse_allow_degraded_read(req.headers())?
+						.unwrap_or(*params.allow_degraded_reads.get()),
 				};
 				handle_list(ctx, &query).await
 			}
@@ -267,19 +551,25 @@ impl ApiHandler for S3ApiServer {
 				..
 			} => {
 				if list_type == "2" {
+					let params = &ctx.bucket_params;
 					let query = ListObjectsQuery {
 						common: ListQueryCommon {
 							bucket_name: ctx.bucket_name.clone(),
 							bucket_id,
-							delimiter,
+							delimiter: delimiter.map(|d| params.index_key(&d)),
 							page_size: max_keys.unwrap_or(1000).clamp(1, 1000),
 							urlencode_resp: encoding_type.map(|e| e == "url").unwrap_or(false),
-							prefix: prefix.unwrap_or_default(),
+							prefix: params.index_key(&prefix.unwrap_or_default()),
+							skip_directory_marker,
+							max_response_size: Some(max_list_response_size),
+							modified_since: parse_modified_since(req.headers())?,
 						},
 						is_v2: true,
 						marker: None,
 						continuation_token,
-						start_after,
+						start_after: start_after.map(|sa| params.index_key(&sa)),
+						allow_degraded_read: parse_allow_degraded_read(req.headers())?
+							.unwrap_or(*params.allow_degraded_reads.get()),
 					};
 					handle_list(ctx, &query).await
 				} else {
@@ -305,6 +595,9 @@ impl ApiHandler for S3ApiServer {
 						page_size: max_uploads.unwrap_or(1000).clamp(1, 1000),
 						prefix: prefix.unwrap_or_default(),
 						urlencode_resp: encoding_type.map(|e| e == "url").unwrap_or(false),
+						skip_directory_marker: false,
+						max_response_size: None,
+						modified_since: None,
 					},
 					key_marker,
 					upload_id_marker,
@@ -322,8 +615,8 @@ impl ApiHandler for S3ApiServer {
 					bucket_id,
 					key,
 					upload_id,
-					part_number_marker: part_number_marker.map(|p| p.min(10000)),
-					max_parts: max_parts.unwrap_or(1000).clamp(1, 1000),
+					part_number_marker: part_number_marker.map(|p| p.min(MAX_PARTS)),
+					max_parts: max_parts.unwrap_or(1000).clamp(1, max_list_parts),
 				};
 				handle_list_parts(ctx, req, &query).await
 			}
@@ -339,16 +632,33 @@ impl ApiHandler for S3ApiServer {
 				handle_put_lifecycle(ctx, req, content_sha256).await
 			}
 			Endpoint::DeleteBucketLifecycle {} => handle_delete_lifecycle(ctx).await,
+			Endpoint::GetBucketRequestPayment {} => handle_get_request_payment(ctx).await,
+			Endpoint::PutBucketRequestPayment {} => {
+				handle_put_request_payment(ctx, req, content_sha256).await
+			}
+			Endpoint::GetBucketLogging {} => handle_get_bucket_logging(ctx).await,
+			Endpoint::PutBucketLogging {} => {
+				handle_put_bucket_logging(ctx, req, content_sha256).await
+			}
 			endpoint => Err(Error::NotImplemented(endpoint.name().to_owned())),
 		};
 
 		// If request was a success and we have a CORS rule that applies to it,
 		// add the corresponding CORS headers to the response
 		let mut resp_ok = resp?;
-		if let Some(rule) = matching_cors_rule {
-			add_cors_headers(&mut resp_ok, &rule)
+		if let (Some(shadow), Some(request)) = (&self.shadow, shadow_request) {
+			shadow.enqueue(request, &resp_ok);
+		}
+		if let Some((rule, origin)) = matching_cors_rule {
+			add_cors_headers(&mut resp_ok, &rule, &origin)
 				.ok_or_internal_error("Invalid bucket CORS configuration")?;
 		}
+		if request_charged {
+			resp_ok.headers_mut().insert(
+				X_AMZ_REQUEST_CHARGED,
+				header::HeaderValue::from_static("requester"),
+			);
+		}
 
 		Ok(resp_ok)
 	}
@@ -365,4 +675,303 @@ impl ApiEndpoint for S3ApiEndpoint {
 			self.bucket_name.clone().unwrap_or_default(),
 		));
 	}
+
+	fn bucket_name(&self) -> Option<&str> {
+		self.bucket_name.as_deref()
+	}
+}
+
+/// Add the timing collected while handling a request to its response.
+/// The body is produced first if it is small enough, so that the time
+/// spent fetching its data is included.
+async fn add_debug_timing(
+	resp: Response<ResBody>,
+	timing: &RequestTiming,
+) -> Result<Response<ResBody>, Error> {
+	let body_size = resp
+		.headers()
+		.get(header::CONTENT_LENGTH)
+		.and_then(|v| v.to_str().ok())
+		.and_then(|v| v.parse::<u64>().ok());
+	let (mut parts, body) = resp.into_parts();
+	let body = match body_size {
+		Some(size) if size <= DEBUG_TIMING_MAX_BUFFERED_BODY => {
+			bytes_body(http_body_util::BodyExt::collect(body).await?.to_bytes())
+		}
+		_ => body,
+	};
+	parts.headers.insert(
+		X_GARAGE_TIMING,
+		header::HeaderValue::from_str(&timing.to_header_value())
+			.ok_or_internal_error("Invalid timing header")?,
+	);
+	Ok(Response::from_parts(parts, body))
+}
+
+/// Check that the method of a request is one of the methods allowed on
+/// its bucket. The methods allowed on the bucket are sent in the Allow
+/// header of the error.
+pub(crate) fn check_bucket_allowed_method(
+	bucket_params: &BucketParams,
+	method: &Method,
+) -> Result<(), Error> {
+	if bucket_params.allows_method(method.as_str()) {
+		return Ok(());
+	}
+	let mut allow = bucket_params
+		.allowed_methods
+		.get()
+		.clone()
+		.unwrap_or_default();
+	allow.push("OPTIONS".into());
+	Err(Error::MethodNotAllowed(allow.join(", ")))
+}
+
+/// Check that the bucket targeted by a request is addressed in a way
+/// that is allowed by the `addressing_style` setting. Path-style requests
+/// refused in vhost mode are redirected to the vhost-style endpoint
+/// of their bucket.
+fn check_addressing_style(
+	style: AddressingStyle,
+	authority: &str,
+	root_domain: Option<&str>,
+	is_vhost: bool,
+	bucket_name: Option<&str>,
+) -> Result<(), Error> {
+	match (style, bucket_name) {
+		(AddressingStyle::Path, _) if is_vhost => Err(Error::bad_request(
+			"Virtual-hosted-style requests are disabled, use path-style requests",
+		)),
+		(AddressingStyle::Vhost, Some(bucket)) if !is_vhost => match root_domain {
+			Some(root_domain) => {
+				// Keep the port of the original request, if any
+				let port = authority
+					.rsplit_once(':')
+					.map(|(_, port)| port)
+					.filter(|port| !port.is_empty() && port.bytes().all(|c| c.is_ascii_digit()));
+				let mut endpoint = format!("{}.{}", bucket, root_domain.trim_start_matches('.'));
+				if let Some(port) = port {
+					endpoint.push(':');
+					endpoint.push_str(port);
+				}
+				Err(Error::PermanentRedirect(endpoint))
+			}
+			None => Err(Error::bad_request(
+				"Path-style requests are disabled, use virtual-hosted-style requests",
+			)),
+		},
+		_ => Ok(()),
+	}
+}
+
+/// Check that the part of the Host header of a virtual-hosted-style
+/// request that precedes the root domain is a valid bucket name. Otherwise,
+/// the Host header does not designate any bucket, and the request is
+/// refused instead of failing later with a misleading NoSuchBucket error.
+fn check_vhost_bucket(
+	authority: &str,
+	root_domain: &str,
+	bucket_name: &str,
+	allow_punycode: bool,
+) -> Result<(), Error> {
+	if is_valid_bucket_name(bucket_name, allow_punycode) {
+		Ok(())
+	} else {
+		Err(Error::bad_request(format!(
+			"Host header {:?} does not designate a valid bucket under root domain {:?}",
+			authority,
+			root_domain.trim_start_matches('.'),
+		)))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Parse the bucket of a request the way `parse_endpoint` does,
+	/// and check its addressing style
+	fn check(
+		style: AddressingStyle,
+		authority: &str,
+		path: &str,
+		root_domain: Option<&str>,
+	) -> Result<Option<String>, Error> {
+		let host = authority_to_host(authority)?;
+		let vhost_bucket = root_domain.and_then(|root| host_to_bucket(&host, root));
+		let bucket_name = match (vhost_bucket, path) {
+			(None, "/") => None,
+			_ => Some(parse_bucket_key(path, vhost_bucket)?.0),
+		};
+		check_addressing_style(
+			style,
+			authority,
+			root_domain,
+			vhost_bucket.is_some(),
+			bucket_name,
+		)?;
+		if let (Some(root), Some(bucket)) = (root_domain, vhost_bucket) {
+			check_vhost_bucket(authority, root, bucket, false)?;
+		}
+		Ok(bucket_name.map(str::to_string))
+	}
+
+	fn redirect_endpoint(res: Result<Option<String>, Error>) -> String {
+		match res {
+			Err(Error::PermanentRedirect(endpoint)) => endpoint,
+			r => panic!("expected PermanentRedirect, got {:?}", r),
+		}
+	}
+
+	fn is_invalid_request(res: Result<Option<String>, Error>) -> bool {
+		matches!(res, Err(e) if e.aws_code() == "InvalidRequest")
+	}
+
+	#[test]
+	fn test_addressing_style_both() -> Result<(), Error> {
+		let style = AddressingStyle::Both;
+		let root = Some(".s3.garage.tld");
+		assert_eq!(
+			check(style, "bucket.s3.garage.tld:3900", "/key", root)?.as_deref(),
+			Some("bucket")
+		);
+		assert_eq!(
+			check(style, "s3.garage.tld:3900", "/bucket/key", root)?.as_deref(),
+			Some("bucket")
+		);
+		assert_eq!(
+			check(style, "[::1]:3900", "/bucket/key", root)?.as_deref(),
+			Some("bucket")
+		);
+		assert_eq!(
+			check(style, "[::1]", "/bucket", None)?.as_deref(),
+			Some("bucket")
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_addressing_style_path() -> Result<(), Error> {
+		let style = AddressingStyle::Path;
+		let root = Some("s3.garage.tld");
+		assert_eq!(
+			check(style, "s3.garage.tld:3900", "/bucket/key", root)?.as_deref(),
+			Some("bucket")
+		);
+		assert_eq!(
+			check(style, "127.0.0.1:3900", "/bucket/key", root)?.as_deref(),
+			Some("bucket")
+		);
+		assert_eq!(
+			check(style, "[::1]:3900", "/bucket/key", root)?.as_deref(),
+			Some("bucket")
+		);
+		assert_eq!(check(style, "s3.garage.tld", "/", root)?, None);
+		// A host that only looks like the root domain is not vhost-style
+		assert_eq!(
+			check(style, "not-s3.garage.tld", "/bucket", root)?.as_deref(),
+			Some("bucket")
+		);
+
+		assert!(is_invalid_request(check(
+			style,
+			"bucket.s3.garage.tld",
+			"/key",
+			root
+		)));
+		assert!(is_invalid_request(check(
+			style,
+			"bucket.s3.garage.tld:3900",
+			"/",
+			root
+		)));
+		Ok(())
+	}
+
+	#[test]
+	fn test_addressing_style_vhost() -> Result<(), Error> {
+		let style = AddressingStyle::Vhost;
+		let root = Some(".s3.garage.tld");
+		assert_eq!(
+			check(style, "bucket.s3.garage.tld:3900", "/key", root)?.as_deref(),
+			Some("bucket")
+		);
+		assert_eq!(
+			check(style, "my.bucket.s3.garage.tld", "/", root)?.as_deref(),
+			Some("my.bucket")
+		);
+		// Requests without a bucket (ListBuckets) are still allowed
+		assert_eq!(check(style, "s3.garage.tld", "/", root)?, None);
+		assert_eq!(check(style, "[::1]:3900", "/", root)?, None);
+
+		assert_eq!(
+			redirect_endpoint(check(style, "s3.garage.tld", "/bucket/key", root)),
+			"bucket.s3.garage.tld"
+		);
+		assert_eq!(
+			redirect_endpoint(check(style, "s3.garage.tld:3900", "/bucket/key", root)),
+			"bucket.s3.garage.tld:3900"
+		);
+		assert_eq!(
+			redirect_endpoint(check(style, "[::1]:3900", "/bucket", root)),
+			"bucket.s3.garage.tld:3900"
+		);
+		assert_eq!(
+			redirect_endpoint(check(style, "[::1]", "/bucket", root)),
+			"bucket.s3.garage.tld"
+		);
+		assert_eq!(
+			redirect_endpoint(check(style, "not-s3.garage.tld", "/bucket", root)),
+			"bucket.s3.garage.tld"
+		);
+
+		assert!(is_invalid_request(check(
+			style,
+			"[::1]:3900",
+			"/bucket",
+			None
+		)));
+		Ok(())
+	}
+	#[test]
+	fn test_addressing_style_invalid_vhost_bucket() -> Result<(), Error> {
+		let root = Some(".s3.garage.tld");
+		for style in [AddressingStyle::Both, AddressingStyle::Vhost] {
+			// Buckets with several labels are extracted from the Host header
+			assert_eq!(
+				check(style, "my.bucket.s3.garage.tld:3900", "/key", root)?.as_deref(),
+				Some("my.bucket")
+			);
+
+			// A Host under the root domain whose prefix is not a bucket name
+			// matches neither addressing style and is refused
+			for authority in [
+				"-bucket.s3.garage.tld",
+				"bucket-.s3.garage.tld:3900",
+				"a..b.s3.garage.tld",
+				"ab.s3.garage.tld",
+				"my_bucket.s3.garage.tld",
+				"192.168.1.1.s3.garage.tld",
+			] {
+				assert!(
+					is_invalid_request(check(style, authority, "/key", root)),
+					"{}",
+					authority
+				);
+			}
+		}
+
+		// Hosts outside of the root domain are still path-style
+		assert_eq!(
+			check(
+				AddressingStyle::Both,
+				"other.example.com",
+				"/bucket/key",
+				root
+			)?
+			.as_deref(),
+			Some("bucket")
+		);
+		Ok(())
+	}
 }
@@ -0,0 +1,350 @@
+//! Bandwidth shaping for the S3 API, to keep big transfers from saturating
+//! the network interface of a node.
+//!
+//! The bodies of requests and responses are paced using token buckets: one
+//! global bucket per direction, shared by all requests of this node, and one
+//! bucket per request body and per response body. Since the requests sent on
+//! a HTTP/1.1 connection are handled one after the other, the latter limits
+//! the bandwidth of each connection. Limits can be changed at runtime through
+//! the admin API, and apply immediately to transfers that are in progress.
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use hyper::body::{Body, Frame, SizeHint};
+use opentelemetry::{global, metrics::ValueObserver, KeyValue};
+
+use garage_util::config::S3ApiConfig;
+
+/// Amount of data, in seconds of transfer at the configured rate, that can
+/// be sent or received at once after a period of inactivity
+const BURST_SECS: f64 = 1.0;
+
+/// Bandwidth limits in bytes per second, applied independently
+/// to incoming and outgoing data. `None` means unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BandwidthLimits {
+	pub global: Option<u64>,
+	pub per_connection: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+	In,
+	Out,
+}
+
+impl Direction {
+	fn as_str(&self) -> &'static str {
+		match self {
+			Direction::In => "in",
+			Direction::Out => "out",
+		}
+	}
+}
+
+pub struct BandwidthLimiter {
+	state: Arc<Mutex<LimiterState>>,
+	_throughput: ValueObserver<u64>,
+}
+
+struct LimiterState {
+	limits: BandwidthLimits,
+	global_in: TokenBucket,
+	global_out: TokenBucket,
+	throughput_in: ThroughputEstimator,
+	throughput_out: ThroughputEstimator,
+}
+
+impl BandwidthLimiter {
+	pub fn new(config: &S3ApiConfig) -> Self {
+		Self::with_limits(BandwidthLimits {
+			global: config.bandwidth_limit,
+			per_connection: config.connection_bandwidth_limit,
+		})
+	}
+
+	fn with_limits(limits: BandwidthLimits) -> Self {
+		let state = Arc::new(Mutex::new(LimiterState {
+			limits,
+			global_in: TokenBucket::new(),
+			global_out: TokenBucket::new(),
+			throughput_in: ThroughputEstimator::new(),
+			throughput_out: ThroughputEstimator::new(),
+		}));
+
+		let state2 = state.clone();
+		let throughput = global::meter("garage/api")
+			.u64_value_observer("api.s3.throughput", move |observer| {
+				let mut state = state2.lock().unwrap();
+				for dir in [Direction::In, Direction::Out] {
+					let rate = state.throughput(dir).current(Instant::now());
+					observer.observe(rate, &[KeyValue::new("direction", dir.as_str())]);
+				}
+			})
+			.with_description("Bytes per second transferred in S3 request and response bodies")
+			.init();
+
+		Self {
+			state,
+			_throughput: throughput,
+		}
+	}
+
+	pub fn limits(&self) -> BandwidthLimits {
+		self.state.lock().unwrap().limits
+	}
+
+	pub fn set_limits(&self, limits: BandwidthLimits) {
+		self.state.lock().unwrap().limits = limits;
+	}
+
+	/// Current throughput in bytes per second, in the given direction
+	pub fn throughput(&self, dir: Direction) -> u64 {
+		self.state
+			.lock()
+			.unwrap()
+			.throughput(dir)
+			.current(Instant::now())
+	}
+
+	/// Pace the data of a request or response body
+	pub fn pace<B>(self: &Arc<Self>, body: B, dir: Direction) -> PacedBody<B> {
+		PacedBody {
+			inner: body,
+			limiter: self.clone(),
+			dir,
+			connection: TokenBucket::new(),
+			sleep: None,
+		}
+	}
+
+	/// Record that `len` bytes are transferred, and return how long
+	/// to wait before transferring more data
+	fn reserve(&self, dir: Direction, connection: &mut TokenBucket, len: u64) -> Duration {
+		let now = Instant::now();
+		let mut state = self.state.lock().unwrap();
+		state.throughput(dir).record(now, len);
+
+		let limits = state.limits;
+		let global_bucket = match dir {
+			Direction::In => &mut state.global_in,
+			Direction::Out => &mut state.global_out,
+		};
+		let global_delay = global_bucket.reserve(now, limits.global, len);
+		let connection_delay = connection.reserve(now, limits.per_connection, len);
+		std::cmp::max(global_delay, connection_delay)
+	}
+}
+
+impl LimiterState {
+	fn throughput(&mut self, dir: Direction) -> &mut ThroughputEstimator {
+		match dir {
+			Direction::In => &mut self.throughput_in,
+			Direction::Out => &mut self.throughput_out,
+		}
+	}
+}
+
+// ---- token bucket ----
+
+struct TokenBucket {
+	tokens: f64,
+	last_refill: Instant,
+}
+
+impl TokenBucket {
+	fn new() -> Self {
+		Self {
+			// clamped to the capacity of the bucket on first use,
+			// i.e. the bucket starts full
+			tokens: f64::INFINITY,
+			last_refill: Instant::now(),
+		}
+	}
+
+	fn reserve(&mut self, now: Instant, rate: Option<u64>, len: u64) -> Duration {
+		let rate = match rate {
+			Some(r) if r > 0 => r as f64,
+			_ => {
+				self.tokens = f64::INFINITY;
+				self.last_refill = now;
+				return Duration::ZERO;
+			}
+		};
+
+		let elapsed = now.saturating_duration_since(self.last_refill);
+		self.tokens = (self.tokens + elapsed.as_secs_f64() * rate).min(rate * BURST_SECS);
+		self.last_refill = now;
+
+		self.tokens -= len as f64;
+		if self.tokens >= 0.0 {
+			Duration::ZERO
+		} else {
+			Duration::from_secs_f64(-self.tokens / rate)
+		}
+	}
+}
+
+// ---- throughput estimation ----
+
+/// Counts bytes transferred over windows of one second
+struct ThroughputEstimator {
+	window_start: Instant,
+	window_bytes: u64,
+	last_rate: u64,
+}
+
+impl ThroughputEstimator {
+	fn new() -> Self {
+		Self {
+			window_start: Instant::now(),
+			window_bytes: 0,
+			last_rate: 0,
+		}
+	}
+
+	fn roll(&mut self, now: Instant) {
+		let elapsed = now.saturating_duration_since(self.window_start);
+		if elapsed >= Duration::from_secs(2) {
+			// nothing was recorded during the last full window
+			self.last_rate = 0;
+		} else if elapsed >= Duration::from_secs(1) {
+			self.last_rate = (self.window_bytes as f64 / elapsed.as_secs_f64()) as u64;
+		} else {
+			return;
+		}
+		self.window_start = now;
+		self.window_bytes = 0;
+	}
+
+	fn record(&mut self, now: Instant, len: u64) {
+		self.roll(now);
+		self.window_bytes += len;
+	}
+
+	fn current(&mut self, now: Instant) -> u64 {
+		self.roll(now);
+		self.last_rate
+	}
+}
+
+// ---- paced body ----
+
+#[pin_project::pin_project]
+pub struct PacedBody<B> {
+	#[pin]
+	inner: B,
+	limiter: Arc<BandwidthLimiter>,
+	dir: Direction,
+	connection: TokenBucket,
+	sleep: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl<B> Body for PacedBody<B>
+where
+	B: Body<Data = bytes::Bytes>,
+{
+	type Data = bytes::Bytes;
+	type Error = B::Error;
+
+	fn poll_frame(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+	) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+		let this = self.project();
+
+		if let Some(sleep) = this.sleep.as_mut() {
+			if sleep.as_mut().poll(cx).is_pending() {
+				return Poll::Pending;
+			}
+			*this.sleep = None;
+		}
+
+		let res = futures::ready!(this.inner.poll_frame(cx));
+		if let Some(Ok(frame)) = &res {
+			if let Some(data) = frame.data_ref() {
+				let delay = this
+					.limiter
+					.reserve(*this.dir, this.connection, data.len() as u64);
+				if !delay.is_zero() {
+					*this.sleep = Some(Box::pin(tokio::time::sleep(delay)));
+				}
+			}
+		}
+		Poll::Ready(res)
+	}
+
+	fn is_end_stream(&self) -> bool {
+		self.sleep.is_none() && self.inner.is_end_stream()
+	}
+
+	fn size_hint(&self) -> SizeHint {
+		self.inner.size_hint()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use futures::stream::StreamExt;
+	use http_body_util::{BodyExt, StreamBody};
+
+	const CHUNK_SIZE: usize = 64 * 1024;
+
+	async fn download(limiter: &Arc<BandwidthLimiter>, len: usize) -> Duration {
+		let chunks = futures::stream::iter(0..len / CHUNK_SIZE).map(|_| {
+			Ok::<_, std::convert::Infallible>(Frame::data(bytes::Bytes::from(vec![
+				0u8;
+				CHUNK_SIZE
+			])))
+		});
+		let body = limiter.pace(StreamBody::new(chunks), Direction::Out);
+
+		let start = Instant::now();
+		let data = body.collect().await.unwrap().to_bytes();
+		assert_eq!(data.len(), len);
+		start.elapsed()
+	}
+
+	#[tokio::test]
+	async fn test_paced_download() {
+		// 3 MiB at 1 MiB/s: the first MiB is sent at once (burst),
+		// the next two at the configured rate
+		let limiter = Arc::new(BandwidthLimiter::with_limits(BandwidthLimits {
+			global: None,
+			per_connection: Some(1024 * 1024),
+		}));
+		let elapsed = download(&limiter, 3 * 1024 * 1024).await;
+		assert!(elapsed >= Duration::from_millis(1800), "{:?}", elapsed);
+		assert!(elapsed < Duration::from_millis(3000), "{:?}", elapsed);
+		assert!(limiter.throughput(Direction::Out) > 0);
+		assert_eq!(limiter.throughput(Direction::In), 0);
+
+		// Without limits, nothing is delayed
+		limiter.set_limits(BandwidthLimits::default());
+		let elapsed = download(&limiter, 3 * 1024 * 1024).await;
+		assert!(elapsed < Duration::from_millis(500), "{:?}", elapsed);
+	}
+
+	#[tokio::test]
+	async fn test_global_limit_is_shared() {
+		// Two concurrent downloads of 1.5 MiB share a global limit of 1 MiB/s,
+		// so that they take about as long as a single download of 3 MiB
+		let limiter = Arc::new(BandwidthLimiter::with_limits(BandwidthLimits {
+			global: Some(1024 * 1024),
+			per_connection: None,
+		}));
+		let start = Instant::now();
+		futures::join!(
+			download(&limiter, 3 * 512 * 1024),
+			download(&limiter, 3 * 512 * 1024)
+		);
+		let elapsed = start.elapsed();
+		assert!(elapsed >= Duration::from_millis(1800), "{:?}", elapsed);
+		assert!(elapsed < Duration::from_millis(3000), "{:?}", elapsed);
+	}
+}
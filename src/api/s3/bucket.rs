@@ -4,7 +4,7 @@ use http_body_util::BodyExt;
 use hyper::{Request, Response, StatusCode};
 
 use garage_model::bucket_alias_table::*;
-use garage_model::bucket_table::Bucket;
+use garage_model::bucket_table::{Bucket, BucketParams};
 use garage_model::garage::Garage;
 use garage_model::key_table::Key;
 use garage_model::permission::BucketKeyPerm;
@@ -17,9 +17,29 @@ use crate::common_error::CommonError;
 use crate::helpers::*;
 use crate::s3::api_server::{ReqBody, ResBody};
 use crate::s3::error::*;
+use crate::s3::put::max_part_size;
+use crate::s3::request_payment::PAYER_REQUESTER;
 use crate::s3::xml as s3_xml;
 use crate::signature::verify_signed_content;
 
+pub const X_GARAGE_MAX_OBJECT_SIZE: &str = "x-garage-max-object-size";
+pub const X_GARAGE_MAX_PART_SIZE: &str = "x-garage-max-part-size";
+
+pub fn handle_head_bucket(ctx: ReqCtx) -> Result<Response<ResBody>, Error> {
+	let config = &ctx.garage.config.s3_api;
+
+	// Advertise size limits to clients that know about them
+	let mut resp = Response::builder();
+	if let Some(max) = config.max_object_size {
+		resp = resp.header(X_GARAGE_MAX_OBJECT_SIZE, max.to_string());
+	}
+	if let Some(max) = max_part_size(config) {
+		resp = resp.header(X_GARAGE_MAX_PART_SIZE, max.to_string());
+	}
+
+	Ok(resp.body(empty_body())?)
+}
+
 pub fn handle_get_bucket_location(ctx: ReqCtx) -> Result<Response<ResBody>, Error> {
 	let ReqCtx { garage, .. } = ctx;
 	let loc = s3_xml::LocationConstraint {
@@ -67,7 +87,7 @@ pub async fn handle_list_buckets(
 		.collect::<Vec<_>>();
 
 	let mut buckets_by_id = HashMap::new();
-	let mut aliases = HashMap::new();
+	let mut global_aliases = vec![];
 
 	for bucket_id in ids.iter() {
 		let bucket = garage.bucket_table.get(&EmptyKey, bucket_id).await?;
@@ -76,37 +96,32 @@ pub async fn handle_list_buckets(
 				let alias_opt = garage.bucket_alias_table.get(&EmptyKey, alias).await?;
 				if let Some(alias_ent) = alias_opt {
 					if *alias_ent.state.get() == Some(*bucket_id) {
-						aliases.insert(alias_ent.name().to_string(), *bucket_id);
+						global_aliases.push((alias_ent.name().to_string(), *bucket_id));
 					}
 				}
 			}
 			if let Deletable::Present(param) = bucket.state {
-				buckets_by_id.insert(bucket_id, param);
+				buckets_by_id.insert(*bucket_id, param);
 			}
 		}
 	}
 
-	for (alias, _, id_opt) in key_p.local_aliases.items() {
-		if let Some(id) = id_opt {
-			aliases.insert(alias.clone(), *id);
-		}
-	}
+	let local_aliases = key_p
+		.local_aliases
+		.items()
+		.iter()
+		.filter_map(|(alias, _, id_opt)| id_opt.map(|id| (alias.clone(), id)))
+		.collect::<Vec<_>>();
 
 	// Generate response
 	let list_buckets = s3_xml::ListAllMyBucketsResult {
+		xmlns: (),
 		owner: s3_xml::Owner {
 			display_name: s3_xml::Value(key_p.name.get().to_string()),
 			id: s3_xml::Value(api_key.key_id.to_string()),
 		},
 		buckets: s3_xml::BucketList {
-			entries: aliases
-				.iter()
-				.filter_map(|(name, id)| buckets_by_id.get(id).map(|p| (name, id, p)))
-				.map(|(name, _id, param)| s3_xml::Bucket {
-					creation_date: s3_xml::Value(msec_to_rfc3339(param.creation_date)),
-					name: s3_xml::Value(name.to_string()),
-				})
-				.collect(),
+			entries: list_buckets_entries(&buckets_by_id, &local_aliases, &global_aliases),
 		},
 	};
 
@@ -118,6 +133,50 @@ pub async fn handle_list_buckets(
 		.body(string_body(xml))?)
 }
 
+/// Entries of the ListBuckets response: one per bucket the key has access to
+/// and that has a name it can use, sorted by name. Buckets are named by the
+/// first of the key's local aliases that point to them if there is one,
+/// and by the first of their global aliases otherwise.
+fn list_buckets_entries(
+	buckets_by_id: &HashMap<Uuid, BucketParams>,
+	local_aliases: &[(String, Uuid)],
+	global_aliases: &[(String, Uuid)],
+) -> Vec<s3_xml::Bucket> {
+	let mut names: HashMap<Uuid, (bool, &str)> = HashMap::new();
+	let candidates = local_aliases
+		.iter()
+		.map(|(name, id)| (false, name, id))
+		.chain(global_aliases.iter().map(|(name, id)| (true, name, id)));
+	for (is_global, name, id) in candidates {
+		if !buckets_by_id.contains_key(id) {
+			continue;
+		}
+		let name = (is_global, name.as_str());
+		names
+			.entry(*id)
+			.and_modify(|best| *best = std::cmp::min(*best, name))
+			.or_insert(name);
+	}
+
+	let mut entries = names
+		.into_iter()
+		.map(|(id, (_, name))| {
+			let param = &buckets_by_id[&id];
+			s3_xml::Bucket {
+				creation_date: s3_xml::Value(msec_to_rfc3339(param.creation_date)),
+				name: s3_xml::Value(name.to_string()),
+				bucket_id: Some(s3_xml::Value(hex::encode(id))),
+				payer: param
+					.requester_pays
+					.get()
+					.then(|| s3_xml::Value(PAYER_REQUESTER.to_string())),
+			}
+		})
+		.collect::<Vec<_>>();
+	entries.sort_by(|a, b| a.name.0.cmp(&b.name.0));
+	entries
+}
+
 pub async fn handle_create_bucket(
 	garage: &Garage,
 	req: Request<ReqBody>,
@@ -179,7 +238,7 @@ pub async fn handle_create_bucket(
 		}
 
 		// Create the bucket!
-		if !is_valid_bucket_name(&bucket_name) {
+		if !is_valid_bucket_name(&bucket_name, garage.config.allow_punycode) {
 			return Err(Error::bad_request(format!(
 				"{}: {}",
 				bucket_name, INVALID_BUCKET_NAME_MESSAGE
@@ -245,30 +304,7 @@ pub async fn handle_delete_bucket(ctx: ReqCtx) -> Result<Response<ResBody>, Erro
 		}
 
 		// --- done checking, now commit ---
-		// 1. delete bucket alias
-		if is_local_alias {
-			helper
-				.unset_local_bucket_alias(*bucket_id, &api_key.key_id, bucket_name)
-				.await?;
-		} else {
-			helper
-				.unset_global_bucket_alias(*bucket_id, bucket_name)
-				.await?;
-		}
-
-		// 2. delete authorization from keys that had access
-		for (key_id, _) in bucket_state.authorized_keys.items() {
-			helper
-				.set_bucket_key_permissions(*bucket_id, key_id, BucketKeyPerm::NO_PERMISSIONS)
-				.await?;
-		}
-
-		let bucket = Bucket {
-			id: *bucket_id,
-			state: Deletable::delete(),
-		};
-		// 3. delete bucket
-		garage.bucket_table.insert(&bucket).await?;
+		helper.delete_bucket(*bucket_id).await?;
 	} else if is_local_alias {
 		// Just unalias
 		helper
@@ -322,6 +358,67 @@ fn parse_create_bucket_xml(xml_bytes: &[u8]) -> Option<Option<String>> {
 mod tests {
 	use super::*;
 
+	#[test]
+	fn list_buckets_aliases() {
+		let bucket = |n: u8, creation_date: u64| {
+			let params = BucketParams {
+				creation_date,
+				..Default::default()
+			};
+			(Uuid::from([n; 32]), params)
+		};
+		let (a, b, c, d) = (
+			Uuid::from([1; 32]),
+			Uuid::from([2; 32]),
+			Uuid::from([3; 32]),
+			Uuid::from([4; 32]),
+		);
+		// The key has access to buckets 1, 2, 3 and 5, but not to bucket 4
+		let buckets_by_id = [
+			bucket(1, 1000),
+			bucket(2, 2000),
+			bucket(3, 3000),
+			bucket(5, 5000),
+		]
+		.iter()
+		.cloned()
+		.collect::<HashMap<_, _>>();
+
+		let local_aliases = vec![
+			("mine".to_string(), a),
+			("also-mine".to_string(), a),
+			("local-only".to_string(), c),
+			("not-allowed".to_string(), d),
+		];
+		let global_aliases = vec![
+			("a-global".to_string(), a),
+			("zeta".to_string(), b),
+			("beta".to_string(), b),
+			("d-global".to_string(), d),
+		];
+
+		let entries = list_buckets_entries(&buckets_by_id, &local_aliases, &global_aliases);
+		let entries = entries
+			.iter()
+			.map(|e| {
+				(
+					e.name.0.as_str(),
+					e.creation_date.0.as_str(),
+					e.bucket_id.as_ref().unwrap().0.clone(),
+				)
+			})
+			.collect::<Vec<_>>();
+		assert_eq!(
+			entries,
+			vec![
+				// Local aliases are preferred over global aliases
+				("also-mine", "1970-01-01T00:00:01.000Z", hex::encode(a)),
+				("beta", "1970-01-01T00:00:02.000Z", hex::encode(b)),
+				("local-only", "1970-01-01T00:00:03.000Z", hex::encode(c)),
+			]
+		);
+	}
+
 	#[test]
 	fn create_bucket() {
 		assert_eq!(parse_create_bucket_xml(br#""#), Some(None));
@@ -1,5 +1,7 @@
 use std::convert::{TryFrom, TryInto};
 use std::hash::Hasher;
+use std::sync::OnceLock;
+use std::time::Instant;
 
 use base64::prelude::*;
 use crc32c::Crc32cHasher as Crc32c;
@@ -9,17 +11,28 @@ use sha1::Sha1;
 use sha2::Sha256;
 
 use http::{HeaderMap, HeaderName, HeaderValue};
+use opentelemetry::{
+	global,
+	metrics::{Counter, ValueRecorder},
+};
 
 use garage_util::data::*;
-use garage_util::error::OkOrMessage;
+use garage_util::error::{Error as GarageError, OkOrMessage};
 
+use garage_net::stream::read_stream_to_end;
+
+use garage_model::garage::Garage;
 use garage_model::s3::object_table::*;
 
 use crate::s3::error::*;
 
+const CONTENT_MD5: HeaderName = HeaderName::from_static("content-md5");
+
 pub const X_AMZ_CHECKSUM_ALGORITHM: HeaderName =
 	HeaderName::from_static("x-amz-checksum-algorithm");
 pub const X_AMZ_CHECKSUM_MODE: HeaderName = HeaderName::from_static("x-amz-checksum-mode");
+pub const X_AMZ_SDK_CHECKSUM_ALGORITHM: HeaderName =
+	HeaderName::from_static("x-amz-sdk-checksum-algorithm");
 pub const X_AMZ_CHECKSUM_CRC32: HeaderName = HeaderName::from_static("x-amz-checksum-crc32");
 pub const X_AMZ_CHECKSUM_CRC32C: HeaderName = HeaderName::from_static("x-amz-checksum-crc32c");
 pub const X_AMZ_CHECKSUM_SHA1: HeaderName = HeaderName::from_static("x-amz-checksum-sha1");
@@ -119,7 +132,7 @@ impl Checksummer {
 			sha1.update(bytes);
 		}
 		if let Some(sha256) = &mut self.sha256 {
-			sha256.update(bytes);
+			sha256_update_timed(sha256, bytes);
 		}
 	}
 
@@ -181,6 +194,75 @@ impl Checksums {
 	}
 }
 
+/// Checksum of a single data block, as stored in the version table
+/// when `s3_api.store_block_checksums` is enabled
+pub(crate) fn block_checksum(algo: ChecksumAlgorithm, bytes: &[u8]) -> ChecksumValue {
+	let mut checksummer = Checksummer::init(&Default::default(), false).add(Some(algo));
+	checksummer.update(bytes);
+	checksummer.finalize().extract(Some(algo)).unwrap()
+}
+
+// ----
+
+struct Sha256Metrics {
+	bytes: Counter<u64>,
+	duration: ValueRecorder<f64>,
+}
+
+fn sha256_metrics() -> &'static Sha256Metrics {
+	static METRICS: OnceLock<Sha256Metrics> = OnceLock::new();
+	METRICS.get_or_init(|| {
+		let meter = global::meter("garage/api");
+		Sha256Metrics {
+			bytes: meter
+				.u64_counter("api.s3.sha256_bytes")
+				.with_description("Number of bytes hashed with SHA256 for S3 checksums")
+				.init(),
+			duration: meter
+				.f64_value_recorder("api.s3.sha256_duration")
+				.with_description("Duration of SHA256 hashing of data blocks for S3 checksums")
+				.init(),
+		}
+	})
+}
+
+/// Feed bytes to a SHA256 hasher, recording the time spent hashing,
+/// so that the hashing throughput can be monitored
+pub(crate) fn sha256_update_timed(sha256: &mut Sha256, bytes: &[u8]) {
+	let start = Instant::now();
+	sha256.update(bytes);
+	let metrics = sha256_metrics();
+	metrics.bytes.add(bytes.len() as u64, &[]);
+	metrics.duration.record(start.elapsed().as_secs_f64(), &[]);
+}
+
+/// Compute the SHA256 checksum of a sequence of plaintext data blocks,
+/// reading them one after the other so that memory usage stays bounded
+pub(crate) async fn blocks_sha256(
+	garage: &Garage,
+	blocks: impl Iterator<Item = Hash>,
+) -> Result<Hash, Error> {
+	let mut sha256 = Sha256::new();
+	for block in blocks {
+		let stream = garage
+			.block_manager
+			.rpc_get_block_streaming(&block, None)
+			.await?;
+		let data = read_stream_to_end(stream)
+			.await
+			.map_err(GarageError::from)?
+			.into_bytes();
+		sha256 = tokio::task::spawn_blocking(move || {
+			sha256_update_timed(&mut sha256, &data);
+			sha256
+		})
+		.await
+		.unwrap();
+	}
+	let digest: [u8; 32] = sha256.finalize()[..].try_into().unwrap();
+	Ok(Hash::from(digest))
+}
+
 // ----
 
 #[derive(Default)]
@@ -293,51 +375,122 @@ pub(crate) fn request_checksum_algorithm(
 	}
 }
 
-/// Extract the value of any of the x-amz-checksum-* headers
+/// Extract the value of any of the x-amz-checksum-* headers.
+///
+/// Integrity headers that conflict are rejected rather than checking the
+/// body against only one of them. Headers conflict when:
+/// - several x-amz-checksum-* headers are given, even with values that
+///   would all be correct, as a single checksum algorithm can be used;
+/// - the same integrity header (including content-md5) is given several
+///   times, as only one of the values could be checked;
+/// - x-amz-checksum-algorithm or x-amz-sdk-checksum-algorithm names
+///   a different algorithm than the x-amz-checksum-* header.
+///
+/// A content-md5 header alongside an x-amz-checksum-* header does not
+/// conflict with it: both are checked.
 pub(crate) fn request_checksum_value(
 	headers: &HeaderMap<HeaderValue>,
 ) -> Result<Option<ChecksumValue>, Error> {
 	let mut ret = vec![];
 
-	if let Some(crc32_str) = headers.get(X_AMZ_CHECKSUM_CRC32) {
+	if let Some(crc32_str) = single_header(headers, &X_AMZ_CHECKSUM_CRC32)? {
 		let crc32 = BASE64_STANDARD
-			.decode(&crc32_str)
+			.decode(crc32_str)
 			.ok()
 			.and_then(|x| x.try_into().ok())
 			.ok_or_bad_request("invalid x-amz-checksum-crc32 header")?;
-		ret.push(ChecksumValue::Crc32(crc32))
+		ret.push((X_AMZ_CHECKSUM_CRC32, ChecksumValue::Crc32(crc32)))
 	}
-	if let Some(crc32c_str) = headers.get(X_AMZ_CHECKSUM_CRC32C) {
+	if let Some(crc32c_str) = single_header(headers, &X_AMZ_CHECKSUM_CRC32C)? {
 		let crc32c = BASE64_STANDARD
-			.decode(&crc32c_str)
+			.decode(crc32c_str)
 			.ok()
 			.and_then(|x| x.try_into().ok())
 			.ok_or_bad_request("invalid x-amz-checksum-crc32c header")?;
-		ret.push(ChecksumValue::Crc32c(crc32c))
+		ret.push((X_AMZ_CHECKSUM_CRC32C, ChecksumValue::Crc32c(crc32c)))
 	}
-	if let Some(sha1_str) = headers.get(X_AMZ_CHECKSUM_SHA1) {
+	if let Some(sha1_str) = single_header(headers, &X_AMZ_CHECKSUM_SHA1)? {
 		let sha1 = BASE64_STANDARD
-			.decode(&sha1_str)
+			.decode(sha1_str)
 			.ok()
 			.and_then(|x| x.try_into().ok())
 			.ok_or_bad_request("invalid x-amz-checksum-sha1 header")?;
-		ret.push(ChecksumValue::Sha1(sha1))
+		ret.push((X_AMZ_CHECKSUM_SHA1, ChecksumValue::Sha1(sha1)))
 	}
-	if let Some(sha256_str) = headers.get(X_AMZ_CHECKSUM_SHA256) {
+	if let Some(sha256_str) = single_header(headers, &X_AMZ_CHECKSUM_SHA256)? {
 		let sha256 = BASE64_STANDARD
-			.decode(&sha256_str)
+			.decode(sha256_str)
 			.ok()
 			.and_then(|x| x.try_into().ok())
 			.ok_or_bad_request("invalid x-amz-checksum-sha256 header")?;
-		ret.push(ChecksumValue::Sha256(sha256))
+		ret.push((X_AMZ_CHECKSUM_SHA256, ChecksumValue::Sha256(sha256)))
 	}
 
 	if ret.len() > 1 {
-		return Err(Error::bad_request(
-			"multiple x-amz-checksum-* headers given",
-		));
+		let names = ret
+			.iter()
+			.map(|(name, _)| name.as_str())
+			.collect::<Vec<_>>();
+		return Err(Error::bad_request(format!(
+			"conflicting integrity headers: {} are all given, but only one checksum algorithm can be used",
+			names.join(", ")
+		)));
+	}
+	let (name, value) = match ret.pop() {
+		Some(x) => x,
+		None => return Ok(None),
+	};
+
+	for algo_header in [X_AMZ_CHECKSUM_ALGORITHM, X_AMZ_SDK_CHECKSUM_ALGORITHM] {
+		if let Some(algo) = single_header(headers, &algo_header)? {
+			if parse_checksum_algorithm(algo) != Some(value.algorithm()) {
+				return Err(Error::bad_request(format!(
+					"conflicting integrity headers: {} is {:?}, but {} is given",
+					algo_header,
+					String::from_utf8_lossy(algo.as_bytes()),
+					name
+				)));
+			}
+		}
+	}
+
+	Ok(Some(value))
+}
+
+/// Extract the value of the content-md5 header, which must not be given several times
+pub(crate) fn request_content_md5(
+	headers: &HeaderMap<HeaderValue>,
+) -> Result<Option<String>, Error> {
+	match single_header(headers, &CONTENT_MD5)? {
+		Some(x) => Ok(Some(x.to_str()?.to_string())),
+		None => Ok(None),
+	}
+}
+
+/// Value of an integrity header, which is rejected if it is given several times
+fn single_header<'a>(
+	headers: &'a HeaderMap<HeaderValue>,
+	name: &HeaderName,
+) -> Result<Option<&'a HeaderValue>, Error> {
+	let mut values = headers.get_all(name).iter();
+	let value = values.next();
+	if values.next().is_some() {
+		return Err(Error::bad_request(format!(
+			"conflicting integrity headers: {} is given several times",
+			name
+		)));
+	}
+	Ok(value)
+}
+
+fn parse_checksum_algorithm(value: &HeaderValue) -> Option<ChecksumAlgorithm> {
+	match &value.as_bytes().to_ascii_uppercase()[..] {
+		b"CRC32" => Some(ChecksumAlgorithm::Crc32),
+		b"CRC32C" => Some(ChecksumAlgorithm::Crc32c),
+		b"SHA1" => Some(ChecksumAlgorithm::Sha1),
+		b"SHA256" => Some(ChecksumAlgorithm::Sha256),
+		_ => None,
 	}
-	Ok(ret.pop())
 }
 
 /// Checks for the presense of x-amz-checksum-algorithm
@@ -404,3 +557,90 @@ pub(crate) fn add_checksum_response_headers(
 	}
 	resp
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const CRC32: &str = "AAAAAA==";
+	const SHA256: &str = "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=";
+	const MD5: &str = "1B2M2Y8AsgTpgAmY7PhCfg==";
+
+	fn headers(list: &[(&str, &str)]) -> HeaderMap<HeaderValue> {
+		let mut headers = HeaderMap::new();
+		for (name, value) in list {
+			headers.append(
+				HeaderName::from_bytes(name.as_bytes()).unwrap(),
+				HeaderValue::from_str(value).unwrap(),
+			);
+		}
+		headers
+	}
+
+	fn is_conflict(res: Result<Option<ChecksumValue>, Error>) -> bool {
+		matches!(res, Err(e) if e.aws_code() == "InvalidRequest"
+			&& e.to_string().contains("conflicting integrity headers"))
+	}
+
+	#[test]
+	fn test_compatible_integrity_headers() {
+		assert_eq!(request_checksum_value(&headers(&[])).unwrap(), None);
+
+		// content-md5 is checked in addition to the other checksum
+		let h = headers(&[("content-md5", MD5), ("x-amz-checksum-crc32", CRC32)]);
+		assert_eq!(
+			request_checksum_value(&h).unwrap(),
+			Some(ChecksumValue::Crc32([0; 4]))
+		);
+		assert_eq!(request_content_md5(&h).unwrap().as_deref(), Some(MD5));
+
+		// The algorithm can be given along with the checksum
+		let h = headers(&[
+			("x-amz-sdk-checksum-algorithm", "SHA256"),
+			("x-amz-checksum-sha256", SHA256),
+		]);
+		assert!(matches!(
+			request_checksum_value(&h).unwrap(),
+			Some(ChecksumValue::Sha256(_))
+		));
+
+		// An algorithm without a checksum header is not a conflict
+		// (the checksum can be sent in a trailer)
+		let h = headers(&[("x-amz-sdk-checksum-algorithm", "CRC32")]);
+		assert_eq!(request_checksum_value(&h).unwrap(), None);
+	}
+
+	#[test]
+	fn test_conflicting_integrity_headers() {
+		// Checksums for several algorithms
+		assert!(is_conflict(request_checksum_value(&headers(&[
+			("x-amz-checksum-crc32", CRC32),
+			("x-amz-checksum-sha256", SHA256),
+		]))));
+
+		// Two values for the same algorithm, even identical ones
+		assert!(is_conflict(request_checksum_value(&headers(&[
+			("x-amz-checksum-crc32", CRC32),
+			("x-amz-checksum-crc32", "AAAAAQ=="),
+		]))));
+		assert!(is_conflict(request_checksum_value(&headers(&[
+			("x-amz-checksum-crc32", CRC32),
+			("x-amz-checksum-crc32", CRC32),
+		]))));
+		assert!(request_content_md5(&headers(&[
+			("content-md5", MD5),
+			("content-md5", "XUFAKrxLKna5cZ2REBfFkg=="),
+		]))
+		.is_err());
+
+		// An algorithm that is not the one of the checksum
+		assert!(is_conflict(request_checksum_value(&headers(&[
+			("x-amz-sdk-checksum-algorithm", "CRC32"),
+			("x-amz-checksum-sha256", SHA256),
+		]))));
+		assert!(is_conflict(request_checksum_value(&headers(&[
+			("x-amz-checksum-algorithm", "SHA1"),
+			("x-amz-checksum-crc32", CRC32),
+		]))));
+	}
+}
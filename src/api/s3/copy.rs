@@ -1,7 +1,9 @@
 use std::pin::Pin;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use futures::{stream, stream::Stream, StreamExt, TryStreamExt};
+use base64::prelude::*;
+use futures::{stream, stream::Stream, Future, StreamExt, TryStreamExt};
+use tokio::sync::mpsc;
 
 use bytes::Bytes;
 use hyper::{Request, Response};
@@ -15,23 +17,37 @@ use garage_util::data::*;
 use garage_util::error::Error as GarageError;
 use garage_util::time::*;
 
+use garage_model::garage::Garage;
 use garage_model::s3::block_ref_table::*;
 use garage_model::s3::mpu_table::*;
 use garage_model::s3::object_table::*;
+use garage_model::s3::previous_version::retain_previous_version;
 use garage_model::s3::version_table::*;
 
 use crate::helpers::*;
 use crate::s3::api_server::{ReqBody, ResBody};
 use crate::s3::checksum::*;
+use crate::s3::delete::delete_if_current_version;
 use crate::s3::encryption::EncryptionParams;
 use crate::s3::error::*;
 use crate::s3::get::full_object_byte_stream;
+use crate::s3::headers::add_version_headers;
 use crate::s3::multipart;
-use crate::s3::put::{get_headers, save_stream, ChecksumMode, SaveStreamResult};
+use crate::s3::put::{
+	check_size_limit, get_headers, max_part_size, save_stream, ChecksumMode, SaveStreamResult,
+};
 use crate::s3::xml::{self as s3_xml, xmlns_tag};
 
 // -------- CopyObject ---------
 
+/// Size of the source object above which the response of CopyObject
+/// is sent before the copy is finished
+pub const DEFAULT_COPY_KEEPALIVE_THRESHOLD: u64 = 5 * 1024 * 1024 * 1024;
+/// Interval at which whitespace is sent while a large copy is running
+const COPY_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+/// Number of block references that are written at once when copying an object
+const COPY_BLOCK_REF_BATCH_SIZE: usize = 1000;
+
 pub async fn handle_copy(
 	ctx: ReqCtx,
 	req: &Request<ReqBody>,
@@ -41,14 +57,31 @@ pub async fn handle_copy(
 
 	let checksum_algorithm = request_checksum_algorithm(req.headers())?;
 
-	let source_object = get_copy_source(&ctx, req).await?;
+	let move_source = parse_move_source_header(req)?;
+
+	let metadata_directive = parse_directive_header(req, "x-amz-metadata-directive")?;
+	// Object tags are not stored by Garage: there are no tags to copy, and
+	// replacing them with those of the request is not supported
+	if parse_directive_header(req, "x-amz-tagging-directive")? == Directive::Replace {
+		return Err(Error::NotImplemented(
+			"Object tagging is not implemented, x-amz-tagging-directive: REPLACE is not supported"
+				.into(),
+		));
+	}
+
+	let (source_object, source_version_uuid) = get_copy_source(&ctx, req).await?;
 
-	let (source_version, source_version_data, source_version_meta) =
-		extract_source_info(&source_object)?;
+	let (source_version, _, source_version_meta) =
+		extract_source_info(&source_object, source_version_uuid)?;
 
 	// Check precondition, e.g. x-amz-copy-source-if-match
 	copy_precondition.check(source_version, &source_version_meta.etag)?;
 
+	check_size_limit(
+		source_version_meta.size,
+		ctx.garage.config.s3_api.max_object_size,
+	)?;
+
 	// Determine encryption parameters
 	let (source_encryption, source_object_meta_inner) =
 		EncryptionParams::check_decrypt_for_copy_source(
@@ -60,6 +93,7 @@ pub async fn handle_copy(
 
 	// Extract source checksum info before source_object_meta_inner is consumed
 	let source_checksum = source_object_meta_inner.checksum;
+	let source_full_sha256 = source_object_meta_inner.full_sha256;
 	let source_checksum_algorithm = source_checksum.map(|x| x.algorithm());
 
 	// If source object has a checksum, the destination object must as well.
@@ -70,13 +104,14 @@ pub async fn handle_copy(
 	// Determine metadata of destination object
 	let was_multipart = source_version_meta.etag.contains('-');
 	let dest_object_meta = ObjectVersionMetaInner {
-		headers: match req.headers().get("x-amz-metadata-directive") {
-			Some(v) if v == hyper::header::HeaderValue::from_static("REPLACE") => {
-				get_headers(req.headers())?
-			}
-			_ => source_object_meta_inner.into_owned().headers,
+		headers: match metadata_directive {
+			Directive::Replace => get_headers(req.headers())?,
+			Directive::Copy => source_object_meta_inner.into_owned().headers,
 		},
 		checksum: source_checksum,
+		// The data is the same, so is its checksum. When data is rewritten,
+		// the checksum is computed again if `always_compute_sha256` is set.
+		full_sha256: source_full_sha256,
 	};
 
 	// Do actual object copying
@@ -99,61 +134,180 @@ pub async fn handle_copy(
 		|| source_checksum_algorithm != checksum_algorithm
 		|| (was_multipart && checksum_algorithm.is_some());
 
-	let res = if !must_recopy {
-		// In most cases, we can just copy the metadata and link blocks of the
-		// old object from the new object.
-		handle_copy_metaonly(
-			ctx,
-			dest_key,
-			dest_object_meta,
-			dest_encryption,
-			source_version,
-			source_version_data,
-			source_version_meta,
-		)
-		.await?
-	} else {
-		let expected_checksum = ExpectedChecksums {
-			md5: None,
-			sha256: None,
-			extra: source_checksum,
-		};
-		let checksum_mode = if was_multipart || source_checksum_algorithm != checksum_algorithm {
-			ChecksumMode::Calculate(checksum_algorithm)
+	if move_source {
+		check_move_source(&ctx, &source_object, source_version, dest_key, must_recopy).await?;
+	}
+
+	let source_size = source_version_meta.size;
+	let source_version_id = source_version.uuid;
+	let garage = ctx.garage.clone();
+	let keepalive_threshold = garage
+		.config
+		.s3_api
+		.copy_keepalive_threshold
+		.unwrap_or(DEFAULT_COPY_KEEPALIVE_THRESHOLD);
+	let region = garage.config.s3_api.s3_region.clone();
+	let dest_key = dest_key.to_string();
+
+	let copy = async move {
+		let (source_version, source_version_data, source_version_meta) =
+			extract_source_info(&source_object, source_version_uuid)?;
+
+		let res = if !must_recopy {
+			// In most cases, we can just copy the metadata and link blocks of the
+			// old object from the new object.
+			handle_copy_metaonly(
+				ctx,
+				&dest_key,
+				dest_object_meta,
+				dest_encryption,
+				source_version,
+				source_version_data,
+				source_version_meta,
+			)
+			.await?
 		} else {
-			ChecksumMode::Verify(&expected_checksum)
+			let expected_checksum = ExpectedChecksums {
+				md5: None,
+				sha256: None,
+				extra: source_checksum,
+			};
+			let checksum_mode = if was_multipart || source_checksum_algorithm != checksum_algorithm
+			{
+				ChecksumMode::Calculate(checksum_algorithm)
+			} else {
+				ChecksumMode::Verify(&expected_checksum)
+			};
+			// If source and dest encryption use different keys,
+			// we must decrypt content and re-encrypt, so rewrite all data blocks.
+			handle_copy_reencrypt(
+				ctx,
+				&dest_key,
+				dest_object_meta,
+				dest_encryption,
+				source_version,
+				source_version_data,
+				source_encryption,
+				checksum_mode,
+			)
+			.await?
 		};
-		// If source and dest encryption use different keys,
-		// we must decrypt content and re-encrypt, so rewrite all data blocks.
-		handle_copy_reencrypt(
-			ctx,
-			dest_key,
-			dest_object_meta,
-			dest_encryption,
-			source_version,
-			source_version_data,
-			source_encryption,
-			checksum_mode,
-		)
-		.await?
-	};
 
-	let last_modified = msec_to_rfc3339(res.version_timestamp);
-	let result = CopyObjectResult {
-		last_modified: s3_xml::Value(last_modified),
-		etag: s3_xml::Value(format!("\"{}\"", res.etag)),
+		// For a move, the source object is deleted only once the destination object
+		// has been fully written, so that it is left intact if anything fails before.
+		if move_source {
+			let deleted = delete_if_current_version(
+				&garage,
+				source_object.bucket_id,
+				&source_object.key,
+				source_version.uuid,
+			)
+			.await?;
+			if deleted.is_none() {
+				info!(
+					"Move source {:?}/{} was modified concurrently, not deleting it",
+					source_object.bucket_id, source_object.key
+				);
+			}
+		}
+
+		Ok::<_, Error>(res)
 	};
-	let xml = s3_xml::to_xml_with_header(&result)?;
 
 	let mut resp = Response::builder()
 		.header("Content-Type", "application/xml")
-		.header("x-amz-version-id", hex::encode(res.version_uuid))
 		.header(
 			"x-amz-copy-source-version-id",
-			hex::encode(source_version.uuid),
+			hex::encode(source_version_id),
 		);
 	dest_encryption.add_response_headers(&mut resp);
-	Ok(resp.body(string_body(xml))?)
+
+	if source_size >= keepalive_threshold {
+		// Copying a large object can take longer than the client is willing
+		// to wait for response headers, so the response is sent right away
+		// and its body is written once the copy is done. The id of the new
+		// version is not known yet and is not sent.
+		let resource = req.uri().path().to_string();
+		return Ok(resp.body(copy_keepalive_body(copy, region, resource))?);
+	}
+
+	let res = copy.await?;
+	let xml = s3_xml::to_xml_with_header(&copy_object_result(&res))?;
+	let resp = add_checksum_response_headers(&res.checksum, resp);
+	Ok(add_version_headers(resp, res.version_uuid, None).body(string_body(xml))?)
+}
+
+fn copy_object_result(res: &SaveStreamResult) -> CopyObjectResult {
+	let encode = |x: &[u8]| Some(s3_xml::Value(BASE64_STANDARD.encode(x)));
+	CopyObjectResult {
+		xmlns: (),
+		last_modified: s3_xml::Value(msec_to_rfc3339(res.version_timestamp)),
+		etag: s3_xml::Value(format!("\"{}\"", res.etag)),
+		checksum_crc32: match &res.checksum {
+			Some(ChecksumValue::Crc32(x)) => encode(x),
+			_ => None,
+		},
+		checksum_crc32c: match &res.checksum {
+			Some(ChecksumValue::Crc32c(x)) => encode(x),
+			_ => None,
+		},
+		checksum_sha1: match &res.checksum {
+			Some(ChecksumValue::Sha1(x)) => encode(x),
+			_ => None,
+		},
+		checksum_sha256: match &res.checksum {
+			Some(ChecksumValue::Sha256(x)) => encode(x),
+			_ => None,
+		},
+	}
+}
+
+/// Build the body of a CopyObject response that is sent before the copy
+/// is finished. Like AWS does, whitespace is sent at regular intervals to
+/// keep the connection alive, followed by the CopyObjectResult, or by an
+/// error document if the copy failed. The copy is not interrupted if the
+/// client goes away.
+fn copy_keepalive_body<F>(copy: F, region: String, resource: String) -> ResBody
+where
+	F: Future<Output = Result<SaveStreamResult, Error>> + Send + 'static,
+{
+	let (tx, rx) = mpsc::channel::<Result<Bytes, Error>>(2);
+
+	tokio::spawn(async move {
+		tokio::pin!(copy);
+		let mut keepalive = tokio::time::interval_at(
+			tokio::time::Instant::now() + COPY_KEEPALIVE_INTERVAL,
+			COPY_KEEPALIVE_INTERVAL,
+		);
+		let res = loop {
+			tokio::select! {
+				res = &mut copy => break res,
+				_ = keepalive.tick() => {
+					let _ = tx.send(Ok(Bytes::from_static(b" "))).await;
+				}
+			}
+		};
+
+		// No XML declaration is sent, as it is not allowed after whitespace
+		let xml = match res {
+			Ok(res) => s3_xml::to_xml(&copy_object_result(&res)),
+			Err(e) => {
+				warn!("Copy to {} failed after response was sent: {}", resource, e);
+				s3_xml::to_xml(&s3_xml::Error {
+					code: s3_xml::Value(e.aws_code().to_string()),
+					message: s3_xml::Value(format!("{}", e)),
+					resource: Some(s3_xml::Value(resource)),
+					region: Some(s3_xml::Value(region)),
+					endpoint: None,
+				})
+			}
+		};
+		let _ = tx.send(xml.map(Bytes::from)).await;
+	});
+
+	let body_stream =
+		tokio_stream::wrappers::ReceiverStream::new(rx).map(|x| x.map(hyper::body::Frame::data));
+	ResBody::new(http_body_util::StreamBody::new(body_stream))
 }
 
 async fn handle_copy_metaonly(
@@ -168,23 +322,42 @@ async fn handle_copy_metaonly(
 	let ReqCtx {
 		garage,
 		bucket_id: dest_bucket_id,
+		bucket_params,
+		original_key,
 		..
 	} = ctx;
 
+	// The object being overwritten is needed only if its version is retained
+	let existing_object = match bucket_params.previous_version_retention_secs.get() {
+		Some(_) => {
+			garage
+				.object_table
+				.get(&dest_bucket_id, &dest_key.to_string())
+				.await?
+		}
+		None => None,
+	};
+
 	// Generate parameters for copied object
 	let new_uuid = gen_uuid();
 	let new_timestamp = now_msec();
 
+	// The data is not rewritten, so the checksum is that of the source
+	let checksum = dest_object_meta.checksum;
 	let new_meta = ObjectVersionMeta {
 		encryption: dest_encryption.encrypt_meta(dest_object_meta)?,
 		size: source_version_meta.size,
 		etag: source_version_meta.etag.clone(),
+		expires_at: None,
 	};
 
 	let res = SaveStreamResult {
 		version_uuid: new_uuid,
 		version_timestamp: new_timestamp,
 		etag: new_meta.etag.clone(),
+		checksum,
+		dedup_ratio: None,
+		quota_warning: None,
 	};
 
 	// Save object copy
@@ -201,11 +374,15 @@ async fn handle_copy_metaonly(
 					bytes.clone(),
 				)),
 			};
-			let dest_object = Object::new(
+			let mut dest_object = Object::new(
 				dest_bucket_id,
 				dest_key.to_string(),
 				vec![dest_object_version],
 			);
+			retain_previous_version(&bucket_params, existing_object.as_ref(), &mut dest_object);
+			if let Some(original_key) = &original_key {
+				dest_object.set_original_key(new_timestamp, original_key);
+			}
 			garage.object_table.insert(&dest_object).await?;
 		}
 		ObjectVersionData::FirstBlock(_meta, first_block_hash) => {
@@ -239,7 +416,7 @@ async fn handle_copy_metaonly(
 			// this means that the BlockRef entries linked to this version cannot be
 			// marked as deleted (they are marked as deleted only if the Version
 			// doesn't exist or is marked as deleted).
-			let mut dest_version = Version::new(
+			let dest_version = Version::new(
 				new_uuid,
 				VersionBacklink::Object {
 					bucket_id: dest_bucket_id,
@@ -249,24 +426,13 @@ async fn handle_copy_metaonly(
 			);
 			garage.version_table.insert(&dest_version).await?;
 
-			// Fill in block list for version and insert block refs
-			for (bk, bv) in source_version.blocks.items().iter() {
-				dest_version.blocks.put(*bk, *bv);
-			}
-			let dest_block_refs = dest_version
-				.blocks
-				.items()
-				.iter()
-				.map(|b| BlockRef {
-					block: b.1.hash,
-					version: new_uuid,
-					deleted: false.into(),
-				})
-				.collect::<Vec<_>>();
-			futures::try_join!(
-				garage.version_table.insert(&dest_version),
-				garage.block_ref_table.insert_many(&dest_block_refs[..]),
-			)?;
+			copy_version_blocks(
+				&garage,
+				*bucket_params.replication_factor.get(),
+				&source_version,
+				&dest_version,
+			)
+			.await?;
 
 			// Insert final object
 			// We do this last because otherwise there is a race condition in the case where
@@ -282,11 +448,15 @@ async fn handle_copy_metaonly(
 					*first_block_hash,
 				)),
 			};
-			let dest_object = Object::new(
+			let mut dest_object = Object::new(
 				dest_bucket_id,
 				dest_key.to_string(),
 				vec![dest_object_version],
 			);
+			retain_previous_version(&bucket_params, existing_object.as_ref(), &mut dest_object);
+			if let Some(original_key) = &original_key {
+				dest_object.set_original_key(new_timestamp, original_key);
+			}
 			garage.object_table.insert(&dest_object).await?;
 		}
 	}
@@ -294,6 +464,43 @@ async fn handle_copy_metaonly(
 	Ok(res)
 }
 
+/// Fill in the block list of a version with the blocks of a source version,
+/// and insert the corresponding block refs, without rewriting the blocks.
+/// This is done by batches so that copying very large objects does not
+/// produce huge RPCs: partial versions are merged together in the version table.
+pub(crate) async fn copy_version_blocks(
+	garage: &Garage,
+	replication_factor: Option<usize>,
+	source_version: &Version,
+	dest_version: &Version,
+) -> Result<(), Error> {
+	for batch in source_version
+		.blocks
+		.items()
+		.chunks(COPY_BLOCK_REF_BATCH_SIZE)
+	{
+		let mut dest_version_batch =
+			Version::new(dest_version.uuid, dest_version.backlink.clone(), false);
+		for (bk, bv) in batch.iter() {
+			dest_version_batch.blocks.put(*bk, *bv);
+		}
+		let dest_block_refs = batch
+			.iter()
+			.map(|(_, bv)| BlockRef {
+				block: bv.hash,
+				version: dest_version.uuid,
+				deleted: false.into(),
+				replication_factor,
+			})
+			.collect::<Vec<_>>();
+		futures::try_join!(
+			garage.version_table.insert(&dest_version_batch),
+			garage.block_ref_table.insert_many(&dest_block_refs[..]),
+		)?;
+	}
+	Ok(())
+}
+
 async fn handle_copy_reencrypt(
 	ctx: ReqCtx,
 	dest_key: &str,
@@ -314,13 +521,23 @@ async fn handle_copy_reencrypt(
 		source_encryption,
 	);
 
+	let source_size = match source_version_data {
+		ObjectVersionData::Inline(meta, _) | ObjectVersionData::FirstBlock(meta, _) => {
+			Some(meta.size)
+		}
+		ObjectVersionData::DeleteMarker => None,
+	};
+
 	save_stream(
 		&ctx,
 		dest_object_meta,
 		dest_encryption,
 		source_stream.map_err(|e| Error::from(GarageError::from(e))),
+		source_size,
 		&dest_key.to_string(),
 		checksum_mode,
+		None,
+		false,
 	)
 	.await
 }
@@ -339,15 +556,20 @@ pub async fn handle_upload_part_copy(
 	let dest_upload_id = multipart::decode_upload_id(upload_id)?;
 
 	let dest_key = dest_key.to_string();
-	let (source_object, (_, dest_version, mut dest_mpu)) = futures::try_join!(
+	let ((source_object, source_version_uuid), (_, dest_version, mut dest_mpu)) = futures::try_join!(
 		get_copy_source(&ctx, req),
 		multipart::get_upload(&ctx, &dest_key, &dest_upload_id)
 	)?;
 
-	let ReqCtx { garage, .. } = ctx;
+	let ReqCtx {
+		garage,
+		bucket_params,
+		..
+	} = ctx;
+	let replication_factor = *bucket_params.replication_factor.get();
 
 	let (source_object_version, source_version_data, source_version_meta) =
-		extract_source_info(&source_object)?;
+		extract_source_info(&source_object, source_version_uuid)?;
 
 	// Check precondition on source, e.g. x-amz-copy-source-if-match
 	copy_precondition.check(source_object_version, &source_version_meta.etag)?;
@@ -390,6 +612,8 @@ pub async fn handle_upload_part_copy(
 		},
 	};
 
+	check_size_limit(source_range.length, max_part_size(&garage.config.s3_api))?;
+
 	// Check source version is not inlined
 	if matches!(source_version_data, ObjectVersionData::Inline(_, _)) {
 		// This is only for small files, we don't bother handling this.
@@ -452,10 +676,12 @@ pub async fn handle_upload_part_copy(
 	let dest_version_id = gen_uuid();
 	let dest_mpu_part_key = MpuPartKey {
 		part_number,
-		timestamp: dest_mpu.next_timestamp(part_number),
+		timestamp: dest_mpu.next_timestamp(&*garage.clock, part_number),
 	};
 
 	// Create the uploaded part
+	// The part insert also records the activity on the upload
+	dest_mpu.touch();
 	dest_mpu.parts.clear();
 	dest_mpu.parts.put(
 		dest_mpu_part_key,
@@ -465,6 +691,7 @@ pub async fn handle_upload_part_copy(
 			etag: None,
 			checksum: None,
 			size: None,
+			sha256: None,
 		},
 	);
 	garage.mpu_table.insert(&dest_mpu).await?;
@@ -482,6 +709,10 @@ pub async fn handle_upload_part_copy(
 	// Now, actually copy the blocks
 	let mut checksummer = Checksummer::init(&Default::default(), !dest_encryption.is_encrypted())
 		.add(dest_object_checksum_algorithm);
+	let always_sha256 = garage.config.s3_api.always_compute_sha256;
+	if always_sha256 {
+		checksummer = checksummer.add(Some(ChecksumAlgorithm::Sha256));
+	}
 
 	// First, create a stream that is able to read the source blocks
 	// and extract the subrange if necessary.
@@ -533,7 +764,8 @@ pub async fn handle_upload_part_copy(
 	// When it is done, it returns an empty vec.
 	// Same as the previous iterator, the Option is Some(_) if and only if
 	// it's an existing block of the Garage data store that can be reused.
-	let mut defragmenter = Defragmenter::new(garage.config.block_size, Box::pin(source_blocks));
+	let block_size = bucket_params.effective_block_size(garage.config.block_size);
+	let mut defragmenter = Defragmenter::new(block_size, Box::pin(source_blocks));
 
 	let mut current_offset = 0;
 	let mut next_block = defragmenter.next().await?;
@@ -575,6 +807,7 @@ pub async fn handle_upload_part_copy(
 			VersionBlock {
 				hash: final_hash,
 				size: data_len,
+				checksum: None,
 			},
 		);
 		current_offset += data_len;
@@ -583,6 +816,7 @@ pub async fn handle_upload_part_copy(
 			block: final_hash,
 			version: dest_version_id,
 			deleted: false.into(),
+			replication_factor,
 		};
 
 		let (_, _, _, next) = futures::try_join!(
@@ -592,7 +826,13 @@ pub async fn handle_upload_part_copy(
 				if let Some(final_data) = data_to_upload {
 					garage
 						.block_manager
-						.rpc_put_block(final_hash, final_data, dest_encryption.is_encrypted(), None)
+						.rpc_put_block(
+							final_hash,
+							final_data,
+							dest_encryption.is_encrypted(),
+							None,
+							replication_factor,
+						)
 						.await
 				} else {
 					Ok(())
@@ -615,6 +855,7 @@ pub async fn handle_upload_part_copy(
 	let checksum = checksums.extract(dest_object_checksum_algorithm);
 
 	// Put the part's ETag in the Versiontable
+	dest_mpu.touch();
 	dest_mpu.parts.put(
 		dest_mpu_part_key,
 		MpuPart {
@@ -622,6 +863,7 @@ pub async fn handle_upload_part_copy(
 			etag: Some(etag.clone()),
 			checksum,
 			size: Some(current_offset),
+			sha256: checksums.sha256.map(Hash::from).filter(|_| always_sha256),
 		},
 	);
 	garage.mpu_table.insert(&dest_mpu).await?;
@@ -643,47 +885,249 @@ pub async fn handle_upload_part_copy(
 	Ok(resp.body(string_body(resp_xml))?)
 }
 
-async fn get_copy_source(ctx: &ReqCtx, req: &Request<ReqBody>) -> Result<Object, Error> {
+/// Source object of a CopyObject or UploadPartCopy request,
+/// as specified in the `x-amz-copy-source` header
+#[derive(Debug, PartialEq, Eq)]
+struct CopySource {
+	bucket: String,
+	key: String,
+	version_id: Option<String>,
+}
+
+const COPY_SOURCE_VERSION_ID: &str = "?versionId=";
+
+/// Parse the value of the `x-amz-copy-source` header, which has the form
+/// `[/]bucket/key[?versionId=version]` where bucket and key are percent-encoded.
+fn parse_copy_source(copy_source: &str) -> Result<CopySource, Error> {
+	// The version ID is given as a query parameter, which is not part of
+	// the percent-encoded path: a key that contains `?versionId=` as literal
+	// text has its `?` and `=` encoded and is not matched here.
+	let (path, version_id) = match copy_source.rfind(COPY_SOURCE_VERSION_ID) {
+		Some(i) => (
+			&copy_source[..i],
+			Some(&copy_source[i + COPY_SOURCE_VERSION_ID.len()..]),
+		),
+		None => (copy_source, None),
+	};
+
+	// Percent-decode the path. Contrary to query strings, `+` is not
+	// a space here and is kept as is.
+	let path = percent_encoding::percent_decode_str(path).decode_utf8()?;
+	let path = path.strip_prefix('/').unwrap_or(&path);
+
+	let (bucket, key) = path
+		.split_once('/')
+		.ok_or_bad_request("No source key specified in x-amz-copy-source")?;
+	if bucket.is_empty() {
+		return Err(Error::bad_request(
+			"No source bucket specified in x-amz-copy-source",
+		));
+	}
+	if key.is_empty() {
+		return Err(Error::bad_request(
+			"No source key specified in x-amz-copy-source",
+		));
+	}
+
+	let version_id = match version_id {
+		Some(v) => {
+			let v = percent_encoding::percent_decode_str(v).decode_utf8()?;
+			if v.is_empty() {
+				return Err(Error::bad_request("Empty versionId in x-amz-copy-source"));
+			}
+			Some(v.into_owned())
+		}
+		None => None,
+	};
+
+	Ok(CopySource {
+		bucket: bucket.to_string(),
+		key: key.to_string(),
+		version_id,
+	})
+}
+
+/// Decode the version ID given in `x-amz-copy-source`. Returns `None`
+/// if the version ID designates the current version of the object.
+fn decode_copy_source_version_id(version_id: Option<&str>) -> Result<Option<Uuid>, Error> {
+	match version_id {
+		// Objects that were not stored with versioning enabled
+		// have version ID "null" in AWS S3
+		None | Some("null") => Ok(None),
+		Some(v) => {
+			let bin = hex::decode(v)
+				.ok()
+				.filter(|b| b.len() == 32)
+				.ok_or_bad_request("Invalid versionId in x-amz-copy-source")?;
+			let mut uuid = [0u8; 32];
+			uuid.copy_from_slice(&bin[..]);
+			Ok(Some(Uuid::from(uuid)))
+		}
+	}
+}
+
+async fn get_copy_source(
+	ctx: &ReqCtx,
+	req: &Request<ReqBody>,
+) -> Result<(Object, Option<Uuid>), Error> {
 	let ReqCtx {
 		garage, api_key, ..
 	} = ctx;
 
 	let copy_source = req.headers().get("x-amz-copy-source").unwrap().to_str()?;
-	let copy_source = percent_encoding::percent_decode_str(copy_source).decode_utf8()?;
+	let copy_source = parse_copy_source(copy_source)?;
+	let version_uuid = decode_copy_source_version_id(copy_source.version_id.as_deref())?;
 
-	let (source_bucket, source_key) = parse_bucket_key(&copy_source, None)?;
 	let source_bucket_id = garage
 		.bucket_helper()
-		.resolve_bucket(&source_bucket.to_string(), api_key)
+		.resolve_bucket(&copy_source.bucket, api_key)
 		.await?;
 
 	if !api_key.allow_read(&source_bucket_id) {
 		return Err(Error::forbidden(format!(
 			"Reading from bucket {} not allowed for this key",
-			source_bucket
+			copy_source.bucket
 		)));
 	}
 
-	let source_key = source_key.ok_or_bad_request("No source key specified")?;
+	// In source buckets with case-insensitive keys, objects are stored
+	// under their lowercased key
+	let mut source_key = copy_source.key;
+	if source_key.chars().any(char::is_uppercase) {
+		let source_bucket = garage
+			.bucket_helper()
+			.get_existing_bucket(source_bucket_id)
+			.await?;
+		source_key = source_bucket.params().unwrap().index_key(&source_key);
+	}
 
 	let source_object = garage
 		.object_table
-		.get(&source_bucket_id, &source_key.to_string())
+		.get(&source_bucket_id, &source_key)
 		.await?
 		.ok_or(Error::NoSuchKey)?;
 
-	Ok(source_object)
+	Ok((source_object, version_uuid))
 }
 
-fn extract_source_info(
+/// Parse the Garage-specific `x-garage-move-source` header, which turns
+/// a CopyObject call into a move of the source object
+fn parse_move_source_header(req: &Request<ReqBody>) -> Result<bool, Error> {
+	match req.headers().get("x-garage-move-source") {
+		None => Ok(false),
+		Some(v) => match v.to_str()? {
+			"true" => Ok(true),
+			"false" => Ok(false),
+			_ => Err(Error::bad_request(
+				"Invalid value for x-garage-move-source, expected true or false",
+			)),
+		},
+	}
+}
+
+/// Value of the x-amz-metadata-directive and x-amz-tagging-directive headers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Directive {
+	Copy,
+	Replace,
+}
+
+fn parse_directive_header(req: &Request<ReqBody>, name: &str) -> Result<Directive, Error> {
+	match req.headers().get(name) {
+		None => Ok(Directive::Copy),
+		Some(v) => match v.to_str()? {
+			"COPY" => Ok(Directive::Copy),
+			"REPLACE" => Ok(Directive::Replace),
+			_ => Err(Error::bad_request(format!(
+				"Invalid value for {}, expected COPY or REPLACE",
+				name
+			))),
+		},
+	}
+}
+
+async fn check_move_source(
+	ctx: &ReqCtx,
 	source_object: &Object,
-) -> Result<(&ObjectVersion, &ObjectVersionData, &ObjectVersionMeta), Error> {
-	let source_version = source_object
+	source_version: &ObjectVersion,
+	dest_key: &str,
+	must_recopy: bool,
+) -> Result<(), Error> {
+	check_move_source_version(source_object, source_version)?;
+	if must_recopy {
+		return Err(Error::bad_request(
+			"Objects cannot be moved when their data has to be rewritten \
+			(change of encryption key or checksum algorithm)",
+		));
+	}
+	if source_object.bucket_id == ctx.bucket_id && source_object.key == dest_key {
+		return Err(Error::bad_request("Cannot move an object onto itself"));
+	}
+	if !ctx.api_key.allow_write(&source_object.bucket_id) {
+		return Err(Error::forbidden(
+			"Moving requires write access to the source bucket",
+		));
+	}
+
+	let source_bucket = ctx
+		.garage
+		.bucket_helper()
+		.get_existing_bucket(source_object.bucket_id)
+		.await?;
+	if *source_bucket.params().unwrap().read_only.get() {
+		return Err(Error::forbidden(
+			"Source bucket is read-only: objects cannot be moved out of it.",
+		));
+	}
+
+	Ok(())
+}
+
+/// Only the current version of an object can be moved, as the source is
+/// deleted by adding a delete marker after it
+fn check_move_source_version(
+	source_object: &Object,
+	source_version: &ObjectVersion,
+) -> Result<(), Error> {
+	let current_version = source_object
 		.versions()
 		.iter()
 		.rev()
-		.find(|v| v.is_complete())
-		.ok_or(Error::NoSuchKey)?;
+		.find(|v| v.is_complete());
+	if current_version.map(|v| v.uuid) != Some(source_version.uuid) {
+		return Err(Error::bad_request(
+			"Only the current version of an object can be moved",
+		));
+	}
+	Ok(())
+}
+
+fn extract_source_info(
+	source_object: &Object,
+	version_uuid: Option<Uuid>,
+) -> Result<(&ObjectVersion, &ObjectVersionData, &ObjectVersionMeta), Error> {
+	let source_version = match version_uuid {
+		None => source_object
+			.versions()
+			.iter()
+			.rev()
+			.find(|v| v.is_complete())
+			.ok_or(Error::NoSuchKey)?,
+		Some(uuid) => {
+			let v = source_object
+				.versions()
+				.iter()
+				.find(|v| v.uuid == uuid && v.is_complete())
+				.ok_or(Error::NoSuchVersion)?;
+			if v.is_data() {
+				v
+			} else {
+				return Err(Error::bad_request(
+					"The source of a copy request may not refer to a delete marker by version id",
+				));
+			}
+		}
+	};
 
 	let source_version_data = match &source_version.state {
 		ObjectVersionState::Complete(x) => x,
@@ -842,10 +1286,20 @@ impl<S: Stream<Item = BlockStreamItem>> Defragmenter<S> {
 
 #[derive(Debug, Serialize, PartialEq, Eq)]
 pub struct CopyObjectResult {
+	#[serde(serialize_with = "xmlns_tag")]
+	pub xmlns: (),
 	#[serde(rename = "LastModified")]
 	pub last_modified: s3_xml::Value,
 	#[serde(rename = "ETag")]
 	pub etag: s3_xml::Value,
+	#[serde(rename = "ChecksumCRC32")]
+	pub checksum_crc32: Option<s3_xml::Value>,
+	#[serde(rename = "ChecksumCRC32C")]
+	pub checksum_crc32c: Option<s3_xml::Value>,
+	#[serde(rename = "ChecksumSHA1")]
+	pub checksum_sha1: Option<s3_xml::Value>,
+	#[serde(rename = "ChecksumSHA256")]
+	pub checksum_sha256: Option<s3_xml::Value>,
 }
 
 #[derive(Debug, Serialize, PartialEq, Eq)]
@@ -861,18 +1315,49 @@ pub struct CopyPartResult {
 #[cfg(test)]
 mod tests {
 	use super::*;
-	use crate::s3::xml::to_xml_with_header;
+	use crate::s3::xml::{to_xml_with_header, XmlShape};
+
+	const COPY_OBJECT_SHAPE: XmlShape = XmlShape {
+		root: "CopyObjectResult",
+		xmlns: true,
+		children: &[(
+			"CopyObjectResult",
+			&[
+				"LastModified",
+				"ETag",
+				"ChecksumCRC32",
+				"ChecksumCRC32C",
+				"ChecksumSHA1",
+				"ChecksumSHA256",
+			],
+		)],
+		may_be_empty: &[],
+	};
+
+	const COPY_PART_SHAPE: XmlShape = XmlShape {
+		root: "CopyPartResult",
+		xmlns: true,
+		children: &[("CopyPartResult", &["LastModified", "ETag"])],
+		may_be_empty: &[],
+	};
 
 	#[test]
 	fn copy_object_result() -> Result<(), Error> {
 		let copy_result = CopyObjectResult {
+			xmlns: (),
 			last_modified: s3_xml::Value(msec_to_rfc3339(0)),
 			etag: s3_xml::Value("\"9b2cf535f27731c974343645a3985328\"".to_string()),
+			checksum_crc32: None,
+			checksum_crc32c: None,
+			checksum_sha1: None,
+			checksum_sha256: None,
 		};
+		let xml = to_xml_with_header(&copy_result)?;
+		COPY_OBJECT_SHAPE.check(&xml);
 		assert_eq!(
-			to_xml_with_header(&copy_result)?,
+			xml,
 			"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
-<CopyObjectResult>\
+<CopyObjectResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">\
     <LastModified>1970-01-01T00:00:00.000Z</LastModified>\
     <ETag>&quot;9b2cf535f27731c974343645a3985328&quot;</ETag>\
 </CopyObjectResult>\
@@ -894,8 +1379,139 @@ mod tests {
 			etag: s3_xml::Value("\"9b2cf535f27731c974343645a3985328\"".into()),
 		};
 
-		assert_eq!(to_xml_with_header(&v)?, expected_retval);
+		let xml = to_xml_with_header(&v)?;
+		COPY_PART_SHAPE.check(&xml);
+		assert_eq!(xml, expected_retval);
 
 		Ok(())
 	}
+
+	fn data_version(uuid: u8, timestamp: u64) -> ObjectVersion {
+		ObjectVersion {
+			uuid: Uuid::from([uuid; 32]),
+			timestamp,
+			state: ObjectVersionState::Complete(ObjectVersionData::Inline(
+				ObjectVersionMeta {
+					size: 5,
+					etag: "etag".to_string(),
+					expires_at: None,
+					encryption: ObjectVersionEncryption::Plaintext {
+						inner: ObjectVersionMetaInner {
+							headers: vec![],
+							checksum: None,
+							full_sha256: None,
+						},
+					},
+				},
+				b"hello".to_vec(),
+			)),
+		}
+	}
+
+	#[test]
+	fn test_move_source_version() {
+		let object = Object::new(
+			Uuid::from([0x01; 32]),
+			"a".to_string(),
+			vec![data_version(0x02, 1000), data_version(0x03, 2000)],
+		);
+		let (old, current) = (&object.versions()[0], &object.versions()[1]);
+
+		assert!(check_move_source_version(&object, current).is_ok());
+		assert!(check_move_source_version(&object, old).is_err());
+		// The version given in the request is the one that is moved
+		let (v, _, _) = extract_source_info(&object, Some(old.uuid)).unwrap();
+		assert_eq!(v.uuid, old.uuid);
+		assert!(check_move_source_version(&object, v).is_err());
+	}
+
+	#[test]
+	fn test_parse_copy_source() {
+		let vid = "a".repeat(64);
+		#[allow(clippy::type_complexity)]
+		let cases: Vec<(String, Option<(&str, &str, Option<&str>)>)> = vec![
+			("bucket/key".into(), Some(("bucket", "key", None))),
+			("/bucket/key".into(), Some(("bucket", "key", None))),
+			(
+				"bucket/a/b/c.txt".into(),
+				Some(("bucket", "a/b/c.txt", None)),
+			),
+			("bucket//leading".into(), Some(("bucket", "/leading", None))),
+			// `+` is not a space in paths
+			("bucket/a+b".into(), Some(("bucket", "a+b", None))),
+			("bucket/a%2Bb".into(), Some(("bucket", "a+b", None))),
+			("bucket/a%20b".into(), Some(("bucket", "a b", None))),
+			// Percent-encoded `?`, `/` and UTF-8
+			("bucket/what%3F".into(), Some(("bucket", "what?", None))),
+			("bucket/a%2Fb".into(), Some(("bucket", "a/b", None))),
+			("bucket/caf%C3%A9".into(), Some(("bucket", "café", None))),
+			// Unencoded `?` not followed by versionId is part of the key
+			("bucket/what?".into(), Some(("bucket", "what?", None))),
+			("bucket/a?b=c".into(), Some(("bucket", "a?b=c", None))),
+			// Version ID
+			(
+				format!("bucket/key?versionId={}", vid),
+				Some(("bucket", "key", Some(vid.as_str()))),
+			),
+			(
+				"/bucket/a%3Fb?versionId=null".into(),
+				Some(("bucket", "a?b", Some("null"))),
+			),
+			(
+				"bucket/a?b?versionId=null".into(),
+				Some(("bucket", "a?b", Some("null"))),
+			),
+			// Literal `?versionId=` text in a fully encoded key
+			(
+				"bucket/key%3FversionId%3Dnull".into(),
+				Some(("bucket", "key?versionId=null", None)),
+			),
+			(
+				"bucket/key%3FversionId%3D1?versionId=null".into(),
+				Some(("bucket", "key?versionId=1", Some("null"))),
+			),
+			// Invalid
+			("".into(), None),
+			("bucket".into(), None),
+			("bucket/".into(), None),
+			("/key".into(), None),
+			("bucket/key?versionId=".into(), None),
+			("bucket/%FF".into(), None),
+		];
+
+		for (input, expected) in cases {
+			let res = parse_copy_source(&input);
+			match expected {
+				Some((bucket, key, version_id)) => {
+					let res = res.unwrap_or_else(|e| panic!("{}: {}", input, e));
+					assert_eq!(
+						res,
+						CopySource {
+							bucket: bucket.into(),
+							key: key.into(),
+							version_id: version_id.map(String::from),
+						},
+						"{}",
+						input
+					);
+				}
+				None => assert!(res.is_err(), "{}", input),
+			}
+		}
+	}
+
+	#[test]
+	fn test_decode_copy_source_version_id() {
+		assert_eq!(decode_copy_source_version_id(None).unwrap(), None);
+		assert_eq!(decode_copy_source_version_id(Some("null")).unwrap(), None);
+
+		let uuid = gen_uuid();
+		assert_eq!(
+			decode_copy_source_version_id(Some(&hex::encode(uuid))).unwrap(),
+			Some(uuid)
+		);
+
+		assert!(decode_copy_source_version_id(Some("abcd")).is_err());
+		assert!(decode_copy_source_version_id(Some(&"z".repeat(64))).is_err());
+	}
 }
@@ -2,8 +2,9 @@ use quick_xml::de::from_reader;
 use std::sync::Arc;
 
 use http::header::{
-	ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
-	ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD,
+	HeaderMap, HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+	ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE,
+	ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, VARY,
 };
 use hyper::{
 	body::Body, body::Incoming as IncomingBody, header::HeaderName, Method, Request, Response,
@@ -41,9 +42,7 @@ pub async fn handle_get_cors(ctx: ReqCtx) -> Result<Response<ResBody>, Error> {
 			.header(http::header::CONTENT_TYPE, "application/xml")
 			.body(string_body(xml))?)
 	} else {
-		Ok(Response::builder()
-			.status(StatusCode::NO_CONTENT)
-			.body(empty_body())?)
+		Err(Error::NoSuchCORSConfiguration)
 	}
 }
 
@@ -65,6 +64,10 @@ pub async fn handle_delete_cors(ctx: ReqCtx) -> Result<Response<ResBody>, Error>
 		.body(empty_body())?)
 }
 
+/// Maximum number of rules in a CORS configuration if
+/// `s3_api.max_cors_rules` is not set (same as AWS)
+const DEFAULT_MAX_CORS_RULES: usize = 100;
+
 pub async fn handle_put_cors(
 	ctx: ReqCtx,
 	req: Request<ReqBody>,
@@ -84,6 +87,14 @@ pub async fn handle_put_cors(
 	}
 
 	let conf: CorsConfiguration = from_reader(&body as &[u8])?;
+	let max_rules = garage
+		.config
+		.s3_api
+		.max_cors_rules
+		.unwrap_or(DEFAULT_MAX_CORS_RULES);
+	if conf.cors_rules.len() > max_rules {
+		return Err(Error::TooManyConfigurations("CORS", max_rules));
+	}
 	conf.validate()?;
 
 	bucket_params
@@ -133,6 +144,7 @@ pub async fn handle_options_api(
 			Ok(Response::builder()
 				.header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
 				.header(ACCESS_CONTROL_ALLOW_METHODS, "*")
+				.header(VARY, PREFLIGHT_VARY)
 				.status(StatusCode::OK)
 				.body(EmptyBody::new())?)
 		}
@@ -143,6 +155,7 @@ pub async fn handle_options_api(
 		Ok(Response::builder()
 			.header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
 			.header(ACCESS_CONTROL_ALLOW_METHODS, "GET")
+			.header(VARY, PREFLIGHT_VARY)
 			.status(StatusCode::OK)
 			.body(EmptyBody::new())?)
 	}
@@ -175,7 +188,8 @@ pub fn handle_options_for_bucket(
 			let mut resp = Response::builder()
 				.status(StatusCode::OK)
 				.body(EmptyBody::new())?;
-			add_cors_headers(&mut resp, rule).ok_or_internal_error("Invalid CORS configuration")?;
+			add_cors_preflight_headers(&mut resp, rule, origin)
+				.ok_or_internal_error("Invalid CORS configuration")?;
 			return Ok(resp);
 		}
 	}
@@ -185,10 +199,12 @@ pub fn handle_options_for_bucket(
 	))
 }
 
-pub fn find_matching_cors_rule<'a>(
+/// Find the CORS rule that applies to a request, if any. The rule is returned
+/// along with the value of the request's Origin header that it matched.
+pub fn find_matching_cors_rule<'a, 'b>(
 	bucket_params: &'a BucketParams,
-	req: &Request<impl Body>,
-) -> Result<Option<&'a GarageCorsRule>, Error> {
+	req: &'b Request<impl Body>,
+) -> Result<Option<(&'a GarageCorsRule, &'b str)>, Error> {
 	if let Some(cors_config) = bucket_params.cors_config.get() {
 		if let Some(origin) = req.headers().get("Origin") {
 			let origin = origin.to_str()?;
@@ -196,9 +212,12 @@ pub fn find_matching_cors_rule<'a>(
 				Some(h) => h.to_str()?.split(',').map(|h| h.trim()).collect::<Vec<_>>(),
 				None => vec![],
 			};
-			return Ok(cors_config.iter().find(|rule| {
-				cors_rule_matches(rule, origin, req.method().as_ref(), request_headers.iter())
-			}));
+			return Ok(cors_config
+				.iter()
+				.find(|rule| {
+					cors_rule_matches(rule, origin, req.method().as_ref(), request_headers.iter())
+				})
+				.map(|rule| (rule, origin)));
 		}
 	}
 	Ok(None)
@@ -223,15 +242,30 @@ where
 		})
 }
 
+// Headers of the request that the response to a preflight request depends on.
+// These must be listed in Vary so that caches (e.g. CDNs) don't serve a response
+// computed for one origin to a request coming from another origin.
+const PREFLIGHT_VARY: &str =
+	"Origin, Access-Control-Request-Method, Access-Control-Request-Headers";
+
+/// Add the CORS headers corresponding to a matching rule to the response
+/// of a request coming from `origin`
 pub fn add_cors_headers(
 	resp: &mut Response<impl Body>,
 	rule: &GarageCorsRule,
+	origin: &str,
 ) -> Result<(), http::header::InvalidHeaderValue> {
 	let h = resp.headers_mut();
-	h.insert(
-		ACCESS_CONTROL_ALLOW_ORIGIN,
-		rule.allow_origins.join(", ").parse()?,
-	);
+	// A response can only allow a single origin, so if the rule
+	// is not a wildcard rule, we return the origin of the request
+	// (which is one of the allowed origins as the rule matched).
+	let allow_origin = if rule.allow_origins.iter().any(|x| x == "*") {
+		"*"
+	} else {
+		origin
+	};
+	h.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin.parse()?);
+	add_vary(h, "Origin")?;
 	h.insert(
 		ACCESS_CONTROL_ALLOW_METHODS,
 		rule.allow_methods.join(", ").parse()?,
@@ -247,6 +281,40 @@ pub fn add_cors_headers(
 	Ok(())
 }
 
+/// Add the CORS headers corresponding to a matching rule to the response
+/// of a preflight (OPTIONS) request coming from `origin`
+pub fn add_cors_preflight_headers(
+	resp: &mut Response<impl Body>,
+	rule: &GarageCorsRule,
+	origin: &str,
+) -> Result<(), http::header::InvalidHeaderValue> {
+	add_cors_headers(resp, rule, origin)?;
+	let h = resp.headers_mut();
+	add_vary(h, "Access-Control-Request-Method")?;
+	add_vary(h, "Access-Control-Request-Headers")?;
+	if let Some(max_age) = rule.max_age_seconds {
+		h.insert(ACCESS_CONTROL_MAX_AGE, max_age.to_string().parse()?);
+	}
+	Ok(())
+}
+
+/// Add a header to the list of headers of the request that the response
+/// depends on, in the Vary header, unless it is already present
+pub fn add_vary(
+	h: &mut HeaderMap<HeaderValue>,
+	header: &str,
+) -> Result<(), http::header::InvalidHeaderValue> {
+	let already_present = h.get_all(VARY).iter().any(|v| {
+		v.to_str()
+			.map(|v| v.split(',').any(|x| x.trim().eq_ignore_ascii_case(header)))
+			.unwrap_or(false)
+	});
+	if !already_present {
+		h.append(VARY, header.parse()?);
+	}
+	Ok(())
+}
+
 // ---- SERIALIZATION AND DESERIALIZATION TO/FROM S3 XML ----
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
@@ -436,4 +504,122 @@ mod tests {
 
 		Ok(())
 	}
+
+	#[test]
+	fn test_stored_rules_roundtrip() -> Result<(), Error> {
+		let message = r#"<?xml version="1.0" encoding="UTF-8"?>
+<CORSConfiguration xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+ <CORSRule>
+   <ID>rule-1</ID>
+   <MaxAgeSeconds>600</MaxAgeSeconds>
+   <AllowedOrigin>https://a.example.com</AllowedOrigin>
+   <AllowedOrigin>https://b.example.com</AllowedOrigin>
+   <AllowedMethod>PUT</AllowedMethod>
+   <AllowedMethod>GET</AllowedMethod>
+   <AllowedHeader>x-amz-meta-*</AllowedHeader>
+   <AllowedHeader>Content-Type</AllowedHeader>
+   <ExposeHeader>ETag</ExposeHeader>
+ </CORSRule>
+ <CORSRule>
+   <AllowedOrigin>*</AllowedOrigin>
+   <AllowedMethod>GET</AllowedMethod>
+   <AllowedMethod>HEAD</AllowedMethod>
+ </CORSRule>
+</CORSConfiguration>"#;
+		let conf: CorsConfiguration = from_str(message).unwrap();
+		conf.validate()?;
+
+		// Convert to the stored representation and back, as done by
+		// PutBucketCors then GetBucketCors
+		let stored = conf
+			.cors_rules
+			.iter()
+			.map(CorsRule::to_garage_cors_rule)
+			.collect::<Vec<_>>();
+		let get_response = |rules: &[GarageCorsRule]| {
+			to_xml_with_header(&CorsConfiguration {
+				xmlns: (),
+				cors_rules: rules.iter().map(CorsRule::from_garage_cors_rule).collect(),
+			})
+		};
+		let message2 = get_response(&stored)?;
+
+		let cleanup = |c: &str| c.replace(char::is_whitespace, "");
+		assert_eq!(cleanup(message), cleanup(&message2));
+
+		// A second PUT/GET cycle gives exactly the same output
+		let conf2: CorsConfiguration = from_str(&message2).unwrap();
+		assert_eq!(conf, conf2);
+		let stored2 = conf2
+			.cors_rules
+			.iter()
+			.map(CorsRule::to_garage_cors_rule)
+			.collect::<Vec<_>>();
+		assert_eq!(stored, stored2);
+		assert_eq!(message2, get_response(&stored2)?);
+
+		Ok(())
+	}
+
+	fn test_rule(origins: &[&str], max_age_seconds: Option<u64>) -> GarageCorsRule {
+		GarageCorsRule {
+			id: None,
+			max_age_seconds,
+			allow_origins: origins.iter().map(|x| x.to_string()).collect(),
+			allow_methods: vec!["GET".into()],
+			allow_headers: vec![],
+			expose_headers: vec![],
+		}
+	}
+
+	fn vary_values(resp: &Response<EmptyBody>) -> Vec<String> {
+		resp.headers()
+			.get_all(VARY)
+			.iter()
+			.flat_map(|v| v.to_str().unwrap().split(',').map(|x| x.trim().to_string()))
+			.collect()
+	}
+
+	#[test]
+	fn test_cors_headers_vary_origin() {
+		let rule = test_rule(&["https://a.example.com", "https://b.example.com"], None);
+		let mut resp = Response::new(EmptyBody::new());
+		add_cors_headers(&mut resp, &rule, "https://b.example.com").unwrap();
+		assert_eq!(
+			resp.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(),
+			"https://b.example.com"
+		);
+		assert_eq!(vary_values(&resp), vec!["Origin"]);
+
+		let rule = test_rule(&["*"], None);
+		let mut resp = Response::new(EmptyBody::new());
+		resp.headers_mut()
+			.insert(VARY, "Accept-Encoding".parse().unwrap());
+		add_cors_headers(&mut resp, &rule, "https://a.example.com").unwrap();
+		assert_eq!(
+			resp.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(),
+			"*"
+		);
+		assert_eq!(vary_values(&resp), vec!["Accept-Encoding", "Origin"]);
+	}
+
+	#[test]
+	fn test_cors_preflight_headers() {
+		let rule = test_rule(&["https://a.example.com"], Some(3600));
+		let mut resp = Response::new(EmptyBody::new());
+		add_cors_preflight_headers(&mut resp, &rule, "https://a.example.com").unwrap();
+		assert_eq!(
+			resp.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(),
+			"https://a.example.com"
+		);
+		assert_eq!(resp.headers().get(ACCESS_CONTROL_MAX_AGE).unwrap(), "3600");
+		assert_eq!(
+			vary_values(&resp),
+			vec![
+				"Origin",
+				"Access-Control-Request-Method",
+				"Access-Control-Request-Headers"
+			]
+		);
+	}
 }
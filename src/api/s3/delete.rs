@@ -3,12 +3,12 @@ use hyper::{Request, Response, StatusCode};
 
 use garage_util::data::*;
 
+use garage_model::garage::Garage;
 use garage_model::s3::object_table::*;
 
 use crate::helpers::*;
 use crate::s3::api_server::{ReqBody, ResBody};
 use crate::s3::error::*;
-use crate::s3::put::next_timestamp;
 use crate::s3::xml as s3_xml;
 use crate::signature::verify_signed_content;
 
@@ -22,7 +22,7 @@ async fn handle_delete_internal(ctx: &ReqCtx, key: &str) -> Result<(Uuid, Uuid),
 		.await?
 		.ok_or(Error::NoSuchKey)?; // No need to delete
 
-	let del_timestamp = next_timestamp(Some(&object));
+	let del_timestamp = next_timestamp(&*garage.clock, Some(&object));
 	let del_uuid = gen_uuid();
 
 	let deleted_version = object
@@ -54,6 +54,45 @@ async fn handle_delete_internal(ctx: &ReqCtx, key: &str) -> Result<(Uuid, Uuid),
 	Ok((deleted_version, del_uuid))
 }
 
+/// Insert a delete marker for an object, only if its current version is still
+/// `version_uuid`. This is used to remove the source of a move once its
+/// destination has been written. Returns the UUID of the delete marker, or
+/// `None` if the object was modified concurrently and has been left untouched.
+pub(crate) async fn delete_if_current_version(
+	garage: &Garage,
+	bucket_id: Uuid,
+	key: &str,
+	version_uuid: Uuid,
+) -> Result<Option<Uuid>, Error> {
+	let object = match garage
+		.object_table
+		.get(&bucket_id, &key.to_string())
+		.await?
+	{
+		Some(o) => o,
+		None => return Ok(None),
+	};
+
+	let current_version = object.versions().iter().rev().find(|v| v.is_complete());
+	if current_version.map(|v| v.uuid) != Some(version_uuid) {
+		return Ok(None);
+	}
+
+	let del_uuid = gen_uuid();
+	let object = Object::new(
+		bucket_id,
+		key.into(),
+		vec![ObjectVersion {
+			uuid: del_uuid,
+			timestamp: next_timestamp(&*garage.clock, Some(&object)),
+			state: ObjectVersionState::Complete(ObjectVersionData::DeleteMarker),
+		}],
+	);
+	garage.object_table.insert(&object).await?;
+
+	Ok(Some(del_uuid))
+}
+
 pub async fn handle_delete(ctx: ReqCtx, key: &str) -> Result<Response<ResBody>, Error> {
 	match handle_delete_internal(&ctx, key).await {
 		Ok(_) | Err(Error::NoSuchKey) => Ok(Response::builder()
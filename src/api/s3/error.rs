@@ -31,13 +31,22 @@ pub enum Error {
 	#[error(display = "Upload not found")]
 	NoSuchUpload,
 
+	/// The object version requested don't exists
+	#[error(display = "Version not found")]
+	NoSuchVersion,
+
+	/// The bucket has no CORS configuration
+	#[error(display = "The CORS configuration does not exist")]
+	NoSuchCORSConfiguration,
+
 	/// Precondition failed (e.g. x-amz-copy-source-if-match)
 	#[error(display = "At least one of the preconditions you specified did not hold")]
 	PreconditionFailed,
 
-	/// Parts specified in CMU request do not match parts actually uploaded
-	#[error(display = "Parts given to CompleteMultipartUpload do not match uploaded parts")]
-	InvalidPart,
+	/// A part specified in CMU request does not match a part actually uploaded,
+	/// or the part number given in GetObject does not exist
+	#[error(display = "Invalid part: {}", _0)]
+	InvalidPart(String),
 
 	/// Parts given to CompleteMultipartUpload were not in ascending order
 	#[error(display = "Parts given to CompleteMultipartUpload were not in ascending order")]
@@ -48,6 +57,20 @@ pub enum Error {
 	#[error(display = "Proposed upload is smaller than the minimum allowed object size")]
 	EntityTooSmall,
 
+	/// The object or part is larger than the maximum size allowed by the configuration
+	#[error(
+		display = "Proposed upload exceeds the maximum allowed size of {} bytes",
+		_0
+	)]
+	EntityTooLarge(u64),
+
+	/// The user metadata of the object exceeds the maximum size
+	#[error(
+		display = "Your metadata headers exceed the maximum allowed metadata size of {} bytes",
+		_0
+	)]
+	MetadataTooLarge(usize),
+
 	// Category: bad request
 	/// The request contained an invalid UTF-8 sequence in its path or in other parameters
 	#[error(display = "Invalid UTF-8: {}", _0)]
@@ -61,6 +84,11 @@ pub enum Error {
 	#[error(display = "Invalid XML: {}", _0)]
 	InvalidXml(String),
 
+	/// A value of the XML document sent by the client is invalid,
+	/// although the document is well-formed
+	#[error(display = "Invalid argument: {}", _0)]
+	InvalidArgument(String),
+
 	/// The client sent a range header with invalid value
 	#[error(display = "Invalid HTTP range: {:?}", _0)]
 	InvalidRange(#[error(from)] (http_range::HttpRangeParseError, u64)),
@@ -74,6 +102,23 @@ pub enum Error {
 	InvalidDigest(String),
 
 	/// The client sent a request for an action not supported by garage
+	/// The method of the request is not supported on the resource it targets,
+	/// the methods that are supported are given
+	#[error(display = "The specified method is not allowed against this resource")]
+	MethodNotAllowed(String),
+
+	/// The bucket must be addressed using the given endpoint
+	#[error(
+		display = "The bucket you are attempting to access must be addressed using the specified endpoint: {}",
+		_0
+	)]
+	PermanentRedirect(String),
+
+	/// A bucket configuration document has more rules than allowed
+	/// by the configuration
+	#[error(display = "Too many {} rules, the maximum is {}", _0, _1)]
+	TooManyConfigurations(&'static str, usize),
+
 	#[error(display = "Unimplemented action: {}", _0)]
 	NotImplemented(String),
 }
@@ -125,13 +170,21 @@ impl Error {
 			Error::Common(c) => c.aws_code(),
 			Error::NoSuchKey => "NoSuchKey",
 			Error::NoSuchUpload => "NoSuchUpload",
+			Error::NoSuchVersion => "NoSuchVersion",
+			Error::NoSuchCORSConfiguration => "NoSuchCORSConfiguration",
 			Error::PreconditionFailed => "PreconditionFailed",
-			Error::InvalidPart => "InvalidPart",
+			Error::InvalidPart(_) => "InvalidPart",
 			Error::InvalidPartOrder => "InvalidPartOrder",
 			Error::EntityTooSmall => "EntityTooSmall",
+			Error::EntityTooLarge(_) => "EntityTooLarge",
+			Error::MetadataTooLarge(_) => "MetadataTooLarge",
 			Error::AuthorizationHeaderMalformed(_) => "AuthorizationHeaderMalformed",
+			Error::MethodNotAllowed(_) => "MethodNotAllowed",
+			Error::PermanentRedirect(_) => "PermanentRedirect",
+			Error::TooManyConfigurations(..) => "TooManyConfigurations",
 			Error::NotImplemented(_) => "NotImplemented",
 			Error::InvalidXml(_) => "MalformedXML",
+			Error::InvalidArgument(_) => "InvalidArgument",
 			Error::InvalidRange(_) => "InvalidRange",
 			Error::InvalidDigest(_) => "InvalidDigest",
 			Error::InvalidUtf8Str(_) | Error::InvalidUtf8String(_) => "InvalidRequest",
@@ -145,19 +198,28 @@ impl ApiError for Error {
 	fn http_status_code(&self) -> StatusCode {
 		match self {
 			Error::Common(c) => c.http_status_code(),
-			Error::NoSuchKey | Error::NoSuchUpload => StatusCode::NOT_FOUND,
+			Error::NoSuchKey
+			| Error::NoSuchUpload
+			| Error::NoSuchVersion
+			| Error::NoSuchCORSConfiguration => StatusCode::NOT_FOUND,
 			Error::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
 			Error::InvalidRange(_) => StatusCode::RANGE_NOT_SATISFIABLE,
+			Error::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+			Error::PermanentRedirect(_) => StatusCode::MOVED_PERMANENTLY,
 			Error::NotImplemented(_) => StatusCode::NOT_IMPLEMENTED,
 			Error::AuthorizationHeaderMalformed(_)
-			| Error::InvalidPart
+			| Error::InvalidPart(_)
 			| Error::InvalidPartOrder
 			| Error::EntityTooSmall
+			| Error::EntityTooLarge(_)
+			| Error::MetadataTooLarge(_)
 			| Error::InvalidDigest(_)
 			| Error::InvalidEncryptionAlgorithm(_)
 			| Error::InvalidXml(_)
+			| Error::InvalidArgument(_)
 			| Error::InvalidUtf8Str(_)
-			| Error::InvalidUtf8String(_) => StatusCode::BAD_REQUEST,
+			| Error::InvalidUtf8String(_)
+			| Error::TooManyConfigurations(..) => StatusCode::BAD_REQUEST,
 		}
 	}
 
@@ -166,7 +228,6 @@ impl ApiError for Error {
 
 		header_map.append(header::CONTENT_TYPE, "application/xml".parse().unwrap());
 
-		#[allow(clippy::single_match)]
 		match self {
 			Error::InvalidRange((_, len)) => {
 				header_map.append(
@@ -176,6 +237,11 @@ impl ApiError for Error {
 						.expect("header value only contain ascii"),
 				);
 			}
+			Error::MethodNotAllowed(allow) => {
+				if let Ok(allow) = HeaderValue::from_str(allow) {
+					header_map.append(header::ALLOW, allow);
+				}
+			}
 			_ => (),
 		}
 	}
@@ -186,6 +252,10 @@ impl ApiError for Error {
 			message: s3_xml::Value(format!("{}", self)),
 			resource: Some(s3_xml::Value(path.to_string())),
 			region: Some(s3_xml::Value(garage_region.to_string())),
+			endpoint: match self {
+				Error::PermanentRedirect(endpoint) => Some(s3_xml::Value(endpoint.clone())),
+				_ => None,
+			},
 		};
 		let error_str = s3_xml::to_xml_with_header(&error).unwrap_or_else(|_| {
 			r#"
@@ -8,30 +8,45 @@ use bytes::Bytes;
 use futures::future;
 use futures::stream::{self, Stream, StreamExt};
 use http::header::{
-	ACCEPT_RANGES, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_ENCODING, CONTENT_LANGUAGE,
-	CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, EXPIRES, IF_MODIFIED_SINCE, IF_NONE_MATCH,
-	LAST_MODIFIED, RANGE,
+	CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_ENCODING, CONTENT_LANGUAGE, CONTENT_LENGTH,
+	CONTENT_RANGE, CONTENT_TYPE, EXPIRES, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LOCATION,
+	RANGE,
 };
 use hyper::{body::Body, Request, Response, StatusCode};
 use tokio::sync::mpsc;
 
-use garage_net::stream::ByteStream;
+use garage_net::stream::{read_stream_to_end, ByteStream};
 use garage_rpc::rpc_helper::OrderTag;
-use garage_table::EmptyKey;
+use garage_table::{EmptyKey, EnumerationOrder};
+use garage_util::config::DirectoryMarkers;
 use garage_util::data::*;
-use garage_util::error::OkOrMessage;
+use garage_util::error::{Error as GarageError, OkOrMessage};
+use garage_util::metrics::{current_request_timing, with_request_timing};
+use garage_util::time::now_msec;
 
 use garage_model::garage::Garage;
 use garage_model::s3::object_table::*;
 use garage_model::s3::version_table::*;
 
+use crate::encoding::uri_encode;
 use crate::helpers::*;
 use crate::s3::api_server::ResBody;
-use crate::s3::checksum::{add_checksum_response_headers, X_AMZ_CHECKSUM_MODE};
+use crate::s3::checksum::{add_checksum_response_headers, block_checksum, X_AMZ_CHECKSUM_MODE};
 use crate::s3::encryption::EncryptionParams;
 use crate::s3::error::*;
+use crate::s3::headers::{
+	add_content_headers, add_expiration_headers, add_version_headers, relative_redirect_location,
+	X_GARAGE_REDIRECT_CODE, X_GARAGE_REDIRECT_KEY,
+};
 
 const X_AMZ_MP_PARTS_COUNT: &str = "x-amz-mp-parts-count";
+/// Maximum age in seconds of the object version returned by a read from a
+/// single node, above which the object is read again from a quorum of nodes
+pub const X_GARAGE_MAX_STALENESS: &str = "x-garage-max-staleness";
+/// Set to `verified` on range reads when all the returned data blocks have a
+/// stored checksum: each block is checked against its checksum before being
+/// sent, and the response is interrupted if one of them doesn't match
+pub const X_GARAGE_BLOCK_CHECKSUMS: &str = "x-garage-block-checksums";
 
 #[derive(Default)]
 pub struct GetObjectOverrides {
@@ -43,6 +58,29 @@ pub struct GetObjectOverrides {
 	pub(crate) response_expires: Option<String>,
 }
 
+impl GetObjectOverrides {
+	/// Parse the overrides that are allowed on unauthenticated requests, i.e.
+	/// on the website endpoint of public buckets. Changing the content type or
+	/// encoding is not allowed on such requests, as that would let anyone
+	/// change how browsers interpret stored objects (e.g. render them as HTML).
+	pub fn from_public_query(query: Option<&str>) -> Self {
+		let mut ret = Self::default();
+		for (k, v) in form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+			let field = match k.as_ref() {
+				"response-cache-control" => &mut ret.response_cache_control,
+				"response-content-disposition" => &mut ret.response_content_disposition,
+				"response-content-language" => &mut ret.response_content_language,
+				"response-expires" => &mut ret.response_expires,
+				_ => continue,
+			};
+			if !v.is_empty() {
+				*field = Some(v.into_owned());
+			}
+		}
+		ret
+	}
+}
+
 fn object_headers(
 	version: &ObjectVersion,
 	version_meta: &ObjectVersionMeta,
@@ -52,16 +90,9 @@ fn object_headers(
 ) -> http::response::Builder {
 	debug!("Version meta: {:?}", version_meta);
 
-	let date = UNIX_EPOCH + Duration::from_millis(version.timestamp);
-	let date_str = httpdate::fmt_http_date(date);
-
-	let mut resp = Response::builder()
-		.header(LAST_MODIFIED, date_str)
-		.header(ACCEPT_RANGES, "bytes".to_string());
-
-	if !version_meta.etag.is_empty() {
-		resp = resp.header(ETAG, format!("\"{}\"", version_meta.etag));
-	}
+	let resp = add_content_headers(Response::builder(), version.timestamp);
+	let resp = add_version_headers(resp, version.uuid, Some(&version_meta.etag));
+	let mut resp = add_expiration_headers(resp, version_meta.expires_at);
 
 	// When metadata is retrieved through the REST API, Amazon S3 combines headers that
 	// have the same name (ignoring case) into a comma-delimited list.
@@ -84,6 +115,17 @@ fn object_headers(
 
 	if checksum_mode.enabled {
 		resp = add_checksum_response_headers(&meta_inner.checksum, resp);
+		// The SHA256 checksum computed by Garage is returned in addition to
+		// the checksum requested by the client, unless it was also SHA256
+		// (for multipart uploads, the client's checksum is a checksum of
+		// the checksums of the parts, which is kept for compatibility)
+		if let Some(sha256) = meta_inner.full_sha256 {
+			if !matches!(meta_inner.checksum, Some(ChecksumValue::Sha256(_))) {
+				let mut bytes = [0u8; 32];
+				bytes.copy_from_slice(sha256.as_slice());
+				resp = add_checksum_response_headers(&Some(ChecksumValue::Sha256(bytes)), resp);
+			}
+		}
 	}
 
 	encryption.add_response_headers(&mut resp);
@@ -98,8 +140,9 @@ fn getobject_override_headers(
 	overrides: GetObjectOverrides,
 	resp: &mut http::response::Builder,
 ) -> Result<(), Error> {
-	// TODO: this only applies for signed requests, so when we support
-	// anonymous access in the future we will have to do a permission check here
+	// On signed requests, all overrides are allowed as they are part of the
+	// signed query string. For public access through the website endpoint,
+	// only a restricted set is parsed, see `GetObjectOverrides::from_public_query`.
 	let overrides = [
 		(CACHE_CONTROL, overrides.response_cache_control),
 		(CONTENT_DISPOSITION, overrides.response_content_disposition),
@@ -117,6 +160,65 @@ fn getobject_override_headers(
 	Ok(())
 }
 
+/// Response to GetObject and HeadObject requests on a redirect object, i.e. an
+/// object written with the `x-garage-redirect-key` header, or None if the
+/// object is not one. The body of redirect objects is never returned.
+fn try_answer_redirect(
+	version: &ObjectVersion,
+	version_meta: &ObjectVersionMeta,
+	key: &str,
+) -> Option<Response<ResBody>> {
+	// Redirect objects cannot be encrypted
+	let meta_inner = match &version_meta.encryption {
+		ObjectVersionEncryption::Plaintext { inner } => inner,
+		ObjectVersionEncryption::SseC { .. } => return None,
+	};
+	let header = |name: &str| {
+		meta_inner
+			.headers
+			.iter()
+			.find(|(k, _)| k == name)
+			.map(|(_, v)| v.as_str())
+	};
+	let target = header(X_GARAGE_REDIRECT_KEY)?;
+	let status = header(X_GARAGE_REDIRECT_CODE)
+		.and_then(|c| StatusCode::from_bytes(c.as_bytes()).ok())
+		.unwrap_or(StatusCode::MOVED_PERMANENTLY);
+
+	object_headers(
+		version,
+		version_meta,
+		meta_inner,
+		EncryptionParams::Plaintext,
+		ChecksumMode { enabled: false },
+	)
+	.header(LOCATION, relative_redirect_location(key, target))
+	.header(CONTENT_LENGTH, "0")
+	.status(status)
+	.body(empty_body())
+	.ok()
+}
+
+/// On the website endpoint, where objects are served at the root of the
+/// domain, the redirects of redirect objects are given as absolute paths
+pub fn make_redirect_location_absolute<B>(resp: &mut Response<B>) {
+	let target = match resp.headers().get(X_GARAGE_REDIRECT_KEY) {
+		Some(target) if resp.status().is_redirection() => target,
+		_ => return,
+	};
+	if let Ok(target) = std::str::from_utf8(target.as_bytes()) {
+		// With a leading slash, the location would be a protocol-relative
+		// URL redirecting to another host: the relative location is kept
+		if target.starts_with('/') {
+			return;
+		}
+		let location = format!("/{}", uri_encode(target, false));
+		if let Ok(location) = location.try_into() {
+			resp.headers_mut().insert(LOCATION, location);
+		}
+	}
+}
+
 fn try_answer_cached(
 	version: &ObjectVersion,
 	version_meta: &ObjectVersionMeta,
@@ -163,7 +265,14 @@ pub async fn handle_head(
 	key: &str,
 	part_number: Option<u64>,
 ) -> Result<Response<ResBody>, Error> {
-	handle_head_without_ctx(ctx.garage, req, ctx.bucket_id, key, part_number).await
+	let res =
+		handle_head_without_ctx(ctx.garage.clone(), req, ctx.bucket_id, key, part_number).await;
+	if matches!(res, Err(Error::NoSuchKey))
+		&& is_implicit_directory(&ctx.garage, ctx.bucket_id, key).await?
+	{
+		return implicit_directory_response();
+	}
+	res
 }
 
 /// Handle HEAD request for website
@@ -174,17 +283,18 @@ pub async fn handle_head_without_ctx(
 	key: &str,
 	part_number: Option<u64>,
 ) -> Result<Response<ResBody>, Error> {
-	let object = garage
-		.object_table
-		.get(&bucket_id, &key.to_string())
+	let object = get_object_within_staleness(&garage, req, bucket_id, key)
 		.await?
 		.ok_or(Error::NoSuchKey)?;
 
+	// Versions that are still being uploaded (e.g. an in-progress multipart
+	// upload) are ignored, and if the last complete version is a delete
+	// marker the object doesn't exist, even if older versions are still there.
 	let object_version = object
 		.versions()
 		.iter()
 		.rev()
-		.find(|v| v.is_data())
+		.find(|v| v.is_complete())
 		.ok_or(Error::NoSuchKey)?;
 
 	let version_data = match &object_version.state {
@@ -193,11 +303,19 @@ pub async fn handle_head_without_ctx(
 	};
 
 	let version_meta = match version_data {
+		ObjectVersionData::DeleteMarker => return Err(Error::NoSuchKey),
 		ObjectVersionData::Inline(meta, _) => meta,
 		ObjectVersionData::FirstBlock(meta, _) => meta,
-		_ => unreachable!(),
 	};
 
+	if version_meta.is_expired() {
+		return Err(Error::NoSuchKey);
+	}
+
+	if let Some(redirect) = try_answer_redirect(object_version, version_meta, key) {
+		return Ok(redirect);
+	}
+
 	if let Some(cached) = try_answer_cached(object_version, version_meta, req) {
 		return Ok(cached);
 	}
@@ -211,7 +329,7 @@ pub async fn handle_head_without_ctx(
 		match version_data {
 			ObjectVersionData::Inline(_, _) => {
 				if pn != 1 {
-					return Err(Error::InvalidPart);
+					return Err(Error::InvalidPart(format!("part {} does not exist", pn)));
 				}
 				let bytes_len = version_meta.size;
 				Ok(object_headers(
@@ -237,8 +355,8 @@ pub async fn handle_head_without_ctx(
 					.await?
 					.ok_or(Error::NoSuchKey)?;
 
-				let (part_offset, part_end) =
-					calculate_part_bounds(&version, pn).ok_or(Error::InvalidPart)?;
+				let (part_offset, part_end) = calculate_part_bounds(&version, pn)
+					.ok_or_else(|| Error::InvalidPart(format!("part {} does not exist", pn)))?;
 
 				Ok(object_headers(
 					object_version,
@@ -285,7 +403,21 @@ pub async fn handle_get(
 	part_number: Option<u64>,
 	overrides: GetObjectOverrides,
 ) -> Result<Response<ResBody>, Error> {
-	handle_get_without_ctx(ctx.garage, req, ctx.bucket_id, key, part_number, overrides).await
+	let res = handle_get_without_ctx(
+		ctx.garage.clone(),
+		req,
+		ctx.bucket_id,
+		key,
+		part_number,
+		overrides,
+	)
+	.await;
+	if matches!(res, Err(Error::NoSuchKey))
+		&& is_implicit_directory(&ctx.garage, ctx.bucket_id, key).await?
+	{
+		return implicit_directory_response();
+	}
+	res
 }
 
 /// Handle GET request
@@ -297,9 +429,7 @@ pub async fn handle_get_without_ctx(
 	part_number: Option<u64>,
 	overrides: GetObjectOverrides,
 ) -> Result<Response<ResBody>, Error> {
-	let object = garage
-		.object_table
-		.get(&bucket_id, &key.to_string())
+	let object = get_object_within_staleness(&garage, req, bucket_id, key)
 		.await?
 		.ok_or(Error::NoSuchKey)?;
 
@@ -320,6 +450,14 @@ pub async fn handle_get_without_ctx(
 		ObjectVersionData::FirstBlock(meta, _) => meta,
 	};
 
+	if last_v_meta.is_expired() {
+		return Err(Error::NoSuchKey);
+	}
+
+	if let Some(redirect) = try_answer_redirect(last_v, last_v_meta, key) {
+		return Ok(redirect);
+	}
+
 	if let Some(cached) = try_answer_cached(last_v, last_v_meta, req) {
 		return Ok(cached);
 	}
@@ -329,7 +467,15 @@ pub async fn handle_get_without_ctx(
 
 	let checksum_mode = checksum_mode(&req);
 
-	match (part_number, parse_range_header(req, last_v_meta.size)?) {
+	// If the object has changed since the client got the If-Range validator,
+	// the Range header is ignored and the whole object is returned
+	let range = if if_range_matches(last_v, last_v_meta, req) {
+		parse_range_header(req, last_v_meta.size)?
+	} else {
+		None
+	};
+
+	match (part_number, range) {
 		(Some(_), Some(_)) => Err(Error::bad_request(
 			"Cannot specify both partNumber and Range header",
 		)),
@@ -376,6 +522,78 @@ pub async fn handle_get_without_ctx(
 	}
 }
 
+/// In the `directory` mode for directory markers, a key ending with a slash
+/// for which there is no folder marker is still a directory if there are
+/// objects under it
+/// Read an object for a GetObject or HeadObject request. If the request has an
+/// `x-garage-max-staleness` header, the object is first read from a single node,
+/// and this result is used if the most recent version that node knows of was
+/// written less than the given number of seconds ago. Otherwise, the object is
+/// read from a quorum of nodes as usual. This relies on version timestamps,
+/// i.e. on the clocks of the nodes that handled the writes.
+async fn get_object_within_staleness(
+	garage: &Garage,
+	req: &Request<impl Body>,
+	bucket_id: Uuid,
+	key: &str,
+) -> Result<Option<Object>, Error> {
+	let key = key.to_string();
+	if let Some(max_staleness) = req.headers().get(X_GARAGE_MAX_STALENESS) {
+		let max_staleness_secs = max_staleness
+			.to_str()?
+			.parse::<u64>()
+			.ok_or_bad_request("Invalid x-garage-max-staleness header")?;
+		if let Some(object) = garage.object_table.get_single(&bucket_id, &key).await? {
+			if is_within_staleness(&object, now_msec(), max_staleness_secs) {
+				return Ok(Some(object));
+			}
+		}
+	}
+	Ok(garage.object_table.get(&bucket_id, &key).await?)
+}
+
+/// Whether the most recent version of an object was written at most
+/// `max_staleness_secs` seconds before `now` (in msec)
+fn is_within_staleness(object: &Object, now: u64, max_staleness_secs: u64) -> bool {
+	match object.versions().last() {
+		Some(v) => {
+			v.timestamp
+				.saturating_add(max_staleness_secs.saturating_mul(1000))
+				>= now
+		}
+		None => false,
+	}
+}
+
+async fn is_implicit_directory(garage: &Garage, bucket_id: Uuid, key: &str) -> Result<bool, Error> {
+	if garage.config.s3_api.directory_markers != DirectoryMarkers::Directory || !key.ends_with('/')
+	{
+		return Ok(false);
+	}
+
+	let objects = garage
+		.object_table
+		.get_range(
+			&bucket_id,
+			Some(key.to_string()),
+			Some(ObjectFilter::IsData),
+			1,
+			EnumerationOrder::Forward,
+		)
+		.await?;
+	Ok(objects.iter().any(|o| o.key.starts_with(key)))
+}
+
+/// Response to GetObject/HeadObject on a directory that has no folder marker:
+/// an empty object, as if the folder marker existed
+fn implicit_directory_response() -> Result<Response<ResBody>, Error> {
+	Ok(Response::builder()
+		.header(CONTENT_TYPE, "application/x-directory")
+		.header(CONTENT_LENGTH, "0")
+		.status(StatusCode::OK)
+		.body(empty_body())?)
+}
+
 async fn handle_get_full(
 	garage: Arc<Garage>,
 	version: &ObjectVersion,
@@ -393,8 +611,12 @@ async fn handle_get_full(
 		encryption,
 		checksum_mode,
 	)
-	.header(CONTENT_LENGTH, format!("{}", version_meta.size))
 	.status(StatusCode::OK);
+	// Without a Content-Length header, the body is sent using
+	// chunked transfer encoding as its size is not known by hyper
+	if !garage.config.s3_api.prefer_chunked_get {
+		resp_builder = resp_builder.header(CONTENT_LENGTH, format!("{}", version_meta.size));
+	}
 	getobject_override_headers(overrides, &mut resp_builder)?;
 
 	let stream = full_object_byte_stream(garage, version, version_data, encryption);
@@ -426,26 +648,50 @@ pub fn full_object_byte_stream(
 			let first_block_hash = *first_block_hash;
 			let version_uuid = version.uuid;
 
-			tokio::spawn(async move {
+			tokio::spawn(with_request_timing(current_request_timing(), async move {
 				match async {
 					let garage2 = garage.clone();
-					let version_fut = tokio::spawn(async move {
-						garage2.version_table.get(&version_uuid, &EmptyKey).await
-					});
-
-					let stream_block_0 = encryption
-						.get_block(&garage, &first_block_hash, Some(order_stream.order(0)))
-						.await?;
+					let version_fut =
+						tokio::spawn(with_request_timing(current_request_timing(), async move {
+							garage2.version_table.get(&version_uuid, &EmptyKey).await
+						}));
+
+					let _hold_block_0 = garage
+						.block_manager
+						.holds
+						.hold(version_uuid, vec![first_block_hash]);
+					let stream_block_0 = get_held_block(
+						&garage,
+						&encryption,
+						version_uuid,
+						&first_block_hash,
+						Some(order_stream.order(0)),
+					)
+					.await?;
 
 					tx.send(stream_block_0)
 						.await
 						.ok_or_message("channel closed")?;
 
 					let version = version_fut.await.unwrap()?.ok_or(Error::NoSuchKey)?;
+					let _hold = garage.block_manager.holds.hold(
+						version_uuid,
+						version
+							.blocks
+							.items()
+							.iter()
+							.map(|(_, vb)| vb.hash)
+							.collect(),
+					);
 					for (i, (_, vb)) in version.blocks.items().iter().enumerate().skip(1) {
-						let stream_block_i = encryption
-							.get_block(&garage, &vb.hash, Some(order_stream.order(i as u64)))
-							.await?;
+						let stream_block_i = get_held_block(
+							&garage,
+							&encryption,
+							version_uuid,
+							&vb.hash,
+							Some(order_stream.order(i as u64)),
+						)
+						.await?;
 						tx.send(stream_block_i)
 							.await
 							.ok_or_message("channel closed")?;
@@ -460,7 +706,7 @@ pub fn full_object_byte_stream(
 						let _ = tx.send(error_stream_item(e)).await;
 					}
 				}
-			});
+			}));
 
 			Box::pin(tokio_stream::wrappers::ReceiverStream::new(rx).flatten())
 		}
@@ -509,8 +755,9 @@ async fn handle_get_range(
 				.await?
 				.ok_or(Error::NoSuchKey)?;
 
-			let body =
-				body_from_blocks_range(garage, encryption, version.blocks.items(), begin, end);
+			let blocks = blocks_in_range(version.blocks.items(), begin, end);
+			let resp_builder = add_block_checksums_header(resp_builder, &blocks);
+			let body = body_from_blocks_range(garage, encryption, version.uuid, blocks, begin, end);
 			Ok(resp_builder.body(body)?)
 		}
 	}
@@ -539,7 +786,10 @@ async fn handle_get_part(
 	match version_data {
 		ObjectVersionData::Inline(_, bytes) => {
 			if part_number != 1 {
-				return Err(Error::InvalidPart);
+				return Err(Error::InvalidPart(format!(
+					"part {} does not exist",
+					part_number
+				)));
 			}
 			let bytes = encryption.decrypt_blob(&bytes)?;
 			assert_eq!(bytes.len() as u64, version_meta.size);
@@ -559,11 +809,13 @@ async fn handle_get_part(
 				.await?
 				.ok_or(Error::NoSuchKey)?;
 
-			let (begin, end) =
-				calculate_part_bounds(&version, part_number).ok_or(Error::InvalidPart)?;
+			let (begin, end) = calculate_part_bounds(&version, part_number).ok_or_else(|| {
+				Error::InvalidPart(format!("part {} does not exist", part_number))
+			})?;
 
-			let body =
-				body_from_blocks_range(garage, encryption, version.blocks.items(), begin, end);
+			let blocks = blocks_in_range(version.blocks.items(), begin, end);
+			let resp_builder = add_block_checksums_header(resp_builder, &blocks);
+			let body = body_from_blocks_range(garage, encryption, version.uuid, blocks, begin, end);
 
 			Ok(resp_builder
 				.header(CONTENT_LENGTH, format!("{}", end - begin))
@@ -597,7 +849,45 @@ fn parse_range_header(
 		}
 		None => None,
 	};
-	Ok(range)
+	match range {
+		// A suffix range on an empty object selects no bytes at all, which
+		// cannot be expressed in a Content-Range header: the range is not
+		// satisfiable, as for any other range on an empty object.
+		Some(r) if r.length == 0 => Err(Error::InvalidRange((
+			http_range::HttpRangeParseError::NoOverlap,
+			total_size,
+		))),
+		r => Ok(r),
+	}
+}
+
+/// Check whether the validator given in the If-Range header, if any, matches the
+/// current version of the object. As required by RFC 9110 (section 13.1.5), an
+/// entity tag only matches with a strong comparison, and a date only matches
+/// if it is exactly the Last-Modified date of the object.
+fn if_range_matches(
+	version: &ObjectVersion,
+	version_meta: &ObjectVersionMeta,
+	req: &Request<impl Body>,
+) -> bool {
+	let if_range = match req.headers().get(IF_RANGE).map(|v| v.to_str()) {
+		None => return true,
+		Some(Ok(v)) => v.trim(),
+		Some(Err(_)) => return false,
+	};
+
+	if if_range.starts_with('"') {
+		!version_meta.etag.is_empty() && if_range == format!("\"{}\"", version_meta.etag)
+	} else if if_range.starts_with("W/") {
+		// weak entity tags never match
+		false
+	} else {
+		let last_modified = UNIX_EPOCH + Duration::from_millis(version.timestamp);
+		match httpdate::parse_http_date(if_range) {
+			Ok(date) => httpdate::fmt_http_date(date) == httpdate::fmt_http_date(last_modified),
+			Err(_) => false,
+		}
+	}
 }
 
 fn calculate_part_bounds(v: &Version, part_number: u64) -> Option<(u64, u64)> {
@@ -630,13 +920,13 @@ fn checksum_mode(req: &Request<impl Body>) -> ChecksumMode {
 	}
 }
 
-fn body_from_blocks_range(
-	garage: Arc<Garage>,
-	encryption: EncryptionParams,
+/// Blocks of a version that have an intersection with the range
+/// `begin..end`, along with their offset in the complete object
+fn blocks_in_range(
 	all_blocks: &[(VersionBlockKey, VersionBlock)],
 	begin: u64,
 	end: u64,
-) -> ResBody {
+) -> Vec<(VersionBlock, u64)> {
 	// We will store here the list of blocks that have an intersection with the requested
 	// range, as well as their "true offset", which is their actual offset in the complete
 	// file (whereas block.offset designates the offset of the block WITHIN THE PART
@@ -656,16 +946,52 @@ fn body_from_blocks_range(
 		}
 		block_offset += b.size;
 	}
+	blocks
+}
 
+fn add_block_checksums_header(
+	resp: http::response::Builder,
+	blocks: &[(VersionBlock, u64)],
+) -> http::response::Builder {
+	if !blocks.is_empty() && blocks.iter().all(|(b, _)| b.checksum.is_some()) {
+		resp.header(X_GARAGE_BLOCK_CHECKSUMS, "verified")
+	} else {
+		resp
+	}
+}
+
+fn body_from_blocks_range(
+	garage: Arc<Garage>,
+	encryption: EncryptionParams,
+	version_uuid: Uuid,
+	blocks: Vec<(VersionBlock, u64)>,
+	begin: u64,
+	end: u64,
+) -> ResBody {
 	let order_stream = OrderTag::stream();
 	let (tx, rx) = mpsc::channel::<ByteStream>(2);
 
-	tokio::spawn(async move {
+	tokio::spawn(with_request_timing(current_request_timing(), async move {
+		let _hold = garage
+			.block_manager
+			.holds
+			.hold(version_uuid, blocks.iter().map(|(b, _)| b.hash).collect());
 		match async {
 			for (i, (block, block_offset)) in blocks.iter().enumerate() {
-				let block_stream = encryption
-					.get_block(&garage, &block.hash, Some(order_stream.order(i as u64)))
-					.await?;
+				// Encryption is done per block, so each block is fetched and
+				// decrypted entirely, and the plaintext is sliced afterwards
+				let block_stream = get_held_block(
+					&garage,
+					&encryption,
+					version_uuid,
+					&block.hash,
+					Some(order_stream.order(i as u64)),
+				)
+				.await?;
+				let block_stream = match block.checksum {
+					Some(expected) => verify_block(block_stream, &block.hash, expected).await?,
+					None => block_stream,
+				};
 				let block_stream = block_stream
 					.scan(*block_offset, move |chunk_offset, chunk| {
 						let r = match chunk {
@@ -720,11 +1046,75 @@ fn body_from_blocks_range(
 				let _ = tx.send(error_stream_item(e)).await;
 			}
 		}
-	});
+	}));
 
 	response_body_from_block_stream(rx)
 }
 
+/// Read a whole data block and check it against the checksum that was stored
+/// for it at upload time, before any of its bytes are sent to the client
+async fn verify_block(
+	stream: ByteStream,
+	hash: &Hash,
+	expected: ChecksumValue,
+) -> Result<ByteStream, Error> {
+	let data = read_stream_to_end(stream)
+		.await
+		.map_err(GarageError::from)?
+		.into_bytes();
+	let (data, checksum) = tokio::task::spawn_blocking(move || {
+		let checksum = block_checksum(expected.algorithm(), &data);
+		(data, checksum)
+	})
+	.await
+	.unwrap();
+	if checksum != expected {
+		return Err(Error::internal_error(format!(
+			"Data block {:?} does not match its stored checksum",
+			hash
+		)));
+	}
+	Ok(Box::pin(stream::once(future::ready(
+		Ok::<_, std::io::Error>(data),
+	))))
+}
+
+/// Fetch a block of a version that is being streamed to a client, while a
+/// read hold is registered on it. Blocks held locally are not deleted, but
+/// other nodes may have deleted them if the object was removed during the
+/// read. In that case the block is fetched from any remaining node, and if
+/// none has it anymore the read is interrupted with a NoSuchKey error
+/// instead of a missing block error.
+async fn get_held_block(
+	garage: &Garage,
+	encryption: &EncryptionParams,
+	version_uuid: Uuid,
+	hash: &Hash,
+	order: Option<OrderTag>,
+) -> Result<ByteStream, Error> {
+	match encryption.get_block(garage, hash, order).await {
+		Ok(stream) => Ok(stream),
+		Err(GarageError::MissingBlock(h)) => {
+			let version_deleted = garage
+				.version_table
+				.get(&version_uuid, &EmptyKey)
+				.await?
+				.map(|v| v.deleted.get())
+				.unwrap_or(true);
+			if version_deleted {
+				warn!(
+					"Version {:?} was deleted while being read, block {:?} is no longer available: terminating stream",
+					version_uuid, h
+				);
+				Err(Error::NoSuchKey)
+			} else {
+				Err(GarageError::MissingBlock(h).into())
+			}
+		}
+		Err(e) => Err(e.into()),
+	}
+}
+
 fn response_body_from_block_stream(rx: mpsc::Receiver<ByteStream>) -> ResBody {
 	let body_stream = tokio_stream::wrappers::ReceiverStream::new(rx).flatten();
 	response_body_from_stream(body_stream)
@@ -753,3 +1143,133 @@ fn std_error_from_read_error<E: std::fmt::Display>(e: E) -> std::io::Error {
 		format!("Error while reading object data: {}", e),
 	)
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use http_body_util::BodyExt;
+
+	#[test]
+	fn test_make_redirect_location_absolute() {
+		let redirect = |target: &'static str| {
+			let mut resp = Response::builder()
+				.status(StatusCode::MOVED_PERMANENTLY)
+				.header(X_GARAGE_REDIRECT_KEY, target)
+				.header(LOCATION, relative_redirect_location("a/b", target))
+				.body(())
+				.unwrap();
+			make_redirect_location_absolute(&mut resp);
+			resp.headers()[LOCATION].to_str().unwrap().to_string()
+		};
+
+		assert_eq!(redirect("new/key"), "/new/key");
+		assert_eq!(redirect("with space"), "/with%20space");
+		// Never a redirect to another host
+		assert_eq!(redirect("/evil.com"), "..//evil.com");
+		assert_eq!(redirect("//evil.com"), "..///evil.com");
+	}
+
+	#[tokio::test]
+	async fn test_chunked_response_body() {
+		let chunks = (0..10u8)
+			.map(|i| Ok(Bytes::from(vec![i; 1000 + i as usize])))
+			.collect::<Vec<Result<Bytes, std::io::Error>>>();
+		let expected = chunks
+			.iter()
+			.flat_map(|c| c.as_ref().unwrap().to_vec())
+			.collect::<Vec<u8>>();
+
+		let body = response_body_from_stream(stream::iter(chunks));
+		// The body has no known size, so hyper sends it with chunked encoding
+		// when no Content-Length header is set
+		assert_eq!(body.size_hint().exact(), None);
+
+		let data = body.collect().await.unwrap().to_bytes();
+		assert_eq!(data.len(), expected.len());
+		assert_eq!(sha256sum(&data), sha256sum(&expected));
+	}
+
+	fn range_of(range: &str, total_size: u64) -> Result<Option<(u64, u64)>, Error> {
+		let req = Request::builder()
+			.header(RANGE, range)
+			.body(http_body_util::Empty::<Bytes>::new())
+			.unwrap();
+		parse_range_header(&req, total_size).map(|r| r.map(|r| (r.start, r.length)))
+	}
+
+	#[test]
+	fn test_parse_range_header() {
+		assert_eq!(range_of("bytes=1-9", 62).unwrap(), Some((1, 9)));
+		assert_eq!(range_of("bytes=-5", 3).unwrap(), Some((0, 3)));
+		assert_eq!(range_of("bytes=2-", 3).unwrap(), Some((2, 1)));
+
+		for (range, total_size) in [
+			("bytes=0-", 0),
+			("bytes=0-0", 0),
+			("bytes=-5", 0),
+			("bytes=3-", 3),
+			("bytes=10-20", 3),
+		] {
+			match range_of(range, total_size) {
+				Err(Error::InvalidRange((_, size))) => assert_eq!(size, total_size),
+				r => panic!(
+					"{} on {} bytes: expected InvalidRange, got {:?}",
+					range, total_size, r
+				),
+			}
+		}
+	}
+
+	#[test]
+	fn test_public_overrides() {
+		let overrides = GetObjectOverrides::from_public_query(Some(
+			"response-content-disposition=attachment%3B%20filename%3D%22r%C3%A9sum%C3%A9.txt%22\
+			&response-content-type=text%2Fhtml&response-cache-control=no-cache\
+			&response-content-encoding=gzip&response-expires=",
+		));
+		assert_eq!(
+			overrides.response_content_disposition.as_deref(),
+			Some("attachment; filename=\"résumé.txt\"")
+		);
+		assert_eq!(
+			overrides.response_cache_control.as_deref(),
+			Some("no-cache")
+		);
+		assert_eq!(overrides.response_content_type, None);
+		assert_eq!(overrides.response_content_encoding, None);
+		assert_eq!(overrides.response_expires, None);
+
+		let overrides = GetObjectOverrides::from_public_query(None);
+		assert_eq!(overrides.response_content_disposition, None);
+	}
+
+	#[test]
+	fn test_staleness_bound() {
+		let now = 1_000_000_000;
+		let object_at = |timestamps: &[u64]| {
+			let versions = timestamps
+				.iter()
+				.map(|ts| ObjectVersion {
+					uuid: gen_uuid(),
+					timestamp: *ts,
+					state: ObjectVersionState::Complete(ObjectVersionData::DeleteMarker),
+				})
+				.collect();
+			Object::new(gen_uuid(), "key".into(), versions)
+		};
+
+		// A replica that missed the last writes only has an old version:
+		// the object must be read again from a quorum of nodes
+		let stale = object_at(&[now - 600_000, now - 60_000]);
+		assert!(!is_within_staleness(&stale, now, 10));
+		assert!(is_within_staleness(&stale, now, 60));
+		assert!(is_within_staleness(&stale, now, 3600));
+
+		// The version returned by the quorum read is within the bound
+		let fresh = object_at(&[now - 60_000, now - 2_000]);
+		assert!(is_within_staleness(&fresh, now, 10));
+		assert!(!is_within_staleness(&fresh, now, 1));
+
+		assert!(!is_within_staleness(&object_at(&[]), now, 10));
+	}
+}
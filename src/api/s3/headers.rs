@@ -0,0 +1,96 @@
+//! Headers common to the responses of requests that read or write an object
+use chrono::{TimeZone, Utc};
+use http::header::{ACCEPT_RANGES, ETAG, LAST_MODIFIED};
+
+use garage_util::data::*;
+
+use crate::encoding::uri_encode;
+
+pub const X_AMZ_VERSION_ID: &str = "x-amz-version-id";
+pub const X_GARAGE_EXPIRES_AT: &str = "x-garage-expires-at";
+/// Header of redirect objects (a Garage extension) giving the key of the
+/// object to which GET and HEAD requests on them are redirected
+pub const X_GARAGE_REDIRECT_KEY: &str = "x-garage-redirect-key";
+/// Header of redirect objects giving the status code of the redirect
+pub const X_GARAGE_REDIRECT_CODE: &str = "x-garage-redirect-code";
+
+/// Format a timestamp in milliseconds as an HTTP date (IMF-fixdate, RFC 7231),
+/// e.g. `Sun, 06 Nov 1994 08:49:37 GMT`. The day of the month is always
+/// written with two digits, as some clients fail to parse it otherwise.
+pub fn http_date(msecs: u64) -> String {
+	Utc.timestamp_opt((msecs / 1000) as i64, 0)
+		.unwrap()
+		.format("%a, %d %b %Y %H:%M:%S GMT")
+		.to_string()
+}
+
+/// Add the headers identifying an object version that was read or written
+pub fn add_version_headers(
+	resp: http::response::Builder,
+	version_uuid: Uuid,
+	etag: Option<&str>,
+) -> http::response::Builder {
+	let resp = resp.header(X_AMZ_VERSION_ID, hex::encode(version_uuid));
+	match etag {
+		Some(etag) if !etag.is_empty() => resp.header(ETAG, format!("\"{}\"", etag)),
+		_ => resp,
+	}
+}
+
+/// Add the headers of responses to GetObject and HeadObject requests
+/// that describe the content of an object version
+pub fn add_content_headers(
+	resp: http::response::Builder,
+	timestamp: u64,
+) -> http::response::Builder {
+	resp.header(LAST_MODIFIED, http_date(timestamp))
+		.header(ACCEPT_RANGES, "bytes")
+}
+
+/// Add the header giving the time after which an object version is deleted,
+/// if it was written with a TTL
+pub fn add_expiration_headers(
+	resp: http::response::Builder,
+	expires_at: Option<u64>,
+) -> http::response::Builder {
+	match expires_at {
+		Some(expires_at) => resp.header(X_GARAGE_EXPIRES_AT, http_date(expires_at)),
+		None => resp,
+	}
+}
+
+/// Location of a redirect from the object `key` to the object `target` of the
+/// same bucket, relative to the URL of `key`, so that it is valid whether the
+/// bucket is in the path or in the host name of the request
+pub fn relative_redirect_location(key: &str, target: &str) -> String {
+	let up = match key.matches('/').count() {
+		// The target might otherwise look like a URL with a scheme
+		0 => "./".to_string(),
+		n => "../".repeat(n),
+	};
+	format!("{}{}", up, uri_encode(target, false))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_http_date() {
+		// 1994-11-06T08:49:37Z
+		assert_eq!(http_date(784111777000), "Sun, 06 Nov 1994 08:49:37 GMT");
+		assert_eq!(http_date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+		// Milliseconds are dropped
+		assert_eq!(http_date(1641394898314), "Wed, 05 Jan 2022 15:01:38 GMT");
+	}
+
+	#[test]
+	fn test_relative_redirect_location() {
+		assert_eq!(relative_redirect_location("old", "new"), "./new");
+		assert_eq!(
+			relative_redirect_location("a/b/old", "new/x y.bin"),
+			"../../new/x%20y.bin"
+		);
+		assert_eq!(relative_redirect_location("a/", "http:x"), "../http%3Ax");
+	}
+}
@@ -0,0 +1,1094 @@
+//! Import of the objects of a bucket of an external S3 endpoint (e.g. AWS S3
+//! or MinIO) into a bucket of the cluster. Imports are done by a background
+//! job running on the node that received the request, which lists the source
+//! bucket with ListObjectsV2 and streams each object directly into the
+//! destination bucket, without going through the S3 API of Garage.
+//!
+//! The status of jobs, including the key up to which objects have been
+//! imported, is persisted on disk, so that jobs that were running when the
+//! node was stopped are resumed when it restarts. The credentials of the
+//! source endpoint are either given in the request, in which case they are
+//! only kept in memory while the job runs, or are the name of credentials
+//! defined in the configuration file, which is the only thing saved on disk.
+//! They are never logged nor returned by the admin API.
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use base64::prelude::*;
+use bytes::Bytes;
+use chrono::Utc;
+use futures::stream::{self, StreamExt};
+use http_body_util::{BodyExt, Empty};
+use hyper::body::Incoming as IncomingBody;
+use hyper::{Method, Request, Response, StatusCode, Uri};
+use hyper_rustls::HttpsConnector;
+use hyper_util::client::legacy::{connect::HttpConnector, Client as HttpClient};
+use hyper_util::rt::TokioExecutor;
+use tokio::sync::watch;
+
+use garage_model::garage::Garage;
+use garage_model::key_table::Key;
+use garage_model::s3::object_table::*;
+use garage_util::config::S3ImportCredentialsConfig;
+use garage_util::data::*;
+use garage_util::error::Error as GarageError;
+use garage_util::persister::Persister;
+use garage_util::time::*;
+
+use crate::common_error::CommonError;
+use crate::encoding::uri_encode;
+use crate::generic_server::ApiError;
+use crate::helpers::{body_stream, ReqCtx};
+use crate::s3::checksum::ExpectedChecksums;
+use crate::s3::encryption::EncryptionParams;
+use crate::s3::error::*;
+use crate::s3::put::{get_headers, save_multipart_stream, save_stream, ChecksumMode};
+use crate::signature::sign::{sign_request, PayloadSignature, SigningParams};
+
+/// Number of objects that are listed at once in the source bucket
+const IMPORT_LIST_BATCH_SIZE: usize = 1000;
+/// Maximum number of objects imported concurrently by a job
+pub const IMPORT_MAX_CONCURRENCY: usize = 64;
+/// Number of objects imported concurrently by a job, by default
+pub const IMPORT_DEFAULT_CONCURRENCY: usize = 4;
+/// Number of times a request to the source endpoint is tried
+const IMPORT_MAX_ATTEMPTS: u32 = 3;
+/// Delay before a failed request is tried again, doubled after each attempt
+const IMPORT_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Timeout for the headers of the responses of the source endpoint
+const IMPORT_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+/// Number of failed objects whose key and error are kept in the job status
+const IMPORT_MAX_REPORTED_FAILURES: usize = 100;
+/// Finished jobs are forgotten after this delay
+const IMPORT_JOB_RETENTION_MSEC: u64 = 24 * 3600 * 1000;
+
+const X_AMZ_MP_PARTS_COUNT: &str = "x-amz-mp-parts-count";
+
+mod v010 {
+	use garage_util::data::Uuid;
+	use serde::{Deserialize, Serialize};
+
+	#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+	pub enum ImportJobState {
+		Running,
+		Done,
+		Cancelled,
+		Failed,
+	}
+
+	/// What to do with objects that already exist in the destination bucket
+	#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+	pub enum ImportConflictPolicy {
+		/// Objects are skipped if they have the same ETag as in the source
+		/// bucket, and replaced otherwise
+		SkipIdentical,
+		/// Objects are always replaced
+		Overwrite,
+	}
+
+	/// An object that could not be imported
+	#[derive(Clone, Debug, Serialize, Deserialize)]
+	pub struct ImportFailure {
+		pub key: String,
+		pub error: String,
+	}
+
+	/// Status and progress of an import job
+	#[derive(Clone, Debug, Serialize, Deserialize)]
+	pub struct ImportJobStatus {
+		pub job_id: Uuid,
+		pub dest_bucket_id: Uuid,
+		pub source_endpoint: String,
+		pub source_region: String,
+		pub source_bucket: String,
+		/// Name of the credentials of the source endpoint in the
+		/// configuration, or None if they were given in the request, in
+		/// which case they are not saved and the job is not resumed
+		pub credentials_name: Option<String>,
+		pub prefix: String,
+		pub conflict_policy: ImportConflictPolicy,
+		/// Whether objects uploaded with a multipart upload are imported
+		/// part by part, so that they keep the same ETag
+		pub preserve_multipart: bool,
+		/// Number of objects imported concurrently
+		pub concurrency: usize,
+		pub state: ImportJobState,
+		pub error: Option<String>,
+		/// Key of the last object of the source bucket that was processed,
+		/// from which the job resumes
+		pub cursor: Option<String>,
+		pub objects_imported: u64,
+		/// Number of objects not imported because they already exist in the
+		/// destination bucket
+		pub objects_skipped: u64,
+		pub objects_failed: u64,
+		pub bytes_imported: u64,
+		pub bytes_skipped: u64,
+		/// Number and size of the objects to import, known once the source
+		/// bucket has been listed entirely
+		pub objects_total: Option<u64>,
+		pub bytes_total: Option<u64>,
+		/// Last objects that could not be imported
+		pub failures: Vec<ImportFailure>,
+		pub started_at: u64,
+		pub finished_at: Option<u64>,
+	}
+
+	#[derive(Serialize, Deserialize, Default)]
+	pub struct ImportJobsPersisted {
+		pub jobs: Vec<ImportJobStatus>,
+	}
+
+	impl garage_util::migrate::InitialFormat for ImportJobsPersisted {
+		const VERSION_MARKER: &'static [u8] = b"G010s3ij";
+	}
+}
+
+pub use v010::*;
+
+impl std::fmt::Display for ImportJobState {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Running => write!(f, "running"),
+			Self::Done => write!(f, "done"),
+			Self::Cancelled => write!(f, "cancelled"),
+			Self::Failed => write!(f, "failed"),
+		}
+	}
+}
+
+impl ImportConflictPolicy {
+	pub fn parse(s: &str) -> Option<Self> {
+		match s {
+			"skipIdentical" => Some(Self::SkipIdentical),
+			"overwrite" => Some(Self::Overwrite),
+			_ => None,
+		}
+	}
+}
+
+impl std::fmt::Display for ImportConflictPolicy {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::SkipIdentical => write!(f, "skipIdentical"),
+			Self::Overwrite => write!(f, "overwrite"),
+		}
+	}
+}
+
+/// Credentials of the source endpoint
+#[derive(Clone)]
+pub struct ImportCredentials {
+	pub access_key_id: String,
+	pub secret_access_key: String,
+}
+
+/// How the credentials of the source endpoint of a new job are given
+pub enum ImportCredentialsSource {
+	/// The credentials themselves
+	Literal(ImportCredentials),
+	/// The name of credentials defined in `s3_api.import_credentials`
+	Named(String),
+}
+
+impl std::fmt::Debug for ImportCredentials {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("ImportCredentials")
+			.field("access_key_id", &self.access_key_id)
+			.field("secret_access_key", &"[redacted]")
+			.finish()
+	}
+}
+
+impl ImportJobStatus {
+	/// Estimate the number of seconds until a running job is finished,
+	/// from the amount of data processed until now
+	pub fn eta_secs(&self, now: u64) -> Option<u64> {
+		if self.state != ImportJobState::Running {
+			return None;
+		}
+		let processed = self.bytes_imported + self.bytes_skipped;
+		if processed == 0 {
+			return None;
+		}
+		let remaining = self.bytes_total?.saturating_sub(processed);
+		let elapsed_msec = now.saturating_sub(self.started_at);
+		Some((elapsed_msec as u128 * remaining as u128 / processed as u128 / 1000) as u64)
+	}
+}
+
+/// Parameters of a new import job
+pub struct ImportParams {
+	pub dest_bucket_id: Uuid,
+	pub source_endpoint: String,
+	pub source_region: String,
+	pub source_bucket: String,
+	pub credentials: ImportCredentialsSource,
+	pub prefix: String,
+	pub conflict_policy: ImportConflictPolicy,
+	pub preserve_multipart: bool,
+	pub concurrency: usize,
+}
+
+struct ImportJob {
+	status: Arc<Mutex<ImportJobStatus>>,
+	cancel: watch::Sender<bool>,
+}
+
+/// Registry of the import jobs started on this node
+pub struct ImportJobs {
+	garage: Arc<Garage>,
+	persister: Persister<ImportJobsPersisted>,
+	jobs: Mutex<HashMap<Uuid, ImportJob>>,
+}
+
+impl ImportJobs {
+	pub fn new(garage: Arc<Garage>) -> Arc<Self> {
+		let persister: Persister<ImportJobsPersisted> =
+			Persister::new(&garage.config.metadata_dir, "s3_import_jobs");
+		let jobs = persister
+			.load()
+			.map(|p| p.jobs)
+			.unwrap_or_default()
+			.into_iter()
+			.map(|status| {
+				let (cancel, _) = watch::channel(false);
+				let job = ImportJob {
+					status: Arc::new(Mutex::new(status.clone())),
+					cancel,
+				};
+				(status.job_id, job)
+			})
+			.collect();
+		Arc::new(Self {
+			garage,
+			persister,
+			jobs: Mutex::new(jobs),
+		})
+	}
+
+	/// Restart the jobs that were running when the node was last stopped
+	pub fn resume(self: &Arc<Self>) {
+		let mut jobs = self.jobs.lock().unwrap();
+		let mut failed = false;
+		for job in jobs.values_mut() {
+			let status = job.status.lock().unwrap().clone();
+			if status.state != ImportJobState::Running {
+				continue;
+			}
+			let credentials = match &status.credentials_name {
+				Some(name) => named_credentials(&self.garage.config.s3_api.import_credentials, name)
+					.map_err(|e| e.to_string()),
+				None => Err("The credentials of the job were given in the request and are not saved: it must be started again".into()),
+			};
+			let credentials = match credentials {
+				Ok(c) => c,
+				Err(e) => {
+					error!("Cannot resume import job {:?}: {}", status.job_id, e);
+					let mut s = job.status.lock().unwrap();
+					s.state = ImportJobState::Failed;
+					s.error = Some(e);
+					s.finished_at = Some(now_msec());
+					failed = true;
+					continue;
+				}
+			};
+			info!(
+				"Resuming import job {:?} from {} bucket {} into bucket {:?}, after key {:?}",
+				status.job_id,
+				status.source_endpoint,
+				status.source_bucket,
+				status.dest_bucket_id,
+				status.cursor
+			);
+			let (cancel, cancel_recv) = watch::channel(false);
+			job.cancel = cancel;
+			tokio::spawn(
+				self.clone()
+					.run(job.status.clone(), credentials, cancel_recv),
+			);
+		}
+		drop(jobs);
+		if failed {
+			self.save();
+		}
+	}
+
+	/// Start a job importing all objects of the source bucket whose key
+	/// starts with the given prefix into the destination bucket
+	pub async fn start(
+		self: &Arc<Self>,
+		params: ImportParams,
+	) -> Result<ImportJobStatus, CommonError> {
+		if params.concurrency == 0 || params.concurrency > IMPORT_MAX_CONCURRENCY {
+			return Err(CommonError::BadRequest(format!(
+				"The concurrency of an import must be between 1 and {}",
+				IMPORT_MAX_CONCURRENCY
+			)));
+		}
+		if params.source_bucket.is_empty() {
+			return Err(CommonError::BadRequest(
+				"The source bucket must be given".into(),
+			));
+		}
+		let source_endpoint = parse_source_endpoint(&params.source_endpoint)?;
+
+		let dest_bucket = self
+			.garage
+			.bucket_helper()
+			.get_existing_bucket(params.dest_bucket_id)
+			.await?;
+		if *dest_bucket.params().unwrap().read_only.get() {
+			return Err(CommonError::BadRequest(
+				"Destination bucket is read-only: objects cannot be imported into it.".into(),
+			));
+		}
+
+		let (credentials, credentials_name) = match params.credentials {
+			ImportCredentialsSource::Literal(c) => (c, None),
+			ImportCredentialsSource::Named(name) => (
+				named_credentials(&self.garage.config.s3_api.import_credentials, &name)?,
+				Some(name),
+			),
+		};
+
+		let status = ImportJobStatus {
+			job_id: gen_uuid(),
+			dest_bucket_id: params.dest_bucket_id,
+			source_endpoint,
+			source_region: params.source_region,
+			source_bucket: params.source_bucket,
+			credentials_name,
+			prefix: params.prefix,
+			conflict_policy: params.conflict_policy,
+			preserve_multipart: params.preserve_multipart,
+			concurrency: params.concurrency,
+			state: ImportJobState::Running,
+			error: None,
+			cursor: None,
+			objects_imported: 0,
+			objects_skipped: 0,
+			objects_failed: 0,
+			bytes_imported: 0,
+			bytes_skipped: 0,
+			objects_total: None,
+			bytes_total: None,
+			failures: vec![],
+			started_at: now_msec(),
+			finished_at: None,
+		};
+		let shared_status = Arc::new(Mutex::new(status.clone()));
+		let (cancel, cancel_recv) = watch::channel(false);
+
+		{
+			let mut jobs = self.jobs.lock().unwrap();
+			let now = now_msec();
+			jobs.retain(|_, job| {
+				job.status
+					.lock()
+					.unwrap()
+					.finished_at
+					.map(|t| t + IMPORT_JOB_RETENTION_MSEC > now)
+					.unwrap_or(true)
+			});
+			jobs.insert(
+				status.job_id,
+				ImportJob {
+					status: shared_status.clone(),
+					cancel,
+				},
+			);
+		}
+		self.save();
+
+		info!(
+			"Starting import job {:?} from {} bucket {} into bucket {:?}, prefix {:?}",
+			status.job_id,
+			status.source_endpoint,
+			status.source_bucket,
+			status.dest_bucket_id,
+			status.prefix,
+		);
+		tokio::spawn(self.clone().run(shared_status, credentials, cancel_recv));
+
+		Ok(status)
+	}
+
+	/// Get the status of a job
+	pub fn get(&self, job_id: &Uuid) -> Option<ImportJobStatus> {
+		let jobs = self.jobs.lock().unwrap();
+		jobs.get(job_id).map(|j| j.status.lock().unwrap().clone())
+	}
+
+	/// Ask for a job to stop after the objects it is currently importing.
+	/// Returns the status of the job, or None if it does not exist.
+	pub fn cancel(&self, job_id: &Uuid) -> Option<ImportJobStatus> {
+		let jobs = self.jobs.lock().unwrap();
+		let job = jobs.get(job_id)?;
+		job.cancel.send_replace(true);
+		let status = job.status.lock().unwrap().clone();
+		Some(status)
+	}
+
+	/// Write the status of all jobs to disk
+	fn save(&self) {
+		let jobs = self.jobs.lock().unwrap();
+		let persisted = ImportJobsPersisted {
+			jobs: jobs
+				.values()
+				.map(|j| j.status.lock().unwrap().clone())
+				.collect(),
+		};
+		if let Err(e) = self.persister.save(&persisted) {
+			error!("Could not save the status of import jobs: {}", e);
+		}
+	}
+
+	async fn run(
+		self: Arc<Self>,
+		status: Arc<Mutex<ImportJobStatus>>,
+		credentials: ImportCredentials,
+		cancel: watch::Receiver<bool>,
+	) {
+		let job = status.lock().unwrap().clone();
+		// The credentials are dropped with the source client when the job ends
+		let res = match SourceClient::new(&job, credentials) {
+			Ok(source) => self.run_with(&source, &status, &cancel).await,
+			Err(e) => Err(e),
+		};
+
+		{
+			let mut s = status.lock().unwrap();
+			s.finished_at = Some(now_msec());
+			match res {
+				Ok(state) => {
+					s.state = state;
+					info!(
+						"Import job {:?} {}: {} objects imported ({} bytes), {} skipped, {} failed",
+						s.job_id,
+						state,
+						s.objects_imported,
+						s.bytes_imported,
+						s.objects_skipped,
+						s.objects_failed
+					);
+				}
+				Err(e) => {
+					error!("Import job {:?} failed: {}", s.job_id, e);
+					s.state = ImportJobState::Failed;
+					s.error = Some(e.to_string());
+				}
+			}
+		}
+		self.save();
+	}
+
+	async fn run_with(
+		&self,
+		source: &SourceClient,
+		status: &Mutex<ImportJobStatus>,
+		cancel: &watch::Receiver<bool>,
+	) -> Result<ImportJobState, Error> {
+		let job = status.lock().unwrap().clone();
+
+		// List the source bucket entirely once to know the amount
+		// of data to import, which gives the progress of the job
+		if job.objects_total.is_none() {
+			let mut cursor = None;
+			let (mut objects_total, mut bytes_total) = (0, 0);
+			loop {
+				if *cancel.borrow() {
+					return Ok(ImportJobState::Cancelled);
+				}
+				let page = source.list(&job.prefix, cursor.as_deref()).await?;
+				objects_total += page.objects.len() as u64;
+				bytes_total += page.objects.iter().map(|o| o.size).sum::<u64>();
+				cursor = page.objects.last().map(|o| o.key.clone());
+				if !page.is_truncated || cursor.is_none() {
+					break;
+				}
+			}
+			let mut s = status.lock().unwrap();
+			s.objects_total = Some(objects_total);
+			s.bytes_total = Some(bytes_total);
+		}
+
+		loop {
+			if *cancel.borrow() {
+				return Ok(ImportJobState::Cancelled);
+			}
+
+			let cursor = status.lock().unwrap().cursor.clone();
+			let page = source.list(&job.prefix, cursor.as_deref()).await?;
+
+			// The parameters of the destination bucket are read again for each
+			// page, so that the job stops if it is made read-only or deleted
+			let ctx = &self.dest_ctx(job.dest_bucket_id).await?;
+			let job = &job;
+
+			let results = stream::iter(page.objects.iter().cloned())
+				.map(|object| {
+					let cancel = cancel.clone();
+					async move {
+						if *cancel.borrow() {
+							return None;
+						}
+						let res = import_object_with_retries(ctx, source, job, &object).await;
+						Some((object, res))
+					}
+				})
+				.buffer_unordered(job.concurrency)
+				.collect::<Vec<_>>()
+				.await;
+
+			let cancelled = results.iter().any(Option::is_none);
+			{
+				let mut s = status.lock().unwrap();
+				for (object, res) in results.into_iter().flatten() {
+					match res {
+						Ok(true) => {
+							s.objects_imported += 1;
+							s.bytes_imported += object.size;
+						}
+						Ok(false) => {
+							s.objects_skipped += 1;
+							s.bytes_skipped += object.size;
+						}
+						Err(e) => {
+							warn!("Could not import object {:?}: {}", object.key, e);
+							s.objects_failed += 1;
+							if s.failures.len() >= IMPORT_MAX_REPORTED_FAILURES {
+								s.failures.remove(0);
+							}
+							s.failures.push(ImportFailure {
+								key: object.key.clone(),
+								error: e.to_string(),
+							});
+						}
+					}
+				}
+				// The cursor only moves once all objects of the page have been
+				// processed, so that none is missed if the job is interrupted
+				if !cancelled {
+					if let Some(last) = page.objects.last() {
+						s.cursor = Some(last.key.clone());
+					}
+				}
+			}
+			if cancelled {
+				return Ok(ImportJobState::Cancelled);
+			}
+			if !page.is_truncated || page.objects.is_empty() {
+				return Ok(ImportJobState::Done);
+			}
+			self.save();
+		}
+	}
+
+	async fn dest_ctx(&self, bucket_id: Uuid) -> Result<ReqCtx, Error> {
+		let bucket = self
+			.garage
+			.bucket_helper()
+			.get_existing_bucket(bucket_id)
+			.await?;
+		let bucket_params = bucket.state.into_option().unwrap();
+		if *bucket_params.read_only.get() {
+			return Err(Error::bad_request(
+				"Destination bucket has been made read-only",
+			));
+		}
+		Ok(ReqCtx {
+			garage: self.garage.clone(),
+			bucket_id,
+			bucket_name: hex::encode(bucket_id),
+			bucket_params,
+			// Not stored and not used for authorization: imported objects
+			// are written by Garage itself
+			api_key: Key::new("S3 import"),
+			original_key: None,
+		})
+	}
+}
+
+/// Import an object, trying again if the source endpoint cannot be reached.
+/// Returns whether the object was imported, or skipped because it already
+/// exists in the destination bucket.
+async fn import_object_with_retries(
+	ctx: &ReqCtx,
+	source: &SourceClient,
+	job: &ImportJobStatus,
+	object: &SourceObject,
+) -> Result<bool, Error> {
+	let key = ctx.bucket_params.index_key(&object.key);
+	if job.conflict_policy == ImportConflictPolicy::SkipIdentical {
+		let existing = ctx.garage.object_table.get(&ctx.bucket_id, &key).await?;
+		let existing_etag = existing
+			.as_ref()
+			.and_then(|o| o.versions().iter().rev().find(|v| v.is_complete()))
+			.and_then(|v| match &v.state {
+				ObjectVersionState::Complete(
+					ObjectVersionData::Inline(meta, _) | ObjectVersionData::FirstBlock(meta, _),
+				) => Some(meta.etag.as_str()),
+				_ => None,
+			});
+		if existing_etag == Some(object.etag.as_str()) {
+			return Ok(false);
+		}
+	}
+
+	let ctx = ReqCtx {
+		garage: ctx.garage.clone(),
+		bucket_id: ctx.bucket_id,
+		bucket_name: ctx.bucket_name.clone(),
+		bucket_params: ctx.bucket_params.clone(),
+		api_key: ctx.api_key.clone(),
+		original_key: match *ctx.bucket_params.case_insensitive_keys.get() {
+			true => Some(object.key.clone()),
+			false => None,
+		},
+	};
+	let mut attempt = 0;
+	loop {
+		match import_object(&ctx, source, job, object, &key).await {
+			Err(ImportError::Transient(e)) if attempt + 1 < IMPORT_MAX_ATTEMPTS => {
+				debug!(
+					"Importing object {:?} failed, trying again: {}",
+					object.key, e
+				);
+				tokio::time::sleep(IMPORT_RETRY_DELAY * 2u32.pow(attempt)).await;
+				attempt += 1;
+			}
+			Err(ImportError::Transient(e) | ImportError::Fatal(e)) => return Err(e),
+			Ok(imported) => return Ok(imported),
+		}
+	}
+}
+
+enum ImportError {
+	/// The error can be caused by a problem of the network or of the source
+	/// endpoint, and the object can be tried again
+	Transient(Error),
+	Fatal(Error),
+}
+
+async fn import_object(
+	ctx: &ReqCtx,
+	source: &SourceClient,
+	job: &ImportJobStatus,
+	object: &SourceObject,
+	key: &String,
+) -> Result<bool, ImportError> {
+	let n_parts = match object.etag.rsplit_once('-') {
+		Some((_, n)) if job.preserve_multipart => n.parse::<u64>().ok(),
+		_ => None,
+	};
+	let first_part = n_parts.map(|_| 1);
+	let resp = match source
+		.get_object(&object.key, first_part)
+		.await
+		.map_err(ImportError::Transient)?
+	{
+		Some(resp) => resp,
+		// The object has been deleted from the source since it was listed
+		None => return Ok(false),
+	};
+
+	let headers = get_headers(resp.headers()).map_err(ImportError::Fatal)?;
+	let meta = ObjectVersionMetaInner {
+		headers,
+		checksum: None,
+		full_sha256: None,
+	};
+
+	// The source returns the whole object with a 200 status
+	// if it does not support reading objects part by part
+	let res = match n_parts.filter(|_| resp.status() == StatusCode::PARTIAL_CONTENT) {
+		Some(n_parts) => {
+			let parts_count = resp
+				.headers()
+				.get(X_AMZ_MP_PARTS_COUNT)
+				.and_then(|v| v.to_str().ok())
+				.and_then(|v| v.parse::<u64>().ok());
+			if parts_count != Some(n_parts) {
+				return Err(ImportError::Fatal(Error::bad_request(format!(
+					"The number of parts of the object in the source ({:?}) does not match its ETag",
+					parts_count
+				))));
+			}
+			let mut first_part = Some(resp);
+			save_multipart_stream(ctx, meta, n_parts, key, Some(&object.etag), |part_number| {
+				let resp = first_part.take();
+				async move {
+					let resp = match resp {
+						Some(resp) => resp,
+						None => source
+							.get_object(&object.key, Some(part_number))
+							.await?
+							.ok_or_bad_request("Object has been deleted from the source")?,
+					};
+					Ok::<_, Error>(body_stream::<_, Error>(resp.into_body()))
+				}
+			})
+			.await
+		}
+		None => {
+			// The ETag of objects that were not uploaded with a multipart
+			// upload is the MD5 of their content, which is checked before
+			// the object is stored
+			let expected = ExpectedChecksums {
+				md5: plain_md5_etag(&object.etag).map(|md5| BASE64_STANDARD.encode(md5)),
+				..Default::default()
+			};
+			let checksum_mode = match expected.md5 {
+				Some(_) => ChecksumMode::Verify(&expected),
+				None => ChecksumMode::Calculate(None),
+			};
+			save_stream(
+				ctx,
+				meta,
+				EncryptionParams::Plaintext,
+				body_stream::<_, Error>(resp.into_body()),
+				Some(object.size),
+				key,
+				checksum_mode,
+				None,
+				false,
+			)
+			.await
+		}
+	};
+
+	match res {
+		Ok(_) => Ok(true),
+		// Data was corrupted in transit
+		Err(e @ Error::InvalidDigest(_)) => Err(ImportError::Transient(e)),
+		// The object cannot be stored, e.g. its metadata is too large
+		Err(e) if e.http_status_code().is_client_error() => Err(ImportError::Fatal(e)),
+		Err(e) => Err(ImportError::Transient(e)),
+	}
+}
+
+/// The MD5 of an object, if its ETag is one
+fn plain_md5_etag(etag: &str) -> Option<Vec<u8>> {
+	hex::decode(etag).ok().filter(|md5| md5.len() == 16)
+}
+
+/// Check that the endpoint of the source is an HTTP(S) URL, and return it
+/// without the trailing slash
+/// Get the credentials with the given name from the configuration. Names
+/// given in requests are never resolved in any other way, so that the
+/// admin API cannot be used to read files or environment variables.
+fn named_credentials(
+	config: &BTreeMap<String, S3ImportCredentialsConfig>,
+	name: &str,
+) -> Result<ImportCredentials, CommonError> {
+	config
+		.get(name)
+		.map(|c| ImportCredentials {
+			access_key_id: c.access_key_id.clone(),
+			secret_access_key: c.secret_access_key.clone(),
+		})
+		.ok_or_else(|| {
+			CommonError::BadRequest(format!(
+				"No credentials named {:?} in s3_api.import_credentials",
+				name
+			))
+		})
+}
+
+pub(crate) fn parse_source_endpoint(endpoint: &str) -> Result<String, CommonError> {
+	let endpoint = endpoint.trim_end_matches('/');
+	let uri = Uri::try_from(endpoint)
+		.ok()
+		.filter(|u| matches!(u.scheme_str(), Some("http" | "https")) && u.host().is_some())
+		.filter(|u| u.path() == "/" || u.path().is_empty())
+		.ok_or_bad_request(format!(
+			"Invalid source endpoint {:?}, expected e.g. https://s3.example.com",
+			endpoint
+		))?;
+	Ok(uri.to_string().trim_end_matches('/').to_string())
+}
+
+// ---- Client of the source endpoint ----
+
+#[derive(Clone)]
+struct SourceObject {
+	key: String,
+	size: u64,
+	/// ETag WITHOUT THE QUOTES
+	etag: String,
+}
+
+struct SourcePage {
+	objects: Vec<SourceObject>,
+	is_truncated: bool,
+}
+
+struct SourceClient {
+	client: HttpClient<HttpsConnector<HttpConnector>, Empty<Bytes>>,
+	endpoint: String,
+	region: String,
+	bucket: String,
+	credentials: ImportCredentials,
+}
+
+impl SourceClient {
+	fn new(job: &ImportJobStatus, credentials: ImportCredentials) -> Result<Self, Error> {
+		let connector = hyper_rustls::HttpsConnectorBuilder::new()
+			.with_native_roots()
+			.map_err(|e| GarageError::Message(format!("Cannot load TLS root certificates: {}", e)))?
+			.https_or_http()
+			.enable_http1()
+			.build();
+		Ok(Self {
+			client: HttpClient::builder(TokioExecutor::new()).build(connector),
+			endpoint: job.source_endpoint.clone(),
+			region: job.source_region.clone(),
+			bucket: job.source_bucket.clone(),
+			credentials,
+		})
+	}
+
+	/// List the objects of the source bucket with the given prefix,
+	/// after the given key
+	async fn list(&self, prefix: &str, start_after: Option<&str>) -> Result<SourcePage, Error> {
+		let mut query = format!(
+			"list-type=2&max-keys={}&prefix={}",
+			IMPORT_LIST_BATCH_SIZE,
+			uri_encode(prefix, true)
+		);
+		if let Some(start_after) = start_after {
+			query.push_str(&format!("&start-after={}", uri_encode(start_after, true)));
+		}
+		let path = format!("/{}?{}", uri_encode(&self.bucket, true), query);
+
+		let mut attempt = 0;
+		let body = loop {
+			let res = match self.request(&path).await {
+				Ok(resp) if resp.status() == StatusCode::OK => {
+					resp.into_body().collect().await.map_err(Error::from)
+				}
+				Ok(resp) => Err(response_error(resp).await),
+				Err(e) => Err(e),
+			};
+			match res {
+				Ok(body) => break body.to_bytes(),
+				Err(e) if attempt + 1 < IMPORT_MAX_ATTEMPTS => {
+					debug!("Listing the source bucket failed, trying again: {}", e);
+					tokio::time::sleep(IMPORT_RETRY_DELAY * 2u32.pow(attempt)).await;
+					attempt += 1;
+				}
+				Err(e) => return Err(e),
+			}
+		};
+
+		parse_list_response(&body)
+	}
+
+	/// Read an object, or one of its parts, of the source bucket.
+	/// Returns None if the object does not exist.
+	async fn get_object(
+		&self,
+		key: &str,
+		part_number: Option<u64>,
+	) -> Result<Option<Response<IncomingBody>>, Error> {
+		let mut path = format!(
+			"/{}/{}",
+			uri_encode(&self.bucket, true),
+			uri_encode(key, false)
+		);
+		if let Some(n) = part_number {
+			path.push_str(&format!("?partNumber={}", n));
+		}
+		let resp = self.request(&path).await?;
+		match resp.status() {
+			StatusCode::OK | StatusCode::PARTIAL_CONTENT => Ok(Some(resp)),
+			StatusCode::NOT_FOUND => Ok(None),
+			_ => Err(response_error(resp).await),
+		}
+	}
+
+	async fn request(&self, path: &str) -> Result<Response<IncomingBody>, Error> {
+		let uri = Uri::try_from(format!("{}{}", self.endpoint, path))
+			.ok_or_bad_request("Invalid URL for the source endpoint")?;
+
+		let params = SigningParams {
+			key_id: &self.credentials.access_key_id,
+			secret_key: &self.credentials.secret_access_key,
+			region: &self.region,
+			service: "s3",
+			datetime: Utc::now(),
+		};
+		let mut req = Request::builder()
+			.method(Method::GET)
+			.uri(uri.clone())
+			.body(Empty::new())?;
+		sign_request(
+			&params,
+			&Method::GET,
+			&uri,
+			req.headers_mut(),
+			PayloadSignature::Sha256(sha256sum(&[])),
+		)
+		.map_err(|e| GarageError::Message(format!("Cannot sign request: {}", e)))?;
+
+		match tokio::time::timeout(IMPORT_REQUEST_TIMEOUT, self.client.request(req)).await {
+			Ok(Ok(resp)) => Ok(resp),
+			Ok(Err(e)) => {
+				Err(GarageError::Message(format!("Cannot reach the source endpoint: {}", e)).into())
+			}
+			Err(_) => Err(GarageError::Message("Timeout from the source endpoint".into()).into()),
+		}
+	}
+}
+
+/// Error for an unexpected response of the source endpoint, with the error
+/// code of the body if it is an S3 error
+async fn response_error(resp: Response<IncomingBody>) -> Error {
+	let status = resp.status();
+	let code = match resp.into_body().collect().await {
+		Ok(body) => String::from_utf8(body.to_bytes().to_vec())
+			.ok()
+			.and_then(|body| {
+				let doc = roxmltree::Document::parse(&body).ok()?;
+				let code = doc.descendants().find(|n| n.has_tag_name("Code"))?;
+				code.text().map(str::to_string)
+			}),
+		Err(_) => None,
+	};
+	GarageError::Message(format!(
+		"Source endpoint returned {}{}",
+		status,
+		code.map(|c| format!(" ({})", c)).unwrap_or_default()
+	))
+	.into()
+}
+
+fn parse_list_response(body: &[u8]) -> Result<SourcePage, Error> {
+	let body = std::str::from_utf8(body)?;
+	let doc = roxmltree::Document::parse(body)
+		.ok_or_bad_request("Invalid ListObjectsV2 response from the source endpoint")?;
+	let root = doc.root_element();
+	fn child_text(node: roxmltree::Node<'_, '_>, name: &str) -> Option<String> {
+		node.children()
+			.find(|n| n.has_tag_name(name))
+			.map(|n| n.text().unwrap_or_default().to_string())
+	}
+
+	let is_truncated = child_text(root, "IsTruncated").as_deref() == Some("true");
+	let mut objects = vec![];
+	for node in root.children().filter(|n| n.has_tag_name("Contents")) {
+		let key = child_text(node, "Key")
+			.ok_or_bad_request("Object without a key in the listing of the source")?;
+		let size = child_text(node, "Size")
+			.and_then(|s| s.parse::<u64>().ok())
+			.ok_or_bad_request("Object without a size in the listing of the source")?;
+		let etag = child_text(node, "ETag")
+			.unwrap_or_default()
+			.trim_matches('"')
+			.to_string();
+		objects.push(SourceObject { key, size, etag });
+	}
+	Ok(SourcePage {
+		objects,
+		is_truncated,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse_list_response() {
+		let body = br#"<?xml version="1.0" encoding="UTF-8"?>
+<ListBucketResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+	<Name>source</Name>
+	<Prefix>photos/</Prefix>
+	<KeyCount>2</KeyCount>
+	<MaxKeys>2</MaxKeys>
+	<IsTruncated>true</IsTruncated>
+	<Contents>
+		<Key>photos/a&amp;b.jpg</Key>
+		<LastModified>2024-01-01T00:00:00.000Z</LastModified>
+		<ETag>&quot;9b2cf535f27731c974343645a3985328&quot;</ETag>
+		<Size>1234</Size>
+		<StorageClass>STANDARD</StorageClass>
+	</Contents>
+	<Contents>
+		<Key>photos/big.mp4</Key>
+		<ETag>"a7d414b9133d6483d9a1c4e04e856e3b-3"</ETag>
+		<Size>31457280</Size>
+	</Contents>
+</ListBucketResult>"#;
+		let page = parse_list_response(body).unwrap();
+		assert!(page.is_truncated);
+		assert_eq!(page.objects.len(), 2);
+		assert_eq!(page.objects[0].key, "photos/a&b.jpg");
+		assert_eq!(page.objects[0].size, 1234);
+		assert_eq!(page.objects[0].etag, "9b2cf535f27731c974343645a3985328");
+		assert_eq!(page.objects[1].etag, "a7d414b9133d6483d9a1c4e04e856e3b-3");
+
+		assert!(parse_list_response(b"not xml").is_err());
+	}
+
+	#[test]
+	fn test_plain_md5_etag() {
+		assert_eq!(
+			plain_md5_etag("9b2cf535f27731c974343645a3985328").map(|x| x.len()),
+			Some(16)
+		);
+		assert_eq!(plain_md5_etag("a7d414b9133d6483d9a1c4e04e856e3b-3"), None);
+		assert_eq!(plain_md5_etag("abcd"), None);
+	}
+
+	#[test]
+	fn test_parse_source_endpoint() {
+		assert_eq!(
+			parse_source_endpoint("https://s3.example.com/").unwrap(),
+			"https://s3.example.com"
+		);
+		assert_eq!(
+			parse_source_endpoint("http://127.0.0.1:9000").unwrap(),
+			"http://127.0.0.1:9000"
+		);
+		assert!(parse_source_endpoint("s3.example.com").is_err());
+		assert!(parse_source_endpoint("ftp://s3.example.com").is_err());
+		assert!(parse_source_endpoint("https://s3.example.com/bucket").is_err());
+	}
+
+	#[test]
+	fn test_credentials_are_not_logged() {
+		let credentials = ImportCredentials {
+			access_key_id: "AKIAEXAMPLE".into(),
+			secret_access_key: "wJalrXUtnFEMI".into(),
+		};
+		let debug = format!("{:?}", credentials);
+		assert!(debug.contains("AKIAEXAMPLE"));
+		assert!(!debug.contains("wJalrXUtnFEMI"));
+	}
+
+	#[test]
+	fn test_named_credentials() {
+		let mut config = BTreeMap::new();
+		config.insert(
+			"aws".to_string(),
+			S3ImportCredentialsConfig {
+				access_key_id: "AKIAEXAMPLE".into(),
+				secret_access_key: "wJalrXUtnFEMI".into(),
+			},
+		);
+
+		let credentials = named_credentials(&config, "aws").unwrap();
+		assert_eq!(credentials.access_key_id, "AKIAEXAMPLE");
+		assert_eq!(credentials.secret_access_key, "wJalrXUtnFEMI");
+
+		// Names that are not in the configuration are never resolved as
+		// references to secrets stored elsewhere
+		assert!(named_credentials(&config, "gcs").is_err());
+		assert!(named_credentials(&config, "file:/etc/shadow").is_err());
+		assert!(named_credentials(&config, "env:GARAGE_RPC_SECRET").is_err());
+	}
+}
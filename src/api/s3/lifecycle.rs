@@ -1,3 +1,5 @@
+use std::convert::TryFrom;
+
 use quick_xml::de::from_reader;
 
 use http_body_util::BodyExt;
@@ -52,6 +54,10 @@ pub async fn handle_delete_lifecycle(ctx: ReqCtx) -> Result<Response<ResBody>, E
 		.body(empty_body())?)
 }
 
+/// Maximum number of rules in a lifecycle configuration if
+/// `s3_api.max_lifecycle_rules` is not set (same as AWS)
+const DEFAULT_MAX_LIFECYCLE_RULES: usize = 1000;
+
 pub async fn handle_put_lifecycle(
 	ctx: ReqCtx,
 	req: Request<ReqBody>,
@@ -71,9 +77,15 @@ pub async fn handle_put_lifecycle(
 	}
 
 	let conf: LifecycleConfiguration = from_reader(&body as &[u8])?;
-	let config = conf
-		.validate_into_garage_lifecycle_config()
-		.ok_or_bad_request("Invalid lifecycle configuration")?;
+	let max_rules = garage
+		.config
+		.s3_api
+		.max_lifecycle_rules
+		.unwrap_or(DEFAULT_MAX_LIFECYCLE_RULES);
+	if conf.lifecycle_rules.len() > max_rules {
+		return Err(Error::TooManyConfigurations("lifecycle", max_rules));
+	}
+	let config = conf.validate_into_garage_lifecycle_config()?;
 
 	bucket_params.lifecycle_config.update(Some(config));
 	garage
@@ -92,7 +104,7 @@ pub async fn handle_put_lifecycle(
 pub struct LifecycleConfiguration {
 	#[serde(serialize_with = "xmlns_tag", skip_deserializing)]
 	pub xmlns: (),
-	#[serde(rename = "Rule")]
+	#[serde(rename = "Rule", default)]
 	pub lifecycle_rules: Vec<LifecycleRule>,
 }
 
@@ -136,13 +148,71 @@ pub struct AbortIncompleteMpu {
 	pub days: IntValue,
 }
 
+// ---- VALIDATION ----
+//
+// Lifecycle configurations are fully validated when they are written, so that
+// the lifecycle worker can rely on the stored rules. Errors give the path of
+// the offending element, e.g. `/LifecycleConfiguration/Rule/2/Expiration/Days`
+// for the third rule: the document is rejected with MalformedXML if its
+// structure is invalid, and with InvalidArgument if a value is invalid.
+
+/// Maximum length of the ID of a rule (same as AWS)
+const MAX_RULE_ID_LEN: usize = 255;
+
+fn malformed(path: &str, msg: &str) -> Error {
+	Error::InvalidXml(format!("{}: {}", path, msg))
+}
+
+fn invalid_argument(path: &str, msg: &str) -> Error {
+	Error::InvalidArgument(format!("{}: {}", path, msg))
+}
+
+/// Parse a number of days, which must be at least 1
+fn validate_days(days: &IntValue, path: &str) -> Result<usize, Error> {
+	match usize::try_from(days.0) {
+		Ok(days) if days >= 1 => Ok(days),
+		_ => Err(invalid_argument(path, "must be a positive integer")),
+	}
+}
+
+/// Parse a date in ISO 8601 format, which must be at midnight UTC,
+/// e.g. `2024-01-01T00:00:00Z` or `2024-01-01T00:00:00.000Z`
+fn validate_date(date: &str, path: &str) -> Result<chrono::NaiveDate, Error> {
+	let datetime = chrono::DateTime::parse_from_rfc3339(date).map_err(|_| {
+		invalid_argument(
+			path,
+			"must be a date in ISO 8601 format, e.g. 2024-01-01T00:00:00Z",
+		)
+	})?;
+	if datetime.offset().local_minus_utc() != 0 || datetime.time() != chrono::NaiveTime::MIN {
+		return Err(invalid_argument(path, "must be at midnight UTC"));
+	}
+	Ok(datetime.date_naive())
+}
+
 impl LifecycleConfiguration {
-	pub fn validate_into_garage_lifecycle_config(
-		self,
-	) -> Result<Vec<GarageLifecycleRule>, &'static str> {
+	pub fn validate_into_garage_lifecycle_config(self) -> Result<Vec<GarageLifecycleRule>, Error> {
+		if self.lifecycle_rules.is_empty() {
+			return Err(malformed(
+				"/LifecycleConfiguration",
+				"at least one <Rule> is required",
+			));
+		}
+
+		let mut ids = std::collections::HashSet::new();
 		let mut ret = vec![];
-		for rule in self.lifecycle_rules {
-			ret.push(rule.validate_into_garage_lifecycle_rule()?);
+		for (i, rule) in self.lifecycle_rules.into_iter().enumerate() {
+			let path = format!("/LifecycleConfiguration/Rule/{}", i);
+			let rule = rule.validate_into_garage_lifecycle_rule(&path)?;
+			if let Some(id) = &rule.id {
+				if !ids.insert(id.clone()) {
+					return Err(invalid_argument(
+						&format!("{}/ID", path),
+						"the ID of each rule must be unique",
+					));
+				}
+			}
+			ret.push(rule);
 		}
 		Ok(ret)
 	}
@@ -159,28 +229,65 @@ impl LifecycleConfiguration {
 }
 
 impl LifecycleRule {
-	pub fn validate_into_garage_lifecycle_rule(self) -> Result<GarageLifecycleRule, &'static str> {
+	pub fn validate_into_garage_lifecycle_rule(
+		self,
+		path: &str,
+	) -> Result<GarageLifecycleRule, Error> {
+		let id = match self.id {
+			Some(id) if id.0.len() > MAX_RULE_ID_LEN => {
+				return Err(invalid_argument(
+					&format!("{}/ID", path),
+					"must be at most 255 characters long",
+				))
+			}
+			Some(id) if !id.0.is_empty() => Some(id.0),
+			_ => None,
+		};
+
 		let enabled = match self.status.0.as_str() {
 			"Enabled" => true,
 			"Disabled" => false,
-			_ => return Err("invalid value for <Status>"),
+			_ => {
+				return Err(malformed(
+					&format!("{}/Status", path),
+					"must be Enabled or Disabled",
+				))
+			}
 		};
 
 		let filter = self
 			.filter
-			.map(Filter::validate_into_garage_lifecycle_filter)
+			.map(|f| f.validate_into_garage_lifecycle_filter(&format!("{}/Filter", path)))
 			.transpose()?
 			.unwrap_or_default();
 
-		let abort_incomplete_mpu_days = self.abort_incomplete_mpu.map(|x| x.days.0 as usize);
+		let abort_incomplete_mpu_days = self
+			.abort_incomplete_mpu
+			.map(|x| {
+				validate_days(
+					&x.days,
+					&format!(
+						"{}/AbortIncompleteMultipartUpload/DaysAfterInitiation",
+						path
+					),
+				)
+			})
+			.transpose()?;
 
 		let expiration = self
 			.expiration
-			.map(Expiration::validate_into_garage_lifecycle_expiration)
+			.map(|e| e.validate_into_garage_lifecycle_expiration(&format!("{}/Expiration", path)))
 			.transpose()?;
 
+		if expiration.is_none() && abort_incomplete_mpu_days.is_none() {
+			return Err(invalid_argument(
+				path,
+				"at least one action (<Expiration> or <AbortIncompleteMultipartUpload>) is required",
+			));
+		}
+
 		Ok(GarageLifecycleRule {
-			id: self.id.map(|x| x.0),
+			id,
 			enabled,
 			filter,
 			abort_incomplete_mpu_days,
@@ -220,27 +327,61 @@ impl Filter {
 
 	pub fn validate_into_garage_lifecycle_filter(
 		self,
-	) -> Result<GarageLifecycleFilter, &'static str> {
+		path: &str,
+	) -> Result<GarageLifecycleFilter, Error> {
 		if self.count() > 0 && self.and.is_some() {
-			Err("Filter tag cannot contain both <And> and another condition")
+			Err(malformed(
+				path,
+				"cannot contain both <And> and another condition",
+			))
 		} else if let Some(and) = self.and {
+			let path = format!("{}/And", path);
 			if and.and.is_some() {
-				return Err("Nested <And> tags");
+				return Err(malformed(&path, "<And> tags cannot be nested"));
 			}
-			Ok(and.internal_into_garage_lifecycle_filter())
+			if and.count() < 2 {
+				return Err(malformed(&path, "must contain at least two conditions"));
+			}
+			and.internal_into_garage_lifecycle_filter(&path)
 		} else if self.count() > 1 {
-			Err("Multiple Filter conditions must be wrapped in an <And> tag")
+			Err(malformed(
+				path,
+				"multiple conditions must be wrapped in an <And> tag",
+			))
 		} else {
-			Ok(self.internal_into_garage_lifecycle_filter())
+			self.internal_into_garage_lifecycle_filter(path)
 		}
 	}
 
-	fn internal_into_garage_lifecycle_filter(self) -> GarageLifecycleFilter {
-		GarageLifecycleFilter {
-			prefix: self.prefix.map(|x| x.0),
-			size_gt: self.size_gt.map(|x| x.0 as u64),
-			size_lt: self.size_lt.map(|x| x.0 as u64),
+	fn internal_into_garage_lifecycle_filter(
+		self,
+		path: &str,
+	) -> Result<GarageLifecycleFilter, Error> {
+		let size = |size: Option<IntValue>, name: &str| {
+			size.map(|x| {
+				u64::try_from(x.0).map_err(|_| {
+					invalid_argument(&format!("{}/{}", path, name), "must not be negative")
+				})
+			})
+			.transpose()
+		};
+		let size_gt = size(self.size_gt, "ObjectSizeGreaterThan")?;
+		let size_lt = size(self.size_lt, "ObjectSizeLessThan")?;
+		if let (Some(gt), Some(lt)) = (size_gt, size_lt) {
+			if gt >= lt {
+				return Err(invalid_argument(
+					&format!("{}/ObjectSizeLessThan", path),
+					"must be greater than ObjectSizeGreaterThan",
+				));
+			}
 		}
+
+		Ok(GarageLifecycleFilter {
+			// An empty prefix matches all objects
+			prefix: self.prefix.map(|x| x.0).filter(|p| !p.is_empty()),
+			size_gt,
+			size_lt,
+		})
 	}
 
 	pub fn from_garage_lifecycle_filter(rule: &GarageLifecycleFilter) -> Option<Self> {
@@ -264,14 +405,21 @@ impl Filter {
 impl Expiration {
 	pub fn validate_into_garage_lifecycle_expiration(
 		self,
-	) -> Result<GarageLifecycleExpiration, &'static str> {
+		path: &str,
+	) -> Result<GarageLifecycleExpiration, Error> {
 		match (self.days, self.at_date) {
-			(Some(_), Some(_)) => Err("cannot have both <Days> and <Date> in <Expiration>"),
-			(None, None) => Err("<Expiration> must contain either <Days> or <Date>"),
-			(Some(days), None) => Ok(GarageLifecycleExpiration::AfterDays(days.0 as usize)),
+			(Some(_), Some(_)) => Err(malformed(path, "cannot contain both <Days> and <Date>")),
+			(None, None) => Err(malformed(path, "must contain either <Days> or <Date>")),
+			(Some(days), None) => Ok(GarageLifecycleExpiration::AfterDays(validate_days(
+				&days,
+				&format!("{}/Days", path),
+			)?)),
 			(None, Some(date)) => {
-				parse_lifecycle_date(&date.0)?;
-				Ok(GarageLifecycleExpiration::AtDate(date.0))
+				// Dates are stored in the yyyy-mm-dd format
+				let date = validate_date(&date.0, &format!("{}/Date", path))?;
+				Ok(GarageLifecycleExpiration::AtDate(
+					date.format("%Y-%m-%d").to_string(),
+				))
 			}
 		}
 	}
@@ -284,7 +432,10 @@ impl Expiration {
 			},
 			GarageLifecycleExpiration::AtDate(date) => Expiration {
 				days: None,
-				at_date: Some(Value(date.to_string())),
+				at_date: Some(Value(match parse_lifecycle_date(date) {
+					Ok(date) => date.format("%Y-%m-%dT00:00:00Z").to_string(),
+					Err(_) => date.to_string(),
+				})),
 			},
 		}
 	}
@@ -368,9 +519,7 @@ mod tests {
 		assert_eq!(cleanup(message), cleanup(&message2));
 
 		// Check validation
-		let validated = ref_value
-			.validate_into_garage_lifecycle_config()
-			.ok_or_bad_request("invalid xml config")?;
+		let validated = ref_value.validate_into_garage_lifecycle_config()?;
 
 		let ref_config = vec![
 			GarageLifecycleRule {
@@ -404,4 +553,232 @@ mod tests {
 
 		Ok(())
 	}
+
+	fn validate(rules: &str) -> Result<Vec<GarageLifecycleRule>, Error> {
+		let message = format!(
+			r#"<LifecycleConfiguration xmlns="http://s3.amazonaws.com/doc/2006-03-01/">{}</LifecycleConfiguration>"#,
+			rules
+		);
+		let conf: LifecycleConfiguration = from_str(&message).unwrap();
+		conf.validate_into_garage_lifecycle_config()
+	}
+
+	fn assert_rejected(rules: &str, code: &str, path: &str) {
+		match validate(rules) {
+			Ok(r) => panic!("configuration should be rejected: {:?}", r),
+			Err(e) => {
+				assert_eq!(e.aws_code(), code, "{}", e);
+				assert!(e.to_string().contains(&format!("{}:", path)), "{}", e);
+			}
+		}
+	}
+
+	const EXPIRE: &str = "<Expiration><Days>1</Days></Expiration>";
+
+	#[test]
+	fn test_reject_invalid_structure() {
+		assert_rejected("", "MalformedXML", "/LifecycleConfiguration");
+		assert_rejected(
+			&format!("<Rule><Status>Maybe</Status>{}</Rule>", EXPIRE),
+			"MalformedXML",
+			"/LifecycleConfiguration/Rule/0/Status",
+		);
+		assert_rejected(
+			"<Rule><Status>Enabled</Status><Expiration><Days>1</Days><Date>2024-01-01T00:00:00Z</Date></Expiration></Rule>",
+			"MalformedXML",
+			"/LifecycleConfiguration/Rule/0/Expiration",
+		);
+		assert_rejected(
+			"<Rule><Status>Enabled</Status><Expiration></Expiration></Rule>",
+			"MalformedXML",
+			"/LifecycleConfiguration/Rule/0/Expiration",
+		);
+	}
+
+	#[test]
+	fn test_reject_invalid_filters() {
+		let rule = |filter: &str| {
+			format!(
+				"<Rule><Status>Enabled</Status>{}</Rule><Rule><Status>Enabled</Status><Filter>{}</Filter>{}</Rule>",
+				EXPIRE, filter, EXPIRE
+			)
+		};
+		assert_rejected(
+			&rule("<Prefix>a/</Prefix><And><Prefix>b/</Prefix><ObjectSizeLessThan>10</ObjectSizeLessThan></And>"),
+			"MalformedXML",
+			"/LifecycleConfiguration/Rule/1/Filter",
+		);
+		assert_rejected(
+			&rule("<Prefix>a/</Prefix><ObjectSizeLessThan>10</ObjectSizeLessThan>"),
+			"MalformedXML",
+			"/LifecycleConfiguration/Rule/1/Filter",
+		);
+		assert_rejected(
+			&rule("<And><Prefix>a/</Prefix></And>"),
+			"MalformedXML",
+			"/LifecycleConfiguration/Rule/1/Filter/And",
+		);
+		assert_rejected(
+			&rule("<And><And><Prefix>a/</Prefix><ObjectSizeLessThan>10</ObjectSizeLessThan></And></And>"),
+			"MalformedXML",
+			"/LifecycleConfiguration/Rule/1/Filter/And",
+		);
+		assert_rejected(
+			&rule("<ObjectSizeGreaterThan>-1</ObjectSizeGreaterThan>"),
+			"InvalidArgument",
+			"/LifecycleConfiguration/Rule/1/Filter/ObjectSizeGreaterThan",
+		);
+		assert_rejected(
+			&rule("<And><ObjectSizeGreaterThan>10</ObjectSizeGreaterThan><ObjectSizeLessThan>10</ObjectSizeLessThan></And>"),
+			"InvalidArgument",
+			"/LifecycleConfiguration/Rule/1/Filter/And/ObjectSizeLessThan",
+		);
+	}
+
+	#[test]
+	fn test_reject_invalid_values() {
+		assert_rejected(
+			&format!(
+				"<Rule><ID>a</ID><Status>Enabled</Status>{}</Rule><Rule><ID>a</ID><Status>Disabled</Status>{}</Rule>",
+				EXPIRE, EXPIRE
+			),
+			"InvalidArgument",
+			"/LifecycleConfiguration/Rule/1/ID",
+		);
+		assert_rejected(
+			&format!(
+				"<Rule><ID>{}</ID><Status>Enabled</Status>{}</Rule>",
+				"x".repeat(256),
+				EXPIRE
+			),
+			"InvalidArgument",
+			"/LifecycleConfiguration/Rule/0/ID",
+		);
+		assert_rejected(
+			"<Rule><Status>Enabled</Status></Rule>",
+			"InvalidArgument",
+			"/LifecycleConfiguration/Rule/0",
+		);
+		for days in ["0", "-3"] {
+			assert_rejected(
+				&format!(
+					"<Rule><Status>Enabled</Status><Expiration><Days>{}</Days></Expiration></Rule>",
+					days
+				),
+				"InvalidArgument",
+				"/LifecycleConfiguration/Rule/0/Expiration/Days",
+			);
+			assert_rejected(
+				&format!(
+					"<Rule><Status>Enabled</Status><AbortIncompleteMultipartUpload><DaysAfterInitiation>{}</DaysAfterInitiation></AbortIncompleteMultipartUpload></Rule>",
+					days
+				),
+				"InvalidArgument",
+				"/LifecycleConfiguration/Rule/0/AbortIncompleteMultipartUpload/DaysAfterInitiation",
+			);
+		}
+		for date in [
+			"2024-01-01",
+			"2024-01-01T12:00:00Z",
+			"2024-01-01T00:00:00+02:00",
+			"2024-02-30T00:00:00Z",
+			"tomorrow",
+		] {
+			assert_rejected(
+				&format!(
+					"<Rule><Status>Enabled</Status><Expiration><Date>{}</Date></Expiration></Rule>",
+					date
+				),
+				"InvalidArgument",
+				"/LifecycleConfiguration/Rule/0/Expiration/Date",
+			);
+		}
+	}
+
+	#[test]
+	fn test_maximal_lifecycle_config() -> Result<(), Error> {
+		let message = r#"<?xml version="1.0" encoding="UTF-8"?>
+<LifecycleConfiguration xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+  <Rule>
+    <ID>expire-old-logs</ID>
+    <Status>Enabled</Status>
+    <Filter>
+       <And>
+          <Prefix>logs/</Prefix>
+          <ObjectSizeGreaterThan>1024</ObjectSizeGreaterThan>
+          <ObjectSizeLessThan>1048576</ObjectSizeLessThan>
+       </And>
+    </Filter>
+    <Expiration>
+      <Date>2030-06-01T00:00:00Z</Date>
+    </Expiration>
+    <AbortIncompleteMultipartUpload>
+       <DaysAfterInitiation>3</DaysAfterInitiation>
+    </AbortIncompleteMultipartUpload>
+  </Rule>
+  <Rule>
+    <ID>disabled</ID>
+    <Status>Disabled</Status>
+    <Filter>
+       <ObjectSizeLessThan>10</ObjectSizeLessThan>
+    </Filter>
+    <Expiration>
+      <Days>30</Days>
+    </Expiration>
+  </Rule>
+</LifecycleConfiguration>"#;
+		let conf: LifecycleConfiguration = from_str(message).unwrap();
+		let validated = conf.validate_into_garage_lifecycle_config()?;
+
+		let ref_config = vec![
+			GarageLifecycleRule {
+				id: Some("expire-old-logs".into()),
+				enabled: true,
+				filter: GarageLifecycleFilter {
+					prefix: Some("logs/".into()),
+					size_gt: Some(1024),
+					size_lt: Some(1048576),
+				},
+				expiration: Some(GarageLifecycleExpiration::AtDate("2030-06-01".into())),
+				abort_incomplete_mpu_days: Some(3),
+			},
+			GarageLifecycleRule {
+				id: Some("disabled".into()),
+				enabled: false,
+				filter: GarageLifecycleFilter {
+					size_lt: Some(10),
+					..Default::default()
+				},
+				expiration: Some(GarageLifecycleExpiration::AfterDays(30)),
+				abort_incomplete_mpu_days: None,
+			},
+		];
+		assert_eq!(validated, ref_config);
+
+		// The stored form is rendered back as the original document
+		let rendered = LifecycleConfiguration::from_garage_lifecycle_config(&validated);
+		let cleanup = |c: &str| c.replace(char::is_whitespace, "");
+		assert_eq!(cleanup(message), cleanup(&to_xml_with_header(&rendered)?));
+		assert_eq!(
+			rendered.validate_into_garage_lifecycle_config()?,
+			ref_config
+		);
+
+		// Equivalent forms are normalized
+		let normalized = validate(
+			"<Rule><Status>Enabled</Status><Expiration><Date>2030-06-01T00:00:00.000Z</Date></Expiration></Rule>",
+		)?;
+		assert_eq!(
+			normalized,
+			vec![GarageLifecycleRule {
+				id: None,
+				enabled: true,
+				filter: GarageLifecycleFilter::default(),
+				expiration: Some(GarageLifecycleExpiration::AtDate("2030-06-01".into())),
+				abort_incomplete_mpu_days: None,
+			}]
+		);
+
+		Ok(())
+	}
 }
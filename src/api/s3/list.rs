@@ -1,8 +1,12 @@
 use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Write};
 use std::iter::{Iterator, Peekable};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use base64::prelude::*;
-use hyper::{Request, Response};
+use bytes::Bytes;
+use futures::{future, stream, Stream, StreamExt};
+use hyper::{HeaderMap, Request, Response};
 
 use garage_util::data::*;
 use garage_util::error::Error as GarageError;
@@ -11,7 +15,7 @@ use garage_util::time::*;
 use garage_model::s3::mpu_table::*;
 use garage_model::s3::object_table::*;
 
-use garage_table::EnumerationOrder;
+use garage_table::{EmptyKey, EnumerationOrder};
 
 use crate::encoding::*;
 use crate::helpers::*;
@@ -24,6 +28,31 @@ use crate::s3::xml as s3_xml;
 const DUMMY_NAME: &str = "Dummy Key";
 const DUMMY_KEY: &str = "GKDummyKey";
 
+/// Default maximum size of the entries of a ListObjects response
+pub const DEFAULT_MAX_LIST_RESPONSE_SIZE: usize = 10 * 1024 * 1024;
+
+/// Default maximum number of parts returned by a ListParts request
+pub const DEFAULT_MAX_LIST_PARTS: u64 = 1000;
+/// Maximum number of parts of a multipart upload
+pub const MAX_PARTS: u64 = 10000;
+
+// Number of parts serialized in each chunk of a ListParts response body
+const LIST_PARTS_CHUNK: u64 = 100;
+
+/// Garage extension: only list objects modified at or after this date
+pub const X_GARAGE_MODIFIED_SINCE: &str = "x-garage-modified-since";
+
+/// Garage extension: if set to `true`, a ListObjects request that cannot
+/// reach a read quorum is answered with the entries of a single node
+pub const X_GARAGE_ALLOW_DEGRADED_READ: &str = "x-garage-allow-degraded-read";
+/// Set to `true` on ListObjects responses built from the entries of a
+/// single node, which might not include the latest writes
+pub const X_GARAGE_DEGRADED: &str = "x-garage-degraded";
+
+// Maximum number of objects skipped because of x-garage-modified-since
+// in a single ListObjects request, after which the response is truncated
+const MAX_SKIPPED_UNMODIFIED: usize = 10000;
+
 #[derive(Debug)]
 pub struct ListQueryCommon {
 	pub bucket_name: String,
@@ -32,6 +61,15 @@ pub struct ListQueryCommon {
 	pub page_size: usize,
 	pub prefix: String,
 	pub urlencode_resp: bool,
+	/// Omit the folder marker whose key is exactly the listed prefix
+	/// (`directory` mode for directory markers)
+	pub skip_directory_marker: bool,
+	/// Maximum size in bytes of the entries of the response, above which
+	/// the listing is truncated even if `page_size` is not reached
+	pub max_response_size: Option<usize>,
+	/// Only list objects whose last modification is at or after this
+	/// timestamp, in msec (`x-garage-modified-since`, ListObjects only)
+	pub modified_since: Option<u64>,
 }
 
 #[derive(Debug)]
@@ -40,6 +78,9 @@ pub struct ListObjectsQuery {
 	pub marker: Option<String>,
 	pub continuation_token: Option<String>,
 	pub start_after: Option<String>,
+	/// Fall back to a single node if a read quorum cannot be reached
+	/// (`x-garage-allow-degraded-read`, or the bucket's default)
+	pub allow_degraded_read: bool,
 	pub common: ListQueryCommon,
 }
 
@@ -60,22 +101,67 @@ pub struct ListPartsQuery {
 	pub max_parts: u64,
 }
 
+/// Parse the `x-garage-modified-since` header of a ListObjects request: an
+/// RFC 3339 date, such as the `LastModified` date of a listed object
+pub fn parse_modified_since(headers: &HeaderMap) -> Result<Option<u64>, Error> {
+	let value = match headers.get(X_GARAGE_MODIFIED_SINCE) {
+		Some(v) => v.to_str()?,
+		None => return Ok(None),
+	};
+	let date = chrono::DateTime::parse_from_rfc3339(value)
+		.ok_or_bad_request("Invalid x-garage-modified-since header")?;
+	Ok(Some(std::cmp::max(date.timestamp_millis(), 0) as u64))
+}
+
+/// Parse the `x-garage-allow-degraded-read` header of a ListObjects request,
+/// which overrides the default of the bucket when present
+pub fn parse_allow_degraded_read(headers: &HeaderMap) -> Result<Option<bool>, Error> {
+	match headers.get(X_GARAGE_ALLOW_DEGRADED_READ) {
+		Some(v) => match v.to_str()? {
+			"true" => Ok(Some(true)),
+			"false" => Ok(Some(false)),
+			_ => Err(Error::bad_request(
+				"Invalid x-garage-allow-degraded-read header",
+			)),
+		},
+		None => Ok(None),
+	}
+}
+
 pub async fn handle_list(
 	ctx: ReqCtx,
 	query: &ListObjectsQuery,
 ) -> Result<Response<ResBody>, Error> {
 	let ReqCtx { garage, .. } = &ctx;
+	let degraded = AtomicBool::new(false);
 	let io = |bucket, key, count| {
 		let t = &garage.object_table;
+		let degraded = &degraded;
 		async move {
-			t.get_range(
-				&bucket,
-				key,
-				Some(ObjectFilter::IsData),
-				count,
-				EnumerationOrder::Forward,
-			)
-			.await
+			if query.allow_degraded_read {
+				let (objects, is_degraded) = t
+					.get_range_allow_degraded(
+						&bucket,
+						key,
+						Some(ObjectFilter::IsData),
+						count,
+						EnumerationOrder::Forward,
+					)
+					.await?;
+				if is_degraded {
+					degraded.store(true, Ordering::Relaxed);
+				}
+				Ok::<_, GarageError>(objects)
+			} else {
+				t.get_range(
+					&bucket,
+					key,
+					Some(ObjectFilter::IsData),
+					count,
+					EnumerationOrder::Forward,
+				)
+				.await
+			}
 		}
 	};
 
@@ -139,31 +225,21 @@ pub async fn handle_list(
 			_ => None,
 		},
 
-		// Body
-		contents: acc
-			.keys
-			.iter()
-			.map(|(key, info)| s3_xml::ListBucketItem {
-				key: uriencode_maybe(key, query.common.urlencode_resp),
-				last_modified: s3_xml::Value(msec_to_rfc3339(info.last_modified)),
-				size: s3_xml::IntValue(info.size as i64),
-				etag: s3_xml::Value(format!("\"{}\"", info.etag)),
-				storage_class: s3_xml::Value("STANDARD".to_string()),
-			})
-			.collect(),
-		common_prefixes: acc
-			.common_prefixes
-			.iter()
-			.map(|pfx| s3_xml::CommonPrefix {
-				prefix: uriencode_maybe(pfx, query.common.urlencode_resp),
-			})
-			.collect(),
+		// Body, written by `list_objects_xml`
+		contents: vec![],
+		common_prefixes: vec![],
 	};
 
-	let xml = s3_xml::to_xml_with_header(&result)?;
-	Ok(Response::builder()
-		.header("Content-Type", "application/xml")
-		.body(string_body(xml))?)
+	let xml = list_objects_xml(&result, &acc, query.common.urlencode_resp)?;
+	let mut resp = Response::builder().header("Content-Type", "application/xml");
+	if degraded.load(Ordering::Relaxed) {
+		warn!(
+			"ListObjects on bucket {:?} answered from a single node, read quorum could not be reached",
+			query.common.bucket_id
+		);
+		resp = resp.header(X_GARAGE_DEGRADED, "true");
+	}
+	Ok(resp.body(string_body(xml))?)
 }
 
 pub async fn handle_list_multipart_upload(
@@ -192,6 +268,15 @@ pub async fn handle_list_multipart_upload(
 	let mut acc = query.build_accumulator();
 	let pagination = fetch_list_entries(&query.common, query.begin()?, &mut acc, &io).await?;
 
+	// The activity timestamps are informative, so they are read from a
+	// single node instead of a quorum
+	let last_activity = futures::future::try_join_all(
+		acc.keys
+			.keys()
+			.map(|uuid| garage.mpu_table.get_single(uuid, &EmptyKey)),
+	)
+	.await?;
+
 	let result = s3_xml::ListMultipartUploadsResult {
 		xmlns: (),
 
@@ -239,11 +324,13 @@ pub async fn handle_list_multipart_upload(
 		upload: acc
 			.keys
 			.iter()
-			.map(|(uuid, info)| s3_xml::ListMultipartItem {
+			.zip(last_activity)
+			.map(|((uuid, info), mpu)| s3_xml::ListMultipartItem {
 				initiated: s3_xml::Value(msec_to_rfc3339(info.timestamp)),
 				key: uriencode_maybe(&info.key, query.common.urlencode_resp),
 				upload_id: s3_xml::Value(hex::encode(uuid)),
 				storage_class: s3_xml::Value("STANDARD".to_string()),
+				last_activity: mpu.map(|m| s3_xml::Value(msec_to_rfc3339(m.last_activity))),
 				initiator: s3_xml::Initiator {
 					display_name: s3_xml::Value(DUMMY_NAME.to_string()),
 					id: s3_xml::Value(DUMMY_KEY.to_string()),
@@ -288,7 +375,12 @@ pub async fn handle_list_parts(
 	let encryption_res =
 		EncryptionParams::check_decrypt(&ctx.garage, req.headers(), &object_encryption);
 
-	let (info, next) = fetch_part_info(query, &mpu)?;
+	// hide checksums if object is encrypted and the decryption
+	// keys are not provided
+	let show_checksums = encryption_res.is_ok();
+
+	let (info, next) = fetch_part_info(&mpu, query.part_number_marker, query.max_parts)?;
+	let part_count = info.len() as u64;
 
 	let result = s3_xml::ListPartsResult {
 		xmlns: (),
@@ -303,44 +395,8 @@ pub async fn handle_list_parts(
 		// Result values
 		next_part_number_marker: next.map(|e| s3_xml::IntValue(e as i64)),
 		is_truncated: s3_xml::Value(format!("{}", next.is_some())),
-		parts: info
-			.iter()
-			.map(|part| {
-				// hide checksum if object is encrypted and the decryption
-				// keys are not provided
-				let checksum = part.checksum.filter(|_| encryption_res.is_ok());
-				s3_xml::PartItem {
-					etag: s3_xml::Value(format!("\"{}\"", part.etag)),
-					last_modified: s3_xml::Value(msec_to_rfc3339(part.timestamp)),
-					part_number: s3_xml::IntValue(part.part_number as i64),
-					size: s3_xml::IntValue(part.size as i64),
-					checksum_crc32: match &checksum {
-						Some(ChecksumValue::Crc32(x)) => {
-							Some(s3_xml::Value(BASE64_STANDARD.encode(&x)))
-						}
-						_ => None,
-					},
-					checksum_crc32c: match &checksum {
-						Some(ChecksumValue::Crc32c(x)) => {
-							Some(s3_xml::Value(BASE64_STANDARD.encode(&x)))
-						}
-						_ => None,
-					},
-					checksum_sha1: match &checksum {
-						Some(ChecksumValue::Sha1(x)) => {
-							Some(s3_xml::Value(BASE64_STANDARD.encode(&x)))
-						}
-						_ => None,
-					},
-					checksum_sha256: match &checksum {
-						Some(ChecksumValue::Sha256(x)) => {
-							Some(s3_xml::Value(BASE64_STANDARD.encode(&x)))
-						}
-						_ => None,
-					},
-				}
-			})
-			.collect(),
+		// Serialized as the response body is sent, see below
+		parts: vec![],
 
 		// Dummy result values (unsupported features)
 		initiator: s3_xml::Initiator {
@@ -353,12 +409,112 @@ pub async fn handle_list_parts(
 		},
 		storage_class: s3_xml::Value("STANDARD".to_string()),
 	};
-
-	let xml = s3_xml::to_xml_with_header(&result)?;
+	let (head, tail) = split_list_parts_xml(&result)?;
+
+	let body_stream = list_parts_stream(
+		head,
+		tail,
+		mpu,
+		query.part_number_marker,
+		part_count,
+		show_checksums,
+	)
+	.map(|x| x.map(hyper::body::Frame::data));
 
 	Ok(Response::builder()
 		.header("Content-Type", "application/xml")
-		.body(string_body(xml))?)
+		.body(ResBody::new(http_body_util::StreamBody::new(body_stream)))?)
+}
+
+/// Split the serialization of a ListParts result without parts into what
+/// goes before and after the `Part` elements
+fn split_list_parts_xml(result: &s3_xml::ListPartsResult) -> Result<(String, String), Error> {
+	// `Initiator` is the first element after the parts in the result
+	const PARTS_END: &str = "<Initiator>";
+
+	let mut head = s3_xml::to_xml_with_header(result)?;
+	let split = head
+		.find(PARTS_END)
+		.ok_or_internal_error("unexpected ListPartsResult serialization")?;
+	let tail = head.split_off(split);
+	Ok((head, tail))
+}
+
+/// Stream the body of a ListParts response, serializing the `part_count`
+/// parts after `marker` in chunks as the body is sent, instead of building
+/// the whole response in memory for uploads with many parts
+fn list_parts_stream(
+	head: String,
+	tail: String,
+	mpu: MultipartUpload,
+	marker: Option<u64>,
+	part_count: u64,
+	show_checksums: bool,
+) -> impl Stream<Item = Result<Bytes, Error>> + Send + Sync + 'static {
+	let parts = stream::unfold(
+		(mpu, marker, part_count),
+		move |(mpu, marker, remaining)| {
+			let item = match remaining {
+				0 => None,
+				_ => {
+					let max_parts = std::cmp::min(remaining, LIST_PARTS_CHUNK);
+					match list_parts_chunk_xml(&mpu, marker, max_parts, show_checksums) {
+						Ok((xml, 0, _)) => Some((Ok(Bytes::from(xml)), (mpu, marker, 0))),
+						Ok((xml, n, last)) => {
+							Some((Ok(Bytes::from(xml)), (mpu, last, remaining - n)))
+						}
+						Err(e) => Some((Err(e), (mpu, marker, 0))),
+					}
+				}
+			};
+			future::ready(item)
+		},
+	);
+	stream::once(future::ready(Ok(Bytes::from(head))))
+		.chain(parts)
+		.chain(stream::once(future::ready(Ok(Bytes::from(tail)))))
+}
+
+/// Serialize the `Part` elements of at most `max_parts` parts after `marker`,
+/// returning them with the number of parts and the number of the last one
+fn list_parts_chunk_xml(
+	mpu: &MultipartUpload,
+	marker: Option<u64>,
+	max_parts: u64,
+	show_checksums: bool,
+) -> Result<(String, u64, Option<u64>), Error> {
+	let (parts, _) = fetch_part_info(mpu, marker, max_parts)?;
+	let mut xml = String::new();
+	for part in parts.iter() {
+		write_part_item(&mut xml, part, show_checksums)
+			.ok_or_internal_error("could not serialize ListParts entry")?;
+	}
+	let last = parts.last().map(|p| p.part_number);
+	Ok((xml, parts.len() as u64, last))
+}
+
+/// Write the `Part` element of a part in a ListParts response
+fn write_part_item<W: Write>(w: &mut W, part: &PartInfo, show_checksums: bool) -> fmt::Result {
+	w.write_str("<Part><ETag>&quot;")?;
+	s3_xml::write_escaped(w, part.etag)?;
+	w.write_str("&quot;</ETag><LastModified>")?;
+	s3_xml::write_timestamp(w, part.timestamp)?;
+	write!(
+		w,
+		"</LastModified><PartNumber>{}</PartNumber><Size>{}</Size>",
+		part.part_number, part.size
+	)?;
+	let checksum = match part.checksum.filter(|_| show_checksums) {
+		Some(ChecksumValue::Crc32(x)) => Some(("ChecksumCRC32", BASE64_STANDARD.encode(x))),
+		Some(ChecksumValue::Crc32c(x)) => Some(("ChecksumCRC32C", BASE64_STANDARD.encode(x))),
+		Some(ChecksumValue::Sha1(x)) => Some(("ChecksumSHA1", BASE64_STANDARD.encode(x))),
+		Some(ChecksumValue::Sha256(x)) => Some(("ChecksumSHA256", BASE64_STANDARD.encode(x))),
+		None => None,
+	};
+	if let Some((name, value)) = checksum {
+		write!(w, "<{}>{}</{}>", name, value, name)?;
+	}
+	w.write_str("</Part>")
 }
 
 /*
@@ -370,6 +526,8 @@ struct ObjectInfo {
 	last_modified: u64,
 	size: u64,
 	etag: String,
+	/// Key with its original casing, in buckets with case-insensitive keys
+	original_key: Option<String>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -509,15 +667,16 @@ where
 	}
 }
 
-fn fetch_part_info<'a>(
-	query: &ListPartsQuery,
-	mpu: &'a MultipartUpload,
-) -> Result<(Vec<PartInfo<'a>>, Option<u64>), Error> {
-	assert!((1..=1000).contains(&query.max_parts)); // see s3/api_server.rs
+fn fetch_part_info(
+	mpu: &MultipartUpload,
+	part_number_marker: Option<u64>,
+	max_parts: u64,
+) -> Result<(Vec<PartInfo<'_>>, Option<u64>), Error> {
+	assert!(max_parts >= 1); // see s3/api_server.rs
 
 	// Parse multipart upload part list, removing parts not yet finished
 	// and failed part uploads that were overwritten
-	let mut parts: Vec<PartInfo<'a>> = Vec::with_capacity(mpu.parts.items().len());
+	let mut parts: Vec<PartInfo> = Vec::with_capacity(mpu.parts.items().len());
 	for (pk, p) in mpu.parts.items().iter() {
 		if let (Some(etag), Some(size)) = (&p.etag, p.size) {
 			let part_info = PartInfo {
@@ -539,7 +698,7 @@ fn fetch_part_info<'a>(
 	}
 
 	// Cut the beginning if we have a marker
-	if let Some(marker) = &query.part_number_marker {
+	if let Some(marker) = &part_number_marker {
 		let next = marker + 1;
 		let part_idx = parts
 			.binary_search_by(|part| part.part_number.cmp(&next))
@@ -548,8 +707,8 @@ fn fetch_part_info<'a>(
 	}
 
 	// Cut the end if we have too many parts
-	if parts.len() > query.max_parts as usize {
-		parts.truncate(query.max_parts as usize);
+	if parts.len() > max_parts as usize {
+		parts.truncate(max_parts as usize);
 		let pagination = Some(parts.last().unwrap().part_number);
 		return Ok((parts, pagination));
 	}
@@ -569,7 +728,7 @@ fn fetch_part_info<'a>(
 /// points in the dataset if we are continuing a previous listing.
 impl ListObjectsQuery {
 	fn build_accumulator(&self) -> ObjectAccumulator {
-		ObjectAccumulator::new(self.common.page_size)
+		ObjectAccumulator::new(self.common.page_size, self.common.max_response_size)
 	}
 
 	fn begin(&self) -> Result<RangeBegin, Error> {
@@ -631,7 +790,7 @@ impl ListObjectsQuery {
 
 impl ListMultipartUploadsQuery {
 	fn build_accumulator(&self) -> UploadAccumulator {
-		UploadAccumulator::new(self.common.page_size)
+		UploadAccumulator::new(self.common.page_size, None)
 	}
 
 	fn begin(&self) -> Result<RangeBegin, Error> {
@@ -684,17 +843,25 @@ struct Accumulator<K, V> {
 	common_prefixes: BTreeSet<String>,
 	keys: BTreeMap<K, V>,
 	max_capacity: usize,
+	/// Size of the XML serialization of the entries in the accumulator
+	size: usize,
+	max_size: Option<usize>,
+	/// Number of entries skipped because they did not match a filter
+	skipped: usize,
 }
 
 type ObjectAccumulator = Accumulator<String, ObjectInfo>;
 type UploadAccumulator = Accumulator<Uuid, UploadInfo>;
 
 impl<K: std::cmp::Ord, V> Accumulator<K, V> {
-	fn new(page_size: usize) -> Accumulator<K, V> {
+	fn new(page_size: usize, max_size: Option<usize>) -> Accumulator<K, V> {
 		Accumulator {
 			common_prefixes: BTreeSet::<String>::new(),
 			keys: BTreeMap::<K, V>::new(),
 			max_capacity: page_size,
+			size: 0,
+			max_size,
+			skipped: 0,
 		}
 	}
 
@@ -719,7 +886,8 @@ impl<K: std::cmp::Ord, V> Accumulator<K, V> {
 
 		// Try to register this prefix
 		// If not possible, we can return early
-		if !self.try_insert_common_prefix(pfx.to_string()) {
+		let size = xml_size(|w| write_common_prefix(w, pfx, query.urlencode_resp));
+		if !self.try_insert_common_prefix(pfx.to_string(), size) {
 			return Some(ExtractionResult::Filled);
 		}
 
@@ -753,29 +921,43 @@ impl<K: std::cmp::Ord, V> Accumulator<K, V> {
 		self.keys.len() + self.common_prefixes.len() >= self.max_capacity
 	}
 
-	fn try_insert_common_prefix(&mut self, key: String) -> bool {
+	/// Check that an entry whose XML serialization is `size` bytes long
+	/// can be added without exceeding the maximum response size.
+	/// The first entry is always accepted so that listings can make progress.
+	fn has_room_for(&self, size: usize) -> bool {
+		match self.max_size {
+			Some(max) => {
+				(self.keys.is_empty() && self.common_prefixes.is_empty()) || self.size + size <= max
+			}
+			None => true,
+		}
+	}
+
+	fn try_insert_common_prefix(&mut self, key: String, size: usize) -> bool {
 		// If we already have an entry, we can continue
 		if self.common_prefixes.contains(&key) {
 			return true;
 		}
 
 		// Otherwise, we need to check if we can add it
-		if self.is_full() {
+		if self.is_full() || !self.has_room_for(size) {
 			false
 		} else {
 			self.common_prefixes.insert(key);
+			self.size += size;
 			true
 		}
 	}
 
-	fn try_insert_entry(&mut self, key: K, value: V) -> bool {
+	fn try_insert_entry(&mut self, key: K, value: V, size: usize) -> bool {
 		// It is impossible to add twice a key, this is an error
 		assert!(!self.keys.contains_key(&key));
 
-		if self.is_full() {
+		if self.is_full() || !self.has_room_for(size) {
 			false
 		} else {
 			self.keys.insert(key, value);
+			self.size += size;
 			true
 		}
 	}
@@ -795,6 +977,12 @@ impl ExtractAccumulator for ObjectAccumulator {
 		let object = objects.next().expect("This iterator can not be empty as it is checked earlier in the code. This is a logic bug, please report it.");
 		assert!(object.key.starts_with(&query.prefix));
 
+		if query.skip_directory_marker && is_directory_marker(object, query) {
+			return ExtractionResult::Extracted {
+				key: object.key.clone(),
+			};
+		}
+
 		let version = match object.versions().iter().find(|x| x.is_data()) {
 			Some(v) => v,
 			None => unreachable!(
@@ -807,13 +995,38 @@ impl ExtractAccumulator for ObjectAccumulator {
 			ObjectVersionState::Complete(ObjectVersionData::FirstBlock(meta, _)) => meta,
 			_ => unreachable!(),
 		};
+
+		// Objects whose TTL has elapsed are hidden until they are deleted
+		if meta.is_expired() {
+			return ExtractionResult::Extracted {
+				key: object.key.clone(),
+			};
+		}
+
+		// Objects not modified since the requested date are skipped, but
+		// a listing that skips too many of them is truncated, so that a
+		// single request never scans a whole large bucket
+		if query.modified_since.is_some_and(|t| version.timestamp < t) {
+			self.skipped += 1;
+			if self.skipped >= MAX_SKIPPED_UNMODIFIED {
+				return ExtractionResult::Filled;
+			}
+			return ExtractionResult::Extracted {
+				key: object.key.clone(),
+			};
+		}
+
 		let info = ObjectInfo {
 			last_modified: version.timestamp,
 			size: meta.size,
 			etag: meta.etag.to_string(),
+			original_key: Some(object.original_key())
+				.filter(|k| *k != object.key)
+				.map(str::to_string),
 		};
 
-		match self.try_insert_entry(object.key.clone(), info) {
+		let size = xml_size(|w| write_list_item(w, &object.key, &info, query.urlencode_resp));
+		match self.try_insert_entry(object.key.clone(), info, size) {
 			true => ExtractionResult::Extracted {
 				key: object.key.clone(),
 			},
@@ -883,7 +1096,7 @@ impl ExtractAccumulator for UploadAccumulator {
 			key: object.key.to_string(),
 			timestamp: first_upload.timestamp,
 		};
-		if !self.try_insert_entry(first_upload.uuid, first_up_info) {
+		if !self.try_insert_entry(first_upload.uuid, first_up_info, 0) {
 			return ExtractionResult::Filled;
 		}
 
@@ -897,7 +1110,7 @@ impl ExtractAccumulator for UploadAccumulator {
 
 			// Insert data in our accumulator
 			// If it is full, return information to paginate.
-			if !self.try_insert_entry(upload.uuid, up_info) {
+			if !self.try_insert_entry(upload.uuid, up_info, 0) {
 				return ExtractionResult::FilledAtUpload {
 					key: object.key.clone(),
 					upload: prev_uuid,
@@ -918,13 +1131,93 @@ impl ExtractAccumulator for UploadAccumulator {
  * Utility functions
  */
 
-/// Returns the common prefix of the object given the query prefix and delimiter
+/// Returns the common prefix of the object given the query prefix and delimiter.
+/// The delimiter can be a string of any length: the common prefix ends at its
+/// first occurrence after the query prefix. An empty delimiter is ignored.
 fn common_prefix<'a>(object: &'a Object, query: &ListQueryCommon) -> Option<&'a str> {
-	match &query.delimiter {
-		Some(delimiter) => object.key[query.prefix.len()..]
-			.find(delimiter)
-			.map(|i| &object.key[..query.prefix.len() + i + delimiter.len()]),
-		None => None,
+	let delimiter = query.delimiter.as_deref().filter(|d| !d.is_empty())?;
+	object.key[query.prefix.len()..]
+		.find(delimiter)
+		.map(|i| &object.key[..query.prefix.len() + i + delimiter.len()])
+}
+
+/// Returns whether the object is the folder marker of the directory being listed
+fn is_directory_marker(object: &Object, query: &ListQueryCommon) -> bool {
+	!query.prefix.is_empty() && query.prefix.ends_with('/') && object.key == query.prefix
+}
+
+/// Serialize a ListObjects response. The entries of the accumulator are written
+/// directly into the response buffer, which is allocated once with its final size,
+/// instead of going through an intermediate `ListBucketResult`.
+fn list_objects_xml(
+	result: &s3_xml::ListBucketResult,
+	acc: &ObjectAccumulator,
+	urlencode: bool,
+) -> Result<String, Error> {
+	const END_TAG: &str = "</ListBucketResult>";
+
+	// `Contents` and `CommonPrefixes` are the last elements of the result,
+	// so the entries go right before its end tag
+	let header = s3_xml::to_xml_with_header(result)?;
+	let header = header
+		.strip_suffix(END_TAG)
+		.ok_or_internal_error("unexpected ListBucketResult serialization")?;
+
+	let mut xml = String::with_capacity(header.len() + acc.size + END_TAG.len());
+	xml.push_str(header);
+	for (key, info) in acc.keys.iter() {
+		write_list_item(&mut xml, key, info, urlencode)
+			.ok_or_internal_error("could not serialize ListObjects entry")?;
+	}
+	for pfx in acc.common_prefixes.iter() {
+		write_common_prefix(&mut xml, pfx, urlencode)
+			.ok_or_internal_error("could not serialize ListObjects entry")?;
+	}
+	xml.push_str(END_TAG);
+	Ok(xml)
+}
+
+/// Write the `Contents` element of an object in a ListObjects response
+fn write_list_item<W: Write>(
+	w: &mut W,
+	key: &str,
+	info: &ObjectInfo,
+	urlencode: bool,
+) -> fmt::Result {
+	w.write_str("<Contents><Key>")?;
+	write_uriencode_maybe(w, info.original_key.as_deref().unwrap_or(key), urlencode)?;
+	w.write_str("</Key><LastModified>")?;
+	s3_xml::write_timestamp(w, info.last_modified)?;
+	w.write_str("</LastModified><ETag>&quot;")?;
+	s3_xml::write_escaped(w, &info.etag)?;
+	write!(
+		w,
+		"&quot;</ETag><Size>{}</Size><StorageClass>STANDARD</StorageClass></Contents>",
+		info.size
+	)
+}
+
+/// Write the `CommonPrefixes` element of a prefix in a list response
+fn write_common_prefix<W: Write>(w: &mut W, pfx: &str, urlencode: bool) -> fmt::Result {
+	w.write_str("<CommonPrefixes><Prefix>")?;
+	write_uriencode_maybe(w, pfx, urlencode)?;
+	w.write_str("</Prefix></CommonPrefixes>")
+}
+
+/// Size in bytes of the output of a serialization function
+fn xml_size(f: impl FnOnce(&mut s3_xml::ByteCounter) -> fmt::Result) -> usize {
+	let mut counter = s3_xml::ByteCounter::default();
+	// Writing to a ByteCounter never fails
+	let _ = f(&mut counter);
+	counter.0
+}
+
+/// URIencode a value if needed, and write it escaped for XML
+fn write_uriencode_maybe<W: Write>(w: &mut W, s: &str, yes: bool) -> fmt::Result {
+	if yes {
+		uri_encode_into(w, s, true)
+	} else {
+		s3_xml::write_escaped(w, s)
 	}
 }
 
@@ -944,6 +1237,7 @@ fn uriencode_maybe(s: &str, yes: bool) -> s3_xml::Value {
 mod tests {
 	use super::*;
 	use garage_util::*;
+	use std::alloc::{GlobalAlloc, Layout, System};
 	use std::iter::FromIterator;
 
 	const TS: u64 = 1641394898314;
@@ -959,6 +1253,9 @@ mod tests {
 				delimiter: Some("/".to_string()),
 				page_size: 1000,
 				urlencode_resp: false,
+				skip_directory_marker: false,
+				max_response_size: None,
+				modified_since: None,
 				bucket_name: "a".to_string(),
 				bucket_id: Uuid::from([0x00; 32]),
 			},
@@ -988,6 +1285,7 @@ mod tests {
 					inner: ObjectVersionMetaInner {
 						headers: vec![],
 						checksum: None,
+						full_sha256: None,
 					},
 				},
 				checksum_algorithm: None,
@@ -1010,12 +1308,54 @@ mod tests {
 		assert_eq!(common_prefix(objs.get(0).unwrap(), &query.common), None);
 	}
 
+	#[test]
+	fn test_common_prefixes_multichar() {
+		let mut query = query();
+		let obj = Object::new(
+			bucket(),
+			"logs//2024//x-2024-01".to_string(),
+			vec![objup_version([0x01; 32])],
+		);
+
+		query.common.delimiter = Some("//".to_string());
+		assert_eq!(common_prefix(&obj, &query.common), Some("logs//"));
+
+		// The delimiter is searched for after the prefix, even if
+		// the prefix ends with it
+		query.common.prefix = "logs//".to_string();
+		assert_eq!(common_prefix(&obj, &query.common), Some("logs//2024//"));
+
+		query.common.prefix = "logs".to_string();
+		assert_eq!(common_prefix(&obj, &query.common), Some("logs//"));
+
+		query.common.prefix = "".to_string();
+		query.common.delimiter = Some("-2024-".to_string());
+		assert_eq!(
+			common_prefix(&obj, &query.common),
+			Some("logs//2024//x-2024-")
+		);
+
+		query.common.delimiter = Some("|".to_string());
+		assert_eq!(common_prefix(&obj, &query.common), None);
+
+		query.common.delimiter = Some("".to_string());
+		assert_eq!(common_prefix(&obj, &query.common), None);
+
+		// The whole common prefix is skipped when seeking to the next key
+		assert_eq!(
+			key_after_prefix("logs//2024//").as_deref(),
+			Some("logs//2024/0")
+		);
+
+		assert_eq!(uriencode_maybe("//", true).0, "%2F%2F");
+	}
+
 	#[test]
 	fn test_extract_common_prefix() {
 		let mut query = query();
 		query.common.prefix = "a/".to_string();
 		let objs = objs();
-		let mut acc = UploadAccumulator::new(query.common.page_size);
+		let mut acc = UploadAccumulator::new(query.common.page_size, None);
 
 		let mut iter = objs.iter().peekable();
 		match acc.extract_common_prefix(&mut iter, &query.common) {
@@ -1026,6 +1366,110 @@ mod tests {
 		assert_eq!(acc.common_prefixes.iter().next().unwrap(), "a/b/");
 	}
 
+	fn data_version(size: u64) -> ObjectVersion {
+		ObjectVersion {
+			uuid: Uuid::from([0x02; 32]),
+			timestamp: TS,
+			state: ObjectVersionState::Complete(ObjectVersionData::Inline(
+				ObjectVersionMeta {
+					size,
+					etag: "etag".to_string(),
+					expires_at: None,
+					encryption: ObjectVersionEncryption::Plaintext {
+						inner: ObjectVersionMetaInner {
+							headers: vec![],
+							checksum: None,
+							full_sha256: None,
+						},
+					},
+				},
+				vec![0; size as usize],
+			)),
+		}
+	}
+
+	#[test]
+	fn test_extract_directory_marker() {
+		let objs = vec![
+			Object::new(bucket(), "a/".to_string(), vec![data_version(0)]),
+			Object::new(bucket(), "a/b".to_string(), vec![data_version(3)]),
+			Object::new(bucket(), "a/c/".to_string(), vec![data_version(0)]),
+			Object::new(bucket(), "a/c/d".to_string(), vec![data_version(3)]),
+		];
+		let mut common = query().common;
+		common.prefix = "a/".to_string();
+		let cursor = RangeBegin::IncludingKey {
+			key: "a/".to_string(),
+			fallback_key: None,
+		};
+
+		for skip in [false, true] {
+			common.skip_directory_marker = skip;
+			let mut acc = ObjectAccumulator::new(common.page_size, None);
+			let mut iter = objs.iter().peekable();
+			while iter.peek().is_some() {
+				acc.extract(&common, &cursor, &mut iter);
+			}
+
+			// The folder marker of a subdirectory is always rolled up in a common prefix
+			assert_eq!(acc.common_prefixes.len(), 1);
+			assert!(acc.common_prefixes.contains("a/c/"));
+			// The folder marker of the listed directory is only listed in `object` mode
+			assert_eq!(acc.keys.contains_key("a/"), !skip);
+			assert!(acc.keys.contains_key("a/b"));
+		}
+	}
+
+	#[test]
+	fn test_extract_modified_since() {
+		let version_at = |timestamp: u64| ObjectVersion {
+			timestamp,
+			..data_version(3)
+		};
+		let objs = vec![
+			Object::new(bucket(), "a".to_string(), vec![version_at(TS - 1)]),
+			Object::new(bucket(), "b".to_string(), vec![version_at(TS)]),
+			Object::new(bucket(), "c".to_string(), vec![version_at(TS - 1000)]),
+			Object::new(bucket(), "d".to_string(), vec![version_at(TS + 1000)]),
+		];
+		let mut common = query().common;
+		common.delimiter = None;
+		common.modified_since = Some(TS);
+		let cursor = RangeBegin::IncludingKey {
+			key: "".to_string(),
+			fallback_key: None,
+		};
+
+		let mut acc = ObjectAccumulator::new(2, None);
+		let mut iter = objs.iter().peekable();
+		while iter.peek().is_some() {
+			assert!(matches!(
+				acc.extract(&common, &cursor, &mut iter),
+				ExtractionResult::Extracted { .. }
+			));
+		}
+		// Skipped objects do not count against the page size
+		assert_eq!(acc.keys.keys().collect::<Vec<_>>(), vec!["b", "d"]);
+		assert_eq!(acc.skipped, 2);
+
+		// A listing that skips too many objects is truncated
+		let old = (0..MAX_SKIPPED_UNMODIFIED + 1)
+			.map(|i| Object::new(bucket(), format!("{:06}", i), vec![version_at(TS - 1)]))
+			.collect::<Vec<_>>();
+		let mut acc = ObjectAccumulator::new(1000, None);
+		let mut iter = old.iter().peekable();
+		let mut extracted = 0;
+		loop {
+			match acc.extract(&common, &cursor, &mut iter) {
+				ExtractionResult::Extracted { .. } => extracted += 1,
+				ExtractionResult::Filled => break,
+				_ => panic!("wrong result"),
+			}
+		}
+		assert_eq!(extracted, MAX_SKIPPED_UNMODIFIED - 1);
+		assert!(acc.keys.is_empty());
+	}
+
 	#[test]
 	fn test_extract_upload() {
 		let objs = vec![
@@ -1042,7 +1486,7 @@ mod tests {
 			Object::new(bucket(), "c".to_string(), vec![]),
 		];
 
-		let mut acc = UploadAccumulator::new(2);
+		let mut acc = UploadAccumulator::new(2, None);
 		let mut start = RangeBegin::AfterUpload {
 			key: "b".to_string(),
 			upload: Uuid::from([0x01; 32]),
@@ -1075,7 +1519,7 @@ mod tests {
 			}
 		);
 
-		acc = UploadAccumulator::new(2);
+		acc = UploadAccumulator::new(2, None);
 		start = RangeBegin::AfterUpload {
 			key: "b".to_string(),
 			upload: Uuid::from([0xff; 32]),
@@ -1180,6 +1624,7 @@ mod tests {
 					size: Some(3),
 					etag: Some("etag1".into()),
 					checksum: None,
+					sha256: None,
 				},
 			),
 			(
@@ -1192,6 +1637,7 @@ mod tests {
 					size: None,
 					etag: None,
 					checksum: None,
+					sha256: None,
 				},
 			),
 			(
@@ -1204,6 +1650,7 @@ mod tests {
 					size: Some(10),
 					etag: Some("etag2".into()),
 					checksum: None,
+					sha256: None,
 				},
 			),
 			(
@@ -1216,6 +1663,7 @@ mod tests {
 					size: Some(7),
 					etag: Some("etag3".into()),
 					checksum: None,
+					sha256: None,
 				},
 			),
 			(
@@ -1228,6 +1676,7 @@ mod tests {
 					size: Some(5),
 					etag: Some("etag4".into()),
 					checksum: None,
+					sha256: None,
 				},
 			),
 		];
@@ -1235,10 +1684,12 @@ mod tests {
 		MultipartUpload {
 			upload_id: uuid,
 			timestamp: TS,
+			last_activity: TS,
 			deleted: false.into(),
 			parts: crdt::Map::<MpuPartKey, MpuPart>::from_iter(parts),
 			bucket_id: uuid,
 			key: "a".into(),
+			completing: None,
 		}
 	}
 
@@ -1257,7 +1708,7 @@ mod tests {
 		let mpu = mpu();
 
 		// Start from the beginning but with limited size to trigger pagination
-		let (info, pagination) = fetch_part_info(&query, &mpu)?;
+		let (info, pagination) = fetch_part_info(&mpu, query.part_number_marker, query.max_parts)?;
 		assert_eq!(pagination.unwrap(), 3);
 		assert_eq!(
 			info,
@@ -1281,7 +1732,7 @@ mod tests {
 
 		// Use previous pagination to make a new request
 		query.part_number_marker = Some(pagination.unwrap());
-		let (info, pagination) = fetch_part_info(&query, &mpu)?;
+		let (info, pagination) = fetch_part_info(&mpu, query.part_number_marker, query.max_parts)?;
 		assert!(pagination.is_none());
 		assert_eq!(
 			info,
@@ -1305,14 +1756,14 @@ mod tests {
 
 		// Trying to access a part that is way larger than registered ones
 		query.part_number_marker = Some(9999);
-		let (info, pagination) = fetch_part_info(&query, &mpu)?;
+		let (info, pagination) = fetch_part_info(&mpu, query.part_number_marker, query.max_parts)?;
 		assert!(pagination.is_none());
 		assert_eq!(info, vec![]);
 
 		// Try without any limitation
 		query.max_parts = 1000;
 		query.part_number_marker = None;
-		let (info, pagination) = fetch_part_info(&query, &mpu)?;
+		let (info, pagination) = fetch_part_info(&mpu, query.part_number_marker, query.max_parts)?;
 		assert!(pagination.is_none());
 		assert_eq!(
 			info,
@@ -1350,4 +1801,249 @@ mod tests {
 
 		Ok(())
 	}
+
+	fn list_parts_result(parts: Vec<s3_xml::PartItem>) -> s3_xml::ListPartsResult {
+		s3_xml::ListPartsResult {
+			xmlns: (),
+			bucket: s3_xml::Value("a".to_string()),
+			key: s3_xml::Value("a".to_string()),
+			upload_id: s3_xml::Value("xx".to_string()),
+			part_number_marker: Some(s3_xml::IntValue(1)),
+			next_part_number_marker: Some(s3_xml::IntValue(206)),
+			max_parts: s3_xml::IntValue(200),
+			is_truncated: s3_xml::Value("true".to_string()),
+			parts,
+			initiator: s3_xml::Initiator {
+				display_name: s3_xml::Value(DUMMY_NAME.to_string()),
+				id: s3_xml::Value(DUMMY_KEY.to_string()),
+			},
+			owner: s3_xml::Owner {
+				display_name: s3_xml::Value(DUMMY_NAME.to_string()),
+				id: s3_xml::Value(DUMMY_KEY.to_string()),
+			},
+			storage_class: s3_xml::Value("STANDARD".to_string()),
+		}
+	}
+
+	#[tokio::test]
+	async fn test_list_parts_stream() -> Result<(), Error> {
+		// An upload with more parts than are serialized in a chunk
+		let mut mpu = mpu();
+		for i in 10..260u64 {
+			mpu.parts.put(
+				MpuPartKey {
+					part_number: i,
+					timestamp: TS,
+				},
+				MpuPart {
+					version: Uuid::from([0x08; 32]),
+					size: Some(i),
+					etag: Some(format!("etag&{}", i)),
+					checksum: Some(ChecksumValue::Crc32((i as u32).to_be_bytes())),
+					sha256: None,
+				},
+			);
+		}
+
+		let marker = Some(1);
+		let (info, next) = fetch_part_info(&mpu, marker, 200)?;
+		assert_eq!(info.len(), 200);
+		assert_eq!(next, Some(206));
+
+		let read_body = |show_checksums| {
+			let (head, tail) = split_list_parts_xml(&list_parts_result(vec![])).unwrap();
+			let chunks = list_parts_stream(head, tail, mpu.clone(), marker, 200, show_checksums);
+			async move {
+				let chunks = chunks.collect::<Vec<_>>().await;
+				// Header, two chunks of parts, end of the result
+				assert_eq!(chunks.len(), 4);
+				chunks
+					.into_iter()
+					.map(|c| c.map(|b| String::from_utf8(b.to_vec()).unwrap()))
+					.collect::<Result<String, Error>>()
+			}
+		};
+
+		// The streamed serialization gives the same result as
+		// serializing the whole response at once
+		let expected = s3_xml::to_xml_with_header(&list_parts_result(
+			info.iter()
+				.map(|part| s3_xml::PartItem {
+					etag: s3_xml::Value(format!("\"{}\"", part.etag)),
+					last_modified: s3_xml::Value(msec_to_rfc3339(part.timestamp)),
+					part_number: s3_xml::IntValue(part.part_number as i64),
+					size: s3_xml::IntValue(part.size as i64),
+					checksum_crc32: match part.checksum {
+						Some(ChecksumValue::Crc32(x)) => {
+							Some(s3_xml::Value(BASE64_STANDARD.encode(x)))
+						}
+						_ => None,
+					},
+					checksum_crc32c: None,
+					checksum_sha1: None,
+					checksum_sha256: None,
+				})
+				.collect(),
+		))?;
+		assert_eq!(read_body(true).await?, expected);
+
+		// Checksums are hidden if requested
+		let xml = read_body(false).await?;
+		assert!(!xml.contains("Checksum"));
+		assert_eq!(xml.matches("<Part>").count(), 200);
+
+		Ok(())
+	}
+
+	/// Allocator counting the allocations made by the current thread
+	struct CountingAllocator;
+
+	thread_local! {
+		static ALLOCATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+	}
+
+	unsafe impl GlobalAlloc for CountingAllocator {
+		unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+			let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+			System.alloc(layout)
+		}
+
+		unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+			System.dealloc(ptr, layout)
+		}
+
+		unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+			let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+			System.realloc(ptr, layout, new_size)
+		}
+	}
+
+	#[global_allocator]
+	static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+	fn allocations() -> usize {
+		ALLOCATIONS.with(|n| n.get())
+	}
+
+	fn list_result(
+		contents: Vec<s3_xml::ListBucketItem>,
+		common_prefixes: Vec<s3_xml::CommonPrefix>,
+	) -> s3_xml::ListBucketResult {
+		s3_xml::ListBucketResult {
+			xmlns: (),
+			name: s3_xml::Value("a".to_string()),
+			prefix: s3_xml::Value("".to_string()),
+			marker: None,
+			next_marker: None,
+			start_after: None,
+			continuation_token: None,
+			next_continuation_token: None,
+			key_count: Some(s3_xml::IntValue(3)),
+			max_keys: s3_xml::IntValue(1000),
+			delimiter: Some(s3_xml::Value("/".to_string())),
+			encoding_type: None,
+			is_truncated: s3_xml::Value("false".to_string()),
+			contents,
+			common_prefixes,
+		}
+	}
+
+	fn insert_object(acc: &mut ObjectAccumulator, key: &str, size: u64) -> bool {
+		let info = ObjectInfo {
+			last_modified: TS,
+			size,
+			etag: "etag".to_string(),
+			original_key: None,
+		};
+		let xml_len = xml_size(|w| write_list_item(w, key, &info, false));
+		acc.try_insert_entry(key.to_string(), info, xml_len)
+	}
+
+	#[test]
+	fn test_list_objects_xml() -> Result<(), Error> {
+		let mut acc = ObjectAccumulator::new(1000, None);
+		insert_object(&mut acc, "a&b", 12);
+		insert_object(&mut acc, "c<\"d\">", 0);
+		let pfx_len = xml_size(|w| write_common_prefix(w, "e'/", false));
+		acc.try_insert_common_prefix("e'/".to_string(), pfx_len);
+
+		// The incremental serialization gives the same result as serializing
+		// the whole response at once
+		let expected = s3_xml::to_xml_with_header(&list_result(
+			acc.keys
+				.iter()
+				.map(|(key, info)| s3_xml::ListBucketItem {
+					key: uriencode_maybe(key, false),
+					last_modified: s3_xml::Value(msec_to_rfc3339(info.last_modified)),
+					size: s3_xml::IntValue(info.size as i64),
+					etag: s3_xml::Value(format!("\"{}\"", info.etag)),
+					storage_class: s3_xml::Value("STANDARD".to_string()),
+				})
+				.collect(),
+			vec![s3_xml::CommonPrefix {
+				prefix: uriencode_maybe("e'/", false),
+			}],
+		))?;
+		let xml = list_objects_xml(&list_result(vec![], vec![]), &acc, false)?;
+		assert_eq!(xml, expected);
+
+		let header = s3_xml::to_xml_with_header(&list_result(vec![], vec![]))?;
+		assert_eq!(xml.len(), header.len() + acc.size);
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_list_objects_xml_allocations() -> Result<(), Error> {
+		let mut acc = ObjectAccumulator::new(1000, None);
+		for i in 0..1000 {
+			assert!(insert_object(
+				&mut acc,
+				&format!("dir/object & file {:04}", i),
+				i
+			));
+		}
+		let result = list_result(vec![], vec![]);
+
+		let before = allocations();
+		let xml = list_objects_xml(&result, &acc, false)?;
+		let count = allocations() - before;
+
+		assert_eq!(xml.matches("<Contents>").count(), 1000);
+		// Only the serialization of the fixed part of the response and the
+		// response buffer allocate, not the entries
+		assert!(count < 50, "{} allocations for 1000 keys", count);
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_list_max_response_size() {
+		let entry_len = xml_size(|w| {
+			write_list_item(
+				w,
+				"a",
+				&ObjectInfo {
+					last_modified: TS,
+					size: 1,
+					etag: "etag".to_string(),
+					original_key: None,
+				},
+				false,
+			)
+		});
+
+		// The response is truncated before exceeding the maximum size
+		let mut acc = ObjectAccumulator::new(1000, Some(2 * entry_len + 1));
+		assert!(insert_object(&mut acc, "a", 1));
+		assert!(insert_object(&mut acc, "b", 1));
+		assert!(!insert_object(&mut acc, "c", 1));
+		assert_eq!(acc.keys.len(), 2);
+		assert_eq!(acc.size, 2 * entry_len);
+
+		// The first entry is always accepted
+		let mut acc = ObjectAccumulator::new(1000, Some(1));
+		assert!(insert_object(&mut acc, "a", 1));
+		assert!(!insert_object(&mut acc, "b", 1));
+	}
 }
@@ -0,0 +1,138 @@
+use quick_xml::de::from_reader;
+
+use http_body_util::BodyExt;
+use hyper::{Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::helpers::*;
+use crate::s3::api_server::{ReqBody, ResBody};
+use crate::s3::error::*;
+use crate::s3::xml::{to_xml_with_header, xmlns_tag, Value};
+use crate::signature::verify_signed_content;
+
+use garage_model::bucket_table::*;
+use garage_util::data::*;
+
+pub async fn handle_get_bucket_logging(ctx: ReqCtx) -> Result<Response<ResBody>, Error> {
+	let ReqCtx { bucket_params, .. } = ctx;
+	let status =
+		BucketLoggingStatus {
+			xmlns: (),
+			logging_enabled: bucket_params.logging_config.get().as_ref().map(|conf| {
+				LoggingEnabled {
+					target_bucket: Value(conf.target_bucket_name.clone()),
+					target_prefix: Some(Value(conf.target_prefix.clone())),
+				}
+			}),
+		};
+	let xml = to_xml_with_header(&status)?;
+	Ok(Response::builder()
+		.status(StatusCode::OK)
+		.header(http::header::CONTENT_TYPE, "application/xml")
+		.body(string_body(xml))?)
+}
+
+pub async fn handle_put_bucket_logging(
+	ctx: ReqCtx,
+	req: Request<ReqBody>,
+	content_sha256: Option<Hash>,
+) -> Result<Response<ResBody>, Error> {
+	let ReqCtx {
+		garage,
+		bucket_id,
+		mut bucket_params,
+		api_key,
+		..
+	} = ctx;
+
+	let body = BodyExt::collect(req.into_body()).await?.to_bytes();
+
+	if let Some(content_sha256) = content_sha256 {
+		verify_signed_content(content_sha256, &body[..])?;
+	}
+
+	let status: BucketLoggingStatus = from_reader(&body as &[u8])?;
+
+	let logging_config = match status.logging_enabled {
+		None => None,
+		Some(enabled) => {
+			let target_bucket_name = enabled.target_bucket.0;
+			let target_bucket = garage
+				.bucket_helper()
+				.resolve_bucket(&target_bucket_name, &api_key)
+				.await?;
+			// Log objects are written on behalf of the bucket owner,
+			// who must be allowed to write to the target bucket
+			if !api_key.allow_write(&target_bucket) {
+				return Err(Error::forbidden(
+					"The target bucket of access logs must be writable by this key.",
+				));
+			}
+			Some(LoggingConfig {
+				target_bucket,
+				target_bucket_name,
+				target_prefix: enabled.target_prefix.map(|p| p.0).unwrap_or_default(),
+			})
+		}
+	};
+
+	bucket_params.logging_config.update(logging_config);
+	garage
+		.bucket_table
+		.insert(&Bucket::present(bucket_id, bucket_params))
+		.await?;
+
+	Ok(Response::builder()
+		.status(StatusCode::OK)
+		.body(empty_body())?)
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BucketLoggingStatus {
+	#[serde(serialize_with = "xmlns_tag", skip_deserializing)]
+	pub xmlns: (),
+	#[serde(rename = "LoggingEnabled", skip_serializing_if = "Option::is_none")]
+	pub logging_enabled: Option<LoggingEnabled>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LoggingEnabled {
+	#[serde(rename = "TargetBucket")]
+	pub target_bucket: Value,
+	#[serde(rename = "TargetPrefix")]
+	pub target_prefix: Option<Value>,
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use quick_xml::de::from_str;
+
+	#[test]
+	fn test_deserialize_bucket_logging_status() -> Result<(), Error> {
+		let message = r#"<?xml version="1.0" encoding="UTF-8"?>
+<BucketLoggingStatus xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+  <LoggingEnabled>
+    <TargetBucket>logs</TargetBucket>
+    <TargetPrefix>mybucket/</TargetPrefix>
+  </LoggingEnabled>
+</BucketLoggingStatus>"#;
+		let status: BucketLoggingStatus = from_str(message).unwrap();
+		let enabled = status.logging_enabled.as_ref().unwrap();
+		assert_eq!(enabled.target_bucket.0, "logs");
+		assert_eq!(enabled.target_prefix.as_ref().unwrap().0, "mybucket/");
+
+		let message2 = to_xml_with_header(&status)?;
+		let cleanup = |c: &str| c.replace(char::is_whitespace, "");
+		assert_eq!(cleanup(message), cleanup(&message2));
+
+		// Logging is disabled by an empty BucketLoggingStatus
+		let status: BucketLoggingStatus =
+			from_str(r#"<BucketLoggingStatus xmlns="http://s3.amazonaws.com/doc/2006-03-01/" />"#)
+				.unwrap();
+		assert!(status.logging_enabled.is_none());
+
+		Ok(())
+	}
+}
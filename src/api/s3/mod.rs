@@ -1,19 +1,28 @@
+pub mod access_log;
 pub mod api_server;
 pub mod error;
 
+pub mod bandwidth;
 mod bucket;
 mod copy;
 pub mod cors;
 mod delete;
 pub mod get;
+pub mod import;
 mod lifecycle;
 mod list;
+mod logging;
 mod multipart;
 mod post_object;
 mod put;
+pub mod recent_errors;
+mod recent_puts;
+mod request_payment;
+mod shadow;
 mod website;
 
 mod checksum;
 mod encryption;
+mod headers;
 mod router;
 pub mod xml;
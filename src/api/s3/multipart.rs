@@ -4,17 +4,21 @@ use std::sync::Arc;
 
 use base64::prelude::*;
 use futures::prelude::*;
-use hyper::{Request, Response};
+use hyper::{HeaderMap, Request, Response};
 
 use garage_table::*;
 use garage_util::data::*;
+use garage_util::time::*;
 
 use garage_model::garage::Garage;
-use garage_model::s3::block_ref_table::*;
+use garage_model::s3::mpu_completion::{
+	commit_mpu_completion, map_parts_concurrently, mpu_completion_concurrency,
+};
 use garage_model::s3::mpu_table::*;
 use garage_model::s3::object_table::*;
 use garage_model::s3::version_table::*;
 
+use crate::common_error::CommonError;
 use crate::helpers::*;
 use crate::s3::api_server::{ReqBody, ResBody};
 use crate::s3::checksum::*;
@@ -37,15 +41,19 @@ pub async fn handle_create_multipart_upload(
 		bucket_name,
 		..
 	} = &ctx;
+
+	check_multipart_upload_quota(&ctx).await?;
+
 	let existing_object = garage.object_table.get(&bucket_id, &key).await?;
 
 	let upload_id = gen_uuid();
-	let timestamp = next_timestamp(existing_object.as_ref());
+	let timestamp = next_timestamp(&*garage.clock, existing_object.as_ref());
 
 	let headers = get_headers(req.headers())?;
 	let meta = ObjectVersionMetaInner {
 		headers,
 		checksum: None,
+		full_sha256: None,
 	};
 
 	// Determine whether object should be encrypted, and if so the key
@@ -100,10 +108,7 @@ pub async fn handle_put_part(
 	let upload_id = decode_upload_id(upload_id)?;
 
 	let expected_checksums = ExpectedChecksums {
-		md5: match req.headers().get("content-md5") {
-			Some(x) => Some(x.to_str()?.to_string()),
-			None => None,
-		},
+		md5: request_content_md5(req.headers())?,
 		sha256: content_sha256,
 		extra: request_checksum_value(req.headers())?,
 	};
@@ -113,7 +118,10 @@ pub async fn handle_put_part(
 
 	let (req_head, req_body) = req.into_parts();
 	let stream = body_stream(req_body);
-	let mut chunker = StreamChunker::new(stream, garage.config.block_size);
+	let block_size = ctx
+		.bucket_params
+		.effective_block_size(garage.config.block_size);
+	let mut chunker = StreamChunker::new(stream, block_size);
 
 	let ((_, object_version, mut mpu), first_block) =
 		futures::try_join!(get_upload(&ctx, &key, &upload_id), chunker.next(),)?;
@@ -134,10 +142,15 @@ pub async fn handle_put_part(
 	let first_block = first_block.ok_or_bad_request("Empty body")?;
 
 	// Calculate part identity: timestamp, version id
+	// A previous upload of the same part number is superseded by giving
+	// this one a newer timestamp. This is computed from the MPU entry that
+	// we had to read anyway, so no additional lookup is needed to detect
+	// duplicate part numbers. The entry cannot be replaced by state cached
+	// on this node, as parts of an upload can be sent to any node.
 	let version_uuid = gen_uuid();
 	let mpu_part_key = MpuPartKey {
 		part_number,
-		timestamp: mpu.next_timestamp(part_number),
+		timestamp: mpu.next_timestamp(&*garage.clock, part_number),
 	};
 
 	// The following consists in many steps that can each fail.
@@ -150,6 +163,8 @@ pub async fn handle_put_part(
 	}));
 
 	// Create version and link version from MPU
+	// The part insert also records the activity on the upload
+	mpu.touch();
 	mpu.parts.clear();
 	mpu.parts.put(
 		mpu_part_key,
@@ -159,6 +174,7 @@ pub async fn handle_put_part(
 			etag: None,
 			checksum: None,
 			size: None,
+			sha256: None,
 		},
 	);
 	garage.mpu_table.insert(&mpu).await?;
@@ -171,9 +187,13 @@ pub async fn handle_put_part(
 	garage.version_table.insert(&version).await?;
 
 	// Copy data to version
-	let checksummer =
+	let mut checksummer =
 		Checksummer::init(&expected_checksums, !encryption.is_encrypted()).add(checksum_algorithm);
-	let (total_size, checksums, _) = read_and_put_blocks(
+	let always_sha256 = garage.config.s3_api.always_compute_sha256;
+	if always_sha256 {
+		checksummer = checksummer.add(Some(ChecksumAlgorithm::Sha256));
+	}
+	let (total_size, checksums, _, _) = read_and_put_blocks(
 		&ctx,
 		&version,
 		encryption,
@@ -181,6 +201,9 @@ pub async fn handle_put_part(
 		first_block,
 		&mut chunker,
 		checksummer,
+		block_checksum_algorithm(&garage.config.s3_api, checksum_algorithm),
+		max_part_size(&garage.config.s3_api),
+		false,
 	)
 	.await?;
 
@@ -190,6 +213,7 @@ pub async fn handle_put_part(
 	// Store part etag in version
 	let etag = encryption.etag_from_md5(&checksums.md5);
 
+	mpu.touch();
 	mpu.parts.put(
 		mpu_part_key,
 		MpuPart {
@@ -197,6 +221,7 @@ pub async fn handle_put_part(
 			etag: Some(etag.clone()),
 			checksum: checksums.extract(checksum_algorithm),
 			size: Some(total_size),
+			sha256: checksums.sha256.map(Hash::from).filter(|_| always_sha256),
 		},
 	);
 	garage.mpu_table.insert(&mpu).await?;
@@ -252,7 +277,7 @@ pub async fn handle_complete_multipart_upload(
 	let ReqCtx {
 		garage,
 		bucket_id,
-		bucket_name,
+		bucket_params,
 		..
 	} = &ctx;
 	let (req_head, req_body) = req.into_parts();
@@ -277,7 +302,46 @@ pub async fn handle_complete_multipart_upload(
 
 	// Get object and multipart upload
 	let key = key.to_string();
-	let (object, mut object_version, mpu) = get_upload(&ctx, &key, &upload_id).await?;
+	let (object, mut object_version, mpu) = match get_upload(&ctx, &key, &upload_id).await {
+		Ok(x) => x,
+		Err(Error::NoSuchUpload) => {
+			// The upload might have been completed by a previous request
+			// with the same list of parts, that the client is retrying
+			return handle_completed_upload(
+				&ctx,
+				&req_head.headers,
+				key,
+				&upload_id,
+				&body_list_of_parts,
+				&expected_checksum,
+			)
+			.await;
+		}
+		Err(e) => return Err(e),
+	};
+
+	if let Some(completion) = &mpu.completing {
+		// A previous request validated its list of parts but was interrupted
+		// before the final object version was written: finish its work
+		let checksum_extra = check_same_parts(
+			garage,
+			&req_head.headers,
+			&body_list_of_parts,
+			&completion.meta,
+			&expected_checksum,
+		)?;
+		if !commit_mpu_completion(garage, bucket_params, &mpu, completion, None).await? {
+			return Err(Error::NoSuchUpload);
+		}
+		return complete_multipart_upload_response(
+			&ctx,
+			key,
+			&completion.meta.etag,
+			&checksum_extra,
+			&expected_checksum,
+			None,
+		);
+	}
 
 	if mpu.parts.is_empty() {
 		return Err(Error::bad_request("No data was uploaded"));
@@ -314,61 +378,38 @@ pub async fn handle_complete_multipart_upload(
 	}
 	let mut parts = vec![];
 	for req_part in body_list_of_parts.iter() {
-		match have_parts.get(&req_part.part_number) {
-			Some(part) if part.etag.as_ref() == Some(&req_part.etag) && part.size.is_some() => {
-				// alternative version: if req_part.checksum.is_some() && part.checksum != req_part.checksum {
-				if part.checksum != req_part.checksum {
-					return Err(Error::InvalidDigest(format!(
-						"Invalid checksum for part {}: in request = {:?}, uploaded part = {:?}",
-						req_part.part_number, req_part.checksum, part.checksum
-					)));
-				}
-				parts.push(*part)
-			}
-			_ => return Err(Error::InvalidPart),
-		}
+		let part = check_completed_part(&have_parts, req_part)?;
+		parts.push(part);
 	}
 
+	// Read the versions of all parts, which hold their lists of blocks.
+	// Nothing has been written yet, so the completion can be stopped
+	// at the first part that is missing.
 	let grg = &garage;
-	let parts_versions = futures::future::try_join_all(parts.iter().map(|p| async move {
-		grg.version_table
-			.get(&p.version, &EmptyKey)
-			.await?
-			.ok_or_internal_error("Part version missing from version table")
-	}))
-	.await?;
-
-	// Create final version and block refs
-	let mut final_version = Version::new(
-		upload_id,
-		VersionBacklink::Object {
-			bucket_id: *bucket_id,
-			key: key.to_string(),
+	let part_versions = body_list_of_parts
+		.iter()
+		.map(|req_part| req_part.part_number)
+		.zip(parts.iter().map(|p| p.version))
+		.collect::<Vec<_>>();
+	let parts_versions = map_parts_concurrently(
+		mpu_completion_concurrency(&garage.config.s3_api),
+		part_versions,
+		|(part_number, version_uuid)| async move {
+			let version = grg
+				.version_table
+				.get(&version_uuid, &EmptyKey)
+				.await?
+				.ok_or_internal_error("Part version missing from version table")?;
+			if version.deleted.get() {
+				return Err(Error::InvalidPart(format!(
+					"part {} has been deleted",
+					part_number
+				)));
+			}
+			Ok(version)
 		},
-		false,
-	);
-	for (part_number, part_version) in parts_versions.iter().enumerate() {
-		if part_version.deleted.get() {
-			return Err(Error::InvalidPart);
-		}
-		for (vbk, vb) in part_version.blocks.items().iter() {
-			final_version.blocks.put(
-				VersionBlockKey {
-					part_number: (part_number + 1) as u64,
-					offset: vbk.offset,
-				},
-				*vb,
-			);
-		}
-	}
-	garage.version_table.insert(&final_version).await?;
-
-	let block_refs = final_version.blocks.items().iter().map(|(_, b)| BlockRef {
-		block: b.hash,
-		version: upload_id,
-		deleted: false.into(),
-	});
-	garage.block_ref_table.insert_many(block_refs).await?;
+	)
+	.await?;
 
 	// Calculate checksum and etag of final object
 	// To understand how etags are calculated, read more here:
@@ -391,69 +432,211 @@ pub async fn handle_complete_multipart_upload(
 	// Calculate total size of final object
 	let total_size = parts.iter().map(|x| x.size.unwrap()).sum();
 
-	if let Err(e) = check_quotas(&ctx, total_size, Some(&object)).await {
-		object_version.state = ObjectVersionState::Aborted;
-		let final_object = Object::new(*bucket_id, key.clone(), vec![object_version]);
-		garage.object_table.insert(&final_object).await?;
+	let size_check = match check_size_limit(total_size, garage.config.s3_api.max_object_size) {
+		Ok(()) => check_quotas(&ctx, total_size, Some(&object)).await,
+		Err(e) => Err(e),
+	};
+	let quota_warning = match size_check {
+		Ok(warning) => warning,
+		Err(e) => {
+			object_version.state = ObjectVersionState::Aborted;
+			let final_object = Object::new(*bucket_id, key.clone(), vec![object_version]);
+			garage.object_table.insert(&final_object).await?;
+
+			return Err(e);
+		}
+	};
 
-		return Err(e);
-	}
+	// If Garage must compute the SHA256 checksum of all objects, compute
+	// the checksum of the whole object by reading the data of all parts.
+	// This is not possible for SSE-C encrypted objects, as the key is not
+	// given when the upload is completed.
+	let full_sha256 = match &object_encryption {
+		ObjectVersionEncryption::Plaintext { .. } if garage.config.s3_api.always_compute_sha256 => {
+			let blocks = parts_versions
+				.iter()
+				.flat_map(|v| v.blocks.items().iter().map(|(_, b)| b.hash))
+				.collect::<Vec<_>>();
+			Some(blocks_sha256(garage, blocks.into_iter()).await?)
+		}
+		_ => None,
+	};
 
 	// If there is a checksum algorithm, update metadata with checksum
-	let object_encryption = match checksum_algorithm {
-		None => object_encryption,
-		Some(_) => {
+	let object_encryption = match (checksum_algorithm, full_sha256) {
+		(None, None) => object_encryption,
+		_ => {
 			let (encryption, meta) =
 				EncryptionParams::check_decrypt(&garage, &req_head.headers, &object_encryption)?;
 			let new_meta = ObjectVersionMetaInner {
 				headers: meta.into_owned().headers,
 				checksum: checksum_extra,
+				full_sha256,
 			};
 			encryption.encrypt_meta(new_meta)?
 		}
 	};
 
-	// Write final object version
-	object_version.state = ObjectVersionState::Complete(ObjectVersionData::FirstBlock(
-		ObjectVersionMeta {
+	// Record the validated list of parts before writing the final object
+	// version, so that the completion can be resumed if it is interrupted
+	let completion = MpuCompletion {
+		timestamp: now_msec(),
+		parts: body_list_of_parts
+			.iter()
+			.zip(parts.iter())
+			.map(|(req_part, part)| (req_part.part_number, part.version))
+			.collect(),
+		meta: ObjectVersionMeta {
 			encryption: object_encryption,
 			size: total_size,
 			etag: etag.clone(),
+			expires_at: None,
 		},
-		final_version.blocks.items()[0].1.hash,
-	));
+		original_key: ctx.original_key.clone(),
+	};
+	let mut mpu_completing =
+		MultipartUpload::new(upload_id, mpu.timestamp, *bucket_id, key.clone(), false);
+	mpu_completing.completing = Some(completion.clone());
+	garage.mpu_table.insert(&mpu_completing).await?;
 
-	let final_object = Object::new(*bucket_id, key.clone(), vec![object_version]);
-	garage.object_table.insert(&final_object).await?;
+	// Write final version, block refs and object version
+	if !commit_mpu_completion(
+		garage,
+		bucket_params,
+		&mpu,
+		&completion,
+		Some(parts_versions),
+	)
+	.await?
+	{
+		return Err(Error::NoSuchUpload);
+	}
+
+	complete_multipart_upload_response(
+		&ctx,
+		key,
+		&etag,
+		&checksum_extra,
+		&expected_checksum,
+		quota_warning.as_deref(),
+	)
+}
+
+/// Answer a CompleteMultipartUpload request for an upload that is already
+/// completed, which is a success if the request has the same list of parts
+/// as the one that completed the upload
+async fn handle_completed_upload(
+	ctx: &ReqCtx,
+	headers: &HeaderMap,
+	key: String,
+	upload_id: &Uuid,
+	req_parts: &[CompleteMultipartUploadPart],
+	expected_checksum: &Option<ChecksumValue>,
+) -> Result<Response<ResBody>, Error> {
+	let ReqCtx {
+		garage, bucket_id, ..
+	} = ctx;
+
+	let object = garage
+		.object_table
+		.get(bucket_id, &key)
+		.await?
+		.ok_or(Error::NoSuchUpload)?;
+	let meta = object
+		.versions()
+		.iter()
+		.find(|v| v.uuid == *upload_id)
+		.and_then(|v| match &v.state {
+			ObjectVersionState::Complete(ObjectVersionData::FirstBlock(meta, _)) => Some(meta),
+			_ => None,
+		})
+		.ok_or(Error::NoSuchUpload)?;
+
+	let checksum_extra = check_same_parts(garage, headers, req_parts, meta, expected_checksum)?;
+	complete_multipart_upload_response(
+		ctx,
+		key,
+		&meta.etag,
+		&checksum_extra,
+		expected_checksum,
+		None,
+	)
+}
+
+/// Check that a retried CompleteMultipartUpload request has the same list of
+/// parts as the request that started the completion, by comparing the ETag
+/// that the final object would have. Returns the checksum of the final object.
+fn check_same_parts(
+	garage: &Garage,
+	headers: &HeaderMap,
+	req_parts: &[CompleteMultipartUploadPart],
+	meta: &ObjectVersionMeta,
+	expected_checksum: &Option<ChecksumValue>,
+) -> Result<Option<ChecksumValue>, Error> {
+	let mut checksummer = MultipartChecksummer::init(None);
+	let valid_etags = req_parts
+		.iter()
+		.all(|p| checksummer.update(&p.etag, None).is_ok());
+	let (checksum_md5, _) = checksummer.finalize();
+	let etag = format!("{}-{}", hex::encode(&checksum_md5[..]), req_parts.len());
+	if !valid_etags || etag != meta.etag {
+		return Err(Error::InvalidPart(
+			"the completion of this upload was started with a different list of parts".into(),
+		));
+	}
+
+	// The checksum can be read only if the object is not encrypted,
+	// or if the request contains the encryption key
+	let checksum_extra = EncryptionParams::check_decrypt(garage, headers, &meta.encryption)
+		.ok()
+		.and_then(|(_, inner)| inner.checksum);
+	if expected_checksum.is_some() && checksum_extra != *expected_checksum {
+		return Err(Error::InvalidDigest(
+			"Failed to validate x-amz-checksum-*".into(),
+		));
+	}
+
+	Ok(checksum_extra)
+}
 
-	// Send response saying ok we're done
+fn complete_multipart_upload_response(
+	ctx: &ReqCtx,
+	key: String,
+	etag: &str,
+	checksum_extra: &Option<ChecksumValue>,
+	expected_checksum: &Option<ChecksumValue>,
+	quota_warning: Option<&str>,
+) -> Result<Response<ResBody>, Error> {
 	let result = s3_xml::CompleteMultipartUploadResult {
 		xmlns: (),
 		location: None,
-		bucket: s3_xml::Value(bucket_name.to_string()),
-		key: s3_xml::Value(key),
+		bucket: s3_xml::Value(ctx.bucket_name.to_string()),
+		key: s3_xml::Value(ctx.original_key.clone().unwrap_or(key)),
 		etag: s3_xml::Value(format!("\"{}\"", etag)),
-		checksum_crc32: match &checksum_extra {
+		checksum_crc32: match checksum_extra {
 			Some(ChecksumValue::Crc32(x)) => Some(s3_xml::Value(BASE64_STANDARD.encode(&x))),
 			_ => None,
 		},
-		checksum_crc32c: match &checksum_extra {
+		checksum_crc32c: match checksum_extra {
 			Some(ChecksumValue::Crc32c(x)) => Some(s3_xml::Value(BASE64_STANDARD.encode(&x))),
 			_ => None,
 		},
-		checksum_sha1: match &checksum_extra {
+		checksum_sha1: match checksum_extra {
 			Some(ChecksumValue::Sha1(x)) => Some(s3_xml::Value(BASE64_STANDARD.encode(&x))),
 			_ => None,
 		},
-		checksum_sha256: match &checksum_extra {
+		checksum_sha256: match checksum_extra {
 			Some(ChecksumValue::Sha256(x)) => Some(s3_xml::Value(BASE64_STANDARD.encode(&x))),
 			_ => None,
 		},
 	};
 	let xml = s3_xml::to_xml_with_header(&result)?;
 
-	let resp = Response::builder();
-	let resp = add_checksum_response_headers(&expected_checksum, resp);
+	let mut resp = Response::builder();
+	if let Some(warning) = quota_warning {
+		resp = resp.header(X_GARAGE_QUOTA_WARNING, warning);
+	}
+	let resp = add_checksum_response_headers(expected_checksum, resp);
 	Ok(resp.body(string_body(xml))?)
 }
 
@@ -509,6 +692,39 @@ pub(crate) async fn get_upload(
 	Ok((object, object_version, mpu))
 }
 
+/// Check that the bucket's maximum number of multipart uploads in progress
+/// is not reached
+async fn check_multipart_upload_quota(ctx: &ReqCtx) -> Result<(), Error> {
+	let ReqCtx {
+		garage,
+		bucket_id,
+		bucket_params,
+		..
+	} = ctx;
+
+	let max_uploads = match bucket_params.quotas.get().max_multipart_uploads {
+		Some(m) => m,
+		None => return Ok(()),
+	};
+
+	let current_uploads = garage
+		.mpu_counter_table
+		.table
+		.get(bucket_id, &EmptyKey)
+		.await?
+		.map(|x| x.filtered_values(&garage.system.cluster_layout()))
+		.and_then(|x| x.get(UPLOADS).cloned())
+		.unwrap_or_default();
+
+	if current_uploads >= max_uploads as i64 {
+		return Err(Error::Common(CommonError::ServiceUnavailable(format!(
+			"Too many multipart uploads in progress, maximum for this bucket: {}",
+			max_uploads
+		))));
+	}
+	Ok(())
+}
+
 pub fn decode_upload_id(id: &str) -> Result<Uuid, Error> {
 	let id_bin = hex::decode(id).map_err(|_| Error::NoSuchUpload)?;
 	if id_bin.len() != 32 {
@@ -519,6 +735,37 @@ pub fn decode_upload_id(id: &str) -> Result<Uuid, Error> {
 	Ok(Uuid::from(uuid))
 }
 
+/// Check that a part given in a CompleteMultipartUpload request is a part
+/// that has been fully uploaded, with the same ETag and checksum
+fn check_completed_part<'a>(
+	have_parts: &HashMap<u64, &'a MpuPart>,
+	req_part: &CompleteMultipartUploadPart,
+) -> Result<&'a MpuPart, Error> {
+	let part = match have_parts.get(&req_part.part_number) {
+		Some(part) if part.size.is_some() => *part,
+		_ => {
+			return Err(Error::InvalidPart(format!(
+				"part {} has not been uploaded",
+				req_part.part_number
+			)))
+		}
+	};
+	if part.etag.as_ref() != Some(&req_part.etag) {
+		return Err(Error::InvalidPart(format!(
+			"ETag of part {} does not match the uploaded part",
+			req_part.part_number
+		)));
+	}
+	// alternative version: if req_part.checksum.is_some() && part.checksum != req_part.checksum {
+	if part.checksum != req_part.checksum {
+		return Err(Error::InvalidDigest(format!(
+			"Invalid checksum for part {}: in request = {:?}, uploaded part = {:?}",
+			req_part.part_number, req_part.checksum, part.checksum
+		)));
+	}
+	Ok(part)
+}
+
 #[derive(Debug)]
 struct CompleteMultipartUploadPart {
 	etag: String,
@@ -8,8 +8,9 @@ use base64::prelude::*;
 use bytes::Bytes;
 use chrono::{DateTime, Duration, Utc};
 use futures::{Stream, StreamExt};
+use hyper::body::Body;
 use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
-use hyper::{body::Incoming as IncomingBody, Request, Response, StatusCode};
+use hyper::{Method, Request, Response, StatusCode};
 use multer::{Constraints, Multipart, SizeLimit};
 use serde::Deserialize;
 
@@ -17,20 +18,24 @@ use garage_model::garage::Garage;
 use garage_model::s3::object_table::*;
 
 use crate::helpers::*;
-use crate::s3::api_server::ResBody;
+use crate::s3::api_server::{check_bucket_allowed_method, ResBody};
 use crate::s3::checksum::*;
 use crate::s3::cors::*;
 use crate::s3::encryption::EncryptionParams;
 use crate::s3::error::*;
-use crate::s3::put::{get_headers, save_stream, ChecksumMode};
+use crate::s3::put::{get_headers, save_stream, ChecksumMode, X_GARAGE_QUOTA_WARNING};
 use crate::s3::xml as s3_xml;
 use crate::signature::payload::{verify_v4, Authorization};
 
-pub async fn handle_post_object(
+pub async fn handle_post_object<B>(
 	garage: Arc<Garage>,
-	req: Request<IncomingBody>,
+	req: Request<B>,
 	bucket_name: String,
-) -> Result<Response<ResBody>, Error> {
+) -> Result<Response<ResBody>, Error>
+where
+	B: Body<Data = Bytes> + Send + 'static,
+	B::Error: Into<Error>,
+{
 	let boundary = req
 		.headers()
 		.get(header::CONTENT_TYPE)
@@ -119,20 +124,27 @@ pub async fn handle_post_object(
 		.resolve_bucket(&bucket_name, &api_key)
 		.await?;
 
-	if !api_key.allow_write(&bucket_id) {
-		return Err(Error::forbidden("Operation is not allowed for this key."));
-	}
-
 	let bucket = garage
 		.bucket_helper()
 		.get_existing_bucket(bucket_id)
 		.await?;
 	let bucket_params = bucket.state.into_option().unwrap();
+	check_bucket_allowed_method(&bucket_params, &Method::POST)?;
+
+	if !api_key.allow_write(&bucket_id) {
+		return Err(Error::forbidden("Operation is not allowed for this key."));
+	}
+	if *bucket_params.read_only.get() {
+		return Err(Error::forbidden(
+			"Bucket is read-only: objects and bucket configuration cannot be modified.",
+		));
+	}
+
 	let matching_cors_rule = find_matching_cors_rule(
 		&bucket_params,
 		&Request::from_parts(head.clone(), empty_body::<Infallible>()),
 	)?
-	.cloned();
+	.map(|(rule, origin)| (rule.clone(), origin.to_string()));
 
 	let decoded_policy = BASE64_STANDARD
 		.decode(policy)
@@ -237,17 +249,21 @@ pub async fn handle_post_object(
 	let meta = ObjectVersionMetaInner {
 		headers,
 		checksum: expected_checksums.extra,
+		full_sha256: None,
 	};
 
 	let encryption = EncryptionParams::new_from_headers(&garage, &params)?;
 
 	let stream = file_field.map(|r| r.map_err(Into::into));
+	let index_key = bucket_params.index_key(&key);
+	let original_key = Some(key.clone()).filter(|_| *bucket_params.case_insensitive_keys.get());
 	let ctx = ReqCtx {
 		garage,
 		bucket_id,
 		bucket_name,
 		bucket_params,
 		api_key,
+		original_key,
 	};
 
 	let res = save_stream(
@@ -255,8 +271,11 @@ pub async fn handle_post_object(
 		meta,
 		encryption,
 		StreamLimiter::new(stream, conditions.content_length),
-		&key,
+		None,
+		&index_key,
 		ChecksumMode::Verify(&expected_checksums),
+		None,
+		false,
 	)
 	.await?;
 
@@ -329,8 +348,14 @@ pub async fn handle_post_object(
 		}
 	};
 
-	if let Some(rule) = matching_cors_rule {
-		add_cors_headers(&mut resp, &rule)
+	if let Some(warning) = res.quota_warning {
+		if let Ok(warning) = HeaderValue::from_str(&warning) {
+			resp.headers_mut().insert(X_GARAGE_QUOTA_WARNING, warning);
+		}
+	}
+
+	if let Some((rule, origin)) = matching_cors_rule {
+		add_cors_headers(&mut resp, &rule, &origin)
 			.ok_or_internal_error("Invalid bucket CORS configuration")?;
 	}
 
@@ -1,9 +1,11 @@
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
 
 use futures::prelude::*;
 use futures::stream::FuturesOrdered;
 use futures::try_join;
+use md5::{Digest, Md5};
 
 use tokio::sync::mpsc;
 
@@ -12,6 +14,8 @@ use hyper::header::{HeaderMap, HeaderValue};
 use hyper::{Request, Response};
 
 use opentelemetry::{
+	global,
+	metrics::Counter,
 	trace::{FutureExt as OtelFutureExt, TraceContextExt, Tracer},
 	Context,
 };
@@ -19,30 +23,64 @@ use opentelemetry::{
 use garage_net::bytes_buf::BytesBuf;
 use garage_rpc::rpc_helper::OrderTag;
 use garage_table::*;
+use garage_util::config::{BlockSizeThreshold, Config, S3ApiConfig};
 use garage_util::data::*;
 use garage_util::error::Error as GarageError;
 use garage_util::time::*;
 
 use garage_block::manager::INLINE_THRESHOLD;
+use garage_model::bucket_table::BucketParams;
 use garage_model::garage::Garage;
 use garage_model::index_counter::CountedItem;
 use garage_model::s3::block_ref_table::*;
 use garage_model::s3::object_table::*;
+use garage_model::s3::previous_version::retain_previous_version;
 use garage_model::s3::version_table::*;
+use garage_model::usage_alert_worker::{usage_alert_thresholds, UsageAlertKind};
 
 use crate::helpers::*;
 use crate::s3::api_server::{ReqBody, ResBody};
 use crate::s3::checksum::*;
+use crate::s3::copy::copy_version_blocks;
 use crate::s3::encryption::EncryptionParams;
 use crate::s3::error::*;
+use crate::s3::headers::{
+	add_expiration_headers, add_version_headers, X_GARAGE_REDIRECT_CODE, X_GARAGE_REDIRECT_KEY,
+};
+use crate::s3::recent_puts::{is_current_version, PutRequest, RecentPut, RecentPuts};
+use crate::signature::sign::X_AMZ_DECODED_CONTENT_LENGTH;
 
 const PUT_BLOCKS_MAX_PARALLEL: usize = 3;
 
+/// Maximum total size of the user metadata of an object, in bytes
+const MAX_USER_METADATA_SIZE: usize = 2048;
+
+/// Request header by which a client asks for the deduplication ratio of an upload
+pub const X_GARAGE_REPORT_DEDUP: &str = "x-garage-report-dedup";
+/// Response header giving the fraction of the uploaded data that was stored
+/// in blocks that already existed
+pub const X_GARAGE_DEDUP_RATIO: &str = "x-garage-dedup-ratio";
+/// Response header to writes that bring the usage of a bucket over one of
+/// its usage alert thresholds (soft limits), while staying within its quotas
+pub const X_GARAGE_QUOTA_WARNING: &str = "x-garage-quota-warning";
+/// Request header by which a PutObject request appends its body
+/// to the existing object instead of replacing it
+pub const X_GARAGE_APPEND: &str = "x-garage-append";
+/// Response header giving the total size of an object after an append
+pub const X_GARAGE_OBJECT_SIZE: &str = "x-garage-object-size";
+
 pub(crate) struct SaveStreamResult {
 	pub(crate) version_uuid: Uuid,
 	pub(crate) version_timestamp: u64,
 	/// Etag WITHOUT THE QUOTES (just the hex value)
 	pub(crate) etag: String,
+	/// Checksum stored with the object, if any
+	pub(crate) checksum: Option<ChecksumValue>,
+	/// Fraction of the data of the object that was stored in blocks
+	/// that already existed, if it was requested
+	pub(crate) dedup_ratio: Option<f64>,
+	/// Usage alert thresholds of the bucket that are crossed after the write
+	pub(crate) quota_warning: Option<String>,
 }
 
 pub(crate) enum ChecksumMode<'a> {
@@ -50,32 +88,85 @@ pub(crate) enum ChecksumMode<'a> {
 	Calculate(Option<ChecksumAlgorithm>),
 }
 
-pub async fn handle_put(
+pub(crate) async fn handle_put(
 	ctx: ReqCtx,
 	req: Request<ReqBody>,
 	key: &String,
 	content_sha256: Option<Hash>,
+	recent_puts: &RecentPuts,
 ) -> Result<Response<ResBody>, Error> {
+	if parse_append_header(req.headers())? {
+		return handle_append(ctx, req, key, content_sha256).await;
+	}
+
 	// Retrieve interesting headers from request
-	let headers = get_headers(req.headers())?;
+	let mut headers = get_headers(req.headers())?;
 	debug!("Object headers: {:?}", headers);
 
 	let expected_checksums = ExpectedChecksums {
-		md5: match req.headers().get("content-md5") {
-			Some(x) => Some(x.to_str()?.to_string()),
-			None => None,
-		},
+		md5: request_content_md5(req.headers())?,
 		sha256: content_sha256,
 		extra: request_checksum_value(req.headers())?,
 	};
 
+	let expires_at = parse_expiration_ttl(req.headers())?;
+
+	// Determine whether object should be encrypted, and if so the key
+	let encryption = EncryptionParams::new_from_headers(&ctx.garage, req.headers())?;
+
+	if let Some(redirect) = parse_redirect_headers(req.headers())? {
+		if declared_object_size(req.headers()).unwrap_or(0) != 0 {
+			return Err(Error::bad_request(
+				"A redirect object must have an empty body",
+			));
+		}
+		if encryption.is_encrypted() {
+			return Err(Error::bad_request("A redirect object cannot be encrypted"));
+		}
+		headers.extend(redirect);
+	}
+
 	let meta = ObjectVersionMetaInner {
 		headers,
 		checksum: expected_checksums.extra,
+		full_sha256: None,
 	};
 
-	// Determine whether object should be encrypted, and if so the key
-	let encryption = EncryptionParams::new_from_headers(&ctx.garage, req.headers())?;
+	let report_dedup = parse_report_dedup_header(req.headers())?;
+	let declared_size = declared_object_size(req.headers());
+
+	// An upload whose signed content is the same as that of a recent upload
+	// to the same key, with the same headers, is answered without writing
+	// the object again. Anything that is not compared is excluded.
+	let recent_put_key = match (content_sha256, declared_size) {
+		(Some(sha256), Some(size))
+			if !encryption.is_encrypted() && expires_at.is_none() && !report_dedup =>
+		{
+			let request = PutRequest {
+				size,
+				headers: meta.headers.clone(),
+				checksum: meta.checksum,
+				original_key: ctx.original_key.clone(),
+			};
+			if let Some(put) =
+				recent_puts.find(ctx.bucket_id, key, sha256, &request, Instant::now())
+			{
+				let object = ctx.garage.object_table.get(&ctx.bucket_id, key).await?;
+				if is_current_version(object.as_ref(), put.version_uuid) {
+					debug!(
+						"PutObject of {} is identical to recent upload of version {:?}",
+						key, put.version_uuid
+					);
+					let resp =
+						add_version_headers(Response::builder(), put.version_uuid, Some(&put.etag));
+					let resp = add_checksum_response_headers(&expected_checksums.extra, resp);
+					return Ok(resp.body(empty_body())?);
+				}
+			}
+			Some((sha256, request))
+		}
+		_ => None,
+	};
 
 	let stream = body_stream(req.into_body());
 
@@ -84,14 +175,32 @@ pub async fn handle_put(
 		meta,
 		encryption,
 		stream,
+		declared_size,
 		key,
 		ChecksumMode::Verify(&expected_checksums),
+		expires_at,
+		report_dedup,
 	)
 	.await?;
 
-	let mut resp = Response::builder()
-		.header("x-amz-version-id", hex::encode(res.version_uuid))
-		.header("ETag", format!("\"{}\"", res.etag));
+	if let Some((sha256, request)) = recent_put_key {
+		let put = RecentPut {
+			request,
+			version_uuid: res.version_uuid,
+			etag: res.etag.clone(),
+			completed_at: Instant::now(),
+		};
+		recent_puts.record(ctx.bucket_id, key, sha256, put);
+	}
+
+	let mut resp = add_version_headers(Response::builder(), res.version_uuid, Some(&res.etag));
+	if let Some(ratio) = res.dedup_ratio {
+		resp = resp.header(X_GARAGE_DEDUP_RATIO, format!("{:.3}", ratio));
+	}
+	if let Some(warning) = &res.quota_warning {
+		resp = resp.header(X_GARAGE_QUOTA_WARNING, warning);
+	}
+	let mut resp = add_expiration_headers(resp, expires_at);
 	encryption.add_response_headers(&mut resp);
 	let resp = add_checksum_response_headers(&expected_checksums.extra, resp);
 	Ok(resp.body(empty_body())?)
@@ -102,14 +211,21 @@ pub(crate) async fn save_stream<S: Stream<Item = Result<Bytes, Error>> + Unpin>(
 	mut meta: ObjectVersionMetaInner,
 	encryption: EncryptionParams,
 	body: S,
+	declared_size: Option<u64>,
 	key: &String,
 	checksum_mode: ChecksumMode<'_>,
+	expires_at: Option<u64>,
+	report_dedup: bool,
 ) -> Result<SaveStreamResult, Error> {
 	let ReqCtx {
-		garage, bucket_id, ..
+		garage,
+		bucket_id,
+		bucket_params,
+		..
 	} = ctx;
 
-	let mut chunker = StreamChunker::new(body, garage.config.block_size);
+	let block_size = object_block_size(&garage.config, bucket_params, declared_size);
+	let mut chunker = StreamChunker::new(body, block_size);
 	let (first_block_opt, existing_object) = try_join!(
 		chunker.next(),
 		garage.object_table.get(bucket_id, key).map_err(Error::from),
@@ -119,7 +235,7 @@ pub(crate) async fn save_stream<S: Stream<Item = Result<Bytes, Error>> + Unpin>(
 
 	// Generate identity of new version
 	let version_uuid = gen_uuid();
-	let version_timestamp = next_timestamp(existing_object.as_ref());
+	let version_timestamp = next_timestamp(&*garage.clock, existing_object.as_ref());
 
 	let mut checksummer = match checksum_mode {
 		ChecksumMode::Verify(expected) => Checksummer::init(expected, !encryption.is_encrypted()),
@@ -127,6 +243,14 @@ pub(crate) async fn save_stream<S: Stream<Item = Result<Bytes, Error>> + Unpin>(
 			Checksummer::init(&Default::default(), !encryption.is_encrypted()).add(algo)
 		}
 	};
+	let always_sha256 = garage.config.s3_api.always_compute_sha256;
+	if always_sha256 {
+		checksummer = checksummer.add(Some(ChecksumAlgorithm::Sha256));
+	}
+	let checksum_algorithm = match checksum_mode {
+		ChecksumMode::Verify(expected) => expected.extra.map(|c| c.algorithm()),
+		ChecksumMode::Calculate(algo) => algo,
+	};
 
 	// If body is small enough, store it directly in the object table
 	// as "inline data". We can then return immediately.
@@ -142,12 +266,17 @@ pub(crate) async fn save_stream<S: Stream<Item = Result<Bytes, Error>> + Unpin>(
 				meta.checksum = checksums.extract(algo);
 			}
 		};
+		if always_sha256 {
+			meta.full_sha256 = checksums.sha256.map(Hash::from);
+		}
 
 		let size = first_block.len() as u64;
-		check_quotas(ctx, size, existing_object.as_ref()).await?;
+		check_size_limit(size, garage.config.s3_api.max_object_size)?;
+		let quota_warning = check_quotas(ctx, size, existing_object.as_ref()).await?;
 
 		let etag = encryption.etag_from_md5(&checksums.md5);
 		let inline_data = encryption.encrypt_blob(&first_block)?.to_vec();
+		let checksum = meta.checksum;
 
 		let object_version = ObjectVersion {
 			uuid: version_uuid,
@@ -157,18 +286,27 @@ pub(crate) async fn save_stream<S: Stream<Item = Result<Bytes, Error>> + Unpin>(
 					encryption: encryption.encrypt_meta(meta)?,
 					size,
 					etag: etag.clone(),
+					expires_at,
 				},
 				inline_data,
 			)),
 		};
 
-		let object = Object::new(*bucket_id, key.into(), vec![object_version]);
+		let mut object = Object::new(*bucket_id, key.into(), vec![object_version]);
+		retain_previous_version(&ctx.bucket_params, existing_object.as_ref(), &mut object);
+		if let Some(original_key) = &ctx.original_key {
+			object.set_original_key(version_timestamp, original_key);
+		}
 		garage.object_table.insert(&object).await?;
 
 		return Ok(SaveStreamResult {
 			version_uuid,
 			version_timestamp,
 			etag,
+			checksum,
+			// Inline data is never deduplicated
+			dedup_ratio: report_dedup.then_some(0.0),
+			quota_warning,
 		});
 	}
 
@@ -212,7 +350,7 @@ pub(crate) async fn save_stream<S: Stream<Item = Result<Bytes, Error>> + Unpin>(
 	garage.version_table.insert(&version).await?;
 
 	// Transfer data
-	let (total_size, checksums, first_block_hash) = read_and_put_blocks(
+	let (total_size, checksums, first_block_hash, dedup_bytes) = read_and_put_blocks(
 		ctx,
 		&version,
 		encryption,
@@ -220,6 +358,9 @@ pub(crate) async fn save_stream<S: Stream<Item = Result<Bytes, Error>> + Unpin>(
 		first_block,
 		&mut chunker,
 		checksummer,
+		block_checksum_algorithm(&garage.config.s3_api, checksum_algorithm),
+		garage.config.s3_api.max_object_size,
+		report_dedup,
 	)
 	.await?;
 
@@ -232,22 +373,31 @@ pub(crate) async fn save_stream<S: Stream<Item = Result<Bytes, Error>> + Unpin>(
 			meta.checksum = checksums.extract(algo);
 		}
 	};
+	if always_sha256 {
+		meta.full_sha256 = checksums.sha256.map(Hash::from);
+	}
 
 	// Verify quotas are respsected
-	check_quotas(ctx, total_size, existing_object.as_ref()).await?;
+	let quota_warning = check_quotas(ctx, total_size, existing_object.as_ref()).await?;
 
 	// Save final object state, marked as Complete
 	let etag = encryption.etag_from_md5(&checksums.md5);
+	let checksum = meta.checksum;
 
 	object_version.state = ObjectVersionState::Complete(ObjectVersionData::FirstBlock(
 		ObjectVersionMeta {
 			encryption: encryption.encrypt_meta(meta)?,
 			size: total_size,
 			etag: etag.clone(),
+			expires_at,
 		},
 		first_block_hash,
 	));
-	let object = Object::new(*bucket_id, key.into(), vec![object_version]);
+	let mut object = Object::new(*bucket_id, key.into(), vec![object_version]);
+	retain_previous_version(&ctx.bucket_params, existing_object.as_ref(), &mut object);
+	if let Some(original_key) = &ctx.original_key {
+		object.set_original_key(version_timestamp, original_key);
+	}
 	garage.object_table.insert(&object).await?;
 
 	// We were not interrupted, everything went fine.
@@ -258,15 +408,509 @@ pub(crate) async fn save_stream<S: Stream<Item = Result<Bytes, Error>> + Unpin>(
 		version_uuid,
 		version_timestamp,
 		etag,
+		checksum,
+		dedup_ratio: report_dedup.then(|| dedup_bytes as f64 / total_size as f64),
+		quota_warning,
 	})
 }
 
-/// Check that inserting this object with this size doesn't exceed bucket quotas
+/// Store an object made of `n_parts` parts, whose data is read from the
+/// streams returned by `next_part` for each part number. The object has the
+/// same structure as if it had been uploaded with a multipart upload, and so
+/// the same ETag, which is checked against `expected_etag` if it is given
+/// before the new version is committed.
+pub(crate) async fn save_multipart_stream<S, F, Fut>(
+	ctx: &ReqCtx,
+	mut meta: ObjectVersionMetaInner,
+	n_parts: u64,
+	key: &String,
+	expected_etag: Option<&str>,
+	mut next_part: F,
+) -> Result<SaveStreamResult, Error>
+where
+	S: Stream<Item = Result<Bytes, Error>> + Unpin,
+	F: FnMut(u64) -> Fut,
+	Fut: Future<Output = Result<S, Error>>,
+{
+	let ReqCtx {
+		garage,
+		bucket_id,
+		bucket_params,
+		..
+	} = ctx;
+
+	let existing_object = garage.object_table.get(bucket_id, key).await?;
+	let version_uuid = gen_uuid();
+	let version_timestamp = next_timestamp(&*garage.clock, existing_object.as_ref());
+
+	let mut interrupted_cleanup = InterruptedCleanup(Some(InterruptedCleanupInner {
+		garage: garage.clone(),
+		bucket_id: *bucket_id,
+		key: key.into(),
+		version_uuid,
+		version_timestamp,
+	}));
+
+	let mut object_version = ObjectVersion {
+		uuid: version_uuid,
+		timestamp: version_timestamp,
+		state: ObjectVersionState::Uploading {
+			encryption: EncryptionParams::Plaintext.encrypt_meta(meta.clone())?,
+			checksum_algorithm: None,
+			multipart: false,
+		},
+	};
+	let object = Object::new(*bucket_id, key.into(), vec![object_version.clone()]);
+	garage.object_table.insert(&object).await?;
+
+	let version = Version::new(
+		version_uuid,
+		VersionBacklink::Object {
+			bucket_id: *bucket_id,
+			key: key.into(),
+		},
+		false,
+	);
+	garage.version_table.insert(&version).await?;
+
+	let block_size = object_block_size(&garage.config, bucket_params, None);
+	let max_size = garage.config.s3_api.max_object_size;
+	let mut total_size = 0;
+	let mut first_block_hash = None;
+	let mut etag_md5 = Md5::new();
+	for part_number in 1..=n_parts {
+		let mut chunker = StreamChunker::new(next_part(part_number).await?, block_size);
+		let first_block = chunker
+			.next()
+			.await?
+			.ok_or_bad_request(format!("Part {} of the object is empty", part_number))?;
+		let (size, checksums, block_hash, _) = read_and_put_blocks(
+			ctx,
+			&version,
+			EncryptionParams::Plaintext,
+			part_number,
+			first_block,
+			&mut chunker,
+			Checksummer::init(&Default::default(), true),
+			None,
+			max_size.map(|max| max.saturating_sub(total_size)),
+			false,
+		)
+		.await?;
+		total_size += size;
+		first_block_hash.get_or_insert(block_hash);
+		etag_md5.update(&checksums.md5.unwrap_or_default()[..]);
+	}
+	let first_block_hash = first_block_hash.ok_or_bad_request("Object has no parts")?;
+
+	let etag = format!("{}-{}", hex::encode(etag_md5.finalize()), n_parts);
+	if let Some(expected) = expected_etag {
+		if etag != expected.trim_matches('"') {
+			return Err(Error::InvalidDigest(format!(
+				"ETag of the parts ({}) does not match the expected ETag ({})",
+				etag, expected
+			)));
+		}
+	}
+	let quota_warning = check_quotas(ctx, total_size, existing_object.as_ref()).await?;
+
+	if garage.config.s3_api.always_compute_sha256 {
+		let version = garage
+			.version_table
+			.get(&version_uuid, &EmptyKey)
+			.await?
+			.ok_or_internal_error("Version missing from version table")?;
+		let blocks = version.blocks.items().iter().map(|(_, b)| b.hash);
+		meta.full_sha256 = Some(blocks_sha256(garage, blocks).await?);
+	}
+
+	object_version.state = ObjectVersionState::Complete(ObjectVersionData::FirstBlock(
+		ObjectVersionMeta {
+			encryption: EncryptionParams::Plaintext.encrypt_meta(meta)?,
+			size: total_size,
+			etag: etag.clone(),
+			expires_at: None,
+		},
+		first_block_hash,
+	));
+	let mut object = Object::new(*bucket_id, key.into(), vec![object_version]);
+	retain_previous_version(bucket_params, existing_object.as_ref(), &mut object);
+	if let Some(original_key) = &ctx.original_key {
+		object.set_original_key(version_timestamp, original_key);
+	}
+	garage.object_table.insert(&object).await?;
+
+	interrupted_cleanup.cancel();
+
+	Ok(SaveStreamResult {
+		version_uuid,
+		version_timestamp,
+		etag,
+		checksum: None,
+		dedup_ratio: None,
+		quota_warning,
+	})
+}
+
+/// Append the body of a PutObject request with the `x-garage-append: true`
+/// header to an existing object. The new version of the object references
+/// all the data blocks of the current one, and the body is stored in new
+/// blocks, as an additional part: the existing data is never rewritten.
+///
+/// The request must have an `If-Match` header with the ETag of the current
+/// version, so that concurrent appends don't silently overwrite each other:
+/// the append fails with 412 PreconditionFailed if the object has changed,
+/// and the client can read the new ETag and retry. Only objects stored in
+/// data blocks and without SSE-C encryption can be appended to.
+async fn handle_append(
+	ctx: ReqCtx,
+	req: Request<ReqBody>,
+	key: &String,
+	content_sha256: Option<Hash>,
+) -> Result<Response<ResBody>, Error> {
+	let ReqCtx {
+		garage,
+		bucket_id,
+		bucket_params,
+		..
+	} = &ctx;
+
+	let if_match = req
+		.headers()
+		.get(hyper::header::IF_MATCH)
+		.ok_or_bad_request("x-garage-append requires an If-Match header with the current ETag")?
+		.to_str()?
+		.trim()
+		.trim_matches('"')
+		.to_string();
+	if EncryptionParams::new_from_headers(garage, req.headers())?.is_encrypted() {
+		return Err(Error::bad_request(
+			"x-garage-append cannot be used with SSE-C encryption",
+		));
+	}
+
+	let expected_checksums = ExpectedChecksums {
+		md5: request_content_md5(req.headers())?,
+		sha256: content_sha256,
+		extra: request_checksum_value(req.headers())?,
+	};
+
+	// Find the current version and check that it can be appended to
+	let existing_object = garage
+		.object_table
+		.get(bucket_id, key)
+		.await?
+		.ok_or(Error::PreconditionFailed)?;
+	let current = existing_object
+		.versions()
+		.iter()
+		.rev()
+		.find(|v| v.is_complete())
+		.ok_or(Error::PreconditionFailed)?;
+	let (current_meta, first_block_hash) = match &current.state {
+		ObjectVersionState::Complete(ObjectVersionData::FirstBlock(meta, hash)) => (meta, *hash),
+		ObjectVersionState::Complete(ObjectVersionData::Inline(meta, _))
+			if meta.etag == if_match =>
+		{
+			return Err(Error::bad_request(
+				"x-garage-append: objects whose data is stored inline cannot be appended to",
+			));
+		}
+		_ => return Err(Error::PreconditionFailed),
+	};
+	if current_meta.etag != if_match {
+		return Err(Error::PreconditionFailed);
+	}
+	let mut meta = match &current_meta.encryption {
+		ObjectVersionEncryption::Plaintext { inner } => inner.clone(),
+		ObjectVersionEncryption::SseC { .. } => {
+			return Err(Error::bad_request(
+				"x-garage-append: encrypted objects cannot be appended to",
+			));
+		}
+	};
+	// The checksums of the whole object are not known anymore,
+	// as the existing data is not read again
+	meta.checksum = None;
+	meta.full_sha256 = None;
+
+	let current_version = garage
+		.version_table
+		.get(&current.uuid, &EmptyKey)
+		.await?
+		.ok_or(Error::NoSuchKey)?;
+	let part_number = current_version.n_parts()? + 1;
+
+	let block_size = object_block_size(
+		&garage.config,
+		bucket_params,
+		declared_object_size(req.headers()),
+	);
+	let mut chunker = StreamChunker::new(body_stream(req.into_body()), block_size);
+	let first_block = chunker
+		.next()
+		.await?
+		.ok_or_bad_request("x-garage-append: nothing to append")?;
+
+	let version_uuid = gen_uuid();
+	let version_timestamp = next_timestamp(&*garage.clock, Some(&existing_object));
+
+	let mut interrupted_cleanup = InterruptedCleanup(Some(InterruptedCleanupInner {
+		garage: garage.clone(),
+		bucket_id: *bucket_id,
+		key: key.into(),
+		version_uuid,
+		version_timestamp,
+	}));
+
+	// Write the new version as uploading, and give it the blocks of the
+	// current version, before the appended data is written
+	let mut object_version = ObjectVersion {
+		uuid: version_uuid,
+		timestamp: version_timestamp,
+		state: ObjectVersionState::Uploading {
+			encryption: EncryptionParams::Plaintext.encrypt_meta(meta.clone())?,
+			checksum_algorithm: None,
+			multipart: false,
+		},
+	};
+	let object = Object::new(*bucket_id, key.into(), vec![object_version.clone()]);
+	garage.object_table.insert(&object).await?;
+
+	let version = Version::new(
+		version_uuid,
+		VersionBacklink::Object {
+			bucket_id: *bucket_id,
+			key: key.into(),
+		},
+		false,
+	);
+	garage.version_table.insert(&version).await?;
+	copy_version_blocks(
+		garage,
+		*bucket_params.replication_factor.get(),
+		&current_version,
+		&version,
+	)
+	.await?;
+
+	let (appended_size, checksums, _, _) = read_and_put_blocks(
+		&ctx,
+		&version,
+		EncryptionParams::Plaintext,
+		part_number,
+		first_block,
+		&mut chunker,
+		Checksummer::init(&expected_checksums, true),
+		block_checksum_algorithm(
+			&garage.config.s3_api,
+			expected_checksums.extra.map(|c| c.algorithm()),
+		),
+		garage
+			.config
+			.s3_api
+			.max_object_size
+			.map(|max| max.saturating_sub(current_meta.size)),
+		false,
+	)
+	.await?;
+	checksums.verify(&expected_checksums)?;
+
+	let total_size = current_meta.size + appended_size;
+	let quota_warning = check_quotas(&ctx, total_size, Some(&existing_object)).await?;
+
+	// Check that no other write was made to the object in the meantime,
+	// e.g. a concurrent append that would otherwise be lost
+	let still_current = garage
+		.object_table
+		.get(bucket_id, key)
+		.await?
+		.and_then(|o| {
+			o.versions()
+				.iter()
+				.rev()
+				.find(|v| v.is_complete())
+				.map(|v| v.uuid)
+		});
+	if still_current != Some(current.uuid) {
+		return Err(Error::PreconditionFailed);
+	}
+
+	let etag = append_etag(&current_meta.etag, &checksums.md5, part_number);
+	object_version.state = ObjectVersionState::Complete(ObjectVersionData::FirstBlock(
+		ObjectVersionMeta {
+			encryption: EncryptionParams::Plaintext.encrypt_meta(meta)?,
+			size: total_size,
+			etag: etag.clone(),
+			expires_at: current_meta.expires_at,
+		},
+		first_block_hash,
+	));
+	let mut object = Object::new(*bucket_id, key.into(), vec![object_version]);
+	retain_previous_version(bucket_params, Some(&existing_object), &mut object);
+	if let Some(original_key) = &ctx.original_key {
+		object.set_original_key(version_timestamp, original_key);
+	}
+	garage.object_table.insert(&object).await?;
+
+	interrupted_cleanup.cancel();
+
+	let mut resp = add_version_headers(Response::builder(), version_uuid, Some(&etag))
+		.header(X_GARAGE_OBJECT_SIZE, total_size.to_string());
+	if let Some(warning) = quota_warning {
+		resp = resp.header(X_GARAGE_QUOTA_WARNING, warning);
+	}
+	Ok(resp.body(empty_body())?)
+}
+
+/// ETag of an object after an append: as the existing data is not read
+/// again, it is derived from the previous ETag and the MD5 of the appended
+/// data, and counts each append as a part like the ETag of multipart uploads
+fn append_etag(previous_etag: &str, appended_md5: &Option<Md5Checksum>, n_parts: u64) -> String {
+	let mut hasher = Md5::new();
+	hasher.update(previous_etag.as_bytes());
+	if let Some(md5) = appended_md5 {
+		hasher.update(&md5[..]);
+	}
+	format!("{}-{}", hex::encode(hasher.finalize()), n_parts)
+}
+
+/// Parse the Garage-specific `x-garage-append` header
+fn parse_append_header(headers: &HeaderMap<HeaderValue>) -> Result<bool, Error> {
+	match headers.get(X_GARAGE_APPEND) {
+		None => Ok(false),
+		Some(v) => match v.to_str()? {
+			"true" => Ok(true),
+			"false" => Ok(false),
+			_ => Err(Error::bad_request(
+				"Invalid value for x-garage-append, expected true or false",
+			)),
+		},
+	}
+}
+
+/// Parse the Garage-specific `x-garage-redirect-key` and `x-garage-redirect-code`
+/// headers, by which a PutObject request creates a redirect object: GET and HEAD
+/// requests on it are redirected to another key of the bucket. The headers are
+/// returned to be stored with the object.
+fn parse_redirect_headers(headers: &HeaderMap<HeaderValue>) -> Result<Option<HeaderList>, Error> {
+	let code = match headers.get(X_GARAGE_REDIRECT_CODE) {
+		None => None,
+		Some(code) => match code.to_str()? {
+			c @ ("301" | "302") => Some(c.to_string()),
+			_ => {
+				return Err(Error::bad_request(
+					"Invalid value for x-garage-redirect-code, expected 301 or 302",
+				))
+			}
+		},
+	};
+	let target = match headers.get(X_GARAGE_REDIRECT_KEY) {
+		Some(target) => std::str::from_utf8(target.as_bytes())?.to_string(),
+		None if code.is_some() => {
+			return Err(Error::bad_request(
+				"x-garage-redirect-code requires x-garage-redirect-key",
+			))
+		}
+		None => return Ok(None),
+	};
+	// The target is sent as a relative URL, which must not be
+	// changed by the resolution of dot segments, and as an absolute path
+	// on the website endpoint, which must not start with two slashes
+	if target.is_empty()
+		|| target.starts_with('/')
+		|| target.split('/').any(|s| s == "." || s == "..")
+	{
+		return Err(Error::bad_request(
+			"Invalid value for x-garage-redirect-key, expected an object key",
+		));
+	}
+
+	let mut ret = vec![(X_GARAGE_REDIRECT_KEY.to_string(), target)];
+	if let Some(code) = code {
+		ret.push((X_GARAGE_REDIRECT_CODE.to_string(), code));
+	}
+	Ok(Some(ret))
+}
+
+/// Parse the Garage-specific `x-garage-report-dedup` header, by which a
+/// client asks for the deduplication ratio of an upload in the response
+pub(crate) fn parse_report_dedup_header(headers: &HeaderMap<HeaderValue>) -> Result<bool, Error> {
+	match headers.get(X_GARAGE_REPORT_DEDUP) {
+		None => Ok(false),
+		Some(v) => match v.to_str()? {
+			"true" => Ok(true),
+			"false" => Ok(false),
+			_ => Err(Error::bad_request(
+				"Invalid value for x-garage-report-dedup, expected true or false",
+			)),
+		},
+	}
+}
+
+/// Size of the object uploaded by a request, as declared by the client
+/// (the size of the decoded body for streaming signed uploads)
+fn declared_object_size(headers: &HeaderMap<HeaderValue>) -> Option<u64> {
+	headers
+		.get(X_AMZ_DECODED_CONTENT_LENGTH)
+		.or_else(|| headers.get(hyper::header::CONTENT_LENGTH))
+		.and_then(|v| v.to_str().ok())
+		.and_then(|v| v.parse().ok())
+}
+
+/// Size of the data blocks of a new object whose size is `declared_size`
+/// if it is known: the bucket's block size if one is set, otherwise the
+/// block size of the largest threshold of `block_size_by_object_size`
+/// reached by the object, or the cluster's `block_size`.
+/// The size of each block is stored in the version of the object,
+/// so that objects can be read whatever block size they were written with.
+pub(crate) fn object_block_size(
+	config: &Config,
+	bucket_params: &BucketParams,
+	declared_size: Option<u64>,
+) -> usize {
+	match bucket_params.block_size.get() {
+		Some(block_size) => *block_size,
+		None => auto_block_size(&config.block_size_by_object_size, declared_size)
+			.unwrap_or(config.block_size),
+	}
+}
+
+fn auto_block_size(thresholds: &[BlockSizeThreshold], object_size: Option<u64>) -> Option<usize> {
+	let object_size = object_size?;
+	thresholds
+		.iter()
+		.filter(|t| object_size >= t.object_size as u64)
+		.max_by_key(|t| t.object_size)
+		.map(|t| t.block_size)
+}
+
+/// Check that an object or a part of this size doesn't exceed the maximum size
+/// set in the configuration
+pub(crate) fn check_size_limit(size: u64, max_size: Option<u64>) -> Result<(), Error> {
+	match max_size {
+		Some(max) if size > max => Err(Error::EntityTooLarge(max)),
+		_ => Ok(()),
+	}
+}
+
+/// Maximum size of a part of a multipart upload, which can be
+/// no larger than an object
+pub(crate) fn max_part_size(config: &S3ApiConfig) -> Option<u64> {
+	match (config.max_part_size, config.max_object_size) {
+		(Some(part), Some(object)) => Some(std::cmp::min(part, object)),
+		(part, object) => part.or(object),
+	}
+}
+
+/// Check that inserting this object with this size doesn't exceed bucket quotas.
+/// If the object is accepted but brings the usage of the bucket over one of its
+/// usage alert thresholds, return the warning to send in x-garage-quota-warning
 pub(crate) async fn check_quotas(
 	ctx: &ReqCtx,
 	size: u64,
 	prev_object: Option<&Object>,
-) -> Result<(), Error> {
+) -> Result<Option<String>, Error> {
 	let ReqCtx {
 		garage,
 		bucket_id,
@@ -275,8 +919,9 @@ pub(crate) async fn check_quotas(
 	} = ctx;
 
 	let quotas = bucket_params.quotas.get();
-	if quotas.max_objects.is_none() && quotas.max_size.is_none() {
-		return Ok(());
+	let alert_thresholds = usage_alert_thresholds(bucket_params);
+	if quotas.max_objects.is_none() && quotas.max_size.is_none() && alert_thresholds.is_empty() {
+		return Ok(None);
 	};
 
 	let counters = garage
@@ -301,9 +946,10 @@ pub(crate) async fn check_quotas(
 	};
 	let cnt_obj_diff = 1 - prev_cnt_obj;
 	let cnt_size_diff = size as i64 - prev_cnt_size;
+	let current_objects = counters.get(OBJECTS).cloned().unwrap_or_default();
+	let current_size = counters.get(BYTES).cloned().unwrap_or_default();
 
 	if let Some(mo) = quotas.max_objects {
-		let current_objects = counters.get(OBJECTS).cloned().unwrap_or_default();
 		if cnt_obj_diff > 0 && current_objects + cnt_obj_diff > mo as i64 {
 			return Err(Error::forbidden(format!(
 				"Object quota is reached, maximum objects for this bucket: {}",
@@ -313,7 +959,6 @@ pub(crate) async fn check_quotas(
 	}
 
 	if let Some(ms) = quotas.max_size {
-		let current_size = counters.get(BYTES).cloned().unwrap_or_default();
 		if cnt_size_diff > 0 && current_size + cnt_size_diff > ms as i64 {
 			return Err(Error::forbidden(format!(
 				"Bucket size quota is reached, maximum total size of objects for this bucket: {}. The bucket is already {} bytes, and this object would add {} bytes.",
@@ -322,7 +967,56 @@ pub(crate) async fn check_quotas(
 		}
 	}
 
-	Ok(())
+	let new_objects = (current_objects + cnt_obj_diff).max(0) as u64;
+	let new_size = (current_size + cnt_size_diff).max(0) as u64;
+	Ok(quota_warning(&alert_thresholds, new_objects, new_size))
+}
+
+/// Usage alert thresholds that are crossed by the usage of a bucket, in the
+/// format of the x-garage-quota-warning header, e.g. `size=850;threshold=800`
+fn quota_warning(
+	alert_thresholds: &[(UsageAlertKind, u64)],
+	objects: u64,
+	size: u64,
+) -> Option<String> {
+	let warnings = alert_thresholds
+		.iter()
+		.filter_map(|(kind, threshold)| {
+			let usage = match kind {
+				UsageAlertKind::Size => size,
+				UsageAlertKind::Objects => objects,
+			};
+			(usage >= *threshold)
+				.then(|| format!("{}={};threshold={}", kind.name(), usage, threshold))
+		})
+		.collect::<Vec<_>>();
+	if warnings.is_empty() {
+		return None;
+	}
+
+	quota_warning_counter().add(1, &[]);
+	Some(warnings.join(", "))
+}
+
+fn quota_warning_counter() -> &'static Counter<u64> {
+	static COUNTER: OnceLock<Counter<u64>> = OnceLock::new();
+	COUNTER.get_or_init(|| {
+		global::meter("garage/api")
+			.u64_counter("api.s3.quota_warning_counter")
+			.with_description(
+				"Number of writes that brought the usage of a bucket over one of its usage alert thresholds",
+			)
+			.init()
+	})
+}
+
+/// Algorithm with which the checksum of each data block is computed,
+/// if block checksums are stored for an object uploaded with a checksum
+pub(crate) fn block_checksum_algorithm(
+	config: &S3ApiConfig,
+	object_checksum: Option<ChecksumAlgorithm>,
+) -> Option<ChecksumAlgorithm> {
+	object_checksum.filter(|_| config.store_block_checksums)
 }
 
 pub(crate) async fn read_and_put_blocks<S: Stream<Item = Result<Bytes, Error>> + Unpin>(
@@ -333,9 +1027,22 @@ pub(crate) async fn read_and_put_blocks<S: Stream<Item = Result<Bytes, Error>> +
 	first_block: Bytes,
 	chunker: &mut StreamChunker<S>,
 	checksummer: Checksummer,
-) -> Result<(u64, Checksums, Hash), Error> {
+	block_checksum_algorithm: Option<ChecksumAlgorithm>,
+	max_size: Option<u64>,
+	report_dedup: bool,
+) -> Result<(u64, Checksums, Hash, u64), Error> {
+	// The body is processed as a pipeline of stages connected by bounded
+	// channels, and checksums are updated block by block. At most about ten
+	// blocks are held in memory at any time, whatever the size of the object:
+	// the block being read by the chunker, those waiting in the channels or
+	// being hashed and encrypted, and the PUT_BLOCKS_MAX_PARALLEL blocks being
+	// written. Expected checksums are verified by the caller once the whole
+	// body has been read, before the new version is committed.
 	let tracer = opentelemetry::global::tracer("garage");
 
+	let mut read_bytes = first_block.len() as u64;
+	check_size_limit(read_bytes, max_size)?;
+
 	let (block_tx, mut block_rx) = mpsc::channel::<Result<Bytes, Error>>(2);
 	let read_blocks = async {
 		block_tx.send(Ok(first_block)).await?;
@@ -347,7 +1054,16 @@ pub(crate) async fn read_and_put_blocks<S: Stream<Item = Result<Bytes, Error>> +
 				))
 				.await;
 			match res {
-				Ok(Some(block)) => block_tx.send(Ok(block)).await?,
+				Ok(Some(block)) => {
+					// Stop reading as soon as the data is too large: the error
+					// goes through the pipeline and the upload is cleaned up
+					read_bytes += block.len() as u64;
+					if let Err(e) = check_size_limit(read_bytes, max_size) {
+						block_tx.send(Err(e)).await?;
+						break;
+					}
+					block_tx.send(Ok(block)).await?
+				}
 				Ok(None) => break,
 				Err(e) => {
 					block_tx.send(Err(e)).await?;
@@ -386,7 +1102,8 @@ pub(crate) async fn read_and_put_blocks<S: Stream<Item = Result<Bytes, Error>> +
 		Ok::<_, mpsc::error::SendError<_>>(checksummer)
 	};
 
-	let (block_tx3, mut block_rx3) = mpsc::channel::<Result<(Bytes, u64, Hash), Error>>(1);
+	let (block_tx3, mut block_rx3) =
+		mpsc::channel::<Result<(Bytes, u64, Hash, Option<ChecksumValue>), Error>>(1);
 	let encrypt_hash_blocks = async {
 		let mut first_block_hash = None;
 		while let Some(next) = block_rx2.recv().await {
@@ -394,9 +1111,11 @@ pub(crate) async fn read_and_put_blocks<S: Stream<Item = Result<Bytes, Error>> +
 				Ok(block) => {
 					let unencrypted_len = block.len() as u64;
 					let res = tokio::task::spawn_blocking(move || {
+						let checksum =
+							block_checksum_algorithm.map(|algo| block_checksum(algo, &block));
 						let block = encryption.encrypt_block(block)?;
 						let hash = blake2sum(&block);
-						Ok((block, hash))
+						Ok((block, hash, checksum))
 					})
 					.with_context(Context::current_with_span(
 						tracer.start("Encrypt and hash (blake2) block"),
@@ -404,11 +1123,13 @@ pub(crate) async fn read_and_put_blocks<S: Stream<Item = Result<Bytes, Error>> +
 					.await
 					.unwrap();
 					match res {
-						Ok((block, hash)) => {
+						Ok((block, hash, checksum)) => {
 							if first_block_hash.is_none() {
 								first_block_hash = Some(hash);
 							}
-							block_tx3.send(Ok((block, unencrypted_len, hash))).await?;
+							block_tx3
+								.send(Ok((block, unencrypted_len, hash, checksum)))
+								.await?;
 						}
 						Err(e) => {
 							block_tx3.send(Err(e)).await?;
@@ -431,6 +1152,7 @@ pub(crate) async fn read_and_put_blocks<S: Stream<Item = Result<Bytes, Error>> +
 		let order_stream = OrderTag::stream();
 		let mut write_futs = FuturesOrdered::new();
 		let mut written_bytes = 0u64;
+		let mut dedup_bytes = 0u64;
 		loop {
 			// Simultaneously write blocks to storage nodes & await for next block to be written
 			let currently_running = write_futs.len();
@@ -449,9 +1171,9 @@ pub(crate) async fn read_and_put_blocks<S: Stream<Item = Result<Bytes, Error>> +
 					block_rx3.recv().await
 				}
 			};
-			let (block, unencrypted_len, hash) = tokio::select! {
+			let (block, unencrypted_len, hash, checksum) = tokio::select! {
 				result = write_futs_next => {
-					result?;
+					dedup_bytes += result?;
 					continue;
 				},
 				recv = recv_next => match recv {
@@ -469,27 +1191,29 @@ pub(crate) async fn read_and_put_blocks<S: Stream<Item = Result<Bytes, Error>> +
 				hash,
 				block,
 				unencrypted_len,
+				checksum,
 				encryption.is_encrypted(),
 				order_stream.order(written_bytes),
+				report_dedup,
 			));
 			written_bytes += unencrypted_len;
 		}
 		while let Some(res) = write_futs.next().await {
-			res?;
+			dedup_bytes += res?;
 		}
-		Ok::<_, Error>(written_bytes)
+		Ok::<_, Error>((written_bytes, dedup_bytes))
 	};
 
 	let (_, stream_hash_result, block_hash_result, final_result) =
 		futures::join!(read_blocks, hash_stream, encrypt_hash_blocks, put_blocks);
 
-	let total_size = final_result?;
+	let (total_size, dedup_bytes) = final_result?;
 	// unwrap here is ok, because if hasher failed, it is because something failed
 	// later in the pipeline which already caused a return at the ? on previous line
 	let first_block_hash = block_hash_result.unwrap();
 	let checksums = stream_hash_result.unwrap().finalize();
 
-	Ok((total_size, checksums, first_block_hash))
+	Ok((total_size, checksums, first_block_hash, dedup_bytes))
 }
 
 async fn put_block_and_meta(
@@ -500,10 +1224,17 @@ async fn put_block_and_meta(
 	hash: Hash,
 	block: Bytes,
 	size: u64,
+	checksum: Option<ChecksumValue>,
 	is_encrypted: bool,
 	order_tag: OrderTag,
-) -> Result<(), GarageError> {
-	let ReqCtx { garage, .. } = ctx;
+	check_existing: bool,
+) -> Result<u64, GarageError> {
+	let ReqCtx {
+		garage,
+		bucket_params,
+		..
+	} = ctx;
+	let replication_factor = *bucket_params.replication_factor.get();
 
 	let mut version = version.clone();
 	version.blocks.put(
@@ -511,23 +1242,50 @@ async fn put_block_and_meta(
 			part_number,
 			offset,
 		},
-		VersionBlock { hash, size },
+		VersionBlock {
+			hash,
+			size,
+			checksum,
+		},
 	);
 
 	let block_ref = BlockRef {
 		block: hash,
 		version: version.uuid,
 		deleted: false.into(),
+		replication_factor,
+	};
+
+	// The block is deduplicated if it is already referenced by another
+	// version, this must be checked before our reference is written
+	let existing = if check_existing {
+		!garage
+			.block_ref_table
+			.get_range(
+				&hash,
+				None,
+				Some(DeletedFilter::NotDeleted),
+				1,
+				EnumerationOrder::Forward,
+			)
+			.await?
+			.is_empty()
+	} else {
+		false
 	};
 
 	futures::try_join!(
-		garage
-			.block_manager
-			.rpc_put_block(hash, block, is_encrypted, Some(order_tag)),
+		garage.block_manager.rpc_put_block(
+			hash,
+			block,
+			is_encrypted,
+			Some(order_tag),
+			replication_factor,
+		),
 		garage.version_table.insert(&version),
 		garage.block_ref_table.insert(&block_ref),
 	)?;
-	Ok(())
+	Ok(if existing { size } else { 0 })
 }
 
 pub(crate) struct StreamChunker<S: Stream<Item = Result<Bytes, Error>>> {
@@ -618,23 +1376,196 @@ pub(crate) fn get_headers(headers: &HeaderMap<HeaderValue>) -> Result<HeaderList
 		}
 	}
 
-	// Preserve x-amz-meta- headers
+	// Preserve x-amz-meta- headers, whose total size is limited as in AWS S3:
+	// the size of user metadata is the sum of the sizes of the keys (without
+	// the x-amz-meta- prefix) and of the values
+	let mut user_metadata_size = 0;
 	for (name, value) in headers.iter() {
-		if name.as_str().starts_with("x-amz-meta-") {
+		if let Some(meta_key) = name.as_str().strip_prefix("x-amz-meta-") {
+			user_metadata_size += meta_key.len() + value.len();
 			ret.push((
 				name.to_string(),
 				std::str::from_utf8(value.as_bytes())?.to_string(),
 			));
 		}
 	}
+	if user_metadata_size > MAX_USER_METADATA_SIZE {
+		return Err(Error::MetadataTooLarge(MAX_USER_METADATA_SIZE));
+	}
 
 	Ok(ret)
 }
 
-pub(crate) fn next_timestamp(existing_object: Option<&Object>) -> u64 {
-	existing_object
-		.as_ref()
-		.and_then(|obj| obj.versions().iter().map(|v| v.timestamp).max())
-		.map(|t| std::cmp::max(t + 1, now_msec()))
-		.unwrap_or_else(now_msec)
+/// Get the time at which an object expires from the x-amz-expiration-ttl header
+/// (a Garage extension), which gives its time to live in seconds
+fn parse_expiration_ttl(headers: &HeaderMap<HeaderValue>) -> Result<Option<u64>, Error> {
+	match headers.get("x-amz-expiration-ttl") {
+		None => Ok(None),
+		Some(ttl) => {
+			let ttl = ttl.to_str()?.parse::<u64>().ok_or_bad_request(
+				"Invalid x-amz-expiration-ttl header, expected a number of seconds",
+			)?;
+			Ok(Some(now_msec().saturating_add(ttl.saturating_mul(1000))))
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const MB: usize = 1 << 20;
+
+	fn thresholds() -> Vec<BlockSizeThreshold> {
+		// Not sorted, to check that the largest threshold reached is used
+		vec![
+			BlockSizeThreshold {
+				object_size: 1024 * MB,
+				block_size: 16 * MB,
+			},
+			BlockSizeThreshold {
+				object_size: 100 * MB,
+				block_size: 4 * MB,
+			},
+			BlockSizeThreshold {
+				object_size: 0,
+				block_size: 256 * 1024,
+			},
+		]
+	}
+
+	#[test]
+	fn test_auto_block_size() {
+		let t = thresholds();
+		let size = |s: usize| auto_block_size(&t, Some(s as u64));
+
+		assert_eq!(size(0), Some(256 * 1024));
+		assert_eq!(size(10 * MB), Some(256 * 1024));
+		assert_eq!(size(100 * MB - 1), Some(256 * 1024));
+		assert_eq!(size(100 * MB), Some(4 * MB));
+		assert_eq!(size(500 * MB), Some(4 * MB));
+		assert_eq!(size(1024 * MB), Some(16 * MB));
+		assert_eq!(size(10 * 1024 * MB), Some(16 * MB));
+
+		// Unknown size, or no threshold reached
+		assert_eq!(auto_block_size(&t, None), None);
+		assert_eq!(auto_block_size(&t[..2], Some(MB as u64)), None);
+		assert_eq!(auto_block_size(&[], Some(MB as u64)), None);
+	}
+
+	#[test]
+	fn test_declared_object_size() {
+		let mut headers = HeaderMap::new();
+		assert_eq!(declared_object_size(&headers), None);
+
+		headers.insert(
+			hyper::header::CONTENT_LENGTH,
+			HeaderValue::from_static("1234"),
+		);
+		assert_eq!(declared_object_size(&headers), Some(1234));
+
+		// Streaming signed uploads declare the size of the decoded body
+		headers.insert(
+			hyper::header::CONTENT_LENGTH,
+			HeaderValue::from_static("1500"),
+		);
+		headers.insert(
+			X_AMZ_DECODED_CONTENT_LENGTH,
+			HeaderValue::from_static("1234"),
+		);
+		assert_eq!(declared_object_size(&headers), Some(1234));
+	}
+
+	#[test]
+	fn test_parse_redirect_headers() {
+		let mut headers = HeaderMap::new();
+		assert_eq!(parse_redirect_headers(&headers).unwrap(), None);
+
+		headers.insert(X_GARAGE_REDIRECT_CODE, HeaderValue::from_static("302"));
+		assert!(parse_redirect_headers(&headers).is_err());
+
+		headers.insert(X_GARAGE_REDIRECT_KEY, HeaderValue::from_static("new/key"));
+		assert_eq!(
+			parse_redirect_headers(&headers).unwrap(),
+			Some(vec![
+				(X_GARAGE_REDIRECT_KEY.to_string(), "new/key".to_string()),
+				(X_GARAGE_REDIRECT_CODE.to_string(), "302".to_string()),
+			])
+		);
+
+		headers.insert(X_GARAGE_REDIRECT_CODE, HeaderValue::from_static("307"));
+		assert!(parse_redirect_headers(&headers).is_err());
+
+		headers.remove(X_GARAGE_REDIRECT_CODE);
+		for key in ["", "a/../b", "./b", "/evil.com", "//evil.com"] {
+			headers.insert(X_GARAGE_REDIRECT_KEY, HeaderValue::from_static(key));
+			assert!(parse_redirect_headers(&headers).is_err());
+		}
+	}
+
+	#[tokio::test]
+	async fn test_stream_chunker_bounded_memory() {
+		// As many blocks as a 4 GiB object with 1 MiB blocks,
+		// with 4 KiB blocks received in frames of 1500 bytes
+		const BLOCK_SIZE: usize = 4096;
+		const N_BLOCKS: usize = 4096;
+		const FRAME_SIZE: usize = 1500;
+		const TOTAL_SIZE: usize = BLOCK_SIZE * N_BLOCKS;
+
+		// Frames are generated only as the body is read
+		let frames = || {
+			(0..TOTAL_SIZE).step_by(FRAME_SIZE).map(|start| {
+				(start..std::cmp::min(start + FRAME_SIZE, TOTAL_SIZE))
+					.map(|i| (i % 251) as u8)
+					.collect::<Vec<_>>()
+			})
+		};
+		let mut hasher = sha2::Sha256::new();
+		for frame in frames() {
+			hasher.update(&frame);
+		}
+		let mut sha256 = [0u8; 32];
+		sha256.copy_from_slice(&hasher.finalize());
+		let expected = ExpectedChecksums {
+			sha256: Some(sha256.into()),
+			..Default::default()
+		};
+
+		let body = futures::stream::iter(frames().map(|f| Ok::<_, Error>(Bytes::from(f))));
+		let mut chunker = StreamChunker::new(body, BLOCK_SIZE);
+		let mut checksummer = Checksummer::init(&expected, true);
+		let mut n_blocks = 0;
+		while let Some(block) = chunker.next().await.unwrap() {
+			assert_eq!(block.len(), BLOCK_SIZE);
+			// Besides the block being processed, only the beginning
+			// of the next block is kept in memory
+			assert!(chunker.buf.len() < FRAME_SIZE);
+			checksummer.update(&block);
+			n_blocks += 1;
+		}
+		assert_eq!(n_blocks, N_BLOCKS);
+
+		let checksums = checksummer.finalize();
+		checksums.verify(&expected).unwrap();
+		let wrong = ExpectedChecksums {
+			sha256: Some(Hash::default()),
+			..Default::default()
+		};
+		assert!(checksums.verify(&wrong).is_err());
+	}
+
+	#[test]
+	fn test_quota_warning() {
+		let thresholds = vec![(UsageAlertKind::Size, 800), (UsageAlertKind::Objects, 3)];
+		assert_eq!(quota_warning(&thresholds, 2, 799), None);
+		assert_eq!(quota_warning(&[], 100, 100_000), None);
+		assert_eq!(
+			quota_warning(&thresholds, 2, 850).as_deref(),
+			Some("size=850;threshold=800")
+		);
+		assert_eq!(
+			quota_warning(&thresholds, 3, 900).as_deref(),
+			Some("size=900;threshold=800, objects=3;threshold=3")
+		);
+	}
 }
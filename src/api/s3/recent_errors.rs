@@ -0,0 +1,255 @@
+//! In-memory record of the last error responses sent by the S3 API of this
+//! node, used to help investigate client issues (e.g. sporadic 403 errors).
+//!
+//! Errors are stored in a fixed number of shards, each of them being a small
+//! ring buffer protected by its own mutex, so that concurrent requests
+//! failing at the same time rarely contend on the same lock.
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use hyper::header::AUTHORIZATION;
+use hyper::Request;
+
+use garage_util::config::S3ApiConfig;
+use garage_util::data::*;
+use garage_util::time::*;
+
+const N_SHARDS: usize = 16;
+
+/// Number of errors that are kept by default
+pub const DEFAULT_RECENT_ERRORS_BUFFER_SIZE: usize = 1000;
+
+/// An error response sent by the S3 API
+#[derive(Clone, Debug)]
+pub struct RecentError {
+	/// Sequence number, used to order errors recorded in different shards
+	pub seq: u64,
+	pub timestamp: u64,
+	pub operation: &'static str,
+	pub bucket: Option<String>,
+	/// Hash of the object key, if the request targeted an object
+	pub key_hash: Option<Hash>,
+	/// Object key in plaintext, only recorded if enabled in the configuration
+	pub key: Option<String>,
+	pub status_code: u16,
+	pub error_code: &'static str,
+	/// Access key ID given by the client. It is recorded as given,
+	/// even if the signature of the request was invalid.
+	pub access_key_id: Option<String>,
+	pub source_addr: String,
+	pub forwarded_for: Option<String>,
+	pub request_id: String,
+}
+
+/// Filters for listing recent errors
+#[derive(Default, Debug)]
+pub struct RecentErrorsFilter {
+	pub error_code: Option<String>,
+	pub access_key_id: Option<String>,
+}
+
+/// Information about a request, gathered before it is handled,
+/// that is recorded if the request fails
+pub(crate) struct RequestInfo {
+	pub operation: &'static str,
+	pub bucket: Option<String>,
+	pub key: Option<String>,
+	pub access_key_id: Option<String>,
+	pub source_addr: String,
+	pub forwarded_for: Option<String>,
+	pub request_id: String,
+}
+
+pub struct RecentErrors {
+	shard_capacity: usize,
+	plaintext_keys: bool,
+	shards: Vec<Mutex<VecDeque<RecentError>>>,
+	next_seq: AtomicU64,
+}
+
+impl RecentErrors {
+	pub fn new(config: &S3ApiConfig) -> Self {
+		let capacity = config
+			.recent_errors_buffer_size
+			.unwrap_or(DEFAULT_RECENT_ERRORS_BUFFER_SIZE);
+		Self::with_capacity(capacity, config.recent_errors_plaintext_keys)
+	}
+
+	fn with_capacity(capacity: usize, plaintext_keys: bool) -> Self {
+		Self {
+			shard_capacity: capacity.div_ceil(N_SHARDS),
+			plaintext_keys,
+			shards: (0..N_SHARDS).map(|_| Mutex::new(VecDeque::new())).collect(),
+			next_seq: AtomicU64::new(0),
+		}
+	}
+
+	/// Record an error response
+	pub(crate) fn record(&self, info: RequestInfo, status_code: u16, error_code: &'static str) {
+		if self.shard_capacity == 0 {
+			return;
+		}
+
+		let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+		let entry = RecentError {
+			seq,
+			timestamp: now_msec(),
+			operation: info.operation,
+			bucket: info.bucket,
+			key_hash: info.key.as_ref().map(|k| blake2sum(k.as_bytes())),
+			key: info.key.filter(|_| self.plaintext_keys),
+			status_code,
+			error_code,
+			access_key_id: info.access_key_id,
+			source_addr: info.source_addr,
+			forwarded_for: info.forwarded_for,
+			request_id: info.request_id,
+		};
+
+		let mut shard = self.shards[seq as usize % N_SHARDS].lock().unwrap();
+		if shard.len() >= self.shard_capacity {
+			shard.pop_front();
+		}
+		shard.push_back(entry);
+	}
+
+	/// List recorded errors matching a filter, most recent first
+	pub fn list(&self, filter: &RecentErrorsFilter) -> Vec<RecentError> {
+		let mut ret = vec![];
+		for shard in self.shards.iter() {
+			let shard = shard.lock().unwrap();
+			ret.extend(
+				shard
+					.iter()
+					.filter(|e| {
+						filter
+							.error_code
+							.as_ref()
+							.map(|c| c == e.error_code)
+							.unwrap_or(true)
+					})
+					.filter(|e| {
+						filter
+							.access_key_id
+							.as_ref()
+							.map(|k| Some(k) == e.access_key_id.as_ref())
+							.unwrap_or(true)
+					})
+					.cloned(),
+			);
+		}
+		ret.sort_by_key(|e| std::cmp::Reverse(e.seq));
+		ret
+	}
+}
+
+/// Extract the access key ID from the credentials of a request, without
+/// checking them in any way. This is used to identify the client in the
+/// record of recent errors, including for requests with invalid signatures.
+pub(crate) fn request_access_key_id<T>(req: &Request<T>) -> Option<String> {
+	let credential = match req
+		.headers()
+		.get(AUTHORIZATION)
+		.and_then(|h| h.to_str().ok())
+	{
+		Some(auth) => auth
+			.split([' ', ','])
+			.find_map(|part| part.trim().strip_prefix("Credential="))
+			.map(str::to_string),
+		None => req.uri().query().and_then(|q| {
+			url::form_urlencoded::parse(q.as_bytes())
+				.find(|(k, _)| k == "X-Amz-Credential")
+				.map(|(_, v)| v.into_owned())
+		}),
+	}?;
+	credential
+		.split('/')
+		.next()
+		.filter(|k| !k.is_empty())
+		.map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn info(key_id: &str) -> RequestInfo {
+		RequestInfo {
+			operation: "GetObject",
+			bucket: Some("bucket".into()),
+			key: Some("a/secret/key".into()),
+			access_key_id: Some(key_id.into()),
+			source_addr: "192.0.2.1:4242".into(),
+			forwarded_for: None,
+			request_id: "4E6A2B1C9D0F3A57".into(),
+		}
+	}
+
+	#[test]
+	fn test_recent_errors() {
+		let errors = RecentErrors::with_capacity(32, false);
+		for i in 0..100 {
+			let code = if i % 2 == 0 {
+				"AccessDenied"
+			} else {
+				"NoSuchKey"
+			};
+			errors.record(info(&format!("GK{}", i % 3)), 403, code);
+		}
+
+		let all = errors.list(&RecentErrorsFilter::default());
+		assert_eq!(all.len(), 32);
+		assert_eq!(all[0].seq, 99);
+		assert!(all.windows(2).all(|w| w[0].seq > w[1].seq));
+		assert!(all
+			.iter()
+			.all(|e| e.key.is_none() && e.key_hash == Some(blake2sum(b"a/secret/key"))));
+
+		let filtered = errors.list(&RecentErrorsFilter {
+			error_code: Some("AccessDenied".into()),
+			access_key_id: Some("GK0".into()),
+		});
+		assert!(!filtered.is_empty());
+		assert!(filtered
+			.iter()
+			.all(|e| e.error_code == "AccessDenied" && e.access_key_id.as_deref() == Some("GK0")));
+	}
+
+	#[test]
+	fn test_recent_errors_plaintext_keys() {
+		let errors = RecentErrors::with_capacity(10, true);
+		errors.record(info("GK1"), 404, "NoSuchKey");
+		let all = errors.list(&RecentErrorsFilter::default());
+		assert_eq!(all[0].key.as_deref(), Some("a/secret/key"));
+
+		let disabled = RecentErrors::with_capacity(0, true);
+		disabled.record(info("GK1"), 404, "NoSuchKey");
+		assert!(disabled.list(&RecentErrorsFilter::default()).is_empty());
+	}
+
+	#[test]
+	fn test_request_access_key_id() {
+		let req = Request::builder()
+			.uri("/bucket/key")
+			.header(
+				AUTHORIZATION,
+				"AWS4-HMAC-SHA256 Credential=GK31c2f218a2e44f485b94239e/20240101/garage/s3/aws4_request, SignedHeaders=host, Signature=abcd",
+			)
+			.body(())
+			.unwrap();
+		assert_eq!(
+			request_access_key_id(&req).as_deref(),
+			Some("GK31c2f218a2e44f485b94239e")
+		);
+
+		let req = Request::builder()
+			.uri("/bucket/key?X-Amz-Credential=GK42%2F20240101%2Fgarage%2Fs3%2Faws4_request")
+			.body(())
+			.unwrap();
+		assert_eq!(request_access_key_id(&req).as_deref(), Some("GK42"));
+
+		let req = Request::builder().uri("/bucket/key").body(()).unwrap();
+		assert_eq!(request_access_key_id(&req), None);
+	}
+}
@@ -0,0 +1,197 @@
+//! Short-lived record of the objects written by PutObject on this node, used
+//! to answer a PutObject that uploads the same content to the same key again
+//! without transferring and writing the data a second time. This typically
+//! happens when parallel CI jobs upload the same artifact.
+//!
+//! This is only an optimization: a PutObject is answered from this record
+//! only if its content is signed (so that its SHA256 is known before its
+//! body is read), it has the same size and the same headers as the recorded
+//! upload, and the recorded version is still the current version of the
+//! object. In all other cases, the object is written as usual.
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use garage_model::s3::object_table::*;
+use garage_util::data::*;
+
+/// Time during which a PutObject is remembered
+pub(crate) const RECENT_PUT_TTL: Duration = Duration::from_secs(10);
+
+// Above this number of recorded uploads, expired uploads are removed
+// when a new upload is recorded
+const RECENT_PUTS_PURGE_THRESHOLD: usize = 10000;
+
+/// What an upload is compared to, in addition to its content
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct PutRequest {
+	pub size: u64,
+	pub headers: HeaderList,
+	pub checksum: Option<ChecksumValue>,
+	pub original_key: Option<String>,
+}
+
+/// Outcome of a PutObject that completed recently
+#[derive(Clone, Debug)]
+pub(crate) struct RecentPut {
+	pub request: PutRequest,
+	pub version_uuid: Uuid,
+	/// Etag WITHOUT THE QUOTES (just the hex value)
+	pub etag: String,
+	pub completed_at: Instant,
+}
+
+pub(crate) struct RecentPuts {
+	ttl: Duration,
+	puts: Mutex<HashMap<(Uuid, String, Hash), RecentPut>>,
+}
+
+impl RecentPuts {
+	pub(crate) fn new(ttl: Duration) -> Self {
+		Self {
+			ttl,
+			puts: Mutex::new(HashMap::new()),
+		}
+	}
+
+	/// Record that a PutObject of content with hash `content_sha256` completed
+	pub(crate) fn record(&self, bucket_id: Uuid, key: &str, content_sha256: Hash, put: RecentPut) {
+		let mut puts = self.puts.lock().unwrap();
+		if puts.len() >= RECENT_PUTS_PURGE_THRESHOLD {
+			let now = put.completed_at;
+			puts.retain(|_, p| now < p.completed_at + self.ttl);
+		}
+		puts.insert((bucket_id, key.to_string(), content_sha256), put);
+	}
+
+	/// Find an upload of the same content to the same key that completed
+	/// less than the TTL ago, with the same size and headers
+	pub(crate) fn find(
+		&self,
+		bucket_id: Uuid,
+		key: &str,
+		content_sha256: Hash,
+		request: &PutRequest,
+		now: Instant,
+	) -> Option<RecentPut> {
+		let puts = self.puts.lock().unwrap();
+		puts.get(&(bucket_id, key.to_string(), content_sha256))
+			.filter(|put| now < put.completed_at + self.ttl)
+			.filter(|put| put.request == *request)
+			.cloned()
+	}
+}
+
+/// Check that the version created by a recent upload is still the current
+/// version of the object, i.e. that it has not been overwritten or deleted
+pub(crate) fn is_current_version(object: Option<&Object>, version_uuid: Uuid) -> bool {
+	object
+		.and_then(|o| o.versions().iter().rev().find(|v| v.is_complete()))
+		.is_some_and(|v| v.uuid == version_uuid && v.is_data())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn request(size: u64) -> PutRequest {
+		PutRequest {
+			size,
+			headers: vec![("content-type".into(), "application/zip".into())],
+			checksum: None,
+			original_key: None,
+		}
+	}
+
+	#[test]
+	fn test_recent_puts() {
+		let puts = RecentPuts::new(RECENT_PUT_TTL);
+		let bucket = gen_uuid();
+		let content = sha256sum(b"artifact");
+		let version = gen_uuid();
+		let start = Instant::now();
+		puts.record(
+			bucket,
+			"build.zip",
+			content,
+			RecentPut {
+				request: request(8),
+				version_uuid: version,
+				etag: "abcd".into(),
+				completed_at: start,
+			},
+		);
+
+		let hit = puts
+			.find(bucket, "build.zip", content, &request(8), start)
+			.unwrap();
+		assert_eq!(hit.version_uuid, version);
+		assert_eq!(hit.etag, "abcd");
+
+		// Different key, content, size or headers
+		let now = start + RECENT_PUT_TTL / 2;
+		assert!(puts
+			.find(bucket, "other.zip", content, &request(8), now)
+			.is_none());
+		assert!(puts
+			.find(bucket, "build.zip", sha256sum(b"other"), &request(8), now)
+			.is_none());
+		assert!(puts
+			.find(bucket, "build.zip", content, &request(9), now)
+			.is_none());
+		let mut other_headers = request(8);
+		other_headers.headers[0].1 = "text/plain".into();
+		assert!(puts
+			.find(bucket, "build.zip", content, &other_headers, now)
+			.is_none());
+
+		// Expired
+		assert!(puts
+			.find(
+				bucket,
+				"build.zip",
+				content,
+				&request(8),
+				start + RECENT_PUT_TTL
+			)
+			.is_none());
+	}
+
+	#[test]
+	fn test_is_current_version() {
+		let bucket = gen_uuid();
+		let meta = ObjectVersionMeta {
+			encryption: ObjectVersionEncryption::Plaintext {
+				inner: ObjectVersionMetaInner {
+					headers: vec![],
+					checksum: None,
+					full_sha256: None,
+				},
+			},
+			size: 8,
+			etag: "abcd".into(),
+			expires_at: None,
+		};
+		let version = |timestamp, data| ObjectVersion {
+			uuid: gen_uuid(),
+			timestamp,
+			state: ObjectVersionState::Complete(data),
+		};
+
+		let v1 = version(1, ObjectVersionData::Inline(meta.clone(), vec![]));
+		let uuid = v1.uuid;
+		let object = Object::new(bucket, "build.zip".into(), vec![v1.clone()]);
+		assert!(is_current_version(Some(&object), uuid));
+		assert!(!is_current_version(None, uuid));
+
+		// Overwritten
+		let v2 = version(2, ObjectVersionData::Inline(meta, vec![]));
+		let object = Object::new(bucket, "build.zip".into(), vec![v1.clone(), v2]);
+		assert!(!is_current_version(Some(&object), uuid));
+
+		// Deleted
+		let v3 = version(2, ObjectVersionData::DeleteMarker);
+		let object = Object::new(bucket, "build.zip".into(), vec![v1, v3]);
+		assert!(!is_current_version(Some(&object), uuid));
+	}
+}
@@ -0,0 +1,115 @@
+use quick_xml::de::from_reader;
+
+use http_body_util::BodyExt;
+use hyper::{Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::helpers::*;
+use crate::s3::api_server::{ReqBody, ResBody};
+use crate::s3::error::*;
+use crate::s3::xml::{to_xml_with_header, xmlns_tag, Value};
+use crate::signature::verify_signed_content;
+
+use garage_model::bucket_table::*;
+use garage_util::data::*;
+
+pub const X_AMZ_REQUEST_CHARGED: &str = "x-amz-request-charged";
+
+pub const PAYER_BUCKET_OWNER: &str = "BucketOwner";
+pub const PAYER_REQUESTER: &str = "Requester";
+
+pub async fn handle_get_request_payment(ctx: ReqCtx) -> Result<Response<ResBody>, Error> {
+	let ReqCtx { bucket_params, .. } = ctx;
+	let payer = match *bucket_params.requester_pays.get() {
+		true => PAYER_REQUESTER,
+		false => PAYER_BUCKET_OWNER,
+	};
+	let conf = RequestPaymentConfiguration {
+		xmlns: (),
+		payer: Value(payer.to_string()),
+	};
+	let xml = to_xml_with_header(&conf)?;
+	Ok(Response::builder()
+		.status(StatusCode::OK)
+		.header(http::header::CONTENT_TYPE, "application/xml")
+		.body(string_body(xml))?)
+}
+
+pub async fn handle_put_request_payment(
+	ctx: ReqCtx,
+	req: Request<ReqBody>,
+	content_sha256: Option<Hash>,
+) -> Result<Response<ResBody>, Error> {
+	let ReqCtx {
+		garage,
+		bucket_id,
+		mut bucket_params,
+		..
+	} = ctx;
+
+	let body = BodyExt::collect(req.into_body()).await?.to_bytes();
+
+	if let Some(content_sha256) = content_sha256 {
+		verify_signed_content(content_sha256, &body[..])?;
+	}
+
+	let conf: RequestPaymentConfiguration = from_reader(&body as &[u8])?;
+	let requester_pays = conf.requester_pays()?;
+
+	bucket_params.requester_pays.update(requester_pays);
+	garage
+		.bucket_table
+		.insert(&Bucket::present(bucket_id, bucket_params))
+		.await?;
+
+	Ok(Response::builder()
+		.status(StatusCode::OK)
+		.body(empty_body())?)
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RequestPaymentConfiguration {
+	#[serde(serialize_with = "xmlns_tag", skip_deserializing)]
+	pub xmlns: (),
+	#[serde(rename = "Payer")]
+	pub payer: Value,
+}
+
+impl RequestPaymentConfiguration {
+	pub fn requester_pays(&self) -> Result<bool, Error> {
+		match self.payer.0.as_str() {
+			PAYER_REQUESTER => Ok(true),
+			PAYER_BUCKET_OWNER => Ok(false),
+			_ => Err(Error::bad_request("Payer must be Requester or BucketOwner")),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use quick_xml::de::from_str;
+
+	#[test]
+	fn test_deserialize_request_payment() -> Result<(), Error> {
+		let message = r#"<?xml version="1.0" encoding="UTF-8"?>
+<RequestPaymentConfiguration xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+  <Payer>Requester</Payer>
+</RequestPaymentConfiguration>"#;
+		let conf: RequestPaymentConfiguration = from_str(message).unwrap();
+		assert!(conf.requester_pays()?);
+
+		let message2 = to_xml_with_header(&conf)?;
+		let cleanup = |c: &str| c.replace(char::is_whitespace, "");
+		assert_eq!(cleanup(message), cleanup(&message2));
+
+		let conf: RequestPaymentConfiguration = from_str(
+			"<RequestPaymentConfiguration><Payer>Someone</Payer></RequestPaymentConfiguration>",
+		)
+		.unwrap();
+		assert!(conf.requester_pays().is_err());
+
+		Ok(())
+	}
+}
@@ -305,6 +305,28 @@ pub enum Endpoint {
 	PostObject,
 }}
 
+/// Kind of resource targeted by a request, as determined by its path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RouteClass {
+	/// The root of the service, for ListBuckets
+	Service,
+	/// A bucket
+	Bucket,
+	/// An object in a bucket
+	Object,
+}
+
+impl RouteClass {
+	/// Methods supported by Garage on this kind of resource,
+	/// as sent in the Allow header of MethodNotAllowed errors
+	fn allowed_methods(self) -> &'static str {
+		match self {
+			Self::Service => "GET, HEAD, OPTIONS",
+			Self::Bucket | Self::Object => "GET, HEAD, PUT, POST, DELETE, OPTIONS",
+		}
+	}
+}
+
 impl Endpoint {
 	/// Determine which S3 endpoint a request is for using the request, and a bucket which was
 	/// possibly extracted from the Host header.
@@ -316,12 +338,17 @@ impl Endpoint {
 		let uri = req.uri();
 		let path = uri.path().trim_start_matches('/');
 		let query = uri.query();
+
+		// First determine the kind of resource targeted by the request from
+		// its path, then check that the method is one that exists for it
 		if bucket.is_none() && path.is_empty() {
-			if *req.method() == Method::OPTIONS {
-				return Ok((Self::Options, None));
-			} else {
-				return Ok((Self::ListBuckets, None));
-			}
+			return match *req.method() {
+				Method::OPTIONS => Ok((Self::Options, None)),
+				Method::GET | Method::HEAD => Ok((Self::ListBuckets, None)),
+				_ => Err(Error::MethodNotAllowed(
+					RouteClass::Service.allowed_methods().into(),
+				)),
+			};
 		}
 
 		let (bucket, key) = if let Some(bucket) = bucket {
@@ -332,8 +359,15 @@ impl Endpoint {
 				.unwrap_or((path.to_owned(), ""))
 		};
 
-		if *req.method() == Method::OPTIONS {
-			return Ok((Self::Options, Some(bucket)));
+		let class = if key.is_empty() {
+			RouteClass::Bucket
+		} else {
+			RouteClass::Object
+		};
+		match *req.method() {
+			Method::OPTIONS => return Ok((Self::Options, Some(bucket))),
+			Method::GET | Method::HEAD | Method::POST | Method::PUT | Method::DELETE => (),
+			_ => return Err(Error::MethodNotAllowed(class.allowed_methods().into())),
 		}
 
 		let key = percent_encoding::percent_decode_str(key)
@@ -348,7 +382,7 @@ impl Endpoint {
 			Method::POST => Self::from_post(key, &mut query)?,
 			Method::PUT => Self::from_put(key, &mut query, req.headers())?,
 			Method::DELETE => Self::from_delete(key, &mut query)?,
-			_ => return Err(Error::bad_request("Unknown method")),
+			_ => unreachable!(),
 		};
 
 		if let Some(message) = query.nonempty_message() {
@@ -529,7 +563,6 @@ impl Endpoint {
 	}
 
 	/// Get the key the request target. Returns None for requests which don't use a key.
-	#[allow(dead_code)]
 	pub fn get_key(&self) -> Option<&str> {
 		router_match! {
 			@extract
@@ -563,6 +596,41 @@ impl Endpoint {
 		}
 	}
 
+	/// Get a mutable reference to the key the request target, to rewrite it.
+	/// Returns None for requests which don't use a key.
+	pub fn get_key_mut(&mut self) -> Option<&mut String> {
+		router_match! {
+			@extract
+			self,
+			key,
+			[
+				AbortMultipartUpload,
+				CompleteMultipartUpload,
+				CopyObject,
+				CreateMultipartUpload,
+				DeleteObject,
+				DeleteObjectTagging,
+				GetObject,
+				GetObjectAcl,
+				GetObjectLegalHold,
+				GetObjectRetention,
+				GetObjectTagging,
+				GetObjectTorrent,
+				HeadObject,
+				ListParts,
+				PutObject,
+				PutObjectAcl,
+				PutObjectLegalHold,
+				PutObjectRetention,
+				PutObjectTagging,
+				RestoreObject,
+				SelectObjectContent,
+				UploadPart,
+				UploadPartCopy,
+			]
+		}
+	}
+
 	/// Get the kind of authorization which is required to perform the operation.
 	pub fn authorization_type(&self) -> Authorization {
 		if let Endpoint::ListBuckets = self {
@@ -580,7 +648,6 @@ impl Endpoint {
 				GetBucketInventoryConfiguration,
 				GetBucketLifecycleConfiguration,
 				GetBucketLocation,
-				GetBucketLogging,
 				GetBucketMetricsConfiguration,
 				GetBucketNotificationConfiguration,
 				GetBucketOwnershipControls,
@@ -604,12 +671,18 @@ impl Endpoint {
 				ListBucketIntelligentTieringConfigurations,
 				ListBucketInventoryConfigurations,
 				ListBucketMetricsConfigurations,
+				ListParts,
+				SelectObjectContent,
+			]
+		};
+		let list = router_match! {
+			@match
+			self,
+			[
 				ListMultipartUploads,
 				ListObjects,
 				ListObjectsV2,
 				ListObjectVersions,
-				ListParts,
-				SelectObjectContent,
 			]
 		};
 		let owner = router_match! {
@@ -623,16 +696,39 @@ impl Endpoint {
 				GetBucketCors,
 				PutBucketCors,
 				DeleteBucketCors,
+				PutBucketRequestPayment,
+				GetBucketLogging,
+				PutBucketLogging,
 			]
 		};
 		if readonly {
 			Authorization::Read
+		} else if list {
+			Authorization::List
 		} else if owner {
 			Authorization::Owner
 		} else {
 			Authorization::Write
 		}
 	}
+
+	/// Whether this endpoint modifies a bucket or an object, and must
+	/// be refused if the API or the bucket is read-only
+	pub fn is_mutation(&self) -> bool {
+		match self.authorization_type() {
+			Authorization::None | Authorization::Read | Authorization::List => false,
+			_ => !router_match! {
+				@match
+				self,
+				[
+					Options,
+					GetBucketWebsite,
+					GetBucketCors,
+					GetBucketLogging,
+				]
+			},
+		}
+	}
 }
 
 // parameter name => struct field
@@ -741,6 +837,7 @@ mod tests {
         (@actual_method HEAD) => {{ "HEAD" }};
         (@actual_method GET) => {{ "GET" }};
         (@actual_method OWNER_GET) => {{ "GET" }};
+        (@actual_method LIST_GET) => {{ "GET" }};
         (@actual_method PUT) => {{ "PUT" }};
         (@actual_method OWNER_PUT) => {{ "PUT" }};
         (@actual_method POST) => {{ "POST" }};
@@ -749,38 +846,113 @@ mod tests {
 
         (@auth HEAD $uri:expr) => {{
             assert_eq!(parse("HEAD", concat!("/my_bucket", $uri), None, None).0.authorization_type(),
-                Authorization::Read)
+                Authorization::Read);
+            assert!(!parse("HEAD", concat!("/my_bucket", $uri), None, None).0.is_mutation())
         }};
         (@auth GET $uri:expr) => {{
             assert_eq!(parse("GET", concat!("/my_bucket", $uri), None, None).0.authorization_type(),
-                Authorization::Read)
+                Authorization::Read);
+            assert!(!parse("GET", concat!("/my_bucket", $uri), None, None).0.is_mutation())
+        }};
+        (@auth LIST_GET $uri:expr) => {{
+            assert_eq!(parse("GET", concat!("/my_bucket", $uri), None, None).0.authorization_type(),
+                Authorization::List);
+            assert!(!parse("GET", concat!("/my_bucket", $uri), None, None).0.is_mutation())
         }};
         (@auth OWNER_GET $uri:expr) => {{
             assert_eq!(parse("GET", concat!("/my_bucket", $uri), None, None).0.authorization_type(),
-                Authorization::Owner)
+                Authorization::Owner);
+            assert!(!parse("GET", concat!("/my_bucket", $uri), None, None).0.is_mutation())
         }};
         (@auth PUT $uri:expr) => {{
             assert_eq!(parse("PUT", concat!("/my_bucket", $uri), None, None).0.authorization_type(),
-                Authorization::Write)
+                Authorization::Write);
+            assert!(parse("PUT", concat!("/my_bucket", $uri), None, None).0.is_mutation())
         }};
         (@auth OWNER_PUT $uri:expr) => {{
             assert_eq!(parse("PUT", concat!("/my_bucket", $uri), None, None).0.authorization_type(),
-                Authorization::Owner)
+                Authorization::Owner);
+            assert!(parse("PUT", concat!("/my_bucket", $uri), None, None).0.is_mutation())
         }};
         (@auth POST $uri:expr) => {{
             assert_eq!(parse("POST", concat!("/my_bucket", $uri), None, None).0.authorization_type(),
-                Authorization::Write)
+                Authorization::Write);
+            assert!(parse("POST", concat!("/my_bucket", $uri), None, None).0.is_mutation())
         }};
         (@auth DELETE $uri:expr) => {{
             assert_eq!(parse("DELETE", concat!("/my_bucket", $uri), None, None).0.authorization_type(),
-                Authorization::Write)
+                Authorization::Write);
+            assert!(parse("DELETE", concat!("/my_bucket", $uri), None, None).0.is_mutation())
         }};
         (@auth OWNER_DELETE $uri:expr) => {{
             assert_eq!(parse("DELETE", concat!("/my_bucket", $uri), None, None).0.authorization_type(),
-                Authorization::Owner)
+                Authorization::Owner);
+            assert!(parse("DELETE", concat!("/my_bucket", $uri), None, None).0.is_mutation())
         }};
     }
 
+	fn method_not_allowed(method: &str, uri: &str, bucket: Option<String>) -> String {
+		let req = Request::builder().method(method).uri(uri).body(()).unwrap();
+		match Endpoint::from_request(&req, bucket) {
+			Err(Error::MethodNotAllowed(allow)) => allow,
+			r => panic!("expected MethodNotAllowed, got {:?}", r),
+		}
+	}
+
+	#[test]
+	fn test_method_not_allowed() {
+		let object_methods = "GET, HEAD, PUT, POST, DELETE, OPTIONS";
+
+		// Object
+		assert_eq!(
+			method_not_allowed("PATCH", "/my_bucket/my/key", None),
+			object_methods
+		);
+		assert_eq!(
+			method_not_allowed("PROPFIND", "/my/key", Some("my_bucket".to_owned())),
+			object_methods
+		);
+		assert_eq!(
+			method_not_allowed("PATCH", "/my_bucket/my/key?uploads", None),
+			object_methods
+		);
+
+		// Bucket
+		assert_eq!(
+			method_not_allowed("PROPFIND", "/my_bucket", None),
+			object_methods
+		);
+		assert_eq!(
+			method_not_allowed("PATCH", "/", Some("my_bucket".to_owned())),
+			object_methods
+		);
+
+		// Service
+		assert_eq!(
+			method_not_allowed("DELETE", "/", None),
+			"GET, HEAD, OPTIONS"
+		);
+		assert_eq!(method_not_allowed("PUT", "/", None), "GET, HEAD, OPTIONS");
+		assert!(matches!(
+			parse("GET", "/", None, None),
+			(Endpoint::ListBuckets, None)
+		));
+
+		// OPTIONS is still passed to the CORS handling
+		assert!(matches!(
+			parse("OPTIONS", "/", None, None),
+			(Endpoint::Options, None)
+		));
+		assert_eq!(
+			parse("OPTIONS", "/my_bucket/my/key", None, None),
+			(Endpoint::Options, Some("my_bucket".to_owned()))
+		);
+		assert_eq!(
+			parse("OPTIONS", "/my/key", Some("my_bucket".to_owned()), None),
+			(Endpoint::Options, Some("my_bucket".to_owned()))
+		);
+	}
+
 	#[test]
 	fn test_bucket_extraction() {
 		assert_eq!(
@@ -891,7 +1063,7 @@ mod tests {
 			GET "/?inventory&id=Id" => GetBucketInventoryConfiguration
 			GET "/?lifecycle" => GetBucketLifecycleConfiguration
 			GET "/?location" => GetBucketLocation
-			GET "/?logging" => GetBucketLogging
+			OWNER_GET "/?logging" => GetBucketLogging
 			GET "/?metrics&id=Documents" => GetBucketMetricsConfiguration
 			GET "/?metrics&id=Id" => GetBucketMetricsConfiguration
 			GET "/?notification" => GetBucketNotificationConfiguration
@@ -930,30 +1102,30 @@ mod tests {
 			GET "/?inventory&continuation-token=ContinuationToken" => ListBucketInventoryConfigurations
 			GET "/?metrics" => ListBucketMetricsConfigurations
 			GET "/?metrics&continuation-token=ContinuationToken" => ListBucketMetricsConfigurations
-			GET "/?uploads&max-uploads=3" => ListMultipartUploads
-			GET "/?uploads&delimiter=/" => ListMultipartUploads
-			GET "/?uploads&delimiter=/&prefix=photos/2006/" => ListMultipartUploads
-			GET "/?uploads&delimiter=D&encoding-type=EncodingType&key-marker=KeyMarker&max-uploads=1&prefix=Prefix&upload-id-marker=UploadIdMarker" => ListMultipartUploads
-			GET "/" => ListObjects
-			GET "/?prefix=N&marker=Ned&max-keys=40" => ListObjects
-			GET "/?delimiter=/" => ListObjects
-			GET "/?prefix=photos/2006/&delimiter=/" => ListObjects
+			LIST_GET "/?uploads&max-uploads=3" => ListMultipartUploads
+			LIST_GET "/?uploads&delimiter=/" => ListMultipartUploads
+			LIST_GET "/?uploads&delimiter=/&prefix=photos/2006/" => ListMultipartUploads
+			LIST_GET "/?uploads&delimiter=D&encoding-type=EncodingType&key-marker=KeyMarker&max-uploads=1&prefix=Prefix&upload-id-marker=UploadIdMarker" => ListMultipartUploads
+			LIST_GET "/" => ListObjects
+			LIST_GET "/?prefix=N&marker=Ned&max-keys=40" => ListObjects
+			LIST_GET "/?delimiter=/" => ListObjects
+			LIST_GET "/?prefix=photos/2006/&delimiter=/" => ListObjects
 
-			GET "/?delimiter=D&encoding-type=EncodingType&marker=Marker&max-keys=1&prefix=Prefix" => ListObjects
-			GET "/?list-type=2" => ListObjectsV2
-			GET "/?list-type=2&max-keys=3&prefix=E&start-after=ExampleGuide.pdf" => ListObjectsV2
-			GET "/?list-type=2&delimiter=/" => ListObjectsV2
-			GET "/?list-type=2&prefix=photos/2006/&delimiter=/" => ListObjectsV2
-			GET "/?list-type=2" => ListObjectsV2
-			GET "/?list-type=2&continuation-token=1ueGcxLPRx1Tr/XYExHnhbYLgveDs2J/wm36Hy4vbOwM=" => ListObjectsV2
-			GET "/?list-type=2&continuation-token=ContinuationToken&delimiter=D&encoding-type=EncodingType&fetch-owner=true&max-keys=1&prefix=Prefix&start-after=StartAfter" => ListObjectsV2
-			GET "/?versions" => ListObjectVersions
-			GET "/?versions&key-marker=key2" => ListObjectVersions
-			GET "/?versions&key-marker=key3&version-id-marker=t46ZenlYTZBnj" => ListObjectVersions
-			GET "/?versions&key-marker=key3&version-id-marker=t46Z0menlYTZBnj&max-keys=3" => ListObjectVersions
-			GET "/?versions&delimiter=/" => ListObjectVersions
-			GET "/?versions&prefix=photos/2006/&delimiter=/" => ListObjectVersions
-			GET "/?versions&delimiter=D&encoding-type=EncodingType&key-marker=KeyMarker&max-keys=2&prefix=Prefix&version-id-marker=VersionIdMarker" => ListObjectVersions
+			LIST_GET "/?delimiter=D&encoding-type=EncodingType&marker=Marker&max-keys=1&prefix=Prefix" => ListObjects
+			LIST_GET "/?list-type=2" => ListObjectsV2
+			LIST_GET "/?list-type=2&max-keys=3&prefix=E&start-after=ExampleGuide.pdf" => ListObjectsV2
+			LIST_GET "/?list-type=2&delimiter=/" => ListObjectsV2
+			LIST_GET "/?list-type=2&prefix=photos/2006/&delimiter=/" => ListObjectsV2
+			LIST_GET "/?list-type=2" => ListObjectsV2
+			LIST_GET "/?list-type=2&continuation-token=1ueGcxLPRx1Tr/XYExHnhbYLgveDs2J/wm36Hy4vbOwM=" => ListObjectsV2
+			LIST_GET "/?list-type=2&continuation-token=ContinuationToken&delimiter=D&encoding-type=EncodingType&fetch-owner=true&max-keys=1&prefix=Prefix&start-after=StartAfter" => ListObjectsV2
+			LIST_GET "/?versions" => ListObjectVersions
+			LIST_GET "/?versions&key-marker=key2" => ListObjectVersions
+			LIST_GET "/?versions&key-marker=key3&version-id-marker=t46ZenlYTZBnj" => ListObjectVersions
+			LIST_GET "/?versions&key-marker=key3&version-id-marker=t46Z0menlYTZBnj&max-keys=3" => ListObjectVersions
+			LIST_GET "/?versions&delimiter=/" => ListObjectVersions
+			LIST_GET "/?versions&prefix=photos/2006/&delimiter=/" => ListObjectVersions
+			LIST_GET "/?versions&delimiter=D&encoding-type=EncodingType&key-marker=KeyMarker&max-keys=2&prefix=Prefix&version-id-marker=VersionIdMarker" => ListObjectVersions
 			GET "/example-object?uploadId=XXBsb2FkIElEIGZvciBlbHZpbmcncyVcdS1tb3ZpZS5tMnRzEEEwbG9hZA&max-parts=2&part-number-marker=1" => ListParts
 			GET "/Key+?max-parts=2&part-number-marker=2&uploadId=UploadId" => ListParts
 			PUT "/?accelerate" => PutBucketAccelerateConfiguration
@@ -966,14 +1138,14 @@ mod tests {
 			PUT "/?inventory&id=report1" => PutBucketInventoryConfiguration
 			PUT "/?inventory&id=Id" => PutBucketInventoryConfiguration
 			PUT "/?lifecycle" => PutBucketLifecycleConfiguration
-			PUT "/?logging" => PutBucketLogging
+			OWNER_PUT "/?logging" => PutBucketLogging
 			PUT "/?metrics&id=EntireBucket" => PutBucketMetricsConfiguration
 			PUT "/?metrics&id=Id" => PutBucketMetricsConfiguration
 			PUT "/?notification" => PutBucketNotificationConfiguration
 			PUT "/?ownershipControls" => PutBucketOwnershipControls
 			PUT "/?policy" => PutBucketPolicy
 			PUT "/?replication" => PutBucketReplication
-			PUT "/?requestPayment" => PutBucketRequestPayment
+			OWNER_PUT "/?requestPayment" => PutBucketRequestPayment
 			PUT "/?tagging" => PutBucketTagging
 			PUT "/?versioning" => PutBucketVersioning
 			OWNER_PUT "/?website" => PutBucketWebsite
@@ -1091,5 +1263,19 @@ mod tests {
 				.authorization_type(),
 			Authorization::Read
 		));
+		assert!(
+			!parse("POST", "/my_bucket/{Key+}?select&select-type=2", None, None)
+				.0
+				.is_mutation()
+		);
+	}
+
+	#[test]
+	fn test_is_mutation() {
+		// Other endpoints are covered by test_cases!
+		assert!(!parse("GET", "/", None, None).0.is_mutation());
+		assert!(!parse("OPTIONS", "/my_bucket/my/key", None, None)
+			.0
+			.is_mutation());
 	}
 }
@@ -0,0 +1,793 @@
+//! Mirroring of S3 write requests to another cluster (the shadow cluster),
+//! to validate it with production traffic before migrating to it.
+//!
+//! Once a sampled write request has succeeded, it is put in a bounded queue
+//! and replayed asynchronously against the shadow cluster, so that the
+//! client never waits for it: if the queue is full, the request is dropped.
+//! Bodies of objects are not kept from the original request, but read back
+//! from the version that it has just written. PutObject, CopyObject and
+//! CompleteMultipartUpload are all replayed as a PutObject of the new
+//! version, and DeleteObject (without a version ID) as a DeleteObject.
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use chrono::Utc;
+use futures::TryStreamExt;
+use http_body_util::{combinators::BoxBody, BodyExt, Empty, StreamBody};
+use hyper::body::{Frame, Incoming as IncomingBody};
+use hyper::header::{HeaderName, HeaderValue, CONTENT_LENGTH};
+use hyper::{Method, Request, Response, Uri};
+use hyper_rustls::HttpsConnector;
+use hyper_util::client::legacy::{connect::HttpConnector, Client as HttpClient};
+use hyper_util::rt::TokioExecutor;
+use opentelemetry::{
+	global,
+	metrics::{Counter, Meter},
+	KeyValue,
+};
+use tokio::sync::{mpsc, Semaphore};
+
+use garage_model::garage::Garage;
+use garage_model::s3::object_table::*;
+use garage_util::config::S3ShadowConfig;
+use garage_util::data::*;
+use garage_util::error::Error as GarageError;
+
+use crate::encoding::uri_encode;
+use crate::s3::encryption::EncryptionParams;
+use crate::s3::error::*;
+use crate::s3::get::full_object_byte_stream;
+use crate::s3::headers::X_AMZ_VERSION_ID;
+use crate::s3::import::parse_source_endpoint;
+use crate::s3::multipart::decode_upload_id;
+use crate::s3::router::Endpoint;
+use crate::signature::sign::{sign_request, PayloadSignature, SigningParams};
+
+/// Number of mirrored requests waiting to be replayed, by default
+const SHADOW_DEFAULT_QUEUE_SIZE: usize = 1000;
+/// Number of requests replayed at the same time, by default
+const SHADOW_DEFAULT_CONCURRENCY: usize = 4;
+/// Timeout for a replayed request, including the upload of the object
+const SHADOW_REQUEST_TIMEOUT: Duration = Duration::from_secs(600);
+
+type ShadowBody = BoxBody<Bytes, std::io::Error>;
+
+/// Write operations that can be mirrored
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShadowOperation {
+	PutObject,
+	CopyObject,
+	CompleteMultipartUpload,
+	DeleteObject,
+}
+
+impl ShadowOperation {
+	const ALL: [ShadowOperation; 4] = [
+		Self::PutObject,
+		Self::CopyObject,
+		Self::CompleteMultipartUpload,
+		Self::DeleteObject,
+	];
+
+	pub fn name(&self) -> &'static str {
+		match self {
+			Self::PutObject => "PutObject",
+			Self::CopyObject => "CopyObject",
+			Self::CompleteMultipartUpload => "CompleteMultipartUpload",
+			Self::DeleteObject => "DeleteObject",
+		}
+	}
+
+	fn parse(name: &str) -> Option<Self> {
+		Self::ALL.iter().copied().find(|op| op.name() == name)
+	}
+
+	/// The operation of a request, if it can be mirrored
+	fn of_endpoint(endpoint: &Endpoint) -> Option<Self> {
+		match endpoint {
+			Endpoint::PutObject { .. } => Some(Self::PutObject),
+			Endpoint::CopyObject { .. } => Some(Self::CopyObject),
+			Endpoint::CompleteMultipartUpload { .. } => Some(Self::CompleteMultipartUpload),
+			// Version IDs are not the same in the shadow cluster
+			Endpoint::DeleteObject {
+				version_id: None, ..
+			} => Some(Self::DeleteObject),
+			_ => None,
+		}
+	}
+}
+
+/// A request that was sampled for mirroring, before it is handled
+pub(crate) struct ShadowRequest {
+	operation: ShadowOperation,
+	bucket_id: Uuid,
+	bucket_name: String,
+	/// Key of the object in the object table
+	key: String,
+	/// Key of the object as sent by the client, which is used in the
+	/// shadow cluster
+	shadow_key: String,
+	/// For CompleteMultipartUpload, the version of the upload
+	version_uuid: Option<Uuid>,
+}
+
+enum ReplayOutcome {
+	Mirrored,
+	/// The request could not be replayed, for the given reason
+	Skipped(&'static str),
+}
+
+pub struct ShadowMirror {
+	operations: Vec<ShadowOperation>,
+	sample_rate: f64,
+	queue: mpsc::Sender<ShadowRequest>,
+	metrics: Arc<ShadowMetrics>,
+}
+
+struct ShadowMetrics {
+	mirrored: Counter<u64>,
+	dropped: Counter<u64>,
+	errors: Counter<u64>,
+}
+
+struct ShadowClient {
+	garage: Arc<Garage>,
+	client: HttpClient<HttpsConnector<HttpConnector>, ShadowBody>,
+	endpoint: String,
+	region: String,
+	access_key_id: String,
+	secret_access_key: String,
+}
+
+impl ShadowMirror {
+	/// Start mirroring requests if `s3_api.shadow` is set in the configuration.
+	/// Must be called from a Tokio runtime, as it spawns the task that
+	/// replays the requests.
+	pub fn from_config(garage: Arc<Garage>) -> Result<Option<Self>, GarageError> {
+		Self::with_meter(garage, &global::meter("garage/api"))
+	}
+
+	/// Same as `from_config`, with the metrics registered in `meter`
+	fn with_meter(garage: Arc<Garage>, meter: &Meter) -> Result<Option<Self>, GarageError> {
+		let config = match &garage.config.s3_api.shadow {
+			Some(config) => config.clone(),
+			None => return Ok(None),
+		};
+		let (operations, sample_rate) = parse_shadow_filter(&config)?;
+		let endpoint = parse_source_endpoint(&config.endpoint).map_err(|_| {
+			GarageError::Message(format!(
+				"Invalid s3_api.shadow.endpoint {:?}, expected e.g. https://s3.example.com",
+				config.endpoint
+			))
+		})?;
+
+		let connector = hyper_rustls::HttpsConnectorBuilder::new()
+			.with_native_roots()
+			.map_err(|e| GarageError::Message(format!("Cannot load TLS root certificates: {}", e)))?
+			.https_or_http()
+			.enable_http1()
+			.build();
+		let client = Arc::new(ShadowClient {
+			garage,
+			client: HttpClient::builder(TokioExecutor::new()).build(connector),
+			endpoint,
+			region: config.region.unwrap_or_else(|| "garage".into()),
+			access_key_id: config.access_key_id,
+			secret_access_key: config.secret_access_key,
+		});
+
+		let metrics = Arc::new(ShadowMetrics {
+			mirrored: meter
+				.u64_counter("api.s3.shadow_mirrored_counter")
+				.with_description(
+					"Number of S3 requests replayed successfully on the shadow cluster",
+				)
+				.init(),
+			dropped: meter
+				.u64_counter("api.s3.shadow_dropped_counter")
+				.with_description(
+					"Number of sampled S3 requests that were not replayed on the shadow cluster",
+				)
+				.init(),
+			errors: meter
+				.u64_counter("api.s3.shadow_error_counter")
+				.with_description(
+					"Number of S3 requests that failed when replayed on the shadow cluster",
+				)
+				.init(),
+		});
+
+		let (queue, recv) = mpsc::channel(
+			config
+				.queue_size
+				.unwrap_or(SHADOW_DEFAULT_QUEUE_SIZE)
+				.max(1),
+		);
+		let concurrency = config
+			.concurrency
+			.unwrap_or(SHADOW_DEFAULT_CONCURRENCY)
+			.max(1);
+		tokio::spawn(replay_worker(client, metrics.clone(), recv, concurrency));
+
+		info!(
+			"Mirroring {:.1}% of S3 write requests to shadow cluster {}",
+			sample_rate * 100.0,
+			config.endpoint
+		);
+		Ok(Some(Self {
+			operations,
+			sample_rate,
+			queue,
+			metrics,
+		}))
+	}
+
+	/// Decide whether a request is mirrored, before it is handled
+	pub(crate) fn sample(
+		&self,
+		endpoint: &Endpoint,
+		bucket_id: Uuid,
+		bucket_name: &str,
+		original_key: Option<&str>,
+	) -> Option<ShadowRequest> {
+		let operation = ShadowOperation::of_endpoint(endpoint)?;
+		if !self.operations.contains(&operation) || rand::random::<f64>() >= self.sample_rate {
+			return None;
+		}
+		let key = endpoint.get_key()?.to_string();
+		let version_uuid = match endpoint {
+			Endpoint::CompleteMultipartUpload { upload_id, .. } => {
+				Some(decode_upload_id(upload_id).ok()?)
+			}
+			_ => None,
+		};
+		Some(ShadowRequest {
+			operation,
+			bucket_id,
+			bucket_name: bucket_name.to_string(),
+			shadow_key: original_key.unwrap_or(&key).to_string(),
+			key,
+			version_uuid,
+		})
+	}
+
+	/// Queue the replay of a request that succeeded, without waiting
+	pub(crate) fn enqueue<B>(&self, mut request: ShadowRequest, resp: &Response<B>) {
+		if request.version_uuid.is_none() {
+			request.version_uuid = resp
+				.headers()
+				.get(X_AMZ_VERSION_ID)
+				.and_then(|v| v.to_str().ok())
+				.and_then(|v| decode_upload_id(v).ok());
+		}
+		let operation = request.operation;
+		if self.queue.try_send(request).is_err() {
+			self.metrics.dropped.add(
+				1,
+				&[
+					KeyValue::new("operation", operation.name()),
+					KeyValue::new("reason", "queue_full"),
+				],
+			);
+		}
+	}
+}
+
+/// Operations to mirror and fraction of them that is sampled
+fn parse_shadow_filter(
+	config: &S3ShadowConfig,
+) -> Result<(Vec<ShadowOperation>, f64), GarageError> {
+	let operations = if config.operations.is_empty() {
+		ShadowOperation::ALL.to_vec()
+	} else {
+		config
+			.operations
+			.iter()
+			.map(|name| {
+				ShadowOperation::parse(name).ok_or_else(|| {
+					GarageError::Message(format!(
+						"Operation {:?} cannot be mirrored (s3_api.shadow.operations), expected one of: {}",
+						name,
+						ShadowOperation::ALL.map(|op| op.name()).join(", ")
+					))
+				})
+			})
+			.collect::<Result<Vec<_>, _>>()?
+	};
+	let sample_rate = config.sample_rate.unwrap_or(1.0);
+	if !(0.0..=1.0).contains(&sample_rate) {
+		return Err(GarageError::Message(format!(
+			"s3_api.shadow.sample_rate must be between 0 and 1, got {}",
+			sample_rate
+		)));
+	}
+	Ok((operations, sample_rate))
+}
+
+async fn replay_worker(
+	client: Arc<ShadowClient>,
+	metrics: Arc<ShadowMetrics>,
+	mut recv: mpsc::Receiver<ShadowRequest>,
+	concurrency: usize,
+) {
+	let semaphore = Arc::new(Semaphore::new(concurrency));
+	while let Some(request) = recv.recv().await {
+		let permit = match semaphore.clone().acquire_owned().await {
+			Ok(permit) => permit,
+			Err(_) => break,
+		};
+		let client = client.clone();
+		let metrics = metrics.clone();
+		tokio::spawn(async move {
+			let operation = KeyValue::new("operation", request.operation.name());
+			match client.replay(&request).await {
+				Ok(ReplayOutcome::Mirrored) => metrics.mirrored.add(1, &[operation]),
+				Ok(ReplayOutcome::Skipped(reason)) => metrics
+					.dropped
+					.add(1, &[operation, KeyValue::new("reason", reason)]),
+				Err(e) => {
+					debug!(
+						"Mirroring {} of {}/{} to the shadow cluster failed: {}",
+						request.operation.name(),
+						request.bucket_name,
+						request.shadow_key,
+						e
+					);
+					metrics.errors.add(1, &[operation]);
+				}
+			}
+			drop(permit);
+		});
+	}
+}
+
+impl ShadowClient {
+	async fn replay(&self, request: &ShadowRequest) -> Result<ReplayOutcome, Error> {
+		let path = format!(
+			"/{}/{}",
+			uri_encode(&request.bucket_name, true),
+			uri_encode(&request.shadow_key, false)
+		);
+		match request.operation {
+			ShadowOperation::DeleteObject => {
+				let body = Empty::<Bytes>::new()
+					.map_err(|never| match never {})
+					.boxed();
+				self.request(Method::DELETE, &path, vec![], body, None)
+					.await?;
+				Ok(ReplayOutcome::Mirrored)
+			}
+			_ => self.replay_put(request, &path).await,
+		}
+	}
+
+	/// Upload the version written by the request to the shadow cluster
+	async fn replay_put(
+		&self,
+		request: &ShadowRequest,
+		path: &str,
+	) -> Result<ReplayOutcome, Error> {
+		let version_uuid = match request.version_uuid {
+			Some(v) => v,
+			None => return Ok(ReplayOutcome::Skipped("unknown_version")),
+		};
+		let object = self
+			.garage
+			.object_table
+			.get(&request.bucket_id, &request.key)
+			.await?;
+		let version = object
+			.as_ref()
+			.and_then(|o| o.versions().iter().find(|v| v.uuid == version_uuid));
+		let (version, data, meta) = match version.map(|v| (v, &v.state)) {
+			Some((v, ObjectVersionState::Complete(d @ ObjectVersionData::Inline(meta, _))))
+			| Some((v, ObjectVersionState::Complete(d @ ObjectVersionData::FirstBlock(meta, _)))) => {
+				(v, d, meta)
+			}
+			// The object has been deleted or overwritten since, and the
+			// versions that replaced it are mirrored on their own
+			_ => return Ok(ReplayOutcome::Skipped("version_gone")),
+		};
+		let headers = match &meta.encryption {
+			ObjectVersionEncryption::Plaintext { inner } => inner.headers.clone(),
+			_ => return Ok(ReplayOutcome::Skipped("encrypted")),
+		};
+
+		let stream = full_object_byte_stream(
+			self.garage.clone(),
+			version,
+			data,
+			EncryptionParams::Plaintext,
+		);
+		let body = BodyExt::boxed(StreamBody::new(stream.map_ok(Frame::data)));
+		self.request(Method::PUT, path, headers, body, Some(meta.size))
+			.await?;
+		Ok(ReplayOutcome::Mirrored)
+	}
+
+	async fn request(
+		&self,
+		method: Method,
+		path: &str,
+		headers: HeaderList,
+		body: ShadowBody,
+		content_length: Option<u64>,
+	) -> Result<(), Error> {
+		let uri = Uri::try_from(format!("{}{}", self.endpoint, path))
+			.ok_or_bad_request("Invalid URL for the shadow cluster")?;
+
+		let mut req = Request::builder().method(method.clone()).uri(uri.clone());
+		for (name, value) in headers.iter() {
+			if let (Ok(name), Ok(value)) = (
+				HeaderName::try_from(name.as_str()),
+				HeaderValue::try_from(value.as_str()),
+			) {
+				req = req.header(name, value);
+			}
+		}
+		let mut req = req.body(body)?;
+		let payload = match content_length {
+			Some(length) => {
+				req.headers_mut().insert(CONTENT_LENGTH, length.into());
+				PayloadSignature::Unsigned
+			}
+			None => PayloadSignature::Sha256(sha256sum(&[])),
+		};
+
+		let params = SigningParams {
+			key_id: &self.access_key_id,
+			secret_key: &self.secret_access_key,
+			region: &self.region,
+			service: "s3",
+			datetime: Utc::now(),
+		};
+		sign_request(&params, &method, &uri, req.headers_mut(), payload)
+			.map_err(|e| GarageError::Message(format!("Cannot sign request: {}", e)))?;
+
+		let resp: Response<IncomingBody> =
+			match tokio::time::timeout(SHADOW_REQUEST_TIMEOUT, self.client.request(req)).await {
+				Ok(Ok(resp)) => resp,
+				Ok(Err(e)) => {
+					return Err(GarageError::Message(format!(
+						"Cannot reach the shadow cluster: {}",
+						e
+					))
+					.into())
+				}
+				Err(_) => {
+					return Err(
+						GarageError::Message("Timeout from the shadow cluster".into()).into(),
+					)
+				}
+			};
+		let status = resp.status();
+		// Read the body so that the connection can be reused
+		let _ = resp.into_body().collect().await;
+		if status.is_success() {
+			Ok(())
+		} else {
+			Err(GarageError::Message(format!("Shadow cluster returned {}", status)).into())
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use std::collections::HashMap;
+	use std::sync::Mutex;
+
+	use opentelemetry::metrics::MeterProvider;
+	use opentelemetry::sdk::export::metrics::{CheckpointSet, ExportKindSelector, Sum};
+	use opentelemetry::sdk::metrics::{
+		aggregators::SumAggregator, controllers, selectors::simple::Selector, PullController,
+	};
+	use rand::Rng;
+	use tokio::io::{AsyncReadExt, AsyncWriteExt};
+	use tokio::net::TcpListener;
+
+	use garage_rpc::layout::{NodeRole, NodeRoleV};
+	use garage_util::config::read_config;
+	use garage_util::crdt::Crdt;
+
+	fn config(operations: &[&str], sample_rate: Option<f64>) -> S3ShadowConfig {
+		S3ShadowConfig {
+			endpoint: "https://s3.example.com".into(),
+			region: None,
+			access_key_id: "GK1".into(),
+			secret_access_key: "secret".into(),
+			sample_rate,
+			operations: operations.iter().map(|s| s.to_string()).collect(),
+			queue_size: None,
+			concurrency: None,
+		}
+	}
+
+	#[test]
+	fn test_shadow_filter() {
+		let (ops, rate) = parse_shadow_filter(&config(&[], None)).unwrap();
+		assert_eq!(ops, ShadowOperation::ALL.to_vec());
+		assert_eq!(rate, 1.0);
+
+		let (ops, rate) =
+			parse_shadow_filter(&config(&["PutObject", "DeleteObject"], Some(0.25))).unwrap();
+		assert_eq!(
+			ops,
+			vec![ShadowOperation::PutObject, ShadowOperation::DeleteObject]
+		);
+		assert_eq!(rate, 0.25);
+
+		assert!(parse_shadow_filter(&config(&["GetObject"], None)).is_err());
+		assert!(parse_shadow_filter(&config(&["DeleteObjects"], None)).is_err());
+		assert!(parse_shadow_filter(&config(&[], Some(1.5))).is_err());
+	}
+
+	#[test]
+	fn test_shadow_operations() {
+		let op = |e: Endpoint| ShadowOperation::of_endpoint(&e);
+		assert_eq!(
+			op(Endpoint::PutObject { key: "a".into() }),
+			Some(ShadowOperation::PutObject)
+		);
+		assert_eq!(
+			op(Endpoint::DeleteObject {
+				key: "a".into(),
+				version_id: None
+			}),
+			Some(ShadowOperation::DeleteObject)
+		);
+		assert_eq!(
+			op(Endpoint::DeleteObject {
+				key: "a".into(),
+				version_id: Some("v".into())
+			}),
+			None
+		);
+		assert_eq!(
+			op(Endpoint::UploadPart {
+				key: "a".into(),
+				part_number: 1,
+				upload_id: "u".into()
+			}),
+			None
+		);
+	}
+
+	/// Requests received by the mock shadow cluster, as "METHOD path"
+	/// and body. Requests to keys starting with "fail" get an error.
+	type Received = Arc<Mutex<Vec<(String, Vec<u8>)>>>;
+
+	async fn mock_shadow_cluster() -> (String, Received) {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let endpoint = format!("http://{}", listener.local_addr().unwrap());
+		let received = Received::default();
+		let received2 = received.clone();
+		tokio::spawn(async move {
+			loop {
+				let (mut conn, _) = listener.accept().await.unwrap();
+				let received = received2.clone();
+				tokio::spawn(async move {
+					let mut buf = vec![];
+					let mut chunk = [0u8; 4096];
+					loop {
+						let n = conn.read(&mut chunk).await.unwrap();
+						if n == 0 {
+							return;
+						}
+						buf.extend_from_slice(&chunk[..n]);
+						let head_len = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+							Some(i) => i + 4,
+							None => continue,
+						};
+						let head = String::from_utf8_lossy(&buf[..head_len]).to_string();
+						let length = head
+							.lines()
+							.find_map(|l| {
+								let l = l.to_lowercase();
+								l.strip_prefix("content-length:")?.trim().parse().ok()
+							})
+							.unwrap_or(0);
+						if buf.len() < head_len + length {
+							continue;
+						}
+						let request = head.split(' ').take(2).collect::<Vec<_>>().join(" ");
+						let status = match request.contains("/fail") {
+							true => "500 Internal Server Error",
+							false => "200 OK",
+						};
+						let body = buf[head_len..head_len + length].to_vec();
+						received.lock().unwrap().push((request, body));
+						let resp = format!("HTTP/1.1 {}\r\ncontent-length: 0\r\n\r\n", status);
+						conn.write_all(resp.as_bytes()).await.unwrap();
+						buf.drain(..head_len + length);
+					}
+				});
+			}
+		});
+		(endpoint, received)
+	}
+
+	/// Start a single-node Garage that mirrors requests to `endpoint`
+	async fn test_garage(endpoint: &str) -> Arc<Garage> {
+		let path = std::env::temp_dir().join(format!(
+			"garage-shadow-test-{}",
+			hex::encode(rand::thread_rng().gen::<[u8; 8]>())
+		));
+		std::fs::create_dir_all(&path).unwrap();
+		let config = format!(
+			r#"
+metadata_dir = "{path}/meta"
+data_dir = "{path}/data"
+db_engine = "sqlite"
+replication_factor = 1
+rpc_bind_addr = "127.0.0.1:0"
+rpc_secret = "c3ea8cb80333d04e208d136698b1a01ae370d463f0d435ab2177510b3478bf44"
+
+[s3_api]
+s3_region = "garage"
+
+[s3_api.shadow]
+endpoint = "{endpoint}"
+access_key_id = "GK1"
+secret_access_key = "secret"
+"#,
+			path = path.display(),
+			endpoint = endpoint,
+		);
+		std::fs::write(path.join("config.toml"), config).unwrap();
+		garage_util::version::init_version("test");
+		let garage = Garage::new(read_config(path.join("config.toml")).unwrap()).unwrap();
+
+		let mut layout = garage.system.cluster_layout().inner().clone();
+		let staging = layout.staging.get_mut();
+		let update = staging.roles.update_mutator(
+			garage.system.id,
+			NodeRoleV(Some(NodeRole {
+				zone: "dc1".into(),
+				capacity: Some(1 << 30),
+				tags: vec![],
+				non_voting: false,
+			})),
+		);
+		staging.roles.merge(&update);
+		let (layout, _) = layout.apply_staged_changes(Some(1)).unwrap();
+		garage
+			.system
+			.layout_manager
+			.update_cluster_layout(&layout)
+			.await
+			.unwrap();
+
+		garage
+	}
+
+	/// Values of the counters of the shadow mirror, by metric name
+	/// and attributes
+	fn counters(controller: &mut PullController) -> HashMap<String, u64> {
+		let mut counters = HashMap::new();
+		controller.collect().unwrap();
+		controller
+			.try_for_each(&ExportKindSelector::Cumulative, &mut |record| {
+				let sum = record.aggregator().unwrap();
+				let sum = sum.as_any().downcast_ref::<SumAggregator>().unwrap();
+				let attributes = record
+					.attributes()
+					.iter()
+					.map(|(k, v)| format!("{}={}", k, v))
+					.collect::<Vec<_>>();
+				let name = format!("{}{:?}", record.descriptor().name(), attributes);
+				let value = sum.sum()?.to_u64(record.descriptor().number_kind());
+				counters.insert(name, value);
+				Ok(())
+			})
+			.unwrap();
+		counters
+	}
+
+	#[tokio::test]
+	async fn test_replay_to_shadow_cluster() {
+		let (endpoint, received) = mock_shadow_cluster().await;
+		let garage = test_garage(&endpoint).await;
+
+		let mut controller = controllers::pull(
+			Box::new(Selector::Inexpensive),
+			Box::new(ExportKindSelector::Cumulative),
+		)
+		.with_cache_period(Duration::ZERO)
+		.with_memory(true)
+		.build();
+		let meter = controller.provider().meter("test", None);
+		let mirror = ShadowMirror::with_meter(garage.clone(), &meter)
+			.unwrap()
+			.unwrap();
+
+		let bucket_id = gen_uuid();
+		let version_uuid = gen_uuid();
+		let object = Object::new(
+			bucket_id,
+			"a".into(),
+			vec![ObjectVersion {
+				uuid: version_uuid,
+				timestamp: 1,
+				state: ObjectVersionState::Complete(ObjectVersionData::Inline(
+					ObjectVersionMeta {
+						size: 5,
+						etag: "etag".into(),
+						expires_at: None,
+						encryption: ObjectVersionEncryption::Plaintext {
+							inner: ObjectVersionMetaInner {
+								headers: vec![],
+								checksum: None,
+								full_sha256: None,
+							},
+						},
+					},
+					b"hello".to_vec(),
+				)),
+			}],
+		);
+		garage.object_table.insert(&object).await.unwrap();
+
+		let request = |operation, key: &str, version_uuid| ShadowRequest {
+			operation,
+			bucket_id,
+			bucket_name: "bucket".into(),
+			key: key.into(),
+			shadow_key: key.into(),
+			version_uuid,
+		};
+		let resp = Response::new(());
+		// Replayed as a PutObject of the version that was written
+		mirror.enqueue(
+			request(ShadowOperation::PutObject, "a", Some(version_uuid)),
+			&resp,
+		);
+		// The version has been overwritten since
+		mirror.enqueue(
+			request(ShadowOperation::PutObject, "a", Some(gen_uuid())),
+			&resp,
+		);
+		mirror.enqueue(request(ShadowOperation::DeleteObject, "b", None), &resp);
+		// The shadow cluster returns an error
+		mirror.enqueue(request(ShadowOperation::DeleteObject, "fail", None), &resp);
+
+		let expected = [
+			("api.s3.shadow_mirrored_counter[\"operation=PutObject\"]", 1),
+			(
+				"api.s3.shadow_mirrored_counter[\"operation=DeleteObject\"]",
+				1,
+			),
+			(
+				"api.s3.shadow_dropped_counter[\"operation=PutObject\", \"reason=version_gone\"]",
+				1,
+			),
+			("api.s3.shadow_error_counter[\"operation=DeleteObject\"]", 1),
+		]
+		.iter()
+		.map(|(k, v)| (k.to_string(), *v))
+		.collect::<HashMap<_, u64>>();
+		let mut values = counters(&mut controller);
+		for _ in 0..50 {
+			if values == expected {
+				break;
+			}
+			tokio::time::sleep(Duration::from_millis(100)).await;
+			values = counters(&mut controller);
+		}
+		assert_eq!(values, expected);
+
+		let mut received = received.lock().unwrap().clone();
+		received.sort();
+		assert_eq!(
+			received,
+			vec![
+				("DELETE /bucket/b".to_string(), vec![]),
+				("DELETE /bucket/fail".to_string(), vec![]),
+				("PUT /bucket/a".to_string(), b"hello".to_vec()),
+			]
+		);
+	}
+}
@@ -1,16 +1,84 @@
+use std::fmt;
+
+use chrono::{Datelike, TimeZone, Timelike, Utc};
 use quick_xml::se::to_string;
 use serde::{Deserialize, Serialize, Serializer};
 
+use garage_util::metrics::timed_sync;
+
 use crate::s3::error::Error as ApiError;
 
 pub fn to_xml_with_header<T: Serialize>(x: &T) -> Result<String, ApiError> {
-	let mut xml = r#"<?xml version="1.0" encoding="UTF-8"?>"#.to_string();
-	xml.push_str(&to_string(x)?);
-	Ok(xml)
+	timed_sync("serialization", || {
+		let mut xml = r#"<?xml version="1.0" encoding="UTF-8"?>"#.to_string();
+		xml.push_str(&to_xml(x)?);
+		Ok(xml)
+	})
+}
+
+pub fn to_xml<T: Serialize>(x: &T) -> Result<String, ApiError> {
+	Ok(to_string(x)?)
+}
+
+/// Write text escaped for use as the content of an XML element, in the same
+/// way as the serializer used by `to_xml`
+pub fn write_escaped<W: fmt::Write>(w: &mut W, s: &str) -> fmt::Result {
+	let mut last = 0;
+	for (i, c) in s.char_indices() {
+		let escaped = match c {
+			'<' => "&lt;",
+			'>' => "&gt;",
+			'&' => "&amp;",
+			'\'' => "&apos;",
+			'"' => "&quot;",
+			_ => continue,
+		};
+		w.write_str(&s[last..i])?;
+		w.write_str(escaped)?;
+		last = i + 1;
+	}
+	w.write_str(&s[last..])
+}
+
+/// Write a timestamp in milliseconds in the format of `msec_to_rfc3339`,
+/// without allocating
+pub fn write_timestamp<W: fmt::Write>(w: &mut W, msecs: u64) -> fmt::Result {
+	let t = Utc
+		.timestamp_opt(msecs as i64 / 1000, 0)
+		.single()
+		.ok_or(fmt::Error)?;
+	write!(
+		w,
+		"{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+		t.year(),
+		t.month(),
+		t.day(),
+		t.hour(),
+		t.minute(),
+		t.second(),
+		msecs % 1000
+	)
 }
 
+/// Writer that only counts the bytes written to it, used to compute
+/// the size of serialized values without allocating
+#[derive(Default)]
+pub struct ByteCounter(pub usize);
+
+impl fmt::Write for ByteCounter {
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+		self.0 += s.len();
+		Ok(())
+	}
+}
+
+const S3_XMLNS: &str = "http://s3.amazonaws.com/doc/2006-03-01/";
+
+/// Serializer for the `xmlns` field of response types, which must only
+/// be present in types that are serialized as the root element: the
+/// serializer would otherwise declare the namespace again on nested elements
 pub fn xmlns_tag<S: Serializer>(_v: &(), s: S) -> Result<S::Ok, S::Error> {
-	s.serialize_str("http://s3.amazonaws.com/doc/2006-03-01/")
+	s.serialize_str(S3_XMLNS)
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
@@ -31,6 +99,12 @@ pub struct Bucket {
 	pub creation_date: Value,
 	#[serde(rename = "Name")]
 	pub name: Value,
+	/// Garage extension: identifier of the bucket, to tell
+	/// apart buckets that are known under several aliases
+	#[serde(rename = "GarageBucketId", skip_serializing_if = "Option::is_none")]
+	pub bucket_id: Option<Value>,
+	#[serde(rename = "Payer", skip_serializing_if = "Option::is_none")]
+	pub payer: Option<Value>,
 }
 
 #[derive(Debug, Serialize, PartialEq, Eq)]
@@ -49,6 +123,8 @@ pub struct BucketList {
 
 #[derive(Debug, Serialize, PartialEq, Eq)]
 pub struct ListAllMyBucketsResult {
+	#[serde(serialize_with = "xmlns_tag")]
+	pub xmlns: (),
 	#[serde(rename = "Buckets")]
 	pub buckets: BucketList,
 	#[serde(rename = "Owner")]
@@ -83,6 +159,8 @@ pub struct Error {
 	pub resource: Option<Value>,
 	#[serde(rename = "Region")]
 	pub region: Option<Value>,
+	#[serde(rename = "Endpoint", skip_serializing_if = "Option::is_none")]
+	pub endpoint: Option<Value>,
 }
 
 #[derive(Debug, Serialize, PartialEq, Eq)]
@@ -163,6 +241,9 @@ pub struct ListMultipartItem {
 	pub owner: Owner,
 	#[serde(rename = "StorageClass")]
 	pub storage_class: Value,
+	/// Garage extension: time of the last part uploaded
+	#[serde(rename = "GarageLastActivity", skip_serializing_if = "Option::is_none")]
+	pub last_activity: Option<Value>,
 }
 
 #[derive(Debug, Serialize, PartialEq, Eq)]
@@ -306,6 +387,7 @@ pub struct VersioningConfiguration {
 }
 
 #[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(rename = "PostResponse")]
 pub struct PostObject {
 	#[serde(serialize_with = "xmlns_tag")]
 	pub xmlns: (),
@@ -319,12 +401,324 @@ pub struct PostObject {
 	pub etag: Value,
 }
 
+/// Expected shape of a serialized response, checked in tests
+#[cfg(test)]
+pub(crate) struct XmlShape {
+	pub root: &'static str,
+	/// Whether the S3 namespace is declared (on the root element only)
+	pub xmlns: bool,
+	/// Child elements of each element that has some,
+	/// in the order in which they must appear
+	pub children: &'static [(&'static str, &'static [&'static str])],
+	/// Elements that can legitimately be empty, e.g. an empty prefix.
+	/// Other elements must have some content: unset fields are omitted.
+	pub may_be_empty: &'static [&'static str],
+}
+
+#[cfg(test)]
+impl XmlShape {
+	/// Check that `xml`, as returned by `to_xml_with_header`, has this shape,
+	/// and parse it back into the text of its leaf elements, by path
+	pub fn check(&self, xml: &str) -> Vec<(String, String)> {
+		let body = xml
+			.strip_prefix(r#"<?xml version="1.0" encoding="UTF-8"?>"#)
+			.expect("missing XML declaration");
+		let doc = roxmltree::Document::parse(body).expect("invalid XML");
+
+		let root = doc.root_element();
+		assert_eq!(root.tag_name().name(), self.root);
+		let namespace = Some(S3_XMLNS).filter(|_| self.xmlns);
+		assert_eq!(root.tag_name().namespace(), namespace, "{}", body);
+		assert_eq!(
+			body.matches("xmlns").count(),
+			self.xmlns as usize,
+			"namespace declared on nested elements: {}",
+			body
+		);
+
+		let mut leaves = vec![];
+		self.check_element(root, self.root.to_string(), &mut leaves);
+		leaves
+	}
+
+	fn check_element(
+		&self,
+		node: roxmltree::Node,
+		path: String,
+		leaves: &mut Vec<(String, String)>,
+	) {
+		let name = node.tag_name().name();
+		let children = node
+			.children()
+			.filter(|c| c.is_element())
+			.collect::<Vec<_>>();
+
+		if children.is_empty() {
+			let text = node.text().unwrap_or_default();
+			assert!(
+				!text.is_empty()
+					|| node == node.document().root_element()
+					|| self.may_be_empty.contains(&name),
+				"unexpected empty element {}",
+				path
+			);
+			leaves.push((path, text.to_string()));
+			return;
+		}
+
+		let expected = match self.children.iter().find(|(n, _)| *n == name) {
+			Some((_, c)) => *c,
+			None => panic!("unexpected child elements in {}", path),
+		};
+		let mut last = 0;
+		for child in children {
+			let child_name = child.tag_name().name();
+			let pos = match expected.iter().position(|n| *n == child_name) {
+				Some(pos) => pos,
+				None => panic!("unexpected element {} in {}", child_name, path),
+			};
+			assert!(
+				pos >= last,
+				"element {} out of order in {}",
+				child_name,
+				path
+			);
+			last = pos;
+			self.check_element(child, format!("{}/{}", path, child_name), leaves);
+		}
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
 
 	use garage_util::time::*;
 
+	// Expected shape of responses, from the S3 API reference
+
+	const OWNER: (&str, &[&str]) = ("Owner", &["DisplayName", "ID"]);
+	const INITIATOR: (&str, &[&str]) = ("Initiator", &["DisplayName", "ID"]);
+	const COMMON_PREFIXES: (&str, &[&str]) = ("CommonPrefixes", &["Prefix"]);
+
+	const ERROR_SHAPE: XmlShape = XmlShape {
+		root: "Error",
+		xmlns: false,
+		children: &[(
+			"Error",
+			&["Code", "Message", "Resource", "Region", "Endpoint"],
+		)],
+		may_be_empty: &[],
+	};
+
+	const LIST_ALL_MY_BUCKETS_SHAPE: XmlShape = XmlShape {
+		root: "ListAllMyBucketsResult",
+		xmlns: true,
+		children: &[
+			("ListAllMyBucketsResult", &["Buckets", "Owner"]),
+			("Buckets", &["Bucket"]),
+			(
+				"Bucket",
+				&["CreationDate", "Name", "GarageBucketId", "Payer"],
+			),
+			OWNER,
+		],
+		may_be_empty: &["Buckets", "DisplayName"],
+	};
+
+	const LOCATION_CONSTRAINT_SHAPE: XmlShape = XmlShape {
+		root: "LocationConstraint",
+		xmlns: true,
+		children: &[],
+		may_be_empty: &[],
+	};
+
+	const VERSIONING_SHAPE: XmlShape = XmlShape {
+		root: "VersioningConfiguration",
+		xmlns: true,
+		children: &[("VersioningConfiguration", &["Status"])],
+		may_be_empty: &[],
+	};
+
+	const DELETE_RESULT_SHAPE: XmlShape = XmlShape {
+		root: "DeleteResult",
+		xmlns: true,
+		children: &[
+			("DeleteResult", &["Deleted", "Error"]),
+			("Deleted", &["Key", "VersionId", "DeleteMarkerVersionId"]),
+			("Error", &["Code", "Key", "Message", "VersionId"]),
+		],
+		may_be_empty: &[],
+	};
+
+	const INITIATE_MPU_SHAPE: XmlShape = XmlShape {
+		root: "InitiateMultipartUploadResult",
+		xmlns: true,
+		children: &[(
+			"InitiateMultipartUploadResult",
+			&["Bucket", "Key", "UploadId"],
+		)],
+		may_be_empty: &[],
+	};
+
+	const COMPLETE_MPU_SHAPE: XmlShape = XmlShape {
+		root: "CompleteMultipartUploadResult",
+		xmlns: true,
+		children: &[(
+			"CompleteMultipartUploadResult",
+			&[
+				"Location",
+				"Bucket",
+				"Key",
+				"ETag",
+				"ChecksumCRC32",
+				"ChecksumCRC32C",
+				"ChecksumSHA1",
+				"ChecksumSHA256",
+			],
+		)],
+		may_be_empty: &[],
+	};
+
+	const LIST_MPU_SHAPE: XmlShape = XmlShape {
+		root: "ListMultipartUploadsResult",
+		xmlns: true,
+		children: &[
+			(
+				"ListMultipartUploadsResult",
+				&[
+					"Bucket",
+					"KeyMarker",
+					"UploadIdMarker",
+					"NextKeyMarker",
+					"NextUploadIdMarker",
+					"Prefix",
+					"Delimiter",
+					"MaxUploads",
+					"IsTruncated",
+					"Upload",
+					"CommonPrefixes",
+					"EncodingType",
+				],
+			),
+			(
+				"Upload",
+				&[
+					"Initiated",
+					"Initiator",
+					"Key",
+					"UploadId",
+					"Owner",
+					"StorageClass",
+					"GarageLastActivity",
+				],
+			),
+			INITIATOR,
+			OWNER,
+			COMMON_PREFIXES,
+		],
+		may_be_empty: &[
+			"KeyMarker",
+			"UploadIdMarker",
+			"Prefix",
+			"Delimiter",
+			"DisplayName",
+		],
+	};
+
+	const LIST_PARTS_SHAPE: XmlShape = XmlShape {
+		root: "ListPartsResult",
+		xmlns: true,
+		children: &[
+			(
+				"ListPartsResult",
+				&[
+					"Bucket",
+					"Key",
+					"UploadId",
+					"PartNumberMarker",
+					"NextPartNumberMarker",
+					"MaxParts",
+					"IsTruncated",
+					"Part",
+					"Initiator",
+					"Owner",
+					"StorageClass",
+				],
+			),
+			(
+				"Part",
+				&[
+					"ETag",
+					"LastModified",
+					"PartNumber",
+					"Size",
+					"ChecksumCRC32",
+					"ChecksumCRC32C",
+					"ChecksumSHA1",
+					"ChecksumSHA256",
+				],
+			),
+			INITIATOR,
+			OWNER,
+		],
+		may_be_empty: &["DisplayName"],
+	};
+
+	const LIST_BUCKET_SHAPE: XmlShape = XmlShape {
+		root: "ListBucketResult",
+		xmlns: true,
+		children: &[
+			(
+				"ListBucketResult",
+				&[
+					"Name",
+					"Prefix",
+					"Marker",
+					"NextMarker",
+					"StartAfter",
+					"ContinuationToken",
+					"NextContinuationToken",
+					"KeyCount",
+					"MaxKeys",
+					"Delimiter",
+					"EncodingType",
+					"IsTruncated",
+					"Contents",
+					"CommonPrefixes",
+				],
+			),
+			(
+				"Contents",
+				&["Key", "LastModified", "ETag", "Size", "StorageClass"],
+			),
+			COMMON_PREFIXES,
+		],
+		may_be_empty: &[
+			"Prefix",
+			"Marker",
+			"StartAfter",
+			"ContinuationToken",
+			"Delimiter",
+			"Key",
+		],
+	};
+
+	const POST_RESPONSE_SHAPE: XmlShape = XmlShape {
+		root: "PostResponse",
+		xmlns: true,
+		children: &[("PostResponse", &["Location", "Bucket", "Key", "ETag"])],
+		may_be_empty: &[],
+	};
+
+	fn leaf<'a>(leaves: &'a [(String, String)], path: &str) -> Vec<&'a str> {
+		leaves
+			.iter()
+			.filter(|(p, _)| p == path)
+			.map(|(_, v)| v.as_str())
+			.collect()
+	}
+
 	#[test]
 	fn error_message() -> Result<(), ApiError> {
 		let error = Error {
@@ -332,9 +726,12 @@ mod tests {
 			message: Value("A dummy error message".to_string()),
 			resource: Some(Value("/bucket/a/plop".to_string())),
 			region: Some(Value("garage".to_string())),
+			endpoint: None,
 		};
+		let xml = to_xml_with_header(&error)?;
+		ERROR_SHAPE.check(&xml);
 		assert_eq!(
-			to_xml_with_header(&error)?,
+			xml,
 			"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
 <Error>\
 	<Code>TestError</Code>\
@@ -349,6 +746,7 @@ mod tests {
 	#[test]
 	fn list_all_my_buckets_result() -> Result<(), ApiError> {
 		let list_buckets = ListAllMyBucketsResult {
+			xmlns: (),
 			owner: Owner {
 				display_name: Value("owner_name".to_string()),
 				id: Value("qsdfjklm".to_string()),
@@ -358,18 +756,24 @@ mod tests {
 					Bucket {
 						creation_date: Value(msec_to_rfc3339(0)),
 						name: Value("bucket_A".to_string()),
+						bucket_id: None,
+						payer: None,
 					},
 					Bucket {
 						creation_date: Value(msec_to_rfc3339(3600 * 24 * 1000)),
 						name: Value("bucket_B".to_string()),
+						bucket_id: Some(Value("0123abcd".to_string())),
+						payer: None,
 					},
 				],
 			},
 		};
+		let xml = to_xml_with_header(&list_buckets)?;
+		LIST_ALL_MY_BUCKETS_SHAPE.check(&xml);
 		assert_eq!(
-			to_xml_with_header(&list_buckets)?,
+			xml,
 			"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
-<ListAllMyBucketsResult>\
+<ListAllMyBucketsResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">\
    <Buckets>\
       <Bucket>\
          <CreationDate>1970-01-01T00:00:00.000Z</CreationDate>\
@@ -378,6 +782,7 @@ mod tests {
       <Bucket>\
          <CreationDate>1970-01-02T00:00:00.000Z</CreationDate>\
          <Name>bucket_B</Name>\
+         <GarageBucketId>0123abcd</GarageBucketId>\
       </Bucket>\
    </Buckets>\
    <Owner>\
@@ -395,8 +800,10 @@ mod tests {
 			xmlns: (),
 			region: "garage".to_string(),
 		};
+		let xml = to_xml_with_header(&get_bucket_location)?;
+		LOCATION_CONSTRAINT_SHAPE.check(&xml);
 		assert_eq!(
-			to_xml_with_header(&get_bucket_location)?,
+			xml,
 			"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
 <LocationConstraint xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">garage</LocationConstraint>"
 		);
@@ -409,8 +816,10 @@ mod tests {
 			xmlns: (),
 			status: None,
 		};
+		let xml = to_xml_with_header(&get_bucket_versioning)?;
+		VERSIONING_SHAPE.check(&xml);
 		assert_eq!(
-			to_xml_with_header(&get_bucket_versioning)?,
+			xml,
 			"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
 <VersioningConfiguration xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\"/>"
 		);
@@ -418,8 +827,10 @@ mod tests {
 			xmlns: (),
 			status: Some(Value("Suspended".to_string())),
 		};
+		let xml = to_xml_with_header(&get_bucket_versioning2)?;
+		VERSIONING_SHAPE.check(&xml);
 		assert_eq!(
-			to_xml_with_header(&get_bucket_versioning2)?,
+			xml,
 			"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
 <VersioningConfiguration xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\"><Status>Suspended</Status></VersioningConfiguration>"
 		);
@@ -458,8 +869,10 @@ mod tests {
 				},
 			],
 		};
+		let xml = to_xml_with_header(&delete_result)?;
+		DELETE_RESULT_SHAPE.check(&xml);
 		assert_eq!(
-			to_xml_with_header(&delete_result)?,
+			xml,
 			"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
 <DeleteResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">\
     <Deleted>\
@@ -496,8 +909,10 @@ mod tests {
 			key: Value("a/plop".to_string()),
 			upload_id: Value("azerty".to_string()),
 		};
+		let xml = to_xml_with_header(&result)?;
+		INITIATE_MPU_SHAPE.check(&xml);
 		assert_eq!(
-			to_xml_with_header(&result)?,
+			xml,
 			"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
 <InitiateMultipartUploadResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">\
 	<Bucket>mybucket</Bucket>\
@@ -521,8 +936,10 @@ mod tests {
 			checksum_sha1: Some(Value("ZJAnHyG8PeKz9tI8UTcHrJos39A=".into())),
 			checksum_sha256: None,
 		};
+		let xml = to_xml_with_header(&result)?;
+		COMPLETE_MPU_SHAPE.check(&xml);
 		assert_eq!(
-			to_xml_with_header(&result)?,
+			xml,
 			"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
 <CompleteMultipartUploadResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">\
 	<Location>https://garage.tld/mybucket/a/plop</Location>\
@@ -563,8 +980,10 @@ mod tests {
 			],
 		};
 
+		let xml = to_xml_with_header(&result)?;
+		LIST_MPU_SHAPE.check(&xml);
 		assert_eq!(
-			to_xml_with_header(&result)?,
+			xml,
 			"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
 <ListMultipartUploadsResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">\
 	<Bucket>example-bucket</Bucket>\
@@ -614,8 +1033,10 @@ mod tests {
 				prefix: Value("photos/".to_string()),
 			}],
 		};
+		let xml = to_xml_with_header(&result)?;
+		LIST_BUCKET_SHAPE.check(&xml);
 		assert_eq!(
-			to_xml_with_header(&result)?,
+			xml,
 			"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
 <ListBucketResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">\
   <Name>example-bucket</Name>\
@@ -665,8 +1086,10 @@ mod tests {
 				},
 			],
 		};
+		let xml = to_xml_with_header(&result)?;
+		LIST_BUCKET_SHAPE.check(&xml);
 		assert_eq!(
-			to_xml_with_header(&result)?,
+			xml,
 			"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
 <ListBucketResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">\
   <Name>example-bucket</Name>\
@@ -711,8 +1134,10 @@ mod tests {
 			}],
 			common_prefixes: vec![],
 		};
+		let xml = to_xml_with_header(&result)?;
+		LIST_BUCKET_SHAPE.check(&xml);
 		assert_eq!(
-			to_xml_with_header(&result)?,
+			xml,
 			"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
 <ListBucketResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">\
   <Name>quotes</Name>\
@@ -759,8 +1184,10 @@ mod tests {
 			}],
 			common_prefixes: vec![],
 		};
+		let xml = to_xml_with_header(&result)?;
+		LIST_BUCKET_SHAPE.check(&xml);
 		assert_eq!(
-			to_xml_with_header(&result)?,
+			xml,
 			"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
 <ListBucketResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">\
   <Name>bucket</Name>\
@@ -835,8 +1262,10 @@ mod tests {
 			storage_class: Value("STANDARD".to_string()),
 		};
 
+		let xml = to_xml_with_header(&result)?;
+		LIST_PARTS_SHAPE.check(&xml);
 		assert_eq!(
-			to_xml_with_header(&result)?,
+			xml,
 			"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
 <ListPartsResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">\
   <Bucket>example-bucket</Bucket>\
@@ -874,4 +1303,351 @@ mod tests {
 
 		Ok(())
 	}
+
+	#[test]
+	fn post_object_result() -> Result<(), ApiError> {
+		let result = PostObject {
+			xmlns: (),
+			location: Value("https://garage.tld/mybucket/a/plop".to_string()),
+			bucket: Value("mybucket".to_string()),
+			key: Value("a/plop".to_string()),
+			etag: Value("\"3858f62230ac3c915f300c664312c11f\"".to_string()),
+		};
+		let xml = to_xml_with_header(&result)?;
+		POST_RESPONSE_SHAPE.check(&xml);
+		assert_eq!(
+			xml,
+			"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+<PostResponse xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">\
+	<Location>https://garage.tld/mybucket/a/plop</Location>\
+	<Bucket>mybucket</Bucket>\
+	<Key>a/plop</Key>\
+	<ETag>&quot;3858f62230ac3c915f300c664312c11f&quot;</ETag>\
+</PostResponse>"
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn all_fields_round_trip() -> Result<(), ApiError> {
+		// Values that need escaping are parsed back as they were
+		let key = "a/<b>&'c\"dé";
+		let etag = "\"9b2cf535f27731c974343645a3985328\"";
+
+		let error = Error {
+			code: Value("NoSuchKey".into()),
+			message: Value("Key not found".into()),
+			resource: Some(Value(key.into())),
+			region: Some(Value("garage".into())),
+			endpoint: Some(Value("s3.garage.tld".into())),
+		};
+		let leaves = ERROR_SHAPE.check(&to_xml_with_header(&error)?);
+		assert_eq!(leaves.len(), 5);
+		assert_eq!(leaf(&leaves, "Error/Resource"), [key]);
+
+		let list_buckets = ListAllMyBucketsResult {
+			xmlns: (),
+			buckets: BucketList {
+				entries: vec![Bucket {
+					creation_date: Value(msec_to_rfc3339(0)),
+					name: Value("bucket".into()),
+					bucket_id: Some(Value("0123abcd".into())),
+					payer: Some(Value("Requester".into())),
+				}],
+			},
+			owner: Owner {
+				display_name: Value("owner".into()),
+				id: Value("GK123".into()),
+			},
+		};
+		let leaves = LIST_ALL_MY_BUCKETS_SHAPE.check(&to_xml_with_header(&list_buckets)?);
+		assert_eq!(
+			leaf(&leaves, "ListAllMyBucketsResult/Buckets/Bucket/Payer"),
+			["Requester"]
+		);
+
+		let complete = CompleteMultipartUploadResult {
+			xmlns: (),
+			location: Some(Value("https://garage.tld/bucket/a".into())),
+			bucket: Value("bucket".into()),
+			key: Value(key.into()),
+			etag: Value(etag.into()),
+			checksum_crc32: Some(Value("crc32".into())),
+			checksum_crc32c: Some(Value("crc32c".into())),
+			checksum_sha1: Some(Value("sha1".into())),
+			checksum_sha256: Some(Value("sha256".into())),
+		};
+		let leaves = COMPLETE_MPU_SHAPE.check(&to_xml_with_header(&complete)?);
+		let r = "CompleteMultipartUploadResult";
+		assert_eq!(
+			leaves,
+			[
+				("Location", "https://garage.tld/bucket/a"),
+				("Bucket", "bucket"),
+				("Key", key),
+				("ETag", etag),
+				("ChecksumCRC32", "crc32"),
+				("ChecksumCRC32C", "crc32c"),
+				("ChecksumSHA1", "sha1"),
+				("ChecksumSHA256", "sha256"),
+			]
+			.iter()
+			.map(|(p, v)| (format!("{}/{}", r, p), v.to_string()))
+			.collect::<Vec<_>>()
+		);
+
+		let list_mpu = ListMultipartUploadsResult {
+			xmlns: (),
+			bucket: Value("bucket".into()),
+			key_marker: Some(Value("a".into())),
+			upload_id_marker: Some(Value("0123".into())),
+			next_key_marker: Some(Value(key.into())),
+			next_upload_id_marker: Some(Value("4567".into())),
+			prefix: Value("a/".into()),
+			delimiter: Some(Value("/".into())),
+			max_uploads: IntValue(1),
+			is_truncated: Value("true".into()),
+			upload: vec![ListMultipartItem {
+				initiated: Value(msec_to_rfc3339(0)),
+				initiator: Initiator {
+					display_name: Value("owner".into()),
+					id: Value("GK123".into()),
+				},
+				key: Value(key.into()),
+				upload_id: Value("4567".into()),
+				owner: Owner {
+					display_name: Value("owner".into()),
+					id: Value("GK123".into()),
+				},
+				storage_class: Value("STANDARD".into()),
+				last_activity: Some(Value(msec_to_rfc3339(1000))),
+			}],
+			common_prefixes: vec![CommonPrefix {
+				prefix: Value("a/b/".into()),
+			}],
+			encoding_type: Some(Value("url".into())),
+		};
+		let leaves = LIST_MPU_SHAPE.check(&to_xml_with_header(&list_mpu)?);
+		assert_eq!(leaves.len(), 20);
+		assert_eq!(
+			leaf(&leaves, "ListMultipartUploadsResult/Upload/Key"),
+			[key]
+		);
+
+		let list_parts = ListPartsResult {
+			xmlns: (),
+			bucket: Value("bucket".into()),
+			key: Value(key.into()),
+			upload_id: Value("4567".into()),
+			part_number_marker: Some(IntValue(1)),
+			next_part_number_marker: Some(IntValue(2)),
+			max_parts: IntValue(1),
+			is_truncated: Value("true".into()),
+			parts: vec![PartItem {
+				etag: Value(etag.into()),
+				last_modified: Value(msec_to_rfc3339(0)),
+				part_number: IntValue(2),
+				size: IntValue(5242880),
+				checksum_crc32: Some(Value("crc32".into())),
+				checksum_crc32c: Some(Value("crc32c".into())),
+				checksum_sha1: Some(Value("sha1".into())),
+				checksum_sha256: Some(Value("sha256".into())),
+			}],
+			initiator: Initiator {
+				display_name: Value("owner".into()),
+				id: Value("GK123".into()),
+			},
+			owner: Owner {
+				display_name: Value("owner".into()),
+				id: Value("GK123".into()),
+			},
+			storage_class: Value("STANDARD".into()),
+		};
+		let leaves = LIST_PARTS_SHAPE.check(&to_xml_with_header(&list_parts)?);
+		assert_eq!(leaves.len(), 20);
+		assert_eq!(leaf(&leaves, "ListPartsResult/Part/ETag"), [etag]);
+
+		let list_bucket = ListBucketResult {
+			xmlns: (),
+			name: Value("bucket".into()),
+			prefix: Value("a/".into()),
+			marker: Some(Value("a/0".into())),
+			next_marker: Some(Value("a/1".into())),
+			start_after: Some(Value("a/0".into())),
+			continuation_token: Some(Value("token".into())),
+			next_continuation_token: Some(Value("next-token".into())),
+			key_count: Some(IntValue(2)),
+			max_keys: IntValue(2),
+			delimiter: Some(Value("/".into())),
+			encoding_type: Some(Value("url".into())),
+			is_truncated: Value("true".into()),
+			contents: vec![ListBucketItem {
+				key: Value(key.into()),
+				last_modified: Value(msec_to_rfc3339(0)),
+				etag: Value(etag.into()),
+				size: IntValue(42),
+				storage_class: Value("STANDARD".into()),
+			}],
+			common_prefixes: vec![CommonPrefix {
+				prefix: Value("a/b/".into()),
+			}],
+		};
+		let leaves = LIST_BUCKET_SHAPE.check(&to_xml_with_header(&list_bucket)?);
+		assert_eq!(leaves.len(), 18);
+		assert_eq!(leaf(&leaves, "ListBucketResult/Contents/Key"), [key]);
+		assert_eq!(
+			leaf(&leaves, "ListBucketResult/CommonPrefixes/Prefix"),
+			["a/b/"]
+		);
+
+		let delete = DeleteResult {
+			xmlns: (),
+			deleted: vec![Deleted {
+				key: Value(key.into()),
+				version_id: Value("0123".into()),
+				delete_marker_version_id: Value("4567".into()),
+			}],
+			errors: vec![DeleteError {
+				code: Value("AccessDenied".into()),
+				key: Some(Value("b".into())),
+				message: Value("Access denied".into()),
+				version_id: Some(Value("89ab".into())),
+			}],
+		};
+		let leaves = DELETE_RESULT_SHAPE.check(&to_xml_with_header(&delete)?);
+		assert_eq!(leaves.len(), 7);
+		assert_eq!(leaf(&leaves, "DeleteResult/Deleted/Key"), [key]);
+
+		Ok(())
+	}
+
+	#[test]
+	fn empty_results() -> Result<(), ApiError> {
+		// Unset fields and empty lists are omitted,
+		// empty strings are kept as empty elements
+		let list_buckets = ListAllMyBucketsResult {
+			xmlns: (),
+			buckets: BucketList { entries: vec![] },
+			owner: Owner {
+				display_name: Value("".into()),
+				id: Value("GK123".into()),
+			},
+		};
+		let leaves = LIST_ALL_MY_BUCKETS_SHAPE.check(&to_xml_with_header(&list_buckets)?);
+		assert_eq!(leaf(&leaves, "ListAllMyBucketsResult/Buckets"), [""]);
+
+		let list_bucket = ListBucketResult {
+			xmlns: (),
+			name: Value("bucket".into()),
+			prefix: Value("".into()),
+			marker: None,
+			next_marker: None,
+			start_after: None,
+			continuation_token: None,
+			next_continuation_token: None,
+			key_count: Some(IntValue(0)),
+			max_keys: IntValue(1000),
+			delimiter: Some(Value("".into())),
+			encoding_type: None,
+			is_truncated: Value("false".into()),
+			contents: vec![],
+			common_prefixes: vec![],
+		};
+		let leaves = LIST_BUCKET_SHAPE.check(&to_xml_with_header(&list_bucket)?);
+		let r = "ListBucketResult";
+		assert_eq!(
+			leaves,
+			[
+				("Name", "bucket"),
+				("Prefix", ""),
+				("KeyCount", "0"),
+				("MaxKeys", "1000"),
+				("Delimiter", ""),
+				("IsTruncated", "false"),
+			]
+			.iter()
+			.map(|(p, v)| (format!("{}/{}", r, p), v.to_string()))
+			.collect::<Vec<_>>()
+		);
+
+		let delete = DeleteResult {
+			xmlns: (),
+			deleted: vec![],
+			errors: vec![],
+		};
+		let xml = to_xml_with_header(&delete)?;
+		DELETE_RESULT_SHAPE.check(&xml);
+		assert_eq!(
+			xml,
+			"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+<DeleteResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\"/>"
+		);
+
+		let complete = CompleteMultipartUploadResult {
+			xmlns: (),
+			location: None,
+			bucket: Value("bucket".into()),
+			key: Value("a".into()),
+			etag: Value("\"etag\"".into()),
+			checksum_crc32: None,
+			checksum_crc32c: None,
+			checksum_sha1: None,
+			checksum_sha256: None,
+		};
+		let leaves = COMPLETE_MPU_SHAPE.check(&to_xml_with_header(&complete)?);
+		assert_eq!(leaves.len(), 3);
+
+		Ok(())
+	}
+
+	#[test]
+	#[should_panic(expected = "namespace declared on nested elements")]
+	fn nested_namespace_is_detected() {
+		#[derive(Serialize)]
+		struct Wrapper {
+			#[serde(serialize_with = "xmlns_tag")]
+			xmlns: (),
+			#[serde(rename = "InitiateMultipartUploadResult")]
+			inner: InitiateMultipartUploadResult,
+		}
+		const SHAPE: XmlShape = XmlShape {
+			root: "Wrapper",
+			xmlns: true,
+			children: &[
+				("Wrapper", &["InitiateMultipartUploadResult"]),
+				INITIATE_MPU_SHAPE.children[0],
+			],
+			may_be_empty: &[],
+		};
+		SHAPE.check(
+			&to_xml_with_header(&Wrapper {
+				xmlns: (),
+				inner: InitiateMultipartUploadResult {
+					xmlns: (),
+					bucket: Value("bucket".into()),
+					key: Value("a".into()),
+					upload_id: Value("0123".into()),
+				},
+			})
+			.unwrap(),
+		);
+	}
+
+	#[test]
+	fn incremental_writers() {
+		for ts in [0, 1641394898314, 1641394898000, 253402300799999] {
+			let mut s = String::new();
+			write_timestamp(&mut s, ts).unwrap();
+			assert_eq!(s, msec_to_rfc3339(ts));
+		}
+
+		let text = "a<b>&'c\"dé";
+		let mut s = String::new();
+		write_escaped(&mut s, text).unwrap();
+		assert_eq!(s, "a&lt;b&gt;&amp;&apos;c&quot;dé");
+
+		let mut counter = ByteCounter::default();
+		write_escaped(&mut counter, text).unwrap();
+		assert_eq!(counter.0, s.len());
+	}
 }
@@ -12,6 +12,7 @@ use error::*;
 
 pub mod error;
 pub mod payload;
+pub mod sign;
 pub mod streaming;
 
 pub const SHORT_DATE: &str = "%Y%m%d";
@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::time::Instant;
 
 use chrono::{DateTime, Duration, NaiveDateTime, TimeZone, Utc};
 use hmac::Mac;
@@ -9,6 +10,7 @@ use sha2::{Digest, Sha256};
 
 use garage_table::*;
 use garage_util::data::Hash;
+use garage_util::time::now_msec;
 
 use garage_model::garage::Garage;
 use garage_model::key_table::*;
@@ -45,6 +47,14 @@ pub async fn check_payload_signature(
 	service: &'static str,
 ) -> Result<(Option<Key>, Option<Hash>), Error> {
 	let query = parse_query_map(request.uri())?;
+	if garage.config.s3_api.reject_duplicate_query_params {
+		if let Some(name) = query.keys().find(|k| query.get_all(*k).iter().count() > 1) {
+			return Err(Error::bad_request(format!(
+				"duplicate query parameter: `{}`",
+				name
+			)));
+		}
+	}
 
 	if query.contains_key(&X_AMZ_ALGORITHM) {
 		// We check for presigned-URL-style authentification first, because
@@ -77,34 +87,13 @@ async fn check_standard_signature(
 	request: &Request<IncomingBody>,
 	query: QueryMap,
 ) -> Result<(Option<Key>, Option<Hash>), Error> {
-	let authorization = Authorization::parse_header(request.headers())?;
-
-	// Verify that all necessary request headers are included in signed_headers
-	// The following must be included for all signatures:
-	// - the Host header (mandatory)
-	// - all x-amz-* headers used in the request
-	// AWS also indicates that the Content-Type header should be signed if
-	// it is used, but Minio client doesn't sign it so we don't check it for compatibility.
-	let signed_headers = split_signed_headers(&authorization)?;
-	verify_signed_headers(request.headers(), &signed_headers)?;
-
-	let canonical_request = canonical_request(
+	let (authorization, string_to_sign) = standard_string_to_sign(
 		service,
 		request.method(),
 		request.uri().path(),
 		&query,
 		request.headers(),
-		&signed_headers,
-		&authorization.content_sha256,
 	)?;
-	let string_to_sign = string_to_sign(
-		&authorization.date,
-		&authorization.scope,
-		&canonical_request,
-	);
-
-	trace!("canonical request:\n{}", canonical_request);
-	trace!("string to sign:\n{}", string_to_sign);
 
 	let key = verify_v4(garage, service, &authorization, string_to_sign.as_bytes()).await?;
 
@@ -128,38 +117,13 @@ async fn check_presigned_signature(
 	request: &mut Request<IncomingBody>,
 	mut query: QueryMap,
 ) -> Result<(Option<Key>, Option<Hash>), Error> {
-	let algorithm = query.get(&X_AMZ_ALGORITHM).unwrap();
-	let authorization = Authorization::parse_presigned(&algorithm.value, &query)?;
-
-	// Verify that all necessary request headers are included in signed_headers
-	// For AWSv4 pre-signed URLs, the following must be incldued:
-	// - the Host header (mandatory)
-	// - all x-amz-* headers used in the request
-	let signed_headers = split_signed_headers(&authorization)?;
-	verify_signed_headers(request.headers(), &signed_headers)?;
-
-	// The X-Amz-Signature value is passed as a query parameter,
-	// but the signature cannot be computed from a string that contains itself.
-	// AWS specifies that all query params except X-Amz-Signature are included
-	// in the canonical request.
-	query.remove(&X_AMZ_SIGNATURE);
-	let canonical_request = canonical_request(
+	let (authorization, signed_headers, string_to_sign) = presigned_string_to_sign(
 		service,
 		request.method(),
 		request.uri().path(),
-		&query,
+		&mut query,
 		request.headers(),
-		&signed_headers,
-		&authorization.content_sha256,
 	)?;
-	let string_to_sign = string_to_sign(
-		&authorization.date,
-		&authorization.scope,
-		&canonical_request,
-	);
-
-	trace!("canonical request (presigned url):\n{}", canonical_request);
-	trace!("string to sign (presigned url):\n{}", string_to_sign);
 
 	let key = verify_v4(garage, service, &authorization, string_to_sign.as_bytes()).await?;
 
@@ -196,6 +160,95 @@ async fn check_presigned_signature(
 	Ok((Some(key), None))
 }
 
+/// Parses the Authorization header of a request and computes the string
+/// that its signature is expected to be computed on
+pub(super) fn standard_string_to_sign(
+	service: &'static str,
+	method: &Method,
+	path: &str,
+	query: &QueryMap,
+	headers: &HeaderMap,
+) -> Result<(Authorization, String), Error> {
+	let authorization = Authorization::parse_header(headers)?;
+
+	// Verify that all necessary request headers are included in signed_headers
+	// The following must be included for all signatures:
+	// - the Host header (mandatory)
+	// - all x-amz-* headers used in the request
+	// AWS also indicates that the Content-Type header should be signed if
+	// it is used, but Minio client doesn't sign it so we don't check it for compatibility.
+	let signed_headers = split_signed_headers(&authorization)?;
+	verify_signed_headers(headers, &signed_headers)?;
+
+	let canonical_request = canonical_request(
+		service,
+		method,
+		path,
+		query,
+		headers,
+		&signed_headers,
+		&authorization.content_sha256,
+	)?;
+	let string_to_sign = string_to_sign(
+		&authorization.date,
+		&authorization.scope,
+		&canonical_request,
+	);
+
+	trace!("canonical request:\n{}", canonical_request);
+	trace!("string to sign:\n{}", string_to_sign);
+
+	Ok((authorization, string_to_sign))
+}
+
+/// Parses the X-Amz-* query parameters of a presigned URL and computes the
+/// string that its signature is expected to be computed on.
+/// The X-Amz-Signature parameter is removed from `query`.
+pub(super) fn presigned_string_to_sign(
+	service: &'static str,
+	method: &Method,
+	path: &str,
+	query: &mut QueryMap,
+	headers: &HeaderMap,
+) -> Result<(Authorization, Vec<HeaderName>, String), Error> {
+	let algorithm = query
+		.get(&X_AMZ_ALGORITHM)
+		.ok_or_bad_request("X-Amz-Algorithm not found in query parameters")?;
+	let authorization = Authorization::parse_presigned(&algorithm.value, query)?;
+
+	// Verify that all necessary request headers are included in signed_headers
+	// For AWSv4 pre-signed URLs, the following must be incldued:
+	// - the Host header (mandatory)
+	// - all x-amz-* headers used in the request
+	let signed_headers = split_signed_headers(&authorization)?;
+	verify_signed_headers(headers, &signed_headers)?;
+
+	// The X-Amz-Signature value is passed as a query parameter,
+	// but the signature cannot be computed from a string that contains itself.
+	// AWS specifies that all query params except X-Amz-Signature are included
+	// in the canonical request.
+	query.remove(&X_AMZ_SIGNATURE);
+	let canonical_request = canonical_request(
+		service,
+		method,
+		path,
+		query,
+		headers,
+		&signed_headers,
+		&authorization.content_sha256,
+	)?;
+	let string_to_sign = string_to_sign(
+		&authorization.date,
+		&authorization.scope,
+		&canonical_request,
+	);
+
+	trace!("canonical request (presigned url):\n{}", canonical_request);
+	trace!("string to sign (presigned url):\n{}", string_to_sign);
+
+	Ok((authorization, signed_headers, string_to_sign))
+}
+
 pub fn parse_query_map(uri: &http::uri::Uri) -> Result<QueryMap, Error> {
 	let mut query = QueryMap::with_capacity(0);
 	if let Some(query_str) = uri.query() {
@@ -204,17 +257,20 @@ pub fn parse_query_map(uri: &http::uri::Uri) -> Result<QueryMap, Error> {
 			let name =
 				HeaderName::from_bytes(key.as_bytes()).ok_or_bad_request("Invalid header name")?;
 
-			let value = QueryValue {
-				key: key.to_string(),
-				value: val.into_owned(),
-			};
-
-			if query.insert(name, value).is_some() {
+			// The X-Amz-* parameters of presigned URLs, and the x-amz-* parameters
+			// that stand in for headers, can only have one value
+			if name.as_str().starts_with("x-amz-") && query.contains_key(&name) {
 				return Err(Error::bad_request(format!(
 					"duplicate query parameter: `{}`",
 					key
 				)));
 			}
+
+			let value = QueryValue {
+				key: key.to_string(),
+				value: val.into_owned(),
+			};
+			query.append(name, value);
 		}
 	}
 	Ok(query)
@@ -315,14 +371,19 @@ pub fn canonical_request(
 		canonical_uri.into()
 	};
 
-	// Canonical query string from passed HeaderMap
+	// Canonical query string from passed HeaderMap. Parameters are sorted by
+	// name, and parameters that appear several times are sorted by value.
 	let canonical_query_string = {
 		let mut items = Vec::with_capacity(query.len());
 		for (_, QueryValue { key, value }) in query.iter() {
-			items.push(uri_encode(&key, true) + "=" + &uri_encode(&value, true));
+			items.push((uri_encode(key, true), uri_encode(value, true)));
 		}
 		items.sort();
-		items.join("&")
+		items
+			.iter()
+			.map(|(key, value)| format!("{}={}", key, value))
+			.collect::<Vec<_>>()
+			.join("&")
 	};
 
 	// Canonical header string calculated from signed headers
@@ -369,28 +430,53 @@ pub async fn verify_v4(
 	}
 
 	let key = garage
-		.key_table
-		.get(&EmptyKey, &auth.key_id)
+		.key_cache
+		.get_or_fetch(&auth.key_id, Instant::now(), || {
+			garage.key_table.get(&EmptyKey, &auth.key_id)
+		})
 		.await?
 		.filter(|k| !k.state.is_deleted())
 		.ok_or_else(|| Error::forbidden(format!("No such key: {}", &auth.key_id)))?;
 	let key_p = key.params().unwrap();
 
-	let mut hmac = signing_hmac(
-		&auth.date,
-		&key_p.secret_key,
+	check_signature(
+		auth,
+		key_p.secret(),
 		&garage.config.s3_api.s3_region,
 		service,
-	)
-	.ok_or_internal_error("Unable to build signing HMAC")?;
+		payload,
+	)?;
+
+	if key.is_suspended() {
+		return Err(Error::forbidden(format!(
+			"Key {} is suspended",
+			&auth.key_id
+		)));
+	}
+
+	garage.key_usage.record(&key.key_id, now_msec());
+
+	Ok(key)
+}
+
+/// Checks that the signature of `auth` is a valid signature of `payload`
+/// using the given secret key
+pub(super) fn check_signature(
+	auth: &Authorization,
+	secret_key: &str,
+	region: &str,
+	service: &str,
+	payload: &[u8],
+) -> Result<(), Error> {
+	let mut hmac = signing_hmac(&auth.date, secret_key, region, service)
+		.ok_or_internal_error("Unable to build signing HMAC")?;
 	hmac.update(payload);
 	let signature =
 		hex::decode(&auth.signature).map_err(|_| Error::forbidden("Invalid signature"))?;
 	if hmac.verify_slice(&signature).is_err() {
 		return Err(Error::forbidden("Invalid signature"));
 	}
-
-	Ok(key)
+	Ok(())
 }
 
 // ============ Authorization header, or X-Amz-* query params =========
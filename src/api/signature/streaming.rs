@@ -1,5 +1,6 @@
 use std::pin::Pin;
 
+use bytes::Buf;
 use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
 use futures::prelude::*;
 use futures::task;
@@ -8,10 +9,11 @@ use hmac::Mac;
 use http_body_util::StreamBody;
 use hyper::body::{Bytes, Incoming as IncomingBody};
 use hyper::Request;
+use sha2::{Digest, Sha256};
 
 use garage_util::data::Hash;
 
-use super::{compute_scope, sha256sum, HmacSha256, LONG_DATETIME};
+use super::{compute_scope, HmacSha256, LONG_DATETIME};
 
 use crate::helpers::*;
 use crate::signature::error::*;
@@ -40,7 +42,7 @@ pub fn parse_streaming_body(
 				.state
 				.as_option()
 				.ok_or_internal_error("Deleted key state")?
-				.secret_key;
+				.secret();
 
 			let date = req
 				.headers()
@@ -72,7 +74,7 @@ pub fn parse_streaming_body(
 const EMPTY_STRING_HEX_DIGEST: &str =
 	"e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
 
-fn compute_streaming_payload_signature(
+pub(super) fn compute_streaming_payload_signature(
 	signing_hmac: &HmacSha256,
 	date: DateTime<Utc>,
 	scope: &str,
@@ -189,11 +191,32 @@ impl<I> From<nom::error::Error<I>> for SignedPayloadStreamError {
 	}
 }
 
-struct SignedPayload {
-	header: payload::Header,
-	data: Bytes,
+// Maximum length of the header of a chunk, which is normally
+// `<hex size>;chunk-signature=<64 hex digits>\r\n`
+const MAX_CHUNK_HEADER_LEN: usize = 1024;
+
+enum PayloadState {
+	/// Waiting for the header of the next chunk
+	Header,
+	/// Reading the data of a chunk, of which `remaining` bytes are not read yet
+	Data {
+		signature: Hash,
+		remaining: usize,
+		hasher: Sha256,
+	},
+	/// The last chunk has been read
+	Done,
 }
 
+/// Stream of the data of an `aws-chunked` body, whose chunks are each signed.
+///
+/// The data of a chunk is passed on as soon as it is received, without
+/// waiting for the whole chunk, so that the memory used does not depend on
+/// the size of chunks, which is chosen by the client. The signature of a chunk
+/// is checked when all its data has been read: if it is invalid, the stream
+/// returns an error instead of reading the next chunk. As for requests whose
+/// body is checked against the `x-amz-content-sha256` header, the consumer of
+/// the stream must not commit anything before the end of the stream.
 #[pin_project::pin_project]
 pub struct SignedPayloadStream<S>
 where
@@ -202,6 +225,7 @@ where
 	#[pin]
 	stream: S,
 	buf: bytes::BytesMut,
+	state: PayloadState,
 	datetime: DateTime<Utc>,
 	scope: String,
 	signing_hmac: HmacSha256,
@@ -222,42 +246,13 @@ where
 		Self {
 			stream,
 			buf: bytes::BytesMut::new(),
+			state: PayloadState::Header,
 			datetime,
 			scope: scope.into(),
 			signing_hmac,
 			previous_signature: seed_signature,
 		}
 	}
-
-	fn parse_next(input: &[u8]) -> nom::IResult<&[u8], SignedPayload, SignedPayloadStreamError> {
-		use nom::bytes::streaming::{tag, take};
-
-		macro_rules! try_parse {
-			($expr:expr) => {
-				$expr.map_err(nom::Err::convert)?
-			};
-		}
-
-		let (input, header) = try_parse!(payload::Header::parse(input));
-
-		// 0-sized chunk is the last
-		if header.size == 0 {
-			return Ok((
-				input,
-				SignedPayload {
-					header,
-					data: Bytes::new(),
-				},
-			));
-		}
-
-		let (input, data) = try_parse!(take::<_, _, nom::error::Error<_>>(header.size)(input));
-		let (input, _) = try_parse!(tag::<_, _, nom::error::Error<_>>("\r\n")(input));
-
-		let data = Bytes::from(data.to_vec());
-
-		Ok((input, SignedPayload { header, data }))
-	}
 }
 
 impl<S> Stream for SignedPayloadStream<S>
@@ -275,55 +270,98 @@ where
 		let mut this = self.project();
 
 		loop {
-			let (input, payload) = match Self::parse_next(this.buf) {
-				Ok(res) => res,
-				Err(nom::Err::Incomplete(_)) => {
-					match futures::ready!(this.stream.as_mut().poll_next(cx)) {
-						Some(Ok(bytes)) => {
-							this.buf.extend(bytes);
-							continue;
-						}
-						Some(Err(e)) => {
-							return Poll::Ready(Some(Err(SignedPayloadStreamError::Stream(e))))
+			match this.state {
+				PayloadState::Header => match payload::Header::parse(this.buf) {
+					Ok((input, header)) => {
+						let header_len = this.buf.len() - input.len();
+						this.buf.advance(header_len);
+
+						// 0-sized chunk is the last
+						if header.size == 0 {
+							*this.state = PayloadState::Done;
+							return Poll::Ready(None);
 						}
-						None => {
+
+						*this.state = PayloadState::Data {
+							signature: header.signature,
+							remaining: header.size,
+							hasher: Sha256::new(),
+						};
+						continue;
+					}
+					Err(nom::Err::Incomplete(_)) => {
+						if this.buf.len() > MAX_CHUNK_HEADER_LEN {
 							return Poll::Ready(Some(Err(SignedPayloadStreamError::message(
-								"Unexpected EOF",
+								"Chunk header too long",
 							))));
 						}
 					}
+					Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+						return Poll::Ready(Some(Err(e.into())))
+					}
+				},
+				PayloadState::Data {
+					remaining, hasher, ..
+				} if *remaining > 0 => {
+					if !this.buf.is_empty() {
+						let len = std::cmp::min(*remaining, this.buf.len());
+						let data = this.buf.split_to(len).freeze();
+						hasher.update(&data);
+						*remaining -= len;
+						return Poll::Ready(Some(Ok(data)));
+					}
 				}
-				Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
-					return Poll::Ready(Some(Err(e)))
-				}
-			};
+				PayloadState::Data {
+					signature, hasher, ..
+				} => {
+					if this.buf.len() >= 2 {
+						if &this.buf[..2] != b"\r\n" {
+							return Poll::Ready(Some(Err(SignedPayloadStreamError::message(
+								"Missing CRLF after chunk data",
+							))));
+						}
+						this.buf.advance(2);
+
+						let mut data_sha256sum = [0u8; 32];
+						data_sha256sum.copy_from_slice(&hasher.finalize_reset());
+						let expected_signature = compute_streaming_payload_signature(
+							this.signing_hmac,
+							*this.datetime,
+							this.scope,
+							*this.previous_signature,
+							Hash::from(data_sha256sum),
+						)
+						.map_err(|e| {
+							SignedPayloadStreamError::Message(format!(
+								"Could not build signature: {}",
+								e
+							))
+						})?;
+
+						if *signature != expected_signature {
+							return Poll::Ready(Some(Err(
+								SignedPayloadStreamError::InvalidSignature,
+							)));
+						}
 
-			// 0-sized chunk is the last
-			if payload.data.is_empty() {
-				return Poll::Ready(None);
+						*this.previous_signature = *signature;
+						*this.state = PayloadState::Header;
+						continue;
+					}
+				}
+				PayloadState::Done => return Poll::Ready(None),
 			}
 
-			let data_sha256sum = sha256sum(&payload.data);
-
-			let expected_signature = compute_streaming_payload_signature(
-				this.signing_hmac,
-				*this.datetime,
-				this.scope,
-				*this.previous_signature,
-				data_sha256sum,
-			)
-			.map_err(|e| {
-				SignedPayloadStreamError::Message(format!("Could not build signature: {}", e))
-			})?;
-
-			if payload.header.signature != expected_signature {
-				return Poll::Ready(Some(Err(SignedPayloadStreamError::InvalidSignature)));
+			// More data is needed to go further
+			match futures::ready!(this.stream.as_mut().poll_next(cx)) {
+				Some(Ok(bytes)) => this.buf.extend_from_slice(&bytes),
+				Some(Err(e)) => return Poll::Ready(Some(Err(SignedPayloadStreamError::Stream(e)))),
+				None => {
+					return Poll::Ready(Some(Err(SignedPayloadStreamError::message(
+						"Unexpected EOF",
+					))));
+				}
 			}
-
-			*this.buf = input.into();
-			*this.previous_signature = payload.header.signature;
-
-			return Poll::Ready(Some(Ok(payload.data)));
 		}
 	}
 
@@ -370,4 +408,136 @@ mod tests {
 			),
 		}
 	}
+
+	// Size of the frames in which the body of the following tests is received
+	const FRAME_SIZE: usize = 64 * 1024;
+
+	fn frame(i: usize) -> hyper::body::Bytes {
+		(0..FRAME_SIZE)
+			.map(|j| ((i * FRAME_SIZE + j) % 251) as u8)
+			.collect::<Vec<_>>()
+			.into()
+	}
+
+	fn frames_sha256(n_frames: usize) -> garage_util::data::Hash {
+		use sha2::{Digest, Sha256};
+
+		let mut hasher = Sha256::new();
+		for i in 0..n_frames {
+			hasher.update(frame(i));
+		}
+		let mut hash = [0u8; 32];
+		hash.copy_from_slice(&hasher.finalize());
+		hash.into()
+	}
+
+	/// Stream of the frames of a body made of a single chunk of `n_frames`
+	/// frames, which are generated only as the body is read. If `corrupt`
+	/// is set, the data of the first frame does not match its signature.
+	fn single_chunk_body(
+		n_frames: usize,
+		corrupt: bool,
+	) -> impl Stream<Item = Result<hyper::body::Bytes, super::Error>> + Unpin {
+		use garage_util::data::{sha256sum, Hash};
+
+		let (signing_hmac, datetime, scope) = signing_params();
+		let signature = super::compute_streaming_payload_signature(
+			&signing_hmac,
+			datetime,
+			&scope,
+			Hash::default(),
+			frames_sha256(n_frames),
+		)
+		.unwrap();
+		let final_signature = super::compute_streaming_payload_signature(
+			&signing_hmac,
+			datetime,
+			&scope,
+			signature,
+			sha256sum(&[]),
+		)
+		.unwrap();
+
+		let header = format!(
+			"{:x};chunk-signature={}\r\n",
+			n_frames * FRAME_SIZE,
+			hex::encode(signature)
+		);
+		let trailer = format!(
+			"\r\n0;chunk-signature={}\r\n\r\n",
+			hex::encode(final_signature)
+		);
+		let frames = (0..n_frames).map(move |i| match i {
+			0 if corrupt => frame(1),
+			i => frame(i),
+		});
+		let body = std::iter::once(header.into())
+			.chain(frames)
+			.chain(std::iter::once(trailer.into()));
+		futures::stream::iter(body.map(Ok))
+	}
+
+	fn signing_params() -> (super::HmacSha256, chrono::DateTime<chrono::Utc>, String) {
+		let datetime = chrono::DateTime::parse_from_rfc3339("2021-12-13T13:12:42+01:00")
+			.unwrap()
+			.with_timezone(&chrono::Utc);
+		let scope = crate::signature::compute_scope(&datetime, "test", "s3");
+		let signing_hmac = crate::signature::signing_hmac(&datetime, "test", "test", "s3").unwrap();
+		(signing_hmac, datetime, scope)
+	}
+
+	#[tokio::test]
+	async fn test_signed_payload_stream_large_chunk() {
+		use sha2::{Digest, Sha256};
+
+		// A single chunk of 16 MiB, received in frames of 64 KiB
+		let n_frames = 256;
+		let (signing_hmac, datetime, scope) = signing_params();
+		let mut stream = SignedPayloadStream::new(
+			single_chunk_body(n_frames, false),
+			signing_hmac,
+			datetime,
+			&scope,
+			Default::default(),
+		);
+
+		let mut hasher = Sha256::new();
+		let mut len = 0;
+		while let Some(data) = stream.try_next().await.unwrap() {
+			// The data is passed on as it is received,
+			// the chunk is never buffered as a whole
+			assert!(data.len() <= FRAME_SIZE);
+			assert!(stream.buf.len() <= FRAME_SIZE + super::MAX_CHUNK_HEADER_LEN);
+			hasher.update(&data);
+			len += data.len();
+		}
+		assert_eq!(len, n_frames * FRAME_SIZE);
+		assert_eq!(&hasher.finalize()[..], frames_sha256(n_frames).as_ref());
+	}
+
+	#[tokio::test]
+	async fn test_signed_payload_stream_invalid_large_chunk() {
+		let n_frames = 16;
+		let (signing_hmac, datetime, scope) = signing_params();
+		let mut stream = SignedPayloadStream::new(
+			single_chunk_body(n_frames, true),
+			signing_hmac,
+			datetime,
+			&scope,
+			Default::default(),
+		);
+
+		// The data is passed on before the signature of the chunk is checked,
+		// but the stream fails instead of ending normally
+		let mut len = 0;
+		let err = loop {
+			match stream.try_next().await {
+				Ok(Some(data)) => len += data.len(),
+				Ok(None) => panic!("the invalid chunk should be rejected"),
+				Err(e) => break e,
+			}
+		};
+		assert_eq!(len, n_frames * FRAME_SIZE);
+		assert!(matches!(err, SignedPayloadStreamError::InvalidSignature));
+	}
 }
@@ -0,0 +1,548 @@
+//! Policy enforcing that API requests are made over TLS.
+//!
+//! Garage does not terminate TLS itself: when `require_tls` is enabled,
+//! a request is considered secure only if it was received from one of the
+//! configured trusted reverse proxies and that proxy indicated, through the
+//! `X-Forwarded-Proto` header, that the client connection used HTTPS.
+//! Connections made directly to Garage are always plaintext.
+//!
+//! When `unsigned_payload_requires_tls` is enabled, the same check is
+//! applied only to requests whose body is not covered by the signature
+//! (`x-amz-content-sha256: UNSIGNED-PAYLOAD`), so that the integrity
+//! of their body is at least protected by the transport.
+//!
+//! When `public_endpoints` are configured, signed requests are only accepted
+//! if their (signed) Host header and their scheme match one of them, so that
+//! requests signed for an HTTPS endpoint cannot be replayed over plain HTTP
+//! to another listener.
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use hyper::header::{HeaderMap, HeaderValue, AUTHORIZATION, HOST};
+use hyper::http::uri::Authority;
+
+use garage_util::config::S3ApiConfig;
+use garage_util::error::Error as GarageError;
+
+pub const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+
+const X_AMZ_CONTENT_SHA256: &str = "x-amz-content-sha256";
+
+pub(crate) struct TlsPolicy {
+	require_tls: bool,
+	unsigned_payload_requires_tls: bool,
+	trusted_proxies: Vec<IpAddr>,
+	hsts: Option<HeaderValue>,
+	public_endpoints: Vec<PublicEndpoint>,
+}
+
+/// A public endpoint of the API, for which signed requests are accepted
+#[derive(Debug, PartialEq, Eq)]
+struct PublicEndpoint {
+	https: bool,
+	host: String,
+	port: u16,
+}
+
+impl PublicEndpoint {
+	fn parse(endpoint: &str) -> Result<Self, GarageError> {
+		let invalid = |reason: &str| {
+			GarageError::Message(format!(
+				"Invalid endpoint in s3_api.public_endpoints: {} ({})",
+				endpoint, reason
+			))
+		};
+		let (https, authority) = match endpoint.split_once("://") {
+			Some(("https", authority)) => (true, authority),
+			Some(("http", authority)) => (false, authority),
+			_ => {
+				return Err(invalid(
+					"expected https://host[:port] or http://host[:port]",
+				))
+			}
+		};
+		let authority = Authority::from_str(authority.trim_end_matches('/'))
+			.map_err(|e| invalid(&e.to_string()))?;
+		let port = match authority.port_u16() {
+			Some(port) => port,
+			None if authority.as_str().ends_with(authority.host()) => default_port(https),
+			None => return Err(invalid("invalid port")),
+		};
+		Ok(Self {
+			https,
+			host: normalize_host(authority.host()),
+			port,
+		})
+	}
+
+	/// Check if a request made to the given host and port, over HTTPS or not,
+	/// is for this endpoint, including for vhost-style requests
+	fn matches(&self, https: bool, host: &str, port: u16) -> bool {
+		self.https == https
+			&& self.port == port
+			&& (host == self.host
+				|| host
+					.strip_suffix(self.host.as_str())
+					.map(|bucket| bucket.ends_with('.'))
+					.unwrap_or(false))
+	}
+}
+
+impl TlsPolicy {
+	/// Build the TLS policy from the S3 API configuration. Returns `None`
+	/// if no TLS-related option is set, in which case no check is done.
+	pub fn from_s3_config(config: &S3ApiConfig) -> Result<Option<Self>, GarageError> {
+		if !config.require_tls
+			&& !config.unsigned_payload_requires_tls
+			&& config.hsts_max_age_secs.is_none()
+			&& config.public_endpoints.is_empty()
+		{
+			return Ok(None);
+		}
+
+		let trusted_proxies = config
+			.trusted_proxies
+			.iter()
+			.map(|p| {
+				IpAddr::from_str(p).map_err(|e| {
+					GarageError::Message(format!(
+						"Invalid IP address in s3_api.trusted_proxies: {} ({})",
+						p, e
+					))
+				})
+			})
+			.collect::<Result<Vec<_>, _>>()?;
+
+		let hsts = config.hsts_max_age_secs.map(|max_age| {
+			HeaderValue::from_str(&format!("max-age={}", max_age))
+				.expect("valid Strict-Transport-Security header value")
+		});
+
+		let public_endpoints = config
+			.public_endpoints
+			.iter()
+			.map(|e| PublicEndpoint::parse(e))
+			.collect::<Result<Vec<_>, _>>()?;
+
+		Ok(Some(Self {
+			require_tls: config.require_tls,
+			unsigned_payload_requires_tls: config.unsigned_payload_requires_tls,
+			trusted_proxies,
+			hsts,
+			public_endpoints,
+		}))
+	}
+
+	/// Returns true if the client connection was made over HTTPS, as
+	/// reported by a trusted reverse proxy. `peer_addr` is the address
+	/// of the remote end of the connection, as returned by the listener.
+	pub fn is_secure(&self, peer_addr: &str, headers: &HeaderMap<HeaderValue>) -> bool {
+		// Peers connected through a Unix socket have no IP address and
+		// can therefore never be trusted proxies
+		let peer_ip = match SocketAddr::from_str(peer_addr) {
+			Ok(addr) => addr.ip(),
+			Err(_) => return false,
+		};
+		if !self.trusted_proxies.contains(&peer_ip) {
+			return false;
+		}
+
+		headers
+			.get(X_FORWARDED_PROTO)
+			.and_then(|v| v.to_str().ok())
+			.map(|proto| proto.trim().eq_ignore_ascii_case("https"))
+			.unwrap_or(false)
+	}
+
+	/// Returns true if the request must be rejected because it was
+	/// not made over HTTPS
+	pub fn must_reject(&self, peer_addr: &str, headers: &HeaderMap<HeaderValue>) -> bool {
+		self.require_tls && !self.is_secure(peer_addr, headers)
+	}
+
+	/// Returns true if the request must be rejected because its body
+	/// is not signed and it was not made over HTTPS
+	pub fn must_reject_unsigned_payload(
+		&self,
+		peer_addr: &str,
+		headers: &HeaderMap<HeaderValue>,
+	) -> bool {
+		self.unsigned_payload_requires_tls
+			&& has_unsigned_payload(headers)
+			&& !self.is_secure(peer_addr, headers)
+	}
+
+	/// Returns the reason for which a signed request must be rejected, if
+	/// public endpoints are configured and the scheme and Host header of the
+	/// request do not match any of them. Unsigned requests are not checked.
+	pub fn reject_signed_host(
+		&self,
+		peer_addr: &str,
+		headers: &HeaderMap<HeaderValue>,
+		query: Option<&str>,
+	) -> Option<String> {
+		if self.public_endpoints.is_empty() || !is_signed(headers, query) {
+			return None;
+		}
+
+		let https = self.is_secure(peer_addr, headers);
+		let scheme = if https { "https" } else { "http" };
+		let authority = match headers
+			.get(HOST)
+			.and_then(|h| h.to_str().ok())
+			.and_then(|h| Authority::from_str(h).ok())
+		{
+			Some(authority) => authority,
+			None => {
+				return Some(format!(
+					"The Host header of this {} request is missing or invalid",
+					scheme
+				))
+			}
+		};
+		let host = normalize_host(authority.host());
+		let port = authority.port_u16().unwrap_or(default_port(https));
+
+		if self
+			.public_endpoints
+			.iter()
+			.any(|e| e.matches(https, &host, port))
+		{
+			None
+		} else {
+			Some(format!(
+				"The signed host {} does not match any public endpoint of this server \
+				for {} requests: the request may have been signed for another endpoint",
+				authority, scheme
+			))
+		}
+	}
+
+	/// Value of the `Strict-Transport-Security` header to add to responses
+	/// to a request, if any. The header is only sent on secure requests,
+	/// as browsers ignore it when it is received over plain HTTP.
+	pub fn hsts_header(
+		&self,
+		peer_addr: &str,
+		headers: &HeaderMap<HeaderValue>,
+	) -> Option<HeaderValue> {
+		match &self.hsts {
+			Some(v) if self.is_secure(peer_addr, headers) => Some(v.clone()),
+			_ => None,
+		}
+	}
+}
+
+fn is_signed(headers: &HeaderMap<HeaderValue>, query: Option<&str>) -> bool {
+	headers.contains_key(AUTHORIZATION)
+		|| query
+			.unwrap_or_default()
+			.split('&')
+			.any(|param| param.starts_with("X-Amz-Signature="))
+}
+
+fn normalize_host(host: &str) -> String {
+	host.trim_end_matches('.').to_lowercase()
+}
+
+fn default_port(https: bool) -> u16 {
+	if https {
+		443
+	} else {
+		80
+	}
+}
+
+fn has_unsigned_payload(headers: &HeaderMap<HeaderValue>) -> bool {
+	headers
+		.get(X_AMZ_CONTENT_SHA256)
+		.and_then(|v| v.to_str().ok())
+		.map(|v| v == "UNSIGNED-PAYLOAD" || v.starts_with("STREAMING-UNSIGNED-PAYLOAD"))
+		.unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// S3 API configuration in which all the optional settings are unset
+	fn s3_config() -> S3ApiConfig {
+		S3ApiConfig {
+			api_bind_addr: None,
+			s3_region: "garage".into(),
+			root_domain: None,
+			prefer_chunked_get: false,
+			require_tls: false,
+			unsigned_payload_requires_tls: false,
+			trusted_proxies: vec![],
+			hsts_max_age_secs: None,
+			public_endpoints: vec![],
+			recent_errors_buffer_size: None,
+			recent_errors_plaintext_keys: false,
+			copy_keepalive_threshold: None,
+			bandwidth_limit: None,
+			connection_bandwidth_limit: None,
+			max_object_size: None,
+			max_part_size: None,
+			addressing_style: Default::default(),
+			directory_markers: Default::default(),
+			max_list_response_size: None,
+			max_list_parts: None,
+			access_log_flush_interval_secs: None,
+			access_log_flush_size: None,
+			max_cors_rules: None,
+			max_lifecycle_rules: None,
+			always_compute_sha256: false,
+			store_block_checksums: false,
+			max_header_size: None,
+			max_header_count: None,
+			max_requests_per_connection: None,
+			read_only: false,
+			reject_duplicate_query_params: false,
+			abort_stalled_uploads_after_days: None,
+			mpu_completion_concurrency: None,
+			key_cache_ttl_msec: None,
+			shadow: None,
+			import_credentials: Default::default(),
+			access_log_buffer_size: None,
+		}
+	}
+
+	fn policy(require_tls: bool, trusted_proxies: &[&str], hsts: Option<u64>) -> TlsPolicy {
+		let config = S3ApiConfig {
+			require_tls,
+			trusted_proxies: trusted_proxies.iter().map(|p| p.to_string()).collect(),
+			hsts_max_age_secs: hsts,
+			..s3_config()
+		};
+		TlsPolicy::from_s3_config(&config).unwrap().unwrap()
+	}
+
+	fn headers(proto: Option<&str>) -> HeaderMap<HeaderValue> {
+		let mut headers = HeaderMap::new();
+		if let Some(p) = proto {
+			headers.insert(X_FORWARDED_PROTO, p.parse().unwrap());
+		}
+		headers
+	}
+
+	#[test]
+	fn test_reject_plaintext() {
+		let p = policy(true, &[], None);
+		assert!(p.must_reject("192.0.2.10:4242", &headers(None)));
+		// Header is not trusted when there is no trusted proxy
+		assert!(p.must_reject("192.0.2.10:4242", &headers(Some("https"))));
+		// Unix socket peers are never trusted
+		assert!(p.must_reject("/run/garage/s3.sock", &headers(Some("https"))));
+	}
+
+	#[test]
+	fn test_forwarded_proto() {
+		let p = policy(true, &["192.0.2.1", "2001:db8::1"], Some(3600));
+
+		assert!(!p.must_reject("192.0.2.1:4242", &headers(Some("https"))));
+		assert!(!p.must_reject("[2001:db8::1]:4242", &headers(Some("HTTPS"))));
+		assert_eq!(
+			p.hsts_header("192.0.2.1:4242", &headers(Some("https"))),
+			Some(HeaderValue::from_static("max-age=3600"))
+		);
+
+		// Trusted proxy, but client connection was plaintext
+		assert!(p.must_reject("192.0.2.1:4242", &headers(Some("http"))));
+		assert!(p.must_reject("192.0.2.1:4242", &headers(None)));
+		assert_eq!(
+			p.hsts_header("192.0.2.1:4242", &headers(Some("http"))),
+			None
+		);
+
+		// Header sent by an untrusted peer is ignored
+		assert!(p.must_reject("192.0.2.2:4242", &headers(Some("https"))));
+		assert_eq!(
+			p.hsts_header("192.0.2.2:4242", &headers(Some("https"))),
+			None
+		);
+	}
+
+	#[test]
+	fn test_tls_not_required() {
+		let p = policy(false, &[], Some(3600));
+		assert!(!p.must_reject("192.0.2.10:4242", &headers(None)));
+
+		let config = s3_config();
+		assert!(TlsPolicy::from_s3_config(&config).unwrap().is_none());
+	}
+
+	#[test]
+	fn test_invalid_trusted_proxy() {
+		let config = S3ApiConfig {
+			require_tls: true,
+			trusted_proxies: vec!["proxy.example.com".into()],
+			..s3_config()
+		};
+		assert!(TlsPolicy::from_s3_config(&config).is_err());
+	}
+
+	#[test]
+	fn test_unsigned_payload_requires_tls() {
+		let mut config = S3ApiConfig {
+			unsigned_payload_requires_tls: true,
+			trusted_proxies: vec!["192.0.2.1".into()],
+			..s3_config()
+		};
+		let p = TlsPolicy::from_s3_config(&config).unwrap().unwrap();
+
+		let unsigned = |proto: Option<&str>, sha256: &str| {
+			let mut h = headers(proto);
+			h.insert(X_AMZ_CONTENT_SHA256, sha256.parse().unwrap());
+			h
+		};
+		let signed_sha256 = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+		// Unsigned payload over plaintext is rejected
+		assert!(
+			p.must_reject_unsigned_payload("192.0.2.10:4242", &unsigned(None, "UNSIGNED-PAYLOAD"))
+		);
+		assert!(p.must_reject_unsigned_payload(
+			"192.0.2.1:4242",
+			&unsigned(Some("http"), "UNSIGNED-PAYLOAD")
+		));
+		assert!(p.must_reject_unsigned_payload(
+			"192.0.2.10:4242",
+			&unsigned(None, "STREAMING-UNSIGNED-PAYLOAD-TRAILER")
+		));
+		// Header sent by an untrusted peer is ignored
+		assert!(p.must_reject_unsigned_payload(
+			"192.0.2.10:4242",
+			&unsigned(Some("https"), "UNSIGNED-PAYLOAD")
+		));
+
+		// Unsigned payload over TLS is allowed
+		assert!(!p.must_reject_unsigned_payload(
+			"192.0.2.1:4242",
+			&unsigned(Some("https"), "UNSIGNED-PAYLOAD")
+		));
+
+		// Signed payloads and requests without a body hash are not affected
+		assert!(!p.must_reject_unsigned_payload("192.0.2.10:4242", &unsigned(None, signed_sha256)));
+		assert!(!p.must_reject_unsigned_payload("192.0.2.10:4242", &headers(None)));
+		assert!(!p.must_reject("192.0.2.10:4242", &unsigned(None, "UNSIGNED-PAYLOAD")));
+
+		// Without the option, unsigned payloads are allowed over plaintext
+		config.unsigned_payload_requires_tls = false;
+		config.require_tls = true;
+		let p = TlsPolicy::from_s3_config(&config).unwrap().unwrap();
+		assert!(
+			!p.must_reject_unsigned_payload("192.0.2.10:4242", &unsigned(None, "UNSIGNED-PAYLOAD"))
+		);
+	}
+
+	fn config_with_endpoints(endpoints: &[&str]) -> S3ApiConfig {
+		S3ApiConfig {
+			trusted_proxies: vec!["192.0.2.1".into()],
+			public_endpoints: endpoints.iter().map(|e| e.to_string()).collect(),
+			..s3_config()
+		}
+	}
+
+	#[test]
+	fn test_public_endpoints() {
+		let config = config_with_endpoints(&["https://s3.example.com", "http://10.0.0.5:3900"]);
+		let p = TlsPolicy::from_s3_config(&config).unwrap().unwrap();
+
+		let signed = |proto: Option<&str>, host: &str| {
+			let mut h = headers(proto);
+			h.insert(HOST, host.parse().unwrap());
+			h.insert(
+				AUTHORIZATION,
+				"AWS4-HMAC-SHA256 Credential=...".parse().unwrap(),
+			);
+			h
+		};
+		let https = Some("https");
+		let proxy = "192.0.2.1:4242";
+		let direct = "192.0.2.10:4242";
+
+		// Requests matching a public endpoint are accepted
+		assert_eq!(
+			p.reject_signed_host(proxy, &signed(https, "s3.example.com"), None),
+			None
+		);
+		assert_eq!(
+			p.reject_signed_host(proxy, &signed(https, "S3.Example.COM.:443"), None),
+			None
+		);
+		assert_eq!(
+			p.reject_signed_host(proxy, &signed(https, "bucket.s3.example.com"), None),
+			None
+		);
+		assert_eq!(
+			p.reject_signed_host(direct, &signed(None, "10.0.0.5:3900"), None),
+			None
+		);
+
+		// A request signed for the HTTPS endpoint, replayed over plain HTTP
+		assert!(p
+			.reject_signed_host(direct, &signed(None, "s3.example.com"), None)
+			.is_some());
+		assert!(p
+			.reject_signed_host(proxy, &signed(Some("http"), "s3.example.com"), None)
+			.is_some());
+		// X-Forwarded-Proto from an untrusted peer is ignored
+		assert!(p
+			.reject_signed_host(direct, &signed(https, "s3.example.com"), None)
+			.is_some());
+
+		// Mismatched hosts and ports
+		for host in [
+			"s3.example.org",
+			"nots3.example.com",
+			"s3.example.com:8443",
+			"10.0.0.5",
+			"10.0.0.6:3900",
+			"[::1]:3900",
+		] {
+			let reason = p.reject_signed_host(proxy, &signed(https, host), None);
+			assert!(reason.is_some(), "{} should be rejected", host);
+			let reason = p.reject_signed_host(direct, &signed(None, host), None);
+			assert!(reason
+				.unwrap()
+				.contains("does not match any public endpoint"));
+		}
+
+		// Presigned URLs are checked as well
+		let presigned = Some("X-Amz-Algorithm=AWS4-HMAC-SHA256&X-Amz-Signature=abcd");
+		let mut h = headers(None);
+		h.insert(HOST, "s3.example.com".parse().unwrap());
+		assert!(p.reject_signed_host(direct, &h, presigned).is_some());
+		assert_eq!(p.reject_signed_host(direct, &h, Some("uploads")), None);
+		let mut h = headers(https);
+		h.insert(HOST, "s3.example.com".parse().unwrap());
+		assert_eq!(p.reject_signed_host(proxy, &h, presigned), None);
+
+		// Signed requests without a Host header are rejected
+		let mut h = headers(None);
+		h.insert(AUTHORIZATION, "AWS4-HMAC-SHA256".parse().unwrap());
+		assert!(p.reject_signed_host(direct, &h, None).is_some());
+
+		// Nothing is checked when no public endpoint is configured
+		let p = policy(false, &[], Some(3600));
+		assert_eq!(
+			p.reject_signed_host(direct, &signed(None, "s3.example.org"), None),
+			None
+		);
+	}
+
+	#[test]
+	fn test_invalid_public_endpoint() {
+		for endpoint in [
+			"s3.example.com",
+			"ftp://s3.example.com",
+			"https://s3.example.com:xx",
+		] {
+			assert!(TlsPolicy::from_s3_config(&config_with_endpoints(&[endpoint])).is_err());
+		}
+		assert!(
+			TlsPolicy::from_s3_config(&config_with_endpoints(&["https://s3.example.com/"])).is_ok()
+		);
+	}
+}
@@ -0,0 +1,213 @@
+//! Tracking of I/O errors on the data directories of the node.
+//!
+//! When the number of I/O errors on data blocks without any successful
+//! read or write in between reaches `data_io_error_threshold`, the node
+//! applies its `data_io_error_policy`: it either keeps going as before,
+//! marks its data storage as degraded and stops accepting block writes,
+//! or stops. The degraded state is not persisted: it is cleared when the
+//! node restarts, e.g. after the failing drive has been replaced.
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use garage_util::config::DataIoErrorPolicy;
+use garage_util::time::now_msec;
+
+// errno values for "too many open files" (same on Linux and BSDs)
+const ENFILE: i32 = 23;
+const EMFILE: i32 = 24;
+
+/// What must be done after an I/O error was recorded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DiskHealthAction {
+	/// Nothing more than returning the error
+	None,
+	/// The data storage just became degraded
+	Quarantine,
+	/// The node must stop
+	Crash,
+}
+
+pub struct DiskHealth {
+	policy: DataIoErrorPolicy,
+	threshold: u64,
+
+	consecutive_errors: AtomicU64,
+	total_errors: AtomicU64,
+	degraded: AtomicBool,
+	/// Time at which the storage became degraded, in msec since the epoch
+	degraded_since: AtomicU64,
+	last_error: Mutex<Option<String>>,
+}
+
+/// State of the data storage of the node, for reporting purposes
+#[derive(Debug, Clone)]
+pub struct DiskHealthInfo {
+	pub policy: DataIoErrorPolicy,
+	pub degraded: bool,
+	pub degraded_since: Option<u64>,
+	pub consecutive_errors: u64,
+	pub total_errors: u64,
+	pub last_error: Option<String>,
+}
+
+impl DiskHealth {
+	pub(crate) fn new(policy: DataIoErrorPolicy, threshold: u64) -> Self {
+		Self {
+			policy,
+			threshold: std::cmp::max(threshold, 1),
+			consecutive_errors: AtomicU64::new(0),
+			total_errors: AtomicU64::new(0),
+			degraded: AtomicBool::new(false),
+			degraded_since: AtomicU64::new(0),
+			last_error: Mutex::new(None),
+		}
+	}
+
+	/// Whether an error returned by a filesystem operation on a data block
+	/// is an I/O error, as opposed to the block simply not being there or
+	/// the process running out of file descriptors
+	pub(crate) fn is_io_error(e: &io::Error) -> bool {
+		e.kind() != io::ErrorKind::NotFound
+			&& !matches!(e.raw_os_error(), Some(ENFILE) | Some(EMFILE))
+	}
+
+	/// Record a successful read or write of a data block
+	pub(crate) fn record_success(&self) {
+		self.consecutive_errors.store(0, Ordering::Relaxed);
+	}
+
+	/// Record an I/O error during a read or write of a data block
+	pub(crate) fn record_error(&self, what: &str, e: &io::Error) -> DiskHealthAction {
+		if !Self::is_io_error(e) {
+			return DiskHealthAction::None;
+		}
+
+		self.total_errors.fetch_add(1, Ordering::Relaxed);
+		*self.last_error.lock().unwrap() = Some(format!("{}: {}", what, e));
+		let errors = self.consecutive_errors.fetch_add(1, Ordering::Relaxed) + 1;
+		if errors < self.threshold {
+			return DiskHealthAction::None;
+		}
+
+		match self.policy {
+			DataIoErrorPolicy::None => DiskHealthAction::None,
+			DataIoErrorPolicy::Quarantine => {
+				if self.degraded.swap(true, Ordering::Relaxed) {
+					DiskHealthAction::None
+				} else {
+					self.degraded_since.store(now_msec(), Ordering::Relaxed);
+					DiskHealthAction::Quarantine
+				}
+			}
+			DataIoErrorPolicy::Crash => DiskHealthAction::Crash,
+		}
+	}
+
+	/// Whether the data storage is degraded, in which case
+	/// the node does not accept block writes
+	pub fn is_degraded(&self) -> bool {
+		self.degraded.load(Ordering::Relaxed)
+	}
+
+	pub fn info(&self) -> DiskHealthInfo {
+		DiskHealthInfo {
+			policy: self.policy,
+			degraded: self.is_degraded(),
+			degraded_since: Some(self.degraded_since.load(Ordering::Relaxed)).filter(|t| *t > 0),
+			consecutive_errors: self.consecutive_errors.load(Ordering::Relaxed),
+			total_errors: self.total_errors.load(Ordering::Relaxed),
+			last_error: self.last_error.lock().unwrap().clone(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// A real I/O error, as returned when trying to read a block
+	// whose path is a directory
+	fn io_error() -> io::Error {
+		std::fs::read(std::env::temp_dir()).unwrap_err()
+	}
+
+	#[test]
+	fn test_quarantine_after_repeated_errors() {
+		let health = DiskHealth::new(DataIoErrorPolicy::Quarantine, 3);
+
+		assert_eq!(
+			health.record_error("read", &io_error()),
+			DiskHealthAction::None
+		);
+		assert_eq!(
+			health.record_error("read", &io_error()),
+			DiskHealthAction::None
+		);
+		assert!(!health.is_degraded());
+
+		// A success in between resets the count
+		health.record_success();
+		assert_eq!(
+			health.record_error("write", &io_error()),
+			DiskHealthAction::None
+		);
+		assert_eq!(
+			health.record_error("write", &io_error()),
+			DiskHealthAction::None
+		);
+		assert_eq!(
+			health.record_error("write", &io_error()),
+			DiskHealthAction::Quarantine
+		);
+		assert!(health.is_degraded());
+
+		// The transition is reported once, and the storage stays degraded
+		assert_eq!(
+			health.record_error("write", &io_error()),
+			DiskHealthAction::None
+		);
+		health.record_success();
+		assert!(health.is_degraded());
+
+		let info = health.info();
+		assert_eq!(info.total_errors, 6);
+		assert!(info.degraded_since.is_some());
+		assert!(info.last_error.unwrap().starts_with("write: "));
+	}
+
+	#[test]
+	fn test_missing_blocks_are_not_io_errors() {
+		let health = DiskHealth::new(DataIoErrorPolicy::Quarantine, 1);
+		let not_found = std::fs::read(std::env::temp_dir().join("no-such-garage-block"));
+		assert_eq!(
+			health.record_error("read", &not_found.unwrap_err()),
+			DiskHealthAction::None
+		);
+		assert!(!health.is_degraded());
+		assert_eq!(health.info().total_errors, 0);
+	}
+
+	#[test]
+	fn test_other_policies() {
+		let health = DiskHealth::new(DataIoErrorPolicy::Crash, 2);
+		assert_eq!(
+			health.record_error("read", &io_error()),
+			DiskHealthAction::None
+		);
+		assert_eq!(
+			health.record_error("read", &io_error()),
+			DiskHealthAction::Crash
+		);
+
+		let health = DiskHealth::new(DataIoErrorPolicy::None, 1);
+		for _ in 0..10 {
+			assert_eq!(
+				health.record_error("read", &io_error()),
+				DiskHealthAction::None
+			);
+		}
+		assert!(!health.is_degraded());
+		assert_eq!(health.info().total_errors, 10);
+	}
+}
@@ -0,0 +1,356 @@
+//! Hedged requests: send a request to a first node, and if it doesn't answer
+//! fast enough, send the same request to other nodes as well, taking the first
+//! response that comes back.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use futures::future::FutureExt;
+use futures::stream::{FuturesUnordered, StreamExt};
+
+/// Successful outcome of a hedged request
+pub(crate) struct Hedged<T> {
+	pub(crate) value: T,
+	/// Index, in the list of nodes, of the node whose response was used
+	pub(crate) winner: usize,
+	/// Number of requests that were sent because previous ones
+	/// did not answer within the hedge delay
+	pub(crate) hedges: usize,
+}
+
+/// Send a request to the nodes in `nodes`, in order. A request is sent to the
+/// next node when all requests in flight have failed, or when none of them
+/// returned within `hedge_delay` and less than `max_in_flight` requests are in
+/// flight. The first successful response is returned, and the requests that
+/// are still in flight are dropped. `f` returns `None` if the request to a node
+/// failed. With `max_in_flight` set to 1, nodes are simply tried one after the
+/// other. `allow_hedge` is called before each hedge is sent: if it returns
+/// false, no more hedges are sent for this request.
+pub(crate) async fn hedged_request<N, F, Fut, T>(
+	nodes: &[N],
+	hedge_delay: Duration,
+	max_in_flight: usize,
+	mut allow_hedge: impl FnMut() -> bool,
+	f: F,
+) -> Option<Hedged<T>>
+where
+	N: Copy,
+	F: Fn(N) -> Fut,
+	Fut: Future<Output = Option<T>>,
+{
+	let mut next_nodes = nodes.iter().copied().enumerate();
+	let launch = |(i, node): (usize, N)| f(node).map(move |res| (i, res));
+
+	let mut in_flight = FuturesUnordered::new();
+	in_flight.push(launch(next_nodes.next()?));
+	let mut hedges = 0;
+	let mut hedging = true;
+
+	loop {
+		let can_hedge = hedging && in_flight.len() < max_in_flight && next_nodes.len() > 0;
+		let hedge_timer = async {
+			if can_hedge {
+				tokio::time::sleep(hedge_delay).await
+			} else {
+				futures::future::pending().await
+			}
+		};
+
+		tokio::select! {
+			res = in_flight.next() => match res {
+				Some((winner, Some(value))) => {
+					return Some(Hedged {
+						value,
+						winner,
+						hedges,
+					})
+				}
+				_ => {
+					// The request failed, replace it by a request to the next node
+					match next_nodes.next() {
+						Some(next) => in_flight.push(launch(next)),
+						None if in_flight.is_empty() => return None,
+						None => (),
+					}
+				}
+			},
+			_ = hedge_timer => {
+				if !allow_hedge() {
+					hedging = false;
+				} else if let Some(next) = next_nodes.next() {
+					hedges += 1;
+					in_flight.push(launch(next));
+				}
+			}
+		}
+	}
+}
+
+// Number of recent block fetches used to compute the adaptive hedge delay
+const LATENCY_WINDOW: usize = 1000;
+// The adaptive hedge delay is only used once there are enough samples,
+// and is recomputed every this many samples
+const LATENCY_MIN_SAMPLES: usize = 50;
+
+/// Keeps the latency of recent block fetches, to use a percentile
+/// of it as the hedge delay
+pub(crate) struct LatencyTracker {
+	percentile: f64,
+	state: Mutex<LatencyState>,
+}
+
+struct LatencyState {
+	samples: VecDeque<Duration>,
+	new_samples: usize,
+	value: Option<Duration>,
+}
+
+impl LatencyTracker {
+	pub(crate) fn new(percentile: f64) -> Self {
+		Self {
+			percentile: percentile.clamp(0.0, 100.0),
+			state: Mutex::new(LatencyState {
+				samples: VecDeque::with_capacity(LATENCY_WINDOW),
+				new_samples: 0,
+				value: None,
+			}),
+		}
+	}
+
+	pub(crate) fn record(&self, latency: Duration) {
+		let mut state = self.state.lock().unwrap();
+		if state.samples.len() == LATENCY_WINDOW {
+			state.samples.pop_front();
+		}
+		state.samples.push_back(latency);
+		state.new_samples += 1;
+
+		if state.new_samples >= LATENCY_MIN_SAMPLES && state.samples.len() >= LATENCY_MIN_SAMPLES {
+			let mut sorted = state.samples.iter().copied().collect::<Vec<_>>();
+			sorted.sort_unstable();
+			let index = (self.percentile / 100.0 * (sorted.len() - 1) as f64).round() as usize;
+			state.value = Some(sorted[index]);
+			state.new_samples = 0;
+		}
+	}
+
+	/// The configured percentile of the latency of recent block fetches,
+	/// or None if not enough fetches were recorded yet
+	pub(crate) fn get(&self) -> Option<Duration> {
+		self.state.lock().unwrap().value
+	}
+}
+
+// The budget is counted in thousandths of a hedge
+const HEDGE_COST: u64 = 1000;
+// Number of hedges that can be sent in a burst
+const HEDGE_BUDGET_BURST: u64 = 10 * HEDGE_COST;
+
+/// Limits the hedges that are sent to a fraction of the block reads: each
+/// read adds this fraction of a hedge to the budget, and each hedge that is
+/// sent takes a full hedge from it
+pub(crate) struct HedgeBudget {
+	credit_per_read: u64,
+	budget: AtomicU64,
+}
+
+impl HedgeBudget {
+	pub(crate) fn new(max_ratio: f64) -> Self {
+		Self {
+			credit_per_read: (max_ratio.clamp(0.0, 1.0) * HEDGE_COST as f64).round() as u64,
+			budget: AtomicU64::new(0),
+		}
+	}
+
+	pub(crate) fn on_read(&self) {
+		let _ = self
+			.budget
+			.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |b| {
+				Some(std::cmp::min(b + self.credit_per_read, HEDGE_BUDGET_BURST))
+			});
+	}
+
+	pub(crate) fn try_hedge(&self) -> bool {
+		self.budget
+			.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |b| {
+				b.checked_sub(HEDGE_COST)
+			})
+			.is_ok()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use std::time::Instant;
+
+	// Each node answers after the given delay, or fails if the delay is None
+	async fn node(delay: Option<u64>) -> Option<u64> {
+		let delay = delay?;
+		tokio::time::sleep(Duration::from_millis(delay)).await;
+		Some(delay)
+	}
+
+	#[tokio::test]
+	async fn test_hedge_slow_replica() {
+		let start = Instant::now();
+		let res = hedged_request(
+			&[Some(10_000), Some(10)],
+			Duration::from_millis(50),
+			2,
+			|| true,
+			node,
+		)
+		.await
+		.unwrap();
+		assert_eq!(res.value, 10);
+		assert_eq!(res.winner, 1);
+		assert_eq!(res.hedges, 1);
+		assert!(start.elapsed() < Duration::from_secs(5));
+	}
+
+	#[tokio::test]
+	async fn test_hedge_fast_replica() {
+		let res = hedged_request(
+			&[Some(10), Some(20)],
+			Duration::from_millis(1000),
+			2,
+			|| true,
+			node,
+		)
+		.await
+		.unwrap();
+		assert_eq!(res.winner, 0);
+		assert_eq!(res.hedges, 0);
+	}
+
+	#[tokio::test]
+	async fn test_hedge_failures() {
+		// Failed requests are replaced immediately, without hedging
+		let res = hedged_request(
+			&[None, None, Some(10)],
+			Duration::from_secs(1000),
+			1,
+			|| true,
+			node,
+		)
+		.await
+		.unwrap();
+		assert_eq!(res.winner, 2);
+		assert_eq!(res.hedges, 0);
+
+		assert!(
+			hedged_request(&[None, None], Duration::from_millis(10), 2, || true, node)
+				.await
+				.is_none()
+		);
+		assert!(
+			hedged_request::<Option<u64>, _, _, _>(&[], Duration::ZERO, 2, || true, node)
+				.await
+				.is_none()
+		);
+	}
+
+	#[tokio::test]
+	async fn test_hedge_max_in_flight() {
+		// With a single request in flight, the slow node is waited for
+		let res = hedged_request(
+			&[Some(200), Some(10)],
+			Duration::from_millis(10),
+			1,
+			|| true,
+			node,
+		)
+		.await
+		.unwrap();
+		assert_eq!(res.winner, 0);
+		assert_eq!(res.hedges, 0);
+	}
+
+	#[tokio::test]
+	async fn test_hedge_not_allowed() {
+		// The slow node is waited for when no hedge can be sent
+		let res = hedged_request(
+			&[Some(200), Some(10)],
+			Duration::from_millis(10),
+			2,
+			|| false,
+			node,
+		)
+		.await
+		.unwrap();
+		assert_eq!(res.winner, 0);
+		assert_eq!(res.hedges, 0);
+	}
+
+	#[test]
+	fn test_latency_percentile() {
+		let tracker = LatencyTracker::new(95.0);
+		for i in 1..LATENCY_MIN_SAMPLES as u64 {
+			tracker.record(Duration::from_millis(i));
+		}
+		assert_eq!(tracker.get(), None);
+
+		let tracker = LatencyTracker::new(95.0);
+		for i in 1..=100 {
+			tracker.record(Duration::from_millis(i));
+		}
+		assert_eq!(tracker.get(), Some(Duration::from_millis(95)));
+
+		// Old samples are forgotten
+		for _ in 0..LATENCY_WINDOW {
+			tracker.record(Duration::from_millis(3));
+		}
+		assert_eq!(tracker.get(), Some(Duration::from_millis(3)));
+	}
+
+	#[test]
+	fn test_hedge_budget() {
+		let budget = HedgeBudget::new(0.1);
+		assert!(!budget.try_hedge());
+		for _ in 0..10 {
+			budget.on_read();
+		}
+		assert!(budget.try_hedge());
+		assert!(!budget.try_hedge());
+
+		// The budget saved while no hedges are sent is bounded
+		for _ in 0..1000 {
+			budget.on_read();
+		}
+		for _ in 0..10 {
+			assert!(budget.try_hedge());
+		}
+		assert!(!budget.try_hedge());
+	}
+
+	#[tokio::test]
+	async fn test_adaptive_hedge_slow_replica() {
+		// Reads usually take 5ms, learn that
+		let tracker = LatencyTracker::new(95.0);
+		for _ in 0..LATENCY_MIN_SAMPLES {
+			tracker.record(Duration::from_millis(5));
+		}
+		let delay = tracker.get().unwrap();
+
+		// One in five reads has a first replica that takes 500ms to answer
+		let reads = (0..100).map(|i| async move {
+			let first = if i % 5 == 0 { 500 } else { 5 };
+			let start = Instant::now();
+			hedged_request(&[Some(first), Some(5)], delay, 2, || true, node)
+				.await
+				.unwrap();
+			start.elapsed()
+		});
+		let mut latencies = futures::future::join_all(reads).await;
+		latencies.sort();
+
+		// The p99 latency is close to the latency of the fast replica
+		let p99 = latencies[98];
+		assert!(p99 < Duration::from_millis(200), "p99 is {:?}", p99);
+	}
+}
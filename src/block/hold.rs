@@ -0,0 +1,165 @@
+//! In-memory registry of blocks that are currently being read by a client
+//! request, and that must therefore not be deleted locally even if their
+//! reference count has dropped to zero.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use garage_util::data::*;
+
+struct HoldEntry {
+	version: Uuid,
+	blocks: Vec<Hash>,
+	expires: Instant,
+}
+
+#[derive(Default)]
+struct HoldTableInner {
+	next_id: u64,
+	holds: HashMap<u64, HoldEntry>,
+	held_blocks: HashMap<Hash, usize>,
+}
+
+impl HoldTableInner {
+	fn remove(&mut self, id: u64) -> Option<HoldEntry> {
+		let entry = self.holds.remove(&id)?;
+		for hash in entry.blocks.iter() {
+			if let Some(cnt) = self.held_blocks.get_mut(hash) {
+				*cnt -= 1;
+				if *cnt == 0 {
+					self.held_blocks.remove(hash);
+				}
+			}
+		}
+		Some(entry)
+	}
+
+	fn remove_expired(&mut self, now: Instant) {
+		let expired = self
+			.holds
+			.iter()
+			.filter(|(_, ent)| ent.expires <= now)
+			.map(|(id, _)| *id)
+			.collect::<Vec<_>>();
+		for id in expired {
+			if let Some(ent) = self.remove(id) {
+				info!(
+					"Read hold on version {:?} ({} blocks) expired",
+					ent.version,
+					ent.blocks.len()
+				);
+			}
+		}
+	}
+}
+
+/// Table of read holds. A read hold is registered for the duration
+/// of a GET request, so that blocks of the version being read are
+/// not deleted underneath it by the local block GC. Holds expire after
+/// a configurable maximum duration so that abandoned connections
+/// cannot prevent block deletion forever.
+pub struct BlockHoldTable {
+	max_duration: Duration,
+	inner: Mutex<HoldTableInner>,
+}
+
+impl BlockHoldTable {
+	pub(crate) fn new(max_duration: Duration) -> Arc<Self> {
+		Arc::new(Self {
+			max_duration,
+			inner: Mutex::new(HoldTableInner::default()),
+		})
+	}
+
+	/// Register a read hold on blocks of a version. The hold is released
+	/// when the returned guard is dropped, or when it expires.
+	pub fn hold(self: &Arc<Self>, version: Uuid, blocks: Vec<Hash>) -> BlockHold {
+		let now = Instant::now();
+		let mut inner = self.inner.lock().unwrap();
+		inner.remove_expired(now);
+
+		let id = inner.next_id;
+		inner.next_id += 1;
+		for hash in blocks.iter() {
+			*inner.held_blocks.entry(*hash).or_insert(0) += 1;
+		}
+		inner.holds.insert(
+			id,
+			HoldEntry {
+				version,
+				blocks,
+				expires: now + self.max_duration,
+			},
+		);
+
+		BlockHold {
+			table: self.clone(),
+			id,
+		}
+	}
+
+	/// Returns true if a non-expired read hold exists on this block
+	pub fn is_held(&self, hash: &Hash) -> bool {
+		let mut inner = self.inner.lock().unwrap();
+		inner.remove_expired(Instant::now());
+		inner.held_blocks.contains_key(hash)
+	}
+
+	/// Number of read holds currently registered
+	pub fn count(&self) -> usize {
+		self.inner.lock().unwrap().holds.len()
+	}
+}
+
+/// Guard object for a read hold, which releases the hold when dropped
+pub struct BlockHold {
+	table: Arc<BlockHoldTable>,
+	id: u64,
+}
+
+impl Drop for BlockHold {
+	fn drop(&mut self) {
+		self.table.inner.lock().unwrap().remove(self.id);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_block_hold() {
+		let table = BlockHoldTable::new(Duration::from_secs(3600));
+		let h1 = gen_uuid();
+		let h2 = gen_uuid();
+		let h3 = gen_uuid();
+
+		let hold_a = table.hold(gen_uuid(), vec![h1, h2]);
+		let hold_b = table.hold(gen_uuid(), vec![h2]);
+		assert!(table.is_held(&h1));
+		assert!(table.is_held(&h2));
+		assert!(!table.is_held(&h3));
+
+		drop(hold_a);
+		assert!(!table.is_held(&h1));
+		assert!(table.is_held(&h2));
+
+		drop(hold_b);
+		assert!(!table.is_held(&h2));
+		assert_eq!(table.count(), 0);
+	}
+
+	#[test]
+	fn test_block_hold_expires() {
+		let table = BlockHoldTable::new(Duration::from_secs(0));
+		let h1 = gen_uuid();
+
+		let hold = table.hold(gen_uuid(), vec![h1]);
+		assert!(!table.is_held(&h1));
+		assert_eq!(table.count(), 0);
+
+		// Dropping an expired hold is a no-op
+		drop(hold);
+		assert_eq!(table.count(), 0);
+	}
+}
@@ -1,14 +1,18 @@
 #[macro_use]
 extern crate tracing;
 
+pub mod disk_health;
+pub mod hold;
 pub mod manager;
 pub mod repair;
 pub mod resync;
 
 mod block;
+mod hedge;
 mod layout;
 mod metrics;
+mod pack;
 mod rc;
 
-pub use block::zstd_encode;
+pub use block::{zstd_encode, DataBlock};
 pub use rc::CalculateRefcount;
@@ -1,7 +1,8 @@
 use std::convert::TryInto;
-use std::path::PathBuf;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use arc_swap::{ArcSwap, ArcSwapOption};
 use async_trait::async_trait;
@@ -15,7 +16,7 @@ use tokio::sync::{mpsc, Mutex, MutexGuard, Semaphore};
 
 use opentelemetry::{
 	trace::{FutureExt as OtelFutureExt, TraceContextExt, Tracer},
-	Context,
+	Context, KeyValue,
 };
 
 use garage_net::stream::{read_stream_to_end, stream_asyncread, ByteStream};
@@ -26,7 +27,7 @@ use garage_util::background::{vars, BackgroundRunner};
 use garage_util::config::Config;
 use garage_util::data::*;
 use garage_util::error::*;
-use garage_util::metrics::RecordDuration;
+use garage_util::metrics::{record_timing, timed_phase, RecordDuration};
 use garage_util::persister::{Persister, PersisterShared};
 use garage_util::time::msec_to_rfc3339;
 
@@ -37,8 +38,12 @@ use garage_rpc::*;
 use garage_table::replication::{TableReplication, TableShardedReplication};
 
 use crate::block::*;
+use crate::disk_health::*;
+use crate::hedge::*;
+use crate::hold::*;
 use crate::layout::*;
 use crate::metrics::*;
+use crate::pack::*;
 use crate::rc::*;
 use crate::repair::*;
 use crate::resync::*;
@@ -67,6 +72,16 @@ pub enum BlockRpc {
 	NeedBlockQuery(Hash),
 	/// Response : whether the node do require that block
 	NeedBlockReply(bool),
+	/// Ask other node if they currently store this block
+	HasBlockQuery(Hash),
+	/// Response : whether the node stores that block
+	HasBlockReply(bool),
+	/// Message to send a block of data, replacing any copy that the receiving
+	/// node already stores (used for manual repairs)
+	ForcePutBlock {
+		hash: Hash,
+		header: DataBlockHeader,
+	},
 }
 
 impl Rpc for BlockRpc {
@@ -86,11 +101,20 @@ pub struct BlockManager {
 	data_fsync: bool,
 	compression_level: Option<i32>,
 	disable_scrub: bool,
+	read_hedge_delay: Option<Duration>,
+	read_hedge_max_in_flight: usize,
+	read_hedge_latency: Option<LatencyTracker>,
+	read_hedge_budget: Option<HedgeBudget>,
 
 	mutation_lock: Vec<Mutex<BlockManagerLocked>>,
 
+	/// Small blocks stored packed in container files
+	pub(crate) pack: PackStore,
+
 	pub rc: BlockRc,
 	pub resync: BlockResyncManager,
+	/// Read holds on blocks being streamed to clients
+	pub holds: Arc<BlockHoldTable>,
 
 	pub(crate) system: Arc<System>,
 	pub(crate) endpoint: Arc<Endpoint<BlockRpc, Self>>,
@@ -98,7 +122,12 @@ pub struct BlockManager {
 
 	pub(crate) metrics: BlockManagerMetrics,
 
+	/// I/O errors on the data directories, and whether
+	/// the data storage of the node is degraded
+	pub disk_health: Arc<DiskHealth>,
+
 	pub scrub_persister: PersisterShared<ScrubWorkerPersisted>,
+	pub(crate) repair_checkpoint: Persister<RepairCheckpoint>,
 	tx_scrub_command: ArcSwapOption<mpsc::Sender<ScrubWorkerCommand>>,
 }
 
@@ -146,25 +175,46 @@ impl BlockManager {
 		let rc = db
 			.open_tree("block_local_rc")
 			.expect("Unable to open block_local_rc tree");
-		let rc = BlockRc::new(rc);
+		let rc_replication_factor = db
+			.open_tree("block_local_replication_factor")
+			.expect("Unable to open block_local_replication_factor tree");
+		let rc = BlockRc::new(rc, rc_replication_factor);
 
 		let resync = BlockResyncManager::new(db, &system);
 
+		let pack = PackStore::new(
+			db,
+			config.block_pack_threshold,
+			config.block_pack_max_dead_ratio,
+		);
+
 		let endpoint = system
 			.netapp
 			.endpoint("garage_block/manager.rs/Rpc".to_string());
 
 		let buffer_kb_semaphore = Arc::new(Semaphore::new(config.block_ram_buffer_max / 1024));
 
+		let disk_health = Arc::new(DiskHealth::new(
+			config.data_io_error_policy,
+			config.data_io_error_threshold,
+		));
+
 		let metrics = BlockManagerMetrics::new(
 			config.compression_level,
 			rc.rc_table.clone(),
-			resync.queue.clone(),
+			vec![
+				resync.queue.clone(),
+				resync.queue_high.clone(),
+				resync.queue_normal.clone(),
+			],
+			resync.queue_high.clone(),
 			resync.errors.clone(),
 			buffer_kb_semaphore.clone(),
+			disk_health.clone(),
 		);
 
 		let scrub_persister = PersisterShared::new(&system.metadata_dir, "scrub_info");
+		let repair_checkpoint = Persister::new(&system.metadata_dir, "repair_blocks_checkpoint");
 
 		let block_manager = Arc::new(Self {
 			replication,
@@ -173,17 +223,27 @@ impl BlockManager {
 			data_fsync: config.data_fsync,
 			disable_scrub: config.disable_scrub,
 			compression_level: config.compression_level,
+			read_hedge_delay: config
+				.block_read_hedge_delay_msec
+				.map(Duration::from_millis),
+			read_hedge_max_in_flight: std::cmp::max(config.block_read_hedge_max_in_flight, 1),
+			read_hedge_latency: config.block_read_hedge_percentile.map(LatencyTracker::new),
+			read_hedge_budget: config.block_read_hedge_max_ratio.map(HedgeBudget::new),
 			mutation_lock: vec![(); MUTEX_COUNT]
 				.iter()
 				.map(|_| Mutex::new(BlockManagerLocked()))
 				.collect::<Vec<_>>(),
+			pack,
 			rc,
 			resync,
+			holds: BlockHoldTable::new(Duration::from_secs(config.block_read_hold_max_secs)),
 			system,
 			endpoint,
 			buffer_kb_semaphore,
 			metrics,
+			disk_health,
 			scrub_persister,
+			repair_checkpoint,
 			tx_scrub_command: ArcSwapOption::new(None),
 		});
 		block_manager.endpoint.set_handler(block_manager.clone());
@@ -209,6 +269,18 @@ impl BlockManager {
 				self.scrub_persister.clone(),
 			));
 		}
+
+		// Resume a block repair that was interrupted while scanning the data directories
+		if let Ok(checkpoint) = self.repair_checkpoint.load() {
+			if !checkpoint.todo.is_empty() {
+				info!(
+					"Resuming interrupted block repair: {} of {} directories remaining",
+					checkpoint.todo.len(),
+					checkpoint.total_dirs
+				);
+				bg.spawn_worker(RepairWorker::resume(self.clone(), checkpoint));
+			}
+		}
 	}
 
 	pub fn register_bg_vars(&self, vars: &mut vars::BgVars) {
@@ -239,6 +311,12 @@ impl BlockManager {
 		self.rc.recalc_rc.store(Some(Arc::new(recalc)));
 	}
 
+	/// Initialization: set how the priority with which blocks
+	/// are resynced is determined
+	pub fn set_resync_priority(&self, resolve: ResolveResyncPriority) {
+		self.resync.resolve_priority.store(Some(Arc::new(resolve)));
+	}
+
 	/// Ask nodes that might have a (possibly compressed) block for it
 	/// Return it as a stream with a header
 	async fn rpc_get_raw_block_streaming(
@@ -270,6 +348,12 @@ impl BlockManager {
 				.await
 				.err_context("error in block data stream")
 				.map(|data| DataBlock::from_parts(header, data.into_bytes()))
+				.and_then(|block| {
+					// Check the block before using it, so that a node returning
+					// corrupted data doesn't win over the other ones
+					block.verify(*hash)?;
+					Ok(block)
+				})
 		})
 		.await
 	}
@@ -290,53 +374,139 @@ impl BlockManager {
 			.rpc_helper()
 			.block_read_nodes_of(hash, self.system.rpc_helper());
 
-		for node in who.iter() {
-			let node_id = NodeID::from(*node);
-			let rpc = self.endpoint.call_streaming(
-				&node_id,
-				BlockRpc::GetBlock(*hash, order_tag),
-				priority,
-			);
-			tokio::select! {
-				res = rpc => {
-					let res = match res {
-						Ok(res) => res,
-						Err(e) => {
-							debug!("Get block {:?}: node {:?} could not be contacted: {}", hash, node, e);
-							continue;
-						}
-					};
-					let block_stream = match res.into_parts() {
-						(Ok(BlockRpc::PutBlock { hash: _, header }), Some(stream)) => DataBlockStream::from_parts(header, stream),
-						(Ok(_), _) => {
-							debug!("Get block {:?}: node {:?} returned a malformed response", hash, node);
-							continue;
-						}
-						(Err(e), _) => {
-							debug!("Get block {:?}: node {:?} returned error: {}", hash, node, e);
-							continue;
-						}
-					};
-					match f(block_stream).await {
-						Ok(ret) => return Ok(ret),
-						Err(e) => {
-							debug!("Get block {:?}: error reading stream from node {:?}: {}", hash, node, e);
-						}
-					}
-				}
-				// TODO: sleep less long (fail early), initiate a second request earlier
-				// if the first one doesn't succeed rapidly
-				// TODO: keep first request running when initiating a new one and take the
-				// one that finishes earlier
-				_ = tokio::time::sleep(self.system.rpc_helper().rpc_timeout()) => {
-					debug!("Get block {:?}: node {:?} didn't return block in time, trying next.", hash, node);
+		// Without hedging, nodes are tried one after the other
+		let (hedge_delay, max_in_flight) = match self.read_hedge_delay {
+			Some(delay) => {
+				let adaptive_delay = self.read_hedge_latency.as_ref().and_then(|l| l.get());
+				(
+					std::cmp::max(delay, adaptive_delay.unwrap_or_default()),
+					self.read_hedge_max_in_flight,
+				)
+			}
+			None => (Duration::ZERO, 1),
+		};
+		if let Some(budget) = &self.read_hedge_budget {
+			budget.on_read();
+		}
+		let allow_hedge = || match &self.read_hedge_budget {
+			Some(budget) if !budget.try_hedge() => {
+				self.metrics.read_hedge_throttled_counter.add(1);
+				false
+			}
+			_ => true,
+		};
+
+		let res = hedged_request(&who, hedge_delay, max_in_flight, allow_hedge, |node| {
+			self.rpc_try_get_raw_block_from(hash, node, priority, order_tag, &f)
+		})
+		.await;
+
+		match res {
+			Some(hedged) => {
+				self.system
+					.rpc_helper()
+					.record_read_served(who[hedged.winner], RpcClass::BlockGet);
+				if hedged.hedges > 0 {
+					self.metrics.read_hedge_counter.add(hedged.hedges as u64);
+					let winner = if hedged.winner == 0 { "first" } else { "hedge" };
+					self.metrics
+						.read_hedge_winner_counter
+						.add(1, &[KeyValue::new("winner", winner)]);
 				}
-			};
+				Ok(hedged.value)
+			}
+			None => {
+				let err = Error::MissingBlock(*hash);
+				debug!("{}", err);
+				Err(err)
+			}
 		}
+	}
 
-		let err = Error::MissingBlock(*hash);
-		debug!("{}", err);
-		Err(err)
+	/// Ask a single node for a block, returns None if the node could not
+	/// be contacted, didn't return the block in time, or returned an error
+	async fn rpc_try_get_raw_block_from<F, Fut, T>(
+		&self,
+		hash: &Hash,
+		node: Uuid,
+		priority: RequestPriority,
+		order_tag: Option<OrderTag>,
+		f: &F,
+	) -> Option<T>
+	where
+		F: Fn(DataBlockStream) -> Fut,
+		Fut: futures::Future<Output = Result<T, Error>>,
+	{
+		let node_id = NodeID::from(node);
+		let rpc =
+			self.endpoint
+				.call_streaming(&node_id, BlockRpc::GetBlock(*hash, order_tag), priority);
+		let timeout = self.system.rpc_helper().rpc_timeout_for(RpcClass::BlockGet);
+
+		let start = Instant::now();
+		let res = match tokio::time::timeout(timeout, rpc).await {
+			Ok(Ok(res)) => {
+				if let Some(latency) = &self.read_hedge_latency {
+					latency.record(start.elapsed());
+				}
+				res
+			}
+			Ok(Err(e)) => {
+				debug!(
+					"Get block {:?}: node {:?} could not be contacted: {}",
+					hash, node, e
+				);
+				return None;
+			}
+			Err(_) => {
+				self.system.rpc_helper().record_timeout(
+					self.endpoint.path(),
+					node,
+					RpcClass::BlockGet,
+				);
+				debug!(
+					"Get block {:?}: node {:?} didn't return block in time, trying next.",
+					hash, node
+				);
+				return None;
+			}
+		};
+		let block_stream = match res.into_parts() {
+			(Ok(BlockRpc::PutBlock { hash: _, header }), Some(stream)) => {
+				DataBlockStream::from_parts(header, stream)
+			}
+			(Ok(_), _) => {
+				debug!(
+					"Get block {:?}: node {:?} returned a malformed response",
+					hash, node
+				);
+				return None;
+			}
+			(Err(e), _) => {
+				debug!(
+					"Get block {:?}: node {:?} returned error: {}",
+					hash, node, e
+				);
+				return None;
+			}
+		};
+		match f(block_stream).await {
+			Ok(ret) => {
+				record_timing(
+					"block fetch",
+					|| Some(format!("{:?} from {:?}", hash, node)),
+					start.elapsed(),
+				);
+				Some(ret)
+			}
+			Err(e) => {
+				debug!(
+					"Get block {:?}: error reading stream from node {:?}: {}",
+					hash, node, e
+				);
+				None
+			}
+		}
 	}
 
 	// ---- Public interface ----
@@ -347,9 +517,11 @@ impl BlockManager {
 		hash: &Hash,
 		order_tag: Option<OrderTag>,
 	) -> Result<ByteStream, Error> {
-		let block_stream = self
-			.rpc_get_raw_block_streaming(hash, PRIO_NORMAL | PRIO_SECONDARY, order_tag)
-			.await?;
+		let block_stream = timed_phase(
+			"block io",
+			self.rpc_get_raw_block_streaming(hash, PRIO_NORMAL | PRIO_SECONDARY, order_tag),
+		)
+		.await?;
 		let (header, stream) = block_stream.into_parts();
 		match header {
 			DataBlockHeader::Plain => Ok(stream),
@@ -370,8 +542,22 @@ impl BlockManager {
 		data: Bytes,
 		prevent_compression: bool,
 		order_tag: Option<OrderTag>,
+		replication_factor: Option<usize>,
 	) -> Result<(), Error> {
-		let who = self.replication.write_sets(&hash);
+		// Blocks of buckets that override the replication factor are only
+		// written to the first nodes of the storage sets of the block
+		let (who, quorum) = match replication_factor {
+			Some(n) if n < self.replication.replication_factor => (
+				self.system
+					.layout_manager
+					.write_sets_of_with_factor(&hash, n),
+				std::cmp::min(n, self.replication.write_quorum()),
+			),
+			_ => (
+				self.replication.write_sets(&hash),
+				self.replication.write_quorum(),
+			),
+		};
 
 		let compression_level = self.compression_level.filter(|_| !prevent_compression);
 		let (header, bytes) = DataBlock::from_buffer(data, compression_level)
@@ -393,24 +579,125 @@ impl BlockManager {
 			put_block_rpc
 		};
 
-		self.system
-			.rpc_helper()
-			.try_write_many_sets(
+		timed_phase(
+			"block io",
+			self.system.rpc_helper().try_write_many_sets(
 				&self.endpoint,
 				who.as_ref(),
+				&[],
 				put_block_rpc,
 				RequestStrategy::with_priority(PRIO_NORMAL | PRIO_SECONDARY)
+					.with_timeout_class(RpcClass::BlockPut)
 					.with_drop_on_completion(permit)
-					.with_quorum(self.replication.write_quorum()),
-			)
-			.await?;
+					.with_quorum(quorum)
+					.with_min_zones(self.replication.write_min_zones),
+			),
+		)
+		.await?;
 
 		Ok(())
 	}
 
+	/// Ask a specific node for a (possibly compressed) block,
+	/// and check the integrity of the data it returns
+	pub async fn rpc_get_raw_block_from(
+		&self,
+		hash: &Hash,
+		node: Uuid,
+	) -> Result<DataBlock, Error> {
+		let get = async {
+			let res = self
+				.endpoint
+				.call_streaming(
+					&NodeID::from(node),
+					BlockRpc::GetBlock(*hash, None),
+					PRIO_NORMAL | PRIO_SECONDARY,
+				)
+				.await?;
+			match res.into_parts() {
+				(Ok(BlockRpc::PutBlock { hash: _, header }), Some(stream)) => {
+					let data = read_stream_to_end(stream)
+						.await
+						.err_context("error in block data stream")?;
+					Ok(DataBlock::from_parts(header, data.into_bytes()))
+				}
+				(Ok(m), _) => Err(Error::unexpected_rpc_message(m)),
+				(Err(e), _) => Err(e),
+			}
+		};
+		let timeout = self.system.rpc_helper().rpc_timeout_for(RpcClass::BlockGet);
+		let block = match tokio::time::timeout(timeout, get).await {
+			Ok(res) => res?,
+			Err(_) => {
+				self.system.rpc_helper().record_timeout(
+					self.endpoint.path(),
+					node,
+					RpcClass::BlockGet,
+				);
+				return Err(Error::Timeout);
+			}
+		};
+		block.verify(*hash)?;
+		Ok(block)
+	}
+
+	/// Ask a specific node whether it currently stores a block
+	pub async fn rpc_has_block(&self, hash: &Hash, node: Uuid) -> Result<bool, Error> {
+		let res = self
+			.endpoint
+			.call(
+				&NodeID::from(node),
+				BlockRpc::HasBlockQuery(*hash),
+				PRIO_NORMAL | PRIO_SECONDARY,
+			)
+			.await??;
+		match res {
+			BlockRpc::HasBlockReply(x) => Ok(x),
+			m => Err(Error::unexpected_rpc_message(m)),
+		}
+	}
+
+	/// Send a (possibly compressed) block to a specific node. If `force` is set,
+	/// the node replaces any copy of the block that it already stores.
+	pub async fn rpc_put_raw_block_to(
+		&self,
+		hash: &Hash,
+		node: Uuid,
+		block: DataBlock,
+		force: bool,
+	) -> Result<(), Error> {
+		let (header, bytes) = block.into_parts();
+		let msg = if force {
+			BlockRpc::ForcePutBlock {
+				hash: *hash,
+				header,
+			}
+		} else {
+			BlockRpc::PutBlock {
+				hash: *hash,
+				header,
+			}
+		};
+		let req = Req::new(msg)?.with_stream_from_buffer(bytes);
+		let res = self
+			.endpoint
+			.call_streaming(&NodeID::from(node), req, PRIO_NORMAL | PRIO_SECONDARY)
+			.await?
+			.into_msg()?;
+		match res {
+			BlockRpc::Ok => Ok(()),
+			m => Err(Error::unexpected_rpc_message(m)),
+		}
+	}
+
+	/// Nodes that should store a block according to the current cluster layout
+	pub fn storage_nodes_of(&self, hash: &Hash) -> Vec<Uuid> {
+		self.replication.storage_nodes(hash)
+	}
+
 	/// Get number of items in the refcount table
 	pub fn rc_len(&self) -> Result<usize, Error> {
-		Ok(self.rc.rc_table.len()?)
+		Ok(self.rc.rc_table.fast_len()?)
 	}
 
 	/// Send command to start/stop/manager scrub worker
@@ -428,7 +715,7 @@ impl BlockManager {
 
 	/// List all resync errors
 	pub fn list_resync_errors(&self) -> Result<Vec<BlockResyncErrorInfo>, Error> {
-		let mut blocks = Vec::with_capacity(self.resync.errors.len()?);
+		let mut blocks = Vec::with_capacity(self.resync.errors.fast_len()?);
 		for ent in self.resync.errors.iter()? {
 			let (hash, cnt) = ent?;
 			let cnt = ErrorCounter::decode(&cnt);
@@ -450,18 +737,23 @@ impl BlockManager {
 
 	/// Increment the number of time a block is used, putting it to resynchronization if it is
 	/// required, but not known
+	/// `replication_factor` is the number of copies of the block required by
+	/// this reference, if it is less than the cluster's replication factor
 	pub fn block_incref(
 		self: &Arc<Self>,
 		tx: &mut db::Transaction,
 		hash: Hash,
+		replication_factor: Option<usize>,
 	) -> db::TxOpResult<()> {
-		if self.rc.block_incref(tx, &hash)? {
+		if self.rc.block_incref(tx, &hash, replication_factor)? {
 			// When the reference counter is incremented, there is
 			// normally a node that is responsible for sending us the
 			// data of the block. However that operation may fail,
 			// so in all cases we add the block here to the todo list
 			// to check later that it arrived correctly, and if not
 			// we will fecth it from someone.
+			// This is also the case when the block was stored with less
+			// copies than what is now required.
 			let this = self.clone();
 			tokio::spawn(async move {
 				if let Err(e) = this
@@ -514,20 +806,50 @@ impl BlockManager {
 		self.write_block(&hash, &data).await
 	}
 
+	async fn handle_force_put_block(
+		&self,
+		hash: Hash,
+		header: DataBlockHeader,
+		stream: Option<ByteStream>,
+	) -> Result<(), Error> {
+		let stream = stream.ok_or_message("missing stream")?;
+		let bytes = read_stream_to_end(stream).await?.into_bytes();
+		let data = DataBlock::from_parts(header, bytes);
+		data.verify(hash)?;
+		self.lock_mutate(&hash)
+			.await
+			.replace_block(&hash, &data, self)
+			.await
+	}
+
 	/// Write a block to disk
 	pub(crate) async fn write_block(&self, hash: &Hash, data: &DataBlock) -> Result<(), Error> {
 		let tracer = opentelemetry::global::tracer("garage");
 
-		self.lock_mutate(hash)
+		if self.disk_health.is_degraded() {
+			return Err(Error::Message(
+				"data storage of this node is degraded after repeated I/O errors, not accepting block writes".into(),
+			));
+		}
+
+		let res = self
+			.lock_mutate(hash)
 			.await
 			.write_block(hash, data, self)
 			.bound_record_duration(&self.metrics.block_write_duration)
 			.with_context(Context::current_with_span(
 				tracer.start("BlockManagerLocked::write_block"),
 			))
-			.await?;
+			.await;
 
-		Ok(())
+		match &res {
+			Ok(()) => self.disk_health.record_success(),
+			Err(Error::Io(e)) => {
+				self.record_io_error("write", &format!("writing block {:?}", hash), e)
+			}
+			Err(_) => (),
+		}
+		res
 	}
 
 	async fn handle_get_block(&self, hash: &Hash, order_tag: Option<OrderTag>) -> Resp<BlockRpc> {
@@ -555,6 +877,9 @@ impl BlockManager {
 	pub(crate) async fn read_block(&self, hash: &Hash) -> Result<DataBlock, Error> {
 		let tracer = opentelemetry::global::tracer("garage");
 		async {
+			if let Some(block) = self.pack.get(hash)? {
+				return self.read_packed_block(hash, block).await;
+			}
 			match self.find_block(hash).await {
 				Some(p) => self.read_block_from(hash, &p).await,
 				None => {
@@ -582,11 +907,42 @@ impl BlockManager {
 	) -> Result<DataBlock, Error> {
 		let (header, path) = block_path.as_parts_ref();
 
-		let mut f = fs::File::open(&path).await?;
-		let mut data = vec![];
-		f.read_to_end(&mut data).await?;
+		let data = match read_block_file(path).await {
+			Ok(data) => {
+				self.disk_health.record_success();
+				data
+			}
+			Err(e) if DiskHealth::is_io_error(&e) => {
+				self.record_io_error("read", &format!("reading block {:?}", hash), &e);
+
+				// The local copy cannot be read: set it aside so that
+				// a copy is fetched again from the other nodes
+				warn!(
+					"I/O error while reading block {:?} from {}: {}. Renaming to .corrupted and resyncing.",
+					hash,
+					path.display(),
+					e
+				);
+				if let Err(e2) = self
+					.lock_mutate(hash)
+					.await
+					.move_block_to_corrupted(block_path)
+					.await
+				{
+					warn!("Could not set aside unreadable block {:?}: {}", hash, e2);
+				}
+				self.resync.put_to_resync(hash, Duration::from_millis(0))?;
+
+				return Err(Error::Message(format!(
+					"I/O error while reading block {:?} from {}: {}, block was queued for resync from other nodes",
+					hash,
+					path.display(),
+					e
+				)));
+			}
+			Err(e) => return Err(e.into()),
+		};
 		self.metrics.bytes_read.add(data.len() as u64);
-		drop(f);
 
 		let data = DataBlock::from_parts(header, data.into());
 
@@ -609,11 +965,100 @@ impl BlockManager {
 		Ok(data)
 	}
 
+	/// Read a block stored packed in a container, verifying its integrity
+	async fn read_packed_block(
+		&self,
+		hash: &Hash,
+		mut block: PackedBlock,
+	) -> Result<DataBlock, Error> {
+		let data = loop {
+			match self.pack.read(&block, &self.data_layout.load_full()).await {
+				Ok(data) => {
+					self.disk_health.record_success();
+					break data;
+				}
+				Err(e) if e.kind() == io::ErrorKind::NotFound => match self.pack.get(hash)? {
+					// The block was moved by the compaction of its container
+					Some(b) if b != block => block = b,
+					_ => {
+						// The container is missing, forget about the block
+						// so that it is fetched again from the other nodes
+						self.lock_mutate(hash).await.drop_packed_block(hash, self)?;
+						self.resync
+							.put_to_resync(hash, 2 * self.system.rpc_helper().rpc_timeout())?;
+						return Err(Error::Message(format!(
+							"block {:?} not found on node",
+							hash
+						)));
+					}
+				},
+				Err(e) if DiskHealth::is_io_error(&e) => {
+					self.record_io_error("read", &format!("reading block {:?}", hash), &e);
+
+					// Drop the packed copy so that it is fetched again from the other nodes
+					warn!(
+						"I/O error while reading packed block {:?} from container {:x}: {}. Dropping it and resyncing.",
+						hash, block.container, e
+					);
+					if let Err(e2) = self.lock_mutate(hash).await.drop_packed_block(hash, self) {
+						warn!("Could not drop unreadable block {:?}: {}", hash, e2);
+					}
+					self.resync.put_to_resync(hash, Duration::from_millis(0))?;
+
+					return Err(Error::Message(format!(
+						"I/O error while reading packed block {:?}: {}, block was queued for resync from other nodes",
+						hash, e
+					)));
+				}
+				Err(e) => return Err(e.into()),
+			}
+		};
+		self.metrics.bytes_read.add(block.len);
+
+		if data.verify(*hash).is_err() {
+			self.metrics.corruption_counter.add(1);
+
+			warn!(
+				"Packed block {:?} is corrupted (container {:x}, offset {}). Dropping it and resyncing.",
+				hash, block.container, block.offset
+			);
+			self.lock_mutate(hash).await.drop_packed_block(hash, self)?;
+			self.resync.put_to_resync(hash, Duration::from_millis(0))?;
+
+			return Err(Error::CorruptData(*hash));
+		}
+
+		Ok(data)
+	}
+
 	/// Check if this node should have a block, but don't actually have it
 	async fn need_block(&self, hash: &Hash) -> Result<bool, Error> {
+		if self.disk_health.is_degraded() {
+			// We would refuse to store the block anyway
+			return Ok(false);
+		}
 		let rc = self.rc.get_block_rc(hash)?;
-		let exists = self.find_block(hash).await.is_some();
-		Ok(rc.is_nonzero() && !exists)
+		let exists = self.has_block(hash).await?;
+		Ok(rc.is_nonzero() && !exists && self.is_block_replica(hash)?)
+	}
+
+	/// Check if this node currently stores a block, packed or not
+	pub(crate) async fn has_block(&self, hash: &Hash) -> Result<bool, Error> {
+		Ok(self.pack.contains(hash)? || self.find_block(hash).await.is_some())
+	}
+
+	/// Whether this node is one of the nodes that should store a copy of a block,
+	/// taking into account blocks stored with less copies than the cluster's
+	/// replication factor
+	pub(crate) fn is_block_replica(&self, hash: &Hash) -> Result<bool, Error> {
+		match self.rc.get_block_replication_factor(hash)? {
+			None => Ok(true),
+			Some(n) => Ok(self
+				.system
+				.cluster_layout()
+				.storage_nodes_of_with_factor(hash, n)
+				.contains(&self.system.id)),
+		}
 	}
 
 	/// Delete block if it is not needed anymore
@@ -624,7 +1069,7 @@ impl BlockManager {
 			.await
 	}
 
-	/// Find the path where a block is currently stored
+	/// Find the path where a block is currently stored as a file of its own
 	pub(crate) async fn find_block(&self, hash: &Hash) -> Option<DataBlockPath> {
 		let data_layout = self.data_layout.load_full();
 		let dirs = Some(data_layout.primary_block_dir(hash))
@@ -675,6 +1120,43 @@ impl BlockManager {
 			.await
 	}
 
+	/// Move a packed block to a container of its primary location.
+	/// Returns the number of bytes read/written
+	pub(crate) async fn repack_block(&self, hash: &Hash) -> Result<usize, Error> {
+		let data = self.read_block(hash).await?;
+		self.lock_mutate(hash)
+			.await
+			.repack_block(hash, &data, self)
+			.await
+	}
+
+	/// Record an I/O error on a data block,
+	/// and apply the data_io_error_policy if needed
+	fn record_io_error(&self, op: &'static str, what: &str, e: &io::Error) {
+		if DiskHealth::is_io_error(e) {
+			self.metrics
+				.io_error_counter
+				.add(1, &[KeyValue::new("op", op)]);
+		}
+		match self.disk_health.record_error(what, e) {
+			DiskHealthAction::None => (),
+			DiskHealthAction::Quarantine => {
+				error!(
+					"Data storage of this node is degraded after repeated I/O errors (last one {}: {}). The node no longer accepts block writes. Check the drives of the data directories, and restart the node once they are fixed.",
+					what, e
+				);
+				self.system.set_data_storage_degraded(true);
+			}
+			DiskHealthAction::Crash => {
+				error!(
+					"Repeated I/O errors on the data directories (last one {}: {}). Shutting down as data_io_error_policy is \"crash\".",
+					what, e
+				);
+				std::process::exit(1);
+			}
+		}
+	}
+
 	async fn lock_mutate(&self, hash: &Hash) -> MutexGuard<'_, BlockManagerLocked> {
 		let tracer = opentelemetry::global::tracer("garage");
 		let ilock = u16::from_be_bytes([hash.as_slice()[0], hash.as_slice()[1]]) as usize
@@ -701,6 +1183,14 @@ impl StreamingEndpointHandler<BlockRpc> for BlockManager {
 			BlockRpc::NeedBlockQuery(h) => {
 				Resp::new(self.need_block(h).await.map(BlockRpc::NeedBlockReply))
 			}
+			BlockRpc::HasBlockQuery(h) => {
+				Resp::new(self.has_block(h).await.map(BlockRpc::HasBlockReply))
+			}
+			BlockRpc::ForcePutBlock { hash, header } => Resp::new(
+				self.handle_force_put_block(*hash, *header, message.take_stream())
+					.await
+					.map(|()| BlockRpc::Ok),
+			),
 			m => Resp::new(Err(Error::unexpected_rpc_message(m))),
 		}
 	}
@@ -713,10 +1203,76 @@ impl BlockManagerLocked {
 		data: &DataBlock,
 		mgr: &BlockManager,
 	) -> Result<(), Error> {
+		if mgr.pack.contains(hash)? {
+			// The block is already stored packed, we have nothing to do
+			return Ok(());
+		}
 		let existing_path = mgr.find_block(hash).await;
+		if existing_path.is_none() && mgr.pack.should_pack(data.as_parts_ref().1.len() as u64) {
+			return self.write_packed_block(hash, data, mgr).await;
+		}
 		self.write_block_inner(hash, data, mgr, existing_path).await
 	}
 
+	async fn write_packed_block(
+		&self,
+		hash: &Hash,
+		data: &DataBlock,
+		mgr: &BlockManager,
+	) -> Result<(), Error> {
+		let directory = mgr.data_layout.load().primary_block_dir(hash);
+		let previous = mgr
+			.pack
+			.append(hash, data, &directory, mgr.data_fsync)
+			.await?;
+		mgr.metrics
+			.bytes_written
+			.add(data.as_parts_ref().1.len() as u64);
+
+		if let Some(previous) = previous {
+			self.cleanup_container(hash, &previous, mgr).await?;
+		}
+		Ok(())
+	}
+
+	/// Drop the packed copy of a block, leaving its data as dead space
+	/// in its container
+	fn drop_packed_block(&self, hash: &Hash, mgr: &BlockManager) -> Result<(), Error> {
+		mgr.pack.remove(hash)?;
+		Ok(())
+	}
+
+	/// Delete the packed copy of a block, and clean up its container.
+	/// Returns true if the block was stored packed.
+	async fn delete_packed_block(&self, hash: &Hash, mgr: &BlockManager) -> Result<bool, Error> {
+		match mgr.pack.remove(hash)? {
+			Some(removed) => {
+				self.cleanup_container(hash, &removed, mgr).await?;
+				Ok(true)
+			}
+			None => Ok(false),
+		}
+	}
+
+	async fn cleanup_container(
+		&self,
+		hash: &Hash,
+		removed: &PackedBlock,
+		mgr: &BlockManager,
+	) -> Result<(), Error> {
+		let data_layout = mgr.data_layout.load_full();
+		let directory = data_layout.primary_block_dir(hash);
+		let corrupted = mgr
+			.pack
+			.cleanup(hash, removed, &directory, &data_layout, mgr.data_fsync)
+			.await?;
+		for hash in corrupted {
+			mgr.metrics.corruption_counter.add(1);
+			mgr.resync.put_to_resync(&hash, Duration::from_millis(0))?;
+		}
+		Ok(())
+	}
+
 	async fn write_block_inner(
 		&self,
 		hash: &Hash,
@@ -804,6 +1360,26 @@ impl BlockManagerLocked {
 		Ok(())
 	}
 
+	async fn replace_block(
+		&self,
+		hash: &Hash,
+		data: &DataBlock,
+		mgr: &BlockManager,
+	) -> Result<(), Error> {
+		// Remove all copies that we currently store, which might be corrupted,
+		// and write the new one at its primary location
+		self.delete_packed_block(hash, mgr).await?;
+		while let Some(path) = mgr.find_block(hash).await {
+			let (_header, path) = path.as_parts_ref();
+			fs::remove_file(path).await?;
+		}
+		if mgr.pack.should_pack(data.as_parts_ref().1.len() as u64) {
+			self.write_packed_block(hash, data, mgr).await
+		} else {
+			self.write_block_inner(hash, data, mgr, None).await
+		}
+	}
+
 	async fn move_block_to_corrupted(&self, block_path: &DataBlockPath) -> Result<(), Error> {
 		let (header, path) = block_path.as_parts_ref();
 
@@ -821,6 +1397,14 @@ impl BlockManagerLocked {
 	async fn delete_if_unneeded(&self, hash: &Hash, mgr: &BlockManager) -> Result<(), Error> {
 		let rc = mgr.rc.get_block_rc(hash)?;
 		if rc.is_deletable() {
+			if mgr.holds.is_held(hash) {
+				// Block is being read by a client, try again later
+				mgr.resync.put_to_resync(hash, BLOCK_HOLD_RECHECK_DELAY)?;
+				return Ok(());
+			}
+			if self.delete_packed_block(hash, mgr).await? {
+				mgr.metrics.delete_counter.add(1);
+			}
 			while let Some(path) = mgr.find_block(hash).await {
 				let (_header, path) = path.as_parts_ref();
 				fs::remove_file(path).await?;
@@ -837,12 +1421,42 @@ impl BlockManagerLocked {
 		mgr: &BlockManager,
 	) -> Result<usize, Error> {
 		let data = mgr.read_block_from(hash, &wrong_path).await?;
-		self.write_block_inner(hash, &data, mgr, Some(wrong_path))
-			.await?;
+		let len = data.as_parts_ref().1.len();
+		if mgr.pack.should_pack(len as u64) {
+			// Small blocks are moved to a container of their primary location
+			if !mgr.pack.contains(hash)? {
+				self.write_packed_block(hash, &data, mgr).await?;
+			}
+			fs::remove_file(wrong_path.as_parts_ref().1).await?;
+		} else {
+			self.write_block_inner(hash, &data, mgr, Some(wrong_path))
+				.await?;
+		}
+		Ok(len)
+	}
+
+	async fn repack_block(
+		&self,
+		hash: &Hash,
+		data: &DataBlock,
+		mgr: &BlockManager,
+	) -> Result<usize, Error> {
+		if !mgr.pack.contains(hash)? {
+			// The block was deleted in the meantime
+			return Ok(0);
+		}
+		self.write_packed_block(hash, data, mgr).await?;
 		Ok(data.as_parts_ref().1.len())
 	}
 }
 
+async fn read_block_file(path: &Path) -> Result<Vec<u8>, io::Error> {
+	let mut f = fs::File::open(path).await?;
+	let mut data = vec![];
+	f.read_to_end(&mut data).await?;
+	Ok(data)
+}
+
 struct DeleteOnDrop(Option<PathBuf>);
 
 impl DeleteOnDrop {
@@ -6,16 +6,21 @@ use opentelemetry::{global, metrics::*};
 
 use garage_db as db;
 
+use crate::disk_health::DiskHealth;
+
 /// TableMetrics reference all counter used for metrics
 pub struct BlockManagerMetrics {
 	pub(crate) _compression_level: ValueObserver<u64>,
 	pub(crate) _rc_size: ValueObserver<u64>,
 	pub(crate) _resync_queue_len: ValueObserver<u64>,
+	pub(crate) _resync_high_priority_queue_len: ValueObserver<u64>,
 	pub(crate) _resync_errored_blocks: ValueObserver<u64>,
 	pub(crate) _buffer_free_kb: ValueObserver<u64>,
+	pub(crate) _data_storage_degraded: ValueObserver<u64>,
 
 	pub(crate) resync_counter: BoundCounter<u64>,
 	pub(crate) resync_error_counter: BoundCounter<u64>,
+	pub(crate) resync_priority_counter: Counter<u64>,
 	pub(crate) resync_duration: BoundValueRecorder<f64>,
 	pub(crate) resync_send_counter: Counter<u64>,
 	pub(crate) resync_recv_counter: BoundCounter<u64>,
@@ -27,15 +32,22 @@ pub struct BlockManagerMetrics {
 	pub(crate) delete_counter: BoundCounter<u64>,
 
 	pub(crate) corruption_counter: BoundCounter<u64>,
+	pub(crate) io_error_counter: Counter<u64>,
+
+	pub(crate) read_hedge_counter: BoundCounter<u64>,
+	pub(crate) read_hedge_winner_counter: Counter<u64>,
+	pub(crate) read_hedge_throttled_counter: BoundCounter<u64>,
 }
 
 impl BlockManagerMetrics {
 	pub fn new(
 		compression_level: Option<i32>,
 		rc_tree: db::Tree,
-		resync_queue: db::Tree,
+		resync_queues: Vec<db::Tree>,
+		resync_queue_high: db::Tree,
 		resync_errors: db::Tree,
 		buffer_semaphore: Arc<Semaphore>,
+		disk_health: Arc<DiskHealth>,
 	) -> Self {
 		let meter = global::meter("garage_model/block");
 		Self {
@@ -50,7 +62,7 @@ impl BlockManagerMetrics {
 				.init(),
 			_rc_size: meter
 				.u64_value_observer("block.rc_size", move |observer| {
-					if let Ok(value) = rc_tree.len() {
+					if let Ok(value) = rc_tree.fast_len() {
 						observer.observe(value as u64, &[])
 					}
 				})
@@ -58,17 +70,31 @@ impl BlockManagerMetrics {
 				.init(),
 			_resync_queue_len: meter
 				.u64_value_observer("block.resync_queue_length", move |observer| {
-					if let Ok(value) = resync_queue.len() {
-						observer.observe(value as u64, &[]);
+					let len = resync_queues
+						.iter()
+						.map(|q| q.fast_len())
+						.collect::<std::result::Result<Vec<_>, _>>();
+					if let Ok(value) = len {
+						observer.observe(value.iter().sum::<usize>() as u64, &[]);
 					}
 				})
 				.with_description(
 					"Number of block hashes queued for local check and possible resync",
 				)
 				.init(),
+			_resync_high_priority_queue_len: meter
+				.u64_value_observer("block.resync_high_priority_queue_length", move |observer| {
+					if let Ok(value) = resync_queue_high.fast_len() {
+						observer.observe(value as u64, &[]);
+					}
+				})
+				.with_description(
+					"Number of block hashes queued for resync with high priority",
+				)
+				.init(),
 			_resync_errored_blocks: meter
 				.u64_value_observer("block.resync_errored_blocks", move |observer| {
-					if let Ok(value) = resync_errors.len() {
+					if let Ok(value) = resync_errors.fast_len() {
 						observer.observe(value as u64, &[]);
 					}
 				})
@@ -83,6 +109,14 @@ impl BlockManagerMetrics {
 					"Available RAM in KiB to use for buffering data blocks to be written to remote nodes",
 				)
 				.init(),
+			_data_storage_degraded: meter
+				.u64_value_observer("block.data_storage_degraded", move |observer| {
+					observer.observe(disk_health.is_degraded() as u64, &[])
+				})
+				.with_description(
+					"Whether the data storage of the node is degraded after repeated I/O errors (1 if so)",
+				)
+				.init(),
 
 			resync_counter: meter
 				.u64_counter("block.resync_counter")
@@ -94,6 +128,10 @@ impl BlockManagerMetrics {
 				.with_description("Number of calls to resync_block that returned an error")
 				.init()
 				.bind(&[]),
+			resync_priority_counter: meter
+				.u64_counter("block.resync_priority_counter")
+				.with_description("Number of calls to resync_block, by priority of the block")
+				.init(),
 			resync_duration: meter
 				.f64_value_recorder("block.resync_duration")
 				.with_description("Duration of resync_block operations")
@@ -140,6 +178,25 @@ impl BlockManagerMetrics {
 				.with_description("Data corruptions detected on block reads")
 				.init()
 				.bind(&[]),
+			io_error_counter: meter
+				.u64_counter("block.io_error_counter")
+				.with_description("I/O errors on reads and writes of data blocks, by operation (read or write)")
+				.init(),
+
+			read_hedge_counter: meter
+				.u64_counter("block.read_hedge_counter")
+				.with_description("Number of additional block requests sent because the previous ones did not answer within the hedge delay")
+				.init()
+				.bind(&[]),
+			read_hedge_winner_counter: meter
+				.u64_counter("block.read_hedge_winner_counter")
+				.with_description("Number of hedged block reads, by request that returned first (first or hedge)")
+				.init(),
+			read_hedge_throttled_counter: meter
+				.u64_counter("block.read_hedge_throttled_counter")
+				.with_description("Number of block requests that were not sent as hedges because of block_read_hedge_max_ratio")
+				.init()
+				.bind(&[]),
 		}
 	}
 }
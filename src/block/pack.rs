@@ -0,0 +1,805 @@
+//! Packed storage of small data blocks.
+//!
+//! When `block_pack_threshold` is set, blocks smaller than the threshold are
+//! not stored in a file of their own, but appended to a container file, so
+//! that nodes storing a very large number of small blocks do not run out of
+//! inodes. An index in the metadata database gives the location of each
+//! packed block in its container.
+//!
+//! A container only holds blocks whose hashes start with the same two bytes.
+//! All these blocks are protected by the same mutation lock of the block
+//! manager, and containers are only modified while this lock is held.
+//! Data is only ever appended to containers: deleting a block leaves dead
+//! space in its container, which is compacted (its live blocks are moved to
+//! the current container of their directory) once the fraction of dead space
+//! exceeds `block_pack_max_dead_ratio`.
+//!
+//! Container ids are never reused: a counter per prefix gives the id of the
+//! next container. Their paths are stored relative to the data directory
+//! they are in, so that data directories can be moved.
+use std::convert::TryInto;
+use std::ffi::OsStr;
+use std::io::{self, SeekFrom};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use garage_db as db;
+
+use garage_util::data::*;
+use garage_util::error::*;
+
+use crate::block::*;
+use crate::layout::DataLayout;
+
+/// Prefix of the names of container files in the data directories
+pub(crate) const PACK_FILE_PREFIX: &str = "pack-";
+
+/// Size above which no more blocks are appended to a container
+const PACK_MAX_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Location of a packed block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PackedBlock {
+	pub(crate) container: u64,
+	pub(crate) offset: u64,
+	pub(crate) len: u64,
+	pub(crate) header: DataBlockHeader,
+}
+
+/// A container file
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct PackContainer {
+	/// Path of the container, relative to its data directory
+	/// (containers created by previous versions have an absolute path)
+	pub(crate) path: PathBuf,
+	/// Size of the container, including dead space
+	pub(crate) size: u64,
+	/// Total size of the blocks of the container that are still in use
+	pub(crate) live_bytes: u64,
+	pub(crate) live_blocks: u64,
+}
+
+pub(crate) struct PackStore {
+	/// Blocks smaller than this are packed (disabled if not set)
+	threshold: Option<usize>,
+	max_dead_ratio: f64,
+
+	/// Block hash -> PackedBlock
+	index: db::Tree,
+	/// Container id -> PackContainer
+	containers: db::Tree,
+	/// Container id ++ block hash -> (), to list the blocks of a container
+	contents: db::Tree,
+	/// Hash prefix -> id of the next container for this prefix
+	next_ids: db::Tree,
+}
+
+impl PackStore {
+	pub(crate) fn new(db: &db::Db, threshold: Option<usize>, max_dead_ratio: f64) -> Self {
+		let index = db
+			.open_tree("block_pack_index")
+			.expect("Unable to open block_pack_index tree");
+		let containers = db
+			.open_tree("block_pack_containers")
+			.expect("Unable to open block_pack_containers tree");
+		let contents = db
+			.open_tree("block_pack_contents")
+			.expect("Unable to open block_pack_contents tree");
+		let next_ids = db
+			.open_tree("block_pack_next_ids")
+			.expect("Unable to open block_pack_next_ids tree");
+		Self {
+			threshold,
+			max_dead_ratio,
+			index,
+			containers,
+			contents,
+			next_ids,
+		}
+	}
+
+	/// Whether packing of small blocks is enabled
+	pub(crate) fn is_enabled(&self) -> bool {
+		self.threshold.is_some()
+	}
+
+	/// Whether a block of this size (as stored) must be packed
+	pub(crate) fn should_pack(&self, len: u64) -> bool {
+		matches!(self.threshold, Some(t) if len < t as u64)
+	}
+
+	/// Location of a block, if it is stored packed
+	pub(crate) fn get(&self, hash: &Hash) -> Result<Option<PackedBlock>, Error> {
+		Ok(self.index.get(hash)?.map(|v| PackedBlock::decode(&v)))
+	}
+
+	pub(crate) fn contains(&self, hash: &Hash) -> Result<bool, Error> {
+		Ok(self.index.get(hash)?.is_some())
+	}
+
+	pub(crate) fn container(&self, id: u64) -> Result<Option<PackContainer>, Error> {
+		Ok(self
+			.containers
+			.get(id.to_be_bytes())?
+			.map(|v| PackContainer::decode(&v)))
+	}
+
+	/// Hashes of the blocks stored in a container
+	pub(crate) fn container_blocks(&self, id: u64) -> Result<Vec<Hash>, Error> {
+		let start = id.to_be_bytes();
+		let mut ret = vec![];
+		for item in self.contents.range(&start[..]..)? {
+			let (k, _) = item?;
+			if k[..8] != start[..] {
+				break;
+			}
+			ret.push(Hash::try_from(&k[8..]).unwrap());
+		}
+		Ok(ret)
+	}
+
+	/// The container to which the blocks with the same prefix as `hash`
+	/// are currently appended, if there is one
+	fn current_container(&self, hash: &Hash) -> Result<Option<(u64, PackContainer)>, Error> {
+		let (first, last) = container_id_range(hash);
+		match self
+			.containers
+			.range_rev(first.to_be_bytes()..=last.to_be_bytes())?
+			.next()
+		{
+			Some(item) => {
+				let (k, v) = item?;
+				Ok(Some((
+					u64::from_be_bytes(k[..].try_into().unwrap()),
+					PackContainer::decode(&v),
+				)))
+			}
+			None => Ok(None),
+		}
+	}
+
+	/// Id of a new container for the blocks with the same prefix as `hash`.
+	/// Ids are never reused, so that a location recorded before a container
+	/// was deleted cannot point into another container.
+	fn new_container_id(&self, hash: &Hash) -> Result<u64, Error> {
+		let (first, last) = container_id_range(hash);
+		// Before the counter is set, ids follow the ones already used
+		let initial = match self.current_container(hash)? {
+			Some((id, _)) => id + 1,
+			None => first,
+		};
+		let prefix = &hash.as_slice()[..2];
+		let id = self.next_ids.db().transaction(|tx| {
+			let id = match tx.get(&self.next_ids, prefix)? {
+				Some(v) => u64::from_be_bytes(v[..].try_into().unwrap()),
+				None => initial,
+			};
+			tx.insert(&self.next_ids, prefix, (id + 1).to_be_bytes())?;
+			Ok(id)
+		})?;
+		if id > last {
+			return Err(Error::Message(format!(
+				"No more container ids for blocks with prefix {}",
+				hex::encode(prefix)
+			)));
+		}
+		Ok(id)
+	}
+
+	/// Read the data of a packed block, without checking its integrity
+	pub(crate) async fn read(
+		&self,
+		block: &PackedBlock,
+		layout: &DataLayout,
+	) -> Result<DataBlock, io::Error> {
+		let container = self
+			.container(block.container)
+			.map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?
+			.ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+
+		let mut f = open_container(&container, layout).await?;
+		f.seek(SeekFrom::Start(block.offset)).await?;
+		let mut data = vec![0u8; block.len as usize];
+		f.read_exact(&mut data).await?;
+
+		Ok(DataBlock::from_parts(block.header, data.into()))
+	}
+
+	// ---- Functions that must be called with the mutation lock of the block held ----
+
+	/// Append a block to the current container for its prefix in directory `dir`.
+	/// If the block was already packed, it is moved and its previous location
+	/// is returned.
+	pub(crate) async fn append(
+		&self,
+		hash: &Hash,
+		data: &DataBlock,
+		dir: &Path,
+		fsync: bool,
+	) -> Result<Option<PackedBlock>, Error> {
+		let (header, bytes) = data.as_parts_ref();
+		let len = bytes.len() as u64;
+
+		// Blocks are appended to the current container of their prefix
+		// only if it is in the given directory
+		let (id, container) = match self.current_container(hash)? {
+			Some((id, c))
+				if c.size + len <= PACK_MAX_SIZE
+					&& fs::metadata(dir.join(container_file_name(id)))
+						.await
+						.is_ok() =>
+			{
+				(id, c)
+			}
+			_ => {
+				let id = self.new_container_id(hash)?;
+				let container = PackContainer {
+					path: container_dir(hash).join(container_file_name(id)),
+					size: 0,
+					live_bytes: 0,
+					live_blocks: 0,
+				};
+				(id, container)
+			}
+		};
+		let path = dir.join(container_file_name(id));
+
+		fs::create_dir_all(dir).await?;
+		let is_new = fs::metadata(&path).await.is_err();
+
+		let mut f = fs::OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(&path)
+			.await?;
+		// If a previous append was interrupted, its data is dead space
+		let offset = f.metadata().await?.len();
+		f.write_all(bytes).await?;
+		if fsync {
+			f.sync_all().await?;
+		}
+		drop(f);
+
+		if fsync && is_new {
+			// Make sure the creation of the container is persisted,
+			// as done for the files of unpacked blocks
+			let dir = fs::OpenOptions::new().read(true).mode(0).open(dir).await?;
+			dir.sync_all().await?;
+		}
+
+		let block = PackedBlock {
+			container: id,
+			offset,
+			len,
+			header,
+		};
+		let previous = self.index.db().transaction(|tx| {
+			let previous = tx.get(&self.index, hash)?.map(|v| PackedBlock::decode(&v));
+			if let Some(prev) = &previous {
+				self.unlink(tx, hash, prev)?;
+			}
+
+			let mut c = match tx.get(&self.containers, id.to_be_bytes())? {
+				Some(v) => PackContainer::decode(&v),
+				None => container.clone(),
+			};
+			c.size = offset + len;
+			c.live_bytes += len;
+			c.live_blocks += 1;
+			tx.insert(&self.containers, id.to_be_bytes(), c.encode())?;
+			tx.insert(&self.contents, contents_key(id, hash), vec![])?;
+			tx.insert(&self.index, hash, block.encode())?;
+
+			Ok(previous)
+		})?;
+
+		Ok(previous)
+	}
+
+	/// Remove a block from the index. Its data is left as dead space
+	/// in its container. Returns the location of the block, if it was packed.
+	pub(crate) fn remove(&self, hash: &Hash) -> Result<Option<PackedBlock>, Error> {
+		let removed = self.index.db().transaction(|tx| {
+			let block = tx.get(&self.index, hash)?.map(|v| PackedBlock::decode(&v));
+			if let Some(block) = &block {
+				tx.remove(&self.index, hash)?;
+				self.unlink(tx, hash, block)?;
+			}
+			Ok(block)
+		})?;
+		Ok(removed)
+	}
+
+	/// After a block was removed from a container, delete the container if
+	/// it is empty, or compact it if it has too much dead space (unless it is
+	/// the current container of its prefix, to which blocks are still being
+	/// appended). Returns the blocks that were found corrupted during
+	/// the compaction, which have been dropped.
+	pub(crate) async fn cleanup(
+		&self,
+		hash: &Hash,
+		removed: &PackedBlock,
+		dir: &Path,
+		layout: &DataLayout,
+		fsync: bool,
+	) -> Result<Vec<Hash>, Error> {
+		let id = removed.container;
+		let container = match self.container(id)? {
+			Some(c) => c,
+			None => return Ok(vec![]),
+		};
+
+		if container.live_blocks == 0 {
+			self.delete_container(id, &container, layout).await?;
+			return Ok(vec![]);
+		}
+
+		let is_current = matches!(self.current_container(hash)?, Some((cur, _)) if cur == id);
+		if is_current || container.dead_ratio() <= self.max_dead_ratio {
+			return Ok(vec![]);
+		}
+
+		info!(
+			"Compacting block container {} ({} live blocks, {:.0}% dead space)",
+			container.path.display(),
+			container.live_blocks,
+			container.dead_ratio() * 100.
+		);
+		let mut corrupted = vec![];
+		for block_hash in self.container_blocks(id)? {
+			let block = match self.get(&block_hash)? {
+				Some(b) if b.container == id => b,
+				_ => continue,
+			};
+			let data = self.read(&block, layout).await?;
+			if data.verify(block_hash).is_err() {
+				warn!(
+					"Block {:?} is corrupted in container {}, dropping it",
+					block_hash,
+					container.path.display()
+				);
+				self.remove(&block_hash)?;
+				corrupted.push(block_hash);
+				continue;
+			}
+			self.append(&block_hash, &data, dir, fsync).await?;
+		}
+		self.delete_container(id, &container, layout).await?;
+
+		Ok(corrupted)
+	}
+
+	async fn delete_container(
+		&self,
+		id: u64,
+		container: &PackContainer,
+		layout: &DataLayout,
+	) -> Result<(), Error> {
+		let blocks = self.container_blocks(id)?;
+		self.containers.db().transaction(|tx| {
+			tx.remove(&self.containers, id.to_be_bytes())?;
+			for block_hash in blocks.iter() {
+				tx.remove(&self.contents, contents_key(id, block_hash))?;
+			}
+			Ok(())
+		})?;
+		for data_dir in layout.data_dirs.iter() {
+			match fs::remove_file(data_dir.path.join(&container.path)).await {
+				Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+				_ => (),
+			}
+		}
+		Ok(())
+	}
+
+	/// Remove a block from the list of blocks of its container
+	fn unlink(
+		&self,
+		tx: &mut db::Transaction,
+		hash: &Hash,
+		block: &PackedBlock,
+	) -> db::TxOpResult<()> {
+		tx.remove(&self.contents, contents_key(block.container, hash))?;
+		if let Some(v) = tx.get(&self.containers, block.container.to_be_bytes())? {
+			let mut c = PackContainer::decode(&v);
+			c.live_bytes = c.live_bytes.saturating_sub(block.len);
+			c.live_blocks = c.live_blocks.saturating_sub(1);
+			tx.insert(&self.containers, block.container.to_be_bytes(), c.encode())?;
+		}
+		Ok(())
+	}
+}
+
+/// Open a container file, which can be in any of the data directories
+async fn open_container(
+	container: &PackContainer,
+	layout: &DataLayout,
+) -> Result<fs::File, io::Error> {
+	for data_dir in layout.data_dirs.iter() {
+		match fs::File::open(data_dir.path.join(&container.path)).await {
+			Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+			res => return res,
+		}
+	}
+	Err(io::ErrorKind::NotFound.into())
+}
+
+fn container_file_name(id: u64) -> String {
+	format!("{}{:016x}", PACK_FILE_PREFIX, id)
+}
+
+/// Directory of the containers of the blocks with the same prefix as
+/// `hash`, relative to their data directory
+fn container_dir(hash: &Hash) -> PathBuf {
+	PathBuf::from(hex::encode(&hash.as_slice()[0..1])).join(hex::encode(&hash.as_slice()[1..2]))
+}
+
+/// Whether a file of a data directory is a container file,
+/// and if so, the id of the container
+pub(crate) fn parse_container_file_name(name: &str) -> Option<u64> {
+	let id = name.strip_prefix(PACK_FILE_PREFIX)?;
+	if id.len() != 16 {
+		return None;
+	}
+	u64::from_str_radix(id, 16).ok()
+}
+
+/// Ids of the containers of the blocks with the same prefix as `hash`
+fn container_id_range(hash: &Hash) -> (u64, u64) {
+	let prefix = u16::from_be_bytes([hash.as_slice()[0], hash.as_slice()[1]]) as u64;
+	(prefix << 32, (prefix << 32) | 0xFFFF_FFFF)
+}
+
+fn contents_key(id: u64, hash: &Hash) -> Vec<u8> {
+	[&id.to_be_bytes()[..], hash.as_slice()].concat()
+}
+
+impl PackedBlock {
+	fn decode(data: &[u8]) -> Self {
+		Self {
+			container: u64::from_be_bytes(data[0..8].try_into().unwrap()),
+			offset: u64::from_be_bytes(data[8..16].try_into().unwrap()),
+			len: u64::from_be_bytes(data[16..24].try_into().unwrap()),
+			header: if data[24] == 1 {
+				DataBlockHeader::Compressed
+			} else {
+				DataBlockHeader::Plain
+			},
+		}
+	}
+
+	fn encode(&self) -> Vec<u8> {
+		[
+			&u64::to_be_bytes(self.container)[..],
+			&u64::to_be_bytes(self.offset)[..],
+			&u64::to_be_bytes(self.len)[..],
+			&[self.header.is_compressed() as u8][..],
+		]
+		.concat()
+	}
+}
+
+impl PackContainer {
+	/// Fraction of the container occupied by blocks that are not used anymore
+	pub(crate) fn dead_ratio(&self) -> f64 {
+		if self.size == 0 {
+			0.0
+		} else {
+			self.size.saturating_sub(self.live_bytes) as f64 / self.size as f64
+		}
+	}
+
+	fn decode(data: &[u8]) -> Self {
+		Self {
+			size: u64::from_be_bytes(data[0..8].try_into().unwrap()),
+			live_bytes: u64::from_be_bytes(data[8..16].try_into().unwrap()),
+			live_blocks: u64::from_be_bytes(data[16..24].try_into().unwrap()),
+			path: PathBuf::from(OsStr::from_bytes(&data[24..])),
+		}
+	}
+
+	fn encode(&self) -> Vec<u8> {
+		[
+			&u64::to_be_bytes(self.size)[..],
+			&u64::to_be_bytes(self.live_bytes)[..],
+			&u64::to_be_bytes(self.live_blocks)[..],
+			self.path.as_os_str().as_bytes(),
+		]
+		.concat()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use rand::Rng;
+
+	use garage_util::config::{DataDir as DataDirConfig, DataDirEnum};
+
+	use super::*;
+
+	/// A pack store in a temporary directory, with two data directories
+	struct TestStore {
+		store: PackStore,
+		layout: DataLayout,
+		data_dirs: [PathBuf; 2],
+	}
+
+	impl TestStore {
+		fn new(max_dead_ratio: f64) -> Self {
+			let root = std::env::temp_dir().join(format!(
+				"garage-pack-test-{}",
+				hex::encode(rand::thread_rng().gen::<[u8; 8]>())
+			));
+			let data_dirs = [root.join("data1"), root.join("data2")];
+			for dir in data_dirs.iter() {
+				std::fs::create_dir_all(dir).unwrap();
+			}
+			let db = db::open_db(
+				&root.join("db.sqlite"),
+				db::Engine::Sqlite,
+				&Default::default(),
+			)
+			.unwrap();
+			let layout = DataLayout::initialize(&DataDirEnum::Multiple(
+				data_dirs
+					.iter()
+					.map(|path| DataDirConfig {
+						path: path.clone(),
+						capacity: Some("1G".into()),
+						read_only: false,
+					})
+					.collect(),
+			))
+			.unwrap();
+			Self {
+				store: PackStore::new(&db, Some(1024), max_dead_ratio),
+				layout,
+				data_dirs,
+			}
+		}
+
+		/// Directory of the containers of a block in the i-th data directory
+		fn dir(&self, i: usize, hash: &Hash) -> PathBuf {
+			self.data_dirs[i].join(container_dir(hash))
+		}
+
+		async fn append(&self, i: usize, (hash, data): &(Hash, DataBlock)) -> Option<PackedBlock> {
+			self.store
+				.append(hash, data, &self.dir(i, hash), false)
+				.await
+				.unwrap()
+		}
+
+		async fn read(&self, hash: &Hash) -> DataBlock {
+			let block = self.store.get(hash).unwrap().unwrap();
+			self.store.read(&block, &self.layout).await.unwrap()
+		}
+
+		/// Remove a block and clean up its container, as done when
+		/// a block is deleted, with `i` the primary data directory
+		async fn delete(&self, i: usize, hash: &Hash) -> Vec<Hash> {
+			let removed = self.store.remove(hash).unwrap().unwrap();
+			self.store
+				.cleanup(hash, &removed, &self.dir(i, hash), &self.layout, false)
+				.await
+				.unwrap()
+		}
+	}
+
+	/// Blocks whose hashes start with the same two bytes,
+	/// which are packed in the same containers
+	fn blocks(n: usize) -> Vec<(Hash, DataBlock)> {
+		let mut ret: Vec<(Hash, DataBlock)> = vec![];
+		for i in 0u64.. {
+			if ret.len() == n {
+				break;
+			}
+			let data = [&i.to_le_bytes()[..], &[0x42; 92][..]].concat();
+			let hash = blake2sum(&data);
+			if ret
+				.first()
+				.map_or(true, |(h, _)| h.as_slice()[..2] == hash.as_slice()[..2])
+			{
+				ret.push((hash, DataBlock::plain(data.into())));
+			}
+		}
+		ret
+	}
+
+	fn bytes(block: &DataBlock) -> &[u8] {
+		&block.as_parts_ref().1[..]
+	}
+
+	#[tokio::test]
+	async fn test_append_read_remove() {
+		let t = TestStore::new(0.5);
+		let blocks = blocks(3);
+		for b in blocks.iter() {
+			assert_eq!(t.append(0, b).await, None);
+		}
+
+		let first = t.store.get(&blocks[0].0).unwrap().unwrap();
+		let container = t.store.container(first.container).unwrap().unwrap();
+		assert_eq!(container.live_blocks, 3);
+		assert_eq!(container.size, 300);
+		assert!(container.path.is_relative());
+		assert!(t.data_dirs[0].join(&container.path).is_file());
+		assert_eq!(t.store.container_blocks(first.container).unwrap().len(), 3);
+		for (hash, data) in blocks.iter() {
+			let read = t.read(hash).await;
+			assert_eq!(bytes(&read), bytes(data));
+			read.verify(*hash).unwrap();
+		}
+
+		// Writing a block again moves it, leaving dead space
+		let previous = t.append(0, &blocks[0]).await;
+		assert_eq!(previous, Some(first));
+		let container = t.store.container(first.container).unwrap().unwrap();
+		assert_eq!(container.live_blocks, 3);
+		assert_eq!(container.size, 400);
+		assert_eq!(bytes(&t.read(&blocks[0].0).await), bytes(&blocks[0].1));
+
+		let removed = t.store.remove(&blocks[1].0).unwrap();
+		assert!(removed.is_some());
+		assert!(!t.store.contains(&blocks[1].0).unwrap());
+		assert_eq!(t.store.remove(&blocks[1].0).unwrap(), None);
+		let container = t.store.container(first.container).unwrap().unwrap();
+		assert_eq!(container.live_blocks, 2);
+		assert_eq!(container.live_bytes, 200);
+		assert_eq!(bytes(&t.read(&blocks[2].0).await), bytes(&blocks[2].1));
+	}
+
+	#[tokio::test]
+	async fn test_container_ids_are_not_reused() {
+		let t = TestStore::new(0.5);
+		let blocks = blocks(2);
+
+		t.append(0, &blocks[0]).await;
+		let id = t.store.get(&blocks[0].0).unwrap().unwrap().container;
+		let path = t.dir(0, &blocks[0].0).join(container_file_name(id));
+		assert!(path.is_file());
+
+		// The container is deleted with its last block
+		assert!(t.delete(0, &blocks[0].0).await.is_empty());
+		assert_eq!(t.store.container(id).unwrap(), None);
+		assert!(!path.exists());
+
+		t.append(0, &blocks[1]).await;
+		let new_id = t.store.get(&blocks[1].0).unwrap().unwrap().container;
+		assert_eq!(new_id, id + 1);
+	}
+
+	#[tokio::test]
+	async fn test_compaction_and_rebalance() {
+		let t = TestStore::new(0.5);
+		let blocks = blocks(5);
+		for b in blocks[..4].iter() {
+			t.append(0, b).await;
+		}
+		let old_id = t.store.get(&blocks[0].0).unwrap().unwrap().container;
+
+		// The primary location of the blocks is now the second data
+		// directory: blocks are written to a new container there
+		t.append(1, &blocks[4]).await;
+		let new_id = t.store.get(&blocks[4].0).unwrap().unwrap().container;
+		assert!(new_id > old_id);
+
+		// A block moved by the rebalance is read from the new container,
+		// the old one is kept while it has live blocks
+		assert!(t.append(1, &blocks[3]).await.is_some());
+		assert_eq!(
+			t.store.get(&blocks[3].0).unwrap().unwrap().container,
+			new_id
+		);
+		assert_eq!(bytes(&t.read(&blocks[3].0).await), bytes(&blocks[3].1));
+		assert!(t.store.container(old_id).unwrap().is_some());
+
+		// Deleting a block leaves 50% of dead space, not compacted yet
+		assert!(t.delete(1, &blocks[0].0).await.is_empty());
+		assert_eq!(t.store.container(old_id).unwrap().unwrap().live_blocks, 2);
+
+		// Above, the remaining blocks are moved to the current container
+		assert!(t.delete(1, &blocks[1].0).await.is_empty());
+		assert_eq!(t.store.container(old_id).unwrap(), None);
+		assert!(!t
+			.dir(0, &blocks[0].0)
+			.join(container_file_name(old_id))
+			.exists());
+		let moved = t.store.get(&blocks[2].0).unwrap().unwrap();
+		assert_eq!(moved.container, new_id);
+		assert_eq!(bytes(&t.read(&blocks[2].0).await), bytes(&blocks[2].1));
+		assert_eq!(t.store.container(new_id).unwrap().unwrap().live_blocks, 3);
+	}
+
+	#[tokio::test]
+	async fn test_corrupted_container() {
+		let t = TestStore::new(0.5);
+		let blocks = blocks(4);
+		for b in blocks[..3].iter() {
+			t.append(0, b).await;
+		}
+		t.append(1, &blocks[3]).await;
+
+		// Corrupt the second block in its container
+		let corrupted = t.store.get(&blocks[1].0).unwrap().unwrap();
+		let container = t.store.container(corrupted.container).unwrap().unwrap();
+		let path = t.data_dirs[0].join(&container.path);
+		let mut data = std::fs::read(&path).unwrap();
+		data[corrupted.offset as usize + 10] ^= 1;
+		std::fs::write(&path, data).unwrap();
+
+		// It is detected when the block is read and verified, as done by
+		// the scrub, and the other blocks can still be read
+		assert!(t.read(&blocks[1].0).await.verify(blocks[1].0).is_err());
+		t.read(&blocks[2].0).await.verify(blocks[2].0).unwrap();
+
+		// When the container is compacted, the corrupted block is dropped
+		// so that it is fetched again from other nodes
+		assert!(t.delete(0, &blocks[0].0).await.is_empty());
+		assert_eq!(t.delete(0, &blocks[2].0).await, vec![blocks[1].0]);
+		assert!(!t.store.contains(&blocks[1].0).unwrap());
+		assert_eq!(t.store.container(corrupted.container).unwrap(), None);
+		assert!(!path.exists());
+	}
+
+	#[test]
+	fn test_encoding() {
+		let block = PackedBlock {
+			container: 0x1234_0000_0002,
+			offset: 4096,
+			len: 1000,
+			header: DataBlockHeader::Compressed,
+		};
+		let decoded = PackedBlock::decode(&block.encode());
+		assert_eq!(decoded, block);
+		assert!(decoded.header.is_compressed());
+
+		let container = PackContainer {
+			path: PathBuf::from("12/34/pack-0000123400000002"),
+			size: 5096,
+			live_bytes: 1000,
+			live_blocks: 1,
+		};
+		assert_eq!(PackContainer::decode(&container.encode()), container);
+	}
+
+	#[test]
+	fn test_container_ids() {
+		let mut hash = [0u8; 32];
+		hash[0] = 0x12;
+		hash[1] = 0x34;
+		hash[2] = 0xff;
+		let (first, last) = container_id_range(&hash.into());
+		assert_eq!(first, 0x1234_0000_0000);
+		assert_eq!(last, 0x1234_ffff_ffff);
+
+		assert_eq!(
+			parse_container_file_name(&format!("{}{:016x}", PACK_FILE_PREFIX, first + 3)),
+			Some(first + 3)
+		);
+		assert_eq!(parse_container_file_name("pack-1234"), None);
+		assert_eq!(parse_container_file_name(&hex::encode(hash)), None);
+	}
+
+	#[test]
+	fn test_dead_ratio() {
+		let mut container = PackContainer {
+			path: PathBuf::from("pack-0000000000000000"),
+			size: 0,
+			live_bytes: 0,
+			live_blocks: 0,
+		};
+		assert_eq!(container.dead_ratio(), 0.0);
+
+		container.size = 4000;
+		container.live_bytes = 1000;
+		assert_eq!(container.dead_ratio(), 0.75);
+
+		// Data of an interrupted append is not counted
+		container.live_bytes = 4000;
+		assert_eq!(container.dead_ratio(), 0.0);
+	}
+}
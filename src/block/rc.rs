@@ -15,30 +15,62 @@ pub type CalculateRefcount =
 
 pub struct BlockRc {
 	pub rc_table: db::Tree,
+	/// Number of copies of blocks that are stored with less copies than the
+	/// cluster's replication factor (blocks that are not in this table
+	/// are stored with the cluster's replication factor)
+	pub(crate) replication_factor_table: db::Tree,
 	pub(crate) recalc_rc: ArcSwapOption<Vec<CalculateRefcount>>,
 }
 
 impl BlockRc {
-	pub(crate) fn new(rc: db::Tree) -> Self {
+	pub(crate) fn new(rc: db::Tree, replication_factor: db::Tree) -> Self {
 		Self {
 			rc_table: rc,
+			replication_factor_table: replication_factor,
 			recalc_rc: ArcSwapOption::new(None),
 		}
 	}
 
-	/// Increment the reference counter associated to a hash.
-	/// Returns true if the RC goes from zero to nonzero.
+	/// Increment the reference counter associated to a hash,
+	/// for a reference that requires `replication_factor` copies of the block
+	/// (None meaning the cluster's replication factor).
+	/// Returns true if the RC goes from zero to nonzero, or if the block
+	/// now requires more copies than before.
 	pub(crate) fn block_incref(
 		&self,
 		tx: &mut db::Transaction,
 		hash: &Hash,
+		replication_factor: Option<usize>,
 	) -> db::TxOpResult<bool> {
 		let old_rc = RcEntry::parse_opt(tx.get(&self.rc_table, hash)?);
 		match old_rc.increment().serialize() {
 			Some(x) => tx.insert(&self.rc_table, hash, x)?,
 			None => unreachable!(),
 		};
-		Ok(old_rc.is_zero())
+
+		// The block is stored with the number of copies required by
+		// the reference that requires the most copies
+		let old_rf = tx
+			.get(&self.replication_factor_table, hash)?
+			.map(|x| parse_replication_factor(x.as_ref()));
+		let new_rf = if old_rc.is_zero() {
+			replication_factor
+		} else {
+			match (old_rf, replication_factor) {
+				(Some(a), Some(b)) => Some(std::cmp::max(a, b)),
+				_ => None,
+			}
+		};
+		match new_rf {
+			Some(rf) => tx.insert(
+				&self.replication_factor_table,
+				hash,
+				u64::to_be_bytes(rf as u64),
+			)?,
+			None => tx.remove(&self.replication_factor_table, hash)?,
+		};
+
+		Ok(old_rc.is_zero() || new_rf != old_rf)
 	}
 
 	/// Decrement the reference counter associated to a hash.
@@ -53,6 +85,9 @@ impl BlockRc {
 			Some(x) => tx.insert(&self.rc_table, hash, x)?,
 			None => tx.remove(&self.rc_table, hash)?,
 		};
+		if new_rc.is_zero() {
+			tx.remove(&self.replication_factor_table, hash)?;
+		}
 		Ok(matches!(new_rc, RcEntry::Deletable { .. }))
 	}
 
@@ -61,6 +96,15 @@ impl BlockRc {
 		Ok(RcEntry::parse_opt(self.rc_table.get(hash.as_ref())?))
 	}
 
+	/// Read the number of copies with which a block is stored,
+	/// if it is less than the cluster's replication factor
+	pub(crate) fn get_block_replication_factor(&self, hash: &Hash) -> Result<Option<usize>, Error> {
+		Ok(self
+			.replication_factor_table
+			.get(hash.as_ref())?
+			.map(|x| parse_replication_factor(x.as_ref())))
+	}
+
 	/// Delete an entry in the RC table if it is deletable and the
 	/// deletion time has passed
 	pub(crate) fn clear_deleted_block_rc(&self, hash: &Hash) -> Result<(), Error> {
@@ -131,6 +175,16 @@ impl BlockRc {
 	}
 }
 
+fn parse_replication_factor(bytes: &[u8]) -> usize {
+	match bytes.try_into() {
+		Ok(x) => u64::from_be_bytes(x) as usize,
+		Err(_) => panic!(
+			"Invalid block replication factor entry: {:?}, database is corrupted.",
+			bytes
+		),
+	}
+}
+
 /// Describes the state of the reference counter for a block
 #[derive(Clone, Copy, Debug)]
 pub(crate) enum RcEntry {
@@ -1,9 +1,10 @@
 use core::ops::Bound;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
+use futures::future::join_all;
 use rand::Rng;
 use tokio::fs;
 use tokio::select;
@@ -19,6 +20,7 @@ use garage_util::tranquilizer::Tranquilizer;
 
 use crate::block::*;
 use crate::manager::*;
+use crate::pack::*;
 
 // Full scrub every 25 days with a random element of 10 days mixed in below
 const SCRUB_INTERVAL: Duration = Duration::from_secs(3600 * 24 * 25);
@@ -32,10 +34,126 @@ const INITIAL_SCRUB_TRANQUILITY: u32 = 4;
 // checks everything, and then exits.
 // ---- ---- ----
 
+// Number of top-level directories of the data store that are scanned
+// concurrently in phase 2
+const REPAIR_SCAN_CONCURRENCY: usize = 8;
+// Interval at which the progress of phase 2 is logged
+const REPAIR_PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+mod v011 {
+	use serde::{Deserialize, Serialize};
+	use std::path::PathBuf;
+
+	/// Progress of the scan of the data directories by the repair worker,
+	/// saved after each batch of directories so that an interrupted scan
+	/// can be resumed
+	#[derive(Serialize, Deserialize, Clone, Default)]
+	pub struct RepairCheckpoint {
+		/// Top-level directories of the data store that remain to be scanned
+		pub(crate) todo: Vec<PathBuf>,
+		pub(crate) total_dirs: usize,
+		pub(crate) blocks_seen: u64,
+	}
+
+	impl garage_util::migrate::InitialFormat for RepairCheckpoint {
+		const VERSION_MARKER: &'static [u8] = b"G011rpck";
+	}
+}
+
+pub use v011::*;
+
+impl RepairCheckpoint {
+	/// List the top-level directories of the given data directories
+	async fn new(data_dirs: &[PathBuf]) -> Result<Self, Error> {
+		let mut todo = vec![];
+		for dir in data_dirs {
+			let mut reader = fs::read_dir(dir).await?;
+			while let Some(ent) = reader.next_entry().await? {
+				let is_hex = ent
+					.file_name()
+					.to_str()
+					.map(|n| hex::decode(n).is_ok())
+					.unwrap_or(false);
+				if is_hex && ent.file_type().await?.is_dir() {
+					todo.push(ent.path());
+				}
+			}
+		}
+		// directories are taken from the end of the list,
+		// reverse it to scan them in order
+		todo.sort();
+		todo.reverse();
+
+		Ok(Self {
+			total_dirs: todo.len(),
+			todo,
+			blocks_seen: 0,
+		})
+	}
+
+	/// Take the next directories to scan concurrently
+	fn next_batch(&mut self) -> Vec<PathBuf> {
+		let at = self.todo.len().saturating_sub(REPAIR_SCAN_CONCURRENCY);
+		self.todo.split_off(at)
+	}
+
+	fn dirs_done(&self) -> usize {
+		self.total_dirs.saturating_sub(self.todo.len())
+	}
+
+	fn describe(&self) -> String {
+		format!(
+			"{} of {} directories scanned, {} blocks seen",
+			self.dirs_done(),
+			self.total_dirs,
+			self.blocks_seen
+		)
+	}
+}
+
+/// Blocks stored as files and pack containers found in
+/// a directory of the data store and its subdirectories
+#[derive(Default, Debug)]
+struct DirScan {
+	blocks: Vec<Hash>,
+	containers: Vec<u64>,
+}
+
+async fn scan_data_dir(path: &Path) -> Result<DirScan, Error> {
+	let mut scan = DirScan::default();
+	let mut todo = vec![path.to_path_buf()];
+	while let Some(dir) = todo.pop() {
+		let mut reader = fs::read_dir(&dir).await?;
+		while let Some(ent) = reader.next_entry().await? {
+			let name = match ent.file_name().into_string() {
+				Ok(n) => n,
+				Err(_) => continue,
+			};
+			let ft = ent.file_type().await?;
+			if ft.is_dir() && hex::decode(&name).is_ok() {
+				todo.push(ent.path());
+			} else if !ft.is_file() {
+				continue;
+			} else if let Some(id) = parse_container_file_name(&name) {
+				scan.containers.push(id);
+			} else {
+				let filename = name.split_once('.').map(|(f, _)| f).unwrap_or(&name);
+				if filename.len() == 64 {
+					if let Ok(h) = hex::decode(filename) {
+						scan.blocks.push(Hash::try_from(&h[..]).unwrap());
+					}
+				}
+			}
+		}
+	}
+	Ok(scan)
+}
+
 pub struct RepairWorker {
 	manager: Arc<BlockManager>,
 	next_start: Option<Hash>,
-	block_iter: Option<BlockStoreIterator>,
+	checkpoint: Option<RepairCheckpoint>,
+	last_progress_log: Instant,
 }
 
 impl RepairWorker {
@@ -43,9 +161,86 @@ impl RepairWorker {
 		Self {
 			manager,
 			next_start: None,
-			block_iter: None,
+			checkpoint: None,
+			last_progress_log: Instant::now(),
 		}
 	}
+
+	/// Resume the scan of the data directories from a saved checkpoint,
+	/// skipping phase 1
+	pub(crate) fn resume(manager: Arc<BlockManager>, checkpoint: RepairCheckpoint) -> Self {
+		Self {
+			manager,
+			next_start: None,
+			checkpoint: Some(checkpoint),
+			last_progress_log: Instant::now(),
+		}
+	}
+
+	/// Scan a batch of directories concurrently, and add the blocks
+	/// they contain to the resync queue
+	async fn scan_batch(&mut self) -> Result<WorkerState, Error> {
+		let checkpoint = self.checkpoint.as_mut().unwrap();
+		let batch = checkpoint.next_batch();
+		if batch.is_empty() {
+			info!("Block repair finished: {}", checkpoint.describe());
+			self.manager
+				.repair_checkpoint
+				.save_async(checkpoint)
+				.await?;
+			return Ok(WorkerState::Done);
+		}
+
+		let scans = join_all(batch.iter().map(|dir| scan_data_dir(dir))).await;
+
+		let mut ret = Ok(WorkerState::Busy);
+		for (dir, scan) in batch.into_iter().zip(scans) {
+			let res = match scan {
+				Ok(scan) => self.queue_scanned_blocks(scan),
+				Err(e) => Err(e),
+			};
+			let checkpoint = self.checkpoint.as_mut().unwrap();
+			match res {
+				Ok(n) => checkpoint.blocks_seen += n,
+				Err(e) => {
+					// scan the directory again at the next try
+					checkpoint.todo.push(dir);
+					ret = Err(e);
+				}
+			}
+		}
+
+		let checkpoint = self.checkpoint.as_ref().unwrap();
+		self.manager
+			.repair_checkpoint
+			.save_async(checkpoint)
+			.await?;
+		if self.last_progress_log.elapsed() >= REPAIR_PROGRESS_LOG_INTERVAL {
+			info!("Block repair in progress: {}", checkpoint.describe());
+			self.last_progress_log = Instant::now();
+		}
+
+		ret
+	}
+
+	fn queue_scanned_blocks(&self, scan: DirScan) -> Result<u64, Error> {
+		let mut n = 0;
+		for hash in scan.blocks {
+			self.manager
+				.resync
+				.put_to_resync(&hash, Duration::from_secs(0))?;
+			n += 1;
+		}
+		for id in scan.containers {
+			for hash in self.manager.pack.container_blocks(id)? {
+				self.manager
+					.resync
+					.put_to_resync(&hash, Duration::from_secs(0))?;
+				n += 1;
+			}
+		}
+		Ok(n)
+	}
 }
 
 #[async_trait]
@@ -55,7 +250,7 @@ impl Worker for RepairWorker {
 	}
 
 	fn status(&self) -> WorkerStatus {
-		match self.block_iter.as_ref() {
+		match self.checkpoint.as_ref() {
 			None => {
 				let idx_bytes = self
 					.next_start
@@ -76,77 +271,88 @@ impl Worker for RepairWorker {
 					..Default::default()
 				}
 			}
-			Some(bi) => WorkerStatus {
-				progress: Some(format!("{:.2}%", bi.progress() * 100.)),
-				freeform: vec!["Currently in phase 2".into()],
+			Some(cp) => WorkerStatus {
+				progress: Some(format!(
+					"{:.2}%",
+					cp.dirs_done() as f32 * 100. / std::cmp::max(cp.total_dirs, 1) as f32
+				)),
+				freeform: vec![format!("Currently in phase 2: {}", cp.describe())],
 				..Default::default()
 			},
 		}
 	}
 
 	async fn work(&mut self, _must_exit: &mut watch::Receiver<bool>) -> Result<WorkerState, Error> {
-		match self.block_iter.as_mut() {
-			None => {
-				// Phase 1: Repair blocks from RC table.
-
-				// We have to do this complicated two-step process where we first read a bunch
-				// of hashes from the RC table, and then insert them in the to-resync queue,
-				// because of SQLite. Basically, as long as we have an iterator on a DB table,
-				// we can't do anything else on the DB. The naive approach (which we had previously)
-				// of just iterating on the RC table and inserting items one to one in the resync
-				// queue can't work here, it would just provoke a deadlock in the SQLite adapter code.
-				// This is mostly because the Rust bindings for SQLite assume a worst-case scenario
-				// where SQLite is not compiled in thread-safe mode, so we have to wrap everything
-				// in a mutex (see db/sqlite_adapter.rs and discussion in PR #322).
-				// TODO: maybe do this with tokio::task::spawn_blocking ?
-				let mut batch_of_hashes = vec![];
-				let start_bound = match self.next_start.as_ref() {
-					None => Bound::Unbounded,
-					Some(x) => Bound::Excluded(x.as_slice()),
-				};
-				for entry in self
-					.manager
-					.rc
-					.rc_table
-					.range::<&[u8], _>((start_bound, Bound::Unbounded))?
-				{
-					let (hash, _) = entry?;
-					let hash = Hash::try_from(&hash[..]).unwrap();
-					batch_of_hashes.push(hash);
-					if batch_of_hashes.len() >= 1000 {
-						break;
-					}
-				}
-				if batch_of_hashes.is_empty() {
-					// move on to phase 2
-					self.block_iter = Some(BlockStoreIterator::new(&self.manager));
-					return Ok(WorkerState::Busy);
-				}
-
-				for hash in batch_of_hashes.into_iter() {
-					self.manager
-						.resync
-						.put_to_resync(&hash, Duration::from_secs(0))?;
-					self.next_start = Some(hash)
-				}
+		if self.checkpoint.is_some() {
+			// Phase 2: Repair blocks actually on disk
+			// Lists all blocks on disk and adds them to the resync queue.
+			// This allows us to find blocks we are storing but don't actually need,
+			// so that we can offload them if necessary and then delete them locally.
+			// The top-level directories of the data dirs are scanned concurrently.
+			return self.scan_batch().await;
+		}
 
-				Ok(WorkerState::Busy)
-			}
-			Some(bi) => {
-				// Phase 2: Repair blocks actually on disk
-				// Lists all blocks on disk and adds them to the resync queue.
-				// This allows us to find blocks we are storing but don't actually need,
-				// so that we can offload them if necessary and then delete them locally.
-				if let Some((_path, hash)) = bi.next().await? {
-					self.manager
-						.resync
-						.put_to_resync(&hash, Duration::from_secs(0))?;
-					Ok(WorkerState::Busy)
-				} else {
-					Ok(WorkerState::Done)
-				}
+		// Phase 1: Repair blocks from RC table.
+
+		// We have to do this complicated two-step process where we first read a bunch
+		// of hashes from the RC table, and then insert them in the to-resync queue,
+		// because of SQLite. Basically, as long as we have an iterator on a DB table,
+		// we can't do anything else on the DB. The naive approach (which we had previously)
+		// of just iterating on the RC table and inserting items one to one in the resync
+		// queue can't work here, it would just provoke a deadlock in the SQLite adapter code.
+		// This is mostly because the Rust bindings for SQLite assume a worst-case scenario
+		// where SQLite is not compiled in thread-safe mode, so we have to wrap everything
+		// in a mutex (see db/sqlite_adapter.rs and discussion in PR #322).
+		// TODO: maybe do this with tokio::task::spawn_blocking ?
+		let mut batch_of_hashes = vec![];
+		let start_bound = match self.next_start.as_ref() {
+			None => Bound::Unbounded,
+			Some(x) => Bound::Excluded(x.as_slice()),
+		};
+		for entry in self
+			.manager
+			.rc
+			.rc_table
+			.range::<&[u8], _>((start_bound, Bound::Unbounded))?
+		{
+			let (hash, _) = entry?;
+			let hash = Hash::try_from(&hash[..]).unwrap();
+			batch_of_hashes.push(hash);
+			if batch_of_hashes.len() >= 1000 {
+				break;
 			}
 		}
+		if batch_of_hashes.is_empty() {
+			// move on to phase 2
+			let data_dirs = self
+				.manager
+				.data_layout
+				.load()
+				.data_dirs
+				.iter()
+				.map(|d| d.path.clone())
+				.collect::<Vec<_>>();
+			let checkpoint = RepairCheckpoint::new(&data_dirs).await?;
+			info!(
+				"Block repair: scanning {} directories of the data store",
+				checkpoint.total_dirs
+			);
+			self.manager
+				.repair_checkpoint
+				.save_async(&checkpoint)
+				.await?;
+			self.checkpoint = Some(checkpoint);
+			return Ok(WorkerState::Busy);
+		}
+
+		for hash in batch_of_hashes.into_iter() {
+			self.manager
+				.resync
+				.put_to_resync(&hash, Duration::from_secs(0))?;
+			self.next_start = Some(hash)
+		}
+
+		Ok(WorkerState::Busy)
 	}
 
 	async fn wait_for_work(&mut self) -> WorkerState {
@@ -447,7 +653,7 @@ impl Worker for ScrubWorker {
 				self.tranquilizer.reset();
 				let now = now_msec();
 
-				if let Some((_path, hash)) = iterator.next().await? {
+				if let Some((_path, hash)) = iterator.next(&self.manager).await? {
 					match self.manager.read_block(&hash).await {
 						Err(Error::CorruptData(_)) => {
 							error!("Found corrupt data block during scrub: {:?}", hash);
@@ -549,6 +755,18 @@ impl RebalanceWorker {
 			moved_bytes: 0,
 		}
 	}
+
+	/// Whether a block stored as a file of its own must be packed
+	/// (blocks written before packing was enabled are packed by rebalancing)
+	async fn should_pack_file(&self, path: &Path) -> bool {
+		if !self.manager.pack.is_enabled() {
+			return false;
+		}
+		match fs::metadata(path).await {
+			Ok(m) => self.manager.pack.should_pack(m.len()),
+			Err(_) => false,
+		}
+	}
 }
 
 #[async_trait]
@@ -580,9 +798,19 @@ impl Worker for RebalanceWorker {
 	}
 
 	async fn work(&mut self, _must_exit: &mut watch::Receiver<bool>) -> Result<WorkerState, Error> {
-		if let Some((path, hash)) = self.block_iter.next().await? {
+		if let Some((path, hash)) = self.block_iter.next(&self.manager).await? {
 			let prim_loc = self.manager.data_layout.load().primary_block_dir(&hash);
-			if path.ancestors().all(|x| x != prim_loc) {
+			let in_prim_loc = path.ancestors().any(|x| x == prim_loc);
+			if is_container_path(&path) {
+				if !in_prim_loc {
+					// block is packed in a container that is not in its
+					// primary location, move it to a container there
+					debug!("rebalance: repacking block {:?} => {:?}", hash, prim_loc);
+					let block_len = self.manager.repack_block(&hash).await?;
+					self.moved += 1;
+					self.moved_bytes += block_len as u64;
+				}
+			} else if !in_prim_loc || self.should_pack_file(&path).await {
 				let block_path = match path.extension() {
 					None => DataBlockPath::plain(path.clone()),
 					Some(x) if x.to_str() == Some("zst") => DataBlockPath::compressed(path.clone()),
@@ -591,8 +819,8 @@ impl Worker for RebalanceWorker {
 						return Ok(WorkerState::Busy);
 					}
 				};
-				// block is not in its primary location,
-				// move it there (reading and re-writing does the trick)
+				// block is not in its primary location, or is small enough
+				// to be packed: move it there (reading and re-writing does the trick)
 				debug!("rebalance: moving block {:?} => {:?}", block_path, prim_loc);
 				let block_len = self.manager.fix_block_location(&hash, block_path).await?;
 				self.moved += 1;
@@ -629,6 +857,13 @@ impl Worker for RebalanceWorker {
 // UTILITY FOR ENUMERATING THE BLOCK STORE
 // ---- ---- ----
 
+fn is_container_path(path: &Path) -> bool {
+	path.file_name()
+		.and_then(|n| n.to_str())
+		.and_then(parse_container_file_name)
+		.is_some()
+}
+
 const PROGRESS_FP: u64 = 1_000_000_000;
 
 impl BlockStoreIterator {
@@ -681,7 +916,9 @@ impl BlockStoreIterator {
 			.unwrap_or(1.0)
 	}
 
-	async fn next(&mut self) -> Result<Option<(PathBuf, Hash)>, Error> {
+	/// Returns the next block stored on disk, with the path of its file or of
+	/// the container in which it is packed
+	async fn next(&mut self, manager: &BlockManager) -> Result<Option<(PathBuf, Hash)>, Error> {
 		loop {
 			match self.todo.pop() {
 				None => return Ok(None),
@@ -706,6 +943,18 @@ impl BlockStoreIterator {
 								progress_min: 0,
 								progress_max: 0,
 							});
+						} else if let Some(id) = ft
+							.is_file()
+							.then(|| parse_container_file_name(&name))
+							.flatten()
+						{
+							for hash in manager.pack.container_blocks(id)? {
+								self.todo.push(BsiTodo::File {
+									path: ent.path(),
+									hash,
+									progress: 0,
+								});
+							}
 						} else if ft.is_file() {
 							let filename = name.split_once('.').map(|(f, _)| f).unwrap_or(&name);
 							if filename.len() == 64 {
@@ -762,3 +1011,92 @@ impl BlockStoreIterator {
 		iter.zip(iter_1).all(|(prev, next)| prev >= next)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use garage_util::migrate::Migrate;
+
+	use super::*;
+
+	/// Create a data directory with `n` blocks spread over
+	/// its xx/yy subdirectories, and a pack container
+	fn make_fixture(n: usize) -> (PathBuf, Vec<Hash>) {
+		let root = std::env::temp_dir().join(format!(
+			"garage-repair-scan-{}",
+			hex::encode(rand::thread_rng().gen::<[u8; 8]>())
+		));
+		let mut hashes = vec![];
+		for i in 0..n {
+			let hash = blake2sum(&i.to_le_bytes());
+			let dir = root
+				.join(hex::encode(&hash.as_slice()[..1]))
+				.join(hex::encode(&hash.as_slice()[1..2]));
+			std::fs::create_dir_all(&dir).unwrap();
+			let name = if i % 2 == 0 {
+				hex::encode(hash)
+			} else {
+				format!("{}.zst", hex::encode(hash))
+			};
+			std::fs::write(dir.join(name), b"x").unwrap();
+			hashes.push(hash);
+		}
+		let dir = root.join("00").join("00");
+		std::fs::create_dir_all(&dir).unwrap();
+		std::fs::write(dir.join(format!("{}{:016x}", PACK_FILE_PREFIX, 42)), b"").unwrap();
+		std::fs::write(dir.join("not-a-block"), b"").unwrap();
+		(root, hashes)
+	}
+
+	#[tokio::test]
+	async fn test_parallel_data_dir_scan() {
+		let (root, mut hashes) = make_fixture(4000);
+
+		let mut checkpoint = RepairCheckpoint::new(std::slice::from_ref(&root))
+			.await
+			.unwrap();
+		assert_eq!(checkpoint.total_dirs, 256);
+		assert_eq!(checkpoint.dirs_done(), 0);
+
+		let first = checkpoint.next_batch();
+		assert_eq!(first.len(), REPAIR_SCAN_CONCURRENCY);
+		assert_eq!(first[REPAIR_SCAN_CONCURRENCY - 1], root.join("00"));
+		assert_eq!(checkpoint.dirs_done(), REPAIR_SCAN_CONCURRENCY);
+
+		// An interrupted scan resumes where it stopped
+		let saved = checkpoint.encode().unwrap();
+		let mut checkpoint = RepairCheckpoint::decode(&saved).unwrap();
+		assert_eq!(checkpoint.dirs_done(), REPAIR_SCAN_CONCURRENCY);
+
+		let mut batches = vec![first];
+		loop {
+			let batch = checkpoint.next_batch();
+			if batch.is_empty() {
+				break;
+			}
+			assert!(batch.len() <= REPAIR_SCAN_CONCURRENCY);
+			batches.push(batch);
+		}
+		assert_eq!(checkpoint.dirs_done(), 256);
+
+		let mut parallel = DirScan::default();
+		for batch in batches.iter() {
+			for scan in join_all(batch.iter().map(|d| scan_data_dir(d))).await {
+				let scan = scan.unwrap();
+				parallel.blocks.extend(scan.blocks);
+				parallel.containers.extend(scan.containers);
+			}
+		}
+		let sequential = scan_data_dir(&root).await.unwrap();
+
+		std::fs::remove_dir_all(&root).unwrap();
+
+		hashes.sort();
+		parallel.blocks.sort();
+		let mut sequential_blocks = sequential.blocks;
+		sequential_blocks.sort();
+		assert_eq!(parallel.blocks, hashes);
+		assert_eq!(sequential_blocks, hashes);
+		assert_eq!(parallel.containers, vec![42]);
+		assert_eq!(sequential.containers, vec![42]);
+	}
+}
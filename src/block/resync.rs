@@ -3,7 +3,9 @@ use std::convert::TryInto;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+use arc_swap::ArcSwapOption;
 use async_trait::async_trait;
+use futures::future::BoxFuture;
 use serde::{Deserialize, Serialize};
 
 use tokio::select;
@@ -41,18 +43,51 @@ pub(crate) const RESYNC_RETRY_DELAY_MAX_BACKOFF_POWER: u64 = 6;
 
 // No more than 4 resync workers can be running in the system
 pub(crate) const MAX_RESYNC_WORKERS: usize = 8;
+
+// The delay after which deletion of a block that has a read hold
+// on it is attempted again
+pub(crate) const BLOCK_HOLD_RECHECK_DELAY: Duration = Duration::from_secs(60);
+
 // Resync tranquility is initially set to 2, but can be changed in the CLI
 // and the updated version is persisted over Garage restarts
 const INITIAL_RESYNC_TRANQUILITY: u32 = 2;
 
+/// Priority with which a block is resynced
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResyncPriority {
+	High,
+	Normal,
+}
+
+impl ResyncPriority {
+	pub(crate) fn name(&self) -> &'static str {
+		match self {
+			ResyncPriority::High => "high",
+			ResyncPriority::Normal => "normal",
+		}
+	}
+}
+
+/// Function that determines the priority with which a block is resynced
+/// (typically from the buckets of the objects that reference it)
+pub type ResolveResyncPriority =
+	Box<dyn Fn(Hash) -> BoxFuture<'static, Result<ResyncPriority, Error>> + Send + Sync>;
+
 pub struct BlockResyncManager {
+	/// Blocks queued for resync whose priority has not been determined yet
 	pub(crate) queue: db::Tree,
+	/// Blocks queued for resync with high priority
+	pub(crate) queue_high: db::Tree,
+	/// Blocks queued for resync with normal priority
+	pub(crate) queue_normal: db::Tree,
 	pub(crate) notify: Arc<Notify>,
 	pub(crate) errors: db::Tree,
 
 	busy_set: BusySet,
 
 	persister: PersisterShared<ResyncPersistedConfig>,
+
+	pub(crate) resolve_priority: ArcSwapOption<ResolveResyncPriority>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Copy)]
@@ -76,20 +111,72 @@ enum ResyncIterResult {
 	IdleFor(Duration),
 }
 
-type BusySet = Arc<Mutex<HashSet<Vec<u8>>>>;
+/// The queues in which blocks to resync are stored, in the order in which
+/// they are looked at when choosing the next block to resync
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ResyncQueue {
+	High,
+	Unclassified,
+	Normal,
+}
+
+const RESYNC_QUEUES: [ResyncQueue; 3] = [
+	ResyncQueue::High,
+	ResyncQueue::Unclassified,
+	ResyncQueue::Normal,
+];
+
+#[derive(Debug, PartialEq, Eq)]
+enum ResyncChoice {
+	Take(ResyncQueue),
+	IdleUntil(u64),
+	Empty,
+}
+
+/// Given the time at which the first block of each queue (in the order of
+/// `RESYNC_QUEUES`) is due, choose the queue from which a block is taken:
+/// the first one whose first block is due
+fn choose_resync_queue(heads: [Option<u64>; 3], now: u64) -> ResyncChoice {
+	for (queue, head) in RESYNC_QUEUES.iter().zip(heads.iter()) {
+		if matches!(head, Some(t) if *t <= now) {
+			return ResyncChoice::Take(*queue);
+		}
+	}
+	match heads.iter().flatten().min() {
+		Some(t) => ResyncChoice::IdleUntil(*t),
+		None => ResyncChoice::Empty,
+	}
+}
+
+type BusySet = Arc<Mutex<HashSet<(u8, Vec<u8>)>>>;
 
 struct BusyBlock {
+	queue: ResyncQueue,
 	time_bytes: Vec<u8>,
 	hash_bytes: Vec<u8>,
 	busy_set: BusySet,
 }
 
+impl BusyBlock {
+	fn time_msec(&self) -> u64 {
+		u64::from_be_bytes(self.time_bytes[0..8].try_into().unwrap())
+	}
+}
+
 impl BlockResyncManager {
 	pub(crate) fn new(db: &db::Db, system: &System) -> Self {
 		let queue = db
 			.open_tree("block_local_resync_queue")
 			.expect("Unable to open block_local_resync_queue tree");
 
+		let queue_high = db
+			.open_tree("block_local_resync_queue_high")
+			.expect("Unable to open block_local_resync_queue_high tree");
+
+		let queue_normal = db
+			.open_tree("block_local_resync_queue_normal")
+			.expect("Unable to open block_local_resync_queue_normal tree");
+
 		let errors = db
 			.open_tree("block_local_resync_errors")
 			.expect("Unable to open block_local_resync_errors tree");
@@ -98,21 +185,37 @@ impl BlockResyncManager {
 
 		Self {
 			queue,
+			queue_high,
+			queue_normal,
 			notify: Arc::new(Notify::new()),
 			errors,
 			busy_set: Arc::new(Mutex::new(HashSet::new())),
 			persister,
+			resolve_priority: ArcSwapOption::new(None),
 		}
 	}
 
 	/// Get lenght of resync queue
 	pub fn queue_len(&self) -> Result<usize, Error> {
-		Ok(self.queue.len()?)
+		Ok(self.queue.fast_len()? + self.queue_high.fast_len()? + self.queue_normal.fast_len()?)
+	}
+
+	/// Get number of blocks queued for resync with high priority
+	pub fn high_priority_queue_len(&self) -> Result<usize, Error> {
+		Ok(self.queue_high.fast_len()?)
+	}
+
+	fn queue_tree(&self, queue: ResyncQueue) -> &db::Tree {
+		match queue {
+			ResyncQueue::High => &self.queue_high,
+			ResyncQueue::Unclassified => &self.queue,
+			ResyncQueue::Normal => &self.queue_normal,
+		}
 	}
 
 	/// Get number of blocks that have an error
 	pub fn errors_len(&self) -> Result<usize, Error> {
-		Ok(self.errors.len()?)
+		Ok(self.errors.fast_len()?)
 	}
 
 	/// Clear the error counter for a block and put it in queue immediately
@@ -202,6 +305,18 @@ impl BlockResyncManager {
 	// This is why we need one tree ordered by time, and one
 	// ordered by identifier of item to be processed (block hash).
 	//
+	// The resync queue is in fact split in three trees with the
+	// same format: blocks are always added to resync.queue, and
+	// when a priority function has been set (see
+	// `BlockManager::set_resync_priority`), due blocks are moved
+	// to resync.queue_high or resync.queue_normal according to
+	// the priority it returns, before being processed. Blocks are
+	// taken from resync.queue_high first, so that high-priority
+	// blocks are processed before all normal-priority blocks that
+	// are due. Determining the priority of a block requires lookups
+	// in the metadata tables (for instance to find the buckets that
+	// reference the block), so it adds some cost to each resync.
+	//
 	// When the worker wants to process an item it takes from
 	// resync.queue, it checks in resync.errors that if there is an
 	// exponential back-off delay to await, it has passed before we
@@ -253,95 +368,136 @@ impl BlockResyncManager {
 	}
 
 	async fn resync_iter(&self, manager: &BlockManager) -> Result<ResyncIterResult, db::Error> {
-		if let Some(block) = self.get_block_to_resync()? {
-			let time_msec = u64::from_be_bytes(block.time_bytes[0..8].try_into().unwrap());
-			let now = now_msec();
-
-			if now >= time_msec {
-				let hash = Hash::try_from(&block.hash_bytes[..]).unwrap();
-
-				if let Some(ec) = self.errors.get(hash.as_slice())? {
-					let ec = ErrorCounter::decode(&ec);
-					if now < ec.next_try() {
-						// if next retry after an error is not yet,
-						// don't do resync and return early, but still
-						// make sure the item is still in queue at expected time
-						self.put_to_resync_at(&hash, ec.next_try())?;
-						// ec.next_try() > now >= time_msec, so this remove
-						// is not removing the one we added just above
-						// (we want to do the remove after the insert to ensure
-						// that the item is not lost if we crash in-between)
-						self.queue.remove(&block.time_bytes)?;
-						return Ok(ResyncIterResult::BusyDidNothing);
-					}
-				}
+		let heads = RESYNC_QUEUES
+			.iter()
+			.map(|q| self.get_block_to_resync(*q))
+			.collect::<Result<Vec<_>, _>>()?;
+		let head_times = [0, 1, 2].map(|i| heads[i].as_ref().map(BusyBlock::time_msec));
 
-				let tracer = opentelemetry::global::tracer("garage");
-				let trace_id = gen_uuid();
-				let span = tracer
-					.span_builder("Resync block")
-					.with_trace_id(
-						opentelemetry::trace::TraceId::from_hex(&hex::encode(
-							&trace_id.as_slice()[..16],
-						))
-						.unwrap(),
-					)
-					.with_attributes(vec![KeyValue::new("block", format!("{:?}", hash))])
-					.start(&tracer);
-
-				let res = self
-					.resync_block(manager, &hash)
-					.with_context(Context::current_with_span(span))
-					.bound_record_duration(&manager.metrics.resync_duration)
-					.await;
-
-				manager.metrics.resync_counter.add(1);
-
-				if let Err(e) = &res {
-					manager.metrics.resync_error_counter.add(1);
-					error!("Error when resyncing {:?}: {}", hash, e);
-
-					let err_counter = match self.errors.get(hash.as_slice())? {
-						Some(ec) => ErrorCounter::decode(&ec).add1(now + 1),
-						None => ErrorCounter::new(now + 1),
-					};
-
-					self.errors.insert(hash.as_slice(), err_counter.encode())?;
-
-					self.put_to_resync_at(&hash, err_counter.next_try())?;
-					// err_counter.next_try() >= now + 1 > now,
-					// the entry we remove from the queue is not
-					// the entry we inserted with put_to_resync_at
-					self.queue.remove(&block.time_bytes)?;
-				} else {
-					self.errors.remove(hash.as_slice())?;
-					self.queue.remove(&block.time_bytes)?;
-				}
-
-				Ok(ResyncIterResult::BusyDidSomething)
-			} else {
-				Ok(ResyncIterResult::IdleFor(Duration::from_millis(
+		let now = now_msec();
+		let block = match choose_resync_queue(head_times, now) {
+			ResyncChoice::Take(queue) => heads
+				.into_iter()
+				.flatten()
+				.find(|b| b.queue == queue)
+				.unwrap(),
+			ResyncChoice::IdleUntil(time_msec) => {
+				return Ok(ResyncIterResult::IdleFor(Duration::from_millis(
 					time_msec - now,
 				)))
 			}
+			ResyncChoice::Empty => {
+				// Here we wait either for a notification that an item has been
+				// added to the queue, or for a constant delay of 10 secs to expire.
+				// The delay avoids a race condition where the notification happens
+				// between the time we checked the queue and the first poll
+				// to resync_notify.notified(): if that happens, we'll just loop
+				// back 10 seconds later, which is fine.
+				return Ok(ResyncIterResult::IdleFor(Duration::from_secs(10)));
+			}
+		};
+
+		let hash = Hash::try_from(&block.hash_bytes[..]).unwrap();
+
+		if block.queue == ResyncQueue::Unclassified {
+			if let Some(resolve) = self.resolve_priority.load_full() {
+				let priority = match resolve(hash).await {
+					Ok(p) => p,
+					Err(e) => {
+						debug!("Could not determine resync priority of {:?}: {}", hash, e);
+						ResyncPriority::Normal
+					}
+				};
+				let target = match priority {
+					ResyncPriority::High => &self.queue_high,
+					ResyncPriority::Normal => &self.queue_normal,
+				};
+				// Insert before removing, so that the item is not lost
+				// if we crash in-between
+				target.insert(&block.time_bytes, &block.hash_bytes)?;
+				self.queue.remove(&block.time_bytes)?;
+				return Ok(ResyncIterResult::BusyDidNothing);
+			}
+		}
+		let priority = match block.queue {
+			ResyncQueue::High => ResyncPriority::High,
+			_ => ResyncPriority::Normal,
+		};
+		let queue = self.queue_tree(block.queue);
+
+		if let Some(ec) = self.errors.get(hash.as_slice())? {
+			let ec = ErrorCounter::decode(&ec);
+			if now < ec.next_try() {
+				// if next retry after an error is not yet,
+				// don't do resync and return early, but still
+				// make sure the item is still in queue at expected time
+				self.put_to_resync_at(&hash, ec.next_try())?;
+				// ec.next_try() > now >= time_msec, so this remove
+				// is not removing the one we added just above
+				// (we want to do the remove after the insert to ensure
+				// that the item is not lost if we crash in-between)
+				queue.remove(&block.time_bytes)?;
+				return Ok(ResyncIterResult::BusyDidNothing);
+			}
+		}
+
+		let tracer = opentelemetry::global::tracer("garage");
+		let trace_id = gen_uuid();
+		let span = tracer
+			.span_builder("Resync block")
+			.with_trace_id(
+				opentelemetry::trace::TraceId::from_hex(&hex::encode(&trace_id.as_slice()[..16]))
+					.unwrap(),
+			)
+			.with_attributes(vec![KeyValue::new("block", format!("{:?}", hash))])
+			.start(&tracer);
+
+		let res = self
+			.resync_block(manager, &hash)
+			.with_context(Context::current_with_span(span))
+			.bound_record_duration(&manager.metrics.resync_duration)
+			.await;
+
+		manager.metrics.resync_counter.add(1);
+		manager
+			.metrics
+			.resync_priority_counter
+			.add(1, &[KeyValue::new("priority", priority.name())]);
+
+		if let Err(e) = &res {
+			manager.metrics.resync_error_counter.add(1);
+			error!("Error when resyncing {:?}: {}", hash, e);
+
+			let err_counter = match self.errors.get(hash.as_slice())? {
+				Some(ec) => ErrorCounter::decode(&ec).add1(now + 1),
+				None => ErrorCounter::new(now + 1),
+			};
+
+			self.errors.insert(hash.as_slice(), err_counter.encode())?;
+
+			self.put_to_resync_at(&hash, err_counter.next_try())?;
+			// err_counter.next_try() >= now + 1 > now,
+			// the entry we remove from the queue is not
+			// the entry we inserted with put_to_resync_at
+			queue.remove(&block.time_bytes)?;
 		} else {
-			// Here we wait either for a notification that an item has been
-			// added to the queue, or for a constant delay of 10 secs to expire.
-			// The delay avoids a race condition where the notification happens
-			// between the time we checked the queue and the first poll
-			// to resync_notify.notified(): if that happens, we'll just loop
-			// back 10 seconds later, which is fine.
-			Ok(ResyncIterResult::IdleFor(Duration::from_secs(10)))
+			self.errors.remove(hash.as_slice())?;
+			queue.remove(&block.time_bytes)?;
 		}
+
+		Ok(ResyncIterResult::BusyDidSomething)
 	}
 
-	fn get_block_to_resync(&self) -> Result<Option<BusyBlock>, db::Error> {
+	fn get_block_to_resync(&self, queue: ResyncQueue) -> Result<Option<BusyBlock>, db::Error> {
 		let mut busy = self.busy_set.lock().unwrap();
-		for it in self.queue.iter()? {
+		for it in self.queue_tree(queue).iter()? {
 			let (time_bytes, hash_bytes) = it?;
-			if !busy.contains(&time_bytes) {
-				busy.insert(time_bytes.clone());
+			let busy_key = (queue as u8, time_bytes);
+			if !busy.contains(&busy_key) {
+				let time_bytes = busy_key.1.clone();
+				busy.insert(busy_key);
 				return Ok(Some(BusyBlock {
+					queue,
 					time_bytes,
 					hash_bytes,
 					busy_set: self.busy_set.clone(),
@@ -352,8 +508,7 @@ impl BlockResyncManager {
 	}
 
 	async fn resync_block(&self, manager: &BlockManager, hash: &Hash) -> Result<(), Error> {
-		let existing_path = manager.find_block(hash).await;
-		let exists = existing_path.is_some();
+		let exists = manager.has_block(hash).await?;
 		let rc = manager.rc.get_block_rc(hash)?;
 
 		if exists != rc.is_needed() || exists != rc.is_nonzero() {
@@ -366,6 +521,19 @@ impl BlockResyncManager {
 			);
 		}
 
+		if exists && rc.is_deletable() && manager.holds.is_held(hash) {
+			// A client is currently reading a version that uses this block,
+			// defer deletion until the read hold is released or expires.
+			debug!(
+				"Resync block {:?}: deletable but held by a reader, retrying later",
+				hash
+			);
+			manager
+				.resync
+				.put_to_resync(hash, BLOCK_HOLD_RECHECK_DELAY)?;
+			return Ok(());
+		}
+
 		if exists && rc.is_deletable() {
 			if manager.rc.recalculate_rc(hash)?.0 > 0 {
 				return Err(Error::Message(format!(
@@ -375,7 +543,6 @@ impl BlockResyncManager {
 			}
 
 			info!("Resync block {:?}: offloading and deleting", hash);
-			let existing_path = existing_path.unwrap();
 
 			let mut who = manager.replication.storage_nodes(hash);
 			if who.len() < manager.replication.write_quorum() {
@@ -390,7 +557,8 @@ impl BlockResyncManager {
 					&manager.endpoint,
 					&who,
 					BlockRpc::NeedBlockQuery(*hash),
-					RequestStrategy::with_priority(PRIO_BACKGROUND),
+					RequestStrategy::with_priority(PRIO_BACKGROUND)
+						.with_timeout_class(RpcClass::Sync),
 				)
 				.await?;
 
@@ -422,7 +590,7 @@ impl BlockResyncManager {
 						.add(1, &[KeyValue::new("to", format!("{:?}", node))]);
 				}
 
-				let block = manager.read_block_from(hash, &existing_path).await?;
+				let block = manager.read_block(hash).await?;
 				let (header, bytes) = block.into_parts();
 				let put_block_message = Req::new(BlockRpc::PutBlock {
 					hash: *hash,
@@ -437,7 +605,8 @@ impl BlockResyncManager {
 						&need_nodes,
 						put_block_message,
 						RequestStrategy::with_priority(PRIO_BACKGROUND | PRIO_SECONDARY)
-							.with_quorum(need_nodes.len()),
+							.with_quorum(need_nodes.len())
+							.with_timeout_class(RpcClass::BlockPut),
 					)
 					.await
 					.err_context("PutBlock RPC")?;
@@ -454,7 +623,7 @@ impl BlockResyncManager {
 			manager.rc.clear_deleted_block_rc(hash)?;
 		}
 
-		if rc.is_nonzero() && !exists {
+		if rc.is_nonzero() && !exists && manager.is_block_replica(hash)? {
 			info!(
 				"Resync block {:?}: fetching absent but needed block (refcount > 0)",
 				hash
@@ -484,7 +653,7 @@ impl BlockResyncManager {
 impl Drop for BusyBlock {
 	fn drop(&mut self) {
 		let mut busy = self.busy_set.lock().unwrap();
-		busy.remove(&self.time_bytes);
+		busy.remove(&(self.queue as u8, std::mem::take(&mut self.time_bytes)));
 	}
 }
 
@@ -624,3 +793,88 @@ impl ErrorCounter {
 		self.last_try + self.delay_msec()
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use std::collections::BTreeMap;
+
+	#[test]
+	fn test_choose_resync_queue() {
+		use ResyncQueue::*;
+
+		assert_eq!(
+			choose_resync_queue([None, None, None], 10),
+			ResyncChoice::Empty
+		);
+		assert_eq!(
+			choose_resync_queue([Some(20), None, Some(15)], 10),
+			ResyncChoice::IdleUntil(15)
+		);
+		assert_eq!(
+			choose_resync_queue([Some(20), Some(5), Some(1)], 10),
+			ResyncChoice::Take(Unclassified)
+		);
+		assert_eq!(
+			choose_resync_queue([Some(10), Some(5), Some(1)], 10),
+			ResyncChoice::Take(High)
+		);
+		assert_eq!(
+			choose_resync_queue([None, Some(20), Some(1)], 10),
+			ResyncChoice::Take(Normal)
+		);
+	}
+
+	#[test]
+	fn test_high_priority_resynced_first() {
+		// Blocks queued at times 0..20, every third one belongs to a high-priority bucket
+		let priority = |t: u64| {
+			if t % 3 == 0 {
+				ResyncPriority::High
+			} else {
+				ResyncPriority::Normal
+			}
+		};
+		let mut queues: [BTreeMap<u64, ()>; 3] = Default::default();
+		for t in 0..20 {
+			queues[1].insert(t, ());
+		}
+
+		// Simulate the resync loop, blocks being taken, classified or
+		// processed as in resync_iter
+		let now = 100;
+		let mut processed = vec![];
+		loop {
+			let heads = [0, 1, 2].map(|i| queues[i].keys().next().copied());
+			match choose_resync_queue(heads, now) {
+				ResyncChoice::Take(queue) => {
+					let i = RESYNC_QUEUES.iter().position(|q| *q == queue).unwrap();
+					let t = *queues[i].keys().next().unwrap();
+					queues[i].remove(&t);
+					match (queue, priority(t)) {
+						(ResyncQueue::Unclassified, ResyncPriority::High) => {
+							queues[0].insert(t, ());
+						}
+						(ResyncQueue::Unclassified, ResyncPriority::Normal) => {
+							queues[2].insert(t, ());
+						}
+						_ => processed.push(t),
+					}
+				}
+				_ => break,
+			}
+		}
+
+		assert_eq!(processed.len(), 20);
+		let n_high = (0..20)
+			.filter(|t| priority(*t) == ResyncPriority::High)
+			.count();
+		assert!(processed[..n_high]
+			.iter()
+			.all(|t| priority(*t) == ResyncPriority::High));
+		assert!(processed[n_high..]
+			.iter()
+			.all(|t| priority(*t) == ResyncPriority::Normal));
+	}
+}
@@ -15,7 +15,7 @@ use core::ops::{Bound, RangeBounds};
 
 use std::borrow::Cow;
 use std::cell::Cell;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use err_derive::Error;
@@ -42,12 +42,29 @@ pub type TxValueIter<'a> = Box<dyn std::iter::Iterator<Item = TxOpResult<(Value,
 // ----
 
 #[derive(Debug, Error)]
-#[error(display = "{}", _0)]
-pub struct Error(pub Cow<'static, str>);
+pub enum Error {
+	#[error(display = "{}", _0)]
+	Message(Cow<'static, str>),
+	/// All the reader slots of an LMDB database are used by read
+	/// transactions, so no other read transaction can be started
+	#[error(
+		display = "LMDB: all {} reader slots are in use (MDB_READERS_FULL): too many read transactions are open at the same time, because of many concurrent requests or of long-running iterations. Increase `lmdb_max_readers` in the configuration if this happens under normal load.",
+		_0
+	)]
+	ReadersFull(u32),
+}
+
+impl Error {
+	/// Whether the error is caused by the database being overloaded,
+	/// in which case the operation can be retried later
+	pub fn is_overloaded(&self) -> bool {
+		matches!(self, Error::ReadersFull(_))
+	}
+}
 
 impl From<std::io::Error> for Error {
 	fn from(e: std::io::Error) -> Error {
-		Error(format!("IO: {}", e).into())
+		Error::Message(format!("IO: {}", e).into())
 	}
 }
 
@@ -79,6 +96,33 @@ pub fn unabort<R, E>(res: TxResult<R, E>) -> TxOpResult<std::result::Result<R, E
 	}
 }
 
+/// Path of the manifest that is written next to a snapshot of the database,
+/// for the engines that write one
+pub fn snapshot_manifest_path(snapshot: &Path) -> PathBuf {
+	let mut path = snapshot.as_os_str().to_owned();
+	path.push(".manifest");
+	PathBuf::from(path)
+}
+
+/// Optional features of the database engines, that higher layers can
+/// check for with `Db::supports` before relying on them
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Capability {
+	/// Snapshots are checked for integrity once written, and
+	/// described by a manifest written next to them
+	SnapshotManifest,
+	/// The database can be compacted while it is in use
+	Compaction,
+}
+
+/// Usage of the reader slots of a database, for the engines that have
+/// a fixed number of them (each read transaction takes one slot)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReaderSlots {
+	pub used: usize,
+	pub max: usize,
+}
+
 // ----
 
 impl Db {
@@ -86,6 +130,11 @@ impl Db {
 		self.0.engine()
 	}
 
+	/// Whether the database engine supports an optional feature
+	pub fn supports(&self, cap: Capability) -> bool {
+		self.0.supports(cap)
+	}
+
 	pub fn open_tree<S: AsRef<str>>(&self, name: S) -> Result<Tree> {
 		let tree_id = self.0.open_tree(name.as_ref())?;
 		Ok(Tree(self.0.clone(), tree_id))
@@ -104,10 +153,19 @@ impl Db {
 			result: Cell::new(None),
 		};
 		let tx_res = self.0.transaction(&f);
-		let ret = f
-			.result
-			.into_inner()
-			.expect("Transaction did not store result");
+		let ret = match f.result.into_inner() {
+			Some(ret) => ret,
+			// The engine failed before the transaction function could be
+			// called, e.g. because it could not start a transaction
+			None => {
+				return match tx_res {
+					Err(TxError::Db(e)) => Err(TxError::Db(e)),
+					_ => Err(TxError::Db(Error::Message(
+						"Transaction did not store result".into(),
+					))),
+				}
+			}
+		};
 
 		match tx_res {
 			Ok(on_commit) => match ret {
@@ -137,10 +195,28 @@ impl Db {
 		self.0.snapshot(path)
 	}
 
+	/// Force all committed transactions to be written to persistent storage
+	pub fn sync(&self) -> Result<()> {
+		self.0.sync()
+	}
+
+	/// Rewrite the database to reclaim the space left unused by deleted
+	/// data, on engines that support it (see `Capability::Compaction`).
+	/// Returns the number of bytes reclaimed.
+	pub fn compact(&self) -> Result<u64> {
+		self.0.compact()
+	}
+
+	/// Number of reader slots in use, on engines that have a limited
+	/// number of them
+	pub fn reader_slots(&self) -> Option<ReaderSlots> {
+		self.0.reader_slots()
+	}
+
 	pub fn import(&self, other: &Db) -> Result<()> {
 		let existing_trees = self.list_trees()?;
 		if !existing_trees.is_empty() {
-			return Err(Error(
+			return Err(Error::Message(
 				format!(
 					"destination database already contains data: {:?}",
 					existing_trees
@@ -152,8 +228,10 @@ impl Db {
 		let tree_names = other.list_trees()?;
 		for name in tree_names {
 			let tree = self.open_tree(&name)?;
-			if tree.len()? > 0 {
-				return Err(Error(format!("tree {} already contains data", name).into()));
+			if tree.exact_len()? > 0 {
+				return Err(Error::Message(
+					format!("tree {} already contains data", name).into(),
+				));
 			}
 
 			let ex_tree = other.open_tree(&name)?;
@@ -182,7 +260,6 @@ impl Db {
 	}
 }
 
-#[allow(clippy::len_without_is_empty)]
 impl Tree {
 	#[inline]
 	pub fn db(&self) -> Db {
@@ -193,9 +270,18 @@ impl Tree {
 	pub fn get<T: AsRef<[u8]>>(&self, key: T) -> Result<Option<Value>> {
 		self.0.get(self.1, key.as_ref())
 	}
+	/// Number of items in the tree, read in constant time or close to it
+	/// on all engines, but possibly approximate. To be used for statistics
+	/// and monitoring.
+	#[inline]
+	pub fn fast_len(&self) -> Result<usize> {
+		self.0.fast_len(self.1)
+	}
+	/// Exact number of items in the tree, which may require scanning the
+	/// whole tree. To be used where the exact count matters, e.g. in repairs.
 	#[inline]
-	pub fn len(&self) -> Result<usize> {
-		self.0.len(self.1)
+	pub fn exact_len(&self) -> Result<usize> {
+		self.0.exact_len(self.1)
 	}
 
 	#[inline]
@@ -332,12 +418,28 @@ impl<'a> Transaction<'a> {
 
 pub(crate) trait IDb: Send + Sync {
 	fn engine(&self) -> String;
+	fn supports(&self, cap: Capability) -> bool;
 	fn open_tree(&self, name: &str) -> Result<usize>;
 	fn list_trees(&self) -> Result<Vec<String>>;
 	fn snapshot(&self, path: &PathBuf) -> Result<()>;
+	fn sync(&self) -> Result<()>;
+	fn compact(&self) -> Result<u64> {
+		Err(Error::Message(
+			format!(
+				"{} databases cannot be compacted while in use",
+				self.engine()
+			)
+			.into(),
+		))
+	}
+
+	fn reader_slots(&self) -> Option<ReaderSlots> {
+		None
+	}
 
 	fn get(&self, tree: usize, key: &[u8]) -> Result<Option<Value>>;
-	fn len(&self, tree: usize) -> Result<usize>;
+	fn fast_len(&self, tree: usize) -> Result<usize>;
+	fn exact_len(&self, tree: usize) -> Result<usize>;
 
 	fn insert(&self, tree: usize, key: &[u8], value: &[u8]) -> Result<Option<Value>>;
 	fn remove(&self, tree: usize, key: &[u8]) -> Result<Option<Value>>;
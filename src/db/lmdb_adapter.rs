@@ -5,23 +5,28 @@ use std::collections::HashMap;
 use std::convert::TryInto;
 use std::path::PathBuf;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, RwLock};
 
 use heed::types::ByteSlice;
 use heed::{BytesDecode, Env, RoTxn, RwTxn, UntypedDatabase as Database};
 
 use crate::{
-	Db, Error, IDb, ITx, ITxFn, OnCommit, Result, TxError, TxFnResult, TxOpError, TxOpResult,
-	TxResult, TxValueIter, Value, ValueIter,
+	Capability, Db, Error, IDb, ITx, ITxFn, OnCommit, ReaderSlots, Result, TxError, TxFnResult,
+	TxOpError, TxOpResult, TxResult, TxValueIter, Value, ValueIter,
 };
 
 pub use heed;
 
+/// Number of reader slots of the LMDB environment, i.e. of read
+/// transactions that can be open at the same time, by default
+pub const DEFAULT_MAX_READERS: u32 = 2048;
+
 // -- err
 
 impl From<heed::Error> for Error {
 	fn from(e: heed::Error) -> Error {
-		Error(format!("LMDB: {}", e).into())
+		Error::Message(format!("LMDB: {}", e).into())
 	}
 }
 
@@ -36,17 +41,34 @@ impl From<heed::Error> for TxOpError {
 pub struct LmdbDb {
 	db: heed::Env,
 	trees: RwLock<(Vec<Database>, HashMap<String, usize>)>,
+	max_readers: u32,
+	/// Number of read transactions currently open, each of them
+	/// holding one of the reader slots
+	readers: AtomicUsize,
 }
 
 impl LmdbDb {
-	pub fn init(db: Env) -> Db {
+	pub fn init(db: Env, max_readers: u32) -> Db {
 		let s = Self {
 			db,
 			trees: RwLock::new((Vec::new(), HashMap::new())),
+			max_readers,
+			readers: AtomicUsize::new(0),
 		};
 		Db(Arc::new(s))
 	}
 
+	/// Start a read transaction. It holds a reader slot until both the
+	/// transaction and the returned `ReaderSlot` are dropped.
+	fn read_txn(&self) -> Result<(RoTxn<'_>, ReaderSlot<'_>)> {
+		let tx = self.db.read_txn().map_err(|e| match e {
+			heed::Error::Mdb(heed::MdbError::ReadersFull) => Error::ReadersFull(self.max_readers),
+			e => e.into(),
+		})?;
+		self.readers.fetch_add(1, Ordering::Relaxed);
+		Ok((tx, ReaderSlot(&self.readers)))
+	}
+
 	fn get_tree(&self, i: usize) -> Result<Database> {
 		self.trees
 			.read()
@@ -54,7 +76,7 @@ impl LmdbDb {
 			.0
 			.get(i)
 			.cloned()
-			.ok_or_else(|| Error("invalid tree id".into()))
+			.ok_or_else(|| Error::Message("invalid tree id".into()))
 	}
 }
 
@@ -63,6 +85,13 @@ impl IDb for LmdbDb {
 		"LMDB (using Heed crate)".into()
 	}
 
+	fn supports(&self, cap: Capability) -> bool {
+		match cap {
+			Capability::SnapshotManifest => false,
+			Capability::Compaction => false,
+		}
+	}
+
 	fn open_tree(&self, name: &str) -> Result<usize> {
 		let mut trees = self.trees.write().unwrap();
 		if let Some(i) = trees.1.get(name) {
@@ -83,12 +112,13 @@ impl IDb for LmdbDb {
 		};
 
 		let mut ret = vec![];
-		let tx = self.db.read_txn()?;
+		let (tx, slot) = self.read_txn()?;
 		for item in tree0.iter(&tx)? {
 			let (tree_name, _) = item?;
 			ret.push(tree_name.to_string());
 		}
 		drop(tx);
+		drop(slot);
 
 		let mut ret2 = vec![];
 		for tree_name in ret {
@@ -104,6 +134,13 @@ impl IDb for LmdbDb {
 		Ok(ret2)
 	}
 
+	fn reader_slots(&self) -> Option<ReaderSlots> {
+		Some(ReaderSlots {
+			used: self.readers.load(Ordering::Relaxed),
+			max: self.max_readers as usize,
+		})
+	}
+
 	fn snapshot(&self, to: &PathBuf) -> Result<()> {
 		std::fs::create_dir_all(to)?;
 		let mut path = to.clone();
@@ -113,12 +150,17 @@ impl IDb for LmdbDb {
 		Ok(())
 	}
 
+	fn sync(&self) -> Result<()> {
+		self.db.force_sync()?;
+		Ok(())
+	}
+
 	// ----
 
 	fn get(&self, tree: usize, key: &[u8]) -> Result<Option<Value>> {
 		let tree = self.get_tree(tree)?;
 
-		let tx = self.db.read_txn()?;
+		let (tx, _slot) = self.read_txn()?;
 		let val = tree.get(&tx, key)?;
 		match val {
 			None => Ok(None),
@@ -126,10 +168,19 @@ impl IDb for LmdbDb {
 		}
 	}
 
-	fn len(&self, tree: usize) -> Result<usize> {
+	fn fast_len(&self, tree: usize) -> Result<usize> {
+		// LMDB keeps the number of entries of each tree in its metadata,
+		// so the exact length is cheap to read
+		self.exact_len(tree)
+	}
+
+	fn exact_len(&self, tree: usize) -> Result<usize> {
 		let tree = self.get_tree(tree)?;
-		let tx = self.db.read_txn()?;
-		Ok(tree.len(&tx)?.try_into().unwrap())
+		let (tx, _slot) = self.read_txn()?;
+		let len = tree.len(&tx)?;
+		len.try_into().map_err(|_| {
+			Error::Message(format!("LMDB: tree length {} overflows usize", len).into())
+		})
 	}
 
 	fn insert(&self, tree: usize, key: &[u8], value: &[u8]) -> Result<Option<Value>> {
@@ -160,14 +211,14 @@ impl IDb for LmdbDb {
 
 	fn iter(&self, tree: usize) -> Result<ValueIter<'_>> {
 		let tree = self.get_tree(tree)?;
-		let tx = self.db.read_txn()?;
-		TxAndIterator::make(tx, |tx| Ok(tree.iter(tx)?))
+		let (tx, slot) = self.read_txn()?;
+		TxAndIterator::make(tx, slot, |tx| Ok(tree.iter(tx)?))
 	}
 
 	fn iter_rev(&self, tree: usize) -> Result<ValueIter<'_>> {
 		let tree = self.get_tree(tree)?;
-		let tx = self.db.read_txn()?;
-		TxAndIterator::make(tx, |tx| Ok(tree.rev_iter(tx)?))
+		let (tx, slot) = self.read_txn()?;
+		TxAndIterator::make(tx, slot, |tx| Ok(tree.rev_iter(tx)?))
 	}
 
 	fn range<'r>(
@@ -177,8 +228,8 @@ impl IDb for LmdbDb {
 		high: Bound<&'r [u8]>,
 	) -> Result<ValueIter<'_>> {
 		let tree = self.get_tree(tree)?;
-		let tx = self.db.read_txn()?;
-		TxAndIterator::make(tx, |tx| Ok(tree.range(tx, &(low, high))?))
+		let (tx, slot) = self.read_txn()?;
+		TxAndIterator::make(tx, slot, |tx| Ok(tree.range(tx, &(low, high))?))
 	}
 	fn range_rev<'r>(
 		&self,
@@ -187,8 +238,8 @@ impl IDb for LmdbDb {
 		high: Bound<&'r [u8]>,
 	) -> Result<ValueIter<'_>> {
 		let tree = self.get_tree(tree)?;
-		let tx = self.db.read_txn()?;
-		TxAndIterator::make(tx, |tx| Ok(tree.rev_range(tx, &(low, high))?))
+		let (tx, slot) = self.read_txn()?;
+		TxAndIterator::make(tx, slot, |tx| Ok(tree.rev_range(tx, &(low, high))?))
 	}
 
 	// ----
@@ -216,7 +267,7 @@ impl IDb for LmdbDb {
 			}
 			TxFnResult::DbErr => {
 				tx.tx.abort().map_err(Error::from).map_err(TxError::Db)?;
-				Err(TxError::Db(Error(
+				Err(TxError::Db(Error::Message(
 					"(this message will be discarded)".into(),
 				)))
 			}
@@ -234,7 +285,7 @@ struct LmdbTx<'a> {
 impl<'a> LmdbTx<'a> {
 	fn get_tree(&self, i: usize) -> TxOpResult<&Database> {
 		self.trees.get(i).ok_or_else(|| {
-			TxOpError(Error(
+			TxOpError(Error::Message(
 				"invalid tree id (it might have been openned after the transaction started)".into(),
 			))
 		})
@@ -319,6 +370,7 @@ where
 	I: Iterator<Item = IteratorItem<'a>> + 'a,
 {
 	tx: RoTxn<'a>,
+	_slot: ReaderSlot<'a>,
 	iter: Option<I>,
 }
 
@@ -326,11 +378,15 @@ impl<'a, I> TxAndIterator<'a, I>
 where
 	I: Iterator<Item = IteratorItem<'a>> + 'a,
 {
-	fn make<F>(tx: RoTxn<'a>, iterfun: F) -> Result<ValueIter<'a>>
+	fn make<F>(tx: RoTxn<'a>, slot: ReaderSlot<'a>, iterfun: F) -> Result<ValueIter<'a>>
 	where
 		F: FnOnce(&'a RoTxn<'a>) -> Result<I>,
 	{
-		let res = TxAndIterator { tx, iter: None };
+		let res = TxAndIterator {
+			tx,
+			_slot: slot,
+			iter: None,
+		};
 		let mut boxed = Box::pin(res);
 
 		// This unsafe allows us to bypass lifetime checks
@@ -379,6 +435,16 @@ where
 	}
 }
 
+/// Reader slot taken by a read transaction, which is counted in the
+/// reader slots in use until it is dropped
+struct ReaderSlot<'a>(&'a AtomicUsize);
+
+impl<'a> Drop for ReaderSlot<'a> {
+	fn drop(&mut self) {
+		self.0.fetch_sub(1, Ordering::Relaxed);
+	}
+}
+
 // ---- iterators within transactions ----
 
 fn tx_iter_item<'a>(
@@ -1,4 +1,6 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 use crate::{Db, Error, Result};
 
@@ -36,8 +38,8 @@ impl std::str::FromStr for Engine {
 		match text {
 			"lmdb" | "heed" => Ok(Self::Lmdb),
 			"sqlite" | "sqlite3" | "rusqlite" => Ok(Self::Sqlite),
-			"sled" => Err(Error("Sled is no longer supported as a database engine. Converting your old metadata db can be done using an older Garage binary (e.g. v0.9.4).".into())),
-			kind => Err(Error(
+			"sled" => Err(Error::Message("Sled is no longer supported as a database engine. Converting your old metadata db can be done using an older Garage binary (e.g. v0.9.4).".into())),
+			kind => Err(Error::Message(
 				format!(
 					"Invalid DB engine: {} (options are: lmdb, sqlite)",
 					kind
@@ -50,26 +52,62 @@ impl std::str::FromStr for Engine {
 
 pub struct OpenOpt {
 	pub fsync: bool,
+	/// If set (and `fsync` is not), writes are not synced individually
+	/// but all at once by a background thread at this interval
+	pub fsync_interval: Option<Duration>,
 	pub lmdb_map_size: Option<usize>,
+	/// Number of reader slots of LMDB databases (defaults to
+	/// `lmdb_adapter::DEFAULT_MAX_READERS`)
+	pub lmdb_max_readers: Option<u32>,
+	/// If set, permissions of the database directory and files, which are
+	/// applied when the database is opened (Unix only). Files get the same
+	/// permissions without the execute bits.
+	pub mode: Option<u32>,
 }
 
 impl Default for OpenOpt {
 	fn default() -> Self {
 		Self {
 			fsync: false,
+			fsync_interval: None,
 			lmdb_map_size: None,
+			lmdb_max_readers: None,
+			mode: None,
 		}
 	}
 }
 
 pub fn open_db(path: &PathBuf, engine: Engine, opt: &OpenOpt) -> Result<Db> {
+	let db = open_db_engine(path, engine, opt)?;
+
+	if let (false, Some(interval)) = (opt.fsync, opt.fsync_interval) {
+		spawn_sync_thread(&db, interval)?;
+	}
+
+	Ok(db)
+}
+
+fn open_db_engine(path: &PathBuf, engine: Engine, opt: &OpenOpt) -> Result<Db> {
 	match engine {
 		// ---- Sqlite DB ----
 		#[cfg(feature = "sqlite")]
 		Engine::Sqlite => {
 			info!("Opening Sqlite database at: {}", path.display());
 			let manager = r2d2_sqlite::SqliteConnectionManager::file(path);
-			Ok(crate::sqlite_adapter::SqliteDb::new(manager, opt.fsync)?)
+			// In group commit mode, synchronous=NORMAL is used so that
+			// the WAL file is synced at each checkpoint
+			let sync_mode = opt.fsync || opt.fsync_interval.is_some();
+			let db = crate::sqlite_adapter::SqliteDb::new(manager, sync_mode)?;
+			if let Some(mode) = opt.mode {
+				// Sqlite creates the WAL and shared memory files
+				// with the same permissions as the database file
+				for suffix in ["", "-wal", "-shm"] {
+					let mut file = path.as_os_str().to_owned();
+					file.push(suffix);
+					set_permissions(Path::new(&file), mode)?;
+				}
+			}
+			Ok(db)
 		}
 
 		// ---- LMDB DB ----
@@ -77,10 +115,13 @@ pub fn open_db(path: &PathBuf, engine: Engine, opt: &OpenOpt) -> Result<Db> {
 		Engine::Lmdb => {
 			info!("Opening LMDB database at: {}", path.display());
 			if let Err(e) = std::fs::create_dir_all(&path) {
-				return Err(Error(
+				return Err(Error::Message(
 					format!("Unable to create LMDB data directory: {}", e).into(),
 				));
 			}
+			if let Some(mode) = opt.mode {
+				set_permissions(path, mode)?;
+			}
 
 			let map_size = match opt.lmdb_map_size {
 				None => crate::lmdb_adapter::recommended_map_size(),
@@ -90,7 +131,10 @@ pub fn open_db(path: &PathBuf, engine: Engine, opt: &OpenOpt) -> Result<Db> {
 			let mut env_builder = heed::EnvOpenOptions::new();
 			env_builder.max_dbs(100);
 			env_builder.map_size(map_size);
-			env_builder.max_readers(2048);
+			let max_readers = opt
+				.lmdb_max_readers
+				.unwrap_or(crate::lmdb_adapter::DEFAULT_MAX_READERS);
+			env_builder.max_readers(max_readers);
 			unsafe {
 				env_builder.flag(crate::lmdb_adapter::heed::flags::Flags::MdbNoMetaSync);
 				if !opt.fsync {
@@ -99,7 +143,7 @@ pub fn open_db(path: &PathBuf, engine: Engine, opt: &OpenOpt) -> Result<Db> {
 			}
 			match env_builder.open(&path) {
 				Err(heed::Error::Io(e)) if e.kind() == std::io::ErrorKind::OutOfMemory => {
-					return Err(Error(
+					return Err(Error::Message(
 						"OutOfMemory error while trying to open LMDB database. This can happen \
                         if your operating system is not allowing you to use sufficient virtual \
                         memory address space. Please check that no limit is set (ulimit -v). \
@@ -108,8 +152,16 @@ pub fn open_db(path: &PathBuf, engine: Engine, opt: &OpenOpt) -> Result<Db> {
 							.into(),
 					))
 				}
-				Err(e) => Err(Error(format!("Cannot open LMDB database: {}", e).into())),
-				Ok(db) => Ok(crate::lmdb_adapter::LmdbDb::init(db)),
+				Err(e) => Err(Error::Message(
+					format!("Cannot open LMDB database: {}", e).into(),
+				)),
+				Ok(db) => {
+					if let Some(mode) = opt.mode {
+						set_permissions(&path.join("data.mdb"), mode)?;
+						set_permissions(&path.join("lock.mdb"), mode)?;
+					}
+					Ok(crate::lmdb_adapter::LmdbDb::init(db, max_readers))
+				}
 			}
 		}
 
@@ -117,8 +169,62 @@ pub fn open_db(path: &PathBuf, engine: Engine, opt: &OpenOpt) -> Result<Db> {
 		// attribute is added so that we won't have to change this match in case stop building
 		// support for one or more engines by default.
 		#[allow(unreachable_patterns)]
-		engine => Err(Error(
+		engine => Err(Error::Message(
 			format!("DB engine support not available in this build: {}", engine).into(),
 		)),
 	}
 }
+
+/// Set the permissions of a file or directory, if it exists.
+/// The execute bits are removed for files that are not directories.
+/// This does nothing on platforms other than Unix.
+pub fn set_permissions(path: &Path, mode: u32) -> Result<()> {
+	#[cfg(unix)]
+	{
+		use std::os::unix::fs::PermissionsExt;
+
+		let metadata = match std::fs::metadata(path) {
+			Ok(m) => m,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+			Err(e) => return Err(e.into()),
+		};
+		let mode = if metadata.is_dir() {
+			mode
+		} else {
+			mode & 0o666
+		};
+		if metadata.permissions().mode() & 0o7777 != mode {
+			std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).map_err(|e| {
+				Error::Message(
+					format!("Unable to set permissions of {}: {}", path.display(), e).into(),
+				)
+			})?;
+		}
+	}
+	#[cfg(not(unix))]
+	let _ = (path, mode);
+	Ok(())
+}
+
+/// Spawn a thread that periodically syncs the database to disk (group commit).
+/// The thread exits when the database is closed.
+fn spawn_sync_thread(db: &Db, interval: Duration) -> Result<()> {
+	info!(
+		"Metadata database will be synced to disk every {:?}",
+		interval
+	);
+	let db = Arc::downgrade(&db.0);
+	std::thread::Builder::new()
+		.name("db-sync".into())
+		.spawn(move || loop {
+			std::thread::sleep(interval);
+			let db = match db.upgrade() {
+				Some(db) => db,
+				None => break,
+			};
+			if let Err(e) = db.sync() {
+				error!("Failed to sync metadata database: {}", e);
+			}
+		})?;
+	Ok(())
+}
@@ -11,8 +11,8 @@ use r2d2_sqlite::SqliteConnectionManager;
 use rusqlite::{params, Rows, Statement, Transaction};
 
 use crate::{
-	Db, Error, IDb, ITx, ITxFn, OnCommit, Result, TxError, TxFnResult, TxOpError, TxOpResult,
-	TxResult, TxValueIter, Value, ValueIter,
+	Capability, Db, Error, IDb, ITx, ITxFn, OnCommit, Result, TxError, TxFnResult, TxOpError,
+	TxOpResult, TxResult, TxValueIter, Value, ValueIter,
 };
 
 pub use rusqlite;
@@ -23,13 +23,13 @@ type Connection = r2d2::PooledConnection<SqliteConnectionManager>;
 
 impl From<rusqlite::Error> for Error {
 	fn from(e: rusqlite::Error) -> Error {
-		Error(format!("Sqlite: {}", e).into())
+		Error::Message(format!("Sqlite: {}", e).into())
 	}
 }
 
 impl From<r2d2::Error> for Error {
 	fn from(e: r2d2::Error) -> Error {
-		Error(format!("Sqlite: {}", e).into())
+		Error::Message(format!("Sqlite: {}", e).into())
 	}
 }
 
@@ -41,6 +41,83 @@ impl From<rusqlite::Error> for TxOpError {
 
 // -- db
 
+/// List the trees of a database, as pairs of a tree name and
+/// the name of the table that stores it
+fn list_tree_tables(db: &rusqlite::Connection) -> Result<Vec<(String, String)>> {
+	let mut trees = vec![];
+
+	let mut stmt =
+		db.prepare("SELECT name FROM sqlite_schema WHERE type = 'table' AND name LIKE 'tree_%'")?;
+	let mut rows = stmt.query([])?;
+	while let Some(row) = rows.next()? {
+		let table = row.get::<_, String>(0)?;
+		let name = table.replace("_COLON_", ":");
+		// `_` is a wildcard in LIKE patterns, so this might
+		// be an unrelated table
+		if let Some(name) = name.strip_prefix("tree_") {
+			trees.push((name.to_string(), table));
+		}
+	}
+	Ok(trees)
+}
+
+/// Table in which the number of rows of each tree is kept, so that it can
+/// be read without counting them. It is maintained by triggers on the tables
+/// of the trees, so that it is updated in the same transaction as the rows.
+const TREE_LEN_TABLE: &str = "garage_tree_len";
+
+/// Create the table of a tree if it does not exist, as well as its row
+/// counter and the triggers that maintain it
+fn create_tree_table(db: &mut Connection, table: &str) -> Result<()> {
+	let tx = db.transaction()?;
+	tx.execute_batch(&format!(
+		"CREATE TABLE IF NOT EXISTS {table} (
+			k BLOB PRIMARY KEY,
+			v BLOB
+		);
+		CREATE TABLE IF NOT EXISTS {len_table} (
+			tree TEXT PRIMARY KEY,
+			n INTEGER NOT NULL
+		);
+		CREATE TRIGGER IF NOT EXISTS {table}_len_insert AFTER INSERT ON {table}
+		BEGIN
+			UPDATE {len_table} SET n = n + 1 WHERE tree = '{table}';
+		END;
+		CREATE TRIGGER IF NOT EXISTS {table}_len_delete AFTER DELETE ON {table}
+		BEGIN
+			UPDATE {len_table} SET n = n - 1 WHERE tree = '{table}';
+		END;",
+		table = table,
+		len_table = TREE_LEN_TABLE,
+	))?;
+
+	// Trees created before the counters existed are counted once
+	let counted = tx.query_row(
+		&format!("SELECT COUNT(*) FROM {} WHERE tree = ?1", TREE_LEN_TABLE),
+		[table],
+		|row| row.get::<_, usize>(0),
+	)?;
+	if counted == 0 {
+		tx.execute(
+			&format!(
+				"INSERT INTO {} (tree, n) SELECT ?1, COUNT(*) FROM {}",
+				TREE_LEN_TABLE, table
+			),
+			[table],
+		)?;
+	}
+
+	tx.commit()?;
+	Ok(())
+}
+
+/// Size of the database file, computed from its number of pages
+fn database_size(db: &Connection) -> Result<u64> {
+	let pages = db.query_row("PRAGMA page_count", [], |row| row.get::<_, i64>(0))?;
+	let page_size = db.query_row("PRAGMA page_size", [], |row| row.get::<_, i64>(0))?;
+	Ok((pages * page_size) as u64)
+}
+
 pub struct SqliteDb {
 	db: Pool<SqliteConnectionManager>,
 	trees: RwLock<Vec<Arc<str>>>,
@@ -77,7 +154,7 @@ impl SqliteDb {
 			.unwrap()
 			.get(i)
 			.cloned()
-			.ok_or_else(|| Error("invalid tree id".into()))
+			.ok_or_else(|| Error::Message("invalid tree id".into()))
 	}
 
 	fn internal_get(&self, db: &Connection, tree: &str, key: &[u8]) -> Result<Option<Value>> {
@@ -95,6 +172,13 @@ impl IDb for SqliteDb {
 		format!("sqlite3 v{} (using rusqlite crate)", rusqlite::version())
 	}
 
+	fn supports(&self, cap: Capability) -> bool {
+		match cap {
+			Capability::SnapshotManifest => true,
+			Capability::Compaction => true,
+		}
+	}
+
 	fn open_tree(&self, name: &str) -> Result<usize> {
 		let name = format!("tree_{}", name.replace(':', "_COLON_"));
 		let mut trees = self.trees.write().unwrap();
@@ -102,19 +186,12 @@ impl IDb for SqliteDb {
 		if let Some(i) = trees.iter().position(|x| x.as_ref() == &name) {
 			Ok(i)
 		} else {
-			let db = self.db.get()?;
+			let mut db = self.db.get()?;
+			let lock = self.write_lock.lock();
 			trace!("create table {}", name);
-			db.execute(
-				&format!(
-					"CREATE TABLE IF NOT EXISTS {} (
-						k BLOB PRIMARY KEY,
-						v BLOB
-					)",
-					name
-				),
-				[],
-			)?;
+			create_tree_table(&mut db, &name)?;
 			trace!("table created: {}, unlocking", name);
+			drop(lock);
 
 			let i = trees.len();
 			trees.push(name.to_string().into_boxed_str().into());
@@ -123,33 +200,82 @@ impl IDb for SqliteDb {
 	}
 
 	fn list_trees(&self) -> Result<Vec<String>> {
-		let mut trees = vec![];
-
 		let db = self.db.get()?;
-		let mut stmt = db.prepare(
-			"SELECT name FROM sqlite_schema WHERE type = 'table' AND name LIKE 'tree_%'",
-		)?;
-		let mut rows = stmt.query([])?;
-		while let Some(row) = rows.next()? {
-			let name = row.get::<_, String>(0)?;
-			let name = name.replace("_COLON_", ":");
-			let name = name.strip_prefix("tree_").unwrap().to_string();
-			trees.push(name);
-		}
+		let trees = list_tree_tables(&db)?
+			.into_iter()
+			.map(|(name, _)| name)
+			.collect();
 		Ok(trees)
 	}
 
 	fn snapshot(&self, to: &PathBuf) -> Result<()> {
-		fn progress(p: rusqlite::backup::Progress) {
-			let percent = (p.pagecount - p.remaining) * 100 / p.pagecount;
-			info!("Sqlite snapshot progres: {}%", percent);
+		let to_str = to
+			.to_str()
+			.ok_or_else(|| Error::Message("Sqlite: snapshot path is not valid UTF-8".into()))?;
+
+		let db = self.db.get()?;
+		// Copy what we can of the WAL into the database file beforehand,
+		// without waiting for readers or writers
+		db.query_row("PRAGMA wal_checkpoint(PASSIVE)", [], |_| Ok(()))?;
+		// VACUUM INTO writes the database as seen by a single read
+		// transaction, so the snapshot is a consistent point-in-time copy.
+		// In WAL mode, writers are not blocked while it runs.
+		db.execute("VACUUM INTO ?1", [to_str])?;
+		drop(db);
+
+		// Check that the snapshot opens cleanly, and describe its content
+		// in a manifest written next to it
+		let snap = rusqlite::Connection::open_with_flags(
+			to,
+			rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX,
+		)?;
+		let check = snap.query_row("PRAGMA quick_check", [], |row| row.get::<_, String>(0))?;
+		if check != "ok" {
+			return Err(Error::Message(
+				format!("Sqlite: snapshot failed integrity check: {}", check).into(),
+			));
 		}
+
+		let mut manifest = format!(
+			"engine: {}\nsize: {}\n",
+			self.engine(),
+			std::fs::metadata(to)?.len()
+		);
+		for (name, table) in list_tree_tables(&snap)? {
+			let rows = snap.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| {
+				row.get::<_, usize>(0)
+			})?;
+			manifest.push_str(&format!("tree {}: {} rows\n", name, rows));
+		}
+		drop(snap);
+
+		std::fs::write(crate::snapshot_manifest_path(to), manifest)?;
+		Ok(())
+	}
+
+	fn sync(&self) -> Result<()> {
+		// In WAL mode with synchronous=NORMAL, the WAL file is synced
+		// to disk when a checkpoint is made
 		self.db
 			.get()?
-			.backup(rusqlite::DatabaseName::Main, to, Some(progress))?;
+			.query_row("PRAGMA wal_checkpoint(PASSIVE)", [], |_| Ok(()))?;
 		Ok(())
 	}
 
+	fn compact(&self) -> Result<u64> {
+		let db = self.db.get()?;
+		let lock = self.write_lock.lock();
+
+		let before = database_size(&db)?;
+		db.execute_batch("VACUUM")?;
+		// VACUUM writes the whole database to the WAL, truncate it
+		db.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+		let after = database_size(&db)?;
+
+		drop(lock);
+		Ok(before.saturating_sub(after))
+	}
+
 	// ----
 
 	fn get(&self, tree: usize, key: &[u8]) -> Result<Option<Value>> {
@@ -157,7 +283,19 @@ impl IDb for SqliteDb {
 		self.internal_get(&self.db.get()?, &tree, key)
 	}
 
-	fn len(&self, tree: usize) -> Result<usize> {
+	fn fast_len(&self, tree: usize) -> Result<usize> {
+		let tree = self.get_tree(tree)?;
+		let db = self.db.get()?;
+
+		let len = db.query_row(
+			&format!("SELECT n FROM {} WHERE tree = ?1", TREE_LEN_TABLE),
+			[tree.as_ref()],
+			|row| row.get::<_, usize>(0),
+		)?;
+		Ok(len)
+	}
+
+	fn exact_len(&self, tree: usize) -> Result<usize> {
 		let tree = self.get_tree(tree)?;
 		let db = self.db.get()?;
 
@@ -286,7 +424,7 @@ impl IDb for SqliteDb {
 			}
 			TxFnResult::DbErr => {
 				tx.tx.rollback().map_err(Error::from).map_err(TxError::Db)?;
-				Err(TxError::Db(Error(
+				Err(TxError::Db(Error::Message(
 					"(this message will be discarded)".into(),
 				)))
 			}
@@ -308,7 +446,7 @@ struct SqliteTx<'a> {
 impl<'a> SqliteTx<'a> {
 	fn get_tree(&self, i: usize) -> TxOpResult<&'_ str> {
 		self.trees.get(i).map(Arc::as_ref).ok_or_else(|| {
-			TxOpError(Error(
+			TxOpError(Error::Message(
 				"invalid tree id (it might have been openned after the transaction started)".into(),
 			))
 		})
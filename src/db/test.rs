@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use crate::*;
 
 fn test_suite(db: Db) {
@@ -14,7 +16,8 @@ fn test_suite(db: Db) {
 
 	assert!(tree.insert(ka, va).unwrap().is_none());
 	assert_eq!(tree.get(ka).unwrap().unwrap(), va);
-	assert_eq!(tree.len().unwrap(), 1);
+	assert_eq!(tree.exact_len().unwrap(), 1);
+	assert_eq!(tree.fast_len().unwrap(), 1);
 
 	// ---- test transaction logic ----
 
@@ -122,6 +125,47 @@ fn test_suite(db: Db) {
 		Ok(())
 	})
 	.unwrap();
+
+	// ---- test fast_len and exact_len ----
+
+	let tree2 = db.open_tree("tree2").unwrap();
+	assert_eq!(tree2.exact_len().unwrap(), 0);
+	assert_eq!(tree2.fast_len().unwrap(), 0);
+	for i in 0u32..100 {
+		tree2.insert(i.to_be_bytes(), va).unwrap();
+	}
+	assert_eq!(tree2.exact_len().unwrap(), 100);
+	assert_eq!(tree2.fast_len().unwrap(), 100);
+	db.transaction::<_, (), _>(|tx| {
+		assert_eq!(tx.len(&tree2).unwrap(), 100);
+		Ok(())
+	})
+	.unwrap();
+
+	// ---- test clear within transactions ----
+
+	let res = db.transaction::<(), _, _>(|tx| {
+		tx.clear(&tree2).unwrap();
+		assert!(tx.iter(&tree2).unwrap().next().is_none());
+		tx.insert(&tree, kint, va).unwrap();
+		Err(TxError::Abort(42))
+	});
+	assert!(matches!(res, Err(TxError::Abort(42))));
+	assert_eq!(tree2.exact_len().unwrap(), 100);
+	assert_eq!(tree2.fast_len().unwrap(), 100);
+	assert!(tree.get(kint).unwrap().is_none());
+
+	db.transaction::<_, (), _>(|tx| {
+		tx.clear(&tree2).unwrap();
+		assert_eq!(tx.len(&tree2).unwrap(), 0);
+		tx.insert(&tree, kint, va).unwrap();
+		Ok(())
+	})
+	.unwrap();
+	assert_eq!(tree2.exact_len().unwrap(), 0);
+	assert_eq!(tree2.fast_len().unwrap(), 0);
+	assert!(tree2.iter().unwrap().next().is_none());
+	assert_eq!(tree.get(kint).unwrap().unwrap(), va);
 }
 
 #[test]
@@ -134,7 +178,7 @@ fn test_lmdb_db() {
 		.max_dbs(100)
 		.open(&path)
 		.unwrap();
-	let db = LmdbDb::init(db);
+	let db = LmdbDb::init(db, 126);
 	test_suite(db);
 	drop(path);
 }
@@ -148,3 +192,392 @@ fn test_sqlite_db() {
 	let db = SqliteDb::new(manager, false).unwrap();
 	test_suite(db);
 }
+
+fn test_group_commit(engine: Engine, path: &PathBuf) {
+	let opt = OpenOpt {
+		fsync_interval: Some(std::time::Duration::from_millis(50)),
+		..Default::default()
+	};
+
+	let db = open_db(path, engine, &opt).unwrap();
+	let tree = db.open_tree("tree").unwrap();
+	tree.insert(b"test", b"plop").unwrap();
+
+	// Let the background thread sync the database at least once
+	std::thread::sleep(std::time::Duration::from_millis(200));
+	db.sync().unwrap();
+	drop(tree);
+	drop(db);
+
+	let db = open_db(path, engine, &OpenOpt::default()).unwrap();
+	let tree = db.open_tree("tree").unwrap();
+	assert_eq!(tree.get(b"test").unwrap().unwrap(), b"plop");
+}
+
+#[test]
+#[cfg(feature = "lmdb")]
+fn test_lmdb_group_commit() {
+	let path = mktemp::Temp::new_dir().unwrap();
+	test_group_commit(Engine::Lmdb, &path.to_path_buf());
+	drop(path);
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn test_sqlite_group_commit() {
+	let path = mktemp::Temp::new_dir().unwrap();
+	let mut db_path = path.to_path_buf();
+	db_path.push("db.sqlite");
+	test_group_commit(Engine::Sqlite, &db_path);
+	drop(path);
+}
+
+#[test]
+#[cfg(feature = "lmdb")]
+fn test_lmdb_tx_start_error() {
+	use crate::lmdb_adapter::LmdbDb;
+
+	let path = mktemp::Temp::new_dir().unwrap();
+	let db = open_db(&path.to_path_buf(), Engine::Lmdb, &OpenOpt::default()).unwrap();
+	assert!(!db.supports(Capability::SnapshotManifest));
+
+	// heed keeps environments open until the end of the process, so the
+	// read-only environment is opened on a copy of the database
+	let copy_path = path.to_path_buf().join("copy");
+	db.snapshot(&copy_path).unwrap();
+	drop(db);
+
+	// Write transactions cannot be started on a read-only environment:
+	// this must be returned as an error instead of panicking
+	let mut env_builder = heed::EnvOpenOptions::new();
+	env_builder.max_dbs(100);
+	unsafe {
+		env_builder.flag(heed::flags::Flags::MdbRdOnly);
+	}
+	let db = LmdbDb::init(env_builder.open(&copy_path).unwrap(), 126);
+	let res = db.transaction::<_, (), _>(|_tx| Ok(()));
+	assert!(matches!(res, Err(TxError::Db(_))));
+	drop(db);
+	drop(path);
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn test_sqlite_unrelated_tables() {
+	use crate::sqlite_adapter::SqliteDb;
+
+	let path = mktemp::Temp::new_dir().unwrap();
+	let db_path = path.to_path_buf().join("db.sqlite");
+	rusqlite::Connection::open(&db_path)
+		.unwrap()
+		.execute("CREATE TABLE treeish (k BLOB)", [])
+		.unwrap();
+
+	let manager = r2d2_sqlite::SqliteConnectionManager::file(&db_path);
+	let db = SqliteDb::new(manager, false).unwrap();
+	assert!(db.supports(Capability::SnapshotManifest));
+	db.open_tree("tree").unwrap();
+	assert_eq!(db.list_trees().unwrap(), vec!["tree".to_string()]);
+	drop(db);
+	drop(path);
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn test_sqlite_fast_len() {
+	let path = mktemp::Temp::new_dir().unwrap();
+	let db_path = path.to_path_buf().join("db.sqlite");
+
+	// A tree written before row counters existed is counted when opened
+	{
+		let db = rusqlite::Connection::open(&db_path).unwrap();
+		db.execute("CREATE TABLE tree_old (k BLOB PRIMARY KEY, v BLOB)", [])
+			.unwrap();
+		for i in 0u32..10 {
+			db.execute(
+				"INSERT INTO tree_old (k, v) VALUES (?1, ?2)",
+				rusqlite::params![&i.to_be_bytes()[..], &b"plop"[..]],
+			)
+			.unwrap();
+		}
+	}
+
+	let db = open_db(&db_path, Engine::Sqlite, &OpenOpt::default()).unwrap();
+	let old = db.open_tree("old").unwrap();
+	assert_eq!(old.fast_len().unwrap(), 10);
+
+	let tree = db.open_tree("tree").unwrap();
+	let check_len = |n: usize| {
+		assert_eq!(tree.fast_len().unwrap(), n);
+		assert_eq!(tree.exact_len().unwrap(), n);
+	};
+
+	// Inserts, overwrites and removes
+	for i in 0u32..100 {
+		tree.insert(i.to_be_bytes(), b"plop").unwrap();
+	}
+	tree.insert(0u32.to_be_bytes(), b"plip").unwrap();
+	check_len(100);
+	for i in 0u32..10 {
+		tree.remove(i.to_be_bytes()).unwrap();
+	}
+	tree.remove(b"absent").unwrap();
+	check_len(90);
+
+	// Transactions, which are only counted if they are committed
+	let res = db.transaction::<(), _, _>(|tx| {
+		for i in 100u32..150 {
+			tx.insert(&tree, i.to_be_bytes(), b"plop")?;
+		}
+		tx.remove(&tree, 50u32.to_be_bytes())?;
+		Err(TxError::Abort(()))
+	});
+	assert!(matches!(res, Err(TxError::Abort(()))));
+	check_len(90);
+	let res = db.transaction::<(), _, _>(|tx| {
+		tx.clear(&tree)?;
+		Err(TxError::Abort(()))
+	});
+	assert!(matches!(res, Err(TxError::Abort(()))));
+	check_len(90);
+	db.transaction::<_, (), _>(|tx| {
+		for i in 100u32..150 {
+			tx.insert(&tree, i.to_be_bytes(), b"plop")?;
+		}
+		tx.remove(&tree, 50u32.to_be_bytes())?;
+		Ok(())
+	})
+	.unwrap();
+	check_len(139);
+
+	// Clears, outside and within transactions
+	tree.clear().unwrap();
+	check_len(0);
+	tree.insert(b"test", b"plop").unwrap();
+	db.transaction::<_, (), _>(|tx| {
+		tx.clear(&tree)?;
+		tx.insert(&tree, b"a", b"plop")?;
+		tx.insert(&tree, b"b", b"plop")?;
+		Ok(())
+	})
+	.unwrap();
+	check_len(2);
+	drop(tree);
+	drop(old);
+	drop(db);
+
+	// The counters are kept when the database is reopened
+	let db = open_db(&db_path, Engine::Sqlite, &OpenOpt::default()).unwrap();
+	assert_eq!(db.open_tree("tree").unwrap().fast_len().unwrap(), 2);
+	assert_eq!(db.open_tree("old").unwrap().fast_len().unwrap(), 10);
+	let mut trees = db.list_trees().unwrap();
+	trees.sort();
+	assert_eq!(trees, vec!["old".to_string(), "tree".to_string()]);
+	drop(db);
+	drop(path);
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn test_sqlite_compaction() {
+	let path = mktemp::Temp::new_dir().unwrap();
+	let db_path = path.to_path_buf().join("db.sqlite");
+	let db = open_db(&db_path, Engine::Sqlite, &OpenOpt::default()).unwrap();
+	assert!(db.supports(Capability::Compaction));
+
+	let tree = db.open_tree("tree").unwrap();
+	for i in 0u32..1000 {
+		tree.insert(i.to_be_bytes(), [0u8; 1024]).unwrap();
+	}
+	tree.clear().unwrap();
+	assert!(db.compact().unwrap() >= 1000 * 1024);
+	assert_eq!(tree.exact_len().unwrap(), 0);
+	assert_eq!(tree.fast_len().unwrap(), 0);
+	tree.insert(b"test", b"plop").unwrap();
+	assert_eq!(tree.get(b"test").unwrap().unwrap(), b"plop");
+	drop(tree);
+	drop(db);
+	drop(path);
+}
+
+#[test]
+#[cfg(feature = "lmdb")]
+fn test_lmdb_compaction_unsupported() {
+	let path = mktemp::Temp::new_dir().unwrap();
+	let db = open_db(&path.to_path_buf(), Engine::Lmdb, &OpenOpt::default()).unwrap();
+	assert!(!db.supports(Capability::Compaction));
+	assert!(db.compact().is_err());
+	drop(db);
+	drop(path);
+}
+
+#[test]
+#[cfg(feature = "lmdb")]
+fn test_lmdb_readers_full() {
+	let path = mktemp::Temp::new_dir().unwrap();
+	let opt = OpenOpt {
+		lmdb_max_readers: Some(2),
+		..Default::default()
+	};
+	let db = open_db(&path.to_path_buf(), Engine::Lmdb, &opt).unwrap();
+	let tree = db.open_tree("tree").unwrap();
+	tree.insert(b"test", b"plop").unwrap();
+	assert_eq!(db.reader_slots(), Some(ReaderSlots { used: 0, max: 2 }));
+
+	// Iterators hold their read transaction until they are dropped.
+	// LMDB gives one reader slot to each thread, so each iterator is
+	// held by a different thread until it is told to drop it.
+	let hold_iter = || {
+		let tree = tree.clone();
+		let (held_send, held_recv) = std::sync::mpsc::channel();
+		let (release_send, release_recv) = std::sync::mpsc::channel::<()>();
+		let thread = std::thread::spawn(move || {
+			let iter = tree.iter().unwrap();
+			held_send.send(()).unwrap();
+			release_recv.recv().unwrap();
+			drop(iter);
+		});
+		held_recv.recv().unwrap();
+		(release_send, thread)
+	};
+	let (release1, thread1) = hold_iter();
+	let (release2, thread2) = hold_iter();
+	assert_eq!(db.reader_slots().unwrap().used, 2);
+	let err = tree.get(b"test").unwrap_err();
+	assert!(matches!(err, Error::ReadersFull(2)));
+	assert!(err.is_overloaded());
+	assert!(err.to_string().contains("lmdb_max_readers"));
+
+	release1.send(()).unwrap();
+	thread1.join().unwrap();
+	assert_eq!(tree.get(b"test").unwrap().unwrap(), b"plop");
+	release2.send(()).unwrap();
+	thread2.join().unwrap();
+	assert_eq!(db.reader_slots().unwrap().used, 0);
+
+	drop(tree);
+	drop(db);
+	drop(path);
+}
+
+#[cfg(all(unix, any(feature = "lmdb", feature = "sqlite")))]
+fn file_mode(path: &std::path::Path) -> u32 {
+	use std::os::unix::fs::PermissionsExt;
+	std::fs::metadata(path).unwrap().permissions().mode() & 0o7777
+}
+
+#[test]
+#[cfg(all(unix, feature = "lmdb"))]
+fn test_lmdb_mode() {
+	let path = mktemp::Temp::new_dir().unwrap();
+	let db_path = path.to_path_buf().join("db.lmdb");
+	let opt = OpenOpt {
+		mode: Some(0o700),
+		..Default::default()
+	};
+
+	let db = open_db(&db_path, Engine::Lmdb, &opt).unwrap();
+	assert_eq!(file_mode(&db_path), 0o700);
+	assert_eq!(file_mode(&db_path.join("data.mdb")), 0o600);
+	assert_eq!(file_mode(&db_path.join("lock.mdb")), 0o600);
+	drop(db);
+	drop(path);
+}
+
+#[test]
+#[cfg(all(unix, feature = "sqlite"))]
+fn test_sqlite_mode() {
+	let path = mktemp::Temp::new_dir().unwrap();
+	let db_path = path.to_path_buf().join("db.sqlite");
+	let opt = OpenOpt {
+		mode: Some(0o700),
+		..Default::default()
+	};
+
+	let db = open_db(&db_path, Engine::Sqlite, &opt).unwrap();
+	let tree = db.open_tree("tree").unwrap();
+	tree.insert(b"test", b"plop").unwrap();
+	assert_eq!(file_mode(&db_path), 0o600);
+	drop(tree);
+	drop(db);
+	drop(path);
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn test_sqlite_snapshot_during_writes() {
+	use std::sync::atomic::{AtomicBool, Ordering};
+	use std::sync::Arc;
+
+	let path = mktemp::Temp::new_dir().unwrap();
+	let db_path = path.to_path_buf().join("db.sqlite");
+	let snapshot_path = path.to_path_buf().join("snapshot");
+
+	let db = open_db(&db_path, Engine::Sqlite, &OpenOpt::default()).unwrap();
+	let tree_a = db.open_tree("a").unwrap();
+	let tree_b = db.open_tree("b").unwrap();
+
+	// Each transaction inserts the same key in both trees, so in any
+	// consistent snapshot both trees contain keys 0..n for the same n
+	let insert = |i: u32| {
+		db.transaction::<_, (), _>(|tx| {
+			tx.insert(&tree_a, i.to_be_bytes(), [0u8; 1024])?;
+			tx.insert(&tree_b, i.to_be_bytes(), [0u8; 1024])?;
+			Ok(())
+		})
+		.unwrap();
+	};
+	for i in 0u32..2000 {
+		insert(i);
+	}
+
+	let stop = Arc::new(AtomicBool::new(false));
+	let writer = std::thread::spawn({
+		let stop = stop.clone();
+		let db = db.clone();
+		let (tree_a, tree_b) = (tree_a.clone(), tree_b.clone());
+		move || {
+			let mut i = 2000u32;
+			while !stop.load(Ordering::Relaxed) {
+				db.transaction::<_, (), _>(|tx| {
+					tx.insert(&tree_a, i.to_be_bytes(), [0u8; 1024])?;
+					tx.insert(&tree_b, i.to_be_bytes(), [0u8; 1024])?;
+					Ok(())
+				})
+				.unwrap();
+				i += 1;
+			}
+			i
+		}
+	});
+
+	db.snapshot(&snapshot_path).unwrap();
+	stop.store(true, Ordering::Relaxed);
+	let written = writer.join().unwrap() as usize;
+
+	let snap = open_db(&snapshot_path, Engine::Sqlite, &OpenOpt::default()).unwrap();
+	let snap_a = snap.open_tree("a").unwrap();
+	let snap_b = snap.open_tree("b").unwrap();
+	let n = snap_a.exact_len().unwrap();
+	assert!((2000..=written).contains(&n), "{} rows of {}", n, written);
+	assert_eq!(snap_b.exact_len().unwrap(), n);
+	assert_eq!(snap_b.fast_len().unwrap(), n);
+	for tree in [&snap_a, &snap_b] {
+		let (last_key, _) = tree.iter_rev().unwrap().next().unwrap().unwrap();
+		assert_eq!(last_key, (n as u32 - 1).to_be_bytes());
+	}
+
+	let manifest = std::fs::read_to_string(snapshot_manifest_path(&snapshot_path)).unwrap();
+	assert!(
+		manifest.contains(&format!("tree a: {} rows\n", n)),
+		"{}",
+		manifest
+	);
+	assert!(
+		manifest.contains(&format!("tree b: {} rows\n", n)),
+		"{}",
+		manifest
+	);
+
+	drop(path);
+}
@@ -1,7 +1,12 @@
+use std::fmt::Write;
+use std::time::Instant;
+
 use garage_util::data::*;
 
 use garage_table::*;
 
+use garage_block::DataBlock;
+
 use garage_model::helper::error::{Error, OkOrBadRequest};
 use garage_model::s3::object_table::*;
 use garage_model::s3::version_table::*;
@@ -21,6 +26,15 @@ impl AdminRpcHandler {
 				self.handle_block_retry_now(*all, blocks).await
 			}
 			BlockOperation::Purge { yes, blocks } => self.handle_block_purge(*yes, blocks).await,
+			BlockOperation::Replicate {
+				from,
+				to,
+				force,
+				hash,
+			} => {
+				self.handle_block_replicate(hash, from.as_deref(), to.as_deref(), *force)
+					.await
+			}
 		}
 	}
 
@@ -189,6 +203,151 @@ impl AdminRpcHandler {
 		Ok(())
 	}
 
+	async fn handle_block_replicate(
+		&self,
+		hash: &str,
+		from: Option<&str>,
+		to: Option<&str>,
+		force: bool,
+	) -> Result<AdminRpc, Error> {
+		let hash = if hash.len() == 64 {
+			let hash = hex::decode(hash).ok_or_bad_request("invalid hash")?;
+			Hash::try_from(&hash).ok_or_bad_request("invalid hash")?
+		} else {
+			self.find_block_hash_by_prefix(hash)?
+		};
+		let block_manager = &self.garage.block_manager;
+		let known_nodes = self
+			.garage
+			.system
+			.get_known_nodes()
+			.into_iter()
+			.map(|n| n.id)
+			.collect::<Vec<_>>();
+
+		let targets = match to {
+			Some(to) => vec![find_matching_node(known_nodes.iter().cloned(), to)
+				.ok_or_bad_request("invalid --to node")?],
+			None => block_manager.storage_nodes_of(&hash),
+		};
+
+		// Fetch the block, its integrity is checked upon reception
+		let start = Instant::now();
+		let (source, block) = match from {
+			Some(from) => {
+				let node = find_matching_node(known_nodes.iter().cloned(), from)
+					.ok_or_bad_request("invalid --from node")?;
+				(
+					node,
+					block_manager.rpc_get_raw_block_from(&hash, node).await?,
+				)
+			}
+			None => {
+				let mut candidates = self
+					.garage
+					.system
+					.rpc_helper()
+					.block_read_nodes_of(&hash, self.garage.system.rpc_helper());
+				for node in known_nodes.iter() {
+					if !candidates.contains(node) {
+						candidates.push(*node);
+					}
+				}
+				let mut found = None;
+				for node in candidates {
+					match block_manager.rpc_get_raw_block_from(&hash, node).await {
+						Ok(block) => {
+							found = Some((node, block));
+							break;
+						}
+						Err(e) => debug!(
+							"Replicate block {:?}: not read from node {:?}: {}",
+							hash, node, e
+						),
+					}
+				}
+				found.ok_or(GarageError::MissingBlock(hash))?
+			}
+		};
+		let (header, data) = block.into_parts();
+		let size = data.len();
+		info!(
+			"Replicate block {:?}: read {} bytes from node {:?} in {:?}",
+			hash,
+			size,
+			source,
+			start.elapsed()
+		);
+
+		let mut ret = format!(
+			"Block {:?}: read {} bytes from node {:?}\n",
+			hash, size, source
+		);
+		let mut failed = 0;
+		for node in targets {
+			let start = Instant::now();
+			let res = async {
+				if !force && block_manager.rpc_has_block(&hash, node).await? {
+					return Ok(false);
+				}
+				block_manager
+					.rpc_put_raw_block_to(
+						&hash,
+						node,
+						DataBlock::from_parts(header, data.clone()),
+						force,
+					)
+					.await?;
+				// Read the block back to confirm that it was stored correctly
+				block_manager.rpc_get_raw_block_from(&hash, node).await?;
+				Ok::<_, GarageError>(true)
+			}
+			.await;
+			match res {
+				Ok(false) => {
+					info!(
+						"Replicate block {:?}: node {:?} already has it, skipping",
+						hash, node
+					);
+					writeln!(&mut ret, "  {:?}: already stored, skipped", node).unwrap();
+				}
+				Ok(true) => {
+					info!(
+						"Replicate block {:?}: wrote {} bytes to node {:?} in {:?} (verified)",
+						hash,
+						size,
+						node,
+						start.elapsed()
+					);
+					writeln!(
+						&mut ret,
+						"  {:?}: written and verified in {:?}",
+						node,
+						start.elapsed()
+					)
+					.unwrap();
+				}
+				Err(e) => {
+					warn!(
+						"Replicate block {:?}: could not write to node {:?}: {}",
+						hash, node, e
+					);
+					writeln!(&mut ret, "  {:?}: error: {}", node, e).unwrap();
+					failed += 1;
+				}
+			}
+		}
+
+		if failed > 0 {
+			Err(Error::Internal(GarageError::Message(format!(
+				"{}Could not replicate block to {} node(s)",
+				ret, failed
+			))))
+		} else {
+			Ok(AdminRpc::Ok(ret))
+		}
+	}
+
 	// ---- helper function ----
 	fn find_block_hash_by_prefix(&self, prefix: &str) -> Result<Hash, Error> {
 		if prefix.len() < 4 {
@@ -1,7 +1,6 @@
 use std::collections::HashMap;
 use std::fmt::Write;
 
-use garage_util::crdt::*;
 use garage_util::time::*;
 
 use garage_table::*;
@@ -10,6 +9,7 @@ use garage_model::bucket_alias_table::*;
 use garage_model::bucket_table::*;
 use garage_model::helper::error::{Error, OkOrBadRequest};
 use garage_model::permission::*;
+use garage_model::s3::previous_version::restore_previous_version;
 
 use crate::cli::*;
 
@@ -28,9 +28,33 @@ impl AdminRpcHandler {
 			BucketOperation::Deny(query) => self.handle_bucket_deny(query).await,
 			BucketOperation::Website(query) => self.handle_bucket_website(query).await,
 			BucketOperation::SetQuotas(query) => self.handle_bucket_set_quotas(query).await,
+			BucketOperation::SetReadOnly(query) => self.handle_bucket_set_read_only(query).await,
+			BucketOperation::SetAllowedMethods(query) => {
+				self.handle_bucket_set_allowed_methods(query).await
+			}
+			BucketOperation::SetForbiddenAsNotFound(query) => {
+				self.handle_bucket_set_forbidden_as_not_found(query).await
+			}
+			BucketOperation::SetPreviousVersionRetention(query) => {
+				self.handle_bucket_set_previous_version_retention(query)
+					.await
+			}
+			BucketOperation::SetCaseInsensitiveKeys(query) => {
+				self.handle_bucket_set_case_insensitive_keys(query).await
+			}
+			BucketOperation::RestorePreviousVersion(query) => {
+				self.handle_bucket_restore_previous_version(query).await
+			}
 			BucketOperation::CleanupIncompleteUploads(query) => {
 				self.handle_bucket_cleanup_incomplete_uploads(query).await
 			}
+			BucketOperation::DeletePrefix(query) => self.handle_bucket_delete_prefix(query).await,
+			BucketOperation::DeletePrefixStatus(query) => {
+				self.handle_bucket_delete_prefix_status(query)
+			}
+			BucketOperation::VerifyCounters(query) => {
+				self.handle_bucket_verify_counters(query).await
+			}
 		}
 	}
 
@@ -126,7 +150,8 @@ impl AdminRpcHandler {
 
 	#[allow(clippy::ptr_arg)]
 	async fn handle_create_bucket(&self, name: &String) -> Result<AdminRpc, Error> {
-		if !is_valid_bucket_name(name) {
+		let name = &canonical_bucket_name(name).into_owned();
+		if !is_valid_bucket_name(name, self.garage.config.allow_punycode) {
 			return Err(Error::BadRequest(format!(
 				"{}: {}",
 				name, INVALID_BUCKET_NAME_MESSAGE
@@ -168,11 +193,11 @@ impl AdminRpcHandler {
 		let bucket_alias = self
 			.garage
 			.bucket_alias_table
-			.get(&EmptyKey, &query.name)
+			.get(&EmptyKey, &canonical_bucket_name(&query.name).into_owned())
 			.await?;
 
 		// Check bucket doesn't have other aliases
-		let mut bucket = helper.bucket().get_existing_bucket(bucket_id).await?;
+		let bucket = helper.bucket().get_existing_bucket(bucket_id).await?;
 		let bucket_state = bucket.state.as_option().unwrap();
 		if bucket_state
 			.aliases
@@ -207,23 +232,15 @@ impl AdminRpcHandler {
 		}
 
 		// --- done checking, now commit ---
-		// 1. delete authorization from keys that had access
-		for (key_id, _) in bucket.authorized_keys() {
-			helper
-				.set_bucket_key_permissions(bucket.id, key_id, BucketKeyPerm::NO_PERMISSIONS)
-				.await?;
-		}
-
-		// 2. delete bucket alias
+		// 1. delete bucket alias
 		if bucket_alias.is_some() {
 			helper
 				.purge_global_bucket_alias(bucket_id, &query.name)
 				.await?;
 		}
 
-		// 3. delete bucket
-		bucket.state = Deletable::delete();
-		self.garage.bucket_table.insert(&bucket).await?;
+		// 2. delete bucket, its permissions and its remaining aliases
+		helper.delete_bucket(bucket_id).await?;
 
 		Ok(AdminRpc::Ok(format!("Bucket {} was deleted.", query.name)))
 	}
@@ -314,27 +331,44 @@ impl AdminRpcHandler {
 		let allow_read = query.read || key.allow_read(&bucket_id);
 		let allow_write = query.write || key.allow_write(&bucket_id);
 		let allow_owner = query.owner || key.allow_owner(&bucket_id);
+		let allow_list = if query.list {
+			Some(true)
+		} else if query.no_list {
+			Some(false)
+		} else {
+			key.bucket_permissions(&bucket_id).allow_list
+		};
 
+		let perm = BucketKeyPerm {
+			timestamp: now_msec(),
+			allow_read,
+			allow_write,
+			allow_owner,
+			allow_list,
+		};
 		helper
-			.set_bucket_key_permissions(
-				bucket_id,
-				&key.key_id,
-				BucketKeyPerm {
-					timestamp: now_msec(),
-					allow_read,
-					allow_write,
-					allow_owner,
-				},
-			)
+			.set_bucket_key_permissions(bucket_id, &key.key_id, perm)
 			.await?;
 
 		Ok(AdminRpc::Ok(format!(
-			"New permissions for {} on {}: read {}, write {}, owner {}.",
-			&key.key_id, &query.bucket, allow_read, allow_write, allow_owner
+			"New permissions for {} on {}: read {}, write {}, owner {}, list {}.",
+			&key.key_id,
+			&query.bucket,
+			allow_read,
+			allow_write,
+			allow_owner,
+			perm.can_list()
 		)))
 	}
 
 	async fn handle_bucket_deny(&self, query: &PermBucketOpt) -> Result<AdminRpc, Error> {
+		if query.no_list {
+			return Err(Error::BadRequest(
+				"--no-list can only be used with `bucket allow`, use --list to deny listing"
+					.to_string(),
+			));
+		}
+
 		let helper = self.garage.locked_helper().await;
 
 		let bucket_id = helper
@@ -349,23 +383,31 @@ impl AdminRpcHandler {
 		let allow_read = !query.read && key.allow_read(&bucket_id);
 		let allow_write = !query.write && key.allow_write(&bucket_id);
 		let allow_owner = !query.owner && key.allow_owner(&bucket_id);
+		let allow_list = if query.list {
+			Some(false)
+		} else {
+			key.bucket_permissions(&bucket_id).allow_list
+		};
 
+		let perm = BucketKeyPerm {
+			timestamp: now_msec(),
+			allow_read,
+			allow_write,
+			allow_owner,
+			allow_list,
+		};
 		helper
-			.set_bucket_key_permissions(
-				bucket_id,
-				&key.key_id,
-				BucketKeyPerm {
-					timestamp: now_msec(),
-					allow_read,
-					allow_write,
-					allow_owner,
-				},
-			)
+			.set_bucket_key_permissions(bucket_id, &key.key_id, perm)
 			.await?;
 
 		Ok(AdminRpc::Ok(format!(
-			"New permissions for {} on {}: read {}, write {}, owner {}.",
-			&key.key_id, &query.bucket, allow_read, allow_write, allow_owner
+			"New permissions for {} on {}: read {}, write {}, owner {}, list {}.",
+			&key.key_id,
+			&query.bucket,
+			allow_read,
+			allow_write,
+			allow_owner,
+			perm.can_list()
 		)))
 	}
 
@@ -424,9 +466,12 @@ impl AdminRpcHandler {
 			.await?;
 		let bucket_state = bucket.state.as_option_mut().unwrap();
 
-		if query.max_size.is_none() && query.max_objects.is_none() {
+		if query.max_size.is_none()
+			&& query.max_objects.is_none()
+			&& query.max_multipart_uploads.is_none()
+		{
 			return Err(Error::BadRequest(
-				"You must specify either --max-size or --max-objects (or both) for this command to do something.".to_string(),
+				"You must specify at least one of --max-size, --max-objects or --max-multipart-uploads for this command to do something.".to_string(),
 			));
 		}
 
@@ -454,6 +499,17 @@ impl AdminRpcHandler {
 			_ => (),
 		}
 
+		match query.max_multipart_uploads.as_ref().map(String::as_ref) {
+			Some("none") => quotas.max_multipart_uploads = None,
+			Some(v) => {
+				let mu = v
+					.parse::<u64>()
+					.ok_or_bad_request(format!("Invalid number specified: {}", v))?;
+				quotas.max_multipart_uploads = Some(mu);
+			}
+			_ => (),
+		}
+
 		bucket_state.quotas.update(quotas);
 		self.garage.bucket_table.insert(&bucket).await?;
 
@@ -463,6 +519,232 @@ impl AdminRpcHandler {
 		)))
 	}
 
+	async fn handle_bucket_set_read_only(&self, query: &SetReadOnlyOpt) -> Result<AdminRpc, Error> {
+		if query.enable == query.disable {
+			return Err(Error::BadRequest(
+				"You must specify exactly one of --enable or --disable".to_string(),
+			));
+		}
+
+		let bucket_id = self
+			.garage
+			.bucket_helper()
+			.admin_get_existing_matching_bucket(&query.bucket)
+			.await?;
+
+		let mut bucket = self
+			.garage
+			.bucket_helper()
+			.get_existing_bucket(bucket_id)
+			.await?;
+		let bucket_state = bucket.state.as_option_mut().unwrap();
+
+		bucket_state.read_only.update(query.enable);
+		self.garage.bucket_table.insert(&bucket).await?;
+
+		let msg = if query.enable {
+			format!("Bucket {} is now read-only.", &query.bucket)
+		} else {
+			format!("Bucket {} is now writable.", &query.bucket)
+		};
+		Ok(AdminRpc::Ok(msg))
+	}
+
+	async fn handle_bucket_set_allowed_methods(
+		&self,
+		query: &SetAllowedMethodsOpt,
+	) -> Result<AdminRpc, Error> {
+		if query.all != query.methods.is_empty() {
+			return Err(Error::BadRequest(
+				"You must specify either a list of methods or --all".to_string(),
+			));
+		}
+		let methods = parse_bucket_allowed_methods(&query.methods).map_err(Error::BadRequest)?;
+
+		let bucket_id = self
+			.garage
+			.bucket_helper()
+			.admin_get_existing_matching_bucket(&query.bucket)
+			.await?;
+
+		let mut bucket = self
+			.garage
+			.bucket_helper()
+			.get_existing_bucket(bucket_id)
+			.await?;
+		let bucket_state = bucket.state.as_option_mut().unwrap();
+
+		bucket_state.allowed_methods.update(methods.clone());
+		self.garage.bucket_table.insert(&bucket).await?;
+
+		let msg = match methods {
+			None => format!("All methods are now allowed on bucket {}.", &query.bucket),
+			Some(m) => format!(
+				"Methods allowed on bucket {}: {}",
+				&query.bucket,
+				m.join(", ")
+			),
+		};
+		Ok(AdminRpc::Ok(msg))
+	}
+
+	async fn handle_bucket_set_forbidden_as_not_found(
+		&self,
+		query: &SetForbiddenAsNotFoundOpt,
+	) -> Result<AdminRpc, Error> {
+		if query.enable == query.disable {
+			return Err(Error::BadRequest(
+				"You must specify exactly one of --enable or --disable".to_string(),
+			));
+		}
+
+		let bucket_id = self
+			.garage
+			.bucket_helper()
+			.admin_get_existing_matching_bucket(&query.bucket)
+			.await?;
+
+		let mut bucket = self
+			.garage
+			.bucket_helper()
+			.get_existing_bucket(bucket_id)
+			.await?;
+		let bucket_state = bucket.state.as_option_mut().unwrap();
+
+		bucket_state.forbidden_as_not_found.update(query.enable);
+		self.garage.bucket_table.insert(&bucket).await?;
+
+		let msg = if query.enable {
+			format!(
+				"Reading objects of bucket {} without permission now returns 404 NoSuchKey.",
+				&query.bucket
+			)
+		} else {
+			format!(
+				"Reading objects of bucket {} without permission now returns 403 AccessDenied.",
+				&query.bucket
+			)
+		};
+		Ok(AdminRpc::Ok(msg))
+	}
+
+	async fn handle_bucket_set_case_insensitive_keys(
+		&self,
+		query: &SetCaseInsensitiveKeysOpt,
+	) -> Result<AdminRpc, Error> {
+		if query.enable == query.disable {
+			return Err(Error::BadRequest(
+				"You must specify exactly one of --enable or --disable".to_string(),
+			));
+		}
+
+		let bucket_id = self
+			.garage
+			.bucket_helper()
+			.admin_get_existing_matching_bucket(&query.bucket)
+			.await?;
+
+		// Existing objects are stored under keys that would not be found
+		// anymore if the key casing rules changed
+		if !self
+			.garage
+			.bucket_helper()
+			.is_bucket_empty(bucket_id)
+			.await?
+		{
+			return Err(Error::BadRequest(format!(
+				"Bucket {} is not empty: key case sensitivity can only be changed on empty buckets.",
+				&query.bucket
+			)));
+		}
+
+		let mut bucket = self
+			.garage
+			.bucket_helper()
+			.get_existing_bucket(bucket_id)
+			.await?;
+		let bucket_state = bucket.state.as_option_mut().unwrap();
+
+		bucket_state.case_insensitive_keys.update(query.enable);
+		self.garage.bucket_table.insert(&bucket).await?;
+
+		let msg = if query.enable {
+			format!(
+				"Object keys of bucket {} are now case-insensitive.",
+				&query.bucket
+			)
+		} else {
+			format!(
+				"Object keys of bucket {} are now case-sensitive.",
+				&query.bucket
+			)
+		};
+		Ok(AdminRpc::Ok(msg))
+	}
+
+	async fn handle_bucket_set_previous_version_retention(
+		&self,
+		query: &SetPreviousVersionRetentionOpt,
+	) -> Result<AdminRpc, Error> {
+		let retention = match query.retention.as_str() {
+			"none" => None,
+			r => {
+				let duration = parse_duration::parse::parse(r)
+					.ok_or_bad_request("Invalid retention duration")?;
+				Some(duration.as_secs()).filter(|s| *s > 0)
+			}
+		};
+
+		let bucket_id = self
+			.garage
+			.bucket_helper()
+			.admin_get_existing_matching_bucket(&query.bucket)
+			.await?;
+
+		let mut bucket = self
+			.garage
+			.bucket_helper()
+			.get_existing_bucket(bucket_id)
+			.await?;
+		let bucket_state = bucket.state.as_option_mut().unwrap();
+
+		bucket_state
+			.previous_version_retention_secs
+			.update(retention);
+		self.garage.bucket_table.insert(&bucket).await?;
+
+		let msg = match retention {
+			Some(secs) => format!(
+				"Previous versions of overwritten objects in bucket {} are now kept for {} seconds.",
+				&query.bucket, secs
+			),
+			None => format!(
+				"Previous versions of overwritten objects in bucket {} are no longer kept.",
+				&query.bucket
+			),
+		};
+		Ok(AdminRpc::Ok(msg))
+	}
+
+	async fn handle_bucket_restore_previous_version(
+		&self,
+		query: &RestorePreviousVersionOpt,
+	) -> Result<AdminRpc, Error> {
+		let bucket_id = self
+			.garage
+			.bucket_helper()
+			.admin_get_existing_matching_bucket(&query.bucket)
+			.await?;
+
+		let restored = restore_previous_version(&self.garage, bucket_id, &query.key).await?;
+
+		Ok(AdminRpc::Ok(format!(
+			"Restored version {} of object {} (the replaced version is now the previous version).",
+			hex::encode(restored.uuid),
+			&query.key
+		)))
+	}
+
 	async fn handle_bucket_cleanup_incomplete_uploads(
 		&self,
 		query: &CleanupIncompleteUploadsOpt,
@@ -497,4 +779,183 @@ impl AdminRpcHandler {
 
 		Ok(AdminRpc::Ok(ret))
 	}
+
+	async fn handle_bucket_delete_prefix(
+		&self,
+		query: &DeletePrefixOpt,
+	) -> Result<AdminRpc, Error> {
+		let bucket_id = self
+			.garage
+			.bucket_helper()
+			.admin_get_existing_matching_bucket(&query.bucket)
+			.await?;
+
+		let status = self
+			.garage
+			.delete_prefix_jobs
+			.start(
+				self.garage.clone(),
+				bucket_id,
+				query.prefix.clone(),
+				query.dry_run,
+				query.allow_empty_prefix,
+			)
+			.await?;
+
+		Ok(AdminRpc::Ok(format!(
+			"Started delete-prefix job {}{}.\n\
+			Use `garage bucket delete-prefix-status {}` to follow its progress.",
+			hex::encode(status.job_id),
+			if status.dry_run { " (dry run)" } else { "" },
+			hex::encode(status.job_id),
+		)))
+	}
+
+	fn handle_bucket_delete_prefix_status(
+		&self,
+		query: &DeletePrefixStatusOpt,
+	) -> Result<AdminRpc, Error> {
+		let jobs = &self.garage.delete_prefix_jobs;
+
+		let statuses = match &query.job_id {
+			None if query.cancel => {
+				return Err(Error::BadRequest(
+					"Please specify the ID of the job to cancel".into(),
+				))
+			}
+			None => jobs.list(),
+			Some(id) => {
+				let job_id = hex::decode(id)
+					.ok()
+					.and_then(|b| Uuid::try_from(&b))
+					.ok_or_bad_request("Invalid job ID")?;
+				let status = if query.cancel {
+					jobs.cancel(&job_id)
+				} else {
+					jobs.get(&job_id)
+				};
+				vec![status.ok_or_bad_request(format!("Job {} not found on this node", id))?]
+			}
+		};
+
+		let mut table = vec!["ID\tBucket\tPrefix\tState\tObjects\tSize\tStarted".to_string()];
+		for s in statuses {
+			table.push(format!(
+				"{}\t{:?}\t{}\t{}{}\t{}\t{}\t{}",
+				hex::encode(s.job_id),
+				s.bucket_id,
+				s.prefix,
+				s.state,
+				if s.dry_run { " (dry run)" } else { "" },
+				s.objects_processed,
+				bytesize::ByteSize::b(s.bytes_freed).to_string_as(true),
+				msec_to_rfc3339(s.started_at),
+			));
+			if let Some(e) = s.error {
+				table.push(format!("\t\t\terror: {}", e));
+			}
+		}
+
+		let mut ret = String::new();
+		format_table_to_string(table)
+			.lines()
+			.for_each(|l| writeln!(&mut ret, "{}", l).unwrap());
+		if query.cancel {
+			writeln!(&mut ret, "Cancellation requested.").unwrap();
+		}
+		Ok(AdminRpc::Ok(ret))
+	}
+
+	async fn handle_bucket_verify_counters(
+		&self,
+		query: &VerifyCountersOpt,
+	) -> Result<AdminRpc, Error> {
+		let bucket_id = self
+			.garage
+			.bucket_helper()
+			.admin_get_existing_matching_bucket(&query.bucket)
+			.await?;
+
+		let report = self
+			.garage
+			.object_counter_checker
+			.check_bucket(bucket_id, query.fix)
+			.await?;
+
+		if query.json {
+			let json = serde_json::json!({
+				"bucketId": hex::encode(report.bucket_id),
+				"counters": report.counters,
+				"drift": report.has_drift(),
+				"nodes": report.nodes.iter().map(|n| serde_json::json!({
+					"nodeId": hex::encode(n.node),
+					"counters": n.counters,
+					"actual": n.actual,
+					"drift": n.drift(),
+					"fixed": n.fixed,
+				})).collect::<Vec<_>>(),
+				"errors": report.errors.iter().map(|(node, e)| serde_json::json!({
+					"nodeId": hex::encode(node),
+					"error": e,
+				})).collect::<Vec<_>>(),
+			});
+			return Ok(AdminRpc::Ok(
+				serde_json::to_string_pretty(&json).map_err(GarageError::from)?,
+			));
+		}
+
+		let mut table = vec!["Node\tCounter\tValue\tActual\tDrift".to_string()];
+		for n in report.nodes.iter() {
+			let drift = n.drift();
+			for (name, actual) in n.actual.iter() {
+				table.push(format!(
+					"{:?}\t{}\t{}\t{}\t{}",
+					n.node,
+					name,
+					n.counters.get(name).copied().unwrap_or(0),
+					actual,
+					match drift.get(name) {
+						Some(d) if n.fixed => format!("{:+} (fixed)", d),
+						Some(d) => format!("{:+}", d),
+						None => "-".into(),
+					}
+				));
+			}
+		}
+
+		let mut ret = String::new();
+		format_table_to_string(table)
+			.lines()
+			.for_each(|l| writeln!(&mut ret, "{}", l).unwrap());
+		writeln!(
+			&mut ret,
+			"\nValues in the counter table: {}",
+			report
+				.counters
+				.iter()
+				.map(|(name, v)| format!("{}={}", name, v))
+				.collect::<Vec<_>>()
+				.join(", ")
+		)
+		.unwrap();
+		for (node, e) in report.errors.iter() {
+			writeln!(&mut ret, "Could not check node {:?}: {}", node, e).unwrap();
+		}
+		if !report.has_drift() {
+			writeln!(
+				&mut ret,
+				"\nObject counters are consistent with the objects."
+			)
+			.unwrap();
+		} else if query.fix {
+			writeln!(&mut ret, "\nObject counters have been corrected.").unwrap();
+		} else {
+			writeln!(
+				&mut ret,
+				"\nObject counters have drifted, use --fix to correct them."
+			)
+			.unwrap();
+		}
+		Ok(AdminRpc::Ok(ret))
+	}
 }
@@ -19,6 +19,8 @@ impl AdminRpcHandler {
 			KeyOperation::Delete(query) => self.handle_delete_key(query).await,
 			KeyOperation::Allow(query) => self.handle_allow_key(query).await,
 			KeyOperation::Deny(query) => self.handle_deny_key(query).await,
+			KeyOperation::Suspend(query) => self.handle_suspend_key(query, true).await,
+			KeyOperation::Resume(query) => self.handle_suspend_key(query, false).await,
 			KeyOperation::Import(query) => self.handle_import_key(query).await,
 		}
 	}
@@ -36,7 +38,13 @@ impl AdminRpcHandler {
 			)
 			.await?
 			.iter()
-			.map(|k| (k.key_id.to_string(), k.params().unwrap().name.get().clone()))
+			.map(|k| {
+				(
+					k.key_id.to_string(),
+					k.params().unwrap().name.get().clone(),
+					k.is_suspended(),
+				)
+			})
 			.collect::<Vec<_>>();
 		Ok(AdminRpc::KeyList(key_ids))
 	}
@@ -49,7 +57,9 @@ impl AdminRpcHandler {
 			.await?;
 
 		if !query.show_secret {
-			key.state.as_option_mut().unwrap().secret_key = "(redacted)".into();
+			let params = key.state.as_option_mut().unwrap();
+			params.secret_key = "(redacted)".into();
+			params.rotated_secret_key.update(None);
 		}
 
 		self.key_info_result(key).await
@@ -123,6 +133,21 @@ impl AdminRpcHandler {
 		self.key_info_result(key).await
 	}
 
+	async fn handle_suspend_key(
+		&self,
+		query: &KeySuspendOpt,
+		suspended: bool,
+	) -> Result<AdminRpc, Error> {
+		let mut key = self
+			.garage
+			.key_helper()
+			.get_existing_matching_key(&query.key_pattern)
+			.await?;
+		key.params_mut().unwrap().suspended.update(suspended);
+		self.garage.key_table.insert(&key).await?;
+		self.key_info_result(key).await
+	}
+
 	async fn handle_import_key(&self, query: &KeyImportOpt) -> Result<AdminRpc, Error> {
 		if !query.yes {
 			return Err(Error::BadRequest("This command is intended to re-import keys that were previously generated by Garage. If you want to create a new key, use `garage key new` instead. Add the --yes flag if you really want to re-import a key.".to_string()));
@@ -14,6 +14,7 @@ use format_table::format_table_to_string;
 use garage_util::background::BackgroundRunner;
 use garage_util::data::*;
 use garage_util::error::Error as GarageError;
+use garage_util::time::*;
 
 use garage_table::replication::*;
 use garage_table::*;
@@ -24,11 +25,14 @@ use garage_rpc::*;
 use garage_block::manager::BlockResyncErrorInfo;
 
 use garage_model::bucket_table::*;
+use garage_model::compaction::async_compact_metadata;
 use garage_model::garage::Garage;
 use garage_model::helper::error::{Error, OkOrBadRequest};
 use garage_model::key_table::*;
 use garage_model::s3::mpu_table::MultipartUpload;
 use garage_model::s3::version_table::Version;
+use garage_model::snapshot::SnapshotWorker;
+use garage_model::usage_history::UsageSample;
 
 use crate::cli::*;
 use crate::repair::online::launch_online_repair;
@@ -55,7 +59,7 @@ pub enum AdminRpc {
 		counters: HashMap<String, i64>,
 		mpu_counters: HashMap<String, i64>,
 	},
-	KeyList(Vec<(String, String)>),
+	KeyList(Vec<(String, String, bool)>),
 	KeyInfo(Key, HashMap<Uuid, Bucket>),
 	WorkerList(
 		HashMap<usize, garage_util::background::WorkerInfo>,
@@ -142,6 +146,10 @@ impl AdminRpcHandler {
 	// ================ STATS COMMANDS ====================
 
 	async fn handle_stats(&self, opt: StatsOpt) -> Result<AdminRpc, Error> {
+		if let Some(period) = &opt.history {
+			return Ok(AdminRpc::Ok(self.gather_usage_history(period)?));
+		}
+
 		if opt.all_nodes {
 			let mut ret = String::new();
 			let all_nodes = self.garage.system.cluster_layout().all_nodes().to_vec();
@@ -334,12 +342,83 @@ impl AdminRpcHandler {
 		ret
 	}
 
+	fn gather_usage_history(&self, period: &str) -> Result<String, Error> {
+		let history = self.garage.usage_history.as_ref().ok_or_bad_request(
+			"Usage history is not enabled on this node (`usage_history_interval` is not set)",
+		)?;
+		let period = parse_duration::parse(period).ok_or_bad_request("Invalid period")?;
+		let since = now_msec().saturating_sub(period.as_millis() as u64);
+		let samples = history.get_since(since)?;
+		if samples.is_empty() {
+			return Ok("No usage history has been recorded for this period.".into());
+		}
+
+		let mut ret = String::new();
+		writeln!(
+			&mut ret,
+			"Usage history recorded by node {:?} (one sample every {}s):",
+			self.garage.system.id,
+			history.interval.as_secs()
+		)
+		.unwrap();
+
+		let points = sample_over_time(&samples, 60);
+		writeln!(
+			&mut ret,
+			"\n  Size:        {}",
+			sparkline(points.iter().map(|s| s.bytes))
+		)
+		.unwrap();
+		writeln!(
+			&mut ret,
+			"  Objects:     {}",
+			sparkline(points.iter().map(|s| s.objects))
+		)
+		.unwrap();
+		writeln!(
+			&mut ret,
+			"  Free space:  {}",
+			sparkline(points.iter().map(|s| data_avail(s).0 as i64))
+		)
+		.unwrap();
+
+		let mut table = vec!["  Time\tSize\tObjects\tDataAvail\tResyncQueue".into()];
+		for s in sample_over_time(&samples, 24) {
+			let (avail, total) = data_avail(s);
+			let avail = if total > 0 {
+				format!(
+					"{} ({:.1}%)",
+					bytesize::ByteSize::b(avail),
+					(avail as f64) / (total as f64) * 100.
+				)
+			} else {
+				"?".into()
+			};
+			table.push(format!(
+				"  {}\t{}\t{}\t{}\t{}",
+				msec_to_rfc3339(s.timestamp),
+				bytesize::ByteSize::b(s.bytes.max(0) as u64),
+				s.objects,
+				avail,
+				s.resync_queue_len
+			));
+		}
+		write!(&mut ret, "\n{}", format_table_to_string(table)).unwrap();
+
+		Ok(ret)
+	}
+
 	fn gather_table_stats<F, R>(&self, t: &Arc<Table<F, R>>) -> Result<String, Error>
 	where
 		F: TableSchema + 'static,
 		R: TableReplication + 'static,
 	{
-		let data_len = t.data.store.len().map_err(GarageError::from)?.to_string();
+		let data_len = t
+			.data
+			.store
+			.fast_len()
+			.map_err(GarageError::from)?
+			.to_string();
 		let mkl_len = t.merkle_updater.merkle_tree_len()?.to_string();
 
 		Ok(format!(
@@ -421,7 +500,7 @@ impl AdminRpcHandler {
 				vars.sort();
 				Ok(AdminRpc::WorkerVars(
 					vars.into_iter()
-						.map(|(k, v)| (self.garage.system.id, k.to_string(), v))
+						.map(|(k, v)| (self.garage.system.id, k, v))
 						.collect(),
 				))
 			}
@@ -498,11 +577,60 @@ impl AdminRpcHandler {
 				Ok(AdminRpc::Ok(format_table_to_string(ret)))
 			}
 			MetaOperation::Snapshot { all: false } => {
-				garage_model::snapshot::async_snapshot_metadata(&self.garage).await?;
-				Ok(AdminRpc::Ok("Snapshot has been saved.".into()))
+				self.background
+					.spawn_worker(SnapshotWorker::new(self.garage.clone()));
+				Ok(AdminRpc::Ok(
+					"Snapshot started, use `garage worker list` to follow its progress.".into(),
+				))
 			}
+			MetaOperation::Compact => {
+				let report = async_compact_metadata(&self.garage).await?;
+				Ok(AdminRpc::Ok(format!(
+					"Metadata db compacted in {:.1}s, {} reclaimed.",
+					report.duration.as_secs_f32(),
+					bytesize::ByteSize::b(report.reclaimed_bytes)
+				)))
+			}
+		}
+	}
+}
+
+/// Select at most `n` samples evenly spread over the period covered by `samples`
+fn sample_over_time(samples: &[UsageSample], n: usize) -> Vec<&UsageSample> {
+	let (first, last) = match (samples.first(), samples.last()) {
+		(Some(first), Some(last)) => (first.timestamp, last.timestamp),
+		_ => return vec![],
+	};
+
+	let mut ret: Vec<&UsageSample> = vec![];
+	for i in 0..n as u64 {
+		let t = first + (last - first) * i / (n as u64 - 1).max(1);
+		// Last sample taken at or before t
+		let s = &samples[samples.partition_point(|s| s.timestamp <= t) - 1];
+		if ret.last().map(|x| x.timestamp) != Some(s.timestamp) {
+			ret.push(s);
 		}
 	}
+	ret
+}
+
+/// Available and total space in the data directories of all storage nodes
+fn data_avail(sample: &UsageSample) -> (u64, u64) {
+	sample.nodes.iter().fold((0, 0), |(avail, total), n| {
+		(avail + n.data_avail, total + n.data_total)
+	})
+}
+
+fn sparkline(values: impl Iterator<Item = i64> + Clone) -> String {
+	const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+	let min = values.clone().min().unwrap_or(0);
+	let max = values.clone().max().unwrap_or(0);
+	values
+		.map(|v| match max - min {
+			0 => BARS[0],
+			range => BARS[((v - min) as i128 * 7 / range as i128) as usize],
+		})
+		.collect()
 }
 
 #[async_trait]
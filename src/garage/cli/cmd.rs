@@ -5,6 +5,7 @@ use format_table::format_table;
 use garage_util::error::*;
 
 use garage_rpc::layout::*;
+use garage_rpc::peer_state::PeerState;
 use garage_rpc::system::*;
 use garage_rpc::*;
 
@@ -24,6 +25,9 @@ pub async fn cli_command_dispatch(
 		Command::Node(NodeOperation::Connect(connect_opt)) => {
 			Ok(cmd_connect(system_rpc_endpoint, rpc_host, connect_opt).await?)
 		}
+		Command::Node(NodeOperation::Reconnect(reconnect_opt)) => {
+			Ok(cmd_reconnect(system_rpc_endpoint, rpc_host, reconnect_opt).await?)
+		}
 		Command::Layout(layout_opt) => {
 			Ok(cli_layout_command_dispatch(layout_opt, system_rpc_endpoint, rpc_host).await?)
 		}
@@ -44,6 +48,9 @@ pub async fn cli_command_dispatch(
 		Command::Meta(mo) => {
 			cmd_admin(admin_rpc_endpoint, rpc_host, AdminRpc::MetaOperation(mo)).await
 		}
+		Command::CompleteNames(cno) => {
+			completions::cmd_complete_names(admin_rpc_endpoint, rpc_host, cno).await
+		}
 		_ => unreachable!(),
 	}
 }
@@ -116,15 +123,111 @@ pub async fn cmd_status(rpc_cli: &Endpoint<SystemRpc, ()>, rpc_host: NodeID) ->
 	}
 	format_table(healthy_nodes);
 
+	// Nodes that are up but lagging behind are still used normally,
+	// we just report them here
+	let tf = timeago::Formatter::new();
+	let mut degraded_nodes = vec!["ID\tHostname\tState\tSince\tLast RPC".to_string()];
+	for adv in status.iter().filter(|adv| adv.is_up) {
+		if !matches!(adv.state, PeerState::Slow | PeerState::LayoutLagging) {
+			continue;
+		}
+		degraded_nodes.push(format!(
+			"{id:?}\t{host}\t{state}\t{since}\t{last_rpc}",
+			id = adv.id,
+			host = adv.status.hostname.as_deref().unwrap_or("?"),
+			state = adv.state.as_str(),
+			since = secs_ago_string(&tf, adv.state_since_secs_ago),
+			last_rpc = secs_ago_string(&tf, adv.last_rpc_secs_ago),
+		));
+	}
+	if degraded_nodes.len() > 1 {
+		println!("\n==== DEGRADED NODES ====");
+		format_table(degraded_nodes);
+	}
+
+	// Nodes that see themselves as partitioned from the majority of the
+	// layout refuse writes if minority_partition_protection is enabled
+	let partitioned_nodes = status
+		.iter()
+		.filter(|adv| adv.is_up && adv.status.minority_partition)
+		.map(|adv| {
+			format!(
+				"{id:?}\t{host}",
+				id = adv.id,
+				host = adv.status.hostname.as_deref().unwrap_or("?"),
+			)
+		})
+		.collect::<Vec<_>>();
+	if !partitioned_nodes.is_empty() {
+		println!("\n==== NODES IN A MINORITY PARTITION (WRITES REFUSED) ====");
+		let mut table = vec!["ID\tHostname".to_string()];
+		table.extend(partitioned_nodes);
+		format_table(table);
+	}
+
+	let read_only_nodes = status
+		.iter()
+		.filter(|adv| adv.is_up && adv.status.read_only_api)
+		.map(|adv| {
+			format!(
+				"{id:?}\t{host}",
+				id = adv.id,
+				host = adv.status.hostname.as_deref().unwrap_or("?"),
+			)
+		})
+		.collect::<Vec<_>>();
+	if !read_only_nodes.is_empty() {
+		println!("\n==== NODES WITH A READ-ONLY S3 API ====");
+		let mut table = vec!["ID\tHostname".to_string()];
+		table.extend(read_only_nodes);
+		format_table(table);
+	}
+
+	let degraded_storage_nodes = status
+		.iter()
+		.filter(|adv| adv.is_up && adv.status.data_storage_degraded)
+		.map(|adv| {
+			format!(
+				"{id:?}\t{host}",
+				id = adv.id,
+				host = adv.status.hostname.as_deref().unwrap_or("?"),
+			)
+		})
+		.collect::<Vec<_>>();
+	if !degraded_storage_nodes.is_empty() {
+		println!("\n==== NODES WITH DEGRADED DATA STORAGE (BLOCK WRITES REFUSED) ====");
+		let mut table = vec!["ID\tHostname".to_string()];
+		table.extend(degraded_storage_nodes);
+		format_table(table);
+	}
+
+	let clock_behind_nodes = status
+		.iter()
+		.filter(|adv| adv.is_up)
+		.filter_map(|adv| {
+			let behind = adv.status.clock_behind_msec?;
+			Some(format!(
+				"{id:?}\t{host}\t{behind} ms",
+				id = adv.id,
+				host = adv.status.hostname.as_deref().unwrap_or("?"),
+			))
+		})
+		.collect::<Vec<_>>();
+	if !clock_behind_nodes.is_empty() {
+		println!("\n==== NODES WITH A SYSTEM CLOCK THAT WENT BACKWARDS ====");
+		let mut table = vec!["ID\tHostname\tBehind latest timestamp".to_string()];
+		table.extend(clock_behind_nodes);
+		format_table(table);
+	}
+
 	// Determine which nodes are unhealthy and print that to stdout
 	let status_map = status
 		.iter()
 		.map(|adv| (adv.id, adv))
 		.collect::<HashMap<_, _>>();
 
-	let tf = timeago::Formatter::new();
 	let mut drain_msg = false;
-	let mut failed_nodes = vec!["ID\tHostname\tTags\tZone\tCapacity\tLast seen".to_string()];
+	let mut failed_nodes = vec!["ID\tHostname\tTags\tZone\tCapacity\tState\tLast seen".to_string()];
 	let mut listed = HashSet::new();
 	for ver in layout.versions.iter().rev() {
 		for (node, _, role) in ver.roles.items().iter() {
@@ -145,14 +248,15 @@ pub async fn cmd_status(rpc_cli: &Endpoint<SystemRpc, ()>, rpc_host: NodeID) ->
 
 			// Node is in a layout version, is not a gateway node, and is not up:
 			// it is in a failed state, add proper line to the output
-			let (host, last_seen) = match adv {
+			let (host, state, last_seen) = match adv {
 				Some(adv) => (
 					adv.status.hostname.as_deref().unwrap_or("?"),
+					adv.state,
 					adv.last_seen_secs_ago
 						.map(|s| tf.convert(Duration::from_secs(s)))
 						.unwrap_or_else(|| "never seen".into()),
 				),
-				None => ("??", "never seen".into()),
+				None => ("??", PeerState::NeverConnected, "never seen".into()),
 			};
 			let capacity = if ver.version == layout.current().version {
 				cfg.capacity_string()
@@ -161,12 +265,13 @@ pub async fn cmd_status(rpc_cli: &Endpoint<SystemRpc, ()>, rpc_host: NodeID) ->
 				"draining metadata...".to_string()
 			};
 			failed_nodes.push(format!(
-				"{id:?}\t{host}\t[{tags}]\t{zone}\t{capacity}\t{last_seen}",
+				"{id:?}\t{host}\t[{tags}]\t{zone}\t{capacity}\t{state}\t{last_seen}",
 				id = node,
 				host = host,
 				tags = cfg.tags.join(","),
 				zone = cfg.zone,
 				capacity = capacity,
+				state = state.as_str(),
 				last_seen = last_seen,
 			));
 		}
@@ -194,6 +299,12 @@ pub async fn cmd_status(rpc_cli: &Endpoint<SystemRpc, ()>, rpc_host: NodeID) ->
 	Ok(())
 }
 
+fn secs_ago_string(tf: &timeago::Formatter, secs_ago: Option<u64>) -> String {
+	secs_ago
+		.map(|s| tf.convert(Duration::from_secs(s)))
+		.unwrap_or_else(|| "never".into())
+}
+
 pub async fn cmd_connect(
 	rpc_cli: &Endpoint<SystemRpc, ()>,
 	rpc_host: NodeID,
@@ -211,6 +322,36 @@ pub async fn cmd_connect(
 	}
 }
 
+pub async fn cmd_reconnect(
+	rpc_cli: &Endpoint<SystemRpc, ()>,
+	rpc_host: NodeID,
+	args: ReconnectNodeOpt,
+) -> Result<(), Error> {
+	let status = fetch_status(rpc_cli, rpc_host).await?;
+	let node = find_matching_node(status.iter().map(|adv| adv.id), &args.node)?;
+
+	match rpc_cli
+		.call(&rpc_host, SystemRpc::Reconnect(node), PRIO_NORMAL)
+		.await??
+	{
+		SystemRpc::ReturnKnownNodes(nodes) if nodes.len() == 1 => {
+			let adv = &nodes[0];
+			match (adv.is_up, adv.addr) {
+				(true, Some(addr)) => println!("Reconnected to {:?} at {}.", node, addr),
+				(true, None) => println!("Reconnected to {:?}.", node),
+				(false, _) => {
+					return Err(Error::Message(format!(
+						"Could not reconnect to {:?}, the connection will be retried in the background.",
+						node
+					)))
+				}
+			}
+			Ok(())
+		}
+		m => Err(Error::unexpected_rpc_message(m)),
+	}
+}
+
 pub async fn cmd_admin(
 	rpc_cli: &Endpoint<AdminRpc, ()>,
 	rpc_host: NodeID,
@@ -0,0 +1,178 @@
+//! Shell completion scripts of the CLI. The scripts generated by clap
+//! complete subcommands and options; for bash and fish, they are extended
+//! to complete the names of buckets and keys, which are listed by the
+//! hidden `garage complete-names` subcommand.
+use std::collections::HashMap;
+use std::time::Duration;
+
+use structopt::clap::{App, Shell};
+use structopt::StructOpt;
+
+use garage_rpc::*;
+
+use garage_model::helper::error::Error as HelperError;
+
+use crate::admin::*;
+use crate::cli::structs::*;
+
+/// Time after which `garage complete-names` gives up on reaching the
+/// daemon, so that completion never hangs
+pub const COMPLETE_NAMES_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(StructOpt, Debug)]
+pub struct CompletionsOpt {
+	/// Shell to generate the completion script for
+	#[structopt(possible_values = &Shell::variants(), case_insensitive = true)]
+	pub shell: Shell,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct CompleteNamesOpt {
+	/// What to list the names of
+	#[structopt(possible_values = &["buckets", "keys"])]
+	pub what: String,
+}
+
+const BASH_NAMES: &str = r#"
+_garage_complete_names() {
+	local IFS=$'\n'
+	COMPREPLY=( $(compgen -W "$("${COMP_WORDS[0]}" complete-names "$1" 2>/dev/null)" -- "${COMP_WORDS[COMP_CWORD]}") )
+}
+
+_garage_names() {
+	local cur="${COMP_WORDS[COMP_CWORD]}"
+	local prev="${COMP_WORDS[COMP_CWORD-1]}"
+	if [[ ${COMP_CWORD} -ge 3 && "${cur}" != -* ]]; then
+		case "${COMP_WORDS[1]} ${COMP_WORDS[2]}" in
+			"bucket list"|"bucket create"|"key list"|"key create"|"key import")
+				;;
+			"bucket "*)
+				if [[ "${prev}" == "--key" ]]; then
+					_garage_complete_names keys
+				else
+					_garage_complete_names buckets
+				fi
+				return 0
+				;;
+			"key "*)
+				_garage_complete_names keys
+				return 0
+				;;
+		esac
+	fi
+	_garage "$@"
+}
+
+complete -F _garage_names -o bashdefault -o default garage
+"#;
+
+const FISH_NAMES: &str = r#"
+complete -c garage -n "__fish_seen_subcommand_from bucket; and not __fish_seen_subcommand_from list create; and test (count (commandline -opc)) -ge 3" -f -a "(garage complete-names buckets 2>/dev/null)"
+complete -c garage -n "__fish_seen_subcommand_from bucket; and __fish_seen_subcommand_from allow deny" -l key -x -a "(garage complete-names keys 2>/dev/null)"
+complete -c garage -n "__fish_seen_subcommand_from key; and not __fish_seen_subcommand_from list create import; and test (count (commandline -opc)) -ge 3" -f -a "(garage complete-names keys 2>/dev/null)"
+"#;
+
+/// Generate the completion script of the CLI for a shell
+pub fn completion_script(mut app: App, shell: Shell) -> String {
+	let mut script = vec![];
+	app.gen_completions_to("garage", shell, &mut script);
+	let mut script = String::from_utf8(script).expect("completion script is not valid UTF-8");
+	match shell {
+		Shell::Bash => script.push_str(BASH_NAMES),
+		Shell::Fish => script.push_str(FISH_NAMES),
+		_ => (),
+	}
+	script
+}
+
+/// Print the names of buckets or keys, one per line, for completion
+pub async fn cmd_complete_names(
+	rpc_cli: &Endpoint<AdminRpc, ()>,
+	rpc_host: NodeID,
+	opt: CompleteNamesOpt,
+) -> Result<(), HelperError> {
+	let names = match opt.what.as_str() {
+		"buckets" => {
+			let req = AdminRpc::BucketOperation(BucketOperation::List);
+			match rpc_cli.call(&rpc_host, req, PRIO_NORMAL).await?? {
+				AdminRpc::BucketList(buckets) => buckets
+					.iter()
+					.flat_map(|b| b.aliases().iter())
+					.filter(|(_, _, active)| *active)
+					.map(|(name, _, _)| name.to_string())
+					.collect::<Vec<_>>(),
+				r => {
+					return Err(HelperError::BadRequest(format!(
+						"Unexpected response: {:?}",
+						r
+					)))
+				}
+			}
+		}
+		_ => {
+			let req = AdminRpc::KeyOperation(KeyOperation::List);
+			match rpc_cli.call(&rpc_host, req, PRIO_NORMAL).await?? {
+				AdminRpc::KeyList(keys) => key_completion_names(keys),
+				r => {
+					return Err(HelperError::BadRequest(format!(
+						"Unexpected response: {:?}",
+						r
+					)))
+				}
+			}
+		}
+	};
+	for name in names {
+		println!("{}", name);
+	}
+	Ok(())
+}
+
+/// Keys are completed with their name, or with their ID if they have
+/// no name or if several keys have the same name
+fn key_completion_names(keys: Vec<(String, String, bool)>) -> Vec<String> {
+	let mut name_count = HashMap::new();
+	for (_, name, _) in keys.iter() {
+		*name_count.entry(name.clone()).or_insert(0) += 1;
+	}
+	keys.into_iter()
+		.map(|(key_id, name, _)| match name_count[&name] {
+			1 if !name.is_empty() => name,
+			_ => key_id,
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_completion_scripts() {
+		garage_util::version::init_version("test");
+
+		let bash = completion_script(Command::clap(), Shell::Bash);
+		assert!(bash.contains("_garage()"));
+		assert!(bash.contains("set-quotas"));
+		assert!(bash.contains("complete-names \"$1\""));
+		assert!(bash.ends_with("complete -F _garage_names -o bashdefault -o default garage\n"));
+
+		let fish = completion_script(Command::clap(), Shell::Fish);
+		assert!(fish.contains("complete -c garage"));
+		assert!(fish.contains("garage complete-names keys"));
+
+		let zsh = completion_script(Command::clap(), Shell::Zsh);
+		assert!(zsh.starts_with("#compdef garage"));
+	}
+
+	#[test]
+	fn test_key_completion_names() {
+		let keys = vec![
+			("GK1".to_string(), "ci".to_string(), false),
+			("GK2".to_string(), "".to_string(), false),
+			("GK3".to_string(), "backup".to_string(), true),
+			("GK4".to_string(), "backup".to_string(), false),
+		];
+		assert_eq!(key_completion_names(keys), vec!["ci", "GK2", "GK3", "GK4"]);
+	}
+}
@@ -48,7 +48,9 @@ pub struct OpenLmdbOpt {
 
 pub(crate) fn do_conversion(args: ConvertDbOpt) -> Result<()> {
 	if args.input_engine == args.output_engine {
-		return Err(Error("input and output database engine must differ".into()));
+		return Err(Error::Message(
+			"input and output database engine must differ".into(),
+		));
 	}
 
 	let opt = OpenOpt {
@@ -112,10 +112,16 @@ pub async fn cmd_assign_role(
 				} else {
 					args.tags.clone()
 				};
+				let non_voting = if args.non_voting {
+					true
+				} else {
+					old.non_voting && !args.voting && capacity.is_none()
+				};
 				NodeRole {
 					zone: args.zone.clone().unwrap_or_else(|| old.zone.to_string()),
 					capacity,
 					tags,
+					non_voting,
 				}
 			}
 			_ => {
@@ -132,10 +138,17 @@ pub async fn cmd_assign_role(
 						.ok_or("Please specifiy a zone with the -z flag")?,
 					capacity,
 					tags: args.tags.clone(),
+					non_voting: args.non_voting,
 				}
 			}
 		};
 
+		if new_entry.non_voting && new_entry.capacity.is_some() {
+			return Err(Error::Message(
+				"Only gateway nodes can be non-voting, please configure node with -g".into(),
+			));
+		}
+
 		layout
 			.staging
 			.get_mut()
@@ -191,6 +204,15 @@ pub async fn cmd_show_layout(
 		"Current cluster layout version: {}",
 		layout.current().version
 	);
+	if let Some(canary) = &layout.current().canary {
+		println!(
+			"This version is a canary: {} of the {} partitions changed by the staged roles have been moved ({}%).",
+			canary.partitions.len(),
+			canary.changed_partitions,
+			canary.percent
+		);
+		println!("Move the other partitions with `garage layout apply --continue`, or revert with `garage layout revert --yes`.");
+	}
 
 	let has_role_changes = print_staging_role_changes(&layout);
 	if has_role_changes {
@@ -233,19 +255,68 @@ pub async fn cmd_apply_layout(
 ) -> Result<(), Error> {
 	let layout = fetch_layout(rpc_cli, rpc_host).await?;
 
-	let (layout, msg) = layout.apply_staged_changes(apply_opt.version)?;
+	if apply_opt.dry_run {
+		let plan = layout.plan_staged_changes()?;
+		print_layout_plan(&plan);
+		println!();
+		println!("To enact the staged role changes, type:");
+		println!();
+		println!("    garage layout apply --version {}", plan.new_version);
+		return Ok(());
+	}
+
+	let (layout, msg) = match (&apply_opt.canary, apply_opt.continue_canary) {
+		(Some(_), true) => {
+			return Err(Error::Message(
+				"--canary and --continue cannot be used together".into(),
+			));
+		}
+		(Some(canary), false) => {
+			let percent = parse_canary_percent(canary)?;
+			layout.apply_staged_changes_canary(apply_opt.version, percent)?
+		}
+		(None, true) => {
+			if layout.current().canary.is_none() {
+				return Err(Error::Message(
+					"No canary layout change is in progress".into(),
+				));
+			}
+			let version = apply_opt.version.unwrap_or(layout.current().version + 1);
+			layout.apply_staged_changes(Some(version))?
+		}
+		(None, false) => layout.apply_staged_changes(apply_opt.version)?,
+	};
 	for line in msg.iter() {
 		println!("{}", line);
 	}
 
+	let is_canary = layout.current().canary.is_some();
 	send_layout(rpc_cli, rpc_host, layout).await?;
 
 	println!("New cluster layout with updated role assignment has been applied in cluster.");
 	println!("Data will now be moved around between nodes accordingly.");
+	if is_canary {
+		println!();
+		println!("Only part of the partitions are being moved. Once their transfer looks");
+		println!("healthy, move the other partitions with:");
+		println!();
+		println!("    garage layout apply --continue");
+		println!();
+		println!("or move the canary partitions back with: garage layout revert --yes");
+	}
 
 	Ok(())
 }
 
+fn parse_canary_percent(canary: &str) -> Result<u8, Error> {
+	canary
+		.trim_end_matches('%')
+		.parse::<u8>()
+		.ok()
+		.filter(|p| (1..=100).contains(p))
+		.ok_or_message("The canary must be a percentage between 1% and 100%")
+}
+
 pub async fn cmd_revert_layout(
 	rpc_cli: &Endpoint<SystemRpc, ()>,
 	rpc_host: NodeID,
@@ -259,11 +330,15 @@ pub async fn cmd_revert_layout(
 
 	let layout = fetch_layout(rpc_cli, rpc_host).await?;
 
+	let was_canary = layout.current().canary.is_some();
 	let layout = layout.revert_staged_changes()?;
 
 	send_layout(rpc_cli, rpc_host, layout).await?;
 
 	println!("All proposed role changes in cluster layout have been canceled.");
+	if was_canary {
+		println!("The partitions moved by the canary layout change are being moved back.");
+	}
 	Ok(())
 }
 
@@ -525,6 +600,56 @@ pub fn print_cluster_layout(layout: &LayoutVersion, empty_msg: &str) {
 	}
 }
 
+pub fn print_layout_plan(plan: &LayoutPlan) {
+	for line in plan.message.iter() {
+		println!("{}", line);
+	}
+
+	println!("==== CHANGES FOR EACH NODE ====");
+	let mut table =
+		vec!["ID\tZone\tCapacity\tPartitions\tGained\tLost\tData before\tData after".to_string()];
+	for n in plan.nodes.iter() {
+		let zone = match (&n.zone_before, &n.zone_after) {
+			(Some(b), Some(a)) if a != b => format!("{} -> {}", b, a),
+			(_, Some(a)) => a.clone(),
+			(Some(b), None) => format!("{} (removed)", b),
+			(None, None) => "".into(),
+		};
+		let capacity = |c: Option<u64>| {
+			c.map(|c| ByteSize::b(c).to_string_as(false))
+				.unwrap_or_else(|| "-".into())
+		};
+		table.push(format!(
+			"{:?}\t{}\t{} -> {}\t{} -> {}\t+{}\t-{}\t{}\t{}",
+			n.id,
+			zone,
+			capacity(n.capacity_before),
+			capacity(n.capacity_after),
+			n.partitions_before,
+			n.partitions_after,
+			n.partitions_gained,
+			n.partitions_lost,
+			ByteSize::b(n.usage_before).to_string_as(false),
+			ByteSize::b(n.usage_after).to_string_as(false),
+		));
+	}
+	format_table(table);
+	println!();
+	println!(
+		"Partition size: {} -> {}, {} partitions moved.",
+		ByteSize::b(plan.partition_size_before).to_string_as(false),
+		ByteSize::b(plan.partition_size_after).to_string_as(false),
+		plan.partitions_moved,
+	);
+
+	if !plan.warnings.is_empty() {
+		println!();
+		for w in plan.warnings.iter() {
+			println!("{}", w);
+		}
+	}
+}
+
 pub fn print_staging_role_changes(layout: &LayoutHistory) -> bool {
 	let staging = layout.staging.get();
 	let has_role_changes = staging
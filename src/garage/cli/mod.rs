@@ -1,4 +1,5 @@
 pub(crate) mod cmd;
+pub(crate) mod completions;
 pub(crate) mod init;
 pub(crate) mod layout;
 pub(crate) mod structs;
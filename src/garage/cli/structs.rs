@@ -3,6 +3,7 @@ use structopt::StructOpt;
 
 use garage_util::version::garage_version;
 
+use crate::cli::completions::{CompleteNamesOpt, CompletionsOpt};
 use crate::cli::convert_db;
 
 #[derive(StructOpt, Debug)]
@@ -59,6 +60,17 @@ pub enum Command {
 	/// Convert metadata db between database engine formats
 	#[structopt(name = "convert-db", version = garage_version())]
 	ConvertDb(convert_db::ConvertDbOpt),
+
+	/// Print a shell completion script (bash, zsh, fish, powershell or elvish)
+	#[structopt(name = "completions", version = garage_version())]
+	Completions(CompletionsOpt),
+
+	/// List the names of buckets or keys, used by the completion scripts
+	#[structopt(
+		name = "complete-names",
+		setting = structopt::clap::AppSettings::Hidden
+	)]
+	CompleteNames(CompleteNamesOpt),
 }
 
 #[derive(StructOpt, Debug)]
@@ -71,6 +83,10 @@ pub enum NodeOperation {
 	/// Connect to Garage node that is currently isolated from the system
 	#[structopt(name = "connect", version = garage_version())]
 	Connect(ConnectNodeOpt),
+
+	/// Close the connection to a node and immediately reconnect to it
+	#[structopt(name = "reconnect", version = garage_version())]
+	Reconnect(ReconnectNodeOpt),
 }
 
 #[derive(StructOpt, Debug)]
@@ -88,6 +104,12 @@ pub struct ConnectNodeOpt {
 	pub(crate) node: String,
 }
 
+#[derive(StructOpt, Debug)]
+pub struct ReconnectNodeOpt {
+	/// Node ID (or a prefix of it) of the node to reconnect to
+	pub(crate) node: String,
+}
+
 #[derive(StructOpt, Debug)]
 pub enum LayoutOperation {
 	/// Assign role to Garage node
@@ -141,6 +163,16 @@ pub struct AssignRoleOpt {
 	#[structopt(short = "g", long = "gateway")]
 	pub(crate) gateway: bool,
 
+	/// Gateway node that is not counted in write quorums of tables replicated
+	/// on all nodes (bucket and key tables, etc.), and that does not hold
+	/// back the cluster's progress to new layout versions
+	#[structopt(long = "non-voting", conflicts_with = "voting")]
+	pub(crate) non_voting: bool,
+
+	/// Make a non-voting gateway node count in quorums again
+	#[structopt(long = "voting")]
+	pub(crate) voting: bool,
+
 	/// Optional tags to add to node
 	#[structopt(short = "t", long = "tag")]
 	pub(crate) tags: Vec<String>,
@@ -169,6 +201,20 @@ pub struct ApplyLayoutOpt {
 	/// it is not exactly 1 + the previous configuration's version
 	#[structopt(long = "version")]
 	pub(crate) version: Option<u64>,
+
+	/// Only show the changes that applying the staged layout would make,
+	/// without applying it
+	#[structopt(long = "dry-run")]
+	pub(crate) dry_run: bool,
+
+	/// Only move this percentage (e.g. `10%`) of the partitions whose
+	/// assignment changes, the others are moved by `--continue`
+	#[structopt(long = "canary")]
+	pub(crate) canary: Option<String>,
+
+	/// Move the partitions that were left in place by a canary layout change
+	#[structopt(long = "continue")]
+	pub(crate) continue_canary: bool,
 }
 
 #[derive(StructOpt, Debug)]
@@ -232,9 +278,50 @@ pub enum BucketOperation {
 	#[structopt(name = "set-quotas", version = garage_version())]
 	SetQuotas(SetQuotasOpt),
 
+	/// Make a bucket read-only (frozen) or writable again
+	#[structopt(name = "set-read-only", version = garage_version())]
+	SetReadOnly(SetReadOnlyOpt),
+
+	/// Restrict the HTTP methods accepted on the S3 API for a bucket,
+	/// whatever the permissions of access keys
+	#[structopt(name = "set-allowed-methods", version = garage_version())]
+	SetAllowedMethods(SetAllowedMethodsOpt),
+
+	/// Choose whether reading objects without permission returns
+	/// 404 NoSuchKey instead of 403 AccessDenied
+	#[structopt(name = "set-forbidden-as-not-found", version = garage_version())]
+	SetForbiddenAsNotFound(SetForbiddenAsNotFoundOpt),
+
+	/// Keep the previous version of objects for some time after they are
+	/// overwritten, so that the overwrite can be undone
+	#[structopt(name = "set-previous-version-retention", version = garage_version())]
+	SetPreviousVersionRetention(SetPreviousVersionRetentionOpt),
+
+	/// Make object keys case-insensitive, or case-sensitive again
+	/// (only possible while the bucket is empty)
+	#[structopt(name = "set-case-insensitive-keys", version = garage_version())]
+	SetCaseInsensitiveKeys(SetCaseInsensitiveKeysOpt),
+
+	/// Restore the previous version of an overwritten object
+	#[structopt(name = "restore-previous-version", version = garage_version())]
+	RestorePreviousVersion(RestorePreviousVersionOpt),
+
 	/// Clean up (abort) old incomplete multipart uploads
 	#[structopt(name = "cleanup-incomplete-uploads", version = garage_version())]
 	CleanupIncompleteUploads(CleanupIncompleteUploadsOpt),
+
+	/// Delete all objects whose key starts with a prefix, as a background job
+	#[structopt(name = "delete-prefix", version = garage_version())]
+	DeletePrefix(DeletePrefixOpt),
+
+	/// Show the progress of delete-prefix jobs, or cancel one
+	#[structopt(name = "delete-prefix-status", version = garage_version())]
+	DeletePrefixStatus(DeletePrefixStatusOpt),
+
+	/// Recount the objects of a bucket on its storage nodes, and compare
+	/// the result with the object counters of the bucket
+	#[structopt(name = "verify-counters", version = garage_version())]
+	VerifyCounters(VerifyCountersOpt),
 }
 
 #[derive(Serialize, Deserialize, StructOpt, Debug)]
@@ -261,7 +348,7 @@ pub struct WebsiteOpt {
 
 #[derive(Serialize, Deserialize, StructOpt, Debug)]
 pub struct BucketOpt {
-	/// Bucket name
+	/// Bucket name, or an unambiguous prefix of its name or ID
 	pub name: String,
 }
 
@@ -317,6 +404,17 @@ pub struct PermBucketOpt {
 	#[structopt(long = "owner")]
 	pub owner: bool,
 
+	/// Allow/deny listing objects in the bucket
+	/// (if never set, listing follows the read permission)
+	#[structopt(long = "list")]
+	pub list: bool,
+
+	/// With `bucket allow`: do not allow listing objects in the bucket, even
+	/// if read operations are allowed, so that only objects whose key is known
+	/// can be read
+	#[structopt(long = "no-list", conflicts_with = "list")]
+	pub no_list: bool,
+
 	/// Bucket name
 	pub bucket: String,
 }
@@ -334,6 +432,86 @@ pub struct SetQuotasOpt {
 	/// Set a maximum number of objects for the bucket (or `none` for no restriction)
 	#[structopt(long = "max-objects")]
 	pub max_objects: Option<String>,
+
+	/// Set a maximum number of multipart uploads in progress in the bucket
+	/// (or `none` for no restriction)
+	#[structopt(long = "max-multipart-uploads")]
+	pub max_multipart_uploads: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, StructOpt, Debug)]
+pub struct SetReadOnlyOpt {
+	/// Bucket name
+	pub bucket: String,
+
+	/// Make the bucket read-only
+	#[structopt(long = "enable")]
+	pub enable: bool,
+
+	/// Make the bucket writable again
+	#[structopt(long = "disable")]
+	pub disable: bool,
+}
+
+#[derive(Serialize, Deserialize, StructOpt, Debug)]
+pub struct SetAllowedMethodsOpt {
+	/// Bucket name
+	pub bucket: String,
+
+	/// Methods to allow, among GET, HEAD, PUT, POST and DELETE
+	/// (e.g. `GET HEAD` for a read-only bucket)
+	pub methods: Vec<String>,
+
+	/// Allow all methods again
+	#[structopt(long = "all")]
+	pub all: bool,
+}
+
+#[derive(Serialize, Deserialize, StructOpt, Debug)]
+pub struct SetForbiddenAsNotFoundOpt {
+	/// Bucket name
+	pub bucket: String,
+
+	/// Return 404 NoSuchKey to keys that cannot read objects
+	#[structopt(long = "enable")]
+	pub enable: bool,
+
+	/// Return 403 AccessDenied to keys that cannot read objects (default)
+	#[structopt(long = "disable")]
+	pub disable: bool,
+}
+
+#[derive(Serialize, Deserialize, StructOpt, Debug)]
+pub struct SetCaseInsensitiveKeysOpt {
+	/// Bucket name
+	pub bucket: String,
+
+	/// Make object keys case-insensitive
+	#[structopt(long = "enable")]
+	pub enable: bool,
+
+	/// Make object keys case-sensitive (default)
+	#[structopt(long = "disable")]
+	pub disable: bool,
+}
+
+#[derive(Serialize, Deserialize, StructOpt, Debug)]
+pub struct SetPreviousVersionRetentionOpt {
+	/// Bucket name
+	pub bucket: String,
+
+	/// How long previous versions are kept (e.g. `1h` or `2d`),
+	/// or `none` to stop keeping them
+	pub retention: String,
+}
+
+#[derive(Serialize, Deserialize, StructOpt, Debug)]
+pub struct RestorePreviousVersionOpt {
+	/// Bucket name
+	pub bucket: String,
+
+	/// Key of the object to restore
+	pub key: String,
 }
 
 #[derive(Serialize, Deserialize, StructOpt, Debug)]
@@ -347,6 +525,47 @@ pub struct CleanupIncompleteUploadsOpt {
 	pub buckets: Vec<String>,
 }
 
+#[derive(Serialize, Deserialize, StructOpt, Debug)]
+pub struct DeletePrefixOpt {
+	/// Bucket name
+	pub bucket: String,
+
+	/// Delete objects whose key starts with this prefix
+	pub prefix: String,
+
+	/// Only count the objects that would be deleted
+	#[structopt(long = "dry-run")]
+	pub dry_run: bool,
+
+	/// Allow an empty prefix, i.e. deleting all objects of the bucket
+	#[structopt(long = "allow-empty-prefix")]
+	pub allow_empty_prefix: bool,
+}
+
+#[derive(Serialize, Deserialize, StructOpt, Debug)]
+pub struct VerifyCountersOpt {
+	/// Bucket name
+	pub bucket: String,
+
+	/// Correct the counters that have drifted
+	#[structopt(long = "fix")]
+	pub fix: bool,
+
+	/// Output the result as JSON
+	#[structopt(long = "json")]
+	pub json: bool,
+}
+
+#[derive(Serialize, Deserialize, StructOpt, Debug)]
+pub struct DeletePrefixStatusOpt {
+	/// ID of the job (all jobs started on this node are shown if not given)
+	pub job_id: Option<String>,
+
+	/// Cancel the job
+	#[structopt(long = "cancel")]
+	pub cancel: bool,
+}
+
 #[derive(Serialize, Deserialize, StructOpt, Debug)]
 pub enum KeyOperation {
 	/// List keys
@@ -377,6 +596,14 @@ pub enum KeyOperation {
 	#[structopt(name = "deny", version = garage_version())]
 	Deny(KeyPermOpt),
 
+	/// Suspend key, rejecting all requests signed with it until it is resumed
+	#[structopt(name = "suspend", version = garage_version())]
+	Suspend(KeySuspendOpt),
+
+	/// Resume suspended key
+	#[structopt(name = "resume", version = garage_version())]
+	Resume(KeySuspendOpt),
+
 	/// Import key
 	#[structopt(name = "import", version = garage_version())]
 	Import(KeyImportOpt),
@@ -384,7 +611,7 @@ pub enum KeyOperation {
 
 #[derive(Serialize, Deserialize, StructOpt, Debug)]
 pub struct KeyInfoOpt {
-	/// ID or name of the key
+	/// ID or name of the key, or an unambiguous prefix of it
 	pub key_pattern: String,
 	/// Whether to display the secret key
 	#[structopt(long = "show-secret")]
@@ -427,6 +654,12 @@ pub struct KeyPermOpt {
 	pub create_bucket: bool,
 }
 
+#[derive(Serialize, Deserialize, StructOpt, Debug)]
+pub struct KeySuspendOpt {
+	/// ID or name of the key
+	pub key_pattern: String,
+}
+
 #[derive(Serialize, Deserialize, StructOpt, Debug)]
 pub struct KeyImportOpt {
 	/// Access key ID
@@ -538,6 +771,12 @@ pub struct StatsOpt {
 	#[structopt(short = "a", long = "all-nodes")]
 	pub all_nodes: bool,
 
+	/// Show the usage history recorded by the node over the given period (e.g. 7d),
+	/// if enabled by `usage_history_interval`
+	#[structopt(long = "history")]
+	#[serde(default)]
+	pub history: Option<String>,
+
 	/// Don't show global cluster stats (internal use in RPC)
 	#[structopt(skip)]
 	#[serde(default)]
@@ -617,15 +856,37 @@ pub enum BlockOperation {
 		#[structopt(required = true)]
 		blocks: Vec<String>,
 	},
+	/// Copy a block from one node to other nodes, bypassing the resync queue
+	#[structopt(name = "replicate", version = garage_version())]
+	Replicate {
+		/// Node to read the block from (default: any node that has it)
+		#[structopt(long = "from")]
+		from: Option<String>,
+		/// Node to write the block to (default: all nodes that should store it
+		/// according to the current layout)
+		#[structopt(long = "to")]
+		to: Option<String>,
+		/// Write the block even to nodes that already store it, replacing their copy
+		#[structopt(long = "force")]
+		force: bool,
+		/// Hash of the block to replicate
+		hash: String,
+	},
 }
 
 #[derive(Serialize, Deserialize, StructOpt, Debug, Eq, PartialEq, Clone, Copy)]
 pub enum MetaOperation {
-	/// Save a snapshot of the metadata db file
+	/// Save a snapshot of the metadata db file, in the background
+	/// (use `garage worker list` to follow its progress)
 	#[structopt(name = "snapshot", version = garage_version())]
 	Snapshot {
 		/// Run on all nodes instead of only local node
 		#[structopt(long = "all")]
 		all: bool,
 	},
+
+	/// Compact the metadata db of the local node to reclaim the space
+	/// left unused by deleted data (only supported by the sqlite engine)
+	#[structopt(name = "compact", version = garage_version())]
+	Compact,
 }
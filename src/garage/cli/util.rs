@@ -50,11 +50,12 @@ pub fn print_bucket_list(bl: Vec<Bucket>) {
 	format_table(table);
 }
 
-pub fn print_key_list(kl: Vec<(String, String)>) {
+pub fn print_key_list(kl: Vec<(String, String, bool)>) {
 	println!("List of keys:");
 	let mut table = vec![];
-	for key in kl {
-		table.push(format!("\t{}\t{}", key.0, key.1));
+	for (key_id, name, suspended) in kl {
+		let state = if suspended { "suspended" } else { "" };
+		table.push(format!("\t{}\t{}\t{}", key_id, name, state));
 	}
 	format_table(table);
 }
@@ -81,8 +82,17 @@ pub fn print_key_info(key: &Key, relevant_buckets: &HashMap<Uuid, Bucket>) {
 		Deletable::Present(p) => {
 			println!("Key name: {}", p.name.get());
 			println!("Key ID: {}", key.key_id);
-			println!("Secret key: {}", p.secret_key);
+			println!("Secret key: {}", p.secret());
 			println!("Can create buckets: {}", p.allow_create_bucket.get());
+			println!("System key: {}", p.system.get());
+			println!("Suspended: {}", p.suspended.get());
+			println!(
+				"Last used: {}",
+				p.last_used_at
+					.map(msec_to_rfc3339)
+					.unwrap_or_else(|| "unknown".into())
+			);
+			println!("Requests: {}", p.request_count());
 			println!("\nKey-specific bucket aliases:");
 			let mut table = vec![];
 			for (alias_name, _, alias) in p.local_aliases.items().iter() {
@@ -106,6 +116,7 @@ pub fn print_key_info(key: &Key, relevant_buckets: &HashMap<Uuid, Bucket>) {
 				let rflag = if perm.allow_read { "R" } else { " " };
 				let wflag = if perm.allow_write { "W" } else { " " };
 				let oflag = if perm.allow_owner { "O" } else { " " };
+				let lflag = if perm.can_list() { "L" } else { " " };
 				let local_aliases = p
 					.local_aliases
 					.items()
@@ -115,10 +126,11 @@ pub fn print_key_info(key: &Key, relevant_buckets: &HashMap<Uuid, Bucket>) {
 					.collect::<Vec<_>>()
 					.join(", ");
 				table.push(format!(
-					"\t{}{}{}\t{}\t{}\t{:?}",
+					"\t{}{}{}{}\t{}\t{}\t{:?}",
 					rflag,
 					wflag,
 					oflag,
+					lflag,
 					bucket_global_aliases(bucket_id),
 					local_aliases,
 					bucket_id
@@ -144,6 +156,10 @@ pub fn print_bucket_info(
 			.map(|k| k.params().unwrap().name.get().as_str())
 			.unwrap_or("<deleted>")
 	};
+	let key_state = |k| match relevant_keys.get(k) {
+		Some(k) if k.is_suspended() => "(suspended)",
+		_ => "",
+	};
 
 	println!("Bucket: {}", hex::encode(bucket.id));
 	match &bucket.state {
@@ -177,9 +193,39 @@ pub fn print_bucket_info(
 			);
 
 			println!("\nWebsite access: {}", p.website_config.get().is_some());
+			if *p.read_only.get() {
+				println!("Read-only: true");
+			}
+			if let Some(methods) = p.allowed_methods.get() {
+				println!("Allowed methods: {}", methods.join(", "));
+			}
+			if *p.forbidden_as_not_found.get() {
+				println!("Forbidden objects reported as not found: true");
+			}
+			if let Some(secs) = p.previous_version_retention_secs.get() {
+				println!(
+					"Previous versions of overwritten objects kept for: {}s",
+					secs
+				);
+			}
+			if let Some(rf) = p.replication_factor.get() {
+				println!("Copies of object data: {}", rf);
+			}
+			if *p.case_insensitive_keys.get() {
+				println!("Case-insensitive keys: true");
+			}
+			if let Some(bs) = p.block_size.get() {
+				println!(
+					"Block size: {}",
+					bytesize::ByteSize::b(*bs as u64).to_string_as(true)
+				);
+			}
 
 			let quotas = p.quotas.get();
-			if quotas.max_size.is_some() || quotas.max_objects.is_some() {
+			if quotas.max_size.is_some()
+				|| quotas.max_objects.is_some()
+				|| quotas.max_multipart_uploads.is_some()
+			{
 				println!("\nQuotas:");
 				if let Some(ms) = quotas.max_size {
 					let ms = bytesize::ByteSize::b(ms);
@@ -192,6 +238,9 @@ pub fn print_bucket_info(
 				if let Some(mo) = quotas.max_objects {
 					println!(" maximum number of objects: {}", mo);
 				}
+				if let Some(mu) = quotas.max_multipart_uploads {
+					println!(" maximum number of multipart uploads: {}", mu);
+				}
 			}
 
 			println!("\nGlobal aliases:");
@@ -219,13 +268,16 @@ pub fn print_bucket_info(
 				let rflag = if perm.allow_read { "R" } else { " " };
 				let wflag = if perm.allow_write { "W" } else { " " };
 				let oflag = if perm.allow_owner { "O" } else { " " };
+				let lflag = if perm.can_list() { "L" } else { " " };
 				table.push(format!(
-					"\t{}{}{}\t{}\t{}",
+					"\t{}{}{}{}\t{}\t{}\t{}",
 					rflag,
 					wflag,
 					oflag,
+					lflag,
 					k,
-					key_name(k)
+					key_name(k),
+					key_state(k)
 				));
 			}
 			format_table(table);
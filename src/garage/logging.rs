@@ -0,0 +1,313 @@
+//! Initialization of the logs, written to stderr (or to syslog) either as
+//! human-readable lines or as JSON objects, one per line.
+//!
+//! The records of the `log` crate, which is used by some of the components,
+//! go through the same subscriber as the events of `tracing`, so that they
+//! are filtered and formatted in the same way.
+use std::fmt;
+use std::sync::OnceLock;
+
+use serde_json::{Map, Value};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::filter::EnvFilter;
+use tracing_subscriber::fmt::format::{JsonFields, Writer};
+use tracing_subscriber::fmt::time::{FormatTime, SystemTime};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields};
+use tracing_subscriber::registry::LookupSpan;
+
+use garage_util::config::{LogFormat, LoggingConfig};
+
+static NODE_ID: OnceLock<String> = OnceLock::new();
+
+/// Set the ID of this node, which is added to the JSON log lines
+/// written after it is known
+pub fn set_node_id(node_id: String) {
+	let _ = NODE_ID.set(node_id);
+}
+
+pub fn init_logging(is_server: bool, config: Option<&LoggingConfig>) {
+	let default_config = LoggingConfig::default();
+	let config = config.unwrap_or(&default_config);
+
+	let rust_log = std::env::var("RUST_LOG").ok();
+	let env_filter = match env_filter(is_server, config, rust_log.as_deref()) {
+		Ok(f) => f,
+		Err(e) => {
+			eprintln!("Error: invalid log level in [logging] section: {}", e);
+			std::process::exit(1);
+		}
+	};
+
+	let format = match std::env::var("GARAGE_LOG_FORMAT") {
+		Ok(f) => match f.as_str() {
+			"text" => LogFormat::Text,
+			"json" => LogFormat::Json,
+			_ => {
+				eprintln!(
+					"Error: invalid GARAGE_LOG_FORMAT {:?}, expected \"text\" or \"json\"",
+					f
+				);
+				std::process::exit(1);
+			}
+		},
+		Err(_) => config.format,
+	};
+
+	if std::env::var("GARAGE_LOG_TO_SYSLOG")
+		.map(|x| x == "1" || x == "true")
+		.unwrap_or(false)
+	{
+		#[cfg(feature = "syslog")]
+		{
+			use std::ffi::CStr;
+			use syslog_tracing::{Facility, Options, Syslog};
+
+			let syslog = Syslog::new(
+				CStr::from_bytes_with_nul(b"garage\0").unwrap(),
+				Options::LOG_PID | Options::LOG_PERROR,
+				Facility::Daemon,
+			)
+			.expect("Unable to init syslog");
+
+			tracing_subscriber::fmt()
+				.with_writer(syslog)
+				.with_env_filter(env_filter)
+				.with_ansi(false) // disable ANSI escape sequences (colours)
+				.with_file(false)
+				.with_level(false)
+				.without_time()
+				.compact()
+				.init();
+
+			return;
+		}
+		#[cfg(not(feature = "syslog"))]
+		{
+			eprintln!("Syslog support is not enabled in this build.");
+			std::process::exit(1);
+		}
+	}
+
+	match format {
+		LogFormat::Text => tracing_subscriber::fmt()
+			.with_writer(std::io::stderr)
+			.with_env_filter(env_filter)
+			.init(),
+		LogFormat::Json => tracing_subscriber::fmt()
+			.fmt_fields(JsonFields::new())
+			.event_format(JsonFormat)
+			.with_writer(std::io::stderr)
+			.with_env_filter(env_filter)
+			.init(),
+	}
+}
+
+/// Levels of the logs: those of `RUST_LOG` if it is set, otherwise
+/// the default levels with the overrides of the config file
+fn env_filter(
+	is_server: bool,
+	config: &LoggingConfig,
+	rust_log: Option<&str>,
+) -> Result<EnvFilter, String> {
+	if let Some(rust_log) = rust_log {
+		return Ok(EnvFilter::new(rust_log));
+	}
+
+	let default_log = match is_server {
+		true => "netapp=info,garage=info",
+		false => "netapp=warn,garage=warn",
+	};
+	let mut filter = EnvFilter::new(default_log);
+	for (module, level) in config.levels.iter() {
+		let directive = format!("{}={}", module, level);
+		let parsed = directive
+			.parse()
+			.map_err(|e| format!("{}: {}", directive, e))?;
+		filter = filter.add_directive(parsed);
+	}
+	Ok(filter)
+}
+
+/// Formats each event as a JSON object containing its timestamp, level,
+/// target and fields (including the message), the ID of the node, and
+/// the fields of the spans in which it happened. The fields of the spans,
+/// such as the `request_id` of the API requests, are also copied at the
+/// top level of the object so that they can easily be searched for.
+pub struct JsonFormat;
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+	S: Subscriber + for<'a> LookupSpan<'a>,
+	N: for<'a> FormatFields<'a> + 'static,
+{
+	fn format_event(
+		&self,
+		ctx: &FmtContext<'_, S, N>,
+		mut writer: Writer<'_>,
+		event: &Event<'_>,
+	) -> fmt::Result {
+		let mut timestamp = String::new();
+		SystemTime.format_time(&mut Writer::new(&mut timestamp))?;
+
+		let mut fields = JsonVisitor::default();
+		event.record(&mut fields);
+		let mut fields = fields.0;
+
+		// The records of the log crate have their actual target in a field
+		let metadata = event.metadata();
+		let target = match fields.remove("log.target") {
+			Some(Value::String(target)) => target,
+			_ => metadata.target().to_string(),
+		};
+		fields.retain(|name, _| !name.starts_with("log."));
+
+		let mut line = Map::new();
+		line.insert("timestamp".into(), timestamp.into());
+		line.insert("level".into(), metadata.level().as_str().into());
+		line.insert("target".into(), target.into());
+		if let Some(node_id) = NODE_ID.get() {
+			line.insert("node_id".into(), node_id.clone().into());
+		}
+
+		let mut spans = vec![];
+		if let Some(scope) = ctx.event_scope() {
+			for span in scope.from_root() {
+				// The fields of the spans are formatted as JSON by JsonFields
+				let mut span_fields = span
+					.extensions()
+					.get::<FormattedFields<N>>()
+					.and_then(|f| serde_json::from_str::<Map<String, Value>>(&f.fields).ok())
+					.unwrap_or_default();
+				line.extend(span_fields.clone());
+				span_fields.insert("name".into(), span.name().into());
+				spans.push(Value::Object(span_fields));
+			}
+		}
+		line.extend(fields);
+		if !spans.is_empty() {
+			line.insert("spans".into(), spans.into());
+		}
+
+		writeln!(writer, "{}", Value::Object(line))
+	}
+}
+
+#[derive(Default)]
+struct JsonVisitor(Map<String, Value>);
+
+impl Visit for JsonVisitor {
+	fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+		self.0
+			.insert(field.name().into(), format!("{:?}", value).into());
+	}
+
+	fn record_str(&mut self, field: &Field, value: &str) {
+		self.0.insert(field.name().into(), value.into());
+	}
+
+	fn record_i64(&mut self, field: &Field, value: i64) {
+		self.0.insert(field.name().into(), value.into());
+	}
+
+	fn record_u64(&mut self, field: &Field, value: u64) {
+		self.0.insert(field.name().into(), value.into());
+	}
+
+	fn record_f64(&mut self, field: &Field, value: f64) {
+		self.0.insert(field.name().into(), value.into());
+	}
+
+	fn record_bool(&mut self, field: &Field, value: bool) {
+		self.0.insert(field.name().into(), value.into());
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io;
+	use std::sync::{Arc, Mutex};
+
+	#[derive(Clone, Default)]
+	struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+	impl io::Write for Buffer {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			self.0.lock().unwrap().extend_from_slice(buf);
+			Ok(buf.len())
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	/// Run f with the JSON subscriber, and parse the lines it writes
+	fn json_lines(f: impl FnOnce()) -> Vec<Map<String, Value>> {
+		let buffer = Buffer::default();
+		let writer = buffer.clone();
+		let subscriber = tracing_subscriber::fmt()
+			.fmt_fields(JsonFields::new())
+			.event_format(JsonFormat)
+			.with_writer(move || writer.clone())
+			.with_env_filter(EnvFilter::new("debug"))
+			.finish();
+		tracing::subscriber::with_default(subscriber, f);
+
+		let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+		output
+			.lines()
+			.map(|line| serde_json::from_str(line).expect("log line is not valid JSON"))
+			.collect()
+	}
+
+	#[test]
+	fn test_json_logs() {
+		use tracing_subscriber::util::SubscriberInitExt;
+
+		// Installs the forwarding of the records of the log crate, which
+		// are then handled by the subscriber of the current thread
+		let _ = tracing_subscriber::registry().try_init();
+		set_node_id("6f1c1d1a0b".into());
+
+		let lines = json_lines(|| {
+			let span = info_span!("request", request_id = "4E6A2B1C9D0F3A57", api = "S3");
+			let _enter = span.enter();
+			info!(bucket = "photos", "GET /photos/{}", "cat.jpg");
+			log::warn!(target: "garage_table::sync", "Offload of partition {}", 3);
+		});
+		assert_eq!(lines.len(), 2);
+
+		let line = &lines[0];
+		assert!(chrono::DateTime::parse_from_rfc3339(line["timestamp"].as_str().unwrap()).is_ok());
+		assert_eq!(line["level"], "INFO");
+		assert_eq!(line["target"], "garage::logging::tests");
+		assert_eq!(line["message"], "GET /photos/cat.jpg");
+		assert_eq!(line["bucket"], "photos");
+		assert_eq!(line["node_id"], "6f1c1d1a0b");
+		assert_eq!(line["request_id"], "4E6A2B1C9D0F3A57");
+		assert_eq!(line["spans"][0]["name"], "request");
+		assert_eq!(line["spans"][0]["api"], "S3");
+
+		let line = &lines[1];
+		assert_eq!(line["level"], "WARN");
+		assert_eq!(line["target"], "garage_table::sync");
+		assert_eq!(line["message"], "Offload of partition 3");
+		assert_eq!(line["request_id"], "4E6A2B1C9D0F3A57");
+		assert!(!line.keys().any(|k| k.starts_with("log.")));
+	}
+
+	#[test]
+	fn test_level_overrides() {
+		let mut config = LoggingConfig::default();
+		config.levels.insert("garage_table".into(), "debug".into());
+		assert!(env_filter(true, &config, None).is_ok());
+
+		config.levels.insert("garage_api".into(), "loud".into());
+		assert!(env_filter(true, &config, None).is_err());
+
+		// RUST_LOG replaces the levels of the config file
+		assert!(env_filter(true, &config, Some("garage=debug")).is_ok());
+	}
+}
@@ -6,11 +6,15 @@ extern crate tracing;
 
 mod admin;
 mod cli;
+mod logging;
 mod repair;
+mod runtime;
 mod secrets;
 mod server;
 #[cfg(feature = "telemetry-otlp")]
 mod tracing_setup;
+#[cfg(feature = "vault-secrets")]
+mod vault;
 
 #[cfg(not(any(feature = "bundled-libs", feature = "system-libs")))]
 compile_error!("Either bundled-libs or system-libs Cargo feature must be enabled");
@@ -66,8 +70,7 @@ struct Opt {
 	cmd: Command,
 }
 
-#[tokio::main]
-async fn main() {
+fn main() {
 	// Initialize version and features info
 	let features = &[
 		#[cfg(feature = "k2v")]
@@ -84,6 +87,8 @@ async fn main() {
 		"metrics",
 		#[cfg(feature = "telemetry-otlp")]
 		"telemetry-otlp",
+		#[cfg(feature = "vault-secrets")]
+		"vault-secrets",
 		#[cfg(feature = "bundled-libs")]
 		"bundled-libs",
 		#[cfg(feature = "system-libs")]
@@ -137,83 +142,73 @@ async fn main() {
 	// Parse arguments and dispatch command line
 	let opt = Opt::from_clap(&Opt::clap().version(version.as_str()).get_matches());
 
-	// Initialize logging as well as other libraries used in Garage
-	init_logging(&opt);
+	// When running the server, the logs and the Tokio runtime are configured
+	// using parameters from the config file. If the config file cannot be read,
+	// run_server will report the error.
+	let runtime_config = match opt.cmd {
+		Command::Server => garage_util::config::read_config(opt.config_file.clone()).ok(),
+		_ => None,
+	};
+
+	// Initialize logging as well as other libraries used in Garage.
+	// Nothing is logged when listing names for shell completion, as it
+	// would be printed in the middle of the command line being completed.
+	if !matches!(opt.cmd, Command::CompleteNames(_)) {
+		logging::init_logging(
+			matches!(opt.cmd, Command::Server),
+			runtime_config.as_ref().map(|c| &c.logging),
+		);
+	}
 
 	sodiumoxide::init().expect("Unable to init sodiumoxide");
 
-	let res = match opt.cmd {
-		Command::Server => server::run_server(opt.config_file, opt.secrets).await,
-		Command::OfflineRepair(repair_opt) => {
-			repair::offline::offline_repair(opt.config_file, opt.secrets, repair_opt).await
-		}
-		Command::ConvertDb(conv_opt) => {
-			cli::convert_db::do_conversion(conv_opt).map_err(From::from)
-		}
-		Command::Node(NodeOperation::NodeId(node_id_opt)) => {
-			node_id_command(opt.config_file, node_id_opt.quiet)
+	let runtime = match runtime::build_runtime(runtime_config.as_ref()) {
+		Ok(rt) => rt,
+		Err(e) => {
+			eprintln!("Error: unable to start Tokio runtime: {}", e);
+			std::process::exit(1);
 		}
-		_ => cli_command(opt).await,
 	};
 
+	let res = runtime.block_on(async move {
+		match opt.cmd {
+			Command::Server => server::run_server(opt.config_file, opt.secrets).await,
+			Command::OfflineRepair(repair_opt) => {
+				repair::offline::offline_repair(opt.config_file, opt.secrets, repair_opt).await
+			}
+			Command::ConvertDb(conv_opt) => {
+				cli::convert_db::do_conversion(conv_opt).map_err(From::from)
+			}
+			Command::Node(NodeOperation::NodeId(node_id_opt)) => {
+				node_id_command(opt.config_file, node_id_opt.quiet)
+			}
+			Command::Completions(completions_opt) => {
+				print!(
+					"{}",
+					cli::completions::completion_script(Opt::clap(), completions_opt.shell)
+				);
+				Ok(())
+			}
+			Command::CompleteNames(_) => {
+				// Completion must neither hang nor show errors
+				// if the daemon cannot be reached
+				let _ = tokio::time::timeout(
+					cli::completions::COMPLETE_NAMES_TIMEOUT,
+					cli_command(opt),
+				)
+				.await;
+				Ok(())
+			}
+			_ => cli_command(opt).await,
+		}
+	});
+
 	if let Err(e) = res {
 		eprintln!("Error: {}", e);
 		std::process::exit(1);
 	}
 }
 
-fn init_logging(opt: &Opt) {
-	if std::env::var("RUST_LOG").is_err() {
-		let default_log = match &opt.cmd {
-			Command::Server => "netapp=info,garage=info",
-			_ => "netapp=warn,garage=warn",
-		};
-		std::env::set_var("RUST_LOG", default_log)
-	}
-
-	let env_filter = tracing_subscriber::filter::EnvFilter::from_default_env();
-
-	if std::env::var("GARAGE_LOG_TO_SYSLOG")
-		.map(|x| x == "1" || x == "true")
-		.unwrap_or(false)
-	{
-		#[cfg(feature = "syslog")]
-		{
-			use std::ffi::CStr;
-			use syslog_tracing::{Facility, Options, Syslog};
-
-			let syslog = Syslog::new(
-				CStr::from_bytes_with_nul(b"garage\0").unwrap(),
-				Options::LOG_PID | Options::LOG_PERROR,
-				Facility::Daemon,
-			)
-			.expect("Unable to init syslog");
-
-			tracing_subscriber::fmt()
-				.with_writer(syslog)
-				.with_env_filter(env_filter)
-				.with_ansi(false) // disable ANSI escape sequences (colours)
-				.with_file(false)
-				.with_level(false)
-				.without_time()
-				.compact()
-				.init();
-
-			return;
-		}
-		#[cfg(not(feature = "syslog"))]
-		{
-			eprintln!("Syslog support is not enabled in this build.");
-			std::process::exit(1);
-		}
-	}
-
-	tracing_subscriber::fmt()
-		.with_writer(std::io::stderr)
-		.with_env_filter(env_filter)
-		.init();
-}
-
 async fn cli_command(opt: Opt) -> Result<(), Error> {
 	let config = if (opt.secrets.rpc_secret.is_none() && opt.secrets.rpc_secret_file.is_none())
 		|| opt.rpc_host.is_none()
@@ -233,7 +228,14 @@ async fn cli_command(opt: Opt) -> Result<(), Error> {
 		&opt.secrets.rpc_secret_file,
 		"rpc_secret",
 		true,
-	)?;
+	)
+	.await?;
+	if rpc_secret.is_none() {
+		// Use the RPC secret generated by the daemon, if any
+		if let Some(config) = config.as_ref().filter(|c| c.auto_generate_secrets) {
+			rpc_secret = secrets::read_generated_secrets(config, true)?.rpc_secret;
+		}
+	}
 
 	let net_key_hex_str = rpc_secret.ok_or("No RPC secret provided")?;
 	let network_key = NetworkKey::from_slice(
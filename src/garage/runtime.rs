@@ -0,0 +1,118 @@
+//! Construction of the Tokio runtime, with parameters taken from the configuration file
+
+use garage_util::config::Config;
+
+/// Default number of threads used for blocking operations (same as Tokio's default)
+const DEFAULT_BLOCKING_THREADS: usize = 512;
+
+/// Build the multi-threaded Tokio runtime on which Garage runs.
+/// If no configuration is given, default parameters are used.
+pub fn build_runtime(config: Option<&Config>) -> std::io::Result<tokio::runtime::Runtime> {
+	let worker_threads = config.and_then(|c| c.tokio_worker_threads);
+	let blocking_threads = config
+		.and_then(|c| c.tokio_blocking_threads)
+		.unwrap_or(DEFAULT_BLOCKING_THREADS);
+	if worker_threads == Some(0) || blocking_threads == 0 {
+		return Err(std::io::Error::new(
+			std::io::ErrorKind::InvalidInput,
+			"tokio_worker_threads and tokio_blocking_threads must be greater than zero",
+		));
+	}
+
+	let mut builder = tokio::runtime::Builder::new_multi_thread();
+	builder.enable_all();
+
+	if let Some(n) = worker_threads {
+		builder.worker_threads(n);
+	}
+	builder.max_blocking_threads(blocking_threads);
+	// The stack size and name of the threads are left to Tokio's defaults
+	// unless they are set in the configuration
+	if let Some(kb) = config.and_then(|c| c.tokio_thread_stack_size_kb) {
+		builder.thread_stack_size(kb * 1024);
+	}
+	if let Some(name) = config.and_then(|c| c.tokio_thread_name.clone()) {
+		builder.thread_name(name);
+	}
+
+	builder.build()
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashSet;
+	use std::fs::File;
+	use std::io::Write;
+	use std::time::Duration;
+
+	use garage_util::config::read_config;
+	use garage_util::error::Error;
+
+	use super::*;
+
+	#[test]
+	fn test_runtime_from_config() -> Result<(), Error> {
+		let path_config = mktemp::Temp::new_file()?;
+		let mut file_config = File::create(path_config.as_path())?;
+		writeln!(
+			file_config,
+			r#"
+			metadata_dir = "/tmp/garage/meta"
+			data_dir = "/tmp/garage/data"
+			replication_factor = 3
+			rpc_bind_addr = "[::]:3901"
+			rpc_secret = "foo"
+
+			tokio_worker_threads = 3
+			tokio_thread_name = "garage-test"
+
+			[s3_api]
+			s3_region = "garage"
+			api_bind_addr = "[::]:3900"
+			"#
+		)?;
+		let config = read_config(path_config.to_path_buf())?;
+
+		let runtime = build_runtime(Some(&config))?;
+
+		// Runtime metrics (which would give us the number of workers directly)
+		// require tokio_unstable, so we instead look at which threads run tasks.
+		let threads = runtime.block_on(async {
+			let tasks = (0..32)
+				.map(|_| {
+					tokio::spawn(async {
+						std::thread::sleep(Duration::from_millis(10));
+						let thread = std::thread::current();
+						(thread.id(), thread.name().map(str::to_string))
+					})
+				})
+				.collect::<Vec<_>>();
+			let mut threads = vec![];
+			for t in tasks {
+				threads.push(t.await.unwrap());
+			}
+			threads
+		});
+
+		let ids = threads.iter().map(|(id, _)| *id).collect::<HashSet<_>>();
+		assert!(!ids.is_empty() && ids.len() <= 3);
+		assert!(threads
+			.iter()
+			.all(|(_, name)| name.as_deref() == Some("garage-test")));
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_runtime_defaults() -> Result<(), Error> {
+		let runtime = build_runtime(None)?;
+		let name = runtime.block_on(async {
+			tokio::spawn(async { std::thread::current().name().map(str::to_string) })
+				.await
+				.unwrap()
+		});
+		assert_eq!(name.as_deref(), Some("tokio-runtime-worker"));
+
+		Ok(())
+	}
+}
@@ -1,5 +1,8 @@
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
+use rand::prelude::*;
+use serde::Deserialize;
 use structopt::StructOpt;
 
 use garage_util::config::Config;
@@ -50,8 +53,9 @@ pub struct Secrets {
 
 /// Single function to fill all secrets in the Config struct from their correct source (value
 /// from config or CLI param or env variable or read from a file specified in config or CLI
-/// param or env variable)
-pub fn fill_secrets(mut config: Config, secrets: Secrets) -> Result<Config, Error> {
+/// param or env variable). Values given inline can also be references to a secret stored
+/// elsewhere, see `resolve_secret`.
+pub async fn fill_secrets(mut config: Config, secrets: Secrets) -> Result<Config, Error> {
 	let allow_world_readable = secrets
 		.allow_world_readable_secrets
 		.unwrap_or(config.allow_world_readable_secrets);
@@ -63,7 +67,8 @@ pub fn fill_secrets(mut config: Config, secrets: Secrets) -> Result<Config, Erro
 		&secrets.rpc_secret_file,
 		"rpc_secret",
 		allow_world_readable,
-	)?;
+	)
+	.await?;
 
 	fill_secret(
 		&mut config.admin.admin_token,
@@ -72,7 +77,8 @@ pub fn fill_secrets(mut config: Config, secrets: Secrets) -> Result<Config, Erro
 		&secrets.admin_token_file,
 		"admin.admin_token",
 		allow_world_readable,
-	)?;
+	)
+	.await?;
 	fill_secret(
 		&mut config.admin.metrics_token,
 		&config.admin.metrics_token_file,
@@ -80,12 +86,26 @@ pub fn fill_secrets(mut config: Config, secrets: Secrets) -> Result<Config, Erro
 		&secrets.metrics_token_file,
 		"admin.metrics_token",
 		allow_world_readable,
-	)?;
+	)
+	.await?;
+
+	for credentials in config.s3_api.import_credentials.values_mut() {
+		credentials.secret_access_key = resolve_secret(
+			&credentials.secret_access_key,
+			"s3_api.import_credentials.secret_access_key",
+			allow_world_readable,
+		)
+		.await?;
+	}
+
+	if config.auto_generate_secrets {
+		fill_generated_secrets(&mut config, allow_world_readable)?;
+	}
 
 	Ok(config)
 }
 
-pub(crate) fn fill_secret(
+pub(crate) async fn fill_secret(
 	config_secret: &mut Option<String>,
 	config_secret_file: &Option<PathBuf>,
 	cli_secret: &Option<String>,
@@ -97,7 +117,7 @@ pub(crate) fn fill_secret(
 		(Some(_), Some(_)) => {
 			return Err(format!("only one of `{}` and `{}_file` can be set", name, name).into());
 		}
-		(Some(secret), None) => Some(secret.to_string()),
+		(Some(secret), None) => Some(resolve_secret(secret, name, allow_world_readable).await?),
 		(None, Some(file)) => Some(read_secret_file(file, allow_world_readable)?),
 		(None, None) => None,
 	};
@@ -114,12 +134,171 @@ pub(crate) fn fill_secret(
 		}
 
 		*config_secret = Some(read_secret_file(file_path, allow_world_readable)?);
+	} else if let Some(secret) = config_secret.take() {
+		*config_secret = Some(resolve_secret(&secret, name, allow_world_readable).await?);
 	}
 
 	Ok(())
 }
 
-fn read_secret_file(file_path: &PathBuf, allow_world_readable: bool) -> Result<String, Error> {
+/// Resolve a secret value that is a reference to a secret stored elsewhere:
+///
+/// - `file:<path>` reads the secret from a file, e.g. `file:/run/secrets/rpc`
+/// - `env:<name>` reads the secret from an environment variable
+/// - `vault:<mount>/<path>[#<field>]` reads the secret from a KV v2 secrets engine
+///   of HashiCorp Vault (requires the `vault-secrets` feature)
+///
+/// Any other value is returned unchanged.
+async fn resolve_secret(
+	value: &str,
+	name: &'static str,
+	allow_world_readable: bool,
+) -> Result<String, Error> {
+	if let Some(path) = value.strip_prefix("file:") {
+		read_secret_file(&PathBuf::from(path), allow_world_readable)
+			.map_err(|e| format!("unable to read `{}` from file {}: {}", name, path, e).into())
+	} else if let Some(var) = value.strip_prefix("env:") {
+		std::env::var(var).map_err(|e| {
+			format!(
+				"unable to read `{}` from environment variable {}: {}",
+				name, var, e
+			)
+			.into()
+		})
+	} else if let Some(path) = value.strip_prefix("vault:") {
+		#[cfg(feature = "vault-secrets")]
+		{
+			crate::vault::read_vault_secret(path)
+				.await
+				.map_err(|e| format!("unable to read `{}` from Vault: {}", name, e).into())
+		}
+		#[cfg(not(feature = "vault-secrets"))]
+		{
+			Err(format!(
+				"`{}` references Vault secret {}, but Vault support is not enabled in this build",
+				name, path
+			)
+			.into())
+		}
+	} else {
+		Ok(value.to_string())
+	}
+}
+
+/// Secrets generated on first start when `auto_generate_secrets` is set
+#[derive(Deserialize, Debug, Default)]
+pub(crate) struct GeneratedSecrets {
+	pub(crate) rpc_secret: Option<String>,
+	pub(crate) admin_token: Option<String>,
+}
+
+fn generated_secrets_path(config: &Config) -> PathBuf {
+	config
+		.generated_secrets_file
+		.clone()
+		.unwrap_or_else(|| config.metadata_dir.join("generated_secrets"))
+}
+
+/// Read the secrets that were generated by previous starts of the daemon,
+/// if any
+pub(crate) fn read_generated_secrets(
+	config: &Config,
+	allow_world_readable: bool,
+) -> Result<GeneratedSecrets, Error> {
+	let path = generated_secrets_path(config);
+	if !path.exists() {
+		return Ok(GeneratedSecrets::default());
+	}
+	check_secret_file_mode(&path, allow_world_readable)?;
+	let content = std::fs::read_to_string(&path)?;
+	toml::from_str(&content).map_err(|e| {
+		format!(
+			"unable to parse generated secrets file {}: {}",
+			path.display(),
+			e
+		)
+		.into()
+	})
+}
+
+/// Use generated secrets for the RPC secret and the admin token if they are
+/// not set. Secrets generated by a previous start are reused, missing ones
+/// are generated and added to the generated secrets file. Secrets that are
+/// already in the file are never overwritten.
+fn fill_generated_secrets(config: &mut Config, allow_world_readable: bool) -> Result<(), Error> {
+	let path = generated_secrets_path(config);
+	let mut generated = read_generated_secrets(config, allow_world_readable)?;
+
+	let mut new_secrets = vec![];
+	if config.rpc_secret.is_none() {
+		if generated.rpc_secret.is_none() {
+			generated.rpc_secret = Some(hex::encode(thread_rng().gen::<[u8; 32]>()));
+			new_secrets.push("rpc_secret");
+		}
+		config.rpc_secret = generated.rpc_secret.clone();
+	}
+	if config.admin.admin_token.is_none() {
+		if generated.admin_token.is_none() {
+			generated.admin_token = Some(hex::encode(thread_rng().gen::<[u8; 32]>()));
+			new_secrets.push("admin.admin_token");
+		}
+		config.admin.admin_token = generated.admin_token.clone();
+	}
+
+	if !new_secrets.is_empty() {
+		write_generated_secrets(&path, &generated).map_err(|e| {
+			Error::Message(format!(
+				"unable to store generated secrets in {}: {}",
+				path.display(),
+				e
+			))
+		})?;
+		info!(
+			"Generated {} and stored them in {}",
+			new_secrets.join(", "),
+			path.display()
+		);
+	}
+
+	Ok(())
+}
+
+fn write_generated_secrets(path: &Path, generated: &GeneratedSecrets) -> Result<(), Error> {
+	if let Some(parent) = path.parent() {
+		std::fs::create_dir_all(parent)?;
+	}
+
+	let mut content = String::new();
+	if let Some(rpc_secret) = &generated.rpc_secret {
+		content.push_str(&format!("rpc_secret = \"{}\"\n", rpc_secret));
+	}
+	if let Some(admin_token) = &generated.admin_token {
+		content.push_str(&format!("admin_token = \"{}\"\n", admin_token));
+	}
+
+	// Write to a temporary file that only the owner can read,
+	// and move it in place once it is complete
+	let tmp_path = path.with_extension("tmp");
+	if tmp_path.exists() {
+		std::fs::remove_file(&tmp_path)?;
+	}
+	let mut options = std::fs::OpenOptions::new();
+	options.write(true).create_new(true);
+	#[cfg(unix)]
+	{
+		use std::os::unix::fs::OpenOptionsExt;
+		options.mode(0o600);
+	}
+	let mut file = options.open(&tmp_path)?;
+	file.write_all(content.as_bytes())?;
+	file.sync_all()?;
+	drop(file);
+	std::fs::rename(&tmp_path, path)?;
+
+	Ok(())
+}
+
+fn check_secret_file_mode(file_path: &Path, allow_world_readable: bool) -> Result<(), Error> {
 	if !allow_world_readable {
 		#[cfg(unix)]
 		{
@@ -130,6 +309,11 @@ fn read_secret_file(file_path: &PathBuf, allow_world_readable: bool) -> Result<S
 			}
 		}
 	}
+	Ok(())
+}
+
+fn read_secret_file(file_path: &PathBuf, allow_world_readable: bool) -> Result<String, Error> {
+	check_secret_file_mode(file_path, allow_world_readable)?;
 
 	let secret_buf = std::fs::read_to_string(file_path)?;
 
@@ -148,8 +332,8 @@ mod tests {
 
 	use super::*;
 
-	#[test]
-	fn test_rpc_secret_file_works() -> Result<(), Error> {
+	#[tokio::test]
+	async fn test_rpc_secret_file_works() -> Result<(), Error> {
 		let path_secret = mktemp::Temp::new_file()?;
 		let mut file_secret = File::create(path_secret.as_path())?;
 		writeln!(file_secret, "foo")?;
@@ -199,7 +383,7 @@ mod tests {
 		drop(file_config_allow_world_readable);
 
 		let config = read_config(path_config.to_path_buf())?;
-		let config = fill_secrets(config, Secrets::default())?;
+		let config = fill_secrets(config, Secrets::default()).await?;
 		assert_eq!("foo", config.rpc_secret.unwrap());
 
 		// ---- Check non world-readable secrets config ----
@@ -222,23 +406,33 @@ mod tests {
 
 			// Config file that just specifies the path
 			let config = read_config(path_config.to_path_buf())?;
-			assert!(fill_secrets(config, Secrets::default()).is_err());
+			assert!(fill_secrets(config, Secrets::default()).await.is_err());
 
 			let config = read_config(path_config.to_path_buf())?;
-			assert!(fill_secrets(config, secrets_allow_world_readable.clone()).is_ok());
+			assert!(fill_secrets(config, secrets_allow_world_readable.clone())
+				.await
+				.is_ok());
 
 			let config = read_config(path_config.to_path_buf())?;
-			assert!(fill_secrets(config, secrets_no_allow_world_readable.clone()).is_err());
+			assert!(
+				fill_secrets(config, secrets_no_allow_world_readable.clone())
+					.await
+					.is_err()
+			);
 
 			// Config file that also specifies to allow world_readable_secrets
 			let config = read_config(path_config_allow_world_readable.to_path_buf())?;
-			assert!(fill_secrets(config, Secrets::default()).is_ok());
+			assert!(fill_secrets(config, Secrets::default()).await.is_ok());
 
 			let config = read_config(path_config_allow_world_readable.to_path_buf())?;
-			assert!(fill_secrets(config, secrets_allow_world_readable).is_ok());
+			assert!(fill_secrets(config, secrets_allow_world_readable)
+				.await
+				.is_ok());
 
 			let config = read_config(path_config_allow_world_readable.to_path_buf())?;
-			assert!(fill_secrets(config, secrets_no_allow_world_readable).is_err());
+			assert!(fill_secrets(config, secrets_no_allow_world_readable)
+				.await
+				.is_err());
 		}
 
 		// ---- Check alternative secrets specified on CLI ----
@@ -255,7 +449,8 @@ mod tests {
 				rpc_secret: Some("baz".into()),
 				..Default::default()
 			},
-		)?;
+		)
+		.await?;
 		assert_eq!(config.rpc_secret.as_deref(), Some("baz"));
 
 		let config = read_config(path_config.to_path_buf())?;
@@ -265,7 +460,8 @@ mod tests {
 				rpc_secret_file: Some(path_secret2.clone()),
 				..Default::default()
 			},
-		)?;
+		)
+		.await?;
 		assert_eq!(config.rpc_secret.as_deref(), Some("bar"));
 
 		let config = read_config(path_config.to_path_buf())?;
@@ -277,6 +473,7 @@ mod tests {
 				..Default::default()
 			}
 		)
+		.await
 		.is_err());
 
 		drop(path_secret);
@@ -287,8 +484,8 @@ mod tests {
 		Ok(())
 	}
 
-	#[test]
-	fn test_rcp_secret_and_rpc_secret_file_cannot_be_set_both() -> Result<(), Error> {
+	#[tokio::test]
+	async fn test_rcp_secret_and_rpc_secret_file_cannot_be_set_both() -> Result<(), Error> {
 		let path_config = mktemp::Temp::new_file()?;
 		let mut file_config = File::create(path_config.as_path())?;
 		writeln!(
@@ -310,6 +507,7 @@ mod tests {
 		assert_eq!(
 			"only one of `rpc_secret` and `rpc_secret_file` can be set",
 			fill_secrets(config, Secrets::default())
+				.await
 				.unwrap_err()
 				.to_string()
 		);
@@ -317,4 +515,198 @@ mod tests {
 		drop(file_config);
 		Ok(())
 	}
+
+	#[tokio::test]
+	async fn test_secret_file_reference() -> Result<(), Error> {
+		let path_secret = mktemp::Temp::new_file()?;
+		let mut file_secret = File::create(path_secret.as_path())?;
+		writeln!(file_secret, "foo")?;
+		drop(file_secret);
+
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::PermissionsExt;
+			let mut perm = std::fs::metadata(path_secret.as_path())?.permissions();
+			perm.set_mode(0o600);
+			std::fs::set_permissions(path_secret.as_path(), perm)?;
+		}
+
+		let path_config = mktemp::Temp::new_file()?;
+		let mut file_config = File::create(path_config.as_path())?;
+		writeln!(
+			file_config,
+			r#"
+			metadata_dir = "/tmp/garage/meta"
+			data_dir = "/tmp/garage/data"
+			replication_factor = 3
+			rpc_bind_addr = "[::]:3901"
+			rpc_secret = "file:{}"
+
+			[s3_api]
+			s3_region = "garage"
+			api_bind_addr = "[::]:3900"
+
+			[admin]
+			admin_token = "file:/nonexistent/garage/admin_token"
+			"#,
+			path_secret.as_path().display()
+		)?;
+		drop(file_config);
+
+		// The admin token references a file that does not exist
+		let config = read_config(path_config.to_path_buf())?;
+		assert!(fill_secrets(config, Secrets::default()).await.is_err());
+
+		let config = read_config(path_config.to_path_buf())?;
+		let config = fill_secrets(
+			config,
+			Secrets {
+				admin_token: Some("bar".into()),
+				..Default::default()
+			},
+		)
+		.await?;
+		assert_eq!(config.rpc_secret.as_deref(), Some("foo"));
+		assert_eq!(config.admin.admin_token.as_deref(), Some("bar"));
+
+		// References can also be given on the CLI
+		let config = read_config(path_config.to_path_buf())?;
+		let config = fill_secrets(
+			config,
+			Secrets {
+				admin_token: Some(format!("file:{}", path_secret.as_path().display())),
+				..Default::default()
+			},
+		)
+		.await?;
+		assert_eq!(config.admin.admin_token.as_deref(), Some("foo"));
+
+		drop(path_secret);
+		drop(path_config);
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn test_secret_env_reference() -> Result<(), Error> {
+		std::env::set_var("GARAGE_TEST_SECRET_ENV_REFERENCE", "foo");
+		std::env::remove_var("GARAGE_TEST_SECRET_ENV_REFERENCE_UNSET");
+
+		let path_config = mktemp::Temp::new_file()?;
+		let mut file_config = File::create(path_config.as_path())?;
+		writeln!(
+			file_config,
+			r#"
+			metadata_dir = "/tmp/garage/meta"
+			data_dir = "/tmp/garage/data"
+			replication_factor = 3
+			rpc_bind_addr = "[::]:3901"
+			rpc_secret = "env:GARAGE_TEST_SECRET_ENV_REFERENCE"
+
+			[s3_api]
+			s3_region = "garage"
+			api_bind_addr = "[::]:3900"
+
+			[admin]
+			metrics_token = "env:GARAGE_TEST_SECRET_ENV_REFERENCE_UNSET"
+			"#
+		)?;
+		drop(file_config);
+
+		let config = read_config(path_config.to_path_buf())?;
+		assert_eq!(
+			fill_secrets(config, Secrets::default())
+				.await
+				.unwrap_err()
+				.to_string(),
+			"unable to read `admin.metrics_token` from environment variable GARAGE_TEST_SECRET_ENV_REFERENCE_UNSET: environment variable not found"
+		);
+
+		let config = read_config(path_config.to_path_buf())?;
+		let config = fill_secrets(
+			config,
+			Secrets {
+				metrics_token: Some("bar".into()),
+				..Default::default()
+			},
+		)
+		.await?;
+		assert_eq!(config.rpc_secret.as_deref(), Some("foo"));
+		assert_eq!(config.admin.metrics_token.as_deref(), Some("bar"));
+
+		drop(path_config);
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn test_auto_generate_secrets() -> Result<(), Error> {
+		let dir = mktemp::Temp::new_dir()?;
+		let secrets_path = dir.as_path().join("generated_secrets");
+
+		let path_config = mktemp::Temp::new_file()?;
+		let mut file_config = File::create(path_config.as_path())?;
+		writeln!(
+			file_config,
+			r#"
+			metadata_dir = "/tmp/garage/meta"
+			data_dir = "/tmp/garage/data"
+			replication_factor = 3
+			rpc_bind_addr = "[::]:3901"
+			auto_generate_secrets = true
+			generated_secrets_file = "{}"
+
+			[s3_api]
+			s3_region = "garage"
+			api_bind_addr = "[::]:3900"
+			"#,
+			secrets_path.display()
+		)?;
+		drop(file_config);
+
+		// First start: the secrets are generated and stored
+		let config = read_config(path_config.to_path_buf())?;
+		let config = fill_secrets(config, Secrets::default()).await?;
+		let rpc_secret = config.rpc_secret.unwrap();
+		let admin_token = config.admin.admin_token.unwrap();
+		assert_eq!(hex::decode(&rpc_secret).unwrap().len(), 32);
+		assert_ne!(rpc_secret, admin_token);
+
+		let content = std::fs::read_to_string(&secrets_path)?;
+		assert!(content.contains(&rpc_secret));
+		assert!(content.contains(&admin_token));
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::PermissionsExt;
+			let mode = std::fs::metadata(&secrets_path)?.permissions().mode();
+			assert_eq!(mode & 0o777, 0o600);
+		}
+
+		// Second start: the same secrets are used
+		let config = read_config(path_config.to_path_buf())?;
+		let config = fill_secrets(config, Secrets::default()).await?;
+		assert_eq!(config.rpc_secret.as_ref(), Some(&rpc_secret));
+		assert_eq!(config.admin.admin_token.as_ref(), Some(&admin_token));
+
+		// Secrets that are set take precedence, the stored ones are kept
+		let config = read_config(path_config.to_path_buf())?;
+		let config = fill_secrets(
+			config,
+			Secrets {
+				rpc_secret: Some("baz".into()),
+				..Default::default()
+			},
+		)
+		.await?;
+		assert_eq!(config.rpc_secret.as_deref(), Some("baz"));
+		assert_eq!(config.admin.admin_token.as_ref(), Some(&admin_token));
+		assert_eq!(std::fs::read_to_string(&secrets_path)?, content);
+
+		// Starting fails if the secrets can't be stored
+		let mut config = read_config(path_config.to_path_buf())?;
+		config.generated_secrets_file = Some(path_config.as_path().join("generated_secrets"));
+		assert!(fill_secrets(config, Secrets::default()).await.is_err());
+
+		drop(path_config);
+		drop(dir);
+		Ok(())
+	}
 }
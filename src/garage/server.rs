@@ -1,4 +1,6 @@
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 use tokio::sync::watch;
 
@@ -7,7 +9,11 @@ use garage_util::config::*;
 use garage_util::error::Error;
 
 use garage_api::admin::api_server::AdminApiServer;
+use garage_api::s3::access_log::{AccessLogWorker, AccessLogger};
 use garage_api::s3::api_server::S3ApiServer;
+use garage_api::s3::bandwidth::BandwidthLimiter;
+use garage_api::s3::import::ImportJobs;
+use garage_api::s3::recent_errors::RecentErrors;
 use garage_model::garage::Garage;
 use garage_web::WebServer;
 
@@ -29,7 +35,7 @@ async fn wait_from(mut chan: watch::Receiver<bool>) {
 
 pub async fn run_server(config_file: PathBuf, secrets: Secrets) -> Result<(), Error> {
 	info!("Loading configuration...");
-	let config = fill_secrets(read_config(config_file)?, secrets)?;
+	let config = fill_secrets(read_config(config_file)?, secrets).await?;
 
 	// ---- Initialize Garage internals ----
 
@@ -45,10 +51,14 @@ pub async fn run_server(config_file: PathBuf, secrets: Secrets) -> Result<(), Er
 
 	info!("Initializing Garage main data store...");
 	let garage = Garage::new(config.clone())?;
+	crate::logging::set_node_id(hex::encode(garage.system.id));
 
 	info!("Initializing background runner...");
 	let watch_cancel = watch_shutdown_signal();
-	let (background, await_background_done) = BackgroundRunner::new(watch_cancel.clone());
+	let (background, await_background_done) = BackgroundRunner::new(
+		watch_cancel.clone(),
+		Duration::from_secs(config.shutdown_deadline_secs),
+	);
 
 	info!("Spawning Garage workers...");
 	garage.spawn_workers(&background)?;
@@ -63,9 +73,23 @@ pub async fn run_server(config_file: PathBuf, secrets: Secrets) -> Result<(), Er
 		error!("Garage was built without OTLP exporter, admin.trace_sink is ignored.");
 	}
 
+	let s3_recent_errors = Arc::new(RecentErrors::new(&config.s3_api));
+	let s3_bandwidth = Arc::new(BandwidthLimiter::new(&config.s3_api));
+	let s3_access_logger = Arc::new(AccessLogger::new(&config.s3_api));
+	background.spawn_worker(AccessLogWorker::new(
+		garage.clone(),
+		s3_access_logger.clone(),
+	));
+
+	let s3_import_jobs = ImportJobs::new(garage.clone());
+	s3_import_jobs.resume();
+
 	info!("Initialize Admin API server and metrics collector...");
 	let admin_server = AdminApiServer::new(
 		garage.clone(),
+		s3_recent_errors.clone(),
+		s3_bandwidth.clone(),
+		s3_import_jobs,
 		#[cfg(feature = "metrics")]
 		metrics_exporter,
 	);
@@ -88,6 +112,9 @@ pub async fn run_server(config_file: PathBuf, secrets: Secrets) -> Result<(), Er
 				garage.clone(),
 				s3_bind_addr.clone(),
 				config.s3_api.s3_region.clone(),
+				s3_recent_errors.clone(),
+				s3_bandwidth.clone(),
+				s3_access_logger.clone(),
 				watch_cancel.clone(),
 			)),
 		));
@@ -113,7 +140,11 @@ pub async fn run_server(config_file: PathBuf, secrets: Secrets) -> Result<(), Er
 
 	if let Some(web_config) = &config.s3_web {
 		info!("Initializing web server...");
-		let web_server = WebServer::new(garage.clone(), web_config.root_domain.clone());
+		let web_server = WebServer::new(
+			garage.clone(),
+			web_config.root_domain.clone(),
+			web_config.precompressed_variants.clone(),
+		);
 		servers.push((
 			"Web",
 			tokio::spawn(web_server.run(web_config.bind_addr.clone(), watch_cancel.clone())),
@@ -161,12 +192,29 @@ pub async fn run_server(config_file: PathBuf, secrets: Secrets) -> Result<(), Er
 	run_system.await?;
 	info!("Netapp exited");
 
+	// Keep a handle on the metadata database to flush it once all
+	// background tasks have ended
+	let db = garage.db.clone();
+
 	// Drop all references so that stuff can terminate properly
 	garage.system.cleanup();
 	drop(garage);
 
-	// Await for all background tasks to end
-	await_background_done.await?;
+	// Await for all background tasks to end, workers still running
+	// after the shutdown deadline are interrupted
+	let shutdown_report = await_background_done.await?;
+	if !shutdown_report.is_clean() {
+		warn!(
+			"Shutdown report: {}",
+			serde_json::to_string(&shutdown_report).unwrap_or_default()
+		);
+	}
+
+	info!("Flushing metadata database...");
+	if let Err(e) = db.sync() {
+		error!("Could not flush metadata database: {}", e);
+	}
+	drop(db);
 
 	info!("Cleaning up...");
 
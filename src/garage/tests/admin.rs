@@ -1,5 +1,19 @@
+use std::path::PathBuf;
+
+use aws_sdk_s3::error::ProvideErrorMetadata;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use garage_util::data::blake2sum;
+use http::{Method, Request, StatusCode};
+use http_body_util::Full as FullBody;
+use hyper::body::Bytes;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use serde_json::{json, Value};
+
 use crate::common;
 use crate::common::ext::*;
+use crate::json_body;
 
 const BCKT_NAME: &str = "seau";
 
@@ -51,3 +65,1654 @@ async fn test_admin_bucket_perms() {
 
 	assert!(hb().await.is_err());
 }
+
+#[tokio::test]
+async fn test_admin_block_replicate() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("block-replicate");
+
+	// Large enough to not be inlined, small enough to fit in a single block
+	let body = (0..65536u32)
+		.map(|i| (i.wrapping_mul(2654435761) >> 24) as u8)
+		.collect::<Vec<u8>>();
+	let hash = hex::encode(blake2sum(&body));
+
+	ctx.client
+		.put_object()
+		.bucket(&bucket)
+		.key("obj")
+		.body(ByteStream::from(body.clone()))
+		.send()
+		.await
+		.unwrap();
+
+	let block_dir = ctx
+		.garage
+		.path
+		.join("data")
+		.join(&hash[0..2])
+		.join(&hash[2..4]);
+	let block_file = || -> Option<PathBuf> {
+		[
+			block_dir.join(&hash),
+			block_dir.join(format!("{}.zst", hash)),
+		]
+		.iter()
+		.find(|p| p.exists())
+		.cloned()
+	};
+	let replicate = |extra: &[&str]| {
+		ctx.garage
+			.command()
+			.args(["block", "replicate"])
+			.args(extra)
+			.arg(&hash)
+			.output()
+			.unwrap()
+	};
+
+	// The only node already stores the block, nothing to do
+	let out = replicate(&[]);
+	assert!(out.status.success());
+	assert!(String::from_utf8_lossy(&out.stdout).contains("already stored"));
+
+	// With --force, the block is read, rewritten and verified
+	let out = replicate(&["--force"]);
+	assert!(out.status.success());
+	assert!(String::from_utf8_lossy(&out.stdout).contains("written and verified"));
+	assert!(block_file().is_some());
+
+	let res = ctx
+		.client
+		.get_object()
+		.bucket(&bucket)
+		.key("obj")
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(res.body.collect().await.unwrap().into_bytes(), body);
+
+	// Once the only copy is gone, there is nothing to replicate from
+	std::fs::remove_file(block_file().unwrap()).unwrap();
+	let out = replicate(&[]);
+	assert!(!out.status.success());
+}
+
+#[tokio::test]
+async fn test_admin_delete_prefix() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("delete-prefix");
+
+	let keys = ["logs", "logs/a", "logs/b/c", "logs/z", "logsx", "other"];
+	for key in keys {
+		ctx.client
+			.put_object()
+			.bucket(&bucket)
+			.key(key)
+			.body(ByteStream::from_static(b"0123456789"))
+			.send()
+			.await
+			.unwrap();
+	}
+
+	let list_keys = || async {
+		ctx.client
+			.list_objects_v2()
+			.bucket(&bucket)
+			.send()
+			.await
+			.unwrap()
+			.contents()
+			.iter()
+			.map(|o| o.key().unwrap().to_string())
+			.collect::<Vec<_>>()
+	};
+
+	let run_job = |extra: &[&str]| {
+		let out = ctx
+			.garage
+			.command()
+			.args(["bucket", "delete-prefix", &bucket, "logs/"])
+			.args(extra)
+			.expect_success_output("Could not start delete-prefix job");
+		let out = String::from_utf8(out.stdout).unwrap();
+		let job_id = out
+			.split_whitespace()
+			.skip_while(|w| *w != "job")
+			.nth(1)
+			.unwrap()
+			.trim_end_matches('.')
+			.to_string();
+
+		// Wait for the job to complete
+		for _ in 0..50 {
+			let out = ctx
+				.garage
+				.command()
+				.args(["bucket", "delete-prefix-status", &job_id])
+				.expect_success_output("Could not get delete-prefix job status");
+			let out = String::from_utf8(out.stdout).unwrap();
+			if out.contains("done") {
+				return out;
+			}
+			std::thread::sleep(std::time::Duration::from_millis(100));
+		}
+		panic!("delete-prefix job did not complete");
+	};
+
+	// Dry run counts the objects but deletes nothing
+	let status = run_job(&["--dry-run"]);
+	assert!(status.contains("done (dry run)"));
+	assert!(status.split_whitespace().any(|w| w == "3"));
+	assert_eq!(list_keys().await.len(), keys.len());
+
+	let status = run_job(&[]);
+	assert!(status.split_whitespace().any(|w| w == "3"));
+	assert_eq!(list_keys().await, vec!["logs", "logsx", "other"]);
+
+	// An empty prefix must be explicitly allowed
+	let out = ctx
+		.garage
+		.command()
+		.args(["bucket", "delete-prefix", &bucket, ""])
+		.output()
+		.unwrap();
+	assert!(!out.status.success());
+	assert_eq!(list_keys().await.len(), 3);
+
+	// Objects of read-only buckets cannot be deleted
+	ctx.garage
+		.command()
+		.args(["bucket", "set-read-only", "--enable", &bucket])
+		.quiet()
+		.expect_success_status("Could not make bucket read-only");
+	let out = ctx
+		.garage
+		.command()
+		.args(["bucket", "delete-prefix", &bucket, "logs"])
+		.output()
+		.unwrap();
+	assert!(!out.status.success());
+	assert_eq!(list_keys().await.len(), 3);
+}
+
+#[tokio::test]
+async fn test_admin_copy_bucket_contents() {
+	let ctx = common::context();
+	let src = ctx.create_bucket("copy-contents-src");
+	let dst = ctx.create_bucket("copy-contents-dst");
+
+	for key in ["photos/a", "photos/b", "photos/c", "other"] {
+		ctx.client
+			.put_object()
+			.bucket(&src)
+			.key(key)
+			.body(ByteStream::from(key.as_bytes().to_vec()))
+			.send()
+			.await
+			.unwrap();
+	}
+	ctx.client
+		.put_object()
+		.bucket(&dst)
+		.key("photos/b")
+		.body(ByteStream::from_static(b"already here"))
+		.send()
+		.await
+		.unwrap();
+
+	let bucket_id = |name: String| {
+		let ctx = &ctx;
+		async move {
+			let info = admin_request(
+				ctx,
+				Method::GET,
+				&format!("/v1/bucket?globalAlias={}", name),
+				json!({}),
+			)
+			.await;
+			info["id"].as_str().unwrap().to_string()
+		}
+	};
+	let src_id = bucket_id(src.clone()).await;
+	let dst_id = bucket_id(dst.clone()).await;
+
+	let get_object = |key: &'static str| {
+		let ctx = &ctx;
+		let dst = &dst;
+		async move {
+			let o = ctx
+				.client
+				.get_object()
+				.bucket(dst)
+				.key(key)
+				.send()
+				.await
+				.unwrap();
+			o.body.collect().await.unwrap().into_bytes()
+		}
+	};
+
+	let run_job = |overwrite: bool| {
+		let ctx = &ctx;
+		let body = json!({
+			"sourceBucketId": src_id,
+			"destBucketId": dst_id,
+			"prefix": "photos/",
+			"overwrite": overwrite,
+		});
+		async move {
+			let job = admin_request(ctx, Method::POST, "/v1/bucket/copy-contents", body).await;
+			let job_id = job["jobId"].as_str().unwrap().to_string();
+			for _ in 0..50 {
+				let job = admin_request(
+					ctx,
+					Method::GET,
+					&format!("/v1/bucket/copy-contents?jobId={}", job_id),
+					json!({}),
+				)
+				.await;
+				if job["state"] != "running" {
+					return job;
+				}
+				tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+			}
+			panic!("copy job did not complete");
+		}
+	};
+
+	// Existing objects of the destination bucket are skipped
+	let job = run_job(false).await;
+	assert_eq!(job["state"], "done");
+	assert_eq!(job["objectsCopied"], 2);
+	assert_eq!(job["objectsSkipped"], 1);
+	assert_eq!(job["lastKey"], "photos/c");
+	assert_eq!(&get_object("photos/a").await[..], b"photos/a");
+	assert_eq!(&get_object("photos/b").await[..], b"already here");
+	assert_eq!(&get_object("photos/c").await[..], b"photos/c");
+	let err = ctx
+		.client
+		.head_object()
+		.bucket(&dst)
+		.key("other")
+		.send()
+		.await
+		.unwrap_err();
+	assert_eq!(err.raw_response().unwrap().status().as_u16(), 404);
+
+	// With overwrite, they are replaced
+	let job = run_job(true).await;
+	assert_eq!(job["objectsCopied"], 3);
+	assert_eq!(&get_object("photos/b").await[..], b"photos/b");
+
+	// Deleting the source objects does not affect the copies
+	ctx.client
+		.delete_object()
+		.bucket(&src)
+		.key("photos/a")
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(&get_object("photos/a").await[..], b"photos/a");
+}
+
+#[tokio::test]
+async fn test_admin_copy_bucket_contents_case_insensitive() {
+	let ctx = common::context();
+	let src = ctx.create_bucket("copy-ci-src");
+	let dst = ctx.create_bucket("copy-ci-dst");
+
+	ctx.client
+		.put_object()
+		.bucket(&src)
+		.key("Photos/Cat.JPG")
+		.body(ByteStream::from_static(b"meow"))
+		.send()
+		.await
+		.unwrap();
+
+	let bucket_id = |name: String| {
+		let ctx = &ctx;
+		async move {
+			let info = admin_request(
+				ctx,
+				Method::GET,
+				&format!("/v1/bucket?globalAlias={}", name),
+				json!({}),
+			)
+			.await;
+			info["id"].as_str().unwrap().to_string()
+		}
+	};
+	let src_id = bucket_id(src.clone()).await;
+	let dst_id = bucket_id(dst.clone()).await;
+	admin_request(
+		&ctx,
+		Method::PUT,
+		&format!("/v1/bucket?id={}", dst_id),
+		json!({ "caseInsensitiveKeys": true }),
+	)
+	.await;
+
+	let run_job = || {
+		let ctx = &ctx;
+		let body = json!({
+			"sourceBucketId": src_id,
+			"destBucketId": dst_id,
+			"prefix": "Photos/",
+		});
+		async move {
+			let job = admin_request(ctx, Method::POST, "/v1/bucket/copy-contents", body).await;
+			let job_id = job["jobId"].as_str().unwrap().to_string();
+			for _ in 0..50 {
+				let job = admin_request(
+					ctx,
+					Method::GET,
+					&format!("/v1/bucket/copy-contents?jobId={}", job_id),
+					json!({}),
+				)
+				.await;
+				if job["state"] != "running" {
+					return job;
+				}
+				tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+			}
+			panic!("copy job did not complete");
+		}
+	};
+
+	let job = run_job().await;
+	assert_eq!(job["state"], "done");
+	assert_eq!(job["objectsCopied"], 1);
+
+	// The copy is found whatever the casing, and keeps its original key
+	for key in ["Photos/Cat.JPG", "photos/cat.jpg"] {
+		let o = ctx
+			.client
+			.get_object()
+			.bucket(&dst)
+			.key(key)
+			.send()
+			.await
+			.unwrap();
+		assert_eq!(&o.body.collect().await.unwrap().into_bytes()[..], b"meow");
+	}
+	let list = ctx
+		.client
+		.list_objects_v2()
+		.bucket(&dst)
+		.send()
+		.await
+		.unwrap();
+	let keys = list
+		.contents
+		.unwrap_or_default()
+		.into_iter()
+		.map(|o| o.key.unwrap())
+		.collect::<Vec<_>>();
+	assert_eq!(keys, vec!["Photos/Cat.JPG".to_string()]);
+
+	// Running the job again finds the copy and skips it
+	let job = run_job().await;
+	assert_eq!(job["objectsCopied"], 0);
+	assert_eq!(job["objectsSkipped"], 1);
+}
+
+#[tokio::test]
+async fn test_admin_import_from_s3() {
+	let ctx = common::context();
+	let src = ctx.create_bucket("import-s3-src");
+	let dst = ctx.create_bucket("import-s3-dst");
+
+	ctx.client
+		.put_object()
+		.bucket(&src)
+		.key("docs/simple")
+		.content_type("text/plain")
+		.metadata("owner", "alice")
+		.body(ByteStream::from_static(b"hello import"))
+		.send()
+		.await
+		.unwrap();
+	ctx.client
+		.put_object()
+		.bucket(&src)
+		.key("other")
+		.body(ByteStream::from_static(b"not imported"))
+		.send()
+		.await
+		.unwrap();
+
+	let part1 = vec![b'a'; 5 * 1024 * 1024];
+	let part2 = vec![b'b'; 1000];
+	let mpu = ctx
+		.client
+		.create_multipart_upload()
+		.bucket(&src)
+		.key("docs/multipart")
+		.send()
+		.await
+		.unwrap();
+	let upload_id = mpu.upload_id.unwrap();
+	let mut parts = CompletedMultipartUpload::builder();
+	for (i, data) in [&part1, &part2].iter().enumerate() {
+		let part_number = i as i32 + 1;
+		let p = ctx
+			.client
+			.upload_part()
+			.bucket(&src)
+			.key("docs/multipart")
+			.upload_id(&upload_id)
+			.part_number(part_number)
+			.body(ByteStream::from(data.to_vec()))
+			.send()
+			.await
+			.unwrap();
+		parts = parts.parts(
+			CompletedPart::builder()
+				.part_number(part_number)
+				.e_tag(p.e_tag.unwrap())
+				.build(),
+		);
+	}
+	ctx.client
+		.complete_multipart_upload()
+		.bucket(&src)
+		.key("docs/multipart")
+		.upload_id(&upload_id)
+		.multipart_upload(parts.build())
+		.send()
+		.await
+		.unwrap();
+
+	let info = admin_request(
+		&ctx,
+		Method::GET,
+		&format!("/v1/bucket?globalAlias={}", dst),
+		json!({}),
+	)
+	.await;
+	let dst_id = info["id"].as_str().unwrap().to_string();
+
+	let run_job = |conflict_policy: &'static str| {
+		let ctx = &ctx;
+		let body = json!({
+			"destBucketId": dst_id,
+			"sourceEndpoint": format!("http://127.0.0.1:{}/", ctx.garage.s3_port),
+			"sourceRegion": "garage-integ-test",
+			"sourceBucket": src,
+			"credentials": {
+				"accessKeyId": ctx.key.id,
+				"secretAccessKey": ctx.key.secret,
+			},
+			"prefix": "docs/",
+			"conflictPolicy": conflict_policy,
+			"preserveMultipart": true,
+			"concurrency": 2,
+		});
+		async move {
+			let job = admin_request(ctx, Method::POST, "/v1/bucket/import-s3", body).await;
+			let job_id = job["jobId"].as_str().unwrap().to_string();
+			for _ in 0..100 {
+				let job = admin_request(
+					ctx,
+					Method::GET,
+					&format!("/v1/bucket/import-s3?jobId={}", job_id),
+					json!({}),
+				)
+				.await;
+				if job["state"] != "running" {
+					// The credentials are never returned
+					assert!(!job.to_string().contains(&ctx.key.secret));
+					return job;
+				}
+				tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+			}
+			panic!("import job did not complete");
+		}
+	};
+
+	let job = run_job("skipIdentical").await;
+	assert_eq!(job["state"], "done", "{}", job);
+	assert_eq!(job["objectsTotal"], 2);
+	assert_eq!(job["objectsImported"], 2);
+	assert_eq!(job["objectsFailed"], 0);
+	assert_eq!(job["cursor"], "docs/simple");
+
+	// Metadata and multipart ETags are preserved
+	for key in ["docs/simple", "docs/multipart"] {
+		let src_head = ctx
+			.client
+			.head_object()
+			.bucket(&src)
+			.key(key)
+			.send()
+			.await
+			.unwrap();
+		let o = ctx
+			.client
+			.get_object()
+			.bucket(&dst)
+			.key(key)
+			.send()
+			.await
+			.unwrap();
+		assert_eq!(o.e_tag, src_head.e_tag);
+		assert_eq!(o.content_type, src_head.content_type);
+		assert_eq!(o.metadata, src_head.metadata);
+		assert_eq!(o.content_length, src_head.content_length);
+	}
+	let o = ctx
+		.client
+		.get_object()
+		.bucket(&dst)
+		.key("docs/simple")
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(o.metadata.unwrap()["owner"], "alice");
+	assert_bytes_eq!(o.body, b"hello import");
+	let err = ctx
+		.client
+		.head_object()
+		.bucket(&dst)
+		.key("other")
+		.send()
+		.await
+		.unwrap_err();
+	assert_eq!(err.raw_response().unwrap().status().as_u16(), 404);
+
+	// Identical objects are skipped when the import is run again
+	let job = run_job("skipIdentical").await;
+	assert_eq!(job["state"], "done");
+	assert_eq!(job["objectsImported"], 0);
+	assert_eq!(job["objectsSkipped"], 2);
+
+	// They are imported again with the overwrite policy
+	let job = run_job("overwrite").await;
+	assert_eq!(job["state"], "done");
+	assert_eq!(job["objectsImported"], 2);
+	assert_eq!(job["objectsSkipped"], 0);
+}
+
+#[tokio::test]
+async fn test_admin_header_limits() {
+	let ctx = common::context();
+	let client = Client::builder(TokioExecutor::new()).build_http();
+
+	let request = |port: u16, headers: Vec<(String, String)>| {
+		let mut req = Request::builder()
+			.method(Method::GET)
+			.uri(format!("http://127.0.0.1:{}/v1/status", port))
+			.header(
+				"Authorization",
+				format!("Bearer {}", common::garage::GARAGE_TEST_ADMIN_TOKEN),
+			);
+		for (name, value) in headers {
+			req = req.header(name, value);
+		}
+		client.request(req.body(FullBody::new(Bytes::new())).unwrap())
+	};
+	let many_headers = |n: usize| -> Vec<(String, String)> {
+		(0..n)
+			.map(|i| (format!("x-test-{}", i), "a".into()))
+			.collect()
+	};
+
+	// Within the limits configured for the admin API (20 headers, 4 KiB)
+	let resp = request(ctx.garage.admin_port, many_headers(10))
+		.await
+		.unwrap();
+	assert_eq!(resp.status(), StatusCode::OK);
+
+	// Too many headers
+	let resp = request(ctx.garage.admin_port, many_headers(30))
+		.await
+		.unwrap();
+	assert_eq!(resp.status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+
+	// Headers too large
+	let big_header = vec![("x-test".to_string(), "a".repeat(5000))];
+	let resp = request(ctx.garage.admin_port, big_header).await.unwrap();
+	assert_eq!(resp.status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+
+	// Thousands of headers are rejected by all listeners, before the
+	// request is authenticated
+	for port in [
+		ctx.garage.s3_port,
+		ctx.garage.k2v_port,
+		ctx.garage.web_port,
+		ctx.garage.admin_port,
+	] {
+		// The server might close the connection before having read all
+		// headers, in which case the client does not get the response
+		if let Ok(resp) = request(port, many_headers(5000)).await {
+			assert_eq!(resp.status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+		}
+	}
+}
+
+async fn admin_request(ctx: &common::Context, method: Method, path: &str, body: Value) -> Value {
+	let client = Client::builder(TokioExecutor::new()).build_http();
+	let req = Request::builder()
+		.method(method)
+		.uri(format!(
+			"http://127.0.0.1:{}{}",
+			ctx.garage.admin_port, path
+		))
+		.header(
+			"Authorization",
+			format!("Bearer {}", common::garage::GARAGE_TEST_ADMIN_TOKEN),
+		)
+		.body(FullBody::new(Bytes::from(body.to_string())))
+		.unwrap();
+	let resp = client.request(req).await.unwrap();
+	assert_eq!(resp.status(), StatusCode::OK);
+	json_body(resp).await
+}
+
+#[tokio::test]
+async fn test_admin_find_orphan_buckets() {
+	let ctx = common::context();
+
+	let find_orphan = |id: String| {
+		let ctx = &ctx;
+		async move {
+			let orphans = admin_request(ctx, Method::GET, "/v1/bucket/orphans", json!({})).await;
+			orphans
+				.as_array()
+				.unwrap()
+				.iter()
+				.find(|b| b["id"] == id.as_str())
+				.cloned()
+		}
+	};
+
+	// A bucket created without any alias cannot be reached by its name
+	let orphan = admin_request(&ctx, Method::POST, "/v1/bucket", json!({})).await;
+	let orphan_id = orphan["id"].as_str().unwrap().to_string();
+
+	// It can still be accessed with its full id
+	ctx.garage
+		.command()
+		.args(["bucket", "allow", "--read", "--write", &orphan_id])
+		.args(["--key", &ctx.key.id])
+		.quiet()
+		.expect_success_status("Could not allow key for bucket");
+	ctx.client
+		.put_object()
+		.bucket(&orphan_id)
+		.key("obj")
+		.body(ByteStream::from_static(b"0123456789"))
+		.send()
+		.await
+		.unwrap();
+
+	// Object counters are updated asynchronously
+	let mut item = find_orphan(orphan_id.clone()).await.unwrap();
+	for _ in 0..50 {
+		if item["objects"] == 1 {
+			break;
+		}
+		tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+		item = find_orphan(orphan_id.clone()).await.unwrap();
+	}
+	assert_eq!(item["objects"], 1);
+	assert_eq!(item["bytes"], 10);
+	assert_eq!(item["globalAliases"], json!([]));
+
+	// Buckets with an alias are not listed
+	let bucket = ctx.create_bucket("not-an-orphan");
+	let info = admin_request(
+		&ctx,
+		Method::GET,
+		&format!("/v1/bucket?globalAlias={}", bucket),
+		json!({}),
+	)
+	.await;
+	assert!(find_orphan(info["id"].as_str().unwrap().to_string())
+		.await
+		.is_none());
+
+	// Once aliased, the bucket is no longer an orphan
+	admin_request(
+		&ctx,
+		Method::PUT,
+		&format!(
+			"/v1/bucket/alias/global?id={}&alias=orphan-adopted",
+			orphan_id
+		),
+		json!({}),
+	)
+	.await;
+	assert!(find_orphan(orphan_id).await.is_none());
+}
+
+#[tokio::test]
+async fn test_admin_rotate_key_secret() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("rotate-key-secret");
+
+	let key = ctx.garage.key(Some("rotated-key"));
+	ctx.garage
+		.command()
+		.args(["bucket", "allow", "--read", "--write", &bucket])
+		.args(["--key", &key.id])
+		.quiet()
+		.expect_success_status("Could not allow key for bucket");
+
+	let put = |client: aws_sdk_s3::Client| {
+		let bucket = bucket.clone();
+		async move {
+			client
+				.put_object()
+				.bucket(&bucket)
+				.key("obj")
+				.body(ByteStream::from_static(b"0123456789"))
+				.send()
+				.await
+		}
+	};
+
+	let old_client = common::client::build_client(&key);
+	put(old_client.clone()).await.unwrap();
+
+	let before = admin_request(
+		&ctx,
+		Method::GET,
+		&format!("/v1/key?id={}", key.id),
+		json!({}),
+	)
+	.await;
+
+	// Rotate with a newly generated secret
+	let rotated = admin_request(
+		&ctx,
+		Method::POST,
+		&format!("/v1/key/rotate-secret?id={}", key.id),
+		json!({}),
+	)
+	.await;
+	assert_eq!(rotated["accessKeyId"], key.id.as_str());
+	let new_secret = rotated["secretAccessKey"].as_str().unwrap().to_string();
+	assert_ne!(new_secret, key.secret);
+	assert_eq!(rotated["name"], before["name"]);
+	assert_eq!(rotated["buckets"], before["buckets"]);
+	assert_eq!(rotated["permissions"], before["permissions"]);
+
+	// Requests signed with the old secret are refused
+	assert!(put(old_client).await.is_err());
+
+	let new_key = common::garage::Key {
+		secret: new_secret,
+		..key.clone()
+	};
+	let new_client = common::client::build_client(&new_key);
+	put(new_client.clone()).await.unwrap();
+	let obj = new_client
+		.get_object()
+		.bucket(&bucket)
+		.key("obj")
+		.send()
+		.await
+		.unwrap();
+	assert_bytes_eq!(obj.body, b"0123456789");
+
+	// Rotate to a given secret
+	let given_secret = "b892c0665f0ada8a4755dae98baa3b133590e11dae3bcc1f9d769d67f16c3835";
+	let rotated = admin_request(
+		&ctx,
+		Method::POST,
+		&format!("/v1/key/rotate-secret?id={}", key.id),
+		json!({ "secretAccessKey": given_secret }),
+	)
+	.await;
+	assert_eq!(rotated["secretAccessKey"], given_secret);
+	assert!(put(new_client).await.is_err());
+
+	let given_key = common::garage::Key {
+		secret: given_secret.to_string(),
+		..key
+	};
+	put(common::client::build_client(&given_key)).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_admin_key_usage() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("key-usage");
+
+	let unused_key = ctx.garage.key(Some("unused-key"));
+	let key = ctx.garage.key(Some("used-key"));
+	ctx.garage
+		.command()
+		.args(["bucket", "allow", "--read", "--write", &bucket])
+		.args(["--key", &key.id])
+		.quiet()
+		.expect_success_status("Could not allow key for bucket");
+
+	let key_path = format!("/v1/key?id={}", key.id);
+	let key_info = || admin_request(&ctx, Method::GET, &key_path, json!({}));
+	assert_eq!(key_info().await["requests"], 0);
+
+	let client = common::client::build_client(&key);
+	for _ in 0..2 {
+		client
+			.put_object()
+			.bucket(&bucket)
+			.key("obj")
+			.body(ByteStream::from_static(b"0123456789"))
+			.send()
+			.await
+			.unwrap();
+	}
+
+	// Usage is reported before it is written to the key table
+	let info = key_info().await;
+	assert_eq!(info["requests"], 2);
+	assert!(info["lastUsed"].is_string());
+
+	let list = admin_request(&ctx, Method::GET, "/v1/key?sort=lastUsed", json!({})).await;
+	let ids = list
+		.as_array()
+		.unwrap()
+		.iter()
+		.map(|k| k["id"].as_str().unwrap())
+		.collect::<Vec<_>>();
+	let pos = |id: &str| ids.iter().position(|x| *x == id).unwrap();
+	assert!(pos(&key.id) < pos(&unused_key.id));
+}
+
+#[tokio::test]
+async fn test_admin_suspend_key() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("suspend-key");
+
+	let key = ctx.garage.key(Some("suspended-key"));
+	ctx.garage
+		.command()
+		.args(["bucket", "allow", "--read", "--write", &bucket])
+		.args(["--key", &key.id])
+		.quiet()
+		.expect_success_status("Could not allow key for bucket");
+
+	let client = common::client::build_client(&key);
+	let put = || {
+		client
+			.put_object()
+			.bucket(&bucket)
+			.key("obj")
+			.body(ByteStream::from_static(b"0123456789"))
+			.send()
+	};
+	put().await.unwrap();
+
+	let key_path = format!("/v1/key?id={}", key.id);
+	let suspended = admin_request(&ctx, Method::POST, &key_path, json!({"suspended": true})).await;
+	assert_eq!(suspended["suspended"], true);
+
+	// Requests signed with the key are rejected, with a specific message
+	let err = put().await.unwrap_err();
+	assert_eq!(err.raw_response().unwrap().status().as_u16(), 403);
+	assert!(err.message().unwrap().contains("suspended"));
+
+	// The key and its permissions are kept, and shown as suspended
+	let list = admin_request(&ctx, Method::GET, "/v1/key", json!({})).await;
+	let item = list
+		.as_array()
+		.unwrap()
+		.iter()
+		.find(|k| k["id"] == key.id.as_str())
+		.unwrap();
+	assert_eq!(item["suspended"], true);
+	let info = admin_request(
+		&ctx,
+		Method::GET,
+		&format!("/v1/bucket?globalAlias={}", bucket),
+		json!({}),
+	)
+	.await;
+	let grant = info["keys"]
+		.as_array()
+		.unwrap()
+		.iter()
+		.find(|k| k["accessKeyId"] == key.id.as_str())
+		.unwrap();
+	assert_eq!(grant["suspended"], true);
+	assert_eq!(grant["permissions"]["write"], true);
+
+	// Resuming the key allows it to be used again
+	ctx.garage
+		.command()
+		.args(["key", "resume", &key.id])
+		.quiet()
+		.expect_success_status("Could not resume key");
+	let resumed = admin_request(&ctx, Method::GET, &key_path, json!({})).await;
+	assert_eq!(resumed["suspended"], false);
+	put().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_admin_bucket_block_size() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("bucket-block-size");
+	let info = admin_request(
+		&ctx,
+		Method::GET,
+		&format!("/v1/bucket?globalAlias={}", bucket),
+		json!({}),
+	)
+	.await;
+	let bucket_id = info["id"].as_str().unwrap().to_string();
+	assert_eq!(info["blockSize"], Value::Null);
+
+	let set_block_size = |block_size: usize| {
+		let ctx = &ctx;
+		let bucket_id = bucket_id.clone();
+		async move {
+			admin_request(
+				ctx,
+				Method::PUT,
+				&format!("/v1/bucket?id={}", bucket_id),
+				json!({ "blockSize": block_size }),
+			)
+			.await
+		}
+	};
+
+	let body = |len: usize, seed: u8| -> Vec<u8> {
+		(0..len)
+			.map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed))
+			.collect()
+	};
+	let big = body(300 * 1024, 1);
+	let small_blocks = body(300 * 1024, 2);
+	let part1 = body(5 * 1024 * 1024, 3);
+	let part2 = body(200 * 1024, 4);
+
+	// Uploaded with the cluster's block size
+	ctx.client
+		.put_object()
+		.bucket(&bucket)
+		.key("default")
+		.body(ByteStream::from(big.clone()))
+		.send()
+		.await
+		.unwrap();
+
+	// Uploaded with the bucket's block size
+	let info = set_block_size(64 * 1024).await;
+	assert_eq!(info["blockSize"], 64 * 1024);
+	ctx.client
+		.put_object()
+		.bucket(&bucket)
+		.key("small-blocks")
+		.body(ByteStream::from(small_blocks.clone()))
+		.send()
+		.await
+		.unwrap();
+
+	// Parts of a multipart upload use the block size in effect when
+	// they are uploaded
+	let mpu = ctx
+		.client
+		.create_multipart_upload()
+		.bucket(&bucket)
+		.key("mixed")
+		.send()
+		.await
+		.unwrap();
+	let upload_id = mpu.upload_id.unwrap();
+	let p1 = ctx
+		.client
+		.upload_part()
+		.bucket(&bucket)
+		.key("mixed")
+		.upload_id(&upload_id)
+		.part_number(1)
+		.body(ByteStream::from(part1.clone()))
+		.send()
+		.await
+		.unwrap();
+	let info = set_block_size(0).await;
+	assert_eq!(info["blockSize"], Value::Null);
+	let p2 = ctx
+		.client
+		.upload_part()
+		.bucket(&bucket)
+		.key("mixed")
+		.upload_id(&upload_id)
+		.part_number(2)
+		.body(ByteStream::from(part2.clone()))
+		.send()
+		.await
+		.unwrap();
+	ctx.client
+		.complete_multipart_upload()
+		.bucket(&bucket)
+		.key("mixed")
+		.upload_id(&upload_id)
+		.multipart_upload(
+			CompletedMultipartUpload::builder()
+				.parts(
+					CompletedPart::builder()
+						.part_number(1)
+						.e_tag(p1.e_tag.unwrap())
+						.build(),
+				)
+				.parts(
+					CompletedPart::builder()
+						.part_number(2)
+						.e_tag(p2.e_tag.unwrap())
+						.build(),
+				)
+				.build(),
+		)
+		.send()
+		.await
+		.unwrap();
+
+	let mut mixed = part1.clone();
+	mixed.extend_from_slice(&part2);
+
+	for (key, expected) in [
+		("default", &big),
+		("small-blocks", &small_blocks),
+		("mixed", &mixed),
+	] {
+		let o = ctx
+			.client
+			.get_object()
+			.bucket(&bucket)
+			.key(key)
+			.send()
+			.await
+			.unwrap();
+		assert_bytes_eq!(o.body, expected);
+	}
+
+	// Range spanning several small blocks and the boundary between parts
+	let begin = 5 * 1024 * 1024 - 100 * 1024 - 7;
+	let end = 5 * 1024 * 1024 + 70 * 1024;
+	let o = ctx
+		.client
+		.get_object()
+		.bucket(&bucket)
+		.key("mixed")
+		.range(format!("bytes={}-{}", begin, end - 1))
+		.send()
+		.await
+		.unwrap();
+	assert_bytes_eq!(o.body, &mixed[begin..end]);
+}
+
+#[tokio::test]
+async fn test_admin_list_incomplete_uploads() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("bucket-incomplete-uploads");
+	let info = admin_request(
+		&ctx,
+		Method::GET,
+		&format!("/v1/bucket?globalAlias={}", bucket),
+		json!({}),
+	)
+	.await;
+	let bucket_id = info["id"].as_str().unwrap().to_string();
+	let path = format!("/v1/bucket/incomplete-uploads?id={}", bucket_id);
+
+	let uploads = admin_request(&ctx, Method::GET, &path, json!({})).await;
+	assert_eq!(uploads, json!([]));
+
+	let mpu = ctx
+		.client
+		.create_multipart_upload()
+		.bucket(&bucket)
+		.key("stalled")
+		.send()
+		.await
+		.unwrap();
+	let upload_id = mpu.upload_id.unwrap();
+
+	let uploads = admin_request(&ctx, Method::GET, &path, json!({})).await;
+	assert_eq!(uploads.as_array().unwrap().len(), 1);
+	assert_eq!(uploads[0]["key"], "stalled");
+	assert_eq!(uploads[0]["uploadId"], upload_id.as_str());
+	assert_eq!(uploads[0]["parts"], 0);
+	assert_eq!(uploads[0]["lastActivity"], uploads[0]["initiated"]);
+	let initiated = uploads[0]["initiated"].as_str().unwrap().to_string();
+
+	// Uploading a part records activity on the upload
+	tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+	ctx.client
+		.upload_part()
+		.bucket(&bucket)
+		.key("stalled")
+		.upload_id(&upload_id)
+		.part_number(1)
+		.body(ByteStream::from(vec![0u8; 1024]))
+		.send()
+		.await
+		.unwrap();
+
+	let uploads = admin_request(&ctx, Method::GET, &path, json!({})).await;
+	assert_eq!(uploads[0]["parts"], 1);
+	assert_eq!(uploads[0]["initiated"], initiated.as_str());
+	assert!(uploads[0]["lastActivity"].as_str().unwrap() > initiated.as_str());
+
+	ctx.client
+		.abort_multipart_upload()
+		.bucket(&bucket)
+		.key("stalled")
+		.upload_id(&upload_id)
+		.send()
+		.await
+		.unwrap();
+
+	let uploads = admin_request(&ctx, Method::GET, &path, json!({})).await;
+	assert_eq!(uploads, json!([]));
+}
+
+#[tokio::test]
+async fn test_admin_dump_bucket_objects() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("bucket-dump-objects");
+	let info = admin_request(
+		&ctx,
+		Method::GET,
+		&format!("/v1/bucket?globalAlias={}", bucket),
+		json!({}),
+	)
+	.await;
+	let bucket_id = info["id"].as_str().unwrap().to_string();
+
+	// Enough objects for the listing to be read in several pages
+	const N_OBJECTS: usize = 2500;
+	let keys = (0..N_OBJECTS)
+		.map(|i| format!("obj-{:05}", i))
+		.collect::<Vec<_>>();
+	for chunk in keys.chunks(100) {
+		futures::future::join_all(chunk.iter().map(|key| {
+			ctx.client
+				.put_object()
+				.bucket(&bucket)
+				.key(key)
+				.body(ByteStream::from_static(b"hello"))
+				.send()
+		}))
+		.await
+		.into_iter()
+		.for_each(|r| {
+			r.unwrap();
+		});
+	}
+	ctx.client
+		.put_object()
+		.bucket(&bucket)
+		.key("with,comma")
+		.body(ByteStream::from_static(b"hi"))
+		.send()
+		.await
+		.unwrap();
+	ctx.client
+		.create_multipart_upload()
+		.bucket(&bucket)
+		.key("uploading")
+		.send()
+		.await
+		.unwrap();
+
+	let dump = |query: String, accept_encoding: Option<&'static str>| {
+		let client = Client::builder(TokioExecutor::new()).build_http();
+		let mut req = Request::builder()
+			.method(Method::GET)
+			.uri(format!(
+				"http://127.0.0.1:{}/v1/bucket/objects/dump?id={}{}",
+				ctx.garage.admin_port, bucket_id, query
+			))
+			.header(
+				"Authorization",
+				format!("Bearer {}", common::garage::GARAGE_TEST_ADMIN_TOKEN),
+			);
+		if let Some(enc) = accept_encoding {
+			req = req.header("Accept-Encoding", enc);
+		}
+		let req = req.body(FullBody::new(Bytes::new())).unwrap();
+		async move {
+			let resp = client.request(req).await.unwrap();
+			assert_eq!(resp.status(), StatusCode::OK);
+			let encoding = resp
+				.headers()
+				.get("content-encoding")
+				.map(|v| v.to_str().unwrap().to_string());
+			let body = http_body_util::BodyExt::collect(resp.into_body())
+				.await
+				.unwrap()
+				.to_bytes();
+			(encoding, body)
+		}
+	};
+
+	// CSV listing of the current versions
+	let (encoding, body) = dump(String::new(), None).await;
+	assert_eq!(encoding, None);
+	let body = std::str::from_utf8(&body).unwrap();
+	let lines = body.lines().collect::<Vec<_>>();
+	assert_eq!(lines.len(), N_OBJECTS + 2);
+	assert_eq!(lines[0], "key,size,etag,lastModified");
+	assert!(lines[1].starts_with("obj-00000,5,"));
+	assert!(lines[N_OBJECTS].starts_with(&format!("obj-{:05},5,", N_OBJECTS - 1)));
+	assert!(lines[N_OBJECTS + 1].starts_with("\"with,comma\",2,"));
+	assert!(!body.contains("uploading"));
+
+	// Resuming after a key
+	let (_, body) = dump(format!("&startAfter=obj-{:05}", N_OBJECTS - 2), None).await;
+	let body = std::str::from_utf8(&body).unwrap();
+	let lines = body.lines().collect::<Vec<_>>();
+	assert_eq!(lines.len(), 3);
+	assert!(lines[1].starts_with(&format!("obj-{:05},", N_OBJECTS - 1)));
+
+	// JSON lines with all versions
+	let (_, body) = dump("&format=jsonl&allVersions=true".into(), None).await;
+	let rows = std::str::from_utf8(&body)
+		.unwrap()
+		.lines()
+		.map(|l| serde_json::from_str::<Value>(l).unwrap())
+		.collect::<Vec<_>>();
+	assert_eq!(rows.len(), N_OBJECTS + 2);
+	let uploading = rows.iter().find(|r| r["key"] == "uploading").unwrap();
+	assert_eq!(uploading["state"], "uploading");
+	assert_eq!(uploading["size"], Value::Null);
+	assert_eq!(rows[0]["state"], "complete");
+	assert_eq!(rows[0]["size"], 5);
+	assert!(rows[0]["versionId"].is_string());
+
+	// zstd compression when accepted by the client
+	let (encoding, body) = dump(String::new(), Some("gzip, zstd")).await;
+	assert_eq!(encoding.as_deref(), Some("zstd"));
+	assert_eq!(&body[..4], &[0x28, 0xb5, 0x2f, 0xfd]);
+	let (encoding, _) = dump(String::new(), Some("zstd;q=0")).await;
+	assert_eq!(encoding, None);
+}
+
+#[tokio::test]
+async fn test_admin_verify_bucket_counters() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("bucket-verify-counters");
+	let info = admin_request(
+		&ctx,
+		Method::GET,
+		&format!("/v1/bucket?globalAlias={}", bucket),
+		json!({}),
+	)
+	.await;
+	let bucket_id = info["id"].as_str().unwrap().to_string();
+
+	for (key, size) in [("a", 10), ("b", 20), ("c", 30)] {
+		ctx.client
+			.put_object()
+			.bucket(&bucket)
+			.key(key)
+			.body(ByteStream::from(vec![0u8; size]))
+			.send()
+			.await
+			.unwrap();
+	}
+	ctx.client
+		.create_multipart_upload()
+		.bucket(&bucket)
+		.key("d")
+		.send()
+		.await
+		.unwrap();
+
+	let expected = json!({"objects": 3, "unfinished_uploads": 1, "bytes": 60});
+	for fix in [false, true] {
+		let report = admin_request(
+			&ctx,
+			Method::POST,
+			"/v1/bucket/verify-counters",
+			json!({"bucketId": bucket_id, "fix": fix}),
+		)
+		.await;
+		assert_eq!(report["bucketId"], bucket_id.as_str());
+		assert_eq!(report["drift"], false);
+		assert_eq!(report["errors"], json!([]));
+		let nodes = report["nodes"].as_array().unwrap();
+		assert!(!nodes.is_empty());
+		for node in nodes {
+			assert_eq!(node["actual"], expected);
+			assert_eq!(node["counters"], expected);
+			assert_eq!(node["drift"], json!({}));
+			assert_eq!(node["fixed"], false);
+		}
+	}
+
+	// Same report with the CLI
+	let output = ctx
+		.garage
+		.command()
+		.args(["bucket", "verify-counters", "--json", &bucket])
+		.expect_success_output("Could not verify counters");
+	let report: Value = serde_json::from_slice(&output.stdout).unwrap();
+	assert_eq!(report["drift"], false);
+	assert_eq!(report["nodes"][0]["actual"], expected);
+
+	let output = ctx
+		.garage
+		.command()
+		.args(["bucket", "verify-counters", &bucket])
+		.expect_success_output("Could not verify counters");
+	assert!(String::from_utf8(output.stdout)
+		.unwrap()
+		.contains("Object counters are consistent with the objects."));
+}
+
+#[tokio::test]
+async fn test_admin_table_worker_variables() {
+	let ctx = common::context();
+
+	let vars = admin_request(&ctx, Method::GET, "/v1/worker/variable", json!(null)).await;
+	let vars = &vars["variables"];
+	assert_eq!(vars["gc-paused-object"], "false");
+	assert_eq!(vars["gc-batch-size-version"], "1024");
+	assert_eq!(vars["merkle-tranquility-block_ref"], "0");
+
+	let resp = admin_request(
+		&ctx,
+		Method::POST,
+		"/v1/worker/variable",
+		json!({"variable": "gc-paused-object", "value": "true"}),
+	)
+	.await;
+	assert_eq!(resp["variables"], json!({"gc-paused-object": "true"}));
+
+	let resp = admin_request(
+		&ctx,
+		Method::GET,
+		"/v1/worker/variable?variable=gc-paused-object",
+		json!(null),
+	)
+	.await;
+	assert_eq!(resp["variables"], json!({"gc-paused-object": "true"}));
+
+	// Same variables with the CLI
+	ctx.garage
+		.command()
+		.args(["worker", "set", "merkle-tranquility-object", "2"])
+		.expect_success_output("Could not set worker variable");
+	let resp = admin_request(
+		&ctx,
+		Method::GET,
+		"/v1/worker/variable?variable=merkle-tranquility-object",
+		json!(null),
+	)
+	.await;
+	assert_eq!(resp["variables"], json!({"merkle-tranquility-object": "2"}));
+
+	// The GC batch size can't be zero
+	let output = ctx
+		.garage
+		.command()
+		.args(["worker", "set", "gc-batch-size-object", "0"])
+		.output()
+		.unwrap();
+	assert!(!output.status.success());
+
+	// Restore the defaults for the other tests
+	for (variable, value) in [
+		("gc-paused-object", "false"),
+		("merkle-tranquility-object", "0"),
+	] {
+		admin_request(
+			&ctx,
+			Method::POST,
+			"/v1/worker/variable",
+			json!({"variable": variable, "value": value}),
+		)
+		.await;
+	}
+}
+
+#[tokio::test]
+async fn test_admin_bucket_allow_degraded_reads() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("bucket-degraded-reads");
+	let info = admin_request(
+		&ctx,
+		Method::GET,
+		&format!("/v1/bucket?globalAlias={}", bucket),
+		json!({}),
+	)
+	.await;
+	let bucket_id = info["id"].as_str().unwrap().to_string();
+	assert_eq!(info["allowDegradedReads"], false);
+
+	ctx.client
+		.put_object()
+		.bucket(&bucket)
+		.key("a")
+		.body(ByteStream::from_static(b"a"))
+		.send()
+		.await
+		.unwrap();
+
+	let list = |header: Option<&'static str>| {
+		let ctx = &ctx;
+		let bucket = bucket.clone();
+		async move {
+			let mut req = ctx.custom_request.builder(bucket);
+			req.query_param("list-type", Some("2"));
+			if let Some(h) = header {
+				req.signed_header("x-garage-allow-degraded-read", h);
+			}
+			req.send().await.unwrap()
+		}
+	};
+
+	for header in [None, Some("true"), Some("false")] {
+		let res = list(header).await;
+		assert_eq!(res.status(), StatusCode::OK);
+		// The read quorum is reachable: the listing is not degraded
+		assert!(res.headers().get("x-garage-degraded").is_none());
+	}
+	assert_eq!(list(Some("yes")).await.status(), StatusCode::BAD_REQUEST);
+
+	let info = admin_request(
+		&ctx,
+		Method::PUT,
+		&format!("/v1/bucket?id={}", bucket_id),
+		json!({ "allowDegradedReads": true }),
+	)
+	.await;
+	assert_eq!(info["allowDegradedReads"], true);
+
+	let r = ctx
+		.client
+		.list_objects_v2()
+		.bucket(&bucket)
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(r.contents.unwrap().len(), 1);
+	let res = list(None).await;
+	assert_eq!(res.status(), StatusCode::OK);
+	assert!(res.headers().get("x-garage-degraded").is_none());
+}
+
+#[tokio::test]
+async fn test_admin_node_versions() {
+	let ctx = common::context();
+
+	let status = admin_request(&ctx, Method::GET, "/v1/status", json!(null)).await;
+	let versions = admin_request(&ctx, Method::GET, "/v1/status/versions", json!(null)).await;
+
+	assert_eq!(versions["node"], status["node"]);
+	assert_eq!(versions["rpcProtocolVersion"], "6761726167650010");
+
+	let nodes = versions["nodes"].as_array().unwrap();
+	assert_eq!(nodes.len(), 1);
+	let node = &nodes[0];
+	assert_eq!(node["id"], status["node"]);
+	assert_eq!(node["isUp"], true);
+	assert_eq!(node["garageVersion"], status["garageVersion"]);
+	assert_eq!(node["replicationFactor"], 1);
+	assert_eq!(node["consistencyMode"], "consistent");
+
+	// The features reported are those the daemon was built with,
+	// which are the features of this test build
+	let features = node["garageFeatures"]
+		.as_array()
+		.unwrap()
+		.iter()
+		.map(|f| f.as_str().unwrap())
+		.collect::<Vec<_>>();
+	let built_with = vec![
+		("k2v", cfg!(feature = "k2v")),
+		("lmdb", cfg!(feature = "lmdb")),
+		("sqlite", cfg!(feature = "sqlite")),
+		("metrics", cfg!(feature = "metrics")),
+	];
+	for (feature, enabled) in built_with {
+		assert_eq!(features.contains(&feature), enabled, "{}", feature);
+	}
+	assert_eq!(node["garageFeatures"], status["garageFeatures"]);
+	assert_eq!(versions["commonFeatures"], status["garageFeatures"]);
+
+	let mut db_engines = vec![];
+	if cfg!(feature = "lmdb") {
+		db_engines.push("lmdb");
+	}
+	if cfg!(feature = "sqlite") {
+		db_engines.push("sqlite");
+	}
+	assert_eq!(node["dbEngines"], json!(db_engines));
+}
+
+#[tokio::test]
+async fn test_admin_bucket_quota_warning() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("bucket-quota-warning");
+	let info = admin_request(
+		&ctx,
+		Method::GET,
+		&format!("/v1/bucket?globalAlias={}", bucket),
+		json!({}),
+	)
+	.await;
+	let bucket_id = info["id"].as_str().unwrap().to_string();
+
+	// Warn from 60% of the quota of 5 objects, i.e. from 3 objects
+	admin_request(
+		&ctx,
+		Method::PUT,
+		&format!("/v1/bucket?id={}", bucket_id),
+		json!({
+			"quotas": { "maxSize": null, "maxObjects": 5, "maxMultipartUploads": null },
+			"usageAlerts": { "quotaPercent": 60, "maxSize": null, "maxObjects": null },
+		}),
+	)
+	.await;
+
+	// Put an object, and wait until the bucket counts `objects` objects
+	let put = |key: &'static str, body: &'static [u8], objects: u64| {
+		let ctx = &ctx;
+		let bucket = bucket.clone();
+		let info_path = format!("/v1/bucket?id={}", bucket_id);
+		async move {
+			let res = ctx
+				.custom_request
+				.builder(bucket)
+				.method(Method::PUT)
+				.path(key.to_owned())
+				.body(body.to_vec())
+				.send()
+				.await
+				.unwrap();
+			// Object counters are updated asynchronously
+			let mut info = admin_request(ctx, Method::GET, &info_path, json!({})).await;
+			for _ in 0..50 {
+				if info["objects"] == objects {
+					break;
+				}
+				tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+				info = admin_request(ctx, Method::GET, &info_path, json!({})).await;
+			}
+			assert_eq!(info["objects"], objects);
+			res
+		}
+	};
+	let warning = |res: &http::Response<_>| {
+		res.headers()
+			.get("x-garage-quota-warning")
+			.map(|v| v.to_str().unwrap().to_string())
+	};
+
+	for (key, objects) in [("a", 1), ("b", 2)] {
+		let res = put(key, b"data", objects).await;
+		assert_eq!(res.status(), StatusCode::OK);
+		assert_eq!(warning(&res), None);
+	}
+
+	// Writes over the threshold succeed, with a warning
+	let res = put("c", b"data", 3).await;
+	assert_eq!(res.status(), StatusCode::OK);
+	assert_eq!(warning(&res).unwrap(), "objects=3;threshold=3");
+	let res = put("d", b"data", 4).await;
+	assert_eq!(res.status(), StatusCode::OK);
+	assert_eq!(warning(&res).unwrap(), "objects=4;threshold=3");
+
+	// Overwriting an object does not add to the number of objects (the
+	// content is changed, so that the upload is not deduplicated)
+	let res = put("a", b"new data", 4).await;
+	assert_eq!(res.status(), StatusCode::OK);
+	assert_eq!(warning(&res).unwrap(), "objects=4;threshold=3");
+
+	let res = put("e", b"data", 5).await;
+	assert_eq!(res.status(), StatusCode::OK);
+	assert_eq!(warning(&res).unwrap(), "objects=5;threshold=3");
+
+	// Above the quota, writes are still rejected
+	let res = put("f", b"data", 5).await;
+	assert_eq!(res.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn test_admin_cluster_placement() {
+	let ctx = common::context();
+
+	let layout = admin_request(&ctx, Method::GET, "/v1/layout", json!(null)).await;
+	let placement = admin_request(&ctx, Method::GET, "/v1/layout/placement", json!(null)).await;
+
+	assert_eq!(placement["layoutVersion"], layout["version"]);
+	assert_eq!(placement["replicationFactor"], 1);
+	assert!(placement.get("partitions").is_none());
+
+	// The zone and capacity of each node are those of the applied layout
+	let roles = layout["roles"].as_array().unwrap();
+	let nodes = placement["nodes"].as_array().unwrap();
+	assert_eq!(nodes.len(), roles.len());
+	for role in roles {
+		let node = nodes.iter().find(|n| n["id"] == role["id"]).unwrap();
+		assert_eq!(node["zone"], role["zone"]);
+		assert_eq!(node["capacity"], role["capacity"]);
+		assert_eq!(node["isUp"], true);
+	}
+	let node = &nodes[0];
+	assert_eq!(node["zone"], "unzonned");
+	assert_eq!(node["capacity"], 1_000_000_000u64);
+	assert_eq!(node["partitions"], 256);
+
+	let placement = admin_request(
+		&ctx,
+		Method::GET,
+		"/v1/layout/placement?includePartitions=true",
+		json!(null),
+	)
+	.await;
+	let partitions = placement["partitions"].as_array().unwrap();
+	assert_eq!(partitions.len(), 256);
+	for (i, p) in partitions.iter().enumerate() {
+		assert_eq!(p["partition"], i);
+		assert_eq!(p["nodes"], json!([node["id"]]));
+	}
+}
@@ -1,6 +1,12 @@
 use crate::common;
 use crate::common::ext::CommandExt;
+use aws_sdk_s3::error::ProvideErrorMetadata;
 use aws_sdk_s3::operation::delete_bucket::DeleteBucketOutput;
+use aws_sdk_s3::types::{
+	BucketLifecycleConfiguration, CorsConfiguration, CorsRule, ExpirationStatus, IndexDocument,
+	LifecycleExpiration, LifecycleRule, WebsiteConfiguration,
+};
+use http::StatusCode;
 
 #[tokio::test]
 async fn test_bucket_all() {
@@ -101,3 +107,122 @@ async fn test_bucket_all() {
 			.any(|x| x.name.as_ref().unwrap() == "hello"));
 	}
 }
+
+#[tokio::test]
+async fn test_bucket_delete_cleanup() {
+	let ctx = common::context();
+	let bucket_name = "cleanup";
+
+	// Create a bucket with a single global alias and all kinds of configuration
+	ctx.create_bucket(bucket_name);
+	ctx.garage
+		.command()
+		.args(["bucket", "set-quotas", bucket_name])
+		.args(["--max-size", "1GiB", "--max-objects", "1000"])
+		.quiet()
+		.expect_success_status("Could not set bucket quotas");
+
+	ctx.client
+		.put_bucket_website()
+		.bucket(bucket_name)
+		.website_configuration(
+			WebsiteConfiguration::builder()
+				.index_document(
+					IndexDocument::builder()
+						.suffix("index.html")
+						.build()
+						.unwrap(),
+				)
+				.build(),
+		)
+		.send()
+		.await
+		.unwrap();
+
+	ctx.client
+		.put_bucket_cors()
+		.bucket(bucket_name)
+		.cors_configuration(
+			CorsConfiguration::builder()
+				.cors_rules(
+					CorsRule::builder()
+						.allowed_methods("GET")
+						.allowed_origins("*")
+						.build()
+						.unwrap(),
+				)
+				.build()
+				.unwrap(),
+		)
+		.send()
+		.await
+		.unwrap();
+
+	ctx.client
+		.put_bucket_lifecycle_configuration()
+		.bucket(bucket_name)
+		.lifecycle_configuration(
+			BucketLifecycleConfiguration::builder()
+				.rules(
+					LifecycleRule::builder()
+						.id("expire")
+						.status(ExpirationStatus::Enabled)
+						.expiration(LifecycleExpiration::builder().days(30).build())
+						.build()
+						.unwrap(),
+				)
+				.build()
+				.unwrap(),
+		)
+		.send()
+		.await
+		.unwrap();
+
+	// Delete the bucket through its only alias: this is a true deletion
+	ctx.client
+		.delete_bucket()
+		.bucket(bucket_name)
+		.send()
+		.await
+		.unwrap();
+
+	// A new bucket with the same name must not inherit anything
+	ctx.create_bucket(bucket_name);
+
+	// Garage answers 204 No Content when there is no website configuration
+	let website = ctx
+		.custom_request
+		.builder(bucket_name.to_string())
+		.query_param("website", None::<String>)
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(website.status(), StatusCode::NO_CONTENT);
+
+	let err = ctx
+		.client
+		.get_bucket_cors()
+		.bucket(bucket_name)
+		.send()
+		.await
+		.unwrap_err();
+	assert_eq!(err.code(), Some("NoSuchCORSConfiguration"));
+
+	let lifecycle = ctx
+		.custom_request
+		.builder(bucket_name.to_string())
+		.query_param("lifecycle", None::<String>)
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(lifecycle.status(), StatusCode::NO_CONTENT);
+
+	let info = ctx
+		.garage
+		.command()
+		.args(["bucket", "info", bucket_name])
+		.quiet()
+		.expect_success_output("Could not get bucket info");
+	let info = String::from_utf8(info.stdout).unwrap();
+	assert!(!info.contains("Quotas"));
+}
@@ -1,21 +1,18 @@
 #![allow(dead_code)]
 
 use std::collections::HashMap;
-use std::convert::{TryFrom, TryInto};
+use std::convert::TryFrom;
 
-use chrono::{offset::Utc, DateTime};
-use hmac::{Hmac, Mac};
+use chrono::offset::Utc;
 use http_body_util::BodyExt;
 use http_body_util::Full as FullBody;
-use hyper::header::{
-	HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_ENCODING, CONTENT_LENGTH, HOST,
-};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, HOST};
 use hyper::{Method, Request, Response, Uri};
 use hyper_util::client::legacy::{connect::HttpConnector, Client};
 use hyper_util::rt::TokioExecutor;
 
 use super::garage::{Instance, Key};
-use garage_api::signature;
+use garage_api::signature::sign::{self, PayloadSignature, SigningParams};
 
 pub type Body = FullBody<hyper::body::Bytes>;
 
@@ -169,16 +166,14 @@ impl<'a> RequestBuilder<'a> {
 		};
 		let uri = format!("{}{}", self.requester.uri, path);
 
-		let now = Utc::now();
-		let scope = signature::compute_scope(&now, super::REGION.as_ref(), self.service);
-		let mut signer = signature::signing_hmac(
-			&now,
-			&self.requester.key.secret,
-			super::REGION.as_ref(),
-			self.service,
-		)
-		.unwrap();
-		let streaming_signer = signer.clone();
+		let region = super::REGION.to_string();
+		let params = SigningParams {
+			key_id: &self.requester.key.id,
+			secret_key: &self.requester.key.secret,
+			region: &region,
+			service: self.service,
+			datetime: Utc::now(),
+		};
 
 		let mut all_headers = self
 			.signed_headers
@@ -190,54 +185,22 @@ impl<'a> RequestBuilder<'a> {
 				)
 			})
 			.collect::<HeaderMap>();
+		all_headers.insert(HOST, HeaderValue::from_bytes(host.as_bytes()).unwrap());
 
-		let date = now.format(signature::LONG_DATETIME).to_string();
-		all_headers.insert(
-			signature::payload::X_AMZ_DATE,
-			HeaderValue::from_str(&date).unwrap(),
-		);
-		all_headers.insert(HOST, HeaderValue::from_str(&host).unwrap());
-
-		let body_sha = match self.body_signature {
-			BodySignature::Unsigned => "UNSIGNED-PAYLOAD".to_owned(),
-			BodySignature::Classic => hex::encode(garage_util::data::sha256sum(&self.body)),
-			BodySignature::Streaming(size) => {
-				all_headers.insert(
-					CONTENT_ENCODING,
-					HeaderValue::from_str("aws-chunked").unwrap(),
-				);
-				all_headers.insert(
-					HeaderName::from_static("x-amz-decoded-content-length"),
-					HeaderValue::from_str(&self.body.len().to_string()).unwrap(),
-				);
-				// Get lenght of body by doing the conversion to a streaming body with an
-				// invalid signature (we don't know the seed) just to get its length. This
-				// is a pretty lazy and inefficient way to do it, but it's enought for test
-				// code.
-				all_headers.insert(
-					CONTENT_LENGTH,
-					to_streaming_body(&self.body, size, String::new(), signer.clone(), now, "")
-						.len()
-						.to_string()
-						.try_into()
-						.unwrap(),
-				);
-
-				"STREAMING-AWS4-HMAC-SHA256-PAYLOAD".to_owned()
+		let payload = match self.body_signature {
+			BodySignature::Unsigned => PayloadSignature::Unsigned,
+			BodySignature::Classic => {
+				PayloadSignature::Sha256(garage_util::data::sha256sum(&self.body))
 			}
+			BodySignature::Streaming(size) => PayloadSignature::Streaming {
+				decoded_length: self.body.len() as u64,
+				chunk_size: size,
+			},
 		};
-		all_headers.insert(
-			signature::payload::X_AMZ_CONTENT_SH256,
-			HeaderValue::from_str(&body_sha).unwrap(),
-		);
 
-		let mut signed_headers = all_headers.keys().cloned().collect::<Vec<_>>();
-		signed_headers.sort_by(|h1, h2| h1.as_str().cmp(h2.as_str()));
-		let signed_headers_str = signed_headers
-			.iter()
-			.map(ToString::to_string)
-			.collect::<Vec<_>>()
-			.join(";");
+		let uri = Uri::try_from(&uri).unwrap();
+		let signature =
+			sign::sign_request(&params, &self.method, &uri, &mut all_headers, payload).unwrap();
 
 		all_headers.extend(self.unsigned_headers.iter().map(|(k, v)| {
 			(
@@ -246,38 +209,14 @@ impl<'a> RequestBuilder<'a> {
 			)
 		}));
 
-		let uri = Uri::try_from(&uri).unwrap();
-		let query = signature::payload::parse_query_map(&uri).unwrap();
-
-		let canonical_request = signature::payload::canonical_request(
-			self.service,
-			&self.method,
-			uri.path(),
-			&query,
-			&all_headers,
-			&signed_headers,
-			&body_sha,
-		)
-		.unwrap();
-
-		let string_to_sign = signature::payload::string_to_sign(&now, &scope, &canonical_request);
-
-		signer.update(string_to_sign.as_bytes());
-		let signature = hex::encode(signer.finalize().into_bytes());
-		let authorization = format!(
-			"AWS4-HMAC-SHA256 Credential={}/{},SignedHeaders={},Signature={}",
-			self.requester.key.id, scope, signed_headers_str, signature
-		);
-		all_headers.insert(
-			AUTHORIZATION,
-			HeaderValue::from_str(&authorization).unwrap(),
-		);
-
 		let mut request = Request::builder();
 		*request.headers_mut().unwrap() = all_headers;
 
 		let body = if let BodySignature::Streaming(size) = self.body_signature {
-			to_streaming_body(&self.body, size, signature, streaming_signer, now, &scope)
+			sign::StreamingSigner::new(&params, signature)
+				.unwrap()
+				.encode_body(&self.body, size)
+				.unwrap()
 		} else {
 			self.body.clone()
 		};
@@ -329,37 +268,3 @@ fn query_param_to_string(params: &HashMap<String, Option<String>>) -> String {
 			.collect::<Vec<String>>()
 			.join("&")
 }
-
-fn to_streaming_body(
-	body: &[u8],
-	chunk_size: usize,
-	mut seed: String,
-	hasher: Hmac<sha2::Sha256>,
-	now: DateTime<Utc>,
-	scope: &str,
-) -> Vec<u8> {
-	const SHA_NULL: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
-	let now = now.format(signature::LONG_DATETIME).to_string();
-	let mut res = Vec::with_capacity(body.len());
-	for chunk in body.chunks(chunk_size).chain(std::iter::once(&[][..])) {
-		let to_sign = format!(
-			"AWS4-HMAC-SHA256-PAYLOAD\n{}\n{}\n{}\n{}\n{}",
-			now,
-			scope,
-			seed,
-			SHA_NULL,
-			hex::encode(garage_util::data::sha256sum(chunk))
-		);
-
-		let mut hasher = hasher.clone();
-		hasher.update(to_sign.as_bytes());
-		seed = hex::encode(hasher.finalize().into_bytes());
-
-		let header = format!("{:x};chunk-signature={}\r\n", chunk.len(), seed);
-		res.extend_from_slice(header.as_bytes());
-		res.extend_from_slice(chunk);
-		res.extend_from_slice(b"\r\n");
-	}
-
-	res
-}
@@ -11,6 +11,8 @@ pub const DEFAULT_PORT: u16 = 49995;
 static GARAGE_TEST_SECRET: &str =
 	"c3ea8cb80333d04e208d136698b1a01ae370d463f0d435ab2177510b3478bf44";
 
+pub static GARAGE_TEST_ADMIN_TOKEN: &str = "garage-integ-test-admin-token";
+
 #[derive(Debug, Default, Clone)]
 pub struct Key {
 	pub name: Option<String>,
@@ -64,24 +66,47 @@ rpc_bind_addr = "127.0.0.1:{rpc_port}"
 rpc_public_addr = "127.0.0.1:{rpc_port}"
 rpc_secret = "{secret}"
 
+allow_punycode = true
+
 [s3_api]
 s3_region = "{region}"
 api_bind_addr = "127.0.0.1:{s3_port}"
 root_domain = ".s3.garage"
+copy_keepalive_threshold = 8388608
+max_object_size = 25165824
+max_part_size = 16777216
+max_list_parts = 100
+max_cors_rules = 4
+max_lifecycle_rules = 4
+always_compute_sha256 = true
+store_block_checksums = true
+access_log_flush_interval_secs = 1
 
 [k2v_api]
 api_bind_addr = "127.0.0.1:{k2v_port}"
+max_value_size = 4096
+max_concurrent_values_per_item = 8
+max_item_total_size = 16384
 
 [s3_web]
 bind_addr = "127.0.0.1:{web_port}"
 root_domain = ".web.garage"
 index = "index.html"
+max_requests_per_connection = 3
+precompressed_variants = [
+	{{ encoding = "br", suffix = ".br" }},
+	{{ encoding = "gzip", suffix = ".gz" }},
+]
 
 [admin]
 api_bind_addr = "127.0.0.1:{admin_port}"
+admin_token = "{admin_token}"
+max_header_size = 4096
+max_header_count = 20
 "#,
 			path = path.display(),
 			secret = GARAGE_TEST_SECRET,
+			admin_token = GARAGE_TEST_ADMIN_TOKEN,
 			region = super::REGION,
 			s3_port = port,
 			k2v_port = port + 1,
@@ -0,0 +1,201 @@
+use crate::common;
+
+use base64::prelude::*;
+use serde_json::json;
+
+use crate::json_body;
+use hyper::{Method, StatusCode};
+
+// Limits set in the test configuration
+const MAX_VALUE_SIZE: usize = 4096;
+const MAX_CONCURRENT_VALUES: usize = 8;
+
+#[tokio::test]
+async fn test_item_limits() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("test-k2v-limits");
+
+	// A value of exactly the maximum size is accepted
+	let res = ctx
+		.k2v
+		.request
+		.builder(bucket.clone())
+		.method(Method::PUT)
+		.path("root")
+		.query_param("sort_key", Some("big"))
+		.body(vec![0x42; MAX_VALUE_SIZE])
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+	// One more byte is too much
+	let res = ctx
+		.k2v
+		.request
+		.builder(bucket.clone())
+		.method(Method::PUT)
+		.path("root")
+		.query_param("sort_key", Some("big"))
+		.body(vec![0x42; MAX_VALUE_SIZE + 1])
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+	let body = json_body(res).await;
+	assert_eq!(body["code"], "LimitExceeded");
+	assert!(body["message"].as_str().unwrap().contains("max_value_size"));
+
+	// Concurrent values are accepted up to the limit
+	for i in 0..MAX_CONCURRENT_VALUES {
+		let res = ctx
+			.k2v
+			.request
+			.builder(bucket.clone())
+			.method(Method::PUT)
+			.path("root")
+			.query_param("sort_key", Some("concurrent"))
+			.body(format!("value {}", i).into_bytes())
+			.send()
+			.await
+			.unwrap();
+		assert_eq!(res.status(), StatusCode::NO_CONTENT);
+	}
+	let res = ctx
+		.k2v
+		.request
+		.builder(bucket.clone())
+		.method(Method::PUT)
+		.path("root")
+		.query_param("sort_key", Some("concurrent"))
+		.body(b"one too many".to_vec())
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+	let body = json_body(res).await;
+	assert_eq!(body["code"], "LimitExceeded");
+	assert!(body["message"]
+		.as_str()
+		.unwrap()
+		.contains("max_concurrent_values_per_item"));
+
+	// Reading the item and writing with its causality token
+	// replaces all concurrent values
+	let res = ctx
+		.k2v
+		.request
+		.builder(bucket.clone())
+		.path("root")
+		.query_param("sort_key", Some("concurrent"))
+		.signed_header("accept", "application/json")
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(res.status(), StatusCode::OK);
+	let ct = res
+		.headers()
+		.get("x-garage-causality-token")
+		.unwrap()
+		.to_str()
+		.unwrap()
+		.to_string();
+	let res = ctx
+		.k2v
+		.request
+		.builder(bucket.clone())
+		.method(Method::PUT)
+		.path("root")
+		.query_param("sort_key", Some("concurrent"))
+		.signed_header("x-garage-causality-token", ct.clone())
+		.body(b"single value".to_vec())
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+	// Deletions are never refused
+	let res = ctx
+		.k2v
+		.request
+		.builder(bucket.clone())
+		.method(Method::DELETE)
+		.path("root")
+		.query_param("sort_key", Some("concurrent"))
+		.signed_header("x-garage-causality-token", ct)
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(res.status(), StatusCode::NO_CONTENT);
+}
+
+#[tokio::test]
+async fn test_batch_limits() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("test-k2v-batch-limits");
+
+	// The second and fourth entries are too big: nothing is inserted
+	let small = BASE64_STANDARD.encode(b"small value");
+	let big = BASE64_STANDARD.encode(vec![0x42; MAX_VALUE_SIZE + 1]);
+	let res = ctx
+		.k2v
+		.request
+		.builder(bucket.clone())
+		.body(
+			json!([
+				{"pk": "root", "sk": "a", "ct": null, "v": small},
+				{"pk": "root", "sk": "b", "ct": null, "v": big},
+				{"pk": "root", "sk": "c", "ct": null, "v": small},
+				{"pk": "root", "sk": "d", "ct": null, "v": big},
+			])
+			.to_string()
+			.into_bytes(),
+		)
+		.method(Method::POST)
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+	let body = json_body(res).await;
+	assert_eq!(body["code"], "LimitExceeded");
+	let entries = body["entries"].as_array().unwrap();
+	assert_eq!(entries.len(), 2);
+	assert_eq!(entries[0]["index"], 1);
+	assert_eq!(entries[0]["sk"], "b");
+	assert_eq!(entries[0]["limit"], "max_value_size");
+	assert_eq!(entries[1]["index"], 3);
+	assert_eq!(entries[1]["sk"], "d");
+
+	for sk in ["a", "b", "c", "d"] {
+		let res = ctx
+			.k2v
+			.request
+			.builder(bucket.clone())
+			.path("root")
+			.query_param("sort_key", Some(sk))
+			.signed_header("accept", "application/octet-stream")
+			.send()
+			.await
+			.unwrap();
+		assert_eq!(res.status(), StatusCode::NOT_FOUND);
+	}
+
+	// Without the oversized entries, the batch is inserted
+	let res = ctx
+		.k2v
+		.request
+		.builder(bucket.clone())
+		.body(
+			json!([
+				{"pk": "root", "sk": "a", "ct": null, "v": small},
+				{"pk": "root", "sk": "c", "ct": null, "v": small},
+			])
+			.to_string()
+			.into_bytes(),
+		)
+		.method(Method::POST)
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(res.status(), StatusCode::NO_CONTENT);
+}
@@ -1,5 +1,6 @@
 pub mod batch;
 pub mod errorcodes;
 pub mod item;
+pub mod limits;
 pub mod poll;
 pub mod simple;
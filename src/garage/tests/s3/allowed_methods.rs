@@ -0,0 +1,97 @@
+use crate::common;
+use crate::common::ext::*;
+use aws_sdk_s3::primitives::ByteStream;
+use http_body_util::BodyExt;
+use hyper::{Method, StatusCode};
+
+const BODY: &[u8; 62] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+fn set_allowed_methods(ctx: &common::Context, bucket: &str, methods: &[&str]) {
+	ctx.garage
+		.command()
+		.args(["bucket", "set-allowed-methods", bucket])
+		.args(methods)
+		.quiet()
+		.expect_success_status("Could not change allowed methods of bucket");
+}
+
+#[tokio::test]
+async fn test_allowed_methods() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("allowedmethods");
+
+	ctx.client
+		.put_object()
+		.bucket(&bucket)
+		.key("a")
+		.body(ByteStream::from_static(BODY))
+		.send()
+		.await
+		.unwrap();
+
+	set_allowed_methods(&ctx, &bucket, &["get", "HEAD"]);
+
+	// Reads still work
+	let o = ctx
+		.client
+		.get_object()
+		.bucket(&bucket)
+		.key("a")
+		.send()
+		.await
+		.unwrap();
+	assert_bytes_eq!(o.body, BODY);
+	ctx.client
+		.head_object()
+		.bucket(&bucket)
+		.key("a")
+		.send()
+		.await
+		.unwrap();
+
+	// Writes are rejected, although the key is allowed to write
+	for method in [Method::PUT, Method::DELETE] {
+		let res = ctx
+			.custom_request
+			.builder(bucket.clone())
+			.method(method)
+			.path("a")
+			.body(BODY.to_vec())
+			.send()
+			.await
+			.unwrap();
+		assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+		assert_eq!(res.headers()["allow"], "GET, HEAD, OPTIONS");
+		let body = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+		let body = std::str::from_utf8(&body).unwrap();
+		assert!(body.contains("<Code>MethodNotAllowed</Code>"));
+	}
+	let err = ctx
+		.client
+		.put_object()
+		.bucket(&bucket)
+		.key("b")
+		.body(ByteStream::from_static(BODY))
+		.send()
+		.await
+		.unwrap_err();
+	assert_eq!(err.raw_response().unwrap().status().as_u16(), 405);
+
+	// All methods are allowed again
+	set_allowed_methods(&ctx, &bucket, &["--all"]);
+	ctx.client
+		.put_object()
+		.bucket(&bucket)
+		.key("b")
+		.body(ByteStream::from_static(BODY))
+		.send()
+		.await
+		.unwrap();
+	ctx.client
+		.delete_object()
+		.bucket(&bucket)
+		.key("a")
+		.send()
+		.await
+		.unwrap();
+}
@@ -0,0 +1,183 @@
+use std::path::PathBuf;
+
+use crate::common;
+use aws_sdk_s3::primitives::ByteStream;
+use garage_util::data::blake2sum;
+use hyper::{HeaderMap, Method, StatusCode};
+
+async fn append(
+	ctx: &common::Context,
+	bucket: &str,
+	key: &str,
+	if_match: Option<&str>,
+	body: &[u8],
+) -> (StatusCode, HeaderMap) {
+	let mut req = ctx.custom_request.builder(bucket.to_owned());
+	req.method(Method::PUT)
+		.path(key.to_owned())
+		.signed_header("x-garage-append", "true")
+		.body(body.to_vec());
+	if let Some(etag) = if_match {
+		req.signed_header("if-match", etag);
+	}
+	let res = req.send().await.unwrap();
+	(res.status(), res.headers().clone())
+}
+
+async fn etag(ctx: &common::Context, bucket: &str, key: &str) -> String {
+	ctx.client
+		.head_object()
+		.bucket(bucket)
+		.key(key)
+		.send()
+		.await
+		.unwrap()
+		.e_tag
+		.unwrap()
+}
+
+/// Path of the file in which the block of this data is stored
+fn block_file(ctx: &common::Context, data: &[u8]) -> Option<PathBuf> {
+	let hash = hex::encode(blake2sum(data));
+	let dir = ctx
+		.garage
+		.path
+		.join("data")
+		.join(&hash[0..2])
+		.join(&hash[2..4]);
+	[dir.join(&hash), dir.join(format!("{}.zst", hash))]
+		.iter()
+		.find(|p| p.exists())
+		.cloned()
+}
+
+#[tokio::test]
+async fn test_append() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("append");
+
+	// Large enough to not be inlined, small enough to fit in a single block
+	let first = (0..65536u32)
+		.map(|i| (i.wrapping_mul(2654435761) >> 24) as u8)
+		.collect::<Vec<u8>>();
+	let second = b"2024-05-01 12:00:00 something happened\n".to_vec();
+	let third = b"2024-05-01 12:00:01 something else happened\n".to_vec();
+
+	ctx.client
+		.put_object()
+		.bucket(&bucket)
+		.key("log")
+		.body(ByteStream::from(first.clone()))
+		.send()
+		.await
+		.unwrap();
+	let first_block = block_file(&ctx, &first).unwrap();
+	let first_mtime = std::fs::metadata(&first_block).unwrap().modified().unwrap();
+
+	let etag1 = etag(&ctx, &bucket, "log").await;
+	let (status, headers) = append(&ctx, &bucket, "log", Some(&etag1), &second).await;
+	assert_eq!(status, StatusCode::OK);
+	let size = (first.len() + second.len()).to_string();
+	assert_eq!(headers["x-garage-object-size"], size.as_str());
+	assert!(headers.contains_key("x-amz-version-id"));
+	let etag2 = headers["etag"].to_str().unwrap().to_string();
+	assert_ne!(etag2, etag1);
+	assert_eq!(etag(&ctx, &bucket, "log").await, etag2);
+
+	let (status, _) = append(&ctx, &bucket, "log", Some(&etag2), &third).await;
+	assert_eq!(status, StatusCode::OK);
+
+	// The appended data is stored in new blocks, the existing block
+	// is shared with the new versions and is not written again
+	assert!(block_file(&ctx, &second).is_some());
+	assert!(block_file(&ctx, &third).is_some());
+	assert_eq!(
+		std::fs::metadata(&first_block).unwrap().modified().unwrap(),
+		first_mtime
+	);
+
+	let expected = [&first[..], &second[..], &third[..]].concat();
+	let res = ctx
+		.client
+		.get_object()
+		.bucket(&bucket)
+		.key("log")
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(res.content_length, Some(expected.len() as i64));
+	assert_eq!(res.body.collect().await.unwrap().into_bytes(), expected);
+
+	// Each append can be read back as a part
+	let res = ctx
+		.client
+		.get_object()
+		.bucket(&bucket)
+		.key("log")
+		.part_number(3)
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(res.parts_count, Some(3));
+	assert_eq!(res.body.collect().await.unwrap().into_bytes(), third);
+}
+
+#[tokio::test]
+async fn test_append_conflicts() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("append-conflicts");
+
+	let data = vec![42u8; 65536];
+	ctx.client
+		.put_object()
+		.bucket(&bucket)
+		.key("log")
+		.body(ByteStream::from(data.clone()))
+		.send()
+		.await
+		.unwrap();
+	let etag1 = etag(&ctx, &bucket, "log").await;
+
+	// Of two clients that append with the same ETag, only the first
+	// one succeeds: the other one must read the new ETag and retry
+	let (status, _) = append(&ctx, &bucket, "log", Some(&etag1), b"first append").await;
+	assert_eq!(status, StatusCode::OK);
+	let (status, _) = append(&ctx, &bucket, "log", Some(&etag1), b"second append").await;
+	assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+
+	let etag2 = etag(&ctx, &bucket, "log").await;
+	let (status, _) = append(&ctx, &bucket, "log", Some(&etag2), b"second append").await;
+	assert_eq!(status, StatusCode::OK);
+
+	let res = ctx
+		.client
+		.get_object()
+		.bucket(&bucket)
+		.key("log")
+		.send()
+		.await
+		.unwrap();
+	let expected = [&data[..], b"first append", b"second append"].concat();
+	assert_eq!(res.body.collect().await.unwrap().into_bytes(), expected);
+
+	// If-Match is required
+	let (status, _) = append(&ctx, &bucket, "log", None, b"x").await;
+	assert_eq!(status, StatusCode::BAD_REQUEST);
+
+	// Objects that don't exist cannot be appended to
+	let (status, _) = append(&ctx, &bucket, "missing", Some(&etag1), b"x").await;
+	assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+
+	// Objects stored inline cannot be appended to
+	ctx.client
+		.put_object()
+		.bucket(&bucket)
+		.key("small")
+		.body(ByteStream::from_static(b"hello"))
+		.send()
+		.await
+		.unwrap();
+	let small_etag = etag(&ctx, &bucket, "small").await;
+	let (status, _) = append(&ctx, &bucket, "small", Some(&small_etag), b"x").await;
+	assert_eq!(status, StatusCode::BAD_REQUEST);
+}
@@ -0,0 +1,134 @@
+use crate::common;
+use crate::common::ext::*;
+use aws_sdk_s3::primitives::ByteStream;
+
+const BODY: &[u8; 62] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+const BODY2: &[u8; 5] = b"hello";
+
+fn set_case_insensitive_keys(ctx: &common::Context, bucket: &str, flag: &str) {
+	ctx.garage
+		.command()
+		.args(["bucket", "set-case-insensitive-keys", flag])
+		.arg(bucket)
+		.quiet()
+		.expect_success_status("Could not change case-insensitive keys flag of bucket");
+}
+
+#[tokio::test]
+async fn test_case_insensitive_keys() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("caseinsensitive");
+
+	set_case_insensitive_keys(&ctx, &bucket, "--enable");
+
+	ctx.client
+		.put_object()
+		.bucket(&bucket)
+		.key("Hello/World.TXT")
+		.body(ByteStream::from_static(BODY))
+		.send()
+		.await
+		.unwrap();
+
+	for key in ["Hello/World.TXT", "hello/world.txt", "HELLO/WORLD.txt"] {
+		let o = ctx
+			.client
+			.get_object()
+			.bucket(&bucket)
+			.key(key)
+			.send()
+			.await
+			.unwrap();
+		assert_bytes_eq!(o.body, BODY);
+	}
+
+	// Listing matches the prefix regardless of its casing,
+	// and returns the key as it was written
+	let r = ctx
+		.client
+		.list_objects_v2()
+		.bucket(&bucket)
+		.prefix("HELLO/")
+		.send()
+		.await
+		.unwrap();
+	let keys = r
+		.contents
+		.unwrap_or_default()
+		.into_iter()
+		.map(|o| o.key.unwrap())
+		.collect::<Vec<_>>();
+	assert_eq!(keys, vec!["Hello/World.TXT"]);
+
+	// Writing a key that differs only in its casing overwrites the object
+	ctx.client
+		.put_object()
+		.bucket(&bucket)
+		.key("HELLO/world.TXT")
+		.body(ByteStream::from_static(BODY2))
+		.send()
+		.await
+		.unwrap();
+
+	let o = ctx
+		.client
+		.get_object()
+		.bucket(&bucket)
+		.key("Hello/World.TXT")
+		.send()
+		.await
+		.unwrap();
+	assert_bytes_eq!(o.body, BODY2);
+
+	let r = ctx
+		.client
+		.list_objects()
+		.bucket(&bucket)
+		.send()
+		.await
+		.unwrap();
+	let keys = r
+		.contents
+		.unwrap_or_default()
+		.into_iter()
+		.map(|o| o.key.unwrap())
+		.collect::<Vec<_>>();
+	assert_eq!(keys, vec!["HELLO/world.TXT"]);
+
+	// The mode cannot be changed on a bucket that is not empty
+	let out = ctx
+		.garage
+		.command()
+		.args(["bucket", "set-case-insensitive-keys", "--disable"])
+		.arg(&bucket)
+		.output()
+		.unwrap();
+	assert!(!out.status.success());
+
+	ctx.client
+		.delete_object()
+		.bucket(&bucket)
+		.key("hello/world.txt")
+		.send()
+		.await
+		.unwrap();
+
+	set_case_insensitive_keys(&ctx, &bucket, "--disable");
+
+	ctx.client
+		.put_object()
+		.bucket(&bucket)
+		.key("Hello")
+		.body(ByteStream::from_static(BODY))
+		.send()
+		.await
+		.unwrap();
+	assert!(ctx
+		.client
+		.get_object()
+		.bucket(&bucket)
+		.key("hello")
+		.send()
+		.await
+		.is_err());
+}
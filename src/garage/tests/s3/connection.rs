@@ -0,0 +1,79 @@
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::common;
+
+/// Send raw requests on a new connection, and read the responses until
+/// the server closes the connection. Returns None if it is still open
+/// after the timeout.
+async fn send_raw(port: u16, requests: &str) -> Option<String> {
+	let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+	stream.write_all(requests.as_bytes()).await.unwrap();
+
+	let mut response = vec![];
+	let read = stream.read_to_end(&mut response);
+	match tokio::time::timeout(Duration::from_secs(2), read).await {
+		Ok(res) => {
+			res.unwrap();
+			Some(String::from_utf8_lossy(&response).into_owned())
+		}
+		Err(_) => None,
+	}
+}
+
+fn count_responses(raw: &str) -> usize {
+	raw.lines()
+		.filter(|l| l.starts_with("HTTP/1.1 ") || l.starts_with("HTTP/1.0 "))
+		.count()
+}
+
+#[tokio::test]
+async fn test_connection_close() {
+	let ctx = common::context();
+	let port = ctx.garage.s3_port;
+
+	// HTTP/1.0 connections are closed after the response...
+	let res = send_raw(port, "GET / HTTP/1.0\r\nHost: localhost\r\n\r\n")
+		.await
+		.expect("HTTP/1.0 connection was not closed");
+	assert_eq!(count_responses(&res), 1);
+	assert!(res.contains("\r\nconnection: close\r\n"), "{}", res);
+
+	// ...unless the client asks for them to be kept alive
+	let res = send_raw(
+		port,
+		"GET / HTTP/1.0\r\nHost: localhost\r\nConnection: keep-alive\r\n\r\n",
+	)
+	.await;
+	assert!(res.is_none());
+
+	// HTTP/1.1 connections are closed when the client asks for it,
+	// and later requests sent on the same connection are not answered
+	let res = send_raw(
+		port,
+		"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n\
+		GET / HTTP/1.1\r\nHost: localhost\r\n\r\n",
+	)
+	.await
+	.expect("connection was not closed");
+	assert_eq!(count_responses(&res), 1);
+	assert!(res.contains("\r\nconnection: close\r\n"), "{}", res);
+
+	let res = send_raw(port, "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+	assert!(res.is_none());
+}
+
+#[tokio::test]
+async fn test_max_requests_per_connection() {
+	let ctx = common::context();
+
+	// The web server of the tests closes connections after 3 requests
+	let request = "GET / HTTP/1.1\r\nHost: nonexistent.web.garage\r\n\r\n";
+	let res = send_raw(ctx.garage.web_port, &request.repeat(5))
+		.await
+		.expect("connection was not closed");
+	assert_eq!(count_responses(&res), 3);
+	assert_eq!(res.matches("\r\nconnection: close\r\n").count(), 1);
+}
@@ -0,0 +1,91 @@
+use crate::common;
+use aws_sdk_s3::primitives::ByteStream;
+use http_body_util::BodyExt;
+
+// With the default block size of 1MiB, this object is stored in 4 blocks
+const SZ_3_5MB: usize = 3 * 1024 * 1024 + 512 * 1024;
+
+#[tokio::test]
+async fn test_debug_timing() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("debugtiming");
+
+	let data = (0..SZ_3_5MB).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+	ctx.client
+		.put_object()
+		.bucket(&bucket)
+		.key("big")
+		.body(ByteStream::from(data.clone()))
+		.send()
+		.await
+		.unwrap();
+
+	// Without the header, no timing is returned
+	let res = ctx
+		.custom_request
+		.builder(bucket.clone())
+		.path("big")
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(res.status(), 200);
+	assert!(res.headers().get("x-garage-timing").is_none());
+
+	let res = ctx
+		.custom_request
+		.builder(bucket.clone())
+		.path("big")
+		.signed_header("x-garage-debug-timing", "1")
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(res.status(), 200);
+	let timing = res
+		.headers()
+		.get("x-garage-timing")
+		.expect("missing x-garage-timing header")
+		.to_str()
+		.unwrap()
+		.to_string();
+	let body = res.into_body().collect().await.unwrap().to_bytes();
+	assert_eq!(&body[..], &data[..]);
+
+	// Parse the entries: `phase;dur=<ms>[;desc="<detail>"]`
+	let entries = timing
+		.split(", ")
+		.map(|entry| {
+			let mut fields = entry.split(';');
+			let phase = fields.next().unwrap().to_string();
+			let dur = fields
+				.next()
+				.and_then(|d| d.strip_prefix("dur="))
+				.and_then(|d| d.parse::<f64>().ok())
+				.unwrap_or_else(|| panic!("invalid timing entry: {}", entry));
+			let desc = fields.next().map(|d| d.to_string());
+			(phase, dur, desc)
+		})
+		.collect::<Vec<_>>();
+
+	let phases = entries
+		.iter()
+		.map(|(p, _, _)| p.as_str())
+		.collect::<Vec<_>>();
+	assert_eq!(phases[0], "signature", "{}", timing);
+	assert!(phases.contains(&"bucket-resolution"), "{}", timing);
+	assert!(phases.contains(&"table-read"), "{}", timing);
+	assert!(entries.iter().all(|(_, dur, _)| *dur >= 0.), "{}", timing);
+
+	// Each block fetch is given with the node that returned the block
+	let node_id = ctx.garage.node_id();
+	let block_fetches = entries
+		.iter()
+		.filter(|(p, _, _)| p == "block-fetch")
+		.collect::<Vec<_>>();
+	assert_eq!(block_fetches.len(), 4, "{}", timing);
+	for (_, _, desc) in block_fetches {
+		let desc = desc.as_deref().unwrap();
+		assert!(desc.starts_with("desc=\""), "{}", desc);
+		assert!(desc.contains(" from "), "{}", desc);
+		assert!(node_id.starts_with(desc.rsplit(' ').next().unwrap().trim_end_matches('"')));
+	}
+}
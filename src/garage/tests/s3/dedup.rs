@@ -0,0 +1,73 @@
+use crate::common;
+use hyper::{Method, StatusCode};
+
+// Spans several data blocks with the block size of the test configuration
+const SIZE: usize = 3 * 1024 * 1024 + 1000;
+
+async fn put(ctx: &common::Context, bucket: &str, key: &str, body: &[u8]) -> Option<String> {
+	let res = ctx
+		.custom_request
+		.builder(bucket.to_owned())
+		.method(Method::PUT)
+		.path(key.to_owned())
+		.signed_header("x-garage-report-dedup", "true")
+		.body(body.to_vec())
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(res.status(), StatusCode::OK);
+	res.headers()
+		.get("x-garage-dedup-ratio")
+		.map(|v| v.to_str().unwrap().to_string())
+}
+
+#[tokio::test]
+async fn test_dedup_ratio() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("dedup-ratio");
+
+	// The top byte of the product is used so that no block of the body is
+	// a repetition of another one
+	let body = (0..SIZE as u32)
+		.map(|i| (i.wrapping_mul(2654435761) >> 24) as u8)
+		.collect::<Vec<u8>>();
+
+	// The first upload stores new blocks
+	assert_eq!(
+		put(&ctx, &bucket, "first", &body).await.as_deref(),
+		Some("0.000")
+	);
+
+	// The same content uploaded again is fully deduplicated
+	assert_eq!(
+		put(&ctx, &bucket, "second", &body).await.as_deref(),
+		Some("1.000")
+	);
+
+	// Only the blocks that are not modified are deduplicated
+	let mut modified = body.clone();
+	modified[0] ^= 0xff;
+	assert_eq!(
+		put(&ctx, &bucket, "third", &modified).await.as_deref(),
+		Some("0.667")
+	);
+
+	// Small objects are stored inline and never deduplicated
+	assert_eq!(
+		put(&ctx, &bucket, "small", b"hello").await.as_deref(),
+		Some("0.000")
+	);
+
+	// The ratio is only reported when requested
+	let res = ctx
+		.custom_request
+		.builder(bucket.clone())
+		.method(Method::PUT)
+		.path("fourth".to_owned())
+		.body(body)
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(res.status(), StatusCode::OK);
+	assert!(res.headers().get("x-garage-dedup-ratio").is_none());
+}
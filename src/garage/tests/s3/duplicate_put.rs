@@ -0,0 +1,100 @@
+use crate::common;
+use crate::common::custom_requester::BodySignature;
+use hyper::{Method, StatusCode};
+
+const BCKT_NAME: &str = "duplicate-put";
+const KEY: &str = "build/artifact.zip";
+
+/// Upload an object, and return the version ID and the ETag of the response
+async fn put(
+	ctx: &common::Context,
+	bucket: &str,
+	body: &[u8],
+	content_type: &str,
+	signature: BodySignature,
+) -> (String, String) {
+	let res = ctx
+		.custom_request
+		.builder(bucket.to_owned())
+		.method(Method::PUT)
+		.path(KEY)
+		.signed_header("content-type", content_type)
+		.body(body.to_vec())
+		.body_signature(signature)
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(res.status(), StatusCode::OK);
+	let header = |name| res.headers()[name].to_str().unwrap().to_string();
+	(header("x-amz-version-id"), header("etag"))
+}
+
+#[tokio::test]
+async fn test_duplicate_put() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket(BCKT_NAME);
+
+	let artifact = (0..100_000u32)
+		.map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+		.collect::<Vec<u8>>();
+	let zip = "application/zip";
+
+	let (v1, etag) = put(&ctx, &bucket, &artifact, zip, BodySignature::Classic).await;
+
+	// The same signed content uploaded again is not written again
+	let (v, e) = put(&ctx, &bucket, &artifact, zip, BodySignature::Classic).await;
+	assert_eq!(v, v1);
+	assert_eq!(e, etag);
+
+	// Unsigned content is always written
+	let (v2, e) = put(&ctx, &bucket, &artifact, zip, BodySignature::Unsigned).await;
+	assert_ne!(v2, v1);
+	assert_eq!(e, etag);
+
+	// The first version is not the current version anymore,
+	// so the object is written again
+	let (v3, _) = put(&ctx, &bucket, &artifact, zip, BodySignature::Classic).await;
+	assert_ne!(v3, v1);
+	assert_ne!(v3, v2);
+
+	// Different headers, or different content, make a new version
+	let (v4, _) = put(
+		&ctx,
+		&bucket,
+		&artifact,
+		"text/plain",
+		BodySignature::Classic,
+	)
+	.await;
+	assert_ne!(v4, v3);
+	let (v5, _) = put(
+		&ctx,
+		&bucket,
+		b"other",
+		"text/plain",
+		BodySignature::Classic,
+	)
+	.await;
+	assert_ne!(v5, v4);
+
+	// After an overwrite, uploading the previous content again makes it current
+	let (v6, _) = put(
+		&ctx,
+		&bucket,
+		&artifact,
+		"text/plain",
+		BodySignature::Classic,
+	)
+	.await;
+	assert_ne!(v6, v4);
+	let o = ctx
+		.client
+		.get_object()
+		.bucket(&bucket)
+		.key(KEY)
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(o.content_type.as_deref(), Some("text/plain"));
+	assert_bytes_eq!(o.body, &artifact[..]);
+}
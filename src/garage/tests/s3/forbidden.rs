@@ -0,0 +1,96 @@
+use crate::common;
+use crate::common::ext::*;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::Client;
+
+const BODY: &[u8; 62] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+async fn get_status(client: &Client, bucket: &str, key: &str) -> u16 {
+	let err = client
+		.get_object()
+		.bucket(bucket)
+		.key(key)
+		.send()
+		.await
+		.unwrap_err();
+	err.raw_response().unwrap().status().as_u16()
+}
+
+async fn head_status(client: &Client, bucket: &str, key: &str) -> u16 {
+	let err = client
+		.head_object()
+		.bucket(bucket)
+		.key(key)
+		.send()
+		.await
+		.unwrap_err();
+	err.raw_response().unwrap().status().as_u16()
+}
+
+fn set_forbidden_as_not_found(ctx: &common::Context, bucket: &str, enable: bool) {
+	ctx.garage
+		.command()
+		.args(["bucket", "set-forbidden-as-not-found", bucket])
+		.arg(if enable { "--enable" } else { "--disable" })
+		.quiet()
+		.expect_success_status("Could not set forbidden-as-not-found");
+}
+
+#[tokio::test]
+async fn test_forbidden_as_not_found() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("forbiddenasnotfound");
+
+	ctx.client
+		.put_object()
+		.bucket(&bucket)
+		.key("exists")
+		.body(ByteStream::from_static(BODY))
+		.send()
+		.await
+		.unwrap();
+
+	// A key that can write to the bucket, but not read from it
+	let writer_key = ctx.garage.key(Some("forbidden-writer"));
+	ctx.garage
+		.command()
+		.args(["bucket", "allow", "--write", &bucket])
+		.args(["--key", &writer_key.id])
+		.quiet()
+		.expect_success_status("Could not allow key to write to bucket");
+	let writer = common::client::build_client(&writer_key);
+
+	// Default: access is denied, whether the object exists or not
+	assert_eq!(get_status(&writer, &bucket, "exists").await, 403);
+	assert_eq!(head_status(&writer, &bucket, "exists").await, 403);
+	assert_eq!(get_status(&writer, &bucket, "missing").await, 403);
+
+	// The bucket is configured to report objects as not found
+	set_forbidden_as_not_found(&ctx, &bucket, true);
+	assert_eq!(get_status(&writer, &bucket, "exists").await, 404);
+	assert_eq!(head_status(&writer, &bucket, "exists").await, 404);
+	assert_eq!(get_status(&writer, &bucket, "missing").await, 404);
+
+	// Other operations are still denied with 403
+	let err = writer
+		.list_objects_v2()
+		.bucket(&bucket)
+		.send()
+		.await
+		.unwrap_err();
+	assert_eq!(err.raw_response().unwrap().status().as_u16(), 403);
+
+	// Keys that can read the bucket are not affected
+	ctx.client
+		.get_object()
+		.bucket(&bucket)
+		.key("exists")
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(get_status(&ctx.client, &bucket, "missing").await, 404);
+
+	set_forbidden_as_not_found(&ctx, &bucket, false);
+	assert_eq!(get_status(&writer, &bucket, "exists").await, 403);
+	assert_eq!(head_status(&writer, &bucket, "exists").await, 403);
+}
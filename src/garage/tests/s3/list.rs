@@ -1,4 +1,5 @@
 use crate::common;
+use crate::common::ext::*;
 
 const KEYS: [&str; 8] = ["a", "a/a", "a/b", "a/c", "a/d/a", "a/é", "b", "c"];
 const KEYS_MULTIPART: [&str; 5] = ["a", "a", "c", "c/a", "c/b"];
@@ -673,3 +674,357 @@ async fn test_multichar_delimiter() {
 		assert_eq!(common_prefixes[1].prefix.as_deref().unwrap(), "a/c/b/");
 	}
 }
+
+#[tokio::test]
+async fn test_multichar_delimiter_prefixes() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("multichardelimprefixes");
+
+	for k in [
+		"data//2024//a",
+		"data//2024//b",
+		"data//2025//a",
+		"data//x",
+		"other",
+	] {
+		ctx.client
+			.put_object()
+			.bucket(&bucket)
+			.key(k)
+			.send()
+			.await
+			.unwrap();
+	}
+
+	// With a two-character delimiter
+	{
+		let r = ctx
+			.client
+			.list_objects_v2()
+			.bucket(&bucket)
+			.delimiter("//")
+			.send()
+			.await
+			.unwrap();
+
+		let contents = r.contents.unwrap();
+		assert_eq!(contents.len(), 1);
+		assert_eq!(contents[0].key.as_deref().unwrap(), "other");
+
+		let common_prefixes = r.common_prefixes.unwrap();
+		assert_eq!(common_prefixes.len(), 1);
+		assert_eq!(common_prefixes[0].prefix.as_deref().unwrap(), "data//");
+	}
+
+	// With a prefix that ends with the delimiter, and pagination
+	{
+		let mut seen = vec![];
+		let mut token = None;
+		loop {
+			let r = ctx
+				.client
+				.list_objects_v2()
+				.bucket(&bucket)
+				.prefix("data//")
+				.delimiter("//")
+				.max_keys(1)
+				.set_continuation_token(token)
+				.send()
+				.await
+				.unwrap();
+
+			for c in r.contents.unwrap_or_default() {
+				seen.push(c.key.unwrap());
+			}
+			for p in r.common_prefixes.unwrap_or_default() {
+				seen.push(p.prefix.unwrap());
+			}
+			token = r.next_continuation_token;
+			if token.is_none() {
+				break;
+			}
+		}
+		assert_eq!(seen, ["data//2024//", "data//2025//", "data//x"]);
+	}
+
+	// With a delimiter that is not present in any key
+	{
+		let r = ctx
+			.client
+			.list_objects_v2()
+			.bucket(&bucket)
+			.delimiter("-2024-")
+			.send()
+			.await
+			.unwrap();
+
+		assert_eq!(r.contents.unwrap().len(), 5);
+		assert!(r.common_prefixes.is_none());
+	}
+}
+
+#[tokio::test]
+async fn test_list_permission() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("listpermission");
+
+	for k in KEYS {
+		ctx.client
+			.put_object()
+			.bucket(&bucket)
+			.key(k)
+			.send()
+			.await
+			.unwrap();
+	}
+
+	ctx.garage
+		.command()
+		.args(["bucket", "deny"])
+		.args(["--list"])
+		.arg(&bucket)
+		.args(["--key", &ctx.key.id])
+		.quiet()
+		.expect_success_status("Could not deny list permission");
+
+	// Objects can still be read if their name is known
+	ctx.client
+		.get_object()
+		.bucket(&bucket)
+		.key("a/b")
+		.send()
+		.await
+		.unwrap();
+
+	// But the bucket can no longer be listed
+	let err = ctx
+		.client
+		.list_objects_v2()
+		.bucket(&bucket)
+		.send()
+		.await
+		.unwrap_err();
+	assert_eq!(err.raw_response().unwrap().status().as_u16(), 403);
+
+	let err = ctx
+		.client
+		.list_multipart_uploads()
+		.bucket(&bucket)
+		.send()
+		.await
+		.unwrap_err();
+	assert_eq!(err.raw_response().unwrap().status().as_u16(), 403);
+
+	ctx.garage
+		.command()
+		.args(["bucket", "allow"])
+		.args(["--list"])
+		.arg(&bucket)
+		.args(["--key", &ctx.key.id])
+		.quiet()
+		.expect_success_status("Could not allow list permission");
+
+	let r = ctx
+		.client
+		.list_objects_v2()
+		.bucket(&bucket)
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(r.contents.unwrap().len(), 8);
+
+	// `--no-list` gives the read permission without the list permission
+	ctx.garage
+		.command()
+		.args(["bucket", "allow"])
+		.args(["--read", "--no-list"])
+		.arg(&bucket)
+		.args(["--key", &ctx.key.id])
+		.quiet()
+		.expect_success_status("Could not allow read without list permission");
+
+	ctx.client
+		.head_object()
+		.bucket(&bucket)
+		.key("a/b")
+		.send()
+		.await
+		.unwrap();
+	let err = ctx
+		.client
+		.list_object_versions()
+		.bucket(&bucket)
+		.send()
+		.await
+		.unwrap_err();
+	assert_eq!(err.raw_response().unwrap().status().as_u16(), 403);
+}
+
+#[tokio::test]
+async fn test_directory_markers() {
+	// With the default `object` mode for directory markers,
+	// folder markers are regular objects, like on AWS
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("directorymarkers");
+
+	for k in ["dir/", "dir/a", "dir/sub/", "dir/sub/b"] {
+		ctx.client
+			.put_object()
+			.bucket(&bucket)
+			.key(k)
+			.send()
+			.await
+			.unwrap();
+	}
+
+	// The folder marker can be retrieved as a zero-byte object
+	let o = ctx
+		.client
+		.get_object()
+		.bucket(&bucket)
+		.key("dir/")
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(o.content_length.unwrap(), 0);
+
+	// A directory without a folder marker is not an object
+	let err = ctx
+		.client
+		.head_object()
+		.bucket(&bucket)
+		.key("other/")
+		.send()
+		.await
+		.unwrap_err();
+	assert_eq!(err.raw_response().unwrap().status().as_u16(), 404);
+
+	// At the root, the folder marker is rolled up in the common prefix
+	{
+		let r = ctx
+			.client
+			.list_objects_v2()
+			.bucket(&bucket)
+			.delimiter("/")
+			.send()
+			.await
+			.unwrap();
+
+		assert!(r.contents.is_none());
+		let common_prefixes = r.common_prefixes.unwrap();
+		assert_eq!(common_prefixes.len(), 1);
+		assert_eq!(common_prefixes[0].prefix.as_deref().unwrap(), "dir/");
+	}
+
+	// Inside the directory, its own folder marker is listed,
+	// the one of the subdirectory is rolled up
+	{
+		let r = ctx
+			.client
+			.list_objects_v2()
+			.bucket(&bucket)
+			.prefix("dir/")
+			.delimiter("/")
+			.send()
+			.await
+			.unwrap();
+
+		let contents = r.contents.unwrap();
+		assert_eq!(contents.len(), 2);
+		assert_eq!(contents[0].key.as_deref().unwrap(), "dir/");
+		assert_eq!(contents[0].size.unwrap(), 0);
+		assert_eq!(contents[1].key.as_deref().unwrap(), "dir/a");
+
+		let common_prefixes = r.common_prefixes.unwrap();
+		assert_eq!(common_prefixes.len(), 1);
+		assert_eq!(common_prefixes[0].prefix.as_deref().unwrap(), "dir/sub/");
+	}
+}
+
+#[tokio::test]
+async fn test_list_modified_since() {
+	use garage_util::time::{msec_to_rfc3339, now_msec};
+	use http_body_util::BodyExt;
+	use hyper::StatusCode;
+	use std::time::Duration;
+
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("listmodifiedsince");
+
+	for k in ["old1", "old2", "x/old3"] {
+		ctx.client
+			.put_object()
+			.bucket(&bucket)
+			.key(k)
+			.send()
+			.await
+			.unwrap();
+	}
+
+	tokio::time::sleep(Duration::from_millis(100)).await;
+	let since = msec_to_rfc3339(now_msec());
+	tokio::time::sleep(Duration::from_millis(100)).await;
+
+	for k in ["new1", "x/new2"] {
+		ctx.client
+			.put_object()
+			.bucket(&bucket)
+			.key(k)
+			.send()
+			.await
+			.unwrap();
+	}
+	// Overwriting an object makes it modified again. The new content is
+	// different, so that the upload is not answered as a duplicate of the
+	// first one.
+	ctx.client
+		.put_object()
+		.bucket(&bucket)
+		.key("old2")
+		.body(aws_sdk_s3::primitives::ByteStream::from_static(b"new"))
+		.send()
+		.await
+		.unwrap();
+
+	for list_type in [Some("2"), None] {
+		let mut req = ctx.custom_request.builder(bucket.clone());
+		if let Some(t) = list_type {
+			req.query_param("list-type", Some(t));
+		}
+		let res = req
+			.signed_header("x-garage-modified-since", &since)
+			.send()
+			.await
+			.unwrap();
+		assert_eq!(res.status(), StatusCode::OK);
+		let body = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+		let body = std::str::from_utf8(&body).unwrap();
+
+		let keys = body
+			.split("<Key>")
+			.skip(1)
+			.map(|s| s.split("</Key>").next().unwrap())
+			.collect::<Vec<_>>();
+		assert_eq!(keys, vec!["new1", "old2", "x/new2"]);
+	}
+
+	// Without the header, all objects are listed
+	let r = ctx
+		.client
+		.list_objects_v2()
+		.bucket(&bucket)
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(r.contents.unwrap().len(), 5);
+
+	// Invalid dates are rejected
+	let res = ctx
+		.custom_request
+		.builder(bucket.clone())
+		.signed_header("x-garage-modified-since", "yesterday")
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
@@ -0,0 +1,152 @@
+use std::time::Duration;
+
+use crate::common;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{BucketLoggingStatus, LoggingEnabled};
+
+const BODY: &[u8; 62] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// Wait for access logs to be delivered in the given bucket, and return
+/// the content of all the log objects
+async fn wait_for_logs(ctx: &common::Context, bucket: &str, prefix: &str) -> Vec<String> {
+	for _ in 0..50 {
+		let r = ctx
+			.client
+			.list_objects_v2()
+			.bucket(bucket)
+			.prefix(prefix)
+			.send()
+			.await
+			.unwrap();
+		let keys = r
+			.contents
+			.unwrap_or_default()
+			.into_iter()
+			.map(|o| o.key.unwrap())
+			.collect::<Vec<_>>();
+		if !keys.is_empty() {
+			let mut lines = vec![];
+			for key in keys {
+				let o = ctx
+					.client
+					.get_object()
+					.bucket(bucket)
+					.key(&key)
+					.send()
+					.await
+					.unwrap();
+				let data = o.body.collect().await.unwrap().into_bytes();
+				lines.extend(
+					std::str::from_utf8(&data)
+						.unwrap()
+						.lines()
+						.map(str::to_string),
+				);
+			}
+			return lines;
+		}
+		tokio::time::sleep(Duration::from_millis(200)).await;
+	}
+	panic!("access logs were not delivered in {}", bucket);
+}
+
+#[tokio::test]
+async fn test_bucket_logging() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("logged");
+	let logs = ctx.create_bucket("accesslogs");
+
+	let r = ctx
+		.client
+		.get_bucket_logging()
+		.bucket(&bucket)
+		.send()
+		.await
+		.unwrap();
+	assert!(r.logging_enabled.is_none());
+
+	ctx.client
+		.put_bucket_logging()
+		.bucket(&bucket)
+		.bucket_logging_status(
+			BucketLoggingStatus::builder()
+				.logging_enabled(
+					LoggingEnabled::builder()
+						.target_bucket(&logs)
+						.target_prefix("logged/")
+						.build()
+						.unwrap(),
+				)
+				.build(),
+		)
+		.send()
+		.await
+		.unwrap();
+
+	let r = ctx
+		.client
+		.get_bucket_logging()
+		.bucket(&bucket)
+		.send()
+		.await
+		.unwrap();
+	let enabled = r.logging_enabled.unwrap();
+	assert_eq!(enabled.target_bucket(), logs);
+	assert_eq!(enabled.target_prefix(), "logged/");
+
+	ctx.client
+		.put_object()
+		.bucket(&bucket)
+		.key("some key")
+		.body(ByteStream::from_static(BODY))
+		.send()
+		.await
+		.unwrap();
+	ctx.client
+		.get_object()
+		.bucket(&bucket)
+		.key("missing")
+		.send()
+		.await
+		.unwrap_err();
+
+	let lines = wait_for_logs(&ctx, &logs, "logged/").await;
+	let put = lines
+		.iter()
+		.find(|l| l.contains(" REST.PUT.PutObject "))
+		.expect("PutObject request was not logged");
+	let fields = put.split(' ').collect::<Vec<_>>();
+	assert_eq!(fields[1], bucket);
+	assert_eq!(fields[5], ctx.key.id);
+	assert_eq!(fields[8], "some%20key");
+	assert!(put.contains("\" 200 - "));
+
+	let get = lines
+		.iter()
+		.find(|l| l.contains(" REST.GET.GetObject "))
+		.expect("failed GetObject request was not logged");
+	assert!(get.contains("\" 404 NoSuchKey "));
+
+	// Requests to the target bucket, which does not have logging enabled,
+	// and deliveries themselves are not logged
+	assert!(lines
+		.iter()
+		.all(|l| l.split(' ').nth(1) == Some(bucket.as_str())));
+
+	// Logging is disabled with an empty logging status
+	ctx.client
+		.put_bucket_logging()
+		.bucket(&bucket)
+		.bucket_logging_status(BucketLoggingStatus::builder().build())
+		.send()
+		.await
+		.unwrap();
+	let r = ctx
+		.client
+		.get_bucket_logging()
+		.bucket(&bucket)
+		.send()
+		.await
+		.unwrap();
+	assert!(r.logging_enabled.is_none());
+}
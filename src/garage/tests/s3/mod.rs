@@ -1,8 +1,24 @@
+mod allowed_methods;
+mod append;
+mod case_insensitive;
+mod connection;
+mod debug_timing;
+mod dedup;
+mod duplicate_put;
+mod forbidden;
 mod list;
+mod logging;
+mod move_object;
 mod multipart;
 mod objects;
 mod presigned;
+mod previous_version;
+mod punycode;
+mod read_only;
+mod redirect;
+mod requester_pays;
 mod simple;
+mod size_limits;
 mod ssec;
 mod streaming_signature;
 mod website;
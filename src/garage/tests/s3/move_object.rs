@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::common;
+use crate::common::ext::*;
+use aws_sdk_s3::primitives::ByteStream;
+use hyper::{Method, StatusCode};
+
+async fn move_object(ctx: &common::Context, source: &str, bucket: &str, key: &str) -> StatusCode {
+	let mut headers = HashMap::new();
+	headers.insert("x-amz-copy-source".to_owned(), source.to_owned());
+	headers.insert("x-garage-move-source".to_owned(), "true".to_owned());
+	ctx.custom_request
+		.builder(bucket.to_owned())
+		.method(Method::PUT)
+		.path(key.to_owned())
+		.signed_headers(headers)
+		.send()
+		.await
+		.unwrap()
+		.status()
+}
+
+/// Object count and size lines of `garage bucket info`
+fn bucket_counters(ctx: &common::Context, bucket: &str) -> (String, String) {
+	let output = ctx
+		.garage
+		.command()
+		.args(["bucket", "info", bucket])
+		.expect_success_output("Could not get bucket info");
+	let output = String::from_utf8(output.stdout).unwrap();
+	let line = |prefix: &str| {
+		output
+			.lines()
+			.find(|l| l.starts_with(prefix))
+			.unwrap()
+			.to_string()
+	};
+	(line("Objects:"), line("Size:"))
+}
+
+/// Counters are updated asynchronously, wait for them to reach
+/// the expected number of objects
+async fn wait_bucket_counters(ctx: &common::Context, bucket: &str, objects: usize) -> String {
+	let expected = format!("Objects: {}", objects);
+	for _ in 0..50 {
+		let (o, size) = bucket_counters(ctx, bucket);
+		if o == expected {
+			return size;
+		}
+		tokio::time::sleep(Duration::from_millis(100)).await;
+	}
+	panic!("bucket {} does not have {} objects", bucket, objects);
+}
+
+#[tokio::test]
+async fn test_move_object() {
+	let ctx = common::context();
+	let src = ctx.create_bucket("move-src");
+	let dst = ctx.create_bucket("move-dst");
+
+	// Large enough to be stored in data blocks
+	let body = vec![42u8; 65536];
+	ctx.client
+		.put_object()
+		.bucket(&src)
+		.key("a")
+		.body(ByteStream::from(body.clone()))
+		.send()
+		.await
+		.unwrap();
+	let size_before = wait_bucket_counters(&ctx, &src, 1).await;
+
+	// Move within the same bucket: counters are unchanged
+	let status = move_object(&ctx, &format!("{}/a", src), &src, "b").await;
+	assert_eq!(status, StatusCode::OK);
+
+	let o = ctx
+		.client
+		.get_object()
+		.bucket(&src)
+		.key("b")
+		.send()
+		.await
+		.unwrap();
+	assert_bytes_eq!(o.body, &body[..]);
+	assert!(ctx
+		.client
+		.get_object()
+		.bucket(&src)
+		.key("a")
+		.send()
+		.await
+		.is_err());
+
+	tokio::time::sleep(Duration::from_millis(500)).await;
+	assert_eq!(wait_bucket_counters(&ctx, &src, 1).await, size_before);
+
+	// Move to another bucket: counters move with the object
+	let status = move_object(&ctx, &format!("{}/b", src), &dst, "c").await;
+	assert_eq!(status, StatusCode::OK);
+
+	let o = ctx
+		.client
+		.get_object()
+		.bucket(&dst)
+		.key("c")
+		.send()
+		.await
+		.unwrap();
+	assert_bytes_eq!(o.body, &body[..]);
+	assert!(ctx
+		.client
+		.get_object()
+		.bucket(&src)
+		.key("b")
+		.send()
+		.await
+		.is_err());
+
+	wait_bucket_counters(&ctx, &src, 0).await;
+	assert_eq!(wait_bucket_counters(&ctx, &dst, 1).await, size_before);
+
+	// Invalid moves
+	let status = move_object(&ctx, &format!("{}/c", dst), &dst, "c").await;
+	assert_eq!(status, StatusCode::BAD_REQUEST);
+	let status = move_object(&ctx, &format!("{}/nonexistent", src), &dst, "d").await;
+	assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_move_object_failure_keeps_source() {
+	let ctx = common::context();
+	let src = ctx.create_bucket("move-fail-src");
+	let dst = ctx.create_bucket("move-fail-dst");
+
+	ctx.client
+		.put_object()
+		.bucket(&src)
+		.key("a")
+		.body(ByteStream::from_static(b"hello"))
+		.send()
+		.await
+		.unwrap();
+
+	// Objects cannot be moved out of a read-only bucket
+	ctx.garage
+		.command()
+		.args(["bucket", "set-read-only", "--enable", &src])
+		.quiet()
+		.expect_success_status("Could not set bucket read-only");
+
+	let status = move_object(&ctx, &format!("{}/a", src), &dst, "a").await;
+	assert_eq!(status, StatusCode::FORBIDDEN);
+
+	let o = ctx
+		.client
+		.get_object()
+		.bucket(&src)
+		.key("a")
+		.send()
+		.await
+		.unwrap();
+	assert_bytes_eq!(o.body, b"hello");
+	assert!(ctx
+		.client
+		.head_object()
+		.bucket(&dst)
+		.key("a")
+		.send()
+		.await
+		.is_err());
+}
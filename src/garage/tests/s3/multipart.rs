@@ -1,6 +1,8 @@
 use crate::common;
+use crate::common::ext::*;
+use aws_sdk_s3::error::ProvideErrorMetadata;
 use aws_sdk_s3::primitives::ByteStream;
-use aws_sdk_s3::types::{ChecksumAlgorithm, CompletedMultipartUpload, CompletedPart};
+use aws_sdk_s3::types::{ChecksumAlgorithm, ChecksumMode, CompletedMultipartUpload, CompletedPart};
 use base64::prelude::*;
 
 const SZ_5MB: usize = 5 * 1024 * 1024;
@@ -563,6 +565,94 @@ async fn test_uploadlistpart() {
 	}
 }
 
+#[tokio::test]
+async fn test_list_parts_pagination() {
+	// Set in the test configuration
+	const MAX_LIST_PARTS: i32 = 100;
+	const PART_COUNT: i32 = 250;
+
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("listpartspagination");
+
+	let up = ctx
+		.client
+		.create_multipart_upload()
+		.bucket(&bucket)
+		.key("a")
+		.send()
+		.await
+		.unwrap();
+	let uid = up.upload_id.as_ref().unwrap();
+
+	let mut etags = vec![];
+	for i in 1..=PART_COUNT {
+		let p = ctx
+			.client
+			.upload_part()
+			.bucket(&bucket)
+			.key("a")
+			.upload_id(uid)
+			.part_number(i)
+			.body(ByteStream::from(format!("part {}", i).into_bytes()))
+			.send()
+			.await
+			.unwrap();
+		etags.push(p.e_tag.unwrap());
+	}
+
+	// Without max-parts, and with a larger max-parts,
+	// at most the configured maximum number of parts is returned
+	for max_parts in [None, Some(1000), Some(30)] {
+		let page_size = max_parts.unwrap_or(MAX_LIST_PARTS).min(MAX_LIST_PARTS);
+		let mut marker: Option<String> = None;
+		let mut listed = vec![];
+		loop {
+			let r = ctx
+				.client
+				.list_parts()
+				.bucket(&bucket)
+				.key("a")
+				.upload_id(uid)
+				.set_max_parts(max_parts)
+				.set_part_number_marker(marker.clone())
+				.send()
+				.await
+				.unwrap();
+			assert_eq!(r.max_parts.unwrap(), page_size);
+			assert_eq!(r.part_number_marker, marker);
+
+			let parts = r.parts.unwrap_or_default();
+			let remaining = PART_COUNT - listed.len() as i32;
+			assert_eq!(parts.len() as i32, remaining.min(page_size));
+			listed.extend(parts);
+
+			if !r.is_truncated.unwrap() {
+				assert!(r.next_part_number_marker.is_none());
+				break;
+			}
+			let next = r.next_part_number_marker.unwrap();
+			assert_eq!(next, listed.len().to_string());
+			marker = Some(next);
+		}
+
+		assert_eq!(listed.len() as i32, PART_COUNT);
+		for (i, part) in listed.iter().enumerate() {
+			assert_eq!(part.part_number.unwrap(), i as i32 + 1);
+			assert_eq!(part.e_tag.as_ref().unwrap(), &etags[i]);
+			assert_eq!(part.size.unwrap(), format!("part {}", i + 1).len() as i64);
+		}
+	}
+
+	ctx.client
+		.abort_multipart_upload()
+		.bucket(&bucket)
+		.key("a")
+		.upload_id(uid)
+		.send()
+		.await
+		.unwrap();
+}
+
 #[tokio::test]
 async fn test_uploadpartcopy() {
 	let ctx = common::context();
@@ -773,6 +863,118 @@ async fn test_uploadpartcopy() {
 	assert_eq!(real_obj, exp_obj);
 }
 
+#[tokio::test]
+async fn test_copy_large_multipart_object() {
+	// The test configuration sets copy_keepalive_threshold to 8 MiB,
+	// so that this copy is handled like a copy of a huge object
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("copylargeobject");
+
+	let u1 = (0..SZ_5MB).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+	let u2 = (0..SZ_5MB).map(|i| (i % 241) as u8).collect::<Vec<_>>();
+	let mut exp_obj = u1.clone();
+	exp_obj.extend(&u2);
+
+	let up = ctx
+		.client
+		.create_multipart_upload()
+		.bucket(&bucket)
+		.key("source")
+		.send()
+		.await
+		.unwrap();
+	let uid = up.upload_id.as_ref().unwrap();
+
+	let mut cmp = CompletedMultipartUpload::builder();
+	for (i, data) in [&u1, &u2].iter().enumerate() {
+		let part_number = i as i32 + 1;
+		let p = ctx
+			.client
+			.upload_part()
+			.bucket(&bucket)
+			.key("source")
+			.upload_id(uid)
+			.part_number(part_number)
+			.body(ByteStream::from(data.to_vec()))
+			.send()
+			.await
+			.unwrap();
+		cmp = cmp.parts(
+			CompletedPart::builder()
+				.part_number(part_number)
+				.e_tag(p.e_tag.unwrap())
+				.build(),
+		);
+	}
+	let source = ctx
+		.client
+		.complete_multipart_upload()
+		.bucket(&bucket)
+		.key("source")
+		.upload_id(uid)
+		.multipart_upload(cmp.build())
+		.send()
+		.await
+		.unwrap();
+
+	// The first data block of the object is referenced once by the source
+	let block_hash = hex::encode(garage_util::data::blake2sum(&u1[..1024 * 1024]));
+	let block_refcount = || {
+		let out = ctx
+			.garage
+			.command()
+			.args(["block", "info", &block_hash])
+			.expect_success_output("Could not get block info");
+		let out = String::from_utf8(out.stdout).unwrap();
+		let refcount = out
+			.lines()
+			.find_map(|l| l.strip_prefix("Refcount: "))
+			.unwrap()
+			.parse::<u64>()
+			.unwrap();
+		(refcount, out)
+	};
+	let (refcount_before, _) = block_refcount();
+
+	let res = ctx
+		.client
+		.copy_object()
+		.bucket(&bucket)
+		.key("copy")
+		.copy_source(format!("{}/source", bucket))
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(
+		res.copy_object_result.unwrap().e_tag.unwrap(),
+		source.e_tag.unwrap()
+	);
+
+	// No data was written: the copy references the blocks of the source
+	let (refcount_after, info) = block_refcount();
+	assert_eq!(refcount_after, refcount_before + 1);
+	assert!(info
+		.lines()
+		.any(|l| l.split_whitespace().any(|w| w == "copy")));
+
+	let obj = ctx
+		.client
+		.get_object()
+		.bucket(&bucket)
+		.key("copy")
+		.send()
+		.await
+		.unwrap();
+	let real_obj = obj
+		.body
+		.collect()
+		.await
+		.expect("Error reading data")
+		.into_bytes();
+	assert_eq!(real_obj.len(), exp_obj.len());
+	assert_eq!(real_obj, exp_obj);
+}
+
 fn calculate_sha1(bytes: &[u8]) -> String {
 	use sha1::{Digest, Sha1};
 
@@ -780,3 +982,446 @@ fn calculate_sha1(bytes: &[u8]) -> String {
 	hasher.update(bytes);
 	BASE64_STANDARD.encode(&hasher.finalize()[..])
 }
+
+#[tokio::test]
+async fn test_multipart_complete_etag_mismatch() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("mpuetagmismatch");
+
+	let up = ctx
+		.client
+		.create_multipart_upload()
+		.bucket(&bucket)
+		.key("a")
+		.send()
+		.await
+		.unwrap();
+	let uid = up.upload_id.as_ref().unwrap();
+
+	let mut etags = vec![];
+	for (part_number, byte) in [(1, 0x11), (2, 0x22)] {
+		let p = ctx
+			.client
+			.upload_part()
+			.bucket(&bucket)
+			.key("a")
+			.upload_id(uid)
+			.part_number(part_number)
+			.body(ByteStream::from(vec![byte; SZ_5MB]))
+			.send()
+			.await
+			.unwrap();
+		etags.push(p.e_tag.unwrap());
+	}
+
+	let complete = |etag2: &str| {
+		ctx.client
+			.complete_multipart_upload()
+			.bucket(&bucket)
+			.key("a")
+			.upload_id(uid)
+			.multipart_upload(
+				CompletedMultipartUpload::builder()
+					.parts(
+						CompletedPart::builder()
+							.part_number(1)
+							.e_tag(&etags[0])
+							.build(),
+					)
+					.parts(CompletedPart::builder().part_number(2).e_tag(etag2).build())
+					.build(),
+			)
+			.send()
+	};
+
+	// The ETag given for part 2 is that of part 1
+	let err = complete(&etags[0]).await.unwrap_err();
+	assert_eq!(err.raw_response().unwrap().status().as_u16(), 400);
+	assert_eq!(err.code(), Some("InvalidPart"));
+	assert!(
+		err.message().unwrap().contains("part 2"),
+		"{:?}",
+		err.message()
+	);
+
+	// The upload can still be completed with the right ETags
+	let r = complete(&etags[1]).await.unwrap();
+	assert!(r.e_tag.unwrap().ends_with("-2\""));
+
+	let o = ctx
+		.client
+		.head_object()
+		.bucket(&bucket)
+		.key("a")
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(o.content_length(), Some(2 * SZ_5MB as i64));
+}
+
+#[tokio::test]
+async fn test_multipart_complete_retry() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("mpucompleteretry");
+
+	let up = ctx
+		.client
+		.create_multipart_upload()
+		.bucket(&bucket)
+		.key("a")
+		.send()
+		.await
+		.unwrap();
+	let uid = up.upload_id.as_ref().unwrap();
+
+	let mut etags = vec![];
+	for (part_number, byte) in [(1, 0x11), (2, 0x22)] {
+		let p = ctx
+			.client
+			.upload_part()
+			.bucket(&bucket)
+			.key("a")
+			.upload_id(uid)
+			.part_number(part_number)
+			.body(ByteStream::from(vec![byte; SZ_5MB]))
+			.send()
+			.await
+			.unwrap();
+		etags.push(p.e_tag.unwrap());
+	}
+
+	let complete = |n_parts: usize| {
+		let mut parts = CompletedMultipartUpload::builder();
+		for (i, etag) in etags.iter().take(n_parts).enumerate() {
+			parts = parts.parts(
+				CompletedPart::builder()
+					.part_number(i as i32 + 1)
+					.e_tag(etag)
+					.build(),
+			);
+		}
+		ctx.client
+			.complete_multipart_upload()
+			.bucket(&bucket)
+			.key("a")
+			.upload_id(uid)
+			.multipart_upload(parts.build())
+			.send()
+	};
+
+	let etag = complete(2).await.unwrap().e_tag.unwrap();
+	assert!(etag.ends_with("-2\""));
+
+	// A retry with the same list of parts, e.g. after the response to the
+	// first request was lost, succeeds
+	let r = complete(2).await.unwrap();
+	assert_eq!(r.e_tag.unwrap(), etag);
+
+	// A retry with a different list of parts fails
+	let err = complete(1).await.unwrap_err();
+	assert_eq!(err.raw_response().unwrap().status().as_u16(), 400);
+	assert_eq!(err.code(), Some("InvalidPart"));
+
+	let o = ctx
+		.client
+		.head_object()
+		.bucket(&bucket)
+		.key("a")
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(o.e_tag.as_deref(), Some(etag.as_str()));
+	assert_eq!(o.content_length(), Some(2 * SZ_5MB as i64));
+}
+
+#[tokio::test]
+async fn test_multipart_in_progress_not_visible() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("mpuinprogress");
+
+	let up = ctx
+		.client
+		.create_multipart_upload()
+		.bucket(&bucket)
+		.key("a")
+		.send()
+		.await
+		.unwrap();
+	let uid = up.upload_id.as_ref().unwrap();
+
+	let p1 = ctx
+		.client
+		.upload_part()
+		.bucket(&bucket)
+		.key("a")
+		.upload_id(uid)
+		.part_number(1)
+		.body(ByteStream::from(vec![0x42; SZ_5MB]))
+		.send()
+		.await
+		.unwrap();
+
+	// The key only has an in-progress upload: it doesn't exist yet
+	let err = ctx
+		.client
+		.head_object()
+		.bucket(&bucket)
+		.key("a")
+		.send()
+		.await
+		.unwrap_err();
+	assert_eq!(err.raw_response().unwrap().status().as_u16(), 404);
+
+	let err = ctx
+		.client
+		.get_object()
+		.bucket(&bucket)
+		.key("a")
+		.send()
+		.await
+		.unwrap_err();
+	assert_eq!(err.raw_response().unwrap().status().as_u16(), 404);
+	assert_eq!(err.code(), Some("NoSuchKey"));
+
+	let l = ctx
+		.client
+		.list_objects_v2()
+		.bucket(&bucket)
+		.send()
+		.await
+		.unwrap();
+	assert!(l.contents.unwrap_or_default().is_empty());
+
+	// The upload is listed as in progress
+	let uploads = ctx
+		.client
+		.list_multipart_uploads()
+		.bucket(&bucket)
+		.send()
+		.await
+		.unwrap();
+	let uploads = uploads.uploads.unwrap();
+	assert_eq!(uploads.len(), 1);
+	assert_eq!(uploads[0].key.as_deref(), Some("a"));
+	assert_eq!(uploads[0].upload_id.as_deref(), Some(uid.as_str()));
+
+	ctx.client
+		.complete_multipart_upload()
+		.bucket(&bucket)
+		.key("a")
+		.upload_id(uid)
+		.multipart_upload(
+			CompletedMultipartUpload::builder()
+				.parts(
+					CompletedPart::builder()
+						.part_number(1)
+						.e_tag(p1.e_tag.unwrap())
+						.build(),
+				)
+				.build(),
+		)
+		.send()
+		.await
+		.unwrap();
+
+	// Once completed, the object is visible
+	let o = ctx
+		.client
+		.head_object()
+		.bucket(&bucket)
+		.key("a")
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(o.content_length(), Some(SZ_5MB as i64));
+}
+
+#[tokio::test]
+async fn test_always_compute_sha256() {
+	// The test configuration sets `always_compute_sha256 = true`
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("alwayssha256");
+
+	let data = vec![0x17; 300];
+	ctx.client
+		.put_object()
+		.bucket(&bucket)
+		.key("simple")
+		.body(ByteStream::from(data.clone()))
+		.send()
+		.await
+		.unwrap();
+
+	let o = ctx
+		.client
+		.head_object()
+		.bucket(&bucket)
+		.key("simple")
+		.checksum_mode(ChecksumMode::Enabled)
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(o.checksum_sha256.unwrap(), calculate_sha256(&data));
+
+	let u1 = vec![0x11; SZ_5MB];
+	let u2 = vec![0x22; 1234];
+
+	let up = ctx
+		.client
+		.create_multipart_upload()
+		.bucket(&bucket)
+		.key("mpu")
+		.send()
+		.await
+		.unwrap();
+	let uid = up.upload_id.as_ref().unwrap();
+
+	let mut parts = vec![];
+	for (i, data) in [&u1, &u2].iter().enumerate() {
+		let p = ctx
+			.client
+			.upload_part()
+			.bucket(&bucket)
+			.key("mpu")
+			.upload_id(uid)
+			.part_number(i as i32 + 1)
+			.body(ByteStream::from(data.to_vec()))
+			.send()
+			.await
+			.unwrap();
+		parts.push(
+			CompletedPart::builder()
+				.part_number(i as i32 + 1)
+				.e_tag(p.e_tag.unwrap())
+				.build(),
+		);
+	}
+
+	ctx.client
+		.complete_multipart_upload()
+		.bucket(&bucket)
+		.key("mpu")
+		.upload_id(uid)
+		.multipart_upload(
+			CompletedMultipartUpload::builder()
+				.set_parts(Some(parts))
+				.build(),
+		)
+		.send()
+		.await
+		.unwrap();
+
+	// The checksum is that of the whole object, not a checksum of checksums
+	let full = [u1, u2].concat();
+	let o = ctx
+		.client
+		.get_object()
+		.bucket(&bucket)
+		.key("mpu")
+		.checksum_mode(ChecksumMode::Enabled)
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(
+		o.checksum_sha256.as_deref(),
+		Some(&calculate_sha256(&full)[..])
+	);
+	let body = o.body.collect().await.unwrap().into_bytes();
+	assert_eq!(&body[..], &full[..]);
+}
+
+#[tokio::test]
+async fn test_multipart_upload_quota() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("testmpuquota");
+	ctx.garage
+		.command()
+		.args(["bucket", "set-quotas", bucket.as_str()])
+		.args(["--max-multipart-uploads", "2"])
+		.quiet()
+		.expect_success_status("Could not set bucket quotas");
+
+	let create = |key: &'static str| {
+		ctx.client
+			.create_multipart_upload()
+			.bucket(&bucket)
+			.key(key)
+			.send()
+	};
+	// Counters of uploads in progress are updated asynchronously
+	let settle = || tokio::time::sleep(std::time::Duration::from_millis(500));
+
+	let up1 = create("a").await.unwrap().upload_id.unwrap();
+	settle().await;
+	let up2 = create("b").await.unwrap().upload_id.unwrap();
+	settle().await;
+
+	let err = create("c").await.unwrap_err();
+	assert_eq!(err.raw_response().unwrap().status().as_u16(), 503);
+
+	// Aborting an upload frees a slot
+	ctx.client
+		.abort_multipart_upload()
+		.bucket(&bucket)
+		.key("a")
+		.upload_id(up1)
+		.send()
+		.await
+		.unwrap();
+	settle().await;
+	create("c").await.unwrap();
+	settle().await;
+
+	let err = create("d").await.unwrap_err();
+	assert_eq!(err.raw_response().unwrap().status().as_u16(), 503);
+
+	// Completing an upload frees a slot too
+	let p = ctx
+		.client
+		.upload_part()
+		.bucket(&bucket)
+		.key("b")
+		.upload_id(&up2)
+		.part_number(1)
+		.body(ByteStream::from(vec![0x11; 1024]))
+		.send()
+		.await
+		.unwrap();
+	ctx.client
+		.complete_multipart_upload()
+		.bucket(&bucket)
+		.key("b")
+		.upload_id(&up2)
+		.multipart_upload(
+			CompletedMultipartUpload::builder()
+				.parts(
+					CompletedPart::builder()
+						.part_number(1)
+						.e_tag(p.e_tag.unwrap())
+						.build(),
+				)
+				.build(),
+		)
+		.send()
+		.await
+		.unwrap();
+	settle().await;
+	create("d").await.unwrap();
+
+	// Uploads are not limited anymore once the quota is removed
+	ctx.garage
+		.command()
+		.args(["bucket", "set-quotas", bucket.as_str()])
+		.args(["--max-multipart-uploads", "none"])
+		.quiet()
+		.expect_success_status("Could not set bucket quotas");
+	create("e").await.unwrap();
+}
+
+fn calculate_sha256(bytes: &[u8]) -> String {
+	use sha2::{Digest, Sha256};
+
+	let mut hasher = Sha256::new();
+	hasher.update(bytes);
+	BASE64_STANDARD.encode(&hasher.finalize()[..])
+}
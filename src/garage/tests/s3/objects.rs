@@ -1,6 +1,8 @@
 use crate::common;
-use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::primitives::{ByteStream, DateTimeFormat};
 use aws_sdk_s3::types::{Delete, ObjectIdentifier};
+use http_body_util::BodyExt;
+use hyper::{Method, StatusCode};
 
 const STD_KEY: &str = "hello world";
 const CTRL_KEY: &str = "\x00\x01\x02\x00";
@@ -32,7 +34,7 @@ async fn test_putobject() {
 		// We should check if Amazon is returning one when versioning is not enabled
 		assert!(r.version_id.is_some());
 
-		let _version = r.version_id.unwrap();
+		let version = r.version_id.unwrap();
 
 		let o = ctx
 			.client
@@ -45,9 +47,8 @@ async fn test_putobject() {
 
 		assert_bytes_eq!(o.body, b"");
 		assert_eq!(o.e_tag.unwrap(), etag);
-		// We do not return version ID
-		// We should check if Amazon is returning one when versioning is not enabled
-		// assert_eq!(o.version_id.unwrap(), _version);
+		assert_eq!(o.version_id.unwrap(), version);
+		assert_eq!(o.accept_ranges.unwrap(), "bytes");
 		assert_eq!(o.content_type.unwrap(), content_type);
 		assert!(o.last_modified.is_some());
 		assert_eq!(o.content_length.unwrap(), 0);
@@ -187,6 +188,204 @@ async fn test_getobject() {
 	}
 }
 
+/// GET an object with the given Range header, returning the status code,
+/// the Content-Range header and the body of the response
+async fn get_with_range(
+	ctx: &common::Context,
+	bucket: &str,
+	key: &str,
+	range: &str,
+) -> (StatusCode, Option<String>, bytes::Bytes) {
+	let res = ctx
+		.custom_request
+		.builder(bucket.to_owned())
+		.method(Method::GET)
+		.path(key.to_owned())
+		.signed_header("range", range)
+		.send()
+		.await
+		.unwrap();
+	let status = res.status();
+	let content_range = res
+		.headers()
+		.get("content-range")
+		.map(|v| v.to_str().unwrap().to_string());
+	let body = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+	(status, content_range, body)
+}
+
+#[tokio::test]
+async fn test_getobject_unsatisfiable_range() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("unsatisfiablerange");
+
+	for (key, body) in [("empty", &b""[..]), ("short", &b"abc"[..])] {
+		ctx.client
+			.put_object()
+			.bucket(&bucket)
+			.key(key)
+			.body(ByteStream::from(body.to_vec()))
+			.send()
+			.await
+			.unwrap();
+	}
+
+	for (key, range, size) in [
+		("empty", "bytes=0-", 0),
+		("empty", "bytes=0-10", 0),
+		("empty", "bytes=-5", 0),
+		("short", "bytes=3-", 3),
+		("short", "bytes=10-20", 3),
+	] {
+		let (status, content_range, _) = get_with_range(&ctx, &bucket, key, range).await;
+		assert_eq!(
+			status,
+			StatusCode::RANGE_NOT_SATISFIABLE,
+			"{} {}",
+			key,
+			range
+		);
+		assert_eq!(content_range, Some(format!("bytes */{}", size)));
+	}
+
+	// A suffix range longer than the object selects the whole object
+	let (status, content_range, body) = get_with_range(&ctx, &bucket, "short", "bytes=-5").await;
+	assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+	assert_eq!(content_range.as_deref(), Some("bytes 0-2/3"));
+	assert_eq!(&body[..], b"abc");
+
+	// A range ending past the end of the object is truncated
+	let (status, content_range, body) = get_with_range(&ctx, &bucket, "short", "bytes=1-10").await;
+	assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+	assert_eq!(content_range.as_deref(), Some("bytes 1-2/3"));
+	assert_eq!(&body[..], b"bc");
+}
+
+#[tokio::test]
+async fn test_object_response_headers() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("objectresponseheaders");
+
+	let version = ctx
+		.client
+		.put_object()
+		.bucket(&bucket)
+		.key("headers")
+		.body(ByteStream::from_static(BODY))
+		.send()
+		.await
+		.unwrap()
+		.version_id
+		.unwrap();
+
+	let h = ctx
+		.client
+		.head_object()
+		.bucket(&bucket)
+		.key("headers")
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(h.version_id.unwrap(), version);
+	assert_eq!(h.accept_ranges.unwrap(), "bytes");
+	assert!(h.last_modified.is_some());
+
+	for method in [Method::GET, Method::HEAD] {
+		let res = ctx
+			.custom_request
+			.builder(bucket.clone())
+			.method(method)
+			.path("headers".to_owned())
+			.send()
+			.await
+			.unwrap();
+		assert_eq!(res.status(), StatusCode::OK);
+		let headers = res.headers();
+		assert_eq!(headers["x-amz-version-id"], version.as_str());
+		assert_eq!(headers["accept-ranges"], "bytes");
+
+		// IMF-fixdate, such as "Sun, 06 Nov 1994 08:49:37 GMT",
+		// with a two-digit day of the month
+		let last_modified = headers["last-modified"].to_str().unwrap();
+		assert_eq!(last_modified.len(), 29);
+		assert!(last_modified[5..7].bytes().all(|c| c.is_ascii_digit()));
+		assert!(last_modified.ends_with(" GMT"));
+	}
+}
+
+/// GET the bytes 1 to 9 of an object, with the given If-Range header
+async fn get_with_if_range(
+	ctx: &common::Context,
+	bucket: &str,
+	key: &str,
+	if_range: &str,
+) -> (StatusCode, bytes::Bytes) {
+	let res = ctx
+		.custom_request
+		.builder(bucket.to_owned())
+		.method(Method::GET)
+		.path(key.to_owned())
+		.signed_header("range", "bytes=1-9")
+		.signed_header("if-range", if_range)
+		.send()
+		.await
+		.unwrap();
+	let status = res.status();
+	let body = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+	(status, body)
+}
+
+#[tokio::test]
+async fn test_getobject_if_range() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("getobjectifrange");
+	let key = "resumable";
+
+	let r = ctx
+		.client
+		.put_object()
+		.bucket(&bucket)
+		.key(key)
+		.body(ByteStream::from_static(BODY))
+		.send()
+		.await
+		.unwrap();
+	let etag = r.e_tag.unwrap();
+
+	let o = ctx
+		.client
+		.head_object()
+		.bucket(&bucket)
+		.key(key)
+		.send()
+		.await
+		.unwrap();
+	let last_modified = o
+		.last_modified
+		.unwrap()
+		.fmt(DateTimeFormat::HttpDate)
+		.unwrap();
+
+	// The validator matches: the range is returned
+	for validator in [etag.clone(), last_modified] {
+		let (status, body) = get_with_if_range(&ctx, &bucket, key, &validator).await;
+		assert_eq!(status, 206);
+		assert_eq!(&body[..], &BODY[1..10]);
+	}
+
+	// The object has changed since the validator was obtained, or the validator
+	// can only be compared weakly: the whole object is returned
+	for validator in [
+		"\"0123456789abcdef0123456789abcdef\"".to_string(),
+		"Mon, 01 Jan 2001 00:00:00 GMT".to_string(),
+		format!("W/{}", etag),
+	] {
+		let (status, body) = get_with_if_range(&ctx, &bucket, key, &validator).await;
+		assert_eq!(status, 200);
+		assert_eq!(&body[..], &BODY[..]);
+	}
+}
+
 #[tokio::test]
 async fn test_metadata() {
 	let ctx = common::context();
@@ -404,3 +603,479 @@ async fn test_deleteobject() {
 		.await
 		.unwrap();
 }
+
+#[tokio::test]
+async fn test_copyobject_source() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("copyobjectsource");
+
+	let key = "a+b?versionId=c";
+	let encoded_key = "a%2Bb%3FversionId%3Dc";
+
+	let v1 = ctx
+		.client
+		.put_object()
+		.bucket(&bucket)
+		.key(key)
+		.body(ByteStream::from_static(b"first"))
+		.send()
+		.await
+		.unwrap()
+		.version_id
+		.unwrap();
+	let v2 = ctx
+		.client
+		.put_object()
+		.bucket(&bucket)
+		.key(key)
+		.body(ByteStream::from_static(BODY))
+		.send()
+		.await
+		.unwrap()
+		.version_id
+		.unwrap();
+
+	for (i, source) in [
+		format!("{}/{}", bucket, encoded_key),
+		format!("/{}/{}?versionId=null", bucket, encoded_key),
+		format!("{}/{}?versionId={}", bucket, encoded_key, v2),
+	]
+	.iter()
+	.cloned()
+	.enumerate()
+	{
+		let dest = format!("copy{}", i);
+		let r = ctx
+			.client
+			.copy_object()
+			.bucket(&bucket)
+			.key(&dest)
+			.copy_source(&source)
+			.send()
+			.await
+			.unwrap();
+		assert_eq!(r.copy_source_version_id.as_deref(), Some(v2.as_str()));
+
+		let o = ctx
+			.client
+			.get_object()
+			.bucket(&bucket)
+			.key(&dest)
+			.send()
+			.await
+			.unwrap();
+		assert_eq!(o.version_id, r.version_id);
+		assert_bytes_eq!(o.body, BODY);
+	}
+
+	// The first version has been overwritten and no longer exists
+	let err = ctx
+		.client
+		.copy_object()
+		.bucket(&bucket)
+		.key("copy-old")
+		.copy_source(format!("{}/{}?versionId={}", bucket, encoded_key, v1))
+		.send()
+		.await
+		.unwrap_err();
+	assert_eq!(err.raw_response().unwrap().status().as_u16(), 404);
+
+	// Malformed version ID
+	let err = ctx
+		.client
+		.copy_object()
+		.bucket(&bucket)
+		.key("copy-bad")
+		.copy_source(format!("{}/{}?versionId=xyz", bucket, encoded_key))
+		.send()
+		.await
+		.unwrap_err();
+	assert_eq!(err.raw_response().unwrap().status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn test_copyobject_directives() {
+	use aws_sdk_s3::types::{MetadataDirective, TaggingDirective};
+
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("copyobjectdirectives");
+
+	ctx.client
+		.put_object()
+		.bucket(&bucket)
+		.key("source")
+		.metadata("origin", "source")
+		.content_type("text/plain")
+		.body(ByteStream::from_static(BODY))
+		.send()
+		.await
+		.unwrap();
+
+	// Tags are not stored, so the COPY tagging directive has no effect
+	// on the destination object, whatever the metadata directive
+	for (i, metadata_directive) in [MetadataDirective::Copy, MetadataDirective::Replace]
+		.iter()
+		.cloned()
+		.enumerate()
+	{
+		let dest = format!("dest{}", i);
+		let replace = metadata_directive == MetadataDirective::Replace;
+		ctx.client
+			.copy_object()
+			.bucket(&bucket)
+			.key(&dest)
+			.copy_source(format!("{}/source", bucket))
+			.metadata_directive(metadata_directive)
+			.tagging_directive(TaggingDirective::Copy)
+			.tagging("tag=value")
+			.metadata("origin", "copy")
+			.content_type("application/json")
+			.send()
+			.await
+			.unwrap();
+
+		let o = ctx
+			.client
+			.head_object()
+			.bucket(&bucket)
+			.key(&dest)
+			.send()
+			.await
+			.unwrap();
+		let (origin, content_type) = match replace {
+			true => ("copy", "application/json"),
+			false => ("source", "text/plain"),
+		};
+		assert_eq!(
+			o.metadata.unwrap().get("origin").map(String::as_str),
+			Some(origin)
+		);
+		assert_eq!(o.content_type.as_deref(), Some(content_type));
+	}
+
+	// Replacing the tags is not supported
+	let err = ctx
+		.client
+		.copy_object()
+		.bucket(&bucket)
+		.key("dest-replace-tags")
+		.copy_source(format!("{}/source", bucket))
+		.tagging_directive(TaggingDirective::Replace)
+		.tagging("tag=value")
+		.send()
+		.await
+		.unwrap_err();
+	assert_eq!(err.raw_response().unwrap().status().as_u16(), 501);
+
+	// Invalid values of the directives are refused
+	for (metadata_directive, tagging_directive) in [
+		(MetadataDirective::from("MOVE"), TaggingDirective::Copy),
+		(MetadataDirective::Copy, TaggingDirective::from("MOVE")),
+	] {
+		let err = ctx
+			.client
+			.copy_object()
+			.bucket(&bucket)
+			.key("dest-invalid")
+			.copy_source(format!("{}/source", bucket))
+			.metadata_directive(metadata_directive)
+			.tagging_directive(tagging_directive)
+			.send()
+			.await
+			.unwrap_err();
+		assert_eq!(err.raw_response().unwrap().status().as_u16(), 400);
+	}
+}
+
+#[tokio::test]
+async fn test_copyobject_checksum() {
+	use aws_sdk_s3::types::ChecksumAlgorithm;
+
+	// Base64-encoded checksums of BODY
+	const BODY_CRC32: &str = "1iE63A==";
+	const BODY_SHA256: &str = "pYu6LMVhvdvDBQVjJSjIrsDDZ7hZVVRi9S/kR23E1Ls=";
+
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("copyobjectchecksum");
+
+	ctx.client
+		.put_object()
+		.bucket(&bucket)
+		.key("source")
+		.checksum_crc32(BODY_CRC32)
+		.body(ByteStream::from_static(BODY))
+		.send()
+		.await
+		.unwrap();
+
+	// The data is not rewritten, the checksum of the source is reported
+	let r = ctx
+		.client
+		.copy_object()
+		.bucket(&bucket)
+		.key("copy")
+		.copy_source(format!("{}/source", bucket))
+		.send()
+		.await
+		.unwrap();
+	let result = r.copy_object_result.unwrap();
+	assert_eq!(result.checksum_crc32.as_deref(), Some(BODY_CRC32));
+	assert!(result.checksum_sha256.is_none());
+
+	// The checksum is computed again when another algorithm is requested
+	let r = ctx
+		.client
+		.copy_object()
+		.bucket(&bucket)
+		.key("copy-sha256")
+		.copy_source(format!("{}/source", bucket))
+		.checksum_algorithm(ChecksumAlgorithm::Sha256)
+		.send()
+		.await
+		.unwrap();
+	let result = r.copy_object_result.unwrap();
+	assert_eq!(result.checksum_sha256.as_deref(), Some(BODY_SHA256));
+	assert!(result.checksum_crc32.is_none());
+}
+
+#[tokio::test]
+async fn test_method_not_allowed() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("methodnotallowed");
+
+	for (method, path) in [("PATCH", "a"), ("PROPFIND", "a"), ("PROPFIND", "")] {
+		let res = ctx
+			.custom_request
+			.builder(bucket.clone())
+			.method(Method::from_bytes(method.as_bytes()).unwrap())
+			.path(path.to_owned())
+			.send()
+			.await
+			.unwrap();
+		assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+		assert_eq!(
+			res.headers()["allow"],
+			"GET, HEAD, PUT, POST, DELETE, OPTIONS"
+		);
+		let body = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+		let body = std::str::from_utf8(&body).unwrap();
+		assert!(body.contains("<Code>MethodNotAllowed</Code>"));
+	}
+}
+
+#[tokio::test]
+async fn test_putobject_expiration_ttl() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("putobjectttl");
+
+	for (key, ttl) in [("short", "1"), ("long", "3600")] {
+		let res = ctx
+			.custom_request
+			.builder(bucket.clone())
+			.method(Method::PUT)
+			.path(key.to_owned())
+			.signed_header("x-amz-expiration-ttl", ttl)
+			.body(BODY.to_vec())
+			.send()
+			.await
+			.unwrap();
+		assert_eq!(res.status(), StatusCode::OK);
+		assert!(res.headers().contains_key("x-garage-expires-at"));
+	}
+
+	// An invalid TTL is refused
+	let res = ctx
+		.custom_request
+		.builder(bucket.clone())
+		.method(Method::PUT)
+		.path("invalid".to_owned())
+		.signed_header("x-amz-expiration-ttl", "soon")
+		.body(BODY.to_vec())
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+	// Before its TTL, the object can be read normally
+	let o = ctx
+		.client
+		.get_object()
+		.bucket(&bucket)
+		.key("short")
+		.send()
+		.await
+		.unwrap();
+	assert_bytes_eq!(o.body, BODY);
+
+	tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+
+	// After its TTL, the object is gone
+	let err = ctx
+		.client
+		.get_object()
+		.bucket(&bucket)
+		.key("short")
+		.send()
+		.await
+		.unwrap_err();
+	assert_eq!(err.raw_response().unwrap().status().as_u16(), 404);
+	let err = ctx
+		.client
+		.head_object()
+		.bucket(&bucket)
+		.key("short")
+		.send()
+		.await
+		.unwrap_err();
+	assert_eq!(err.raw_response().unwrap().status().as_u16(), 404);
+
+	// The object with a longer TTL survives
+	let res = ctx
+		.custom_request
+		.builder(bucket.clone())
+		.method(Method::GET)
+		.path("long".to_owned())
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(res.status(), StatusCode::OK);
+	assert!(res.headers().contains_key("x-garage-expires-at"));
+
+	let list = ctx
+		.client
+		.list_objects_v2()
+		.bucket(&bucket)
+		.send()
+		.await
+		.unwrap();
+	let keys = list
+		.contents
+		.unwrap_or_default()
+		.into_iter()
+		.map(|o| o.key.unwrap())
+		.collect::<Vec<_>>();
+	assert_eq!(keys, vec!["long".to_string()]);
+}
+
+#[tokio::test]
+async fn test_getobject_max_staleness() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("getobject-max-staleness");
+
+	let get = |max_staleness: &'static str| {
+		let ctx = &ctx;
+		let bucket = bucket.clone();
+		async move {
+			ctx.custom_request
+				.builder(bucket)
+				.method(Method::GET)
+				.path("staleness".to_owned())
+				.signed_header("x-garage-max-staleness", max_staleness)
+				.send()
+				.await
+				.unwrap()
+		}
+	};
+
+	ctx.client
+		.put_object()
+		.bucket(&bucket)
+		.key("staleness")
+		.body(ByteStream::from_static(b"old"))
+		.send()
+		.await
+		.unwrap();
+	ctx.client
+		.put_object()
+		.bucket(&bucket)
+		.key("staleness")
+		.body(ByteStream::from_static(BODY))
+		.send()
+		.await
+		.unwrap();
+
+	// Whether the version read from a single node is recent enough or the
+	// object is read again from a quorum of nodes, the last version is returned
+	for max_staleness in ["0", "3600"] {
+		let res = get(max_staleness).await;
+		assert_eq!(res.status(), StatusCode::OK);
+		let body = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+		assert_eq!(body.as_ref(), BODY);
+	}
+
+	assert_eq!(get("soon").await.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_getobject_range_block_checksums() {
+	// The test configuration sets `store_block_checksums = true`
+	use base64::prelude::*;
+	use sha1::{Digest, Sha1};
+
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("getobject-block-checksums");
+
+	// Several blocks of 1 MiB, each with different contents
+	let body = (0..3 * 1024 * 1024 + 1000)
+		.map(|i: usize| (i / 1024 + i) as u8)
+		.collect::<Vec<u8>>();
+	let sha1 = BASE64_STANDARD.encode(Sha1::digest(&body));
+
+	ctx.client
+		.put_object()
+		.bucket(&bucket)
+		.key("checksummed")
+		.checksum_sha1(sha1)
+		.body(ByteStream::from(body.clone()))
+		.send()
+		.await
+		.unwrap();
+	ctx.client
+		.put_object()
+		.bucket(&bucket)
+		.key("plain")
+		.body(ByteStream::from(body.clone()))
+		.send()
+		.await
+		.unwrap();
+
+	let get = |key: &'static str, range: String| {
+		let ctx = &ctx;
+		let bucket = bucket.clone();
+		async move {
+			ctx.custom_request
+				.builder(bucket)
+				.method(Method::GET)
+				.path(key.to_owned())
+				.signed_header("range", range)
+				.send()
+				.await
+				.unwrap()
+		}
+	};
+
+	// Ranges within a block, across several blocks, and at the end of the object
+	for (begin, end) in [
+		(10usize, 1000usize),
+		(1000000, 2200000),
+		(3 * 1024 * 1024, body.len() - 1),
+	] {
+		let res = get("checksummed", format!("bytes={}-{}", begin, end)).await;
+		assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+		assert_eq!(
+			res.headers()
+				.get("x-garage-block-checksums")
+				.map(|v| v.to_str().unwrap()),
+			Some("verified")
+		);
+		let data = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+		assert_eq!(&data[..], &body[begin..=end]);
+	}
+
+	// No checksums are stored for objects uploaded without a checksum
+	let res = get("plain", "bytes=1000000-2200000".into()).await;
+	assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+	assert!(res.headers().get("x-garage-block-checksums").is_none());
+	let data = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+	assert_eq!(&data[..], &body[1000000..=2200000]);
+}
@@ -2,6 +2,7 @@ use std::time::{Duration, SystemTime};
 
 use crate::common;
 use aws_sdk_s3::presigning::PresigningConfig;
+use aws_sdk_s3::primitives::ByteStream;
 use bytes::Bytes;
 use http_body_util::{BodyExt, Full};
 use hyper::Request;
@@ -70,3 +71,62 @@ async fn test_presigned_url() {
 		assert_eq!(body, body2);
 	}
 }
+
+#[tokio::test]
+async fn test_presigned_url_response_overrides() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("presigned-overrides");
+
+	ctx.client
+		.put_object()
+		.bucket(&bucket)
+		.key(STD_KEY)
+		.content_type("text/plain")
+		.body(ByteStream::from_static(BODY))
+		.send()
+		.await
+		.unwrap();
+
+	let psc = PresigningConfig::builder()
+		.start_time(SystemTime::now() - Duration::from_secs(60))
+		.expires_in(Duration::from_secs(3600))
+		.build()
+		.unwrap();
+
+	let disposition = "attachment; filename=\"résumé 📄.txt\"";
+	let req = ctx
+		.client
+		.get_object()
+		.bucket(&bucket)
+		.key(STD_KEY)
+		.response_content_disposition(disposition)
+		.response_content_type("application/octet-stream")
+		.response_cache_control("no-store")
+		.presigned(psc)
+		.await
+		.unwrap();
+
+	let client = ctx.custom_request.client();
+	let req = Request::builder()
+		.method("GET")
+		.uri(req.uri())
+		.body(Full::new(Bytes::new()))
+		.unwrap();
+	let res = client.request(req).await.unwrap();
+	assert_eq!(res.status(), 200);
+
+	// Overrides take precedence over stored metadata
+	let headers = res.headers();
+	assert_eq!(
+		headers.get("content-disposition").unwrap().as_bytes(),
+		disposition.as_bytes()
+	);
+	assert_eq!(
+		headers.get("content-type").unwrap(),
+		"application/octet-stream"
+	);
+	assert_eq!(headers.get("cache-control").unwrap(), "no-store");
+
+	let body = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+	assert_eq!(body.as_ref(), BODY);
+}
@@ -0,0 +1,108 @@
+use crate::common;
+use crate::common::ext::*;
+use aws_sdk_s3::primitives::ByteStream;
+
+const SZ_2MB: usize = 2 * 1024 * 1024;
+
+fn set_retention(ctx: &common::Context, bucket: &str, retention: &str) {
+	ctx.garage
+		.command()
+		.args([
+			"bucket",
+			"set-previous-version-retention",
+			bucket,
+			retention,
+		])
+		.quiet()
+		.expect_success_status("Could not set previous version retention");
+}
+
+fn restore(ctx: &common::Context, bucket: &str, key: &str) -> bool {
+	ctx.garage
+		.command()
+		.args(["bucket", "restore-previous-version", bucket, key])
+		.quiet()
+		.status()
+		.unwrap()
+		.success()
+}
+
+async fn put(ctx: &common::Context, bucket: &str, key: &str, body: Vec<u8>) {
+	ctx.client
+		.put_object()
+		.bucket(bucket)
+		.key(key)
+		.body(ByteStream::from(body))
+		.send()
+		.await
+		.unwrap();
+}
+
+async fn get(ctx: &common::Context, bucket: &str, key: &str) -> Vec<u8> {
+	let res = ctx
+		.client
+		.get_object()
+		.bucket(bucket)
+		.key(key)
+		.send()
+		.await
+		.unwrap();
+	res.body.collect().await.unwrap().into_bytes().to_vec()
+}
+
+#[tokio::test]
+async fn test_overwrite_then_restore_previous() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("prevversionrestore");
+	set_retention(&ctx, &bucket, "1h");
+
+	// Inline objects and objects stored in data blocks
+	for (key, len) in [("small", 16), ("large", SZ_2MB)] {
+		let v1 = vec![0x61; len];
+		let v2 = vec![0x62; len];
+
+		// Nothing to restore before the first overwrite
+		put(&ctx, &bucket, key, v1.clone()).await;
+		assert!(!restore(&ctx, &bucket, key));
+
+		put(&ctx, &bucket, key, v2.clone()).await;
+		assert_eq!(get(&ctx, &bucket, key).await, v2);
+
+		assert!(restore(&ctx, &bucket, key));
+		assert_eq!(get(&ctx, &bucket, key).await, v1);
+
+		// The replaced version can be restored in turn
+		assert!(restore(&ctx, &bucket, key));
+		assert_eq!(get(&ctx, &bucket, key).await, v2);
+	}
+
+	// Only the last overwrite is kept
+	put(&ctx, &bucket, "multi", b"one".to_vec()).await;
+	put(&ctx, &bucket, "multi", b"two".to_vec()).await;
+	put(&ctx, &bucket, "multi", b"three".to_vec()).await;
+	assert!(restore(&ctx, &bucket, "multi"));
+	assert_eq!(get(&ctx, &bucket, "multi").await, b"two");
+
+	// Previous versions are not kept when retention is disabled
+	set_retention(&ctx, &bucket, "none");
+	put(&ctx, &bucket, "disabled", b"one".to_vec()).await;
+	put(&ctx, &bucket, "disabled", b"two".to_vec()).await;
+	assert!(!restore(&ctx, &bucket, "disabled"));
+}
+
+#[tokio::test]
+async fn test_overwrite_then_expire() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("prevversionexpire");
+	set_retention(&ctx, &bucket, "1s");
+
+	put(&ctx, &bucket, "a", vec![0x61; SZ_2MB]).await;
+	put(&ctx, &bucket, "a", vec![0x62; SZ_2MB]).await;
+
+	tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+
+	// The retention period is over: the previous version cannot be
+	// restored anymore, and will be removed by the lifecycle worker
+	assert!(!restore(&ctx, &bucket, "a"));
+	assert_eq!(get(&ctx, &bucket, "a").await, vec![0x62; SZ_2MB]);
+}
@@ -0,0 +1,127 @@
+use crate::common;
+use crate::common::ext::*;
+use aws_sdk_s3::{
+	primitives::ByteStream,
+	types::{IndexDocument, WebsiteConfiguration},
+};
+use http::{HeaderValue, Request, StatusCode};
+use http_body_util::BodyExt;
+use http_body_util::Full as FullBody;
+use hyper::body::Bytes;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+
+const BODY: &[u8; 16] = b"<h1>bonjour</h1>";
+
+// The canonical form of "bücher"
+const PUNYCODE_NAME: &str = "xn--bcher-kva";
+
+#[tokio::test]
+async fn test_unicode_bucket_name() {
+	let ctx = common::context();
+
+	// The bucket is created with its unicode name, and stored in punycode
+	ctx.create_bucket("bücher");
+	let out = ctx
+		.garage
+		.command()
+		.args(["bucket", "info", "bücher"])
+		.expect_success_output("Could not get bucket info");
+	assert!(String::from_utf8(out.stdout)
+		.unwrap()
+		.contains(PUNYCODE_NAME));
+
+	ctx.client
+		.put_object()
+		.bucket(PUNYCODE_NAME)
+		.key("index.html")
+		.body(ByteStream::from_static(BODY))
+		.send()
+		.await
+		.unwrap();
+
+	// The bucket can be reached with path-style requests, with its name in
+	// unicode (percent-encoded) or in punycode, and with vhost-style requests,
+	// with a unicode or a punycode Host header
+	for (bucket, vhost_style) in [
+		("b%C3%BCcher", false),
+		(PUNYCODE_NAME, false),
+		("bücher", true),
+		(PUNYCODE_NAME, true),
+	] {
+		let res = ctx
+			.custom_request
+			.builder(bucket.to_string())
+			.path("index.html")
+			.vhost_style(vhost_style)
+			.send()
+			.await
+			.unwrap();
+		assert_eq!(
+			res.status(),
+			StatusCode::OK,
+			"bucket {}, vhost-style {}",
+			bucket,
+			vhost_style
+		);
+		let body = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+		assert_eq!(&body[..], BODY);
+	}
+
+	// Same for the website endpoint
+	ctx.client
+		.put_bucket_website()
+		.bucket(PUNYCODE_NAME)
+		.website_configuration(
+			WebsiteConfiguration::builder()
+				.index_document(
+					IndexDocument::builder()
+						.suffix("index.html")
+						.build()
+						.unwrap(),
+				)
+				.build(),
+		)
+		.send()
+		.await
+		.unwrap();
+
+	let client = Client::builder(TokioExecutor::new()).build_http();
+	for host in ["bücher.web.garage", "xn--bcher-kva.web.garage"] {
+		let req = Request::builder()
+			.method("GET")
+			.uri(format!("http://127.0.0.1:{}/", ctx.garage.web_port))
+			.header("Host", HeaderValue::from_bytes(host.as_bytes()).unwrap())
+			.body(FullBody::new(Bytes::new()))
+			.unwrap();
+		let resp = client.request(req).await.unwrap();
+		assert_eq!(resp.status(), StatusCode::OK, "host {}", host);
+		assert_eq!(
+			BodyExt::collect(resp.into_body()).await.unwrap().to_bytes(),
+			BODY.as_ref()
+		);
+	}
+}
+
+#[tokio::test]
+async fn test_invalid_punycode_bucket_name() {
+	let ctx = common::context();
+
+	ctx.garage
+		.command()
+		.args(["key", "allow"])
+		.args(["--create-bucket", &ctx.key.id])
+		.quiet()
+		.expect_success_output("Could not allow key to create buckets");
+
+	// Punycode names that are not the canonical form of a unicode name
+	// are refused
+	let res = ctx
+		.custom_request
+		.builder("xn--abc-".to_string())
+		.method(http::Method::PUT)
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
@@ -0,0 +1,242 @@
+use crate::common;
+use crate::common::ext::*;
+use aws_sdk_s3::error::SdkError;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{
+	CompletedMultipartUpload, CompletedPart, CorsConfiguration, CorsRule, Delete, IndexDocument,
+	ObjectIdentifier, WebsiteConfiguration,
+};
+
+const BODY: &[u8; 62] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+fn assert_forbidden<T: std::fmt::Debug, E: std::fmt::Debug>(res: Result<T, SdkError<E>>) {
+	let err = res.unwrap_err();
+	assert_eq!(err.raw_response().unwrap().status().as_u16(), 403);
+}
+
+fn set_read_only(ctx: &common::Context, bucket: &str, flag: &str) {
+	ctx.garage
+		.command()
+		.args(["bucket", "set-read-only", flag])
+		.arg(bucket)
+		.quiet()
+		.expect_success_status("Could not change read-only flag of bucket");
+}
+
+#[tokio::test]
+async fn test_read_only_bucket() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("readonly");
+
+	ctx.client
+		.put_object()
+		.bucket(&bucket)
+		.key("a")
+		.body(ByteStream::from_static(BODY))
+		.send()
+		.await
+		.unwrap();
+
+	let up = ctx
+		.client
+		.create_multipart_upload()
+		.bucket(&bucket)
+		.key("mpu")
+		.send()
+		.await
+		.unwrap();
+	let uid = up.upload_id.unwrap();
+	let part = ctx
+		.client
+		.upload_part()
+		.bucket(&bucket)
+		.key("mpu")
+		.upload_id(&uid)
+		.part_number(1)
+		.body(ByteStream::from_static(BODY))
+		.send()
+		.await
+		.unwrap();
+
+	set_read_only(&ctx, &bucket, "--enable");
+
+	// Reads and listings still work
+	ctx.client
+		.get_object()
+		.bucket(&bucket)
+		.key("a")
+		.send()
+		.await
+		.unwrap();
+	let l = ctx
+		.client
+		.list_objects_v2()
+		.bucket(&bucket)
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(l.contents.unwrap().len(), 1);
+
+	// All mutating endpoints are rejected
+	assert_forbidden(
+		ctx.client
+			.put_object()
+			.bucket(&bucket)
+			.key("b")
+			.body(ByteStream::from_static(BODY))
+			.send()
+			.await,
+	);
+	assert_forbidden(
+		ctx.client
+			.copy_object()
+			.bucket(&bucket)
+			.key("b")
+			.copy_source(format!("{}/a", bucket))
+			.send()
+			.await,
+	);
+	assert_forbidden(
+		ctx.client
+			.delete_object()
+			.bucket(&bucket)
+			.key("a")
+			.send()
+			.await,
+	);
+	assert_forbidden(
+		ctx.client
+			.delete_objects()
+			.bucket(&bucket)
+			.delete(
+				Delete::builder()
+					.objects(ObjectIdentifier::builder().key("a").build().unwrap())
+					.build()
+					.unwrap(),
+			)
+			.send()
+			.await,
+	);
+	assert_forbidden(
+		ctx.client
+			.create_multipart_upload()
+			.bucket(&bucket)
+			.key("mpu2")
+			.send()
+			.await,
+	);
+	assert_forbidden(
+		ctx.client
+			.upload_part()
+			.bucket(&bucket)
+			.key("mpu")
+			.upload_id(&uid)
+			.part_number(2)
+			.body(ByteStream::from_static(BODY))
+			.send()
+			.await,
+	);
+	assert_forbidden(
+		ctx.client
+			.complete_multipart_upload()
+			.bucket(&bucket)
+			.key("mpu")
+			.upload_id(&uid)
+			.multipart_upload(
+				CompletedMultipartUpload::builder()
+					.parts(
+						CompletedPart::builder()
+							.part_number(1)
+							.e_tag(part.e_tag.clone().unwrap())
+							.build(),
+					)
+					.build(),
+			)
+			.send()
+			.await,
+	);
+	assert_forbidden(
+		ctx.client
+			.abort_multipart_upload()
+			.bucket(&bucket)
+			.key("mpu")
+			.upload_id(&uid)
+			.send()
+			.await,
+	);
+	assert_forbidden(
+		ctx.client
+			.put_bucket_cors()
+			.bucket(&bucket)
+			.cors_configuration(
+				CorsConfiguration::builder()
+					.cors_rules(
+						CorsRule::builder()
+							.allowed_methods("GET")
+							.allowed_origins("*")
+							.build()
+							.unwrap(),
+					)
+					.build()
+					.unwrap(),
+			)
+			.send()
+			.await,
+	);
+	assert_forbidden(ctx.client.delete_bucket_cors().bucket(&bucket).send().await);
+	assert_forbidden(
+		ctx.client
+			.put_bucket_website()
+			.bucket(&bucket)
+			.website_configuration(
+				WebsiteConfiguration::builder()
+					.index_document(
+						IndexDocument::builder()
+							.suffix("index.html")
+							.build()
+							.unwrap(),
+					)
+					.build(),
+			)
+			.send()
+			.await,
+	);
+	assert_forbidden(
+		ctx.client
+			.delete_bucket_website()
+			.bucket(&bucket)
+			.send()
+			.await,
+	);
+	assert_forbidden(ctx.client.delete_bucket().bucket(&bucket).send().await);
+
+	// Once the flag is removed, writes are accepted again
+	set_read_only(&ctx, &bucket, "--disable");
+
+	ctx.client
+		.put_object()
+		.bucket(&bucket)
+		.key("b")
+		.body(ByteStream::from_static(BODY))
+		.send()
+		.await
+		.unwrap();
+	ctx.client
+		.complete_multipart_upload()
+		.bucket(&bucket)
+		.key("mpu")
+		.upload_id(&uid)
+		.multipart_upload(
+			CompletedMultipartUpload::builder()
+				.parts(
+					CompletedPart::builder()
+						.part_number(1)
+						.e_tag(part.e_tag.unwrap())
+						.build(),
+				)
+				.build(),
+		)
+		.send()
+		.await
+		.unwrap();
+}
@@ -0,0 +1,208 @@
+use crate::common;
+use crate::common::ext::*;
+use aws_sdk_s3::primitives::ByteStream;
+use http::{Request, StatusCode};
+use http_body_util::BodyExt;
+use http_body_util::Full as FullBody;
+use hyper::body::Bytes;
+use hyper::Method;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+
+const BCKT_NAME: &str = "redirects";
+const BODY: &[u8] = b"moved here";
+
+async fn put_redirect(
+	ctx: &common::Context,
+	bucket: &str,
+	key: &str,
+	target: &str,
+	code: Option<&str>,
+	body: &[u8],
+) -> StatusCode {
+	let mut req = ctx.custom_request.builder(bucket.to_owned());
+	req.method(Method::PUT)
+		.path(key.to_owned())
+		.signed_header("x-garage-redirect-key", target)
+		.body(body.to_vec());
+	if let Some(code) = code {
+		req.signed_header("x-garage-redirect-code", code);
+	}
+	req.send().await.unwrap().status()
+}
+
+#[tokio::test]
+async fn test_redirect_object() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket(BCKT_NAME);
+
+	ctx.client
+		.put_object()
+		.bucket(&bucket)
+		.key("new/data.bin")
+		.body(ByteStream::from_static(BODY))
+		.send()
+		.await
+		.unwrap();
+
+	assert_eq!(
+		put_redirect(&ctx, &bucket, "old/data.bin", "new/data.bin", None, b"").await,
+		StatusCode::OK
+	);
+	assert_eq!(
+		put_redirect(&ctx, &bucket, "tmp", "new/data.bin", Some("302"), b"").await,
+		StatusCode::OK
+	);
+
+	// Redirect objects have no content, and can only redirect with 301 or 302
+	assert_eq!(
+		put_redirect(&ctx, &bucket, "bad", "new/data.bin", None, b"data").await,
+		StatusCode::BAD_REQUEST
+	);
+	assert_eq!(
+		put_redirect(&ctx, &bucket, "bad", "new/data.bin", Some("303"), b"").await,
+		StatusCode::BAD_REQUEST
+	);
+	assert_eq!(
+		put_redirect(&ctx, &bucket, "bad", "../data.bin", None, b"").await,
+		StatusCode::BAD_REQUEST
+	);
+
+	for method in [Method::GET, Method::HEAD] {
+		// The location is relative to the URL of the redirect object,
+		// so that it is the same for path-style and vhost-style requests
+		for vhost_style in [false, true] {
+			let res = ctx
+				.custom_request
+				.builder(bucket.clone())
+				.method(method.clone())
+				.path("old/data.bin")
+				.vhost_style(vhost_style)
+				.send()
+				.await
+				.unwrap();
+			assert_eq!(res.status(), StatusCode::MOVED_PERMANENTLY);
+			assert_eq!(res.headers()["location"], "../new/data.bin");
+			assert_eq!(res.headers()["x-garage-redirect-key"], "new/data.bin");
+			assert_eq!(res.headers()["content-length"], "0");
+			assert!(res.headers().contains_key("etag"));
+			let body = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+			assert!(body.is_empty());
+		}
+
+		let res = ctx
+			.custom_request
+			.builder(bucket.clone())
+			.method(method.clone())
+			.path("tmp")
+			.send()
+			.await
+			.unwrap();
+		assert_eq!(res.status(), StatusCode::FOUND);
+		assert_eq!(res.headers()["location"], "./new/data.bin");
+		assert_eq!(res.headers()["x-garage-redirect-code"], "302");
+	}
+
+	// The target itself is read as usual
+	let o = ctx
+		.client
+		.get_object()
+		.bucket(&bucket)
+		.key("new/data.bin")
+		.send()
+		.await
+		.unwrap();
+	assert_bytes_eq!(o.body, BODY);
+
+	// Overwriting a redirect object with a regular object removes the redirect
+	ctx.client
+		.put_object()
+		.bucket(&bucket)
+		.key("tmp")
+		.body(ByteStream::from_static(BODY))
+		.send()
+		.await
+		.unwrap();
+	let o = ctx
+		.client
+		.get_object()
+		.bucket(&bucket)
+		.key("tmp")
+		.send()
+		.await
+		.unwrap();
+	assert_bytes_eq!(o.body, BODY);
+}
+
+#[tokio::test]
+async fn test_redirect_object_website() {
+	const BCKT_NAME: &str = "redirects-website";
+	let ctx = common::context();
+	let bucket = ctx.create_bucket(BCKT_NAME);
+
+	ctx.garage
+		.command()
+		.args(["bucket", "website", "--allow", BCKT_NAME])
+		.quiet()
+		.expect_success_status("Could not allow website on bucket");
+
+	ctx.client
+		.put_object()
+		.bucket(&bucket)
+		.key("new/page.html")
+		.body(ByteStream::from_static(BODY))
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(
+		put_redirect(&ctx, &bucket, "old/page.html", "new/page.html", None, b"").await,
+		StatusCode::OK
+	);
+	assert_eq!(
+		put_redirect(
+			&ctx,
+			&bucket,
+			"moved/index.html",
+			"new/page.html",
+			Some("302"),
+			b""
+		)
+		.await,
+		StatusCode::OK
+	);
+
+	let client = Client::builder(TokioExecutor::new()).build_http();
+	let request = |method: Method, path: &str| {
+		Request::builder()
+			.method(method)
+			.uri(format!("http://127.0.0.1:{}{}", ctx.garage.web_port, path))
+			.header("Host", format!("{}.web.garage", BCKT_NAME))
+			.body(FullBody::new(Bytes::new()))
+			.unwrap()
+	};
+
+	for method in [Method::GET, Method::HEAD] {
+		let res = client
+			.request(request(method.clone(), "/old/page.html"))
+			.await
+			.unwrap();
+		assert_eq!(res.status(), StatusCode::MOVED_PERMANENTLY);
+		assert_eq!(res.headers()["location"], "/new/page.html");
+
+		// Index documents can be redirect objects too
+		let res = client
+			.request(request(method.clone(), "/moved/"))
+			.await
+			.unwrap();
+		assert_eq!(res.status(), StatusCode::FOUND);
+		assert_eq!(res.headers()["location"], "/new/page.html");
+	}
+
+	let res = client
+		.request(request(Method::GET, "/new/page.html"))
+		.await
+		.unwrap();
+	assert_eq!(res.status(), StatusCode::OK);
+	let body = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+	assert_eq!(&body[..], BODY);
+}
@@ -0,0 +1,152 @@
+use crate::common;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{Payer, RequestCharged, RequestPaymentConfiguration};
+use http_body_util::BodyExt;
+
+const BODY: &[u8; 62] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+async fn check_request_charged(ctx: &common::Context, bucket: &str, charged: bool) {
+	let expected = charged.then_some(&RequestCharged::Requester);
+
+	let r = ctx
+		.client
+		.put_object()
+		.bucket(bucket)
+		.key("a")
+		.body(ByteStream::from_static(BODY))
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(r.request_charged(), expected);
+
+	let r = ctx
+		.client
+		.head_object()
+		.bucket(bucket)
+		.key("a")
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(r.request_charged(), expected);
+
+	let r = ctx
+		.client
+		.get_object()
+		.bucket(bucket)
+		.key("a")
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(r.request_charged(), expected);
+	assert_bytes_eq!(r.body, BODY);
+
+	let r = ctx
+		.client
+		.copy_object()
+		.bucket(bucket)
+		.key("b")
+		.copy_source(format!("{}/a", bucket))
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(r.request_charged(), expected);
+
+	let r = ctx
+		.client
+		.list_objects_v2()
+		.bucket(bucket)
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(r.request_charged(), expected);
+	assert_eq!(r.contents().len(), 2);
+
+	for key in ["a", "b"] {
+		let r = ctx
+			.client
+			.delete_object()
+			.bucket(bucket)
+			.key(key)
+			.send()
+			.await
+			.unwrap();
+		assert_eq!(r.request_charged(), expected);
+	}
+}
+
+#[tokio::test]
+async fn test_requester_pays() {
+	let ctx = common::context();
+	let rp_bucket = ctx.create_bucket("requesterpays");
+	let normal_bucket = ctx.create_bucket("requesterpays-normal");
+
+	// Buckets are not Requester Pays by default
+	let r = ctx
+		.client
+		.get_bucket_request_payment()
+		.bucket(&rp_bucket)
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(r.payer(), Some(&Payer::BucketOwner));
+
+	ctx.client
+		.put_bucket_request_payment()
+		.bucket(&rp_bucket)
+		.request_payment_configuration(
+			RequestPaymentConfiguration::builder()
+				.payer(Payer::Requester)
+				.build()
+				.unwrap(),
+		)
+		.send()
+		.await
+		.unwrap();
+
+	let r = ctx
+		.client
+		.get_bucket_request_payment()
+		.bucket(&rp_bucket)
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(r.payer(), Some(&Payer::Requester));
+
+	check_request_charged(&ctx, &rp_bucket, true).await;
+	check_request_charged(&ctx, &normal_bucket, false).await;
+
+	// ListBuckets indicates which buckets are Requester Pays
+	let res = ctx
+		.custom_request
+		.builder(String::new())
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(res.status(), 200);
+	let body = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+	let body = std::str::from_utf8(&body).unwrap();
+	let entry = |name: &str| {
+		body.split("<Bucket>")
+			.find(|b| b.contains(&format!("<Name>{}</Name>", name)))
+			.and_then(|b| b.split("</Bucket>").next())
+			.unwrap()
+			.to_string()
+	};
+	assert!(entry(&rp_bucket).contains("<Payer>Requester</Payer>"));
+	assert!(!entry(&normal_bucket).contains("<Payer>"));
+
+	// Switching back to BucketOwner removes the header
+	ctx.client
+		.put_bucket_request_payment()
+		.bucket(&rp_bucket)
+		.request_payment_configuration(
+			RequestPaymentConfiguration::builder()
+				.payer(Payer::BucketOwner)
+				.build()
+				.unwrap(),
+		)
+		.send()
+		.await
+		.unwrap();
+	check_request_charged(&ctx, &rp_bucket, false).await;
+}
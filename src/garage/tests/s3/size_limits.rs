@@ -0,0 +1,388 @@
+use crate::common;
+use aws_sdk_s3::error::ProvideErrorMetadata;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{
+	BucketLifecycleConfiguration, CompletedMultipartUpload, CompletedPart, CorsConfiguration,
+	CorsRule, ExpirationStatus, LifecycleExpiration, LifecycleRule, LifecycleRuleFilter,
+};
+use hyper::Method;
+
+// Limits set in the configuration of the test instance
+const MAX_OBJECT_SIZE: usize = 24 * 1024 * 1024;
+const MAX_PART_SIZE: usize = 16 * 1024 * 1024;
+const MAX_CORS_RULES: usize = 4;
+const MAX_LIFECYCLE_RULES: usize = 4;
+
+#[tokio::test]
+async fn test_put_object_size_limit() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("sizelimitput");
+
+	ctx.client
+		.put_object()
+		.bucket(&bucket)
+		.key("a")
+		.body(ByteStream::from_static(b"initial content"))
+		.send()
+		.await
+		.unwrap();
+
+	// One byte too many: the upload is interrupted and the previous
+	// content of the object is kept
+	let err = ctx
+		.client
+		.put_object()
+		.bucket(&bucket)
+		.key("a")
+		.body(ByteStream::from(vec![0x61; MAX_OBJECT_SIZE + 1]))
+		.send()
+		.await
+		.unwrap_err();
+	if let Some(resp) = err.raw_response() {
+		assert_eq!(resp.status().as_u16(), 400);
+		assert_eq!(err.code(), Some("EntityTooLarge"));
+	}
+
+	let r = ctx
+		.client
+		.get_object()
+		.bucket(&bucket)
+		.key("a")
+		.send()
+		.await
+		.unwrap();
+	let data = r.body.collect().await.unwrap().into_bytes();
+	assert_eq!(&data[..], b"initial content");
+
+	let r = ctx
+		.client
+		.list_objects_v2()
+		.bucket(&bucket)
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(r.contents().len(), 1);
+	assert_eq!(r.contents()[0].size(), Some(15));
+
+	// Exactly at the limit
+	ctx.client
+		.put_object()
+		.bucket(&bucket)
+		.key("a")
+		.body(ByteStream::from(vec![0x62; MAX_OBJECT_SIZE]))
+		.send()
+		.await
+		.unwrap();
+	let r = ctx
+		.client
+		.head_object()
+		.bucket(&bucket)
+		.key("a")
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(r.content_length(), Some(MAX_OBJECT_SIZE as i64));
+}
+
+#[tokio::test]
+async fn test_multipart_size_limits() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("sizelimitmpu");
+
+	let up = ctx
+		.client
+		.create_multipart_upload()
+		.bucket(&bucket)
+		.key("a")
+		.send()
+		.await
+		.unwrap();
+	let uid = up.upload_id.unwrap();
+
+	// A part that is too large is rejected and not listed
+	let err = ctx
+		.client
+		.upload_part()
+		.bucket(&bucket)
+		.key("a")
+		.upload_id(&uid)
+		.part_number(1)
+		.body(ByteStream::from(vec![0x61; MAX_PART_SIZE + 1]))
+		.send()
+		.await
+		.unwrap_err();
+	if let Some(resp) = err.raw_response() {
+		assert_eq!(resp.status().as_u16(), 400);
+		assert_eq!(err.code(), Some("EntityTooLarge"));
+	}
+
+	let r = ctx
+		.client
+		.list_parts()
+		.bucket(&bucket)
+		.key("a")
+		.upload_id(&uid)
+		.send()
+		.await
+		.unwrap();
+	assert!(r.parts().is_empty());
+
+	// Parts at the limit are accepted, but together they exceed
+	// the maximum size of an object
+	let mut parts = vec![];
+	for part_number in [1, 2] {
+		let p = ctx
+			.client
+			.upload_part()
+			.bucket(&bucket)
+			.key("a")
+			.upload_id(&uid)
+			.part_number(part_number)
+			.body(ByteStream::from(vec![0x62; MAX_PART_SIZE]))
+			.send()
+			.await
+			.unwrap();
+		parts.push(
+			CompletedPart::builder()
+				.part_number(part_number)
+				.e_tag(p.e_tag.unwrap())
+				.build(),
+		);
+	}
+
+	let err = ctx
+		.client
+		.complete_multipart_upload()
+		.bucket(&bucket)
+		.key("a")
+		.upload_id(&uid)
+		.multipart_upload(
+			CompletedMultipartUpload::builder()
+				.set_parts(Some(parts))
+				.build(),
+		)
+		.send()
+		.await
+		.unwrap_err();
+	assert_eq!(err.raw_response().unwrap().status().as_u16(), 400);
+	assert_eq!(err.code(), Some("EntityTooLarge"));
+
+	// The upload has been aborted
+	let r = ctx
+		.client
+		.list_multipart_uploads()
+		.bucket(&bucket)
+		.send()
+		.await
+		.unwrap();
+	assert!(r.uploads().is_empty());
+	assert!(ctx
+		.client
+		.head_object()
+		.bucket(&bucket)
+		.key("a")
+		.send()
+		.await
+		.is_err());
+}
+
+#[tokio::test]
+async fn test_head_bucket_size_limits() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("sizelimithead");
+
+	let res = ctx
+		.custom_request
+		.builder(bucket)
+		.method(Method::HEAD)
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(res.status(), 200);
+	assert_eq!(
+		res.headers().get("x-garage-max-object-size").unwrap(),
+		&MAX_OBJECT_SIZE.to_string()
+	);
+	assert_eq!(
+		res.headers().get("x-garage-max-part-size").unwrap(),
+		&MAX_PART_SIZE.to_string()
+	);
+}
+
+#[tokio::test]
+async fn test_put_cors_rules_limit() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("corsruleslimit");
+
+	let cors = |n: usize| {
+		let mut conf = CorsConfiguration::builder();
+		for i in 0..n {
+			conf = conf.cors_rules(
+				CorsRule::builder()
+					.id(format!("rule-{}", i))
+					.allowed_methods("GET")
+					.allowed_origins(format!("https://{}.example.com", i))
+					.build()
+					.unwrap(),
+			);
+		}
+		conf.build().unwrap()
+	};
+
+	ctx.client
+		.put_bucket_cors()
+		.bucket(&bucket)
+		.cors_configuration(cors(MAX_CORS_RULES))
+		.send()
+		.await
+		.unwrap();
+
+	let err = ctx
+		.client
+		.put_bucket_cors()
+		.bucket(&bucket)
+		.cors_configuration(cors(MAX_CORS_RULES + 1))
+		.send()
+		.await
+		.unwrap_err();
+	assert_eq!(err.raw_response().unwrap().status().as_u16(), 400);
+	assert_eq!(err.code(), Some("TooManyConfigurations"));
+
+	// The previous configuration is kept
+	let r = ctx
+		.client
+		.get_bucket_cors()
+		.bucket(&bucket)
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(r.cors_rules().len(), MAX_CORS_RULES);
+}
+
+#[tokio::test]
+async fn test_put_lifecycle_rules_limit() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("lifecycleruleslimit");
+
+	let lifecycle = |n: usize| {
+		let mut conf = BucketLifecycleConfiguration::builder();
+		for i in 0..n {
+			conf = conf.rules(
+				LifecycleRule::builder()
+					.id(format!("rule-{}", i))
+					.filter(LifecycleRuleFilter::Prefix(format!("prefix-{}/", i)))
+					.status(ExpirationStatus::Enabled)
+					.expiration(LifecycleExpiration::builder().days(30).build())
+					.build()
+					.unwrap(),
+			);
+		}
+		conf.build().unwrap()
+	};
+
+	ctx.client
+		.put_bucket_lifecycle_configuration()
+		.bucket(&bucket)
+		.lifecycle_configuration(lifecycle(MAX_LIFECYCLE_RULES))
+		.send()
+		.await
+		.unwrap();
+
+	let err = ctx
+		.client
+		.put_bucket_lifecycle_configuration()
+		.bucket(&bucket)
+		.lifecycle_configuration(lifecycle(MAX_LIFECYCLE_RULES + 1))
+		.send()
+		.await
+		.unwrap_err();
+	assert_eq!(err.raw_response().unwrap().status().as_u16(), 400);
+	assert_eq!(err.code(), Some("TooManyConfigurations"));
+
+	let r = ctx
+		.client
+		.get_bucket_lifecycle_configuration()
+		.bucket(&bucket)
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(r.rules().len(), MAX_LIFECYCLE_RULES);
+}
+
+#[tokio::test]
+async fn test_metadata_size_limit() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("sizelimitmetadata");
+
+	// The size of the key "data" and of the value count towards the 2KB limit
+	let metadata = |size: usize| ("data".to_string(), "a".repeat(size - "data".len()));
+
+	let (k, v) = metadata(2048);
+	ctx.client
+		.put_object()
+		.bucket(&bucket)
+		.key("a")
+		.metadata(k, v.clone())
+		.body(ByteStream::from_static(b"content"))
+		.send()
+		.await
+		.unwrap();
+	let r = ctx
+		.client
+		.head_object()
+		.bucket(&bucket)
+		.key("a")
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(r.metadata().unwrap()["data"], v);
+
+	let (k, v) = metadata(2049);
+	let err = ctx
+		.client
+		.put_object()
+		.bucket(&bucket)
+		.key("b")
+		.metadata(k.clone(), v.clone())
+		.body(ByteStream::from_static(b"content"))
+		.send()
+		.await
+		.unwrap_err();
+	assert_eq!(err.raw_response().unwrap().status().as_u16(), 400);
+	assert_eq!(err.code(), Some("MetadataTooLarge"));
+
+	// Same when replacing the metadata in a copy,
+	// or when creating a multipart upload
+	let err = ctx
+		.client
+		.copy_object()
+		.bucket(&bucket)
+		.key("b")
+		.copy_source(format!("{}/a", bucket))
+		.metadata_directive(aws_sdk_s3::types::MetadataDirective::Replace)
+		.metadata(k.clone(), v.clone())
+		.send()
+		.await
+		.unwrap_err();
+	assert_eq!(err.code(), Some("MetadataTooLarge"));
+
+	let err = ctx
+		.client
+		.create_multipart_upload()
+		.bucket(&bucket)
+		.key("b")
+		.metadata(k, v)
+		.send()
+		.await
+		.unwrap_err();
+	assert_eq!(err.code(), Some("MetadataTooLarge"));
+
+	// Nothing was stored
+	ctx.client
+		.head_object()
+		.bucket(&bucket)
+		.key("b")
+		.send()
+		.await
+		.unwrap_err();
+}
@@ -399,6 +399,81 @@ async fn test_multipart_upload() {
 	}
 }
 
+#[tokio::test]
+async fn test_ssec_range() {
+	let ctx = common::context();
+	let bucket = ctx.create_bucket("sse-c-range");
+
+	// Inline object, and object that spans three data blocks
+	let small = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz".to_vec();
+	let large = (0..(SZ_2MB + 500000))
+		.map(|x| ((x * 3792 + x / 1000) % 256) as u8)
+		.collect::<Vec<u8>>();
+
+	for (key, data) in [("small", &small), ("large", &large)] {
+		ctx.client
+			.put_object()
+			.bucket(&bucket)
+			.key(key)
+			.sse_customer_algorithm("AES256")
+			.sse_customer_key(SSEC_KEY)
+			.sse_customer_key_md5(SSEC_KEY_MD5)
+			.body(ByteStream::from(data.clone()))
+			.send()
+			.await
+			.unwrap();
+	}
+
+	let len = large.len();
+	let cases = [
+		// Range within the first block
+		("small", "bytes=3-12", 3, 13),
+		("large", "bytes=1000-5000", 1000, 5001),
+		// Range within a block that is not the first one
+		("large", "bytes=1100000-1200000", 1100000, 1200001),
+		// Range spanning several blocks
+		("large", "bytes=500000-2200000", 500000, 2200001),
+		// Suffix ranges
+		("small", "bytes=-10", small.len() - 10, small.len()),
+		("large", "bytes=-700000", len - 700000, len),
+		// Open-ended range
+		("large", "bytes=2000000-", 2000000, len),
+	];
+
+	for (key, range, begin, end) in cases {
+		let data = if key == "small" { &small } else { &large };
+		let o = ctx
+			.client
+			.get_object()
+			.bucket(&bucket)
+			.key(key)
+			.range(range)
+			.sse_customer_algorithm("AES256")
+			.sse_customer_key(SSEC_KEY)
+			.sse_customer_key_md5(SSEC_KEY_MD5)
+			.send()
+			.await
+			.unwrap();
+		assert_eq!(
+			o.content_range.as_deref(),
+			Some(format!("bytes {}-{}/{}", begin, end - 1, data.len()).as_str())
+		);
+		assert_eq!(o.content_length, Some((end - begin) as i64));
+		assert_bytes_eq!(o.body, &data[begin..end]);
+	}
+
+	// Range reads require the encryption key
+	let o = ctx
+		.client
+		.get_object()
+		.bucket(&bucket)
+		.key("large")
+		.range("bytes=0-100")
+		.send()
+		.await;
+	assert!(o.is_err());
+}
+
 async fn test_read_encrypted(
 	ctx: &Context,
 	bucket: &str,
@@ -4,6 +4,7 @@ use crate::json_body;
 
 use assert_json_diff::assert_json_eq;
 use aws_sdk_s3::{
+	error::ProvideErrorMetadata,
 	primitives::ByteStream,
 	types::{CorsConfiguration, CorsRule, ErrorDocument, IndexDocument, WebsiteConfiguration},
 };
@@ -268,6 +269,7 @@ async fn test_website_s3_api() {
 			resp.headers().get("access-control-allow-origin").unwrap(),
 			"*"
 		);
+		assert_eq!(resp.headers().get("vary").unwrap(), "Origin");
 		assert_eq!(
 			resp.into_body().collect().await.unwrap().to_bytes(),
 			BODY.as_ref()
@@ -313,6 +315,20 @@ async fn test_website_s3_api() {
 			resp.headers().get("access-control-allow-origin").unwrap(),
 			"*"
 		);
+		let vary = resp
+			.headers()
+			.get_all("vary")
+			.iter()
+			.map(|v| v.to_str().unwrap())
+			.collect::<Vec<_>>();
+		assert_eq!(
+			vary,
+			vec![
+				"Origin",
+				"Access-Control-Request-Method",
+				"Access-Control-Request-Headers"
+			]
+		);
 		assert_ne!(
 			resp.into_body().collect().await.unwrap().to_bytes(),
 			BODY.as_ref()
@@ -350,15 +366,15 @@ async fn test_website_s3_api() {
 		.unwrap();
 
 	// Check CORS are deleted from the API
-	// @FIXME check what is the expected behavior when GetBucketCors is called on a bucket without
-	// any CORS.
-	assert!(ctx
+	let err = ctx
 		.client
 		.get_bucket_cors()
 		.bucket(&bucket)
 		.send()
 		.await
-		.is_err());
+		.unwrap_err();
+	assert_eq!(err.raw_response().unwrap().status().as_u16(), 404);
+	assert_eq!(err.code(), Some("NoSuchCORSConfiguration"));
 
 	// Test CORS are not sent anymore on a previously allowed request
 	{
@@ -505,3 +521,213 @@ async fn test_website_check_domain() {
 		})
 	);
 }
+
+#[tokio::test]
+async fn test_website_response_overrides() {
+	const BCKT_NAME: &str = "my-website-overrides";
+	let ctx = common::context();
+	let bucket = ctx.create_bucket(BCKT_NAME);
+
+	ctx.client
+		.put_object()
+		.bucket(&bucket)
+		.key("file.txt")
+		.content_type("text/plain")
+		.body(ByteStream::from_static(BODY))
+		.send()
+		.await
+		.unwrap();
+
+	ctx.garage
+		.command()
+		.args(["bucket", "website", "--allow", BCKT_NAME])
+		.quiet()
+		.expect_success_status("Could not allow website on bucket");
+
+	let client = Client::builder(TokioExecutor::new()).build_http();
+	let req = Request::builder()
+		.method("GET")
+		.uri(format!(
+			"http://127.0.0.1:{}/file.txt?response-content-disposition=attachment%3B%20filename%3D%22r%C3%A9sum%C3%A9.txt%22&response-content-type=text%2Fhtml",
+			ctx.garage.web_port
+		))
+		.header("Host", format!("{}.web.garage", BCKT_NAME))
+		.body(Body::new(Bytes::new()))
+		.unwrap();
+	let resp = client.request(req).await.unwrap();
+	assert_eq!(resp.status(), StatusCode::OK);
+
+	// Content-Disposition can be overridden on public buckets,
+	// but the content type cannot
+	assert_eq!(
+		resp.headers()
+			.get("content-disposition")
+			.unwrap()
+			.as_bytes(),
+		"attachment; filename=\"résumé.txt\"".as_bytes()
+	);
+	assert_eq!(resp.headers().get("content-type").unwrap(), "text/plain");
+	assert_eq!(
+		resp.into_body().collect().await.unwrap().to_bytes(),
+		BODY.as_ref()
+	);
+}
+
+#[tokio::test]
+async fn test_website_precompressed_variants() {
+	// The test configuration has variants with suffix `.br` for `br`
+	// and `.gz` for `gzip`, by order of preference
+	const BCKT_NAME: &str = "my-website-precompressed";
+	let ctx = common::context();
+	let bucket = ctx.create_bucket(BCKT_NAME);
+
+	for (key, body) in [
+		("both.js", "both plain"),
+		("both.js.br", "both br"),
+		("both.js.gz", "both gz"),
+		("gz-only.js", "gz-only plain"),
+		("gz-only.js.gz", "gz-only gz"),
+		("plain.js", "plain only"),
+	] {
+		ctx.client
+			.put_object()
+			.bucket(&bucket)
+			.key(key)
+			.content_type("text/javascript")
+			.body(ByteStream::from_static(body.as_bytes()))
+			.send()
+			.await
+			.unwrap();
+	}
+
+	ctx.garage
+		.command()
+		.args(["bucket", "website", "--allow", BCKT_NAME])
+		.quiet()
+		.expect_success_status("Could not allow website on bucket");
+
+	let client = Client::builder(TokioExecutor::new()).build_http();
+	let fetch = |path: &'static str, accept_encoding: Option<&'static str>| {
+		let client = &client;
+		let ctx = &ctx;
+		async move {
+			let mut req = Request::builder()
+				.method("GET")
+				.uri(format!("http://127.0.0.1:{}/{}", ctx.garage.web_port, path))
+				.header("Host", format!("{}.web.garage", BCKT_NAME));
+			if let Some(ae) = accept_encoding {
+				req = req.header("Accept-Encoding", ae);
+			}
+			let resp = client
+				.request(req.body(Body::new(Bytes::new())).unwrap())
+				.await
+				.unwrap();
+			assert_eq!(resp.status(), StatusCode::OK);
+			assert_eq!(resp.headers().get("vary").unwrap(), "Accept-Encoding");
+			let encoding = resp
+				.headers()
+				.get("content-encoding")
+				.map(|v| v.to_str().unwrap().to_string());
+			let body = resp.into_body().collect().await.unwrap().to_bytes();
+			(encoding, String::from_utf8(body.to_vec()).unwrap())
+		}
+	};
+
+	// br variant available
+	assert_eq!(
+		fetch("both.js", Some("gzip, deflate, br")).await,
+		(Some("br".into()), "both br".into())
+	);
+	// The client's preference is respected
+	assert_eq!(
+		fetch("both.js", Some("br;q=0.5, gzip")).await,
+		(Some("gzip".into()), "both gz".into())
+	);
+	// Fallback to the gzip variant
+	assert_eq!(
+		fetch("gz-only.js", Some("br, gzip")).await,
+		(Some("gzip".into()), "gz-only gz".into())
+	);
+	// No variant
+	assert_eq!(
+		fetch("plain.js", Some("br, gzip")).await,
+		(None, "plain only".into())
+	);
+	// The client does not accept compressed content
+	assert_eq!(fetch("both.js", None).await, (None, "both plain".into()));
+	assert_eq!(
+		fetch("gz-only.js", Some("br")).await,
+		(None, "gz-only plain".into())
+	);
+}
+
+#[tokio::test]
+async fn test_website_if_range() {
+	const BCKT_NAME: &str = "my-website-if-range";
+	let ctx = common::context();
+	let bucket = ctx.create_bucket(BCKT_NAME);
+
+	let etag = ctx
+		.client
+		.put_object()
+		.bucket(&bucket)
+		.key("download.bin")
+		.body(ByteStream::from_static(BODY))
+		.send()
+		.await
+		.unwrap()
+		.e_tag
+		.unwrap();
+
+	ctx.garage
+		.command()
+		.args(["bucket", "website", "--allow", BCKT_NAME])
+		.quiet()
+		.expect_success_status("Could not allow website on bucket");
+
+	let client = Client::builder(TokioExecutor::new()).build_http();
+	let fetch = |if_range: String| {
+		let client = &client;
+		let ctx = &ctx;
+		async move {
+			let req = Request::builder()
+				.method("GET")
+				.uri(format!(
+					"http://127.0.0.1:{}/download.bin",
+					ctx.garage.web_port
+				))
+				.header("Host", format!("{}.web.garage", BCKT_NAME))
+				.header("Range", "bytes=4-7")
+				.header("If-Range", if_range)
+				.body(Body::new(Bytes::new()))
+				.unwrap();
+			let resp = client.request(req).await.unwrap();
+			let status = resp.status();
+			let last_modified = resp.headers().get("last-modified").cloned();
+			let body = resp.into_body().collect().await.unwrap().to_bytes();
+			(status, body, last_modified)
+		}
+	};
+
+	// The ETag matches: the range is returned
+	let (status, body, last_modified) = fetch(etag.clone()).await;
+	assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+	assert_eq!(&body[..], &BODY[4..8]);
+
+	// The Last-Modified date matches: the range is returned
+	let last_modified = last_modified.unwrap().to_str().unwrap().to_string();
+	let (status, body, _) = fetch(last_modified).await;
+	assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+	assert_eq!(&body[..], &BODY[4..8]);
+
+	// The object has changed: the whole object is returned
+	for validator in [
+		"\"0123456789abcdef0123456789abcdef\"".to_string(),
+		"Mon, 01 Jan 2001 00:00:00 GMT".to_string(),
+		format!("W/{}", etag),
+	] {
+		let (status, body, _) = fetch(validator).await;
+		assert_eq!(status, StatusCode::OK);
+		assert_eq!(&body[..], BODY.as_ref());
+	}
+}
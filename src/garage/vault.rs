@@ -0,0 +1,125 @@
+//! Reading secrets from the KV v2 secrets engine of HashiCorp Vault.
+//!
+//! The Vault server and the credentials used to access it are configured using
+//! the same environment variables as the Vault CLI:
+//!
+//! - `VAULT_ADDR`: address of the Vault server, e.g. `https://vault.example.com:8200`
+//! - `VAULT_TOKEN`: token used to authenticate to Vault, or alternatively
+//!   `VAULT_TOKEN_FILE`: path to a file containing the token, such as the sink file
+//!   written by Vault Agent
+//! - `VAULT_CACERT` (optional): path to the PEM-encoded CA certificate used to verify
+//!   the certificate of the Vault server, required when `VAULT_ADDR` uses HTTPS
+//! - `VAULT_NAMESPACE` (optional): Vault Enterprise namespace
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+use garage_util::error::Error;
+
+/// Field of the Vault secret that is read when none is specified
+const DEFAULT_FIELD: &str = "value";
+
+#[derive(Deserialize, Debug)]
+struct KvV2Response {
+	data: KvV2Data,
+}
+
+#[derive(Deserialize, Debug)]
+struct KvV2Data {
+	data: HashMap<String, String>,
+}
+
+/// Read a secret referenced as `<mount>/<path>[#<field>]`
+pub async fn read_vault_secret(reference: &str) -> Result<String, Error> {
+	let (mount, path, field) = parse_reference(reference)?;
+
+	let addr = std::env::var("VAULT_ADDR").map_err(|_| "VAULT_ADDR is not set")?;
+	let token = vault_token()?;
+
+	let mut builder = reqwest::Client::builder().use_rustls_tls();
+	if let Ok(ca_cert) = std::env::var("VAULT_CACERT") {
+		let ca_cert_buf = std::fs::read(&ca_cert)?;
+		let ca_cert = reqwest::Certificate::from_pem(&ca_cert_buf[..])
+			.map_err(|e| format!("invalid VAULT_CACERT: {}", e))?;
+		builder = builder.add_root_certificate(ca_cert);
+	}
+	let client = builder
+		.build()
+		.map_err(|e| format!("unable to build HTTP client: {}", e))?;
+
+	let url = format!("{}/v1/{}/data/{}", addr.trim_end_matches('/'), mount, path);
+	let mut req = client.get(&url).header("X-Vault-Token", token);
+	if let Ok(namespace) = std::env::var("VAULT_NAMESPACE") {
+		req = req.header("X-Vault-Namespace", namespace);
+	}
+
+	let resp = req
+		.send()
+		.await
+		.map_err(|e| format!("request to {} failed: {}", url, e))?;
+	if !resp.status().is_success() {
+		return Err(format!("request to {} failed: {}", url, resp.status()).into());
+	}
+	let mut resp: KvV2Response = resp
+		.json()
+		.await
+		.map_err(|e| format!("invalid response from {}: {}", url, e))?;
+
+	resp.data
+		.data
+		.remove(field)
+		.ok_or_else(|| format!("secret {}/{} has no field `{}`", mount, path, field).into())
+}
+
+fn vault_token() -> Result<String, Error> {
+	if let Ok(token) = std::env::var("VAULT_TOKEN") {
+		return Ok(token);
+	}
+	match std::env::var("VAULT_TOKEN_FILE") {
+		Ok(file) => {
+			let token = std::fs::read_to_string(PathBuf::from(&file))
+				.map_err(|e| format!("unable to read VAULT_TOKEN_FILE {}: {}", file, e))?;
+			Ok(token.trim_end().to_string())
+		}
+		Err(_) => Err("neither VAULT_TOKEN nor VAULT_TOKEN_FILE is set".into()),
+	}
+}
+
+fn parse_reference(reference: &str) -> Result<(&str, &str, &str), Error> {
+	let (path, field) = match reference.split_once('#') {
+		Some((path, field)) => (path, field),
+		None => (reference, DEFAULT_FIELD),
+	};
+	match path.split_once('/') {
+		Some((mount, path)) if !mount.is_empty() && !path.is_empty() && !field.is_empty() => {
+			Ok((mount, path, field))
+		}
+		_ => Err(format!(
+			"invalid Vault secret reference `{}`, expected <mount>/<path>[#<field>]",
+			reference
+		)
+		.into()),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse_reference() {
+		assert_eq!(
+			parse_reference("secret/garage/rpc").unwrap(),
+			("secret", "garage/rpc", "value")
+		);
+		assert_eq!(
+			parse_reference("kv/garage#admin_token").unwrap(),
+			("kv", "garage", "admin_token")
+		);
+		assert!(parse_reference("secret").is_err());
+		assert!(parse_reference("/garage").is_err());
+		assert!(parse_reference("secret/garage#").is_err());
+	}
+}
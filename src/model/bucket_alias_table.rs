@@ -1,3 +1,5 @@
+use std::borrow::Cow;
+
 use garage_util::data::*;
 
 use garage_table::crdt::*;
@@ -23,7 +25,7 @@ pub use v08::*;
 
 impl BucketAlias {
 	pub fn new(name: String, ts: u64, bucket_id: Option<Uuid>) -> Option<Self> {
-		if !is_valid_bucket_name(&name) {
+		if !is_valid_bucket_name(&name, true) {
 			None
 		} else {
 			Some(BucketAlias {
@@ -71,7 +73,23 @@ impl TableSchema for BucketAliasTable {
 	}
 }
 
-/// Check if a bucket name is valid.
+/// Canonical form of a bucket name, in which bucket names are stored and
+/// looked up. Names that contain non-ASCII characters, e.g. names obtained
+/// from a unicode hostname, are converted to their IDNA form (punycode), so
+/// that `bücher` becomes `xn--bcher-kva`. ASCII names are returned unchanged.
+pub fn canonical_bucket_name(n: &str) -> Cow<'_, str> {
+	if n.is_ascii() {
+		return Cow::Borrowed(n);
+	}
+	match idna::domain_to_ascii(n) {
+		Ok(ascii) => Cow::Owned(ascii),
+		// Not a valid international domain name, this will be
+		// refused by is_valid_bucket_name
+		Err(_) => Cow::Borrowed(n),
+	}
+}
+
+/// Check if a bucket name, in its canonical form, is valid.
 ///
 /// The requirements are listed here:
 ///
@@ -80,7 +98,11 @@ impl TableSchema for BucketAliasTable {
 /// In the case of Garage, bucket names must not be hex-encoded
 /// 32 byte string, which is excluded thanks to the
 /// maximum length of 63 bytes given in the spec.
-pub fn is_valid_bucket_name(n: &str) -> bool {
+///
+/// If `allow_punycode` is set, bucket names can contain punycode labels
+/// (starting with "xn--"), which are the canonical form of international
+/// bucket names, as long as they can be decoded.
+pub fn is_valid_bucket_name(n: &str, allow_punycode: bool) -> bool {
 	// Bucket names must be between 3 and 63 characters
 	n.len() >= 3 && n.len() <= 63
 	// Bucket names must be composed of lowercase letters, numbers,
@@ -89,13 +111,58 @@ pub fn is_valid_bucket_name(n: &str) -> bool {
 	//  Bucket names must start and end with a letter or a number
 	&& !n.starts_with(&['-', '.'][..])
 	&& !n.ends_with(&['-', '.'][..])
+	// Bucket names must not contain two adjacent periods
+	&& !n.contains("..")
 	// Bucket names must not be formated as an IP address
 	&& n.parse::<std::net::IpAddr>().is_err()
-	// Bucket names must not start wih "xn--"
-	&& !n.starts_with("xn--")
+	// Bucket names must not start wih "xn--", unless punycode is allowed
+	// and the name is the canonical form of an international name
+	&& if allow_punycode {
+		!n.split('.').any(|label| label.starts_with("xn--"))
+			|| matches!(idna::domain_to_unicode(n), (u, Ok(())) if canonical_bucket_name(&u) == n)
+	} else {
+		!n.starts_with("xn--")
+	}
 	// Bucket names must not end with "-s3alias"
 	&& !n.ends_with("-s3alias")
 }
 
 /// Error message to return for invalid bucket names
 pub const INVALID_BUCKET_NAME_MESSAGE: &str = "Invalid bucket name. See AWS documentation for constraints on S3 bucket names:\nhttps://docs.aws.amazon.com/AmazonS3/latest/userguide/bucketnamingrules.html";
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_canonical_bucket_name() {
+		assert_eq!(canonical_bucket_name("my-bucket"), "my-bucket");
+		assert_eq!(canonical_bucket_name("bücher"), "xn--bcher-kva");
+		assert_eq!(canonical_bucket_name("Bücher"), "xn--bcher-kva");
+		assert_eq!(
+			canonical_bucket_name("www.bücher.example"),
+			"www.xn--bcher-kva.example"
+		);
+		// Uppercase ASCII names are not lowercased, they are invalid
+		assert_eq!(canonical_bucket_name("MyBucket"), "MyBucket");
+	}
+
+	#[test]
+	fn test_punycode_bucket_names() {
+		assert!(is_valid_bucket_name("my-bucket", false));
+		assert!(is_valid_bucket_name("my-bucket", true));
+		assert!(!is_valid_bucket_name("MyBucket", true));
+
+		assert!(!is_valid_bucket_name("xn--bcher-kva", false));
+		assert!(is_valid_bucket_name("xn--bcher-kva", true));
+		assert!(is_valid_bucket_name("www.xn--bcher-kva.example", true));
+
+		// Unicode names must be converted first
+		assert!(!is_valid_bucket_name("bücher", true));
+		assert!(is_valid_bucket_name(&canonical_bucket_name("bücher"), true));
+
+		// Punycode labels that can't be decoded are refused
+		assert!(!is_valid_bucket_name("xn--", true));
+		assert!(!is_valid_bucket_name("xn--abc-", true));
+	}
+}
@@ -5,6 +5,19 @@ use garage_util::time::*;
 
 use crate::permission::BucketKeyPerm;
 
+/// Bounds of the block size that can be set on a bucket. The lower bound
+/// must stay above the size under which objects are stored inline in the
+/// object table, as an upload is only inlined if its first block is
+/// smaller than that size.
+pub const MIN_BUCKET_BLOCK_SIZE: usize = 64 * 1024;
+pub const MAX_BUCKET_BLOCK_SIZE: usize = 64 * 1024 * 1024;
+/// The block size of a bucket must be a multiple of this value
+pub const BUCKET_BLOCK_SIZE_ALIGN: usize = 4 * 1024;
+
+/// HTTP methods that can be allowed on a bucket's S3 API
+/// (OPTIONS requests are always allowed, for CORS preflight requests)
+pub const BUCKET_METHODS: &[&str] = &["GET", "HEAD", "PUT", "POST", "DELETE"];
+
 mod v08 {
 	use crate::permission::BucketKeyPerm;
 	use garage_util::crdt;
@@ -54,6 +67,60 @@ mod v08 {
 		/// Bucket quotas
 		#[serde(default)]
 		pub quotas: crdt::Lww<BucketQuotas>,
+		/// Whether the bucket is frozen: objects and bucket configuration
+		/// cannot be modified, and lifecycle rules are not applied
+		#[serde(default)]
+		pub read_only: crdt::Lww<bool>,
+		/// Whether requests to this bucket are billed to the requester
+		/// (Requester Pays): responses to data operations then include
+		/// the `x-amz-request-charged` header
+		#[serde(default)]
+		pub requester_pays: crdt::Lww<bool>,
+		/// Number of seconds during which the previous version of an object
+		/// is kept after it is overwritten, so that the overwrite can be
+		/// undone (None if previous versions are not kept)
+		#[serde(default)]
+		pub previous_version_retention_secs: crdt::Lww<Option<u64>>,
+		/// Whether GetObject and HeadObject requests made with a key that
+		/// cannot read the bucket get a 404 NoSuchKey error, instead of
+		/// a 403 AccessDenied error
+		#[serde(default)]
+		pub forbidden_as_not_found: crdt::Lww<bool>,
+		/// Number of copies of the data blocks of the bucket's objects,
+		/// if it is less than the cluster's replication factor
+		/// (object metadata is always stored with the cluster's replication factor)
+		#[serde(default)]
+		pub replication_factor: crdt::Lww<Option<usize>>,
+		/// Whether object keys are case-insensitive: objects are stored under
+		/// their lowercased key, and the key with its original casing is
+		/// kept in the object to be returned in listings
+		#[serde(default)]
+		pub case_insensitive_keys: crdt::Lww<bool>,
+		/// Server access logging configuration: where records of the
+		/// requests made to this bucket are delivered
+		#[serde(default)]
+		pub logging_config: crdt::Lww<Option<LoggingConfig>>,
+		/// Thresholds at which alerts are raised on the bucket's usage
+		#[serde(default)]
+		pub usage_alerts: crdt::Lww<BucketUsageAlerts>,
+		/// Whether the data blocks of this bucket's objects are resynced
+		/// before those of other buckets, e.g. after a node failure
+		#[serde(default)]
+		pub resync_high_priority: crdt::Lww<bool>,
+		/// Size of the data blocks of objects uploaded to this bucket,
+		/// instead of the cluster's `block_size` (only applies to new uploads,
+		/// as the block list of each version is stored explicitly)
+		#[serde(default)]
+		pub block_size: crdt::Lww<Option<usize>>,
+		/// HTTP methods accepted on the S3 API for this bucket, whatever
+		/// the permissions of the access key (None if all are accepted)
+		#[serde(default)]
+		pub allowed_methods: crdt::Lww<Option<Vec<String>>>,
+		/// Whether ListObjects requests to this bucket fall back to reading
+		/// from a single node when a read quorum cannot be reached, as if
+		/// they had the `x-garage-allow-degraded-read` header
+		#[serde(default)]
+		pub allow_degraded_reads: crdt::Lww<bool>,
 	}
 
 	#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
@@ -62,6 +129,16 @@ mod v08 {
 		pub error_document: Option<String>,
 	}
 
+	#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+	pub struct LoggingConfig {
+		/// Bucket in which access logs are delivered
+		pub target_bucket: Uuid,
+		/// Name of the target bucket, as given when logging was enabled
+		pub target_bucket_name: String,
+		/// Prefix of the keys of the log objects
+		pub target_prefix: String,
+	}
+
 	#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
 	pub struct CorsRule {
 		pub id: Option<String>,
@@ -114,6 +191,20 @@ mod v08 {
 		pub max_size: Option<u64>,
 		/// Maximum number of non-deleted objects in the bucket
 		pub max_objects: Option<u64>,
+		/// Maximum number of multipart uploads in progress in the bucket
+		#[serde(default)]
+		pub max_multipart_uploads: Option<u64>,
+	}
+
+	#[derive(Default, PartialEq, Eq, PartialOrd, Ord, Clone, Debug, Serialize, Deserialize)]
+	pub struct BucketUsageAlerts {
+		/// Raise an alert when the bucket's size or number of objects reaches
+		/// this percentage of the corresponding quota
+		pub quota_percent: Option<u8>,
+		/// Raise an alert when the bucket's size reaches this number of bytes
+		pub max_size: Option<u64>,
+		/// Raise an alert when the bucket's number of objects reaches this value
+		pub max_objects: Option<u64>,
 	}
 
 	impl garage_util::migrate::InitialFormat for Bucket {}
@@ -125,6 +216,10 @@ impl AutoCrdt for BucketQuotas {
 	const WARN_IF_DIFFERENT: bool = true;
 }
 
+impl AutoCrdt for BucketUsageAlerts {
+	const WARN_IF_DIFFERENT: bool = true;
+}
+
 impl BucketParams {
 	/// Create an empty BucketParams with no authorized keys and no website accesss
 	fn new() -> Self {
@@ -137,6 +232,43 @@ impl BucketParams {
 			cors_config: crdt::Lww::new(None),
 			lifecycle_config: crdt::Lww::new(None),
 			quotas: crdt::Lww::new(BucketQuotas::default()),
+			read_only: crdt::Lww::new(false),
+			requester_pays: crdt::Lww::new(false),
+			previous_version_retention_secs: crdt::Lww::raw(0, None),
+			forbidden_as_not_found: crdt::Lww::new(false),
+			replication_factor: crdt::Lww::raw(0, None),
+			case_insensitive_keys: crdt::Lww::new(false),
+			logging_config: crdt::Lww::new(None),
+			usage_alerts: crdt::Lww::new(BucketUsageAlerts::default()),
+			resync_high_priority: crdt::Lww::new(false),
+			block_size: crdt::Lww::raw(0, None),
+			allowed_methods: crdt::Lww::raw(0, None),
+			allow_degraded_reads: crdt::Lww::new(false),
+		}
+	}
+
+	/// Size of the data blocks of new uploads to this bucket, given the
+	/// cluster's default block size
+	pub fn effective_block_size(&self, default: usize) -> usize {
+		self.block_size.get().unwrap_or(default)
+	}
+
+	/// Whether requests with this HTTP method are accepted on the S3 API
+	pub fn allows_method(&self, method: &str) -> bool {
+		method == "OPTIONS"
+			|| match self.allowed_methods.get() {
+				None => true,
+				Some(methods) => methods.iter().any(|m| m == method),
+			}
+	}
+
+	/// Key under which an object is stored in this bucket: the key itself,
+	/// or its lowercased version if keys are case-insensitive
+	pub fn index_key(&self, key: &str) -> String {
+		if *self.case_insensitive_keys.get() {
+			key.to_lowercase()
+		} else {
+			key.to_string()
 		}
 	}
 }
@@ -153,7 +285,66 @@ impl Crdt for BucketParams {
 		self.cors_config.merge(&o.cors_config);
 		self.lifecycle_config.merge(&o.lifecycle_config);
 		self.quotas.merge(&o.quotas);
+		self.read_only.merge(&o.read_only);
+		self.requester_pays.merge(&o.requester_pays);
+		self.previous_version_retention_secs
+			.merge(&o.previous_version_retention_secs);
+		self.forbidden_as_not_found.merge(&o.forbidden_as_not_found);
+		self.replication_factor.merge(&o.replication_factor);
+		self.case_insensitive_keys.merge(&o.case_insensitive_keys);
+		self.logging_config.merge(&o.logging_config);
+		self.usage_alerts.merge(&o.usage_alerts);
+		self.resync_high_priority.merge(&o.resync_high_priority);
+		self.block_size.merge(&o.block_size);
+		self.allowed_methods.merge(&o.allowed_methods);
+		self.allow_degraded_reads.merge(&o.allow_degraded_reads);
+	}
+}
+
+/// Check that a block size can be set on a bucket
+pub fn check_bucket_block_size(block_size: usize) -> Result<(), String> {
+	if !(MIN_BUCKET_BLOCK_SIZE..=MAX_BUCKET_BLOCK_SIZE).contains(&block_size) {
+		return Err(format!(
+			"block size must be between {} and {} bytes",
+			MIN_BUCKET_BLOCK_SIZE, MAX_BUCKET_BLOCK_SIZE
+		));
+	}
+	if block_size % BUCKET_BLOCK_SIZE_ALIGN != 0 {
+		return Err(format!(
+			"block size must be a multiple of {} bytes",
+			BUCKET_BLOCK_SIZE_ALIGN
+		));
+	}
+	Ok(())
+}
+
+/// Parse a list of HTTP methods to allow on a bucket: methods are
+/// uppercased, sorted and deduplicated, and an empty list allows all methods
+pub fn parse_bucket_allowed_methods<S: AsRef<str>>(
+	methods: &[S],
+) -> Result<Option<Vec<String>>, String> {
+	if methods.is_empty() {
+		return Ok(None);
+	}
+	let mut ret = vec![];
+	for m in methods {
+		let m = m.as_ref().trim().to_uppercase();
+		if m == "OPTIONS" {
+			// Always allowed
+			continue;
+		}
+		if !BUCKET_METHODS.contains(&m.as_str()) {
+			return Err(format!(
+				"unknown method {:?}, allowed methods are {}",
+				m,
+				BUCKET_METHODS.join(", ")
+			));
+		}
+		ret.push(m);
 	}
+	ret.sort_by_key(|m| BUCKET_METHODS.iter().position(|x| x == m));
+	ret.dedup();
+	Ok(Some(ret))
 }
 
 pub fn parse_lifecycle_date(date: &str) -> Result<chrono::NaiveDate, &'static str> {
@@ -0,0 +1,239 @@
+//! Compaction of the metadata database, which rewrites it to reclaim the
+//! space left unused by deleted data, e.g. after bulk deletions.
+//!
+//! Compaction can be run manually with `garage meta compact`, or at a
+//! regular interval set by `metadata_auto_compaction_interval`. Scheduled
+//! compactions are postponed while a snapshot or another compaction is
+//! running, or while the node is processing many writes.
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use tokio::sync::watch;
+
+use garage_util::background::*;
+use garage_util::error::*;
+
+use crate::garage::Garage;
+use crate::snapshot::snapshot_in_progress;
+
+// A scheduled compaction is postponed if more writes than this
+// are still waiting to be processed by the Merkle updaters
+const COMPACTION_BUSY_PENDING_WRITES: usize = 1000;
+// Delay after which a postponed compaction is tried again
+const COMPACTION_RETRY_DELAY: Duration = Duration::from_secs(600);
+
+static COMPACTION_MUTEX: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Clone, Copy)]
+pub struct CompactionReport {
+	pub reclaimed_bytes: u64,
+	pub duration: Duration,
+}
+
+/// Compact the metadata database.
+/// This is not an async function, it should be spawned on a thread pool
+fn compact_metadata(garage: &Garage) -> Result<CompactionReport, Error> {
+	let lock = match COMPACTION_MUTEX.try_lock() {
+		Ok(lock) => lock,
+		Err(_) => {
+			return Err(Error::Message(
+				"Cannot acquire lock, another compaction might be in progress".into(),
+			))
+		}
+	};
+
+	info!("Compacting metadata db");
+	let start = Instant::now();
+	let reclaimed_bytes = garage.db.compact()?;
+	let report = CompactionReport {
+		reclaimed_bytes,
+		duration: start.elapsed(),
+	};
+	info!(
+		"Metadata db compaction finished in {:.1}s, {} bytes reclaimed",
+		report.duration.as_secs_f32(),
+		report.reclaimed_bytes
+	);
+
+	drop(lock);
+
+	Ok(report)
+}
+
+/// Run compact_metadata in a blocking thread and async await on it
+pub async fn async_compact_metadata(garage: &Arc<Garage>) -> Result<CompactionReport, Error> {
+	let garage = garage.clone();
+	tokio::task::spawn_blocking(move || compact_metadata(&garage)).await?
+}
+
+/// Reason for which a scheduled compaction has to be postponed, if any
+fn busy_reason(garage: &Garage) -> Result<Option<String>, Error> {
+	let pending_writes = garage.object_table.merkle_updater.todo_len()?
+		+ garage.version_table.merkle_updater.todo_len()?
+		+ garage.block_ref_table.merkle_updater.todo_len()?
+		+ garage.mpu_table.merkle_updater.todo_len()?;
+	Ok(postpone_reason(
+		COMPACTION_MUTEX.try_lock().is_err(),
+		snapshot_in_progress(),
+		pending_writes,
+	))
+}
+
+fn postpone_reason(
+	compaction_running: bool,
+	snapshot_running: bool,
+	pending_writes: usize,
+) -> Option<String> {
+	if compaction_running {
+		Some("a compaction is already running".into())
+	} else if snapshot_running {
+		Some("a snapshot is being taken".into())
+	} else if pending_writes > COMPACTION_BUSY_PENDING_WRITES {
+		Some(format!(
+			"{} writes are waiting to be processed",
+			pending_writes
+		))
+	} else {
+		None
+	}
+}
+
+/// Time of the next scheduled compaction
+struct CompactionSchedule {
+	interval: Duration,
+	next_run: Instant,
+}
+
+impl CompactionSchedule {
+	fn new(interval: Duration, now: Instant) -> Self {
+		Self {
+			interval,
+			next_run: now + interval,
+		}
+	}
+
+	fn is_due(&self, now: Instant) -> bool {
+		now >= self.next_run
+	}
+
+	/// The compaction could not run now, try again soon
+	fn postpone(&mut self, now: Instant) {
+		self.next_run = now + std::cmp::min(COMPACTION_RETRY_DELAY, self.interval);
+	}
+
+	/// The compaction has run, schedule the next one
+	fn reschedule(&mut self, now: Instant) {
+		self.next_run = now + self.interval;
+	}
+}
+
+pub struct AutoCompactionWorker {
+	garage: Arc<Garage>,
+	schedule: CompactionSchedule,
+	last_report: Option<CompactionReport>,
+	postponed: usize,
+}
+
+impl AutoCompactionWorker {
+	pub(crate) fn new(garage: Arc<Garage>, interval: Duration) -> Self {
+		Self {
+			garage,
+			schedule: CompactionSchedule::new(interval, Instant::now()),
+			last_report: None,
+			postponed: 0,
+		}
+	}
+}
+
+#[async_trait]
+impl Worker for AutoCompactionWorker {
+	fn name(&self) -> String {
+		"Metadata compaction".into()
+	}
+
+	fn status(&self) -> WorkerStatus {
+		let mut freeform = vec![format!("Postponed compactions: {}", self.postponed)];
+		if let Some(r) = &self.last_report {
+			freeform.push(format!(
+				"Last compaction: {} bytes reclaimed in {:.1}s",
+				r.reclaimed_bytes,
+				r.duration.as_secs_f32()
+			));
+		}
+		WorkerStatus {
+			freeform,
+			..Default::default()
+		}
+	}
+
+	async fn work(&mut self, _must_exit: &mut watch::Receiver<bool>) -> Result<WorkerState, Error> {
+		if !self.schedule.is_due(Instant::now()) {
+			return Ok(WorkerState::Idle);
+		}
+
+		if let Some(reason) = busy_reason(&self.garage)? {
+			info!("Postponing scheduled metadata db compaction: {}", reason);
+			self.postponed += 1;
+			self.schedule.postpone(Instant::now());
+			return Ok(WorkerState::Idle);
+		}
+
+		let res = async_compact_metadata(&self.garage).await;
+		self.schedule.reschedule(Instant::now());
+		self.last_report = Some(res?);
+
+		Ok(WorkerState::Idle)
+	}
+
+	async fn wait_for_work(&mut self) -> WorkerState {
+		tokio::time::sleep_until(self.schedule.next_run.into()).await;
+		WorkerState::Busy
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_compaction_schedule() {
+		let start = Instant::now();
+		let hour = Duration::from_secs(3600);
+		let mut schedule = CompactionSchedule::new(24 * hour, start);
+
+		assert!(!schedule.is_due(start));
+		assert!(!schedule.is_due(start + 23 * hour));
+		assert!(schedule.is_due(start + 24 * hour));
+
+		// A compaction skipped because the node is busy is tried again
+		// soon, without waiting for the whole interval
+		let now = start + 24 * hour;
+		schedule.postpone(now);
+		assert!(!schedule.is_due(now));
+		assert!(schedule.is_due(now + COMPACTION_RETRY_DELAY));
+
+		let now = now + COMPACTION_RETRY_DELAY;
+		schedule.reschedule(now);
+		assert!(!schedule.is_due(now + COMPACTION_RETRY_DELAY));
+		assert!(schedule.is_due(now + 24 * hour));
+	}
+
+	#[test]
+	fn test_postpone_if_busy() {
+		assert_eq!(postpone_reason(false, false, 0), None);
+		assert_eq!(
+			postpone_reason(false, false, COMPACTION_BUSY_PENDING_WRITES),
+			None
+		);
+		assert!(postpone_reason(true, false, 0).is_some());
+		assert!(postpone_reason(false, true, 0).is_some());
+		assert!(postpone_reason(false, false, COMPACTION_BUSY_PENDING_WRITES + 1).is_some());
+
+		// The lock held by a running compaction is detected
+		let lock = COMPACTION_MUTEX.lock().unwrap();
+		assert!(COMPACTION_MUTEX.try_lock().is_err());
+		drop(lock);
+		assert!(COMPACTION_MUTEX.try_lock().is_ok());
+	}
+}
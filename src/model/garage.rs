@@ -1,5 +1,8 @@
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
+
+use opentelemetry::{global, metrics::ValueObserver};
 
 use garage_net::NetworkKey;
 
@@ -8,7 +11,8 @@ use garage_db as db;
 use garage_util::background::*;
 use garage_util::config::*;
 use garage_util::error::*;
-use garage_util::persister::PersisterShared;
+use garage_util::persister::{Persister, PersisterShared};
+use garage_util::time::{Clock, SystemClock};
 
 use garage_rpc::replication_mode::*;
 use garage_rpc::system::System;
@@ -19,7 +23,11 @@ use garage_table::replication::TableShardedReplication;
 use garage_table::*;
 
 use crate::s3::block_ref_table::*;
+use crate::s3::copy_bucket::CopyBucketJobs;
+use crate::s3::counter_check::ObjectCounterChecker;
+use crate::s3::delete_prefix::DeletePrefixJobs;
 use crate::s3::lifecycle_worker;
+use crate::s3::mpu_completion;
 use crate::s3::mpu_table::*;
 use crate::s3::object_table::*;
 use crate::s3::version_table::*;
@@ -28,7 +36,10 @@ use crate::bucket_alias_table::*;
 use crate::bucket_table::*;
 use crate::helper;
 use crate::index_counter::*;
+use crate::key_cache::{KeyCache, DEFAULT_KEY_CACHE_TTL};
 use crate::key_table::*;
+use crate::key_usage::KeyUsageTracker;
+use crate::usage_history::UsageHistory;
 
 #[cfg(feature = "k2v")]
 use crate::k2v::{item_table::*, rpc::*, sub::*};
@@ -45,8 +56,13 @@ pub struct Garage {
 
 	/// The local database
 	pub db: db::Db,
+	/// Gauge of the reader slots of the database in use, for the
+	/// engines that have a limited number of them
+	_db_reader_slots: Option<ValueObserver<u64>>,
 	/// The membership manager
 	pub system: Arc<System>,
+	/// Clock issuing the timestamps of writes
+	pub clock: Arc<dyn Clock>,
 	/// The block manager
 	pub block_manager: Arc<BlockManager>,
 
@@ -56,6 +72,10 @@ pub struct Garage {
 	pub bucket_alias_table: Arc<Table<BucketAliasTable, TableFullReplication>>,
 	/// Table containing api keys
 	pub key_table: Arc<Table<KeyTable, TableFullReplication>>,
+	/// Cache of the access keys used to check the signature of requests
+	pub key_cache: Arc<KeyCache>,
+	/// Usage of the access keys, not yet written to the key table
+	pub key_usage: KeyUsageTracker,
 
 	/// Lock to prevent concurrent modification of buckets and access keys
 	bucket_lock: tokio::sync::Mutex<()>,
@@ -64,6 +84,8 @@ pub struct Garage {
 	pub object_table: Arc<Table<ObjectTable, TableShardedReplication>>,
 	/// Counting table containing object counters
 	pub object_counter_table: Arc<IndexCounter<Object>>,
+	/// Verification of the object counters against the objects
+	pub object_counter_checker: Arc<ObjectCounterChecker>,
 	/// Table containing S3 multipart uploads
 	pub mpu_table: Arc<Table<MultipartUploadTable, TableShardedReplication>>,
 	/// Counting table containing multipart object counters
@@ -75,6 +97,12 @@ pub struct Garage {
 
 	/// Persister for lifecycle worker info
 	pub lifecycle_persister: PersisterShared<lifecycle_worker::LifecycleWorkerPersisted>,
+	/// Delete-prefix jobs started on this node
+	pub delete_prefix_jobs: DeletePrefixJobs,
+	/// Bucket copy jobs started on this node
+	pub copy_bucket_jobs: CopyBucketJobs,
+	/// History of cluster usage, if enabled
+	pub usage_history: Option<UsageHistory>,
 
 	#[cfg(feature = "k2v")]
 	pub k2v: GarageK2V,
@@ -94,18 +122,28 @@ impl Garage {
 	/// Create and run garage
 	pub fn new(config: Config) -> Result<Arc<Self>, Error> {
 		// Create meta dir and data dir if they don't exist already
-		std::fs::create_dir_all(&config.metadata_dir)
+		let metadata_dir_mode = parse_dir_mode("metadata_dir_mode", &config.metadata_dir_mode)?;
+		let data_dir_mode = parse_dir_mode("data_dir_mode", &config.data_dir_mode)?;
+		for threshold in config.block_size_by_object_size.iter() {
+			check_bucket_block_size(threshold.block_size).map_err(|e| {
+				Error::Message(format!(
+					"Invalid block size in `block_size_by_object_size`: {}",
+					e
+				))
+			})?;
+		}
+		create_dir_with_mode(&config.metadata_dir, metadata_dir_mode)
 			.ok_or_message("Unable to create Garage metadata directory")?;
 		match &config.data_dir {
 			DataDirEnum::Single(data_dir) => {
-				std::fs::create_dir_all(data_dir).ok_or_message(format!(
+				create_dir_with_mode(data_dir, data_dir_mode).ok_or_message(format!(
 					"Unable to create Garage data directory: {}",
 					data_dir.to_string_lossy()
 				))?;
 			}
 			DataDirEnum::Multiple(data_dirs) => {
 				for dir in data_dirs {
-					std::fs::create_dir_all(&dir.path).ok_or_message(format!(
+					create_dir_with_mode(&dir.path, data_dir_mode).ok_or_message(format!(
 						"Unable to create Garage data directory: {}",
 						dir.path.to_string_lossy()
 					))?;
@@ -125,15 +163,48 @@ impl Garage {
 				db_path.push("db.lmdb");
 			}
 		}
+		let fsync_interval = match config.metadata_fsync_interval.as_deref() {
+			None => None,
+			Some(_) if config.metadata_fsync => {
+				return Err(Error::Message(
+					"`metadata_fsync_interval` cannot be used together with `metadata_fsync = true`"
+						.into(),
+				));
+			}
+			Some(itv) => {
+				let interval = parse_duration::parse(itv)
+					.ok_or_message("Invalid `metadata_fsync_interval`")?;
+				if interval.is_zero() {
+					return Err(Error::Message(
+						"`metadata_fsync_interval` must be greater than zero".into(),
+					));
+				}
+				Some(interval)
+			}
+		};
 		let db_opt = db::OpenOpt {
 			fsync: config.metadata_fsync,
+			fsync_interval,
 			lmdb_map_size: match config.lmdb_map_size {
 				v if v == usize::default() => None,
 				v => Some(v),
 			},
+			lmdb_max_readers: config.lmdb_max_readers,
+			mode: metadata_dir_mode,
 		};
 		let db = db::open_db(&db_path, db_engine, &db_opt)
 			.ok_or_message("Unable to open metadata db")?;
+		let db_reader_slots = db.reader_slots().map(|_| {
+			let db = db.clone();
+			global::meter("garage_model/db")
+				.u64_value_observer("db.reader_slots_used", move |observer| {
+					if let Some(slots) = db.reader_slots() {
+						observer.observe(slots.used as u64, &[]);
+					}
+				})
+				.with_description("Number of reader slots of the metadata database in use")
+				.init()
+		});
 
 		info!("Initializing RPC...");
 		let network_key = hex::decode(config.rpc_secret.as_ref().ok_or_message(
@@ -151,10 +222,20 @@ impl Garage {
 		info!("Initialize membership management system...");
 		let system = System::new(network_key, replication_factor, consistency_mode, &config)?;
 
+		let write_min_zones = config.write_min_zones.unwrap_or(0);
+		if write_min_zones > usize::from(replication_factor) {
+			return Err(Error::Message(format!(
+				"write_min_zones ({}) cannot be larger than replication_factor ({})",
+				write_min_zones,
+				usize::from(replication_factor)
+			)));
+		}
+
 		let data_rep_param = TableShardedReplication {
 			system: system.clone(),
 			replication_factor: replication_factor.into(),
 			write_quorum: replication_factor.write_quorum(consistency_mode),
+			write_min_zones,
 			read_quorum: 1,
 		};
 
@@ -162,6 +243,7 @@ impl Garage {
 			system: system.clone(),
 			replication_factor: replication_factor.into(),
 			write_quorum: replication_factor.write_quorum(consistency_mode),
+			write_min_zones,
 			read_quorum: replication_factor.read_quorum(consistency_mode),
 		};
 
@@ -185,7 +267,21 @@ impl Garage {
 			&db,
 		);
 		info!("Initialize key_table_table...");
-		let key_table = Table::new(KeyTable, control_rep_param, system.clone(), &db);
+		let key_cache = Arc::new(KeyCache::new(
+			config
+				.s3_api
+				.key_cache_ttl_msec
+				.map(Duration::from_millis)
+				.unwrap_or(DEFAULT_KEY_CACHE_TTL),
+		));
+		let key_table = Table::new(
+			KeyTable {
+				key_cache: key_cache.clone(),
+			},
+			control_rep_param,
+			system.clone(),
+			&db,
+		);
 
 		// ---- S3 tables ----
 		info!("Initialize block_ref_table...");
@@ -238,14 +334,45 @@ impl Garage {
 			&db,
 		);
 
+		let object_counter_checker = ObjectCounterChecker::new(
+			system.clone(),
+			object_table.clone(),
+			object_counter_table.clone(),
+		);
+
+		bucket_table.register_bg_vars(&mut bg_vars);
+		bucket_alias_table.register_bg_vars(&mut bg_vars);
+		key_table.register_bg_vars(&mut bg_vars);
+		block_ref_table.register_bg_vars(&mut bg_vars);
+		version_table.register_bg_vars(&mut bg_vars);
+		mpu_table.register_bg_vars(&mut bg_vars);
+		mpu_counter_table.table.register_bg_vars(&mut bg_vars);
+		object_table.register_bg_vars(&mut bg_vars);
+		object_counter_table.table.register_bg_vars(&mut bg_vars);
+
 		info!("Load lifecycle worker state...");
 		let lifecycle_persister =
 			PersisterShared::new(&system.metadata_dir, "lifecycle_worker_state");
 		lifecycle_worker::register_bg_vars(&lifecycle_persister, &mut bg_vars);
 
+		let copy_bucket_jobs =
+			CopyBucketJobs::new(Persister::new(&system.metadata_dir, "copy_bucket_jobs"));
+
+		let usage_history = match config.usage_history_interval.as_deref() {
+			None => None,
+			Some(itv) => {
+				info!("Initialize usage history...");
+				let interval =
+					parse_duration::parse(itv).ok_or_message("Invalid `usage_history_interval`")?;
+				Some(UsageHistory::new(&db, interval)?)
+			}
+		};
+
 		// ---- K2V ----
 		#[cfg(feature = "k2v")]
 		let k2v = GarageK2V::new(system.clone(), &db, meta_rep_param);
+		#[cfg(feature = "k2v")]
+		k2v.register_bg_vars(&mut bg_vars);
 
 		// ---- setup block refcount recalculation ----
 		// this function can be used to fix inconsistencies in the RC table
@@ -254,6 +381,12 @@ impl Garage {
 			// other functions could be added here if we had other tables
 			// that hold references to data blocks
 		]);
+		block_manager.set_resync_priority(block_resync_priority_fn(
+			&block_ref_table,
+			&version_table,
+			&mpu_table,
+			&bucket_table,
+		));
 
 		// -- done --
 		Ok(Arc::new(Self {
@@ -261,19 +394,27 @@ impl Garage {
 			bg_vars,
 			replication_factor,
 			db,
+			_db_reader_slots: db_reader_slots,
 			system,
+			clock: Arc::new(SystemClock),
 			block_manager,
 			bucket_table,
 			bucket_alias_table,
 			key_table,
+			key_cache,
+			key_usage: KeyUsageTracker::new(),
 			bucket_lock: tokio::sync::Mutex::new(()),
 			object_table,
 			object_counter_table,
+			object_counter_checker,
 			mpu_table,
 			mpu_counter_table,
 			version_table,
 			block_ref_table,
 			lifecycle_persister,
+			delete_prefix_jobs: DeletePrefixJobs::new(),
+			copy_bucket_jobs,
+			usage_history,
 			#[cfg(feature = "k2v")]
 			k2v,
 		}))
@@ -297,10 +438,24 @@ impl Garage {
 			self.clone(),
 			self.lifecycle_persister.clone(),
 		));
+		bg.spawn_worker(mpu_completion::MpuCompletionWorker::new(self.clone()));
+		bg.spawn_worker(crate::key_usage::KeyUsageFlushWorker::new(self.clone()));
 
 		#[cfg(feature = "k2v")]
 		self.k2v.spawn_workers(bg);
 
+		if let Some(days) = self.config.security.auto_expire_unused_keys_after_days {
+			if days == 0 {
+				return Err(Error::Message(
+					"security.auto_expire_unused_keys_after_days must be at least 1".into(),
+				));
+			}
+			bg.spawn_worker(crate::key_expiry_worker::KeyExpiryWorker::new(
+				self.clone(),
+				days,
+			)?);
+		}
+
 		if let Some(itv) = self.config.metadata_auto_snapshot_interval.as_deref() {
 			let interval = parse_duration::parse(itv)
 				.ok_or_message("Invalid `metadata_auto_snapshot_interval`")?;
@@ -316,6 +471,40 @@ impl Garage {
 			));
 		}
 
+		if let Some(itv) = self.config.metadata_auto_compaction_interval.as_deref() {
+			let interval = parse_duration::parse(itv)
+				.ok_or_message("Invalid `metadata_auto_compaction_interval`")?;
+			if interval < std::time::Duration::from_secs(3600) {
+				return Err(Error::Message(
+					"metadata_auto_compaction_interval must be at least one hour".into(),
+				));
+			}
+			if !self.db.supports(db::Capability::Compaction) {
+				return Err(Error::Message(format!(
+					"metadata_auto_compaction_interval is set, but the {} db engine does not support compaction",
+					self.db.engine()
+				)));
+			}
+
+			bg.spawn_worker(crate::compaction::AutoCompactionWorker::new(
+				self.clone(),
+				interval,
+			));
+		}
+
+		self.copy_bucket_jobs.resume(self);
+
+		bg.spawn_worker(crate::usage_alert_worker::UsageAlertWorker::new(
+			self.clone(),
+		));
+
+		if let Some(history) = &self.usage_history {
+			bg.spawn_worker(crate::usage_history::UsageHistoryWorker::new(
+				self.clone(),
+				history.interval,
+			));
+		}
+
 		Ok(())
 	}
 
@@ -367,4 +556,30 @@ impl GarageK2V {
 		self.item_table.spawn_workers(bg);
 		self.counter_table.spawn_workers(bg);
 	}
+
+	fn register_bg_vars(&self, vars: &mut vars::BgVars) {
+		self.item_table.register_bg_vars(vars);
+		self.counter_table.table.register_bg_vars(vars);
+	}
+}
+
+fn parse_dir_mode(option: &str, value: &Option<String>) -> Result<Option<u32>, Error> {
+	match value.as_deref() {
+		None => Ok(None),
+		Some(v) => match u32::from_str_radix(v.trim_start_matches("0o"), 8) {
+			Ok(mode) if mode <= 0o7777 => Ok(Some(mode)),
+			_ => Err(Error::Message(format!(
+				"Invalid `{}` value: {} (expected an octal mode such as 0700)",
+				option, v
+			))),
+		},
+	}
+}
+
+fn create_dir_with_mode(path: &std::path::Path, mode: Option<u32>) -> Result<(), Error> {
+	std::fs::create_dir_all(path)?;
+	if let Some(mode) = mode {
+		db::set_permissions(path, mode)?;
+	}
+	Ok(())
 }
@@ -6,9 +6,11 @@ use garage_util::time::*;
 
 use garage_table::util::*;
 
+use crate::bucket_alias_table::canonical_bucket_name;
 use crate::bucket_table::*;
 use crate::garage::Garage;
 use crate::helper::error::*;
+use crate::helper::{select_pattern_match, PatternMatch};
 use crate::key_table::*;
 use crate::s3::object_table::*;
 
@@ -29,6 +31,7 @@ impl<'a> BucketHelper<'a> {
 		// identifier which is returned. There is no risk of this conflicting
 		// with an actual bucket name: bucket names are max 63 chars long by
 		// the AWS spec, and hex-encoded UUIDs are 64 chars long.
+		let bucket_name = &canonical_bucket_name(bucket_name).into_owned();
 		let hexbucket = hex::decode(bucket_name.as_str())
 			.ok()
 			.and_then(|by| Uuid::try_from(&by));
@@ -57,24 +60,66 @@ impl<'a> BucketHelper<'a> {
 			.as_option()
 			.ok_or_message("Key should not be deleted at this point")?;
 
+		let bucket_name = &canonical_bucket_name(bucket_name).into_owned();
 		if let Some(Some(bucket_id)) = api_key_params.local_aliases.get(bucket_name) {
 			Ok(*bucket_id)
 		} else {
-			Ok(self
+			let bucket_id = self
 				.resolve_global_bucket_name(bucket_name)
 				.await?
-				.ok_or_else(|| Error::NoSuchBucket(bucket_name.to_string()))?)
+				.ok_or_else(|| Error::NoSuchBucket(bucket_name.to_string()))?;
+
+			// A bucket that is accessed using its full id might not be reachable
+			// through any of its aliases, which happens when the metadata has
+			// only been partially restored. Such buckets generally have
+			// no permissions either, which can be confusing, so log it.
+			if hex::encode(bucket_id).eq_ignore_ascii_case(bucket_name) {
+				let bucket = self.get_internal_bucket(bucket_id).await?;
+				if !self.is_bucket_reachable(&bucket).await? {
+					warn!(
+						bucket_id = %hex::encode(bucket_id),
+						"Bucket exists but cannot be reached through any of its aliases (orphan bucket)"
+					);
+				}
+			}
+
+			Ok(bucket_id)
 		}
 	}
 
-	/// Find a bucket by its global alias or a prefix of its uuid
+	/// Find a bucket by its global alias or a prefix of its uuid,
+	/// or by a prefix of its global alias if it is unambiguous
 	pub async fn admin_get_existing_matching_bucket(
 		&self,
 		pattern: &String,
 	) -> Result<Uuid, Error> {
 		if let Some(uuid) = self.resolve_global_bucket_name(pattern).await? {
 			return Ok(uuid);
-		} else if pattern.len() >= 2 {
+		}
+
+		let mut matches = self
+			.0
+			.bucket_alias_table
+			.get_range(
+				&EmptyKey,
+				Some(pattern.clone()),
+				Some(DeletedFilter::NotDeleted),
+				10,
+				EnumerationOrder::Forward,
+			)
+			.await?
+			.into_iter()
+			.filter(|alias| alias.name().starts_with(pattern.as_str()))
+			.filter_map(|alias| {
+				alias.state.get().map(|id| PatternMatch {
+					id,
+					description: format!("{} ({:?})", alias.name(), id),
+					exact: false,
+				})
+			})
+			.collect::<Vec<_>>();
+
+		if pattern.len() >= 2 {
 			let hexdec = pattern
 				.get(..pattern.len() & !1)
 				.and_then(|x| hex::decode(x).ok());
@@ -85,7 +130,7 @@ impl<'a> BucketHelper<'a> {
 					.get_mut(..hex.len())
 					.ok_or_bad_request("invalid length")?
 					.copy_from_slice(&hex);
-				let mut candidates = self
+				let candidates = self
 					.0
 					.bucket_table
 					.get_range(
@@ -95,19 +140,21 @@ impl<'a> BucketHelper<'a> {
 						10,
 						EnumerationOrder::Forward,
 					)
-					.await?
-					.into_iter()
-					.collect::<Vec<_>>();
-				candidates.retain(|x| hex::encode(x.id).starts_with(pattern));
-				if candidates.len() == 1 {
-					return Ok(candidates.into_iter().next().unwrap().id);
-				}
+					.await?;
+				matches.extend(
+					candidates
+						.into_iter()
+						.filter(|x| hex::encode(x.id).starts_with(pattern.as_str()))
+						.map(|x| PatternMatch {
+							id: x.id,
+							description: format!("{:?}", x.id),
+							exact: false,
+						}),
+				);
 			}
 		}
-		Err(Error::BadRequest(format!(
-			"Bucket not found / several matching buckets: {}",
-			pattern
-		)))
+
+		select_pattern_match("bucket", pattern, matches)
 	}
 
 	/// Returns a Bucket if it is present in bucket table,
@@ -135,6 +182,89 @@ impl<'a> BucketHelper<'a> {
 			.ok_or_else(|| Error::NoSuchBucket(hex::encode(bucket_id)))
 	}
 
+	/// Returns true if a bucket can be reached through at least one of its
+	/// aliases. The aliases recorded in the bucket are checked against the
+	/// bucket alias table and the local aliases of access keys, which are
+	/// used to resolve bucket names; they can disagree if the metadata has
+	/// been partially restored from a backup.
+	pub async fn is_bucket_reachable(&self, bucket: &Bucket) -> Result<bool, Error> {
+		let state = match bucket.state.as_option() {
+			Some(s) => s,
+			None => return Ok(false),
+		};
+
+		for (alias, _, active) in state.aliases.items().iter() {
+			if !*active {
+				continue;
+			}
+			let target = self
+				.0
+				.bucket_alias_table
+				.get(&EmptyKey, alias)
+				.await?
+				.and_then(|a| *a.state.get());
+			if target == Some(bucket.id) {
+				return Ok(true);
+			}
+		}
+
+		for ((key_id, alias), _, active) in state.local_aliases.items().iter() {
+			if !*active {
+				continue;
+			}
+			let target = self
+				.0
+				.key_table
+				.get(&EmptyKey, key_id)
+				.await?
+				.and_then(|k| k.state.into_option())
+				.and_then(|k| k.local_aliases.get(alias).cloned().flatten());
+			if target == Some(bucket.id) {
+				return Ok(true);
+			}
+		}
+
+		Ok(false)
+	}
+
+	/// Lists buckets that are present in the bucket table but that cannot
+	/// be reached through any of their aliases
+	pub async fn list_orphan_buckets(&self) -> Result<Vec<Bucket>, Error> {
+		let mut ret = vec![];
+		let mut start = None;
+
+		loop {
+			let buckets = self
+				.0
+				.bucket_table
+				.get_range(
+					&EmptyKey,
+					start,
+					Some(DeletedFilter::NotDeleted),
+					1000,
+					EnumerationOrder::Forward,
+				)
+				.await?;
+
+			for bucket in buckets.iter() {
+				if Some(bucket.id) == start {
+					continue;
+				}
+				if !self.is_bucket_reachable(bucket).await? {
+					ret.push(bucket.clone());
+				}
+			}
+
+			if buckets.len() < 1000 {
+				break;
+			} else {
+				start = Some(buckets.last().unwrap().id);
+			}
+		}
+
+		Ok(ret)
+	}
+
 	// ----
 
 	pub async fn is_bucket_empty(&self, bucket_id: Uuid) -> Result<bool, Error> {
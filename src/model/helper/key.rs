@@ -3,6 +3,7 @@ use garage_util::error::OkOrMessage;
 
 use crate::garage::Garage;
 use crate::helper::error::*;
+use crate::helper::{select_pattern_match, PatternMatch};
 use crate::key_table::{Key, KeyFilter};
 
 pub struct KeyHelper<'a>(pub(crate) &'a Garage);
@@ -35,10 +36,10 @@ impl<'a> KeyHelper<'a> {
 	}
 
 	/// Returns a Key if it is present in key table,
-	/// looking it up by key ID or by a match on its name,
+	/// looking it up by a prefix of its key ID or of its name,
 	/// only if it is in non-deleted state.
-	/// Querying a non-existing key ID or a deleted key
-	/// returns a bad request error.
+	/// Querying a non-existing key ID or a deleted key, or a
+	/// pattern that matches several keys, returns a bad request error.
 	pub async fn get_existing_matching_key(&self, pattern: &str) -> Result<Key, Error> {
 		let candidates = self
 			.0
@@ -50,16 +51,25 @@ impl<'a> KeyHelper<'a> {
 				10,
 				EnumerationOrder::Forward,
 			)
-			.await?
-			.into_iter()
+			.await?;
+		let pattern_lc = pattern.to_lowercase();
+		let matches = candidates
+			.iter()
+			.map(|key| {
+				let name = key.params().map(|p| p.name.get().as_str()).unwrap_or("");
+				PatternMatch {
+					id: key.key_id.as_str(),
+					description: format!("{} ({})", key.key_id, name),
+					exact: key.key_id.to_lowercase() == pattern_lc
+						|| name.to_lowercase() == pattern_lc,
+				}
+			})
 			.collect::<Vec<_>>();
-		if candidates.len() != 1 {
-			Err(Error::BadRequest(format!(
-				"{} matching keys",
-				candidates.len()
-			)))
-		} else {
-			Ok(candidates.into_iter().next().unwrap())
-		}
+		let key_id = select_pattern_match("key", pattern, matches)?;
+		Ok(candidates
+			.iter()
+			.find(|key| key.key_id == key_id)
+			.cloned()
+			.unwrap())
 	}
 }
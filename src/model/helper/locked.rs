@@ -1,17 +1,20 @@
+use std::collections::BTreeSet;
+
 use garage_util::crdt::*;
 use garage_util::data::*;
 use garage_util::error::{Error as GarageError, OkOrMessage};
-use garage_util::time::*;
 
 use garage_table::util::*;
 
 use crate::bucket_alias_table::*;
+use crate::bucket_table::*;
 use crate::garage::Garage;
 use crate::helper::bucket::BucketHelper;
 use crate::helper::error::*;
 use crate::helper::key::KeyHelper;
 use crate::key_table::*;
 use crate::permission::BucketKeyPerm;
+use crate::s3::object_table::{OBJECTS, UNFINISHED_UPLOADS};
 
 /// A LockedHelper is the mandatory struct to hold when doing operations
 /// that modify access keys or bucket aliases. This structure takes
@@ -50,7 +53,8 @@ impl<'a> LockedHelper<'a> {
 		bucket_id: Uuid,
 		alias_name: &String,
 	) -> Result<(), Error> {
-		if !is_valid_bucket_name(alias_name) {
+		let alias_name = &canonical_bucket_name(alias_name).into_owned();
+		if !is_valid_bucket_name(alias_name, self.0.config.allow_punycode) {
 			return Err(Error::InvalidBucketName(alias_name.to_string()));
 		}
 
@@ -72,7 +76,7 @@ impl<'a> LockedHelper<'a> {
 		// Checks ok, add alias
 		let bucket_p = bucket.state.as_option_mut().unwrap();
 
-		let alias_ts = increment_logical_clock_2(
+		let alias_ts = self.0.clock.increment_logical_clock_2(
 			bucket_p.aliases.get_timestamp(alias_name),
 			alias.as_ref().map(|a| a.state.timestamp()).unwrap_or(0),
 		);
@@ -106,6 +110,7 @@ impl<'a> LockedHelper<'a> {
 		bucket_id: Uuid,
 		alias_name: &String,
 	) -> Result<(), Error> {
+		let alias_name = &canonical_bucket_name(alias_name).into_owned();
 		let mut bucket = self.bucket().get_existing_bucket(bucket_id).await?;
 		let bucket_state = bucket.state.as_option_mut().unwrap();
 
@@ -135,7 +140,7 @@ impl<'a> LockedHelper<'a> {
 		}
 
 		// Checks ok, remove alias
-		let alias_ts = increment_logical_clock_2(
+		let alias_ts = self.0.clock.increment_logical_clock_2(
 			alias.state.timestamp(),
 			bucket_state.aliases.get_timestamp(alias_name),
 		);
@@ -174,11 +179,14 @@ impl<'a> LockedHelper<'a> {
 
 		// Checks ok, remove alias
 		let alias_ts = match bucket.state.as_option() {
-			Some(bucket_state) => increment_logical_clock_2(
+			Some(bucket_state) => self.0.clock.increment_logical_clock_2(
 				alias.state.timestamp(),
 				bucket_state.aliases.get_timestamp(alias_name),
 			),
-			None => increment_logical_clock(alias.state.timestamp()),
+			None => self
+				.0
+				.clock
+				.increment_logical_clock(alias.state.timestamp()),
 		};
 
 		// ---- timestamp-ensured causality barrier ----
@@ -211,7 +219,8 @@ impl<'a> LockedHelper<'a> {
 	) -> Result<(), Error> {
 		let key_helper = KeyHelper(self.0);
 
-		if !is_valid_bucket_name(alias_name) {
+		let alias_name = &canonical_bucket_name(alias_name).into_owned();
+		if !is_valid_bucket_name(alias_name, self.0.config.allow_punycode) {
 			return Err(Error::InvalidBucketName(alias_name.to_string()));
 		}
 
@@ -234,7 +243,7 @@ impl<'a> LockedHelper<'a> {
 		// (the one from key to bucket, and the reverse one stored in the bucket iself)
 		// so that merges on both maps in case of a concurrent operation resolve
 		// to the same alias being set
-		let alias_ts = increment_logical_clock_2(
+		let alias_ts = self.0.clock.increment_logical_clock_2(
 			key_param.local_aliases.get_timestamp(alias_name),
 			bucket_p
 				.local_aliases
@@ -265,6 +274,7 @@ impl<'a> LockedHelper<'a> {
 		key_id: &String,
 		alias_name: &String,
 	) -> Result<(), Error> {
+		let alias_name = &canonical_bucket_name(alias_name).into_owned();
 		let key_helper = KeyHelper(self.0);
 
 		let mut bucket = self.bucket().get_existing_bucket(bucket_id).await?;
@@ -297,7 +307,7 @@ impl<'a> LockedHelper<'a> {
 			.local_aliases
 			.items()
 			.iter()
-			.any(|((k, n), _, active)| *k == key.key_id && n == alias_name && *active);
+			.any(|((k, n), _, active)| (*k != key.key_id || n != alias_name) && *active);
 		if !has_other_global_aliases && !has_other_local_aliases {
 			return Err(Error::BadRequest(format!("Bucket {} doesn't have other aliases, please delete it instead of just unaliasing.", alias_name)));
 		}
@@ -306,7 +316,7 @@ impl<'a> LockedHelper<'a> {
 		let key_param = key.state.as_option_mut().unwrap();
 		let bucket_p_local_alias_key = (key.key_id.clone(), alias_name.clone());
 
-		let alias_ts = increment_logical_clock_2(
+		let alias_ts = self.0.clock.increment_logical_clock_2(
 			key_param.local_aliases.get_timestamp(alias_name),
 			bucket_p
 				.local_aliases
@@ -325,6 +335,52 @@ impl<'a> LockedHelper<'a> {
 		Ok(())
 	}
 
+	/// Ensures a key does not have a certain local alias to a bucket.
+	/// Contrarily to unset_local_bucket_alias, this does not
+	/// fail on any condition other than:
+	/// - bucket cannot be found (its fine if it is in deleted state)
+	/// - key cannot be found (its fine if it is in deleted state)
+	/// - alias points to nothing or to another bucket in the key's
+	///   namespace (then only the bucket's side is updated)
+	pub async fn purge_local_bucket_alias(
+		&self,
+		bucket_id: Uuid,
+		key_id: &String,
+		alias_name: &String,
+	) -> Result<(), Error> {
+		let mut bucket = self.bucket().get_internal_bucket(bucket_id).await?;
+		let mut key = self.key().get_internal_key(key_id).await?;
+
+		let bucket_p_local_alias_key = (key.key_id.clone(), alias_name.clone());
+
+		let alias_ts = self.0.clock.increment_logical_clock_2(
+			key.params()
+				.map(|p| p.local_aliases.get_timestamp(alias_name))
+				.unwrap_or(0),
+			bucket
+				.params()
+				.map(|p| p.local_aliases.get_timestamp(&bucket_p_local_alias_key))
+				.unwrap_or(0),
+		);
+
+		// ---- timestamp-ensured causality barrier ----
+		// writes are now done and all writes use timestamp alias_ts
+
+		if let Some(key_param) = key.state.as_option_mut() {
+			if key_param.local_aliases.get(alias_name).cloned().flatten() == Some(bucket_id) {
+				key_param.local_aliases = LwwMap::raw_item(alias_name.clone(), alias_ts, None);
+				self.0.key_table.insert(&key).await?;
+			}
+		}
+
+		if let Some(bucket_p) = bucket.state.as_option_mut() {
+			bucket_p.local_aliases = LwwMap::raw_item(bucket_p_local_alias_key, alias_ts, false);
+			self.0.bucket_table.insert(&bucket).await?;
+		}
+
+		Ok(())
+	}
+
 	/// Sets permissions for a key on a bucket.
 	/// This function fails if:
 	/// - bucket or key cannot be found at all (its ok if they are in deleted state)
@@ -343,7 +399,10 @@ impl<'a> LockedHelper<'a> {
 
 		if let Some(bstate) = bucket.state.as_option() {
 			if let Some(kp) = bstate.authorized_keys.get(key_id) {
-				perm.timestamp = increment_logical_clock_2(perm.timestamp, kp.timestamp);
+				perm.timestamp = self
+					.0
+					.clock
+					.increment_logical_clock_2(perm.timestamp, kp.timestamp);
 			}
 		} else if perm.is_any() {
 			return Err(Error::BadRequest(
@@ -353,7 +412,10 @@ impl<'a> LockedHelper<'a> {
 
 		if let Some(kstate) = key.state.as_option() {
 			if let Some(bp) = kstate.authorized_buckets.get(&bucket_id) {
-				perm.timestamp = increment_logical_clock_2(perm.timestamp, bp.timestamp);
+				perm.timestamp = self
+					.0
+					.clock
+					.increment_logical_clock_2(perm.timestamp, bp.timestamp);
 			}
 		} else if perm.is_any() {
 			return Err(Error::BadRequest(
@@ -378,6 +440,140 @@ impl<'a> LockedHelper<'a> {
 
 	// ----
 
+	/// Deletes a bucket. All permissions of keys on the bucket are removed,
+	/// as well as all of its aliases, including local aliases that are only
+	/// still referenced on the key's side. The bucket's configuration
+	/// (website, CORS, lifecycle, quotas...) is cleared before the bucket
+	/// is marked as deleted.
+	/// The caller is responsible for checking that the bucket is empty.
+	pub async fn delete_bucket(&self, bucket_id: Uuid) -> Result<(), Error> {
+		let bucket = self.bucket().get_existing_bucket(bucket_id).await?;
+		let state = bucket.state.as_option().unwrap();
+
+		// Objects are checked to be absent by the caller, but the counters
+		// are propagated asynchronously so they can be temporarily out of date:
+		// only warn if they are inconsistent with what was checked.
+		if let Some(counters) = self
+			.0
+			.object_counter_table
+			.table
+			.get(&bucket_id, &EmptyKey)
+			.await?
+		{
+			let counters = counters.filtered_values(&self.0.system.cluster_layout());
+			let objects = counters.get(OBJECTS).cloned().unwrap_or(0);
+			let unfinished_uploads = counters.get(UNFINISHED_UPLOADS).cloned().unwrap_or(0);
+			if objects > 0 || unfinished_uploads > 0 {
+				warn!(
+					"Deleting bucket {:?} whose counters are not zero ({} objects, {} unfinished uploads)",
+					bucket_id, objects, unfinished_uploads
+				);
+			}
+		}
+
+		// --- done checking, now commit ---
+		// 1. delete authorization from keys that had access
+		for (key_id, perm) in state.authorized_keys.items().iter() {
+			if perm.is_any() {
+				self.set_bucket_key_permissions(bucket_id, key_id, BucketKeyPerm::NO_PERMISSIONS)
+					.await?;
+			}
+		}
+
+		// 2. delete all global aliases
+		for (alias, _, active) in state.aliases.items().iter() {
+			if *active {
+				self.purge_global_bucket_alias(bucket_id, alias).await?;
+			}
+		}
+
+		// 3. delete all local aliases, including those that are
+		// only present in the key's namespace
+		let mut local_aliases = state
+			.local_aliases
+			.items()
+			.iter()
+			.filter(|(_, _, active)| *active)
+			.map(|((key_id, alias), _, _)| (key_id.clone(), alias.clone()))
+			.collect::<BTreeSet<_>>();
+		local_aliases.extend(self.list_local_aliases_to_bucket(bucket_id).await?);
+		for (key_id, alias) in local_aliases.iter() {
+			self.purge_local_bucket_alias(bucket_id, key_id, alias)
+				.await?;
+		}
+
+		// 4. clear bucket configuration
+		let mut bucket = self.bucket().get_existing_bucket(bucket_id).await?;
+		let state = bucket.state.as_option_mut().unwrap();
+		state.website_config.update(None);
+		state.cors_config.update(None);
+		state.lifecycle_config.update(None);
+		state.quotas.update(BucketQuotas::default());
+		state.read_only.update(false);
+		state.requester_pays.update(false);
+		state.previous_version_retention_secs.update(None);
+		state.forbidden_as_not_found.update(false);
+		state.replication_factor.update(None);
+		state.case_insensitive_keys.update(false);
+		state.logging_config.update(None);
+		self.0.bucket_table.insert(&bucket).await?;
+
+		// 5. delete bucket
+		bucket.state = Deletable::delete();
+		self.0.bucket_table.insert(&bucket).await?;
+
+		Ok(())
+	}
+
+	/// Lists the local aliases that point to a bucket in the namespace
+	/// of all existing keys, as (key id, alias name) pairs
+	async fn list_local_aliases_to_bucket(
+		&self,
+		bucket_id: Uuid,
+	) -> Result<Vec<(String, String)>, Error> {
+		let mut ret = vec![];
+		let mut start = None;
+
+		loop {
+			let keys = self
+				.0
+				.key_table
+				.get_range(
+					&EmptyKey,
+					start.clone(),
+					Some(KeyFilter::Deleted(DeletedFilter::NotDeleted)),
+					1000,
+					EnumerationOrder::Forward,
+				)
+				.await?;
+
+			for key in keys.iter() {
+				if Some(&key.key_id) == start.as_ref() {
+					continue;
+				}
+				let key_p = match key.params() {
+					Some(p) => p,
+					None => continue,
+				};
+				for (alias, _, to) in key_p.local_aliases.items().iter() {
+					if *to == Some(bucket_id) {
+						ret.push((key.key_id.clone(), alias.clone()));
+					}
+				}
+			}
+
+			if keys.len() < 1000 {
+				break;
+			} else {
+				start = Some(keys.last().unwrap().key_id.clone());
+			}
+		}
+
+		Ok(ret)
+	}
+
+	// ----
+
 	/// Deletes an API access key
 	pub async fn delete_key(&self, key: &mut Key) -> Result<(), Error> {
 		let state = key.state.as_option_mut().unwrap();
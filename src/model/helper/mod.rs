@@ -2,3 +2,115 @@ pub mod bucket;
 pub mod error;
 pub mod key;
 pub mod locked;
+
+use error::Error;
+
+/// An entry matched by a pattern given by an administrator to designate
+/// a bucket or a key, i.e. a prefix of its ID or of its name
+pub(crate) struct PatternMatch<T> {
+	pub id: T,
+	/// How the entry is shown if the pattern matches several entries
+	pub description: String,
+	/// Whether the pattern is the full ID or name of the entry
+	pub exact: bool,
+}
+
+/// Select the entry designated by a pattern among the entries it matches:
+/// the only entry matched, or the only one that the pattern designates
+/// exactly if it is also a prefix of other entries. An entry can be given
+/// several times, e.g. if the pattern is a prefix of both its ID and its name.
+pub(crate) fn select_pattern_match<T: PartialEq>(
+	what: &str,
+	pattern: &str,
+	matches: Vec<PatternMatch<T>>,
+) -> Result<T, Error> {
+	let mut candidates: Vec<PatternMatch<T>> = vec![];
+	for m in matches {
+		match candidates.iter_mut().find(|c| c.id == m.id) {
+			Some(c) => c.exact |= m.exact,
+			None => candidates.push(m),
+		}
+	}
+
+	if candidates.iter().filter(|c| c.exact).count() == 1 {
+		candidates.retain(|c| c.exact);
+	}
+	match candidates.len() {
+		0 => Err(Error::BadRequest(format!(
+			"No {} matches `{}`",
+			what, pattern
+		))),
+		1 => Ok(candidates.pop().unwrap().id),
+		_ => Err(Error::BadRequest(format!(
+			"Several {}s match `{}`: {}",
+			what,
+			pattern,
+			candidates
+				.iter()
+				.map(|c| c.description.as_str())
+				.collect::<Vec<_>>()
+				.join(", ")
+		))),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn m(id: u32, description: &str, exact: bool) -> PatternMatch<u32> {
+		PatternMatch {
+			id,
+			description: description.into(),
+			exact,
+		}
+	}
+
+	#[test]
+	fn test_select_pattern_match() {
+		assert_eq!(
+			select_pattern_match("bucket", "pho", vec![m(1, "photos", false)]).unwrap(),
+			1
+		);
+
+		// The same entry matched twice is not ambiguous
+		assert_eq!(
+			select_pattern_match(
+				"bucket",
+				"ab",
+				vec![m(1, "abcd", false), m(1, "ab12", false)]
+			)
+			.unwrap(),
+			1
+		);
+
+		// An exact match wins over prefix matches
+		assert_eq!(
+			select_pattern_match(
+				"key",
+				"test",
+				vec![m(1, "test2", false), m(2, "test", true)]
+			)
+			.unwrap(),
+			2
+		);
+
+		// Ambiguous patterns list the candidates
+		let err = select_pattern_match(
+			"bucket",
+			"ph",
+			vec![m(1, "photos", false), m(2, "phones", false)],
+		)
+		.unwrap_err();
+		assert_eq!(
+			err.to_string(),
+			"Bad request: Several buckets match `ph`: photos, phones"
+		);
+		let err = select_pattern_match("key", "test", vec![m(1, "test", true), m(2, "test", true)])
+			.unwrap_err();
+		assert!(err.to_string().contains("Several keys"));
+
+		let err = select_pattern_match::<u32>("key", "nope", vec![]).unwrap_err();
+		assert_eq!(err.to_string(), "Bad request: No key matches `nope`");
+	}
+}
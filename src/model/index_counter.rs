@@ -249,6 +249,59 @@ impl<T: CountedItem> IndexCounter<T> {
 		Ok(())
 	}
 
+	/// Values of the counters maintained by this node for an entry
+	pub fn get_local(&self, pk: &T::CP, sk: &T::CS) -> Result<BTreeMap<String, i64>, Error> {
+		let tree_key = self.table.data.tree_key(pk, sk);
+		match self.local_counter.get(&tree_key[..])? {
+			Some(bytes) => Ok(LocalCounterEntry::<T>::decode(&bytes)
+				.ok_or_message("Cannot decode local counter entry")?
+				.values
+				.into_iter()
+				.map(|(name, (_, v))| (name, v))
+				.collect()),
+			None => Ok(BTreeMap::new()),
+		}
+	}
+
+	/// Correct the counters maintained by this node for an entry, that
+	/// have drifted by `drift` from the values they should have (as computed
+	/// by `counter_drift`), and propagate the new values to the counter table
+	pub fn correct_local(
+		&self,
+		pk: &T::CP,
+		sk: &T::CS,
+		drift: &BTreeMap<String, i64>,
+	) -> Result<(), Error> {
+		let tree_key = self.table.data.tree_key(pk, sk);
+
+		self.local_counter.db().transaction(|tx| {
+			let mut entry = match tx.get(&self.local_counter, &tree_key[..])? {
+				Some(old_bytes) => LocalCounterEntry::<T>::decode(&old_bytes)
+					.ok_or_message("Cannot decode local counter entry")
+					.map_err(db::TxError::Abort)?,
+				None => LocalCounterEntry {
+					pk: pk.clone(),
+					sk: sk.clone(),
+					values: BTreeMap::new(),
+				},
+			};
+			entry.correct(drift, now_msec());
+
+			let new_entry_bytes = entry
+				.encode()
+				.map_err(Error::RmpEncode)
+				.map_err(db::TxError::Abort)?;
+			tx.insert(&self.local_counter, &tree_key[..], new_entry_bytes)?;
+
+			let dist_entry = entry.into_counter_entry(self.this_node);
+			self.table.queue_insert(tx, &dist_entry)?;
+
+			Ok(())
+		})?;
+
+		Ok(())
+	}
+
 	pub fn offline_recount_all<TS, TR>(
 		&self,
 		counted_table: &Arc<Table<TS, TR>>,
@@ -373,7 +426,33 @@ impl<T: CountedItem> IndexCounter<T> {
 
 // ----
 
+/// Difference between the values of counters and the values they should
+/// have, for the counters that are wrong (absent counters count as zero)
+pub fn counter_drift(
+	counters: &BTreeMap<String, i64>,
+	actual: &BTreeMap<String, i64>,
+) -> BTreeMap<String, i64> {
+	counters
+		.keys()
+		.chain(actual.keys())
+		.map(|name| {
+			let value = counters.get(name).copied().unwrap_or(0);
+			let expected = actual.get(name).copied().unwrap_or(0);
+			(name.clone(), value - expected)
+		})
+		.filter(|(_, d)| *d != 0)
+		.collect()
+}
+
 impl<T: CountedItem> LocalCounterEntry<T> {
+	fn correct(&mut self, drift: &BTreeMap<String, i64>, now: u64) {
+		for (name, d) in drift.iter() {
+			let ent = self.values.entry(name.clone()).or_insert((0, 0));
+			ent.0 = std::cmp::max(ent.0 + 1, now);
+			ent.1 -= *d;
+		}
+	}
+
 	fn into_counter_entry(self, this_node: Uuid) -> CounterEntry<T> {
 		CounterEntry {
 			pk: self.pk,
@@ -390,3 +469,85 @@ impl<T: CountedItem> LocalCounterEntry<T> {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use crate::s3::object_table::{Object, BYTES, OBJECTS, UNFINISHED_UPLOADS};
+
+	fn values(v: &[(&str, i64)]) -> BTreeMap<String, i64> {
+		v.iter().map(|(k, v)| (k.to_string(), *v)).collect()
+	}
+
+	#[test]
+	fn test_counter_drift() {
+		let actual = values(&[(OBJECTS, 10), (UNFINISHED_UPLOADS, 0), (BYTES, 1000)]);
+
+		assert!(counter_drift(&actual, &actual).is_empty());
+
+		// Counters that count too much and too little
+		let counters = values(&[(OBJECTS, 12), (UNFINISHED_UPLOADS, 0), (BYTES, 900)]);
+		assert_eq!(
+			counter_drift(&counters, &actual),
+			values(&[(OBJECTS, 2), (BYTES, -100)])
+		);
+
+		// Missing counters and missing objects
+		assert_eq!(
+			counter_drift(&BTreeMap::new(), &actual),
+			values(&[(OBJECTS, -10), (BYTES, -1000)])
+		);
+		assert_eq!(
+			counter_drift(&counters, &BTreeMap::new()),
+			values(&[(OBJECTS, 12), (BYTES, 900)])
+		);
+	}
+
+	#[test]
+	fn test_correct_local_counter() {
+		let now = 1_700_000_000_000;
+		let mut entry = LocalCounterEntry::<Object> {
+			pk: Uuid::from([1u8; 32]),
+			sk: EmptyKey,
+			values: vec![
+				(OBJECTS.to_string(), (now + 10, 12)),
+				(BYTES.to_string(), (now - 10, 900)),
+			]
+			.into_iter()
+			.collect(),
+		};
+		let actual = values(&[(OBJECTS, 10), (UNFINISHED_UPLOADS, 1), (BYTES, 1000)]);
+
+		let before = entry.values.clone();
+		let drift = counter_drift(
+			&before.iter().map(|(k, (_, v))| (k.clone(), *v)).collect(),
+			&actual,
+		);
+		entry.correct(&drift, now);
+
+		assert_eq!(
+			entry.values,
+			vec![
+				(OBJECTS.to_string(), (now + 11, 10)),
+				(UNFINISHED_UPLOADS.to_string(), (now, 1)),
+				(BYTES.to_string(), (now, 1000)),
+			]
+			.into_iter()
+			.collect()
+		);
+
+		// The corrected values win over the previous ones in the counter table
+		let mut merged = LocalCounterEntry::<Object> {
+			pk: entry.pk,
+			sk: EmptyKey,
+			values: before,
+		}
+		.into_counter_entry(Uuid::from([2u8; 32]));
+		merged.merge(&entry.clone().into_counter_entry(Uuid::from([2u8; 32])));
+		assert_eq!(
+			merged.filtered_values_with_nodes(&[Uuid::from([2u8; 32])]),
+			actual.into_iter().collect()
+		);
+	}
+}
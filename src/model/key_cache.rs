@@ -0,0 +1,180 @@
+//! In-memory cache of the access keys used to check the signature of
+//! requests, so that a request does not need to read the key table.
+//!
+//! A key is kept in the cache for at most `key_cache_ttl_msec` (5 seconds
+//! by default). Keys are removed from the cache of a node as soon as a change
+//! to them is written to its copy of the key table, so the cache is only
+//! stale when a change made on another node has not reached this node yet:
+//! in that case, a permission change or a key deletion can take up to the
+//! TTL to be enforced by this node, in addition to the time needed to
+//! propagate the change.
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::key_table::Key;
+
+/// TTL of the cached keys if `key_cache_ttl_msec` is not set
+pub const DEFAULT_KEY_CACHE_TTL: Duration = Duration::from_secs(5);
+
+// Above this number of cached keys, expired keys are removed
+// from the cache when a new key is added
+const KEY_CACHE_PURGE_THRESHOLD: usize = 10000;
+
+struct CachedKey {
+	key: Key,
+	expires_at: Instant,
+}
+
+pub struct KeyCache {
+	ttl: Duration,
+	keys: Mutex<HashMap<String, CachedKey>>,
+}
+
+impl KeyCache {
+	/// Create a cache keeping keys for `ttl` (a zero TTL disables the cache)
+	pub fn new(ttl: Duration) -> Self {
+		Self {
+			ttl,
+			keys: Mutex::new(HashMap::new()),
+		}
+	}
+
+	/// Return the key with the given ID from the cache if it was read
+	/// less than the TTL ago, or read it with `fetch` otherwise.
+	/// Keys that do not exist are not cached.
+	pub async fn get_or_fetch<F, Fut, E>(
+		&self,
+		key_id: &str,
+		now: Instant,
+		fetch: F,
+	) -> Result<Option<Key>, E>
+	where
+		F: FnOnce() -> Fut,
+		Fut: Future<Output = Result<Option<Key>, E>>,
+	{
+		if let Some(key) = self.get(key_id, now) {
+			return Ok(Some(key));
+		}
+		let key = fetch().await?;
+		if let Some(key) = &key {
+			self.insert(key.clone(), now);
+		}
+		Ok(key)
+	}
+
+	fn get(&self, key_id: &str, now: Instant) -> Option<Key> {
+		let mut keys = self.keys.lock().unwrap();
+		match keys.get(key_id) {
+			Some(cached) if now < cached.expires_at => Some(cached.key.clone()),
+			Some(_) => {
+				keys.remove(key_id);
+				None
+			}
+			None => None,
+		}
+	}
+
+	fn insert(&self, key: Key, now: Instant) {
+		if self.ttl.is_zero() {
+			return;
+		}
+		let mut keys = self.keys.lock().unwrap();
+		if keys.len() >= KEY_CACHE_PURGE_THRESHOLD {
+			keys.retain(|_, cached| now < cached.expires_at);
+		}
+		keys.insert(
+			key.key_id.clone(),
+			CachedKey {
+				key,
+				expires_at: now + self.ttl,
+			},
+		);
+	}
+
+	/// Remove a key from the cache, called when it is changed
+	pub fn invalidate(&self, key_id: &str) {
+		self.keys.lock().unwrap().remove(key_id);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	struct FakeTable {
+		key: Mutex<Key>,
+		reads: AtomicUsize,
+	}
+
+	impl FakeTable {
+		async fn get(&self) -> Result<Option<Key>, ()> {
+			self.reads.fetch_add(1, Ordering::Relaxed);
+			Ok(Some(self.key.lock().unwrap().clone()))
+		}
+	}
+
+	async fn check_key(cache: &KeyCache, table: &FakeTable, now: Instant) -> bool {
+		let key = cache
+			.get_or_fetch("GK1", now, || table.get())
+			.await
+			.unwrap();
+		key.filter(|k| !k.state.is_deleted()).is_some()
+	}
+
+	#[tokio::test]
+	async fn test_key_cache_ttl() {
+		let ttl = Duration::from_secs(5);
+		let cache = KeyCache::new(ttl);
+		let mut key = Key::new("test");
+		key.key_id = "GK1".into();
+		let table = FakeTable {
+			key: Mutex::new(key),
+			reads: AtomicUsize::new(0),
+		};
+
+		// Repeated requests within the TTL read the table only once
+		let start = Instant::now();
+		for i in 0..10 {
+			assert!(check_key(&cache, &table, start + i * ttl / 10).await);
+		}
+		assert_eq!(table.reads.load(Ordering::Relaxed), 1);
+
+		// A key deleted on another node can still be used until the TTL...
+		*table.key.lock().unwrap() = Key::delete("GK1".into());
+		assert!(check_key(&cache, &table, start + ttl / 2).await);
+		assert_eq!(table.reads.load(Ordering::Relaxed), 1);
+
+		// ...after which it is rejected
+		assert!(!check_key(&cache, &table, start + ttl).await);
+		assert_eq!(table.reads.load(Ordering::Relaxed), 2);
+	}
+
+	#[tokio::test]
+	async fn test_key_cache_invalidation() {
+		let cache = KeyCache::new(Duration::from_secs(5));
+		let mut key = Key::new("test");
+		key.key_id = "GK1".into();
+		let table = FakeTable {
+			key: Mutex::new(key),
+			reads: AtomicUsize::new(0),
+		};
+
+		let now = Instant::now();
+		assert!(check_key(&cache, &table, now).await);
+
+		// A key deleted on this node is rejected immediately
+		*table.key.lock().unwrap() = Key::delete("GK1".into());
+		cache.invalidate("GK1");
+		assert!(!check_key(&cache, &table, now).await);
+		assert_eq!(table.reads.load(Ordering::Relaxed), 2);
+
+		// With a zero TTL, the table is read by each request
+		let cache = KeyCache::new(Duration::ZERO);
+		assert!(!check_key(&cache, &table, now).await);
+		assert!(!check_key(&cache, &table, now).await);
+		assert_eq!(table.reads.load(Ordering::Relaxed), 4);
+	}
+}
@@ -0,0 +1,538 @@
+//! Worker that deletes access keys that have not been used for a long time,
+//! as configured by `security.auto_expire_unused_keys_after_days`.
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::header::CONTENT_TYPE;
+use hyper::{Method, Request, Uri};
+use hyper_rustls::HttpsConnector;
+use hyper_util::client::legacy::{connect::HttpConnector, Client as HttpClient};
+use hyper_util::rt::TokioExecutor;
+use tokio::sync::watch;
+
+use garage_table::*;
+use garage_util::background::*;
+use garage_util::error::Error;
+use garage_util::time::*;
+
+use crate::garage::Garage;
+use crate::key_table::*;
+
+const DAY_MSEC: u64 = 24 * 3600 * 1000;
+
+// Keys are checked for expiry once a week
+const KEY_EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(7 * 24 * 3600);
+// The first check is done shortly after startup
+const KEY_EXPIRY_FIRST_CHECK_DELAY: Duration = Duration::from_secs(3600);
+// Number of keys read from the key table at once
+const KEY_EXPIRY_BATCH_SIZE: usize = 1000;
+// Timeout of the requests to the warning webhook
+const KEY_EXPIRY_WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyExpiryStatus {
+	/// The key has been used recently enough
+	Active,
+	/// The key is not subject to automatic expiry (system key or deleted key)
+	Exempt,
+	/// The key has no last used timestamp (created before this was tracked)
+	Unknown,
+	/// The key will expire in the given number of days
+	ExpiringSoon(u64),
+	/// The key has not been used in the retention period and must be deleted
+	Expired,
+}
+
+/// Compute whether a key has to be expired at time `now`, given a retention
+/// period and an optionnal warning period (both in days)
+pub fn key_expiry_status(
+	key: &Key,
+	now: u64,
+	retention_days: u64,
+	warning_days: Option<u64>,
+) -> KeyExpiryStatus {
+	let params = match key.params() {
+		Some(p) if !*p.system.get() => p,
+		_ => return KeyExpiryStatus::Exempt,
+	};
+	let last_used = match params.last_used_at {
+		Some(t) => t,
+		None => return KeyExpiryStatus::Unknown,
+	};
+
+	let expires_at = last_used.saturating_add(retention_days.saturating_mul(DAY_MSEC));
+	if now >= expires_at {
+		return KeyExpiryStatus::Expired;
+	}
+
+	match warning_days {
+		Some(w) if now + w.saturating_mul(DAY_MSEC) >= expires_at => {
+			KeyExpiryStatus::ExpiringSoon((expires_at - now).div_ceil(DAY_MSEC))
+		}
+		_ => KeyExpiryStatus::Active,
+	}
+}
+
+/// Sends the warnings about keys that will expire soon
+/// to `security.auto_expire_warning_webhook`
+struct KeyExpiryWebhook {
+	client: HttpClient<HttpsConnector<HttpConnector>, Full<Bytes>>,
+	uri: Uri,
+}
+
+impl KeyExpiryWebhook {
+	fn new(url: &str) -> Result<Self, Error> {
+		let uri = Uri::try_from(url)
+			.ok()
+			.filter(|u| matches!(u.scheme_str(), Some("http") | Some("https")))
+			.ok_or_else(|| {
+				Error::Message(format!(
+					"Invalid security.auto_expire_warning_webhook {:?}, expected an http:// or https:// URL",
+					url
+				))
+			})?;
+		let connector = hyper_rustls::HttpsConnectorBuilder::new()
+			.with_native_roots()
+			.map_err(|e| Error::Message(format!("Cannot load TLS root certificates: {}", e)))?
+			.https_or_http()
+			.enable_http1()
+			.build();
+		Ok(Self {
+			client: HttpClient::builder(TokioExecutor::new()).build(connector),
+			uri,
+		})
+	}
+
+	async fn send(&self, key: &Key, days_left: u64) -> Result<(), Error> {
+		let params = key.params().unwrap();
+		let body = serde_json::json!({
+			"event": "KeyExpiringSoon",
+			"accessKeyId": key.key_id,
+			"name": params.name.get(),
+			"lastUsedAt": params.last_used_at.map(msec_to_rfc3339),
+			"expiresInDays": days_left,
+		});
+		let req = Request::builder()
+			.method(Method::POST)
+			.uri(self.uri.clone())
+			.header(CONTENT_TYPE, "application/json")
+			.body(Full::new(Bytes::from(body.to_string())))
+			.map_err(|e| Error::Message(format!("Invalid webhook request: {}", e)))?;
+
+		let resp = tokio::time::timeout(KEY_EXPIRY_WEBHOOK_TIMEOUT, self.client.request(req))
+			.await
+			.map_err(|_| Error::Message("Timeout".into()))?
+			.map_err(|e| Error::Message(format!("{}", e)))?;
+		let status = resp.status();
+		// Read the body so that the connection can be reused
+		let _ = resp.into_body().collect().await;
+		if status.is_success() {
+			Ok(())
+		} else {
+			Err(Error::Message(format!("Webhook returned {}", status)))
+		}
+	}
+}
+
+pub struct KeyExpiryWorker {
+	garage: Arc<Garage>,
+	retention_days: u64,
+	warning_days: Option<u64>,
+	webhook: Option<KeyExpiryWebhook>,
+	next_check: Instant,
+	last_check_deleted: usize,
+}
+
+impl KeyExpiryWorker {
+	pub(crate) fn new(garage: Arc<Garage>, retention_days: u64) -> Result<Self, Error> {
+		let security = &garage.config.security;
+		let warning_days = security.auto_expire_warning_days_before;
+		let webhook = match &security.auto_expire_warning_webhook {
+			Some(url) => Some(KeyExpiryWebhook::new(url)?),
+			None => None,
+		};
+		Ok(Self {
+			garage,
+			retention_days,
+			warning_days,
+			webhook,
+			next_check: Instant::now() + KEY_EXPIRY_FIRST_CHECK_DELAY,
+			last_check_deleted: 0,
+		})
+	}
+
+	async fn check_all_keys(&self) -> Result<usize, Error> {
+		let now = now_msec();
+		let mut deleted = 0;
+		let mut start = None;
+		loop {
+			let keys = self
+				.garage
+				.key_table
+				.get_range(
+					&EmptyKey,
+					start.clone(),
+					Some(KeyFilter::Deleted(DeletedFilter::NotDeleted)),
+					KEY_EXPIRY_BATCH_SIZE,
+					EnumerationOrder::Forward,
+				)
+				.await?;
+			let truncated = keys.len() >= KEY_EXPIRY_BATCH_SIZE;
+			let last_key_id = keys.last().map(|k| k.key_id.clone());
+
+			for key in keys {
+				if Some(&key.key_id) == start.as_ref() {
+					// Already checked in the previous batch
+					continue;
+				}
+				if self.check_key(key, now).await? {
+					deleted += 1;
+				}
+			}
+
+			match last_key_id {
+				Some(k) if truncated => start = Some(k),
+				_ => break,
+			}
+		}
+
+		Ok(deleted)
+	}
+
+	/// Check a key for expiry, returns true if it has been deleted
+	async fn check_key(&self, key: Key, now: u64) -> Result<bool, Error> {
+		match key_expiry_status(&key, now, self.retention_days, self.warning_days) {
+			KeyExpiryStatus::Active | KeyExpiryStatus::Exempt => (),
+			KeyExpiryStatus::Unknown => {
+				// Start counting the retention period from now. The key is
+				// read again under the helper lock, so that changes made to
+				// it concurrently by the CLI or the admin API are not lost.
+				let _helper = self.garage.locked_helper().await;
+				let key = self.garage.key_table.get(&EmptyKey, &key.key_id).await?;
+				if let Some(mut key) = key {
+					if let Some(params) = key.params_mut() {
+						if params.last_used_at.is_none() {
+							params.last_used_at = Some(now);
+							self.garage.key_table.insert(&key).await?;
+						}
+					}
+				}
+			}
+			KeyExpiryStatus::ExpiringSoon(days_left) => {
+				warn!(
+					"Access key {} ({}) has not been used recently and will be deleted in {} days",
+					key.key_id,
+					key.params().unwrap().name.get(),
+					days_left
+				);
+				if let Some(webhook) = &self.webhook {
+					if let Err(e) = webhook.send(&key, days_left).await {
+						warn!(
+							"Could not send the expiry warning of access key {} to the webhook: {}",
+							key.key_id, e
+						);
+					}
+				}
+			}
+			KeyExpiryStatus::Expired => {
+				// The key may have been used or modified since it was read:
+				// read it again under the helper lock, and check that it has
+				// still expired before deleting it.
+				let helper = self.garage.locked_helper().await;
+				let key = self.garage.key_table.get(&EmptyKey, &key.key_id).await?;
+				let mut key = match key {
+					Some(k)
+						if key_expiry_status(&k, now, self.retention_days, None)
+							== KeyExpiryStatus::Expired =>
+					{
+						k
+					}
+					_ => return Ok(false),
+				};
+				warn!(
+					"Deleting access key {} ({}): not used since {}",
+					key.key_id,
+					key.params().unwrap().name.get(),
+					msec_to_rfc3339(key.params().unwrap().last_used_at.unwrap_or_default())
+				);
+				helper
+					.delete_key(&mut key)
+					.await
+					.map_err(|e| Error::Message(format!("{}", e)))?;
+				return Ok(true);
+			}
+		}
+
+		Ok(false)
+	}
+}
+
+#[async_trait]
+impl Worker for KeyExpiryWorker {
+	fn name(&self) -> String {
+		"Unused key expiry worker".into()
+	}
+
+	fn status(&self) -> WorkerStatus {
+		WorkerStatus {
+			freeform: vec![
+				format!("Retention period: {} days", self.retention_days),
+				format!("Keys deleted in last check: {}", self.last_check_deleted),
+				format!(
+					"Next check: {}",
+					(chrono::Utc::now() + (self.next_check - Instant::now())).to_rfc3339()
+				),
+			],
+			..Default::default()
+		}
+	}
+
+	async fn work(&mut self, _must_exit: &mut watch::Receiver<bool>) -> Result<WorkerState, Error> {
+		if Instant::now() < self.next_check {
+			return Ok(WorkerState::Idle);
+		}
+
+		self.last_check_deleted = self.check_all_keys().await?;
+		self.next_check = Instant::now() + KEY_EXPIRY_CHECK_INTERVAL;
+
+		Ok(WorkerState::Idle)
+	}
+
+	async fn wait_for_work(&mut self) -> WorkerState {
+		tokio::time::sleep_until(self.next_check.into()).await;
+		WorkerState::Busy
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use rand::Rng;
+	use tokio::io::{AsyncReadExt, AsyncWriteExt};
+	use tokio::net::TcpListener;
+	use tokio::sync::mpsc;
+
+	use garage_rpc::layout::{NodeRole, NodeRoleV};
+	use garage_util::config::read_config;
+	use garage_util::crdt::Crdt;
+
+	fn key_last_used(last_used_at: Option<u64>) -> Key {
+		let mut key = Key::new("test");
+		key.params_mut().unwrap().last_used_at = last_used_at;
+		key
+	}
+
+	#[test]
+	fn test_key_expiry() {
+		let now = 1_000 * DAY_MSEC;
+
+		let key = key_last_used(Some(now - 10 * DAY_MSEC));
+		assert_eq!(
+			key_expiry_status(&key, now, 30, None),
+			KeyExpiryStatus::Active
+		);
+		assert_eq!(
+			key_expiry_status(&key, now, 10, None),
+			KeyExpiryStatus::Expired
+		);
+		assert_eq!(
+			key_expiry_status(&key, now, 15, Some(7)),
+			KeyExpiryStatus::ExpiringSoon(5)
+		);
+		assert_eq!(
+			key_expiry_status(&key, now + 20 * DAY_MSEC, 30, None),
+			KeyExpiryStatus::Expired
+		);
+
+		let key = key_last_used(None);
+		assert_eq!(
+			key_expiry_status(&key, now, 30, None),
+			KeyExpiryStatus::Unknown
+		);
+	}
+
+	#[test]
+	fn test_system_key_never_expires() {
+		let mut key = key_last_used(Some(0));
+		key.params_mut().unwrap().system.update(true);
+		assert_eq!(
+			key_expiry_status(&key, 1_000 * DAY_MSEC, 30, None),
+			KeyExpiryStatus::Exempt
+		);
+
+		let key = Key::delete("GK0123".into());
+		assert_eq!(
+			key_expiry_status(&key, 1_000 * DAY_MSEC, 30, None),
+			KeyExpiryStatus::Exempt
+		);
+	}
+
+	/// Start a single-node Garage whose key expiry warnings
+	/// are sent to `webhook`
+	async fn test_garage(webhook: &str) -> Arc<Garage> {
+		let path = std::env::temp_dir().join(format!(
+			"garage-key-expiry-test-{}",
+			hex::encode(rand::thread_rng().gen::<[u8; 8]>())
+		));
+		std::fs::create_dir_all(&path).unwrap();
+		let config = format!(
+			r#"
+metadata_dir = "{path}/meta"
+data_dir = "{path}/data"
+db_engine = "sqlite"
+replication_factor = 1
+rpc_bind_addr = "127.0.0.1:0"
+rpc_secret = "c3ea8cb80333d04e208d136698b1a01ae370d463f0d435ab2177510b3478bf44"
+
+[s3_api]
+s3_region = "garage"
+
+[security]
+auto_expire_unused_keys_after_days = 30
+auto_expire_warning_days_before = 7
+auto_expire_warning_webhook = "{webhook}"
+"#,
+			path = path.display(),
+			webhook = webhook,
+		);
+		std::fs::write(path.join("config.toml"), config).unwrap();
+		garage_util::version::init_version("test");
+		let garage = Garage::new(read_config(path.join("config.toml")).unwrap()).unwrap();
+
+		let mut layout = garage.system.cluster_layout().inner().clone();
+		let staging = layout.staging.get_mut();
+		let update = staging.roles.update_mutator(
+			garage.system.id,
+			NodeRoleV(Some(NodeRole {
+				zone: "dc1".into(),
+				capacity: Some(1 << 30),
+				tags: vec![],
+				non_voting: false,
+			})),
+		);
+		staging.roles.merge(&update);
+		let (layout, _) = layout.apply_staged_changes(Some(1)).unwrap();
+		garage
+			.system
+			.layout_manager
+			.update_cluster_layout(&layout)
+			.await
+			.unwrap();
+
+		garage
+	}
+
+	/// Receive the bodies of the requests made to an HTTP endpoint
+	async fn mock_webhook() -> (String, mpsc::UnboundedReceiver<serde_json::Value>) {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let url = format!("http://{}/hook", listener.local_addr().unwrap());
+		let (tx, rx) = mpsc::unbounded_channel();
+		tokio::spawn(async move {
+			loop {
+				let (mut conn, _) = listener.accept().await.unwrap();
+				let tx = tx.clone();
+				tokio::spawn(async move {
+					let mut buf = vec![];
+					let mut chunk = [0u8; 4096];
+					loop {
+						let n = conn.read(&mut chunk).await.unwrap();
+						if n == 0 {
+							return;
+						}
+						buf.extend_from_slice(&chunk[..n]);
+						let req = String::from_utf8_lossy(&buf).to_string();
+						let (head, body) = match req.split_once("\r\n\r\n") {
+							Some(x) => x,
+							None => continue,
+						};
+						let length = head
+							.lines()
+							.find_map(|l| {
+								l.to_lowercase()
+									.strip_prefix("content-length:")?
+									.trim()
+									.parse::<usize>()
+									.ok()
+							})
+							.unwrap_or(0);
+						if body.len() < length {
+							continue;
+						}
+						tx.send(serde_json::from_str(body).unwrap()).unwrap();
+						conn.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+							.await
+							.unwrap();
+						buf.clear();
+					}
+				});
+			}
+		});
+		(url, rx)
+	}
+
+	#[tokio::test]
+	async fn test_key_expiry_worker() {
+		let (url, mut warnings) = mock_webhook().await;
+		let garage = test_garage(&url).await;
+		let now = now_msec();
+
+		let expired = key_last_used(Some(now - 40 * DAY_MSEC));
+		let expiring = key_last_used(Some(now - 25 * DAY_MSEC));
+		let mut system = key_last_used(Some(0));
+		system.params_mut().unwrap().system.update(true);
+		let unknown = key_last_used(None);
+		for key in [&expired, &expiring, &system, &unknown] {
+			garage.key_table.insert(key).await.unwrap();
+		}
+
+		let worker = KeyExpiryWorker::new(garage.clone(), 30).unwrap();
+		assert_eq!(worker.check_all_keys().await.unwrap(), 1);
+
+		let get = |id: &String| {
+			let garage = garage.clone();
+			let id = id.clone();
+			async move { garage.key_table.get(&EmptyKey, &id).await.unwrap().unwrap() }
+		};
+		assert!(get(&expired.key_id).await.is_deleted());
+		assert!(!get(&expiring.key_id).await.is_deleted());
+		assert!(!get(&system.key_id).await.is_deleted());
+		let unknown = get(&unknown.key_id).await;
+		assert!(unknown.params().unwrap().last_used_at.unwrap() >= now);
+
+		let warning = warnings.recv().await.unwrap();
+		assert_eq!(warning["event"], "KeyExpiringSoon");
+		assert_eq!(warning["accessKeyId"], expiring.key_id.as_str());
+		assert_eq!(warning["name"], "test");
+		assert_eq!(warning["expiresInDays"], 5);
+		assert!(warnings.try_recv().is_err());
+	}
+
+	#[tokio::test]
+	async fn test_key_used_during_check_is_not_deleted() {
+		let (url, _warnings) = mock_webhook().await;
+		let garage = test_garage(&url).await;
+		let now = now_msec();
+
+		// The worker has read the key before it was used again
+		let stale = key_last_used(Some(now - 40 * DAY_MSEC));
+		garage.key_table.insert(&stale).await.unwrap();
+		let mut used = stale.clone();
+		used.params_mut().unwrap().last_used_at = Some(now);
+		garage.key_table.insert(&used).await.unwrap();
+
+		let worker = KeyExpiryWorker::new(garage.clone(), 30).unwrap();
+		assert!(!worker.check_key(stale.clone(), now).await.unwrap());
+		let key = garage
+			.key_table
+			.get(&EmptyKey, &stale.key_id)
+			.await
+			.unwrap()
+			.unwrap();
+		assert!(!key.is_deleted());
+	}
+}
@@ -1,10 +1,16 @@
+use std::sync::Arc;
+
 use serde::{Deserialize, Serialize};
 
+use garage_db as db;
+
 use garage_util::crdt::{self, Crdt};
 use garage_util::data::*;
+use garage_util::time::now_msec;
 
 use garage_table::{DeletedFilter, EmptyKey, Entry, TableSchema};
 
+use crate::key_cache::KeyCache;
 use crate::permission::BucketKeyPerm;
 
 mod v08 {
@@ -26,7 +32,8 @@ mod v08 {
 	/// Configuration for a key
 	#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
 	pub struct KeyParams {
-		/// The secret_key associated (immutable)
+		/// The secret_key associated when the key was created
+		/// (immutable, superseded by `rotated_secret_key` if it is set)
 		pub secret_key: String,
 
 		/// Name for the key
@@ -43,6 +50,29 @@ mod v08 {
 		/// A key can have a local view of buckets names it is
 		/// the only one to see, this is the namespace for these aliases
 		pub local_aliases: crdt::LwwMap<String, Option<Uuid>>,
+
+		/// Timestamp of the last time this key was used to sign a request
+		/// (not updated on every request, see `crate::key_usage`)
+		#[serde(default)]
+		pub last_used_at: Option<u64>,
+
+		/// Number of requests signed with this key, as counted
+		/// by each node of the cluster
+		#[serde(default)]
+		pub request_counts: crdt::Map<Uuid, u64>,
+
+		/// System keys are never automatically expired when unused
+		#[serde(default)]
+		pub system: crdt::Lww<bool>,
+
+		/// Secret key set by the last rotation of the key's secret, if any
+		#[serde(default)]
+		pub rotated_secret_key: crdt::Lww<Option<String>>,
+
+		/// Requests signed with a suspended key are rejected,
+		/// until the key is resumed
+		#[serde(default)]
+		pub suspended: crdt::Lww<bool>,
 	}
 
 	impl garage_util::migrate::InitialFormat for Key {}
@@ -58,8 +88,26 @@ impl KeyParams {
 			allow_create_bucket: crdt::Lww::new(false),
 			authorized_buckets: crdt::Map::new(),
 			local_aliases: crdt::LwwMap::new(),
+			last_used_at: Some(now_msec()),
+			request_counts: crdt::Map::new(),
+			system: crdt::Lww::new(false),
+			rotated_secret_key: crdt::Lww::new(None),
+			suspended: crdt::Lww::new(false),
 		}
 	}
+
+	/// The secret key that must be used to sign requests with this key
+	pub fn secret(&self) -> &str {
+		self.rotated_secret_key
+			.get()
+			.as_deref()
+			.unwrap_or(&self.secret_key)
+	}
+
+	/// Total number of requests signed with this key
+	pub fn request_count(&self) -> u64 {
+		self.request_counts.items().iter().map(|(_, n)| *n).sum()
+	}
 }
 
 impl Crdt for KeyParams {
@@ -68,6 +116,11 @@ impl Crdt for KeyParams {
 		self.allow_create_bucket.merge(&o.allow_create_bucket);
 		self.authorized_buckets.merge(&o.authorized_buckets);
 		self.local_aliases.merge(&o.local_aliases);
+		self.last_used_at = std::cmp::max(self.last_used_at, o.last_used_at);
+		self.request_counts.merge(&o.request_counts);
+		self.system.merge(&o.system);
+		self.rotated_secret_key.merge(&o.rotated_secret_key);
+		self.suspended.merge(&o.suspended);
 	}
 }
 
@@ -88,9 +141,7 @@ impl Key {
 			return Err("The specified key ID is not a valid Garage key ID (starts with `GK`, followed by 12 hex-encoded bytes)");
 		}
 
-		if secret_key.len() != 64 || hex::decode(&secret_key).is_err() {
-			return Err("The specified secret key is not a valid Garage secret key (composed of 32 hex-encoded bytes)");
-		}
+		check_secret_key(secret_key)?;
 
 		Ok(Self {
 			key_id: key_id.to_string(),
@@ -98,6 +149,22 @@ impl Key {
 		})
 	}
 
+	/// Replace the secret key of this key by `secret_key`, or by a newly
+	/// generated one if it is not given, and return the new secret key.
+	/// The key ID and the permissions of the key are left unchanged.
+	pub fn rotate_secret(&mut self, secret_key: Option<&str>) -> Result<String, &'static str> {
+		let secret_key = match secret_key {
+			Some(s) => {
+				check_secret_key(s)?;
+				s.to_string()
+			}
+			None => hex::encode(&rand::random::<[u8; 32]>()[..]),
+		};
+		let params = self.params_mut().ok_or("The key is deleted")?;
+		params.rotated_secret_key.update(Some(secret_key.clone()));
+		Ok(secret_key)
+	}
+
 	/// Create a new Key which can me merged to mark an existing key deleted
 	pub fn delete(key_id: String) -> Self {
 		Self {
@@ -121,6 +188,12 @@ impl Key {
 		self.state.as_option_mut()
 	}
 
+	/// Returns true if requests signed with this key are rejected
+	/// because it has been suspended
+	pub fn is_suspended(&self) -> bool {
+		self.params().map(|p| *p.suspended.get()).unwrap_or(false)
+	}
+
 	/// Get permissions for a bucket
 	pub fn bucket_permissions(&self, bucket: &Uuid) -> BucketKeyPerm {
 		self.params()
@@ -134,6 +207,11 @@ impl Key {
 		self.bucket_permissions(bucket).allow_read
 	}
 
+	/// Check if `Key` is allowed to list objects in bucket
+	pub fn allow_list(&self, bucket: &Uuid) -> bool {
+		self.bucket_permissions(bucket).can_list()
+	}
+
 	/// Check if `Key` is allowed to write in bucket
 	pub fn allow_write(&self, bucket: &Uuid) -> bool {
 		self.bucket_permissions(bucket).allow_write
@@ -145,6 +223,13 @@ impl Key {
 	}
 }
 
+fn check_secret_key(secret_key: &str) -> Result<(), &'static str> {
+	if secret_key.len() != 64 || hex::decode(secret_key).is_err() {
+		return Err("The specified secret key is not a valid Garage secret key (composed of 32 hex-encoded bytes)");
+	}
+	Ok(())
+}
+
 impl Entry<EmptyKey, String> for Key {
 	fn partition_key(&self) -> &EmptyKey {
 		&EmptyKey
@@ -160,7 +245,9 @@ impl Crdt for Key {
 	}
 }
 
-pub struct KeyTable;
+pub struct KeyTable {
+	pub key_cache: Arc<KeyCache>,
+}
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum KeyFilter {
@@ -185,10 +272,22 @@ impl TableSchema for KeyTable {
 					.params()
 					.map(|p| {
 						entry.key_id.to_lowercase().starts_with(&pat)
-							|| p.name.get().to_lowercase() == pat
+							|| p.name.get().to_lowercase().starts_with(&pat)
 					})
 					.unwrap_or(false)
 			}
 		}
 	}
+
+	fn updated(
+		&self,
+		_tx: &mut db::Transaction,
+		old: Option<&Self::E>,
+		new: Option<&Self::E>,
+	) -> db::TxOpResult<()> {
+		if let Some(key) = old.or(new) {
+			self.key_cache.invalidate(&key.key_id);
+		}
+		Ok(())
+	}
 }
@@ -0,0 +1,218 @@
+//! Tracking of the usage of access keys. Each node counts the requests
+//! signed with each key in memory, and a background worker regularly
+//! writes these counts to the key table, along with the time at which
+//! the keys were last used. This way, a key used by many requests causes
+//! at most one write to the key table per `KEY_USAGE_FLUSH_INTERVAL`.
+//!
+//! Each node stores its own request count in the `request_counts` map of
+//! the key, so that the counts of different nodes never conflict. If the
+//! key table cannot be written to, the counts are kept and written at the
+//! next flush. Counts that were not written yet when the node stops are lost.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use tokio::sync::watch;
+
+use garage_table::*;
+use garage_util::background::*;
+use garage_util::error::Error;
+
+use crate::garage::Garage;
+
+/// Interval at which the usage of access keys is written to the key table
+pub const KEY_USAGE_FLUSH_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Usage of a key that has not been written to the key table yet
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PendingKeyUsage {
+	pub requests: u64,
+	pub last_used: u64,
+}
+
+impl PendingKeyUsage {
+	fn merge(&mut self, other: &Self) {
+		self.requests += other.requests;
+		self.last_used = std::cmp::max(self.last_used, other.last_used);
+	}
+}
+
+#[derive(Default)]
+pub struct KeyUsageTracker {
+	pending: Mutex<HashMap<String, PendingKeyUsage>>,
+}
+
+impl KeyUsageTracker {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Record that a request signed with a key was received at time `now`
+	pub fn record(&self, key_id: &str, now: u64) {
+		let mut pending = self.pending.lock().unwrap();
+		let usage = pending.entry(key_id.to_string()).or_default();
+		usage.requests += 1;
+		usage.last_used = std::cmp::max(usage.last_used, now);
+	}
+
+	/// Usage of the keys recorded since the last flush,
+	/// in addition to what is already stored in the key table
+	pub fn pending(&self, key_id: &str) -> PendingKeyUsage {
+		self.pending
+			.lock()
+			.unwrap()
+			.get(key_id)
+			.copied()
+			.unwrap_or_default()
+	}
+
+	fn take(&self) -> HashMap<String, PendingKeyUsage> {
+		std::mem::take(&mut *self.pending.lock().unwrap())
+	}
+
+	/// Put back the usage of a key that could not be written
+	fn restore(&self, key_id: String, usage: PendingKeyUsage) {
+		self.pending
+			.lock()
+			.unwrap()
+			.entry(key_id)
+			.or_default()
+			.merge(&usage);
+	}
+}
+
+/// Write the usage of a key recorded by this node to the key table
+async fn flush_key_usage(
+	garage: &Garage,
+	key_id: &String,
+	usage: PendingKeyUsage,
+) -> Result<(), Error> {
+	let mut key = match garage.key_table.get(&EmptyKey, key_id).await? {
+		Some(k) => k,
+		None => return Ok(()),
+	};
+	let node_id = garage.system.id;
+	let params = match key.params_mut() {
+		Some(p) => p,
+		// The key has been deleted in the meantime
+		None => return Ok(()),
+	};
+
+	let count = params.request_counts.get(&node_id).copied().unwrap_or(0);
+	params.request_counts.put(node_id, count + usage.requests);
+	params.last_used_at = std::cmp::max(params.last_used_at, Some(usage.last_used));
+
+	garage.key_table.insert(&key).await?;
+	Ok(())
+}
+
+pub struct KeyUsageFlushWorker {
+	garage: Arc<Garage>,
+	next_flush: Instant,
+	flushed: usize,
+	errors: usize,
+}
+
+impl KeyUsageFlushWorker {
+	pub(crate) fn new(garage: Arc<Garage>) -> Self {
+		Self {
+			garage,
+			next_flush: Instant::now() + KEY_USAGE_FLUSH_INTERVAL,
+			flushed: 0,
+			errors: 0,
+		}
+	}
+}
+
+#[async_trait]
+impl Worker for KeyUsageFlushWorker {
+	fn name(&self) -> String {
+		"Key usage flush worker".into()
+	}
+
+	fn status(&self) -> WorkerStatus {
+		WorkerStatus {
+			freeform: vec![
+				format!("Key usage updates written: {}", self.flushed),
+				format!("Failed key usage updates: {}", self.errors),
+			],
+			..Default::default()
+		}
+	}
+
+	async fn work(&mut self, _must_exit: &mut watch::Receiver<bool>) -> Result<WorkerState, Error> {
+		if Instant::now() < self.next_flush {
+			return Ok(WorkerState::Idle);
+		}
+		self.next_flush = Instant::now() + KEY_USAGE_FLUSH_INTERVAL;
+
+		let tracker = &self.garage.key_usage;
+		for (key_id, usage) in tracker.take() {
+			match flush_key_usage(&self.garage, &key_id, usage).await {
+				Ok(()) => self.flushed += 1,
+				Err(e) => {
+					warn!("Could not write usage of key {}: {}", key_id, e);
+					self.errors += 1;
+					tracker.restore(key_id, usage);
+				}
+			}
+		}
+
+		Ok(WorkerState::Idle)
+	}
+
+	async fn wait_for_work(&mut self) -> WorkerState {
+		tokio::time::sleep_until(self.next_flush.into()).await;
+		WorkerState::Busy
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_usage_is_batched() {
+		let tracker = KeyUsageTracker::new();
+		tracker.record("GK1", 1000);
+		tracker.record("GK1", 2000);
+		tracker.record("GK2", 1500);
+		assert_eq!(
+			tracker.pending("GK1"),
+			PendingKeyUsage {
+				requests: 2,
+				last_used: 2000
+			}
+		);
+
+		// Two requests a second apart cause a single write
+		let pending = tracker.take();
+		assert_eq!(pending.len(), 2);
+		assert_eq!(pending["GK1"].requests, 2);
+		assert_eq!(pending["GK1"].last_used, 2000);
+		assert_eq!(pending["GK2"].requests, 1);
+		assert!(tracker.take().is_empty());
+		assert_eq!(tracker.pending("GK1"), PendingKeyUsage::default());
+	}
+
+	#[test]
+	fn test_failed_flush_is_retried() {
+		let tracker = KeyUsageTracker::new();
+		tracker.record("GK1", 1000);
+		let pending = tracker.take();
+
+		// A request arrives while the flush is failing
+		tracker.record("GK1", 3000);
+		for (key_id, usage) in pending {
+			tracker.restore(key_id, usage);
+		}
+		assert_eq!(
+			tracker.take()["GK1"],
+			PendingKeyUsage {
+				requests: 2,
+				last_used: 3000
+			}
+		);
+	}
+}
@@ -9,10 +9,17 @@ pub mod bucket_alias_table;
 pub mod bucket_table;
 pub mod key_table;
 
+pub mod key_cache;
+pub mod key_expiry_worker;
+pub mod key_usage;
+
 #[cfg(feature = "k2v")]
 pub mod k2v;
 pub mod s3;
 
+pub mod compaction;
 pub mod garage;
 pub mod helper;
 pub mod snapshot;
+pub mod usage_alert_worker;
+pub mod usage_history;
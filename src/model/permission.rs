@@ -18,6 +18,10 @@ pub struct BucketKeyPerm {
 	/// - enable / disable website access
 	/// - delete bucket
 	pub allow_owner: bool,
+	/// The key can be used to list objects in the bucket.
+	/// If not set, this follows the value of `allow_read`.
+	#[serde(default)]
+	pub allow_list: Option<bool>,
 }
 
 impl BucketKeyPerm {
@@ -26,6 +30,7 @@ impl BucketKeyPerm {
 		allow_read: false,
 		allow_write: false,
 		allow_owner: false,
+		allow_list: None,
 	};
 
 	pub const ALL_PERMISSIONS: Self = Self {
@@ -33,10 +38,16 @@ impl BucketKeyPerm {
 		allow_read: true,
 		allow_write: true,
 		allow_owner: true,
+		allow_list: None,
 	};
 
 	pub fn is_any(&self) -> bool {
-		self.allow_read || self.allow_write || self.allow_owner
+		self.allow_read || self.allow_write || self.allow_owner || self.can_list()
+	}
+
+	/// Whether the key can list objects in the bucket
+	pub fn can_list(&self) -> bool {
+		self.allow_list.unwrap_or(self.allow_read)
 	}
 }
 
@@ -48,6 +59,9 @@ impl Crdt for BucketKeyPerm {
 			}
 			Ordering::Equal if other != self => {
 				warn!("Different permission sets with same timestamp: {:?} and {:?}, merging to most restricted permission set.", self, other);
+				if self.allow_list.is_some() || other.allow_list.is_some() {
+					self.allow_list = Some(self.can_list() && other.can_list());
+				}
 				if !other.allow_read {
 					self.allow_read = false;
 				}
@@ -62,3 +76,79 @@ impl Crdt for BucketKeyPerm {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use garage_util::encode::{nonversioned_decode, nonversioned_encode};
+
+	fn perm(timestamp: u64, allow_read: bool, allow_list: Option<bool>) -> BucketKeyPerm {
+		BucketKeyPerm {
+			timestamp,
+			allow_read,
+			allow_list,
+			..BucketKeyPerm::NO_PERMISSIONS
+		}
+	}
+
+	fn merged(a: BucketKeyPerm, b: BucketKeyPerm) -> BucketKeyPerm {
+		let mut ab = a;
+		ab.merge(&b);
+		let mut ba = b;
+		ba.merge(&a);
+		assert_eq!(ab, ba);
+		ab
+	}
+
+	#[test]
+	fn test_list_follows_read() {
+		assert!(perm(1, true, None).can_list());
+		assert!(!perm(1, false, None).can_list());
+		assert!(!perm(1, true, Some(false)).can_list());
+		assert!(perm(1, false, Some(true)).can_list());
+		assert!(perm(1, false, Some(true)).is_any());
+	}
+
+	#[test]
+	fn test_merge_old_and_new_grants() {
+		// The most recent grant wins, whether it sets the list flag or not
+		let m = merged(perm(1, true, Some(false)), perm(2, true, None));
+		assert_eq!(m, perm(2, true, None));
+		assert!(m.can_list());
+		let m = merged(perm(1, true, None), perm(2, true, Some(false)));
+		assert_eq!(m, perm(2, true, Some(false)));
+		assert!(!m.can_list());
+
+		// With the same timestamp, the most restricted grant wins, an old
+		// grant allowing listing only if it allows reading
+		let m = merged(perm(1, true, None), perm(1, true, Some(false)));
+		assert!(m.allow_read && !m.can_list());
+		let m = merged(perm(1, false, None), perm(1, true, Some(true)));
+		assert!(!m.allow_read && !m.can_list());
+		let m = merged(perm(1, true, None), perm(1, true, Some(true)));
+		assert!(m.allow_read && m.can_list());
+		let m = merged(perm(1, true, None), perm(1, false, None));
+		assert_eq!(m, perm(1, false, None));
+	}
+
+	#[test]
+	fn test_decode_old_grant() {
+		#[derive(Serialize)]
+		struct OldBucketKeyPerm {
+			timestamp: u64,
+			allow_read: bool,
+			allow_write: bool,
+			allow_owner: bool,
+		}
+		let old = OldBucketKeyPerm {
+			timestamp: 12,
+			allow_read: true,
+			allow_write: false,
+			allow_owner: false,
+		};
+		let bytes = nonversioned_encode(&old).unwrap();
+		let decoded = nonversioned_decode::<BucketKeyPerm>(&bytes).unwrap();
+		assert_eq!(decoded, perm(12, true, None));
+		assert!(decoded.can_list());
+	}
+}
@@ -1,5 +1,7 @@
 use std::sync::Arc;
 
+use futures::future::FutureExt;
+
 use garage_db as db;
 
 use garage_util::data::*;
@@ -8,10 +10,15 @@ use garage_util::migrate::Migrate;
 
 use garage_block::CalculateRefcount;
 use garage_table::crdt::Crdt;
-use garage_table::replication::TableShardedReplication;
+use garage_table::replication::{TableFullReplication, TableShardedReplication};
 use garage_table::*;
 
 use garage_block::manager::*;
+use garage_block::resync::{ResolveResyncPriority, ResyncPriority};
+
+use crate::bucket_table::*;
+use crate::s3::mpu_table::*;
+use crate::s3::version_table::*;
 
 mod v08 {
 	use garage_util::crdt;
@@ -29,6 +36,11 @@ mod v08 {
 		// Keep track of deleted status
 		/// Is the Version that contains this block deleted
 		pub deleted: crdt::Bool,
+
+		/// Number of copies of the block required by the bucket of the object,
+		/// if it overrides the cluster's replication factor
+		#[serde(default)]
+		pub replication_factor: Option<usize>,
 	}
 
 	impl garage_util::migrate::InitialFormat for BlockRef {}
@@ -51,6 +63,10 @@ impl Entry<Hash, Uuid> for BlockRef {
 impl Crdt for BlockRef {
 	fn merge(&mut self, other: &Self) {
 		self.deleted.merge(&other.deleted);
+		self.replication_factor = match (self.replication_factor, other.replication_factor) {
+			(Some(a), Some(b)) => Some(std::cmp::max(a, b)),
+			_ => None,
+		};
 	}
 }
 
@@ -76,7 +92,9 @@ impl TableSchema for BlockRefTable {
 		let was_before = old.map(|x| !x.deleted.get()).unwrap_or(false);
 		let is_after = new.map(|x| !x.deleted.get()).unwrap_or(false);
 		if is_after && !was_before {
-			self.block_manager.block_incref(tx, block)?;
+			let replication_factor = new.and_then(|x| x.replication_factor);
+			self.block_manager
+				.block_incref(tx, block, replication_factor)?;
 		}
 		if was_before && !is_after {
 			self.block_manager.block_decref(tx, block)?;
@@ -123,3 +141,79 @@ fn calculate_refcount(
 	}
 	Ok(result)
 }
+
+/// Function that determines the resync priority of a block: high if one
+/// of the objects that reference it is stored in a bucket whose
+/// `resync_high_priority` flag is set. This requires reading the block's
+/// references, and the version, multipart upload and bucket that each of
+/// them points to.
+pub fn block_resync_priority_fn(
+	block_ref_table: &Arc<Table<BlockRefTable, TableShardedReplication>>,
+	version_table: &Arc<Table<VersionTable, TableShardedReplication>>,
+	mpu_table: &Arc<Table<MultipartUploadTable, TableShardedReplication>>,
+	bucket_table: &Arc<Table<BucketTable, TableFullReplication>>,
+) -> ResolveResyncPriority {
+	let block_ref_table = Arc::downgrade(block_ref_table);
+	let version_table = Arc::downgrade(version_table);
+	let mpu_table = Arc::downgrade(mpu_table);
+	let bucket_table = Arc::downgrade(bucket_table);
+	Box::new(move |block: Hash| {
+		let tables = (
+			block_ref_table.upgrade(),
+			version_table.upgrade(),
+			mpu_table.upgrade(),
+			bucket_table.upgrade(),
+		);
+		async move {
+			let (block_ref_table, version_table, mpu_table, bucket_table) = match tables {
+				(Some(a), Some(b), Some(c), Some(d)) => (a, b, c, d),
+				_ => return Err(Error::Message("tables have been dropped".into())),
+			};
+
+			for version_id in local_block_refs(&block_ref_table, &block)? {
+				let version = match version_table.get(&version_id, &EmptyKey).await? {
+					Some(v) if !v.deleted.get() => v,
+					_ => continue,
+				};
+				let bucket_id = match version.backlink {
+					VersionBacklink::Object { bucket_id, .. } => bucket_id,
+					VersionBacklink::MultipartUpload { upload_id } => {
+						match mpu_table.get(&upload_id, &EmptyKey).await? {
+							Some(mpu) => mpu.bucket_id,
+							None => continue,
+						}
+					}
+				};
+				let high = bucket_table
+					.get(&EmptyKey, &bucket_id)
+					.await?
+					.and_then(|b| b.params().map(|p| *p.resync_high_priority.get()))
+					.unwrap_or(false);
+				if high {
+					return Ok(ResyncPriority::High);
+				}
+			}
+			Ok(ResyncPriority::Normal)
+		}
+		.boxed()
+	})
+}
+
+/// Versions that reference a block, according to the local copy of the block_ref table
+fn local_block_refs(
+	block_ref_table: &Table<BlockRefTable, TableShardedReplication>,
+	block: &Hash,
+) -> Result<Vec<Uuid>, Error> {
+	let mut versions = vec![];
+	for entry in block_ref_table.data.store.range(block.as_slice()..)? {
+		let (key, value) = entry?;
+		if &key[..32] != block.as_slice() {
+			break;
+		}
+		let value = BlockRef::decode(&value).ok_or_message("could not decode block_ref")?;
+		if !value.deleted.get() {
+			versions.push(value.version);
+		}
+	}
+	Ok(versions)
+}
@@ -0,0 +1,689 @@
+//! Server-side copy of the objects of a bucket into another bucket of the
+//! same cluster. Copied objects reference the same data blocks as their
+//! source, so no data is moved. Copies are done by a background job running
+//! on the node that received the request, whose progress can be polled and
+//! which can be cancelled. The status of jobs, including the key up to which
+//! objects have been copied, is persisted on disk, so that jobs that were
+//! running when the node was stopped are resumed when it restarts.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+use garage_table::*;
+use garage_util::data::*;
+use garage_util::persister::Persister;
+use garage_util::time::*;
+
+use crate::bucket_table::BucketParams;
+use crate::garage::Garage;
+use crate::helper::error::*;
+use crate::index_counter::CountedItem;
+use crate::s3::block_ref_table::*;
+use crate::s3::object_table::*;
+use crate::s3::previous_version::retain_previous_version;
+use crate::s3::version_table::*;
+
+/// Number of objects that are listed and copied at once
+const COPY_BUCKET_BATCH_SIZE: usize = 100;
+/// Minimum delay between two batches, to avoid saturating the cluster
+const COPY_BUCKET_BATCH_DELAY: Duration = Duration::from_millis(100);
+/// Number of block references that are written at once when copying an object
+const COPY_BUCKET_BLOCK_REF_BATCH_SIZE: usize = 1000;
+/// Finished jobs are forgotten after this delay
+const COPY_BUCKET_JOB_RETENTION_MSEC: u64 = 24 * 3600 * 1000;
+
+mod v010 {
+	use garage_util::data::Uuid;
+	use serde::{Deserialize, Serialize};
+
+	#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+	pub enum CopyBucketJobState {
+		Running,
+		Done,
+		Cancelled,
+		Failed,
+	}
+
+	/// Status and progress of a bucket copy job
+	#[derive(Clone, Debug, Serialize, Deserialize)]
+	pub struct CopyBucketJobStatus {
+		pub job_id: Uuid,
+		pub source_bucket_id: Uuid,
+		pub dest_bucket_id: Uuid,
+		pub prefix: String,
+		/// Whether objects that already exist in the destination bucket are replaced
+		pub overwrite: bool,
+		/// Maximum number of objects copied per second, if limited
+		pub max_objects_per_sec: Option<u64>,
+		pub state: CopyBucketJobState,
+		pub error: Option<String>,
+		/// Key of the last object that was processed, from which the job resumes
+		pub last_key: Option<String>,
+		pub objects_copied: u64,
+		/// Number of objects not copied because they already exist in the destination
+		pub objects_skipped: u64,
+		pub bytes_copied: u64,
+		/// Number of objects of the source bucket when the job was started,
+		/// used to estimate the remaining duration of the job
+		pub objects_total: u64,
+		pub started_at: u64,
+		pub finished_at: Option<u64>,
+	}
+
+	#[derive(Serialize, Deserialize, Default)]
+	pub struct CopyBucketJobsPersisted {
+		pub jobs: Vec<CopyBucketJobStatus>,
+	}
+
+	impl garage_util::migrate::InitialFormat for CopyBucketJobsPersisted {
+		const VERSION_MARKER: &'static [u8] = b"G010cbj";
+	}
+}
+
+pub use v010::*;
+
+impl std::fmt::Display for CopyBucketJobState {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Running => write!(f, "running"),
+			Self::Done => write!(f, "done"),
+			Self::Cancelled => write!(f, "cancelled"),
+			Self::Failed => write!(f, "failed"),
+		}
+	}
+}
+
+impl CopyBucketJobStatus {
+	/// Estimate the number of seconds until a running job is finished,
+	/// assuming that it continues at the same rate as until now
+	pub fn eta_secs(&self, now: u64) -> Option<u64> {
+		if self.state != CopyBucketJobState::Running {
+			return None;
+		}
+		let processed = self.objects_copied + self.objects_skipped;
+		if processed == 0 {
+			return None;
+		}
+		let remaining = self.objects_total.saturating_sub(processed);
+		let elapsed_msec = now.saturating_sub(self.started_at);
+		Some((elapsed_msec as u128 * remaining as u128 / processed as u128 / 1000) as u64)
+	}
+}
+
+struct CopyBucketJob {
+	status: Arc<Mutex<CopyBucketJobStatus>>,
+	cancel: watch::Sender<bool>,
+}
+
+/// Registry of the bucket copy jobs started on this node
+pub struct CopyBucketJobs {
+	persister: Persister<CopyBucketJobsPersisted>,
+	jobs: Mutex<HashMap<Uuid, CopyBucketJob>>,
+}
+
+impl CopyBucketJobs {
+	pub(crate) fn new(persister: Persister<CopyBucketJobsPersisted>) -> Self {
+		let jobs = persister
+			.load()
+			.map(|p| p.jobs)
+			.unwrap_or_default()
+			.into_iter()
+			.map(|status| {
+				let (cancel, _) = watch::channel(false);
+				let job = CopyBucketJob {
+					status: Arc::new(Mutex::new(status.clone())),
+					cancel,
+				};
+				(status.job_id, job)
+			})
+			.collect();
+		Self {
+			persister,
+			jobs: Mutex::new(jobs),
+		}
+	}
+
+	/// Restart the jobs that were running when the node was last stopped
+	pub(crate) fn resume(&self, garage: &Arc<Garage>) {
+		let mut jobs = self.jobs.lock().unwrap();
+		for job in jobs.values_mut() {
+			let status = job.status.lock().unwrap().clone();
+			if status.state != CopyBucketJobState::Running {
+				continue;
+			}
+			info!(
+				"Resuming copy job {:?} from bucket {:?} to bucket {:?}, after key {:?}",
+				status.job_id, status.source_bucket_id, status.dest_bucket_id, status.last_key
+			);
+			let (cancel, cancel_recv) = watch::channel(false);
+			job.cancel = cancel;
+			tokio::spawn(run_copy_bucket(
+				garage.clone(),
+				job.status.clone(),
+				cancel_recv,
+			));
+		}
+	}
+
+	/// Start a job copying all objects of the source bucket whose key starts
+	/// with `prefix` into the destination bucket. Objects that already exist
+	/// in the destination bucket are replaced only if `overwrite` is set.
+	pub async fn start(
+		&self,
+		garage: Arc<Garage>,
+		source_bucket_id: Uuid,
+		dest_bucket_id: Uuid,
+		prefix: String,
+		overwrite: bool,
+		max_objects_per_sec: Option<u64>,
+	) -> Result<CopyBucketJobStatus, Error> {
+		if source_bucket_id == dest_bucket_id {
+			return Err(Error::BadRequest(
+				"Source and destination buckets must be different".into(),
+			));
+		}
+		if max_objects_per_sec == Some(0) {
+			return Err(Error::BadRequest(
+				"The maximum number of objects per second must be at least 1".into(),
+			));
+		}
+
+		let helper = garage.bucket_helper();
+		helper.get_existing_bucket(source_bucket_id).await?;
+		let dest_bucket = helper.get_existing_bucket(dest_bucket_id).await?;
+		if *dest_bucket.params().unwrap().read_only.get() {
+			return Err(Error::BadRequest(
+				"Destination bucket is read-only: objects cannot be copied into it.".into(),
+			));
+		}
+
+		let objects_total = garage
+			.object_counter_table
+			.table
+			.get(&source_bucket_id, &EmptyKey)
+			.await?
+			.map(|x| x.filtered_values(&garage.system.cluster_layout()))
+			.and_then(|c| c.get(OBJECTS).cloned())
+			.unwrap_or_default()
+			.max(0) as u64;
+
+		let status = CopyBucketJobStatus {
+			job_id: gen_uuid(),
+			source_bucket_id,
+			dest_bucket_id,
+			prefix,
+			overwrite,
+			max_objects_per_sec,
+			state: CopyBucketJobState::Running,
+			error: None,
+			last_key: None,
+			objects_copied: 0,
+			objects_skipped: 0,
+			bytes_copied: 0,
+			objects_total,
+			started_at: now_msec(),
+			finished_at: None,
+		};
+		let shared_status = Arc::new(Mutex::new(status.clone()));
+		let (cancel, cancel_recv) = watch::channel(false);
+
+		{
+			let mut jobs = self.jobs.lock().unwrap();
+			let now = now_msec();
+			jobs.retain(|_, job| {
+				job.status
+					.lock()
+					.unwrap()
+					.finished_at
+					.map(|t| t + COPY_BUCKET_JOB_RETENTION_MSEC > now)
+					.unwrap_or(true)
+			});
+			jobs.insert(
+				status.job_id,
+				CopyBucketJob {
+					status: shared_status.clone(),
+					cancel,
+				},
+			);
+		}
+		self.save();
+
+		info!(
+			"Starting copy job {:?} from bucket {:?} to bucket {:?}, prefix {:?}",
+			status.job_id, source_bucket_id, dest_bucket_id, status.prefix,
+		);
+		tokio::spawn(run_copy_bucket(garage, shared_status, cancel_recv));
+
+		Ok(status)
+	}
+
+	/// Get the status of a job
+	pub fn get(&self, job_id: &Uuid) -> Option<CopyBucketJobStatus> {
+		let jobs = self.jobs.lock().unwrap();
+		jobs.get(job_id).map(|j| j.status.lock().unwrap().clone())
+	}
+
+	/// List the status of all known jobs
+	pub fn list(&self) -> Vec<CopyBucketJobStatus> {
+		let jobs = self.jobs.lock().unwrap();
+		let mut ret = jobs
+			.values()
+			.map(|j| j.status.lock().unwrap().clone())
+			.collect::<Vec<_>>();
+		ret.sort_by_key(|s| s.started_at);
+		ret
+	}
+
+	/// Ask for a job to stop after the object it is currently copying.
+	/// Returns the status of the job, or None if it does not exist.
+	pub fn cancel(&self, job_id: &Uuid) -> Option<CopyBucketJobStatus> {
+		let jobs = self.jobs.lock().unwrap();
+		let job = jobs.get(job_id)?;
+		job.cancel.send_replace(true);
+		let status = job.status.lock().unwrap().clone();
+		Some(status)
+	}
+
+	/// Write the status of all jobs to disk
+	fn save(&self) {
+		let jobs = self.jobs.lock().unwrap();
+		let persisted = CopyBucketJobsPersisted {
+			jobs: jobs
+				.values()
+				.map(|j| j.status.lock().unwrap().clone())
+				.collect(),
+		};
+		if let Err(e) = self.persister.save(&persisted) {
+			error!("Could not save the status of bucket copy jobs: {}", e);
+		}
+	}
+}
+
+async fn run_copy_bucket(
+	garage: Arc<Garage>,
+	status: Arc<Mutex<CopyBucketJobStatus>>,
+	mut cancel: watch::Receiver<bool>,
+) {
+	let job_id = status.lock().unwrap().job_id;
+
+	let res = loop {
+		if *cancel.borrow() {
+			break Ok(CopyBucketJobState::Cancelled);
+		}
+
+		let job = status.lock().unwrap().clone();
+		let batch_start = now_msec();
+		let batch = match copy_bucket_batch(&garage, &job, &cancel).await {
+			Ok(batch) => batch,
+			Err(e) => break Err(e),
+		};
+		{
+			let mut s = status.lock().unwrap();
+			s.objects_copied += batch.objects_copied;
+			s.objects_skipped += batch.objects_skipped;
+			s.bytes_copied += batch.bytes_copied;
+			if let Some(k) = &batch.last_key {
+				s.last_key = Some(k.clone());
+			}
+		}
+		if batch.done {
+			break Ok(CopyBucketJobState::Done);
+		}
+		garage.copy_bucket_jobs.save();
+
+		// Throttle: wait at least the batch delay, and longer if needed
+		// so that the maximum rate of the job is respected
+		let mut delay = COPY_BUCKET_BATCH_DELAY;
+		if let Some(rate) = job.max_objects_per_sec {
+			let n_objects = batch.objects_copied + batch.objects_skipped;
+			let min_duration = Duration::from_millis(n_objects * 1000 / rate);
+			let elapsed = Duration::from_millis(now_msec().saturating_sub(batch_start));
+			delay = delay.max(min_duration.saturating_sub(elapsed));
+		}
+		tokio::select! {
+			_ = tokio::time::sleep(delay) => (),
+			_ = cancel.changed() => (),
+		}
+	};
+
+	{
+		let mut s = status.lock().unwrap();
+		s.finished_at = Some(now_msec());
+		match res {
+			Ok(state) => {
+				s.state = state;
+				info!(
+					"Copy job {:?} {}: {} objects copied ({} bytes), {} skipped",
+					job_id, state, s.objects_copied, s.bytes_copied, s.objects_skipped
+				);
+			}
+			Err(e) => {
+				error!("Copy job {:?} failed: {}", job_id, e);
+				s.state = CopyBucketJobState::Failed;
+				s.error = Some(e.to_string());
+			}
+		}
+	}
+	garage.copy_bucket_jobs.save();
+}
+
+struct CopyBucketBatch {
+	objects_copied: u64,
+	objects_skipped: u64,
+	bytes_copied: u64,
+	/// Key of the last object that was processed in this batch
+	last_key: Option<String>,
+	/// Whether all objects with the prefix have been processed
+	done: bool,
+}
+
+/// Copy the next batch of objects, starting after the last key processed by
+/// the job. The batch is interrupted after the current object if the job is
+/// cancelled.
+async fn copy_bucket_batch(
+	garage: &Garage,
+	job: &CopyBucketJobStatus,
+	cancel: &watch::Receiver<bool>,
+) -> Result<CopyBucketBatch, Error> {
+	// The parameters of the destination bucket are read again for each
+	// batch, so that the job stops if it is made read-only or deleted
+	let dest_bucket = garage
+		.bucket_helper()
+		.get_existing_bucket(job.dest_bucket_id)
+		.await?;
+	let dest_params = dest_bucket.params().unwrap();
+	if *dest_params.read_only.get() {
+		return Err(Error::BadRequest(
+			"Destination bucket has been made read-only".into(),
+		));
+	}
+	let mut quota = QuotaTracker::new(garage, job.dest_bucket_id, dest_params).await?;
+
+	let start = job.last_key.clone().unwrap_or_else(|| job.prefix.clone());
+	let objects = garage
+		.object_table
+		.get_range(
+			&job.source_bucket_id,
+			Some(start),
+			Some(ObjectFilter::IsData),
+			COPY_BUCKET_BATCH_SIZE,
+			EnumerationOrder::Forward,
+		)
+		.await?;
+
+	let mut batch = CopyBucketBatch {
+		objects_copied: 0,
+		objects_skipped: 0,
+		bytes_copied: 0,
+		last_key: None,
+		// Done if the listing was not truncated, or if it went past the prefix
+		done: objects.len() < COPY_BUCKET_BATCH_SIZE
+			|| objects.last().map(|o| o.key.starts_with(&job.prefix)) != Some(true),
+	};
+	for object in objects.iter() {
+		if !object.key.starts_with(&job.prefix) {
+			break;
+		}
+		if Some(&object.key) == job.last_key.as_ref() {
+			// Already processed in the previous batch
+			continue;
+		}
+		if *cancel.borrow() {
+			batch.done = false;
+			break;
+		}
+
+		let source_version = match object
+			.versions()
+			.iter()
+			.rev()
+			.find(|v| v.is_complete())
+			.filter(|v| v.is_data())
+		{
+			Some(v) => v,
+			None => {
+				batch.last_key = Some(object.key.clone());
+				continue;
+			}
+		};
+
+		// The key is indexed the way the destination bucket expects it,
+		// e.g. lowercased if it has case-insensitive keys
+		let dest_key = dest_params.index_key(object.original_key());
+		let existing = garage
+			.object_table
+			.get(&job.dest_bucket_id, &dest_key)
+			.await?;
+		let exists = existing
+			.as_ref()
+			.and_then(|o| o.versions().iter().rev().find(|v| v.is_complete()))
+			.map(|v| v.is_data())
+			.unwrap_or(false);
+
+		if exists && !job.overwrite {
+			batch.objects_skipped += 1;
+		} else {
+			let size = match &source_version.state {
+				ObjectVersionState::Complete(
+					ObjectVersionData::Inline(meta, _) | ObjectVersionData::FirstBlock(meta, _),
+				) => meta.size,
+				_ => 0,
+			};
+			quota.add(size, existing.as_ref())?;
+			copy_object(
+				garage,
+				job.dest_bucket_id,
+				dest_params,
+				dest_key,
+				object,
+				source_version,
+				existing.as_ref(),
+			)
+			.await?;
+			batch.objects_copied += 1;
+			batch.bytes_copied += size;
+		}
+		batch.last_key = Some(object.key.clone());
+	}
+
+	Ok(batch)
+}
+
+/// Copy the current version of an object into the destination bucket,
+/// referencing the same data blocks
+async fn copy_object(
+	garage: &Garage,
+	dest_bucket_id: Uuid,
+	dest_params: &BucketParams,
+	key: String,
+	source_object: &Object,
+	source_version: &ObjectVersion,
+	existing: Option<&Object>,
+) -> Result<(), Error> {
+	let new_uuid = gen_uuid();
+	let new_timestamp = now_msec();
+
+	let new_data = match &source_version.state {
+		ObjectVersionState::Complete(ObjectVersionData::Inline(meta, bytes)) => {
+			// Metadata and data are copied as is, including when they are
+			// encrypted: the copy is readable with the same encryption key
+			ObjectVersionData::Inline(meta.clone(), bytes.clone())
+		}
+		ObjectVersionState::Complete(ObjectVersionData::FirstBlock(meta, first_block)) => {
+			let source_blocks = garage
+				.version_table
+				.get(&source_version.uuid, &EmptyKey)
+				.await?
+				.ok_or_bad_request("Version of source object not found")?;
+
+			// Write an "uploading" marker in the object table, that holds
+			// a reference to the version while its blocks are referenced
+			let tmp_version = ObjectVersion {
+				uuid: new_uuid,
+				timestamp: new_timestamp,
+				state: ObjectVersionState::Uploading {
+					encryption: meta.encryption.clone(),
+					checksum_algorithm: None,
+					multipart: false,
+				},
+			};
+			let tmp_object = Object::new(dest_bucket_id, key.clone(), vec![tmp_version]);
+			garage.object_table.insert(&tmp_object).await?;
+
+			let backlink = VersionBacklink::Object {
+				bucket_id: dest_bucket_id,
+				key: key.clone(),
+			};
+			let dest_version = Version::new(new_uuid, backlink.clone(), false);
+			garage.version_table.insert(&dest_version).await?;
+
+			for batch in source_blocks
+				.blocks
+				.items()
+				.chunks(COPY_BUCKET_BLOCK_REF_BATCH_SIZE)
+			{
+				let mut dest_version_batch = Version::new(new_uuid, backlink.clone(), false);
+				for (bk, bv) in batch.iter() {
+					dest_version_batch.blocks.put(*bk, *bv);
+				}
+				let dest_block_refs = batch
+					.iter()
+					.map(|(_, bv)| BlockRef {
+						block: bv.hash,
+						version: new_uuid,
+						deleted: false.into(),
+						replication_factor: *dest_params.replication_factor.get(),
+					})
+					.collect::<Vec<_>>();
+				futures::try_join!(
+					garage.version_table.insert(&dest_version_batch),
+					garage.block_ref_table.insert_many(&dest_block_refs[..]),
+				)?;
+			}
+
+			ObjectVersionData::FirstBlock(meta.clone(), *first_block)
+		}
+		_ => unreachable!(),
+	};
+
+	let new_version = ObjectVersion {
+		uuid: new_uuid,
+		timestamp: new_timestamp,
+		state: ObjectVersionState::Complete(new_data),
+	};
+	let mut dest_object = Object::new(dest_bucket_id, key, vec![new_version]);
+	retain_previous_version(dest_params, existing, &mut dest_object);
+	dest_object.set_original_key(new_timestamp, source_object.original_key());
+	garage.object_table.insert(&dest_object).await?;
+
+	Ok(())
+}
+
+/// Keeps track of the usage of the destination bucket during a batch,
+/// as the object counters are not updated immediately when objects are written
+struct QuotaTracker {
+	max_objects: Option<u64>,
+	max_size: Option<u64>,
+	objects: i64,
+	bytes: i64,
+}
+
+impl QuotaTracker {
+	async fn new(garage: &Garage, bucket_id: Uuid, params: &BucketParams) -> Result<Self, Error> {
+		let quotas = params.quotas.get();
+		let counters = match (quotas.max_objects, quotas.max_size) {
+			(None, None) => HashMap::new(),
+			_ => garage
+				.object_counter_table
+				.table
+				.get(&bucket_id, &EmptyKey)
+				.await?
+				.map(|x| x.filtered_values(&garage.system.cluster_layout()))
+				.unwrap_or_default(),
+		};
+		Ok(Self {
+			max_objects: quotas.max_objects,
+			max_size: quotas.max_size,
+			objects: counters.get(OBJECTS).cloned().unwrap_or_default(),
+			bytes: counters.get(BYTES).cloned().unwrap_or_default(),
+		})
+	}
+
+	/// Account for the copy of an object of `size` bytes that replaces
+	/// `existing`, failing if it would exceed the quotas
+	fn add(&mut self, size: u64, existing: Option<&Object>) -> Result<(), Error> {
+		let count = |counter: &str| {
+			existing
+				.map(|o| o.counts())
+				.unwrap_or_default()
+				.into_iter()
+				.find(|(c, _)| *c == counter)
+				.map(|(_, v)| v)
+				.unwrap_or_default()
+		};
+		let objects_diff = 1 - count(OBJECTS);
+		let bytes_diff = size as i64 - count(BYTES);
+
+		if let Some(mo) = self.max_objects {
+			if objects_diff > 0 && self.objects + objects_diff > mo as i64 {
+				return Err(Error::BadRequest(format!(
+					"Object quota of destination bucket is reached, maximum objects: {}",
+					mo
+				)));
+			}
+		}
+		if let Some(ms) = self.max_size {
+			if bytes_diff > 0 && self.bytes + bytes_diff > ms as i64 {
+				return Err(Error::BadRequest(format!(
+					"Size quota of destination bucket is reached, maximum size: {}",
+					ms
+				)));
+			}
+		}
+
+		self.objects += objects_diff;
+		self.bytes += bytes_diff;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_copy_bucket_eta() {
+		let mut status = CopyBucketJobStatus {
+			job_id: gen_uuid(),
+			source_bucket_id: gen_uuid(),
+			dest_bucket_id: gen_uuid(),
+			prefix: String::new(),
+			overwrite: false,
+			max_objects_per_sec: None,
+			state: CopyBucketJobState::Running,
+			error: None,
+			last_key: None,
+			objects_copied: 0,
+			objects_skipped: 0,
+			bytes_copied: 0,
+			objects_total: 1000,
+			started_at: 10_000,
+			finished_at: None,
+		};
+		assert_eq!(status.eta_secs(20_000), None);
+
+		// 250 objects in 10 seconds: 750 remaining objects take 30 seconds
+		status.objects_copied = 200;
+		status.objects_skipped = 50;
+		assert_eq!(status.eta_secs(20_000), Some(30));
+
+		// More objects than expected were written to the source bucket
+		status.objects_copied = 1200;
+		assert_eq!(status.eta_secs(20_000), Some(0));
+
+		status.state = CopyBucketJobState::Done;
+		assert_eq!(status.eta_secs(20_000), None);
+	}
+}
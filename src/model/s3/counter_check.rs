@@ -0,0 +1,253 @@
+//! Verification of the object counters of a bucket. Each storage node of
+//! a bucket maintains its own counters of the objects of the bucket that
+//! it stores, which are merged in the object counter table. To detect
+//! counters that have drifted, each storage node recounts the objects it
+//! stores and compares the result with its counters, which it can then
+//! correct.
+//!
+//! The verification is only exact if the bucket is not written to while
+//! the objects are being counted.
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use garage_util::data::*;
+use garage_util::error::*;
+use garage_util::tranquilizer::Tranquilizer;
+
+use garage_rpc::system::System;
+use garage_rpc::*;
+
+use garage_table::replication::*;
+use garage_table::*;
+
+use crate::index_counter::*;
+use crate::s3::object_table::*;
+
+// Objects are counted by batches, between which the scan
+// is throttled so as not to disturb the other operations of the node
+const SCAN_BATCH_SIZE: usize = 1000;
+const SCAN_TRANQUILITY: u32 = 1;
+
+/// RPC messages for the verification of object counters
+#[derive(Debug, Serialize, Deserialize)]
+enum CounterCheckRpc {
+	Check { bucket_id: Uuid, fix: bool },
+	NodeReport(NodeCounterReport),
+}
+
+impl Rpc for CounterCheckRpc {
+	type Response = Result<CounterCheckRpc, Error>;
+}
+
+/// Object counters of a bucket on one of its storage nodes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeCounterReport {
+	pub node: Uuid,
+	/// Values of the counters maintained by the node,
+	/// before they were corrected
+	pub counters: BTreeMap<String, i64>,
+	/// Values recomputed by counting the objects stored on the node
+	pub actual: BTreeMap<String, i64>,
+	/// Whether the counters of the node were corrected
+	pub fixed: bool,
+}
+
+impl NodeCounterReport {
+	/// Difference between the counters and the recomputed values,
+	/// for the counters that have drifted
+	pub fn drift(&self) -> BTreeMap<String, i64> {
+		counter_drift(&self.counters, &self.actual)
+	}
+}
+
+/// Result of the verification of the object counters of a bucket
+#[derive(Debug, Clone)]
+pub struct CounterCheckReport {
+	pub bucket_id: Uuid,
+	/// Values of the counters in the counter table, as used for quotas
+	/// and shown by `garage bucket info`, before they were corrected
+	pub counters: BTreeMap<String, i64>,
+	pub nodes: Vec<NodeCounterReport>,
+	/// Storage nodes of the bucket that could not be checked
+	pub errors: Vec<(Uuid, String)>,
+}
+
+impl CounterCheckReport {
+	pub fn has_drift(&self) -> bool {
+		self.nodes.iter().any(|n| !n.drift().is_empty())
+	}
+}
+
+pub struct ObjectCounterChecker {
+	system: Arc<System>,
+	object_table: Arc<Table<ObjectTable, TableShardedReplication>>,
+	object_counter_table: Arc<IndexCounter<Object>>,
+	endpoint: Arc<Endpoint<CounterCheckRpc, Self>>,
+}
+
+impl ObjectCounterChecker {
+	pub(crate) fn new(
+		system: Arc<System>,
+		object_table: Arc<Table<ObjectTable, TableShardedReplication>>,
+		object_counter_table: Arc<IndexCounter<Object>>,
+	) -> Arc<Self> {
+		let endpoint = system
+			.netapp
+			.endpoint("garage_model/s3/counter_check.rs/Rpc".to_string());
+
+		let checker = Arc::new(Self {
+			system,
+			object_table,
+			object_counter_table,
+			endpoint,
+		});
+		checker.endpoint.set_handler(checker.clone());
+
+		checker
+	}
+
+	/// Recount the objects of a bucket on all of its storage nodes and
+	/// compare them with the counters of the nodes. If `fix` is set,
+	/// the counters that have drifted are corrected.
+	pub async fn check_bucket(
+		&self,
+		bucket_id: Uuid,
+		fix: bool,
+	) -> Result<CounterCheckReport, Error> {
+		let counters = self
+			.object_counter_table
+			.table
+			.get(&bucket_id, &EmptyKey)
+			.await?
+			.map(|c| {
+				c.filtered_values(&self.system.cluster_layout())
+					.into_iter()
+					.collect()
+			})
+			.unwrap_or_default();
+
+		let mut who = self
+			.object_table
+			.data
+			.replication
+			.storage_nodes(&bucket_id.hash());
+		who.sort();
+
+		let resps = futures::future::join_all(who.iter().map(|node| {
+			self.system.rpc_helper().call(
+				&self.endpoint,
+				*node,
+				CounterCheckRpc::Check { bucket_id, fix },
+				RequestStrategy::with_priority(PRIO_NORMAL).without_timeout(),
+			)
+		}))
+		.await;
+
+		let mut nodes = vec![];
+		let mut errors = vec![];
+		for (node, resp) in who.into_iter().zip(resps) {
+			match resp {
+				Ok(CounterCheckRpc::NodeReport(report)) => nodes.push(report),
+				Ok(m) => errors.push((node, Error::unexpected_rpc_message(m).to_string())),
+				Err(e) => errors.push((node, e.to_string())),
+			}
+		}
+
+		Ok(CounterCheckReport {
+			bucket_id,
+			counters,
+			nodes,
+			errors,
+		})
+	}
+
+	async fn check_local(&self, bucket_id: Uuid, fix: bool) -> Result<NodeCounterReport, Error> {
+		let counters = self.object_counter_table.get_local(&bucket_id, &EmptyKey)?;
+		let actual = self.count_local_objects(bucket_id).await?;
+
+		let drift = counter_drift(&counters, &actual);
+		let fixed = fix && !drift.is_empty();
+		if fixed {
+			info!(
+				"Correcting object counters of bucket {:?}, which have drifted by {:?}",
+				bucket_id, drift
+			);
+			self.object_counter_table
+				.correct_local(&bucket_id, &EmptyKey, &drift)?;
+		}
+
+		Ok(NodeCounterReport {
+			node: self.system.id,
+			counters,
+			actual,
+			fixed,
+		})
+	}
+
+	async fn count_local_objects(&self, bucket_id: Uuid) -> Result<BTreeMap<String, i64>, Error> {
+		let partition_hash = bucket_id.hash();
+		let mut actual = [OBJECTS, UNFINISHED_UPLOADS, BYTES]
+			.iter()
+			.map(|name| (name.to_string(), 0))
+			.collect::<BTreeMap<_, _>>();
+
+		let mut tranquilizer = Tranquilizer::new(30);
+		let mut start = Bound::Included(partition_hash.to_vec());
+		loop {
+			tranquilizer.reset();
+
+			let mut batch = vec![];
+			for item in self
+				.object_table
+				.data
+				.store
+				.range((start.clone(), Bound::Unbounded))?
+			{
+				let (k, v) = item?;
+				if k.get(..32) != Some(partition_hash.as_slice()) || batch.len() >= SCAN_BATCH_SIZE
+				{
+					break;
+				}
+				batch.push((k, v));
+			}
+
+			let (last_key, _) = match batch.last() {
+				Some(x) => x,
+				None => break,
+			};
+			start = Bound::Excluded(last_key.clone());
+
+			for (_, v) in batch.iter() {
+				let object = self.object_table.data.decode_entry(v)?;
+				for (name, count) in object.counts() {
+					*actual.entry(name.to_string()).or_insert(0) += count;
+				}
+			}
+
+			tranquilizer.tranquilize(SCAN_TRANQUILITY).await;
+		}
+
+		Ok(actual)
+	}
+}
+
+#[async_trait]
+impl EndpointHandler<CounterCheckRpc> for ObjectCounterChecker {
+	async fn handle(
+		self: &Arc<Self>,
+		message: &CounterCheckRpc,
+		_from: NodeID,
+	) -> Result<CounterCheckRpc, Error> {
+		match message {
+			CounterCheckRpc::Check { bucket_id, fix } => self
+				.check_local(*bucket_id, *fix)
+				.await
+				.map(CounterCheckRpc::NodeReport),
+			m => Err(Error::unexpected_rpc_message(m)),
+		}
+	}
+}
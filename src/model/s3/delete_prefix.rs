@@ -0,0 +1,325 @@
+//! Server-side deletion of all objects of a bucket whose key starts with a
+//! given prefix. Deletions are done by a background job running on the node
+//! that received the request, whose progress can be polled and which can be
+//! cancelled. Job statuses are kept in memory only.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+use garage_table::*;
+use garage_util::data::*;
+use garage_util::error::Error as GarageError;
+use garage_util::time::*;
+
+use crate::garage::Garage;
+use crate::helper::error::*;
+use crate::s3::object_table::*;
+
+/// Number of objects that are listed and deleted at once
+const DELETE_PREFIX_BATCH_SIZE: usize = 1000;
+/// Delay between two batches, to avoid saturating the cluster
+const DELETE_PREFIX_BATCH_DELAY: Duration = Duration::from_millis(100);
+/// Finished jobs are forgotten after this delay
+const DELETE_PREFIX_JOB_RETENTION_MSEC: u64 = 24 * 3600 * 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeletePrefixJobState {
+	Running,
+	Done,
+	Cancelled,
+	Failed,
+}
+
+impl std::fmt::Display for DeletePrefixJobState {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Running => write!(f, "running"),
+			Self::Done => write!(f, "done"),
+			Self::Cancelled => write!(f, "cancelled"),
+			Self::Failed => write!(f, "failed"),
+		}
+	}
+}
+
+/// Status and progress of a delete-prefix job
+#[derive(Clone, Debug)]
+pub struct DeletePrefixJobStatus {
+	pub job_id: Uuid,
+	pub bucket_id: Uuid,
+	pub prefix: String,
+	pub dry_run: bool,
+	pub state: DeletePrefixJobState,
+	pub error: Option<String>,
+	/// Number of objects that were deleted (or that would be deleted, for a dry run)
+	pub objects_processed: u64,
+	/// Sum of the sizes of the deleted objects. The corresponding data blocks
+	/// are freed later on by the block garbage collector.
+	pub bytes_freed: u64,
+	pub started_at: u64,
+	pub finished_at: Option<u64>,
+}
+
+struct DeletePrefixJob {
+	status: Arc<Mutex<DeletePrefixJobStatus>>,
+	cancel: watch::Sender<bool>,
+}
+
+/// Registry of the delete-prefix jobs started on this node
+pub struct DeletePrefixJobs {
+	jobs: Mutex<HashMap<Uuid, DeletePrefixJob>>,
+}
+
+impl DeletePrefixJobs {
+	pub(crate) fn new() -> Self {
+		Self {
+			jobs: Mutex::new(HashMap::new()),
+		}
+	}
+
+	/// Start a job deleting all objects of a bucket whose key starts with
+	/// `prefix`. An empty prefix, which deletes all objects of the bucket,
+	/// is refused unless `allow_empty_prefix` is set.
+	pub async fn start(
+		&self,
+		garage: Arc<Garage>,
+		bucket_id: Uuid,
+		prefix: String,
+		dry_run: bool,
+		allow_empty_prefix: bool,
+	) -> Result<DeletePrefixJobStatus, Error> {
+		if prefix.is_empty() && !allow_empty_prefix {
+			return Err(Error::BadRequest(
+				"Refusing to delete all objects of the bucket with an empty prefix \
+				(this must be explicitly allowed)"
+					.into(),
+			));
+		}
+
+		let bucket = garage
+			.bucket_helper()
+			.get_existing_bucket(bucket_id)
+			.await?;
+		if !dry_run && *bucket.params().unwrap().read_only.get() {
+			return Err(Error::BadRequest(
+				"Bucket is read-only: objects cannot be deleted.".into(),
+			));
+		}
+
+		let status = DeletePrefixJobStatus {
+			job_id: gen_uuid(),
+			bucket_id,
+			prefix,
+			dry_run,
+			state: DeletePrefixJobState::Running,
+			error: None,
+			objects_processed: 0,
+			bytes_freed: 0,
+			started_at: now_msec(),
+			finished_at: None,
+		};
+		let shared_status = Arc::new(Mutex::new(status.clone()));
+		let (cancel, cancel_recv) = watch::channel(false);
+
+		{
+			let mut jobs = self.jobs.lock().unwrap();
+			let now = now_msec();
+			jobs.retain(|_, job| {
+				job.status
+					.lock()
+					.unwrap()
+					.finished_at
+					.map(|t| t + DELETE_PREFIX_JOB_RETENTION_MSEC > now)
+					.unwrap_or(true)
+			});
+			jobs.insert(
+				status.job_id,
+				DeletePrefixJob {
+					status: shared_status.clone(),
+					cancel,
+				},
+			);
+		}
+
+		info!(
+			"Starting delete-prefix job {:?} on bucket {:?}, prefix {:?}{}",
+			status.job_id,
+			bucket_id,
+			status.prefix,
+			if dry_run { " (dry run)" } else { "" }
+		);
+		tokio::spawn(run_delete_prefix(garage, shared_status, cancel_recv));
+
+		Ok(status)
+	}
+
+	/// Get the status of a job
+	pub fn get(&self, job_id: &Uuid) -> Option<DeletePrefixJobStatus> {
+		let jobs = self.jobs.lock().unwrap();
+		jobs.get(job_id).map(|j| j.status.lock().unwrap().clone())
+	}
+
+	/// List the status of all known jobs
+	pub fn list(&self) -> Vec<DeletePrefixJobStatus> {
+		let jobs = self.jobs.lock().unwrap();
+		let mut ret = jobs
+			.values()
+			.map(|j| j.status.lock().unwrap().clone())
+			.collect::<Vec<_>>();
+		ret.sort_by_key(|s| s.started_at);
+		ret
+	}
+
+	/// Ask for a job to stop after its current batch. Returns the status
+	/// of the job, or None if it does not exist.
+	pub fn cancel(&self, job_id: &Uuid) -> Option<DeletePrefixJobStatus> {
+		let jobs = self.jobs.lock().unwrap();
+		let job = jobs.get(job_id)?;
+		job.cancel.send_replace(true);
+		let status = job.status.lock().unwrap().clone();
+		Some(status)
+	}
+}
+
+async fn run_delete_prefix(
+	garage: Arc<Garage>,
+	status: Arc<Mutex<DeletePrefixJobStatus>>,
+	mut cancel: watch::Receiver<bool>,
+) {
+	let (job_id, bucket_id, prefix, dry_run) = {
+		let s = status.lock().unwrap();
+		(s.job_id, s.bucket_id, s.prefix.clone(), s.dry_run)
+	};
+
+	let mut cursor = prefix.clone();
+	let res = loop {
+		if *cancel.borrow() {
+			break Ok(DeletePrefixJobState::Cancelled);
+		}
+
+		match delete_prefix_batch(&garage, bucket_id, &prefix, &cursor, dry_run).await {
+			Ok(batch) => {
+				let mut s = status.lock().unwrap();
+				s.objects_processed += batch.objects;
+				s.bytes_freed += batch.bytes;
+				match batch.next_cursor {
+					Some(c) => cursor = c,
+					None => break Ok(DeletePrefixJobState::Done),
+				}
+			}
+			Err(e) => break Err(e),
+		}
+
+		tokio::select! {
+			_ = tokio::time::sleep(DELETE_PREFIX_BATCH_DELAY) => (),
+			_ = cancel.changed() => (),
+		}
+	};
+
+	let mut s = status.lock().unwrap();
+	s.finished_at = Some(now_msec());
+	match res {
+		Ok(state) => {
+			s.state = state;
+			info!(
+				"Delete-prefix job {:?} {}: {} objects, {} bytes",
+				job_id, state, s.objects_processed, s.bytes_freed
+			);
+		}
+		Err(e) => {
+			error!("Delete-prefix job {:?} failed: {}", job_id, e);
+			s.state = DeletePrefixJobState::Failed;
+			s.error = Some(e.to_string());
+		}
+	}
+}
+
+struct DeletePrefixBatch {
+	objects: u64,
+	bytes: u64,
+	/// Key from which to continue, or None if all objects
+	/// with the prefix have been processed
+	next_cursor: Option<String>,
+}
+
+/// Delete the next batch of objects, starting at key `cursor` (excluded,
+/// unless it is the prefix itself)
+async fn delete_prefix_batch(
+	garage: &Garage,
+	bucket_id: Uuid,
+	prefix: &str,
+	cursor: &str,
+	dry_run: bool,
+) -> Result<DeletePrefixBatch, GarageError> {
+	let objects = garage
+		.object_table
+		.get_range(
+			&bucket_id,
+			Some(cursor.to_string()),
+			Some(ObjectFilter::IsData),
+			DELETE_PREFIX_BATCH_SIZE,
+			EnumerationOrder::Forward,
+		)
+		.await?;
+
+	let mut next_cursor = None;
+	let mut n_objects = 0;
+	let mut n_bytes = 0;
+	let mut deletions = vec![];
+	for object in objects.iter() {
+		if !object.key.starts_with(prefix) {
+			break;
+		}
+		next_cursor = Some(object.key.clone());
+		if object.key == cursor && cursor != prefix {
+			// Already processed in the previous batch
+			continue;
+		}
+
+		let current_version = match object
+			.versions()
+			.iter()
+			.rev()
+			.find(|v| v.is_complete())
+			.filter(|v| v.is_data())
+		{
+			Some(v) => v,
+			None => continue,
+		};
+		if let ObjectVersionState::Complete(
+			ObjectVersionData::Inline(meta, _) | ObjectVersionData::FirstBlock(meta, _),
+		) = &current_version.state
+		{
+			n_bytes += meta.size;
+		}
+		n_objects += 1;
+
+		deletions.push(Object::new(
+			bucket_id,
+			object.key.clone(),
+			vec![ObjectVersion {
+				uuid: gen_uuid(),
+				timestamp: next_timestamp(&*garage.clock, Some(object)),
+				state: ObjectVersionState::Complete(ObjectVersionData::DeleteMarker),
+			}],
+		));
+	}
+
+	if !dry_run && !deletions.is_empty() {
+		garage.object_table.insert_many(deletions).await?;
+	}
+
+	// Continue only if the listing was truncated and all listed
+	// objects had the prefix
+	let all_matched = objects.last().map(|o| o.key.starts_with(prefix)) == Some(true);
+	if objects.len() < DELETE_PREFIX_BATCH_SIZE || !all_matched {
+		next_cursor = None;
+	}
+
+	Ok(DeletePrefixBatch {
+		objects: n_objects,
+		bytes: n_bytes,
+		next_cursor,
+	})
+}
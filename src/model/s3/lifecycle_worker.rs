@@ -121,7 +121,7 @@ impl Worker for LifecycleWorker {
 				mpu_aborted,
 				..
 			} => {
-				let n_objects = self.garage.object_table.data.store.len().ok();
+				let n_objects = self.garage.object_table.data.store.fast_len().ok();
 				let progress = match n_objects {
 					None => "...".to_string(),
 					Some(total) => format!(
@@ -234,10 +234,16 @@ async fn process_object(
 	mpu_aborted: &mut usize,
 	last_bucket: &mut Option<Bucket>,
 ) -> Result<Skip, Error> {
-	if !object
-		.versions()
-		.iter()
-		.any(|x| x.is_data() || x.is_uploading(None))
+	let previous_version_expired = object
+		.previous_version()
+		.map(|p| p.expires_at <= now_msec())
+		.unwrap_or(false);
+
+	if !previous_version_expired
+		&& !object
+			.versions()
+			.iter()
+			.any(|x| x.is_data() || x.is_uploading(None))
 	{
 		return Ok(Skip::NextObject);
 	}
@@ -262,17 +268,79 @@ async fn process_object(
 		}
 	};
 
+	if bucket
+		.state
+		.as_option()
+		.map(|s| *s.read_only.get())
+		.unwrap_or(false)
+	{
+		// Lifecycle actions are paused on read-only buckets
+		return Ok(Skip::SkipBucket);
+	}
+
+	let db = garage.object_table.data.store.db();
+
+	if previous_version_expired {
+		let mut cleared_object = Object::new(object.bucket_id, object.key.clone(), vec![]);
+		cleared_object.set_previous_version(None);
+		info!(
+			"Lifecycle: removing the previous version of 1 object in bucket {:?}",
+			object.bucket_id
+		);
+		db.transaction(|tx| garage.object_table.queue_insert(tx, &cleared_object))?;
+	}
+
+	// Delete objects whose TTL has elapsed
+	if let Some(current_version) = object.versions().iter().rev().find(|v| v.is_data()) {
+		let expired = match &current_version.state {
+			ObjectVersionState::Complete(ObjectVersionData::Inline(meta, _))
+			| ObjectVersionState::Complete(ObjectVersionData::FirstBlock(meta, _)) => meta.is_expired(),
+			_ => false,
+		};
+		if expired {
+			let deleted_object = Object::new(
+				object.bucket_id,
+				object.key.clone(),
+				vec![ObjectVersion {
+					uuid: gen_uuid(),
+					timestamp: std::cmp::max(now_msec(), current_version.timestamp + 1),
+					state: ObjectVersionState::Complete(ObjectVersionData::DeleteMarker),
+				}],
+			);
+			info!(
+				"Lifecycle: deleting 1 object past its TTL in bucket {:?}",
+				object.bucket_id
+			);
+			db.transaction(|tx| garage.object_table.queue_insert(tx, &deleted_object))?;
+			*objects_expired += 1;
+			*last_bucket = Some(bucket);
+			return Ok(Skip::NextObject);
+		}
+	}
+
 	let lifecycle_policy: &[LifecycleRule] = bucket
 		.state
 		.as_option()
 		.and_then(|s| s.lifecycle_config.get().as_deref())
 		.unwrap_or_default();
 
-	if lifecycle_policy.iter().all(|x| !x.enabled) {
-		return Ok(Skip::SkipBucket);
+	// Buckets without a rule to abort incomplete uploads fall back to
+	// the cluster-wide setting
+	if let Some(days) = garage.config.s3_api.abort_stalled_uploads_after_days {
+		if !lifecycle_policy
+			.iter()
+			.any(|x| x.enabled && x.abort_incomplete_mpu_days.is_some())
+		{
+			abort_stalled_uploads(garage, now_date, object, days, mpu_aborted).await?;
+		}
 	}
 
-	let db = garage.object_table.data.store.db();
+	if lifecycle_policy.iter().all(|x| !x.enabled) {
+		// Other objects of the bucket may have a TTL or a previous version
+		// to remove, so the bucket cannot be skipped
+		*last_bucket = Some(bucket);
+		return Ok(Skip::NextObject);
+	}
 
 	for rule in lifecycle_policy.iter() {
 		if !rule.enabled {
@@ -367,6 +435,51 @@ async fn process_object(
 	Ok(Skip::NextObject)
 }
 
+/// Abort the multipart uploads of an object to which no part was uploaded
+/// for the given number of days
+async fn abort_stalled_uploads(
+	garage: &Arc<Garage>,
+	now_date: NaiveDate,
+	object: &Object,
+	days: u64,
+	mpu_aborted: &mut usize,
+) -> Result<(), Error> {
+	let max_idle = chrono::Duration::days(days as i64);
+
+	let mut aborted_versions = vec![];
+	for v in object.versions().iter() {
+		// The last activity on an upload is never before its creation,
+		// so the upload does not need to be read for recent uploads
+		if !v.is_uploading(Some(true)) || (now_date - next_date(v.timestamp)) < max_idle {
+			continue;
+		}
+		let last_activity = match garage.mpu_table.get(&v.uuid, &EmptyKey).await? {
+			Some(mpu) => mpu.last_activity,
+			None => v.timestamp,
+		};
+		if (now_date - next_date(last_activity)) >= max_idle {
+			aborted_versions.push(ObjectVersion {
+				state: ObjectVersionState::Aborted,
+				..*v
+			});
+		}
+	}
+
+	if !aborted_versions.is_empty() {
+		let n_aborted = aborted_versions.len();
+		info!(
+			"Lifecycle: aborting {} stalled upload(s) in bucket {:?}",
+			n_aborted, object.bucket_id
+		);
+		let aborted_object = Object::new(object.bucket_id, object.key.clone(), aborted_versions);
+		let db = garage.object_table.data.store.db();
+		db.transaction(|tx| garage.object_table.queue_insert(tx, &aborted_object))?;
+		*mpu_aborted += n_aborted;
+	}
+
+	Ok(())
+}
+
 fn check_size_filter(version_data: &ObjectVersionData, filter: &LifecycleFilter) -> bool {
 	let size = match version_data {
 		ObjectVersionData::Inline(meta, _) | ObjectVersionData::FirstBlock(meta, _) => meta.size,
@@ -3,4 +3,9 @@ pub mod mpu_table;
 pub mod object_table;
 pub mod version_table;
 
+pub mod copy_bucket;
+pub mod counter_check;
+pub mod delete_prefix;
 pub mod lifecycle_worker;
+pub mod mpu_completion;
+pub mod previous_version;
@@ -0,0 +1,361 @@
+//! Completion of multipart uploads. CompleteMultipartUpload first validates
+//! the list of parts and records it in the MultipartUpload entry, and then
+//! writes the final object version. If the completion is interrupted after
+//! the list of parts was recorded (client disconnection, node restart), it
+//! can be retried by the client, or it is rolled forward by a background
+//! worker.
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use tokio::sync::watch;
+
+use garage_table::*;
+use garage_util::background::*;
+use garage_util::config::S3ApiConfig;
+use garage_util::error::Error;
+use garage_util::time::*;
+
+use crate::bucket_table::*;
+use crate::garage::Garage;
+use crate::s3::block_ref_table::*;
+use crate::s3::mpu_table::*;
+use crate::s3::object_table::*;
+use crate::s3::previous_version::retain_previous_version;
+use crate::s3::version_table::*;
+
+// Interrupted completions are looked for shortly after startup, and then regularly
+const FIRST_SCAN_DELAY: Duration = Duration::from_secs(60);
+const SCAN_INTERVAL: Duration = Duration::from_secs(3600);
+// A completion that was started more recently than this might still be in progress
+const COMPLETION_GRACE_PERIOD_MSEC: u64 = 10 * 60 * 1000;
+
+/// Number of parts processed at the same time when completing a multipart
+/// upload, unless set by `s3_api.mpu_completion_concurrency`
+pub const DEFAULT_MPU_COMPLETION_CONCURRENCY: usize = 16;
+
+pub fn mpu_completion_concurrency(config: &S3ApiConfig) -> usize {
+	std::cmp::max(
+		1,
+		config
+			.mpu_completion_concurrency
+			.unwrap_or(DEFAULT_MPU_COMPLETION_CONCURRENCY),
+	)
+}
+
+/// Process the parts of a multipart upload with `f`, at most `concurrency`
+/// of them at the same time, e.g. to read their versions. The results are
+/// returned in the order of the parts. As soon as one of them fails, the
+/// parts that are still being processed are dropped and the error is returned.
+pub async fn map_parts_concurrently<I, F, Fut, T, E>(
+	concurrency: usize,
+	parts: I,
+	f: F,
+) -> Result<Vec<T>, E>
+where
+	I: IntoIterator,
+	F: FnMut(I::Item) -> Fut,
+	Fut: Future<Output = Result<T, E>>,
+{
+	stream::iter(parts)
+		.map(f)
+		.buffered(std::cmp::max(1, concurrency))
+		.try_collect()
+		.await
+}
+
+/// Write the final version of a multipart upload whose list of parts
+/// has been recorded in `completion`. This can safely be called several
+/// times for the same upload. Returns false if the upload does not exist
+/// anymore, e.g. because it was aborted.
+/// The versions of the parts are read, unless they are given in
+/// `parts_versions` in the order of `completion.parts`, before anything
+/// is written, so that nothing is written if one of them is missing.
+pub async fn commit_mpu_completion(
+	garage: &Garage,
+	bucket_params: &BucketParams,
+	mpu: &MultipartUpload,
+	completion: &MpuCompletion,
+	parts_versions: Option<Vec<Version>>,
+) -> Result<bool, Error> {
+	let upload_id = mpu.upload_id;
+
+	let object = match garage.object_table.get(&mpu.bucket_id, &mpu.key).await? {
+		Some(o) => o,
+		None => return Ok(false),
+	};
+	let mut object_version = match object.versions().iter().find(|v| v.uuid == upload_id) {
+		Some(v) if v.is_uploading(Some(true)) => v.clone(),
+		Some(v) if v.is_complete() => return Ok(true),
+		_ => return Ok(false),
+	};
+
+	let parts_versions = match parts_versions {
+		Some(versions) => versions,
+		None => {
+			map_parts_concurrently(
+				mpu_completion_concurrency(&garage.config.s3_api),
+				completion.parts.iter().cloned(),
+				|(part_number, version)| async move {
+					match garage.version_table.get(&version, &EmptyKey).await? {
+						Some(v) if !v.deleted.get() => Ok(v),
+						_ => Err(Error::Message(format!(
+							"part {} of multipart upload {:?} has been deleted",
+							part_number, upload_id
+						))),
+					}
+				},
+			)
+			.await?
+		}
+	};
+
+	// Create final version and block refs
+	let mut final_version = Version::new(
+		upload_id,
+		VersionBacklink::Object {
+			bucket_id: mpu.bucket_id,
+			key: mpu.key.clone(),
+		},
+		false,
+	);
+	for (part_number, part_version) in parts_versions.iter().enumerate() {
+		for (vbk, vb) in part_version.blocks.items().iter() {
+			final_version.blocks.put(
+				VersionBlockKey {
+					part_number: (part_number + 1) as u64,
+					offset: vbk.offset,
+				},
+				*vb,
+			);
+		}
+	}
+	let first_block = match final_version.blocks.items().first() {
+		Some((_, b)) => b.hash,
+		None => {
+			return Err(Error::Message(format!(
+				"multipart upload {:?} has no data blocks",
+				upload_id
+			)))
+		}
+	};
+	garage.version_table.insert(&final_version).await?;
+
+	let block_refs = final_version.blocks.items().iter().map(|(_, b)| BlockRef {
+		block: b.hash,
+		version: upload_id,
+		deleted: false.into(),
+		replication_factor: *bucket_params.replication_factor.get(),
+	});
+	garage.block_ref_table.insert_many(block_refs).await?;
+
+	// Write final object version
+	object_version.state = ObjectVersionState::Complete(ObjectVersionData::FirstBlock(
+		completion.meta.clone(),
+		first_block,
+	));
+
+	let version_timestamp = object_version.timestamp;
+	let mut final_object = Object::new(mpu.bucket_id, mpu.key.clone(), vec![object_version]);
+	retain_previous_version(bucket_params, Some(&object), &mut final_object);
+	if let Some(original_key) = &completion.original_key {
+		final_object.set_original_key(version_timestamp, original_key);
+	}
+	garage.object_table.insert(&final_object).await?;
+
+	Ok(true)
+}
+
+/// Worker that rolls forward the completions of multipart uploads
+/// that have been interrupted
+pub struct MpuCompletionWorker {
+	garage: Arc<Garage>,
+	/// Position in the local multipart upload table, if a scan is in progress
+	pos: Option<Vec<u8>>,
+	next_scan: Instant,
+	resumed: usize,
+	errors: usize,
+}
+
+impl MpuCompletionWorker {
+	pub(crate) fn new(garage: Arc<Garage>) -> Self {
+		Self {
+			garage,
+			pos: None,
+			next_scan: Instant::now() + FIRST_SCAN_DELAY,
+			resumed: 0,
+			errors: 0,
+		}
+	}
+
+	async fn resume(
+		&self,
+		mpu: &MultipartUpload,
+		completion: &MpuCompletion,
+	) -> Result<bool, Error> {
+		let bucket = self
+			.garage
+			.bucket_table
+			.get(&EmptyKey, &mpu.bucket_id)
+			.await?;
+		match bucket.as_ref().and_then(|b| b.state.as_option()) {
+			Some(params) => {
+				commit_mpu_completion(&self.garage, params, mpu, completion, None).await
+			}
+			None => Ok(false),
+		}
+	}
+}
+
+#[async_trait]
+impl Worker for MpuCompletionWorker {
+	fn name(&self) -> String {
+		"Multipart upload completion worker".into()
+	}
+
+	fn status(&self) -> WorkerStatus {
+		WorkerStatus {
+			freeform: vec![
+				format!("Completions rolled forward: {}", self.resumed),
+				format!("Failed completions: {}", self.errors),
+			],
+			..Default::default()
+		}
+	}
+
+	async fn work(&mut self, _must_exit: &mut watch::Receiver<bool>) -> Result<WorkerState, Error> {
+		let mut pos = match self.pos.take() {
+			Some(pos) => pos,
+			None if Instant::now() < self.next_scan => return Ok(WorkerState::Idle),
+			None => vec![],
+		};
+
+		// Process a batch of 100 items before yielding to bg task scheduler
+		for _ in 0..100 {
+			let (next_pos, mpu_bytes) = match self.garage.mpu_table.data.store.get_gt(&pos)? {
+				Some(x) => x,
+				None => {
+					self.next_scan = Instant::now() + SCAN_INTERVAL;
+					return Ok(WorkerState::Idle);
+				}
+			};
+			pos = next_pos;
+
+			let mpu = self.garage.mpu_table.data.decode_entry(&mpu_bytes)?;
+			let completion = match &mpu.completing {
+				Some(c)
+					if !mpu.deleted.get()
+						&& c.timestamp + COMPLETION_GRACE_PERIOD_MSEC < now_msec() =>
+				{
+					c
+				}
+				_ => continue,
+			};
+
+			match self.resume(&mpu, completion).await {
+				Ok(true) => {
+					info!(
+						"Rolled forward interrupted completion of multipart upload {:?}",
+						mpu.upload_id
+					);
+					self.resumed += 1;
+				}
+				Ok(false) => (),
+				Err(e) => {
+					warn!(
+						"Could not complete multipart upload {:?}: {}",
+						mpu.upload_id, e
+					);
+					self.errors += 1;
+				}
+			}
+		}
+
+		self.pos = Some(pos);
+		Ok(WorkerState::Busy)
+	}
+
+	async fn wait_for_work(&mut self) -> WorkerState {
+		tokio::time::sleep_until(self.next_scan.into()).await;
+		WorkerState::Busy
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	use super::*;
+
+	/// Simulates reading the version of a part, which takes `latency`
+	async fn read_part(
+		part_number: u64,
+		latency: Duration,
+		in_flight: &AtomicUsize,
+		max_in_flight: &AtomicUsize,
+	) -> Result<u64, Error> {
+		let n = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+		max_in_flight.fetch_max(n, Ordering::SeqCst);
+		tokio::time::sleep(latency).await;
+		in_flight.fetch_sub(1, Ordering::SeqCst);
+		Ok(part_number)
+	}
+
+	#[tokio::test]
+	async fn test_parts_keep_their_order() {
+		let (in_flight, max_in_flight) = (AtomicUsize::new(0), AtomicUsize::new(0));
+		// The first parts are the slowest to read
+		let parts = (1..=100u64).collect::<Vec<_>>();
+		let res = map_parts_concurrently(8, parts.iter(), |pn| {
+			let latency = Duration::from_millis(101 - *pn);
+			read_part(*pn, latency, &in_flight, &max_in_flight)
+		})
+		.await
+		.unwrap();
+		assert_eq!(res, parts);
+		assert_eq!(max_in_flight.load(Ordering::SeqCst), 8);
+	}
+
+	#[tokio::test]
+	async fn test_error_stops_completion() {
+		let started = AtomicUsize::new(0);
+		let res = map_parts_concurrently(4, 1..=1000u64, |pn| {
+			started.fetch_add(1, Ordering::SeqCst);
+			async move {
+				tokio::time::sleep(Duration::from_millis(1)).await;
+				match pn {
+					10 => Err(Error::Message(format!("part {} has been deleted", pn))),
+					_ => Ok(pn),
+				}
+			}
+		})
+		.await;
+		assert!(res.is_err());
+		// The following parts are not read
+		assert!(started.load(Ordering::SeqCst) < 20);
+	}
+
+	/// Check that reading the versions of the parts of a 1000-part upload
+	/// concurrently is faster than reading them one by one, with a simulated
+	/// latency of 1ms for each read. Ignored as it depends on timings,
+	/// run with `cargo test -p garage_model bench_ -- --ignored`
+	#[tokio::test]
+	#[ignore]
+	async fn bench_complete_1000_parts() {
+		let (in_flight, max_in_flight) = (AtomicUsize::new(0), AtomicUsize::new(0));
+		let mut elapsed = vec![];
+		for concurrency in [1, DEFAULT_MPU_COMPLETION_CONCURRENCY] {
+			let start = Instant::now();
+			let res = map_parts_concurrently(concurrency, 1..=1000u64, |pn| {
+				read_part(pn, Duration::from_millis(1), &in_flight, &max_in_flight)
+			})
+			.await
+			.unwrap();
+			assert_eq!(res.len(), 1000);
+			elapsed.push(start.elapsed());
+		}
+		assert!(elapsed[1] * 2 < elapsed[0]);
+	}
+}
@@ -19,7 +19,7 @@ pub const BYTES: &str = "bytes";
 mod v09 {
 	use crate::s3::object_table::ChecksumValue;
 	use garage_util::crdt;
-	use garage_util::data::Uuid;
+	use garage_util::data::{Hash, Uuid};
 	use serde::{Deserialize, Serialize};
 
 	/// A part of a multipart upload
@@ -67,6 +67,10 @@ mod v09 {
 		pub checksum: Option<ChecksumValue>,
 		/// Size of this part (known only once done uploading)
 		pub size: Option<u64>,
+		/// SHA256 checksum of the content of this part, computed by Garage
+		/// when `s3_api.always_compute_sha256` is enabled
+		#[serde(default)]
+		pub sha256: Option<Hash>,
 	}
 
 	impl garage_util::migrate::InitialFormat for MultipartUpload {
@@ -74,7 +78,92 @@ mod v09 {
 	}
 }
 
-pub use v09::*;
+mod v010 {
+	use crate::s3::object_table::ObjectVersionMeta;
+	use garage_util::crdt;
+	use garage_util::data::Uuid;
+	use serde::{Deserialize, Serialize};
+
+	use super::v09;
+	pub use v09::{MpuPart, MpuPartKey};
+
+	/// A part of a multipart upload
+	#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+	pub struct MultipartUpload {
+		/// Partition key = Upload id = UUID of the object version
+		pub upload_id: Uuid,
+
+		/// The timestamp at which the multipart upload was created
+		pub timestamp: u64,
+		/// The timestamp of the last part uploaded to this multipart upload,
+		/// or of its creation if no part was uploaded yet
+		pub last_activity: u64,
+		/// Is this multipart upload deleted
+		/// The MultipartUpload is marked as deleted as soon as the
+		/// multipart upload is either completed or aborted
+		pub deleted: crdt::Bool,
+		/// List of uploaded parts, key = (part number, timestamp)
+		/// In case of retries, all versions for each part are kept
+		/// Everything is cleaned up only once the MultipartUpload is marked deleted
+		pub parts: crdt::Map<MpuPartKey, MpuPart>,
+
+		// Back link to bucket+key so that we can find the object this mpu
+		// belongs to and check whether it is still valid
+		/// Bucket in which the related object is stored
+		pub bucket_id: Uuid,
+		/// Key in which the related object is stored
+		pub key: String,
+
+		/// Set once CompleteMultipartUpload has validated the list of parts,
+		/// before the final object version is written, so that an
+		/// interrupted completion can be retried or rolled forward
+		#[serde(default)]
+		pub completing: Option<MpuCompletion>,
+	}
+
+	/// A validated CompleteMultipartUpload request
+	#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+	pub struct MpuCompletion {
+		/// The timestamp at which the completion was started
+		pub timestamp: u64,
+		/// Parts of the final object, in order, as (part number, version)
+		pub parts: Vec<(u64, Uuid)>,
+		/// Metadata of the final object version
+		pub meta: ObjectVersionMeta,
+		/// Key of the object as given by the client, for buckets
+		/// with case-insensitive keys
+		pub original_key: Option<String>,
+	}
+
+	impl garage_util::migrate::Migrate for MultipartUpload {
+		const VERSION_MARKER: &'static [u8] = b"G010s3mpu";
+
+		type Previous = v09::MultipartUpload;
+
+		fn migrate(old: v09::MultipartUpload) -> MultipartUpload {
+			// The best estimate of the last activity of an existing upload
+			// is the timestamp of its most recent part
+			let last_activity = old
+				.parts
+				.items()
+				.iter()
+				.map(|(k, _)| k.timestamp)
+				.fold(old.timestamp, std::cmp::max);
+			MultipartUpload {
+				upload_id: old.upload_id,
+				timestamp: old.timestamp,
+				last_activity,
+				deleted: old.deleted,
+				parts: old.parts,
+				bucket_id: old.bucket_id,
+				key: old.key,
+				completing: None,
+			}
+		}
+	}
+}
+
+pub use v010::*;
 
 impl Ord for MpuPartKey {
 	fn cmp(&self, other: &Self) -> std::cmp::Ordering {
@@ -101,16 +190,18 @@ impl MultipartUpload {
 		Self {
 			upload_id,
 			timestamp,
+			last_activity: timestamp,
 			deleted: crdt::Bool::new(deleted),
 			parts: crdt::Map::new(),
 			bucket_id,
 			key,
+			completing: None,
 		}
 	}
 
-	pub fn next_timestamp(&self, part_number: u64) -> u64 {
+	pub fn next_timestamp(&self, clock: &dyn Clock, part_number: u64) -> u64 {
 		std::cmp::max(
-			now_msec(),
+			clock.now_msec(),
 			1 + self
 				.parts
 				.items()
@@ -121,6 +212,12 @@ impl MultipartUpload {
 				.unwrap_or(0),
 		)
 	}
+
+	/// Record activity on this multipart upload, to be called before
+	/// inserting an entry containing a newly uploaded part
+	pub fn touch(&mut self) {
+		self.last_activity = std::cmp::max(now_msec(), self.last_activity);
+	}
 }
 
 impl Entry<Uuid, EmptyKey> for MultipartUpload {
@@ -138,6 +235,17 @@ impl Entry<Uuid, EmptyKey> for MultipartUpload {
 impl Crdt for MultipartUpload {
 	fn merge(&mut self, other: &Self) {
 		self.deleted.merge(&other.deleted);
+		self.last_activity = std::cmp::max(self.last_activity, other.last_activity);
+
+		// The completion is kept once the upload is deleted, so that
+		// retries of CompleteMultipartUpload can still be answered
+		self.completing = match (self.completing.take(), &other.completing) {
+			(None, y) => y.clone(),
+			(Some(x), Some(y)) if (y.timestamp, &y.meta) > (x.timestamp, &x.meta) => {
+				Some(y.clone())
+			}
+			(x, _) => x,
+		};
 
 		if self.deleted.get() {
 			self.parts.clear();
@@ -164,6 +272,11 @@ impl Crdt for MpuPart {
 			(Some(x), Some(y)) if x < *y => other.checksum.clone(),
 			(x, _) => x,
 		};
+		self.sha256 = match (self.sha256, other.sha256) {
+			(None, Some(_)) => other.sha256,
+			(Some(x), Some(y)) if x < y => other.sha256,
+			(x, _) => x,
+		};
 	}
 }
 
@@ -4,6 +4,7 @@ use std::sync::Arc;
 use garage_db as db;
 
 use garage_util::data::*;
+use garage_util::time::{now_msec, Clock};
 
 use garage_table::crdt::*;
 use garage_table::replication::TableShardedReplication;
@@ -172,6 +173,7 @@ mod v09 {
 }
 
 mod v010 {
+	use garage_util::crdt;
 	use garage_util::data::{Hash, Uuid};
 	use serde::{Deserialize, Serialize};
 
@@ -188,6 +190,26 @@ mod v010 {
 
 		/// The list of currenty stored versions of the object
 		pub(super) versions: Vec<ObjectVersion>,
+
+		/// Version that was overwritten by the current version, kept for
+		/// some time if the bucket is configured to do so
+		#[serde(default)]
+		pub(super) previous_version: crdt::Lww<Option<PreviousVersion>>,
+
+		/// In buckets with case-insensitive keys, the key with the casing
+		/// it was last written with (`key` is then its lowercased version)
+		#[serde(default)]
+		pub(super) original_key: crdt::Lww<Option<String>>,
+	}
+
+	/// A version of an object that was overwritten, and that is kept
+	/// so that the overwrite can be undone
+	#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+	pub struct PreviousVersion {
+		/// The overwritten version
+		pub version: ObjectVersion,
+		/// Timestamp after which the version can be deleted
+		pub expires_at: u64,
 	}
 
 	/// Informations about a version of an object
@@ -243,6 +265,10 @@ mod v010 {
 		pub etag: String,
 		/// Encryption params + headers (encrypted or plaintext)
 		pub encryption: ObjectVersionEncryption,
+		/// Timestamp after which the version is considered deleted, if it
+		/// was written with a TTL (x-amz-expiration-ttl, Garage extension)
+		#[serde(default)]
+		pub expires_at: Option<u64>,
 	}
 
 	/// Encryption information + metadata
@@ -269,6 +295,10 @@ mod v010 {
 	pub struct ObjectVersionMetaInner {
 		pub headers: HeaderList,
 		pub checksum: Option<ChecksumValue>,
+		/// SHA256 checksum of the whole object, computed by Garage when
+		/// `s3_api.always_compute_sha256` is enabled
+		#[serde(default)]
+		pub full_sha256: Option<Hash>,
 	}
 
 	pub type HeaderList = Vec<(String, String)>;
@@ -300,6 +330,8 @@ mod v010 {
 				bucket_id: old.bucket_id,
 				key: old.key,
 				versions: old.versions.into_iter().map(migrate_version).collect(),
+				previous_version: Default::default(),
+				original_key: Default::default(),
 			}
 		}
 	}
@@ -341,6 +373,7 @@ mod v010 {
 			size: old.size,
 			etag: old.etag,
 			encryption: migrate_headers(old.headers),
+			expires_at: None,
 		}
 	}
 
@@ -359,6 +392,7 @@ mod v010 {
 			inner: ObjectVersionMetaInner {
 				headers: new_headers,
 				checksum: None,
+				full_sha256: None,
 			},
 		}
 	}
@@ -380,6 +414,8 @@ impl Object {
 			bucket_id,
 			key,
 			versions: vec![],
+			previous_version: Default::default(),
+			original_key: Default::default(),
 		};
 		for v in versions {
 			ret.add_version(v)
@@ -407,6 +443,48 @@ impl Object {
 	pub fn versions(&self) -> &[ObjectVersion] {
 		&self.versions[..]
 	}
+
+	/// Get the overwritten version of `Object` that is retained, if any
+	pub fn previous_version(&self) -> Option<&PreviousVersion> {
+		self.previous_version.get().as_ref()
+	}
+
+	/// Set or clear the retained overwritten version of `Object`
+	pub fn set_previous_version(&mut self, previous: Option<PreviousVersion>) {
+		self.previous_version.update(previous);
+	}
+
+	/// The key of the object with the casing it was written with, which
+	/// differs from `key` in buckets with case-insensitive keys
+	pub fn original_key(&self) -> &str {
+		self.original_key.get().as_deref().unwrap_or(&self.key)
+	}
+
+	/// Record the casing of the key with which the version written
+	/// at `timestamp` was uploaded
+	pub fn set_original_key(&mut self, timestamp: u64, original_key: &str) {
+		let original_key = Some(original_key.to_string()).filter(|k| *k != self.key);
+		self.original_key.merge(&Lww::raw(timestamp, original_key));
+	}
+
+	/// Whether a version is still referenced by this object, either
+	/// in its list of versions or as its retained previous version
+	fn references_version(&self, uuid: &Uuid) -> bool {
+		self.versions
+			.iter()
+			.any(|v| v.uuid == *uuid && v.state != ObjectVersionState::Aborted)
+			|| self.previous_version().map(|p| p.version.uuid) == Some(*uuid)
+	}
+}
+
+/// Timestamp of a new version of an object, which is always after the
+/// timestamps of the existing versions, even if the clock is behind
+pub fn next_timestamp(clock: &dyn Clock, existing_object: Option<&Object>) -> u64 {
+	existing_object
+		.as_ref()
+		.and_then(|obj| obj.versions().iter().map(|v| v.timestamp).max())
+		.map(|t| clock.increment_logical_clock(t))
+		.unwrap_or_else(|| clock.now_msec())
 }
 
 impl Crdt for ObjectVersionState {
@@ -435,6 +513,9 @@ impl AutoCrdt for ObjectVersionData {
 }
 
 impl ObjectVersion {
+	/// Order of versions: by timestamp, and by UUID for versions written
+	/// concurrently with the same timestamp, so that all nodes agree on
+	/// the last version of an object
 	fn cmp_key(&self) -> (u64, Uuid) {
 		(self.timestamp, self.uuid)
 	}
@@ -468,6 +549,15 @@ impl ObjectVersion {
 	}
 }
 
+impl ObjectVersionMeta {
+	/// Has the TTL of the version elapsed
+	pub fn is_expired(&self) -> bool {
+		self.expires_at
+			.map(|expires_at| expires_at <= now_msec())
+			.unwrap_or(false)
+	}
+}
+
 impl Entry<Uuid, String> for Object {
 	fn partition_key(&self) -> &Uuid {
 		&self.bucket_id
@@ -479,6 +569,7 @@ impl Entry<Uuid, String> for Object {
 		self.versions.len() == 1
 			&& self.versions[0].state
 				== ObjectVersionState::Complete(ObjectVersionData::DeleteMarker)
+			&& self.previous_version().is_none()
 	}
 }
 
@@ -523,6 +614,9 @@ impl Crdt for Object {
 		if let Some(last_vi) = last_complete {
 			self.versions = self.versions.drain(last_vi..).collect::<Vec<_>>();
 		}
+
+		self.previous_version.merge(&other.previous_version);
+		self.original_key.merge(&other.original_key);
 	}
 }
 
@@ -544,6 +638,32 @@ pub enum ObjectFilter {
 	IsUploading { check_multipart: Option<bool> },
 }
 
+impl ObjectTable {
+	fn queue_version_deletion(
+		&self,
+		tx: &mut db::Transaction,
+		object: &Object,
+		version_uuid: Uuid,
+	) -> db::TxOpResult<()> {
+		let deleted_version = Version::new(
+			version_uuid,
+			VersionBacklink::Object {
+				bucket_id: object.bucket_id,
+				key: object.key.clone(),
+			},
+			true,
+		);
+		let res = self.version_table.queue_insert(tx, &deleted_version);
+		if let Err(e) = db::unabort(res)? {
+			error!(
+				"Unable to enqueue version deletion propagation: {}. A repair will be needed.",
+				e
+			);
+		}
+		Ok(())
+	}
+}
+
 impl TableSchema for ObjectTable {
 	const TABLE_NAME: &'static str = "object";
 
@@ -574,30 +694,17 @@ impl TableSchema for ObjectTable {
 					.versions
 					.binary_search_by(|nv| nv.cmp_key().cmp(&v.cmp_key()));
 
-				// Propagate deletion of old versions to the Version table
+				// Propagate deletion of old versions to the Version table,
+				// unless they are retained as the previous version
 				let delete_version = match new_v_id {
-					Err(_) => true,
+					Err(_) => !new_v.references_version(&v.uuid),
 					Ok(i) => {
 						new_v.versions[i].state == ObjectVersionState::Aborted
 							&& v.state != ObjectVersionState::Aborted
 					}
 				};
 				if delete_version {
-					let deleted_version = Version::new(
-						v.uuid,
-						VersionBacklink::Object {
-							bucket_id: old_v.bucket_id,
-							key: old_v.key.clone(),
-						},
-						true,
-					);
-					let res = self.version_table.queue_insert(tx, &deleted_version);
-					if let Err(e) = db::unabort(res)? {
-						error!(
-							"Unable to enqueue version deletion propagation: {}. A repair will be needed.",
-							e
-						);
-					}
+					self.queue_version_deletion(tx, old_v, v.uuid)?;
 				}
 
 				// After abortion or completion of multipart uploads, delete MPU table entry
@@ -633,6 +740,13 @@ impl TableSchema for ObjectTable {
 					}
 				}
 			}
+
+			// Propagate deletion of the previous version once it is no longer retained
+			if let Some(prev) = old_v.previous_version() {
+				if !new_v.references_version(&prev.version.uuid) {
+					self.queue_version_deletion(tx, old_v, prev.version.uuid)?;
+				}
+			}
 		}
 
 		Ok(())
@@ -688,3 +802,120 @@ impl CountedItem for Object {
 		]
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const TS: u64 = 1_700_000_000_000;
+
+	fn complete_version(uuid: u8, etag: &str) -> ObjectVersion {
+		ObjectVersion {
+			uuid: Uuid::from([uuid; 32]),
+			timestamp: TS,
+			state: ObjectVersionState::Complete(ObjectVersionData::Inline(
+				ObjectVersionMeta {
+					size: 1,
+					etag: etag.into(),
+					encryption: ObjectVersionEncryption::Plaintext {
+						inner: ObjectVersionMetaInner {
+							headers: vec![],
+							checksum: None,
+							full_sha256: None,
+						},
+					},
+					expires_at: None,
+				},
+				vec![0x42],
+			)),
+		}
+	}
+
+	fn merged(a: &Object, b: &Object) -> Object {
+		let mut m = a.clone();
+		m.merge(b);
+		m
+	}
+
+	#[test]
+	fn test_concurrent_writes_same_timestamp() {
+		let bucket_id = Uuid::from([0x01; 32]);
+
+		// Two writes of the same key with the same timestamp, received by
+		// two different nodes, as well as a deletion with the same timestamp
+		let node1 = Object::new(bucket_id, "a".into(), vec![complete_version(0x10, "low")]);
+		let node2 = Object::new(bucket_id, "a".into(), vec![complete_version(0x20, "high")]);
+		let node3 = Object::new(
+			bucket_id,
+			"a".into(),
+			vec![ObjectVersion {
+				uuid: Uuid::from([0x05; 32]),
+				timestamp: TS,
+				state: ObjectVersionState::Complete(ObjectVersionData::DeleteMarker),
+			}],
+		);
+
+		// Whatever the order in which the nodes sync, they converge
+		// to the version with the highest UUID
+		let orders = [
+			merged(&merged(&node1, &node2), &node3),
+			merged(&merged(&node1, &node3), &node2),
+			merged(&merged(&node2, &node1), &node3),
+			merged(&merged(&node2, &node3), &node1),
+			merged(&merged(&node3, &node1), &node2),
+			merged(&merged(&node3, &node2), &node1),
+		];
+		for o in orders.iter() {
+			assert_eq!(o, &orders[0]);
+			assert_eq!(o.versions().len(), 1);
+			assert_eq!(o.versions()[0].uuid, Uuid::from([0x20; 32]));
+		}
+
+		// Merging is idempotent
+		assert_eq!(merged(&orders[0], &node1), orders[0]);
+	}
+
+	#[test]
+	fn test_concurrent_original_keys_same_timestamp() {
+		let bucket_id = Uuid::from([0x01; 32]);
+
+		let mut node1 = Object::new(bucket_id, "a".into(), vec![complete_version(0x10, "x")]);
+		node1.set_original_key(TS, "A");
+		let mut node2 = Object::new(bucket_id, "a".into(), vec![complete_version(0x20, "y")]);
+		node2.set_original_key(TS, "a");
+
+		// The ambiguity is resolved in the same way on all nodes
+		let m1 = merged(&node1, &node2);
+		let m2 = merged(&node2, &node1);
+		assert_eq!(m1, m2);
+		assert_eq!(m1.original_key(), "a");
+	}
+
+	#[test]
+	fn test_next_timestamp() {
+		struct FixedClock(u64);
+		impl Clock for FixedClock {
+			fn now_msec(&self) -> u64 {
+				self.0
+			}
+		}
+		let clock = FixedClock(1_000);
+		let object = |timestamp: u64| {
+			Object::new(
+				gen_uuid(),
+				"key".into(),
+				vec![ObjectVersion {
+					uuid: gen_uuid(),
+					timestamp,
+					state: ObjectVersionState::Aborted,
+				}],
+			)
+		};
+
+		assert_eq!(next_timestamp(&clock, None), 1_000);
+		assert_eq!(next_timestamp(&clock, Some(&object(500))), 1_000);
+		// New versions are always after the existing ones, even if the
+		// clock is behind
+		assert_eq!(next_timestamp(&clock, Some(&object(2_000))), 2_001);
+	}
+}
@@ -0,0 +1,188 @@
+//! Retention of the previous version of objects when they are overwritten,
+//! so that the last overwrite of an object can be undone. This is enabled
+//! per bucket. A retained version keeps its data blocks referenced until
+//! it is restored, replaced by the next overwrite, or removed by the
+//! lifecycle worker once its retention period is over.
+use garage_table::*;
+use garage_util::data::*;
+use garage_util::time::*;
+
+use crate::bucket_table::BucketParams;
+use crate::garage::Garage;
+use crate::helper::error::*;
+use crate::s3::object_table::*;
+
+/// If the bucket is configured to retain previous versions, record the
+/// current version of `existing` as the previous version of `object`,
+/// which is about to be inserted to overwrite it.
+pub fn retain_previous_version(
+	bucket_params: &BucketParams,
+	existing: Option<&Object>,
+	object: &mut Object,
+) {
+	let retention_secs = match bucket_params.previous_version_retention_secs.get() {
+		Some(secs) => *secs,
+		None => return,
+	};
+
+	let current_version = existing
+		.and_then(|o| o.versions().iter().rev().find(|v| v.is_complete()))
+		.filter(|v| v.is_data());
+	if let Some(v) = current_version {
+		object.set_previous_version(Some(PreviousVersion {
+			version: v.clone(),
+			expires_at: now_msec() + retention_secs * 1000,
+		}));
+	}
+}
+
+/// Make the retained previous version of an object its current version
+/// again. The version that is replaced becomes the previous version in
+/// turn, so that restoring can itself be undone.
+/// Returns the restored version.
+pub async fn restore_previous_version(
+	garage: &Garage,
+	bucket_id: Uuid,
+	key: &str,
+) -> Result<ObjectVersion, Error> {
+	let bucket = garage
+		.bucket_helper()
+		.get_existing_bucket(bucket_id)
+		.await?;
+	let bucket_params = bucket.params().unwrap();
+	if *bucket_params.read_only.get() {
+		return Err(Error::BadRequest(
+			"Bucket is read-only: objects cannot be restored.".into(),
+		));
+	}
+
+	let no_previous_version =
+		|| Error::BadRequest(format!("Object {} has no previous version to restore", key));
+
+	let object = garage
+		.object_table
+		.get(&bucket_id, &key.to_string())
+		.await?
+		.ok_or_else(no_previous_version)?;
+	let previous = object
+		.previous_version()
+		.filter(|p| p.expires_at > now_msec())
+		.ok_or_else(no_previous_version)?;
+
+	// If the previous version was deleted concurrently (e.g. by two
+	// overwrites happening at the same time), its data might be gone.
+	if let ObjectVersionState::Complete(ObjectVersionData::FirstBlock(_, _)) =
+		previous.version.state
+	{
+		let version = garage
+			.version_table
+			.get(&previous.version.uuid, &EmptyKey)
+			.await?;
+		if !matches!(version, Some(v) if !v.deleted.get()) {
+			return Err(Error::BadRequest(format!(
+				"The data of the previous version of object {} is no longer available",
+				key
+			)));
+		}
+	}
+
+	let restored = ObjectVersion {
+		uuid: previous.version.uuid,
+		timestamp: object
+			.versions()
+			.iter()
+			.map(|v| v.timestamp + 1)
+			.fold(now_msec(), std::cmp::max),
+		state: previous.version.state.clone(),
+	};
+
+	let mut new_object = Object::new(bucket_id, key.to_string(), vec![restored.clone()]);
+	let current_version = object
+		.versions()
+		.iter()
+		.rev()
+		.find(|v| v.is_complete())
+		.filter(|v| v.is_data());
+	match current_version {
+		Some(v) => {
+			let expires_at = match bucket_params.previous_version_retention_secs.get() {
+				Some(secs) => now_msec() + secs * 1000,
+				None => previous.expires_at,
+			};
+			new_object.set_previous_version(Some(PreviousVersion {
+				version: v.clone(),
+				expires_at,
+			}));
+		}
+		None => new_object.set_previous_version(None),
+	}
+	garage.object_table.insert(&new_object).await?;
+
+	Ok(restored)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use garage_util::crdt::Lww;
+
+	fn version(timestamp: u64, data: ObjectVersionData) -> ObjectVersion {
+		ObjectVersion {
+			uuid: gen_uuid(),
+			timestamp,
+			state: ObjectVersionState::Complete(data),
+		}
+	}
+
+	fn inline_data() -> ObjectVersionData {
+		ObjectVersionData::Inline(
+			ObjectVersionMeta {
+				size: 4,
+				etag: "etag".into(),
+				expires_at: None,
+				encryption: ObjectVersionEncryption::Plaintext {
+					inner: ObjectVersionMetaInner {
+						headers: vec![],
+						checksum: None,
+						full_sha256: None,
+					},
+				},
+			},
+			b"plop".to_vec(),
+		)
+	}
+
+	#[test]
+	fn test_retain_previous_version() {
+		let bucket_id = gen_uuid();
+		let current = version(10, inline_data());
+		let existing = Object::new(bucket_id, "key".into(), vec![current.clone()]);
+		let new_object = || Object::new(bucket_id, "key".into(), vec![version(20, inline_data())]);
+
+		let mut params = BucketParams::default();
+		let mut object = new_object();
+		retain_previous_version(&params, Some(&existing), &mut object);
+		assert!(object.previous_version().is_none());
+
+		params.previous_version_retention_secs = Lww::new(Some(3600));
+		let mut object = new_object();
+		retain_previous_version(&params, Some(&existing), &mut object);
+		let previous = object.previous_version().unwrap();
+		assert_eq!(previous.version, current);
+		assert!(previous.expires_at > now_msec() + 3500 * 1000);
+
+		// Nothing is retained if the object did not exist
+		let mut object = new_object();
+		retain_previous_version(&params, None, &mut object);
+		assert!(object.previous_version().is_none());
+
+		let deleted = Object::new(
+			bucket_id,
+			"key".into(),
+			vec![version(15, ObjectVersionData::DeleteMarker)],
+		);
+		let mut object = new_object();
+		retain_previous_version(&params, Some(&deleted), &mut object);
+		assert!(object.previous_version().is_none());
+	}
+}
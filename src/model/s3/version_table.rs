@@ -16,6 +16,8 @@ mod v08 {
 	use garage_util::data::{Hash, Uuid};
 	use serde::{Deserialize, Serialize};
 
+	use crate::s3::object_table::ChecksumValue;
+
 	/// A version of an object
 	#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
 	pub struct Version {
@@ -56,6 +58,11 @@ mod v08 {
 		pub hash: Hash,
 		/// Size of the block, before any kind of compression or encryption
 		pub size: u64,
+		/// Checksum of the block, before any kind of compression or encryption,
+		/// stored if `s3_api.store_block_checksums` is enabled and the object
+		/// was uploaded with a checksum
+		#[serde(default)]
+		pub checksum: Option<ChecksumValue>,
 	}
 
 	impl garage_util::migrate::InitialFormat for Version {}
@@ -219,6 +226,7 @@ impl TableSchema for VersionTable {
 					block: vb.hash,
 					version: old_v.uuid,
 					deleted: true.into(),
+					replication_factor: None,
 				});
 				for block_ref in deleted_block_refs {
 					let res = self.block_ref_table.queue_insert(tx, &block_ref);
@@ -1,14 +1,19 @@
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
 use rand::prelude::*;
+use tokio::select;
 use tokio::sync::watch;
 
+use garage_db as db;
+
 use garage_util::background::*;
+use garage_util::config::Config;
 use garage_util::error::*;
 
 use crate::garage::Garage;
@@ -16,22 +21,45 @@ use crate::garage::Garage;
 // The two most recent snapshots are kept
 const KEEP_SNAPSHOTS: usize = 2;
 
+// Interval at which the progress of a running snapshot is logged
+const SNAPSHOT_PROGRESS_INTERVAL: Duration = Duration::from_secs(30);
+// Interval at which the status of a snapshot worker is refreshed
+const SNAPSHOT_WORKER_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
 static SNAPSHOT_MUTEX: Mutex<()> = Mutex::new(());
 
 // ================ snapshotting logic =====================
 
+/// Whether a snapshot of the metadata db is being taken
+pub(crate) fn snapshot_in_progress() -> bool {
+	SNAPSHOT_MUTEX.try_lock().is_err()
+}
+
 /// Run snashot_metadata in a blocking thread and async await on it
 pub async fn async_snapshot_metadata(garage: &Arc<Garage>) -> Result<(), Error> {
+	let new_path = new_snapshot_path(&garage.config);
+	snapshot_metadata_with_timeout(garage, new_path).await
+}
+
+/// Take a snapshot of the metadata database to the given path in a
+/// blocking thread, giving up after `snapshot_timeout_secs`.
+async fn snapshot_metadata_with_timeout(
+	garage: &Arc<Garage>,
+	new_path: PathBuf,
+) -> Result<(), Error> {
 	let garage = garage.clone();
-	let worker = tokio::task::spawn_blocking(move || snapshot_metadata(&garage));
-	worker.await.unwrap()?;
-	Ok(())
+	let timeout = Duration::from_secs(garage.config.snapshot_timeout_secs);
+	let db_path = metadata_db_path(&garage.config);
+	run_with_timeout(new_path, db_path, timeout, move |path| {
+		snapshot_metadata(&garage, path)
+	})
+	.await
 }
 
 /// Take a snapshot of the metadata database, and erase older
 /// snapshots if necessary.
 /// This is not an async function, it should be spawned on a thread pool
-pub fn snapshot_metadata(garage: &Garage) -> Result<(), Error> {
+fn snapshot_metadata(garage: &Garage, new_path: &Path) -> Result<(), Error> {
 	let lock = match SNAPSHOT_MUTEX.try_lock() {
 		Ok(lock) => lock,
 		Err(_) => {
@@ -41,18 +69,21 @@ pub fn snapshot_metadata(garage: &Garage) -> Result<(), Error> {
 		}
 	};
 
-	let mut snapshots_dir = garage.config.metadata_dir.clone();
-	snapshots_dir.push("snapshots");
-	fs::create_dir_all(&snapshots_dir)?;
-
-	let mut new_path = snapshots_dir.clone();
-	new_path.push(chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true));
+	let snapshots_dir = new_path.parent().unwrap();
+	fs::create_dir_all(snapshots_dir)?;
 
 	info!("Snapshotting metadata db to {}", new_path.display());
-	garage.db.snapshot(&new_path)?;
-	info!("Metadata db snapshot finished");
+	garage.db.snapshot(&new_path.to_path_buf())?;
+	if garage.db.supports(db::Capability::SnapshotManifest) {
+		info!(
+			"Metadata db snapshot finished, manifest written to {}",
+			db::snapshot_manifest_path(new_path).display()
+		);
+	} else {
+		info!("Metadata db snapshot finished");
+	}
 
-	if let Err(e) = cleanup_snapshots(&snapshots_dir) {
+	if let Err(e) = cleanup_snapshots(&snapshots_dir.to_path_buf()) {
 		error!("Failed to do cleanup in snapshots directory: {}", e);
 	}
 
@@ -61,15 +92,148 @@ pub fn snapshot_metadata(garage: &Garage) -> Result<(), Error> {
 	Ok(())
 }
 
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SnapshotState {
+	Running,
+	Finished,
+	TimedOut,
+}
+
+/// Run the blocking snapshot function `snapshot_fn`, writing to `new_path`,
+/// on a thread pool. Progress is logged regularly, comparing the size of
+/// the snapshot to that of the database at `db_path`.
+/// If the snapshot does not finish before `timeout`, an error is returned.
+/// As the snapshot cannot be interrupted, the partial snapshot is deleted
+/// when `snapshot_fn` returns.
+async fn run_with_timeout<F>(
+	new_path: PathBuf,
+	db_path: PathBuf,
+	timeout: Duration,
+	snapshot_fn: F,
+) -> Result<(), Error>
+where
+	F: FnOnce(&Path) -> Result<(), Error> + Send + 'static,
+{
+	let state = Arc::new(Mutex::new(SnapshotState::Running));
+
+	let mut worker = tokio::task::spawn_blocking({
+		let state = state.clone();
+		let new_path = new_path.clone();
+		move || {
+			let res = snapshot_fn(&new_path);
+			let mut state = state.lock().unwrap();
+			if *state == SnapshotState::TimedOut {
+				warn!(
+					"Timed out metadata db snapshot has finished, deleting {}",
+					new_path.display()
+				);
+				if let Err(e) = remove_path(&new_path)
+					.and_then(|()| remove_path(&db::snapshot_manifest_path(&new_path)))
+				{
+					error!(
+						"Failed to delete partial snapshot {}: {}",
+						new_path.display(),
+						e
+					);
+				}
+			} else {
+				*state = SnapshotState::Finished;
+			}
+			res
+		}
+	});
+
+	let deadline = tokio::time::Instant::now() + timeout;
+	let mut progress_interval = tokio::time::interval_at(
+		tokio::time::Instant::now() + SNAPSHOT_PROGRESS_INTERVAL,
+		SNAPSHOT_PROGRESS_INTERVAL,
+	);
+	loop {
+		select! {
+			res = &mut worker => return res.unwrap(),
+			_ = progress_interval.tick() => {
+				info!(
+					"Metadata db snapshot in progress: {} of {} bytes copied",
+					path_size(&new_path),
+					path_size(&db_path),
+				);
+			}
+			_ = tokio::time::sleep_until(deadline) => {
+				let finished = {
+					let mut state_val = state.lock().unwrap();
+					if *state_val != SnapshotState::Finished {
+						*state_val = SnapshotState::TimedOut;
+					}
+					*state_val == SnapshotState::Finished
+				};
+				if finished {
+					return worker.await.unwrap();
+				}
+				return Err(Error::Message(format!(
+					"Metadata db snapshot did not finish within {} seconds, \
+					the partial snapshot will be deleted",
+					timeout.as_secs()
+				)));
+			}
+		}
+	}
+}
+
+fn new_snapshot_path(config: &Config) -> PathBuf {
+	let mut new_path = config.metadata_dir.clone();
+	new_path.push("snapshots");
+	new_path.push(chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true));
+	new_path
+}
+
+fn metadata_db_path(config: &Config) -> PathBuf {
+	let mut db_path = config.metadata_dir.clone();
+	match db::Engine::from_str(&config.db_engine) {
+		Ok(db::Engine::Sqlite) => db_path.push("db.sqlite"),
+		_ => db_path.push("db.lmdb"),
+	}
+	db_path
+}
+
+/// Size of a file, or total size of the files in a directory
+fn path_size(path: &Path) -> u64 {
+	match fs::metadata(path) {
+		Ok(m) if m.is_dir() => fs::read_dir(path)
+			.map(|entries| {
+				entries
+					.filter_map(|e| e.ok()?.metadata().ok())
+					.filter(|m| m.is_file())
+					.map(|m| m.len())
+					.sum()
+			})
+			.unwrap_or(0),
+		Ok(m) => m.len(),
+		Err(_) => 0,
+	}
+}
+
+fn remove_path(path: &Path) -> std::io::Result<()> {
+	match fs::metadata(path) {
+		Ok(m) if m.is_dir() => fs::remove_dir_all(path),
+		Ok(_) => fs::remove_file(path),
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+		Err(e) => Err(e),
+	}
+}
+
 fn cleanup_snapshots(snapshots_dir: &PathBuf) -> Result<(), Error> {
 	let mut snapshots =
 		fs::read_dir(&snapshots_dir)?.collect::<Result<Vec<fs::DirEntry>, std::io::Error>>()?;
 
-	snapshots.retain(|x| x.file_name().len() > 8);
+	// Manifests are deleted along with the snapshot they describe
+	snapshots.retain(|x| {
+		x.file_name().len() > 8 && !x.file_name().to_string_lossy().ends_with(".manifest")
+	});
 	snapshots.sort_by_key(|x| x.file_name());
 
 	for to_delete in snapshots.iter().rev().skip(KEEP_SNAPSHOTS) {
 		let path = snapshots_dir.join(to_delete.path());
+		remove_path(&db::snapshot_manifest_path(&path))?;
 		if to_delete.metadata()?.file_type().is_dir() {
 			for file in fs::read_dir(&path)? {
 				let file = file?;
@@ -134,3 +298,139 @@ impl Worker for AutoSnapshotWorker {
 		WorkerState::Busy
 	}
 }
+
+// ================ one-shot snapshot worker =====================
+
+/// Worker that takes a single snapshot of the metadata database, so that
+/// snapshots launched by the admin can be followed using `garage worker list`
+pub struct SnapshotWorker {
+	garage: Arc<Garage>,
+	new_path: PathBuf,
+	db_path: PathBuf,
+	task: Option<tokio::task::JoinHandle<Result<(), Error>>>,
+	result: Option<Result<(), String>>,
+}
+
+impl SnapshotWorker {
+	pub fn new(garage: Arc<Garage>) -> Self {
+		let new_path = new_snapshot_path(&garage.config);
+		let db_path = metadata_db_path(&garage.config);
+		Self {
+			garage,
+			new_path,
+			db_path,
+			task: None,
+			result: None,
+		}
+	}
+}
+
+#[async_trait]
+impl Worker for SnapshotWorker {
+	fn name(&self) -> String {
+		"Metadata snapshot".into()
+	}
+	fn status(&self) -> WorkerStatus {
+		let mut freeform = vec![format!("Snapshot path: {}", self.new_path.display())];
+		match &self.result {
+			None => freeform.push(format!(
+				"Copied: {} of {} bytes",
+				path_size(&self.new_path),
+				path_size(&self.db_path)
+			)),
+			Some(Ok(())) => freeform.push("Snapshot has been saved.".into()),
+			Some(Err(e)) => freeform.push(format!("Snapshot failed: {}", e)),
+		}
+		WorkerStatus {
+			freeform,
+			..Default::default()
+		}
+	}
+	async fn work(&mut self, _must_exit: &mut watch::Receiver<bool>) -> Result<WorkerState, Error> {
+		let garage = &self.garage;
+		let new_path = &self.new_path;
+		let task = self.task.get_or_insert_with(|| {
+			let garage = garage.clone();
+			let new_path = new_path.clone();
+			tokio::spawn(async move { snapshot_metadata_with_timeout(&garage, new_path).await })
+		});
+
+		select! {
+			res = task => {
+				let res = res.map_err(|e| Error::Message(e.to_string())).and_then(|r| r);
+				if let Err(e) = &res {
+					error!("Metadata db snapshot failed: {}", e);
+				}
+				self.result = Some(res.map_err(|e| e.to_string()));
+				Ok(WorkerState::Done)
+			}
+			_ = tokio::time::sleep(SNAPSHOT_WORKER_POLL_INTERVAL) => Ok(WorkerState::Busy),
+		}
+	}
+	async fn wait_for_work(&mut self) -> WorkerState {
+		unreachable!()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use garage_util::data::*;
+
+	fn temp_path() -> PathBuf {
+		std::env::temp_dir().join(format!("garage-snapshot-test-{}", hex::encode(gen_uuid())))
+	}
+
+	#[tokio::test]
+	async fn test_snapshot_finishes() {
+		let path = temp_path();
+		let res = run_with_timeout(
+			path.clone(),
+			path.clone(),
+			Duration::from_secs(10),
+			|p: &Path| {
+				fs::write(p, b"snapshot")?;
+				Ok(())
+			},
+		)
+		.await;
+		assert!(res.is_ok());
+		assert!(path.exists());
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_snapshot_timeout() {
+		let path = temp_path();
+		let (started_send, started_recv) = std::sync::mpsc::channel::<()>();
+		let (finish_send, finish_recv) = std::sync::mpsc::channel::<()>();
+
+		let res = run_with_timeout(
+			path.clone(),
+			path.clone(),
+			Duration::from_millis(100),
+			move |p: &Path| {
+				fs::create_dir_all(p)?;
+				fs::write(p.join("data.mdb"), b"partial")?;
+				started_send.send(()).unwrap();
+				finish_recv.recv().unwrap();
+				Ok(())
+			},
+		)
+		.await;
+		assert!(res.is_err());
+
+		// The partial snapshot is deleted once the snapshot function returns
+		started_recv.recv().unwrap();
+		assert!(path.join("data.mdb").exists());
+		finish_send.send(()).unwrap();
+		for _ in 0..100 {
+			if !path.exists() {
+				return;
+			}
+			tokio::time::sleep(Duration::from_millis(50)).await;
+		}
+		panic!("partial snapshot was not deleted");
+	}
+}
@@ -0,0 +1,320 @@
+//! Worker that watches the object counters of buckets and raises an alert
+//! when a bucket's usage crosses one of the thresholds set in its
+//! `usage_alerts` parameter.
+//!
+//! Alerts are edge-triggered: an alert is raised once when the usage reaches
+//! the threshold, and is raised again only after the usage has gone back
+//! below the rearm level (15/16 of the threshold, e.g. 75% of the quota for
+//! an alert at 80%). Which alerts are currently raised is persisted on disk,
+//! so that a restart does not raise them again.
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use opentelemetry::{global, metrics::Counter, KeyValue};
+use tokio::sync::watch;
+
+use garage_table::*;
+use garage_util::background::*;
+use garage_util::data::*;
+use garage_util::error::Error;
+use garage_util::persister::Persister;
+
+use crate::bucket_table::*;
+use crate::garage::Garage;
+use crate::s3::object_table::{BYTES, OBJECTS};
+
+const USAGE_ALERT_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+// An alert is rearmed when usage goes below REARM_NUM/REARM_DEN of its threshold
+const REARM_NUM: u64 = 15;
+const REARM_DEN: u64 = 16;
+
+mod v010 {
+	use garage_util::data::Uuid;
+	use serde::{Deserialize, Serialize};
+	use std::collections::BTreeSet;
+
+	#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, Serialize, Deserialize)]
+	pub enum UsageAlertKind {
+		Size,
+		Objects,
+	}
+
+	#[derive(Serialize, Deserialize, Default, Clone)]
+	pub struct UsageAlertsPersisted {
+		/// Alerts that have been raised and not yet rearmed
+		pub fired: BTreeSet<(Uuid, UsageAlertKind)>,
+	}
+
+	impl garage_util::migrate::InitialFormat for UsageAlertsPersisted {
+		const VERSION_MARKER: &'static [u8] = b"G010uap";
+	}
+}
+
+pub use v010::*;
+
+impl UsageAlertKind {
+	pub fn name(&self) -> &'static str {
+		match self {
+			UsageAlertKind::Size => "size",
+			UsageAlertKind::Objects => "objects",
+		}
+	}
+}
+
+/// Compute the thresholds at which usage alerts are raised for a bucket,
+/// taking the lowest of the absolute threshold and of the quota percentage
+pub fn usage_alert_thresholds(params: &BucketParams) -> Vec<(UsageAlertKind, u64)> {
+	let alerts = params.usage_alerts.get();
+	let quotas = params.quotas.get();
+	let of_quota = |quota: Option<u64>| {
+		alerts
+			.quota_percent
+			.zip(quota)
+			.map(|(pct, q)| (q as u128 * pct as u128 / 100) as u64)
+	};
+
+	vec![
+		(
+			UsageAlertKind::Size,
+			alerts.max_size,
+			of_quota(quotas.max_size),
+		),
+		(
+			UsageAlertKind::Objects,
+			alerts.max_objects,
+			of_quota(quotas.max_objects),
+		),
+	]
+	.into_iter()
+	.filter_map(|(kind, abs, rel)| {
+		let threshold = match (abs, rel) {
+			(Some(a), Some(r)) => a.min(r),
+			(a, r) => a.or(r)?,
+		};
+		Some((kind, threshold))
+	})
+	.collect()
+}
+
+/// Given whether an alert is currently raised, compute whether it is
+/// still raised when usage is `value`
+pub fn usage_alert_fired(fired: bool, value: u64, threshold: u64) -> bool {
+	if fired {
+		value as u128 * REARM_DEN as u128 >= threshold as u128 * REARM_NUM as u128
+	} else {
+		value >= threshold
+	}
+}
+
+pub struct UsageAlertWorker {
+	garage: Arc<Garage>,
+	persister: Persister<UsageAlertsPersisted>,
+	fired: BTreeSet<(Uuid, UsageAlertKind)>,
+	next_check: Instant,
+	alerts_raised: u64,
+	alert_counter: Counter<u64>,
+}
+
+impl UsageAlertWorker {
+	pub(crate) fn new(garage: Arc<Garage>) -> Self {
+		let persister = Persister::new(&garage.system.metadata_dir, "usage_alerts_state");
+		let fired = persister
+			.load()
+			.map(|p: UsageAlertsPersisted| p.fired)
+			.unwrap_or_default();
+		let alert_counter = global::meter("garage_model/usage_alerts")
+			.u64_counter("bucket.usage_alert_counter")
+			.with_description(
+				"Number of alerts raised because a bucket's usage reached a threshold",
+			)
+			.init();
+		Self {
+			garage,
+			persister,
+			fired,
+			next_check: Instant::now() + USAGE_ALERT_CHECK_INTERVAL,
+			alerts_raised: 0,
+			alert_counter,
+		}
+	}
+
+	async fn check_all_buckets(&mut self) -> Result<(), Error> {
+		let buckets = self
+			.garage
+			.bucket_table
+			.get_range(
+				&EmptyKey,
+				None,
+				Some(DeletedFilter::NotDeleted),
+				10000,
+				EnumerationOrder::Forward,
+			)
+			.await?;
+
+		let mut fired = BTreeSet::new();
+		for bucket in buckets {
+			let thresholds = match bucket.params() {
+				Some(p) => usage_alert_thresholds(p),
+				None => continue,
+			};
+			if thresholds.is_empty() {
+				continue;
+			}
+
+			let counters = self
+				.garage
+				.object_counter_table
+				.table
+				.get(&bucket.id, &EmptyKey)
+				.await?
+				.map(|x| x.filtered_values(&self.garage.system.cluster_layout()))
+				.unwrap_or_default();
+
+			for (kind, threshold) in thresholds {
+				let counter = match kind {
+					UsageAlertKind::Size => BYTES,
+					UsageAlertKind::Objects => OBJECTS,
+				};
+				let value = (*counters.get(counter).unwrap_or(&0)).max(0) as u64;
+
+				let was_fired = self.fired.contains(&(bucket.id, kind));
+				let is_fired = usage_alert_fired(was_fired, value, threshold);
+				if is_fired {
+					fired.insert((bucket.id, kind));
+				}
+
+				if is_fired && !was_fired {
+					warn!(
+						"Usage alert on bucket {:?}: {} is {}, threshold is {}",
+						bucket.id,
+						kind.name(),
+						value,
+						threshold
+					);
+					self.alerts_raised += 1;
+					self.alert_counter
+						.add(1, &[KeyValue::new("kind", kind.name())]);
+				} else if was_fired && !is_fired {
+					info!(
+						"Usage alert on bucket {:?} rearmed: {} is back to {}",
+						bucket.id,
+						kind.name(),
+						value
+					);
+				}
+			}
+		}
+
+		if fired != self.fired {
+			self.fired = fired;
+			self.persister
+				.save_async(&UsageAlertsPersisted {
+					fired: self.fired.clone(),
+				})
+				.await?;
+		}
+
+		Ok(())
+	}
+}
+
+#[async_trait]
+impl Worker for UsageAlertWorker {
+	fn name(&self) -> String {
+		"Bucket usage alert worker".into()
+	}
+
+	fn status(&self) -> WorkerStatus {
+		WorkerStatus {
+			freeform: vec![
+				format!("Alerts currently raised: {}", self.fired.len()),
+				format!("Alerts raised since startup: {}", self.alerts_raised),
+			],
+			..Default::default()
+		}
+	}
+
+	async fn work(&mut self, _must_exit: &mut watch::Receiver<bool>) -> Result<WorkerState, Error> {
+		if Instant::now() < self.next_check {
+			return Ok(WorkerState::Idle);
+		}
+		self.next_check = Instant::now() + USAGE_ALERT_CHECK_INTERVAL;
+
+		self.check_all_buckets().await?;
+
+		Ok(WorkerState::Idle)
+	}
+
+	async fn wait_for_work(&mut self) -> WorkerState {
+		tokio::time::sleep_until(self.next_check.into()).await;
+		WorkerState::Busy
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_usage_alert_thresholds() {
+		let mut params = BucketParams::default();
+		assert!(usage_alert_thresholds(&params).is_empty());
+
+		params.quotas.update(BucketQuotas {
+			max_size: Some(1000),
+			max_objects: None,
+			max_multipart_uploads: None,
+		});
+		params.usage_alerts.update(BucketUsageAlerts {
+			quota_percent: Some(80),
+			max_size: None,
+			max_objects: Some(50),
+		});
+		assert_eq!(
+			usage_alert_thresholds(&params),
+			vec![(UsageAlertKind::Size, 800), (UsageAlertKind::Objects, 50)]
+		);
+
+		params.usage_alerts.update(BucketUsageAlerts {
+			quota_percent: Some(80),
+			max_size: Some(500),
+			max_objects: None,
+		});
+		assert_eq!(
+			usage_alert_thresholds(&params),
+			vec![(UsageAlertKind::Size, 500)]
+		);
+	}
+
+	#[test]
+	fn test_usage_alert_hysteresis() {
+		// Alert at 80% of a quota of 1000, rearmed below 75%
+		let threshold = 800;
+		let usage = [100, 500, 790, 800, 850, 790, 760, 750, 749, 700, 810, 1000];
+
+		let mut fired = false;
+		let mut raised_at = vec![];
+		for value in usage {
+			let now_fired = usage_alert_fired(fired, value, threshold);
+			if now_fired && !fired {
+				raised_at.push(value);
+			}
+			fired = now_fired;
+		}
+		assert_eq!(raised_at, vec![800, 810]);
+		assert!(fired);
+
+		// Oscillating around the threshold raises a single alert
+		let mut fired = false;
+		let mut raised = 0;
+		for value in [799, 801, 799, 801, 780, 820] {
+			let now_fired = usage_alert_fired(fired, value, threshold);
+			raised += (now_fired && !fired) as usize;
+			fired = now_fired;
+		}
+		assert_eq!(raised, 1);
+	}
+}
@@ -0,0 +1,336 @@
+//! Lightweight recording of the evolution of a few cluster metrics over time,
+//! for deployments that don't have a monitoring stack.
+//!
+//! Samples are stored in a tree of the local metadata database, in two tiers:
+//! all samples are kept for 48 hours, and one sample per hour is kept for 30 days.
+//! The size of the history is therefore bounded, whatever the sampling interval.
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use garage_db as db;
+
+use garage_table::*;
+use garage_util::background::*;
+use garage_util::data::*;
+use garage_util::encode::{nonversioned_decode, nonversioned_encode};
+use garage_util::error::Error;
+use garage_util::time::*;
+
+use crate::garage::Garage;
+use crate::s3::object_table::{BYTES, OBJECTS};
+
+const HOUR_MSEC: u64 = 3600 * 1000;
+
+/// Tier containing all samples
+const TIER_FINE: u8 = 0;
+/// Tier containing one sample per hour
+const TIER_HOURLY: u8 = 1;
+
+const FINE_RETENTION_MSEC: u64 = 48 * HOUR_MSEC;
+const HOURLY_RETENTION_MSEC: u64 = 30 * 24 * HOUR_MSEC;
+
+// Bounds of the sampling interval: at least one sample per hour is required
+// to fill the hourly tier
+const MIN_SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_SAMPLE_INTERVAL: Duration = Duration::from_secs(3600);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct UsageSample {
+	/// Time at which the sample was taken, in msec since the epoch
+	pub timestamp: u64,
+	/// Total size of objects in all buckets
+	pub bytes: i64,
+	/// Total number of objects in all buckets
+	pub objects: i64,
+	/// Free space in the data directory of each storage node
+	pub nodes: Vec<NodeSpaceSample>,
+	/// Length of the block resync queue of the node taking the sample
+	pub resync_queue_len: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct NodeSpaceSample {
+	pub id: Uuid,
+	pub data_avail: u64,
+	pub data_total: u64,
+}
+
+/// Storage of the usage history in the local metadata database
+pub struct UsageHistory {
+	/// Interval at which samples are taken
+	pub interval: Duration,
+	tree: db::Tree,
+}
+
+impl UsageHistory {
+	pub fn new(db: &db::Db, interval: Duration) -> Result<Self, Error> {
+		if !(MIN_SAMPLE_INTERVAL..=MAX_SAMPLE_INTERVAL).contains(&interval) {
+			return Err(Error::Message(
+				"usage_history_interval must be between 1 minute and 1 hour".into(),
+			));
+		}
+		let tree = db.open_tree("usage_history")?;
+		Ok(Self { interval, tree })
+	}
+
+	/// Store a new sample, keeping it in the hourly tier if it is the first
+	/// sample of its hour, and remove samples that are too old
+	pub fn record(&self, sample: &UsageSample) -> Result<(), Error> {
+		let now = sample.timestamp;
+		let value = nonversioned_encode(sample)?;
+
+		self.tree.insert(sample_key(TIER_FINE, now), &value)?;
+
+		let last_hourly = self
+			.tree
+			.range_rev(sample_key(TIER_HOURLY, 0)..=sample_key(TIER_HOURLY, u64::MAX))?
+			.next()
+			.transpose()?
+			.map(|(k, _)| key_timestamp(&k));
+		if last_hourly.map(|t| t / HOUR_MSEC) != Some(now / HOUR_MSEC) {
+			self.tree.insert(sample_key(TIER_HOURLY, now), &value)?;
+		}
+
+		self.prune(TIER_FINE, now.saturating_sub(FINE_RETENTION_MSEC))?;
+		self.prune(TIER_HOURLY, now.saturating_sub(HOURLY_RETENTION_MSEC))?;
+
+		Ok(())
+	}
+
+	/// Get all samples taken since the given time, at the finest resolution
+	/// available for each period
+	pub fn get_since(&self, since: u64) -> Result<Vec<UsageSample>, Error> {
+		let fine = self.get_tier(TIER_FINE, since, u64::MAX)?;
+		let hourly_until = fine.first().map(|s| s.timestamp).unwrap_or(u64::MAX);
+		let mut ret = self.get_tier(TIER_HOURLY, since, hourly_until)?;
+		ret.extend(fine);
+		Ok(ret)
+	}
+
+	fn get_tier(&self, tier: u8, from: u64, until: u64) -> Result<Vec<UsageSample>, Error> {
+		let mut ret = vec![];
+		for item in self
+			.tree
+			.range(sample_key(tier, from)..sample_key(tier, until))?
+		{
+			let (_, v) = item?;
+			match nonversioned_decode::<UsageSample>(&v) {
+				Ok(sample) => ret.push(sample),
+				Err(e) => warn!("Invalid usage history sample: {}", e),
+			}
+		}
+		Ok(ret)
+	}
+
+	fn prune(&self, tier: u8, before: u64) -> Result<(), Error> {
+		let to_delete = self
+			.tree
+			.range(sample_key(tier, 0)..sample_key(tier, before))?
+			.map(|item| item.map(|(k, _)| k))
+			.collect::<Result<Vec<_>, _>>()?;
+		for k in to_delete {
+			self.tree.remove(k)?;
+		}
+		Ok(())
+	}
+}
+
+fn sample_key(tier: u8, timestamp: u64) -> Vec<u8> {
+	let mut key = Vec::with_capacity(9);
+	key.push(tier);
+	key.extend_from_slice(&timestamp.to_be_bytes());
+	key
+}
+
+fn key_timestamp(key: &[u8]) -> u64 {
+	let mut ts = [0u8; 8];
+	ts.copy_from_slice(&key[1..9]);
+	u64::from_be_bytes(ts)
+}
+
+// ---- worker ----
+
+pub struct UsageHistoryWorker {
+	garage: Arc<Garage>,
+	interval: Duration,
+	next_sample: Instant,
+	last_sample: Option<u64>,
+}
+
+impl UsageHistoryWorker {
+	pub(crate) fn new(garage: Arc<Garage>, interval: Duration) -> Self {
+		Self {
+			garage,
+			interval,
+			// Let the node connect to the rest of the cluster before the first sample
+			next_sample: Instant::now() + MIN_SAMPLE_INTERVAL,
+			last_sample: None,
+		}
+	}
+
+	async fn take_sample(&self) -> Result<UsageSample, Error> {
+		let buckets = self
+			.garage
+			.bucket_table
+			.get_range(
+				&EmptyKey,
+				None,
+				Some(DeletedFilter::NotDeleted),
+				10000,
+				EnumerationOrder::Forward,
+			)
+			.await?;
+
+		let (mut bytes, mut objects) = (0, 0);
+		for bucket in buckets {
+			let counters = self
+				.garage
+				.object_counter_table
+				.table
+				.get(&bucket.id, &EmptyKey)
+				.await?
+				.map(|x| x.filtered_values(&self.garage.system.cluster_layout()))
+				.unwrap_or_default();
+			bytes += *counters.get(BYTES).unwrap_or(&0);
+			objects += *counters.get(OBJECTS).unwrap_or(&0);
+		}
+
+		let storage_nodes = self
+			.garage
+			.system
+			.cluster_layout()
+			.current()
+			.nongateway_nodes()
+			.to_vec();
+		let nodes = self
+			.garage
+			.system
+			.get_known_nodes()
+			.into_iter()
+			.filter(|n| storage_nodes.contains(&n.id))
+			.filter_map(|n| {
+				n.status
+					.data_disk_avail
+					.map(|(data_avail, data_total)| NodeSpaceSample {
+						id: n.id,
+						data_avail,
+						data_total,
+					})
+			})
+			.collect();
+
+		Ok(UsageSample {
+			timestamp: now_msec(),
+			bytes,
+			objects,
+			nodes,
+			resync_queue_len: self.garage.block_manager.resync.queue_len()? as u64,
+		})
+	}
+}
+
+#[async_trait]
+impl Worker for UsageHistoryWorker {
+	fn name(&self) -> String {
+		"Usage history recorder".into()
+	}
+
+	fn status(&self) -> WorkerStatus {
+		WorkerStatus {
+			freeform: vec![
+				format!("Sampling interval: {}s", self.interval.as_secs()),
+				format!(
+					"Last sample: {}",
+					self.last_sample
+						.map(msec_to_rfc3339)
+						.unwrap_or_else(|| "never".into())
+				),
+			],
+			..Default::default()
+		}
+	}
+
+	async fn work(&mut self, _must_exit: &mut watch::Receiver<bool>) -> Result<WorkerState, Error> {
+		if Instant::now() < self.next_sample {
+			return Ok(WorkerState::Idle);
+		}
+		self.next_sample = Instant::now() + self.interval;
+
+		let sample = self.take_sample().await?;
+		if let Some(history) = &self.garage.usage_history {
+			history.record(&sample)?;
+		}
+		self.last_sample = Some(sample.timestamp);
+
+		Ok(WorkerState::Idle)
+	}
+
+	async fn wait_for_work(&mut self) -> WorkerState {
+		tokio::time::sleep_until(self.next_sample.into()).await;
+		WorkerState::Busy
+	}
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+	use super::*;
+
+	fn sample(timestamp: u64) -> UsageSample {
+		UsageSample {
+			timestamp,
+			bytes: timestamp as i64,
+			objects: 1,
+			nodes: vec![],
+			resync_queue_len: 0,
+		}
+	}
+
+	#[test]
+	fn test_usage_history() {
+		let path = std::env::temp_dir().join(format!(
+			"garage-usage-history-test-{}.sqlite",
+			rand::random::<u64>()
+		));
+		let db = db::open_db(&path, db::Engine::Sqlite, &Default::default()).unwrap();
+		let history = UsageHistory::new(&db, Duration::from_secs(300)).unwrap();
+
+		// One sample every 5 minutes for 40 days
+		let start = 1000 * 24 * HOUR_MSEC;
+		let step = 5 * 60 * 1000;
+		let end = start + 40 * 24 * HOUR_MSEC;
+		let mut t = start;
+		while t < end {
+			history.record(&sample(t)).unwrap();
+			t += step;
+		}
+		let last = t - step;
+
+		// Storage is bounded: 48h of fine samples and 30d of hourly samples
+		let fine = history.get_tier(TIER_FINE, 0, u64::MAX).unwrap();
+		assert_eq!(fine.len(), 48 * 12 + 1);
+		assert_eq!(fine.last().unwrap().timestamp, last);
+		let hourly = history.get_tier(TIER_HOURLY, 0, u64::MAX).unwrap();
+		assert_eq!(hourly.len(), 30 * 24);
+		assert!(hourly.iter().all(|s| s.timestamp % HOUR_MSEC == 0));
+		assert_eq!(history.tree.exact_len().unwrap(), 48 * 12 + 1 + 30 * 24);
+
+		// Recent history is at full resolution
+		let recent = history.get_since(last - 2 * HOUR_MSEC).unwrap();
+		assert_eq!(recent.len(), 2 * 12 + 1);
+		assert_eq!(recent, fine[fine.len() - 25..]);
+
+		// Older history is at hourly resolution, without overlap
+		let week = history.get_since(last - 7 * 24 * HOUR_MSEC).unwrap();
+		assert!(week.windows(2).all(|w| w[0].timestamp < w[1].timestamp));
+		assert_eq!(week.first().unwrap().timestamp % HOUR_MSEC, 0);
+		assert_eq!(week.len(), (7 * 24 - 48) + 48 * 12 + 1);
+
+		drop(history);
+		drop(db);
+		let _ = std::fs::remove_file(&path);
+	}
+}
@@ -42,6 +42,9 @@ pub(crate) struct ClientConn {
 
 	next_query_number: AtomicU32,
 	inflight: Mutex<HashMap<RequestID, oneshot::Sender<ByteStream>>>,
+
+	pub(crate) flow_control: FlowControl,
+	pub(crate) frame_checksums: FrameChecksums,
 }
 
 impl ClientConn {
@@ -91,6 +94,14 @@ impl ClientConn {
 		// Build and launch stuff that manages sending requests client-side
 		let (query_send, query_recv) = mpsc::unbounded_channel();
 
+		// Tell the server that we support flow control, before sending anything else,
+		// and that we can verify frame checksums
+		query_send.send(SendItem::Cancel(FLOW_CONTROL_HELLO_ID))?;
+		query_send.send(SendItem::GrantCredit(
+			FLOW_CONTROL_HELLO_ID,
+			FEATURE_FRAME_CHECKSUM,
+		))?;
+
 		let (stop_recv_loop, stop_recv_loop_recv) = watch::channel(false);
 
 		let conn = Arc::new(ClientConn {
@@ -99,6 +110,8 @@ impl ClientConn {
 			next_query_number: AtomicU32::from(RequestID::default()),
 			query_send: ArcSwapOption::new(Some(Arc::new(query_send))),
 			inflight: Mutex::new(HashMap::new()),
+			flow_control: FlowControl::default(),
+			frame_checksums: FrameChecksums::new(netapp.frame_checksums_enabled()),
 		});
 
 		netapp.connected_as_client(peer_id, conn.clone());
@@ -151,9 +164,15 @@ impl ClientConn {
 	{
 		let query_send = self.query_send.load_full().ok_or(Error::ConnectionClosed)?;
 
-		let id = self
+		let mut id = self
 			.next_query_number
 			.fetch_add(1, atomic::Ordering::Relaxed);
+		if id == FLOW_CONTROL_HELLO_ID {
+			// This id is reserved for flow control messages
+			id = self
+				.next_query_number
+				.fetch_add(1, atomic::Ordering::Relaxed);
+		}
 
 		cfg_if::cfg_if! {
 			if #[cfg(feature = "telemetry")] {
@@ -218,10 +237,30 @@ impl ClientConn {
 	}
 }
 
-impl SendLoop for ClientConn {}
+impl SendLoop for ClientConn {
+	fn flow_control(&self) -> &FlowControl {
+		&self.flow_control
+	}
+
+	fn frame_checksums(&self) -> &FrameChecksums {
+		&self.frame_checksums
+	}
+}
 
 #[async_trait]
 impl RecvLoop for ClientConn {
+	fn flow_control(&self) -> &FlowControl {
+		&self.flow_control
+	}
+
+	fn frame_checksums(&self) -> &FrameChecksums {
+		&self.frame_checksums
+	}
+
+	fn send_channel(&self) -> Option<Arc<mpsc::UnboundedSender<SendItem>>> {
+		self.query_send.load_full()
+	}
+
 	fn recv_handler(self: &Arc<Self>, id: RequestID, stream: ByteStream) {
 		trace!("ClientConn recv_handler {}", id);
 
@@ -8,6 +8,10 @@ use crate::error::Error;
 use crate::message::*;
 use crate::netapp::*;
 
+/// Number of times a request is sent again when it, or the header of its
+/// response, was corrupted on the wire
+const FRAME_CHECKSUM_RETRIES: usize = 2;
+
 /// This trait should be implemented by an object of your application
 /// that can handle a message of type `M`, if it wishes to handle
 /// streams attached to the request and/or to send back streams
@@ -139,7 +143,25 @@ where
 					"Not connected: {}",
 					hex::encode(&target[..8])
 				))),
-				Some(c) => c.call(req.into_req()?, self.path.as_str(), prio).await,
+				Some(c) => {
+					let req = req.into_req()?;
+					if matches!(req.stream, AttachedStream::Stream(_)) {
+						// The request cannot be sent again
+						return c.call(req, self.path.as_str(), prio).await;
+					}
+					let mut retries = 0;
+					loop {
+						match c.clone().call(req.clone(), self.path.as_str(), prio).await {
+							Err(e)
+								if e.is_frame_checksum_mismatch()
+									&& retries < FRAME_CHECKSUM_RETRIES =>
+							{
+								retries += 1;
+							}
+							res => break res,
+						}
+					}
+				}
 			}
 		}
 	}
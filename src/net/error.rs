@@ -3,6 +3,8 @@ use std::io;
 use err_derive::Error;
 use log::error;
 
+use crate::send::FRAME_CHECKSUM_MISMATCH;
+
 #[derive(Debug, Error)]
 pub enum Error {
 	#[error(display = "IO error: {}", _0)]
@@ -47,6 +49,15 @@ pub enum Error {
 	VersionMismatch(String),
 }
 
+impl Error {
+	/// Whether this error was caused by a chunk of the request or of the
+	/// response that was received with an invalid checksum. Such requests
+	/// can be sent again, as the corruption happened on the wire.
+	pub fn is_frame_checksum_mismatch(&self) -> bool {
+		matches!(self, Error::Remote(_, msg) if msg.contains(FRAME_CHECKSUM_MISMATCH))
+	}
+}
+
 impl<T> From<tokio::sync::watch::error::SendError<T>> for Error {
 	fn from(_e: tokio::sync::watch::error::SendError<T>) -> Error {
 		Error::Message("Watch send error".into())
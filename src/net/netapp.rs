@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
 
 use log::{debug, error, info, trace, warn};
@@ -23,6 +24,8 @@ use crate::error::*;
 use crate::message::*;
 use crate::server::*;
 
+pub use crate::send::{FlowControlStats, FrameChecksumStats};
+
 /// A node's identifier, which is also its public cryptographic key
 pub type NodeID = sodiumoxide::crypto::sign::ed25519::PublicKey;
 /// A node's secret key
@@ -76,6 +79,10 @@ pub struct NetApp {
 	/// Private key associated with our peer ID
 	pub privkey: ed25519::SecretKey,
 
+	/// Whether data frames exchanged with peers that support it are
+	/// protected by a checksum
+	frame_checksums: AtomicBool,
+
 	pub(crate) server_conns: RwLock<HashMap<NodeID, Arc<ServerConn>>>,
 	pub(crate) client_conns: RwLock<HashMap<NodeID, Arc<ClientConn>>>,
 
@@ -115,6 +122,7 @@ impl NetApp {
 			netid,
 			id,
 			privkey,
+			frame_checksums: AtomicBool::new(false),
 			server_conns: RwLock::new(HashMap::new()),
 			client_conns: RwLock::new(HashMap::new()),
 			endpoints: RwLock::new(HashMap::new()),
@@ -287,6 +295,45 @@ impl NetApp {
 		self.on_disconnected_handler.store(None);
 	}
 
+	/// Get statistics about the flow control of the streams we are sending
+	/// to each of the peers we are connected to, over both the connections
+	/// we opened and the connections they opened
+	pub fn flow_control_stats(&self) -> HashMap<NodeID, FlowControlStats> {
+		let mut ret = HashMap::<NodeID, FlowControlStats>::new();
+		for (id, conn) in self.client_conns.read().unwrap().iter() {
+			*ret.entry(*id).or_default() += conn.flow_control.stats();
+		}
+		for (id, conn) in self.server_conns.read().unwrap().iter() {
+			*ret.entry(*id).or_default() += conn.flow_control.stats();
+		}
+		ret
+	}
+
+	/// Enable or disable checksums on the data frames sent to peers.
+	/// Checksums are used on a connection only if both ends enable them;
+	/// this only applies to connections established after the call.
+	pub fn set_frame_checksums(&self, enable: bool) {
+		self.frame_checksums.store(enable, Ordering::Relaxed);
+	}
+
+	pub(crate) fn frame_checksums_enabled(&self) -> bool {
+		self.frame_checksums.load(Ordering::Relaxed)
+	}
+
+	/// Get statistics about the frame checksums used with each of the peers
+	/// we are connected to, over both the connections we opened and the
+	/// connections they opened
+	pub fn frame_checksum_stats(&self) -> HashMap<NodeID, FrameChecksumStats> {
+		let mut ret = HashMap::<NodeID, FrameChecksumStats>::new();
+		for (id, conn) in self.client_conns.read().unwrap().iter() {
+			*ret.entry(*id).or_default() += conn.frame_checksums.stats();
+		}
+		for (id, conn) in self.server_conns.read().unwrap().iter() {
+			*ret.entry(*id).or_default() += conn.frame_checksums.stats();
+		}
+		ret
+	}
+
 	/// Attempt to connect to a peer, given by its ip:port and its public key.
 	/// The public key will be checked during the secret handshake process.
 	/// This function returns once the connection has been established and a
@@ -6,6 +6,7 @@ use std::time::{Duration, Instant};
 
 use arc_swap::ArcSwap;
 use async_trait::async_trait;
+use futures::future::BoxFuture;
 use log::{debug, info, trace, warn};
 use serde::{Deserialize, Serialize};
 
@@ -29,6 +30,12 @@ const FAILED_PING_THRESHOLD: usize = 4;
 
 const DEFAULT_PING_TIMEOUT_MILLIS: u64 = 10_000;
 
+const RECONNECT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Function that looks up the current addresses of a peer in external
+/// discovery sources, used when connections to its known addresses keep failing
+pub type AddressResolver = Arc<dyn Fn(NodeID) -> BoxFuture<'static, Vec<SocketAddr>> + Send + Sync>;
+
 // -- Protocol messages --
 
 #[derive(Serialize, Deserialize)]
@@ -209,6 +216,11 @@ pub struct PeeringManager {
 	peer_list_endpoint: Arc<Endpoint<PeerListMessage, Self>>,
 
 	ping_timeout_millis: AtomicU64,
+
+	/// Number of failed connection attempts after which the addresses of a peer
+	/// are looked up again, and the function used to do so
+	rediscovery: RwLock<Option<(usize, AddressResolver)>>,
+	address_refreshes: AtomicU64,
 }
 
 impl PeeringManager {
@@ -245,6 +257,8 @@ impl PeeringManager {
 			ping_endpoint: netapp.endpoint("garage_net/peering.rs/Ping".into()),
 			peer_list_endpoint: netapp.endpoint("garage_net/peering.rs/PeerList".into()),
 			ping_timeout_millis: DEFAULT_PING_TIMEOUT_MILLIS.into(),
+			rediscovery: RwLock::new(None),
+			address_refreshes: AtomicU64::new(0),
 		});
 
 		strat.update_public_peer_list(&strat.known_hosts.read().unwrap());
@@ -355,6 +369,77 @@ impl PeeringManager {
 			.store(timeout, atomic::Ordering::Relaxed);
 	}
 
+	/// Look up the addresses of a peer with the given function once `after_failures`
+	/// consecutive attempts at connecting to it have failed, and try the addresses
+	/// that were not known yet right away. This allows reconnecting to peers whose
+	/// address has changed, e.g. when they are restarted with a new IP.
+	pub fn set_address_resolver(&self, after_failures: usize, resolver: AddressResolver) {
+		*self.rediscovery.write().unwrap() = Some((after_failures, resolver));
+	}
+
+	/// Number of times new addresses have been found for a peer
+	/// using the address resolver
+	pub fn address_refreshes(&self) -> u64 {
+		self.address_refreshes.load(atomic::Ordering::Relaxed)
+	}
+
+	/// Close the connection to a peer and immediately open a new one, doing
+	/// a fresh handshake. This can be used to recover a connection that got
+	/// into a bad state without restarting the node.
+	/// Returns the information about the peer once the new connection is
+	/// established or the first attempt at establishing it has failed,
+	/// or once `timeout` has elapsed.
+	pub async fn reconnect(
+		self: &Arc<Self>,
+		id: &NodeID,
+		timeout: Duration,
+	) -> Result<PeerInfo, Error> {
+		if *id == self.netapp.id {
+			return Err(Error::Message("Cannot reconnect to the local node".into()));
+		}
+		if !self.known_hosts.read().unwrap().list.contains_key(id) {
+			return Err(Error::Message(format!("Unknown peer: {}", hex::encode(id))));
+		}
+
+		info!("Forcing reconnection to {}", hex::encode(&id[..8]));
+		self.netapp.disconnect(id);
+		{
+			// Schedule a connection attempt right away, unless one is
+			// already in progress. The peering loop will pick it up.
+			let mut known_hosts = self.known_hosts.write().unwrap();
+			if let Some(host) = known_hosts.list.get_mut(id) {
+				if !matches!(host.state, PeerConnState::Trying(_)) {
+					host.state = PeerConnState::Waiting(0, Instant::now());
+				}
+				host.failed_pings = 0;
+				known_hosts.update_hash();
+				self.update_public_peer_list(&known_hosts);
+			}
+		}
+
+		let deadline = Instant::now() + timeout;
+		loop {
+			let done = match self.known_hosts.read().unwrap().list.get(id) {
+				Some(host) => match host.state {
+					PeerConnState::Connected { .. } | PeerConnState::Abandonned => true,
+					PeerConnState::Waiting(i, _) => i > 0,
+					_ => false,
+				},
+				None => true,
+			};
+			if done || Instant::now() >= deadline {
+				break;
+			}
+			tokio::time::sleep(RECONNECT_POLL_INTERVAL).await;
+		}
+
+		self.get_peer_list()
+			.iter()
+			.find(|p| p.id == *id)
+			.cloned()
+			.ok_or_else(|| Error::Message(format!("Unknown peer: {}", hex::encode(id))))
+	}
+
 	// -- internal stuff --
 
 	fn update_public_peer_list(&self, known_hosts: &KnownHosts) {
@@ -522,9 +607,11 @@ impl PeeringManager {
 				addresses.len()
 			);
 			let mut known_hosts = self.known_hosts.write().unwrap();
+			let mut failed_attempts = 0;
 			if let Some(host) = known_hosts.list.get_mut(&id) {
 				host.state = match host.state {
 					PeerConnState::Trying(i) => {
+						failed_attempts = i + 1;
 						if i >= CONN_MAX_RETRIES {
 							PeerConnState::Abandonned
 						} else {
@@ -535,9 +622,67 @@ impl PeeringManager {
 				};
 				self.update_public_peer_list(&known_hosts);
 			}
+			drop(known_hosts);
+
+			let resolver = match &*self.rediscovery.read().unwrap() {
+				Some((after_failures, resolver))
+					if *after_failures > 0 && failed_attempts >= *after_failures =>
+				{
+					Some(resolver.clone())
+				}
+				_ => None,
+			};
+			if let Some(resolver) = resolver {
+				tokio::spawn(self.clone().rediscover(id, resolver));
+			}
 		}
 	}
 
+	async fn rediscover(self: Arc<Self>, id: NodeID, resolver: AddressResolver) {
+		debug!("Looking up addresses of peer {}", hex::encode(&id[..8]));
+		let addresses = resolver(id).await;
+
+		let mut known_hosts = self.known_hosts.write().unwrap();
+		let host = match known_hosts.list.get_mut(&id) {
+			Some(host)
+				if matches!(
+					host.state,
+					PeerConnState::Waiting(..) | PeerConnState::Abandonned
+				) =>
+			{
+				host
+			}
+			_ => return,
+		};
+
+		let mut new_addrs = vec![];
+		for addr in addresses {
+			if !host.known_addrs.contains(&addr) && !new_addrs.contains(&addr) {
+				new_addrs.push(addr);
+			}
+		}
+		if new_addrs.is_empty() {
+			debug!("No new address found for peer {}", hex::encode(&id[..8]));
+			return;
+		}
+
+		info!(
+			"Found new address for peer {}: {}, retrying connection",
+			hex::encode(&id[..8]),
+			new_addrs
+				.iter()
+				.map(|x| format!("{}", x))
+				.collect::<Vec<_>>()
+				.join(", ")
+		);
+		// Try the new addresses first, the previous ones are most likely stale
+		host.known_addrs.splice(0..0, new_addrs);
+		host.state = PeerConnState::Waiting(0, Instant::now());
+		self.address_refreshes
+			.fetch_add(1, atomic::Ordering::Relaxed);
+		self.update_public_peer_list(&known_hosts);
+	}
+
 	fn on_connected(self: &Arc<Self>, id: NodeID, addr: SocketAddr, is_incoming: bool) {
 		if id == self.netapp.id {
 			// sanity check
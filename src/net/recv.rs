@@ -1,11 +1,13 @@
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::pin::Pin;
+use std::sync::{Arc, Weak};
+use std::task::{Context, Poll};
 
 use async_trait::async_trait;
 use bytes::Bytes;
 use log::*;
 
-use futures::AsyncReadExt;
+use futures::{AsyncReadExt, Stream};
 use tokio::sync::mpsc;
 
 use crate::error::*;
@@ -43,6 +45,55 @@ impl Drop for Sender {
 	}
 }
 
+/// Stream of the chunks received for a request or response, that grants
+/// credits to the sender as the chunks are consumed by the application
+struct CreditGrantingStream {
+	id: RequestID,
+	stream: ByteStream,
+	send: Weak<mpsc::UnboundedSender<SendItem>>,
+	consumed: u32,
+	eos: bool,
+}
+
+impl CreditGrantingStream {
+	fn grant(&self, n: u32) {
+		if let Some(send) = self.send.upgrade() {
+			let _ = send.send(SendItem::GrantCredit(self.id, n));
+		}
+	}
+}
+
+impl Stream for CreditGrantingStream {
+	type Item = Packet;
+
+	fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+		let res = self.stream.as_mut().poll_next(cx);
+		match &res {
+			Poll::Ready(Some(_)) => {
+				self.consumed += 1;
+				if self.consumed >= CREDIT_GRANT_BATCH {
+					self.grant(self.consumed);
+					self.consumed = 0;
+				}
+			}
+			Poll::Ready(None) => self.eos = true,
+			Poll::Pending => (),
+		}
+		res
+	}
+}
+
+impl Drop for CreditGrantingStream {
+	fn drop(&mut self) {
+		// If the stream is dropped before its end, the remaining chunks
+		// will be discarded by the receive loop: let them come as fast
+		// as possible so that the sender can be done with this stream.
+		if !self.eos {
+			self.grant(CREDIT_UNLIMITED);
+		}
+	}
+}
+
 /// The RecvLoop trait, which is implemented both by the client and the server
 /// connection objects (ServerConn and ClientConn) adds a method `.recv_loop()`
 /// and a prototype of a handler for received messages `.recv_handler()` that
@@ -54,12 +105,21 @@ impl Drop for Sender {
 pub(crate) trait RecvLoop: Sync + 'static {
 	fn recv_handler(self: &Arc<Self>, id: RequestID, stream: ByteStream);
 	fn cancel_handler(self: &Arc<Self>, _id: RequestID) {}
+	fn hello_handler(self: &Arc<Self>) {}
+
+	fn flow_control(&self) -> &FlowControl;
+	fn frame_checksums(&self) -> &FrameChecksums;
+	fn send_channel(&self) -> Option<Arc<mpsc::UnboundedSender<SendItem>>>;
 
 	async fn recv_loop<R>(self: Arc<Self>, mut read: R, debug_name: String) -> Result<(), Error>
 	where
 		R: AsyncReadExt + Unpin + Send + Sync,
 	{
 		let mut streams: HashMap<RequestID, Sender> = HashMap::new();
+		// Streams whose remaining chunks are dropped, because one of
+		// their chunks had an invalid checksum
+		let mut discarded: HashSet<RequestID> = HashSet::new();
+		let mut first_frame = true;
 		loop {
 			trace!(
 				"recv_loop({}): in_progress = {:?}",
@@ -78,8 +138,39 @@ pub(crate) trait RecvLoop: Sync + 'static {
 			let mut header_size = [0u8; ChunkLength::BITS as usize / 8];
 			read.read_exact(&mut header_size[..]).await?;
 			let size = ChunkLength::from_be_bytes(header_size);
+			let is_first_frame = std::mem::replace(&mut first_frame, false);
+
+			if size == CANCEL_REQUEST && id == FLOW_CONTROL_HELLO_ID && is_first_frame {
+				// Not an actual cancellation: the peer tells us that it
+				// supports flow control (see send.rs)
+				self.hello_handler();
+				continue;
+			}
+
+			if size == CREDIT_GRANT {
+				let mut credits = [0u8; 4];
+				read.read_exact(&mut credits[..]).await?;
+				let credits = u32::from_be_bytes(credits);
+				if id == FLOW_CONTROL_HELLO_ID && credits & FRAME_CHECKSUM_START != 0 {
+					debug!("recv_loop({}): peer sends frame checksums", debug_name);
+					self.frame_checksums().start_receiving();
+				} else if id == FLOW_CONTROL_HELLO_ID {
+					debug!("recv_loop({}): flow control enabled", debug_name);
+					self.flow_control().enable();
+					if credits & FEATURE_FRAME_CHECKSUM != 0 && self.frame_checksums().is_enabled()
+					{
+						if let Some(send) = self.send_channel() {
+							let _ = send.send(SendItem::StartChecksums);
+						}
+					}
+				} else if let Some(send) = self.send_channel() {
+					let _ = send.send(SendItem::Credit(id, credits));
+				}
+				continue;
+			}
 
 			if size == CANCEL_REQUEST {
+				discarded.remove(&id);
 				if let Some(mut stream) = streams.remove(&id) {
 					let _ = stream.send(Err(std::io::Error::new(
 						std::io::ErrorKind::Other,
@@ -91,13 +182,43 @@ pub(crate) trait RecvLoop: Sync + 'static {
 				continue;
 			}
 
-			let has_cont = (size & CHUNK_FLAG_HAS_CONTINUATION) != 0;
+			let mut has_cont = (size & CHUNK_FLAG_HAS_CONTINUATION) != 0;
 			let is_error = (size & CHUNK_FLAG_ERROR) != 0;
 			let size = (size & CHUNK_LENGTH_MASK) as usize;
 			let mut next_slice = vec![0; size as usize];
 			read.read_exact(&mut next_slice[..]).await?;
 
-			let packet = if is_error {
+			let checksum_ok = if self.frame_checksums().is_receiving() {
+				let mut checksum = [0u8; 4];
+				read.read_exact(&mut checksum[..]).await?;
+				u32::from_be_bytes(checksum)
+					== frame_checksum(&header_id, &header_size, &next_slice)
+			} else {
+				true
+			};
+
+			if discarded.contains(&id) {
+				if !has_cont {
+					discarded.remove(&id);
+				}
+				continue;
+			}
+
+			let packet = if !checksum_ok {
+				warn!(
+					"recv_loop({}): invalid checksum for a chunk of stream {}",
+					debug_name, id
+				);
+				self.frame_checksums().add_failure();
+				if has_cont {
+					discarded.insert(id);
+					has_cont = false;
+				}
+				Some(Err(std::io::Error::new(
+					std::io::ErrorKind::InvalidData,
+					FRAME_CHECKSUM_MISMATCH,
+				)))
+			} else if is_error {
 				let kind = u8_to_io_errorkind(next_slice[0]);
 				let msg =
 					std::str::from_utf8(&next_slice[1..]).unwrap_or("<invalid utf8 error message>");
@@ -126,10 +247,21 @@ pub(crate) trait RecvLoop: Sync + 'static {
 			} else {
 				let (send, recv) = mpsc::unbounded_channel();
 				trace!("recv_loop({}): id {} is new channel", debug_name, id);
-				self.recv_handler(
-					id,
-					Box::pin(tokio_stream::wrappers::UnboundedReceiverStream::new(recv)),
-				);
+				let stream: ByteStream =
+					Box::pin(tokio_stream::wrappers::UnboundedReceiverStream::new(recv));
+				let stream: ByteStream = match self.send_channel() {
+					Some(chan) if self.flow_control().is_enabled() => {
+						Box::pin(CreditGrantingStream {
+							id,
+							stream,
+							send: Arc::downgrade(&chan),
+							consumed: 0,
+							eos: false,
+						})
+					}
+					_ => stream,
+				};
+				self.recv_handler(id, stream);
 				Sender::new(send)
 			};
 
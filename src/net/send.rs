@@ -1,5 +1,6 @@
 use std::collections::{HashMap, VecDeque};
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::task::{Context, Poll};
 
@@ -8,7 +9,6 @@ use bytes::{BufMut, Bytes, BytesMut};
 use log::*;
 
 use futures::{AsyncWriteExt, Future};
-use kuska_handshake::async_std::BoxStreamWrite;
 use tokio::sync::mpsc;
 
 use crate::error::*;
@@ -23,12 +23,37 @@ use crate::stream::*;
 //		CHUNK_FLAG_ERROR if this chunk denotes an error
 //		(these two flags are exclusive, an error denotes the end of the stream)
 //		**special value** 0xFFFF indicates a CANCEL message
+//		**special value** 0xFFFE indicates a CREDIT message
 // - [u8; chunk_length], either
 //   - if not error: chunk data
 //   - if error:
 //       - u8: error kind, encoded using error::io_errorkind_to_u8
 //       - rest: error message
-//   - absent for cancel messag
+//   - absent for cancel message
+//   - for credit message: u32 BE, number of chunks that the sender of
+//     the stream with this id is allowed to send in addition to what
+//     it was already allowed to send (u32::MAX means no limit)
+//
+// Flow control:
+// A client that supports flow control sends a CANCEL message for request id
+// FLOW_CONTROL_HELLO_ID as its very first message. Servers that don't
+// support flow control just ignore it, servers that do reply with a CREDIT
+// message with the same id. From then on, both ends of the connection
+// send at most STREAM_WINDOW_CHUNKS chunks of a stream in advance of the
+// credits granted by the receiving end, which grants more credits as the
+// chunks it received are consumed by the application.
+//
+// Frame checksums:
+// Nodes that can verify frame checksums say so by sending a CREDIT message
+// for FLOW_CONTROL_HELLO_ID with the FEATURE_FRAME_CHECKSUM bit set: the
+// client right after its flow control hello, the server in its reply to it
+// (nodes that don't support frame checksums ignore the value of this message).
+// A node that has frame checksums enabled and learns that its peer can verify
+// them sends a CREDIT message for FLOW_CONTROL_HELLO_ID with the
+// FRAME_CHECKSUM_START bit set: all data and error chunks that it sends after
+// this message are followed by a u32 BE CRC32C of the request id, of the chunk
+// length + flags and of the chunk data. A chunk whose checksum does not match
+// ends its stream with an error, and the rest of the stream is discarded.
 
 pub(crate) type RequestID = u32;
 pub(crate) type ChunkLength = u16;
@@ -38,10 +63,148 @@ pub(crate) const CHUNK_FLAG_ERROR: ChunkLength = 0x4000;
 pub(crate) const CHUNK_FLAG_HAS_CONTINUATION: ChunkLength = 0x8000;
 pub(crate) const CHUNK_LENGTH_MASK: ChunkLength = 0x3FFF;
 pub(crate) const CANCEL_REQUEST: ChunkLength = 0xFFFF;
+pub(crate) const CREDIT_GRANT: ChunkLength = 0xFFFE;
+
+pub(crate) const FLOW_CONTROL_HELLO_ID: RequestID = RequestID::MAX;
+pub(crate) const STREAM_WINDOW_CHUNKS: u32 = 64;
+pub(crate) const CREDIT_GRANT_BATCH: u32 = STREAM_WINDOW_CHUNKS / 4;
+pub(crate) const CREDIT_UNLIMITED: u32 = u32::MAX;
+
+pub(crate) const FEATURE_FRAME_CHECKSUM: u32 = 0x1;
+pub(crate) const FRAME_CHECKSUM_START: u32 = 0x2;
+pub(crate) const FRAME_CHECKSUM_MISMATCH: &str = "netapp: frame checksum mismatch";
 
 pub(crate) enum SendItem {
 	Stream(RequestID, RequestPriority, Option<OrderTag>, ByteStream),
 	Cancel(RequestID),
+	/// Credits granted by the peer for one of the streams we are sending
+	Credit(RequestID, u32),
+	/// Grant credits to the peer for one of the streams we are receiving
+	GrantCredit(RequestID, u32),
+	/// Start sending checksums after each chunk
+	StartChecksums,
+}
+
+// ----
+
+/// State of the flow control on a connection, shared by its send loop
+/// and its receive loop
+#[derive(Default)]
+pub(crate) struct FlowControl {
+	enabled: AtomicBool,
+	outstanding_credits: AtomicU64,
+	blocked_streams: AtomicU64,
+}
+
+/// Statistics about the flow control of the streams sent to a peer
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FlowControlStats {
+	/// Whether flow control is enabled on the connections with this peer
+	pub enabled: bool,
+	/// Number of chunks that we are still allowed to send to the peer
+	/// without waiting for it to grant us more credits
+	pub outstanding_credits: u64,
+	/// Number of streams that are waiting for the peer to grant us credits
+	pub blocked_streams: u64,
+}
+
+impl FlowControl {
+	pub(crate) fn enable(&self) {
+		self.enabled.store(true, Ordering::Relaxed);
+	}
+
+	pub(crate) fn is_enabled(&self) -> bool {
+		self.enabled.load(Ordering::Relaxed)
+	}
+
+	pub(crate) fn stats(&self) -> FlowControlStats {
+		FlowControlStats {
+			enabled: self.is_enabled(),
+			outstanding_credits: self.outstanding_credits.load(Ordering::Relaxed),
+			blocked_streams: self.blocked_streams.load(Ordering::Relaxed),
+		}
+	}
+}
+
+impl std::ops::AddAssign for FlowControlStats {
+	fn add_assign(&mut self, other: Self) {
+		self.enabled |= other.enabled;
+		self.outstanding_credits += other.outstanding_credits;
+		self.blocked_streams += other.blocked_streams;
+	}
+}
+
+/// State of the frame checksums on a connection, shared by its send loop
+/// and its receive loop
+#[derive(Default)]
+pub(crate) struct FrameChecksums {
+	enabled: bool,
+	sending: AtomicBool,
+	receiving: AtomicBool,
+	failures: AtomicU64,
+}
+
+/// Statistics about the frame checksums on the connections with a peer
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FrameChecksumStats {
+	/// Whether we send checksums with the chunks we send to the peer
+	pub sending: bool,
+	/// Whether the peer sends checksums with the chunks it sends us
+	pub receiving: bool,
+	/// Number of chunks received from the peer whose checksum did not match
+	pub failures: u64,
+}
+
+impl FrameChecksums {
+	pub(crate) fn new(enabled: bool) -> Self {
+		Self {
+			enabled,
+			..Default::default()
+		}
+	}
+
+	pub(crate) fn is_enabled(&self) -> bool {
+		self.enabled
+	}
+
+	pub(crate) fn is_sending(&self) -> bool {
+		self.sending.load(Ordering::Relaxed)
+	}
+
+	pub(crate) fn is_receiving(&self) -> bool {
+		self.receiving.load(Ordering::Relaxed)
+	}
+
+	pub(crate) fn start_receiving(&self) {
+		self.receiving.store(true, Ordering::Relaxed);
+	}
+
+	pub(crate) fn add_failure(&self) {
+		self.failures.fetch_add(1, Ordering::Relaxed);
+	}
+
+	pub(crate) fn stats(&self) -> FrameChecksumStats {
+		FrameChecksumStats {
+			sending: self.is_sending(),
+			receiving: self.is_receiving(),
+			failures: self.failures.load(Ordering::Relaxed),
+		}
+	}
+}
+
+impl std::ops::AddAssign for FrameChecksumStats {
+	fn add_assign(&mut self, other: Self) {
+		self.sending |= other.sending;
+		self.receiving |= other.receiving;
+		self.failures += other.failures;
+	}
+}
+
+/// Checksum sent after a chunk when frame checksums are enabled
+pub(crate) fn frame_checksum(header_id: &[u8], header_size: &[u8], data: &[u8]) -> u32 {
+	let crc = crc32c::crc32c(header_id);
+	let crc = crc32c::crc32c_append(crc, header_size);
+	crc32c::crc32c_append(crc, data)
 }
 
 // ----
@@ -61,6 +224,9 @@ struct SendQueueItem {
 	order_tag: Option<OrderTag>,
 	data: ByteStreamReader,
 	sent: usize,
+	/// Number of chunks we can still send before the peer grants us more
+	/// credits, None if this stream is not subject to flow control
+	credits: Option<u32>,
 }
 
 impl SendQueue {
@@ -89,6 +255,31 @@ impl SendQueue {
 	fn is_empty(&self) -> bool {
 		self.items.iter().all(|(_k, v)| v.is_empty())
 	}
+	fn add_credits(&mut self, id: RequestID, n: u32) {
+		for (_, prioq) in self.items.iter_mut() {
+			for item in prioq.items.iter_mut().filter(|x| x.id == id) {
+				if let Some(credits) = &mut item.credits {
+					*credits = credits.saturating_add(n);
+				}
+			}
+		}
+	}
+	fn update_stats(&self, flow_control: &FlowControl) {
+		let (mut credits, mut blocked) = (0u64, 0u64);
+		for item in self.items.iter().flat_map(|(_, q)| q.items.iter()) {
+			match item.credits {
+				Some(0) => blocked += 1,
+				Some(CREDIT_UNLIMITED) | None => (),
+				Some(n) => credits += n as u64,
+			}
+		}
+		flow_control
+			.outstanding_credits
+			.store(credits, Ordering::Relaxed);
+		flow_control
+			.blocked_streams
+			.store(blocked, Ordering::Relaxed);
+	}
 
 	// this is like an async fn, but hand implemented
 	fn next_ready(&mut self) -> SendQueuePollNextReady<'_> {
@@ -143,6 +334,11 @@ impl SendQueuePriority {
 					continue;
 				}
 
+				if item.credits == Some(0) {
+					// Wait for the peer to grant us more credits
+					continue;
+				}
+
 				let mut item_reader = item.data.read_exact_or_eos(MAX_CHUNK_LENGTH as usize);
 				if let Poll::Ready(bytes_or_err) = Pin::new(&mut item_reader).poll(ctx) {
 					let id = item.id;
@@ -156,6 +352,9 @@ impl SendQueuePriority {
 					let is_err = packet.is_err();
 					let data_frame = DataFrame::from_packet(packet, !eos);
 					item.sent += data_frame.data().len();
+					if let Some(credits) = &mut item.credits {
+						*credits = credits.saturating_sub(1);
+					}
 
 					if eos || is_err {
 						// If item had an order tag, remove it from the corresponding ordering list
@@ -186,7 +385,12 @@ impl SendQueuePriority {
 	fn dump(&self, prio: u8) -> String {
 		self.items
 			.iter()
-			.map(|i| format!("[{} {} {:?} @{}]", prio, i.id, i.order_tag, i.sent))
+			.map(|i| {
+				format!(
+					"[{} {} {:?} @{} {:?}]",
+					prio, i.id, i.order_tag, i.sent, i.credits
+				)
+			})
 			.collect::<Vec<_>>()
 			.join(" ")
 	}
@@ -267,7 +471,8 @@ impl DataFrame {
 
 /// The SendLoop trait, which is implemented both by the client and the server
 /// connection objects (ServerConna and ClientConn) adds a method `.send_loop()`
-/// that takes a channel of messages to send and an asynchronous writer,
+/// that takes a channel of messages to send and an asynchronous writer
+/// (a `BoxStreamWrite` for actual connections),
 /// and sends messages from the channel to the async writer, putting them in a queue
 /// before being sent and doing the round-robin sending strategy.
 ///
@@ -275,10 +480,13 @@ impl DataFrame {
 /// or if there is an error at any time writing to the async writer.
 #[async_trait]
 pub(crate) trait SendLoop: Sync {
+	fn flow_control(&self) -> &FlowControl;
+	fn frame_checksums(&self) -> &FrameChecksums;
+
 	async fn send_loop<W>(
 		self: Arc<Self>,
 		msg_recv: mpsc::UnboundedReceiver<SendItem>,
-		mut write: BoxStreamWrite<W>,
+		mut write: W,
 		debug_name: String,
 	) -> Result<(), Error>
 	where
@@ -321,6 +529,11 @@ pub(crate) trait SendLoop: Sync {
 								order_tag,
 								data: ByteStreamReader::new(data),
 								sent: 0,
+								credits: if self.flow_control().is_enabled() {
+									Some(STREAM_WINDOW_CHUNKS)
+								} else {
+									None
+								},
 							})
 						}
 						Some(SendItem::Cancel(id)) => {
@@ -331,6 +544,27 @@ pub(crate) trait SendLoop: Sync {
 							write.write_all(&ChunkLength::to_be_bytes(CANCEL_REQUEST)).await?;
 							write.flush().await?;
 						}
+						Some(SendItem::Credit(id, n)) => {
+							trace!("send_loop({}): got {} credits for {}", debug_name, n, id);
+							sending.add_credits(id, n);
+						}
+						Some(SendItem::GrantCredit(id, n)) => {
+							trace!("send_loop({}): granting {} credits for {}", debug_name, n, id);
+							let header_id = RequestID::to_be_bytes(id);
+							write.write_all(&header_id[..]).await?;
+							write.write_all(&ChunkLength::to_be_bytes(CREDIT_GRANT)).await?;
+							write.write_all(&u32::to_be_bytes(n)).await?;
+							write.flush().await?;
+						}
+						Some(SendItem::StartChecksums) => {
+							trace!("send_loop({}): start sending frame checksums", debug_name);
+							let header_id = RequestID::to_be_bytes(FLOW_CONTROL_HELLO_ID);
+							write.write_all(&header_id[..]).await?;
+							write.write_all(&ChunkLength::to_be_bytes(CREDIT_GRANT)).await?;
+							write.write_all(&u32::to_be_bytes(FRAME_CHECKSUM_START)).await?;
+							write.flush().await?;
+							self.frame_checksums().sending.store(true, Ordering::Relaxed);
+						}
 						None => {
 							msg_recv = None;
 						}
@@ -348,14 +582,22 @@ pub(crate) trait SendLoop: Sync {
 					let header_id = RequestID::to_be_bytes(id);
 					write.write_all(&header_id[..]).await?;
 
-					write.write_all(&data.header()).await?;
+					let header = data.header();
+					write.write_all(&header).await?;
 					write.write_all(data.data()).await?;
+					if self.frame_checksums().is_sending() {
+						let checksum = frame_checksum(&header_id, &header, data.data());
+						write.write_all(&u32::to_be_bytes(checksum)).await?;
+					}
 					write.flush().await?;
 				}
 			}
+
+			sending.update_stats(self.flow_control());
 		}
 
-		let _ = write.goodbye().await;
+		// For a BoxStreamWrite, this sends the goodbye message
+		let _ = write.close().await;
 		Ok(())
 	}
 }
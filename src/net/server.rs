@@ -56,6 +56,9 @@ pub(crate) struct ServerConn {
 
 	resp_send: ArcSwapOption<mpsc::UnboundedSender<SendItem>>,
 	running_handlers: Mutex<HashMap<RequestID, tokio::task::JoinHandle<()>>>,
+
+	pub(crate) flow_control: FlowControl,
+	pub(crate) frame_checksums: FrameChecksums,
 }
 
 impl ServerConn {
@@ -102,6 +105,8 @@ impl ServerConn {
 			peer_id,
 			resp_send: ArcSwapOption::new(Some(Arc::new(resp_send))),
 			running_handlers: Mutex::new(HashMap::new()),
+			flow_control: FlowControl::default(),
+			frame_checksums: FrameChecksums::new(netapp.frame_checksums_enabled()),
 		});
 
 		netapp.connected_as_server(peer_id, conn.clone());
@@ -172,10 +177,30 @@ impl ServerConn {
 	}
 }
 
-impl SendLoop for ServerConn {}
+impl SendLoop for ServerConn {
+	fn flow_control(&self) -> &FlowControl {
+		&self.flow_control
+	}
+
+	fn frame_checksums(&self) -> &FrameChecksums {
+		&self.frame_checksums
+	}
+}
 
 #[async_trait]
 impl RecvLoop for ServerConn {
+	fn flow_control(&self) -> &FlowControl {
+		&self.flow_control
+	}
+
+	fn frame_checksums(&self) -> &FrameChecksums {
+		&self.frame_checksums
+	}
+
+	fn send_channel(&self) -> Option<Arc<mpsc::UnboundedSender<SendItem>>> {
+		self.resp_send.load_full()
+	}
+
 	fn recv_handler(self: &Arc<Self>, id: RequestID, stream: ByteStream) {
 		let resp_send = match self.resp_send.load_full() {
 			Some(c) => c,
@@ -206,6 +231,25 @@ impl RecvLoop for ServerConn {
 		rh.insert(id, jh);
 	}
 
+	fn hello_handler(self: &Arc<Self>) {
+		debug!(
+			"client {} supports flow control",
+			hex::encode(&self.peer_id[..8])
+		);
+
+		// Enable flow control before acknowledging, so that all streams
+		// sent after the client has received the acknowledgement are
+		// subject to flow control. The acknowledgement also tells the
+		// client that we can verify frame checksums.
+		self.flow_control.enable();
+		if let Some(resp_send) = self.resp_send.load_full() {
+			let _ = resp_send.send(SendItem::GrantCredit(
+				FLOW_CONTROL_HELLO_ID,
+				FEATURE_FRAME_CHECKSUM,
+			));
+		}
+	}
+
 	fn cancel_handler(self: &Arc<Self>, id: RequestID) {
 		trace!("received cancel for request {}", id);
 
@@ -1,17 +1,91 @@
 use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::time::Duration;
 
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::future::FutureExt;
+use futures::{AsyncWrite, StreamExt};
+use serde::{Deserialize, Serialize};
 use tokio::select;
-use tokio::sync::watch;
+use tokio::sync::{mpsc, watch};
+use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
 
 use sodiumoxide::crypto::auth;
 use sodiumoxide::crypto::sign::ed25519;
 
+use crate::endpoint::*;
+use crate::message::*;
 use crate::netapp::*;
 use crate::peering::*;
+use crate::recv::RecvLoop;
+use crate::send::*;
+use crate::stream::ByteStream;
 use crate::NodeID;
 
+#[derive(Serialize, Deserialize)]
+struct EchoMessage(u64);
+
+impl Message for EchoMessage {
+	type Response = EchoMessage;
+}
+
+struct EchoHandler;
+
+#[async_trait]
+impl EndpointHandler<EchoMessage> for EchoHandler {
+	async fn handle(self: &Arc<Self>, msg: &EchoMessage, _from: NodeID) -> EchoMessage {
+		EchoMessage(msg.0)
+	}
+}
+
+#[derive(Serialize, Deserialize)]
+struct BulkMessage(u64);
+
+impl Message for BulkMessage {
+	type Response = BulkMessage;
+}
+
+/// Handler that consumes the stream attached to requests slowly, and checks
+/// how far ahead of it the sender has read from the stream it is sending
+struct SlowConsumerHandler {
+	pulled: Arc<AtomicUsize>,
+	max_ahead: AtomicUsize,
+}
+
+#[async_trait]
+impl StreamingEndpointHandler<BulkMessage> for SlowConsumerHandler {
+	async fn handle(
+		self: &Arc<Self>,
+		mut req: Req<BulkMessage>,
+		_from: NodeID,
+	) -> Resp<BulkMessage> {
+		let mut stream = req.take_stream().unwrap();
+		let mut consumed = 0;
+		while let Some(packet) = stream.next().await {
+			consumed += packet.unwrap().len();
+			let ahead = self.pulled.load(Ordering::SeqCst).saturating_sub(consumed);
+			self.max_ahead.fetch_max(ahead, Ordering::SeqCst);
+			tokio::time::sleep(Duration::from_millis(1)).await;
+		}
+		Resp::new(BulkMessage(consumed as u64))
+	}
+}
+
+/// Handler that counts the requests it receives
+struct CountingHandler(AtomicUsize);
+
+#[async_trait]
+impl EndpointHandler<EchoMessage> for CountingHandler {
+	async fn handle(self: &Arc<Self>, msg: &EchoMessage, _from: NodeID) -> EchoMessage {
+		self.0.fetch_add(1, Ordering::SeqCst);
+		EchoMessage(msg.0)
+	}
+}
+
 #[tokio::test(flavor = "current_thread")]
 async fn test_with_basic_scheduler() {
 	pretty_env_logger::init();
@@ -43,12 +117,12 @@ async fn run_test_inner(port_base: u16) {
 
 	let (stop_tx, stop_rx) = watch::channel(false);
 
-	let (thread1, _netapp1, peering1) =
+	let (thread1, netapp1, peering1) =
 		run_netapp(netid.clone(), pk1, sk1, addr1, vec![], stop_rx.clone());
 	tokio::time::sleep(Duration::from_secs(2)).await;
 
 	// Connect second node and check it peers with everyone
-	let (thread2, _netapp2, peering2) = run_netapp(
+	let (thread2, netapp2, peering2) = run_netapp(
 		netid.clone(),
 		pk2,
 		sk2,
@@ -83,6 +157,40 @@ async fn run_test_inner(port_base: u16) {
 	println!("B pl3: {:?}", pl3);
 	assert_eq!(pl3.len(), 3);
 
+	// Force a reconnection from the first node to the second node,
+	// and check that RPCs still work afterwards
+	let echo1 = netapp1.endpoint::<EchoMessage, EchoHandler>("test/Echo".into());
+	let echo2 = netapp2.endpoint::<EchoMessage, EchoHandler>("test/Echo".into());
+	echo2.set_handler(Arc::new(EchoHandler));
+	assert_eq!(
+		echo1
+			.call(&pk2, EchoMessage(1), PRIO_NORMAL)
+			.await
+			.unwrap()
+			.0,
+		1
+	);
+
+	let info = peering1
+		.reconnect(&pk2, Duration::from_secs(10))
+		.await
+		.unwrap();
+	println!("C reconnect: {:?}", info);
+	assert!(info.is_up());
+	assert!(peering1
+		.reconnect(&pk1, Duration::from_secs(10))
+		.await
+		.is_err());
+
+	assert_eq!(
+		echo1
+			.call(&pk2, EchoMessage(2), PRIO_NORMAL)
+			.await
+			.unwrap()
+			.0,
+		2
+	);
+
 	// Send stop signal and wait for everyone to finish
 	stop_tx.send(true).unwrap();
 	thread1.await.unwrap();
@@ -90,6 +198,334 @@ async fn run_test_inner(port_base: u16) {
 	thread3.await.unwrap();
 }
 
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_peer_rediscovery() {
+	select! {
+		_ = test_peer_rediscovery_inner(19970) => (),
+		_ = tokio::time::sleep(Duration::from_secs(20)) => panic!("timeout"),
+	}
+}
+
+async fn test_peer_rediscovery_inner(port_base: u16) {
+	let netid = auth::gen_key();
+
+	let (pk1, sk1) = ed25519::gen_keypair();
+	let (pk2, sk2) = ed25519::gen_keypair();
+
+	let addr1: SocketAddr = SocketAddr::new("127.0.0.1".parse().unwrap(), port_base);
+	let addr2: SocketAddr = SocketAddr::new("127.0.0.1".parse().unwrap(), port_base + 1);
+	// Address at which the first node was previously reachable,
+	// where nothing listens anymore
+	let stale_addr1: SocketAddr = SocketAddr::new("127.0.0.1".parse().unwrap(), port_base + 2);
+
+	let (stop_tx, stop_rx) = watch::channel(false);
+
+	let (thread1, _netapp1, _peering1) =
+		run_netapp(netid.clone(), pk1, sk1, addr1, vec![], stop_rx.clone());
+
+	let (thread2, _netapp2, peering2) = run_netapp(
+		netid,
+		pk2,
+		sk2,
+		addr2,
+		vec![(pk1, stale_addr1)],
+		stop_rx.clone(),
+	);
+	peering2.set_address_resolver(
+		1,
+		Arc::new(move |id| {
+			async move {
+				if id == pk1 {
+					vec![addr1]
+				} else {
+					vec![]
+				}
+			}
+			.boxed()
+		}),
+	);
+
+	// The second node fails to connect to the first one at its stale
+	// address, finds its new address and connects to it
+	loop {
+		let up = peering2
+			.get_peer_list()
+			.iter()
+			.any(|p| p.id == pk1 && p.is_up());
+		if up {
+			break;
+		}
+		tokio::time::sleep(Duration::from_millis(100)).await;
+	}
+	assert_eq!(peering2.address_refreshes(), 1);
+
+	stop_tx.send(true).unwrap();
+	thread1.await.unwrap();
+	thread2.await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_flow_control() {
+	select! {
+		_ = test_flow_control_inner(19960) => (),
+		_ = tokio::time::sleep(Duration::from_secs(20)) => panic!("timeout"),
+	}
+}
+
+async fn test_flow_control_inner(port_base: u16) {
+	const CHUNKS: usize = 512;
+
+	let netid = auth::gen_key();
+
+	let (pk1, sk1) = ed25519::gen_keypair();
+	let (pk2, sk2) = ed25519::gen_keypair();
+
+	let addr1: SocketAddr = SocketAddr::new("127.0.0.1".parse().unwrap(), port_base);
+	let addr2: SocketAddr = SocketAddr::new("127.0.0.1".parse().unwrap(), port_base + 1);
+
+	let (stop_tx, stop_rx) = watch::channel(false);
+
+	let (thread1, netapp1, peering1) =
+		run_netapp(netid.clone(), pk1, sk1, addr1, vec![], stop_rx.clone());
+	let (thread2, netapp2, _peering2) =
+		run_netapp(netid, pk2, sk2, addr2, vec![(pk1, addr1)], stop_rx.clone());
+
+	while !peering1
+		.get_peer_list()
+		.iter()
+		.any(|p| p.id == pk2 && p.is_up())
+	{
+		tokio::time::sleep(Duration::from_millis(100)).await;
+	}
+
+	let pulled = Arc::new(AtomicUsize::new(0));
+	let handler = Arc::new(SlowConsumerHandler {
+		pulled: pulled.clone(),
+		max_ahead: AtomicUsize::new(0),
+	});
+	let bulk1 = netapp1.endpoint::<BulkMessage, SlowConsumerHandler>("test/Bulk".into());
+	let bulk2 = netapp2.endpoint::<BulkMessage, SlowConsumerHandler>("test/Bulk".into());
+	bulk2.set_handler(handler.clone());
+
+	// The stream counts how much data the sender has read from it
+	let chunk = Bytes::from(vec![0u8; MAX_CHUNK_LENGTH as usize]);
+	let stream = futures::stream::iter(0..CHUNKS).map(move |_| {
+		pulled.fetch_add(chunk.len(), Ordering::SeqCst);
+		Ok(chunk.clone())
+	});
+	let req = Req::new(BulkMessage(0))
+		.unwrap()
+		.with_stream(Box::pin(stream));
+
+	let resp = bulk1.call_streaming(&pk2, req, PRIO_NORMAL).await.unwrap();
+	assert_eq!(resp.msg().0, (CHUNKS * MAX_CHUNK_LENGTH as usize) as u64);
+
+	assert!(netapp1.flow_control_stats()[&pk2].enabled);
+
+	// The sender never got more than a window ahead of the receiver
+	// (with some slack for the chunks buffered in the stream readers)
+	let max_ahead = handler.max_ahead.load(Ordering::SeqCst);
+	println!("max ahead: {} bytes", max_ahead);
+	assert!(max_ahead <= (STREAM_WINDOW_CHUNKS as usize + 8) * MAX_CHUNK_LENGTH as usize);
+
+	stop_tx.send(true).unwrap();
+	thread1.await.unwrap();
+	thread2.await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_frame_checksums() {
+	select! {
+		_ = test_frame_checksums_inner(19950) => (),
+		_ = tokio::time::sleep(Duration::from_secs(20)) => panic!("timeout"),
+	}
+}
+
+async fn test_frame_checksums_inner(port_base: u16) {
+	let netid = auth::gen_key();
+
+	let (pk1, sk1) = ed25519::gen_keypair();
+	let (pk2, sk2) = ed25519::gen_keypair();
+
+	let addr1: SocketAddr = SocketAddr::new("127.0.0.1".parse().unwrap(), port_base);
+	let addr2: SocketAddr = SocketAddr::new("127.0.0.1".parse().unwrap(), port_base + 1);
+
+	let (stop_tx, stop_rx) = watch::channel(false);
+
+	// Checksums must be enabled before the nodes connect
+	let (thread1, netapp1, _peering1) =
+		run_netapp(netid.clone(), pk1, sk1, addr1, vec![], stop_rx.clone());
+	netapp1.set_frame_checksums(true);
+	let (thread2, netapp2, _peering2) =
+		run_netapp(netid, pk2, sk2, addr2, vec![(pk1, addr1)], stop_rx.clone());
+	netapp2.set_frame_checksums(true);
+
+	// Both ends of the connection start sending checksums
+	// once they know that the other end can verify them
+	loop {
+		let client = netapp2
+			.client_conns
+			.read()
+			.unwrap()
+			.get(&pk1)
+			.map(|c| c.frame_checksums.stats());
+		let server = netapp1
+			.server_conns
+			.read()
+			.unwrap()
+			.get(&pk2)
+			.map(|c| c.frame_checksums.stats());
+		let ready = |s: FrameChecksumStats| s.sending && s.receiving;
+		if client.map(ready) == Some(true) && server.map(ready) == Some(true) {
+			break;
+		}
+		tokio::time::sleep(Duration::from_millis(100)).await;
+	}
+
+	let handler = Arc::new(CountingHandler(AtomicUsize::new(0)));
+	let echo1 = netapp1.endpoint::<EchoMessage, CountingHandler>("test/Echo".into());
+	let echo2 = netapp2.endpoint::<EchoMessage, CountingHandler>("test/Echo".into());
+	echo1.set_handler(handler.clone());
+
+	assert_eq!(
+		echo2
+			.call(&pk1, EchoMessage(1), PRIO_NORMAL)
+			.await
+			.unwrap()
+			.0,
+		1
+	);
+	assert_eq!(handler.0.load(Ordering::SeqCst), 1);
+	assert_eq!(netapp1.frame_checksum_stats()[&pk2].failures, 0);
+	assert_eq!(netapp2.frame_checksum_stats()[&pk1].failures, 0);
+
+	stop_tx.send(true).unwrap();
+	thread1.await.unwrap();
+	thread2.await.unwrap();
+}
+
+/// Writer that flips a bit of the next chunk of data written through it
+/// once it is armed. Chunk headers, credits and checksums are at most
+/// 4 bytes long, so they are recognized by their length.
+struct CorruptingWriter<W> {
+	inner: W,
+	corrupt_next: Arc<AtomicBool>,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for CorruptingWriter<W> {
+	fn poll_write(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<std::io::Result<usize>> {
+		if buf.len() > 4 && self.corrupt_next.swap(false, Ordering::SeqCst) {
+			let mut corrupted = buf.to_vec();
+			corrupted[0] ^= 1;
+			let res = Pin::new(&mut self.inner).poll_write(cx, &corrupted);
+			if res.is_pending() {
+				self.corrupt_next.store(true, Ordering::SeqCst);
+			}
+			return res;
+		}
+		Pin::new(&mut self.inner).poll_write(cx, buf)
+	}
+
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+		Pin::new(&mut self.inner).poll_flush(cx)
+	}
+
+	fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+		Pin::new(&mut self.inner).poll_close(cx)
+	}
+}
+
+/// One end of a connection, whose send loop and receive loop are run
+/// directly on an in-memory pipe
+struct LoopConn {
+	flow_control: FlowControl,
+	frame_checksums: FrameChecksums,
+	received: mpsc::UnboundedSender<ByteStream>,
+}
+
+impl SendLoop for LoopConn {
+	fn flow_control(&self) -> &FlowControl {
+		&self.flow_control
+	}
+	fn frame_checksums(&self) -> &FrameChecksums {
+		&self.frame_checksums
+	}
+}
+
+impl RecvLoop for LoopConn {
+	fn recv_handler(self: &Arc<Self>, _id: RequestID, stream: ByteStream) {
+		let _ = self.received.send(stream);
+	}
+	fn flow_control(&self) -> &FlowControl {
+		&self.flow_control
+	}
+	fn frame_checksums(&self) -> &FrameChecksums {
+		&self.frame_checksums
+	}
+	fn send_channel(&self) -> Option<Arc<mpsc::UnboundedSender<SendItem>>> {
+		None
+	}
+}
+
+#[tokio::test]
+async fn test_frame_checksum_mismatch() {
+	let (received_send, mut received) = mpsc::unbounded_channel();
+	let conn = Arc::new(LoopConn {
+		flow_control: FlowControl::default(),
+		frame_checksums: FrameChecksums::new(true),
+		received: received_send,
+	});
+
+	let (write, read) = tokio::io::duplex(1 << 16);
+	let corrupt_next = Arc::new(AtomicBool::new(false));
+	let write = CorruptingWriter {
+		inner: write.compat_write(),
+		corrupt_next: corrupt_next.clone(),
+	};
+	let (send, send_recv) = mpsc::unbounded_channel();
+	let send_loop = tokio::spawn(conn.clone().send_loop(send_recv, write, "send".into()));
+	let recv_loop = tokio::spawn(conn.clone().recv_loop(read.compat(), "recv".into()));
+
+	let send_stream = |id: RequestID, corrupt: bool| {
+		corrupt_next.store(corrupt, Ordering::SeqCst);
+		let data = Bytes::from(vec![id as u8; 100]);
+		let stream: ByteStream = Box::pin(futures::stream::once(async move { Ok(data) }));
+		send.send(SendItem::Stream(id, PRIO_NORMAL, None, stream))
+			.unwrap();
+	};
+
+	send.send(SendItem::StartChecksums).unwrap();
+
+	send_stream(1, false);
+	let mut stream = received.recv().await.unwrap();
+	assert_eq!(stream.next().await.unwrap().unwrap(), vec![1u8; 100]);
+	assert!(conn.frame_checksums.stats().receiving);
+	assert_eq!(conn.frame_checksums.stats().failures, 0);
+
+	// A corrupted chunk ends its stream with an error
+	send_stream(2, true);
+	let mut stream = received.recv().await.unwrap();
+	let err = stream.next().await.unwrap().unwrap_err();
+	assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+	assert_eq!(err.to_string(), FRAME_CHECKSUM_MISMATCH);
+	assert!(stream.next().await.is_none());
+	assert_eq!(conn.frame_checksums.stats().failures, 1);
+
+	// The following streams are received normally
+	send_stream(3, false);
+	let mut stream = received.recv().await.unwrap();
+	assert_eq!(stream.next().await.unwrap().unwrap(), vec![3u8; 100]);
+	assert_eq!(conn.frame_checksums.stats().failures, 1);
+
+	drop(send);
+	send_loop.await.unwrap().unwrap();
+	recv_loop.await.unwrap().unwrap();
+}
+
 fn run_netapp(
 	netid: auth::Key,
 	_pk: NodeID,
@@ -37,6 +37,7 @@ pub struct LayoutHelper {
 	sync_map_min: u64,
 
 	all_nodes: Vec<Uuid>,
+	all_voting_nodes: Vec<Uuid>,
 	all_nongateway_nodes: Vec<Uuid>,
 
 	trackers_hash: Hash,
@@ -71,6 +72,7 @@ impl LayoutHelper {
 		layout.cleanup_old_versions();
 
 		let all_nodes = layout.get_all_nodes();
+		let all_voting_nodes = layout.get_all_voting_nodes();
 		let all_nongateway_nodes = layout.get_all_nongateway_nodes();
 
 		layout.clamp_update_trackers(&all_nodes);
@@ -78,14 +80,18 @@ impl LayoutHelper {
 		let min_version = layout.min_stored();
 
 		// ack_map_min is the minimum value of ack_map among all nodes
-		// in the cluster (gateway, non-gateway, current and previous layouts).
+		// in the cluster (gateway, non-gateway, current and previous layouts),
+		// except gateways that have been marked as non-voting.
 		// It is the highest layout version which all of these nodes have
 		// acknowledged, indicating that they are aware of it and are no
 		// longer processing write operations that did not take it into account.
+		// Non-voting gateways are excluded so that a gateway that is down
+		// does not block the cluster's progress to new layout versions,
+		// at the cost of not waiting for in-flight writes on these nodes.
 		let ack_map_min = layout
 			.update_trackers
 			.ack_map
-			.min_among(&all_nodes, min_version);
+			.min_among(&all_voting_nodes, min_version);
 
 		// sync_map_min is the minimum value of sync_map among storage nodes
 		// in the cluster (non-gateway nodes only, current and previous layouts).
@@ -117,6 +123,7 @@ impl LayoutHelper {
 			ack_map_min,
 			sync_map_min,
 			all_nodes,
+			all_voting_nodes,
 			all_nongateway_nodes,
 			trackers_hash,
 			staging_hash,
@@ -167,12 +174,27 @@ impl LayoutHelper {
 		&self.all_nodes
 	}
 
+	/// Return all nodes that have a role in one of the currently active
+	/// layout versions, except non-voting gateway nodes
+	pub fn all_voting_nodes(&self) -> &[Uuid] {
+		&self.all_voting_nodes
+	}
+
 	/// Return all nodes that are configured to store data
 	/// in one of the currently active layout versions
 	pub fn all_nongateway_nodes(&self) -> &[Uuid] {
 		&self.all_nongateway_nodes
 	}
 
+	/// Return the zone of a node in the most recent of the currently
+	/// active layout versions in which it has a role
+	pub fn node_zone(&self, node: &Uuid) -> Option<&str> {
+		self.versions()
+			.iter()
+			.rev()
+			.find_map(|ver| ver.get_node_zone(node))
+	}
+
 	pub fn ack_map_min(&self) -> u64 {
 		self.ack_map_min
 	}
@@ -219,6 +241,28 @@ impl LayoutHelper {
 		ret
 	}
 
+	/// Same as `storage_sets_of`, but only keeping the first `n` nodes of each set,
+	/// for data that is stored with less copies than the cluster's replication factor
+	pub fn storage_sets_of_with_factor(&self, position: &Hash, n: usize) -> Vec<Vec<Uuid>> {
+		self.versions()
+			.iter()
+			.map(|x| x.nodes_of(position, x.replication_factor).take(n).collect())
+			.collect()
+	}
+
+	/// Same as `storage_nodes_of`, but only keeping the first `n` nodes of each
+	/// layout version
+	pub fn storage_nodes_of_with_factor(&self, position: &Hash, n: usize) -> Vec<Uuid> {
+		let mut ret = self
+			.storage_sets_of_with_factor(position, n)
+			.into_iter()
+			.flatten()
+			.collect::<Vec<_>>();
+		ret.sort();
+		ret.dedup();
+		ret
+	}
+
 	pub fn trackers_hash(&self) -> Hash {
 		self.trackers_hash
 	}
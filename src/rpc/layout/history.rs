@@ -67,6 +67,18 @@ impl LayoutHistory {
 		}
 	}
 
+	/// Calculate the set of all nodes that have a role in one of the currently
+	/// active layout versions, excluding nodes that are non-voting gateways
+	/// in all of these versions
+	pub(crate) fn get_all_voting_nodes(&self) -> Vec<Uuid> {
+		let set = self
+			.versions
+			.iter()
+			.flat_map(|x| x.voting_nodes())
+			.collect::<HashSet<_>>();
+		set.into_iter().copied().collect::<Vec<_>>()
+	}
+
 	// ---- housekeeping (all invoked by LayoutHelper) ----
 
 	pub(crate) fn keep_current_version_only(&mut self) {
@@ -92,12 +104,13 @@ impl LayoutHistory {
 		// remove them (keep them in self.old_versions).
 		// ASSUMPTION: we only care about where nodes in the current layout version
 		// are reading from, as we assume older nodes are being discarded.
-		let current_nodes = &self.current().node_id_vec;
+		// Non-voting gateways are not waited for.
+		let current_nodes = self.current().voting_nodes().copied().collect::<Vec<_>>();
 		let min_version = self.min_stored();
 		let sync_ack_map_min = self
 			.update_trackers
 			.sync_ack_map
-			.min_among(current_nodes, min_version);
+			.min_among(&current_nodes, min_version);
 		while self.min_stored() < sync_ack_map_min {
 			assert!(self.versions.len() > 1);
 			let removed = self.versions.remove(0);
@@ -262,21 +275,28 @@ impl LayoutHistory {
 		changed
 	}
 
-	pub fn apply_staged_changes(mut self, version: Option<u64>) -> Result<(Self, Message), Error> {
+	fn check_new_version_number(&self, version: Option<u64>) -> Result<(), Error> {
 		match version {
 			None => {
 				let error = r#"
 Please pass the new layout version number to ensure that you are writing the correct version of the cluster layout.
 To know the correct value of the new layout version, invoke `garage layout show` and review the proposed changes.
 				"#;
-				return Err(Error::Message(error.into()));
+				Err(Error::Message(error.into()))
 			}
 			Some(v) => {
 				if v != self.current().version + 1 {
 					return Err(Error::Message("Invalid new layout version".into()));
 				}
+				Ok(())
 			}
 		}
+	}
+
+	/// Applies the staged changes. If the current version is a canary, this
+	/// moves the partitions that were not moved by the canary.
+	pub fn apply_staged_changes(mut self, version: Option<u64>) -> Result<(Self, Message), Error> {
+		self.check_new_version_number(version)?;
 
 		// Compute new version and add it to history
 		let (new_version, msg) = self
@@ -296,7 +316,68 @@ To know the correct value of the new layout version, invoke `garage layout show`
 		Ok((self, msg))
 	}
 
+	/// Applies the staged changes as a canary: only `percent`% of the
+	/// partitions whose assignment changes are moved in the new version.
+	/// The staged changes are kept, to be applied to the other partitions
+	/// by a subsequent call to apply_staged_changes.
+	pub fn apply_staged_changes_canary(
+		mut self,
+		version: Option<u64>,
+		percent: u8,
+	) -> Result<(Self, Message), Error> {
+		self.check_new_version_number(version)?;
+
+		if !(1..=100).contains(&percent) {
+			return Err(Error::Message(
+				"The canary percentage must be between 1 and 100".into(),
+			));
+		}
+		if self.current().canary.is_some() {
+			return Err(Error::Message("A canary layout change is already in progress, continue it with `garage layout apply --continue` or revert it with `garage layout revert`".into()));
+		}
+
+		let (target, mut msg) = self
+			.current()
+			.clone()
+			.calculate_next_version(self.staging.get())?;
+		let new_version = self.current().calculate_canary_version(&target, percent)?;
+
+		let canary = new_version.canary.as_ref().unwrap();
+		msg.push(format!(
+			"Canary: moving {} of the {} partitions whose assignment changes ({}%).",
+			canary.partitions.len(),
+			canary.changed_partitions,
+			percent
+		));
+
+		self.versions.push(new_version);
+		self.cleanup_old_versions();
+
+		Ok((self, msg))
+	}
+
+	/// Reverts the staged changes. If the current version is a canary, this
+	/// also adds a new version that restores the assignment of the version
+	/// that preceded the canary.
 	pub fn revert_staged_changes(mut self) -> Result<Self, Error> {
+		if self.current().canary.is_some() {
+			let current = self.current().version;
+			let mut previous = self
+				.versions
+				.iter()
+				.chain(self.old_versions.iter())
+				.find(|v| v.version + 1 == current)
+				.cloned()
+				.ok_or_message(format!(
+					"Layout version {}, which preceded the canary, is not available anymore",
+					current - 1
+				))?;
+			previous.version = current + 1;
+
+			self.versions.push(previous);
+			self.cleanup_old_versions();
+		}
+
 		self.staging.update(LayoutStaging {
 			parameters: Lww::new(self.current().parameters),
 			roles: LwwMap::new(),
@@ -1,6 +1,5 @@
 use std::collections::HashMap;
 use std::sync::{atomic::Ordering, Arc, Mutex, RwLock, RwLockReadGuard};
-use std::time::Duration;
 
 use tokio::sync::Notify;
 
@@ -79,7 +78,9 @@ impl LayoutManager {
 			node_id.into(),
 			peering,
 			layout.clone(),
-			config.rpc_timeout_msec.map(Duration::from_millis),
+			RpcTimeouts::new(config.rpc_timeout_msec, &config.rpc_timeouts),
+			config.no_quorum_behavior,
+			config.rpc.prefer_local_zone.unwrap_or(true),
 		);
 
 		Ok(Arc::new(Self {
@@ -147,6 +148,27 @@ impl LayoutManager {
 		let layout = self.layout();
 		let version = layout.current().version;
 		let nodes = layout.storage_sets_of(position);
+		self.lock_write_sets(&layout, version, nodes)
+	}
+
+	/// Same as `write_sets_of`, for data stored with only `n` copies
+	pub fn write_sets_of_with_factor(
+		self: &Arc<Self>,
+		position: &Hash,
+		n: usize,
+	) -> WriteLock<Vec<Vec<Uuid>>> {
+		let layout = self.layout();
+		let version = layout.current().version;
+		let nodes = layout.storage_sets_of_with_factor(position, n);
+		self.lock_write_sets(&layout, version, nodes)
+	}
+
+	fn lock_write_sets(
+		self: &Arc<Self>,
+		layout: &LayoutHelper,
+		version: u64,
+		nodes: Vec<Vec<Uuid>>,
+	) -> WriteLock<Vec<Vec<Uuid>>> {
 		layout
 			.ack_lock
 			.get(&version)
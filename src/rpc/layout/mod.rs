@@ -8,6 +8,7 @@ use garage_util::data::Uuid;
 mod graph_algo;
 mod helper;
 mod history;
+mod plan;
 mod version;
 
 #[cfg(test)]
@@ -19,6 +20,7 @@ pub mod manager;
 
 pub use helper::{LayoutHelper, RpcLayoutDigest, SyncLayoutDigest};
 pub use manager::WriteLock;
+pub use plan::{AssignmentScore, LayoutPlan, NodePlan, ZoneScore};
 pub use version::*;
 
 // ---- defines: partitions ----
@@ -91,6 +93,12 @@ mod v08 {
 		pub capacity: Option<u64>,
 		/// A set of tags to recognize the node
 		pub tags: Vec<String>,
+		/// If set on a gateway node, the node still receives all updates to
+		/// tables replicated on all nodes, but it is not counted in their write
+		/// quorums and it does not hold back the cluster's progress to new
+		/// layout versions
+		#[serde(default, skip_serializing_if = "std::ops::Not::not")]
+		pub non_voting: bool,
 	}
 
 	impl garage_util::migrate::InitialFormat for ClusterLayout {}
@@ -224,7 +232,7 @@ mod v09 {
 
 mod v010 {
 	use super::v09;
-	use crate::layout::CompactNodeType;
+	use crate::layout::{CompactNodeType, Partition};
 	use garage_util::crdt::{Lww, LwwMap};
 	use garage_util::data::Uuid;
 	use serde::{Deserialize, Serialize};
@@ -284,6 +292,26 @@ mod v010 {
 		/// are indices in node_id_vec
 		#[serde(with = "serde_bytes")]
 		pub ring_assignment_data: Vec<CompactNodeType>,
+
+		/// If this version was applied as a canary, the partitions that
+		/// were moved to their new assignment (the other partitions kept
+		/// the assignment of the previous version)
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		pub canary: Option<LayoutCanary>,
+	}
+
+	/// Information about a layout version applied as a canary, i.e. where
+	/// only a subset of the partitions whose assignment was changed by the
+	/// staged changes were moved
+	#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+	pub struct LayoutCanary {
+		/// Percentage of the changed partitions that were moved
+		pub percent: u8,
+		/// Number of partitions whose assignment is changed by the
+		/// staged changes
+		pub changed_partitions: usize,
+		/// Partitions that were moved to their new assignment
+		pub partitions: Vec<Partition>,
 	}
 
 	/// The staged changes for the next layout version
@@ -338,6 +366,7 @@ mod v010 {
 				node_id_vec: previous.node_id_vec,
 				nongateway_node_count,
 				ring_assignment_data: previous.ring_assignment_data,
+				canary: None,
 			};
 			let update_tracker = UpdateTracker(
 				version
@@ -385,9 +414,16 @@ impl Crdt for LayoutStaging {
 }
 
 impl NodeRole {
+	/// Whether this node is counted in quorums of tables replicated on all
+	/// nodes (only gateway nodes can be non-voting)
+	pub fn is_voting(&self) -> bool {
+		self.capacity.is_some() || !self.non_voting
+	}
+
 	pub fn capacity_string(&self) -> String {
 		match self.capacity {
 			Some(c) => ByteSize::b(c).to_string_as(false),
+			None if self.non_voting => "gateway (non-voting)".to_string(),
 			None => "gateway".to_string(),
 		}
 	}
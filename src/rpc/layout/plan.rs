@@ -0,0 +1,295 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use garage_util::data::*;
+use garage_util::error::*;
+
+use super::*;
+
+/// Report of the changes that applying the staged layout changes would make,
+/// computed without applying them
+#[derive(Clone, Debug)]
+pub struct LayoutPlan {
+	/// Version number of the layout that would be created
+	pub new_version: u64,
+	/// Partition size, in bytes, before and after applying the changes
+	pub partition_size_before: u64,
+	pub partition_size_after: u64,
+	/// Changes for each node that has a role in the current or in the new layout
+	pub nodes: Vec<NodePlan>,
+	/// Number of partitions that have at least one replica on a different node
+	pub partitions_moved: usize,
+	/// Warnings about the fault tolerance of the new layout
+	pub warnings: Vec<String>,
+	/// Breakdown of the quality of the new assignment
+	pub score: AssignmentScore,
+	/// Output of the assignment algorithm
+	pub message: Message,
+}
+
+/// Breakdown of the quality of a partition assignment, to explain
+/// the result of the assignment algorithm
+#[derive(Clone, Debug, PartialEq)]
+pub struct AssignmentScore {
+	/// Capacity used by the assignment (partition size times the number
+	/// of partition copies) and total capacity of the storage nodes, in bytes
+	pub usable_capacity: u64,
+	pub total_capacity: u64,
+	/// Number of partition copies stored on a node that did not store
+	/// them in the previous version
+	pub moved_copies: usize,
+	/// Usage of each zone, in the alphabetical order of zone names
+	pub zones: Vec<ZoneScore>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ZoneScore {
+	pub zone: String,
+	/// Number of partition copies stored in the zone
+	pub partitions: usize,
+	/// Number of partitions with at least one copy in the zone
+	pub unique_partitions: usize,
+	/// Total capacity of the nodes of the zone, in bytes
+	pub capacity: u64,
+	/// Data stored in the zone, in bytes
+	pub usage: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct NodePlan {
+	pub id: Uuid,
+	pub zone_before: Option<String>,
+	pub zone_after: Option<String>,
+	pub capacity_before: Option<u64>,
+	pub capacity_after: Option<u64>,
+	/// Number of partitions stored on the node before and after the changes
+	pub partitions_before: usize,
+	pub partitions_after: usize,
+	/// Number of partitions the node starts storing
+	pub partitions_gained: usize,
+	/// Number of partitions the node stops storing
+	pub partitions_lost: usize,
+	/// Data stored on the node before and after the changes, in bytes
+	pub usage_before: u64,
+	pub usage_after: u64,
+}
+
+impl LayoutHistory {
+	/// Compute the new layout version that `apply_staged_changes` would create,
+	/// and report the differences with the current version
+	pub fn plan_staged_changes(&self) -> Result<LayoutPlan, Error> {
+		let current = self.current();
+		let (next, message) = current.clone().calculate_next_version(self.staging.get())?;
+		Ok(LayoutPlan::compare(current, &next, message))
+	}
+}
+
+impl LayoutPlan {
+	pub(crate) fn compare(before: &LayoutVersion, after: &LayoutVersion, message: Message) -> Self {
+		let assignment_before = assignment(before);
+		let assignment_after = assignment(after);
+		let parts_before = partitions_by_node(&assignment_before);
+		let parts_after = partitions_by_node(&assignment_after);
+
+		let mut node_ids = before.nongateway_nodes().to_vec();
+		for id in after.nongateway_nodes() {
+			if !node_ids.contains(id) {
+				node_ids.push(*id);
+			}
+		}
+
+		let empty = HashSet::new();
+		let nodes = node_ids
+			.into_iter()
+			.map(|id| {
+				let pb = parts_before.get(&id).unwrap_or(&empty);
+				let pa = parts_after.get(&id).unwrap_or(&empty);
+				NodePlan {
+					id,
+					zone_before: before.get_node_zone(&id).map(String::from),
+					zone_after: after.get_node_zone(&id).map(String::from),
+					capacity_before: before.get_node_capacity(&id),
+					capacity_after: after.get_node_capacity(&id),
+					partitions_before: pb.len(),
+					partitions_after: pa.len(),
+					partitions_gained: pa.difference(pb).count(),
+					partitions_lost: pb.difference(pa).count(),
+					usage_before: pb.len() as u64 * before.partition_size,
+					usage_after: pa.len() as u64 * after.partition_size,
+				}
+			})
+			.collect::<Vec<_>>();
+
+		let partitions_moved = (0..NB_PARTITIONS)
+			.filter(|i| {
+				let nb = assignment_before.get(*i).cloned().unwrap_or_default();
+				let na = assignment_after.get(*i).cloned().unwrap_or_default();
+				nb != na
+			})
+			.count();
+
+		LayoutPlan {
+			new_version: after.version,
+			partition_size_before: before.partition_size,
+			partition_size_after: after.partition_size,
+			nodes,
+			partitions_moved,
+			warnings: single_zone_warnings(after, &assignment_after),
+			score: AssignmentScore::compute(before, after),
+			message,
+		}
+	}
+}
+
+impl AssignmentScore {
+	pub(crate) fn compute(before: &LayoutVersion, after: &LayoutVersion) -> Self {
+		let assignment_before = assignment(before);
+		let assignment_after = assignment(after);
+
+		let moved_copies = assignment_after
+			.iter()
+			.enumerate()
+			.map(|(p, nodes)| {
+				let prev = assignment_before.get(p);
+				nodes
+					.iter()
+					.filter(|n| !prev.map(|x| x.contains(n)).unwrap_or(false))
+					.count()
+			})
+			.sum();
+
+		let mut zones = BTreeMap::<&str, ZoneScore>::new();
+		for node in after.nongateway_nodes() {
+			let zone = after.expect_get_node_zone(node);
+			let z = zones.entry(zone).or_insert_with(|| ZoneScore {
+				zone: zone.to_string(),
+				partitions: 0,
+				unique_partitions: 0,
+				capacity: 0,
+				usage: 0,
+			});
+			z.capacity += after.expect_get_node_capacity(node);
+		}
+		for nodes in assignment_after.iter() {
+			let partition_zones = nodes
+				.iter()
+				.map(|n| after.expect_get_node_zone(n))
+				.collect::<Vec<_>>();
+			for zone in partition_zones.iter() {
+				if let Some(z) = zones.get_mut(zone) {
+					z.partitions += 1;
+					z.usage += after.partition_size;
+				}
+			}
+			for zone in partition_zones.iter().collect::<HashSet<_>>() {
+				if let Some(z) = zones.get_mut(zone) {
+					z.unique_partitions += 1;
+				}
+			}
+		}
+
+		let zones = zones.into_values().collect::<Vec<_>>();
+		AssignmentScore {
+			usable_capacity: zones.iter().map(|z| z.usage).sum(),
+			total_capacity: zones.iter().map(|z| z.capacity).sum(),
+			moved_copies,
+			zones,
+		}
+	}
+
+	pub fn message(&self) -> Message {
+		let percent = |used: u64, total: u64| {
+			if total == 0 {
+				0.0
+			} else {
+				100.0 * used as f32 / total as f32
+			}
+		};
+		vec![
+			"==== ASSIGNMENT SCORE ====".into(),
+			format!("Partition copies moved:  {}", self.moved_copies),
+			format!(
+				"Capacity usage:          {:.1} %",
+				percent(self.usable_capacity, self.total_capacity)
+			),
+			format!(
+				"Zone balance:            {}",
+				self.zones
+					.iter()
+					.map(|z| format!("{} {:.1} %", z.zone, percent(z.usage, z.capacity)))
+					.collect::<Vec<_>>()
+					.join(", ")
+			),
+			"".into(),
+		]
+	}
+}
+
+/// Sorted list of the nodes storing each partition, or an empty list
+/// if no assignment has been computed for this layout version
+fn assignment(version: &LayoutVersion) -> Vec<Vec<Uuid>> {
+	let rf = version.replication_factor;
+	if version.ring_assignment_data.len() != rf * NB_PARTITIONS {
+		return vec![];
+	}
+	version
+		.ring_assignment_data
+		.chunks(rf)
+		.map(|nodes| {
+			let mut nodes = nodes
+				.iter()
+				.map(|i| version.node_id_vec[*i as usize])
+				.collect::<Vec<_>>();
+			nodes.sort();
+			nodes
+		})
+		.collect()
+}
+
+fn partitions_by_node(assignment: &[Vec<Uuid>]) -> HashMap<Uuid, HashSet<usize>> {
+	let mut ret = HashMap::<Uuid, HashSet<usize>>::new();
+	for (partition, nodes) in assignment.iter().enumerate() {
+		for node in nodes.iter() {
+			ret.entry(*node).or_default().insert(partition);
+		}
+	}
+	ret
+}
+
+/// Warn about zones that hold all the replicas of some partitions, as the data of
+/// these partitions is then lost or unavailable if that zone goes down
+fn single_zone_warnings(version: &LayoutVersion, assignment: &[Vec<Uuid>]) -> Vec<String> {
+	let zones = version
+		.nongateway_nodes()
+		.iter()
+		.filter_map(|n| version.get_node_zone(n))
+		.collect::<HashSet<_>>();
+	if version.replication_factor < 2 || zones.len() < 2 {
+		return vec![];
+	}
+
+	let mut single_zone = BTreeMap::<&str, usize>::new();
+	for nodes in assignment.iter() {
+		let partition_zones = nodes
+			.iter()
+			.map(|n| version.expect_get_node_zone(n))
+			.collect::<HashSet<_>>();
+		if partition_zones.len() == 1 {
+			*single_zone
+				.entry(partition_zones.into_iter().next().unwrap())
+				.or_default() += 1;
+		}
+	}
+
+	single_zone
+		.into_iter()
+		.map(|(zone, n)| {
+			format!(
+				"WARNING: zone {} would hold all {} replicas of {} partitions out of {}: the data of these partitions would be unavailable if this zone goes down.",
+				zone,
+				version.replication_factor,
+				n,
+				NB_PARTITIONS,
+			)
+		})
+		.collect()
+}
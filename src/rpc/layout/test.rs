@@ -2,10 +2,11 @@ use std::cmp::min;
 use std::collections::HashMap;
 
 use garage_util::crdt::Crdt;
+use garage_util::data::Uuid;
 use garage_util::error::*;
 
 use crate::layout::*;
-use crate::replication_mode::ReplicationFactor;
+use crate::replication_mode::{ConsistencyMode, ReplicationFactor};
 
 // This function checks that the partition size S computed is at least better than the
 // one given by a very naive algorithm. To do so, we try to run the naive algorithm
@@ -107,6 +108,7 @@ fn update_layout(
 				zone: zone.to_string(),
 				capacity: Some(*capacity),
 				tags: (vec![]),
+				non_voting: false,
 			})),
 		);
 		staging.roles.merge(&update);
@@ -156,3 +158,343 @@ fn test_assignment() {
 	assert_eq!(cl.check(), Ok(()));
 	assert!(check_against_naive(cl.current()).unwrap());
 }
+
+#[test]
+fn test_non_voting_gateway() {
+	let mut cl = LayoutHistory::new(ReplicationFactor::new(3).unwrap());
+	update_layout(&mut cl, &[4000, 4000, 4000], &["A", "B", "C"], 3);
+
+	// Node 3 is a non-voting gateway, node 4 a regular gateway
+	let staging = cl.staging.get_mut();
+	for (i, non_voting) in [(3u8, true), (4u8, false)] {
+		let update = staging.roles.update_mutator(
+			[i; 32].into(),
+			NodeRoleV(Some(NodeRole {
+				zone: "A".to_string(),
+				capacity: None,
+				tags: vec![],
+				non_voting,
+			})),
+		);
+		staging.roles.merge(&update);
+	}
+	let v = cl.current().version;
+	let (cl, _) = cl.apply_staged_changes(Some(v + 1)).unwrap();
+	assert_eq!(cl.check(), Ok(()));
+
+	let current = cl.current();
+	assert_eq!(current.all_nodes().len(), 5);
+	let voting = current.voting_nodes().copied().collect::<Vec<Uuid>>();
+	assert_eq!(voting.len(), 4);
+	assert!(!voting.contains(&[3u8; 32].into()));
+	assert!(voting.contains(&[4u8; 32].into()));
+
+	// Add a new layout version, and have all nodes but the
+	// non-voting gateway acknowledge it
+	let mut cl = cl;
+	update_layout(&mut cl, &[4000, 4000, 8000], &["A", "B", "C"], 3);
+	let v = cl.current().version;
+	let (mut cl, _) = cl.apply_staged_changes(Some(v + 1)).unwrap();
+	let v = cl.current().version;
+	for i in [0u8, 1, 2, 4] {
+		cl.update_trackers.ack_map.set_max([i; 32].into(), v);
+	}
+
+	let helper = LayoutHelper::new(
+		ReplicationFactor::new(3).unwrap(),
+		ConsistencyMode::Consistent,
+		cl,
+		HashMap::new(),
+	);
+	assert_eq!(helper.versions().len(), 2);
+	assert_eq!(helper.all_nodes().len(), 5);
+	assert_eq!(helper.all_voting_nodes().len(), 4);
+	assert_eq!(helper.ack_map_min(), v);
+}
+
+#[test]
+fn test_storage_nodes_with_factor() {
+	let mut cl = LayoutHistory::new(ReplicationFactor::new(3).unwrap());
+	update_layout(
+		&mut cl,
+		&[4000, 4000, 4000, 4000, 4000],
+		&["A", "B", "C", "D", "E"],
+		3,
+	);
+	let v = cl.current().version;
+	let (cl, _) = cl.apply_staged_changes(Some(v + 1)).unwrap();
+	assert_eq!(cl.check(), Ok(()));
+
+	let helper = LayoutHelper::new(
+		ReplicationFactor::new(3).unwrap(),
+		ConsistencyMode::Consistent,
+		cl,
+		HashMap::new(),
+	);
+
+	// Data of two buckets, one with a replication factor of 1
+	// and one with a replication factor of 2
+	for i in 0..32u8 {
+		let hash = garage_util::data::blake2sum(&[i]);
+		let all = helper.storage_nodes_of(&hash);
+		assert_eq!(all.len(), 3);
+
+		let bucket_a = helper.storage_nodes_of_with_factor(&hash, 1);
+		assert_eq!(bucket_a.len(), 1);
+		let bucket_b = helper.storage_nodes_of_with_factor(&hash, 2);
+		assert_eq!(bucket_b.len(), 2);
+
+		// The nodes that store less copies are always a subset of the
+		// nodes that store all copies, and are the same for all nodes
+		assert!(bucket_a.iter().all(|n| bucket_b.contains(n)));
+		assert!(bucket_b.iter().all(|n| all.contains(n)));
+		assert_eq!(
+			helper.storage_sets_of_with_factor(&hash, 3),
+			helper.storage_sets_of(&hash)
+		);
+	}
+}
+
+#[test]
+fn test_plan_single_zone_warning() {
+	// Balanced layout: each partition has one replica in each zone
+	let mut cl = LayoutHistory::new(ReplicationFactor::new(3).unwrap());
+	update_layout(&mut cl, &[4000, 4000, 4000], &["A", "B", "C"], 3);
+	let plan = cl.plan_staged_changes().unwrap();
+	assert!(plan.warnings.is_empty());
+	assert_eq!(plan.new_version, cl.current().version + 1);
+	assert_eq!(plan.nodes.len(), 3);
+	for n in plan.nodes.iter() {
+		assert_eq!(n.partitions_before, 0);
+		assert_eq!(n.partitions_after, NB_PARTITIONS);
+		assert_eq!(n.partitions_gained, NB_PARTITIONS);
+	}
+
+	// Planning does not change the layout
+	assert_eq!(cl.current().version, 0);
+	let v = cl.current().version;
+	let (mut cl, _) = cl.apply_staged_changes(Some(v + 1)).unwrap();
+
+	// Add a big zone with three nodes, with a zone redundancy of 1:
+	// some partitions get all of their replicas in that zone
+	update_layout(
+		&mut cl,
+		&[100, 100, 100, 100000, 100000, 100000],
+		&["A", "B", "C", "D", "D", "D"],
+		1,
+	);
+	let plan = cl.plan_staged_changes().unwrap();
+	assert_eq!(plan.warnings.len(), 1);
+	assert!(plan.warnings[0].contains("zone D"));
+	assert!(plan.partitions_moved > 0);
+	for n in plan.nodes.iter().take(3) {
+		assert_eq!(n.partitions_before, NB_PARTITIONS);
+		assert_eq!(
+			n.partitions_after + n.partitions_lost - n.partitions_gained,
+			NB_PARTITIONS
+		);
+	}
+	assert_eq!(cl.current().version, v + 1);
+}
+
+fn partition_nodes(version: &LayoutVersion, p: usize) -> Vec<Uuid> {
+	let rf = version.replication_factor;
+	let mut nodes = version.ring_assignment_data[rf * p..rf * (p + 1)]
+		.iter()
+		.map(|i| version.node_id_vec[*i as usize])
+		.collect::<Vec<_>>();
+	nodes.sort();
+	nodes
+}
+
+#[test]
+fn test_canary_apply() {
+	let mut cl = LayoutHistory::new(ReplicationFactor::new(3).unwrap());
+	update_layout(&mut cl, &[4000, 4000, 4000], &["A", "B", "C"], 3);
+	let v = cl.current().version;
+	let (mut cl, _) = cl.apply_staged_changes(Some(v + 1)).unwrap();
+	let before = cl.current().clone();
+
+	// Add two nodes, but only move 10% of the changed partitions
+	update_layout(
+		&mut cl,
+		&[4000, 4000, 4000, 4000, 4000],
+		&["A", "B", "C", "D", "E"],
+		3,
+	);
+	let (cl, _) = cl.apply_staged_changes_canary(Some(v + 2), 10).unwrap();
+	assert_eq!(cl.check(), Ok(()));
+	assert_eq!(cl.current().version, v + 2);
+
+	let canary = cl.current().canary.clone().unwrap();
+	assert!(canary.changed_partitions > 0);
+	assert_eq!(
+		canary.partitions.len(),
+		canary.changed_partitions.div_ceil(10)
+	);
+	for p in 0..NB_PARTITIONS {
+		let moved = canary.partitions.contains(&(p as Partition));
+		assert_eq!(
+			partition_nodes(cl.current(), p) != partition_nodes(&before, p),
+			moved
+		);
+	}
+
+	// The staged changes are kept, and a second canary is refused
+	assert!(!cl.staging.get().roles.items().is_empty());
+	assert!(cl
+		.clone()
+		.apply_staged_changes_canary(Some(v + 3), 10)
+		.is_err());
+
+	// Reverting moves the canary partitions back
+	let reverted = cl.clone().revert_staged_changes().unwrap();
+	assert_eq!(reverted.check(), Ok(()));
+	assert_eq!(reverted.current().version, v + 3);
+	assert!(reverted.current().canary.is_none());
+	assert!(reverted.staging.get().roles.items().is_empty());
+	for p in 0..NB_PARTITIONS {
+		assert_eq!(
+			partition_nodes(reverted.current(), p),
+			partition_nodes(&before, p)
+		);
+	}
+
+	// Continuing moves the other partitions
+	let (mut cl, _) = cl.apply_staged_changes(Some(v + 3)).unwrap();
+	assert_eq!(cl.check(), Ok(()));
+	assert!(cl.current().canary.is_none());
+	assert_eq!(cl.current().nongateway_nodes().len(), 5);
+	assert!(cl.staging.get().roles.items().is_empty());
+
+	// Remove a node: it keeps its role while it still stores partitions
+	// that were not moved by the canary
+	let node_e: Uuid = [4u8; 32].into();
+	let ts = cl
+		.current()
+		.roles
+		.items()
+		.iter()
+		.find(|(n, _, _)| *n == node_e)
+		.unwrap()
+		.1;
+	cl.staging
+		.get_mut()
+		.roles
+		.merge_raw(&node_e, ts + 1, &NodeRoleV(None));
+	let (cl, _) = cl.apply_staged_changes_canary(Some(v + 4), 50).unwrap();
+	assert_eq!(cl.check(), Ok(()));
+	assert!(cl.current().get_node_capacity(&node_e).is_some());
+	assert!(cl.current().get_node_usage(&node_e).unwrap() > 0);
+
+	let (cl, _) = cl.apply_staged_changes(Some(v + 5)).unwrap();
+	assert_eq!(cl.check(), Ok(()));
+	assert!(!cl.current().all_nodes().contains(&node_e));
+}
+
+fn check_golden_assignment(
+	version: &LayoutVersion,
+	partition_size: u64,
+	ring_hash: &str,
+	usage: &[(u8, usize)],
+	first_partitions: &[[u8; 3]],
+) {
+	assert_eq!(version.partition_size, partition_size);
+	assert_eq!(
+		hex::encode(garage_util::data::blake2sum(&version.ring_assignment_data)),
+		ring_hash
+	);
+	for (node, n) in usage {
+		assert_eq!(version.get_node_usage(&[*node; 32].into()).unwrap(), *n);
+	}
+	for (p, nodes) in first_partitions.iter().enumerate() {
+		let ring_nodes = version.ring_assignment_data[3 * p..3 * (p + 1)]
+			.iter()
+			.map(|i| version.node_id_vec[*i as usize].as_slice()[0])
+			.collect::<Vec<u8>>();
+		assert_eq!(ring_nodes, nodes);
+	}
+}
+
+#[test]
+fn test_assignment_golden() {
+	// The assignment only depends on the roles, the parameters and the
+	// previous assignment: these values must only change if the algorithm
+	// is deliberately modified
+	let mut cl = LayoutHistory::new(ReplicationFactor::new(3).unwrap());
+	update_layout(
+		&mut cl,
+		&[4000, 4000, 2000, 3000, 1000],
+		&["A", "B", "C", "A", "C"],
+		2,
+	);
+	let (mut cl, _) = cl.apply_staged_changes(Some(1)).unwrap();
+	check_golden_assignment(
+		cl.current(),
+		18,
+		"e59772fd252c297be8bda0dac3ed7ce36e30875d79acd6ae11a752bdcc25b08f",
+		&[(0, 214), (1, 222), (2, 111), (3, 166), (4, 55)],
+		&[
+			[0, 3, 1],
+			[0, 1, 4],
+			[0, 1, 4],
+			[0, 3, 2],
+			[0, 3, 1],
+			[0, 3, 1],
+			[3, 0, 1],
+			[0, 3, 1],
+		],
+	);
+
+	// Adding a node starts from the previous assignment
+	update_layout(
+		&mut cl,
+		&[4000, 4000, 2000, 3000, 1000, 3000],
+		&["A", "B", "C", "A", "C", "B"],
+		2,
+	);
+	let (cl, msg) = cl.apply_staged_changes(Some(2)).unwrap();
+	check_golden_assignment(
+		cl.current(),
+		22,
+		"aeb1719b80ba2de3eddf733bba9271c2ba564eaba7bf3462e74f96af805514b9",
+		&[(0, 181), (1, 181), (2, 90), (3, 136), (4, 45), (5, 135)],
+		&[
+			[0, 3, 1],
+			[0, 1, 4],
+			[0, 1, 4],
+			[0, 3, 2],
+			[3, 5, 1],
+			[3, 0, 5],
+			[3, 0, 1],
+			[3, 5, 1],
+		],
+	);
+	assert!(msg.contains(&"Partition copies moved:  135".to_string()));
+
+	// Zones are numbered in alphabetical order, whatever the node ids
+	let mut cl = LayoutHistory::new(ReplicationFactor::new(3).unwrap());
+	update_layout(
+		&mut cl,
+		&[1000, 2000, 3000, 4000, 5000, 6000],
+		&["Z", "Y", "X", "Z", "Y", "X"],
+		3,
+	);
+	let (cl, _) = cl.apply_staged_changes(Some(1)).unwrap();
+	check_golden_assignment(
+		cl.current(),
+		19,
+		"41cf08c399180f6a6e549608db9bc122bfab21d49c5988236f9b3abddb2082d5",
+		&[(0, 52), (1, 105), (2, 124), (3, 204), (4, 151), (5, 132)],
+		&[
+			[5, 1, 3],
+			[2, 1, 0],
+			[5, 1, 0],
+			[5, 4, 3],
+			[5, 1, 3],
+			[2, 4, 3],
+			[5, 1, 3],
+			[5, 4, 3],
+		],
+	);
+}
@@ -1,3 +1,4 @@
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::convert::TryInto;
@@ -32,6 +33,7 @@ impl LayoutVersion {
 			nongateway_node_count: 0,
 			ring_assignment_data: Vec::new(),
 			parameters,
+			canary: None,
 		}
 	}
 
@@ -43,6 +45,22 @@ impl LayoutVersion {
 		&self.node_id_vec[..]
 	}
 
+	/// Returns a list of IDs of nodes that have a role in this version
+	/// of the cluster layout, excluding non-voting gateway nodes
+	pub fn voting_nodes(&self) -> impl Iterator<Item = &Uuid> + '_ {
+		self.node_id_vec
+			.iter()
+			.filter(move |n| self.is_voting_node(n))
+	}
+
+	/// Returns whether a node is counted in quorums of tables replicated
+	/// on all nodes, i.e. whether it is not a non-voting gateway
+	pub fn is_voting_node(&self, node: &Uuid) -> bool {
+		self.node_role(node)
+			.map(NodeRole::is_voting)
+			.unwrap_or(true)
+	}
+
 	/// Returns a list of IDs of nodes that have a storage capacity
 	/// assigned in this version of the cluster layout
 	pub fn nongateway_nodes(&self) -> &[Uuid] {
@@ -64,6 +82,7 @@ impl LayoutVersion {
 				capacity: Some(cap),
 				zone: _,
 				tags: _,
+				non_voting: _,
 			}) => Some(*cap),
 			_ => None,
 		}
@@ -217,6 +236,16 @@ impl LayoutVersion {
 			}
 		}
 
+		// In a canary version, the partitions that were not moved keep the
+		// assignment of the previous version, which was computed with
+		// other roles and parameters
+		let canary_partitions = self.canary.as_ref().map(|c| {
+			c.partitions
+				.iter()
+				.map(|p| *p as usize)
+				.collect::<HashSet<_>>()
+		});
+
 		// Check that every partition is associated to distinct nodes
 		let zone_redundancy = self.effective_zone_redundancy();
 		let rf = self.replication_factor;
@@ -225,6 +254,9 @@ impl LayoutVersion {
 			if nodes_of_p.iter().unique().count() != rf {
 				return Err(format!("partition does not contain {} unique node ids", rf));
 			}
+			if matches!(&canary_partitions, Some(moved) if !moved.contains(&p)) {
+				continue;
+			}
 			// Check that every partition is spread over at least zone_redundancy zones.
 			let zones_of_p = nodes_of_p
 				.iter()
@@ -238,6 +270,12 @@ impl LayoutVersion {
 			}
 		}
 
+		// The capacity and partition size constraints only hold once all
+		// partitions have been moved
+		if self.canary.is_some() {
+			return Ok(());
+		}
+
 		// Check that the nodes capacities is consistent with the stored partitions
 		let mut node_usage = vec![0; MAX_NODE_NUMBER];
 		for n in self.ring_assignment_data.iter() {
@@ -282,17 +320,132 @@ impl LayoutVersion {
 		mut self,
 		staging: &LayoutStaging,
 	) -> Result<(Self, Message), Error> {
+		let previous = self.clone();
 		self.version += 1;
+		self.canary = None;
 
 		self.roles.merge(&staging.roles);
 		self.roles.retain(|(_, _, v)| v.0.is_some());
 		self.parameters = *staging.parameters.get();
 
-		let msg = self.calculate_partition_assignment()?;
+		let mut msg = self.calculate_partition_assignment()?;
+		msg.extend(AssignmentScore::compute(&previous, &self).message());
 
 		Ok((self, msg))
 	}
 
+	/// Calculates a canary version, i.e. a first step from this version
+	/// towards `target` where only `percent`% of the partitions whose
+	/// assignment changes are moved to their new nodes. The moved partitions
+	/// are evenly spaced among the changed ones, so that the choice only
+	/// depends on the two assignments.
+	pub(crate) fn calculate_canary_version(
+		&self,
+		target: &LayoutVersion,
+		percent: u8,
+	) -> Result<Self, Error> {
+		let rf = self.replication_factor;
+		if self.ring_assignment_data.len() != rf * NB_PARTITIONS || target.replication_factor != rf
+		{
+			return Err(Error::Message(
+				"A canary can only be applied to change an existing partition assignment".into(),
+			));
+		}
+
+		let nodes_of = |v: &LayoutVersion, p: usize| {
+			v.ring_assignment_data[rf * p..rf * (p + 1)]
+				.iter()
+				.map(|i| v.node_id_vec[*i as usize])
+				.collect::<Vec<Uuid>>()
+		};
+		let changed = (0..NB_PARTITIONS)
+			.filter(|p| {
+				let mut before = nodes_of(self, *p);
+				let mut after = nodes_of(target, *p);
+				before.sort();
+				after.sort();
+				before != after
+			})
+			.collect::<Vec<_>>();
+		if changed.is_empty() {
+			return Err(Error::Message(
+				"The staged changes do not move any partition, there is nothing to apply as a canary"
+					.into(),
+			));
+		}
+
+		let count = (changed.len() * percent as usize).div_ceil(100);
+		let moved = changed
+			.iter()
+			.enumerate()
+			.filter(|(i, _)| i * count % changed.len() < count)
+			.map(|(_, p)| *p)
+			.collect::<HashSet<_>>();
+		let assignment = (0..NB_PARTITIONS)
+			.map(|p| {
+				if moved.contains(&p) {
+					nodes_of(target, p)
+				} else {
+					nodes_of(self, p)
+				}
+			})
+			.collect::<Vec<_>>();
+
+		// Nodes that still store partitions which were not moved keep
+		// their previous role if the staged changes remove it or make
+		// them gateways
+		let kept = assignment
+			.iter()
+			.flatten()
+			.filter(|n| target.get_node_capacity(n).is_none())
+			.copied()
+			.collect::<HashSet<Uuid>>();
+
+		let mut version = target.clone();
+		version.roles.retain(|(n, _, _)| !kept.contains(n));
+		for (n, ts, role) in self.roles.items().iter() {
+			if kept.contains(n) {
+				version.roles.merge_raw(n, *ts, role);
+			}
+		}
+
+		let mut node_id_vec = target.nongateway_nodes().to_vec();
+		node_id_vec.extend(self.node_id_vec.iter().filter(|n| kept.contains(n)));
+		version.nongateway_node_count = node_id_vec.len();
+		node_id_vec.extend(
+			target.node_id_vec[target.nongateway_node_count..]
+				.iter()
+				.filter(|n| !kept.contains(n)),
+		);
+		version.node_id_vec = node_id_vec;
+
+		version.ring_assignment_data = assignment
+			.iter()
+			.flatten()
+			.map(|n| {
+				let i = version.node_id_vec.iter().position(|x| x == n).unwrap();
+				i as CompactNodeType
+			})
+			.collect();
+
+		let mut partitions = moved
+			.into_iter()
+			.map(|p| p as Partition)
+			.collect::<Vec<_>>();
+		partitions.sort();
+		version.canary = Some(LayoutCanary {
+			percent,
+			changed_partitions: changed.len(),
+			partitions,
+		});
+
+		version
+			.check()
+			.map_err(|e| Error::Message(format!("Invalid canary layout version: {}", e)))?;
+
+		Ok(version)
+	}
+
 	/// This function calculates a new partition-to-node assignment.
 	/// The computed assignment respects the node replication factor
 	/// and the zone redundancy parameter It maximizes the capacity of a
@@ -302,6 +455,11 @@ impl LayoutVersion {
 	/// data to be moved.
 	/// Staged role changes must be merged with nodes roles before calling this function,
 	/// hence it must only be called from apply_staged_changes() and hence is not public.
+	///
+	/// The result only depends on the roles, the parameters and the previous assignment:
+	/// nodes are numbered in the order of their ids, zones in the alphabetical order of
+	/// their names, and ties between equivalent assignments are broken by the order in
+	/// which the flow algorithm explores the edges, which is shuffled with a fixed seed.
 	fn calculate_partition_assignment(&mut self) -> Result<Message, Error> {
 		// We update the node ids, since the node role list might have changed with the
 		// changes in the layout. We retrieve the old_assignment reframed with new ids
@@ -400,7 +558,8 @@ impl LayoutVersion {
 	fn update_node_id_vec(&mut self) -> Result<Option<Vec<Vec<usize>>>, Error> {
 		// (1) We compute the new node list
 		// Non gateway nodes should be coded on 8bits, hence they must be first in the list
-		// We build the new node ids
+		// We build the new node ids, in the order of the node ids (the items of the
+		// LwwMap are sorted by key)
 		let new_non_gateway_nodes: Vec<Uuid> = self
 			.roles
 			.items()
@@ -478,20 +637,24 @@ impl LayoutVersion {
 	}
 
 	/// This function generates ids for the zone of the nodes appearing in
-	/// self.node_id_vec.
+	/// self.node_id_vec, in the alphabetical order of the zone names.
 	pub(crate) fn generate_nongateway_zone_ids(
 		&self,
 	) -> Result<(Vec<String>, HashMap<String, usize>), Error> {
-		let mut id_to_zone = Vec::<String>::new();
-		let mut zone_to_id = HashMap::<String, usize>::new();
-
-		for uuid in self.nongateway_nodes().iter() {
-			let r = self.node_role(uuid).unwrap();
-			if !zone_to_id.contains_key(&r.zone) && r.capacity.is_some() {
-				zone_to_id.insert(r.zone.clone(), id_to_zone.len());
-				id_to_zone.push(r.zone.clone());
-			}
-		}
+		let id_to_zone = self
+			.nongateway_nodes()
+			.iter()
+			.filter_map(|uuid| self.node_role(uuid))
+			.filter(|r| r.capacity.is_some())
+			.map(|r| r.zone.clone())
+			.collect::<BTreeSet<String>>()
+			.into_iter()
+			.collect::<Vec<_>>();
+		let zone_to_id = id_to_zone
+			.iter()
+			.enumerate()
+			.map(|(i, z)| (z.clone(), i))
+			.collect::<HashMap<String, usize>>();
 		Ok((id_to_zone, zone_to_id))
 	}
 
@@ -502,7 +665,7 @@ impl LayoutVersion {
 		zone_to_id: &HashMap<String, usize>,
 		zone_redundancy: usize,
 	) -> Result<u64, Error> {
-		let empty_set = HashSet::<(usize, usize)>::new();
+		let empty_set = BTreeSet::<(usize, usize)>::new();
 		let mut g = self.generate_flow_graph(1, zone_to_id, &empty_set, zone_redundancy)?;
 		g.compute_maximal_flow()?;
 		if g.get_flow_value()? < (NB_PARTITIONS * self.replication_factor) as i64 {
@@ -559,7 +722,7 @@ impl LayoutVersion {
 		&self,
 		partition_size: u64,
 		zone_to_id: &HashMap<String, usize>,
-		exclude_assoc: &HashSet<(usize, usize)>,
+		exclude_assoc: &BTreeSet<(usize, usize)>,
 		zone_redundancy: usize,
 	) -> Result<Graph<FlowEdge>, Error> {
 		let vertices =
@@ -603,8 +766,9 @@ impl LayoutVersion {
 		zone_redundancy: usize,
 	) -> Result<Graph<FlowEdge>, Error> {
 		// We list the (partition,node) associations that are not used in the
-		// previous assignment
-		let mut exclude_edge = HashSet::<(usize, usize)>::new();
+		// previous assignment. They are kept sorted, as the order in which the
+		// edges are added to the graph changes the resulting assignment.
+		let mut exclude_edge = BTreeSet::<(usize, usize)>::new();
 		if let Some(prev_assign) = prev_assign_opt {
 			let nb_nodes = self.nongateway_nodes().len();
 			for (p, prev_assign_p) in prev_assign.iter().enumerate() {
@@ -12,6 +12,8 @@ mod consul;
 mod kubernetes;
 
 pub mod layout;
+pub mod minority_partition;
+pub mod peer_state;
 pub mod replication_mode;
 pub mod system;
 
@@ -6,6 +6,8 @@ pub struct RpcMetrics {
 	pub(crate) rpc_timeout_counter: Counter<u64>,
 	pub(crate) rpc_netapp_error_counter: Counter<u64>,
 	pub(crate) rpc_garage_error_counter: Counter<u64>,
+	pub(crate) rpc_no_quorum_counter: Counter<u64>,
+	pub(crate) rpc_read_served_counter: Counter<u64>,
 
 	pub(crate) rpc_duration: ValueRecorder<f64>,
 }
@@ -29,6 +31,14 @@ impl RpcMetrics {
 				.u64_counter("rpc.garage_error_counter")
 				.with_description("Number of RPC errors (errors happening when handling the RPC)")
 				.init(),
+			rpc_no_quorum_counter: meter
+				.u64_counter("rpc.no_quorum_counter")
+				.with_description("Number of writes refused without sending requests because not enough nodes were connected to reach a quorum")
+				.init(),
+			rpc_read_served_counter: meter
+				.u64_counter("rpc.read_served_counter")
+				.with_description("Number of reads answered by a single node, by distance between this node and the node that answered")
+				.init(),
 			rpc_duration: meter
 				.f64_value_recorder("rpc.duration")
 				.with_description("Duration of RPCs")
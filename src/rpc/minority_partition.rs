@@ -0,0 +1,133 @@
+//! Module containing the protection against writes on a node that is
+//! partitioned from the majority of the nodes of the cluster layout.
+//!
+//! When enabled, writes that such a node accepts in degraded consistency
+//! mode would later conflict with the writes made on the other side of the
+//! partition, so the API servers refuse them until connectivity recovers.
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use garage_util::config::RpcConfig;
+use garage_util::time::now_msec;
+
+const DEFAULT_MINORITY_PARTITION_THRESHOLD: f64 = 0.5;
+
+/// Whether a node that reaches `reachable` nodes (itself included)
+/// out of the `total` nodes of the layout is in a minority partition
+pub fn is_minority(reachable: usize, total: usize, threshold: f64) -> bool {
+	total > 0 && reachable as f64 <= threshold * total as f64
+}
+
+/// Tracks whether the local node is in a minority partition
+pub struct MinorityPartitionGuard {
+	enabled: bool,
+	threshold: f64,
+	refuse_reads: bool,
+
+	partitioned: AtomicBool,
+	/// Time of the last transition, in msec since the epoch (0 if none)
+	since: AtomicU64,
+}
+
+impl MinorityPartitionGuard {
+	pub fn new(config: &RpcConfig) -> Self {
+		Self {
+			enabled: config.minority_partition_protection,
+			threshold: config
+				.minority_partition_threshold
+				.unwrap_or(DEFAULT_MINORITY_PARTITION_THRESHOLD),
+			refuse_reads: config.minority_partition_refuse_reads,
+			partitioned: AtomicBool::new(false),
+			since: AtomicU64::new(0),
+		}
+	}
+
+	pub fn is_enabled(&self) -> bool {
+		self.enabled
+	}
+
+	/// Update the state of the guard given the number of nodes of the
+	/// layout that are currently reachable. Returns the new state if it
+	/// changed.
+	pub fn update(&self, reachable: usize, total: usize) -> Option<bool> {
+		if !self.enabled {
+			return None;
+		}
+		let partitioned = is_minority(reachable, total, self.threshold);
+		if self.partitioned.swap(partitioned, Ordering::Relaxed) != partitioned {
+			self.since.store(now_msec(), Ordering::Relaxed);
+			Some(partitioned)
+		} else {
+			None
+		}
+	}
+
+	/// Whether the local node is currently in a minority partition
+	pub fn is_partitioned(&self) -> bool {
+		self.partitioned.load(Ordering::Relaxed)
+	}
+
+	/// Time of the last change of state, in msec since the epoch
+	pub fn since(&self) -> Option<u64> {
+		Some(self.since.load(Ordering::Relaxed)).filter(|t| *t > 0)
+	}
+
+	/// Whether write requests must be refused
+	pub fn refuses_writes(&self) -> bool {
+		self.is_partitioned()
+	}
+
+	/// Whether read requests must be refused
+	pub fn refuses_reads(&self) -> bool {
+		self.refuse_reads && self.is_partitioned()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_is_minority() {
+		assert!(is_minority(1, 3, 0.5));
+		assert!(!is_minority(2, 3, 0.5));
+		assert!(is_minority(2, 4, 0.5));
+		assert!(!is_minority(3, 4, 0.5));
+		assert!(is_minority(2, 3, 0.7));
+		assert!(!is_minority(0, 0, 0.5));
+	}
+
+	#[test]
+	fn test_partition_and_recovery() {
+		let guard = MinorityPartitionGuard::new(&RpcConfig {
+			minority_partition_protection: true,
+			minority_partition_threshold: None,
+			minority_partition_refuse_reads: false,
+			frame_checksums: false,
+			prefer_local_zone: None,
+		});
+
+		// All three nodes of the layout are reachable
+		assert_eq!(guard.update(3, 3), None);
+		assert!(!guard.refuses_writes());
+
+		// One node is lost, the other two still form a majority
+		assert_eq!(guard.update(2, 3), None);
+		assert!(!guard.refuses_writes());
+
+		// The node is isolated from the two others
+		assert_eq!(guard.update(1, 3), Some(true));
+		assert!(guard.refuses_writes());
+		assert!(!guard.refuses_reads());
+		assert!(guard.since().is_some());
+		assert_eq!(guard.update(1, 3), None);
+
+		// Connectivity recovers
+		assert_eq!(guard.update(3, 3), Some(false));
+		assert!(!guard.refuses_writes());
+
+		// Nothing is refused when the protection is disabled
+		let disabled = MinorityPartitionGuard::new(&RpcConfig::default());
+		assert_eq!(disabled.update(1, 3), None);
+		assert!(!disabled.refuses_writes());
+	}
+}
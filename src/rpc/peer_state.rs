@@ -0,0 +1,249 @@
+//! Module containing the state machine used to report the state of the other
+//! nodes of the cluster.
+//!
+//! This is used for reporting only: whether requests are sent to a node, and
+//! whether quorums can be reached, only depends on whether we are connected to it.
+use std::collections::HashMap;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use garage_util::data::*;
+
+/// State of a node, as seen by the local node
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerState {
+	/// This is the local node
+	Ourself,
+	/// We have not been connected to this node since we started
+	#[default]
+	NeverConnected,
+	/// We are connected to this node and it is working normally
+	Up,
+	/// We are connected to this node, but its RPC latency is above the threshold
+	/// set by `rpc_slow_threshold_msec`
+	Slow,
+	/// We are connected to this node, but it has not acknowledged the
+	/// current version of the cluster layout
+	LayoutLagging,
+	/// We were connected to this node, but the connection was lost
+	/// and it cannot be established again
+	Disconnected,
+}
+
+impl PeerState {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Self::Ourself => "ourself",
+			Self::NeverConnected => "never_connected",
+			Self::Up => "up",
+			Self::Slow => "slow",
+			Self::LayoutLagging => "layout_lagging",
+			Self::Disconnected => "disconnected",
+		}
+	}
+}
+
+/// What the local node currently knows about another node
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PeerObservation {
+	/// The node is the local node
+	pub is_ourself: bool,
+	/// We currently have a connection to the node
+	pub connected: bool,
+	/// Average ping time to the node on recent observations
+	pub avg_ping: Option<Duration>,
+	/// The node has acknowledged the current layout version
+	pub layout_acked: bool,
+}
+
+/// State of a node, with timestamps in msec since the epoch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerStateInfo {
+	pub state: PeerState,
+	/// Time of the last transition to `state`
+	pub since: u64,
+}
+
+/// Transition of a node from one state to another
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerStateTransition {
+	pub node: Uuid,
+	pub from: PeerState,
+	pub to: PeerState,
+}
+
+pub struct PeerStateTracker {
+	slow_threshold: Duration,
+	peers: HashMap<Uuid, PeerStateInfo>,
+}
+
+impl PeerStateTracker {
+	pub fn new(slow_threshold: Duration) -> Self {
+		Self {
+			slow_threshold,
+			peers: HashMap::new(),
+		}
+	}
+
+	/// Update the state of a node given what we currently know about it,
+	/// at time `now`. Returns the transition if the state of the node changed.
+	pub fn observe(
+		&mut self,
+		node: Uuid,
+		obs: &PeerObservation,
+		now: u64,
+	) -> Option<PeerStateTransition> {
+		let slow_threshold = self.slow_threshold;
+		let info = self.peers.entry(node).or_insert(PeerStateInfo {
+			state: PeerState::NeverConnected,
+			since: now,
+		});
+
+		let next = next_state(info.state, obs, slow_threshold);
+		if next == info.state {
+			return None;
+		}
+
+		let transition = PeerStateTransition {
+			node,
+			from: info.state,
+			to: next,
+		};
+		*info = PeerStateInfo {
+			state: next,
+			since: now,
+		};
+		Some(transition)
+	}
+
+	pub fn get(&self, node: &Uuid) -> Option<PeerStateInfo> {
+		self.peers.get(node).copied()
+	}
+}
+
+fn next_state(prev: PeerState, obs: &PeerObservation, slow_threshold: Duration) -> PeerState {
+	if obs.is_ourself {
+		PeerState::Ourself
+	} else if !obs.connected {
+		match prev {
+			PeerState::NeverConnected => PeerState::NeverConnected,
+			_ => PeerState::Disconnected,
+		}
+	} else if !obs.layout_acked {
+		PeerState::LayoutLagging
+	} else if obs.avg_ping.map(|p| p > slow_threshold).unwrap_or(false) {
+		PeerState::Slow
+	} else {
+		PeerState::Up
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const THRESHOLD: Duration = Duration::from_millis(500);
+
+	fn connected(ping_ms: u64, layout_acked: bool) -> PeerObservation {
+		PeerObservation {
+			connected: true,
+			avg_ping: Some(Duration::from_millis(ping_ms)),
+			layout_acked,
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn test_peer_state_transitions() {
+		let node = gen_uuid();
+		let mut tracker = PeerStateTracker::new(THRESHOLD);
+
+		let transition = |from, to| Some(PeerStateTransition { node, from, to });
+
+		// Node that we have not managed to connect to yet
+		let down = PeerObservation::default();
+		assert_eq!(tracker.observe(node, &down, 1000), None);
+		assert_eq!(
+			tracker.get(&node),
+			Some(PeerStateInfo {
+				state: PeerState::NeverConnected,
+				since: 1000
+			})
+		);
+		assert_eq!(tracker.observe(node, &down, 2000), None);
+
+		// Connection established, but the node is still catching up with the layout
+		assert_eq!(
+			tracker.observe(node, &connected(10, false), 3000),
+			transition(PeerState::NeverConnected, PeerState::LayoutLagging)
+		);
+		assert_eq!(
+			tracker.observe(node, &connected(10, true), 4000),
+			transition(PeerState::LayoutLagging, PeerState::Up)
+		);
+		assert_eq!(tracker.observe(node, &connected(20, true), 5000), None);
+		assert_eq!(tracker.get(&node).unwrap().since, 4000);
+
+		// Latency goes above the threshold and back
+		assert_eq!(
+			tracker.observe(node, &connected(800, true), 6000),
+			transition(PeerState::Up, PeerState::Slow)
+		);
+		assert_eq!(
+			tracker.observe(node, &connected(100, true), 7000),
+			transition(PeerState::Slow, PeerState::Up)
+		);
+
+		// Layout lagging takes precedence over latency
+		assert_eq!(
+			tracker.observe(node, &connected(800, false), 8000),
+			transition(PeerState::Up, PeerState::LayoutLagging)
+		);
+
+		// Connection lost, then established again
+		assert_eq!(
+			tracker.observe(node, &down, 9000),
+			transition(PeerState::LayoutLagging, PeerState::Disconnected)
+		);
+		assert_eq!(tracker.observe(node, &down, 10000), None);
+		assert_eq!(
+			tracker.get(&node),
+			Some(PeerStateInfo {
+				state: PeerState::Disconnected,
+				since: 9000
+			})
+		);
+		assert_eq!(
+			tracker.observe(node, &connected(10, true), 11000),
+			transition(PeerState::Disconnected, PeerState::Up)
+		);
+
+		// A slow node that disconnects is reported as disconnected
+		tracker.observe(node, &connected(800, true), 12000);
+		assert_eq!(
+			tracker.observe(node, &down, 13000),
+			transition(PeerState::Slow, PeerState::Disconnected)
+		);
+	}
+
+	#[test]
+	fn test_peer_state_ourself() {
+		let node = gen_uuid();
+		let mut tracker = PeerStateTracker::new(THRESHOLD);
+		let ourself = PeerObservation {
+			is_ourself: true,
+			..Default::default()
+		};
+		assert_eq!(
+			tracker.observe(node, &ourself, 1000),
+			Some(PeerStateTransition {
+				node,
+				from: PeerState::NeverConnected,
+				to: PeerState::Ourself
+			})
+		);
+		assert_eq!(tracker.observe(node, &ourself, 2000), None);
+	}
+}
@@ -1,6 +1,6 @@
 //! Contain structs related to making RPCs
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
 
 use futures::future::join_all;
@@ -22,9 +22,11 @@ pub use garage_net::message::{
 use garage_net::peering::PeeringManager;
 pub use garage_net::{self, NetApp, NodeID};
 
+use garage_util::config::{NoQuorumBehavior, RpcTimeoutsConfig};
 use garage_util::data::*;
 use garage_util::error::Error;
 use garage_util::metrics::RecordDuration;
+use garage_util::time::now_msec;
 
 use crate::layout::{LayoutHelper, LayoutVersion};
 use crate::metrics::RpcMetrics;
@@ -36,6 +38,9 @@ const DEFAULT_TIMEOUT: Duration = Duration::from_secs(300);
 pub struct RequestStrategy<T> {
 	/// Min number of response to consider the request successful
 	rs_quorum: Option<usize>,
+	/// Min number of distinct zones in which the responses counted
+	/// in the quorum must be (for writes to many sets)
+	rs_min_zones: usize,
 	/// Send all requests at once
 	rs_send_all_at_once: Option<bool>,
 	/// Request priority
@@ -46,17 +51,140 @@ pub struct RequestStrategy<T> {
 	rs_drop_on_complete: T,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 enum Timeout {
 	None,
 	Default,
+	Class(RpcClass),
 	Custom(Duration),
 }
 
+/// Class of operation an RPC belongs to, which determines its timeout
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RpcClass {
+	/// Reading entries from a metadata table
+	MetadataRead,
+	/// Writing entries to a metadata table
+	MetadataWrite,
+	/// Fetching a data block
+	BlockGet,
+	/// Storing a data block
+	BlockPut,
+	/// Background synchronization between nodes (table sync, block resync)
+	Sync,
+}
+
+impl RpcClass {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			RpcClass::MetadataRead => "metadata_read",
+			RpcClass::MetadataWrite => "metadata_write",
+			RpcClass::BlockGet => "block_get",
+			RpcClass::BlockPut => "block_put",
+			RpcClass::Sync => "sync",
+		}
+	}
+}
+
+/// Distance between this node and a node to which a request is sent,
+/// used to order the nodes to which reads are sent
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ZoneDistance {
+	/// The node is this node
+	Local,
+	/// The node is in the same zone of the cluster layout as this node
+	SameZone,
+	/// The node is in another zone, or this node has no role in the layout
+	OtherZone,
+}
+
+impl ZoneDistance {
+	fn of(our_zone: Option<&str>, is_local: bool, zone: Option<&str>) -> Self {
+		match (is_local, our_zone) {
+			(true, _) => ZoneDistance::Local,
+			(false, Some(our_zone)) if zone == Some(our_zone) => ZoneDistance::SameZone,
+			_ => ZoneDistance::OtherZone,
+		}
+	}
+
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			ZoneDistance::Local => "local",
+			ZoneDistance::SameZone => "same_zone",
+			ZoneDistance::OtherZone => "other_zone",
+		}
+	}
+}
+
+/// Key by which nodes are sorted to send them requests: this node first,
+/// then nodes of the same zone if `prefer_local_zone` is set, and nodes
+/// with the lowest latency among nodes at the same distance
+fn request_order_key(
+	distance: ZoneDistance,
+	ping: Duration,
+	prefer_local_zone: bool,
+) -> (ZoneDistance, Duration) {
+	match distance {
+		ZoneDistance::SameZone if !prefer_local_zone => (ZoneDistance::OtherZone, ping),
+		d => (d, ping),
+	}
+}
+
+/// Timeouts applied to RPCs, for each class of operation
+#[derive(Clone, Debug)]
+pub struct RpcTimeouts {
+	default: Duration,
+	metadata_read: Duration,
+	metadata_write: Duration,
+	block_get: Duration,
+	block_put: Duration,
+	sync: Duration,
+}
+
+impl RpcTimeouts {
+	/// Build timeouts from the configuration: classes for which no specific
+	/// timeout is configured use the default RPC timeout
+	pub fn new(default_msec: Option<u64>, config: &RpcTimeoutsConfig) -> Self {
+		let default = default_msec
+			.map(Duration::from_millis)
+			.unwrap_or(DEFAULT_TIMEOUT);
+		let or_default = |x: Option<u64>| x.map(Duration::from_millis).unwrap_or(default);
+		Self {
+			default,
+			metadata_read: or_default(config.metadata_read_msec),
+			metadata_write: or_default(config.metadata_write_msec),
+			block_get: or_default(config.block_get_msec),
+			block_put: or_default(config.block_put_msec),
+			sync: or_default(config.sync_msec),
+		}
+	}
+
+	/// Timeout for RPCs of a given class
+	pub fn get(&self, class: RpcClass) -> Duration {
+		match class {
+			RpcClass::MetadataRead => self.metadata_read,
+			RpcClass::MetadataWrite => self.metadata_write,
+			RpcClass::BlockGet => self.block_get,
+			RpcClass::BlockPut => self.block_put,
+			RpcClass::Sync => self.sync,
+		}
+	}
+
+	fn resolve(&self, timeout: Timeout) -> Option<Duration> {
+		match timeout {
+			Timeout::None => None,
+			Timeout::Default => Some(self.default),
+			Timeout::Class(class) => Some(self.get(class)),
+			Timeout::Custom(t) => Some(t),
+		}
+	}
+}
+
 impl Clone for RequestStrategy<()> {
 	fn clone(&self) -> Self {
 		RequestStrategy {
 			rs_quorum: self.rs_quorum,
+			rs_min_zones: self.rs_min_zones,
 			rs_send_all_at_once: self.rs_send_all_at_once,
 			rs_priority: self.rs_priority,
 			rs_timeout: self.rs_timeout,
@@ -70,6 +198,7 @@ impl RequestStrategy<()> {
 	pub fn with_priority(prio: RequestPriority) -> Self {
 		RequestStrategy {
 			rs_quorum: None,
+			rs_min_zones: 0,
 			rs_send_all_at_once: None,
 			rs_priority: prio,
 			rs_timeout: Timeout::Default,
@@ -80,6 +209,7 @@ impl RequestStrategy<()> {
 	pub fn with_drop_on_completion<T>(self, drop_on_complete: T) -> RequestStrategy<T> {
 		RequestStrategy {
 			rs_quorum: self.rs_quorum,
+			rs_min_zones: self.rs_min_zones,
 			rs_send_all_at_once: self.rs_send_all_at_once,
 			rs_priority: self.rs_priority,
 			rs_timeout: self.rs_timeout,
@@ -94,6 +224,12 @@ impl<T> RequestStrategy<T> {
 		self.rs_quorum = Some(quorum);
 		self
 	}
+	/// Require the responses counted in the quorum of each set to come
+	/// from nodes in at least this number of distinct zones
+	pub fn with_min_zones(mut self, min_zones: usize) -> Self {
+		self.rs_min_zones = min_zones;
+		self
+	}
 	/// Set quorum to be reached for request
 	pub fn send_all_at_once(mut self, value: bool) -> Self {
 		self.rs_send_all_at_once = Some(value);
@@ -109,11 +245,17 @@ impl<T> RequestStrategy<T> {
 		self.rs_timeout = Timeout::Custom(timeout);
 		self
 	}
+	/// Use the timeout configured for a class of operations for this request
+	pub fn with_timeout_class(mut self, class: RpcClass) -> Self {
+		self.rs_timeout = Timeout::Class(class);
+		self
+	}
 	/// Extract drop_on_complete item
 	fn extract_drop_on_complete(self) -> (RequestStrategy<()>, T) {
 		(
 			RequestStrategy {
 				rs_quorum: self.rs_quorum,
+				rs_min_zones: self.rs_min_zones,
 				rs_send_all_at_once: self.rs_send_all_at_once,
 				rs_priority: self.rs_priority,
 				rs_timeout: self.rs_timeout,
@@ -132,7 +274,12 @@ struct RpcHelperInner {
 	peering: Arc<PeeringManager>,
 	layout: Arc<RwLock<LayoutHelper>>,
 	metrics: RpcMetrics,
-	rpc_timeout: Duration,
+	timeouts: RpcTimeouts,
+	no_quorum_behavior: NoQuorumBehavior,
+	/// Send reads to nodes of the same zone before nodes of other zones
+	prefer_local_zone: bool,
+	/// Time at which each node last answered one of our RPCs
+	last_response: Mutex<HashMap<Uuid, u64>>,
 }
 
 impl RpcHelper {
@@ -140,7 +287,9 @@ impl RpcHelper {
 		our_node_id: Uuid,
 		peering: Arc<PeeringManager>,
 		layout: Arc<RwLock<LayoutHelper>>,
-		rpc_timeout: Option<Duration>,
+		timeouts: RpcTimeouts,
+		no_quorum_behavior: NoQuorumBehavior,
+		prefer_local_zone: bool,
 	) -> Self {
 		let metrics = RpcMetrics::new();
 
@@ -149,12 +298,61 @@ impl RpcHelper {
 			peering,
 			layout,
 			metrics,
-			rpc_timeout: rpc_timeout.unwrap_or(DEFAULT_TIMEOUT),
+			timeouts,
+			no_quorum_behavior,
+			prefer_local_zone,
+			last_response: Mutex::new(HashMap::new()),
 		}))
 	}
 
 	pub fn rpc_timeout(&self) -> Duration {
-		self.0.rpc_timeout
+		self.0.timeouts.default
+	}
+
+	/// Timeout configured for RPCs of a given class
+	pub fn rpc_timeout_for(&self, class: RpcClass) -> Duration {
+		self.0.timeouts.get(class)
+	}
+
+	/// Time at which a node last answered one of the RPCs made through
+	/// this helper, in msec since the epoch
+	pub fn last_response_time(&self, node: &Uuid) -> Option<u64> {
+		self.0.last_response.lock().unwrap().get(node).copied()
+	}
+
+	/// Count a timeout of an RPC that was not sent through this helper,
+	/// but that is bounded by the timeout of its class
+	pub fn record_timeout(&self, endpoint_path: &str, to: Uuid, class: RpcClass) {
+		self.0.metrics.rpc_timeout_counter.add(
+			1,
+			&[
+				KeyValue::new("rpc_endpoint", endpoint_path.to_string()),
+				KeyValue::new("from", format!("{:?}", self.0.our_node_id)),
+				KeyValue::new("to", format!("{:?}", to)),
+				KeyValue::new("rpc_class", class.as_str()),
+			],
+		);
+	}
+
+	/// Count a read served by a node, by distance between this node and
+	/// the node that served it
+	pub fn record_read_served(&self, node: Uuid, class: RpcClass) {
+		let distance = {
+			let layout = self.0.layout.read().unwrap();
+			let layout = layout.current();
+			ZoneDistance::of(
+				layout.get_node_zone(&self.0.our_node_id),
+				node == self.0.our_node_id,
+				layout.get_node_zone(&node),
+			)
+		};
+		self.0.metrics.rpc_read_served_counter.add(
+			1,
+			&[
+				KeyValue::new("distance", distance.as_str()),
+				KeyValue::new("rpc_class", class.as_str()),
+			],
+		);
 	}
 
 	pub async fn call<M, N, H, S>(
@@ -190,10 +388,9 @@ impl RpcHelper {
 			.record_duration(&self.0.metrics.rpc_duration, &metric_tags);
 
 		let timeout = async {
-			match strat.rs_timeout {
-				Timeout::None => futures::future::pending().await,
-				Timeout::Default => tokio::time::sleep(self.0.rpc_timeout).await,
-				Timeout::Custom(t) => tokio::time::sleep(t).await,
+			match self.0.timeouts.resolve(strat.rs_timeout) {
+				None => futures::future::pending().await,
+				Some(t) => tokio::time::sleep(t).await,
 			}
 		};
 
@@ -203,6 +400,7 @@ impl RpcHelper {
 					self.0.metrics.rpc_netapp_error_counter.add(1, &metric_tags);
 				}
 				let res = res?.into_msg();
+				self.0.last_response.lock().unwrap().insert(to, now_msec());
 
 				if res.is_err() {
 					self.0.metrics.rpc_garage_error_counter.add(1, &metric_tags);
@@ -211,7 +409,12 @@ impl RpcHelper {
 				Ok(res?)
 			}
 			() = timeout => {
-				self.0.metrics.rpc_timeout_counter.add(1, &metric_tags);
+				let class = match strat.rs_timeout {
+					Timeout::Class(class) => class.as_str(),
+					_ => "default",
+				};
+				let timeout_tags = [&metric_tags[..], &[KeyValue::new("rpc_class", class)]].concat();
+				self.0.metrics.rpc_timeout_counter.add(1, &timeout_tags);
 				Err(Error::Timeout)
 			}
 		}
@@ -357,7 +560,7 @@ impl RpcHelper {
 			let msg = msg.clone();
 			let endpoint2 = endpoint.clone();
 			let strategy = strategy.clone();
-			async move { self2.call(&endpoint2, to, msg, strategy).await }
+			async move { (to, self2.call(&endpoint2, to, msg, strategy).await) }
 		});
 
 		// Vectors in which success results and errors will be collected
@@ -387,10 +590,19 @@ impl RpcHelper {
 
 			// Wait for one request to terminate
 			match resp_stream.next().await.unwrap() {
-				Ok(msg) => {
+				(to, Ok(msg)) => {
+					// Reads answered by a single node are counted by the
+					// distance to that node
+					if let (
+						1,
+						Timeout::Class(class @ (RpcClass::MetadataRead | RpcClass::BlockGet)),
+					) = (quorum, strategy.rs_timeout)
+					{
+						self.record_read_served(to, class);
+					}
 					successes.push(msg);
 				}
-				Err(e) => {
+				(_, Err(e)) => {
 					errors.push(e);
 				}
 			}
@@ -429,10 +641,18 @@ impl RpcHelper {
 	/// changes, where data has to be written both in the old layout and in the
 	/// new one as long as all nodes have not successfully tranisitionned and
 	/// moved all data to the new layout.
+	///
+	/// Requests are also sent to the `non_voting` nodes, but their responses
+	/// are not counted in any quorum set.
+	///
+	/// If `no_quorum_behavior` is `fail_fast`, no request is sent and an error
+	/// is returned right away when the nodes we are connected to are not enough
+	/// to reach a quorum in all sets.
 	pub async fn try_write_many_sets<M, N, H, S, T>(
 		&self,
 		endpoint: &Arc<Endpoint<M, H>>,
 		to_sets: &[Vec<Uuid>],
+		non_voting: &[Uuid],
 		msg: N,
 		strategy: RequestStrategy<T>,
 	) -> Result<Vec<S>, Error>
@@ -460,7 +680,7 @@ impl RpcHelper {
 		span.set_attribute(KeyValue::new("to", format!("{:?}", to_sets)));
 		span.set_attribute(KeyValue::new("quorum", quorum as i64));
 
-		self.try_write_many_sets_inner(endpoint, to_sets, msg, strategy, quorum)
+		self.try_write_many_sets_inner(endpoint, to_sets, non_voting, msg, strategy, quorum)
 			.with_context(Context::current_with_span(span))
 			.await
 	}
@@ -469,6 +689,7 @@ impl RpcHelper {
 		&self,
 		endpoint: &Arc<Endpoint<M, H>>,
 		to_sets: &[Vec<Uuid>],
+		non_voting: &[Uuid],
 		msg: N,
 		strategy: RequestStrategy<T>,
 		quorum: usize,
@@ -483,6 +704,36 @@ impl RpcHelper {
 		// Peers may appear in many quorum sets. Here, build a list of peers,
 		// mapping to the index of the quorum sets in which they appear.
 		let mut result_tracker = QuorumSetResultTracker::new(to_sets, quorum);
+		result_tracker.add_non_voting_nodes(non_voting);
+		if strategy.rs_min_zones > 0 {
+			let layout = self.0.layout.read().unwrap();
+			result_tracker.require_zones(strategy.rs_min_zones, |n| {
+				layout.node_zone(n).map(String::from)
+			});
+		}
+
+		if self.0.no_quorum_behavior == NoQuorumBehavior::FailFast {
+			let peer_list = self.0.peering.get_peer_list();
+			let is_up = |node: &Uuid| {
+				*node == self.0.our_node_id
+					|| peer_list
+						.iter()
+						.any(|p| p.id.as_ref() == node.as_slice() && p.is_up())
+			};
+			if !result_tracker.quorum_reachable(is_up) {
+				self.0.metrics.rpc_no_quorum_counter.add(
+					1,
+					&[KeyValue::new("rpc_endpoint", endpoint.path().to_string())],
+				);
+				let unreachable = result_tracker
+					.nodes
+					.keys()
+					.filter(|n| !is_up(n))
+					.map(|n| format!("{:?}", n))
+					.collect::<Vec<_>>();
+				return Err(Error::NoQuorum(quorum, unreachable));
+			}
+		}
 
 		let (strategy, drop_on_complete) = strategy.extract_drop_on_complete();
 
@@ -570,38 +821,35 @@ impl RpcHelper {
 	) -> Vec<Uuid> {
 		// Retrieve some status variables that we will use to sort requests
 		let peer_list = self.0.peering.get_peer_list();
-		let our_zone = layout.get_node_zone(&self.0.our_node_id).unwrap_or("");
-
-		// Augment requests with some information used to sort them.
-		// The tuples are as follows:
-		//         (is another node?, is another zone?, latency, node ID, request future)
-		// We store all of these tuples in a vec that we can sort.
-		// By sorting this vec, we priorize ourself, then nodes in the same zone,
-		// and within a same zone we priorize nodes with the lowest latency.
+		let our_zone = layout.get_node_zone(&self.0.our_node_id);
+
+		// Augment requests with the key used to sort them, i.e. their
+		// distance to us and their latency (see `request_order_key`):
+		// we priorize ourself, then nodes in the same zone (unless
+		// `prefer_local_zone` is disabled), and at the same distance
+		// we priorize nodes with the lowest latency.
 		let mut nodes = nodes
 			.map(|to| {
-				let peer_zone = layout.get_node_zone(&to).unwrap_or("");
+				let distance = ZoneDistance::of(
+					our_zone,
+					to == self.0.our_node_id,
+					layout.get_node_zone(&to),
+				);
 				let peer_avg_ping = peer_list
 					.iter()
 					.find(|x| x.id.as_ref() == to.as_slice())
 					.and_then(|pi| pi.avg_ping)
 					.unwrap_or_else(|| Duration::from_secs(10));
 				(
-					to != self.0.our_node_id,
-					peer_zone != our_zone,
-					peer_avg_ping,
+					request_order_key(distance, peer_avg_ping, self.0.prefer_local_zone),
 					to,
 				)
 			})
 			.collect::<Vec<_>>();
 
-		// Sort requests by (priorize ourself, priorize same zone, priorize low latency)
-		nodes.sort_by_key(|(diffnode, diffzone, ping, _to)| (*diffnode, *diffzone, *ping));
+		nodes.sort_by_key(|(key, _to)| *key);
 
-		nodes
-			.into_iter()
-			.map(|(_, _, _, to)| to)
-			.collect::<Vec<_>>()
+		nodes.into_iter().map(|(_, to)| to).collect::<Vec<_>>()
 	}
 }
 
@@ -624,6 +872,12 @@ pub struct QuorumSetResultTracker<S, E> {
 	pub failure_counters: Box<[usize]>,
 	/// The total number of nodes in each set
 	pub set_lens: Box<[usize]>,
+
+	/// The number of distinct zones in which successes must be
+	/// received in each set, in addition to the quorum (0 if none)
+	pub min_zones: usize,
+	/// The zone of each node, when a number of zones is required
+	pub zones: HashMap<Uuid, String>,
 }
 
 impl<S, E> QuorumSetResultTracker<S, E>
@@ -654,6 +908,58 @@ where
 				.map(|x| x.as_ref().len())
 				.collect::<Vec<_>>()
 				.into_boxed_slice(),
+			min_zones: 0,
+			zones: HashMap::new(),
+		}
+	}
+
+	/// Require the successes in each set to come from nodes in at least
+	/// `min_zones` distinct zones, or from nodes in all of the zones of the
+	/// set if it spans fewer zones
+	pub fn require_zones<F>(&mut self, min_zones: usize, zone_of: F)
+	where
+		F: Fn(&Uuid) -> Option<String>,
+	{
+		self.min_zones = min_zones;
+		self.zones = self
+			.nodes
+			.keys()
+			.filter_map(|n| Some((*n, zone_of(n)?)))
+			.collect();
+	}
+
+	/// Number of distinct zones of the nodes of a set for which `filter` returns true
+	fn zones_in_set<F>(&self, set: usize, filter: F) -> usize
+	where
+		F: Fn(&Uuid) -> bool,
+	{
+		self.nodes
+			.iter()
+			.filter(|(n, sets)| sets.contains(&set) && filter(n))
+			.filter_map(|(n, _)| self.zones.get(n))
+			.collect::<HashSet<_>>()
+			.len()
+	}
+
+	/// Number of distinct zones in which successes must be received in a set
+	fn required_zones(&self, set: usize) -> usize {
+		std::cmp::min(self.min_zones, self.zones_in_set(set, |_| true))
+	}
+
+	fn zones_ok<F>(&self, filter: F) -> bool
+	where
+		F: Fn(&Uuid) -> bool,
+	{
+		self.min_zones == 0
+			|| (0..self.set_lens.len())
+				.all(|set| self.zones_in_set(set, &filter) >= self.required_zones(set))
+	}
+
+	/// Add nodes to which requests are sent, but whose responses
+	/// are not counted in any of the quorum sets
+	pub fn add_non_voting_nodes(&mut self, nodes: &[Uuid]) {
+		for node in nodes.iter() {
+			self.nodes.entry(*node).or_default();
 		}
 	}
 
@@ -674,10 +980,28 @@ where
 		}
 	}
 
+	/// Check if a quorum can be reached in all sets, using only the nodes
+	/// for which `is_up` returns true
+	pub fn quorum_reachable<F>(&self, is_up: F) -> bool
+	where
+		F: Fn(&Uuid) -> bool,
+	{
+		let mut up_counters = vec![0; self.set_lens.len()];
+		for (node, sets) in self.nodes.iter() {
+			if is_up(node) {
+				for set in sets.iter() {
+					up_counters[*set] += 1;
+				}
+			}
+		}
+		up_counters.iter().all(|up_cnt| *up_cnt >= self.quorum) && self.zones_ok(is_up)
+	}
+
 	pub fn all_quorums_ok(&self) -> bool {
 		self.success_counters
 			.iter()
 			.all(|ok_cnt| *ok_cnt >= self.quorum)
+			&& self.zones_ok(|n| self.successes.iter().any(|(x, _)| x == n))
 	}
 
 	pub fn too_many_failures(&self) -> bool {
@@ -685,6 +1009,7 @@ where
 			.iter()
 			.zip(self.set_lens.iter())
 			.any(|(err_cnt, set_len)| *err_cnt + self.quorum > *set_len)
+			|| !self.zones_ok(|n| !self.failures.iter().any(|(x, _)| x == n))
 	}
 
 	pub fn success_values(self) -> Vec<S> {
@@ -709,3 +1034,199 @@ where
 		)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_zone_distance() {
+		assert_eq!(
+			ZoneDistance::of(Some("dc1"), true, Some("dc1")),
+			ZoneDistance::Local
+		);
+		assert_eq!(
+			ZoneDistance::of(Some("dc1"), false, Some("dc1")),
+			ZoneDistance::SameZone
+		);
+		assert_eq!(
+			ZoneDistance::of(Some("dc1"), false, Some("dc2")),
+			ZoneDistance::OtherZone
+		);
+		// A gateway without a role is in no zone
+		assert_eq!(ZoneDistance::of(None, false, None), ZoneDistance::OtherZone);
+		assert_eq!(ZoneDistance::of(None, true, None), ZoneDistance::Local);
+	}
+
+	#[test]
+	fn test_request_order_key() {
+		let ms = Duration::from_millis;
+		let mut nodes = vec![
+			(ZoneDistance::OtherZone, ms(2), "far_fast"),
+			(ZoneDistance::SameZone, ms(20), "near_slow"),
+			(ZoneDistance::Local, ms(50), "local"),
+			(ZoneDistance::SameZone, ms(10), "near_fast"),
+			(ZoneDistance::OtherZone, ms(30), "far_slow"),
+		];
+		let order = |nodes: &mut Vec<(ZoneDistance, Duration, &'static str)>, prefer: bool| {
+			nodes.sort_by_key(|(d, ping, _)| request_order_key(*d, *ping, prefer));
+			nodes.iter().map(|(_, _, n)| *n).collect::<Vec<_>>()
+		};
+
+		assert_eq!(
+			order(&mut nodes, true),
+			vec!["local", "near_fast", "near_slow", "far_fast", "far_slow"]
+		);
+		assert_eq!(
+			order(&mut nodes, false),
+			vec!["local", "far_fast", "near_fast", "near_slow", "far_slow"]
+		);
+	}
+
+	#[test]
+	fn test_rpc_timeouts() {
+		let timeouts = RpcTimeouts::new(None, &RpcTimeoutsConfig::default());
+		assert_eq!(timeouts.get(RpcClass::MetadataRead), DEFAULT_TIMEOUT);
+		assert_eq!(timeouts.get(RpcClass::BlockPut), DEFAULT_TIMEOUT);
+
+		let config = RpcTimeoutsConfig {
+			metadata_read_msec: Some(2000),
+			block_put_msec: Some(600_000),
+			..Default::default()
+		};
+		let timeouts = RpcTimeouts::new(Some(30_000), &config);
+		assert_eq!(timeouts.get(RpcClass::MetadataRead), Duration::from_secs(2));
+		assert_eq!(
+			timeouts.get(RpcClass::MetadataWrite),
+			Duration::from_secs(30)
+		);
+		assert_eq!(timeouts.get(RpcClass::BlockGet), Duration::from_secs(30));
+		assert_eq!(timeouts.get(RpcClass::BlockPut), Duration::from_secs(600));
+		assert_eq!(timeouts.get(RpcClass::Sync), Duration::from_secs(30));
+	}
+
+	#[test]
+	fn test_request_strategy_timeout() {
+		let config = RpcTimeoutsConfig {
+			metadata_read_msec: Some(2000),
+			block_put_msec: Some(600_000),
+			..Default::default()
+		};
+		let timeouts = RpcTimeouts::new(Some(30_000), &config);
+		let strat = || RequestStrategy::with_priority(PRIO_NORMAL);
+
+		assert_eq!(
+			timeouts.resolve(strat().rs_timeout),
+			Some(Duration::from_secs(30))
+		);
+		assert_eq!(
+			timeouts.resolve(
+				strat()
+					.with_timeout_class(RpcClass::MetadataRead)
+					.rs_timeout
+			),
+			Some(Duration::from_secs(2))
+		);
+		assert_eq!(
+			timeouts.resolve(strat().with_timeout_class(RpcClass::BlockPut).rs_timeout),
+			Some(Duration::from_secs(600))
+		);
+		assert_eq!(
+			timeouts.resolve(
+				strat()
+					.with_timeout_class(RpcClass::BlockPut)
+					.with_custom_timeout(Duration::from_secs(1))
+					.rs_timeout
+			),
+			Some(Duration::from_secs(1))
+		);
+		assert_eq!(timeouts.resolve(strat().without_timeout().rs_timeout), None);
+	}
+
+	#[test]
+	fn test_quorum_non_voting_nodes() {
+		let nodes = (0u8..4).map(|i| Uuid::from([i; 32])).collect::<Vec<_>>();
+		let mut tracker = QuorumSetResultTracker::<(), String>::new(&[&nodes[..3]], 2);
+		tracker.add_non_voting_nodes(&nodes[3..]);
+		assert_eq!(tracker.nodes.len(), 4);
+
+		// A failing non-voting node does not count against the quorum
+		tracker.register_result(nodes[3], Err("down".into()));
+		tracker.register_result(nodes[0], Err("down".into()));
+		assert!(!tracker.too_many_failures());
+
+		tracker.register_result(nodes[1], Ok(()));
+		assert!(!tracker.all_quorums_ok());
+		tracker.register_result(nodes[2], Ok(()));
+		assert!(tracker.all_quorums_ok());
+	}
+
+	#[test]
+	fn test_quorum_reachable() {
+		let nodes = (0u8..5).map(|i| Uuid::from([i; 32])).collect::<Vec<_>>();
+		let up = |up: &'static [u8]| move |n: &Uuid| up.contains(&n.as_slice()[0]);
+
+		let tracker = QuorumSetResultTracker::<(), String>::new(&[&nodes[..3]], 2);
+		assert!(tracker.quorum_reachable(up(&[0, 1, 2])));
+		assert!(tracker.quorum_reachable(up(&[0, 2])));
+		assert!(!tracker.quorum_reachable(up(&[1])));
+		assert!(!tracker.quorum_reachable(up(&[])));
+
+		// During a layout change, a quorum must be reachable in all sets
+		let tracker = QuorumSetResultTracker::<(), String>::new(&[&nodes[..3], &nodes[2..5]], 2);
+		assert!(tracker.quorum_reachable(up(&[0, 2, 3])));
+		assert!(!tracker.quorum_reachable(up(&[0, 1, 3])));
+		assert!(!tracker.quorum_reachable(up(&[2, 3, 4])));
+
+		// Non-voting nodes do not help reaching a quorum
+		let mut tracker = QuorumSetResultTracker::<(), String>::new(&[&nodes[..3]], 2);
+		tracker.add_non_voting_nodes(&nodes[3..]);
+		assert!(!tracker.quorum_reachable(up(&[0, 3, 4])));
+	}
+
+	#[test]
+	fn test_write_min_zones() {
+		// Nodes 0 and 1 are in zone a, node 2 in zone b, node 3 has no role
+		let nodes = (0u8..4).map(|i| Uuid::from([i; 32])).collect::<Vec<_>>();
+		let zone_of = |n: &Uuid| match n.as_slice()[0] {
+			0 | 1 => Some("a".to_string()),
+			2 => Some("b".to_string()),
+			_ => None,
+		};
+
+		// A quorum of two nodes in the same zone is not enough,
+		// the write waits for the node in the other zone
+		let mut tracker = QuorumSetResultTracker::<(), String>::new(&[&nodes[..3]], 2);
+		tracker.require_zones(2, zone_of);
+		tracker.register_result(nodes[0], Ok(()));
+		tracker.register_result(nodes[1], Ok(()));
+		assert!(!tracker.all_quorums_ok());
+		assert!(!tracker.too_many_failures());
+		tracker.register_result(nodes[2], Ok(()));
+		assert!(tracker.all_quorums_ok());
+
+		// The write fails as soon as the only node of a zone fails
+		let mut tracker = QuorumSetResultTracker::<(), String>::new(&[&nodes[..3]], 2);
+		tracker.require_zones(2, zone_of);
+		tracker.register_result(nodes[2], Err("timeout".into()));
+		assert!(tracker.too_many_failures());
+
+		// The zones of the nodes that are up are also checked beforehand
+		let up = |up: &'static [u8]| move |n: &Uuid| up.contains(&n.as_slice()[0]);
+		assert!(!tracker.quorum_reachable(up(&[0, 1])));
+		assert!(tracker.quorum_reachable(up(&[0, 2])));
+
+		// A set whose nodes are all in the same zone only requires that zone
+		let mut tracker = QuorumSetResultTracker::<(), String>::new(&[&nodes[..2]], 2);
+		tracker.require_zones(2, zone_of);
+		tracker.register_result(nodes[0], Ok(()));
+		tracker.register_result(nodes[1], Ok(()));
+		assert!(tracker.all_quorums_ok());
+
+		// Without a zone requirement, the node quorum is enough
+		let mut tracker = QuorumSetResultTracker::<(), String>::new(&[&nodes[..3]], 2);
+		tracker.register_result(nodes[0], Ok(()));
+		tracker.register_result(nodes[1], Ok(()));
+		assert!(tracker.all_quorums_ok());
+	}
+}
@@ -3,11 +3,13 @@ use std::collections::{HashMap, HashSet};
 use std::io::{Read, Write};
 use std::net::{IpAddr, SocketAddr};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, RwLock, RwLockReadGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
 use std::time::{Duration, Instant};
 
 use arc_swap::ArcSwapOption;
 use async_trait::async_trait;
+use futures::future::{join_all, FutureExt};
 use futures::join;
 use serde::{Deserialize, Serialize};
 use sodiumoxide::crypto::sign::ed25519;
@@ -22,11 +24,12 @@ use garage_net::{NetApp, NetworkKey, NodeID, NodeKey};
 
 #[cfg(feature = "kubernetes-discovery")]
 use garage_util::config::KubernetesDiscoveryConfig;
-use garage_util::config::{Config, DataDirEnum};
+use garage_util::config::{Config, DataDirEnum, ReadRepairConfig};
 use garage_util::data::*;
 use garage_util::error::*;
 use garage_util::persister::Persister;
 use garage_util::time::*;
+use garage_util::version::{garage_features, garage_version};
 
 #[cfg(feature = "consul-discovery")]
 use crate::consul::ConsulDiscovery;
@@ -35,13 +38,31 @@ use crate::kubernetes::*;
 use crate::layout::{
 	self, manager::LayoutManager, LayoutHelper, LayoutHistory, NodeRoleV, RpcLayoutDigest,
 };
+use crate::minority_partition::*;
+use crate::peer_state::*;
 use crate::replication_mode::*;
 use crate::rpc_helper::*;
 
 use crate::system_metrics::*;
 
 const DISCOVERY_INTERVAL: Duration = Duration::from_secs(60);
+/// Delay before retrying a discovery step when some nodes are still missing,
+/// doubled after each step up to DISCOVERY_INTERVAL
+const DISCOVERY_RETRY_MIN_DELAY: Duration = Duration::from_secs(1);
+/// Timeout for resolving the address of each bootstrap peer
+const PEER_RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Timeout for establishing a connection to each discovered peer
+const PEER_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 const STATUS_EXCHANGE_INTERVAL: Duration = Duration::from_secs(10);
+const MINORITY_PARTITION_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_RPC_SLOW_THRESHOLD_MSEC: u64 = 1000;
+/// Delay by which the system clock must be behind the latest timestamp
+/// issued for the node to be reported as having its clock behind, by default
+const DEFAULT_CLOCK_BACKWARDS_THRESHOLD_MSEC: u64 = 5000;
+/// Number of failed connection attempts to a node after which its address
+/// is looked up again in the discovery sources, by default
+const DEFAULT_RPC_REDISCOVERY_THRESHOLD: usize = 2;
 
 /// Version tag used for version check upon Netapp connection.
 /// Cluster nodes with different version tags are deemed
@@ -74,6 +95,10 @@ pub enum SystemRpc {
 	PullClusterLayoutTrackers,
 	/// Advertisement of cluster layout update trackers.
 	AdvertiseClusterLayoutTrackers(layout::UpdateTrackers),
+
+	/// Request to close the connection to a node and reconnect to it.
+	/// Answered with ReturnKnownNodes containing the new state of that node.
+	Reconnect(Uuid),
 }
 
 impl Rpc for SystemRpc {
@@ -84,18 +109,27 @@ impl Rpc for SystemRpc {
 pub struct PeerList(Vec<(Uuid, SocketAddr)>);
 impl garage_util::migrate::InitialFormat for PeerList {}
 
+/// Latest timestamp issued by this node, saved so that the timestamps
+/// issued after a restart are not before it even if the clock went backwards
+#[derive(Serialize, Deserialize)]
+pub struct LatestTimestamp(u64);
+impl garage_util::migrate::InitialFormat for LatestTimestamp {}
+
 /// This node's membership manager
 pub struct System {
 	/// The id of this node
 	pub id: Uuid,
 
 	persist_peer_list: Persister<PeerList>,
+	persist_latest_timestamp: Persister<LatestTimestamp>,
+	clock_backwards_threshold_msec: u64,
 
 	pub(crate) local_status: RwLock<NodeStatus>,
 	node_status: RwLock<HashMap<Uuid, (u64, NodeStatus)>>,
+	peer_states: Mutex<PeerStateTracker>,
 
 	pub netapp: Arc<NetApp>,
-	peering: Arc<PeeringManager>,
+	pub(crate) peering: Arc<PeeringManager>,
 
 	pub(crate) system_endpoint: Arc<Endpoint<SystemRpc, System>>,
 
@@ -114,6 +148,17 @@ pub struct System {
 
 	pub(crate) replication_factor: ReplicationFactor,
 
+	/// Read repair settings of the tables
+	pub read_repair: ReadRepairConfig,
+
+	/// Whether this node refuses writes because it is partitioned
+	/// from the majority of the cluster layout
+	pub minority_partition: MinorityPartitionGuard,
+
+	/// Whether the node has been connected to enough nodes to reach
+	/// quorum on all partitions since it started
+	ready: AtomicBool,
+
 	/// Path to metadata directory
 	pub metadata_dir: PathBuf,
 	/// Path to data directory
@@ -137,6 +182,37 @@ pub struct NodeStatus {
 	/// Disk usage on partition containing data directory (tuple: `(avail, total)`)
 	#[serde(default)]
 	pub data_disk_avail: Option<(u64, u64)>,
+
+	/// The node refuses writes because it is partitioned from the
+	/// majority of the cluster layout
+	#[serde(default)]
+	pub minority_partition: bool,
+
+	/// The S3 API of the node refuses all requests that modify
+	/// buckets or objects (`read_only` in `[s3_api]`)
+	#[serde(default)]
+	pub read_only_api: bool,
+
+	/// The data storage of the node is degraded after repeated I/O
+	/// errors, and the node does not accept block writes
+	#[serde(default)]
+	pub data_storage_degraded: bool,
+
+	/// Number of milliseconds by which the system clock of the node
+	/// is behind the latest timestamp it issued, if it is above
+	/// `clock_backwards_threshold_msec`
+	#[serde(default)]
+	pub clock_behind_msec: Option<u64>,
+
+	/// Version of the Garage daemon running on the node
+	#[serde(default)]
+	pub garage_version: Option<String>,
+	/// Features the Garage daemon of the node was built with
+	#[serde(default)]
+	pub garage_features: Option<Vec<String>>,
+	/// Consistency mode configured on the node
+	#[serde(default)]
+	pub consistency_mode: Option<ConsistencyMode>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -146,6 +222,15 @@ pub struct KnownNodeInfo {
 	pub is_up: bool,
 	pub last_seen_secs_ago: Option<u64>,
 	pub status: NodeStatus,
+	/// Detailed state of the node, for reporting purposes
+	#[serde(default)]
+	pub state: PeerState,
+	/// Time since the node entered its current state
+	#[serde(default)]
+	pub state_since_secs_ago: Option<u64>,
+	/// Time since the node last answered one of our RPCs
+	#[serde(default)]
+	pub last_rpc_secs_ago: Option<u64>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -258,6 +343,7 @@ impl System {
 			.filter(|x| x.rpc_bind_outgoing)
 			.map(|x| x.rpc_bind_addr.ip());
 		let netapp = NetApp::new(GARAGE_VERSION_TAG, network_key, node_key, bind_outgoing_to);
+		netapp.set_frame_checksums(config.rpc.frame_checksums);
 		let system_endpoint = netapp.endpoint(SYSTEM_RPC_PATH.into());
 
 		// ---- setup netapp public listener and full mesh peering strategy ----
@@ -273,6 +359,20 @@ impl System {
 
 		let persist_peer_list = Persister::new(&config.metadata_dir, "peer_list");
 
+		// ---- make sure timestamps do not go backwards across restarts ----
+		let persist_latest_timestamp =
+			Persister::<LatestTimestamp>::new(&config.metadata_dir, "latest_timestamp");
+		if let Ok(LatestTimestamp(latest)) = persist_latest_timestamp.load() {
+			restore_latest_timestamp(latest);
+			let behind = clock_behind_msec();
+			if behind > 0 {
+				warn!(
+					"The system clock is {} ms behind the latest timestamp issued before this node restarted.",
+					behind
+				);
+			}
+		}
+
 		// ---- setup cluster layout and layout manager ----
 		let layout_manager = LayoutManager::new(
 			config,
@@ -283,7 +383,9 @@ impl System {
 			consistency_mode,
 		)?;
 
-		let mut local_status = NodeStatus::initial(replication_factor, &layout_manager);
+		let mut local_status =
+			NodeStatus::initial(replication_factor, consistency_mode, &layout_manager);
+		local_status.read_only_api = config.s3_api.read_only;
 		local_status.update_disk_usage(&config.metadata_dir, &config.data_dir);
 
 		// ---- if enabled, set up additionnal peer discovery methods ----
@@ -309,8 +411,17 @@ impl System {
 		let sys = Arc::new(System {
 			id: netapp.id.into(),
 			persist_peer_list,
+			persist_latest_timestamp,
+			clock_backwards_threshold_msec: config
+				.clock_backwards_threshold_msec
+				.unwrap_or(DEFAULT_CLOCK_BACKWARDS_THRESHOLD_MSEC),
 			local_status: RwLock::new(local_status),
 			node_status: RwLock::new(HashMap::new()),
+			peer_states: Mutex::new(PeerStateTracker::new(Duration::from_millis(
+				config
+					.rpc_slow_threshold_msec
+					.unwrap_or(DEFAULT_RPC_SLOW_THRESHOLD_MSEC),
+			))),
 			netapp: netapp.clone(),
 			peering: peering.clone(),
 			system_endpoint,
@@ -325,12 +436,34 @@ impl System {
 			layout_manager,
 			metrics: ArcSwapOption::new(None),
 
+			read_repair: config.read_repair.clone(),
+			minority_partition: MinorityPartitionGuard::new(&config.rpc),
+			ready: AtomicBool::new(false),
 			metadata_dir: config.metadata_dir.clone(),
 			data_dir: config.data_dir.clone(),
 		});
 
 		sys.system_endpoint.set_handler(sys.clone());
 
+		// When connections to a node at its known addresses keep failing,
+		// its address might have changed: look it up in the discovery sources
+		let sys2 = Arc::downgrade(&sys);
+		sys.peering.set_address_resolver(
+			config
+				.rpc_rediscovery_threshold
+				.unwrap_or(DEFAULT_RPC_REDISCOVERY_THRESHOLD),
+			Arc::new(move |id| {
+				let sys = sys2.clone();
+				async move {
+					match sys.upgrade() {
+						Some(sys) => sys.discover_node_addrs(id).await,
+						None => vec![],
+					}
+				}
+				.boxed()
+			}),
+		);
+
 		let metrics = SystemMetrics::new(sys.clone());
 		sys.metrics.store(Some(Arc::new(metrics)));
 
@@ -348,6 +481,7 @@ impl System {
 			self.peering.clone().run(must_exit.clone()),
 			self.discovery_loop(must_exit.clone()),
 			self.status_exchange_loop(must_exit.clone()),
+			self.minority_partition_loop(must_exit.clone()),
 		);
 	}
 
@@ -370,36 +504,92 @@ impl System {
 		&self.layout_manager.rpc_helper
 	}
 
+	/// Set whether the data storage of this node is degraded,
+	/// which is advertised to other nodes in the status of the node
+	pub fn set_data_storage_degraded(&self, degraded: bool) {
+		self.local_status.write().unwrap().data_storage_degraded = degraded;
+	}
+
 	// ---- Administrative operations (directly available and
 	//      also available through RPC) ----
 
 	pub fn get_known_nodes(&self) -> Vec<KnownNodeInfo> {
+		let now = now_msec();
+		let secs_ago = |t: u64| now.saturating_sub(t) / 1000;
+
 		let node_status = self.node_status.read().unwrap();
+		let peer_states = self.peer_states.lock().unwrap();
 		let known_nodes = self
 			.peering
 			.get_peer_list()
 			.iter()
-			.map(|n| KnownNodeInfo {
-				id: n.id.into(),
-				addr: match n.state {
-					PeerConnState::Ourself => self.rpc_public_addr,
-					PeerConnState::Connected { addr } => Some(addr),
-					_ => None,
-				},
-				is_up: n.is_up(),
-				last_seen_secs_ago: n
-					.last_seen
-					.map(|t| (Instant::now().saturating_duration_since(t)).as_secs()),
-				status: node_status
-					.get(&n.id.into())
-					.cloned()
-					.map(|(_, st)| st)
-					.unwrap_or_else(NodeStatus::unknown),
+			.map(|n| {
+				let id: Uuid = n.id.into();
+				let state = peer_states.get(&id);
+				KnownNodeInfo {
+					id,
+					addr: match n.state {
+						PeerConnState::Ourself => self.rpc_public_addr,
+						PeerConnState::Connected { addr } => Some(addr),
+						_ => None,
+					},
+					is_up: n.is_up(),
+					last_seen_secs_ago: n
+						.last_seen
+						.map(|t| (Instant::now().saturating_duration_since(t)).as_secs()),
+					status: node_status
+						.get(&id)
+						.cloned()
+						.map(|(_, st)| st)
+						.unwrap_or_else(NodeStatus::unknown),
+					state: state.map(|s| s.state).unwrap_or_default(),
+					state_since_secs_ago: state.map(|s| secs_ago(s.since)),
+					last_rpc_secs_ago: self.rpc_helper().last_response_time(&id).map(secs_ago),
+				}
 			})
 			.collect::<Vec<_>>();
 		known_nodes
 	}
 
+	/// Update the state of all known nodes, logging state transitions
+	fn update_peer_states(&self) {
+		let layout = self.cluster_layout();
+		let now = now_msec();
+		let mut peer_states = self.peer_states.lock().unwrap();
+		for n in self.peering.get_peer_list().iter() {
+			let id: Uuid = n.id.into();
+			let layout_acked = !layout.all_nodes().contains(&id)
+				|| layout
+					.inner()
+					.update_trackers
+					.ack_map
+					.get(&id, layout.inner().min_stored())
+					>= layout.current().version;
+			let obs = PeerObservation {
+				is_ourself: matches!(n.state, PeerConnState::Ourself),
+				connected: n.is_up(),
+				avg_ping: n.avg_ping,
+				layout_acked,
+			};
+			match peer_states.observe(id, &obs, now) {
+				Some(t) if t.to == PeerState::Ourself => (),
+				Some(t) if t.to == PeerState::Up => info!(
+					"Node {:?} is now {} (was {})",
+					t.node,
+					t.to.as_str(),
+					t.from.as_str()
+				),
+				Some(t) => warn!(
+					"Node {:?} is now {} (was {})",
+					t.node,
+					t.to.as_str(),
+					t.from.as_str()
+				),
+				None => (),
+			}
+		}
+	}
+
 	pub async fn connect(&self, node: &str) -> Result<(), Error> {
 		let (pubkey, addrs) = parse_and_resolve_peer_addr_async(node)
 			.await
@@ -428,6 +618,18 @@ impl System {
 		}
 	}
 
+	/// Drop the current connection to a node and establish a new one,
+	/// returning the state of the node after reconnection
+	pub async fn reconnect(&self, node: Uuid) -> Result<KnownNodeInfo, Error> {
+		self.peering
+			.reconnect(&node.into(), RECONNECT_TIMEOUT)
+			.await?;
+		self.get_known_nodes()
+			.into_iter()
+			.find(|n| n.id == node)
+			.ok_or_else(|| Error::Message(format!("Unknown node: {:?}", node)))
+	}
+
 	pub fn health(&self) -> ClusterHealth {
 		let quorum = self
 			.replication_factor
@@ -558,7 +760,18 @@ impl System {
 	fn update_local_status(&self) {
 		let mut local_status = self.local_status.write().unwrap();
 		local_status.layout_digest = self.layout_manager.layout().digest();
+		local_status.minority_partition = self.minority_partition.is_partitioned();
 		local_status.update_disk_usage(&self.metadata_dir, &self.data_dir);
+		local_status.clock_behind_msec =
+			Some(clock_behind_msec()).filter(|x| *x >= self.clock_backwards_threshold_msec);
+		drop(local_status);
+
+		if let Err(e) = self
+			.persist_latest_timestamp
+			.save(&LatestTimestamp(latest_timestamp()))
+		{
+			warn!("Could not save latest timestamp: {}", e);
+		}
 	}
 
 	// --- RPC HANDLERS ---
@@ -568,6 +781,11 @@ impl System {
 		Ok(SystemRpc::Ok)
 	}
 
+	async fn handle_reconnect(&self, node: Uuid) -> Result<SystemRpc, Error> {
+		let info = self.reconnect(node).await?;
+		Ok(SystemRpc::ReturnKnownNodes(vec![info]))
+	}
+
 	fn handle_get_known_nodes(&self) -> SystemRpc {
 		let known_nodes = self.get_known_nodes();
 		SystemRpc::ReturnKnownNodes(known_nodes)
@@ -606,6 +824,7 @@ impl System {
 
 			// Update local node status that is exchanged.
 			self.update_local_status();
+			self.update_peer_states();
 
 			let local_status: NodeStatus = self.local_status.read().unwrap().clone();
 			let _ = self
@@ -625,8 +844,82 @@ impl System {
 		}
 	}
 
+	fn update_minority_partition(&self) {
+		let (reachable, total) = {
+			let layout = self.cluster_layout();
+			let nodes = layout.current().all_nodes();
+			let peers = self.peering.get_peer_list();
+			let reachable = nodes
+				.iter()
+				.filter(|id| {
+					**id == self.id || peers.iter().any(|p| Uuid::from(p.id) == **id && p.is_up())
+				})
+				.count();
+			(reachable, nodes.len())
+		};
+
+		match self.minority_partition.update(reachable, total) {
+			Some(true) => {
+				warn!(
+					"This node can only reach {} of the {} nodes of the cluster layout, refusing writes until connectivity recovers",
+					reachable, total
+				);
+			}
+			Some(false) => {
+				info!(
+					"This node can reach {} of the {} nodes of the cluster layout again, accepting writes",
+					reachable, total
+				);
+			}
+			None => (),
+		}
+	}
+
+	async fn minority_partition_loop(&self, mut stop_signal: watch::Receiver<bool>) {
+		if !self.minority_partition.is_enabled() {
+			return;
+		}
+		while !*stop_signal.borrow() {
+			self.update_minority_partition();
+
+			select! {
+				_ = tokio::time::sleep(MINORITY_PARTITION_CHECK_INTERVAL) => {},
+				_ = stop_signal.changed() => {},
+			}
+		}
+	}
+
+	/// Whether the node has been connected to enough nodes to reach
+	/// quorum on all partitions since it started
+	pub fn is_ready(&self) -> bool {
+		self.ready.load(Ordering::Relaxed)
+	}
+
+	/// Mark the node as ready as soon as it is connected to enough nodes
+	/// to reach quorum on all partitions, and notify the service manager.
+	/// A node that is not part of a configured layout has no nodes to wait
+	/// for and is ready immediately.
+	fn check_ready(&self) {
+		if self.is_ready() {
+			return;
+		}
+		let configured = self.cluster_layout().current().version > 0;
+		if configured && self.health().status == ClusterHealthStatus::Unavailable {
+			return;
+		}
+		if !self.ready.swap(true, Ordering::Relaxed) {
+			info!("Connected to enough nodes to reach quorum on all partitions, node is ready");
+			if let Err(e) = notify_service_ready() {
+				warn!("Could not notify service manager that node is ready: {}", e);
+			}
+		}
+	}
+
 	async fn discovery_loop(self: &Arc<Self>, mut stop_signal: watch::Receiver<bool>) {
+		let mut retry_delay = DISCOVERY_RETRY_MIN_DELAY;
 		while !*stop_signal.borrow() {
+			self.check_ready();
+
 			let n_connected = self
 				.peering
 				.get_peer_list()
@@ -639,30 +932,9 @@ impl System {
 			let expected_n_nodes = self.cluster_layout().all_nodes().len();
 			let bad_peers = n_connected != expected_n_nodes;
 
-			if not_configured || no_peers || bad_peers {
+			let delay = if not_configured || no_peers || bad_peers {
 				info!("Doing a bootstrap/discovery step (not_configured: {}, no_peers: {}, bad_peers: {})", not_configured, no_peers, bad_peers);
 
-				let mut ping_list = resolve_peers(&self.bootstrap_peers).await;
-
-				// Add peer list from list stored on disk
-				if let Ok(peers) = self.persist_peer_list.load_async().await {
-					ping_list.extend(peers.0.iter().map(|(id, addr)| ((*id).into(), *addr)))
-				}
-
-				// Fetch peer list from Consul
-				#[cfg(feature = "consul-discovery")]
-				if let Some(c) = &self.consul_discovery {
-					match c.get_consul_nodes().await {
-						Ok(node_list) => {
-							ping_list.extend(node_list);
-						}
-						Err(e) => {
-							warn!("Could not retrieve node list from Consul: {}", e);
-						}
-					}
-				}
-
-				// Fetch peer list from Kubernetes
 				#[cfg(feature = "kubernetes-discovery")]
 				if let Some(k) = &self.kubernetes_discovery {
 					if !k.skip_crd {
@@ -673,15 +945,13 @@ impl System {
 							}
 						};
 					}
+				}
 
-					match get_kubernetes_nodes(k).await {
-						Ok(node_list) => {
-							ping_list.extend(node_list);
-						}
-						Err(e) => {
-							warn!("Could not retrieve node list from Kubernetes: {}", e);
-						}
-					}
+				let mut ping_list = self.discover_peers().await;
+
+				// Add peer list from list stored on disk
+				if let Ok(peers) = self.persist_peer_list.load_async().await {
+					ping_list.extend(peers.0.iter().map(|(id, addr)| ((*id).into(), *addr)))
 				}
 
 				if !not_configured && !no_peers {
@@ -692,15 +962,34 @@ impl System {
 					ping_list.retain(|(id, _)| layout.all_nodes().contains(&(*id).into()));
 				}
 
+				// Connect to all peers in parallel, so that unreachable peers
+				// don't delay the connection to the other ones
 				for (node_id, node_addr) in ping_list {
 					let self2 = self.clone();
 					tokio::spawn(async move {
-						if let Err(e) = self2.netapp.clone().try_connect(node_addr, node_id).await {
-							error!("{}", connect_error_message(node_addr, node_id, e));
+						let connect = self2.netapp.clone().try_connect(node_addr, node_id);
+						match tokio::time::timeout(PEER_CONNECT_TIMEOUT, connect).await {
+							Ok(Ok(())) => self2.check_ready(),
+							Ok(Err(e)) => {
+								error!("{}", connect_error_message(node_addr, node_id, e))
+							}
+							Err(_) => error!(
+								"Timeout establishing RPC connection to remote node: {}@{}",
+								hex::encode(node_id),
+								node_addr
+							),
 						}
 					});
 				}
-			}
+
+				// Retry soon while nodes are missing, backing off exponentially
+				let delay = retry_delay;
+				retry_delay = std::cmp::min(retry_delay * 2, DISCOVERY_INTERVAL);
+				delay
+			} else {
+				retry_delay = DISCOVERY_RETRY_MIN_DELAY;
+				DISCOVERY_INTERVAL
+			};
 
 			if let Err(e) = self.save_peer_list().await {
 				warn!("Could not save peer list to file: {}", e);
@@ -713,12 +1002,56 @@ impl System {
 			tokio::spawn(self.clone().advertise_to_kubernetes());
 
 			select! {
-				_ = tokio::time::sleep(DISCOVERY_INTERVAL) => {},
+				_ = tokio::time::sleep(delay) => {},
 				_ = stop_signal.changed() => {},
 			}
 		}
 	}
 
+	/// Get the list of nodes and of their addresses from the bootstrap peers,
+	/// and from Consul and Kubernetes if they are used for discovery
+	async fn discover_peers(&self) -> Vec<(NodeID, SocketAddr)> {
+		let mut peers = resolve_peers(&self.bootstrap_peers).await;
+
+		// Fetch peer list from Consul
+		#[cfg(feature = "consul-discovery")]
+		if let Some(c) = &self.consul_discovery {
+			match c.get_consul_nodes().await {
+				Ok(node_list) => {
+					peers.extend(node_list);
+				}
+				Err(e) => {
+					warn!("Could not retrieve node list from Consul: {}", e);
+				}
+			}
+		}
+
+		// Fetch peer list from Kubernetes
+		#[cfg(feature = "kubernetes-discovery")]
+		if let Some(k) = &self.kubernetes_discovery {
+			match get_kubernetes_nodes(k).await {
+				Ok(node_list) => {
+					peers.extend(node_list);
+				}
+				Err(e) => {
+					warn!("Could not retrieve node list from Kubernetes: {}", e);
+				}
+			}
+		}
+
+		peers
+	}
+
+	/// Look up the current addresses of a node in the discovery sources
+	async fn discover_node_addrs(&self, node: NodeID) -> Vec<SocketAddr> {
+		self.discover_peers()
+			.await
+			.into_iter()
+			.filter(|(id, _)| *id == node)
+			.map(|(_, addr)| addr)
+			.collect()
+	}
+
 	async fn save_peer_list(&self) -> Result<(), Error> {
 		// Prepare new peer list to save to file
 		// It is a vec of tuples (node ID as Uuid, node SocketAddr)
@@ -757,6 +1090,7 @@ impl EndpointHandler<SystemRpc> for System {
 			SystemRpc::Connect(node) => self.handle_connect(node).await,
 			SystemRpc::AdvertiseStatus(adv) => self.handle_advertise_status(from.into(), adv).await,
 			SystemRpc::GetKnownNodes => Ok(self.handle_get_known_nodes()),
+			SystemRpc::Reconnect(node) => self.handle_reconnect(*node).await,
 
 			// ---- layout functions -> LayoutManager ----
 			SystemRpc::PullClusterLayout => Ok(self.layout_manager.handle_pull_cluster_layout()),
@@ -781,7 +1115,11 @@ impl EndpointHandler<SystemRpc> for System {
 }
 
 impl NodeStatus {
-	fn initial(replication_factor: ReplicationFactor, layout_manager: &LayoutManager) -> Self {
+	fn initial(
+		replication_factor: ReplicationFactor,
+		consistency_mode: ConsistencyMode,
+		layout_manager: &LayoutManager,
+	) -> Self {
 		NodeStatus {
 			hostname: Some(
 				gethostname::gethostname()
@@ -792,6 +1130,14 @@ impl NodeStatus {
 			layout_digest: layout_manager.layout().digest(),
 			meta_disk_avail: None,
 			data_disk_avail: None,
+			minority_partition: false,
+			read_only_api: false,
+			data_storage_degraded: false,
+			clock_behind_msec: None,
+			garage_version: Some(garage_version().to_string()),
+			garage_features: garage_features()
+				.map(|features| features.iter().map(ToString::to_string).collect()),
+			consistency_mode: Some(consistency_mode),
 		}
 	}
 
@@ -802,6 +1148,13 @@ impl NodeStatus {
 			layout_digest: Default::default(),
 			meta_disk_avail: None,
 			data_disk_avail: None,
+			minority_partition: false,
+			read_only_api: false,
+			data_storage_degraded: false,
+			clock_behind_msec: None,
+			garage_version: None,
+			garage_features: None,
+			consistency_mode: None,
 		}
 	}
 
@@ -915,23 +1268,59 @@ fn get_rpc_public_addr(config: &Config) -> Option<SocketAddr> {
 	}
 }
 
+/// Resolve the addresses of the bootstrap peers in parallel, with a timeout
+/// for each of them so that a slow DNS lookup does not delay the others
 async fn resolve_peers(peers: &[String]) -> Vec<(NodeID, SocketAddr)> {
-	let mut ret = vec![];
-
-	for peer in peers.iter() {
-		match parse_and_resolve_peer_addr_async(peer).await {
-			Some((pubkey, addrs)) => {
-				for ip in addrs {
-					ret.push((pubkey, ip));
-				}
-			}
-			None => {
+	let resolved = join_all(peers.iter().map(|peer| async move {
+		match tokio::time::timeout(
+			PEER_RESOLVE_TIMEOUT,
+			parse_and_resolve_peer_addr_async(peer),
+		)
+		.await
+		{
+			Ok(Some((pubkey, addrs))) => addrs.into_iter().map(|ip| (pubkey, ip)).collect(),
+			Ok(None) => {
 				warn!("Unable to parse and/or resolve peer hostname {}", peer);
+				vec![]
+			}
+			Err(_) => {
+				warn!("Timeout resolving peer hostname {}", peer);
+				vec![]
 			}
 		}
+	}))
+	.await;
+
+	resolved.into_iter().flatten().collect()
+}
+
+/// Tell the service manager that the node is ready, using the systemd
+/// notification protocol if the NOTIFY_SOCKET environment variable is set
+#[cfg(unix)]
+fn notify_service_ready() -> std::io::Result<()> {
+	use std::os::unix::net::UnixDatagram;
+
+	let path = match std::env::var_os("NOTIFY_SOCKET") {
+		Some(p) => p,
+		None => return Ok(()),
+	};
+	let socket = UnixDatagram::unbound()?;
+
+	#[cfg(target_os = "linux")]
+	if let Some(name) = path.to_str().and_then(|p| p.strip_prefix('@')) {
+		use std::os::linux::net::SocketAddrExt;
+		let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+		socket.send_to_addr(b"READY=1", &addr)?;
+		return Ok(());
 	}
 
-	ret
+	socket.send_to(b"READY=1", path)?;
+	Ok(())
+}
+
+#[cfg(not(unix))]
+fn notify_service_ready() -> std::io::Result<()> {
+	Ok(())
 }
 
 fn connect_error_message(
@@ -941,3 +1330,32 @@ fn connect_error_message(
 ) -> String {
 	format!("Error establishing RPC connection to remote node: {}@{}.\nThis can happen if the remote node is not reachable on the network, but also if the two nodes are not configured with the same rpc_secret.\n{}", hex::encode(pubkey), addr, e)
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn test_resolve_peers_skips_bad_entries() {
+		let key = hex::encode([1u8; 32]);
+		let peers = vec![
+			"not a peer".to_string(),
+			format!("{}@unresolvable-bootstrap-peer.invalid:3901", key),
+			format!("{}@127.0.0.1:3901", key),
+			format!("{}@[::1]:3902", key),
+		];
+
+		let start = Instant::now();
+		let resolved = resolve_peers(&peers).await;
+		assert!(start.elapsed() < PEER_RESOLVE_TIMEOUT + Duration::from_secs(1));
+
+		let addrs = resolved.iter().map(|(_, a)| *a).collect::<Vec<_>>();
+		assert_eq!(
+			addrs,
+			vec![
+				"127.0.0.1:3901".parse::<SocketAddr>().unwrap(),
+				"[::1]:3902".parse().unwrap()
+			]
+		);
+	}
+}
@@ -3,6 +3,8 @@ use std::time::{Duration, Instant};
 
 use opentelemetry::{global, metrics::*, KeyValue};
 
+use garage_util::data::Uuid;
+
 use crate::system::{ClusterHealthStatus, System};
 
 /// TableMetrics reference all counter used for metrics
@@ -25,10 +27,21 @@ pub struct SystemMetrics {
 	pub(crate) _partitions: ValueObserver<u64>,
 	pub(crate) _partitions_quorum: ValueObserver<u64>,
 	pub(crate) _partitions_all_ok: ValueObserver<u64>,
+	pub(crate) _minority_partition: ValueObserver<u64>,
 
 	// Status report for individual cluster nodes
 	pub(crate) _layout_node_connected: ValueObserver<u64>,
 	pub(crate) _layout_node_disconnected_time: ValueObserver<u64>,
+
+	// Peering
+	pub(crate) _node_address_refreshes: SumObserver<u64>,
+
+	// Flow control of RPC streams
+	pub(crate) _rpc_flow_control_credits: ValueObserver<u64>,
+	pub(crate) _rpc_flow_control_blocked_streams: ValueObserver<u64>,
+
+	// Checksums of RPC frames
+	pub(crate) _rpc_frame_checksum_failures: SumObserver<u64>,
 }
 
 impl SystemMetrics {
@@ -209,6 +222,21 @@ impl SystemMetrics {
 					.init()
 			},
 
+			_minority_partition: {
+				let system = system.clone();
+				meter
+					.u64_value_observer("cluster_minority_partition", move |observer| {
+						if system.minority_partition.is_enabled() {
+							let partitioned = system.minority_partition.is_partitioned();
+							observer.observe(partitioned as u64, &[]);
+						}
+					})
+					.with_description(
+						"Whether this node refuses writes because it is partitioned from the majority of the cluster layout",
+					)
+					.init()
+			},
+
 			// Status report for individual cluster nodes
 			_layout_node_connected: {
 				let system = system.clone();
@@ -297,6 +325,71 @@ impl SystemMetrics {
 					)
 					.init()
 			},
+
+			// Peering
+			_node_address_refreshes: {
+				let system = system.clone();
+				meter
+					.u64_sum_observer("cluster_node_address_refreshes", move |observer| {
+						observer.observe(system.peering.address_refreshes(), &[])
+					})
+					.with_description(
+						"Number of times the address of a node was looked up again after connection failures",
+					)
+					.init()
+			},
+
+			// Flow control of RPC streams
+			_rpc_flow_control_credits: {
+				let system = system.clone();
+				meter
+					.u64_value_observer("rpc_flow_control_credits", move |observer| {
+						for (id, stats) in system.netapp.flow_control_stats() {
+							if stats.enabled {
+								let kv = [KeyValue::new("peer", format!("{:?}", Uuid::from(id)))];
+								observer.observe(stats.outstanding_credits, &kv);
+							}
+						}
+					})
+					.with_description(
+						"Number of chunks of RPC streams that can be sent to each peer before it grants more credits",
+					)
+					.init()
+			},
+			_rpc_flow_control_blocked_streams: {
+				let system = system.clone();
+				meter
+					.u64_value_observer("rpc_flow_control_blocked_streams", move |observer| {
+						for (id, stats) in system.netapp.flow_control_stats() {
+							if stats.enabled {
+								let kv = [KeyValue::new("peer", format!("{:?}", Uuid::from(id)))];
+								observer.observe(stats.blocked_streams, &kv);
+							}
+						}
+					})
+					.with_description(
+						"Number of RPC streams to each peer that are waiting for it to grant more credits",
+					)
+					.init()
+			},
+
+			// Checksums of RPC frames
+			_rpc_frame_checksum_failures: {
+				let system = system.clone();
+				meter
+					.u64_sum_observer("rpc_frame_checksum_failures", move |observer| {
+						for (id, stats) in system.netapp.frame_checksum_stats() {
+							if stats.receiving {
+								let kv = [KeyValue::new("peer", format!("{:?}", Uuid::from(id)))];
+								observer.observe(stats.failures, &kv);
+							}
+						}
+					})
+					.with_description(
+						"Number of RPC frames received from each peer with an invalid checksum",
+					)
+					.init()
+			},
 		}
 	}
 }
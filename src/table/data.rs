@@ -2,6 +2,7 @@ use core::borrow::Borrow;
 use std::convert::TryInto;
 use std::sync::Arc;
 
+use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 use tokio::sync::Notify;
 
@@ -10,11 +11,12 @@ use garage_db as db;
 use garage_util::data::*;
 use garage_util::error::*;
 use garage_util::migrate::Migrate;
+use garage_util::persister::PersisterShared;
 
 use garage_rpc::system::System;
 
 use crate::crdt::Crdt;
-use crate::gc::GcTodoEntry;
+use crate::gc::{GcTodoEntry, TABLE_GC_BATCH_SIZE};
 use crate::metrics::*;
 use crate::replication::*;
 use crate::schema::*;
@@ -37,9 +39,30 @@ pub struct TableData<F: TableSchema, R: TableReplication> {
 
 	pub(crate) gc_todo: db::Tree,
 
+	pub(crate) worker_params: PersisterShared<TableWorkerParams>,
+
 	pub(crate) metrics: TableMetrics,
 }
 
+/// Parameters of the Merkle updater and GC workers of a table,
+/// that can be changed at runtime with `garage worker set`
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub(crate) struct TableWorkerParams {
+	pub(crate) merkle_tranquility: u32,
+	pub(crate) gc_paused: bool,
+	pub(crate) gc_batch_size: usize,
+}
+impl garage_util::migrate::InitialFormat for TableWorkerParams {}
+impl Default for TableWorkerParams {
+	fn default() -> Self {
+		TableWorkerParams {
+			merkle_tranquility: 0,
+			gc_paused: false,
+			gc_batch_size: TABLE_GC_BATCH_SIZE,
+		}
+	}
+}
+
 impl<F: TableSchema, R: TableReplication> TableData<F, R> {
 	pub fn new(system: Arc<System>, instance: F, replication: R, db: &db::Db) -> Arc<Self> {
 		let store = db
@@ -69,6 +92,11 @@ impl<F: TableSchema, R: TableReplication> TableData<F, R> {
 			gc_todo.clone(),
 		);
 
+		let worker_params = PersisterShared::new(
+			&system.metadata_dir,
+			&format!("{}_worker_params", F::TABLE_NAME),
+		);
+
 		Arc::new(Self {
 			system,
 			instance,
@@ -80,6 +108,7 @@ impl<F: TableSchema, R: TableReplication> TableData<F, R> {
 			insert_queue,
 			insert_queue_notify: Arc::new(Notify::new()),
 			gc_todo,
+			worker_params,
 			metrics,
 		})
 	}
@@ -130,6 +159,10 @@ impl<F: TableSchema, R: TableReplication> TableData<F, R> {
 		}
 	}
 
+	/// Build a page of entries from an iterator on the store. The iterator
+	/// holds a read transaction of the database (one of the limited reader
+	/// slots on LMDB) until it is dropped, so it is consumed here rather
+	/// than returned, and it is released before the page is sent.
 	fn read_range_aux(
 		&self,
 		partition_hash: Hash,
@@ -368,6 +401,6 @@ impl<F: TableSchema, R: TableReplication> TableData<F, R> {
 	}
 
 	pub fn gc_todo_len(&self) -> Result<usize, Error> {
-		Ok(self.gc_todo.len()?)
+		Ok(self.gc_todo.fast_len()?)
 	}
 }
@@ -24,7 +24,11 @@ use crate::data::*;
 use crate::replication::*;
 use crate::schema::*;
 
-const TABLE_GC_BATCH_SIZE: usize = 1024;
+// Default number of tombstones deleted in a batch,
+// can be changed with the gc-batch-size-<table> variable
+pub(crate) const TABLE_GC_BATCH_SIZE: usize = 1024;
+// Delay after which a paused GC worker checks whether it has been resumed
+const TABLE_GC_PAUSED_DELAY: Duration = Duration::from_secs(10);
 
 // GC delay for table entries: 1 day (24 hours)
 // (the delay before the entry is added in the GC todo list
@@ -71,6 +75,7 @@ impl<F: TableSchema, R: TableReplication> TableGc<F, R> {
 
 	async fn gc_loop_iter(&self) -> Result<Option<Duration>, Error> {
 		let now = now_msec();
+		let batch_size = self.data.worker_params.get_with(|p| p.gc_batch_size);
 
 		// List entries in the GC todo list
 		// These entries are put there when a tombstone is inserted in the table
@@ -94,7 +99,7 @@ impl<F: TableSchema, R: TableReplication> TableGc<F, R> {
 			}
 
 			candidates.push(todo_entry);
-			if candidates.len() >= 2 * TABLE_GC_BATCH_SIZE {
+			if candidates.len() >= 2 * batch_size {
 				break;
 			}
 		}
@@ -115,7 +120,7 @@ impl<F: TableSchema, R: TableReplication> TableGc<F, R> {
 
 			if todo_entry.value.is_some() {
 				entries.push(todo_entry);
-				if entries.len() >= TABLE_GC_BATCH_SIZE {
+				if entries.len() >= batch_size {
 					break;
 				}
 			} else {
@@ -232,7 +237,9 @@ impl<F: TableSchema, R: TableReplication> TableGc<F, R> {
 				&self.endpoint,
 				&nodes,
 				GcRpc::Update(updates),
-				RequestStrategy::with_priority(PRIO_BACKGROUND).with_quorum(nodes.len()),
+				RequestStrategy::with_priority(PRIO_BACKGROUND)
+					.with_quorum(nodes.len())
+					.with_timeout_class(RpcClass::MetadataWrite),
 			)
 			.await
 			.err_context("GC: send tombstones")?;
@@ -253,7 +260,9 @@ impl<F: TableSchema, R: TableReplication> TableGc<F, R> {
 				&self.endpoint,
 				&nodes,
 				GcRpc::DeleteIfEqualHash(deletes),
-				RequestStrategy::with_priority(PRIO_BACKGROUND).with_quorum(nodes.len()),
+				RequestStrategy::with_priority(PRIO_BACKGROUND)
+					.with_quorum(nodes.len())
+					.with_timeout_class(RpcClass::MetadataWrite),
 			)
 			.await
 			.err_context("GC: remote delete tombstones")?;
@@ -312,13 +321,23 @@ impl<F: TableSchema, R: TableReplication> Worker for GcWorker<F, R> {
 	}
 
 	fn status(&self) -> WorkerStatus {
+		let params = self.gc.data.worker_params.get_with(|p| *p);
+		let mut freeform = vec![format!("Batch size: {}", params.gc_batch_size)];
+		if params.gc_paused {
+			freeform.push("Paused".into());
+		}
 		WorkerStatus {
 			queue_length: Some(self.gc.data.gc_todo_len().unwrap_or(0) as u64),
+			freeform,
 			..Default::default()
 		}
 	}
 
 	async fn work(&mut self, _must_exit: &mut watch::Receiver<bool>) -> Result<WorkerState, Error> {
+		if self.gc.data.worker_params.get_with(|p| p.gc_paused) {
+			self.wait_delay = TABLE_GC_PAUSED_DELAY;
+			return Ok(WorkerState::Idle);
+		}
 		match self.gc.gc_loop_iter().await? {
 			None => Ok(WorkerState::Busy),
 			Some(delay) => {
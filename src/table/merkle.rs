@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -12,6 +13,7 @@ use garage_util::background::*;
 use garage_util::data::*;
 use garage_util::encode::{nonversioned_decode, nonversioned_encode};
 use garage_util::error::Error;
+use garage_util::tranquilizer::Tranquilizer;
 
 use garage_rpc::layout::*;
 
@@ -23,6 +25,10 @@ use crate::schema::*;
 // 16 bits (two bytes) of item's partition keys' hashes.
 // It builds one Merkle tree for each of these 2**16 partitions.
 
+// Maximum number of items of the todo queue applied to the Merkle tree
+// in a single transaction. All the items of a batch are in the same partition.
+const MERKLE_BATCH_SIZE: usize = 1000;
+
 pub struct MerkleUpdater<F: TableSchema, R: TableReplication> {
 	data: Arc<TableData<F, R>>,
 
@@ -52,7 +58,7 @@ pub struct MerkleNodeKey {
 	pub prefix: Vec<u8>,
 }
 
-#[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum MerkleNode {
 	// The empty Merkle node
 	Empty,
@@ -77,19 +83,200 @@ impl<F: TableSchema, R: TableReplication> MerkleUpdater<F, R> {
 	}
 
 	pub(crate) fn spawn_workers(self: &Arc<Self>, background: &BackgroundRunner) {
-		background.spawn_worker(MerkleWorker(self.clone()));
+		background.spawn_worker(MerkleWorker {
+			updater: self.clone(),
+			tranquilizer: Tranquilizer::new(30),
+		});
 	}
 
 	fn updater_loop_iter(&self) -> Result<WorkerState, Error> {
-		if let Some((key, valhash)) = self.data.merkle_todo.first()? {
-			self.update_item(&key, &valhash)?;
+		let items = self.todo_batch()?;
+		if items.is_empty() {
+			return Ok(WorkerState::Idle);
+		}
+		self.update_items(&items)?;
+		Ok(WorkerState::Busy)
+	}
+
+	fn partition_of_item(&self, k: &[u8]) -> Partition {
+		self.data
+			.replication
+			.partition_of(&Hash::try_from(&k[0..32]).unwrap())
+	}
+
+	/// Take the first items of the todo queue, stopping at the first item that
+	/// is in another partition than the first one
+	#[allow(clippy::type_complexity)]
+	fn todo_batch(&self) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Error> {
+		let mut items = vec![];
+		let mut partition = None;
+		for item in self.data.merkle_todo.iter()? {
+			let (k, vhash_by) = item?;
+			let item_partition = self.partition_of_item(&k);
+			if *partition.get_or_insert(item_partition) != item_partition {
+				break;
+			}
+			items.push((k, vhash_by));
+			if items.len() >= MERKLE_BATCH_SIZE {
+				break;
+			}
+		}
+		Ok(items)
+	}
+
+	/// Apply a batch of items of the todo queue, that are all in the same
+	/// partition, to the Merkle tree
+	fn update_items(&self, items: &[(Vec<u8>, Vec<u8>)]) -> Result<(), Error> {
+		let partition = self.partition_of_item(&items[0].0);
+
+		let node_hashes = self.data.merkle_tree.db().transaction(|tx| {
+			let mut batch =
+				MerkleBatch::new(&self.data.merkle_tree, F::TABLE_NAME, self.empty_node_hash);
+			for (k, vhash_by) in items.iter() {
+				batch.update_item(tx, partition, k, vhash_by)?;
+			}
+			batch.write(tx)
+		})?;
+		self.data.metrics.merkle_node_hash_counter.add(node_hashes);
+
+		let deleted = self.data.merkle_todo.db().transaction(|tx| {
+			let mut deleted = 0;
+			for (k, vhash_by) in items.iter() {
+				let remove =
+					matches!(tx.get(&self.data.merkle_todo, k)?, Some(ov) if ov == *vhash_by);
+				if remove {
+					tx.remove(&self.data.merkle_todo, k)?;
+					deleted += 1;
+				}
+			}
+			Ok(deleted)
+		})?;
+
+		if deleted < items.len() {
+			debug!(
+				"({}) {} items not deleted from Merkle todo because they changed",
+				F::TABLE_NAME,
+				items.len() - deleted
+			);
+		}
+		Ok(())
+	}
+
+	// Access a node in the Merkle tree, used by the sync protocol
+	pub(crate) fn read_node(&self, k: &MerkleNodeKey) -> Result<MerkleNode, Error> {
+		let ent = self.data.merkle_tree.get(k.encode())?;
+		MerkleNode::decode_opt(&ent)
+	}
+
+	pub fn merkle_tree_len(&self) -> Result<usize, Error> {
+		Ok(self.data.merkle_tree.fast_len()?)
+	}
+
+	pub fn todo_len(&self) -> Result<usize, Error> {
+		Ok(self.data.merkle_todo.fast_len()?)
+	}
+}
+
+struct MerkleWorker<F: TableSchema, R: TableReplication> {
+	updater: Arc<MerkleUpdater<F, R>>,
+	tranquilizer: Tranquilizer,
+}
+
+#[async_trait]
+impl<F: TableSchema, R: TableReplication> Worker for MerkleWorker<F, R> {
+	fn name(&self) -> String {
+		format!("{} Merkle", F::TABLE_NAME)
+	}
+
+	fn status(&self) -> WorkerStatus {
+		WorkerStatus {
+			tranquility: Some(
+				self.updater
+					.data
+					.worker_params
+					.get_with(|p| p.merkle_tranquility),
+			),
+			queue_length: Some(self.updater.todo_len().unwrap_or(0) as u64),
+			..Default::default()
+		}
+	}
+
+	async fn work(&mut self, _must_exit: &mut watch::Receiver<bool>) -> Result<WorkerState, Error> {
+		let tranquility = self
+			.updater
+			.data
+			.worker_params
+			.get_with(|p| p.merkle_tranquility);
+		self.tranquilizer.reset();
+
+		let updater = self.updater.clone();
+		let res = tokio::task::spawn_blocking(move || {
+			for _i in 0..10 {
+				let s = updater.updater_loop_iter();
+				if !matches!(s, Ok(WorkerState::Busy)) {
+					return s;
+				}
+			}
 			Ok(WorkerState::Busy)
-		} else {
-			Ok(WorkerState::Idle)
+		})
+		.await
+		.unwrap();
+
+		match res {
+			Ok(WorkerState::Busy) if tranquility > 0 => {
+				Ok(self.tranquilizer.tranquilize_worker(tranquility))
+			}
+			s => s,
 		}
 	}
 
-	fn update_item(&self, k: &[u8], vhash_by: &[u8]) -> Result<(), Error> {
+	async fn wait_for_work(&mut self) -> WorkerState {
+		select! {
+			_ = tokio::time::sleep(Duration::from_secs(60)) => (),
+			_ = self.updater.data.merkle_todo_notify.notified() => (),
+		}
+		WorkerState::Busy
+	}
+}
+
+// ---- batched updates ----
+
+/// Nodes of the Merkle tree of a partition that are read and modified while
+/// applying a batch of updates. Modified nodes are hashed and written to the
+/// database only once, when the whole batch has been applied, so that the nodes
+/// shared by the updated items (at least the root of the partition's tree)
+/// are hashed once per batch instead of once per item.
+struct MerkleBatch<'a> {
+	tree: &'a db::Tree,
+	table_name: &'static str,
+	empty_node_hash: Hash,
+
+	// Nodes read or modified during this batch, by encoded key.
+	// In modified intermediate nodes, the hashes of modified children
+	// are not up to date until the batch is written.
+	nodes: HashMap<Vec<u8>, MerkleNode>,
+	// Keys of the nodes modified during this batch
+	modified: HashMap<Vec<u8>, MerkleNodeKey>,
+}
+
+impl<'a> MerkleBatch<'a> {
+	fn new(tree: &'a db::Tree, table_name: &'static str, empty_node_hash: Hash) -> Self {
+		Self {
+			tree,
+			table_name,
+			empty_node_hash,
+			nodes: HashMap::new(),
+			modified: HashMap::new(),
+		}
+	}
+
+	fn update_item(
+		&mut self,
+		tx: &mut db::Transaction<'_>,
+		partition: Partition,
+		k: &[u8],
+		vhash_by: &[u8],
+	) -> db::TxResult<(), Error> {
 		let khash = blake2sum(k);
 
 		let new_vhash = if vhash_by.is_empty() {
@@ -99,84 +286,65 @@ impl<F: TableSchema, R: TableReplication> MerkleUpdater<F, R> {
 		};
 
 		let key = MerkleNodeKey {
-			partition: self
-				.data
-				.replication
-				.partition_of(&Hash::try_from(&k[0..32]).unwrap()),
+			partition,
 			prefix: vec![],
 		};
-		self.data
-			.merkle_tree
-			.db()
-			.transaction(|tx| self.update_item_rec(tx, k, &khash, &key, new_vhash))?;
-
-		let deleted = self.data.merkle_todo.db().transaction(|tx| {
-			let remove = matches!(tx.get(&self.data.merkle_todo, k)?, Some(ov) if ov == vhash_by);
-			if remove {
-				tx.remove(&self.data.merkle_todo, k)?;
-			}
-			Ok(remove)
-		})?;
-
-		if !deleted {
-			debug!(
-				"({}) Item not deleted from Merkle todo because it changed: {:?}",
-				F::TABLE_NAME,
-				k
-			);
-		}
+		self.update_item_rec(tx, k, &khash, &key, new_vhash)?;
 		Ok(())
 	}
 
+	/// Returns None if the node at `key` is unchanged, or Some(true)
+	/// if it has been modified and is now empty, Some(false) otherwise
 	fn update_item_rec(
-		&self,
+		&mut self,
 		tx: &mut db::Transaction<'_>,
 		k: &[u8],
 		khash: &Hash,
 		key: &MerkleNodeKey,
 		new_vhash: Option<Hash>,
-	) -> db::TxResult<Option<Hash>, Error> {
+	) -> db::TxResult<Option<bool>, Error> {
 		let i = key.prefix.len();
 
 		// Read node at current position (defined by the prefix stored in key)
 		// Calculate an update to apply to this node
 		// This update is an Option<_>, so that it is None if the update is a no-op
 		// and we can thus skip recalculating and re-storing everything
-		let mutate = match self.read_node_txn(tx, key)? {
+		let mutate = match self.read_node(tx, key)? {
 			MerkleNode::Empty => new_vhash.map(|vhv| MerkleNode::Leaf(k.to_vec(), vhv)),
 			MerkleNode::Intermediate(mut children) => {
 				let key2 = key.next_key(khash);
-				if let Some(subhash) = self.update_item_rec(tx, k, khash, &key2, new_vhash)? {
+				if let Some(sub_empty) = self.update_item_rec(tx, k, khash, &key2, new_vhash)? {
 					// Subtree changed, update this node as well
-					if subhash == self.empty_node_hash {
+					if sub_empty {
 						intermediate_rm_child(&mut children, key2.prefix[i]);
 					} else {
-						intermediate_set_child(&mut children, key2.prefix[i], subhash);
+						// The hash of the subtree is set when the batch is written
+						intermediate_set_child(&mut children, key2.prefix[i], self.empty_node_hash);
 					}
 
 					if children.is_empty() {
 						// should not happen
 						warn!(
 							"({}) Replacing intermediate node with empty node, should not happen.",
-							F::TABLE_NAME
+							self.table_name
 						);
 						Some(MerkleNode::Empty)
 					} else if children.len() == 1 {
 						// We now have a single node (case when the update deleted one of only two
 						// children). If that node is a leaf, move it to this level.
 						let key_sub = key.add_byte(children[0].0);
-						let subnode = self.read_node_txn(tx, &key_sub)?;
+						let subnode = self.read_node(tx, &key_sub)?;
 						match subnode {
 							MerkleNode::Empty => {
 								warn!(
 									"({}) Single subnode in tree is empty Merkle node",
-									F::TABLE_NAME
+									self.table_name
 								);
 								Some(MerkleNode::Empty)
 							}
 							MerkleNode::Intermediate(_) => Some(MerkleNode::Intermediate(children)),
 							x @ MerkleNode::Leaf(_, _) => {
-								tx.remove(&self.data.merkle_tree, key_sub.encode())?;
+								self.put_node(&key_sub, MerkleNode::Empty);
 								Some(x)
 							}
 						}
@@ -208,25 +376,28 @@ impl<F: TableSchema, R: TableReplication> MerkleUpdater<F, R> {
 
 						{
 							let exlf_subkey = key.next_key(&exlf_khash);
-							let exlf_sub_hash = self
-								.update_item_rec(
-									tx,
-									&exlf_k[..],
-									&exlf_khash,
-									&exlf_subkey,
-									Some(exlf_vhash),
-								)?
-								.unwrap();
-							intermediate_set_child(&mut int, exlf_subkey.prefix[i], exlf_sub_hash);
+							let exlf_sub_changed = self.update_item_rec(
+								tx,
+								&exlf_k[..],
+								&exlf_khash,
+								&exlf_subkey,
+								Some(exlf_vhash),
+							)?;
+							assert_eq!(exlf_sub_changed, Some(false));
+							intermediate_set_child(
+								&mut int,
+								exlf_subkey.prefix[i],
+								self.empty_node_hash,
+							);
 							assert_eq!(int.len(), 1);
 						}
 
 						{
 							let key2 = key.next_key(khash);
-							let subhash = self
-								.update_item_rec(tx, k, khash, &key2, new_vhash)?
-								.unwrap();
-							intermediate_set_child(&mut int, key2.prefix[i], subhash);
+							let sub_changed =
+								self.update_item_rec(tx, k, khash, &key2, new_vhash)?;
+							assert_eq!(sub_changed, Some(false));
+							intermediate_set_child(&mut int, key2.prefix[i], self.empty_node_hash);
 							if exlf_khash.as_slice()[i] == khash.as_slice()[i] {
 								assert_eq!(int.len(), 1);
 							} else {
@@ -245,93 +416,77 @@ impl<F: TableSchema, R: TableReplication> MerkleUpdater<F, R> {
 		};
 
 		if let Some(new_node) = mutate {
-			let hash = self.put_node_txn(tx, key, &new_node)?;
-			Ok(Some(hash))
+			let empty = new_node.is_empty();
+			self.put_node(key, new_node);
+			Ok(Some(empty))
 		} else {
 			Ok(None)
 		}
 	}
 
-	// Merkle tree node manipulation
-
-	fn read_node_txn(
-		&self,
+	fn read_node(
+		&mut self,
 		tx: &mut db::Transaction<'_>,
 		k: &MerkleNodeKey,
 	) -> db::TxResult<MerkleNode, Error> {
-		let ent = tx.get(&self.data.merkle_tree, k.encode())?;
-		MerkleNode::decode_opt(&ent).map_err(db::TxError::Abort)
-	}
-
-	fn put_node_txn(
-		&self,
-		tx: &mut db::Transaction<'_>,
-		k: &MerkleNodeKey,
-		v: &MerkleNode,
-	) -> db::TxResult<Hash, Error> {
-		trace!("Put Merkle node: {:?} => {:?}", k, v);
-		if *v == MerkleNode::Empty {
-			tx.remove(&self.data.merkle_tree, k.encode())?;
-			Ok(self.empty_node_hash)
-		} else {
-			let vby = nonversioned_encode(v).map_err(|e| db::TxError::Abort(e.into()))?;
-			let rethash = blake2sum(&vby[..]);
-			tx.insert(&self.data.merkle_tree, k.encode(), vby)?;
-			Ok(rethash)
+		let enc = k.encode();
+		if let Some(node) = self.nodes.get(&enc) {
+			return Ok(node.clone());
 		}
+		let ent = tx.get(self.tree, &enc)?;
+		let node = MerkleNode::decode_opt(&ent).map_err(db::TxError::Abort)?;
+		self.nodes.insert(enc, node.clone());
+		Ok(node)
 	}
 
-	// Access a node in the Merkle tree, used by the sync protocol
-	pub(crate) fn read_node(&self, k: &MerkleNodeKey) -> Result<MerkleNode, Error> {
-		let ent = self.data.merkle_tree.get(k.encode())?;
-		MerkleNode::decode_opt(&ent)
-	}
-
-	pub fn merkle_tree_len(&self) -> Result<usize, Error> {
-		Ok(self.data.merkle_tree.len()?)
-	}
-
-	pub fn todo_len(&self) -> Result<usize, Error> {
-		Ok(self.data.merkle_todo.len()?)
-	}
-}
-
-struct MerkleWorker<F: TableSchema, R: TableReplication>(Arc<MerkleUpdater<F, R>>);
-
-#[async_trait]
-impl<F: TableSchema, R: TableReplication> Worker for MerkleWorker<F, R> {
-	fn name(&self) -> String {
-		format!("{} Merkle", F::TABLE_NAME)
+	fn put_node(&mut self, k: &MerkleNodeKey, v: MerkleNode) {
+		let enc = k.encode();
+		self.nodes.insert(enc.clone(), v);
+		self.modified.insert(enc, k.clone());
 	}
 
-	fn status(&self) -> WorkerStatus {
-		WorkerStatus {
-			queue_length: Some(self.0.todo_len().unwrap_or(0) as u64),
-			..Default::default()
-		}
-	}
+	/// Hash and store all modified nodes, children before their parents so
+	/// that the hashes stored in intermediate nodes are up to date.
+	/// Returns the number of nodes that have been hashed.
+	fn write(mut self, tx: &mut db::Transaction<'_>) -> db::TxResult<u64, Error> {
+		let mut modified = std::mem::take(&mut self.modified)
+			.into_values()
+			.collect::<Vec<_>>();
+		modified.sort_by_key(|k| std::cmp::Reverse(k.prefix.len()));
+
+		let mut node_hashes = 0;
+		for k in modified {
+			let enc = k.encode();
+			let node = &self.nodes[&enc];
+			trace!("Put Merkle node: {:?} => {:?}", k, node);
+			if node.is_empty() {
+				// Empty nodes have already been removed from their parent
+				tx.remove(self.tree, enc)?;
+				continue;
+			}
 
-	async fn work(&mut self, _must_exit: &mut watch::Receiver<bool>) -> Result<WorkerState, Error> {
-		let updater = self.0.clone();
-		tokio::task::spawn_blocking(move || {
-			for _i in 0..100 {
-				let s = updater.updater_loop_iter();
-				if !matches!(s, Ok(WorkerState::Busy)) {
-					return s;
+			let vby = nonversioned_encode(node).map_err(|e| db::TxError::Abort(e.into()))?;
+			let hash = blake2sum(&vby[..]);
+			node_hashes += 1;
+			tx.insert(self.tree, enc, vby)?;
+
+			if let Some((pos, parent_prefix)) = k.prefix.split_last() {
+				let parent = MerkleNodeKey {
+					partition: k.partition,
+					prefix: parent_prefix.to_vec(),
+				};
+				match self.nodes.get_mut(&parent.encode()) {
+					Some(MerkleNode::Intermediate(children)) => {
+						intermediate_set_child(children, *pos, hash)
+					}
+					_ => warn!(
+						"({}) Parent of modified Merkle node is not an intermediate node",
+						self.table_name
+					),
 				}
 			}
-			Ok(WorkerState::Busy)
-		})
-		.await
-		.unwrap()
-	}
-
-	async fn wait_for_work(&mut self) -> WorkerState {
-		select! {
-			_ = tokio::time::sleep(Duration::from_secs(60)) => (),
-			_ = self.0.data.merkle_todo_notify.notified() => (),
 		}
-		WorkerState::Busy
+		Ok(node_hashes)
 	}
 }
 
@@ -469,3 +624,83 @@ fn test_intermediate_aux() {
 		]
 	);
 }
+
+#[test]
+fn test_merkle_batch() {
+	// garage_db is used without default features by this crate,
+	// use whichever engine is available in this build
+	let dir = std::env::temp_dir().join(format!("garage-merkle-test-{}", hex::encode(gen_uuid())));
+	std::fs::create_dir_all(&dir).unwrap();
+	let db = vec![db::Engine::Sqlite, db::Engine::Lmdb]
+		.into_iter()
+		.find_map(|engine| {
+			db::open_db(&dir.join(engine.as_str()), engine, &db::OpenOpt::default()).ok()
+		});
+	let db = match db {
+		Some(db) => db,
+		None => return,
+	};
+	let empty_node_hash = blake2sum(&nonversioned_encode(&MerkleNode::Empty).unwrap()[..]);
+
+	// Apply updates to the tree in batches of the given size,
+	// and return the number of nodes that have been hashed
+	let apply = |tree: &db::Tree, items: &[(Vec<u8>, Vec<u8>)], batch_size: usize| {
+		let mut node_hashes = 0;
+		for chunk in items.chunks(batch_size) {
+			node_hashes += db
+				.transaction(|tx| {
+					let mut batch = MerkleBatch::new(tree, "test", empty_node_hash);
+					for (k, vhash_by) in chunk.iter() {
+						batch.update_item(tx, 0, k, vhash_by)?;
+					}
+					batch.write(tx)
+				})
+				.unwrap();
+		}
+		node_hashes
+	};
+	let nodes = |tree: &db::Tree| tree.iter().unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+
+	// Items that all have the same partition key, and thus are in the same partition
+	let partition_key_hash = blake2sum(b"partition key");
+	let inserts = (0u32..10_000)
+		.map(|i| {
+			let mut k = partition_key_hash.to_vec();
+			k.extend(i.to_be_bytes());
+			(k, blake2sum(&i.to_le_bytes()).to_vec())
+		})
+		.collect::<Vec<_>>();
+	let deletes = inserts
+		.iter()
+		.map(|(k, _)| (k.clone(), vec![]))
+		.collect::<Vec<_>>();
+
+	let tree_seq = db.open_tree("one_by_one").unwrap();
+	let tree_batch = db.open_tree("batch").unwrap();
+
+	// Both ways of applying updates give the same tree,
+	// but much less nodes are hashed when using batches
+	let seq_hashes = apply(&tree_seq, &inserts, 1);
+	let batch_hashes = apply(&tree_batch, &inserts, MERKLE_BATCH_SIZE);
+	assert!(
+		batch_hashes * 2 < seq_hashes,
+		"{} node hashes in batches, {} one by one",
+		batch_hashes,
+		seq_hashes
+	);
+	assert_eq!(nodes(&tree_seq), nodes(&tree_batch));
+
+	// Same with deletions, which make intermediate nodes collapse
+	let half = deletes.iter().step_by(2).cloned().collect::<Vec<_>>();
+	apply(&tree_seq, &half, 1);
+	apply(&tree_batch, &half, MERKLE_BATCH_SIZE);
+	assert_eq!(nodes(&tree_seq), nodes(&tree_batch));
+
+	apply(&tree_seq, &deletes, 1);
+	apply(&tree_batch, &deletes, MERKLE_BATCH_SIZE);
+	assert!(nodes(&tree_seq).is_empty());
+	assert!(nodes(&tree_batch).is_empty());
+
+	drop(db);
+	std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -16,6 +16,8 @@ pub struct TableMetrics {
 
 	pub(crate) internal_update_counter: BoundCounter<u64>,
 	pub(crate) internal_delete_counter: BoundCounter<u64>,
+	pub(crate) merkle_node_hash_counter: BoundCounter<u64>,
+	pub(crate) read_repair_counter: BoundCounter<u64>,
 
 	pub(crate) sync_items_sent: Counter<u64>,
 	pub(crate) sync_items_received: Counter<u64>,
@@ -34,7 +36,7 @@ impl TableMetrics {
 				.u64_value_observer(
 					"table.size",
 					move |observer| {
-						if let Ok(value) = store.len() {
+						if let Ok(value) = store.fast_len() {
 							observer.observe(
 								value as u64,
 								&[KeyValue::new("table_name", table_name)],
@@ -48,7 +50,7 @@ impl TableMetrics {
 				.u64_value_observer(
 					"table.merkle_tree_size",
 					move |observer| {
-						if let Ok(value) = merkle_tree.len() {
+						if let Ok(value) = merkle_tree.fast_len() {
 							observer.observe(
 								value as u64,
 								&[KeyValue::new("table_name", table_name)],
@@ -62,7 +64,7 @@ impl TableMetrics {
 				.u64_value_observer(
 					"table.merkle_updater_todo_queue_length",
 					move |observer| {
-						if let Ok(v) = merkle_todo.len() {
+						if let Ok(v) = merkle_todo.fast_len() {
 							observer.observe(
 								v as u64,
 								&[KeyValue::new("table_name", table_name)],
@@ -76,7 +78,7 @@ impl TableMetrics {
 				.u64_value_observer(
 					"table.gc_todo_queue_length",
 					move |observer| {
-                        if let Ok(value) = gc_todo.len() {
+                        if let Ok(value) = gc_todo.fast_len() {
                             observer.observe(
                                 value as u64,
                                 &[KeyValue::new("table_name", table_name)],
@@ -118,6 +120,16 @@ impl TableMetrics {
 				.with_description("Number of value deletions in the tree (due to GC or repartitioning)")
 				.init()
 				.bind(&[KeyValue::new("table_name", table_name)]),
+			merkle_node_hash_counter: meter
+				.u64_counter("table.merkle_node_hash_counter")
+				.with_description("Number of Merkle tree nodes hashed by the Merkle updater")
+				.init()
+				.bind(&[KeyValue::new("table_name", table_name)]),
+			read_repair_counter: meter
+				.u64_counter("table.read_repair_counter")
+				.with_description("Number of entries written back to replicas because they returned diverging values on a read")
+				.init()
+				.bind(&[KeyValue::new("table_name", table_name)]),
 
 			sync_items_sent: meter
 				.u64_counter("table.sync_items_sent")
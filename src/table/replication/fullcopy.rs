@@ -17,6 +17,9 @@ use crate::replication::*;
 /// Advantage: do all reads locally, extremely fast
 /// Inconvenient: only suitable to reasonably small tables
 /// Inconvenient: if some writes fail, nodes will read outdated data
+///
+/// Gateway nodes marked as non-voting in the layout receive all writes,
+/// but are not counted in write quorums.
 #[derive(Clone)]
 pub struct TableFullReplication {
 	/// The membership manager of this node
@@ -38,11 +41,17 @@ impl TableReplication for TableFullReplication {
 		1
 	}
 
-	fn write_sets(&self, hash: &Hash) -> Self::WriteSets {
-		vec![self.storage_nodes(hash)]
+	fn write_sets(&self, _hash: &Hash) -> Self::WriteSets {
+		let layout = self.system.cluster_layout();
+		vec![layout.current().voting_nodes().copied().collect()]
 	}
 	fn write_quorum(&self) -> usize {
-		let nmembers = self.system.cluster_layout().current().all_nodes().len();
+		let nmembers = self
+			.system
+			.cluster_layout()
+			.current()
+			.voting_nodes()
+			.count();
 
 		let max_faults = if nmembers > 1 { 1 } else { 0 };
 
@@ -53,6 +62,17 @@ impl TableReplication for TableFullReplication {
 		}
 	}
 
+	fn non_voting_nodes(&self, _hash: &Hash) -> Vec<Uuid> {
+		let layout = self.system.cluster_layout();
+		let current = layout.current();
+		current
+			.all_nodes()
+			.iter()
+			.filter(|n| !current.is_voting_node(n))
+			.copied()
+			.collect()
+	}
+
 	fn partition_of(&self, _hash: &Hash) -> Partition {
 		0u16
 	}
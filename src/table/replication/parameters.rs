@@ -20,6 +20,15 @@ pub trait TableReplication: Send + Sync + 'static {
 	fn write_sets(&self, hash: &Hash) -> Self::WriteSets;
 	/// Responses needed to consider a write succesfull in each set
 	fn write_quorum(&self) -> usize;
+	/// Distinct zones from which responses are needed to consider
+	/// a write succesfull in each set (0 for no requirement)
+	fn write_min_zones(&self) -> usize {
+		0
+	}
+	/// Nodes to which writes are also sent, but that are not waited for
+	fn non_voting_nodes(&self, _hash: &Hash) -> Vec<Uuid> {
+		vec![]
+	}
 
 	// Accessing partitions, for Merkle tree & sync
 	/// Get partition for data with given hash
@@ -22,6 +22,8 @@ pub struct TableShardedReplication {
 	pub read_quorum: usize,
 	/// How many nodes to contact for a write, should be at most `replication_factor`
 	pub write_quorum: usize,
+	/// In how many distinct zones a write must be acknowledged (0 for no requirement)
+	pub write_min_zones: usize,
 }
 
 impl TableReplication for TableShardedReplication {
@@ -44,6 +46,9 @@ impl TableReplication for TableShardedReplication {
 	fn write_quorum(&self) -> usize {
 		self.write_quorum
 	}
+	fn write_min_zones(&self) -> usize {
+		self.write_min_zones
+	}
 
 	fn partition_of(&self, hash: &Hash) -> Partition {
 		self.system.cluster_layout().current().partition_of(hash)
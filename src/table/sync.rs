@@ -238,7 +238,9 @@ impl<F: TableSchema, R: TableReplication> TableSyncer<F, R> {
 				&self.endpoint,
 				nodes,
 				SyncRpc::Items(values),
-				RequestStrategy::with_priority(PRIO_BACKGROUND).with_quorum(nodes.len()),
+				RequestStrategy::with_priority(PRIO_BACKGROUND)
+					.with_quorum(nodes.len())
+					.with_timeout_class(RpcClass::Sync),
 			)
 			.await?;
 
@@ -299,7 +301,7 @@ impl<F: TableSchema, R: TableReplication> TableSyncer<F, R> {
 				&self.endpoint,
 				who,
 				SyncRpc::RootCkHash(partition.partition, root_ck_hash),
-				RequestStrategy::with_priority(PRIO_BACKGROUND),
+				RequestStrategy::with_priority(PRIO_BACKGROUND).with_timeout_class(RpcClass::Sync),
 			)
 			.await?;
 
@@ -355,7 +357,8 @@ impl<F: TableSchema, R: TableReplication> TableSyncer<F, R> {
 							&self.endpoint,
 							who,
 							SyncRpc::GetNode(key.clone()),
-							RequestStrategy::with_priority(PRIO_BACKGROUND),
+							RequestStrategy::with_priority(PRIO_BACKGROUND)
+								.with_timeout_class(RpcClass::Sync),
 						)
 						.await?
 					{
@@ -431,7 +434,7 @@ impl<F: TableSchema, R: TableReplication> TableSyncer<F, R> {
 				&self.endpoint,
 				who,
 				SyncRpc::Items(values),
-				RequestStrategy::with_priority(PRIO_BACKGROUND),
+				RequestStrategy::with_priority(PRIO_BACKGROUND).with_timeout_class(RpcClass::Sync),
 			)
 			.await?;
 		if let SyncRpc::Ok = rpc_resp {
@@ -1,6 +1,6 @@
 use std::borrow::Borrow;
 use std::collections::{BTreeMap, BTreeSet, HashMap};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use async_trait::async_trait;
 use futures::stream::*;
@@ -14,11 +14,12 @@ use opentelemetry::{
 
 use garage_db as db;
 
-use garage_util::background::BackgroundRunner;
+use garage_util::background::{vars, BackgroundRunner};
 use garage_util::data::*;
 use garage_util::error::Error;
-use garage_util::metrics::RecordDuration;
+use garage_util::metrics::{timed_phase, RecordDuration};
 use garage_util::migrate::Migrate;
+use garage_util::time::now_msec;
 
 use garage_rpc::rpc_helper::QuorumSetResultTracker;
 use garage_rpc::system::System;
@@ -41,6 +42,7 @@ pub struct Table<F: TableSchema, R: TableReplication> {
 	pub syncer: Arc<TableSyncer<F, R>>,
 	gc: Arc<TableGc<F, R>>,
 	endpoint: Arc<Endpoint<TableRpc<F>, Self>>,
+	read_repair_limiter: ReadRepairLimiter,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -83,6 +85,8 @@ impl<F: TableSchema, R: TableReplication> Table<F, R> {
 
 		system.layout_manager.add_table(F::TABLE_NAME);
 
+		let read_repair_limiter = ReadRepairLimiter::new(system.read_repair.max_per_sec);
+
 		let table = Arc::new(Self {
 			system,
 			data,
@@ -90,6 +94,7 @@ impl<F: TableSchema, R: TableReplication> Table<F, R> {
 			gc,
 			syncer,
 			endpoint,
+			read_repair_limiter,
 		});
 
 		table.endpoint.set_handler(table.clone());
@@ -104,6 +109,35 @@ impl<F: TableSchema, R: TableReplication> Table<F, R> {
 		bg.spawn_worker(InsertQueueWorker(self.clone()));
 	}
 
+	/// Register the variables that control the Merkle updater
+	/// and GC workers of this table
+	pub fn register_bg_vars(&self, vars: &mut vars::BgVars) {
+		let params = &self.data.worker_params;
+		vars.register_rw(
+			params,
+			&format!("merkle-tranquility-{}", F::TABLE_NAME),
+			|p| p.get_with(|x| x.merkle_tranquility),
+			|p, tranquility| p.set_with(|x| x.merkle_tranquility = tranquility),
+		);
+		vars.register_rw(
+			params,
+			&format!("gc-paused-{}", F::TABLE_NAME),
+			|p| p.get_with(|x| x.gc_paused),
+			|p, paused| p.set_with(|x| x.gc_paused = paused),
+		);
+		vars.register_rw(
+			params,
+			&format!("gc-batch-size-{}", F::TABLE_NAME),
+			|p| p.get_with(|x| x.gc_batch_size),
+			|p, batch_size| {
+				if batch_size == 0 {
+					return Err(Error::Message("GC batch size must be at least 1".into()));
+				}
+				p.set_with(|x| x.gc_batch_size = batch_size)
+			},
+		);
+	}
+
 	pub async fn insert(&self, e: &F::E) -> Result<(), Error> {
 		let tracer = opentelemetry::global::tracer("garage_table");
 		let span = tracer.start(format!("{} insert", F::TABLE_NAME));
@@ -121,6 +155,7 @@ impl<F: TableSchema, R: TableReplication> Table<F, R> {
 	async fn insert_internal(&self, e: &F::E) -> Result<(), Error> {
 		let hash = e.partition_key().hash();
 		let who = self.data.replication.write_sets(&hash);
+		let non_voting = self.data.replication.non_voting_nodes(&hash);
 
 		let e_enc = Arc::new(ByteBuf::from(e.encode()?));
 		let rpc = TableRpc::<F>::Update(vec![e_enc]);
@@ -130,9 +165,12 @@ impl<F: TableSchema, R: TableReplication> Table<F, R> {
 			.try_write_many_sets(
 				&self.endpoint,
 				who.as_ref(),
+				&non_voting,
 				rpc,
 				RequestStrategy::with_priority(PRIO_NORMAL)
-					.with_quorum(self.data.replication.write_quorum()),
+					.with_quorum(self.data.replication.write_quorum())
+					.with_min_zones(self.data.replication.write_min_zones())
+					.with_timeout_class(RpcClass::MetadataWrite),
 			)
 			.await?;
 
@@ -200,8 +238,9 @@ impl<F: TableSchema, R: TableReplication> Table<F, R> {
 				// nodes but in possibly different orders
 				set.sort();
 			}
+			let non_voting = self.data.replication.non_voting_nodes(&hash);
 			let e_enc = Arc::new(ByteBuf::from(entry.encode()?));
-			entries_vec.push((write_sets, e_enc));
+			entries_vec.push((write_sets, non_voting, e_enc));
 		}
 
 		// Compute a deduplicated list of all of the write sets,
@@ -209,27 +248,37 @@ impl<F: TableSchema, R: TableReplication> Table<F, R> {
 		// it takes part, to optimize the detection of a quorum.
 		let mut write_sets = entries_vec
 			.iter()
-			.flat_map(|(wss, _)| wss.as_ref().iter().map(|ws| ws.as_slice()))
+			.flat_map(|(wss, _, _)| wss.as_ref().iter().map(|ws| ws.as_slice()))
 			.collect::<Vec<&[Uuid]>>();
 		write_sets.sort();
 		write_sets.dedup();
 
 		let mut result_tracker = QuorumSetResultTracker::new(&write_sets, quorum);
+		let min_zones = self.data.replication.write_min_zones();
+		if min_zones > 0 {
+			let layout = self.system.cluster_layout();
+			result_tracker.require_zones(min_zones, |n| layout.node_zone(n).map(String::from));
+		}
 
 		// Build a map of all nodes to the entries that must be sent to that node.
+		// Non-voting nodes receive the entries, but are not part of any write set.
 		let mut call_list: HashMap<Uuid, Vec<_>> = HashMap::new();
-		for (write_sets, entry_enc) in entries_vec.iter() {
-			for write_set in write_sets.as_ref().iter() {
-				for node in write_set.iter() {
-					let node_entries = call_list.entry(*node).or_default();
-					match node_entries.last() {
-						Some(x) if Arc::ptr_eq(x, entry_enc) => {
-							// skip if entry already in list to send to this node
-							// (could happen if node is in several write sets for this entry)
-						}
-						_ => {
-							node_entries.push(entry_enc.clone());
-						}
+		for (write_sets, non_voting, entry_enc) in entries_vec.iter() {
+			result_tracker.add_non_voting_nodes(non_voting);
+			let nodes = write_sets
+				.as_ref()
+				.iter()
+				.flatten()
+				.chain(non_voting.iter());
+			for node in nodes {
+				let node_entries = call_list.entry(*node).or_default();
+				match node_entries.last() {
+					Some(x) if Arc::ptr_eq(x, entry_enc) => {
+						// skip if entry already in list to send to this node
+						// (could happen if node is in several write sets for this entry)
+					}
+					_ => {
+						node_entries.push(entry_enc.clone());
 					}
 				}
 			}
@@ -247,7 +296,9 @@ impl<F: TableSchema, R: TableReplication> Table<F, R> {
 						&this.endpoint,
 						node,
 						rpc,
-						RequestStrategy::with_priority(PRIO_NORMAL).with_quorum(quorum),
+						RequestStrategy::with_priority(PRIO_NORMAL)
+							.with_quorum(quorum)
+							.with_timeout_class(RpcClass::MetadataWrite),
 					)
 					.await;
 				(node, resp)
@@ -289,11 +340,37 @@ impl<F: TableSchema, R: TableReplication> Table<F, R> {
 		let tracer = opentelemetry::global::tracer("garage_table");
 		let span = tracer.start(format!("{} get", F::TABLE_NAME));
 
-		let res = self
-			.get_internal(partition_key, sort_key)
-			.bound_record_duration(&self.data.metrics.get_request_duration)
-			.with_context(Context::current_with_span(span))
-			.await?;
+		let res = timed_phase(
+			"table read",
+			self.get_internal(partition_key, sort_key, self.data.replication.read_quorum())
+				.bound_record_duration(&self.data.metrics.get_request_duration)
+				.with_context(Context::current_with_span(span)),
+		)
+		.await?;
+
+		self.data.metrics.get_request_counter.add(1);
+
+		Ok(res)
+	}
+
+	/// Read an entry from a single one of the nodes that store it, preferably
+	/// the local node. The value returned might not include the latest writes,
+	/// callers must be able to tell if it is recent enough for their use.
+	pub async fn get_single(
+		self: &Arc<Self>,
+		partition_key: &F::P,
+		sort_key: &F::S,
+	) -> Result<Option<F::E>, Error> {
+		let tracer = opentelemetry::global::tracer("garage_table");
+		let span = tracer.start(format!("{} get_single", F::TABLE_NAME));
+
+		let res = timed_phase(
+			"table read",
+			self.get_internal(partition_key, sort_key, 1)
+				.bound_record_duration(&self.data.metrics.get_request_duration)
+				.with_context(Context::current_with_span(span)),
+		)
+		.await?;
 
 		self.data.metrics.get_request_counter.add(1);
 
@@ -304,6 +381,7 @@ impl<F: TableSchema, R: TableReplication> Table<F, R> {
 		self: &Arc<Self>,
 		partition_key: &F::P,
 		sort_key: &F::S,
+		quorum: usize,
 	) -> Result<Option<F::E>, Error> {
 		let hash = partition_key.hash();
 		let who = self.data.replication.read_nodes(&hash);
@@ -317,7 +395,8 @@ impl<F: TableSchema, R: TableReplication> Table<F, R> {
 				&who,
 				rpc,
 				RequestStrategy::with_priority(PRIO_NORMAL)
-					.with_quorum(self.data.replication.read_quorum()),
+					.with_quorum(quorum)
+					.with_timeout_class(RpcClass::MetadataRead),
 			)
 			.await?;
 
@@ -344,13 +423,7 @@ impl<F: TableSchema, R: TableReplication> Table<F, R> {
 		}
 		if let Some(ret_entry) = &ret {
 			if not_all_same {
-				let self2 = self.clone();
-				let ent2 = ret_entry.clone();
-				tokio::spawn(async move {
-					if let Err(e) = self2.repair_on_read(&who[..], ent2).await {
-						warn!("Error doing repair on read: {}", e);
-					}
-				});
+				self.spawn_repair_on_read(who, vec![ret_entry.clone()]);
 			}
 		}
 
@@ -368,23 +441,76 @@ impl<F: TableSchema, R: TableReplication> Table<F, R> {
 		let tracer = opentelemetry::global::tracer("garage_table");
 		let span = tracer.start(format!("{} get_range", F::TABLE_NAME));
 
-		let res = self
-			.get_range_internal(
+		let res = timed_phase(
+			"table read",
+			self.get_range_internal(
 				partition_key,
 				begin_sort_key,
 				filter,
 				limit,
 				enumeration_order,
+				self.data.replication.read_quorum(),
 			)
 			.bound_record_duration(&self.data.metrics.get_request_duration)
-			.with_context(Context::current_with_span(span))
-			.await?;
+			.with_context(Context::current_with_span(span)),
+		)
+		.await?;
 
 		self.data.metrics.get_request_counter.add(1);
 
 		Ok(res)
 	}
 
+	/// Same as `get_range`, but if the read quorum cannot be reached, e.g.
+	/// because a node is down, the entries are read from a single node
+	/// instead. The returned flag is set when this fallback was used, as the
+	/// entries might then not include the latest writes. This must only be
+	/// used by callers that accept such stale reads.
+	pub async fn get_range_allow_degraded(
+		self: &Arc<Self>,
+		partition_key: &F::P,
+		begin_sort_key: Option<F::S>,
+		filter: Option<F::Filter>,
+		limit: usize,
+		enumeration_order: EnumerationOrder,
+	) -> Result<(Vec<F::E>, bool), Error> {
+		let res = self
+			.get_range(
+				partition_key,
+				begin_sort_key.clone(),
+				filter.clone(),
+				limit,
+				enumeration_order,
+			)
+			.await;
+		match res {
+			Err(Error::Quorum(..)) | Err(Error::NoQuorum(..)) => {
+				let tracer = opentelemetry::global::tracer("garage_table");
+				let span = tracer.start(format!("{} get_range degraded", F::TABLE_NAME));
+
+				let res = timed_phase(
+					"table read",
+					self.get_range_internal(
+						partition_key,
+						begin_sort_key,
+						filter,
+						limit,
+						enumeration_order,
+						1,
+					)
+					.bound_record_duration(&self.data.metrics.get_request_duration)
+					.with_context(Context::current_with_span(span)),
+				)
+				.await?;
+
+				self.data.metrics.get_request_counter.add(1);
+
+				Ok((res, true))
+			}
+			res => res.map(|entries| (entries, false)),
+		}
+	}
+
 	async fn get_range_internal(
 		self: &Arc<Self>,
 		partition_key: &F::P,
@@ -392,6 +518,7 @@ impl<F: TableSchema, R: TableReplication> Table<F, R> {
 		filter: Option<F::Filter>,
 		limit: usize,
 		enumeration_order: EnumerationOrder,
+		quorum: usize,
 	) -> Result<Vec<F::E>, Error> {
 		let hash = partition_key.hash();
 		let who = self.data.replication.read_nodes(&hash);
@@ -412,7 +539,8 @@ impl<F: TableSchema, R: TableReplication> Table<F, R> {
 				&who,
 				rpc,
 				RequestStrategy::with_priority(PRIO_NORMAL)
-					.with_quorum(self.data.replication.read_quorum()),
+					.with_quorum(quorum)
+					.with_timeout_class(RpcClass::MetadataRead),
 			)
 			.await?;
 
@@ -441,18 +569,11 @@ impl<F: TableSchema, R: TableReplication> Table<F, R> {
 		}
 
 		if !to_repair.is_empty() {
-			let self2 = self.clone();
 			let to_repair = to_repair
 				.into_iter()
 				.map(|k| ret.get(&k).unwrap().clone())
 				.collect::<Vec<_>>();
-			tokio::spawn(async move {
-				for v in to_repair {
-					if let Err(e) = self2.repair_on_read(&who[..], v).await {
-						warn!("Error doing repair on read: {}", e);
-					}
-				}
-			});
+			self.spawn_repair_on_read(who, to_repair);
 		}
 
 		// At this point, the `ret` btreemap might contain more than `limit`
@@ -481,6 +602,42 @@ impl<F: TableSchema, R: TableReplication> Table<F, R> {
 
 	// =============== UTILITY FUNCTION FOR CLIENT OPERATIONS ===============
 
+	/// Write back, in the background, the merged values of entries on which
+	/// replicas were found to disagree during a read. Does nothing if read
+	/// repair is disabled, and skips the entries that exceed the rate limit.
+	fn spawn_repair_on_read(self: &Arc<Self>, who: Vec<Uuid>, mut entries: Vec<F::E>) {
+		if !self.system.read_repair.enabled {
+			return;
+		}
+
+		let allowed = self.read_repair_limiter.take(entries.len(), now_msec());
+		if allowed < entries.len() {
+			debug!(
+				"({}) Read repair rate limit reached, not repairing {} entries",
+				F::TABLE_NAME,
+				entries.len() - allowed
+			);
+			entries.truncate(allowed);
+		}
+		if entries.is_empty() {
+			return;
+		}
+
+		self.data
+			.metrics
+			.read_repair_counter
+			.add(entries.len() as u64);
+
+		let self2 = self.clone();
+		tokio::spawn(async move {
+			for v in entries {
+				if let Err(e) = self2.repair_on_read(&who[..], v).await {
+					warn!("Error doing repair on read: {}", e);
+				}
+			}
+		});
+	}
+
 	async fn repair_on_read(&self, who: &[Uuid], what: F::E) -> Result<(), Error> {
 		let what_enc = Arc::new(ByteBuf::from(what.encode()?));
 		self.system
@@ -489,7 +646,9 @@ impl<F: TableSchema, R: TableReplication> Table<F, R> {
 				&self.endpoint,
 				who,
 				TableRpc::<F>::Update(vec![what_enc]),
-				RequestStrategy::with_priority(PRIO_NORMAL).with_quorum(who.len()),
+				RequestStrategy::with_priority(PRIO_NORMAL)
+					.with_quorum(who.len())
+					.with_timeout_class(RpcClass::MetadataWrite),
 			)
 			.await?;
 		Ok(())
@@ -532,3 +691,61 @@ impl<F: TableSchema, R: TableReplication> EndpointHandler<TableRpc<F>> for Table
 		}
 	}
 }
+
+/// Rate limiter for read repairs, counting repaired entries
+/// over windows of one second
+struct ReadRepairLimiter {
+	max_per_sec: Option<u64>,
+	/// Current window (in seconds since the epoch) and number
+	/// of repairs done in that window
+	window: Mutex<(u64, u64)>,
+}
+
+impl ReadRepairLimiter {
+	fn new(max_per_sec: Option<u64>) -> Self {
+		Self {
+			max_per_sec,
+			window: Mutex::new((0, 0)),
+		}
+	}
+
+	/// Reserve up to `count` repairs at time `now_msec`,
+	/// returns the number of repairs that may be done
+	fn take(&self, count: usize, now_msec: u64) -> usize {
+		let max = match self.max_per_sec {
+			None => return count,
+			Some(max) => max,
+		};
+
+		let mut window = self.window.lock().unwrap();
+		let sec = now_msec / 1000;
+		if window.0 != sec {
+			*window = (sec, 0);
+		}
+		let allowed = std::cmp::min(count as u64, max.saturating_sub(window.1));
+		window.1 += allowed;
+		allowed as usize
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_read_repair_limiter() {
+		let unlimited = ReadRepairLimiter::new(None);
+		assert_eq!(unlimited.take(1000, 0), 1000);
+
+		let limiter = ReadRepairLimiter::new(Some(10));
+		assert_eq!(limiter.take(4, 1000), 4);
+		assert_eq!(limiter.take(4, 1500), 4);
+		assert_eq!(limiter.take(4, 1999), 2);
+		assert_eq!(limiter.take(1, 1999), 0);
+		// a new window starts every second
+		assert_eq!(limiter.take(4, 2000), 4);
+
+		let disabled = ReadRepairLimiter::new(Some(0));
+		assert_eq!(disabled.take(1, 0), 0);
+	}
+}
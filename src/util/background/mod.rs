@@ -5,6 +5,7 @@ pub mod worker;
 
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
 use tokio::sync::{mpsc, watch};
@@ -39,18 +40,44 @@ pub struct WorkerStatus {
 	pub freeform: Vec<String>,
 }
 
+/// Report of the shutdown of the background runner, listing the workers
+/// that were interrupted because they did not exit before the deadline
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct ShutdownReport {
+	pub unfinished_workers: Vec<UnfinishedWorker>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct UnfinishedWorker {
+	pub task_id: usize,
+	pub name: String,
+	/// Last state and status reported by the worker, if it
+	/// completed at least one step
+	pub last_state: Option<WorkerState>,
+	pub last_status: Option<WorkerStatus>,
+}
+
+impl ShutdownReport {
+	/// Whether all workers exited before the deadline
+	pub fn is_clean(&self) -> bool {
+		self.unfinished_workers.is_empty()
+	}
+}
+
 impl BackgroundRunner {
-	/// Create a new BackgroundRunner
-	pub fn new(stop_signal: watch::Receiver<bool>) -> (Arc<Self>, tokio::task::JoinHandle<()>) {
+	/// Create a new BackgroundRunner. Once `stop_signal` is set, workers
+	/// have `exit_deadline` to exit, after which they are interrupted.
+	pub fn new(
+		stop_signal: watch::Receiver<bool>,
+		exit_deadline: Duration,
+	) -> (Arc<Self>, tokio::task::JoinHandle<ShutdownReport>) {
 		let (send_worker, worker_out) = mpsc::unbounded_channel::<Box<dyn Worker>>();
 
 		let worker_info = Arc::new(std::sync::Mutex::new(HashMap::new()));
 		let mut worker_processor =
-			WorkerProcessor::new(worker_out, stop_signal, worker_info.clone());
+			WorkerProcessor::new(worker_out, stop_signal, exit_deadline, worker_info.clone());
 
-		let await_all_done = tokio::spawn(async move {
-			worker_processor.run().await;
-		});
+		let await_all_done = tokio::spawn(async move { worker_processor.run().await });
 
 		let bgrunner = Arc::new(Self {
 			send_worker,
@@ -6,7 +6,7 @@ use crate::migrate::Migrate;
 use crate::persister::PersisterShared;
 
 pub struct BgVars {
-	vars: HashMap<&'static str, Box<dyn BgVarTrait>>,
+	vars: HashMap<String, Box<dyn BgVarTrait>>,
 }
 
 impl BgVars {
@@ -19,7 +19,7 @@ impl BgVars {
 	pub fn register_rw<V, T, GF, SF>(
 		&mut self,
 		p: &PersisterShared<V>,
-		name: &'static str,
+		name: &str,
 		get_fn: GF,
 		set_fn: SF,
 	) where
@@ -34,10 +34,11 @@ impl BgVars {
 		let p2 = p.clone();
 		let set_fn = move |v| set_fn(&p2, v);
 
-		self.vars.insert(name, Box::new(BgVar { get_fn, set_fn }));
+		self.vars
+			.insert(name.to_string(), Box::new(BgVar { get_fn, set_fn }));
 	}
 
-	pub fn register_ro<V, T, GF>(&mut self, p: &PersisterShared<V>, name: &'static str, get_fn: GF)
+	pub fn register_ro<V, T, GF>(&mut self, p: &PersisterShared<V>, name: &str, get_fn: GF)
 	where
 		V: Migrate + Default + Send + Sync,
 		T: FromStr + ToString + Send + Sync + 'static,
@@ -46,9 +47,11 @@ impl BgVars {
 		let p1 = p.clone();
 		let get_fn = move || get_fn(&p1);
 
-		let set_fn = move |_| Err(Error::Message(format!("Cannot set value of {}", name)));
+		let var_name = name.to_string();
+		let set_fn = move |_| Err(Error::Message(format!("Cannot set value of {}", var_name)));
 
-		self.vars.insert(name, Box::new(BgVar { get_fn, set_fn }));
+		self.vars
+			.insert(name.to_string(), Box::new(BgVar { get_fn, set_fn }));
 	}
 
 	pub fn get(&self, var: &str) -> Result<String, Error> {
@@ -59,8 +62,11 @@ impl BgVars {
 			.get())
 	}
 
-	pub fn get_all(&self) -> Vec<(&'static str, String)> {
-		self.vars.iter().map(|(k, v)| (*k, v.get())).collect()
+	pub fn get_all(&self) -> Vec<(String, String)> {
+		self.vars
+			.iter()
+			.map(|(k, v)| (k.clone(), v.get()))
+			.collect()
 	}
 
 	pub fn set(&self, var: &str, val: &str) -> Result<(), Error> {
@@ -10,14 +10,10 @@ use serde::{Deserialize, Serialize};
 use tokio::select;
 use tokio::sync::{mpsc, watch};
 
-use crate::background::{WorkerInfo, WorkerStatus};
+use crate::background::{ShutdownReport, UnfinishedWorker, WorkerInfo, WorkerStatus};
 use crate::error::Error;
 use crate::time::now_msec;
 
-// All workers that haven't exited for this time after an exit signal was recieved
-// will be interrupted in the middle of whatever they are doing.
-const EXIT_DEADLINE: Duration = Duration::from_secs(8);
-
 #[derive(PartialEq, Copy, Clone, Serialize, Deserialize, Debug)]
 pub enum WorkerState {
 	Busy,
@@ -60,6 +56,9 @@ pub trait Worker: Send {
 
 pub(crate) struct WorkerProcessor {
 	stop_signal: watch::Receiver<bool>,
+	// All workers that haven't exited for this time after an exit signal was recieved
+	// will be interrupted in the middle of whatever they are doing.
+	exit_deadline: Duration,
 	worker_chan: mpsc::UnboundedReceiver<Box<dyn Worker>>,
 	worker_info: Arc<std::sync::Mutex<HashMap<usize, WorkerInfo>>>,
 }
@@ -68,18 +67,22 @@ impl WorkerProcessor {
 	pub(crate) fn new(
 		worker_chan: mpsc::UnboundedReceiver<Box<dyn Worker>>,
 		stop_signal: watch::Receiver<bool>,
+		exit_deadline: Duration,
 		worker_info: Arc<std::sync::Mutex<HashMap<usize, WorkerInfo>>>,
 	) -> Self {
 		Self {
 			stop_signal,
+			exit_deadline,
 			worker_chan,
 			worker_info,
 		}
 	}
 
-	pub(crate) async fn run(&mut self) {
+	pub(crate) async fn run(&mut self) -> ShutdownReport {
 		let mut workers = FuturesUnordered::new();
 		let mut next_task_id = 1;
+		// Names of the workers that have not exited, by task id
+		let mut running = HashMap::new();
 
 		while !*self.stop_signal.borrow() {
 			let await_next_worker = async {
@@ -94,6 +97,7 @@ impl WorkerProcessor {
 					if let Some(new_worker) = new_worker_opt {
 						let task_id = next_task_id;
 						next_task_id += 1;
+						running.insert(task_id, new_worker.name());
 						let stop_signal = self.stop_signal.clone();
 						let mut worker = WorkerHandler {
 								task_id,
@@ -140,6 +144,7 @@ impl WorkerProcessor {
 
 						if worker.state == WorkerState::Done {
 							info!("Worker {} (TID {}) exited", worker.worker.name(), worker.task_id);
+							running.remove(&worker.task_id);
 						} else {
 							workers.push(async move {
 								worker.step().await;
@@ -153,8 +158,9 @@ impl WorkerProcessor {
 		}
 
 		// We are exiting, drain everything
-		let drain_everything = async move {
+		let drain_everything = async {
 			while let Some(worker) = workers.next().await {
+				running.remove(&worker.task_id);
 				info!(
 					"Worker {} (TID {}) exited (last state: {:?})",
 					worker.worker.name(),
@@ -168,10 +174,41 @@ impl WorkerProcessor {
 			_ = drain_everything => {
 				info!("All workers exited peacefully \\o/");
 			}
-			_ = tokio::time::sleep(EXIT_DEADLINE) => {
+			_ = tokio::time::sleep(self.exit_deadline) => {
 				error!("Some workers could not exit in time, we are cancelling some things in the middle");
 			}
 		}
+
+		// Interrupt the workers that are still running
+		drop(workers);
+
+		let wi = self.worker_info.lock().unwrap();
+		let mut unfinished_workers = running
+			.into_iter()
+			.map(|(task_id, name)| {
+				let info = wi.get(&task_id);
+				UnfinishedWorker {
+					task_id,
+					name,
+					last_state: info.map(|i| i.state),
+					last_status: info.map(|i| i.status.clone()),
+				}
+			})
+			.collect::<Vec<_>>();
+		unfinished_workers.sort_by_key(|w| w.task_id);
+
+		for w in unfinished_workers.iter() {
+			error!(
+				"Worker {} (TID {}) was interrupted (last state: {:?}, progress: {:?}, queue length: {:?})",
+				w.name,
+				w.task_id,
+				w.last_state,
+				w.last_status.as_ref().and_then(|s| s.progress.as_ref()),
+				w.last_status.as_ref().and_then(|s| s.queue_length),
+			);
+		}
+
+		ShutdownReport { unfinished_workers }
 	}
 }
 
@@ -210,6 +247,9 @@ impl WorkerHandler {
 					);
 				}
 			},
+			// The exit signal may have been received before this step was
+			// started, in which case waiting for it to change never ends
+			WorkerState::Throttled(_) | WorkerState::Idle if *self.stop_signal.borrow() => (),
 			WorkerState::Throttled(delay) => {
 				// Sleep for given delay and go back to busy state
 				select! {
@@ -231,3 +271,74 @@ impl WorkerHandler {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use crate::background::BackgroundRunner;
+
+	struct StuckWorker;
+
+	#[async_trait]
+	impl Worker for StuckWorker {
+		fn name(&self) -> String {
+			"stuck worker".into()
+		}
+
+		async fn work(
+			&mut self,
+			_must_exit: &mut watch::Receiver<bool>,
+		) -> Result<WorkerState, Error> {
+			futures::future::pending().await
+		}
+
+		async fn wait_for_work(&mut self) -> WorkerState {
+			unreachable!()
+		}
+	}
+
+	struct ExitingWorker;
+
+	#[async_trait]
+	impl Worker for ExitingWorker {
+		fn name(&self) -> String {
+			"exiting worker".into()
+		}
+
+		async fn work(
+			&mut self,
+			must_exit: &mut watch::Receiver<bool>,
+		) -> Result<WorkerState, Error> {
+			if *must_exit.borrow() {
+				Ok(WorkerState::Done)
+			} else {
+				Ok(WorkerState::Idle)
+			}
+		}
+
+		async fn wait_for_work(&mut self) -> WorkerState {
+			futures::future::pending().await
+		}
+	}
+
+	#[tokio::test]
+	async fn test_shutdown_deadline() {
+		let (send_cancel, watch_cancel) = watch::channel(false);
+		let (bg, await_done) = BackgroundRunner::new(watch_cancel, Duration::from_millis(100));
+		bg.spawn_worker(StuckWorker);
+		bg.spawn_worker(ExitingWorker);
+		tokio::time::sleep(Duration::from_millis(50)).await;
+
+		send_cancel.send(true).unwrap();
+		let report = tokio::time::timeout(Duration::from_secs(5), await_done)
+			.await
+			.expect("background runner did not exit in time")
+			.unwrap();
+
+		assert!(!report.is_clean());
+		assert_eq!(report.unfinished_workers.len(), 1);
+		assert_eq!(report.unfinished_workers[0].name, "stuck worker");
+		assert_eq!(report.unfinished_workers[0].last_state, None);
+	}
+}
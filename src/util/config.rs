@@ -1,4 +1,5 @@
 //! Contains type and functions related to Garage configuration file
+use std::collections::BTreeMap;
 use std::convert::TryFrom;
 use std::net::SocketAddr;
 use std::path::PathBuf;
@@ -19,10 +20,23 @@ pub struct Config {
 	/// Whether to fsync after all metadata transactions (disabled by default)
 	#[serde(default)]
 	pub metadata_fsync: bool,
+	/// If metadata_fsync is disabled, sync the metadata database
+	/// to disk at this interval (group commit)
+	#[serde(default)]
+	pub metadata_fsync_interval: Option<String>,
 	/// Whether to fsync after all data block writes (disabled by default)
 	#[serde(default)]
 	pub data_fsync: bool,
 
+	/// Permissions to set on the metadata directory and database files,
+	/// as an octal string (e.g. "0700"). Unix only.
+	#[serde(default)]
+	pub metadata_dir_mode: Option<String>,
+	/// Permissions to set on the data directories, as an octal string
+	/// (e.g. "0700"). Unix only.
+	#[serde(default)]
+	pub data_dir_mode: Option<String>,
+
 	/// Disable automatic scrubbing of the data directory
 	#[serde(default)]
 	pub disable_scrub: bool,
@@ -30,6 +44,23 @@ pub struct Config {
 	/// Automatic snapshot interval for metadata
 	#[serde(default)]
 	pub metadata_auto_snapshot_interval: Option<String>,
+	/// Maximum duration of a metadata snapshot, in seconds
+	#[serde(default = "default_snapshot_timeout_secs")]
+	pub snapshot_timeout_secs: u64,
+	/// Interval at which the metadata db is compacted to reclaim the space
+	/// left unused by deleted data, if the db engine supports it
+	#[serde(default)]
+	pub metadata_auto_compaction_interval: Option<String>,
+
+	/// Time given to background workers to finish their current
+	/// task after a shutdown signal, in seconds
+	#[serde(default = "default_shutdown_deadline_secs")]
+	pub shutdown_deadline_secs: u64,
+
+	/// Interval at which the usage history of the cluster is sampled
+	/// (disabled if not set)
+	#[serde(default)]
+	pub usage_history_interval: Option<String>,
 
 	/// Size of data blocks to save to disk
 	#[serde(
@@ -38,6 +69,11 @@ pub struct Config {
 	)]
 	pub block_size: usize,
 
+	/// Block sizes to use instead of `block_size` for objects whose size
+	/// is declared when they are uploaded, depending on that size
+	#[serde(default)]
+	pub block_size_by_object_size: Vec<BlockSizeThreshold>,
+
 	/// Number of replicas. Can be any positive integer, but uneven numbers are more favorable.
 	/// - 1 for single-node clusters, or to disable replication
 	/// - 3 is the recommended and supported setting.
@@ -50,6 +86,11 @@ pub struct Config {
 	#[serde(default = "default_consistency_mode")]
 	pub consistency_mode: String,
 
+	/// Minimum number of distinct zones in which a write to the sharded
+	/// tables and to data blocks must be acknowledged (no requirement if not set)
+	#[serde(default)]
+	pub write_min_zones: Option<usize>,
+
 	/// Legacy option
 	pub replication_mode: Option<String>,
 
@@ -68,15 +109,69 @@ pub struct Config {
 	)]
 	pub block_ram_buffer_max: usize,
 
+	/// Maximum duration (in seconds) for which a GET request can prevent
+	/// the local deletion of the blocks of the object it is reading
+	#[serde(default = "default_block_read_hold_max_secs")]
+	pub block_read_hold_max_secs: u64,
+
+	/// Delay after which a block read request that has not returned
+	/// is also sent to another node (hedged reads are disabled if not set)
+	#[serde(default)]
+	pub block_read_hedge_delay_msec: Option<u64>,
+	/// Maximum number of requests in flight for a hedged block read
+	#[serde(default = "default_block_read_hedge_max_in_flight")]
+	pub block_read_hedge_max_in_flight: usize,
+	/// Use this percentile of the latency of recent block reads as the
+	/// hedge delay, when it is above `block_read_hedge_delay_msec`
+	/// (the hedge delay is fixed if not set)
+	#[serde(default)]
+	pub block_read_hedge_percentile: Option<f64>,
+	/// Maximum fraction of block reads for which a hedge can be sent
+	/// (no limit if not set)
+	#[serde(default)]
+	pub block_read_hedge_max_ratio: Option<f64>,
+
+	/// Data blocks smaller than this size in bytes (after compression)
+	/// are appended to container files shared with other small blocks,
+	/// instead of being stored in a file of their own (disabled if not set)
+	#[serde(default)]
+	pub block_pack_threshold: Option<usize>,
+	/// Fraction of the space of a container file occupied by deleted
+	/// blocks above which the container is compacted
+	#[serde(default = "default_block_pack_max_dead_ratio")]
+	pub block_pack_max_dead_ratio: f64,
+
+	/// What to do when reads and writes of data blocks keep failing
+	/// with I/O errors (errors are only reported by default)
+	#[serde(default)]
+	pub data_io_error_policy: DataIoErrorPolicy,
+	/// Number of I/O errors on data blocks, without any successful
+	/// read or write in between, after which the policy is applied
+	#[serde(default = "default_data_io_error_threshold")]
+	pub data_io_error_threshold: u64,
+
 	/// Skip the permission check of secret files. Useful when
 	/// POSIX ACLs (or more complex chmods) are used.
 	#[serde(default)]
 	pub allow_world_readable_secrets: bool,
 
+	/// Allow bucket names containing punycode labels, i.e. international
+	/// bucket names in their canonical form
+	#[serde(default)]
+	pub allow_punycode: bool,
+
 	/// RPC secret key: 32 bytes hex encoded
 	pub rpc_secret: Option<String>,
 	/// Optional file where RPC secret key is read from
 	pub rpc_secret_file: Option<PathBuf>,
+	/// Generate the RPC secret and the admin API token on first start
+	/// if they are not set, and store them in `generated_secrets_file`
+	#[serde(default)]
+	pub auto_generate_secrets: bool,
+	/// File where generated secrets are stored (defaults to
+	/// `generated_secrets` in the metadata directory)
+	#[serde(default)]
+	pub generated_secrets_file: Option<PathBuf>,
 	/// Address to bind for RPC
 	pub rpc_bind_addr: SocketAddr,
 	/// Bind outgoing sockets to rpc_bind_addr's IP address as well
@@ -93,6 +188,45 @@ pub struct Config {
 	pub rpc_ping_timeout_msec: Option<u64>,
 	/// Timeout for Netapp RPC calls
 	pub rpc_timeout_msec: Option<u64>,
+	/// Average ping time above which a node is reported as slow
+	pub rpc_slow_threshold_msec: Option<u64>,
+	/// Delay by which the system clock must be behind the latest timestamp
+	/// issued by the node for it to be reported in its status
+	pub clock_backwards_threshold_msec: Option<u64>,
+	/// Timeouts for specific classes of RPC calls
+	#[serde(default)]
+	pub rpc_timeouts: RpcTimeoutsConfig,
+	/// Number of consecutive failed attempts at connecting to a node after
+	/// which its address is looked up again in the discovery sources
+	/// (defaults to 2, 0 disables this)
+	#[serde(default)]
+	pub rpc_rediscovery_threshold: Option<usize>,
+	/// What to do with writes when not enough nodes are connected
+	/// to reach a quorum
+	#[serde(default)]
+	pub no_quorum_behavior: NoQuorumBehavior,
+	/// Repair of table entries on which replicas are found to disagree
+	/// when they are read
+	#[serde(default)]
+	pub read_repair: ReadRepairConfig,
+	/// Protection against writes on a node that is partitioned from
+	/// the majority of the cluster
+	#[serde(default)]
+	pub rpc: RpcConfig,
+
+	// -- Tokio runtime
+	/// Number of worker threads of the Tokio runtime (defaults to the number of CPU cores)
+	#[serde(default)]
+	pub tokio_worker_threads: Option<usize>,
+	/// Maximum number of threads used for blocking operations (defaults to 512)
+	#[serde(default)]
+	pub tokio_blocking_threads: Option<usize>,
+	/// Stack size of runtime threads, in KiB (defaults to the one of Tokio)
+	#[serde(default)]
+	pub tokio_thread_stack_size_kb: Option<usize>,
+	/// Name given to runtime threads (defaults to the one of Tokio)
+	#[serde(default)]
+	pub tokio_thread_name: Option<String>,
 
 	// -- Bootstraping and discovery
 	/// Bootstrap peers RPC address
@@ -114,6 +248,10 @@ pub struct Config {
 	/// LMDB map size
 	#[serde(deserialize_with = "deserialize_capacity", default)]
 	pub lmdb_map_size: usize,
+	/// Number of reader slots of the LMDB database, i.e. of read
+	/// transactions that can be open at the same time (defaults to 2048)
+	#[serde(default)]
+	pub lmdb_max_readers: Option<u32>,
 
 	// -- APIs
 	/// Configuration for S3 api
@@ -128,6 +266,14 @@ pub struct Config {
 	/// Configuration for the admin API endpoint
 	#[serde(default = "Default::default")]
 	pub admin: AdminConfig,
+
+	/// Security-related settings
+	#[serde(default = "Default::default")]
+	pub security: SecurityConfig,
+
+	/// Format and levels of the logs
+	#[serde(default = "Default::default")]
+	pub logging: LoggingConfig,
 }
 
 /// Value for data_dir: either a single directory or a list of dirs with attributes
@@ -160,6 +306,207 @@ pub struct S3ApiConfig {
 	/// Suffix to remove from domain name to find bucket. If None,
 	/// vhost-style S3 request are disabled
 	pub root_domain: Option<String>,
+	/// Ways of addressing buckets that are accepted in S3 requests
+	#[serde(default)]
+	pub addressing_style: AddressingStyle,
+	/// Send GetObject responses with chunked transfer encoding
+	/// instead of a Content-Length header
+	#[serde(default)]
+	pub prefer_chunked_get: bool,
+	/// Reject requests that were not made over HTTPS, as reported by
+	/// one of the trusted proxies
+	#[serde(default)]
+	pub require_tls: bool,
+	/// IP addresses of reverse proxies whose X-Forwarded-Proto header
+	/// is trusted
+	#[serde(default)]
+	pub trusted_proxies: Vec<String>,
+	/// Reject requests whose body is not signed (UNSIGNED-PAYLOAD)
+	/// if they were not made over HTTPS, as reported by one of the
+	/// trusted proxies
+	#[serde(default)]
+	pub unsigned_payload_requires_tls: bool,
+	/// Max age to announce in a Strict-Transport-Security header
+	/// sent on responses to HTTPS requests
+	#[serde(default)]
+	pub hsts_max_age_secs: Option<u64>,
+	/// Public endpoints of the S3 API, as `scheme://host[:port]`. If set,
+	/// signed requests are only accepted if their scheme and their Host
+	/// header match one of them
+	#[serde(default)]
+	pub public_endpoints: Vec<String>,
+	/// Number of recent error responses kept in memory for debugging
+	/// (defaults to 1000, 0 disables recording)
+	#[serde(default)]
+	pub recent_errors_buffer_size: Option<usize>,
+	/// Record object keys in plaintext in the recent errors buffer,
+	/// instead of only their hash
+	#[serde(default)]
+	pub recent_errors_plaintext_keys: bool,
+	/// Size of the source object above which CopyObject responses are sent
+	/// before the copy is finished, with whitespace to keep the connection
+	/// alive (defaults to 5 GiB)
+	#[serde(default)]
+	pub copy_keepalive_threshold: Option<u64>,
+	/// Maximum bandwidth in bytes per second used by the bodies of all
+	/// requests (resp. responses) of the S3 API of this node
+	#[serde(default)]
+	pub bandwidth_limit: Option<u64>,
+	/// Maximum bandwidth in bytes per second used by the body of each
+	/// request (resp. response) of the S3 API, i.e. by each connection
+	#[serde(default)]
+	pub connection_bandwidth_limit: Option<u64>,
+	/// Maximum size of an object in bytes
+	#[serde(default)]
+	pub max_object_size: Option<u64>,
+	/// Maximum size of a part of a multipart upload in bytes
+	#[serde(default)]
+	pub max_part_size: Option<u64>,
+	/// How objects whose key ends with a slash (folder markers) are handled
+	#[serde(default)]
+	pub directory_markers: DirectoryMarkers,
+	/// Maximum size in bytes of the entries of a ListObjects response,
+	/// above which the listing is truncated (defaults to 10 MiB)
+	#[serde(default)]
+	pub max_list_response_size: Option<usize>,
+	/// Maximum number of parts returned by a ListParts request, to which
+	/// larger `max-parts` values are reduced (defaults to 1000)
+	#[serde(default)]
+	pub max_list_parts: Option<u64>,
+	/// Interval in seconds after which the buffered server access log records
+	/// of a bucket are delivered to its target bucket (defaults to 300)
+	#[serde(default)]
+	pub access_log_flush_interval_secs: Option<u64>,
+	/// Size in bytes of the buffered access log records of a bucket above
+	/// which they are delivered without waiting (defaults to 5 MiB)
+	#[serde(default)]
+	pub access_log_flush_size: Option<usize>,
+	/// Maximum size in bytes of all access log records buffered by this node,
+	/// above which new records are dropped (defaults to 64 MiB)
+	#[serde(default)]
+	pub access_log_buffer_size: Option<usize>,
+	/// Maximum number of rules in the CORS configuration of a bucket
+	/// (defaults to 100)
+	#[serde(default)]
+	pub max_cors_rules: Option<usize>,
+	/// Maximum number of rules in the lifecycle configuration of a bucket
+	/// (defaults to 1000)
+	#[serde(default)]
+	pub max_lifecycle_rules: Option<usize>,
+	/// Compute and store the SHA256 checksum of all objects, even when
+	/// clients don't send or request one
+	#[serde(default)]
+	pub always_compute_sha256: bool,
+	/// Store a checksum of each data block of the objects uploaded with
+	/// a checksum, so that range reads can be verified block by block
+	#[serde(default)]
+	pub store_block_checksums: bool,
+	/// Maximum total size in bytes of the headers of a request, above
+	/// which it is rejected (defaults to 64 KiB)
+	#[serde(default)]
+	pub max_header_size: Option<usize>,
+	/// Maximum number of headers of a request, above which it is
+	/// rejected (defaults to 100)
+	#[serde(default)]
+	pub max_header_count: Option<usize>,
+	/// Close connections after this number of requests, for clients
+	/// and proxies that do not handle long-lived connections well
+	/// (no limit if not set)
+	#[serde(default)]
+	pub max_requests_per_connection: Option<u64>,
+	/// Reject all requests that modify buckets or objects, whatever
+	/// the permissions of the key used to make them
+	#[serde(default)]
+	pub read_only: bool,
+	/// Reject requests in which a query parameter appears several times,
+	/// instead of including all of its values in the signature check
+	#[serde(default)]
+	pub reject_duplicate_query_params: bool,
+	/// Number of days without any part uploaded after which incomplete
+	/// multipart uploads are aborted, in buckets whose lifecycle
+	/// configuration has no rule to abort incomplete uploads
+	pub abort_stalled_uploads_after_days: Option<u64>,
+	/// Number of parts whose metadata is read at the same time when
+	/// completing a multipart upload (defaults to 16)
+	#[serde(default)]
+	pub mpu_completion_concurrency: Option<usize>,
+	/// Time in milliseconds during which the access keys used to check
+	/// the signature of requests are cached in memory (defaults to 5000,
+	/// 0 disables the cache)
+	#[serde(default)]
+	pub key_cache_ttl_msec: Option<u64>,
+	/// Mirroring of write requests to another cluster
+	#[serde(default)]
+	pub shadow: Option<S3ShadowConfig>,
+	/// Credentials of external S3 endpoints that imports started through
+	/// the admin API can refer to by name
+	#[serde(default)]
+	pub import_credentials: BTreeMap<String, S3ImportCredentialsConfig>,
+}
+
+/// Credentials of an external S3 endpoint, used by imports
+#[derive(Deserialize, Debug, Clone)]
+pub struct S3ImportCredentialsConfig {
+	pub access_key_id: String,
+	/// Secret key, or a reference to a secret stored elsewhere
+	pub secret_access_key: String,
+}
+
+/// Configuration for the mirroring of S3 write requests to a shadow cluster
+#[derive(Deserialize, Debug, Clone)]
+pub struct S3ShadowConfig {
+	/// S3 endpoint of the shadow cluster, e.g. https://s3.example.com
+	pub endpoint: String,
+	/// Region of the shadow cluster (defaults to "garage")
+	#[serde(default)]
+	pub region: Option<String>,
+	/// Access key used to send requests to the shadow cluster
+	pub access_key_id: String,
+	pub secret_access_key: String,
+	/// Fraction of the write requests that are mirrored, between 0 and 1
+	/// (defaults to 1)
+	#[serde(default)]
+	pub sample_rate: Option<f64>,
+	/// Names of the operations that are mirrored (defaults to all the
+	/// operations that can be mirrored)
+	#[serde(default)]
+	pub operations: Vec<String>,
+	/// Number of mirrored requests that can wait to be replayed, above
+	/// which requests are dropped (defaults to 1000)
+	#[serde(default)]
+	pub queue_size: Option<usize>,
+	/// Number of requests replayed at the same time (defaults to 4)
+	#[serde(default)]
+	pub concurrency: Option<usize>,
+}
+
+/// Ways of addressing buckets in S3 requests
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressingStyle {
+	/// Both path-style and vhost-style requests are accepted
+	#[default]
+	Both,
+	/// Only vhost-style requests are accepted, the bucket name being
+	/// taken from the Host header
+	Vhost,
+	/// Only path-style requests are accepted, the bucket name being
+	/// the first component of the path
+	Path,
+}
+
+/// How objects whose key ends with a slash, that clients create to
+/// represent folders, are handled by the S3 API
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DirectoryMarkers {
+	/// Folder markers are regular objects (the behavior of AWS S3)
+	#[default]
+	Object,
+	/// Keys ending with a slash are directories: the folder marker is not
+	/// listed when listing its own content, and GetObject/HeadObject on
+	/// a directory without a folder marker return an empty object
+	Directory,
 }
 
 /// Configuration for K2V api
@@ -167,6 +514,30 @@ pub struct S3ApiConfig {
 pub struct K2VApiConfig {
 	/// Address and port to bind for api serving
 	pub api_bind_addr: UnixOrTCPSocketAddress,
+	/// Maximum total size in bytes of the headers of a request, above
+	/// which it is rejected (defaults to 64 KiB)
+	#[serde(default)]
+	pub max_header_size: Option<usize>,
+	/// Maximum number of headers of a request, above which it is
+	/// rejected (defaults to 100)
+	#[serde(default)]
+	pub max_header_count: Option<usize>,
+	/// Reject all requests that modify items, whatever the permissions
+	/// of the key used to make them
+	#[serde(default)]
+	pub read_only: bool,
+	/// Maximum size in bytes of a value inserted in an item
+	/// (no limit if not set)
+	#[serde(default)]
+	pub max_value_size: Option<usize>,
+	/// Maximum number of concurrent values that an item can have
+	/// after an insertion (no limit if not set)
+	#[serde(default)]
+	pub max_concurrent_values_per_item: Option<usize>,
+	/// Maximum total size in bytes of the concurrent values of an item
+	/// after an insertion (no limit if not set)
+	#[serde(default)]
+	pub max_item_total_size: Option<usize>,
 }
 
 /// Configuration for serving files as normal web server
@@ -176,6 +547,45 @@ pub struct WebConfig {
 	pub bind_addr: UnixOrTCPSocketAddress,
 	/// Suffix to remove from domain name to find bucket
 	pub root_domain: String,
+	/// Precompressed variants of objects that are served instead of the
+	/// objects themselves to clients that accept their encoding, by order
+	/// of preference
+	#[serde(default)]
+	pub precompressed_variants: Vec<PrecompressedVariant>,
+	/// Maximum total size in bytes of the headers of a request, above
+	/// which it is rejected (defaults to 64 KiB)
+	#[serde(default)]
+	pub max_header_size: Option<usize>,
+	/// Maximum number of headers of a request, above which it is
+	/// rejected (defaults to 100)
+	#[serde(default)]
+	pub max_header_count: Option<usize>,
+	/// Close connections after this number of requests, for clients
+	/// and proxies that do not handle long-lived connections well
+	/// (no limit if not set)
+	#[serde(default)]
+	pub max_requests_per_connection: Option<u64>,
+}
+
+/// Block size used for the objects of at least a given size
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockSizeThreshold {
+	/// Minimum size of the objects, in bytes
+	#[serde(deserialize_with = "deserialize_capacity")]
+	pub object_size: usize,
+	/// Size of the data blocks of these objects
+	#[serde(deserialize_with = "deserialize_capacity")]
+	pub block_size: usize,
+}
+
+/// A precompressed variant of the objects served by the web endpoint,
+/// stored alongside them with a key suffix (e.g. `asset.js.br` for `asset.js`)
+#[derive(Deserialize, Debug, Clone)]
+pub struct PrecompressedVariant {
+	/// Content-Encoding of the variant, as accepted by clients (e.g. `br`)
+	pub encoding: String,
+	/// Suffix of the keys of the variants (e.g. `.br`)
+	pub suffix: String,
 }
 
 /// Configuration for the admin and monitoring HTTP API
@@ -196,6 +606,154 @@ pub struct AdminConfig {
 
 	/// OTLP server to where to export traces
 	pub trace_sink: Option<String>,
+
+	/// Requests to the S3, K2V and admin APIs that take longer than
+	/// this are logged (disabled if 0)
+	#[serde(default)]
+	pub slow_request_threshold_msec: u64,
+
+	/// Maximum total size in bytes of the headers of a request, above
+	/// which it is rejected (defaults to 64 KiB)
+	#[serde(default)]
+	pub max_header_size: Option<usize>,
+	/// Maximum number of headers of a request, above which it is
+	/// rejected (defaults to 100)
+	#[serde(default)]
+	pub max_header_count: Option<usize>,
+
+	/// Only serve admin API endpoints that do not modify anything
+	#[serde(default)]
+	pub read_only: bool,
+
+	/// Honor the `x-garage-debug-timing` header on S3 requests made with
+	/// any access key, not only with keys that own the bucket
+	#[serde(default)]
+	pub allow_debug_timing: bool,
+}
+
+/// Timeouts for specific classes of RPC calls, in milliseconds.
+/// Classes for which no timeout is set use `rpc_timeout_msec`.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct RpcTimeoutsConfig {
+	/// Reading entries from metadata tables
+	pub metadata_read_msec: Option<u64>,
+	/// Writing entries to metadata tables
+	pub metadata_write_msec: Option<u64>,
+	/// Fetching data blocks
+	pub block_get_msec: Option<u64>,
+	/// Storing data blocks
+	pub block_put_msec: Option<u64>,
+	/// Background synchronization between nodes
+	pub sync_msec: Option<u64>,
+}
+
+/// Read repair settings
+#[derive(Deserialize, Debug, Clone)]
+pub struct ReadRepairConfig {
+	/// Write back the merged value to the replicas when a read
+	/// returns diverging values (enabled by default)
+	#[serde(default = "default_read_repair_enabled")]
+	pub enabled: bool,
+	/// Maximum number of entries repaired per second, for each table
+	/// (unlimited if not set)
+	#[serde(default)]
+	pub max_per_sec: Option<u64>,
+}
+
+impl Default for ReadRepairConfig {
+	fn default() -> Self {
+		Self {
+			enabled: default_read_repair_enabled(),
+			max_per_sec: None,
+		}
+	}
+}
+
+/// Settings of the `[rpc]` section
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct RpcConfig {
+	/// Refuse writes to the S3 and K2V APIs of this node when it cannot
+	/// reach enough of the nodes of the cluster layout
+	#[serde(default)]
+	pub minority_partition_protection: bool,
+	/// Fraction of the nodes of the cluster layout that this node must
+	/// reach, strictly, to accept writes (defaults to 0.5)
+	#[serde(default)]
+	pub minority_partition_threshold: Option<f64>,
+	/// Also refuse reads when this node cannot reach enough nodes
+	#[serde(default)]
+	pub minority_partition_refuse_reads: bool,
+	/// Protect the data frames exchanged with other nodes by a checksum,
+	/// when they also enable it
+	#[serde(default)]
+	pub frame_checksums: bool,
+	/// Send reads to nodes of the same zone as this node before nodes
+	/// of other zones (defaults to true)
+	#[serde(default)]
+	pub prefer_local_zone: Option<bool>,
+}
+
+/// Behavior of writes when not enough nodes are connected to reach a quorum
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum NoQuorumBehavior {
+	/// Send requests anyway, and wait for nodes to answer or
+	/// for the requests to time out
+	#[default]
+	Wait,
+	/// Fail immediately, without sending any request
+	FailFast,
+}
+
+/// What a node does when its data directories keep failing with I/O errors
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DataIoErrorPolicy {
+	/// Return the errors to the callers, and keep trying
+	#[default]
+	None,
+	/// Mark the data storage of the node as degraded: the node stops
+	/// accepting block writes, so that they go to other nodes
+	Quarantine,
+	/// Stop the node
+	Crash,
+}
+
+/// Security-related settings
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct SecurityConfig {
+	/// Delete access keys that have not been used for this number of days
+	/// (disabled if not set)
+	pub auto_expire_unused_keys_after_days: Option<u64>,
+	/// Log a warning about keys that will be expired in less than
+	/// this number of days
+	pub auto_expire_warning_days_before: Option<u64>,
+	/// URL to which these warnings are also sent, as JSON objects
+	/// in POST requests
+	pub auto_expire_warning_webhook: Option<String>,
+}
+
+/// Settings of the `[logging]` section
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct LoggingConfig {
+	/// Format of the log lines (overridden by `GARAGE_LOG_FORMAT`)
+	#[serde(default)]
+	pub format: LogFormat,
+	/// Log level of specific modules, e.g. `garage_table = "debug"`,
+	/// in addition to the default levels (ignored if `RUST_LOG` is set)
+	#[serde(default)]
+	pub levels: BTreeMap<String, String>,
+}
+
+/// Format of the log lines
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+	/// Human-readable lines
+	#[default]
+	Text,
+	/// One JSON object per line
+	Json,
 }
 
 #[derive(Deserialize, Debug, Clone, Default)]
@@ -263,10 +821,37 @@ fn default_block_ram_buffer_max() -> usize {
 	256 * 1024 * 1024
 }
 
+fn default_block_read_hold_max_secs() -> u64 {
+	6 * 3600
+}
+
+fn default_block_read_hedge_max_in_flight() -> usize {
+	2
+}
+
+fn default_block_pack_max_dead_ratio() -> f64 {
+	0.5
+}
+
+fn default_data_io_error_threshold() -> u64 {
+	5
+}
+
+fn default_snapshot_timeout_secs() -> u64 {
+	3600
+}
+
+fn default_shutdown_deadline_secs() -> u64 {
+	8
+}
 fn default_consistency_mode() -> String {
 	"consistent".into()
 }
 
+fn default_read_repair_enabled() -> bool {
+	true
+}
+
 fn default_compression() -> Option<i32> {
 	Some(1)
 }
@@ -395,4 +980,45 @@ mod tests {
 
 		Ok(())
 	}
+
+	#[test]
+	fn test_block_size_by_object_size() -> Result<(), Error> {
+		let path = mktemp::Temp::new_file()?;
+		let mut file = File::create(path.as_path())?;
+		writeln!(
+			file,
+			r#"
+			metadata_dir = "/tmp/garage/meta"
+			data_dir = "/tmp/garage/data"
+			replication_factor = 3
+			rpc_bind_addr = "[::]:3901"
+			block_size = "1M"
+			block_size_by_object_size = [
+				{{ object_size = "100M", block_size = "4M" }},
+				{{ object_size = 1073741824, block_size = "16MiB" }},
+			]
+
+			[s3_api]
+			s3_region = "garage"
+			api_bind_addr = "[::]:3900"
+			"#
+		)?;
+
+		let config = super::read_config(path.to_path_buf())?;
+		assert_eq!(
+			config.block_size_by_object_size,
+			vec![
+				super::BlockSizeThreshold {
+					object_size: 100_000_000,
+					block_size: 4_000_000,
+				},
+				super::BlockSizeThreshold {
+					object_size: 1 << 30,
+					block_size: 16 << 20,
+				},
+			]
+		);
+
+		Ok(())
+	}
 }
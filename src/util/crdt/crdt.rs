@@ -85,3 +85,8 @@ impl AutoCrdt for String {
 impl AutoCrdt for bool {
 	const WARN_IF_DIFFERENT: bool = true;
 }
+
+/// Monotonic counters are merged by keeping the highest value
+impl AutoCrdt for u64 {
+	const WARN_IF_DIFFERENT: bool = false;
+}
@@ -64,6 +64,13 @@ pub enum Error {
 	)]
 	Quorum(usize, Option<usize>, usize, usize, Vec<String>),
 
+	#[error(
+		display = "No quorum available: not enough nodes are connected to reach a quorum of {} (unreachable: {:?})",
+		_0,
+		_1
+	)]
+	NoQuorum(usize, Vec<String>),
+
 	#[error(display = "Unexpected RPC message: {}", _0)]
 	UnexpectedRpcMessage(String),
 
@@ -1,4 +1,7 @@
-use std::time::Instant;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use futures::{future::BoxFuture, Future, FutureExt};
 use rand::Rng;
@@ -58,6 +61,189 @@ where
 
 // ----
 
+/// Time spent in the different phases of the handling of a request
+/// (e.g. signature verification, table reads, block IO), by phase name
+pub type RequestPhases = HashMap<&'static str, Duration>;
+
+tokio::task_local! {
+	static REQUEST_PHASES: RefCell<RequestPhases>;
+}
+
+/// Run a future, collecting the time spent in the phases recorded
+/// with `timed_phase` while it runs in the current task
+pub async fn collect_request_phases<F: Future>(f: F) -> (F::Output, RequestPhases) {
+	REQUEST_PHASES
+		.scope(RefCell::new(HashMap::new()), async move {
+			let res = f.await;
+			(res, REQUEST_PHASES.with(|phases| phases.take()))
+		})
+		.await
+}
+
+/// Run a future, and add the time it took to the given phase
+/// of the request being handled, if any
+pub async fn timed_phase<F: Future>(phase: &'static str, f: F) -> F::Output {
+	let start = Instant::now();
+	let res = f.await;
+	let elapsed = Instant::now().saturating_duration_since(start);
+	let _ = REQUEST_PHASES.try_with(|phases| {
+		*phases.borrow_mut().entry(phase).or_default() += elapsed;
+	});
+	record_timing(phase, || None, elapsed);
+	res
+}
+
+// ----
+
+/// Detailed timing of the handling of a single request, collected only
+/// when the client asks for it. Unlike `RequestPhases`, each phase is
+/// recorded separately, with an optional detail (e.g. the node that
+/// returned a data block).
+#[derive(Debug, Default)]
+pub struct RequestTiming {
+	entries: Mutex<Vec<TimingEntry>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TimingEntry {
+	pub phase: &'static str,
+	pub detail: Option<String>,
+	pub duration: Duration,
+}
+
+tokio::task_local! {
+	static REQUEST_TIMING: Arc<RequestTiming>;
+}
+
+impl RequestTiming {
+	pub fn new() -> Arc<Self> {
+		Arc::new(Self::default())
+	}
+
+	pub fn record(&self, phase: &'static str, detail: Option<String>, duration: Duration) {
+		self.entries.lock().unwrap().push(TimingEntry {
+			phase,
+			detail,
+			duration,
+		});
+	}
+
+	pub fn entries(&self) -> Vec<TimingEntry> {
+		self.entries.lock().unwrap().clone()
+	}
+
+	/// Format the timing in the syntax of the Server-Timing header:
+	/// `phase;dur=<milliseconds>;desc="<detail>", ...`
+	pub fn to_header_value(&self) -> String {
+		self.entries()
+			.iter()
+			.map(|e| {
+				let mut s = format!(
+					"{};dur={:.3}",
+					e.phase.replace(' ', "-"),
+					e.duration.as_secs_f64() * 1000.
+				);
+				if let Some(detail) = &e.detail {
+					s.push_str(&format!(";desc=\"{}\"", detail.replace('"', "'")));
+				}
+				s
+			})
+			.collect::<Vec<_>>()
+			.join(", ")
+	}
+}
+
+/// Run a future, collecting the detailed timing of its phases in `timing`
+/// if it is set. This is also used to keep collecting the timing of a request
+/// in tasks spawned to handle it (see `current_request_timing`).
+pub async fn with_request_timing<F: Future>(timing: Option<Arc<RequestTiming>>, f: F) -> F::Output {
+	match timing {
+		Some(timing) => REQUEST_TIMING.scope(timing, f).await,
+		None => f.await,
+	}
+}
+
+/// The detailed timing being collected for the current request, if any
+pub fn current_request_timing() -> Option<Arc<RequestTiming>> {
+	REQUEST_TIMING.try_with(Arc::clone).ok()
+}
+
+/// Add an entry to the detailed timing of the current request, if it is
+/// being collected. The detail is computed only in that case.
+pub fn record_timing<D>(phase: &'static str, detail: D, duration: Duration)
+where
+	D: FnOnce() -> Option<String>,
+{
+	let _ = REQUEST_TIMING.try_with(|timing| timing.record(phase, detail(), duration));
+}
+
+/// Run a synchronous function, and add the time it took to the detailed
+/// timing of the current request, if it is being collected
+pub fn timed_sync<T>(phase: &'static str, f: impl FnOnce() -> T) -> T {
+	if current_request_timing().is_none() {
+		return f();
+	}
+	let start = Instant::now();
+	let res = f();
+	record_timing(phase, || None, start.elapsed());
+	res
+}
+
 pub fn gen_trace_id() -> TraceId {
 	rand::thread_rng().gen::<[u8; 16]>().into()
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn test_request_phases() {
+		let (res, phases) = collect_request_phases(async {
+			timed_phase("table read", tokio::time::sleep(Duration::from_millis(10))).await;
+			timed_phase("table read", tokio::time::sleep(Duration::from_millis(10))).await;
+			timed_phase("block io", async { 42 }).await
+		})
+		.await;
+		assert_eq!(res, 42);
+		assert_eq!(phases.len(), 2);
+		assert!(phases["table read"] >= Duration::from_millis(20));
+
+		// Outside of collect_request_phases, phases are not recorded
+		assert_eq!(timed_phase("block io", async { 1 }).await, 1);
+	}
+
+	#[tokio::test]
+	async fn test_request_timing() {
+		// Nothing is recorded when the timing is not collected
+		assert_eq!(timed_phase("table read", async { 1 }).await, 1);
+		record_timing("block fetch", || panic!("detail computed"), Duration::ZERO);
+		assert!(current_request_timing().is_none());
+
+		let timing = RequestTiming::new();
+		with_request_timing(Some(timing.clone()), async {
+			timed_phase("table read", async {}).await;
+			let spawned_timing = current_request_timing();
+			tokio::spawn(with_request_timing(spawned_timing, async {
+				record_timing(
+					"block fetch",
+					|| Some("block from node".into()),
+					Duration::from_millis(5),
+				);
+			}))
+			.await
+			.unwrap();
+			timed_sync("serialization", || ());
+		})
+		.await;
+
+		let entries = timing.entries();
+		let phases = entries.iter().map(|e| e.phase).collect::<Vec<_>>();
+		assert_eq!(phases, vec!["table read", "block fetch", "serialization"]);
+		assert_eq!(entries[1].detail.as_deref(), Some("block from node"));
+
+		let header = timing.to_header_value();
+		assert!(header.starts_with("table-read;dur="), "{}", header);
+		assert!(header.contains("block-fetch;dur=5.000;desc=\"block from node\""));
+	}
+}
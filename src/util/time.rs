@@ -1,23 +1,79 @@
 //! Module containing helper functions to manipulate time
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
 use chrono::{SecondsFormat, TimeZone, Utc};
-use std::time::{SystemTime, UNIX_EPOCH};
+use opentelemetry::{global, metrics::Counter};
+
+/// Minimum interval between two warnings about the clock being behind
+/// the timestamps that were already issued
+const CLOCK_WARNING_INTERVAL: Duration = Duration::from_secs(60);
+/// Timestamps issued at more than one per millisecond run slightly ahead
+/// of the wall clock, which is only reported above this difference
+const CLOCK_BEHIND_TOLERANCE_MSEC: u64 = 1000;
+/// Maximum difference between the latest timestamp restored after a
+/// restart and the wall clock
+const MAX_RESTORED_SKEW_MSEC: u64 = 3600 * 1000;
+
+static TIMESTAMPS: TimestampGuard<SystemTimeSource> = TimestampGuard::new(SystemTimeSource);
 
-/// Returns milliseconds since UNIX Epoch
+/// Returns milliseconds since UNIX Epoch.
+///
+/// The timestamps returned always increase, even if the wall clock goes
+/// backwards: see `TimestampGuard`. Timestamps of writes should rather be
+/// taken from the `Clock` given to the write path.
 pub fn now_msec() -> u64 {
-	SystemTime::now()
-		.duration_since(UNIX_EPOCH)
-		.expect("Fix your clock :o")
-		.as_millis() as u64
+	TIMESTAMPS.now_msec()
+}
+
+/// Returns milliseconds since UNIX Epoch according to the wall clock,
+/// which might be before timestamps returned previously by `now_msec`
+pub fn wall_clock_msec() -> u64 {
+	SystemTimeSource.wall_clock_msec()
+}
+
+/// Latest wall clock time read by `now_msec`, which is persisted so that
+/// timestamps do not go backwards across restarts either
+pub fn latest_timestamp() -> u64 {
+	TIMESTAMPS.latest()
+}
+
+/// Restore the latest wall clock time read before a restart
+pub fn restore_latest_timestamp(timestamp: u64) {
+	TIMESTAMPS.restore(timestamp)
 }
 
-/// Increment logical clock
-pub fn increment_logical_clock(prev: u64) -> u64 {
-	std::cmp::max(prev + 1, now_msec())
+/// Number of milliseconds by which the wall clock is behind
+/// the latest timestamp issued by `now_msec`
+pub fn clock_behind_msec() -> u64 {
+	TIMESTAMPS.behind_msec()
+}
+
+/// Clock issuing the timestamps of writes, which is given to the write
+/// paths so that it can be replaced in tests
+pub trait Clock: Send + Sync {
+	/// Returns milliseconds since UNIX Epoch, always increasing
+	fn now_msec(&self) -> u64;
+
+	/// Increment logical clock
+	fn increment_logical_clock(&self, prev: u64) -> u64 {
+		std::cmp::max(prev + 1, self.now_msec())
+	}
+
+	/// Increment two logical clocks
+	fn increment_logical_clock_2(&self, prev: u64, prev2: u64) -> u64 {
+		std::cmp::max(prev2 + 1, std::cmp::max(prev + 1, self.now_msec()))
+	}
 }
 
-/// Increment two logical clocks
-pub fn increment_logical_clock_2(prev: u64, prev2: u64) -> u64 {
-	std::cmp::max(prev2 + 1, std::cmp::max(prev + 1, now_msec()))
+/// Clock of this process, the one used by `now_msec`
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+	fn now_msec(&self) -> u64 {
+		TIMESTAMPS.now_msec()
+	}
 }
 
 /// Convert a timestamp represented as milliseconds since UNIX Epoch to
@@ -28,3 +84,201 @@ pub fn msec_to_rfc3339(msecs: u64) -> String {
 	let timestamp = Utc.timestamp_opt(secs, nanos).unwrap();
 	timestamp.to_rfc3339_opts(SecondsFormat::Millis, true)
 }
+
+// ---- timestamp guard ----
+
+/// Source of the wall clock time, that can be replaced in tests
+pub trait TimeSource: Send + Sync {
+	/// Returns milliseconds since UNIX Epoch
+	fn wall_clock_msec(&self) -> u64;
+}
+
+pub struct SystemTimeSource;
+
+impl TimeSource for SystemTimeSource {
+	fn wall_clock_msec(&self) -> u64 {
+		// A clock set before the UNIX epoch is handled like any other
+		// clock that went backwards
+		SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map_or(0, |d| d.as_millis() as u64)
+	}
+}
+
+/// Issues strictly increasing timestamps. Timestamps order the writes to
+/// CRDTs, so if the wall clock is stepped backwards (by NTP, or when a VM
+/// is restored from a snapshot), new writes could otherwise appear older
+/// than existing data and be silently lost.
+///
+/// Each timestamp is the wall clock time, or one millisecond after the
+/// latest timestamp issued if the wall clock is not after it. When the wall
+/// clock is behind by more than a second, a warning is logged (at most once
+/// per minute) and the `clock_behind_timestamps` metric is incremented for
+/// each timestamp issued.
+pub struct TimestampGuard<S: TimeSource> {
+	source: S,
+	latest: AtomicU64,
+	last_warning: Mutex<Option<Instant>>,
+}
+
+impl<S: TimeSource> TimestampGuard<S> {
+	pub const fn new(source: S) -> Self {
+		Self {
+			source,
+			latest: AtomicU64::new(0),
+			last_warning: Mutex::new(None),
+		}
+	}
+
+	pub fn now_msec(&self) -> u64 {
+		let wall = self.source.wall_clock_msec();
+		let prev = self
+			.latest
+			.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |prev| {
+				Some(std::cmp::max(wall, prev + 1))
+			})
+			.unwrap();
+		if wall > prev {
+			return wall;
+		}
+		if prev - wall < CLOCK_BEHIND_TOLERANCE_MSEC {
+			return prev + 1;
+		}
+
+		clock_behind_counter().add(1, &[]);
+		let now = Instant::now();
+		let mut last_warning = self.last_warning.lock().unwrap();
+		if last_warning.map_or(true, |t| now >= t + CLOCK_WARNING_INTERVAL) {
+			*last_warning = Some(now);
+			warn!(
+				"The system clock is {} ms behind the latest timestamp issued by this node (did it go backwards?). Timestamps are issued after this latest timestamp until the clock catches up.",
+				prev - wall
+			);
+		}
+		prev + 1
+	}
+
+	pub fn latest(&self) -> u64 {
+		self.latest.load(Ordering::Relaxed)
+	}
+
+	/// Restore the latest timestamp issued before a restart, capped to one
+	/// hour after the wall clock, so that timestamps can follow the wall
+	/// clock again after it was once set far in the future
+	pub fn restore(&self, timestamp: u64) {
+		let wall = self.source.wall_clock_msec();
+		let max = wall.saturating_add(MAX_RESTORED_SKEW_MSEC);
+		if timestamp > max {
+			warn!(
+				"The latest timestamp issued before this node restarted is {} ms ahead of the system clock, new timestamps will be at most one hour ahead of it.",
+				timestamp - wall
+			);
+		}
+		self.latest
+			.fetch_max(std::cmp::min(timestamp, max), Ordering::Relaxed);
+	}
+
+	pub fn behind_msec(&self) -> u64 {
+		self.latest().saturating_sub(self.source.wall_clock_msec())
+	}
+}
+
+impl<S: TimeSource> Clock for TimestampGuard<S> {
+	fn now_msec(&self) -> u64 {
+		TimestampGuard::now_msec(self)
+	}
+}
+
+fn clock_behind_counter() -> &'static Counter<u64> {
+	static COUNTER: OnceLock<Counter<u64>> = OnceLock::new();
+	COUNTER.get_or_init(|| {
+		global::meter("garage/util")
+			.u64_counter("clock_behind_timestamps")
+			.with_description(
+				"Number of timestamps issued while the system clock was behind the latest timestamp issued",
+			)
+			.init()
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	struct FakeClock(AtomicU64);
+
+	impl TimeSource for &FakeClock {
+		fn wall_clock_msec(&self) -> u64 {
+			self.0.load(Ordering::Relaxed)
+		}
+	}
+
+	#[test]
+	fn test_clock_backwards() {
+		let clock = FakeClock(AtomicU64::new(1_000_000));
+		let set = |t: u64| clock.0.store(t, Ordering::Relaxed);
+		let guard = TimestampGuard::new(&clock);
+
+		assert_eq!(guard.now_msec(), 1_000_000);
+		set(1_000_500);
+		let before_jump = guard.now_msec();
+		assert_eq!(before_jump, 1_000_500);
+		assert_eq!(guard.behind_msec(), 0);
+
+		// The clock goes back by a minute: new timestamps are still
+		// strictly after the ones issued before
+		set(940_500);
+		assert_eq!(guard.behind_msec(), 60_000);
+		for i in 1..=10 {
+			assert_eq!(guard.now_msec(), before_jump + i);
+		}
+		assert_eq!(guard.latest(), before_jump + 10);
+
+		// Once it has caught up, the clock is used again
+		set(1_000_600);
+		assert_eq!(guard.now_msec(), 1_000_600);
+		assert_eq!(guard.behind_msec(), 0);
+	}
+
+	#[test]
+	fn test_timestamps_strictly_increase() {
+		let clock = FakeClock(AtomicU64::new(1_000_000));
+		let guard = TimestampGuard::new(&clock);
+
+		// Timestamps issued during the same millisecond are all different
+		assert_eq!(guard.now_msec(), 1_000_000);
+		assert_eq!(guard.now_msec(), 1_000_001);
+		assert_eq!(guard.now_msec(), 1_000_002);
+
+		clock.0.store(1_000_010, Ordering::Relaxed);
+		assert_eq!(guard.now_msec(), 1_000_010);
+		assert_eq!(guard.increment_logical_clock(1_000_020), 1_000_021);
+		assert_eq!(guard.increment_logical_clock_2(5, 6), 1_000_012);
+	}
+
+	#[test]
+	fn test_clock_backwards_across_restarts() {
+		let clock = FakeClock(AtomicU64::new(2_000_000));
+		let guard = TimestampGuard::new(&clock);
+		guard.now_msec();
+		let persisted = guard.latest();
+
+		// Restarted with a clock that is behind by less than an hour
+		clock.0.store(1_000_000, Ordering::Relaxed);
+		let guard = TimestampGuard::new(&clock);
+		guard.restore(persisted);
+		assert_eq!(guard.now_msec(), 2_000_001);
+		assert_eq!(guard.behind_msec(), 1_000_001);
+	}
+
+	#[test]
+	fn test_restored_timestamp_is_capped() {
+		let clock = FakeClock(AtomicU64::new(1_000_000));
+		let guard = TimestampGuard::new(&clock);
+
+		// The clock was set ten hours in the future before the restart
+		guard.restore(1_000_000 + 10 * 3600 * 1000);
+		assert_eq!(guard.latest(), 1_000_000 + MAX_RESTORED_SKEW_MSEC);
+		assert_eq!(guard.now_msec(), 1_000_001 + MAX_RESTORED_SKEW_MSEC);
+	}
+}
@@ -7,7 +7,7 @@ use tokio::sync::watch;
 
 use hyper::{
 	body::Incoming as IncomingBody,
-	header::{HeaderValue, HOST},
+	header::{HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING},
 	Method, Request, Response, StatusCode,
 };
 
@@ -20,17 +20,21 @@ use opentelemetry::{
 
 use crate::error::*;
 
-use garage_api::generic_server::{server_loop, UnixListenerOn};
+use garage_api::generic_server::{server_loop, HeaderLimits, UnixListenerOn};
 use garage_api::helpers::*;
-use garage_api::s3::cors::{add_cors_headers, find_matching_cors_rule, handle_options_for_bucket};
-use garage_api::s3::error::{
-	CommonErrorDerivative, Error as ApiError, OkOrBadRequest, OkOrInternalError,
+use garage_api::s3::cors::{
+	add_cors_headers, add_vary, find_matching_cors_rule, handle_options_for_bucket,
+};
+use garage_api::s3::error::{CommonErrorDerivative, Error as ApiError, OkOrInternalError};
+use garage_api::s3::get::{
+	handle_get_without_ctx, handle_head_without_ctx, make_redirect_location_absolute,
+	GetObjectOverrides,
 };
-use garage_api::s3::get::{handle_get_without_ctx, handle_head_without_ctx};
 
 use garage_model::garage::Garage;
 
 use garage_table::*;
+use garage_util::config::PrecompressedVariant;
 use garage_util::data::Uuid;
 use garage_util::error::Error as GarageError;
 use garage_util::forwarded_headers;
@@ -67,16 +71,22 @@ pub struct WebServer {
 	garage: Arc<Garage>,
 	metrics: Arc<WebMetrics>,
 	root_domain: String,
+	precompressed_variants: Vec<PrecompressedVariant>,
 }
 
 impl WebServer {
 	/// Run a web server
-	pub fn new(garage: Arc<Garage>, root_domain: String) -> Arc<Self> {
+	pub fn new(
+		garage: Arc<Garage>,
+		root_domain: String,
+		precompressed_variants: Vec<PrecompressedVariant>,
+	) -> Arc<Self> {
 		let metrics = Arc::new(WebMetrics::new());
 		Arc::new(WebServer {
 			garage,
 			metrics,
 			root_domain,
+			precompressed_variants,
 		})
 	}
 
@@ -88,13 +98,28 @@ impl WebServer {
 		let server_name = "Web".into();
 		info!("Web server listening on {}", bind_addr);
 
+		let web_config = self.garage.config.s3_web.as_ref();
+		let header_limits = HeaderLimits::new(
+			web_config.and_then(|c| c.max_header_size),
+			web_config.and_then(|c| c.max_header_count),
+		);
+		let max_requests_per_connection = web_config.and_then(|c| c.max_requests_per_connection);
+
 		match bind_addr {
 			UnixOrTCPSocketAddress::TCPSocket(addr) => {
 				let listener = TcpListener::bind(addr).await?;
 
 				let handler =
 					move |stream, socketaddr| self.clone().handle_request(stream, socketaddr);
-				server_loop(server_name, listener, handler, must_exit).await
+				server_loop(
+					server_name,
+					listener,
+					handler,
+					header_limits,
+					max_requests_per_connection,
+					must_exit,
+				)
+				.await
 			}
 			UnixOrTCPSocketAddress::UnixSocket(ref path) => {
 				if path.exists() {
@@ -108,7 +133,15 @@ impl WebServer {
 
 				let handler =
 					move |stream, socketaddr| self.clone().handle_request(stream, socketaddr);
-				server_loop(server_name, listener, handler, must_exit).await
+				server_loop(
+					server_name,
+					listener,
+					handler,
+					header_limits,
+					max_requests_per_connection,
+					must_exit,
+				)
+				.await
 			}
 		}
 	}
@@ -193,16 +226,65 @@ impl WebServer {
 		Ok(exists)
 	}
 
+	/// Serve an object for a GET or HEAD request, or one of its precompressed
+	/// variants if one exists with an encoding accepted by the client
+	async fn get_or_head_object(
+		self: &Arc<Self>,
+		req: &Request<IncomingBody>,
+		bucket_id: Uuid,
+		key: &str,
+	) -> Result<Response<BoxBody<ApiError>>, ApiError> {
+		let accept_encoding = req
+			.headers()
+			.get(ACCEPT_ENCODING)
+			.and_then(|v| v.to_str().ok())
+			.unwrap_or_default();
+		let variants = select_precompressed_variants(accept_encoding, &self.precompressed_variants);
+		for variant in variants {
+			let variant_key = format!("{}{}", key, variant.suffix);
+			match self.get_or_head_key(req, bucket_id, &variant_key).await {
+				Err(ApiError::NoSuchKey) => continue,
+				Err(e) => return Err(e),
+				Ok(mut resp) => {
+					resp.headers_mut().insert(
+						CONTENT_ENCODING,
+						HeaderValue::from_str(&variant.encoding)
+							.ok_or_internal_error("Invalid encoding of precompressed variant")?,
+					);
+					return Ok(resp);
+				}
+			}
+		}
+		self.get_or_head_key(req, bucket_id, key).await
+	}
+
+	async fn get_or_head_key(
+		self: &Arc<Self>,
+		req: &Request<IncomingBody>,
+		bucket_id: Uuid,
+		key: &str,
+	) -> Result<Response<BoxBody<ApiError>>, ApiError> {
+		if *req.method() == Method::HEAD {
+			handle_head_without_ctx(self.garage.clone(), req, bucket_id, key, None).await
+		} else {
+			handle_get_without_ctx(
+				self.garage.clone(),
+				req,
+				bucket_id,
+				key,
+				None,
+				GetObjectOverrides::from_public_query(req.uri().query()),
+			)
+			.await
+		}
+	}
+
 	async fn serve_file(
 		self: &Arc<Self>,
 		req: &Request<IncomingBody>,
 	) -> Result<Response<BoxBody<ApiError>>, Error> {
 		// Get http authority string (eg. [::1]:3902 or garage.tld:80)
-		let authority = req
-			.headers()
-			.get(HOST)
-			.ok_or_bad_request("HOST header required")?
-			.to_str()?;
+		let authority = host_header(req)?;
 
 		// Get bucket
 		let host = authority_to_host(authority)?;
@@ -235,6 +317,7 @@ impl WebServer {
 		let path = req.uri().path().to_string();
 		let index = &website_config.index_document;
 		let (key, may_redirect) = path_to_keys(&path, index)?;
+		let key = bucket_params.index_key(&key);
 
 		debug!(
 			"Selected bucket: \"{}\" {:?}, target key: \"{}\", may redirect to: {:?}",
@@ -245,27 +328,16 @@ impl WebServer {
 			Method::OPTIONS => handle_options_for_bucket(req, &bucket_params)
 				.map_err(ApiError::from)
 				.map(|res| res.map(|_empty_body: EmptyBody| empty_body())),
-			Method::HEAD => {
-				handle_head_without_ctx(self.garage.clone(), req, bucket_id, &key, None).await
-			}
-			Method::GET => {
-				handle_get_without_ctx(
-					self.garage.clone(),
-					req,
-					bucket_id,
-					&key,
-					None,
-					Default::default(),
-				)
-				.await
-			}
+			Method::HEAD | Method::GET => self.get_or_head_object(req, bucket_id, &key).await,
 			_ => Err(ApiError::bad_request("HTTP method not supported")),
 		};
 
 		// Try implicit redirect on error
 		let ret_doc_with_redir = match (&ret_doc, may_redirect) {
 			(Err(ApiError::NoSuchKey), ImplicitRedirect::To { key, url })
-				if self.check_key_exists(bucket_id, key.as_str()).await? =>
+				if self
+					.check_key_exists(bucket_id, &bucket_params.index_key(key.as_str()))
+					.await? =>
 			{
 				Ok(Response::builder()
 					.status(StatusCode::FOUND)
@@ -306,7 +378,7 @@ impl WebServer {
 					self.garage.clone(),
 					&req2,
 					bucket_id,
-					&error_document,
+					&bucket_params.index_key(&error_document),
 					None,
 					Default::default(),
 				)
@@ -344,11 +416,18 @@ impl WebServer {
 				}
 			}
 			Ok(mut resp) => {
+				make_redirect_location_absolute(&mut resp);
 				// Maybe add CORS headers
-				if let Some(rule) = find_matching_cors_rule(&bucket_params, req)? {
-					add_cors_headers(&mut resp, rule)
+				if let Some((rule, origin)) = find_matching_cors_rule(&bucket_params, req)? {
+					add_cors_headers(&mut resp, rule, origin)
 						.ok_or_internal_error("Invalid bucket CORS configuration")?;
 				}
+				// The content served depends on the Accept-Encoding header
+				// of the request if there are precompressed variants
+				if !self.precompressed_variants.is_empty() && *req.method() != Method::OPTIONS {
+					add_vary(resp.headers_mut(), "Accept-Encoding")
+						.ok_or_internal_error("Invalid Vary header")?;
+				}
 				Ok(resp)
 			}
 		}
@@ -412,6 +491,54 @@ fn path_to_keys<'a>(path: &'a str, index: &str) -> Result<(String, ImplicitRedir
 	}
 }
 
+/// Select the precompressed variants that can be served to a client given
+/// the value of the Accept-Encoding header of its request, by order of
+/// preference: the client's preference given by q-values first, then the
+/// order in which variants are configured.
+fn select_precompressed_variants<'a>(
+	accept_encoding: &str,
+	variants: &'a [PrecompressedVariant],
+) -> Vec<&'a PrecompressedVariant> {
+	let accepted = accept_encoding
+		.split(',')
+		.filter_map(|item| {
+			let mut params = item.split(';');
+			let coding = params.next()?.trim();
+			if coding.is_empty() {
+				return None;
+			}
+			let q = params
+				.filter_map(|p| {
+					let (name, value) = p.split_once('=')?;
+					name.trim()
+						.eq_ignore_ascii_case("q")
+						.then(|| value.trim().parse::<f32>().ok())?
+				})
+				.next()
+				.unwrap_or(1.0);
+			Some((coding, q))
+		})
+		.collect::<Vec<_>>();
+
+	let q_value = |encoding: &str| {
+		accepted
+			.iter()
+			.find(|(c, _)| c.eq_ignore_ascii_case(encoding))
+			.or_else(|| accepted.iter().find(|(c, _)| *c == "*"))
+			.map(|(_, q)| *q)
+			.unwrap_or(0.0)
+	};
+
+	let mut selected = variants
+		.iter()
+		.map(|v| (v, q_value(&v.encoding)))
+		.filter(|(_, q)| *q > 0.0)
+		.collect::<Vec<_>>();
+	// Stable sort, so that the configured order is kept for equal q-values
+	selected.sort_by(|(_, q1), (_, q2)| q2.total_cmp(q1));
+	selected.into_iter().map(|(v, _)| v).collect()
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -450,4 +577,33 @@ mod tests {
 		assert!(path_to_keys("i/am/relative", "index.html").is_err());
 		Ok(())
 	}
+
+	#[test]
+	fn select_precompressed_variants_test() {
+		let variants = vec![
+			PrecompressedVariant {
+				encoding: "br".into(),
+				suffix: ".br".into(),
+			},
+			PrecompressedVariant {
+				encoding: "gzip".into(),
+				suffix: ".gz".into(),
+			},
+		];
+		let select = |accept_encoding| {
+			select_precompressed_variants(accept_encoding, &variants)
+				.into_iter()
+				.map(|v| v.suffix.as_str())
+				.collect::<Vec<_>>()
+		};
+
+		assert_eq!(select("gzip, deflate, br"), vec![".br", ".gz"]);
+		assert_eq!(select("gzip;q=1.0, br;q=0.8"), vec![".gz", ".br"]);
+		assert_eq!(select("GZIP"), vec![".gz"]);
+		assert_eq!(select("br;q=0, gzip"), vec![".gz"]);
+		assert_eq!(select("*"), vec![".br", ".gz"]);
+		assert_eq!(select("br, *;q=0"), vec![".br"]);
+		assert_eq!(select("identity"), Vec::<&str>::new());
+		assert_eq!(select(""), Vec::<&str>::new());
+	}
 }